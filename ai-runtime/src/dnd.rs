@@ -0,0 +1,373 @@
+//! Do Not Disturb schedules
+//!
+//! While a user is in DND, new-mail notifications are not pushed to their
+//! WebSocket clients. Delivery and summary generation are unaffected.
+//! When `batch_summary` is enabled, the suppressed notifications are kept
+//! and sent as a single digest once the window ends.
+//!
+//! DND is active either during the recurring daily window (`start`..`end`,
+//! local time, may wrap past midnight) or until a one-click snooze expires.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, Row};
+use tracing::{debug, info};
+
+use crate::summary::EmailSummary;
+
+/// Per-user DND settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DndSettings {
+    /// Recurring window enabled
+    #[serde(default)]
+    pub enabled: bool,
+    /// Window start, local time (HH:MM)
+    #[serde(default = "default_start")]
+    pub start: String,
+    /// Window end, local time (HH:MM)
+    #[serde(default = "default_end")]
+    pub end: String,
+    /// Days the window starts on (0 = Monday .. 6 = Sunday), empty for every day
+    #[serde(default)]
+    pub days: Vec<u8>,
+    /// Offset of the user's local time from UTC, in minutes
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+    /// Send suppressed notifications as one digest when DND ends
+    #[serde(default = "default_batch_summary")]
+    pub batch_summary: bool,
+    /// One-click snooze end, independent of the recurring window
+    #[serde(default)]
+    pub snoozed_until: Option<DateTime<Utc>>,
+}
+
+fn default_start() -> String {
+    "22:00".to_string()
+}
+
+fn default_end() -> String {
+    "07:00".to_string()
+}
+
+fn default_batch_summary() -> bool {
+    true
+}
+
+impl Default for DndSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            start: default_start(),
+            end: default_end(),
+            days: Vec::new(),
+            utc_offset_minutes: 0,
+            batch_summary: default_batch_summary(),
+            snoozed_until: None,
+        }
+    }
+}
+
+impl DndSettings {
+    /// Check the settings are well-formed
+    pub fn validate(&self) -> Result<()> {
+        parse_time(&self.start)?;
+        parse_time(&self.end)?;
+        if self.days.iter().any(|day| *day > 6) {
+            bail!("days must be between 0 (Monday) and 6 (Sunday)");
+        }
+        if self.utc_offset_minutes.abs() >= 24 * 60 {
+            bail!("utc_offset_minutes must be less than 24 hours");
+        }
+        Ok(())
+    }
+
+    /// Whether notifications are suppressed at `now`
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        if self.snoozed_until.is_some_and(|until| now < until) {
+            return true;
+        }
+        if !self.enabled {
+            return false;
+        }
+
+        let (Ok(start), Ok(end)) = (parse_time(&self.start), parse_time(&self.end)) else {
+            return false;
+        };
+        let Some(offset) = FixedOffset::east_opt(self.utc_offset_minutes * 60) else {
+            return false;
+        };
+
+        let local = now.with_timezone(&offset);
+        let time = local.time();
+        let today = local.weekday().num_days_from_monday() as u8;
+        let yesterday = (today + 6) % 7;
+
+        if start <= end {
+            start <= time && time < end && self.starts_on(today)
+        } else if time >= start {
+            // Evening part of a window wrapping past midnight
+            self.starts_on(today)
+        } else {
+            // Morning part: the window started the day before
+            time < end && self.starts_on(yesterday)
+        }
+    }
+
+    fn starts_on(&self, day: u8) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map_err(|_| anyhow::anyhow!("invalid time '{}', expected HH:MM", value))
+}
+
+/// SQLite-backed DND settings and suppressed notifications
+pub struct DndStore {
+    pool: SqlitePool,
+}
+
+impl DndStore {
+    pub async fn new(database_url: &str) -> Result<Self> {
+        let pool = SqlitePool::connect(database_url).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS dnd_settings (
+                user_email TEXT PRIMARY KEY,
+                settings TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS dnd_pending (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_email TEXT NOT NULL,
+                summary TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        info!("🌙 DND store initialized");
+        Ok(Self { pool })
+    }
+
+    /// Get a user's settings (defaults if none saved)
+    pub async fn get_settings(&self, user_email: &str) -> Result<DndSettings> {
+        let row = sqlx::query("SELECT settings FROM dnd_settings WHERE user_email = ?")
+            .bind(user_email)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match row {
+            Some(row) => Ok(serde_json::from_str(row.get("settings"))?),
+            None => Ok(DndSettings::default()),
+        }
+    }
+
+    /// Save a user's settings
+    pub async fn set_settings(&self, user_email: &str, settings: &DndSettings) -> Result<()> {
+        settings.validate()?;
+
+        sqlx::query("INSERT OR REPLACE INTO dnd_settings (user_email, settings) VALUES (?, ?)")
+            .bind(user_email)
+            .bind(serde_json::to_string(settings)?)
+            .execute(&self.pool)
+            .await?;
+
+        info!("🌙 Updated DND settings for {}", user_email);
+        Ok(())
+    }
+
+    /// One-click snooze for the given number of minutes
+    pub async fn snooze(&self, user_email: &str, minutes: i64) -> Result<DndSettings> {
+        if minutes <= 0 {
+            bail!("snooze duration must be positive");
+        }
+
+        let mut settings = self.get_settings(user_email).await?;
+        settings.snoozed_until = Some(Utc::now() + Duration::minutes(minutes));
+        self.set_settings(user_email, &settings).await?;
+        Ok(settings)
+    }
+
+    /// Cancel a running snooze (the recurring window is kept)
+    pub async fn cancel_snooze(&self, user_email: &str) -> Result<DndSettings> {
+        let mut settings = self.get_settings(user_email).await?;
+        settings.snoozed_until = None;
+        self.set_settings(user_email, &settings).await?;
+        Ok(settings)
+    }
+
+    /// Decide whether a notification may be pushed now
+    ///
+    /// Returns `false` if the user is in DND; the notification is then kept
+    /// for the end-of-window digest when batching is enabled.
+    pub async fn should_notify(&self, summary: &EmailSummary) -> Result<bool> {
+        let settings = self.get_settings(&summary.user_email).await?;
+        if !settings.is_active(Utc::now()) {
+            return Ok(true);
+        }
+
+        if settings.batch_summary {
+            sqlx::query("INSERT INTO dnd_pending (user_email, summary) VALUES (?, ?)")
+                .bind(&summary.user_email)
+                .bind(serde_json::to_string(summary)?)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        debug!("🌙 Suppressed notification for {} (DND)", summary.user_email);
+        Ok(false)
+    }
+
+    /// Take the suppressed notifications of users whose DND has ended
+    pub async fn take_due_digests(&self) -> Result<Vec<(String, Vec<EmailSummary>)>> {
+        let users: Vec<String> = sqlx::query("SELECT DISTINCT user_email FROM dnd_pending")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| row.get("user_email"))
+            .collect();
+
+        let now = Utc::now();
+        let mut digests = Vec::new();
+
+        for user_email in users {
+            if self.get_settings(&user_email).await?.is_active(now) {
+                continue;
+            }
+
+            let mut tx = self.pool.begin().await?;
+            let rows = sqlx::query("SELECT summary FROM dnd_pending WHERE user_email = ? ORDER BY id")
+                .bind(&user_email)
+                .fetch_all(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM dnd_pending WHERE user_email = ?")
+                .bind(&user_email)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            let summaries = rows
+                .iter()
+                .map(|row| serde_json::from_str(row.get("summary")))
+                .collect::<Result<Vec<EmailSummary>, _>>()?;
+
+            if !summaries.is_empty() {
+                digests.push((user_email, summaries));
+            }
+        }
+
+        Ok(digests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // 2024-01-01 is a Monday
+        Utc.with_ymd_and_hms(2024, 1, day, hour, minute, 0).unwrap()
+    }
+
+    fn window(start: &str, end: &str) -> DndSettings {
+        DndSettings {
+            enabled: true,
+            start: start.to_string(),
+            end: end.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_same_day_window() {
+        let settings = window("12:00", "14:00");
+        assert!(settings.is_active(at(1, 12, 0)));
+        assert!(settings.is_active(at(1, 13, 59)));
+        assert!(!settings.is_active(at(1, 14, 0)));
+        assert!(!settings.is_active(at(1, 11, 59)));
+    }
+
+    #[test]
+    fn test_overnight_window_with_days() {
+        let settings = DndSettings {
+            days: vec![4], // Friday night only
+            ..window("22:00", "07:00")
+        };
+
+        assert!(settings.is_active(at(5, 23, 0))); // Friday
+        assert!(settings.is_active(at(6, 6, 30))); // Saturday morning
+        assert!(!settings.is_active(at(6, 23, 0))); // Saturday night
+        assert!(!settings.is_active(at(5, 6, 30))); // Friday morning
+    }
+
+    #[test]
+    fn test_utc_offset() {
+        let settings = DndSettings {
+            utc_offset_minutes: 120,
+            ..window("22:00", "07:00")
+        };
+        assert!(settings.is_active(at(1, 20, 30))); // 22:30 local
+        assert!(!settings.is_active(at(1, 19, 30))); // 21:30 local
+    }
+
+    #[test]
+    fn test_snooze_overrides_disabled_window() {
+        let settings = DndSettings {
+            snoozed_until: Some(at(1, 10, 0)),
+            ..Default::default()
+        };
+        assert!(settings.is_active(at(1, 9, 0)));
+        assert!(!settings.is_active(at(1, 10, 0)));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(window("22:00", "07:00").validate().is_ok());
+        assert!(window("25:00", "07:00").validate().is_err());
+        assert!(DndSettings {
+            days: vec![7],
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_suppressed_notifications_are_batched() {
+        let store = DndStore::new("sqlite:file:dnd_test?mode=memory&cache=shared").await.unwrap();
+        let summary = EmailSummary {
+            id: 0,
+            user_email: "alice@example.com".to_string(),
+            email_id: "1".to_string(),
+            from_addr: "bob@example.com".to_string(),
+            subject: "Hello".to_string(),
+            summary: "Bob says hello".to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            is_read: false,
+        };
+
+        assert!(store.should_notify(&summary).await.unwrap());
+
+        store.snooze("alice@example.com", 30).await.unwrap();
+        assert!(!store.should_notify(&summary).await.unwrap());
+        assert!(store.take_due_digests().await.unwrap().is_empty());
+
+        store.cancel_snooze("alice@example.com").await.unwrap();
+        let digests = store.take_due_digests().await.unwrap();
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].1[0].subject, "Hello");
+        assert!(store.take_due_digests().await.unwrap().is_empty());
+    }
+}
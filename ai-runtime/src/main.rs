@@ -2,24 +2,27 @@
 //!
 //! Core component that orchestrates LLM + MCP servers
 
+mod dnd;
 mod llm;
 mod mcp;
 mod summary;
 mod websocket;
 
 use axum::{
-    extract::State,
+    extract::{Path, State},
     http::StatusCode,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
+use dnd::{DndSettings, DndStore};
 use llm::{LlmEngine, Message, MessageRole};
 use llm::mock::MockLlm;
 use llm::ollama::OllamaLlm;
 use mcp::{McpRegistry, McpServer};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::Duration;
 use summary::{EmailSummary, SummaryStore};
 use tokio::sync::{broadcast, Mutex};
 use tracing::{info, warn, Level};
@@ -31,6 +34,8 @@ pub struct AppState {
     pub mcp_registry: Arc<Mutex<McpRegistry>>,
     pub summary_store: Arc<SummaryStore>,
     pub email_notifier: broadcast::Sender<EmailNotification>,
+    pub dnd_store: Arc<DndStore>,
+    pub digest_notifier: broadcast::Sender<DigestNotification>,
 }
 
 /// Email notification sent to WebSocket clients
@@ -40,6 +45,22 @@ pub struct EmailNotification {
     pub summary: EmailSummary,
 }
 
+/// Notifications held back during Do Not Disturb, sent when it ends
+#[derive(Debug, Clone)]
+pub struct DigestNotification {
+    pub user_email: String,
+    pub summaries: Vec<EmailSummary>,
+}
+
+/// Interval between checks for ended DND windows
+const DND_DIGEST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Snooze request
+#[derive(Debug, Deserialize)]
+struct SnoozeRequest {
+    minutes: i64,
+}
+
 /// Chat request
 #[derive(Debug, Deserialize)]
 struct ChatRequest {
@@ -71,6 +92,18 @@ struct GenerateSummaryResponse {
     summary: String,
 }
 
+/// Flag change published by mail-rs, for any of its frontends
+#[derive(Debug, Deserialize)]
+struct FlagEventRequest {
+    user: String,
+    /// Email id, as in generate-summary requests
+    message: String,
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default)]
+    expunged: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize logging
@@ -125,9 +158,11 @@ async fn main() -> anyhow::Result<()> {
     let db_path = std::env::var("SUMMARY_DB_PATH")
         .unwrap_or_else(|_| "sqlite://summaries.db".to_string());
     let summary_store = SummaryStore::new(&db_path).await?;
+    let dnd_store = Arc::new(DndStore::new(&db_path).await?);
 
     // Create broadcast channel for email notifications
     let (email_notifier, _) = broadcast::channel::<EmailNotification>(100);
+    let (digest_notifier, _) = broadcast::channel::<DigestNotification>(100);
 
     // Create app state
     let state = Arc::new(AppState {
@@ -135,14 +170,28 @@ async fn main() -> anyhow::Result<()> {
         mcp_registry: Arc::new(Mutex::new(mcp_registry)),
         summary_store: Arc::new(summary_store),
         email_notifier,
+        dnd_store,
+        digest_notifier,
     });
 
+    // Send digests of notifications held back during DND
+    tokio::spawn(send_dnd_digests(state.clone()));
+
     // Build router
     let app = Router::new()
         .route("/", get(health_check))
         .route("/health", get(health_check))
         .route("/chat", post(chat_handler))
         .route("/api/generate-summary", post(generate_summary_handler))
+        .route("/api/flag-events", post(flag_event_handler))
+        .route(
+            "/api/settings/dnd/:email",
+            get(get_dnd_handler).put(update_dnd_handler),
+        )
+        .route(
+            "/api/settings/dnd/:email/snooze",
+            post(snooze_handler).delete(cancel_snooze_handler),
+        )
         .route("/ws", get(websocket::ws_handler))
         .with_state(state);
 
@@ -236,6 +285,25 @@ async fn chat_handler(
     }))
 }
 
+/// Flag event endpoint - called by mail-rs when an email is read, marked
+/// unread or removed through IMAP, the web API or the MCP tools
+async fn flag_event_handler(
+    State(state): State<Arc<AppState>>,
+    Json(event): Json<FlagEventRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let store = &state.summary_store;
+    let result = if event.expunged {
+        store.delete_summary(&event.user, &event.message).await
+    } else if event.flags.iter().any(|flag| flag == "\\Seen") {
+        store.mark_as_read(&event.user, &event.message).await
+    } else {
+        store.mark_as_unread(&event.user, &event.message).await
+    };
+    result.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 /// Generate summary endpoint - called by mail-rs when an email is received
 async fn generate_summary_handler(
     State(state): State<Arc<AppState>>,
@@ -296,12 +364,113 @@ async fn generate_summary_handler(
         },
     };
 
-    // Send notification (ignore if no receivers)
-    let _ = state.email_notifier.send(notification);
-    info!("📢 Broadcasted email notification to connected clients");
+    // Respect Do Not Disturb, the summary itself stays available
+    let notify = state
+        .dnd_store
+        .should_notify(&notification.summary)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("⚠️  Failed to check DND for {}: {}", payload.user_email, e);
+            true
+        });
+
+    if notify {
+        // Send notification (ignore if no receivers)
+        let _ = state.email_notifier.send(notification);
+        info!("📢 Broadcasted email notification to connected clients");
+    } else {
+        info!("🌙 Notification for {} held back (Do Not Disturb)", payload.user_email);
+    }
 
     Ok(Json(GenerateSummaryResponse {
         success: true,
         summary,
     }))
 }
+
+/// Periodically send the digest of suppressed notifications once DND ends
+async fn send_dnd_digests(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(DND_DIGEST_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match state.dnd_store.take_due_digests().await {
+            Ok(digests) => {
+                for (user_email, summaries) in digests {
+                    info!("🌙 Sending DND digest of {} emails to {}", summaries.len(), user_email);
+                    let _ = state.digest_notifier.send(DigestNotification {
+                        user_email,
+                        summaries,
+                    });
+                }
+            }
+            Err(e) => warn!("⚠️  Failed to load DND digests: {}", e),
+        }
+    }
+}
+
+/// Get a user's Do Not Disturb settings
+async fn get_dnd_handler(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+) -> Result<Json<DndSettings>, (StatusCode, String)> {
+    state
+        .dnd_store
+        .get_settings(&email)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Replace a user's Do Not Disturb settings
+async fn update_dnd_handler(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+    Json(settings): Json<DndSettings>,
+) -> Result<Json<DndSettings>, (StatusCode, String)> {
+    settings
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    state
+        .dnd_store
+        .set_settings(&email, &settings)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(settings))
+}
+
+/// One-click snooze: suppress notifications for the next `minutes`
+async fn snooze_handler(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+    Json(payload): Json<SnoozeRequest>,
+) -> Result<Json<DndSettings>, (StatusCode, String)> {
+    if payload.minutes <= 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "minutes must be positive".to_string(),
+        ));
+    }
+
+    state
+        .dnd_store
+        .snooze(&email, payload.minutes)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// End a running snooze early
+async fn cancel_snooze_handler(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+) -> Result<Json<DndSettings>, (StatusCode, String)> {
+    state
+        .dnd_store
+        .cancel_snooze(&email)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
@@ -127,6 +127,41 @@ impl SummaryStore {
         Ok(())
     }
 
+    /// Mark a summary as unread again
+    pub async fn mark_as_unread(&self, user_email: &str, email_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE email_summaries
+            SET is_read = 0
+            WHERE user_email = ? AND email_id = ?
+            "#,
+        )
+        .bind(user_email)
+        .bind(email_id)
+        .execute(&self.pool)
+        .await?;
+
+        debug!("✓ Marked email {} as unread for {}", email_id, user_email);
+        Ok(())
+    }
+
+    /// Delete the summary of a removed email
+    pub async fn delete_summary(&self, user_email: &str, email_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM email_summaries
+            WHERE user_email = ? AND email_id = ?
+            "#,
+        )
+        .bind(user_email)
+        .bind(email_id)
+        .execute(&self.pool)
+        .await?;
+
+        debug!("✓ Deleted summary of email {} for {}", email_id, user_email);
+        Ok(())
+    }
+
     /// Mark all summaries as read for a user
     pub async fn mark_all_as_read(&self, user_email: &str) -> Result<()> {
         sqlx::query(
@@ -96,6 +96,7 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
 
     // Subscribe to email notifications
     let mut notification_rx = state.email_notifier.subscribe();
+    let mut digest_rx = state.digest_notifier.subscribe();
 
     // Handle incoming messages and notifications
     loop {
@@ -240,6 +241,31 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                     }
                 }
             },
+            // Handle digests of notifications held back during DND
+            Ok(digest) = digest_rx.recv() => {
+                if authenticated_email.as_ref() == Some(&digest.user_email) {
+                    info!("🌙 Sending DND digest to {}", digest.user_email);
+
+                    let summaries: Vec<EmailSummaryInfo> = digest
+                        .summaries
+                        .into_iter()
+                        .map(|s| EmailSummaryInfo {
+                            from: s.from_addr,
+                            subject: s.subject,
+                            summary: s.summary,
+                        })
+                        .collect();
+
+                    let digest_msg = ServerMessage::EmailSummaries {
+                        count: summaries.len(),
+                        summaries,
+                    };
+
+                    if let Ok(json) = serde_json::to_string(&digest_msg) {
+                        let _ = sender.send(WsMessage::Text(json)).await;
+                    }
+                }
+            },
             // Handle disconnection
             else => {
                 info!("🔌 WebSocket connection ended");
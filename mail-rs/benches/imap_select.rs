@@ -0,0 +1,103 @@
+//! SELECT latency against mailbox size
+//!
+//! Compares the first SELECT, which lists the folder and writes its index,
+//! with later ones answered from the index summary, and with the first
+//! FETCH, which reads the messages from the index.
+//!
+//! ```bash
+//! cargo bench -p mail-rs --bench imap_select
+//! MAIL_RS_BENCH_SIZES=1000,100000 cargo bench -p mail-rs --bench imap_select
+//! ```
+
+use mail_rs::imap::Mailbox;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+const EMAIL: &str = "bench@example.com";
+
+/// Maildir with `count` messages, a tenth of them unread in new/
+fn create_mailbox(root: &Path, count: usize) {
+    let maildir = root.join(EMAIL);
+    for dir in ["new", "cur", "tmp"] {
+        fs::create_dir_all(maildir.join(dir)).unwrap();
+    }
+    for n in 0..count {
+        let message = format!(
+            "From: sender{}@example.com\r\nSubject: Message {}\r\n\r\nBody of message {}\r\n",
+            n % 97,
+            n,
+            n
+        );
+        let path = match n % 10 {
+            0 => maildir.join(format!("new/{}.M{}.bench", 1_700_000_000 + n, n)),
+            _ => maildir.join(format!("cur/{}.M{}.bench:2,S", 1_700_000_000 + n, n)),
+        };
+        fs::write(path, message).unwrap();
+    }
+
+    // As if the folder had been idle for a while
+    let past = SystemTime::now() - Duration::from_secs(60);
+    for dir in ["new", "cur"] {
+        fs::File::open(maildir.join(dir))
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+    }
+}
+
+/// Median time of `runs` runs of `f`
+fn measure(runs: usize, mut f: impl FnMut()) -> Duration {
+    let mut times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[runs / 2]
+}
+
+/// What SELECT reports
+fn select(root: &Path) -> Mailbox {
+    let mailbox = Mailbox::open(EMAIL, "INBOX", root).unwrap();
+    std::hint::black_box((
+        mailbox.message_count(),
+        mailbox.recent_count(),
+        mailbox.first_unseen(),
+        mailbox.uid_next(),
+    ));
+    mailbox
+}
+
+fn main() {
+    let sizes: Vec<usize> = std::env::var("MAIL_RS_BENCH_SIZES")
+        .unwrap_or_else(|_| "1000,10000,100000".to_string())
+        .split(',')
+        .filter_map(|size| size.trim().parse().ok())
+        .collect();
+
+    println!(
+        "{:>10} {:>14} {:>14} {:>14}",
+        "messages", "first SELECT", "SELECT", "first FETCH"
+    );
+    for size in sizes {
+        let dir = tempfile::TempDir::new().unwrap();
+        create_mailbox(dir.path(), size);
+        let listing = measure(1, || {
+            select(dir.path());
+        });
+        let summary = measure(9, || {
+            select(dir.path());
+        });
+        let fetch = measure(3, || {
+            let mailbox = select(dir.path());
+            std::hint::black_box(mailbox.get_message(size).map(|m| m.uid));
+        });
+        println!(
+            "{:>10} {:>14.2?} {:>14.2?} {:>14.2?}",
+            size, listing, summary, fetch
+        );
+    }
+}
@@ -5,6 +5,8 @@ use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::process::Command;
 
+use crate::config::RegionConfig;
+
 /// Backup status
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BackupStatus {
@@ -42,11 +44,15 @@ pub struct BackupMetadata {
     pub status: BackupStatus,
     /// Optional error message
     pub error: Option<String>,
+    /// Residency region the backup was taken in
+    #[serde(default)]
+    pub region: Option<String>,
 }
 
 impl BackupMetadata {
     pub fn new(filename: String, size_bytes: u64) -> Self {
         BackupMetadata {
+            region: region_from_filename(&filename),
             filename,
             created_at: Utc::now(),
             size_bytes,
@@ -57,6 +63,7 @@ impl BackupMetadata {
 
     pub fn failed(filename: String, error: String) -> Self {
         BackupMetadata {
+            region: region_from_filename(&filename),
             filename,
             created_at: Utc::now(),
             size_bytes: 0,
@@ -77,6 +84,31 @@ pub struct BackupConfig {
     pub max_backups: usize,
     /// Enable compression
     pub compress: bool,
+    /// Residency region, recorded in backup filenames
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+impl BackupConfig {
+    /// Back up a residency region's maildir into its backup directory
+    pub fn for_region(region: &RegionConfig) -> Self {
+        BackupConfig {
+            backup_dir: PathBuf::from(&region.backup_dir),
+            maildir_path: PathBuf::from(&region.maildir_path),
+            region: Some(region.name.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Region encoded in a backup filename
+///
+/// Regional backups are named `mail-backup-<region>-<timestamp>`. The
+/// timestamp contains no `-`, so the region is everything before the last one.
+fn region_from_filename(filename: &str) -> Option<String> {
+    let stem = filename.strip_prefix("mail-backup-")?;
+    let stem = stem.split('.').next()?;
+    stem.rsplit_once('-').map(|(region, _)| region.to_string())
 }
 
 impl Default for BackupConfig {
@@ -86,6 +118,7 @@ impl Default for BackupConfig {
             maildir_path: PathBuf::from("/var/mail"),
             max_backups: 7, // Keep 7 days of backups
             compress: true,
+            region: None,
         }
     }
 }
@@ -119,10 +152,14 @@ impl BackupManager {
     /// Generate backup filename
     fn generate_backup_filename(&self) -> String {
         let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
+        let name = match &self.config.region {
+            Some(region) => format!("mail-backup-{}-{}", region, timestamp),
+            None => format!("mail-backup-{}", timestamp),
+        };
         if self.config.compress {
-            format!("mail-backup-{}.tar.gz", timestamp)
+            format!("{}.tar.gz", name)
         } else {
-            format!("mail-backup-{}.tar", timestamp)
+            format!("{}.tar", name)
         }
     }
 
@@ -194,6 +231,7 @@ impl BackupManager {
                             .unwrap_or_else(Utc::now);
 
                         backups.push(BackupMetadata {
+                            region: region_from_filename(&filename_str),
                             filename: filename_str,
                             created_at,
                             size_bytes,
@@ -240,6 +278,40 @@ impl BackupManager {
         Ok(())
     }
 
+    /// Restore a backup into `target` instead of the configured maildir
+    ///
+    /// The archive's top-level directory is replaced by `target`, which is
+    /// how backups are moved between residency regions.
+    pub async fn restore_backup_to(&self, filename: &str, target: &Path) -> Result<()> {
+        let backup_path = self.config.backup_dir.join(filename);
+
+        if !backup_path.exists() {
+            return Err(anyhow!("Backup file not found: {}", filename));
+        }
+
+        fs::create_dir_all(target).await?;
+
+        let mut cmd = Command::new("tar");
+        cmd.arg("-C")
+            .arg(target)
+            .arg("--strip-components=1")
+            .arg("-xf")
+            .arg(&backup_path);
+
+        if filename.ends_with(".tar.gz") {
+            cmd.arg("-z");
+        }
+
+        let output = cmd.output().await?;
+
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("Restore failed: {}", error));
+        }
+
+        Ok(())
+    }
+
     /// Delete a backup
     pub async fn delete_backup(&self, filename: &str) -> Result<()> {
         let backup_path = self.config.backup_dir.join(filename);
@@ -315,6 +387,44 @@ mod tests {
         assert_eq!(metadata.size_bytes, 0);
     }
 
+    #[test]
+    fn test_region_from_filename() {
+        assert_eq!(region_from_filename("mail-backup-20240101_120000.tar.gz"), None);
+        assert_eq!(
+            region_from_filename("mail-backup-eu-west-20240101_120000.tar"),
+            Some("eu-west".to_string())
+        );
+        assert_eq!(region_from_filename("other-file.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn test_regional_backup_restore_to() {
+        let temp_dir = TempDir::new().unwrap();
+        let maildir = temp_dir.path().join("eu-mail");
+        fs::create_dir_all(maildir.join("alice@example.de/new")).await.unwrap();
+        fs::write(maildir.join("alice@example.de/new/1"), b"Subject: hi\r\n\r\nhi")
+            .await
+            .unwrap();
+
+        let manager = BackupManager::new(BackupConfig::for_region(&RegionConfig {
+            name: "eu".to_string(),
+            maildir_path: maildir.to_string_lossy().to_string(),
+            backup_dir: temp_dir.path().join("eu-backups").to_string_lossy().to_string(),
+            export_path: temp_dir.path().join("eu-exports").to_string_lossy().to_string(),
+        }));
+        let backup = manager.create_backup().await.unwrap();
+        assert_eq!(backup.status, BackupStatus::Success);
+        assert_eq!(backup.region.as_deref(), Some("eu"));
+        assert_eq!(manager.list_backups().await.unwrap()[0].region.as_deref(), Some("eu"));
+
+        let target = temp_dir.path().join("us-mail");
+        manager
+            .restore_backup_to(&backup.filename, &target)
+            .await
+            .unwrap();
+        assert!(target.join("alice@example.de/new/1").exists());
+    }
+
     #[test]
     fn test_backup_config_default() {
         let config = BackupConfig::default();
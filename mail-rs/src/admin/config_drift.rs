@@ -0,0 +1,277 @@
+//! Effective-config fingerprints and drift detection between nodes
+//!
+//! A [`ConfigSnapshot`] flattens the loaded configuration into dotted
+//! setting paths (`smtp.require_auth`, `imap.enable_tls`, ...) together with
+//! the compiled-in cargo features, and hashes the result so two nodes can be
+//! compared by fingerprint alone. [`diff`] lists the settings that differ and
+//! marks those that change the security posture of a node, such as TLS
+//! policy or authentication requirements.
+//!
+//! Secrets never leave the node: their values are replaced by a short
+//! digest, enough to tell whether two nodes share the same secret.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config::Config;
+
+/// Setting names whose values are replaced by a digest
+const SECRET_MARKERS: &[&str] = &["secret", "password", "token", "webhook"];
+
+/// Setting names that affect transport security or authentication
+const SECURITY_MARKERS: &[&str] = &[
+    "tls",
+    "ssl",
+    "cert",
+    "auth",
+    "password",
+    "secret",
+    "cram",
+    "mfa",
+    "oauth",
+    "jwt",
+    "relay",
+    "dkim",
+    "spf",
+    "dmarc",
+    "srs",
+    "impersonation",
+    "rate_limit",
+    "max_connections",
+];
+
+/// Normalized effective configuration of one node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigSnapshot {
+    /// Hostname the node announces, not part of the fingerprint
+    pub node: String,
+    pub version: String,
+    /// Cargo features compiled into the binary
+    pub features: Vec<String>,
+    /// SHA-256 over features and settings
+    pub fingerprint: String,
+    /// SHA-256 per top-level section, to spot which part drifted
+    pub sections: BTreeMap<String, String>,
+    /// Every setting by dotted path, secrets redacted
+    pub settings: BTreeMap<String, Value>,
+}
+
+impl ConfigSnapshot {
+    /// Snapshot of `config` as loaded by this binary
+    pub fn capture(config: &Config) -> Result<Self> {
+        let mut settings = BTreeMap::new();
+        flatten("", &serde_json::to_value(config)?, &mut settings);
+        // The hostname names the node rather than configuring it, and would
+        // make every two nodes drift
+        settings.remove("server.hostname");
+        Ok(Self::from_settings(
+            config.server.hostname.clone(),
+            env!("CARGO_PKG_VERSION").to_string(),
+            compiled_features(),
+            settings,
+        ))
+    }
+
+    fn from_settings(
+        node: String,
+        version: String,
+        features: Vec<String>,
+        settings: BTreeMap<String, Value>,
+    ) -> Self {
+        let mut sections: BTreeMap<String, Sha256> = BTreeMap::new();
+        let mut overall = Sha256::new();
+        overall.update(format!("features={}\n", features.join(",")));
+        for (path, value) in &settings {
+            let line = format!("{}={}\n", path, value);
+            overall.update(&line);
+            let section = path.split('.').next().unwrap_or(path).to_string();
+            sections.entry(section).or_default().update(&line);
+        }
+
+        Self {
+            node,
+            version,
+            features,
+            fingerprint: hex(&overall.finalize()),
+            sections: sections
+                .into_iter()
+                .map(|(section, hasher)| (section, hex(&hasher.finalize())))
+                .collect(),
+            settings,
+        }
+    }
+}
+
+/// Cargo features this binary was built with
+pub fn compiled_features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(feature = "chaos") {
+        features.push("chaos".to_string());
+    }
+    if cfg!(feature = "testing") {
+        features.push("testing".to_string());
+    }
+    features
+}
+
+fn flatten(prefix: &str, value: &Value, settings: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&path, value, settings);
+            }
+        }
+        _ => {
+            settings.insert(prefix.to_string(), redact(prefix, value));
+        }
+    }
+}
+
+fn redact(path: &str, value: &Value) -> Value {
+    let name = path.rsplit('.').next().unwrap_or(path).to_lowercase();
+    let secret = SECRET_MARKERS.iter().any(|marker| name.contains(marker));
+    match value {
+        Value::String(s) if secret && !s.is_empty() => {
+            let digest = hex(&Sha256::digest(s.as_bytes()));
+            Value::String(format!("redacted:{}", &digest[..12]))
+        }
+        _ => value.clone(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether a change of the setting at `path` affects security
+pub fn is_security_relevant(path: &str) -> bool {
+    let path = path.to_lowercase();
+    SECURITY_MARKERS.iter().any(|marker| path.contains(marker))
+}
+
+/// One setting that differs between two nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDifference {
+    pub path: String,
+    /// `None` when the setting is missing on that node
+    pub left: Option<Value>,
+    pub right: Option<Value>,
+    /// TLS policy, authentication or another security-relevant setting
+    pub security: bool,
+}
+
+/// Comparison of two snapshots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    pub left_node: String,
+    pub right_node: String,
+    pub identical: bool,
+    /// Top-level sections whose fingerprints differ
+    pub drifted_sections: Vec<String>,
+    /// Security-relevant differences first, then by path
+    pub differences: Vec<ConfigDifference>,
+}
+
+impl ConfigDiff {
+    /// Number of security-relevant differences
+    pub fn security_differences(&self) -> usize {
+        self.differences.iter().filter(|d| d.security).count()
+    }
+}
+
+/// Settings and features that differ between `left` and `right`
+pub fn diff(left: &ConfigSnapshot, right: &ConfigSnapshot) -> ConfigDiff {
+    let mut differences = Vec::new();
+
+    if left.features != right.features {
+        differences.push(ConfigDifference {
+            path: "features".to_string(),
+            left: Some(Value::from(left.features.clone())),
+            right: Some(Value::from(right.features.clone())),
+            // Test-only features such as fault injection must not reach
+            // production nodes
+            security: true,
+        });
+    }
+
+    let paths: BTreeSet<&String> = left.settings.keys().chain(right.settings.keys()).collect();
+    for path in paths {
+        let left_value = left.settings.get(path);
+        let right_value = right.settings.get(path);
+        if left_value != right_value {
+            differences.push(ConfigDifference {
+                path: path.clone(),
+                left: left_value.cloned(),
+                right: right_value.cloned(),
+                security: is_security_relevant(path),
+            });
+        }
+    }
+    differences.sort_by(|a, b| b.security.cmp(&a.security).then(a.path.cmp(&b.path)));
+
+    let sections: BTreeSet<&String> = left.sections.keys().chain(right.sections.keys()).collect();
+    let drifted_sections = sections
+        .into_iter()
+        .filter(|section| left.sections.get(*section) != right.sections.get(*section))
+        .cloned()
+        .collect();
+
+    ConfigDiff {
+        left_node: left.node.clone(),
+        right_node: right.node.clone(),
+        identical: left.fingerprint == right.fingerprint,
+        drifted_sections,
+        differences,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_redacts_secrets() {
+        let mut config = Config::default();
+        config.srs.secret = "hunter2".to_string();
+
+        let snapshot = ConfigSnapshot::capture(&config).unwrap();
+        assert_eq!(snapshot.node, config.server.hostname);
+        let secret = snapshot.settings["srs.secret"].as_str().unwrap();
+        assert!(secret.starts_with("redacted:"));
+        assert!(!secret.contains("hunter2"));
+        assert!(snapshot.sections.contains_key("smtp"));
+
+        // Same config, same fingerprint
+        let again = ConfigSnapshot::capture(&config).unwrap();
+        assert_eq!(snapshot.fingerprint, again.fingerprint);
+        assert!(diff(&snapshot, &again).identical);
+    }
+
+    #[test]
+    fn test_diff_flags_security_settings() {
+        let left = Config::default();
+        let mut right = left.clone();
+        right.server.hostname = "mx2.example.com".to_string();
+        right.smtp.require_auth = !left.smtp.require_auth;
+        right.logging.level = "debug".to_string();
+
+        let left = ConfigSnapshot::capture(&left).unwrap();
+        let right = ConfigSnapshot::capture(&right).unwrap();
+        let diff = diff(&left, &right);
+
+        assert!(!diff.identical);
+        assert_eq!(diff.right_node, "mx2.example.com");
+        assert_eq!(diff.drifted_sections, vec!["logging", "smtp"]);
+        let paths: Vec<&str> = diff.differences.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["smtp.require_auth", "logging.level"]);
+        assert_eq!(diff.security_differences(), 1);
+    }
+}
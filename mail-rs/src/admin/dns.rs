@@ -1,5 +1,8 @@
+use super::mta_sts::MtaStsPolicy;
+use crate::config::Config;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::net::IpAddr;
 
 /// DNS record types for email server
@@ -17,6 +20,8 @@ pub enum DnsRecordType {
     CNAME,
     /// PTR record (reverse DNS)
     PTR,
+    /// TLSA record (DANE)
+    TLSA,
 }
 
 impl std::fmt::Display for DnsRecordType {
@@ -28,6 +33,7 @@ impl std::fmt::Display for DnsRecordType {
             DnsRecordType::TXT => write!(f, "TXT"),
             DnsRecordType::CNAME => write!(f, "CNAME"),
             DnsRecordType::PTR => write!(f, "PTR"),
+            DnsRecordType::TLSA => write!(f, "TLSA"),
         }
     }
 }
@@ -103,6 +109,14 @@ impl DnsRecord {
     }
 }
 
+/// SubjectPublicKeyInfo of a PEM (PKCS#8) private key, for
+/// [`DnsConfigGenerator::with_tlsa_public_key`]
+pub fn public_key_of_pem(key_pem: &str) -> Option<Vec<u8>> {
+    rcgen::KeyPair::from_pem(key_pem)
+        .ok()
+        .map(|key| key.public_key_der())
+}
+
 /// DNS configuration generator
 pub struct DnsConfigGenerator {
     domain: String,
@@ -110,6 +124,10 @@ pub struct DnsConfigGenerator {
     server_ip: IpAddr,
     dkim_selector: String,
     dkim_public_key: Option<String>,
+    tls_rpt_address: Option<String>,
+    mta_sts_id: Option<String>,
+    bimi: Option<(String, Option<String>)>,
+    tlsa_digest: Option<String>,
 }
 
 impl DnsConfigGenerator {
@@ -126,15 +144,70 @@ impl DnsConfigGenerator {
             server_ip,
             dkim_selector,
             dkim_public_key: None,
+            tls_rpt_address: None,
+            mta_sts_id: None,
+            bimi: None,
+            tlsa_digest: None,
         }
     }
 
+    /// Generator for `server.domain` with the TLS-RPT, MTA-STS and BIMI
+    /// records of the configuration
+    pub fn from_config(config: &Config, server_ip: IpAddr) -> Self {
+        let mut generator = Self::new(
+            config.server.domain.clone(),
+            config.server.hostname.clone(),
+            server_ip,
+            "default".to_string(),
+        );
+        if let Some(ref address) = config.mta_sts.tls_rpt_address {
+            generator = generator.with_tls_rpt_address(address.clone());
+        }
+        if config.mta_sts.enabled {
+            generator = generator.with_mta_sts(&MtaStsPolicy::from_config(config));
+        }
+        if let Some(ref logo_url) = config.bimi.logo_url {
+            generator = generator.with_bimi(logo_url.clone(), config.bimi.authority_url.clone());
+        }
+        generator
+    }
+
     /// Set DKIM public key
     pub fn with_dkim_public_key(mut self, public_key: String) -> Self {
         self.dkim_public_key = Some(public_key);
         self
     }
 
+    /// Set the address receiving TLS reports (defaults to postmaster)
+    pub fn with_tls_rpt_address(mut self, address: String) -> Self {
+        self.tls_rpt_address = Some(address);
+        self
+    }
+
+    /// Publish an MTA-STS policy
+    pub fn with_mta_sts(mut self, policy: &MtaStsPolicy) -> Self {
+        self.mta_sts_id = Some(policy.id());
+        self
+    }
+
+    /// Publish a BIMI logo and optionally its Verified Mark Certificate
+    pub fn with_bimi(mut self, logo_url: String, authority_url: Option<String>) -> Self {
+        self.bimi = Some((logo_url, authority_url));
+        self
+    }
+
+    /// Publish a DANE record pinning the SMTP server's public key, given as
+    /// DER-encoded SubjectPublicKeyInfo
+    pub fn with_tlsa_public_key(mut self, spki_der: &[u8]) -> Self {
+        self.tlsa_digest = Some(
+            Sha256::digest(spki_der)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        );
+        self
+    }
+
     /// Generate all required DNS records
     pub fn generate_records(&self) -> Result<Vec<DnsRecord>> {
         let mut records = Vec::new();
@@ -192,6 +265,63 @@ impl DnsConfigGenerator {
             "DMARC policy - Quarantine unauthenticated emails".to_string(),
         ));
 
+        // TLS-RPT record
+        let tls_rpt_address = self
+            .tls_rpt_address
+            .clone()
+            .unwrap_or_else(|| format!("postmaster@{}", self.domain));
+        records.push(DnsRecord::new(
+            DnsRecordType::TXT,
+            format!("_smtp._tls.{}", self.domain),
+            format!("\"v=TLSRPTv1; rua=mailto:{}\"", tls_rpt_address),
+            3600,
+            "TLS-RPT - Where senders report TLS failures".to_string(),
+        ));
+
+        // MTA-STS policy announcement and the host serving it
+        if let Some(ref id) = self.mta_sts_id {
+            records.push(DnsRecord::new(
+                DnsRecordType::TXT,
+                format!("_mta-sts.{}", self.domain),
+                format!("\"v=STSv1; id={}\"", id),
+                3600,
+                "MTA-STS - Policy id, changes with the policy".to_string(),
+            ));
+            records.push(DnsRecord::new(
+                DnsRecordType::CNAME,
+                format!("mta-sts.{}", self.domain),
+                format!("{}.", self.mail_server_hostname),
+                3600,
+                "MTA-STS - Serves /.well-known/mta-sts.txt over HTTPS".to_string(),
+            ));
+        }
+
+        // DANE record of the SMTP server (DANE-EE, SPKI, SHA-256)
+        if let Some(ref digest) = self.tlsa_digest {
+            records.push(DnsRecord::new(
+                DnsRecordType::TLSA,
+                format!("_25._tcp.{}", self.mail_server_hostname),
+                format!("3 1 1 {}", digest),
+                3600,
+                "DANE - Pins the SMTP server key (needs DNSSEC)".to_string(),
+            ));
+        }
+
+        // BIMI logo
+        if let Some((ref logo_url, ref authority_url)) = self.bimi {
+            let mut bimi_value = format!("v=BIMI1; l={}", logo_url);
+            if let Some(authority_url) = authority_url {
+                bimi_value.push_str(&format!("; a={}", authority_url));
+            }
+            records.push(DnsRecord::new(
+                DnsRecordType::TXT,
+                format!("default._bimi.{}", self.domain),
+                format!("\"{}\"", bimi_value),
+                3600,
+                "BIMI - Brand logo shown by supporting mail clients".to_string(),
+            ));
+        }
+
         // Autodiscover for mail clients (optional)
         records.push(DnsRecord::new(
             DnsRecordType::CNAME,
@@ -243,6 +373,9 @@ impl DnsConfigGenerator {
         instructions.push_str("- Verify your SPF record with: dig TXT yourdomain.com\n");
         instructions.push_str("- Verify your DKIM record with: dig TXT selector._domainkey.yourdomain.com\n");
         instructions.push_str("- Verify your DMARC record with: dig TXT _dmarc.yourdomain.com\n");
+        if self.mta_sts_id.is_some() {
+            instructions.push_str("- MTA-STS needs an HTTPS certificate for mta-sts.yourdomain.com\n");
+        }
         instructions.push_str("- Test your configuration at: https://mxtoolbox.com/\n");
 
         Ok(instructions)
@@ -263,24 +396,6 @@ impl DnsConfigGenerator {
 
         Ok(zone)
     }
-
-    /// Verify DNS records are correctly configured
-    pub async fn verify_records(&self) -> Result<Vec<(DnsRecord, bool, String)>> {
-        let records = self.generate_records()?;
-        let mut results = Vec::new();
-
-        // For now, return placeholder results
-        // In production, this would use DNS queries to verify
-        for record in records {
-            results.push((
-                record.clone(),
-                false,
-                "Verification not yet implemented".to_string(),
-            ));
-        }
-
-        Ok(results)
-    }
 }
 
 #[cfg(test)]
@@ -374,8 +489,8 @@ mod tests {
 
         let records = generator.generate_records().unwrap();
 
-        // Should have: A, MX, SPF, DMARC, 2x CNAME (no DKIM without key)
-        assert_eq!(records.len(), 6);
+        // Should have: A, MX, SPF, DMARC, TLS-RPT, 2x CNAME (no DKIM without key)
+        assert_eq!(records.len(), 7);
 
         // Check A record
         assert!(records.iter().any(|r| r.record_type == DnsRecordType::A));
@@ -408,8 +523,8 @@ mod tests {
 
         let records = generator.generate_records().unwrap();
 
-        // Should have: A, MX, SPF, DKIM, DMARC, 2x CNAME
-        assert_eq!(records.len(), 7);
+        // Should have: A, MX, SPF, DKIM, DMARC, TLS-RPT, 2x CNAME
+        assert_eq!(records.len(), 8);
 
         // Check DKIM record
         let dkim_record = records
@@ -420,6 +535,94 @@ mod tests {
         assert!(dkim_record.value.contains("k=rsa"));
     }
 
+    #[test]
+    fn test_generate_mta_sts_tlsa_and_bimi_records() {
+        use crate::admin::mta_sts::MtaStsMode;
+
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let policy = MtaStsPolicy {
+            mode: MtaStsMode::Enforce,
+            mx: vec!["mail.example.com".to_string()],
+            max_age: 86400,
+        };
+        let generator = DnsConfigGenerator::new(
+            "example.com".to_string(),
+            "mail.example.com".to_string(),
+            ip,
+            "default".to_string(),
+        )
+        .with_tls_rpt_address("tlsrpt@example.com".to_string())
+        .with_mta_sts(&policy)
+        .with_tlsa_public_key(b"spki")
+        .with_bimi(
+            "https://example.com/logo.svg".to_string(),
+            Some("https://example.com/vmc.pem".to_string()),
+        );
+
+        let records = generator.generate_records().unwrap();
+        let value = |name: &str| {
+            records
+                .iter()
+                .find(|r| r.name == name)
+                .map(|r| r.value.clone())
+                .unwrap()
+        };
+
+        assert_eq!(
+            value("_smtp._tls.example.com"),
+            "\"v=TLSRPTv1; rua=mailto:tlsrpt@example.com\""
+        );
+        assert_eq!(
+            value("_mta-sts.example.com"),
+            format!("\"v=STSv1; id={}\"", policy.id())
+        );
+        assert_eq!(value("mta-sts.example.com"), "mail.example.com.");
+        assert_eq!(
+            value("_25._tcp.mail.example.com"),
+            "3 1 1 6feecc8c16c5551d9feb3eb5f77e2da773bf68bd9ef9c52927ceb2c86e56892b"
+        );
+        assert_eq!(
+            value("default._bimi.example.com"),
+            "\"v=BIMI1; l=https://example.com/logo.svg; a=https://example.com/vmc.pem\""
+        );
+    }
+
+    #[test]
+    fn test_from_config() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut config = Config::default();
+        config.server.domain = "example.org".to_string();
+        config.server.hostname = "mx.example.org".to_string();
+
+        let records = DnsConfigGenerator::from_config(&config, ip)
+            .generate_records()
+            .unwrap();
+        assert!(!records.iter().any(|r| r.name.contains("mta-sts")));
+        assert!(records
+            .iter()
+            .any(|r| r.value.contains("rua=mailto:postmaster@example.org")));
+
+        config.mta_sts.enabled = true;
+        config.bimi.logo_url = Some("https://example.org/logo.svg".to_string());
+        let records = DnsConfigGenerator::from_config(&config, ip)
+            .generate_records()
+            .unwrap();
+        assert!(records.iter().any(|r| r.name == "_mta-sts.example.org"));
+        assert!(records
+            .iter()
+            .any(|r| r.value == "\"v=BIMI1; l=https://example.org/logo.svg\""));
+    }
+
+    #[test]
+    fn test_public_key_of_pem() {
+        let key = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        assert_eq!(
+            public_key_of_pem(&key.serialize_pem()),
+            Some(key.public_key_der())
+        );
+        assert_eq!(public_key_of_pem("not a key"), None);
+    }
+
     #[test]
     fn test_generate_instructions() {
         let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
@@ -464,19 +667,6 @@ mod tests {
         assert_eq!(DnsRecordType::TXT.to_string(), "TXT");
         assert_eq!(DnsRecordType::CNAME.to_string(), "CNAME");
         assert_eq!(DnsRecordType::PTR.to_string(), "PTR");
-    }
-
-    #[tokio::test]
-    async fn test_verify_records() {
-        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
-        let generator = DnsConfigGenerator::new(
-            "example.com".to_string(),
-            "mail.example.com".to_string(),
-            ip,
-            "default".to_string(),
-        );
-
-        let results = generator.verify_records().await.unwrap();
-        assert!(!results.is_empty());
+        assert_eq!(DnsRecordType::TLSA.to_string(), "TLSA");
     }
 }
@@ -0,0 +1,523 @@
+//! Verification of published DNS records
+//!
+//! Queries the records a domain actually publishes (MX, SPF, DKIM, DMARC,
+//! the PTR of the mail server and MTA-STS) and compares them with what
+//! [`super::dns::DnsConfigGenerator`] suggests. SPF is checked by
+//! evaluating the domain's policy for the server's addresses, so includes
+//! and redirects are followed like a receiver would.
+
+use super::mta_sts::MtaStsPolicy;
+use crate::authentication::spf_check::{check_host, DnsError, SpfDns};
+use crate::config::Config;
+use crate::dkim_keys::DkimKey;
+use mail_auth::SpfResult;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::warn;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// How long fetching the MTA-STS policy may take
+const POLICY_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of one check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// The lookup failed, try again later
+    Error,
+}
+
+/// One record compared with its expected value
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsCheck {
+    /// `mx`, `spf`, `dkim`, `dmarc`, `ptr` or `mta-sts`
+    pub check: &'static str,
+    /// Queried name
+    pub name: String,
+    pub status: CheckStatus,
+    pub expected: String,
+    /// Records found
+    pub found: Vec<String>,
+    pub detail: String,
+}
+
+impl DnsCheck {
+    fn new(check: &'static str, name: &str, expected: String) -> Self {
+        Self {
+            check,
+            name: name.to_string(),
+            status: CheckStatus::Fail,
+            expected,
+            found: Vec::new(),
+            detail: String::new(),
+        }
+    }
+
+    fn pass(mut self, detail: impl Into<String>) -> Self {
+        self.status = CheckStatus::Pass;
+        self.detail = detail.into();
+        self
+    }
+
+    fn fail(mut self, detail: impl Into<String>) -> Self {
+        self.status = CheckStatus::Fail;
+        self.detail = detail.into();
+        self
+    }
+
+    fn lookup_failed(mut self, e: DnsError) -> Self {
+        match e {
+            DnsError::NotFound => self.fail("No record found"),
+            DnsError::Temporary(e) => {
+                self.status = CheckStatus::Error;
+                self.detail = format!("Lookup failed: {}", e);
+                self
+            }
+        }
+    }
+}
+
+/// Checks of one domain
+#[derive(Debug, Clone, Serialize)]
+pub struct DomainVerification {
+    pub domain: String,
+    /// Whether every check passed
+    pub passed: bool,
+    pub checks: Vec<DnsCheck>,
+}
+
+/// Compares published records with the expected ones
+pub struct DnsVerifier<D: SpfDns = TokioAsyncResolver> {
+    dns: D,
+    hostname: String,
+    mta_sts: Option<MtaStsPolicy>,
+    http: Option<reqwest::Client>,
+}
+
+impl DnsVerifier {
+    /// Verifier querying the system resolver that also fetches the served
+    /// MTA-STS policy
+    pub fn new(config: &Config) -> Self {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|_| {
+            warn!("Failed to load system DNS config, using default resolver");
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+        });
+        let http = reqwest::Client::builder()
+            .timeout(POLICY_FETCH_TIMEOUT)
+            .build()
+            .ok();
+
+        Self {
+            http,
+            ..Self::with_resolver(resolver, config)
+        }
+    }
+}
+
+impl<D: SpfDns> DnsVerifier<D> {
+    /// Verifier querying `dns` for the records `config` calls for
+    pub fn with_resolver(dns: D, config: &Config) -> Self {
+        Self {
+            dns,
+            hostname: normalize(&config.server.hostname),
+            mta_sts: config
+                .mta_sts
+                .enabled
+                .then(|| MtaStsPolicy::from_config(config)),
+            http: None,
+        }
+    }
+
+    /// Check the records of `domain`, whose mail is signed with `dkim_keys`
+    pub async fn verify(&self, domain: &str, dkim_keys: &[DkimKey]) -> DomainVerification {
+        let domain = normalize(domain);
+        let server_ips = self.server_ips().await;
+
+        let mut checks = vec![
+            self.check_mx(&domain).await,
+            self.check_spf(&domain, &server_ips).await,
+        ];
+        if dkim_keys.is_empty() {
+            checks.push(
+                DnsCheck::new(
+                    "dkim",
+                    &format!("*._domainkey.{}", domain),
+                    "A DKIM key".into(),
+                )
+                .fail("No DKIM key generated for the domain"),
+            );
+        }
+        for key in dkim_keys {
+            checks.push(self.check_dkim(key).await);
+        }
+        checks.push(self.check_dmarc(&domain).await);
+        checks.push(self.check_ptr(&server_ips).await);
+        if let Some(policy) = &self.mta_sts {
+            checks.push(self.check_mta_sts(&domain, policy).await);
+        }
+
+        DomainVerification {
+            passed: checks.iter().all(|check| check.status == CheckStatus::Pass),
+            domain,
+            checks,
+        }
+    }
+
+    /// Addresses of the mail server's hostname
+    async fn server_ips(&self) -> Vec<IpAddr> {
+        let mut ips = Vec::new();
+        for ipv6 in [false, true] {
+            if let Ok(found) = self.dns.ips(&self.hostname, ipv6).await {
+                ips.extend(found);
+            }
+        }
+        ips
+    }
+
+    async fn txt(&self, name: &str, prefix: &str) -> Result<Vec<String>, DnsError> {
+        let records = self.dns.txt(name).await?;
+        Ok(records
+            .iter()
+            .map(|record| String::from_utf8_lossy(record).into_owned())
+            .filter(|record| {
+                record
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            })
+            .collect())
+    }
+
+    async fn check_mx(&self, domain: &str) -> DnsCheck {
+        let mut check = DnsCheck::new("mx", domain, self.hostname.clone());
+        let hosts = match self.dns.mx(domain).await {
+            Ok(hosts) => hosts,
+            Err(e) => return check.lookup_failed(e),
+        };
+        check.found = hosts.iter().map(|host| normalize(host)).collect();
+        if check.found.contains(&self.hostname) {
+            check.pass("The mail server is an MX of the domain")
+        } else {
+            check.fail("No MX record points at the mail server")
+        }
+    }
+
+    async fn check_spf(&self, domain: &str, server_ips: &[IpAddr]) -> DnsCheck {
+        let mut check = DnsCheck::new(
+            "spf",
+            domain,
+            format!("v=spf1 authorizing {}", self.hostname),
+        );
+        let records = match self.txt(domain, "v=spf1").await {
+            Ok(records) => records,
+            Err(e) => return check.lookup_failed(e),
+        };
+        check.found = records;
+        match check.found.len() {
+            0 => return check.fail("No SPF record found"),
+            1 => {}
+            _ => return check.fail("Multiple SPF records, receivers treat this as an error"),
+        }
+        if server_ips.is_empty() {
+            return check.fail(format!("{} has no address to authorize", self.hostname));
+        }
+
+        let sender = format!("postmaster@{}", domain);
+        for ip in server_ips {
+            let outcome = check_host(&self.dns, *ip, domain, &sender, &self.hostname).await;
+            if outcome.result != SpfResult::Pass {
+                return check.fail(format!("SPF evaluates to {} for {}", outcome.result, ip));
+            }
+        }
+        check.pass("The mail server's addresses are authorized")
+    }
+
+    async fn check_dkim(&self, key: &DkimKey) -> DnsCheck {
+        let mut check = DnsCheck::new("dkim", &key.dns_name, key.dns_value.clone());
+        let records = match self.txt(&key.dns_name, "v=DKIM1").await {
+            Ok(records) => records,
+            Err(e) => return check.lookup_failed(e),
+        };
+        check.found = records;
+        let expected = tag(&key.dns_value, "p").map(strip_whitespace);
+        let published = check
+            .found
+            .iter()
+            .any(|record| tag(record, "p").map(strip_whitespace) == expected);
+        if published {
+            check.pass(format!("Selector {} is published", key.selector))
+        } else if check.found.is_empty() {
+            check.fail("No DKIM record found")
+        } else {
+            check.fail("The published key differs from the generated one")
+        }
+    }
+
+    async fn check_dmarc(&self, domain: &str) -> DnsCheck {
+        let name = format!("_dmarc.{}", domain);
+        let mut check = DnsCheck::new("dmarc", &name, "v=DMARC1 with a p= policy".into());
+        let records = match self.txt(&name, "v=DMARC1").await {
+            Ok(records) => records,
+            Err(e) => return check.lookup_failed(e),
+        };
+        check.found = records;
+        match check.found.as_slice() {
+            [] => check.fail("No DMARC record found"),
+            [record] => match tag(record, "p") {
+                Some(policy) => {
+                    let detail = format!("Policy {}", policy);
+                    check.pass(detail)
+                }
+                None => check.fail("The DMARC record has no p= policy"),
+            },
+            _ => check.fail("Multiple DMARC records, receivers ignore them all"),
+        }
+    }
+
+    async fn check_ptr(&self, server_ips: &[IpAddr]) -> DnsCheck {
+        let mut check = DnsCheck::new("ptr", &self.hostname, self.hostname.clone());
+        if server_ips.is_empty() {
+            return check.fail(format!("{} has no address", self.hostname));
+        }
+
+        for ip in server_ips {
+            check.name = ip.to_string();
+            match self.dns.ptr(*ip).await {
+                Ok(names) => {
+                    check.found = names.iter().map(|name| normalize(name)).collect();
+                    if !check.found.contains(&self.hostname) {
+                        return check.fail(format!("{} doesn't resolve back to the hostname", ip));
+                    }
+                }
+                Err(e) => return check.lookup_failed(e),
+            }
+        }
+        check.pass("The server's addresses resolve back to its hostname")
+    }
+
+    async fn check_mta_sts(&self, domain: &str, policy: &MtaStsPolicy) -> DnsCheck {
+        let name = format!("_mta-sts.{}", domain);
+        let id = policy.id();
+        let mut check = DnsCheck::new("mta-sts", &name, format!("v=STSv1; id={}", id));
+        let records = match self.txt(&name, "v=STSv1").await {
+            Ok(records) => records,
+            Err(e) => return check.lookup_failed(e),
+        };
+        check.found = records;
+        match check.found.as_slice() {
+            [] => return check.fail("No MTA-STS record found"),
+            [record] if tag(record, "id") == Some(id.as_str()) => {}
+            [_] => return check.fail("The policy id is outdated, senders keep the old policy"),
+            _ => return check.fail("Multiple MTA-STS records, senders ignore them all"),
+        }
+
+        let Some(http) = &self.http else {
+            return check.pass("The policy id is current");
+        };
+        let url = format!("https://mta-sts.{}/.well-known/mta-sts.txt", domain);
+        let served = match http.get(&url).send().await {
+            Ok(response) if response.status().is_success() => response.text().await.ok(),
+            _ => None,
+        };
+        match served {
+            Some(text) if policy_lines(&text) == policy_lines(&policy.to_text()) => {
+                check.pass("The policy id is current and the policy is served")
+            }
+            Some(_) => check.fail(format!("The policy served at {} differs", url)),
+            None => check.fail(format!("The policy can't be fetched from {}", url)),
+        }
+    }
+}
+
+/// Lowercase host name without the root dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Value of the `name=` tag of a `;`-separated record
+fn tag<'a>(record: &'a str, name: &str) -> Option<&'a str> {
+    record.split(';').find_map(|field| {
+        let (key, value) = field.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+fn strip_whitespace(value: &str) -> String {
+    value.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Non-empty lines of a policy document, whatever its line endings
+fn policy_lines(text: &str) -> Vec<&str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dkim_keys::DkimAlgorithm;
+    use std::collections::HashMap;
+
+    /// DNS answering from fixed records
+    #[derive(Default)]
+    struct MockDns {
+        txt: HashMap<String, Vec<String>>,
+        ips: HashMap<String, Vec<IpAddr>>,
+        mx: HashMap<String, Vec<String>>,
+        ptr: HashMap<IpAddr, Vec<String>>,
+    }
+
+    impl MockDns {
+        fn txt(mut self, name: &str, record: &str) -> Self {
+            self.txt
+                .entry(name.to_string())
+                .or_default()
+                .push(record.to_string());
+            self
+        }
+    }
+
+    impl SpfDns for MockDns {
+        async fn txt(&self, name: &str) -> Result<Vec<Vec<u8>>, DnsError> {
+            let records = self.txt.get(name).ok_or(DnsError::NotFound)?;
+            Ok(records.iter().map(|r| r.as_bytes().to_vec()).collect())
+        }
+
+        async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>, DnsError> {
+            let name = name.trim_end_matches('.');
+            let ips = self.ips.get(name).cloned().ok_or(DnsError::NotFound)?;
+            Ok(ips.into_iter().filter(|ip| ip.is_ipv6() == ipv6).collect())
+        }
+
+        async fn mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+            if name == "broken.example" {
+                return Err(DnsError::Temporary("timeout".to_string()));
+            }
+            self.mx.get(name).cloned().ok_or(DnsError::NotFound)
+        }
+
+        async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, DnsError> {
+            self.ptr.get(&ip).cloned().ok_or(DnsError::NotFound)
+        }
+    }
+
+    fn config(mta_sts: bool) -> Config {
+        let mut config = Config::default();
+        config.server.domain = "example.com".to_string();
+        config.server.hostname = "mail.example.com".to_string();
+        config.mta_sts.enabled = mta_sts;
+        config
+    }
+
+    fn dkim_key() -> DkimKey {
+        DkimKey {
+            domain: "example.com".to_string(),
+            selector: "s1".to_string(),
+            algorithm: DkimAlgorithm::Ed25519,
+            active: true,
+            created_at: chrono::Utc::now(),
+            dns_name: "s1._domainkey.example.com".to_string(),
+            dns_value: "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+                .to_string(),
+        }
+    }
+
+    /// Records matching `config(true)`
+    fn published() -> MockDns {
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let policy = MtaStsPolicy::from_config(&config(true));
+        let mut dns = MockDns::default()
+            .txt("example.com", "v=spf1 mx -all")
+            .txt("example.com", "google-site-verification=abc")
+            .txt(
+                "s1._domainkey.example.com",
+                "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hc vPapiMlrwIaaPcHURo=",
+            )
+            .txt(
+                "_dmarc.example.com",
+                "v=DMARC1; p=quarantine; rua=mailto:d@example.com",
+            )
+            .txt(
+                "_mta-sts.example.com",
+                &format!("v=STSv1; id={}", policy.id()),
+            );
+        dns.ips.insert("mail.example.com".to_string(), vec![ip]);
+        dns.mx.insert(
+            "example.com".to_string(),
+            vec!["mail.example.com.".to_string()],
+        );
+        dns.ptr.insert(ip, vec!["mail.example.com.".to_string()]);
+        dns
+    }
+
+    fn status(result: &DomainVerification, check: &str) -> CheckStatus {
+        result
+            .checks
+            .iter()
+            .find(|c| c.check == check)
+            .unwrap()
+            .status
+    }
+
+    #[tokio::test]
+    async fn test_verify_published_records() {
+        let verifier = DnsVerifier::with_resolver(published(), &config(true));
+        let result = verifier.verify("example.com", &[dkim_key()]).await;
+
+        assert!(result.passed, "{:?}", result.checks);
+        assert_eq!(result.checks.len(), 6);
+        let spf = result.checks.iter().find(|c| c.check == "spf").unwrap();
+        assert_eq!(spf.found, vec!["v=spf1 mx -all".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_missing_and_wrong_records() {
+        let mut dns = published();
+        dns.txt.remove("_dmarc.example.com");
+        dns.txt.insert(
+            "example.com".to_string(),
+            vec!["v=spf1 ip4:198.51.100.1 -all".to_string()],
+        );
+        dns.ptr.clear();
+        dns.ptr.insert(
+            "192.0.2.10".parse().unwrap(),
+            vec!["host-10.isp.example.net.".to_string()],
+        );
+        let mut changed = config(true);
+        changed.mta_sts.max_age = 86400;
+
+        let verifier = DnsVerifier::with_resolver(dns, &changed);
+        let result = verifier.verify("example.com", &[]).await;
+
+        assert!(!result.passed);
+        assert_eq!(status(&result, "mx"), CheckStatus::Pass);
+        assert_eq!(status(&result, "spf"), CheckStatus::Fail);
+        assert_eq!(status(&result, "dkim"), CheckStatus::Fail);
+        assert_eq!(status(&result, "dmarc"), CheckStatus::Fail);
+        assert_eq!(status(&result, "ptr"), CheckStatus::Fail);
+        // The published id belongs to the previous policy
+        assert_eq!(status(&result, "mta-sts"), CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_verify_lookup_errors_and_skipped_mta_sts() {
+        let verifier = DnsVerifier::with_resolver(published(), &config(false));
+        let result = verifier.verify("broken.example", &[dkim_key()]).await;
+
+        assert_eq!(status(&result, "mx"), CheckStatus::Error);
+        assert!(!result.checks.iter().any(|c| c.check == "mta-sts"));
+    }
+
+    #[test]
+    fn test_tag() {
+        let record = "v=DMARC1; p=reject ; rua=mailto:a@example.com";
+        assert_eq!(tag(record, "p"), Some("reject"));
+        assert_eq!(tag(record, "RUA"), Some("mailto:a@example.com"));
+        assert_eq!(tag(record, "sp"), None);
+    }
+}
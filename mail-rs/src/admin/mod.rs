@@ -1,17 +1,25 @@
 /// Admin module for Mail-in-a-Box equivalent functionality
 ///
 /// Provides:
-/// - DNS auto-configuration
+/// - DNS auto-configuration and verification
+/// - MTA-STS policy hosting
 /// - System diagnostics and monitoring
 /// - Backup management
 /// - SSL certificate automation (Let's Encrypt)
+/// - Config fingerprints and drift detection between nodes
 
 pub mod backup;
+pub mod config_drift;
 pub mod diagnostics;
 pub mod dns;
+pub mod dns_verify;
+pub mod mta_sts;
 pub mod ssl;
 
 pub use backup::{BackupManager, BackupConfig, BackupStatus};
+pub use config_drift::{ConfigDiff, ConfigDifference, ConfigSnapshot};
 pub use diagnostics::{SystemDiagnostics, DiagnosticResult, HealthStatus};
 pub use dns::{DnsConfigGenerator, DnsRecord, DnsRecordType};
+pub use dns_verify::{CheckStatus, DnsCheck, DnsVerifier, DomainVerification};
+pub use mta_sts::{MtaStsMode, MtaStsPolicy};
 pub use ssl::{SslManager, SslConfig, CertificateStatus};
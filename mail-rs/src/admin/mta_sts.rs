@@ -0,0 +1,131 @@
+//! MTA-STS policy (RFC 8461)
+//!
+//! The policy tells senders that mail for our domains must be delivered over
+//! verified TLS to the listed MX hosts. It is served at
+//! `https://mta-sts.<domain>/.well-known/mta-sts.txt` and announced by the
+//! `_mta-sts.<domain>` TXT record, whose id is derived from the policy text
+//! so that it changes whenever the policy does.
+
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// How senders treat a failed TLS check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MtaStsMode {
+    /// Don't deliver
+    Enforce,
+    /// Deliver anyway and report the failure through TLS-RPT
+    #[default]
+    Testing,
+    /// Withdraw a previously published policy
+    None,
+}
+
+impl std::fmt::Display for MtaStsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MtaStsMode::Enforce => write!(f, "enforce"),
+            MtaStsMode::Testing => write!(f, "testing"),
+            MtaStsMode::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Published MTA-STS policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct MtaStsPolicy {
+    pub mode: MtaStsMode,
+    /// MX host names, `*.` wildcards allowed
+    pub mx: Vec<String>,
+    /// Seconds senders may cache the policy
+    pub max_age: u64,
+}
+
+impl MtaStsPolicy {
+    /// Policy of `[mta_sts]`, listing `server.hostname` unless MX hosts are
+    /// configured
+    pub fn from_config(config: &Config) -> Self {
+        let mx = if config.mta_sts.mx.is_empty() {
+            vec![config.server.hostname.clone()]
+        } else {
+            config.mta_sts.mx.clone()
+        };
+        Self {
+            mode: config.mta_sts.mode,
+            mx,
+            max_age: config.mta_sts.max_age,
+        }
+    }
+
+    /// The `mta-sts.txt` document
+    pub fn to_text(&self) -> String {
+        let mut text = format!("version: STSv1\r\nmode: {}\r\n", self.mode);
+        for mx in &self.mx {
+            text.push_str(&format!("mx: {}\r\n", mx));
+        }
+        text.push_str(&format!("max_age: {}\r\n", self.max_age));
+        text
+    }
+
+    /// Policy id for the `_mta-sts` TXT record
+    pub fn id(&self) -> String {
+        Sha256::digest(self.to_text().as_bytes())[..10]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> MtaStsPolicy {
+        MtaStsPolicy {
+            mode: MtaStsMode::Enforce,
+            mx: vec![
+                "mail.example.com".to_string(),
+                "*.mx.example.net".to_string(),
+            ],
+            max_age: 604800,
+        }
+    }
+
+    #[test]
+    fn test_policy_text() {
+        assert_eq!(
+            policy().to_text(),
+            "version: STSv1\r\nmode: enforce\r\nmx: mail.example.com\r\n\
+             mx: *.mx.example.net\r\nmax_age: 604800\r\n"
+        );
+    }
+
+    #[test]
+    fn test_policy_id_follows_policy() {
+        let id = policy().id();
+        assert_eq!(id.len(), 20);
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(id, policy().id());
+
+        let mut testing = policy();
+        testing.mode = MtaStsMode::Testing;
+        assert_ne!(id, testing.id());
+    }
+
+    #[test]
+    fn test_policy_from_config() {
+        let mut config = Config::default();
+        config.server.hostname = "mx1.example.com".to_string();
+        let policy = MtaStsPolicy::from_config(&config);
+        assert_eq!(policy.mx, vec!["mx1.example.com".to_string()]);
+        assert_eq!(policy.mode, MtaStsMode::Testing);
+
+        config.mta_sts.mx = vec!["*.example.com".to_string()];
+        assert_eq!(
+            MtaStsPolicy::from_config(&config).mx,
+            vec!["*.example.com".to_string()]
+        );
+    }
+}
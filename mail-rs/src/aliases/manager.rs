@@ -0,0 +1,540 @@
+//! Alias manager: burner aliases and the senders writing to them
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Characters of the random suffix
+const SUFFIX_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Length of the random suffix
+const SUFFIX_LEN: usize = 4;
+
+/// Attempts at finding an unused address before giving up
+const MAX_ATTEMPTS: usize = 8;
+
+/// Alias manager
+pub struct AliasManager {
+    db: SqlitePool,
+}
+
+impl AliasManager {
+    /// Create a new alias manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the alias tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS aliases (
+                address TEXT PRIMARY KEY,
+                owner TEXT NOT NULL,
+                label TEXT,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                muted INTEGER NOT NULL DEFAULT 0,
+                blocked INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_aliases_owner ON aliases(owner)")
+            .execute(&self.db)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS alias_senders (
+                address TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                messages INTEGER NOT NULL DEFAULT 0,
+                rejected INTEGER NOT NULL DEFAULT 0,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                PRIMARY KEY (address, sender)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Generate a new alias `<prefix>-<random>@<domain>` for `owner`
+    ///
+    /// The prefix is reduced to lowercase letters, digits and `-`.
+    pub async fn create(
+        &self,
+        owner: &str,
+        prefix: Option<&str>,
+        domain: &str,
+        label: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Alias> {
+        self.create_at(owner, prefix, domain, label, expires_at, Utc::now())
+            .await
+    }
+
+    async fn create_at(
+        &self,
+        owner: &str,
+        prefix: Option<&str>,
+        domain: &str,
+        label: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        at: DateTime<Utc>,
+    ) -> Result<Alias> {
+        let domain = domain.trim().to_lowercase();
+        if domain.is_empty() || domain.contains('@') {
+            bail!("Invalid alias domain: {:?}", domain);
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= at) {
+            bail!("Expiry must be in the future");
+        }
+        let prefix = sanitize_prefix(prefix.unwrap_or(DEFAULT_PREFIX));
+
+        for _ in 0..MAX_ATTEMPTS {
+            let alias = Alias {
+                address: format!("{}-{}@{}", prefix, random_suffix(), domain),
+                owner: owner.to_lowercase(),
+                label: label.map(str::to_string),
+                created_at: at,
+                expires_at,
+                muted: false,
+                blocked: false,
+            };
+
+            let inserted = sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO aliases (address, owner, label, created_at, expires_at)
+                VALUES (?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&alias.address)
+            .bind(&alias.owner)
+            .bind(&alias.label)
+            .bind(alias.created_at.to_rfc3339())
+            .bind(alias.expires_at.map(|t| t.to_rfc3339()))
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+
+            if inserted == 1 {
+                return Ok(alias);
+            }
+        }
+
+        Err(anyhow!("No free alias found for prefix {}", prefix))
+    }
+
+    /// Alias with this address, if any
+    pub async fn resolve(&self, address: &str) -> Result<Option<Alias>> {
+        sqlx::query("SELECT * FROM aliases WHERE address = ?")
+            .bind(address.to_lowercase())
+            .fetch_optional(&self.db)
+            .await?
+            .map(|row| alias_from_row(&row))
+            .transpose()
+    }
+
+    /// Aliases of `owner`, newest first
+    pub async fn list(&self, owner: &str) -> Result<Vec<Alias>> {
+        sqlx::query("SELECT * FROM aliases WHERE owner = ? ORDER BY created_at DESC, address")
+            .bind(owner.to_lowercase())
+            .fetch_all(&self.db)
+            .await?
+            .iter()
+            .map(alias_from_row)
+            .collect()
+    }
+
+    /// Apply `update` to an alias of `owner`; `None` if `owner` has no
+    /// such alias
+    pub async fn update(
+        &self,
+        owner: &str,
+        address: &str,
+        update: &AliasUpdate,
+    ) -> Result<Option<Alias>> {
+        let Some(mut alias) = self.owned(owner, address).await? else {
+            return Ok(None);
+        };
+
+        if let Some(muted) = update.muted {
+            alias.muted = muted;
+        }
+        if let Some(blocked) = update.blocked {
+            alias.blocked = blocked;
+        }
+        if let Some(label) = &update.label {
+            alias.label = Some(label.clone()).filter(|label| !label.is_empty());
+        }
+        if let Some(expires_at) = update.expires_at {
+            alias.expires_at = Some(expires_at);
+        }
+
+        sqlx::query(
+            "UPDATE aliases SET label = ?, expires_at = ?, muted = ?, blocked = ? WHERE address = ?",
+        )
+        .bind(&alias.label)
+        .bind(alias.expires_at.map(|t| t.to_rfc3339()))
+        .bind(alias.muted)
+        .bind(alias.blocked)
+        .bind(&alias.address)
+        .execute(&self.db)
+        .await?;
+
+        Ok(Some(alias))
+    }
+
+    /// Delete an alias of `owner` and its statistics; returns whether it
+    /// existed
+    ///
+    /// Mail to a deleted address is no longer accepted as alias mail.
+    pub async fn delete(&self, owner: &str, address: &str) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM aliases WHERE address = ? AND owner = ?")
+            .bind(address.to_lowercase())
+            .bind(owner.to_lowercase())
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("DELETE FROM alias_senders WHERE address = ?")
+            .bind(address.to_lowercase())
+            .execute(&self.db)
+            .await?;
+        Ok(true)
+    }
+
+    /// Count a message from `sender` to `address`, accepted or refused
+    pub async fn record_sender(&self, address: &str, sender: &str, accepted: bool) -> Result<()> {
+        self.record_sender_at(address, sender, accepted, Utc::now())
+            .await
+    }
+
+    async fn record_sender_at(
+        &self,
+        address: &str,
+        sender: &str,
+        accepted: bool,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        let (messages, rejected) = if accepted { (1, 0) } else { (0, 1) };
+        sqlx::query(
+            r#"
+            INSERT INTO alias_senders (address, sender, messages, rejected, first_seen, last_seen)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(address, sender) DO UPDATE SET
+                messages = messages + excluded.messages,
+                rejected = rejected + excluded.rejected,
+                last_seen = excluded.last_seen
+            "#,
+        )
+        .bind(address.to_lowercase())
+        .bind(sender.to_lowercase())
+        .bind(messages)
+        .bind(rejected)
+        .bind(at.to_rfc3339())
+        .bind(at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Senders of an alias of `owner`; `None` if `owner` has no such alias
+    pub async fn stats(&self, owner: &str, address: &str) -> Result<Option<AliasStats>> {
+        let Some(alias) = self.owned(owner, address).await? else {
+            return Ok(None);
+        };
+
+        let senders = sqlx::query(
+            r#"
+            SELECT * FROM alias_senders WHERE address = ?
+            ORDER BY messages + rejected DESC, last_seen DESC
+            "#,
+        )
+        .bind(&alias.address)
+        .fetch_all(&self.db)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(SenderStats {
+                sender: row.get("sender"),
+                messages: row.get("messages"),
+                rejected: row.get("rejected"),
+                first_seen: parse_time(row.get("first_seen"))?,
+                last_seen: parse_time(row.get("last_seen"))?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(AliasStats {
+            address: alias.address,
+            messages: senders.iter().map(|s| s.messages).sum(),
+            rejected: senders.iter().map(|s| s.rejected).sum(),
+            senders,
+        }))
+    }
+
+    async fn owned(&self, owner: &str, address: &str) -> Result<Option<Alias>> {
+        Ok(self
+            .resolve(address)
+            .await?
+            .filter(|alias| alias.owner == owner.to_lowercase()))
+    }
+}
+
+/// Lowercase letters, digits and single dashes, at most
+/// [`MAX_PREFIX_LEN`] characters
+fn sanitize_prefix(prefix: &str) -> String {
+    let mut sanitized = String::new();
+    for c in prefix.trim().to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            sanitized.push(c);
+        } else if !sanitized.is_empty() && !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+        if sanitized.len() == MAX_PREFIX_LEN {
+            break;
+        }
+    }
+
+    let sanitized = sanitized.trim_end_matches('-');
+    if sanitized.is_empty() {
+        DEFAULT_PREFIX.to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+fn random_suffix() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+
+    (0..SUFFIX_LEN)
+        .map(|_| SUFFIX_CHARSET[rng.gen_range(0..SUFFIX_CHARSET.len())] as char)
+        .collect()
+}
+
+fn alias_from_row(row: &SqliteRow) -> Result<Alias> {
+    Ok(Alias {
+        address: row.get("address"),
+        owner: row.get("owner"),
+        label: row.get("label"),
+        created_at: parse_time(row.get("created_at"))?,
+        expires_at: row
+            .get::<Option<String>, _>("expires_at")
+            .map(parse_time)
+            .transpose()?,
+        muted: row.get("muted"),
+        blocked: row.get("blocked"),
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    async fn manager() -> AliasManager {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = AliasManager::new(db);
+        manager.init_db().await.unwrap();
+        manager
+    }
+
+    #[test]
+    fn test_sanitize_prefix() {
+        assert_eq!(sanitize_prefix("Shop"), "shop");
+        assert_eq!(sanitize_prefix(" My Shop!! "), "my-shop");
+        assert_eq!(sanitize_prefix("--"), DEFAULT_PREFIX);
+        assert_eq!(sanitize_prefix(&"a".repeat(40)).len(), MAX_PREFIX_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_create_and_resolve() {
+        let manager = manager().await;
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let alias = manager
+            .create_at(
+                "Alice@example.com",
+                Some("shop"),
+                "example.com",
+                Some("Bike shop"),
+                Some(now + Duration::days(30)),
+                now,
+            )
+            .await
+            .unwrap();
+        let (local, domain) = alias.address.split_once('@').unwrap();
+        assert_eq!(domain, "example.com");
+        assert!(local.starts_with("shop-"));
+        assert_eq!(local.len(), "shop-".len() + SUFFIX_LEN);
+        assert_eq!(alias.owner, "alice@example.com");
+
+        let resolved = manager
+            .resolve(&alias.address.to_uppercase())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved, alias);
+        assert_eq!(resolved.delivery(now), AliasDelivery::Deliver);
+        assert_eq!(
+            resolved.delivery(now + Duration::days(31)),
+            AliasDelivery::Reject
+        );
+        assert!(manager
+            .resolve("nobody@example.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Expiry must lie ahead
+        assert!(manager
+            .create_at(
+                "alice@example.com",
+                None,
+                "example.com",
+                None,
+                Some(now),
+                now
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_is_limited_to_owner() {
+        let manager = manager().await;
+        let alias = manager
+            .create("alice@example.com", None, "example.com", None, None)
+            .await
+            .unwrap();
+        assert!(alias.address.starts_with("alias-"));
+
+        let mute = AliasUpdate {
+            muted: Some(true),
+            ..Default::default()
+        };
+        assert!(manager
+            .update("mallory@example.com", &alias.address, &mute)
+            .await
+            .unwrap()
+            .is_none());
+
+        let muted = manager
+            .update("alice@example.com", &alias.address, &mute)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(muted.delivery(Utc::now()), AliasDelivery::Discard);
+
+        let block = AliasUpdate {
+            blocked: Some(true),
+            ..Default::default()
+        };
+        let blocked = manager
+            .update("alice@example.com", &alias.address, &block)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(blocked.muted);
+        assert_eq!(blocked.delivery(Utc::now()), AliasDelivery::Reject);
+
+        assert_eq!(
+            manager.list("alice@example.com").await.unwrap(),
+            vec![blocked]
+        );
+        assert!(!manager
+            .delete("mallory@example.com", &alias.address)
+            .await
+            .unwrap());
+        assert!(manager
+            .delete("alice@example.com", &alias.address)
+            .await
+            .unwrap());
+        assert!(manager.list("alice@example.com").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sender_stats() {
+        let manager = manager().await;
+        let alias = manager
+            .create("alice@example.com", Some("news"), "example.com", None, None)
+            .await
+            .unwrap();
+        let t0 = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        for i in 0..3 {
+            manager
+                .record_sender_at(
+                    &alias.address,
+                    "promo@shop.test",
+                    true,
+                    t0 + Duration::hours(i),
+                )
+                .await
+                .unwrap();
+        }
+        manager
+            .record_sender_at(&alias.address, "Spam@Broker.test", true, t0)
+            .await
+            .unwrap();
+        manager
+            .record_sender_at(
+                &alias.address,
+                "spam@broker.test",
+                false,
+                t0 + Duration::days(1),
+            )
+            .await
+            .unwrap();
+
+        let stats = manager
+            .stats("alice@example.com", &alias.address)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((stats.messages, stats.rejected), (4, 1));
+        assert_eq!(stats.senders.len(), 2);
+        assert_eq!(stats.senders[0].sender, "promo@shop.test");
+        assert_eq!(stats.senders[0].messages, 3);
+        assert_eq!(stats.senders[0].first_seen, t0);
+        assert_eq!(stats.senders[0].last_seen, t0 + Duration::hours(2));
+        assert_eq!(stats.senders[1].sender, "spam@broker.test");
+        assert_eq!(
+            (stats.senders[1].messages, stats.senders[1].rejected),
+            (1, 1)
+        );
+
+        assert!(manager
+            .stats("bob@example.com", &alias.address)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
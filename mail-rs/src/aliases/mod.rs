@@ -0,0 +1,13 @@
+//! Burner aliases: disposable inbound addresses bound to a user
+//!
+//! Users generate random addresses such as `shop-x7f2@example.com` for
+//! signups. Mail to an alias is delivered to its owner's mailbox until the
+//! alias expires; a muted alias still accepts mail but discards it, a
+//! blocked one refuses it at RCPT TO. Every sender writing to an alias is
+//! counted, so users can see who shared or leaked the address.
+
+pub mod manager;
+pub mod types;
+
+pub use manager::AliasManager;
+pub use types::*;
@@ -0,0 +1,89 @@
+//! Alias data types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Longest prefix kept in front of the random suffix
+pub const MAX_PREFIX_LEN: usize = 24;
+
+/// Prefix of aliases created without one
+pub const DEFAULT_PREFIX: &str = "alias";
+
+/// A burner alias
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alias {
+    pub address: String,
+    /// User receiving the alias's mail
+    pub owner: String,
+    /// Free-form note, e.g. the shop the alias was given to
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Mail is refused after this time
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Accept mail but discard it
+    pub muted: bool,
+    /// Refuse mail at RCPT TO
+    pub blocked: bool,
+}
+
+impl Alias {
+    /// Whether the alias had expired at `at`
+    pub fn is_expired(&self, at: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= at)
+    }
+
+    /// What happens to mail sent to the alias at `at`
+    pub fn delivery(&self, at: DateTime<Utc>) -> AliasDelivery {
+        if self.blocked || self.is_expired(at) {
+            AliasDelivery::Reject
+        } else if self.muted {
+            AliasDelivery::Discard
+        } else {
+            AliasDelivery::Deliver
+        }
+    }
+}
+
+/// Handling of mail sent to an alias
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasDelivery {
+    /// Deliver to the owner's mailbox
+    Deliver,
+    /// Accept and drop (muted)
+    Discard,
+    /// Refuse (blocked or expired)
+    Reject,
+}
+
+/// Mail one sender sent to an alias
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SenderStats {
+    /// Envelope sender, empty for bounces
+    pub sender: String,
+    /// Messages accepted, delivered or discarded
+    pub messages: i64,
+    /// Messages refused because the alias was blocked or expired
+    pub rejected: i64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Statistics of one alias
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AliasStats {
+    pub address: String,
+    pub messages: i64,
+    pub rejected: i64,
+    /// Most active senders first
+    pub senders: Vec<SenderStats>,
+}
+
+/// Changes to an alias; unset fields are kept
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AliasUpdate {
+    pub muted: Option<bool>,
+    pub blocked: Option<bool>,
+    pub label: Option<String>,
+    /// New expiry, e.g. to extend an alias still in use
+    pub expires_at: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,371 @@
+//! DNS blocklist (DNSBL/RBL) checks of SMTP clients
+//!
+//! With `[dnsbl] enabled`, the address of each unauthenticated SMTP client
+//! is looked up in the configured lists when it connects: `1.2.0.192.zen.
+//! spamhaus.org` for 192.0.2.1, nibble-reversed for IPv6. An A record in
+//! 127.0.0.0/8 means the client is listed.
+//!
+//! Each list either refuses listed clients with 554 (`action = "reject"`),
+//! at connection or at MAIL FROM (`reject_at`), or adds its `weight` to the
+//! spam score of their messages (`action = "score"`). Answers, including
+//! "not listed", are cached for `cache_ttl_secs`; lookups, hits, errors and
+//! rejections are counted per list.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::authentication::{CacheStats, TtlCache};
+use crate::config::{DnsblConfig, DnsblListConfig};
+use crate::spam::SpamRuleMatch;
+
+/// Maximum number of cached answers
+const CACHE_CAPACITY: usize = 50_000;
+
+/// What a list does with the clients it lists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsblAction {
+    /// Add the list's weight to the spam score of their messages
+    #[default]
+    Score,
+    /// Refuse them with 554
+    Reject,
+}
+
+/// When clients listed by a rejecting list are refused
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsblStage {
+    /// Instead of the greeting
+    Connect,
+    /// At MAIL FROM, so clients that authenticate can still submit
+    #[default]
+    MailFrom,
+}
+
+/// A list the client is on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DnsblHit {
+    pub zone: String,
+    /// Return code of the list, e.g. 127.0.0.2
+    pub code: Ipv4Addr,
+    pub action: DnsblAction,
+    pub weight: f64,
+}
+
+/// Lists a client is on
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DnsblVerdict {
+    pub hits: Vec<DnsblHit>,
+}
+
+impl DnsblVerdict {
+    /// First rejecting list the client is on
+    pub fn rejection(&self) -> Option<&DnsblHit> {
+        self.hits.iter().find(|hit| hit.action == DnsblAction::Reject)
+    }
+
+    /// Spam rule matches of the scoring lists the client is on
+    pub fn rule_matches(&self) -> Vec<SpamRuleMatch> {
+        self.hits
+            .iter()
+            .filter(|hit| hit.action == DnsblAction::Score)
+            .map(|hit| SpamRuleMatch {
+                rule_name: rule_name(&hit.zone),
+                score: hit.weight,
+                description: format!("Client listed on {} ({})", hit.zone, hit.code),
+            })
+            .collect()
+    }
+}
+
+/// Counters of a list
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsblListStats {
+    pub zone: String,
+    pub action: DnsblAction,
+    pub weight: f64,
+    /// Clients checked, cached answers included
+    pub lookups: u64,
+    pub hits: u64,
+    /// Lookups that failed or timed out; the client counts as not listed
+    pub errors: u64,
+    /// Clients refused because of the list
+    pub rejected: u64,
+}
+
+/// Counters of all lists and the answer cache
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsblStats {
+    pub reject_at: DnsblStage,
+    pub lists: Vec<DnsblListStats>,
+    pub cache: CacheStats,
+}
+
+#[derive(Default)]
+struct Counters {
+    lookups: AtomicU64,
+    hits: AtomicU64,
+    errors: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Checks SMTP clients against the configured blocklists
+pub struct DnsblChecker {
+    lists: Vec<DnsblListConfig>,
+    reject_at: DnsblStage,
+    timeout: Duration,
+    resolver: TokioAsyncResolver,
+    /// Return code per (zone, client), None when not listed
+    cache: TtlCache<(String, IpAddr), Option<Ipv4Addr>>,
+    counters: HashMap<String, Counters>,
+}
+
+impl DnsblChecker {
+    pub fn from_config(config: &DnsblConfig) -> Self {
+        let lists: Vec<DnsblListConfig> = config
+            .lists
+            .iter()
+            .map(|list| DnsblListConfig {
+                zone: list.zone.trim().trim_end_matches('.').to_lowercase(),
+                ..list.clone()
+            })
+            .filter(|list| !list.zone.is_empty())
+            .collect();
+        Self {
+            counters: lists
+                .iter()
+                .map(|list| (list.zone.clone(), Counters::default()))
+                .collect(),
+            lists,
+            reject_at: config.reject_at,
+            timeout: Duration::from_millis(config.timeout_ms),
+            resolver: TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default()),
+            cache: TtlCache::new(Duration::from_secs(config.cache_ttl_secs), CACHE_CAPACITY),
+        }
+    }
+
+    /// When clients on a rejecting list are refused
+    pub fn reject_at(&self) -> DnsblStage {
+        self.reject_at
+    }
+
+    /// Look `ip` up in every list; loopback and private addresses are
+    /// never listed
+    pub async fn check(&self, ip: IpAddr) -> DnsblVerdict {
+        if is_local(ip) {
+            return DnsblVerdict::default();
+        }
+
+        let lookups = self.lists.iter().map(|list| self.lookup(list, ip));
+        let hits: Vec<DnsblHit> = futures::future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        if !hits.is_empty() {
+            let zones: Vec<&str> = hits.iter().map(|hit| hit.zone.as_str()).collect();
+            info!("Client {} listed on {}", ip, zones.join(", "));
+        }
+        DnsblVerdict { hits }
+    }
+
+    async fn lookup(&self, list: &DnsblListConfig, ip: IpAddr) -> Option<DnsblHit> {
+        let counters = &self.counters[&list.zone];
+        counters.lookups.fetch_add(1, Ordering::Relaxed);
+
+        let key = (list.zone.clone(), ip);
+        let code = match self.cache.get(&key) {
+            Some(code) => code,
+            None => {
+                let name = query_name(ip, &list.zone);
+                match tokio::time::timeout(self.timeout, self.resolver.ipv4_lookup(name.as_str())).await {
+                    Ok(Ok(answer)) => {
+                        let code = answer.iter().map(|a| a.0).find(|code| code.octets()[0] == 127);
+                        self.cache.insert(key, code);
+                        code
+                    }
+                    Ok(Err(e)) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                        self.cache.insert(key, None);
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        counters.errors.fetch_add(1, Ordering::Relaxed);
+                        warn!("DNSBL lookup of {} failed: {}", name, e);
+                        None
+                    }
+                    Err(_) => {
+                        counters.errors.fetch_add(1, Ordering::Relaxed);
+                        warn!("DNSBL lookup of {} timed out", name);
+                        None
+                    }
+                }
+            }
+        };
+
+        let code = code?;
+        counters.hits.fetch_add(1, Ordering::Relaxed);
+        debug!("{} listed on {} ({})", ip, list.zone, code);
+        Some(DnsblHit {
+            zone: list.zone.clone(),
+            code,
+            action: list.action,
+            weight: list.weight,
+        })
+    }
+
+    /// Count a client refused because of `hit`
+    pub fn record_rejection(&self, hit: &DnsblHit) {
+        if let Some(counters) = self.counters.get(&hit.zone) {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counters of every list
+    pub fn stats(&self) -> DnsblStats {
+        DnsblStats {
+            reject_at: self.reject_at,
+            lists: self
+                .lists
+                .iter()
+                .map(|list| {
+                    let counters = &self.counters[&list.zone];
+                    DnsblListStats {
+                        zone: list.zone.clone(),
+                        action: list.action,
+                        weight: list.weight,
+                        lookups: counters.lookups.load(Ordering::Relaxed),
+                        hits: counters.hits.load(Ordering::Relaxed),
+                        errors: counters.errors.load(Ordering::Relaxed),
+                        rejected: counters.rejected.load(Ordering::Relaxed),
+                    }
+                })
+                .collect(),
+            cache: self.cache.stats(),
+        }
+    }
+}
+
+/// Reply refusing a client listed by `hit`
+pub fn reply(ip: IpAddr, hit: &DnsblHit) -> String {
+    format!(
+        "554 5.7.1 Service unavailable; client host [{}] blocked using {}\r\n",
+        ip, hit.zone
+    )
+}
+
+/// Name looked up in `zone` for `ip`: the reversed octets of IPv4
+/// addresses, the reversed nibbles of IPv6 ones
+pub fn query_name(ip: IpAddr, zone: &str) -> String {
+    let labels: Vec<String> = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().rev().map(|octet| octet.to_string()).collect(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0x0f, byte >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    format!("{}.{}.", labels.join("."), zone)
+}
+
+/// Spam rule name of a list, e.g. `DNSBL_ZEN_SPAMHAUS_ORG`
+fn rule_name(zone: &str) -> String {
+    let zone: String = zone
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("DNSBL_{}", zone)
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checker() -> DnsblChecker {
+        DnsblChecker::from_config(&DnsblConfig {
+            enabled: true,
+            lists: vec![
+                DnsblListConfig {
+                    zone: "Zen.Spamhaus.org.".to_string(),
+                    action: DnsblAction::Reject,
+                    weight: 1.0,
+                },
+                DnsblListConfig {
+                    zone: "bl.example.net".to_string(),
+                    action: DnsblAction::Score,
+                    weight: 2.5,
+                },
+            ],
+            ..DnsblConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_query_name() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(query_name(ip, "zen.spamhaus.org"), "1.2.0.192.zen.spamhaus.org.");
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            query_name(ip, "bl.example.net"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.example.net."
+        );
+        assert_eq!(rule_name("zen.spamhaus.org"), "DNSBL_ZEN_SPAMHAUS_ORG");
+    }
+
+    #[tokio::test]
+    async fn test_check_uses_cached_answers() {
+        let checker = checker();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let code = Ipv4Addr::new(127, 0, 0, 2);
+        checker.cache.insert(("zen.spamhaus.org".to_string(), ip), None);
+        checker.cache.insert(("bl.example.net".to_string(), ip), Some(code));
+
+        let verdict = checker.check(ip).await;
+        assert!(verdict.rejection().is_none());
+        let matches = verdict.rule_matches();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].rule_name, "DNSBL_BL_EXAMPLE_NET");
+        assert_eq!(matches[0].score, 2.5);
+
+        checker.cache.insert(("zen.spamhaus.org".to_string(), ip), Some(code));
+        let verdict = checker.check(ip).await;
+        let hit = verdict.rejection().unwrap();
+        assert_eq!(
+            reply(ip, hit),
+            "554 5.7.1 Service unavailable; client host [192.0.2.1] blocked using zen.spamhaus.org\r\n"
+        );
+        checker.record_rejection(hit);
+
+        let stats = checker.stats();
+        assert_eq!(stats.reject_at, DnsblStage::MailFrom);
+        let zen = &stats.lists[0];
+        assert_eq!((zen.zone.as_str(), zen.lookups, zen.hits, zen.rejected), ("zen.spamhaus.org", 2, 1, 1));
+        assert_eq!(stats.lists[1].hits, 2);
+        assert_eq!(stats.cache.hits, 4);
+    }
+
+    #[tokio::test]
+    async fn test_local_clients_not_looked_up() {
+        let checker = checker();
+        for ip in ["127.0.0.1", "10.1.2.3", "::1"] {
+            assert_eq!(checker.check(ip.parse().unwrap()).await, DnsblVerdict::default());
+        }
+        assert_eq!(checker.stats().lists[0].lookups, 0);
+    }
+}
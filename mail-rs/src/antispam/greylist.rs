@@ -1,10 +1,20 @@
+//! Greylisting of unknown SMTP clients
+//!
+//! With `[greylisting] enabled`, SMTP defers mail from an unseen sender,
+//! recipient and client IP triplet with 451 until the client retries after
+//! `delay_seconds`. Mail is checked at the end of DATA, once SPF and DKIM
+//! are known: mail passing either, and whitelisted senders or clients, is
+//! accepted at once. Clients retrying successfully `auto_whitelist_after`
+//! times are whitelisted.
+
 use anyhow::Result;
-use chrono::Utc;
-use std::collections::HashMap;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use sqlx::{Row, SqlitePool};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tracing::{error, info};
 
-use super::types::{GreylistEntry, GreylistStatus, ListEntry};
+use super::types::{GreylistEntry, GreylistStats, GreylistStatus, ListEntry};
+use crate::config::GreylistingConfig;
 
 /// Greylist manager configuration
 #[derive(Debug, Clone)]
@@ -15,6 +25,12 @@ pub struct GreylistConfig {
     pub auto_whitelist_days: i64,
     /// Cleanup entries older than N days (default: 30)
     pub cleanup_days: i64,
+    /// Pending triplets not retried within N hours expire (default: 24)
+    pub retry_window_hours: i64,
+    /// Whitelist a client IP after N successful retries (default: 5, 0 = never)
+    pub auto_whitelist_after: u32,
+    /// Skip greylisting for mail passing SPF or DKIM (default: true)
+    pub bypass_authenticated: bool,
 }
 
 impl Default for GreylistConfig {
@@ -23,37 +39,115 @@ impl Default for GreylistConfig {
             delay_seconds: 300,         // 5 minutes
             auto_whitelist_days: 7,     // 1 week
             cleanup_days: 30,           // 1 month
+            retry_window_hours: 24,     // 1 day
+            auto_whitelist_after: 5,
+            bypass_authenticated: true,
+        }
+    }
+}
+
+impl From<&GreylistingConfig> for GreylistConfig {
+    fn from(config: &GreylistingConfig) -> Self {
+        Self {
+            delay_seconds: config.delay_seconds,
+            retry_window_hours: config.retry_window_hours,
+            auto_whitelist_after: config.auto_whitelist_after,
+            bypass_authenticated: config.bypass_authenticated,
+            ..Self::default()
         }
     }
 }
 
 /// Greylist manager for anti-spam
+///
+/// Triplets, lists and counters live in SQLite so greylisting progress
+/// survives restarts.
 pub struct GreylistManager {
     config: GreylistConfig,
-    entries: Arc<RwLock<HashMap<String, GreylistEntry>>>,
-    whitelist: Arc<RwLock<Vec<ListEntry>>>,
-    blacklist: Arc<RwLock<Vec<ListEntry>>>,
+    db: SqlitePool,
 }
 
 impl GreylistManager {
     /// Create new greylist manager with default config
-    pub fn new() -> Self {
-        GreylistManager {
-            config: GreylistConfig::default(),
-            entries: Arc::new(RwLock::new(HashMap::new())),
-            whitelist: Arc::new(RwLock::new(Vec::new())),
-            blacklist: Arc::new(RwLock::new(Vec::new())),
-        }
+    pub fn new(db: SqlitePool) -> Self {
+        Self::with_config(db, GreylistConfig::default())
     }
 
     /// Create greylist manager with custom config
-    pub fn with_config(config: GreylistConfig) -> Self {
-        GreylistManager {
-            config,
-            entries: Arc::new(RwLock::new(HashMap::new())),
-            whitelist: Arc::new(RwLock::new(Vec::new())),
-            blacklist: Arc::new(RwLock::new(Vec::new())),
-        }
+    pub fn with_config(db: SqlitePool, config: GreylistConfig) -> Self {
+        GreylistManager { config, db }
+    }
+
+    /// Connect to `database_url` and create the greylist tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        Self::connect_with(database_url, GreylistConfig::default()).await
+    }
+
+    /// Like [`Self::connect`], with a custom config
+    pub async fn connect_with(database_url: &str, config: GreylistConfig) -> Result<Self> {
+        let manager = Self::with_config(SqlitePool::connect(database_url).await?, config);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS greylist_entries (
+                sender TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                client_ip TEXT NOT NULL,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 1,
+                passes INTEGER NOT NULL DEFAULT 0,
+                status TEXT NOT NULL,
+                PRIMARY KEY (sender, recipient, client_ip)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_greylist_entries_status ON greylist_entries(status, last_seen)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_greylist_entries_client_ip ON greylist_entries(client_ip)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS greylist_lists (
+                list TEXT NOT NULL,
+                pattern TEXT NOT NULL,
+                added_at TEXT NOT NULL,
+                reason TEXT,
+                PRIMARY KEY (list, pattern)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS greylist_counters (
+                name TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
     }
 
     /// Check if email should be accepted, greylisted, or rejected
@@ -62,178 +156,442 @@ impl GreylistManager {
         sender: &str,
         recipient: &str,
         client_ip: &str,
-    ) -> GreylistStatus {
+    ) -> Result<GreylistStatus> {
+        self.check_authenticated(sender, recipient, client_ip, false)
+            .await
+    }
+
+    /// Like [`Self::check`]; mail that `passed_auth` (SPF or DKIM) is
+    /// accepted without a triplet when `bypass_authenticated` is set
+    pub async fn check_authenticated(
+        &self,
+        sender: &str,
+        recipient: &str,
+        client_ip: &str,
+        passed_auth: bool,
+    ) -> Result<GreylistStatus> {
         // Check blacklist first
-        if self.is_blacklisted(sender).await {
-            return GreylistStatus::Blacklisted;
+        if self.is_blacklisted(sender).await? {
+            return Ok(GreylistStatus::Blacklisted);
         }
 
-        // Check whitelist
-        if self.is_whitelisted(sender).await {
-            return GreylistStatus::Whitelisted;
+        if passed_auth && self.config.bypass_authenticated {
+            self.increment_counter("bypassed", 1).await?;
+            return Ok(GreylistStatus::Whitelisted);
         }
 
-        // Check greylist entry
-        let key = format!("{}:{}:{}", sender, recipient, client_ip);
-        let mut entries = self.entries.write().await;
-
-        if let Some(entry) = entries.get_mut(&key) {
-            // Existing entry
-            entry.last_seen = Utc::now();
-            entry.attempts += 1;
-
-            if entry.should_whitelist(self.config.delay_seconds) {
-                entry.status = GreylistStatus::Whitelisted;
-            }
+        // Check whitelist (senders, or client IPs promoted automatically)
+        if self.is_whitelisted(sender).await? || self.is_whitelisted(client_ip).await? {
+            return Ok(GreylistStatus::Whitelisted);
+        }
 
-            entry.status.clone()
-        } else {
+        let now = Utc::now();
+        let Some(mut entry) = self.get_entry(sender, recipient, client_ip).await? else {
             // New sender triple - greylist it
             let entry = GreylistEntry::new(
                 sender.to_string(),
                 recipient.to_string(),
                 client_ip.to_string(),
             );
-            let status = entry.status.clone();
-            entries.insert(key, entry);
-            status
+            self.save_entry(&entry).await?;
+            return Ok(entry.status);
+        };
+
+        if entry.status == GreylistStatus::Greylisted
+            && entry.first_seen < now - Duration::hours(self.config.retry_window_hours)
+        {
+            // Retried too late: start over as if first seen now
+            self.increment_counter("expired", 1).await?;
+            entry = GreylistEntry::new(
+                sender.to_string(),
+                recipient.to_string(),
+                client_ip.to_string(),
+            );
+            self.save_entry(&entry).await?;
+            return Ok(entry.status);
         }
+
+        // Existing entry
+        entry.last_seen = now;
+        entry.attempts += 1;
+
+        if entry.status == GreylistStatus::Whitelisted {
+            entry.passes += 1;
+        } else if entry.should_whitelist(self.config.delay_seconds) {
+            entry.status = GreylistStatus::Whitelisted;
+            entry.passes = 1;
+        }
+        self.save_entry(&entry).await?;
+
+        if entry.status == GreylistStatus::Whitelisted {
+            self.maybe_auto_whitelist(client_ip).await?;
+        }
+
+        Ok(entry.status)
+    }
+
+    /// Whitelist a client IP once its triplets passed often enough
+    async fn maybe_auto_whitelist(&self, client_ip: &str) -> Result<()> {
+        let threshold = self.config.auto_whitelist_after;
+        if threshold == 0 {
+            return Ok(());
+        }
+
+        let passes: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(passes), 0) FROM greylist_entries WHERE client_ip = ?",
+        )
+        .bind(client_ip)
+        .fetch_one(&self.db)
+        .await?;
+
+        if passes >= threshold as i64 {
+            info!("Auto-whitelisting {} after {} successful retries", client_ip, passes);
+            self.add_to_list(
+                "whitelist",
+                ListEntry::with_reason(
+                    client_ip.to_string(),
+                    format!("Auto-whitelisted after {} successful retries", passes),
+                ),
+            )
+            .await?;
+        }
+        Ok(())
     }
 
     /// Check if sender is whitelisted
-    pub async fn is_whitelisted(&self, sender: &str) -> bool {
-        let whitelist = self.whitelist.read().await;
-        whitelist.iter().any(|entry| entry.matches(sender))
+    pub async fn is_whitelisted(&self, sender: &str) -> Result<bool> {
+        let whitelist = self.get_whitelist().await?;
+        Ok(whitelist.iter().any(|entry| entry.matches(sender)))
     }
 
     /// Check if sender is blacklisted
-    pub async fn is_blacklisted(&self, sender: &str) -> bool {
-        let blacklist = self.blacklist.read().await;
-        blacklist.iter().any(|entry| entry.matches(sender))
+    pub async fn is_blacklisted(&self, sender: &str) -> Result<bool> {
+        let blacklist = self.get_blacklist().await?;
+        Ok(blacklist.iter().any(|entry| entry.matches(sender)))
     }
 
     /// Add sender to whitelist
     pub async fn add_to_whitelist(&self, pattern: String, reason: Option<String>) -> Result<()> {
-        let mut whitelist = self.whitelist.write().await;
         let entry = if let Some(r) = reason {
             ListEntry::with_reason(pattern, r)
         } else {
             ListEntry::new(pattern)
         };
-        whitelist.push(entry);
-        Ok(())
+        self.add_to_list("whitelist", entry).await
     }
 
     /// Add sender to blacklist
     pub async fn add_to_blacklist(&self, pattern: String, reason: Option<String>) -> Result<()> {
-        let mut blacklist = self.blacklist.write().await;
         let entry = if let Some(r) = reason {
             ListEntry::with_reason(pattern, r)
         } else {
             ListEntry::new(pattern)
         };
-        blacklist.push(entry);
-        Ok(())
+        self.add_to_list("blacklist", entry).await
     }
 
     /// Remove from whitelist
     pub async fn remove_from_whitelist(&self, pattern: &str) -> Result<()> {
-        let mut whitelist = self.whitelist.write().await;
-        whitelist.retain(|entry| entry.pattern != pattern);
-        Ok(())
+        self.remove_from_list("whitelist", pattern).await
     }
 
     /// Remove from blacklist
     pub async fn remove_from_blacklist(&self, pattern: &str) -> Result<()> {
-        let mut blacklist = self.blacklist.write().await;
-        blacklist.retain(|entry| entry.pattern != pattern);
-        Ok(())
+        self.remove_from_list("blacklist", pattern).await
     }
 
     /// Get whitelist
-    pub async fn get_whitelist(&self) -> Vec<ListEntry> {
-        let whitelist = self.whitelist.read().await;
-        whitelist.clone()
+    pub async fn get_whitelist(&self) -> Result<Vec<ListEntry>> {
+        self.get_list("whitelist").await
     }
 
     /// Get blacklist
-    pub async fn get_blacklist(&self) -> Vec<ListEntry> {
-        let blacklist = self.blacklist.read().await;
-        blacklist.clone()
+    pub async fn get_blacklist(&self) -> Result<Vec<ListEntry>> {
+        self.get_list("blacklist").await
     }
 
-    /// Cleanup old greylist entries
+    /// Expire stale greylist entries
+    ///
+    /// Pending triplets not retried within the retry window are removed and
+    /// counted as expired; passed triplets are removed once unused for
+    /// `cleanup_days`.
     pub async fn cleanup_old_entries(&self) -> Result<usize> {
-        let mut entries = self.entries.write().await;
-        let cutoff = Utc::now() - chrono::Duration::days(self.config.cleanup_days);
-
-        let initial_count = entries.len();
-        entries.retain(|_, entry| entry.last_seen > cutoff);
-        let removed = initial_count - entries.len();
+        let now = Utc::now();
+        let retry_cutoff = timestamp(now - Duration::hours(self.config.retry_window_hours));
+        let cleanup_cutoff = timestamp(now - Duration::days(self.config.cleanup_days));
+
+        let expired = sqlx::query(
+            "DELETE FROM greylist_entries WHERE status = 'greylisted' AND first_seen < ?",
+        )
+        .bind(&retry_cutoff)
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+        self.increment_counter("expired", expired as i64).await?;
+
+        let unused = sqlx::query("DELETE FROM greylist_entries WHERE last_seen < ?")
+            .bind(&cleanup_cutoff)
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+
+        Ok((expired + unused) as usize)
+    }
 
-        Ok(removed)
+    /// Run [`Self::cleanup_old_entries`] every `interval`
+    pub async fn run_cleanup(self: Arc<Self>, interval: std::time::Duration) {
+        loop {
+            match self.cleanup_old_entries().await {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} stale greylist entries", removed),
+                Err(e) => error!("Greylist cleanup failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
     }
 
     /// Get greylist entry count
-    pub async fn entry_count(&self) -> usize {
-        let entries = self.entries.read().await;
-        entries.len()
+    pub async fn entry_count(&self) -> Result<usize> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM greylist_entries")
+            .fetch_one(&self.db)
+            .await?;
+        Ok(count as usize)
     }
 
     /// Get all greylist entries (for admin view)
-    pub async fn get_entries(&self) -> Vec<GreylistEntry> {
-        let entries = self.entries.read().await;
-        entries.values().cloned().collect()
+    pub async fn get_entries(&self) -> Result<Vec<GreylistEntry>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT sender, recipient, client_ip, first_seen, last_seen, attempts, passes, status
+            FROM greylist_entries
+            ORDER BY last_seen DESC
+            "#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Pending, passed and expired triplet counts and list sizes
+    pub async fn stats(&self) -> Result<GreylistStats> {
+        let (pending, passed): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT
+                COALESCE(SUM(CASE WHEN status = 'greylisted' THEN 1 ELSE 0 END), 0),
+                COALESCE(SUM(CASE WHEN status = 'whitelisted' THEN 1 ELSE 0 END), 0)
+            FROM greylist_entries
+            "#,
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        let counter = |name: &'static str| {
+            sqlx::query_scalar::<_, i64>("SELECT value FROM greylist_counters WHERE name = ?")
+                .bind(name)
+                .fetch_optional(&self.db)
+        };
+        let expired = counter("expired").await?;
+        let bypassed = counter("bypassed").await?;
+
+        Ok(GreylistStats {
+            pending: pending as usize,
+            passed: passed as usize,
+            expired: expired.unwrap_or(0) as usize,
+            bypassed: bypassed.unwrap_or(0) as usize,
+            whitelisted: self.get_whitelist().await?.len(),
+            blacklisted: self.get_blacklist().await?.len(),
+        })
+    }
+
+    async fn get_entry(
+        &self,
+        sender: &str,
+        recipient: &str,
+        client_ip: &str,
+    ) -> Result<Option<GreylistEntry>> {
+        let row = sqlx::query(
+            r#"
+            SELECT sender, recipient, client_ip, first_seen, last_seen, attempts, passes, status
+            FROM greylist_entries
+            WHERE sender = ? AND recipient = ? AND client_ip = ?
+            "#,
+        )
+        .bind(sender)
+        .bind(recipient)
+        .bind(client_ip)
+        .fetch_optional(&self.db)
+        .await?;
+
+        row.as_ref().map(entry_from_row).transpose()
+    }
+
+    async fn save_entry(&self, entry: &GreylistEntry) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO greylist_entries
+                (sender, recipient, client_ip, first_seen, last_seen, attempts, passes, status)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&entry.sender)
+        .bind(&entry.recipient)
+        .bind(&entry.client_ip)
+        .bind(timestamp(entry.first_seen))
+        .bind(timestamp(entry.last_seen))
+        .bind(entry.attempts as i64)
+        .bind(entry.passes as i64)
+        .bind(status_str(&entry.status))
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn add_to_list(&self, list: &str, entry: ListEntry) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO greylist_lists (list, pattern, added_at, reason) VALUES (?, ?, ?, ?)",
+        )
+        .bind(list)
+        .bind(&entry.pattern)
+        .bind(timestamp(entry.added_at))
+        .bind(&entry.reason)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_from_list(&self, list: &str, pattern: &str) -> Result<()> {
+        sqlx::query("DELETE FROM greylist_lists WHERE list = ? AND pattern = ?")
+            .bind(list)
+            .bind(pattern)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_list(&self, list: &str) -> Result<Vec<ListEntry>> {
+        let rows = sqlx::query(
+            "SELECT pattern, added_at, reason FROM greylist_lists WHERE list = ? ORDER BY added_at",
+        )
+        .bind(list)
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(ListEntry {
+                    pattern: row.get("pattern"),
+                    added_at: parse_timestamp(row.get("added_at"))?,
+                    reason: row.get("reason"),
+                })
+            })
+            .collect()
+    }
+
+    async fn increment_counter(&self, name: &str, by: i64) -> Result<()> {
+        if by == 0 {
+            return Ok(());
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO greylist_counters (name, value) VALUES (?, ?)
+            ON CONFLICT(name) DO UPDATE SET value = value + excluded.value
+            "#,
+        )
+        .bind(name)
+        .bind(by)
+        .execute(&self.db)
+        .await?;
+        Ok(())
     }
 }
 
-impl Default for GreylistManager {
-    fn default() -> Self {
-        Self::new()
+fn entry_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<GreylistEntry> {
+    Ok(GreylistEntry {
+        sender: row.get("sender"),
+        recipient: row.get("recipient"),
+        client_ip: row.get("client_ip"),
+        first_seen: parse_timestamp(row.get("first_seen"))?,
+        last_seen: parse_timestamp(row.get("last_seen"))?,
+        attempts: row.get::<i64, _>("attempts") as u32,
+        passes: row.get::<i64, _>("passes") as u32,
+        status: match row.get::<String, _>("status").as_str() {
+            "whitelisted" => GreylistStatus::Whitelisted,
+            "blacklisted" => GreylistStatus::Blacklisted,
+            _ => GreylistStatus::Greylisted,
+        },
+    })
+}
+
+fn status_str(status: &GreylistStatus) -> &'static str {
+    match status {
+        GreylistStatus::Greylisted => "greylisted",
+        GreylistStatus::Whitelisted => "whitelisted",
+        GreylistStatus::Blacklisted => "blacklisted",
     }
 }
 
+/// Fixed-width UTC timestamps, so they compare correctly as text
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_timestamp(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    async fn manager_with(config: GreylistConfig) -> GreylistManager {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = GreylistManager::with_config(db, config);
+        manager.init_db().await.unwrap();
+        manager
+    }
+
+    async fn manager() -> GreylistManager {
+        manager_with(GreylistConfig::default()).await
+    }
+
     #[tokio::test]
     async fn test_greylist_manager_new() {
-        let manager = GreylistManager::new();
-        assert_eq!(manager.entry_count().await, 0);
+        let manager = manager().await;
+        assert_eq!(manager.entry_count().await.unwrap(), 0);
     }
 
     #[tokio::test]
     async fn test_check_new_sender_greylisted() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         let status = manager
             .check("sender@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
 
         assert_eq!(status, GreylistStatus::Greylisted);
-        assert_eq!(manager.entry_count().await, 1);
+        assert_eq!(manager.entry_count().await.unwrap(), 1);
     }
 
     #[tokio::test]
     async fn test_check_retry_still_greylisted() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .check("sender@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
 
         // Immediate retry - still greylisted
         let status = manager
             .check("sender@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
 
         assert_eq!(status, GreylistStatus::Greylisted);
     }
 
     #[tokio::test]
     async fn test_check_whitelisted_sender() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_whitelist("sender@example.com".to_string(), None)
@@ -242,14 +600,14 @@ mod tests {
 
         let status = manager
             .check("sender@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
 
         assert_eq!(status, GreylistStatus::Whitelisted);
     }
 
     #[tokio::test]
     async fn test_check_blacklisted_sender() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_blacklist("spam@example.com".to_string(), Some("Spammer".to_string()))
@@ -258,74 +616,74 @@ mod tests {
 
         let status = manager
             .check("spam@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
 
         assert_eq!(status, GreylistStatus::Blacklisted);
     }
 
     #[tokio::test]
     async fn test_whitelist_domain() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_whitelist("@trusted.com".to_string(), None)
             .await
             .unwrap();
 
-        assert!(manager.is_whitelisted("anyone@trusted.com").await);
-        assert!(!manager.is_whitelisted("user@other.com").await);
+        assert!(manager.is_whitelisted("anyone@trusted.com").await.unwrap());
+        assert!(!manager.is_whitelisted("user@other.com").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_blacklist_domain() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_blacklist("@spam.com".to_string(), None)
             .await
             .unwrap();
 
-        assert!(manager.is_blacklisted("anyone@spam.com").await);
-        assert!(!manager.is_blacklisted("user@other.com").await);
+        assert!(manager.is_blacklisted("anyone@spam.com").await.unwrap());
+        assert!(!manager.is_blacklisted("user@other.com").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_remove_from_whitelist() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_whitelist("user@example.com".to_string(), None)
             .await
             .unwrap();
-        assert!(manager.is_whitelisted("user@example.com").await);
+        assert!(manager.is_whitelisted("user@example.com").await.unwrap());
 
         manager
             .remove_from_whitelist("user@example.com")
             .await
             .unwrap();
-        assert!(!manager.is_whitelisted("user@example.com").await);
+        assert!(!manager.is_whitelisted("user@example.com").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_remove_from_blacklist() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_blacklist("user@example.com".to_string(), None)
             .await
             .unwrap();
-        assert!(manager.is_blacklisted("user@example.com").await);
+        assert!(manager.is_blacklisted("user@example.com").await.unwrap());
 
         manager
             .remove_from_blacklist("user@example.com")
             .await
             .unwrap();
-        assert!(!manager.is_blacklisted("user@example.com").await);
+        assert!(!manager.is_blacklisted("user@example.com").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_get_whitelist() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_whitelist("user1@example.com".to_string(), None)
@@ -336,13 +694,13 @@ mod tests {
             .await
             .unwrap();
 
-        let whitelist = manager.get_whitelist().await;
+        let whitelist = manager.get_whitelist().await.unwrap();
         assert_eq!(whitelist.len(), 2);
     }
 
     #[tokio::test]
     async fn test_get_blacklist() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .add_to_blacklist("spam1@example.com".to_string(), None)
@@ -353,7 +711,7 @@ mod tests {
             .await
             .unwrap();
 
-        let blacklist = manager.get_blacklist().await;
+        let blacklist = manager.get_blacklist().await.unwrap();
         assert_eq!(blacklist.len(), 2);
     }
 
@@ -361,33 +719,33 @@ mod tests {
     async fn test_cleanup_old_entries() {
         let mut config = GreylistConfig::default();
         config.cleanup_days = 1; // 1 day
-        let manager = GreylistManager::with_config(config);
+        let manager = manager_with(config).await;
 
         // Add entry
         manager
             .check("sender@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
 
-        assert_eq!(manager.entry_count().await, 1);
+        assert_eq!(manager.entry_count().await.unwrap(), 1);
 
         // Cleanup (entry is recent, should not be removed)
         let removed = manager.cleanup_old_entries().await.unwrap();
         assert_eq!(removed, 0);
-        assert_eq!(manager.entry_count().await, 1);
+        assert_eq!(manager.entry_count().await.unwrap(), 1);
     }
 
     #[tokio::test]
     async fn test_get_entries() {
-        let manager = GreylistManager::new();
+        let manager = manager().await;
 
         manager
             .check("sender1@example.com", "recipient@test.com", "192.0.2.1")
-            .await;
+            .await.unwrap();
         manager
             .check("sender2@example.com", "recipient@test.com", "192.0.2.2")
-            .await;
+            .await.unwrap();
 
-        let entries = manager.get_entries().await;
+        let entries = manager.get_entries().await.unwrap();
         assert_eq!(entries.len(), 2);
     }
 
@@ -397,10 +755,134 @@ mod tests {
             delay_seconds: 60,
             auto_whitelist_days: 14,
             cleanup_days: 60,
+            ..Default::default()
         };
 
-        let manager = GreylistManager::with_config(config.clone());
+        let manager = manager_with(config.clone()).await;
         assert_eq!(manager.config.delay_seconds, 60);
         assert_eq!(manager.config.auto_whitelist_days, 14);
     }
+
+    /// Backdate a triplet's first sighting
+    async fn age_entry(manager: &GreylistManager, sender: &str, by: Duration) {
+        sqlx::query("UPDATE greylist_entries SET first_seen = ? WHERE sender = ?")
+            .bind(timestamp(Utc::now() - by))
+            .bind(sender)
+            .execute(&manager.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entries_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("greylist.db").display());
+
+        let manager = GreylistManager::connect(&url).await.unwrap();
+        manager
+            .check("sender@example.com", "recipient@test.com", "192.0.2.1")
+            .await
+            .unwrap();
+        manager
+            .add_to_blacklist("@spam.com".to_string(), Some("Spammer".to_string()))
+            .await
+            .unwrap();
+        age_entry(&manager, "sender@example.com", Duration::seconds(400)).await;
+        drop(manager);
+
+        // The retry after the delay passes on a fresh manager
+        let manager = GreylistManager::connect(&url).await.unwrap();
+        let status = manager
+            .check("sender@example.com", "recipient@test.com", "192.0.2.1")
+            .await
+            .unwrap();
+        assert_eq!(status, GreylistStatus::Whitelisted);
+        assert!(manager.is_blacklisted("bot@spam.com").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_auto_whitelist_after_successful_retries() {
+        let config = GreylistConfig {
+            auto_whitelist_after: 3,
+            ..Default::default()
+        };
+        let manager = manager_with(config).await;
+
+        for sender in ["a@example.com", "b@example.com"] {
+            manager
+                .check(sender, "recipient@test.com", "192.0.2.1")
+                .await
+                .unwrap();
+            age_entry(&manager, sender, Duration::seconds(400)).await;
+        }
+
+        // Two passes, then a third accepted retry
+        manager.check("a@example.com", "recipient@test.com", "192.0.2.1").await.unwrap();
+        manager.check("b@example.com", "recipient@test.com", "192.0.2.1").await.unwrap();
+        assert!(!manager.is_whitelisted("192.0.2.1").await.unwrap());
+        manager.check("a@example.com", "recipient@test.com", "192.0.2.1").await.unwrap();
+        assert!(manager.is_whitelisted("192.0.2.1").await.unwrap());
+
+        // Any sender from that client is now accepted at once
+        let status = manager
+            .check("new@example.org", "recipient@test.com", "192.0.2.1")
+            .await
+            .unwrap();
+        assert_eq!(status, GreylistStatus::Whitelisted);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_and_stats() {
+        let manager = manager().await;
+
+        manager.check("stale@example.com", "recipient@test.com", "192.0.2.1").await.unwrap();
+        manager.check("fresh@example.com", "recipient@test.com", "192.0.2.2").await.unwrap();
+        manager.check("passed@example.com", "recipient@test.com", "192.0.2.3").await.unwrap();
+        age_entry(&manager, "passed@example.com", Duration::seconds(400)).await;
+        manager.check("passed@example.com", "recipient@test.com", "192.0.2.3").await.unwrap();
+
+        // Never retried within the retry window
+        age_entry(&manager, "stale@example.com", Duration::hours(25)).await;
+        assert_eq!(manager.cleanup_old_entries().await.unwrap(), 1);
+
+        let stats = manager.stats().await.unwrap();
+        assert_eq!(stats.pending, 1);
+        assert_eq!(stats.passed, 1);
+        assert_eq!(stats.expired, 1);
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_mail_bypasses_greylisting() {
+        let manager = manager().await;
+
+        let status = manager
+            .check_authenticated("a@example.com", "recipient@test.com", "192.0.2.1", true)
+            .await
+            .unwrap();
+        assert_eq!(status, GreylistStatus::Whitelisted);
+        assert_eq!(manager.entry_count().await.unwrap(), 0);
+        assert_eq!(manager.stats().await.unwrap().bypassed, 1);
+
+        // Blacklisted senders are refused even when authenticated
+        manager
+            .add_to_blacklist("@spam.test".to_string(), None)
+            .await
+            .unwrap();
+        let status = manager
+            .check_authenticated("x@spam.test", "recipient@test.com", "192.0.2.1", true)
+            .await
+            .unwrap();
+        assert_eq!(status, GreylistStatus::Blacklisted);
+
+        let strict = manager_with(GreylistConfig {
+            bypass_authenticated: false,
+            ..Default::default()
+        })
+        .await;
+        let status = strict
+            .check_authenticated("a@example.com", "recipient@test.com", "192.0.2.1", true)
+            .await
+            .unwrap();
+        assert_eq!(status, GreylistStatus::Greylisted);
+    }
 }
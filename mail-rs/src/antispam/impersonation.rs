@@ -0,0 +1,467 @@
+//! Display-name impersonation protection
+//!
+//! "CEO fraud" mail shows the name of someone inside the organization with
+//! an outside address, e.g. `"Jane Doe" <jane.doe.ceo@freemail.example>`.
+//! Admins keep a list of protected names per domain. Inbound mail to a
+//! recipient of that domain is flagged when its `From:` display name
+//! matches a protected name, or contains an address of the domain, while
+//! the sender address is outside the domain.
+//!
+//! Flagged mail gets an [`IMPERSONATION_HEADER`] warning, which the spam
+//! filter scores (rule `DISPLAY_NAME_SPOOF`), and is delivered to the Junk
+//! folder when the domain asks for quarantine. Names are compared case-,
+//! punctuation- and word-order-insensitively, with common look-alike
+//! letters (Cyrillic `а`, Greek `ο`, ...) folded to Latin.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+use std::collections::BTreeSet;
+
+use super::types::{Impersonation, ImpersonationPolicy};
+
+/// Impersonation policies and the check run on inbound mail
+pub struct ImpersonationGuard {
+    db: SqlitePool,
+}
+
+impl ImpersonationGuard {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let guard = Self::new(SqlitePool::connect(database_url).await?);
+        guard.init_db().await?;
+        Ok(guard)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS impersonation_domains (
+                domain TEXT PRIMARY KEY,
+                quarantine INTEGER NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS impersonation_names (
+                domain TEXT NOT NULL,
+                name TEXT NOT NULL,
+                normalized TEXT NOT NULL,
+                PRIMARY KEY (domain, normalized)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Policies of all domains with protected names or quarantine set
+    pub async fn policies(&self) -> Result<Vec<ImpersonationPolicy>> {
+        let domains: Vec<String> =
+            sqlx::query_scalar("SELECT domain FROM impersonation_domains ORDER BY domain")
+                .fetch_all(&self.db)
+                .await?;
+
+        let mut policies = Vec::new();
+        for domain in domains {
+            policies.extend(self.policy(&domain).await?);
+        }
+        Ok(policies)
+    }
+
+    /// Policy of one domain
+    pub async fn policy(&self, domain: &str) -> Result<Option<ImpersonationPolicy>> {
+        let domain = domain.to_lowercase();
+        let Some(row) = sqlx::query(
+            "SELECT quarantine, updated_at FROM impersonation_domains WHERE domain = ?",
+        )
+        .bind(&domain)
+        .fetch_optional(&self.db)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let names = sqlx::query_scalar(
+            "SELECT name FROM impersonation_names WHERE domain = ? ORDER BY name",
+        )
+        .bind(&domain)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(Some(ImpersonationPolicy {
+            domain,
+            names,
+            quarantine: row.get::<i64, _>("quarantine") != 0,
+            updated_at: parse_timestamp(row.get("updated_at"))?,
+        }))
+    }
+
+    /// Turn quarantine of impersonating mail to `domain` on or off
+    pub async fn set_quarantine(
+        &self,
+        domain: &str,
+        quarantine: bool,
+    ) -> Result<ImpersonationPolicy> {
+        let domain = domain.to_lowercase();
+        sqlx::query(
+            r#"
+            INSERT INTO impersonation_domains (domain, quarantine, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(domain) DO UPDATE SET
+                quarantine = excluded.quarantine,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&domain)
+        .bind(quarantine)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        self.expect_policy(&domain).await
+    }
+
+    /// Protect a name in `domain`
+    pub async fn add_name(&self, domain: &str, name: &str) -> Result<ImpersonationPolicy> {
+        let domain = domain.to_lowercase();
+        let name = name.trim();
+        let normalized = normalize_name(name);
+        if normalized.is_empty() {
+            return Err(anyhow!("Name contains no letters or digits"));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO impersonation_domains (domain, quarantine, updated_at)
+            VALUES (?, 0, ?)
+            ON CONFLICT(domain) DO UPDATE SET updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&domain)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO impersonation_names (domain, name, normalized) VALUES (?, ?, ?)",
+        )
+        .bind(&domain)
+        .bind(name)
+        .bind(&normalized)
+        .execute(&self.db)
+        .await?;
+
+        self.expect_policy(&domain).await
+    }
+
+    /// Stop protecting a name; returns whether it was protected
+    pub async fn remove_name(&self, domain: &str, name: &str) -> Result<bool> {
+        let result =
+            sqlx::query("DELETE FROM impersonation_names WHERE domain = ? AND normalized = ?")
+                .bind(domain.to_lowercase())
+                .bind(normalize_name(name))
+                .execute(&self.db)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remove the policy and names of a domain; returns whether it had one
+    pub async fn remove_domain(&self, domain: &str) -> Result<bool> {
+        let domain = domain.to_lowercase();
+        sqlx::query("DELETE FROM impersonation_names WHERE domain = ?")
+            .bind(&domain)
+            .execute(&self.db)
+            .await?;
+        let result = sqlx::query("DELETE FROM impersonation_domains WHERE domain = ?")
+            .bind(&domain)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Check an inbound message against the policies of its recipients'
+    /// domains
+    pub async fn check(&self, message: &[u8], recipients: &[String]) -> Result<Vec<Impersonation>> {
+        let Some((display_name, address)) = from_header(message) else {
+            return Ok(Vec::new());
+        };
+        if display_name.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let domains: BTreeSet<String> = recipients
+            .iter()
+            .filter_map(|recipient| recipient.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_lowercase())
+            .collect();
+
+        let mut found = Vec::new();
+        for domain in domains {
+            let Some(policy) = self.policy(&domain).await? else {
+                continue;
+            };
+            if let Some(protected_name) =
+                impersonated_name(&display_name, &address, &domain, &policy.names)
+            {
+                found.push(Impersonation {
+                    display_name: display_name.clone(),
+                    address: address.clone(),
+                    domain,
+                    protected_name,
+                    quarantine: policy.quarantine,
+                });
+            }
+        }
+        Ok(found)
+    }
+
+    async fn expect_policy(&self, domain: &str) -> Result<ImpersonationPolicy> {
+        self.policy(domain)
+            .await?
+            .ok_or_else(|| anyhow!("Policy of {} disappeared", domain))
+    }
+}
+
+/// Protected name of `domain` that `display_name` impersonates, if the
+/// sender `address` is outside the domain
+pub fn impersonated_name(
+    display_name: &str,
+    address: &str,
+    domain: &str,
+    protected_names: &[String],
+) -> Option<String> {
+    if is_in_domain(address, domain) {
+        return None;
+    }
+
+    // "jane.doe@example.com" <attacker@freemail.example>
+    let shows_internal_address = display_name
+        .split(|c: char| c.is_whitespace() || "<>()\"',;".contains(c))
+        .any(|word| word.contains('@') && is_in_domain(word, domain));
+    if shows_internal_address {
+        return Some(display_name.to_string());
+    }
+
+    let normalized = normalize_name(display_name);
+    protected_names
+        .iter()
+        .find(|name| !normalized.is_empty() && normalize_name(name) == normalized)
+        .cloned()
+}
+
+/// Whether `address` belongs to `domain` or one of its subdomains
+fn is_in_domain(address: &str, domain: &str) -> bool {
+    let Some((_, address_domain)) = address.rsplit_once('@') else {
+        return false;
+    };
+    let address_domain = address_domain.trim_end_matches('.').to_lowercase();
+    let domain = domain.to_lowercase();
+    address_domain == domain || address_domain.ends_with(&format!(".{}", domain))
+}
+
+/// Name reduced to lower-case words in sorted order, with look-alike
+/// letters folded to Latin and punctuation dropped
+pub fn normalize_name(name: &str) -> String {
+    let folded: String = name
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(fold_confusable)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+
+    let mut words: Vec<&str> = folded.split_whitespace().collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+/// Latin letter a Cyrillic or Greek look-alike is commonly mistaken for
+fn fold_confusable(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'е' | 'ё' | 'ε' => 'e',
+        'һ' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'н' | 'η' => 'n',
+        'о' | 'ο' | 'σ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'у' | 'υ' => 'y',
+        'х' | 'χ' => 'x',
+        _ => c,
+    }
+}
+
+/// Display name and address of the first `From:` header
+pub(crate) fn from_header(message: &[u8]) -> Option<(String, String)> {
+    let text = String::from_utf8_lossy(message);
+    let mut value: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+        match &mut value {
+            Some(value) if line.starts_with([' ', '\t']) => {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            Some(_) => break,
+            None => {
+                if let Some((name, rest)) = line.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("From") {
+                        value = Some(rest.trim().to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    let value = value?;
+    let unquote = |name: &str| -> String {
+        name.trim()
+            .trim_matches('"')
+            .replace("\\\"", "\"")
+            .trim()
+            .to_string()
+    };
+    match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => Some((
+            unquote(&value[..start]),
+            value[start + 1..end].trim().to_string(),
+        )),
+        // jane@example.com (Jane Doe)
+        _ => match value.split_once('(') {
+            Some((address, comment)) => Some((
+                unquote(comment.trim_end_matches(')')),
+                address.trim().to_string(),
+            )),
+            None => Some((String::new(), value.trim().to_string())),
+        },
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(from: &str) -> Vec<u8> {
+        format!(
+            "From: {}\r\nSubject: Wire transfer\r\n\r\nPlease pay today.\r\n",
+            from
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_impersonated_name() {
+        let names = vec!["Jane Doe".to_string()];
+        let check = |display: &str, address: &str| {
+            impersonated_name(display, address, "example.com", &names)
+        };
+
+        assert_eq!(
+            check("Jane Doe", "ceo@freemail.test"),
+            Some("Jane Doe".to_string())
+        );
+        assert!(check("DOE, Jane", "ceo@freemail.test").is_some());
+        // Cyrillic "а" and "о"
+        assert!(check("Jаne Dое", "ceo@freemail.test").is_some());
+        assert!(check("jane.doe@example.com", "x@freemail.test").is_some());
+
+        assert_eq!(check("Jane Doe", "jane@example.com"), None);
+        assert_eq!(check("Jane Doe", "jane@mail.example.com"), None);
+        assert_eq!(check("Jane Doerr", "x@freemail.test"), None);
+        assert!(check("Jane Doe", "x@notexample.com").is_some());
+    }
+
+    #[test]
+    fn test_from_header() {
+        assert_eq!(
+            from_header(&message("\"Doe, Jane\" <ceo@freemail.test>")),
+            Some(("Doe, Jane".to_string(), "ceo@freemail.test".to_string()))
+        );
+        assert_eq!(
+            from_header(&message("ceo@freemail.test (Jane Doe)")),
+            Some(("Jane Doe".to_string(), "ceo@freemail.test".to_string()))
+        );
+        assert_eq!(
+            from_header(b"Subject: x\r\nFrom: Jane\r\n Doe <a@b.test>\r\n\r\nFrom: body <c@d>"),
+            Some(("Jane Doe".to_string(), "a@b.test".to_string()))
+        );
+        assert_eq!(from_header(b"Subject: x\r\n\r\nFrom: body <c@d>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_policies_and_check() {
+        let guard = ImpersonationGuard::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        guard.add_name("Example.com", "Jane Doe").await.unwrap();
+        guard.add_name("example.com", "John Roe").await.unwrap();
+        assert!(guard.add_name("example.com", " ,. ").await.is_err());
+        let policy = guard.set_quarantine("example.com", true).await.unwrap();
+        assert_eq!(policy.names, ["Jane Doe", "John Roe"]);
+        assert!(policy.quarantine);
+
+        let recipients = vec![
+            "bob@example.com".to_string(),
+            "carol@other.test".to_string(),
+        ];
+        let found = guard
+            .check(&message("Jane Doe <ceo@freemail.test>"), &recipients)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].domain, "example.com");
+        assert!(found[0].quarantine);
+        assert!(found[0].header().starts_with(
+            "X-Impersonation-Warning: display name \"Jane Doe\" matches Jane Doe of example.com"
+        ));
+
+        assert!(guard
+            .check(&message("Jane Doe <jane@example.com>"), &recipients)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(guard
+            .check(&message("<ceo@freemail.test>"), &recipients)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert!(guard.remove_name("example.com", "doe jane").await.unwrap());
+        assert!(!guard.remove_name("example.com", "Jane Doe").await.unwrap());
+        assert!(guard
+            .check(&message("Jane Doe <ceo@freemail.test>"), &recipients)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(guard.policies().await.unwrap().len(), 1);
+        assert!(guard.remove_domain("example.com").await.unwrap());
+        assert!(guard.policies().await.unwrap().is_empty());
+    }
+}
@@ -1,9 +1,19 @@
 /// Anti-spam module
 ///
-/// Provides greylisting and whitelist/blacklist management
+/// Provides greylisting, whitelist/blacklist management, DNS and URI
+/// blocklist checks and display-name impersonation protection
 
+pub mod dnsbl;
 pub mod greylist;
+pub mod impersonation;
 pub mod types;
+pub mod uribl;
 
+pub use dnsbl::DnsblChecker;
 pub use greylist::GreylistManager;
-pub use types::{GreylistEntry, GreylistStatus, ListEntry};
+pub use impersonation::ImpersonationGuard;
+pub use uribl::UriblChecker;
+pub use types::{
+    GreylistEntry, GreylistStats, GreylistStatus, Impersonation, ImpersonationPolicy, ListEntry,
+    IMPERSONATION_HEADER,
+};
@@ -16,6 +16,9 @@ pub struct GreylistEntry {
     pub last_seen: DateTime<Utc>,
     /// Number of delivery attempts
     pub attempts: u32,
+    /// Attempts accepted once the greylist delay had passed
+    #[serde(default)]
+    pub passes: u32,
     /// Current status
     pub status: GreylistStatus,
 }
@@ -31,6 +34,7 @@ impl GreylistEntry {
             first_seen: now,
             last_seen: now,
             attempts: 1,
+            passes: 0,
             status: GreylistStatus::Greylisted,
         }
     }
@@ -57,6 +61,25 @@ pub enum GreylistStatus {
     Blacklisted, // Permanently blocked
 }
 
+/// Greylist counters for the admin dashboard
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GreylistStats {
+    /// Triplets waiting for a retry
+    pub pending: usize,
+    /// Triplets that retried after the delay and are accepted
+    pub passed: usize,
+    /// Triplets never retried within the retry window, since the start
+    pub expired: usize,
+    /// Messages passing SPF or DKIM accepted without greylisting, since
+    /// the start
+    #[serde(default)]
+    pub bypassed: usize,
+    /// Whitelist entries, including auto-whitelisted client IPs
+    pub whitelisted: usize,
+    /// Blacklist entries
+    pub blacklisted: usize,
+}
+
 /// Whitelist/Blacklist entry
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListEntry {
@@ -102,6 +125,56 @@ impl ListEntry {
     }
 }
 
+/// Display names protected against impersonation in one domain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImpersonationPolicy {
+    /// Protected domain
+    pub domain: String,
+    /// Names of people in the domain, as entered
+    pub names: Vec<String>,
+    /// Deliver impersonating mail to the Junk folder instead of the inbox
+    pub quarantine: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Mail using a protected display name from outside its domain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Impersonation {
+    /// Display name of the `From:` header
+    pub display_name: String,
+    /// Address of the `From:` header
+    pub address: String,
+    /// Domain the display name is protected in
+    pub domain: String,
+    /// Protected name that matched
+    pub protected_name: String,
+    /// Whether the domain quarantines such mail
+    pub quarantine: bool,
+}
+
+impl Impersonation {
+    /// Warning header added to the message
+    pub fn header(&self) -> String {
+        let clean = |value: &str| -> String {
+            value
+                .chars()
+                .filter(|c| !c.is_control() && *c != '"')
+                .collect()
+        };
+        format!(
+            "{}: display name \"{}\" matches {} of {}, but the sender {} is external\r\n",
+            IMPERSONATION_HEADER,
+            clean(&self.display_name),
+            clean(&self.protected_name),
+            self.domain,
+            clean(&self.address)
+        )
+    }
+}
+
+/// Header flagging impersonating mail, scored by the spam filter
+pub const IMPERSONATION_HEADER: &str = "X-Impersonation-Warning";
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,335 @@
+//! URI blocklist (URIBL/SURBL) checks of message bodies
+//!
+//! With `[uribl] enabled`, the domains of the links in the text and HTML
+//! bodies of mail scored by the built-in spam filter are looked up in the
+//! configured lists: `spam.example.multi.surbl.org` for a link to
+//! `https://www.spam.example/offer`. Links to IP addresses are looked up
+//! reversed, like DNSBL queries. An A record in 127.0.0.0/8 means the domain
+//! is listed, and the list's `weight` is added to the spam score. The
+//! domains that matched are reported in the `X-Spam-Report` header.
+//!
+//! Domains are reduced to their last two labels (three under two-letter
+//! second-level domains like `co.uk`), and at most `max_domains` of them are
+//! looked up per message. Answers are cached for `cache_ttl_secs`.
+
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use super::dnsbl;
+use crate::authentication::TtlCache;
+use crate::config::{UriblConfig, UriblListConfig};
+use crate::mime::ParsedEmail;
+use crate::spam::SpamRuleMatch;
+
+/// Maximum number of cached answers
+const CACHE_CAPACITY: usize = 50_000;
+
+/// Hosts of `http(s)://` links and bare `www.` ones
+static LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)(?:https?://(?:[^/\s@]*@)?|\bwww\.)([a-z0-9](?:[a-z0-9.-]*[a-z0-9])?)")
+        .expect("valid link pattern")
+});
+
+/// A listed domain of the message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UriblHit {
+    pub zone: String,
+    pub domain: String,
+    /// Return code of the list, e.g. 127.0.0.2
+    pub code: Ipv4Addr,
+    pub weight: f64,
+}
+
+/// Listed domains of a message
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UriblVerdict {
+    pub hits: Vec<UriblHit>,
+}
+
+impl UriblVerdict {
+    /// One spam rule match per list, weighted once however many of the
+    /// message's domains it lists
+    pub fn rule_matches(&self) -> Vec<SpamRuleMatch> {
+        let zones: BTreeSet<&str> = self.hits.iter().map(|hit| hit.zone.as_str()).collect();
+        zones
+            .into_iter()
+            .map(|zone| {
+                let hits: Vec<&UriblHit> = self.hits.iter().filter(|hit| hit.zone == zone).collect();
+                let domains: Vec<&str> = hits.iter().map(|hit| hit.domain.as_str()).collect();
+                SpamRuleMatch {
+                    rule_name: rule_name(zone),
+                    score: hits[0].weight,
+                    description: format!("Links to {} listed on {}", domains.join(", "), zone),
+                }
+            })
+            .collect()
+    }
+
+    /// `X-Spam-Report` value, e.g. `uribl=spam.example@multi.surbl.org`;
+    /// None without hits
+    pub fn report(&self) -> Option<String> {
+        if self.hits.is_empty() {
+            return None;
+        }
+        let hits: Vec<String> = self
+            .hits
+            .iter()
+            .map(|hit| format!("{}@{}", hit.domain, hit.zone))
+            .collect();
+        Some(format!("uribl={}", hits.join(",")))
+    }
+}
+
+/// Checks the links of messages against the configured blocklists
+pub struct UriblChecker {
+    lists: Vec<UriblListConfig>,
+    max_domains: usize,
+    timeout: Duration,
+    resolver: TokioAsyncResolver,
+    /// Return code per (zone, domain), None when not listed
+    cache: TtlCache<(String, String), Option<Ipv4Addr>>,
+}
+
+impl UriblChecker {
+    pub fn from_config(config: &UriblConfig) -> Self {
+        let lists = config
+            .lists
+            .iter()
+            .map(|list| UriblListConfig {
+                zone: list.zone.trim().trim_end_matches('.').to_lowercase(),
+                ..list.clone()
+            })
+            .filter(|list| !list.zone.is_empty())
+            .collect();
+        Self {
+            lists,
+            max_domains: config.max_domains,
+            timeout: Duration::from_millis(config.timeout_ms),
+            resolver: TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default()),
+            cache: TtlCache::new(Duration::from_secs(config.cache_ttl_secs), CACHE_CAPACITY),
+        }
+    }
+
+    /// Look the link domains of `parsed` up in every list
+    pub async fn check(&self, parsed: &ParsedEmail) -> UriblVerdict {
+        let mut domains = link_domains(parsed);
+        domains.truncate(self.max_domains);
+
+        let lookups = domains
+            .iter()
+            .flat_map(|domain| self.lists.iter().map(move |list| self.lookup(list, domain)));
+        let hits: Vec<UriblHit> = futures::future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        if !hits.is_empty() {
+            let listed: Vec<String> = hits
+                .iter()
+                .map(|hit| format!("{} ({})", hit.domain, hit.zone))
+                .collect();
+            info!("Message links to listed domains: {}", listed.join(", "));
+        }
+        UriblVerdict { hits }
+    }
+
+    async fn lookup(&self, list: &UriblListConfig, domain: &str) -> Option<UriblHit> {
+        let key = (list.zone.clone(), domain.to_string());
+        let code = match self.cache.get(&key) {
+            Some(code) => code,
+            None => {
+                let name = query_name(domain, &list.zone);
+                match tokio::time::timeout(self.timeout, self.resolver.ipv4_lookup(name.as_str())).await {
+                    Ok(Ok(answer)) => {
+                        let code = answer.iter().map(|a| a.0).find(|code| code.octets()[0] == 127);
+                        self.cache.insert(key, code);
+                        code
+                    }
+                    Ok(Err(e)) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                        self.cache.insert(key, None);
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        warn!("URIBL lookup of {} failed: {}", name, e);
+                        None
+                    }
+                    Err(_) => {
+                        warn!("URIBL lookup of {} timed out", name);
+                        None
+                    }
+                }
+            }
+        };
+
+        let code = code?;
+        debug!("{} listed on {} ({})", domain, list.zone, code);
+        Some(UriblHit {
+            zone: list.zone.clone(),
+            domain: domain.to_string(),
+            code,
+            weight: list.weight,
+        })
+    }
+}
+
+/// Distinct domains linked from the text and HTML bodies, in order of
+/// appearance
+pub fn link_domains(parsed: &ParsedEmail) -> Vec<String> {
+    let bodies = [parsed.text_body.as_deref(), parsed.html_body.as_deref()];
+    let mut domains: Vec<String> = Vec::new();
+    for body in bodies.into_iter().flatten() {
+        for captures in LINK.captures_iter(body) {
+            let Some(domain) = base_domain(&captures[1]) else {
+                continue;
+            };
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    domains
+}
+
+/// Part of `host` the lists know: an IP address, or the registered domain
+fn base_domain(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_lowercase();
+    if host.parse::<IpAddr>().is_ok() {
+        return Some(host);
+    }
+    let labels: Vec<&str> = host.split('.').filter(|label| !label.is_empty()).collect();
+    let [.., second, tld] = labels[..] else {
+        return None;
+    };
+    if tld.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // Two-letter country domains often register under co., com., org.
+    let keep = match tld.len() == 2 && matches!(second, "co" | "com" | "net" | "org" | "ac" | "gov") {
+        true => 3,
+        false => 2,
+    };
+    Some(labels[labels.len().saturating_sub(keep)..].join("."))
+}
+
+/// Name looked up in `zone` for `domain`
+pub fn query_name(domain: &str, zone: &str) -> String {
+    match domain.parse::<IpAddr>() {
+        Ok(ip) => dnsbl::query_name(ip, zone),
+        Err(_) => format!("{}.{}.", domain, zone),
+    }
+}
+
+/// Spam rule name of a list, e.g. `URIBL_MULTI_SURBL_ORG`
+fn rule_name(zone: &str) -> String {
+    let zone: String = zone
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("URIBL_{}", zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::MimeParser;
+
+    fn checker() -> UriblChecker {
+        UriblChecker::from_config(&UriblConfig {
+            enabled: true,
+            lists: vec![
+                UriblListConfig {
+                    zone: "Multi.SURBL.org.".to_string(),
+                    weight: 4.0,
+                },
+                UriblListConfig {
+                    zone: "black.uribl.com".to_string(),
+                    weight: 3.0,
+                },
+            ],
+            ..UriblConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_link_domains() {
+        let message = b"From: a@example.org\r\n\
+            Content-Type: multipart/alternative; boundary=b\r\n\r\n\
+            --b\r\nContent-Type: text/plain\r\n\r\n\
+            Visit https://www.Spam.example/offer or www.shop.co.uk today,\r\n\
+            mail me at user@mail.example.net, see http://192.0.2.7/x\r\n\
+            --b\r\nContent-Type: text/html\r\n\r\n\
+            <a href=\"http://user@click.spam.example:8080/t\">here</a>\r\n\
+            --b--\r\n";
+        let parsed = MimeParser::parse(message).unwrap();
+        assert_eq!(
+            link_domains(&parsed),
+            ["spam.example", "shop.co.uk", "192.0.2.7"]
+        );
+        assert_eq!(
+            query_name("spam.example", "multi.surbl.org"),
+            "spam.example.multi.surbl.org."
+        );
+        assert_eq!(
+            query_name("192.0.2.7", "multi.surbl.org"),
+            "7.2.0.192.multi.surbl.org."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_uses_cached_answers() {
+        let checker = checker();
+        let code = Ipv4Addr::new(127, 0, 0, 2);
+        for (zone, domain, listed) in [
+            ("multi.surbl.org", "spam.example", true),
+            ("multi.surbl.org", "bad.example", true),
+            ("multi.surbl.org", "example.org", false),
+            ("black.uribl.com", "spam.example", false),
+            ("black.uribl.com", "bad.example", true),
+            ("black.uribl.com", "example.org", false),
+        ] {
+            let key = (zone.to_string(), domain.to_string());
+            checker.cache.insert(key, listed.then_some(code));
+        }
+
+        let message = b"Subject: Offer\r\n\r\n\
+            http://spam.example/ http://bad.example/ http://example.org/\r\n";
+        let verdict = checker.check(&MimeParser::parse(message).unwrap()).await;
+        assert_eq!(verdict.hits.len(), 3);
+
+        let matches = verdict.rule_matches();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].rule_name, "URIBL_BLACK_URIBL_COM");
+        assert_eq!(matches[0].score, 3.0);
+        assert_eq!(matches[1].rule_name, "URIBL_MULTI_SURBL_ORG");
+        assert_eq!(
+            matches[1].description,
+            "Links to spam.example, bad.example listed on multi.surbl.org"
+        );
+        assert_eq!(
+            verdict.report().unwrap(),
+            "uribl=spam.example@multi.surbl.org,bad.example@multi.surbl.org,\
+             bad.example@black.uribl.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_domains() {
+        let checker = UriblChecker::from_config(&UriblConfig {
+            max_domains: 1,
+            ..UriblConfig::default()
+        });
+        let message = b"Subject: Hi\r\n\r\nhttp://one.example/ http://two.example/\r\n";
+        let parsed = MimeParser::parse(message).unwrap();
+        assert_eq!(link_domains(&parsed).len(), 2);
+        // No lists: nothing looked up
+        assert_eq!(checker.check(&parsed).await, UriblVerdict::default());
+    }
+}
@@ -0,0 +1,137 @@
+//! clamd client speaking the `INSTREAM` protocol
+//!
+//! The command is `zINSTREAM\0`, followed by chunks each prefixed with
+//! their length as a 4-byte big-endian integer and a zero-length chunk.
+//! clamd answers `stream: OK`, `stream: <signature> FOUND` or an error
+//! ending in `ERROR`.
+
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::scanner::ScanResult;
+
+/// Bytes sent per chunk, well below clamd's default `StreamMaxLength`
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest reply read from clamd
+const MAX_REPLY: u64 = 4096;
+
+/// Client of a clamd daemon
+#[derive(Debug, Clone)]
+pub struct ClamdClient {
+    /// `host:port`, or the path of a Unix socket
+    address: String,
+    timeout: Duration,
+}
+
+impl ClamdClient {
+    pub fn new(address: &str, timeout: Duration) -> Self {
+        Self {
+            address: address.to_string(),
+            timeout,
+        }
+    }
+
+    /// Address of the daemon
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Scan `data`, failing if clamd can't be reached, reports an error or
+    /// takes longer than the timeout
+    pub async fn scan(&self, data: &[u8]) -> Result<ScanResult> {
+        tokio::time::timeout(self.timeout, self.connect_and_scan(data))
+            .await
+            .map_err(|_| anyhow!("clamd at {} timed out", self.address))?
+    }
+
+    async fn connect_and_scan(&self, data: &[u8]) -> Result<ScanResult> {
+        #[cfg(unix)]
+        if self.address.starts_with('/') {
+            let stream = tokio::net::UnixStream::connect(&self.address).await?;
+            return instream(stream, data).await;
+        }
+        let stream = TcpStream::connect(&self.address).await?;
+        instream(stream, data).await
+    }
+}
+
+/// Stream `data` to clamd over `stream` and parse its reply
+pub async fn instream<S>(mut stream: S, data: &[u8]) -> Result<ScanResult>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        stream
+            .write_all(&(chunk.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    (&mut stream)
+        .take(MAX_REPLY)
+        .read_to_end(&mut reply)
+        .await?;
+    parse_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// Result of a clamd reply
+pub fn parse_reply(reply: &str) -> Result<ScanResult> {
+    let reply = reply.trim_end_matches(['\0', '\n', '\r']).trim();
+    let status = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if status == "OK" {
+        return Ok(ScanResult::Clean);
+    }
+    if let Some(signature) = status.strip_suffix(" FOUND") {
+        return Ok(ScanResult::Infected(signature.trim().to_string()));
+    }
+    bail!("clamd answered {:?}", reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("stream: OK\0").unwrap(), ScanResult::Clean);
+        assert_eq!(
+            parse_reply("stream: Eicar-Test-Signature FOUND\0").unwrap(),
+            ScanResult::Infected("Eicar-Test-Signature".to_string())
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+        assert!(parse_reply("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_instream_framing() {
+        let (client, mut server) = tokio::io::duplex(1024 * 1024);
+        let data = vec![b'x'; CHUNK_SIZE + 10];
+        let daemon = tokio::spawn(async move {
+            let mut command = [0u8; 10];
+            server.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut received = 0;
+            loop {
+                let len = server.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                server.read_exact(&mut chunk).await.unwrap();
+                received += len;
+            }
+            server.write_all(b"stream: OK\0").await.unwrap();
+            received
+        });
+
+        assert_eq!(instream(client, &data).await.unwrap(), ScanResult::Clean);
+        assert_eq!(daemon.await.unwrap(), data.len());
+    }
+}
@@ -0,0 +1,18 @@
+//! Virus scanning of received mail
+//!
+//! With `[antivirus] enabled`, each message is streamed to clamd over its
+//! `INSTREAM` protocol at the end of DATA; clamd decodes the MIME parts and
+//! attachments itself. Infected mail is refused with 554, or with
+//! `action = "quarantine"` delivered into the recipients' Junk folder.
+//! Submitted mail is always refused. Delivered mail carries an
+//! `X-Virus-Status:` header (forged copies are stripped). When clamd can't
+//! be reached the message is delivered unscanned, or refused with 451 when
+//! `fail_open` is off. Scan counters are served on `/api/admin/antivirus`.
+
+pub mod clamd;
+pub mod scanner;
+
+pub use clamd::ClamdClient;
+pub use scanner::{
+    AntivirusStats, ScanResult, SignatureCount, VirusAction, VirusScanner, STATUS_HEADER,
+};
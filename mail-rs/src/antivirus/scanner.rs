@@ -0,0 +1,292 @@
+//! Scanner shared by the SMTP listeners, with its counters
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::clamd::ClamdClient;
+use crate::config::AntivirusConfig;
+use crate::mime::entity::strip_header_fields;
+
+/// Header telling recipients whether the message was scanned
+pub const STATUS_HEADER: &str = "X-Virus-Status";
+
+/// What clamd found in a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanResult {
+    Clean,
+    /// Infected, with the name of the matched signature
+    Infected(String),
+}
+
+/// What happens to infected mail received from other servers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VirusAction {
+    /// Refuse it with 554
+    #[default]
+    Reject,
+    /// Deliver it into the recipients' Junk folder
+    Quarantine,
+}
+
+/// Messages in which a signature was found
+#[derive(Debug, Clone, Serialize)]
+pub struct SignatureCount {
+    pub signature: String,
+    pub count: u64,
+}
+
+/// Counters of the scanner
+#[derive(Debug, Clone, Serialize)]
+pub struct AntivirusStats {
+    pub address: String,
+    pub action: VirusAction,
+    pub fail_open: bool,
+    pub scanned: u64,
+    pub clean: u64,
+    pub infected: u64,
+    /// Scans that failed or timed out
+    pub errors: u64,
+    /// Infected messages refused
+    pub rejected: u64,
+    /// Infected messages delivered into Junk
+    pub quarantined: u64,
+    /// Most frequent signatures first
+    pub signatures: Vec<SignatureCount>,
+    pub last_detection: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    scanned: AtomicU64,
+    clean: AtomicU64,
+    infected: AtomicU64,
+    errors: AtomicU64,
+    rejected: AtomicU64,
+    quarantined: AtomicU64,
+}
+
+/// Scans received mail with clamd
+pub struct VirusScanner {
+    client: ClamdClient,
+    action: VirusAction,
+    fail_open: bool,
+    counters: Counters,
+    signatures: Mutex<HashMap<String, u64>>,
+    last_detection: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl VirusScanner {
+    pub fn from_config(config: &AntivirusConfig) -> Self {
+        Self {
+            client: ClamdClient::new(
+                config.address.trim(),
+                Duration::from_millis(config.timeout_ms),
+            ),
+            action: config.action,
+            fail_open: config.fail_open,
+            counters: Counters::default(),
+            signatures: Mutex::new(HashMap::new()),
+            last_detection: Mutex::new(None),
+        }
+    }
+
+    /// Address of clamd
+    pub fn address(&self) -> &str {
+        self.client.address()
+    }
+
+    pub fn action(&self) -> VirusAction {
+        self.action
+    }
+
+    /// Deliver mail unscanned when clamd can't be reached
+    pub fn fail_open(&self) -> bool {
+        self.fail_open
+    }
+
+    /// Scan `message`, counting the result
+    pub async fn scan(&self, message: &[u8]) -> Result<ScanResult> {
+        let result = self.client.scan(message).await;
+        match &result {
+            Ok(ScanResult::Clean) => {
+                self.counters.scanned.fetch_add(1, Ordering::Relaxed);
+                self.counters.clean.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(ScanResult::Infected(signature)) => {
+                self.counters.scanned.fetch_add(1, Ordering::Relaxed);
+                self.counters.infected.fetch_add(1, Ordering::Relaxed);
+                *self
+                    .signatures
+                    .lock()
+                    .unwrap()
+                    .entry(signature.clone())
+                    .or_default() += 1;
+                *self.last_detection.lock().unwrap() = Some(Utc::now());
+            }
+            Err(_) => {
+                self.counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// Count an infected message handled with `action`
+    pub fn record(&self, action: VirusAction) {
+        let counter = match action {
+            VirusAction::Reject => &self.counters.rejected,
+            VirusAction::Quarantine => &self.counters.quarantined,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> AntivirusStats {
+        let mut signatures: Vec<SignatureCount> = self
+            .signatures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(signature, count)| SignatureCount {
+                signature: signature.clone(),
+                count: *count,
+            })
+            .collect();
+        signatures.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.signature.cmp(&b.signature))
+        });
+
+        AntivirusStats {
+            address: self.address().to_string(),
+            action: self.action,
+            fail_open: self.fail_open,
+            scanned: self.counters.scanned.load(Ordering::Relaxed),
+            clean: self.counters.clean.load(Ordering::Relaxed),
+            infected: self.counters.infected.load(Ordering::Relaxed),
+            errors: self.counters.errors.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            quarantined: self.counters.quarantined.load(Ordering::Relaxed),
+            signatures,
+            last_detection: *self.last_detection.lock().unwrap(),
+        }
+    }
+}
+
+/// `X-Virus-Status:` header line for `result`, `None` for unscanned mail
+pub fn header(result: Option<&ScanResult>) -> String {
+    let status = match result {
+        Some(ScanResult::Clean) => "Clean".to_string(),
+        Some(ScanResult::Infected(signature)) => format!("Infected ({})", signature),
+        None => "Unscanned".to_string(),
+    };
+    format!("{}: {}\r\n", STATUS_HEADER, status)
+}
+
+/// `message` without `X-Virus-Status:` fields added by the sender
+pub fn strip_headers(message: &[u8]) -> Vec<u8> {
+    strip_header_fields(message, &[STATUS_HEADER])
+}
+
+/// Reply refusing a message infected with `signature`
+pub fn reply(signature: &str) -> String {
+    format!("554 5.7.1 Message contains a virus ({})\r\n", signature)
+}
+
+/// Reply deferring a message that couldn't be scanned
+pub fn unavailable_reply() -> String {
+    "451 4.7.1 Virus scanner unavailable, try again later\r\n".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const EICAR: &str = "X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    /// clamd stand-in finding the EICAR test string
+    async fn mock_clamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut command = [0u8; 10];
+                stream.read_exact(&mut command).await.unwrap();
+                let mut data = Vec::new();
+                loop {
+                    let len = stream.read_u32().await.unwrap() as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0u8; len];
+                    stream.read_exact(&mut chunk).await.unwrap();
+                    data.extend_from_slice(&chunk);
+                }
+                let reply: &[u8] = match String::from_utf8_lossy(&data).contains(EICAR) {
+                    true => b"stream: Eicar-Test-Signature FOUND\0",
+                    false => b"stream: OK\0",
+                };
+                stream.write_all(reply).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn config(address: &str) -> AntivirusConfig {
+        AntivirusConfig {
+            enabled: true,
+            address: address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_counts_results() {
+        let scanner = VirusScanner::from_config(&config(&mock_clamd().await));
+
+        let clean = b"Subject: Hi\r\n\r\nHello\r\n";
+        assert_eq!(scanner.scan(clean).await.unwrap(), ScanResult::Clean);
+        let infected = format!("Subject: Hi\r\n\r\n{}\r\n", EICAR);
+        let result = scanner.scan(infected.as_bytes()).await.unwrap();
+        assert_eq!(
+            result,
+            ScanResult::Infected("Eicar-Test-Signature".to_string())
+        );
+        scanner.record(VirusAction::Reject);
+
+        let stats = scanner.stats();
+        assert_eq!((stats.scanned, stats.clean, stats.infected), (2, 1, 1));
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.signatures[0].signature, "Eicar-Test-Signature");
+        assert!(stats.last_detection.is_some());
+        assert_eq!(
+            header(Some(&result)),
+            "X-Virus-Status: Infected (Eicar-Test-Signature)\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_error_when_clamd_is_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let scanner = VirusScanner::from_config(&config(&address));
+        assert!(scanner.scan(b"Subject: Hi\r\n\r\nHello\r\n").await.is_err());
+        let stats = scanner.stats();
+        assert_eq!((stats.scanned, stats.errors), (0, 1));
+    }
+
+    #[test]
+    fn test_strip_headers() {
+        let forged = b"X-Virus-Status: Clean\r\nSubject: Hi\r\n\r\nBody\r\n";
+        assert_eq!(strip_headers(forged), b"Subject: Hi\r\n\r\nBody\r\n");
+    }
+}
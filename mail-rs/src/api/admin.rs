@@ -9,7 +9,7 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use super::handlers::{ApiError, AppState};
 
@@ -265,7 +265,11 @@ pub async fn get_config() -> Result<Json<serde_json::Value>, (StatusCode, Json<A
 
 // ========== DNS CONFIGURATION ==========
 
-use crate::admin::dns::DnsConfigGenerator;
+use crate::admin::dns::{public_key_of_pem, DnsConfigGenerator};
+use crate::admin::dns_verify::{DnsVerifier, DomainVerification};
+use crate::config::Config;
+use crate::dkim_keys::{DkimKey, DkimKeyManager};
+use axum::extract::Query;
 use std::net::IpAddr;
 
 /// DNS configuration response
@@ -288,23 +292,49 @@ pub struct DnsRecordResponse {
     pub description: String,
 }
 
+/// App state of the DNS helper
+pub struct DnsState {
+    pub config: Arc<Config>,
+    pub verifier: Arc<DnsVerifier>,
+    /// DKIM keys whose records are verified
+    pub dkim: Option<Arc<DkimKeyManager>>,
+}
+
+/// Query of the DNS verification
+#[derive(Debug, Deserialize)]
+pub struct DnsVerifyQuery {
+    pub domain: Option<String>,
+}
+
 /// Get DNS configuration
 pub async fn get_dns_config(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<DnsState>>,
 ) -> Result<Json<DnsConfigResponse>, (StatusCode, Json<ApiError>)> {
     info!("Admin: Getting DNS configuration");
 
-    // TODO: Read from actual configuration
-    let domain = "example.com".to_string();
-    let hostname = "mail.example.com".to_string();
-    let ip: IpAddr = "203.0.113.10".parse().unwrap();
-
-    let generator = DnsConfigGenerator::new(
-        domain.clone(),
-        hostname.clone(),
-        ip,
-        "default".to_string(),
-    );
+    let config = &state.config;
+    let domain = config.server.domain.clone();
+    let hostname = config.server.hostname.clone();
+    // Address the hostname resolves to, a documentation address until the
+    // A record exists
+    let ip: IpAddr = match tokio::net::lookup_host((hostname.as_str(), 25)).await {
+        Ok(mut addrs) => addrs.next().map(|addr| addr.ip()),
+        Err(_) => None,
+    }
+    .unwrap_or_else(|| "203.0.113.10".parse().unwrap());
+
+    let mut generator = DnsConfigGenerator::from_config(config, ip);
+    if config.smtp.enable_tls {
+        if let Some(ref key_path) = config.smtp.tls_key_path {
+            match tokio::fs::read_to_string(key_path).await {
+                Ok(pem) => match public_key_of_pem(&pem) {
+                    Some(spki) => generator = generator.with_tlsa_public_key(&spki),
+                    None => warn!("No PKCS#8 key in {}, skipping the TLSA record", key_path),
+                },
+                Err(e) => warn!("Failed to read {}: {}", key_path, e),
+            }
+        }
+    }
 
     let records = generator.generate_records()
         .map_err(|e| {
@@ -342,6 +372,49 @@ pub async fn get_dns_config(
     }))
 }
 
+/// Verify the published DNS records of one domain, or of the server's
+/// domain and every domain with a DKIM key
+pub async fn verify_dns(
+    State(state): State<Arc<DnsState>>,
+    Query(query): Query<DnsVerifyQuery>,
+) -> Result<Json<Vec<DomainVerification>>, (StatusCode, Json<ApiError>)> {
+    info!("Admin: Verifying DNS records");
+
+    let keys = match &state.dkim {
+        Some(manager) => manager.list(query.domain.as_deref()).await.map_err(|e| {
+            error!("Failed to list DKIM keys: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError::new("Failed to list DKIM keys"))
+            )
+        })?,
+        None => Vec::new(),
+    };
+
+    let mut domains = vec![query
+        .domain
+        .unwrap_or_else(|| state.config.server.domain.clone())
+        .to_lowercase()];
+    for key in &keys {
+        let domain = key.domain.to_lowercase();
+        if !domains.contains(&domain) {
+            domains.push(domain);
+        }
+    }
+
+    let mut results = Vec::new();
+    for domain in &domains {
+        let domain_keys: Vec<DkimKey> = keys
+            .iter()
+            .filter(|key| key.domain.eq_ignore_ascii_case(domain))
+            .cloned()
+            .collect();
+        results.push(state.verifier.verify(domain, &domain_keys).await);
+    }
+
+    Ok(Json(results))
+}
+
 // ========== SYSTEM DIAGNOSTICS ==========
 
 use crate::admin::diagnostics::SystemDiagnostics;
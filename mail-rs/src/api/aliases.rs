@@ -0,0 +1,193 @@
+//! API endpoints for burner aliases
+//!
+//! Users generate, mute, block and delete their own aliases and see who
+//! sends mail to them. Aliases only receive mail on SMTP listeners with
+//! `[aliases] enabled = true`.
+
+use crate::aliases::{Alias, AliasManager, AliasStats, AliasUpdate};
+use crate::api::auth::get_session_email;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Longest lifetime that can be requested in days
+const MAX_EXPIRY_DAYS: i64 = 3650;
+
+/// App state containing the alias manager
+pub struct AliasesState {
+    pub manager: Arc<AliasManager>,
+    /// Domain of generated aliases, the owner's domain if unset
+    pub domain: Option<String>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No such alias")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Aliases API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access aliases",
+    )
+}
+
+/// Request to generate an alias
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateAliasRequest {
+    /// Readable part in front of the random suffix, e.g. `shop`
+    pub prefix: Option<String>,
+    pub label: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Alternative to `expires_at`
+    pub expires_in_days: Option<i64>,
+}
+
+/// GET /api/aliases - Aliases of the current user
+pub async fn list_aliases(
+    State(state): State<Arc<AliasesState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<Alias>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let aliases = state.manager.list(&email).await.map_err(internal_error)?;
+    Ok(Json(aliases))
+}
+
+/// POST /api/aliases - Generate a new alias for the current user
+pub async fn create_alias(
+    State(state): State<Arc<AliasesState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateAliasRequest>,
+) -> ApiResult<(StatusCode, Json<Alias>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let expires_at = match (payload.expires_at, payload.expires_in_days) {
+        (Some(_), Some(_)) => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Give either expires_at or expires_in_days",
+            ))
+        }
+        (Some(expires_at), None) if expires_at <= Utc::now() => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Expiry must be in the future",
+            ))
+        }
+        (None, Some(days)) if !(1..=MAX_EXPIRY_DAYS).contains(&days) => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                &format!("expires_in_days must be between 1 and {}", MAX_EXPIRY_DAYS),
+            ))
+        }
+        (expires_at, days) => expires_at.or(days.map(|days| Utc::now() + Duration::days(days))),
+    };
+
+    let domain = match &state.domain {
+        Some(domain) => domain.clone(),
+        None => email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_string())
+            .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Account has no domain"))?,
+    };
+
+    let alias = state
+        .manager
+        .create(
+            &email,
+            payload.prefix.as_deref(),
+            &domain,
+            payload.label.as_deref(),
+            expires_at,
+        )
+        .await
+        .map_err(internal_error)?;
+    info!("{} created alias {}", email, alias.address);
+    Ok((StatusCode::CREATED, Json(alias)))
+}
+
+/// PATCH /api/aliases/:address - Mute, block, relabel or extend an alias
+pub async fn update_alias(
+    State(state): State<Arc<AliasesState>>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+    Json(update): Json<AliasUpdate>,
+) -> ApiResult<Json<Alias>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let alias = state
+        .manager
+        .update(&email, &address, &update)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    info!(
+        "{} updated alias {} (muted: {}, blocked: {})",
+        email, alias.address, alias.muted, alias.blocked
+    );
+    Ok(Json(alias))
+}
+
+/// DELETE /api/aliases/:address - Delete an alias and its statistics
+pub async fn delete_alias(
+    State(state): State<Arc<AliasesState>>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> ApiResult<StatusCode> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    if !state
+        .manager
+        .delete(&email, &address)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(not_found());
+    }
+    info!("{} deleted alias {}", email, address);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/aliases/:address/stats - Who sends mail to an alias
+pub async fn alias_stats(
+    State(state): State<Arc<AliasesState>>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> ApiResult<Json<AliasStats>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let stats = state
+        .manager
+        .stats(&email, &address)
+        .await
+        .map_err(internal_error)?;
+    stats.map(Json).ok_or_else(not_found)
+}
@@ -0,0 +1,59 @@
+//! API endpoint for virus scanning metrics
+//!
+//! Reports how many received messages clamd scanned, how many were
+//! infected, refused or quarantined, the signatures found and scan
+//! failures; see [`crate::antivirus`].
+
+use crate::antivirus::{AntivirusStats, VirusScanner};
+use crate::api::auth::get_session_email;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// App state containing the scanner shared with the SMTP listeners
+pub struct AntivirusState {
+    pub scanner: Option<Arc<VirusScanner>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Virus scanning is not enabled",
+    )
+}
+
+/// GET /api/admin/antivirus - Scans, detections, signatures and failures
+pub async fn stats(
+    State(state): State<Arc<AntivirusState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<AntivirusStats>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let scanner = state.scanner.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(scanner.stats()))
+}
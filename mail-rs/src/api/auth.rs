@@ -17,9 +17,14 @@ pub struct Claims {
     pub exp: u64,
     /// Issued at (Unix timestamp)
     pub iat: u64,
+    /// Device the token was issued to; tokens stop working once it is
+    /// revoked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
 }
 
 /// JWT configuration
+#[derive(Clone)]
 pub struct JwtConfig {
     /// Secret key for signing tokens
     secret: String,
@@ -38,6 +43,15 @@ impl JwtConfig {
 
     /// Create a new JWT token for a user
     pub fn create_token(&self, email: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        self.create_device_token(email, None)
+    }
+
+    /// Create a new JWT token for a user's device
+    pub fn create_device_token(
+        &self,
+        email: &str,
+        device: Option<&str>,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -47,6 +61,7 @@ impl JwtConfig {
             sub: email.to_string(),
             exp: now + self.expiration.as_secs(),
             iat: now,
+            device: device.map(str::to_string),
         };
 
         encode(
@@ -101,6 +116,13 @@ mod tests {
 
         let claims = config.validate_token(&token).unwrap();
         assert_eq!(claims.sub, "test@example.com");
+        assert_eq!(claims.device, None);
+
+        let token = config
+            .create_device_token("test@example.com", Some("device-1"))
+            .unwrap();
+        let claims = config.validate_token(&token).unwrap();
+        assert_eq!(claims.device.as_deref(), Some("device-1"));
     }
 
     #[test]
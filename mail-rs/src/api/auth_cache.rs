@@ -0,0 +1,57 @@
+//! API endpoint for SPF and DKIM cache metrics
+//!
+//! Reports how often SMTP found an SPF verdict or a DKIM public key in its
+//! caches instead of querying DNS; see [`crate::authentication::cache`].
+
+use crate::api::auth::get_session_email;
+use crate::authentication::{CacheStats, DkimValidator, SpfValidator};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// App state containing the validators shared with the SMTP listener
+pub struct AuthCacheState {
+    pub spf: Option<Arc<SpfValidator>>,
+    pub dkim: Option<Arc<DkimValidator>>,
+}
+
+/// Counters of each cache, absent when that check is disabled
+#[derive(Debug, Serialize)]
+pub struct AuthCacheStats {
+    pub spf: Option<CacheStats>,
+    pub dkim: Option<CacheStats>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(ApiError {
+            error: "Not authenticated".to_string(),
+        }),
+    )
+}
+
+/// GET /api/admin/auth-cache - Hits and misses of the SPF and DKIM caches
+pub async fn stats(
+    State(state): State<Arc<AuthCacheState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<AuthCacheStats>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    Ok(Json(AuthCacheStats {
+        spf: state.spf.as_ref().map(|validator| validator.cache_stats()),
+        dkim: state.dkim.as_ref().map(|validator| validator.cache_stats()),
+    }))
+}
@@ -0,0 +1,165 @@
+//! Mail client autoconfiguration
+//!
+//! Serves the Thunderbird autoconfig document (also read by K-9, Evolution
+//! and others) at `/mail/config-v1.1.xml` and
+//! `/.well-known/autoconfig/mail/config-v1.1.xml`, which the
+//! `autoconfig.<domain>` CNAME suggested by the DNS helper points at. The
+//! servers and ports come from the running configuration; the display name
+//! from the branding of the user's domain.
+
+use crate::branding::{Branding, BrandingManager};
+use crate::config::Config;
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::warn;
+
+/// App state for the autoconfig document
+pub struct AutoconfigState {
+    pub branding: Arc<BrandingManager>,
+    pub config: Arc<Config>,
+}
+
+/// Query sent by mail clients
+#[derive(Debug, Deserialize)]
+pub struct AutoconfigQuery {
+    pub emailaddress: Option<String>,
+}
+
+/// One incoming or outgoing server entry
+struct ServerEntry {
+    kind: &'static str,
+    port: u16,
+    socket_type: &'static str,
+}
+
+/// GET /mail/config-v1.1.xml - Client configuration of a domain
+pub async fn config_xml(
+    State(state): State<Arc<AutoconfigState>>,
+    headers: HeaderMap,
+    Query(query): Query<AutoconfigQuery>,
+) -> impl IntoResponse {
+    let domain = match query
+        .emailaddress
+        .as_deref()
+        .and_then(|address| address.rsplit_once('@'))
+    {
+        Some((_, domain)) => domain.to_lowercase(),
+        None => request_domain(&headers),
+    };
+    let branding = state.branding.resolve(&domain).await.unwrap_or_else(|e| {
+        warn!("Failed to look up branding of {}: {}", domain, e);
+        Branding::default_for(&domain)
+    });
+
+    (
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        render(&state.config, &domain, &branding),
+    )
+}
+
+/// Domain asked for through the Host header, e.g. `autoconfig.example.com`
+fn request_domain(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let host = host.split(':').next().unwrap_or_default().to_lowercase();
+    match host.strip_prefix("autoconfig.") {
+        Some(domain) => domain.to_string(),
+        None => host,
+    }
+}
+
+fn render(config: &Config, domain: &str, branding: &Branding) -> String {
+    let mut incoming = Vec::new();
+    if config.imap.enable_tls {
+        incoming.push(ServerEntry {
+            kind: "imap",
+            port: port(&config.imap.imaps_listen_addr, 993),
+            socket_type: "SSL",
+        });
+    }
+    incoming.push(ServerEntry {
+        kind: "imap",
+        port: port(&config.imap.listen_addr, 143),
+        socket_type: if config.imap.enable_tls {
+            "STARTTLS"
+        } else {
+            "plain"
+        },
+    });
+    let outgoing = ServerEntry {
+        kind: "smtp",
+        port: if config.submission.enabled {
+            port(&config.submission.listen_addr, 587)
+        } else {
+            port(&config.smtp.listen_addr, 25)
+        },
+        socket_type: if config.smtp.enable_tls {
+            "STARTTLS"
+        } else {
+            "plain"
+        },
+    };
+
+    let hostname = xml_escape(&config.server.hostname);
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <clientConfig version=\"1.1\">\n\
+         \x20 <emailProvider id=\"{domain}\">\n\
+         \x20   <domain>{domain}</domain>\n\
+         \x20   <displayName>{name}</displayName>\n\
+         \x20   <displayShortName>{name}</displayShortName>\n",
+        domain = xml_escape(domain),
+        name = xml_escape(&branding.product_name),
+    );
+    let servers = incoming
+        .iter()
+        .map(|server| ("incomingServer", server))
+        .chain(std::iter::once(("outgoingServer", &outgoing)));
+    for (element, server) in servers {
+        xml.push_str(&format!(
+            "    <{element} type=\"{kind}\">\n\
+             \x20     <hostname>{hostname}</hostname>\n\
+             \x20     <port>{port}</port>\n\
+             \x20     <socketType>{socket_type}</socketType>\n\
+             \x20     <username>%EMAILADDRESS%</username>\n\
+             \x20     <authentication>password-cleartext</authentication>\n\
+             \x20   </{element}>\n",
+            kind = server.kind,
+            port = server.port,
+            socket_type = server.socket_type,
+        ));
+    }
+    xml.push_str("  </emailProvider>\n");
+    if let Some(url) = &branding.support_url {
+        xml.push_str(&format!(
+            "  <documentation url=\"{}\">\n    <descr lang=\"en\">{} support</descr>\n  </documentation>\n",
+            xml_escape(url),
+            xml_escape(&branding.product_name)
+        ));
+    }
+    xml.push_str("</clientConfig>\n");
+    xml
+}
+
+/// Port of a listen address like `0.0.0.0:993`
+fn port(listen_addr: &str, default: u16) -> u16 {
+    listen_addr
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(default)
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
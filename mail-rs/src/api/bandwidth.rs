@@ -0,0 +1,107 @@
+//! API endpoints for throttled downloads and live session throughput
+//!
+//! Raw messages are streamed in chunks through the same
+//! [`BandwidthLimiter`] as IMAP, so a client cannot dodge the limits by
+//! switching protocol. The sessions endpoint lists every throttled
+//! connection with its current throughput.
+
+use crate::api::auth::{get_session_email, Claims};
+use crate::imap::Mailbox;
+use crate::security::bandwidth::{BandwidthStatus, CHUNK_SIZE};
+use crate::security::BandwidthLimiter;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::error;
+
+/// App state containing the shared bandwidth limiter
+pub struct BandwidthState {
+    pub limiter: Arc<BandwidthLimiter>,
+    pub maildir_root: PathBuf,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Email not found")
+}
+
+/// GET /api/mails/:id/raw - Download a message of the INBOX as
+/// `message/rfc822`, held to the bandwidth limits
+pub async fn download_raw(
+    State(state): State<Arc<BandwidthState>>,
+    claims: Claims,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(sequence): Path<usize>,
+) -> ApiResult<Response> {
+    let mailbox =
+        Mailbox::open(&claims.sub, "INBOX", &state.maildir_root).map_err(|_| not_found())?;
+    let content = mailbox
+        .get_message(sequence)
+        .and_then(|msg| msg.content().ok())
+        .map(|content| Bytes::from(content.to_vec()))
+        .ok_or_else(not_found)?;
+
+    let throttle = state
+        .limiter
+        .connect("api", connect_info.map(|ConnectInfo(addr)| addr.ip()));
+    throttle.set_user(&claims.sub);
+
+    let length = content.len();
+    let chunks = futures::stream::unfold((content, throttle), |(mut rest, throttle)| async move {
+        if rest.is_empty() {
+            return None;
+        }
+        let chunk = rest.split_to(rest.len().min(CHUNK_SIZE));
+        throttle.acquire(chunk.len()).await;
+        Some((Ok::<_, Infallible>(chunk), (rest, throttle)))
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "message/rfc822")
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::from_stream(chunks))
+        .map_err(|e| {
+            error!("Bandwidth API error: {}", e);
+            api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to send email")
+        })
+}
+
+/// GET /api/admin/sessions - Throttled IMAP and API connections with their
+/// current throughput and the configured limits
+pub async fn list_sessions(
+    State(state): State<Arc<BandwidthState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<BandwidthStatus>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    Ok(Json(state.limiter.status()))
+}
@@ -0,0 +1,95 @@
+//! API endpoints for billing exports
+
+use crate::api::auth::get_session_email;
+use crate::billing::{BillingManager, BillingPeriod, ExportFormat};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+/// App state containing billing manager
+pub struct BillingState {
+    pub manager: Arc<BillingManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+/// Query parameters for exports
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// Period as `YYYY-MM` (defaults to the last complete month)
+    pub period: Option<String>,
+    /// `json` (default) or `csv`
+    pub format: Option<String>,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Billing API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to export billing data",
+    )
+}
+
+/// GET /api/admin/billing/export - Per-user usage of a billing period as
+/// JSON or CSV
+pub async fn export(
+    State(state): State<Arc<BillingState>>,
+    headers: HeaderMap,
+    Query(query): Query<ExportQuery>,
+) -> ApiResult<Response> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let period = match query.period.as_deref() {
+        Some(period) => BillingPeriod::parse(period)
+            .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Period must be YYYY-MM"))?,
+        None => BillingPeriod::containing(Utc::now()).previous(),
+    };
+    let format = match query.format.as_deref() {
+        Some(format) => ExportFormat::parse(format)
+            .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Format must be json or csv"))?,
+        None => ExportFormat::Json,
+    };
+
+    let export = state.manager.export(period).await.map_err(internal_error)?;
+
+    Ok(match format {
+        ExportFormat::Json => Json(export).into_response(),
+        ExportFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"billing-{}.csv\"", period),
+                ),
+            ],
+            export.to_csv(),
+        )
+            .into_response(),
+    })
+}
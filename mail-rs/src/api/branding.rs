@@ -0,0 +1,147 @@
+//! API endpoints for per-domain branding
+//!
+//! Admins set the product name, logo, colors and support contact shown to
+//! each domain's users; see [`crate::branding`].
+
+use crate::api::auth::get_session_email;
+use crate::branding::{Branding, BrandingManager, BrandingUpdate};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the branding settings
+pub struct BrandingState {
+    pub manager: Arc<BrandingManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No branding found")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Branding API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access branding settings",
+    )
+}
+
+fn validate_domain(domain: &str) -> ApiResult<()> {
+    let valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(api_error(StatusCode::BAD_REQUEST, "Invalid domain"))
+    }
+}
+
+/// GET /api/admin/branding - Branding of all domains that have one
+pub async fn list_branding(
+    State(state): State<Arc<BrandingState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<Branding>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let branding = state.manager.list().await.map_err(internal_error)?;
+    Ok(Json(branding))
+}
+
+/// GET /api/admin/branding/:domain - Branding shown for a domain, which may
+/// be inherited from a parent domain or the defaults
+pub async fn get_branding(
+    State(state): State<Arc<BrandingState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+) -> ApiResult<Json<Branding>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+    validate_domain(&domain)?;
+
+    let branding = state
+        .manager
+        .resolve(&domain)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(branding))
+}
+
+/// PUT /api/admin/branding/:domain - Change a domain's branding
+pub async fn set_branding(
+    State(state): State<Arc<BrandingState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+    Json(payload): Json<BrandingUpdate>,
+) -> ApiResult<Json<Branding>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    validate_domain(&domain)?;
+
+    // Check the merged settings first, so invalid values are reported as
+    // such rather than as a storage failure
+    let mut branding = state
+        .manager
+        .get(&domain)
+        .await
+        .map_err(internal_error)?
+        .unwrap_or_else(|| Branding::default_for(&domain));
+    branding.apply(&payload);
+    branding
+        .validate()
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e))?;
+
+    let branding = state
+        .manager
+        .set(&domain, &payload)
+        .await
+        .map_err(internal_error)?;
+    info!("Admin {}: Set branding of {}", actor, branding.domain);
+    Ok(Json(branding))
+}
+
+/// DELETE /api/admin/branding/:domain - Return a domain to the defaults
+pub async fn delete_branding(
+    State(state): State<Arc<BrandingState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let removed = state
+        .manager
+        .delete(&domain)
+        .await
+        .map_err(internal_error)?;
+    if !removed {
+        return Err(not_found());
+    }
+    info!("Admin {}: Removed branding of {}", actor, domain);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,102 @@
+//! API endpoint for delivery pipeline usage
+//!
+//! Lists the enforced per-user budgets and the users whose mail costs the
+//! most to deliver; see [`crate::smtp::budget`]. Also reports the load of
+//! the post-delivery workers, see [`crate::smtp::postdelivery`].
+
+use crate::api::auth::get_session_email;
+use crate::smtp::budget::{BudgetLimits, DeliveryBudget, UserUsage};
+use crate::smtp::postdelivery::{PostDeliveryQueue, PostDeliveryStats};
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Default number of users listed
+const DEFAULT_LIMIT: usize = 20;
+
+/// App state containing the budgets shared with the SMTP listener
+pub struct BudgetsState {
+    pub budget: Arc<DeliveryBudget>,
+    pub post_delivery: Option<Arc<PostDeliveryQueue>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(ApiError {
+            error: "Not authenticated".to_string(),
+        }),
+    )
+}
+
+fn not_found(message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+/// Query of the usage endpoint
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    pub limit: Option<usize>,
+}
+
+/// Enforced limits and the heaviest users
+#[derive(Serialize)]
+pub struct DeliveryUsage {
+    pub enforced: bool,
+    pub stage_timeout_ms: Option<u64>,
+    pub user_ms_per_minute: Option<u64>,
+    pub user_io_concurrency: Option<usize>,
+    pub users: Vec<UserUsage>,
+}
+
+/// GET /api/admin/delivery/usage - Users whose mail costs the most to
+/// deliver, heaviest first
+pub async fn delivery_usage(
+    State(state): State<Arc<BudgetsState>>,
+    headers: HeaderMap,
+    Query(query): Query<UsageQuery>,
+) -> ApiResult<Json<DeliveryUsage>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let limits = state.budget.limits();
+    let millis = |limit: Option<std::time::Duration>| limit.map(|d| d.as_millis() as u64);
+    Ok(Json(DeliveryUsage {
+        enforced: limits != BudgetLimits::default(),
+        stage_timeout_ms: millis(limits.stage_timeout),
+        user_ms_per_minute: millis(limits.user_time_per_minute),
+        user_io_concurrency: limits.user_io_concurrency,
+        users: state.budget.heaviest(query.limit.unwrap_or(DEFAULT_LIMIT)),
+    }))
+}
+
+/// GET /api/admin/delivery/workers - Queue depth and activity of the
+/// post-delivery lanes, with latency per processing stage
+pub async fn delivery_workers(
+    State(state): State<Arc<BudgetsState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<PostDeliveryStats>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state
+        .post_delivery
+        .as_ref()
+        .ok_or_else(|| not_found("Post-delivery processing is disabled"))?;
+    Ok(Json(queue.stats()))
+}
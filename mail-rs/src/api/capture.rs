@@ -0,0 +1,168 @@
+//! API endpoints to read mail kept in capture mode
+//!
+//! Unlike the queue endpoints, captured messages are returned with their
+//! content so staging setups can check what would have been sent.
+
+use crate::api::auth::get_session_email;
+use crate::mime::{decode_header, MimeParser};
+use crate::smtp::{QueueStatus, QueuedEmail, SmtpQueue};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Messages returned when no limit is given
+const DEFAULT_LIMIT: i64 = 50;
+
+/// Upper bound for the `limit` parameter
+const MAX_LIMIT: i64 = 500;
+
+/// App state containing the outbound queue, if this process runs one
+pub struct CaptureState {
+    pub queue: Option<Arc<SmtpQueue>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "The outbound queue is not available in this process",
+    )
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Captured message not found")
+}
+
+fn internal_error(e: crate::error::MailError) -> (StatusCode, Json<ApiError>) {
+    error!("Capture API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access captured mail",
+    )
+}
+
+/// Query parameters for listing captured messages
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub limit: Option<i64>,
+}
+
+/// A captured message without its content
+#[derive(Debug, Serialize)]
+pub struct CapturedSummary {
+    pub id: String,
+    pub from_addr: String,
+    pub to_addr: String,
+    pub subject: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Message size in bytes
+    pub size: usize,
+}
+
+/// A captured message with its content
+#[derive(Debug, Serialize)]
+pub struct CapturedMessage {
+    #[serde(flatten)]
+    pub summary: CapturedSummary,
+    /// Message as it would have been sent, invalid UTF-8 replaced
+    pub raw: String,
+}
+
+/// Response of clearing captured mail
+#[derive(Debug, Serialize)]
+pub struct ClearResponse {
+    pub removed: u64,
+}
+
+impl From<&QueuedEmail> for CapturedSummary {
+    fn from(email: &QueuedEmail) -> Self {
+        let subject = MimeParser::parse(&email.data)
+            .ok()
+            .and_then(|parsed| parsed.headers.get("subject").map(|s| decode_header(s)));
+        Self {
+            id: email.id.clone(),
+            from_addr: email.from_addr.clone(),
+            to_addr: email.to_addr.clone(),
+            subject,
+            created_at: email.created_at,
+            size: email.data.len(),
+        }
+    }
+}
+
+/// GET /api/admin/capture - Most recent captured messages
+pub async fn list_messages(
+    State(state): State<Arc<CaptureState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Vec<CapturedSummary>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state.queue.as_ref().ok_or_else(unavailable)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let messages = queue
+        .list(Some("captured"), limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(messages.iter().map(CapturedSummary::from).collect()))
+}
+
+/// GET /api/admin/capture/:id - One captured message with its content
+pub async fn get_message(
+    State(state): State<Arc<CaptureState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<CapturedMessage>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state.queue.as_ref().ok_or_else(unavailable)?;
+    let email = queue
+        .get(&id)
+        .await
+        .map_err(internal_error)?
+        .filter(|email| matches!(email.status, QueueStatus::Captured))
+        .ok_or_else(not_found)?;
+    Ok(Json(CapturedMessage {
+        summary: CapturedSummary::from(&email),
+        raw: String::from_utf8_lossy(&email.data).into_owned(),
+    }))
+}
+
+/// DELETE /api/admin/capture - Remove all captured messages
+pub async fn clear_messages(
+    State(state): State<Arc<CaptureState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<ClearResponse>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state.queue.as_ref().ok_or_else(unavailable)?;
+    let removed = queue.clear_captured().await.map_err(internal_error)?;
+    info!("Admin {}: Removed {} captured emails", admin, removed);
+    Ok(Json(ClearResponse { removed }))
+}
@@ -0,0 +1,100 @@
+//! API endpoints to toggle fault injection
+//!
+//! Only servers built with the `chaos` feature accept rules; others answer
+//! 503 so a test run cannot silently pass without any injected faults.
+
+use crate::api::auth::get_session_email;
+use crate::chaos::{self, Fault, FaultInjector, FaultRule, FaultStatus};
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use tracing::info;
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Fault injection is not built into this server",
+    )
+}
+
+fn injector(headers: &HeaderMap) -> ApiResult<(String, &'static FaultInjector)> {
+    let admin = get_session_email(headers).ok_or_else(unauthorized)?;
+    let injector = chaos::injector().ok_or_else(unavailable)?;
+    Ok((admin, injector))
+}
+
+fn parse_fault(fault: &str) -> ApiResult<Fault> {
+    Fault::parse(fault).ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Unknown fault"))
+}
+
+/// GET /api/admin/chaos - Every fault with its rule and injection count
+pub async fn list_faults(headers: HeaderMap) -> ApiResult<Json<Vec<FaultStatus>>> {
+    let (_, injector) = injector(&headers)?;
+    Ok(Json(injector.status()))
+}
+
+/// PUT /api/admin/chaos/:fault - Turn a fault on with a probability and delay
+pub async fn set_fault(
+    headers: HeaderMap,
+    Path(fault): Path<String>,
+    Json(rule): Json<FaultRule>,
+) -> ApiResult<Json<Vec<FaultStatus>>> {
+    let (admin, injector) = injector(&headers)?;
+    let fault = parse_fault(&fault)?;
+
+    injector
+        .set(fault, rule)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+
+    info!(
+        "Fault {} set to probability {} with {} ms delay by {}",
+        fault, rule.probability, rule.delay_ms, admin
+    );
+    Ok(Json(injector.status()))
+}
+
+/// DELETE /api/admin/chaos/:fault - Turn a fault off
+pub async fn clear_fault(
+    headers: HeaderMap,
+    Path(fault): Path<String>,
+) -> ApiResult<Json<Vec<FaultStatus>>> {
+    let (admin, injector) = injector(&headers)?;
+    let fault = parse_fault(&fault)?;
+
+    injector.clear(fault);
+    info!("Fault {} turned off by {}", fault, admin);
+    Ok(Json(injector.status()))
+}
+
+/// DELETE /api/admin/chaos - Turn every fault off
+pub async fn clear_faults(headers: HeaderMap) -> ApiResult<Json<Vec<FaultStatus>>> {
+    let (admin, injector) = injector(&headers)?;
+
+    injector.clear_all();
+    info!("All faults turned off by {}", admin);
+    Ok(Json(injector.status()))
+}
@@ -0,0 +1,105 @@
+//! API endpoints for config fingerprints and drift between nodes
+//!
+//! Each node exports its normalized effective configuration; posting another
+//! node's export to `/api/admin/config/compare` lists the settings that
+//! differ, security-relevant ones (TLS policy, authentication) first.
+//! `mailctl config-diff` compares two nodes or config files the same way.
+
+use crate::admin::config_drift::{self, ConfigDiff, ConfigSnapshot};
+use crate::api::auth::get_session_email;
+use crate::config::Config;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// App state containing the configuration this node runs with
+pub struct ConfigDriftState {
+    pub config: Option<Arc<Config>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "The effective configuration is not available on this listener",
+    )
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Config drift API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to export the configuration",
+    )
+}
+
+fn local_snapshot(state: &ConfigDriftState) -> ApiResult<ConfigSnapshot> {
+    let config = state.config.as_ref().ok_or_else(unavailable)?;
+    ConfigSnapshot::capture(config).map_err(internal_error)
+}
+
+/// GET /api/admin/config/fingerprint - Normalized effective config of this
+/// node with its fingerprint
+pub async fn fingerprint(
+    State(state): State<Arc<ConfigDriftState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<ConfigSnapshot>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+    Ok(Json(local_snapshot(&state)?))
+}
+
+/// POST /api/admin/config/compare - Diff this node against another node's
+/// fingerprint export
+pub async fn compare(
+    State(state): State<Arc<ConfigDriftState>>,
+    headers: HeaderMap,
+    Json(other): Json<ConfigSnapshot>,
+) -> ApiResult<Json<ConfigDiff>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let local = local_snapshot(&state)?;
+
+    let diff = config_drift::diff(&local, &other);
+    if diff.security_differences() > 0 {
+        warn!(
+            "Config of {} differs from {} in {} security-relevant settings (checked by {})",
+            other.node,
+            local.node,
+            diff.security_differences(),
+            admin
+        );
+    } else {
+        info!(
+            "Config of {} compared with {}: {} differences (checked by {})",
+            other.node,
+            local.node,
+            diff.differences.len(),
+            admin
+        );
+    }
+    Ok(Json(diff))
+}
@@ -0,0 +1,114 @@
+//! API endpoints for client devices
+//!
+//! Users see the mail clients, SMTP submitters and API clients that signed
+//! in to their account, create app passwords for clients that should not
+//! know the account password, and revoke devices. Revoking a device drops
+//! its app password and invalidates the API tokens issued to it.
+
+use crate::api::auth::get_session_email;
+use crate::devices::{AppPassword, CreateAppPassword, Device, DeviceManager};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Longest app password label
+const MAX_NAME_LEN: usize = 64;
+
+/// App state containing the device manager
+pub struct DevicesState {
+    pub manager: Arc<DeviceManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No such device")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Devices API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access devices",
+    )
+}
+
+/// GET /api/devices - Devices of the current user, most recently seen first
+pub async fn list_devices(
+    State(state): State<Arc<DevicesState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<Device>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let devices = state.manager.list(&email).await.map_err(internal_error)?;
+    Ok(Json(devices))
+}
+
+/// POST /api/devices/app-passwords - Create an app password
+///
+/// The password is only returned by this call.
+pub async fn create_app_password(
+    State(state): State<Arc<DevicesState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateAppPassword>,
+) -> ApiResult<(StatusCode, Json<AppPassword>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            &format!("Name must be 1 to {} characters", MAX_NAME_LEN),
+        ));
+    }
+
+    let app_password = state
+        .manager
+        .create_app_password(&email, name)
+        .await
+        .map_err(internal_error)?;
+    info!("{} created app password {}", email, app_password.device.id);
+    Ok((StatusCode::CREATED, Json(app_password)))
+}
+
+/// DELETE /api/devices/:id - Revoke a device
+pub async fn revoke_device(
+    State(state): State<Arc<DevicesState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Device>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let device = state
+        .manager
+        .revoke(&email, &id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    info!("{} revoked device {} ({})", email, device.id, device.name);
+    Ok(Json(device))
+}
@@ -0,0 +1,214 @@
+//! API endpoints for DKIM signing keys
+//!
+//! Admins generate RSA and Ed25519 keys per domain and selector, publish
+//! the returned TXT record and choose which key signs outbound mail. Keys
+//! are only managed with `[dkim_signing] enabled`; see [`crate::dkim_keys`].
+
+use crate::api::auth::get_session_email;
+use crate::dkim_keys::{DkimAlgorithm, DkimKey, DkimKeyManager};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the key manager
+pub struct DkimState {
+    pub manager: Option<Arc<DkimKeyManager>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "DKIM signing is not enabled",
+    )
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No such DKIM key")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("DKIM API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access DKIM keys",
+    )
+}
+
+/// Whether `label` can be a domain or selector: dot-separated labels of
+/// letters, digits and hyphens
+fn valid_name(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 253
+        && label.split('.').all(|part| {
+            !part.is_empty()
+                && part.len() <= 63
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn manager(state: &DkimState) -> ApiResult<&Arc<DkimKeyManager>> {
+    state.manager.as_ref().ok_or_else(unavailable)
+}
+
+/// Query of the listing
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub domain: Option<String>,
+}
+
+/// Key to generate
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    pub domain: String,
+    pub selector: String,
+    pub algorithm: DkimAlgorithm,
+}
+
+/// GET /api/admin/dkim/keys - Keys with their DNS records, optionally of
+/// one domain
+pub async fn list_keys(
+    State(state): State<Arc<DkimState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Vec<DkimKey>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let keys = manager(&state)?
+        .list(query.domain.as_deref())
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(keys))
+}
+
+/// POST /api/admin/dkim/keys - Generate a key, which signs the domain's
+/// mail from now on
+pub async fn generate_key(
+    State(state): State<Arc<DkimState>>,
+    headers: HeaderMap,
+    Json(request): Json<GenerateRequest>,
+) -> ApiResult<(StatusCode, Json<DkimKey>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = manager(&state)?;
+    if !valid_name(&request.domain) || !valid_name(&request.selector) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Invalid domain or selector",
+        ));
+    }
+    let key = manager
+        .generate(&request.domain, &request.selector, request.algorithm)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::CONFLICT, "The selector is already in use"))?;
+    info!(
+        "{} generated DKIM key {} for {}",
+        email, key.selector, key.domain
+    );
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// GET /api/admin/dkim/keys/:domain/:selector - One key
+pub async fn get_key(
+    State(state): State<Arc<DkimState>>,
+    headers: HeaderMap,
+    Path((domain, selector)): Path<(String, String)>,
+) -> ApiResult<Json<DkimKey>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let key = manager(&state)?
+        .get(&domain, &selector)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(key))
+}
+
+/// POST /api/admin/dkim/keys/:domain/:selector/activate - Sign the domain's
+/// mail with this key
+pub async fn activate_key(
+    State(state): State<Arc<DkimState>>,
+    headers: HeaderMap,
+    Path((domain, selector)): Path<(String, String)>,
+) -> ApiResult<Json<DkimKey>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = manager(&state)?;
+    if !manager
+        .activate(&domain, &selector)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(not_found());
+    }
+    info!("{} activated DKIM key {} for {}", email, selector, domain);
+    let key = manager
+        .get(&domain, &selector)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(key))
+}
+
+/// DELETE /api/admin/dkim/keys/:domain/:selector - Delete a key
+pub async fn delete_key(
+    State(state): State<Arc<DkimState>>,
+    headers: HeaderMap,
+    Path((domain, selector)): Path<(String, String)>,
+) -> ApiResult<StatusCode> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    if !manager(&state)?
+        .delete(&domain, &selector)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(not_found());
+    }
+    info!("{} deleted DKIM key {} for {}", email, selector, domain);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("example.com"));
+        assert!(valid_name("mail-2024"));
+        assert!(valid_name("s1._dkim"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("a..b"));
+        assert!(!valid_name("bad domain"));
+        assert!(!valid_name("semi;colon"));
+    }
+}
@@ -0,0 +1,121 @@
+//! API endpoints for received DMARC and TLS aggregate reports
+//!
+//! Reports are ingested from mail to the `[dmarc_reports]` addresses; see
+//! [`crate::dmarc_reports`].
+
+use crate::api::auth::get_session_email;
+use crate::dmarc_reports::{
+    DmarcReportManager, ReceivedReport, ReportDetail, ReportFilter, ReportKind, SourceBreakdown,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+/// Most reports listed at once
+const MAX_LIMIT: i64 = 500;
+
+/// App state containing the report manager
+pub struct DmarcReportsState {
+    pub manager: Arc<DmarcReportManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No such report")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("DMARC reports API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access aggregate reports",
+    )
+}
+
+/// Query of the listing: `kind` (dmarc or tls), `domain`, `since`
+/// (RFC 3339) and `limit`
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub kind: Option<ReportKind>,
+    pub domain: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+/// Reports with the per-source-IP breakdown of their results
+#[derive(Debug, Serialize)]
+pub struct ReportsResponse {
+    pub reports: Vec<ReceivedReport>,
+    pub sources: Vec<SourceBreakdown>,
+}
+
+/// GET /api/admin/dmarc-reports - Received reports, latest first, and pass
+/// and fail counts per source IP across all matching reports
+pub async fn list_reports(
+    State(state): State<Arc<DmarcReportsState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<ReportsResponse>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let filter = ReportFilter {
+        kind: query.kind,
+        domain: query.domain,
+        since: query.since,
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_LIMIT);
+    let reports = state
+        .manager
+        .list(&filter, limit)
+        .await
+        .map_err(internal_error)?;
+    let sources = state
+        .manager
+        .sources(&filter)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(ReportsResponse { reports, sources }))
+}
+
+/// GET /api/admin/dmarc-reports/:id - One report with its rows
+pub async fn get_report(
+    State(state): State<Arc<DmarcReportsState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<ReportDetail>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let report = state
+        .manager
+        .get(id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(report))
+}
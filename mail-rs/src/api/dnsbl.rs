@@ -0,0 +1,56 @@
+//! API endpoint for DNS blocklist metrics
+//!
+//! Reports, per configured list, how many SMTP clients were looked up, how
+//! many were listed and refused, and how the answer cache performs; see
+//! [`crate::antispam::dnsbl`].
+
+use crate::antispam::dnsbl::{DnsblChecker, DnsblStats};
+use crate::api::auth::get_session_email;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// App state containing the checker shared with the SMTP listener
+pub struct DnsblState {
+    pub checker: Option<Arc<DnsblChecker>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::SERVICE_UNAVAILABLE, "DNSBL checks are not enabled")
+}
+
+/// GET /api/admin/dnsbl - Lookups, hits, errors and rejections per list
+pub async fn stats(
+    State(state): State<Arc<DnsblState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<DnsblStats>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let checker = state.checker.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(checker.stats()))
+}
@@ -0,0 +1,199 @@
+//! API endpoints for message flags and live flag changes
+//!
+//! Flags changed here are published on the shared
+//! [`FlagEventBus`](crate::storage::FlagEventBus), so IMAP sessions, the MCP
+//! tools and the AI runtime see a message marked read in the web interface
+//! as read too. The websocket pushes changes made by any frontend.
+
+use crate::api::auth::get_session_email;
+use crate::imap::uid::base_name;
+use crate::imap::{Mailbox, StoreOperation};
+use crate::residency::ResidencyMap;
+use crate::storage::{FlagEvent, FlagEventBus, FlagSource};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error};
+
+/// Flags that can be stored in a Maildir file name
+const SYSTEM_FLAGS: &[&str] = &["\\Seen", "\\Answered", "\\Flagged", "\\Deleted", "\\Draft"];
+
+/// App state containing the flag event bus
+pub struct FlagsState {
+    pub bus: Arc<FlagEventBus>,
+    pub maildir_root: PathBuf,
+    pub residency: Option<Arc<ResidencyMap>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Message not found")
+}
+
+fn internal_error(e: crate::error::MailError) -> (StatusCode, Json<ApiError>) {
+    error!("Flags API error: {}", e);
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update flags")
+}
+
+fn default_mailbox() -> String {
+    "INBOX".to_string()
+}
+
+/// Request to change a message's flags
+#[derive(Debug, Deserialize)]
+pub struct FlagsRequest {
+    /// Mailbox holding the message
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// Flags of a message after a change
+#[derive(Debug, Serialize)]
+pub struct FlagsResponse {
+    pub mailbox: String,
+    pub message: String,
+    pub flags: Vec<String>,
+}
+
+/// POST /api/messages/:message/flags - Add and remove flags of one of the
+/// current user's messages, e.g. `{"add": ["\\Seen"]}` to mark it read
+///
+/// `message` is the id returned on delivery, the Maildir base name.
+pub async fn update_flags(
+    State(state): State<Arc<FlagsState>>,
+    headers: HeaderMap,
+    Path(message): Path<String>,
+    Json(payload): Json<FlagsRequest>,
+) -> ApiResult<Json<FlagsResponse>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    if let Some(flag) = payload
+        .add
+        .iter()
+        .chain(&payload.remove)
+        .find(|flag| !SYSTEM_FLAGS.contains(&flag.as_str()))
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            &format!("Unsupported flag: {}", flag),
+        ));
+    }
+
+    let root = match &state.residency {
+        Some(residency) => residency.maildir_root(&email, &state.maildir_root),
+        None => state.maildir_root.clone(),
+    };
+    let mut mailbox = Mailbox::open(&email, &payload.mailbox, &root).map_err(|_| not_found())?;
+    let sequence = mailbox
+        .messages()
+        .iter()
+        .find(|msg| base_name(&msg.filename) == base_name(&message))
+        .map(|msg| msg.sequence.to_string())
+        .ok_or_else(not_found)?;
+
+    for (operation, flags) in [
+        (StoreOperation::Add, &payload.add),
+        (StoreOperation::Remove, &payload.remove),
+    ] {
+        if !flags.is_empty() {
+            mailbox
+                .store_flags(&sequence, &operation, flags)
+                .map_err(internal_error)?;
+        }
+    }
+
+    let msg = sequence
+        .parse()
+        .ok()
+        .and_then(|sequence| mailbox.get_message(sequence))
+        .ok_or_else(not_found)?;
+    state.bus.publish(FlagEvent::flags(
+        &email,
+        &payload.mailbox,
+        &msg.filename,
+        &msg.flags,
+        FlagSource::Api,
+    ));
+
+    Ok(Json(FlagsResponse {
+        mailbox: payload.mailbox,
+        message: base_name(&msg.filename).to_string(),
+        flags: msg.flags.clone(),
+    }))
+}
+
+/// GET /api/messages/events - WebSocket receiving flag changes and expunges
+/// of the current user's messages, made through any frontend, as JSON text
+/// messages
+pub async fn websocket(
+    State(state): State<Arc<FlagsState>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let bus = state.bus.clone();
+    Ok(upgrade.on_upgrade(move |socket| forward(socket, bus, email)))
+}
+
+/// Push the user's flag changes until either side closes
+async fn forward(mut socket: WebSocket, bus: Arc<FlagEventBus>, email: String) {
+    let mut events = bus.subscribe();
+    loop {
+        tokio::select! {
+            received = events.recv() => match received {
+                Ok(event) if event.user == email => {
+                    let Ok(json) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Flag event socket of {} skipped {} events", email, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! API endpoints for footers of outgoing mail
+//!
+//! Admins set the footer of each domain and of groups of senders in it, and
+//! the names and departments footers show for senders. Footers are appended
+//! on the submission port when `[footers]` is enabled; see
+//! [`crate::footers`].
+
+use crate::api::auth::get_session_email;
+use crate::footers::{Footer, FooterManager, FooterUpdate, SenderProfile, SenderProfileUpdate};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the footer manager
+pub struct FootersState {
+    pub manager: Arc<FooterManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Footers API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access footers",
+    )
+}
+
+fn validate_domain(domain: &str) -> ApiResult<()> {
+    let valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(api_error(StatusCode::BAD_REQUEST, "Invalid domain"))
+    }
+}
+
+/// Group of senders a footer applies to, the domain's footer if unset
+#[derive(Debug, Default, Deserialize)]
+pub struct GroupQuery {
+    pub group: Option<String>,
+}
+
+impl GroupQuery {
+    fn group(&self) -> Option<&str> {
+        self.group
+            .as_deref()
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+    }
+}
+
+/// GET /api/admin/footers - Footers of all domains and groups
+pub async fn list_footers(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<Footer>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let footers = state.manager.list().await.map_err(internal_error)?;
+    Ok(Json(footers))
+}
+
+/// GET /api/admin/footers/:domain?group= - Footer of a domain or group
+pub async fn get_footer(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+    Query(query): Query<GroupQuery>,
+) -> ApiResult<Json<Footer>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let footer = state
+        .manager
+        .get(&domain, query.group())
+        .await
+        .map_err(internal_error)?;
+    footer
+        .map(Json)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "No footer found"))
+}
+
+/// PUT /api/admin/footers/:domain?group= - Set the footer of a domain or
+/// group
+pub async fn set_footer(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+    Query(query): Query<GroupQuery>,
+    Json(payload): Json<FooterUpdate>,
+) -> ApiResult<Json<Footer>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    validate_domain(&domain)?;
+    payload
+        .validate()
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e))?;
+
+    let footer = state
+        .manager
+        .set(&domain, query.group(), &payload)
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "Admin {}: Set footer of {} (group {:?})",
+        actor, footer.domain, footer.group
+    );
+    Ok(Json(footer))
+}
+
+/// DELETE /api/admin/footers/:domain?group= - Remove the footer of a domain
+/// or group
+pub async fn delete_footer(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+    Query(query): Query<GroupQuery>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let removed = state
+        .manager
+        .delete(&domain, query.group())
+        .await
+        .map_err(internal_error)?;
+    if !removed {
+        return Err(api_error(StatusCode::NOT_FOUND, "No footer found"));
+    }
+    info!(
+        "Admin {}: Removed footer of {} (group {:?})",
+        actor,
+        domain,
+        query.group()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/admin/sender-profiles/:user - Name and department of a sender
+pub async fn get_profile(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+) -> ApiResult<Json<SenderProfile>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let profile = state.manager.profile(&user).await.map_err(internal_error)?;
+    profile
+        .map(Json)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "No sender profile found"))
+}
+
+/// PUT /api/admin/sender-profiles/:user - Change the name or department of
+/// a sender
+pub async fn set_profile(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+    Json(payload): Json<SenderProfileUpdate>,
+) -> ApiResult<Json<SenderProfile>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    if !user.contains('@') {
+        return Err(api_error(StatusCode::BAD_REQUEST, "Invalid user"));
+    }
+
+    let profile = state
+        .manager
+        .set_profile(&user, &payload)
+        .await
+        .map_err(internal_error)?;
+    info!("Admin {}: Set sender profile of {}", actor, profile.user);
+    Ok(Json(profile))
+}
+
+/// DELETE /api/admin/sender-profiles/:user - Remove a sender profile
+pub async fn delete_profile(
+    State(state): State<Arc<FootersState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let removed = state
+        .manager
+        .delete_profile(&user)
+        .await
+        .map_err(internal_error)?;
+    if !removed {
+        return Err(api_error(StatusCode::NOT_FOUND, "No sender profile found"));
+    }
+    info!("Admin {}: Removed sender profile of {}", actor, user);
+    Ok(StatusCode::NO_CONTENT)
+}
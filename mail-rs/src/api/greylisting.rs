@@ -1,7 +1,7 @@
 //! API endpoints for greylisting management
 
 use crate::antispam::greylist::GreylistManager;
-use crate::antispam::types::{GreylistEntry, GreylistStatus, ListEntry};
+use crate::antispam::types::{GreylistEntry, ListEntry};
 use crate::api::auth::get_session_email;
 use axum::{
     extract::{Path, State},
@@ -29,6 +29,21 @@ pub struct GreylistStatsResponse {
     pub whitelisted_count: usize,
     pub blacklisted_count: usize,
     pub total_entries: usize,
+    /// Triplets waiting for a retry
+    pub pending: usize,
+    /// Triplets accepted after retrying
+    pub passed: usize,
+    /// Triplets never retried within the retry window
+    pub expired: usize,
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ApiError {
+            error: e.to_string(),
+        }),
+    )
 }
 
 /// Request to add to whitelist/blacklist
@@ -59,24 +74,16 @@ pub async fn get_stats(
         )
     })?;
 
-    let entries = state.manager.get_entries().await;
-    let whitelist = state.manager.get_whitelist().await;
-    let blacklist = state.manager.get_blacklist().await;
-
-    let greylisted_count = entries
-        .iter()
-        .filter(|e| e.status == GreylistStatus::Greylisted)
-        .count();
-    let whitelisted_in_entries = entries
-        .iter()
-        .filter(|e| e.status == GreylistStatus::Whitelisted)
-        .count();
+    let stats = state.manager.stats().await.map_err(internal_error)?;
 
     Ok(Json(GreylistStatsResponse {
-        greylisted_count,
-        whitelisted_count: whitelist.len() + whitelisted_in_entries,
-        blacklisted_count: blacklist.len(),
-        total_entries: entries.len(),
+        greylisted_count: stats.pending,
+        whitelisted_count: stats.whitelisted + stats.passed,
+        blacklisted_count: stats.blacklisted,
+        total_entries: stats.pending + stats.passed,
+        pending: stats.pending,
+        passed: stats.passed,
+        expired: stats.expired,
     }))
 }
 
@@ -94,7 +101,7 @@ pub async fn list_entries(
         )
     })?;
 
-    let entries = state.manager.get_entries().await;
+    let entries = state.manager.get_entries().await.map_err(internal_error)?;
     Ok(Json(entries))
 }
 
@@ -112,7 +119,7 @@ pub async fn list_whitelist(
         )
     })?;
 
-    let whitelist = state.manager.get_whitelist().await;
+    let whitelist = state.manager.get_whitelist().await.map_err(internal_error)?;
     Ok(Json(whitelist))
 }
 
@@ -201,7 +208,7 @@ pub async fn list_blacklist(
         )
     })?;
 
-    let blacklist = state.manager.get_blacklist().await;
+    let blacklist = state.manager.get_blacklist().await.map_err(internal_error)?;
     Ok(Json(blacklist))
 }
 
@@ -1,23 +1,52 @@
 //! API request handlers
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use tracing::warn;
 
 use crate::api::auth::{Claims, JwtConfig};
+use crate::api::login_anomaly;
+use crate::devices::{DeviceKind, DeviceManager};
+use crate::imap::uid::base_name;
 use crate::imap::Mailbox;
+use crate::mime::{MessageBuilder, MimeNode, MimeParser};
+use crate::login_anomaly::{Decision, LoginAnomalyDetector, LoginProtocol};
+use crate::mfa::MfaManager;
+use crate::openpgp::{mime::encrypt_message, OpenPgpManager};
 use crate::security::{AuthMechanism, Authenticator};
+use crate::smtp::{Ingress, SmtpQueue};
+use crate::storage::MetadataIndex;
 
 /// Shared application state
 pub struct AppState {
     pub authenticator: Authenticator,
     pub jwt_config: JwtConfig,
     pub maildir_root: String,
+    /// Envelopes and counts of stored mail, when indexed
+    pub metadata: Option<Arc<MetadataIndex>>,
+}
+
+/// State of the login endpoints
+pub struct LoginState {
+    pub app: Arc<AppState>,
+    pub devices: Arc<DeviceManager>,
+    /// Holds unusual logins for step-up verification, when enabled
+    pub anomalies: Option<Arc<LoginAnomalyDetector>>,
+    /// Second factors answering step-up challenges
+    pub mfa: Arc<MfaManager>,
+    /// Sends step-up verification links, when this process delivers mail
+    pub queue: Option<Arc<SmtpQueue>>,
+    pub hostname: String,
+    /// Public URL of the API for verification links
+    pub base_url: Option<String>,
 }
 
 /// Login request body
@@ -38,7 +67,7 @@ pub struct LoginResponse {
 #[derive(Debug, Serialize)]
 pub struct EmailSummary {
     pub sequence: usize,
-    pub uid: String,
+    pub uid: u32,
     pub subject: Option<String>,
     pub from: Option<String>,
     pub date: Option<String>,
@@ -50,13 +79,27 @@ pub struct EmailSummary {
 #[derive(Debug, Serialize)]
 pub struct EmailDetail {
     pub sequence: usize,
-    pub uid: String,
+    pub uid: u32,
     pub subject: Option<String>,
     pub from: Option<String>,
     pub to: Option<String>,
     pub date: Option<String>,
     pub body: String,
     pub flags: Vec<String>,
+    pub security: MessageSecurity,
+    /// Decoded MIME tree; each part's content is at
+    /// `/api/mails/:id/parts/:part_id`
+    pub parts: MimeNode,
+}
+
+/// How a message was received and authenticated
+#[derive(Debug, Serialize)]
+pub struct MessageSecurity {
+    /// SPF/DKIM/DMARC results recorded on receipt
+    pub authentication_results: Option<String>,
+    /// Listener, TLS, identity and client of the delivery, when stored
+    /// by this server
+    pub ingress: Option<Ingress>,
 }
 
 /// Folder info
@@ -64,6 +107,7 @@ pub struct EmailDetail {
 pub struct FolderInfo {
     pub name: String,
     pub message_count: usize,
+    pub unseen_count: usize,
 }
 
 /// API error response
@@ -81,29 +125,38 @@ impl ApiError {
 }
 
 /// POST /api/auth/login - Authenticate and get JWT token
+///
+/// The token is bound to the client's device, named by its user agent, so
+/// revoking the device invalidates it. Logins from an unusual location get
+/// a step-up challenge instead of a token when anomaly detection is on.
 pub async fn login(
-    State(state): State<Arc<AppState>>,
+    State(state): State<Arc<LoginState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     Json(req): Json<LoginRequest>,
-) -> impl IntoResponse {
+) -> Response {
     // Verify credentials using PLAIN mechanism (email as username)
-    match state.authenticator.authenticate(&req.email, &req.password).await {
+    match state.app.authenticator.authenticate(&req.email, &req.password).await {
         Ok(true) => {
-            // Generate JWT token
-            match state.jwt_config.create_token(&req.email) {
-                Ok(token) => (
-                    StatusCode::OK,
-                    Json(LoginResponse {
-                        token,
-                        email: req.email,
-                    }),
-                )
-                    .into_response(),
-                Err(_) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ApiError::new("Failed to create token")),
-                )
-                    .into_response(),
+            let user_agent = headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            let ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+
+            if let Some(detector) = &state.anomalies {
+                match detector.assess(&req.email, LoginProtocol::Api, ip).await {
+                    Ok(assessment) if assessment.decision == Decision::StepUp => {
+                        return login_anomaly::step_up(
+                            &state, detector, &req.email, user_agent, ip, &headers,
+                        )
+                        .await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to assess login of {}: {}", req.email, e),
+                }
             }
+            issue_token(&state, &req.email, user_agent, ip).await
         }
         Ok(false) => (
             StatusCode::UNAUTHORIZED,
@@ -118,12 +171,66 @@ pub async fn login(
     }
 }
 
+/// Record the device of a login and answer with its token
+pub(crate) async fn issue_token(
+    state: &LoginState,
+    email: &str,
+    user_agent: &str,
+    ip: Option<IpAddr>,
+) -> Response {
+    let device = match state
+        .devices
+        .record_login(email, DeviceKind::Api, user_agent, ip)
+        .await
+    {
+        Ok(device) => Some(device.id),
+        Err(e) => {
+            warn!("Failed to record device of {}: {}", email, e);
+            None
+        }
+    };
+
+    // Generate JWT token
+    match state
+        .app
+        .jwt_config
+        .create_device_token(email, device.as_deref())
+    {
+        Ok(token) => (
+            StatusCode::OK,
+            Json(LoginResponse {
+                token,
+                email: email.to_string(),
+            }),
+        )
+            .into_response(),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError::new("Failed to create token")),
+        )
+            .into_response(),
+    }
+}
+
 /// GET /api/mails - List emails in INBOX
+///
+/// Subjects, senders and dates come from the metadata index when there is
+/// one; only messages it doesn't know yet are read.
 pub async fn list_emails(
     State(state): State<Arc<AppState>>,
     claims: Claims,
 ) -> impl IntoResponse {
     let maildir_root = std::path::Path::new(&state.maildir_root);
+    let indexed = match &state.metadata {
+        Some(index) => index
+            .folder_messages(&claims.sub, "INBOX")
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to read metadata index of {}: {}", claims.sub, e);
+                Default::default()
+            }),
+        None => Default::default(),
+    };
 
     match Mailbox::open(&claims.sub, "INBOX", maildir_root) {
         Ok(mailbox) => {
@@ -131,7 +238,18 @@ pub async fn list_emails(
                 .messages()
                 .iter()
                 .map(|msg| {
-                    let content_str = String::from_utf8_lossy(&msg.content);
+                    if let Some(metadata) = indexed.get(base_name(&msg.filename)) {
+                        return EmailSummary {
+                            sequence: msg.sequence,
+                            uid: msg.uid,
+                            subject: metadata.subject.clone(),
+                            from: metadata.from.clone(),
+                            date: Some(metadata.date.to_rfc2822()),
+                            size: msg.size,
+                            flags: msg.flags.clone(),
+                        };
+                    }
+                    let content_str = String::from_utf8_lossy(msg.header().unwrap_or_default());
                     let headers = content_str
                         .split("\r\n\r\n")
                         .next()
@@ -139,7 +257,7 @@ pub async fn list_emails(
 
                     EmailSummary {
                         sequence: msg.sequence,
-                        uid: msg.uid.clone(),
+                        uid: msg.uid,
                         subject: extract_header(headers, "Subject"),
                         from: extract_header(headers, "From"),
                         date: extract_header(headers, "Date"),
@@ -168,9 +286,12 @@ pub async fn get_email(
     let maildir_root = std::path::Path::new(&state.maildir_root);
 
     match Mailbox::open(&claims.sub, "INBOX", maildir_root) {
-        Ok(mailbox) => match mailbox.get_message(sequence) {
-            Some(msg) => {
-                let content_str = String::from_utf8_lossy(&msg.content);
+        Ok(mailbox) => match mailbox
+            .get_message(sequence)
+            .and_then(|msg| Some((msg, msg.content().ok()?)))
+        {
+            Some((msg, content)) => {
+                let content_str = String::from_utf8_lossy(content);
                 let (headers, body) = if let Some(pos) = content_str.find("\r\n\r\n") {
                     (&content_str[..pos], &content_str[pos + 4..])
                 } else {
@@ -179,13 +300,18 @@ pub async fn get_email(
 
                 let detail = EmailDetail {
                     sequence: msg.sequence,
-                    uid: msg.uid.clone(),
+                    uid: msg.uid,
                     subject: extract_header(headers, "Subject"),
                     from: extract_header(headers, "From"),
                     to: extract_header(headers, "To"),
                     date: extract_header(headers, "Date"),
                     body: body.to_string(),
                     flags: msg.flags.clone(),
+                    security: MessageSecurity {
+                        authentication_results: extract_header(headers, "Authentication-Results"),
+                        ingress: Ingress::from_message(content),
+                    },
+                    parts: MimeParser::parse_parts(content),
                 };
 
                 (StatusCode::OK, Json(detail)).into_response()
@@ -204,24 +330,114 @@ pub async fn get_email(
     }
 }
 
+/// GET /api/mails/:id/parts/:part_id - Decoded content of one MIME part,
+/// addressed by the IMAP section number listed in the email's `parts`
+pub async fn get_email_part(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    Path((sequence, part_id)): Path<(usize, String)>,
+) -> impl IntoResponse {
+    let maildir_root = std::path::Path::new(&state.maildir_root);
+
+    let Ok(mailbox) = Mailbox::open(&claims.sub, "INBOX", maildir_root) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new("Mailbox not found")),
+        )
+            .into_response();
+    };
+    let Some(content) = mailbox
+        .get_message(sequence)
+        .and_then(|msg| msg.content().ok())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new("Email not found")),
+        )
+            .into_response();
+    };
+
+    let root = MimeParser::parse_tree(content);
+    let Some(entity) = root.part_by_id(&part_id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new("Part not found")),
+        )
+            .into_response();
+    };
+
+    let (content_type, body) = if entity.content_type.starts_with("text/") {
+        (
+            format!("{}; charset=utf-8", entity.content_type),
+            entity.text().into_bytes(),
+        )
+    } else {
+        (entity.content_type.clone(), entity.decoded_body())
+    };
+    let disposition = match entity.filename() {
+        Some(filename) => format!(
+            "{}; filename=\"{}\"",
+            if entity.is_attachment() { "attachment" } else { "inline" },
+            filename
+                .chars()
+                .map(|c| match c {
+                    ' ' => c,
+                    '"' | '\\' => '_',
+                    c if c.is_ascii_graphic() => c,
+                    _ => '_',
+                })
+                .collect::<String>()
+        ),
+        None => "inline".to_string(),
+    };
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
 /// GET /api/folders - List available folders
+///
+/// Counts come from the metadata index once the user's mailbox is indexed,
+/// otherwise every folder is opened.
 pub async fn list_folders(
     State(state): State<Arc<AppState>>,
     claims: Claims,
 ) -> impl IntoResponse {
     let maildir_root = std::path::Path::new(&state.maildir_root);
+    let counts = match &state.metadata {
+        Some(index) if index.is_indexed(&claims.sub).await.unwrap_or(false) => {
+            index.counts(&claims.sub).await.ok()
+        }
+        _ => None,
+    };
 
     match Mailbox::list_mailboxes(&claims.sub, maildir_root) {
         Ok(mailboxes) => {
             let folders: Vec<FolderInfo> = mailboxes
                 .iter()
-                .filter_map(|name| {
-                    Mailbox::open(&claims.sub, name, maildir_root)
+                .filter_map(|name| match &counts {
+                    Some(counts) => {
+                        let folder = counts.iter().find(|folder| &folder.folder == name);
+                        Some(FolderInfo {
+                            name: name.clone(),
+                            message_count: folder.map_or(0, |folder| folder.messages),
+                            unseen_count: folder.map_or(0, |folder| folder.unseen),
+                        })
+                    }
+                    None => Mailbox::open(&claims.sub, name, maildir_root)
                         .ok()
                         .map(|mb| FolderInfo {
                             name: name.clone(),
                             message_count: mb.message_count(),
-                        })
+                            unseen_count: mb.unseen_count(),
+                        }),
                 })
                 .collect();
 
@@ -241,6 +457,41 @@ pub struct SendEmailRequest {
     pub to: String,
     pub subject: String,
     pub body: String,
+    /// HTML alternative of `body`
+    #[serde(default)]
+    pub html: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<SendAttachment>,
+    /// Encrypt with OpenPGP to the recipient's public key
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+/// File attached to a sent email
+#[derive(Debug, Deserialize)]
+pub struct SendAttachment {
+    pub filename: String,
+    #[serde(default = "default_attachment_type")]
+    pub content_type: String,
+    /// Base64 content
+    pub data: String,
+    /// Show inline under this Content-ID instead of attaching, for
+    /// `cid:` references in the HTML body
+    #[serde(default)]
+    pub content_id: Option<String>,
+}
+
+fn default_attachment_type() -> String {
+    "application/octet-stream".to_string()
+}
+
+/// State of the send endpoint
+pub struct SendState {
+    /// Keeps mail that capture mode doesn't deliver, when this process runs
+    /// a queue
+    pub queue: Option<Arc<SmtpQueue>>,
+    /// Recipient keys of encrypted mail, when OpenPGP is enabled
+    pub openpgp: Option<Arc<OpenPgpManager>>,
 }
 
 /// Send email response
@@ -252,7 +503,7 @@ pub struct SendEmailResponse {
 
 /// POST /api/mails/send - Send an email
 pub async fn send_email(
-    State(state): State<Arc<AppState>>,
+    State(state): State<Arc<SendState>>,
     claims: Claims,
     Json(req): Json<SendEmailRequest>,
 ) -> impl IntoResponse {
@@ -267,23 +518,89 @@ pub async fn send_email(
     );
 
     // Build email content
-    let email_content = format!(
-        "From: {}\r\n\
-         To: {}\r\n\
-         Subject: {}\r\n\
-         Message-ID: {}\r\n\
-         Date: {}\r\n\
-         MIME-Version: 1.0\r\n\
-         Content-Type: text/plain; charset=utf-8\r\n\
-         \r\n\
-         {}",
-        claims.sub,
-        req.to,
-        req.subject,
-        message_id,
-        chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S +0000"),
-        req.body
-    );
+    let mut builder = MessageBuilder::new()
+        .from(&claims.sub)
+        .to(&req.to)
+        .subject(&req.subject)
+        .message_id(&message_id)
+        .text(&req.body);
+    if let Some(html) = &req.html {
+        builder = builder.html(html);
+    }
+    for attachment in &req.attachments {
+        let data = match BASE64.decode(attachment.data.trim()) {
+            Ok(data) => data,
+            Err(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(ApiError::new(&format!(
+                        "Attachment {} is not valid base64",
+                        attachment.filename
+                    ))),
+                )
+                    .into_response()
+            }
+        };
+        builder = match &attachment.content_id {
+            Some(content_id) => builder.inline_image(content_id, &attachment.content_type, data),
+            None => builder.attachment(&attachment.filename, &attachment.content_type, data),
+        };
+    }
+    let mut email_content = builder.build();
+
+    if req.encrypt {
+        let Some(openpgp) = &state.openpgp else {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ApiError::new("OpenPGP is not enabled")),
+            )
+                .into_response();
+        };
+        let recipients = std::slice::from_ref(&req.to);
+        let encrypted = match openpgp.recipient_keys(&claims.sub, recipients).await {
+            Ok((_, missing)) if !missing.is_empty() => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(ApiError::new(&format!(
+                        "No OpenPGP key for {}",
+                        missing.join(", ")
+                    ))),
+                )
+                    .into_response()
+            }
+            Ok((keys, _)) => encrypt_message(&email_content, &keys),
+            Err(e) => Err(e),
+        };
+        email_content = match encrypted {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiError::new(&format!("Failed to encrypt email: {}", e))),
+                )
+                    .into_response()
+            }
+        };
+    }
+
+    // Capture mode keeps the message in the queue instead of sending it
+    if let Some(queue) = state.queue.as_ref().filter(|queue| queue.captures(&req.to)) {
+        return match queue.enqueue(&claims.sub, &req.to, &email_content).await {
+            Ok(_) => (
+                StatusCode::OK,
+                Json(SendEmailResponse {
+                    message_id,
+                    status: "captured".to_string(),
+                }),
+            )
+                .into_response(),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError::new(&format!("Failed to capture email: {}", e))),
+            )
+                .into_response(),
+        };
+    }
 
     // Extract recipient domain
     let recipient_domain = match req.to.split('@').nth(1) {
@@ -313,7 +630,7 @@ pub async fn send_email(
     let smtp_addr = format!("{}:25", mx_host);
     let client = SmtpClient::new(smtp_addr);
 
-    match client.send_mail(&claims.sub, &req.to, email_content.as_bytes()).await {
+    match client.send_mail(&claims.sub, &req.to, &email_content).await {
         Ok(_) => (
             StatusCode::OK,
             Json(SendEmailResponse {
@@ -379,11 +696,22 @@ pub struct MetricsState {
     pub metrics: crate::api::Metrics,
 }
 
-/// Helper: Extract header value from headers string
+/// Helper: Extract header value from headers string, unfolded and with
+/// encoded words decoded
 fn extract_header(headers: &str, name: &str) -> Option<String> {
-    for line in headers.lines() {
-        if line.to_lowercase().starts_with(&format!("{}:", name.to_lowercase())) {
-            return line.split_once(':').map(|(_, v)| v.trim().to_string());
+    let prefix = format!("{}:", name.to_lowercase());
+    let mut lines = headers.lines();
+    while let Some(line) = lines.next() {
+        if line.to_lowercase().starts_with(&prefix) {
+            let mut value = line[prefix.len()..].to_string();
+            for next in lines.by_ref() {
+                if !next.starts_with([' ', '\t']) {
+                    break;
+                }
+                value.push_str("\r\n");
+                value.push_str(next);
+            }
+            return Some(crate::mime::decode_header(&value));
         }
     }
     None
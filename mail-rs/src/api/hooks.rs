@@ -0,0 +1,237 @@
+//! API endpoints for delivery hook management
+//!
+//! Hooks are addressed by their target, a domain (`example.com`) or a
+//! mailbox (`bob@example.com`). Modules are uploaded base64-encoded as new
+//! versions, and can be tried on a message with a dry run before being
+//! activated.
+
+use crate::api::auth::get_session_email;
+use crate::hooks::{
+    DeliveryHook, DryRunHookRequest, HookInput, HookManager, HookReport, HookScope, HookVersion,
+    UploadHookRequest,
+};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the hook manager
+pub struct HooksState {
+    pub manager: Option<Arc<HookManager>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::SERVICE_UNAVAILABLE, "Delivery hooks are not enabled")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Hook not found")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Hook API error: {}", e);
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to access hooks")
+}
+
+fn parse_target(target: &str) -> ApiResult<HookScope> {
+    HookScope::parse(target)
+        .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Invalid hook target"))
+}
+
+fn decode_module(module: &str) -> ApiResult<Vec<u8>> {
+    BASE64
+        .decode(module.trim())
+        .map_err(|_| api_error(StatusCode::BAD_REQUEST, "Module is not valid base64"))
+}
+
+/// GET /api/admin/hooks - List hooks of all domains and users
+pub async fn list_hooks(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<DeliveryHook>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(manager.list_hooks().await.map_err(internal_error)?))
+}
+
+/// GET /api/admin/hooks/:target - Get the hook of a domain or user
+pub async fn get_hook(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+) -> ApiResult<Json<DeliveryHook>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    let hook = manager
+        .get_hook(&scope)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(hook))
+}
+
+/// DELETE /api/admin/hooks/:target - Delete a hook and its versions
+pub async fn delete_hook(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+) -> ApiResult<StatusCode> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    if !manager.delete(&scope).await.map_err(internal_error)? {
+        return Err(not_found());
+    }
+    info!("Admin {}: Deleted hook of {}", admin, scope.target());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/admin/hooks/:target/versions - List saved versions
+pub async fn list_versions(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+) -> ApiResult<Json<Vec<HookVersion>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    Ok(Json(manager.list_versions(&scope).await.map_err(internal_error)?))
+}
+
+/// POST /api/admin/hooks/:target/versions - Upload a new version
+pub async fn upload_version(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+    Json(payload): Json<UploadHookRequest>,
+) -> ApiResult<(StatusCode, Json<HookVersion>)> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    let module = decode_module(&payload.module)?;
+    let version = manager
+        .upload(
+            &scope,
+            &module,
+            &admin,
+            payload.comment.as_deref(),
+            payload.activate,
+        )
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    info!(
+        "Admin {}: Uploaded version {} of hook of {}{}",
+        admin,
+        version.version,
+        version.target,
+        if payload.activate { " (active)" } else { "" }
+    );
+    Ok((StatusCode::CREATED, Json(version)))
+}
+
+/// POST /api/admin/hooks/:target/versions/:version/activate - Run this
+/// version on delivery
+pub async fn activate_version(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path((target, version)): Path<(String, u32)>,
+) -> ApiResult<Json<DeliveryHook>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    let hook = manager
+        .activate(&scope, version)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Version not found"))?;
+    info!(
+        "Admin {}: Activated version {} of hook of {}",
+        admin,
+        version,
+        scope.target()
+    );
+    Ok(Json(hook))
+}
+
+/// POST /api/admin/hooks/:target/deactivate - Stop running the hook
+pub async fn deactivate_hook(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+) -> ApiResult<Json<DeliveryHook>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    let hook = manager
+        .deactivate(&scope)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    info!("Admin {}: Deactivated hook of {}", admin, scope.target());
+    Ok(Json(hook))
+}
+
+/// POST /api/admin/hooks/:target/dry-run - Run a version, or a module not
+/// uploaded yet, on a message without delivering it
+pub async fn dry_run(
+    State(state): State<Arc<HooksState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+    Json(payload): Json<DryRunHookRequest>,
+) -> ApiResult<Json<HookReport>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let scope = parse_target(&target)?;
+    let module = payload.module.as_deref().map(decode_module).transpose()?;
+
+    let recipient = payload.recipient.clone().unwrap_or_else(|| match &scope {
+        HookScope::User(address) => address.clone(),
+        HookScope::Domain(domain) => format!("postmaster@{}", domain),
+    });
+    let input = HookInput::from_message(
+        payload.message.as_bytes(),
+        payload.sender.as_deref().unwrap_or_default(),
+        &recipient,
+    );
+    let report = manager
+        .dry_run(&scope, payload.version, module.as_deref(), input)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Version not found"))?;
+    Ok(Json(report))
+}
@@ -0,0 +1,191 @@
+//! API endpoints for display-name impersonation protection
+//!
+//! Admins maintain the names protected in each domain and whether mail
+//! impersonating them is quarantined. The check itself runs on inbound
+//! SMTP when `[impersonation]` is enabled; see
+//! [`crate::antispam::impersonation`].
+
+use crate::antispam::{ImpersonationGuard, ImpersonationPolicy};
+use crate::api::auth::get_session_email;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the impersonation policies
+pub struct ImpersonationState {
+    pub guard: Arc<ImpersonationGuard>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No impersonation policy found")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Impersonation API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access impersonation policies",
+    )
+}
+
+fn validate_domain(domain: &str) -> ApiResult<()> {
+    let valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(api_error(StatusCode::BAD_REQUEST, "Invalid domain"))
+    }
+}
+
+/// Request to change the quarantine setting of a domain
+#[derive(Debug, Deserialize)]
+pub struct PolicyRequest {
+    pub quarantine: bool,
+}
+
+/// Request to protect a name
+#[derive(Debug, Deserialize)]
+pub struct NameRequest {
+    pub name: String,
+}
+
+/// GET /api/admin/impersonation - Policies of all domains
+pub async fn list_policies(
+    State(state): State<Arc<ImpersonationState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<ImpersonationPolicy>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let policies = state.guard.policies().await.map_err(internal_error)?;
+    Ok(Json(policies))
+}
+
+/// GET /api/admin/impersonation/:domain - Protected names of a domain
+pub async fn get_policy(
+    State(state): State<Arc<ImpersonationState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+) -> ApiResult<Json<ImpersonationPolicy>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let policy = state.guard.policy(&domain).await.map_err(internal_error)?;
+    policy.map(Json).ok_or_else(not_found)
+}
+
+/// PUT /api/admin/impersonation/:domain - Turn quarantine on or off
+pub async fn set_policy(
+    State(state): State<Arc<ImpersonationState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+    Json(payload): Json<PolicyRequest>,
+) -> ApiResult<Json<ImpersonationPolicy>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    validate_domain(&domain)?;
+
+    let policy = state
+        .guard
+        .set_quarantine(&domain, payload.quarantine)
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "Admin {}: Set impersonation quarantine of {} to {}",
+        actor, policy.domain, policy.quarantine
+    );
+    Ok(Json(policy))
+}
+
+/// DELETE /api/admin/impersonation/:domain - Remove a domain's policy
+pub async fn delete_policy(
+    State(state): State<Arc<ImpersonationState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let removed = state
+        .guard
+        .remove_domain(&domain)
+        .await
+        .map_err(internal_error)?;
+    if !removed {
+        return Err(not_found());
+    }
+    info!(
+        "Admin {}: Removed impersonation policy of {}",
+        actor, domain
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/admin/impersonation/:domain/names - Protect a name
+pub async fn add_name(
+    State(state): State<Arc<ImpersonationState>>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+    Json(payload): Json<NameRequest>,
+) -> ApiResult<(StatusCode, Json<ImpersonationPolicy>)> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    validate_domain(&domain)?;
+
+    let policy = state
+        .guard
+        .add_name(&domain, &payload.name)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    info!(
+        "Admin {}: Protected name {:?} in {}",
+        actor, payload.name, policy.domain
+    );
+    Ok((StatusCode::CREATED, Json(policy)))
+}
+
+/// DELETE /api/admin/impersonation/:domain/names/:name - Stop protecting a
+/// name
+pub async fn remove_name(
+    State(state): State<Arc<ImpersonationState>>,
+    headers: HeaderMap,
+    Path((domain, name)): Path<(String, String)>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let removed = state
+        .guard
+        .remove_name(&domain, &name)
+        .await
+        .map_err(internal_error)?;
+    if !removed {
+        return Err(api_error(StatusCode::NOT_FOUND, "Name is not protected"));
+    }
+    info!("Admin {}: Unprotected name {:?} in {}", actor, name, domain);
+    Ok(StatusCode::NO_CONTENT)
+}
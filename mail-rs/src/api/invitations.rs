@@ -0,0 +1,163 @@
+//! API endpoints for calendar invitations received by mail
+//!
+//! Invitations are recorded on delivery when `[calendar_invitations]` is
+//! enabled; see [`crate::caldav::itip`]. Answering one updates the event in
+//! the user's calendar and mails the iTIP reply to the organizer.
+
+use crate::api::auth::get_session_email;
+use crate::caldav::itip::PartStat;
+use crate::caldav::{CalDavManager, Invitation, InvitationStatus};
+use crate::smtp::SmtpQueue;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the calendars and the outbound queue
+pub struct InvitationState {
+    pub manager: Arc<CalDavManager>,
+    pub queue: Option<Arc<SmtpQueue>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Invitation not found")
+}
+
+fn internal_error(e: impl std::fmt::Display) -> (StatusCode, Json<ApiError>) {
+    error!("Invitation API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access invitations",
+    )
+}
+
+/// GET /api/calendar/invitations - Invitations of the current user
+pub async fn list_invitations(
+    State(state): State<Arc<InvitationState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<Invitation>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let invitations = state
+        .manager
+        .list_invitations(&email)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(invitations))
+}
+
+/// GET /api/calendar/invitations/:id - One invitation
+pub async fn get_invitation(
+    State(state): State<Arc<InvitationState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Invitation>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let invitation = state
+        .manager
+        .get_invitation(&email, &id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(invitation))
+}
+
+/// POST /api/calendar/invitations/:id/accept - Accept and keep the event
+pub async fn accept_invitation(
+    State(state): State<Arc<InvitationState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Invitation>> {
+    respond(&state, &headers, &id, PartStat::Accepted).await
+}
+
+/// POST /api/calendar/invitations/:id/tentative - Accept tentatively
+pub async fn tentative_invitation(
+    State(state): State<Arc<InvitationState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Invitation>> {
+    respond(&state, &headers, &id, PartStat::Tentative).await
+}
+
+/// POST /api/calendar/invitations/:id/decline - Decline and remove the event
+pub async fn decline_invitation(
+    State(state): State<Arc<InvitationState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Invitation>> {
+    respond(&state, &headers, &id, PartStat::Declined).await
+}
+
+async fn respond(
+    state: &InvitationState,
+    headers: &HeaderMap,
+    id: &str,
+    partstat: PartStat,
+) -> ApiResult<Json<Invitation>> {
+    let email = get_session_email(headers).ok_or_else(unauthorized)?;
+    let queue = state.queue.as_ref().ok_or_else(|| {
+        api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Replies can't be sent without an outbound queue",
+        )
+    })?;
+
+    let invitation = state
+        .manager
+        .get_invitation(&email, id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    if invitation.status == InvitationStatus::Cancelled {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "The organizer cancelled this invitation",
+        ));
+    }
+
+    let (invitation, reply) = state
+        .manager
+        .respond_invitation(&email, id, partstat)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    queue
+        .enqueue(&email, &invitation.organizer, &reply)
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "{} answered invitation {} of {}: {}",
+        email,
+        invitation.uid,
+        invitation.organizer,
+        partstat.as_str()
+    );
+    Ok(Json(invitation))
+}
@@ -0,0 +1,65 @@
+//! API endpoints to inspect and change log levels at runtime
+//!
+//! Changes apply immediately and last until restart; edit `[logging]` in
+//! the config file to keep them.
+
+use crate::api::auth::get_session_email;
+use crate::logging::{self, LogLevels};
+use axum::{
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use tracing::info;
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Runtime log control is not enabled in this process",
+    )
+}
+
+/// GET /api/admin/logging - Current default and per-module levels
+pub async fn get_levels(headers: HeaderMap) -> ApiResult<Json<LogLevels>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let control = logging::control().ok_or_else(unavailable)?;
+    Ok(Json(control.levels()))
+}
+
+/// PUT /api/admin/logging - Replace the default and per-module levels
+pub async fn set_levels(
+    headers: HeaderMap,
+    Json(levels): Json<LogLevels>,
+) -> ApiResult<Json<LogLevels>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let control = logging::control().ok_or_else(unavailable)?;
+    control
+        .set_levels(levels.clone())
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+
+    info!("Log levels set to {} by {}", levels.directives(), admin);
+    Ok(Json(levels))
+}
@@ -0,0 +1,390 @@
+//! API endpoints for login anomaly step-up verification and overrides
+//!
+//! A successful `POST /api/auth/login` from an unusual location answers
+//! `403` with a step-up challenge instead of a token. The client completes
+//! it with `POST /api/auth/step-up`, sending the user's TOTP code, or
+//! without a code once the user followed the link mailed to them (or an
+//! admin approved the login). Admins review the decision audit log, lift
+//! protocol login blocks and approve held logins under
+//! `/api/admin/login-anomalies`.
+
+use crate::api::auth::get_session_email;
+use crate::api::handlers::{issue_token, LoginState};
+use crate::login_anomaly::{
+    AuditEntry, LoginAnomalyDetector, LoginBlock, StepUpChallenge, StepUpMethod,
+};
+use crate::mfa::MfaVerifyResult;
+use askama_axum::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Audit entries returned when the query does not say
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+/// App state containing the login anomaly detector
+pub struct LoginAnomalyState {
+    pub detector: Option<Arc<LoginAnomalyDetector>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Login anomaly detection is not enabled",
+    )
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Login anomaly API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access login anomalies",
+    )
+}
+
+/// Answer to a login held for step-up verification
+#[derive(Debug, Serialize)]
+pub struct StepUpRequired {
+    pub error: String,
+    pub challenge: String,
+    /// Ways the login can be verified; none means only an admin can
+    /// approve it
+    pub methods: Vec<StepUpMethod>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Step-up completion request
+#[derive(Debug, Deserialize)]
+pub struct StepUpRequest {
+    pub challenge: String,
+    /// TOTP or backup code; omitted once the email link was followed
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+/// Audit log query
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub user: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Admin override request
+#[derive(Debug, Default, Deserialize)]
+pub struct OverrideRequest {
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Page confirming a login from its email link
+#[derive(Template)]
+#[template(path = "step_up.html")]
+struct StepUpTemplate {
+    token: String,
+    confirmed: bool,
+    /// Why the link no longer works, if it doesn't
+    error: String,
+}
+
+/// Hold a login of `email` whose credentials were valid: start a challenge,
+/// mail its verification link and tell the client how to complete it
+pub(crate) async fn step_up(
+    state: &LoginState,
+    detector: &LoginAnomalyDetector,
+    email: &str,
+    user_agent: &str,
+    ip: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Response {
+    let (challenge, token) = match detector.start_step_up(email, user_agent, ip).await {
+        Ok(started) => started,
+        Err(e) => return internal_error(e).into_response(),
+    };
+
+    let mut methods = Vec::new();
+    if let Some(queue) = &state.queue {
+        let base_url = match &state.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!("https://{}", request_host(headers)),
+        };
+        let url = format!("{}/login/verify/{}", base_url, token);
+        let message = verification_message(&state.hostname, &challenge, &url);
+        // Null reverse-path, like notifications
+        match queue.enqueue("", &challenge.user, &message).await {
+            Ok(_) => methods.push(StepUpMethod::Email),
+            Err(e) => warn!("Failed to send sign-in verification to {}: {}", email, e),
+        }
+    }
+    match state.mfa.is_enabled(email).await {
+        Ok(true) => methods.push(StepUpMethod::Totp),
+        Ok(false) => {}
+        Err(e) => warn!("Failed to look up MFA of {}: {}", email, e),
+    }
+    info!(
+        "Login of {} held for step-up verification ({})",
+        email, challenge.id
+    );
+
+    (
+        StatusCode::FORBIDDEN,
+        Json(StepUpRequired {
+            error: "Sign-in from an unusual location needs verification".to_string(),
+            challenge: challenge.id,
+            methods,
+            expires_at: challenge.expires_at,
+        }),
+    )
+        .into_response()
+}
+
+/// POST /api/auth/step-up - Complete a held login and get its token
+pub async fn complete_step_up(
+    State(state): State<Arc<LoginState>>,
+    Json(payload): Json<StepUpRequest>,
+) -> Response {
+    match try_complete_step_up(&state, payload).await {
+        Ok(response) => response,
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn try_complete_step_up(state: &LoginState, payload: StepUpRequest) -> ApiResult<Response> {
+    let detector = state.anomalies.as_ref().ok_or_else(unavailable)?;
+    let challenge = detector
+        .challenge(&payload.challenge)
+        .await
+        .map_err(internal_error)?
+        .filter(|challenge| challenge.is_pending(Utc::now()))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Unknown or expired challenge"))?;
+
+    let totp_verified = match payload.code.as_deref() {
+        Some(code) => {
+            let result = state
+                .mfa
+                .verify(&challenge.user, code)
+                .await
+                .map_err(internal_error)?;
+            match result {
+                MfaVerifyResult::Valid => true,
+                MfaVerifyResult::RateLimited => {
+                    return Err(api_error(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too many attempts, try again later",
+                    ))
+                }
+                _ => {
+                    detector
+                        .record_step_up_failure(&challenge, "invalid code")
+                        .await
+                        .map_err(internal_error)?;
+                    return Err(api_error(StatusCode::UNAUTHORIZED, "Invalid code"));
+                }
+            }
+        }
+        None => false,
+    };
+
+    let challenge = detector
+        .complete_step_up(&challenge.id, totp_verified)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::FORBIDDEN, "Sign-in not verified yet"))?;
+    let ip = challenge.ip.as_deref().and_then(|ip| ip.parse().ok());
+    Ok(issue_token(state, &challenge.user, &challenge.device_name, ip).await)
+}
+
+/// GET /login/verify/:token - Page confirming a held login
+///
+/// Following the link only shows the page; mail scanners that prefetch
+/// links must not verify the login.
+pub async fn verify_page(Path(token): Path<String>) -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        StepUpTemplate {
+            token,
+            confirmed: false,
+            error: String::new(),
+        },
+    )
+}
+
+/// POST /login/verify/:token - Confirm a held login
+pub async fn verify_submit(
+    State(state): State<Arc<LoginState>>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    let verified = match &state.anomalies {
+        Some(detector) => detector.verify_email(&token).await,
+        None => Ok(None),
+    };
+    let (confirmed, error) = match verified {
+        Ok(Some(_)) => (true, String::new()),
+        Ok(None) => (false, "This link expired or was already used.".to_string()),
+        Err(e) => {
+            warn!("Failed to verify sign-in link: {}", e);
+            (false, "This link cannot be opened right now.".to_string())
+        }
+    };
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        StepUpTemplate {
+            token,
+            confirmed,
+            error,
+        },
+    )
+}
+
+/// GET /api/admin/login-anomalies/audit - Most recent login decisions
+pub async fn audit_log(
+    State(state): State<Arc<LoginAnomalyState>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> ApiResult<Json<Vec<AuditEntry>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let detector = state.detector.as_ref().ok_or_else(unavailable)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let entries = detector
+        .audit_log(query.user.as_deref(), limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(entries))
+}
+
+/// GET /api/admin/login-anomalies/blocks - Protocol login blocks in force
+pub async fn list_blocks(
+    State(state): State<Arc<LoginAnomalyState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<LoginBlock>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let detector = state.detector.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(detector.blocks().await.map_err(internal_error)?))
+}
+
+/// POST /api/admin/login-anomalies/blocks/:user/lift - Lift the blocks of
+/// a user and trust the locations they were raised for
+pub async fn lift_blocks(
+    State(state): State<Arc<LoginAnomalyState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+    Json(payload): Json<OverrideRequest>,
+) -> ApiResult<Json<Vec<LoginBlock>>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let detector = state.detector.as_ref().ok_or_else(unavailable)?;
+    let blocks = detector
+        .unblock(&user, &admin, payload.reason.as_deref())
+        .await
+        .map_err(internal_error)?;
+    if blocks.is_empty() {
+        return Err(api_error(StatusCode::NOT_FOUND, "No login block found"));
+    }
+    Ok(Json(blocks))
+}
+
+/// GET /api/admin/login-anomalies/step-ups - Logins awaiting verification
+pub async fn list_step_ups(
+    State(state): State<Arc<LoginAnomalyState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<StepUpChallenge>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let detector = state.detector.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(
+        detector.pending_challenges().await.map_err(internal_error)?,
+    ))
+}
+
+/// POST /api/admin/login-anomalies/step-ups/:id/approve - Verify a held
+/// login on the user's behalf; the client then completes it
+pub async fn approve_step_up(
+    State(state): State<Arc<LoginAnomalyState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(payload): Json<OverrideRequest>,
+) -> ApiResult<Json<StepUpChallenge>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let detector = state.detector.as_ref().ok_or_else(unavailable)?;
+    let challenge = detector
+        .approve(&id, &admin, payload.reason.as_deref())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "No pending challenge found"))?;
+    info!(
+        "Admin {}: Approved sign-in {} of {}",
+        admin, challenge.id, challenge.user
+    );
+    Ok(Json(challenge))
+}
+
+/// Mail with the verification link of `challenge`
+fn verification_message(hostname: &str, challenge: &StepUpChallenge, url: &str) -> Vec<u8> {
+    format!(
+        "From: Security <postmaster@{host}>\r\n\
+         To: <{to}>\r\n\
+         Subject: Confirm your sign-in\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@{host}>\r\n\
+         Auto-Submitted: auto-generated\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         Someone signed in to your account from {ip}, a place you did not\r\n\
+         sign in from before. If it was you, confirm the sign-in before\r\n\
+         {expires}:\r\n\
+         \r\n\
+         {url}\r\n\
+         \r\n\
+         If it was not you, change your password.\r\n",
+        host = hostname,
+        to = challenge.user,
+        date = Utc::now().to_rfc2822(),
+        id = Uuid::new_v4(),
+        ip = challenge.ip.as_deref().unwrap_or("an unknown address"),
+        expires = challenge.expires_at.format("%Y-%m-%d %H:%M UTC"),
+        url = url,
+    )
+    .into_bytes()
+}
+
+fn request_host(headers: &HeaderMap) -> String {
+    headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost")
+        .to_string()
+}
@@ -0,0 +1,86 @@
+//! API endpoints for migration coexistence
+
+use crate::api::auth::get_session_email;
+use crate::migration::{MigrationManager, UserMigrationStatus};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+/// App state containing migration manager
+pub struct MigrationState {
+    pub manager: Arc<MigrationManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+/// Request to set a user's migration flag
+#[derive(Deserialize)]
+pub struct SetMigratedRequest {
+    pub migrated: bool,
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(ApiError {
+            error: "Not authenticated".to_string(),
+        }),
+    )
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Migration API error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ApiError {
+            error: "Failed to access migration status".to_string(),
+        }),
+    )
+}
+
+/// GET /api/admin/migration/users - List per-user migration flags
+pub async fn list_users(
+    State(state): State<Arc<MigrationState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<UserMigrationStatus>>, (StatusCode, Json<ApiError>)> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let status = state.manager.list_user_status().await.map_err(internal_error)?;
+    Ok(Json(status))
+}
+
+/// PUT /api/admin/migration/users/:email - Mark a user as migrated or not
+pub async fn set_user_migrated(
+    State(state): State<Arc<MigrationState>>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+    Json(payload): Json<SetMigratedRequest>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    if !email.contains('@') {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "Invalid email format".to_string(),
+            }),
+        ));
+    }
+
+    state
+        .manager
+        .set_user_migrated(&email, payload.migrated)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
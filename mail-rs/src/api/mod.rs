@@ -3,23 +3,60 @@
 //! Provides HTTP API endpoints for email operations
 
 pub mod admin;
+pub mod aliases;
+pub mod antivirus;
 pub mod auth;
+pub mod auth_cache;
 pub mod auto_reply;
+pub mod autoconfig;
+pub mod bandwidth;
+pub mod billing;
+pub mod branding;
+pub mod budgets;
 pub mod caldav;
+pub mod capture;
+pub mod chaos;
+pub mod config_drift;
+pub mod devices;
+pub mod dkim;
+pub mod dmarc_reports;
+pub mod dnsbl;
+pub mod flags;
+pub mod footers;
 pub mod greylisting;
+pub mod hooks;
 pub mod handlers;
+pub mod impersonation;
 pub mod import_export;
+pub mod invitations;
+pub mod logging;
+pub mod login_anomaly;
 pub mod metrics;
 pub mod mfa;
+pub mod migration;
 pub mod monitoring;
+pub mod mta_sts;
+pub mod notifications;
+pub mod openpgp;
+pub mod queue;
+pub mod quarantine;
 pub mod quotas;
+pub mod recovery;
+pub mod reports;
+pub mod residency;
+pub mod retention;
+pub mod role_accounts;
 pub mod search;
 pub mod security_stats;
 pub mod server;
+pub mod sharing;
 pub mod sieve;
 pub mod spam;
+pub mod storage_jobs;
 pub mod templates;
+pub mod tls_reports;
 pub mod web;
+pub mod workers;
 
 pub use metrics::Metrics;
 pub use server::ApiServer;
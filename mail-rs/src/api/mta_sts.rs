@@ -0,0 +1,34 @@
+//! MTA-STS policy hosting
+//!
+//! Serves the policy of `[mta_sts]` at `/.well-known/mta-sts.txt`. Senders
+//! fetch it from `https://mta-sts.<domain>/`, which the CNAME suggested by
+//! the DNS helper points at; TLS is terminated by proxy-rs or another
+//! reverse proxy holding a certificate for that name. The same policy
+//! answers for every hosted domain since they share the MX hosts.
+
+use crate::admin::mta_sts::MtaStsPolicy;
+use crate::config::Config;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use std::sync::Arc;
+
+/// App state for the policy document
+pub struct MtaStsState {
+    pub config: Arc<Config>,
+}
+
+/// GET /.well-known/mta-sts.txt - The MTA-STS policy
+pub async fn policy(State(state): State<Arc<MtaStsState>>) -> impl IntoResponse {
+    if !state.config.mta_sts.enabled {
+        return (StatusCode::NOT_FOUND, "No MTA-STS policy\n".to_string()).into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        MtaStsPolicy::from_config(&state.config).to_text(),
+    )
+        .into_response()
+}
@@ -0,0 +1,130 @@
+//! API endpoints for notification preferences and live notifications
+
+use crate::api::auth::get_session_email;
+use crate::notifications::{NotificationPreferences, NotificationRouter};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error};
+
+/// App state containing the notification router
+pub struct NotificationsState {
+    pub router: Arc<NotificationRouter>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Notifications API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access notification preferences",
+    )
+}
+
+/// GET /api/notifications/preferences - Current user's preferences
+pub async fn get_preferences(
+    State(state): State<Arc<NotificationsState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<NotificationPreferences>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let preferences = state
+        .router
+        .manager()
+        .preferences(&email)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(preferences))
+}
+
+/// PUT /api/notifications/preferences - Replace the current user's preferences
+pub async fn set_preferences(
+    State(state): State<Arc<NotificationsState>>,
+    headers: HeaderMap,
+    Json(mut preferences): Json<NotificationPreferences>,
+) -> ApiResult<Json<NotificationPreferences>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    // Users only ever change their own preferences
+    preferences.email = email;
+    preferences
+        .validate()
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e))?;
+    state
+        .router
+        .manager()
+        .set_preferences(&preferences)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(preferences))
+}
+
+/// GET /api/notifications/ws - WebSocket receiving the current user's
+/// notifications as JSON text messages
+pub async fn websocket(
+    State(state): State<Arc<NotificationsState>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let router = state.router.clone();
+    Ok(upgrade.on_upgrade(move |socket| forward(socket, router, email)))
+}
+
+/// Push the user's notifications until either side closes
+async fn forward(mut socket: WebSocket, router: Arc<NotificationRouter>, email: String) {
+    let mut notifications = router.subscribe();
+    loop {
+        tokio::select! {
+            received = notifications.recv() => match received {
+                Ok(notification) if notification.email == email => {
+                    let Ok(json) = serde_json::to_string(&notification) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Notification socket of {} skipped {} events", email, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! API endpoints for OpenPGP keys and protected messages
+//!
+//! Users upload their private keys and the public keys of correspondents,
+//! look up the keys mail to an address would be encrypted with, and open
+//! their PGP/MIME or inline messages decrypted, with the signature
+//! checked. Only available with `[openpgp] enabled`; see [`crate::openpgp`].
+
+use crate::api::auth::get_session_email;
+use crate::imap::uid::base_name;
+use crate::imap::Mailbox;
+use crate::mime::{decode_header, MimeEntity, MimeNode, MimeParser};
+use crate::openpgp::crypto::{self, ParsedKey};
+use crate::openpgp::{ImportKey, OpenPgpManager, PgpKey, PgpReport};
+use crate::residency::ResidencyMap;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the key manager
+pub struct OpenPgpState {
+    pub manager: Option<Arc<OpenPgpManager>>,
+    pub maildir_root: PathBuf,
+    pub residency: Option<Arc<ResidencyMap>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::SERVICE_UNAVAILABLE, "OpenPGP is not enabled")
+}
+
+fn not_found(what: &str) -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, &format!("{} not found", what))
+}
+
+fn internal_error(e: impl std::fmt::Display) -> (StatusCode, Json<ApiError>) {
+    error!("OpenPGP API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access OpenPGP keys",
+    )
+}
+
+fn manager(state: &OpenPgpState) -> ApiResult<&Arc<OpenPgpManager>> {
+    state.manager.as_ref().ok_or_else(unavailable)
+}
+
+/// Query of a key lookup
+#[derive(Debug, Deserialize)]
+pub struct LookupQuery {
+    pub email: String,
+}
+
+/// Public key of an address, as the one mail to it is encrypted with
+#[derive(Debug, Serialize)]
+pub struct FoundKey {
+    pub fingerprint: String,
+    pub user_ids: Vec<String>,
+}
+
+/// A message as decrypted for display
+#[derive(Debug, Serialize)]
+pub struct OpenedMail {
+    pub openpgp: PgpReport,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    /// Decoded MIME tree of the decrypted message, or of the message as
+    /// stored when it couldn't be decrypted
+    pub parts: MimeNode,
+}
+
+/// GET /api/openpgp/keys - Keys of the current user
+pub async fn list_keys(
+    State(state): State<Arc<OpenPgpState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<PgpKey>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+
+    let keys = manager.list(&email).await.map_err(internal_error)?;
+    Ok(Json(keys))
+}
+
+/// POST /api/openpgp/keys - Upload an armored public or private key
+pub async fn import_key(
+    State(state): State<Arc<OpenPgpState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ImportKey>,
+) -> ApiResult<(StatusCode, Json<PgpKey>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+
+    let key = ParsedKey::from_armored(&payload.armored)
+        .map_err(|_| api_error(StatusCode::BAD_REQUEST, "Not a valid armored OpenPGP key"))?;
+    let passphrase = payload.passphrase.unwrap_or_default();
+    if let ParsedKey::Secret(secret) = &key {
+        crypto::check_passphrase(secret, &passphrase)
+            .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    }
+
+    let key = manager
+        .import(&email, &key, &passphrase)
+        .await
+        .map_err(internal_error)?;
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// DELETE /api/openpgp/keys/:fingerprint - Delete a key
+pub async fn delete_key(
+    State(state): State<Arc<OpenPgpState>>,
+    headers: HeaderMap,
+    Path(fingerprint): Path<String>,
+) -> ApiResult<StatusCode> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+
+    if !manager
+        .delete(&email, &fingerprint)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(not_found("Key"));
+    }
+    info!("{} deleted OpenPGP key {}", email, fingerprint);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/openpgp/lookup?email= - Keys mail to `email` would be
+/// encrypted with: stored ones, else its Web Key Directory
+pub async fn lookup_key(
+    State(state): State<Arc<OpenPgpState>>,
+    headers: HeaderMap,
+    Query(query): Query<LookupQuery>,
+) -> ApiResult<Json<Vec<FoundKey>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+
+    let keys = manager
+        .public_keys_for(&email, &query.email)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(
+        keys.iter()
+            .map(|key| FoundKey {
+                fingerprint: crypto::fingerprint(key),
+                user_ids: crypto::user_ids(key),
+            })
+            .collect(),
+    ))
+}
+
+/// GET /api/openpgp/mails/:mailbox/:message - A message of the current
+/// user decrypted, with its signature checked
+pub async fn open_mail(
+    State(state): State<Arc<OpenPgpState>>,
+    headers: HeaderMap,
+    Path((mailbox, message)): Path<(String, String)>,
+) -> ApiResult<Json<OpenedMail>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+
+    let root = match &state.residency {
+        Some(residency) => residency.maildir_root(&email, &state.maildir_root),
+        None => state.maildir_root.clone(),
+    };
+    let mailbox = Mailbox::open(&email, &mailbox, &root).map_err(|_| not_found("Message"))?;
+    let stored = mailbox
+        .messages()
+        .iter()
+        .find(|msg| base_name(&msg.filename) == base_name(&message))
+        .ok_or_else(|| not_found("Message"))?;
+    let content = stored.content().map_err(internal_error)?;
+
+    let opened = manager
+        .open(&email, content)
+        .await
+        .map_err(internal_error)?;
+    let shown = opened.message.as_deref().unwrap_or(content);
+    let entity = MimeEntity::parse(shown);
+    Ok(Json(OpenedMail {
+        openpgp: opened.report,
+        subject: entity.header_value("subject").map(|v| decode_header(&v)),
+        from: entity.header_value("from").map(|v| decode_header(&v)),
+        parts: MimeParser::parse_parts(shown),
+    }))
+}
@@ -0,0 +1,133 @@
+//! API endpoints for the spam quarantine
+//!
+//! Users list the messages held for them, release them into their INBOX or
+//! delete them. Messages are only held with `[spam.quarantine] enabled`.
+
+use crate::api::auth::get_session_email;
+use crate::spam::{QuarantineStore, QuarantinedMessage};
+use crate::storage::MaildirStorage;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Most messages listed at once
+const MAX_LIMIT: i64 = 500;
+
+/// App state containing the quarantine and the mailboxes released
+/// messages are delivered to
+pub struct QuarantineState {
+    pub store: Option<Arc<QuarantineStore>>,
+    pub storage: Option<Arc<MaildirStorage>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Spam quarantine is not enabled",
+    )
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "No such quarantined message")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Quarantine API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access the quarantine",
+    )
+}
+
+/// Query of the listing
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub limit: Option<i64>,
+}
+
+/// A released message
+#[derive(Debug, Serialize)]
+pub struct ReleaseResponse {
+    pub id: String,
+    /// Id of the message delivered into the INBOX
+    pub email_id: String,
+}
+
+/// GET /api/spam/quarantine - Messages held for the current user
+pub async fn list_quarantined(
+    State(state): State<Arc<QuarantineState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Vec<QuarantinedMessage>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let store = state.store.as_ref().ok_or_else(unavailable)?;
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_LIMIT);
+    let messages = store.list(&email, limit).await.map_err(internal_error)?;
+    Ok(Json(messages))
+}
+
+/// POST /api/spam/quarantine/:id/release - Deliver a held message into the
+/// INBOX
+pub async fn release_quarantined(
+    State(state): State<Arc<QuarantineState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ReleaseResponse>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let (store, storage) = state
+        .store
+        .as_ref()
+        .zip(state.storage.as_ref())
+        .ok_or_else(unavailable)?;
+    let email_id = store
+        .release(&email, &id, storage)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    info!("{} released quarantined message {}", email, id);
+    Ok(Json(ReleaseResponse { id, email_id }))
+}
+
+/// DELETE /api/spam/quarantine/:id - Delete a held message
+pub async fn delete_quarantined(
+    State(state): State<Arc<QuarantineState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let store = state.store.as_ref().ok_or_else(unavailable)?;
+    if !store.delete(&email, &id).await.map_err(internal_error)? {
+        return Err(not_found());
+    }
+    info!("{} deleted quarantined message {}", email, id);
+    Ok(StatusCode::NO_CONTENT)
+}
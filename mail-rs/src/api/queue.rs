@@ -0,0 +1,171 @@
+//! API endpoints to inspect the outbound queue
+//!
+//! Entries are returned with their delivery state and the SMTP transcript of
+//! the last failed attempt. Message content is never exposed, only its size.
+//! Quarantined entries can be released back into the queue.
+
+use crate::api::auth::get_session_email;
+use crate::smtp::{QueueStatus, QueuedEmail, SmtpQueue, TlsRequirement, Transcript};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Entries returned when no limit is given
+const DEFAULT_LIMIT: i64 = 50;
+
+/// Upper bound for the `limit` parameter
+const MAX_LIMIT: i64 = 500;
+
+/// App state containing the outbound queue, if this process runs one
+pub struct QueueState {
+    pub queue: Option<Arc<SmtpQueue>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "The outbound queue is not available in this process",
+    )
+}
+
+fn internal_error(e: crate::error::MailError) -> (StatusCode, Json<ApiError>) {
+    error!("Queue API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access the queue",
+    )
+}
+
+/// Query parameters for listing entries
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    /// Only entries with this status (pending, sent, bounced, quarantined, ...)
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Queue entry without its message content
+#[derive(Debug, Serialize)]
+pub struct QueueEntry {
+    pub id: String,
+    pub from_addr: String,
+    pub to_addr: String,
+    pub status: QueueStatus,
+    pub retry_count: i32,
+    /// Failed attempts to process the message (quarantined at the threshold)
+    pub failure_count: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub next_retry_at: Option<DateTime<Utc>>,
+    pub relay_host: Option<String>,
+    pub tls_requirement: TlsRequirement,
+    /// Message size in bytes
+    pub size: usize,
+    pub transcript: Option<Transcript>,
+}
+
+impl From<QueuedEmail> for QueueEntry {
+    fn from(email: QueuedEmail) -> Self {
+        Self {
+            size: email.data.len(),
+            id: email.id,
+            from_addr: email.from_addr,
+            to_addr: email.to_addr,
+            status: email.status,
+            retry_count: email.retry_count,
+            failure_count: email.failure_count,
+            last_error: email.last_error,
+            created_at: email.created_at,
+            next_retry_at: email.next_retry_at,
+            relay_host: email.relay_host,
+            tls_requirement: email.tls_requirement,
+            transcript: email.transcript,
+        }
+    }
+}
+
+/// GET /api/admin/queue - Most recent queue entries
+pub async fn list_entries(
+    State(state): State<Arc<QueueState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Vec<QueueEntry>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state.queue.as_ref().ok_or_else(unavailable)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let entries = queue
+        .list(query.status.as_deref(), limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(entries.into_iter().map(QueueEntry::from).collect()))
+}
+
+/// GET /api/admin/queue/:id - One queue entry with its last transcript
+pub async fn get_entry(
+    State(state): State<Arc<QueueState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<QueueEntry>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state.queue.as_ref().ok_or_else(unavailable)?;
+    let entry = queue
+        .get(&id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Queue entry not found"))?;
+    Ok(Json(entry.into()))
+}
+
+/// POST /api/admin/queue/:id/release - Retry a quarantined entry
+pub async fn release_entry(
+    State(state): State<Arc<QueueState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<QueueEntry>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let queue = state.queue.as_ref().ok_or_else(unavailable)?;
+    if !queue.release(&id).await.map_err(internal_error)? {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "No quarantined queue entry found",
+        ));
+    }
+    info!("Admin {}: Released quarantined email {}", admin, id);
+
+    let entry = queue
+        .get(&id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Queue entry not found"))?;
+    Ok(Json(entry.into()))
+}
@@ -1,19 +1,24 @@
 //! API endpoints for quota management
 
 use crate::api::auth::get_session_email;
+use crate::quota::history::{self, QuotaHistory, UsageSample};
 use crate::quota::manager::QuotaManager;
 use crate::quota::types::UserQuota;
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
     Json,
 };
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 /// App state containing quota manager
 pub struct QuotaState {
     pub manager: Arc<QuotaManager>,
+    /// Samples of the periodic storage recalculation
+    pub history: Arc<QuotaHistory>,
 }
 
 /// Response with error details
@@ -40,6 +45,13 @@ pub struct UpdateQuotaRequest {
     pub max_message_size: Option<u64>,
 }
 
+/// Query of the usage history
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// Days to go back (default: 30)
+    pub days: Option<u32>,
+}
+
 /// Default quotas configuration
 #[derive(Serialize, Deserialize)]
 pub struct DefaultQuotasRequest {
@@ -238,3 +250,85 @@ pub async fn reset_daily_counts(
 
     Ok(StatusCode::OK)
 }
+
+/// GET /api/admin/quotas/:email/history - Storage usage of a user as
+/// measured by the periodic recalculation
+pub async fn get_history(
+    State(state): State<Arc<QuotaState>>,
+    Path(user_email): Path<String>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<UsageSample>>, (StatusCode, Json<ApiError>)> {
+    let _email = get_session_email(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "Not authenticated".to_string(),
+            }),
+        )
+    })?;
+
+    let since = history_start(query.days.unwrap_or(30)).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "days is out of range".to_string(),
+            }),
+        )
+    })?;
+    let samples = state.history.history(&user_email, since).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError {
+                error: e.to_string(),
+            }),
+        )
+    })?;
+    Ok(Json(samples))
+}
+
+/// GET /api/admin/quotas/metrics - Storage consumption of the last
+/// recalculation in the Prometheus text format
+pub async fn get_metrics(
+    State(state): State<Arc<QuotaState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiError>)> {
+    let _email = get_session_email(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "Not authenticated".to_string(),
+            }),
+        )
+    })?;
+
+    let samples = state.history.latest().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError {
+                error: e.to_string(),
+            }),
+        )
+    })?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        history::to_prometheus(&samples),
+    ))
+}
+
+/// Start of a history going back `days`, if that date can be represented
+fn history_start(days: u32) -> Option<DateTime<Utc>> {
+    Utc::now().checked_sub_signed(Duration::days(days as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_start() {
+        let start = history_start(30).unwrap();
+        assert_eq!((Utc::now() - start).num_days(), 30);
+        assert!(history_start(u32::MAX).is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! API endpoints to inspect and toggle read-only mode
+//!
+//! While the flag is set, every other API change is refused with 503; see
+//! [`crate::recovery`]. Clearing it resumes SMTP deliveries, IMAP changes
+//! and the paused background writers.
+
+use crate::api::auth::get_session_email;
+use crate::recovery::{ReadOnlyMode, ReadOnlyState, Trigger};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+/// App state containing the flag shared with the other listeners
+pub struct RecoveryState {
+    pub read_only: Option<Arc<ReadOnlyMode>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Read-only mode is not shared with the API",
+    )
+}
+
+/// Whether the server is read-only, and why
+#[derive(Debug, Serialize)]
+pub struct ReadOnlyResponse {
+    pub read_only: bool,
+    #[serde(flatten)]
+    pub state: Option<ReadOnlyState>,
+}
+
+impl ReadOnlyResponse {
+    fn of(mode: &ReadOnlyMode) -> Self {
+        let state = mode.state();
+        Self {
+            read_only: state.is_some(),
+            state,
+        }
+    }
+}
+
+/// Request to enter read-only mode
+#[derive(Debug, Deserialize)]
+pub struct EnterRequest {
+    pub reason: Option<String>,
+}
+
+/// GET /api/admin/read-only - Current read-only state
+pub async fn status(
+    State(state): State<Arc<RecoveryState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<ReadOnlyResponse>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let mode = state.read_only.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(ReadOnlyResponse::of(mode)))
+}
+
+/// PUT /api/admin/read-only - Enter read-only mode
+pub async fn enter(
+    State(state): State<Arc<RecoveryState>>,
+    headers: HeaderMap,
+    Json(request): Json<EnterRequest>,
+) -> ApiResult<Json<ReadOnlyResponse>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let mode = state.read_only.as_ref().ok_or_else(unavailable)?;
+    let reason = request
+        .reason
+        .filter(|reason| !reason.trim().is_empty())
+        .unwrap_or_else(|| format!("set by {}", admin));
+    mode.enter(&reason, Trigger::Admin);
+    Ok(Json(ReadOnlyResponse::of(mode)))
+}
+
+/// DELETE /api/admin/read-only - Leave read-only mode
+pub async fn clear(
+    State(state): State<Arc<RecoveryState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<ReadOnlyResponse>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let mode = state.read_only.as_ref().ok_or_else(unavailable)?;
+    mode.clear();
+    info!("Read-only mode cleared by {}", admin);
+    Ok(Json(ReadOnlyResponse::of(mode)))
+}
@@ -0,0 +1,160 @@
+//! API endpoints for admin usage reports
+
+use crate::api::auth::get_session_email;
+use crate::reporting::{render, ReportFormat, ReportPeriod, ReportingManager, UsageReport};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+/// Top senders/recipients listed in on-demand reports
+const DEFAULT_TOP_N: usize = 10;
+
+/// App state containing reporting manager
+pub struct ReportsState {
+    pub manager: Arc<ReportingManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+/// Query parameters for listing reports
+#[derive(Deserialize)]
+pub struct ListReportsQuery {
+    pub period: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+/// Request to generate a report on demand
+#[derive(Deserialize)]
+pub struct GenerateReportRequest {
+    pub period: ReportPeriod,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Reports API error: {}", e);
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to access reports")
+}
+
+/// GET /api/admin/reports - List historical reports, newest first
+pub async fn list_reports(
+    State(state): State<Arc<ReportsState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListReportsQuery>,
+) -> ApiResult<Json<Vec<UsageReport>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let period = match query.period.as_deref() {
+        Some(period) => Some(
+            ReportPeriod::parse(period)
+                .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Invalid period"))?,
+        ),
+        None => None,
+    };
+
+    let reports = state
+        .manager
+        .list_reports(period, query.limit.clamp(1, 500))
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(reports))
+}
+
+/// POST /api/admin/reports/generate - (Re)generate the last complete period
+pub async fn generate_report(
+    State(state): State<Arc<ReportsState>>,
+    headers: HeaderMap,
+    Json(payload): Json<GenerateReportRequest>,
+) -> ApiResult<Json<UsageReport>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let (start, end) = payload.period.last_complete(Utc::now());
+    let report = state
+        .manager
+        .generate(payload.period, start, end, DEFAULT_TOP_N)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(report))
+}
+
+/// GET /api/admin/reports/:id - Get a report as JSON
+pub async fn get_report(
+    State(state): State<Arc<ReportsState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<UsageReport>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let report = find_report(&state, id).await?;
+    Ok(Json(report))
+}
+
+/// GET /api/admin/reports/:id/:format - Download a report as CSV, HTML or PDF
+pub async fn download_report(
+    State(state): State<Arc<ReportsState>>,
+    headers: HeaderMap,
+    Path((id, format)): Path<(i64, String)>,
+) -> ApiResult<Response> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let format = ReportFormat::parse(&format)
+        .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Format must be csv, html or pdf"))?;
+    let report = find_report(&state, id).await?;
+
+    let filename = format!(
+        "usage-report-{}-{}.{}",
+        report.period.as_str(),
+        report.period_start.format("%Y-%m-%d"),
+        format.extension()
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        render::render(&report, format),
+    )
+        .into_response())
+}
+
+async fn find_report(state: &ReportsState, id: i64) -> ApiResult<UsageReport> {
+    state
+        .manager
+        .get_report(id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Report not found"))
+}
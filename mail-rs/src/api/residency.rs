@@ -0,0 +1,346 @@
+//! API endpoints for regional data residency
+//!
+//! Admins pin domains and users to regions, list and take regional backups,
+//! and restore them. Restoring into another region than the backup's is
+//! refused unless the request carries an override reason, which is written
+//! to the residency audit log along with every assignment change.
+
+use crate::admin::backup::{BackupConfig, BackupManager, BackupMetadata};
+use crate::api::auth::get_session_email;
+use crate::config::RegionConfig;
+use crate::residency::{Assignment, AuditEntry, ResidencyManager, RestoreCheck};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Audit entries returned when no limit is given
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+
+/// Upper bound for the `limit` parameter
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+/// App state containing the residency manager, if regions are configured
+pub struct ResidencyState {
+    pub manager: Option<Arc<ResidencyManager>>,
+    /// Maildir root of mailboxes outside any region
+    pub maildir_root: PathBuf,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "No residency regions are configured",
+    )
+}
+
+fn unknown_region(region: &str) -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::NOT_FOUND,
+        &format!("Unknown region: {}", region),
+    )
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Residency API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access residency data",
+    )
+}
+
+/// Configured regions
+#[derive(Debug, Serialize)]
+pub struct RegionsResponse {
+    pub regions: Vec<RegionConfig>,
+    pub default_region: Option<String>,
+}
+
+/// Request to pin a domain or user to a region
+#[derive(Debug, Deserialize)]
+pub struct AssignRequest {
+    pub region: String,
+}
+
+/// Query parameters for the audit log
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub limit: Option<i64>,
+}
+
+/// Request to restore a backup
+#[derive(Debug, Deserialize)]
+pub struct RestoreRequest {
+    /// Backup filename
+    pub backup: String,
+    /// Region the backup was taken in; unset for the default backups
+    pub source_region: Option<String>,
+    /// Region to restore into; unset for the default maildir
+    pub target_region: Option<String>,
+    /// Required to restore into another region, recorded in the audit log
+    pub override_reason: Option<String>,
+}
+
+/// Result of a restore
+#[derive(Debug, Serialize)]
+pub struct RestoreResponse {
+    pub backup: String,
+    pub source_region: Option<String>,
+    pub target_region: Option<String>,
+    pub cross_region: bool,
+}
+
+fn manager(state: &ResidencyState) -> ApiResult<&Arc<ResidencyManager>> {
+    state.manager.as_ref().ok_or_else(unavailable)
+}
+
+/// Backup manager of a region
+fn region_backups(manager: &ResidencyManager, region: &str) -> ApiResult<BackupManager> {
+    let map = manager.map();
+    let region = map.region(region).ok_or_else(|| unknown_region(region))?;
+    Ok(BackupManager::new(BackupConfig::for_region(region)))
+}
+
+/// GET /api/admin/residency/regions - Configured regions
+pub async fn list_regions(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<RegionsResponse>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let map = manager(&state)?.map();
+    Ok(Json(RegionsResponse {
+        regions: map.regions().to_vec(),
+        default_region: map.default_region().map(str::to_string),
+    }))
+}
+
+/// GET /api/admin/residency/assignments - Domains and users pinned to regions
+pub async fn list_assignments(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<Assignment>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let assignments = manager(&state)?
+        .assignments()
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(assignments))
+}
+
+/// PUT /api/admin/residency/assignments/:subject - Pin a domain or user
+///
+/// Only new mail is stored in the region; existing mailboxes are not moved.
+pub async fn assign(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+    Path(subject): Path<String>,
+    Json(payload): Json<AssignRequest>,
+) -> ApiResult<Json<Assignment>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+
+    if subject.is_empty() || subject.contains('/') || subject.starts_with('.') {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Invalid domain or address",
+        ));
+    }
+    if manager.map().region(&payload.region).is_none() {
+        return Err(unknown_region(&payload.region));
+    }
+
+    let assignment = manager
+        .assign(&subject, &payload.region, &actor)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(assignment))
+}
+
+/// DELETE /api/admin/residency/assignments/:subject - Remove a pin
+pub async fn unassign(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+    Path(subject): Path<String>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let removed = manager(&state)?
+        .unassign(&subject, &actor)
+        .await
+        .map_err(internal_error)?;
+    if removed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(api_error(StatusCode::NOT_FOUND, "No assignment found"))
+    }
+}
+
+/// GET /api/admin/residency/audit - Most recent audit entries
+pub async fn audit_log(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> ApiResult<Json<Vec<AuditEntry>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    let entries = manager(&state)?
+        .audit_log(limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(entries))
+}
+
+/// GET /api/admin/residency/regions/:region/backups - Backups of a region
+pub async fn list_backups(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+    Path(region): Path<String>,
+) -> ApiResult<Json<Vec<BackupMetadata>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let backups = region_backups(manager(&state)?, &region)?
+        .list_backups()
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(backups))
+}
+
+/// POST /api/admin/residency/regions/:region/backups - Back up a region
+pub async fn create_backup(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+    Path(region): Path<String>,
+) -> ApiResult<(StatusCode, Json<BackupMetadata>)> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    info!("Admin {}: Creating backup of region {}", actor, region);
+
+    let backup = region_backups(manager(&state)?, &region)?
+        .create_backup()
+        .await
+        .map_err(internal_error)?;
+    Ok((StatusCode::CREATED, Json(backup)))
+}
+
+/// POST /api/admin/residency/restore - Restore a backup into a region
+///
+/// Restores across regions, including between a region and the default
+/// maildir, need an `override_reason`; they are answered with 403 otherwise.
+pub async fn restore(
+    State(state): State<Arc<ResidencyState>>,
+    headers: HeaderMap,
+    Json(payload): Json<RestoreRequest>,
+) -> ApiResult<Json<RestoreResponse>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let manager = manager(&state)?;
+    let map = manager.map();
+
+    if payload.backup.contains('/') || payload.backup.contains("..") {
+        return Err(api_error(StatusCode::BAD_REQUEST, "Invalid backup name"));
+    }
+
+    let backups = match &payload.source_region {
+        Some(region) => region_backups(manager, region)?,
+        None => BackupManager::with_defaults(),
+    };
+    let target = match &payload.target_region {
+        Some(region) => {
+            let region = map.region(region).ok_or_else(|| unknown_region(region))?;
+            PathBuf::from(&region.maildir_path)
+        }
+        None => state.maildir_root.clone(),
+    };
+
+    // The backup's own region wins over what the caller claims
+    let backup = backups
+        .list_backups()
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .find(|backup| backup.filename == payload.backup)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Backup not found"))?;
+    if backup.region != payload.source_region {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Backup was not taken in the source region",
+        ));
+    }
+
+    let check = RestoreCheck::evaluate(
+        payload.source_region.as_deref(),
+        payload.target_region.as_deref(),
+        payload.override_reason.as_deref(),
+    );
+    if !check.allowed() {
+        warn!(
+            "Admin {}: Refused cross-region restore of {} ({:?} -> {:?})",
+            actor, payload.backup, payload.source_region, payload.target_region
+        );
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "Cross-region restore requires an override_reason",
+        ));
+    }
+
+    let cross_region = check == RestoreCheck::Overridden;
+    let action = if cross_region {
+        "cross_region_restore"
+    } else {
+        "restore"
+    };
+    // Record the override before touching any data
+    manager
+        .record_audit(
+            &actor,
+            action,
+            &payload.backup,
+            payload.source_region.as_deref(),
+            payload.target_region.as_deref(),
+            payload.override_reason.as_deref(),
+        )
+        .await
+        .map_err(internal_error)?;
+
+    backups
+        .restore_backup_to(&payload.backup, &target)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(RestoreResponse {
+        backup: payload.backup,
+        source_region: payload.source_region,
+        target_region: payload.target_region,
+        cross_region,
+    }))
+}
@@ -0,0 +1,144 @@
+//! API endpoints for message retention policies
+//!
+//! Admins set how long messages stay in each folder, for every domain or
+//! one domain. Policies are enforced in the background when `[retention]`
+//! is enabled; see [`crate::retention`].
+
+use crate::api::auth::get_session_email;
+use crate::retention::{RetentionManager, RetentionPolicy, RetentionPolicyRequest};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the retention manager
+pub struct RetentionState {
+    pub manager: Arc<RetentionManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Retention policy not found")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Retention API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access retention policies",
+    )
+}
+
+/// Invalid or conflicting requests are rejected before anything is saved
+fn check(payload: &RetentionPolicyRequest) -> ApiResult<()> {
+    payload
+        .validate()
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e))
+}
+
+/// GET /api/admin/retention/policies - All policies
+pub async fn list_policies(
+    State(state): State<Arc<RetentionState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<RetentionPolicy>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let policies = state.manager.list().await.map_err(internal_error)?;
+    Ok(Json(policies))
+}
+
+/// POST /api/admin/retention/policies - Add a policy
+pub async fn create_policy(
+    State(state): State<Arc<RetentionState>>,
+    headers: HeaderMap,
+    Json(payload): Json<RetentionPolicyRequest>,
+) -> ApiResult<(StatusCode, Json<RetentionPolicy>)> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    check(&payload)?;
+
+    let policy = state
+        .manager
+        .create(&payload)
+        .await
+        .map_err(|e| api_error(StatusCode::CONFLICT, &e.to_string()))?;
+    info!(
+        "Admin {}: Added retention policy {} ({} after {} days in {})",
+        actor,
+        policy.id,
+        policy.action.as_str(),
+        policy.max_age_days,
+        policy.folder
+    );
+    Ok((StatusCode::CREATED, Json(policy)))
+}
+
+/// GET /api/admin/retention/policies/:id - One policy
+pub async fn get_policy(
+    State(state): State<Arc<RetentionState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<RetentionPolicy>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let policy = state.manager.get(id).await.map_err(internal_error)?;
+    policy.map(Json).ok_or_else(not_found)
+}
+
+/// PUT /api/admin/retention/policies/:id - Replace a policy
+pub async fn update_policy(
+    State(state): State<Arc<RetentionState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Json(payload): Json<RetentionPolicyRequest>,
+) -> ApiResult<Json<RetentionPolicy>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    check(&payload)?;
+
+    let policy = state
+        .manager
+        .update(id, &payload)
+        .await
+        .map_err(|e| api_error(StatusCode::CONFLICT, &e.to_string()))?
+        .ok_or_else(not_found)?;
+    info!("Admin {}: Updated retention policy {}", actor, id);
+    Ok(Json(policy))
+}
+
+/// DELETE /api/admin/retention/policies/:id - Remove a policy
+pub async fn delete_policy(
+    State(state): State<Arc<RetentionState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    if !state.manager.delete(id).await.map_err(internal_error)? {
+        return Err(not_found());
+    }
+    info!("Admin {}: Removed retention policy {}", actor, id);
+    Ok(StatusCode::NO_CONTENT)
+}
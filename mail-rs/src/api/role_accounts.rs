@@ -0,0 +1,182 @@
+//! API endpoints for role accounts
+//!
+//! Lists the `postmaster@` and `abuse@` addresses of hosted domains with
+//! where their mail goes and how much they received per day, provisions
+//! them for new domains and overrides their delivery.
+
+use crate::api::auth::get_session_email;
+use crate::role_accounts::{RoleAccount, RoleAccountManager, RoleTarget, RoleVolume, UpdateRoleAccount};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Days of volume returned by default
+const DEFAULT_VOLUME_DAYS: u32 = 30;
+
+/// App state containing the role account manager
+pub struct RoleAccountsState {
+    pub manager: Option<Arc<RoleAccountManager>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::SERVICE_UNAVAILABLE, "Role accounts are not enabled")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Role account not found")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Role account API error: {}", e);
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to access role accounts")
+}
+
+/// Query parameters of the volume
+#[derive(Debug, Deserialize)]
+pub struct VolumeParams {
+    /// Days of volume to return, including today (default 30)
+    pub days: Option<u32>,
+}
+
+/// Request to provision the role accounts of a domain
+#[derive(Debug, Deserialize)]
+pub struct ProvisionRequest {
+    pub domain: String,
+}
+
+/// A role account with its delivery and daily volume
+#[derive(Debug, Serialize)]
+pub struct RoleAccountStatus {
+    #[serde(flatten)]
+    pub account: RoleAccount,
+    pub target: RoleTarget,
+    pub volume: Vec<RoleVolume>,
+}
+
+async fn status(
+    manager: &RoleAccountManager,
+    account: RoleAccount,
+    days: Option<u32>,
+) -> ApiResult<RoleAccountStatus> {
+    let days = days.unwrap_or(DEFAULT_VOLUME_DAYS).max(1);
+    let since = (Utc::now() - Duration::days(days as i64 - 1)).date_naive();
+    let volume = manager
+        .volume(&account.address, since)
+        .await
+        .map_err(internal_error)?;
+    Ok(RoleAccountStatus {
+        target: manager.target(&account),
+        account,
+        volume,
+    })
+}
+
+/// GET /api/admin/role-accounts - List role accounts with their volume
+pub async fn list_accounts(
+    State(state): State<Arc<RoleAccountsState>>,
+    headers: HeaderMap,
+    Query(params): Query<VolumeParams>,
+) -> ApiResult<Json<Vec<RoleAccountStatus>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let mut accounts = Vec::new();
+    for account in manager.list().await.map_err(internal_error)? {
+        accounts.push(status(manager, account, params.days).await?);
+    }
+    Ok(Json(accounts))
+}
+
+/// POST /api/admin/role-accounts - Provision the role accounts of a domain
+pub async fn provision(
+    State(state): State<Arc<RoleAccountsState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ProvisionRequest>,
+) -> ApiResult<(StatusCode, Json<Vec<RoleAccount>>)> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let created = manager
+        .provision(&payload.domain)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    info!(
+        "Admin {}: Provisioned {} role accounts of {}",
+        admin,
+        created.len(),
+        payload.domain
+    );
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// GET /api/admin/role-accounts/:address - Get a role account with its
+/// volume
+pub async fn get_account(
+    State(state): State<Arc<RoleAccountsState>>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+    Query(params): Query<VolumeParams>,
+) -> ApiResult<Json<RoleAccountStatus>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let account = manager
+        .get(&address)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(status(manager, account, params.days).await?))
+}
+
+/// PUT /api/admin/role-accounts/:address - Override where the mail goes
+pub async fn update_account(
+    State(state): State<Arc<RoleAccountsState>>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+    Json(payload): Json<UpdateRoleAccount>,
+) -> ApiResult<Json<RoleAccountStatus>> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let manager = state.manager.as_ref().ok_or_else(unavailable)?;
+    let account = manager
+        .update(&address, &payload)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?
+        .ok_or_else(not_found)?;
+    let status = status(manager, account, None).await?;
+    info!(
+        "Admin {}: Set delivery of {} to {}{}",
+        admin,
+        status.account.address,
+        status.target.mailbox,
+        if status.target.quarantine { " (Junk)" } else { "" }
+    );
+    Ok(Json(status))
+}
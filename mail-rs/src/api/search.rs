@@ -3,7 +3,7 @@
 //! Provides REST API for full-text email search.
 
 use axum::{
-    extract::{Query, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode},
     routing::{delete, get, post},
     Json, Router,
@@ -12,7 +12,7 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use crate::api::auth::get_session_email;
-use crate::search::{IndexStatus, SearchManager, SearchQuery, SearchResults};
+use crate::search::{IndexStatus, ParsedQuery, SearchManager, SearchQuery, SearchResults};
 
 /// Search API state
 pub struct SearchState {
@@ -22,10 +22,13 @@ pub struct SearchState {
 /// Search request query parameters
 #[derive(Debug, Deserialize)]
 pub struct SearchParams {
-    /// Search query string
+    /// Search query, e.g. `invoice from:alice@example.com has:attachment
+    /// after:2024-01-01` (see [`crate::search::query`])
     pub q: String,
     /// Optional folder filter
     pub folder: Option<String>,
+    /// Optional ingress listener filter (mx, submission, lmtp, http)
+    pub listener: Option<String>,
     /// Optional start date (ISO 8601)
     pub from_date: Option<String>,
     /// Optional end date (ISO 8601)
@@ -66,6 +69,10 @@ pub async fn search_emails(
     let email = get_session_email(&headers)
         .ok_or_else(|| (StatusCode::UNAUTHORIZED, Json(ErrorResponse { error: "Unauthorized".to_string() })))?;
 
+    if let Err(e) = ParsedQuery::parse(&params.q) {
+        return Err((StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e.to_string() })));
+    }
+
     // Parse dates if provided
     let from_date = params.from_date.as_ref().and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok().map(|dt| dt.with_timezone(&chrono::Utc)));
     let to_date = params.to_date.as_ref().and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok().map(|dt| dt.with_timezone(&chrono::Utc)));
@@ -73,6 +80,7 @@ pub async fn search_emails(
     let query = SearchQuery {
         query: params.q,
         folder: params.folder,
+        listener: params.listener,
         from_date,
         to_date,
         limit: params.limit,
@@ -130,6 +138,30 @@ pub async fn reindex(
     }
 }
 
+/// Rebuild the index of one user from their mailbox (admin only)
+///
+/// Documents of messages no longer in the mailbox are dropped.
+pub async fn rebuild_user_index(
+    State(state): State<Arc<SearchState>>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<Json<ReindexResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let _admin = get_session_email(&headers)
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, Json(ErrorResponse { error: "Unauthorized".to_string() })))?;
+
+    match state.search_manager.reindex_user(&email).await {
+        Ok(count) => Ok(Json(ReindexResponse {
+            success: true,
+            indexed_count: count,
+            message: format!("Rebuilt the index of {} with {} emails", email, count),
+        })),
+        Err(e) => {
+            tracing::error!("Rebuild error for {}: {}", email, e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorResponse { error: e.to_string() })))
+        }
+    }
+}
+
 /// Reindex all users (admin only)
 pub async fn reindex_all(
     State(state): State<Arc<SearchState>>,
@@ -2,7 +2,7 @@
 
 use axum::{
     extract::{ConnectInfo, FromRequestParts, State},
-    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    http::{header::AUTHORIZATION, request::Parts, Method, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{delete, get, patch, post, put},
@@ -14,22 +14,53 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
-use crate::api::{admin, auto_reply, caldav, greylisting, import_export, mfa, monitoring, quotas, search, security_stats, sieve, spam, templates, web};
-use crate::api::auth::{Claims, JwtConfig};
+use crate::api::{admin, aliases, antivirus, auth_cache, auto_reply, autoconfig, bandwidth, billing, branding, budgets, caldav, capture, chaos, config_drift, devices, dkim, dmarc_reports, dnsbl, flags, footers, greylisting, hooks, impersonation, import_export, invitations, logging, login_anomaly, mfa, migration, monitoring, mta_sts, notifications, openpgp, queue, quarantine, quotas, recovery, reports, residency, retention, role_accounts, search, security_stats, sharing, sieve, spam, storage_jobs, templates, tls_reports, web, workers};
+use crate::api::auth::{get_session_email, Claims, JwtConfig};
 use crate::api::handlers::{self, ApiError, AppState};
+use crate::aliases::AliasManager;
 use crate::antispam::greylist::GreylistManager;
+use crate::antispam::{DnsblChecker, ImpersonationGuard};
+use crate::authentication::{DkimValidator, SpfValidator};
+use crate::antivirus::VirusScanner;
+use crate::workers::WorkerPools;
+use crate::recovery::ReadOnlyMode;
 use crate::auto_reply::AutoReplyManager;
+use crate::billing::{BillingManager, BillingMetric};
+use crate::branding::BrandingManager;
 use crate::caldav::CalDavManager;
+use crate::config::Config;
+use crate::devices::DeviceManager;
+use crate::footers::FooterManager;
+use crate::retention::RetentionManager;
 use crate::import_export::ImportExportManager;
+use crate::hooks::HookManager;
+use crate::role_accounts::RoleAccountManager;
+use crate::login_anomaly::LoginAnomalyDetector;
 use crate::mfa::MfaManager;
+use crate::notifications::{NotificationManager, NotificationRouter};
+use crate::migration::MigrationManager;
 use crate::quota::manager::QuotaManager;
-use crate::search::SearchManager;
-use crate::security::Authenticator;
+use crate::quota::QuotaHistory;
+use crate::reporting::ReportingManager;
+use crate::residency::ResidencyManager;
+use crate::search::{IndexQueue, SearchConfig, SearchManager};
+use crate::security::{Authenticator, BandwidthLimiter};
+use crate::sharing::ShareManager;
+use crate::smtp::budget::DeliveryBudget;
+use crate::smtp::postdelivery::PostDeliveryQueue;
 use crate::sieve::SieveManager;
-use crate::spam::SpamManager;
+use crate::storage::{FlagEventBus, MaildirStorage, MetadataIndex};
+use crate::smtp::SmtpQueue;
+use crate::spam::{QuarantineStore, SpamManager};
+use crate::admin::dns_verify::DnsVerifier;
+use crate::dkim_keys::DkimKeyManager;
+use crate::openpgp::OpenPgpManager;
+use crate::storage::jobs::StorageJobManager;
 use crate::templates::TemplateManager;
+use crate::tlsrpt::TlsRptManager;
+use crate::dmarc_reports::DmarcReportManager;
 use sqlx::SqlitePool;
 
 /// Rate limiter state for tracking requests per IP
@@ -91,6 +122,7 @@ pub struct ApiServer {
     auto_reply_manager: Arc<AutoReplyManager>,
     greylist_manager: Arc<GreylistManager>,
     quota_manager: Arc<QuotaManager>,
+    quota_history: Arc<QuotaHistory>,
     security_stats_manager: Arc<security_stats::SecurityStatsManager>,
     monitoring_manager: Arc<monitoring::MonitoringManager>,
     mfa_manager: Arc<MfaManager>,
@@ -99,6 +131,64 @@ pub struct ApiServer {
     spam_manager: Arc<SpamManager>,
     import_export_manager: Arc<ImportExportManager>,
     caldav_manager: Arc<CalDavManager>,
+    migration_manager: Arc<MigrationManager>,
+    reporting_manager: Arc<ReportingManager>,
+    billing_manager: Arc<BillingManager>,
+    /// Count authenticated requests per user for billing
+    meter_api_calls: bool,
+    tls_rpt_manager: Arc<TlsRptManager>,
+    /// Aggregate reports received at our rua addresses
+    dmarc_report_manager: Arc<DmarcReportManager>,
+    storage_job_manager: Arc<StorageJobManager>,
+    impersonation_guard: Arc<ImpersonationGuard>,
+    footer_manager: Arc<FooterManager>,
+    retention_manager: Arc<RetentionManager>,
+    /// Links sharing single messages
+    share_manager: Arc<ShareManager>,
+    alias_manager: Arc<AliasManager>,
+    branding_manager: Arc<BrandingManager>,
+    notification_router: Arc<NotificationRouter>,
+    /// Client devices, app passwords and the devices of API tokens
+    device_manager: Arc<DeviceManager>,
+    /// Flag changes shared with the other frontends
+    flag_events: Arc<FlagEventBus>,
+    /// Download limits shared with IMAP
+    bandwidth: Arc<BandwidthLimiter>,
+    /// Per-user usage of the SMTP delivery pipeline
+    delivery_budget: Arc<DeliveryBudget>,
+    /// Post-delivery workers of the SMTP listener, when enabled
+    post_delivery: Option<Arc<PostDeliveryQueue>>,
+    /// Outbound queue, when this process delivers mail
+    queue: Option<Arc<SmtpQueue>>,
+    /// Region assignments, when residency regions are configured
+    residency_manager: Option<Arc<ResidencyManager>>,
+    /// Effective configuration, for fingerprints and drift checks
+    config: Option<Arc<Config>>,
+    /// Holds API logins from unusual locations, when enabled
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    /// Delivery hooks of domains and users, when enabled
+    hook_manager: Option<Arc<HookManager>>,
+    /// Role addresses of hosted domains, when enabled
+    role_accounts: Option<Arc<RoleAccountManager>>,
+    /// DNS blocklist checker shared with the SMTP listener, when enabled
+    dnsbl: Option<Arc<DnsblChecker>>,
+    /// SPF and DKIM validators shared with the SMTP listener
+    auth_caches: (Option<Arc<SpfValidator>>, Option<Arc<DkimValidator>>),
+    /// Virus scanner shared with the SMTP listeners, when enabled
+    antivirus: Option<Arc<VirusScanner>>,
+    /// Pools of background work shared with the other listeners
+    workers: Option<Arc<WorkerPools>>,
+    /// Read-only flag shared with the other listeners; changes are refused
+    /// while it is set
+    read_only: Option<Arc<ReadOnlyMode>>,
+    /// Spam held for users to release or delete, and the mailboxes
+    /// released messages are delivered to
+    quarantine: Option<(Arc<QuarantineStore>, Arc<MaildirStorage>)>,
+    /// Keys signing outbound mail
+    dkim_keys: Option<Arc<DkimKeyManager>>,
+    /// OpenPGP keys of users, decrypting their mail and encrypting what
+    /// they send
+    openpgp: Option<Arc<OpenPgpManager>>,
     addr: String,
 }
 
@@ -115,6 +205,7 @@ impl ApiServer {
             authenticator,
             jwt_config: JwtConfig::new(jwt_secret, 24),
             maildir_root,
+            metadata: None,
         });
 
         // Rate limiter: 100 requests per minute per IP
@@ -136,11 +227,44 @@ impl ApiServer {
         })?;
 
         // Create greylist manager
-        let greylist_manager = Arc::new(GreylistManager::new());
+        let greylist_manager = Arc::new(GreylistManager::new(db.clone()));
+        greylist_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize greylist tables: {}", e))
+        })?;
+
+        // Create branding manager (per-domain branding)
+        let branding_db = SqlitePool::connect(&database_url).await?;
+        let branding_manager = Arc::new(BrandingManager::new(branding_db));
+        branding_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize branding tables: {}", e))
+        })?;
+
+        // Create notification router (WebSocket and webhook channels until
+        // a delivery queue is attached)
+        let notification_manager = Arc::new(NotificationManager::new(db.clone()));
+        notification_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize notification tables: {}", e))
+        })?;
+        let notification_router = Arc::new(
+            NotificationRouter::new(notification_manager).with_branding(branding_manager.clone()),
+        );
+
+        // Create device manager (client devices and app passwords)
+        let device_manager = DeviceManager::new(db.clone()).with_alerts(notification_router.clone());
+        device_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize device tables: {}", e))
+        })?;
+        let device_manager = Arc::new(device_manager);
 
         // Create quota manager
         let quota_manager = Arc::new(QuotaManager::new());
 
+        // Create quota history (samples of the storage recalculation)
+        let quota_history = Arc::new(QuotaHistory::new(db.clone()));
+        quota_history.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize quota history tables: {}", e))
+        })?;
+
         // Create security stats manager
         let security_stats_manager = Arc::new(security_stats::SecurityStatsManager::new());
 
@@ -159,8 +283,11 @@ impl ApiServer {
             sqlx::Error::Protocol(format!("Failed to initialize Sieve tables: {}", e))
         })?;
 
-        // Create Search manager
-        let search_manager = Arc::new(SearchManager::new());
+        // Create Search manager over the mailboxes of this server
+        let search_manager = Arc::new(SearchManager::with_config(SearchConfig {
+            mailbox_path: std::path::PathBuf::from(&state.maildir_root),
+            ..SearchConfig::default()
+        }));
         // Initialize search index (optional - may fail if path doesn't exist)
         if let Err(e) = search_manager.init().await {
             tracing::warn!("Failed to initialize search index: {} - search will be disabled", e);
@@ -187,6 +314,83 @@ impl ApiServer {
             sqlx::Error::Protocol(format!("Failed to initialize CalDAV tables: {}", e))
         })?;
 
+        // Create migration manager (coexistence flags)
+        let migration_db = SqlitePool::connect(&database_url).await?;
+        let migration_manager = Arc::new(MigrationManager::new(migration_db));
+        migration_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize migration tables: {}", e))
+        })?;
+
+        // Create reporting manager (usage reports)
+        let reporting_db = SqlitePool::connect(&database_url).await?;
+        let reporting_manager = Arc::new(ReportingManager::new(reporting_db));
+        reporting_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize reporting tables: {}", e))
+        })?;
+
+        // Create billing manager (per-user billing metrics)
+        let billing_db = SqlitePool::connect(&database_url).await?;
+        let billing_manager = Arc::new(BillingManager::new(billing_db));
+        billing_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize billing tables: {}", e))
+        })?;
+
+        // Create TLS-RPT manager (outbound TLS reports)
+        let tls_rpt_db = SqlitePool::connect(&database_url).await?;
+        let tls_rpt_manager = Arc::new(TlsRptManager::new(tls_rpt_db));
+        tls_rpt_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize TLS-RPT tables: {}", e))
+        })?;
+
+        // Create report manager (received DMARC and TLS reports)
+        let dmarc_report_db = SqlitePool::connect(&database_url).await?;
+        let dmarc_report_manager = Arc::new(DmarcReportManager::new(dmarc_report_db));
+        dmarc_report_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize DMARC report tables: {}", e))
+        })?;
+
+        // Create storage job manager (background maintenance jobs)
+        let storage_job_db = SqlitePool::connect(&database_url).await?;
+        let storage_job_manager = Arc::new(StorageJobManager::new(storage_job_db));
+        storage_job_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize storage job tables: {}", e))
+        })?;
+
+        // Create impersonation guard (protected display names)
+        let impersonation_db = SqlitePool::connect(&database_url).await?;
+        let impersonation_guard = Arc::new(ImpersonationGuard::new(impersonation_db));
+        impersonation_guard.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize impersonation tables: {}", e))
+        })?;
+
+        // Create footer manager (footers of outgoing mail)
+        let footer_db = SqlitePool::connect(&database_url).await?;
+        let footer_manager = Arc::new(FooterManager::new(footer_db));
+        footer_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize footer tables: {}", e))
+        })?;
+
+        // Create retention manager (how long messages stay in each folder)
+        let retention_db = SqlitePool::connect(&database_url).await?;
+        let retention_manager = Arc::new(RetentionManager::new(retention_db));
+        retention_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize retention tables: {}", e))
+        })?;
+
+        // Create share manager (encrypted links to single messages)
+        let share_db = SqlitePool::connect(&database_url).await?;
+        let share_manager = Arc::new(ShareManager::new(share_db));
+        share_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize share tables: {}", e))
+        })?;
+
+        // Create alias manager (burner aliases)
+        let alias_db = SqlitePool::connect(&database_url).await?;
+        let alias_manager = Arc::new(AliasManager::new(alias_db));
+        alias_manager.init_db().await.map_err(|e| {
+            sqlx::Error::Protocol(format!("Failed to initialize alias tables: {}", e))
+        })?;
+
         Ok(Self {
             state,
             rate_limiter,
@@ -194,6 +398,7 @@ impl ApiServer {
             auto_reply_manager,
             greylist_manager,
             quota_manager,
+            quota_history,
             security_stats_manager,
             monitoring_manager,
             mfa_manager,
@@ -202,10 +407,226 @@ impl ApiServer {
             spam_manager,
             import_export_manager,
             caldav_manager,
+            migration_manager,
+            reporting_manager,
+            billing_manager,
+            meter_api_calls: false,
+            tls_rpt_manager,
+            dmarc_report_manager,
+            storage_job_manager,
+            impersonation_guard,
+            footer_manager,
+            retention_manager,
+            share_manager,
+            alias_manager,
+            branding_manager,
+            notification_router,
+            device_manager,
+            flag_events: Arc::new(FlagEventBus::new()),
+            bandwidth: Arc::new(BandwidthLimiter::new(Default::default())),
+            delivery_budget: Arc::new(DeliveryBudget::new(Default::default())),
+            post_delivery: None,
+            queue: None,
+            residency_manager: None,
+            config: None,
+            login_anomalies: None,
+            hook_manager: None,
+            role_accounts: None,
+            dnsbl: None,
+            auth_caches: (None, None),
+            antivirus: None,
+            workers: None,
+            read_only: None,
+            quarantine: None,
+            dkim_keys: None,
+            openpgp: None,
             addr,
         })
     }
 
+    /// Manage these quotas instead of a private set, e.g. the ones the SMTP
+    /// listener enforces
+    pub fn with_quota_manager(mut self, quota_manager: Arc<QuotaManager>) -> Self {
+        self.quota_manager = quota_manager;
+        self
+    }
+
+    /// Route notifications through a router shared with the mail listeners
+    pub fn with_notifications(mut self, router: Arc<NotificationRouter>) -> Self {
+        self.notification_router = router;
+        self
+    }
+
+    /// Record devices in a manager shared with the mail listeners, whose
+    /// sign-in alerts go out by email
+    pub fn with_devices(mut self, devices: Arc<DeviceManager>) -> Self {
+        self.device_manager = devices;
+        self
+    }
+
+    /// Share flag changes with the other frontends through this bus
+    pub fn with_flag_events(mut self, bus: Arc<FlagEventBus>) -> Self {
+        self.flag_events = bus;
+        self
+    }
+
+    /// List mail and count folders from the metadata index
+    pub fn with_metadata(mut self, index: Arc<MetadataIndex>) -> Self {
+        self.state = Arc::new(AppState {
+            authenticator: self.state.authenticator.clone(),
+            jwt_config: self.state.jwt_config.clone(),
+            maildir_root: self.state.maildir_root.clone(),
+            metadata: Some(index),
+        });
+        self
+    }
+
+    /// Throttle raw downloads with these limits instead of only metering
+    /// them, e.g. the limiter the IMAP listener uses
+    pub fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> Self {
+        self.bandwidth = limiter;
+        self
+    }
+
+    /// Report the usage accounted in these budgets, i.e. the ones of the
+    /// SMTP listener
+    pub fn with_delivery_budget(mut self, budget: Arc<DeliveryBudget>) -> Self {
+        self.delivery_budget = budget;
+        self
+    }
+
+    /// Report the load of these post-delivery workers
+    pub fn with_post_delivery(mut self, queue: Arc<PostDeliveryQueue>) -> Self {
+        self.post_delivery = Some(queue);
+        self
+    }
+
+    /// Let this queue apply stored and removed messages to this server's
+    /// search index, which allows a single writer
+    pub fn with_search_queue(self, queue: Arc<IndexQueue>) -> Self {
+        queue.index_into(self.search_manager.clone());
+        self
+    }
+
+    /// Count authenticated API requests per user in this billing manager,
+    /// e.g. the one the mail listeners record messages in
+    pub fn with_billing(mut self, manager: Arc<BillingManager>) -> Self {
+        self.billing_manager = manager;
+        self.meter_api_calls = true;
+        self
+    }
+
+    /// Expose the outbound queue under `/api/admin/queue`
+    pub fn with_queue(mut self, queue: Arc<SmtpQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Expose residency administration under `/api/admin/residency` and
+    /// route exports to the users' regions
+    pub fn with_residency(mut self, manager: Arc<ResidencyManager>) -> Self {
+        let export_path = std::path::PathBuf::from(&self.state.maildir_root).join("exports");
+        let maildir_path = std::path::PathBuf::from(&self.state.maildir_root);
+        self.import_export_manager = Arc::new(
+            ImportExportManager::new(export_path, maildir_path).with_residency(manager.map()),
+        );
+        self.residency_manager = Some(manager);
+        self
+    }
+
+    /// Expose the fingerprint of `config` under `/api/admin/config` for
+    /// drift checks between nodes, and index attachments as it says
+    pub fn with_config(mut self, config: Arc<Config>) -> Self {
+        self.search_manager
+            .set_attachment_config(config.search.attachments.clone());
+        self.config = Some(config);
+        self
+    }
+
+    /// Hold logins from unusual locations for step-up verification with
+    /// this detector, e.g. the one the mail listeners report logins to
+    pub fn with_login_anomalies(mut self, detector: Arc<LoginAnomalyDetector>) -> Self {
+        self.login_anomalies = Some(detector);
+        self
+    }
+
+    /// Manage delivery hooks under `/api/admin/hooks` with this manager,
+    /// e.g. the one SMTP delivery runs hooks from
+    pub fn with_hooks(mut self, manager: Arc<HookManager>) -> Self {
+        self.hook_manager = Some(manager);
+        self
+    }
+
+    /// Manage role accounts under `/api/admin/role-accounts` with this
+    /// manager
+    pub fn with_role_accounts(mut self, manager: Arc<RoleAccountManager>) -> Self {
+        self.role_accounts = Some(manager);
+        self
+    }
+
+    /// Report the counters of this DNS blocklist checker under
+    /// `/api/admin/dnsbl`
+    pub fn with_dnsbl(mut self, checker: Arc<DnsblChecker>) -> Self {
+        self.dnsbl = Some(checker);
+        self
+    }
+
+    /// Report the cache counters of these SPF and DKIM validators under
+    /// `/api/admin/auth-cache`
+    pub fn with_auth_caches(
+        mut self,
+        spf: Option<Arc<SpfValidator>>,
+        dkim: Option<Arc<DkimValidator>>,
+    ) -> Self {
+        self.auth_caches = (spf, dkim);
+        self
+    }
+
+    /// Report the counters of this virus scanner under
+    /// `/api/admin/antivirus`
+    pub fn with_antivirus(mut self, scanner: Arc<VirusScanner>) -> Self {
+        self.antivirus = Some(scanner);
+        self
+    }
+
+    /// Report the load of these worker pools under `/api/admin/workers`
+    pub fn with_workers(mut self, workers: Arc<WorkerPools>) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Refuse changes while this flag is set, and toggle it under
+    /// `/api/admin/read-only`
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(mode);
+        self
+    }
+
+    /// Let users release or delete the spam held in `store` under
+    /// `/api/spam/quarantine`, delivering released messages to `storage`
+    pub fn with_quarantine(
+        mut self,
+        store: Arc<QuarantineStore>,
+        storage: Arc<MaildirStorage>,
+    ) -> Self {
+        self.quarantine = Some((store, storage));
+        self
+    }
+
+    /// Let admins manage the DKIM keys of `manager` under
+    /// `/api/admin/dkim/keys`
+    pub fn with_dkim_keys(mut self, manager: Arc<DkimKeyManager>) -> Self {
+        self.dkim_keys = Some(manager);
+        self
+    }
+
+    /// Let users manage their OpenPGP keys under `/api/openpgp` and send
+    /// encrypted mail
+    pub fn with_openpgp(mut self, manager: Arc<OpenPgpManager>) -> Self {
+        self.openpgp = Some(manager);
+        self
+    }
+
     /// Build the router with all routes
     pub fn router(&self) -> Router {
         // CORS configuration
@@ -214,21 +635,53 @@ impl ApiServer {
             .allow_methods(Any)
             .allow_headers(Any);
 
+        // Tokens are checked against the devices they were issued to
+        let token_state = (self.state.clone(), self.device_manager.clone());
+
         // Public routes (no auth required)
+        let login_state = Arc::new(handlers::LoginState {
+            app: self.state.clone(),
+            devices: self.device_manager.clone(),
+            anomalies: self.login_anomalies.clone(),
+            mfa: self.mfa_manager.clone(),
+            queue: self.queue.clone(),
+            hostname: self
+                .config
+                .as_ref()
+                .map_or_else(|| "localhost".to_string(), |config| config.server.hostname.clone()),
+            base_url: self
+                .config
+                .as_ref()
+                .and_then(|config| config.login_anomaly.base_url.clone()),
+        });
+        let login_routes = Router::new()
+            .route("/auth/login", post(handlers::login))
+            .route("/auth/step-up", post(login_anomaly::complete_step_up))
+            .with_state(login_state.clone());
         let public_routes = Router::new()
             .route("/health", get(handlers::health))
-            .route("/auth/login", post(handlers::login));
+            .merge(login_routes);
 
         // Protected routes (auth required)
         let protected_routes = Router::new()
             .route("/mails", get(handlers::list_emails))
             .route("/mails/:id", get(handlers::get_email))
-            .route("/mails/send", post(handlers::send_email))
+            .route("/mails/:id/parts/:part_id", get(handlers::get_email_part))
             .route("/folders", get(handlers::list_folders))
             .route_layer(middleware::from_fn_with_state(
-                self.state.clone(),
+                token_state.clone(),
                 auth_middleware,
             ));
+        let send_routes = Router::new()
+            .route("/mails/send", post(handlers::send_email))
+            .route_layer(middleware::from_fn_with_state(
+                token_state.clone(),
+                auth_middleware,
+            ))
+            .with_state(Arc::new(handlers::SendState {
+                queue: self.queue.clone(),
+                openpgp: self.openpgp.clone(),
+            }));
 
         // Admin API routes (auth required + admin role check)
         let admin_api_routes = Router::new()
@@ -239,7 +692,6 @@ impl ApiServer {
             .route("/users/:id", delete(admin::delete_user))
             .route("/stats", get(admin::get_system_stats))
             .route("/config", get(admin::get_config))
-            .route("/dns", get(admin::get_dns_config))
             .route("/diagnostics", get(admin::get_diagnostics))
             .route("/backups", get(admin::list_backups))
             .route("/backups", post(admin::create_backup))
@@ -249,10 +701,25 @@ impl ApiServer {
             .route("/ssl/request", post(admin::request_ssl_certificate))
             .route("/ssl/renew", post(admin::renew_ssl_certificate))
             .route_layer(middleware::from_fn_with_state(
-                self.state.clone(),
+                token_state.clone(),
                 auth_middleware,
             ));
 
+        // DNS helper, generated from the running configuration
+        let dns_config = self.config.clone().unwrap_or_else(|| Arc::new(Config::default()));
+        let dns_api_routes = Router::new()
+            .route("/dns", get(admin::get_dns_config))
+            .route("/dns/verify", get(admin::verify_dns))
+            .route_layer(middleware::from_fn_with_state(
+                token_state,
+                auth_middleware,
+            ))
+            .with_state(Arc::new(admin::DnsState {
+                verifier: Arc::new(DnsVerifier::new(&dns_config)),
+                config: dns_config,
+                dkim: self.dkim_keys.clone(),
+            }));
+
         // Template API routes (session-based auth via cookies)
         let template_state = Arc::new(templates::TemplateState {
             template_manager: self.template_manager.clone(),
@@ -302,6 +769,7 @@ impl ApiServer {
         // Quotas API routes (session-based auth via cookies)
         let quota_state = Arc::new(quotas::QuotaState {
             manager: self.quota_manager.clone(),
+            history: self.quota_history.clone(),
         });
 
         let quotas_api_routes = Router::new()
@@ -310,6 +778,8 @@ impl ApiServer {
             .route("/admin/quotas/defaults", get(quotas::get_defaults))
             .route("/admin/quotas/defaults", put(quotas::update_defaults))
             .route("/admin/quotas/reset-daily", post(quotas::reset_daily_counts))
+            .route("/admin/quotas/metrics", get(quotas::get_metrics))
+            .route("/admin/quotas/:email/history", get(quotas::get_history))
             .route("/admin/quotas/:email", get(quotas::get_quota))
             .route("/admin/quotas/:email", put(quotas::update_quota))
             .with_state(quota_state);
@@ -368,9 +838,21 @@ impl ApiServer {
             .route("/sieve/scripts/:id", delete(sieve::delete_script))
             .route("/sieve/scripts/:id/activate", post(sieve::activate_script))
             .route("/sieve/scripts/:id/deactivate", post(sieve::deactivate_script))
+            .route("/sieve/scripts/:id/versions", get(sieve::list_versions))
+            .route("/sieve/scripts/:id/versions/:version", get(sieve::get_version))
+            .route(
+                "/sieve/scripts/:id/versions/:version/rollback",
+                post(sieve::rollback_script),
+            )
+            .route("/sieve/scripts/:id/diff", get(sieve::diff_versions))
             .route("/sieve/validate", post(sieve::validate_script))
+            .route("/sieve/test", post(sieve::test_script))
             .route("/sieve/logs", get(sieve::get_logs))
             .route("/sieve/logs", delete(sieve::clear_logs))
+            .route("/admin/sieve/global", get(sieve::list_global_scripts))
+            .route("/admin/sieve/global", post(sieve::create_global_script))
+            .route("/admin/sieve/global/:id", put(sieve::update_global_script))
+            .route("/admin/sieve/global/:id", delete(sieve::delete_global_script))
             .with_state(sieve_state);
 
         // Search API routes (session-based auth via cookies)
@@ -384,11 +866,14 @@ impl ApiServer {
             .route("/search/reindex", post(search::reindex))
             .route("/search/reindex-all", post(search::reindex_all))
             .route("/search/clear", delete(search::clear_index))
+            .route("/admin/search/:email/rebuild", post(search::rebuild_user_index))
             .with_state(search_state);
 
         // Spam API routes (session-based auth via cookies)
         let spam_state = Arc::new(spam::SpamState {
             spam_manager: self.spam_manager.clone(),
+            maildir_root: std::path::PathBuf::from(&self.state.maildir_root),
+            residency: self.residency_manager.as_ref().map(|manager| manager.map()),
         });
 
         let spam_api_routes = Router::new()
@@ -402,10 +887,46 @@ impl ApiServer {
             .route("/spam/test", post(spam::test_message))
             .route("/spam/learn/spam", post(spam::learn_spam))
             .route("/spam/learn/ham", post(spam::learn_ham))
+            .route("/spam/train/:message", post(spam::train_message))
             .route("/spam/logs", get(spam::get_logs))
             .route("/spam/logs", delete(spam::clear_logs))
             .with_state(spam_state);
 
+        // Spam quarantine routes (session-based auth via cookies)
+        let quarantine_state = Arc::new(quarantine::QuarantineState {
+            store: self.quarantine.as_ref().map(|(store, _)| store.clone()),
+            storage: self.quarantine.as_ref().map(|(_, storage)| storage.clone()),
+        });
+
+        let quarantine_api_routes = Router::new()
+            .route("/spam/quarantine", get(quarantine::list_quarantined))
+            .route(
+                "/spam/quarantine/:id/release",
+                post(quarantine::release_quarantined),
+            )
+            .route("/spam/quarantine/:id", delete(quarantine::delete_quarantined))
+            .with_state(quarantine_state);
+
+        // DKIM key routes (session-based auth via cookies)
+        let dkim_state = Arc::new(dkim::DkimState {
+            manager: self.dkim_keys.clone(),
+        });
+
+        let dkim_api_routes = Router::new()
+            .route(
+                "/admin/dkim/keys",
+                get(dkim::list_keys).post(dkim::generate_key),
+            )
+            .route(
+                "/admin/dkim/keys/:domain/:selector",
+                get(dkim::get_key).delete(dkim::delete_key),
+            )
+            .route(
+                "/admin/dkim/keys/:domain/:selector/activate",
+                post(dkim::activate_key),
+            )
+            .with_state(dkim_state);
+
         // Import/Export API routes (session-based auth via cookies)
         let import_export_state = Arc::new(import_export::ImportExportState {
             manager: self.import_export_manager.clone(),
@@ -458,9 +979,441 @@ impl ApiServer {
             .route("/caldav/contacts/:contact_id", delete(caldav::delete_contact))
             .with_state(caldav_state);
 
+        // Migration coexistence API routes (session-based auth via cookies)
+        let migration_state = Arc::new(migration::MigrationState {
+            manager: self.migration_manager.clone(),
+        });
+
+        let migration_api_routes = Router::new()
+            .route("/admin/migration/users", get(migration::list_users))
+            .route("/admin/migration/users/:email", put(migration::set_user_migrated))
+            .with_state(migration_state);
+
+        // Usage report API routes (session-based auth via cookies)
+        let reports_state = Arc::new(reports::ReportsState {
+            manager: self.reporting_manager.clone(),
+        });
+
+        let reports_api_routes = Router::new()
+            .route("/admin/reports", get(reports::list_reports))
+            .route("/admin/reports/generate", post(reports::generate_report))
+            .route("/admin/reports/:id", get(reports::get_report))
+            .route("/admin/reports/:id/:format", get(reports::download_report))
+            .with_state(reports_state);
+
+        // Billing export API routes (session-based auth via cookies)
+        let billing_state = Arc::new(billing::BillingState {
+            manager: self.billing_manager.clone(),
+        });
+
+        let billing_api_routes = Router::new()
+            .route("/admin/billing/export", get(billing::export))
+            .with_state(billing_state);
+
+        // TLS report API routes (session-based auth via cookies)
+        let tls_reports_state = Arc::new(tls_reports::TlsReportsState {
+            manager: self.tls_rpt_manager.clone(),
+        });
+
+        let tls_reports_api_routes = Router::new()
+            .route("/admin/tls-reports", get(tls_reports::list_pending))
+            .route("/admin/tls-reports/:domain/:day", get(tls_reports::get_policy_result))
+            .with_state(tls_reports_state);
+
+        let dmarc_reports_state = Arc::new(dmarc_reports::DmarcReportsState {
+            manager: self.dmarc_report_manager.clone(),
+        });
+
+        let dmarc_reports_api_routes = Router::new()
+            .route("/admin/dmarc-reports", get(dmarc_reports::list_reports))
+            .route("/admin/dmarc-reports/:id", get(dmarc_reports::get_report))
+            .with_state(dmarc_reports_state);
+
+        // Storage job API routes (session-based auth via cookies)
+        let storage_jobs_state = Arc::new(storage_jobs::StorageJobsState {
+            manager: self.storage_job_manager.clone(),
+        });
+
+        let storage_jobs_api_routes = Router::new()
+            .route("/admin/storage/jobs", get(storage_jobs::list_jobs))
+            .route("/admin/storage/jobs", post(storage_jobs::create_job))
+            .route("/admin/storage/jobs/:id", get(storage_jobs::get_job))
+            .route("/admin/storage/jobs/:id/pause", post(storage_jobs::pause_job))
+            .route("/admin/storage/jobs/:id/resume", post(storage_jobs::resume_job))
+            .route("/admin/storage/jobs/:id/cancel", post(storage_jobs::cancel_job))
+            .with_state(storage_jobs_state);
+
+        // Impersonation protection API routes (session-based auth via cookies)
+        let impersonation_state = Arc::new(impersonation::ImpersonationState {
+            guard: self.impersonation_guard.clone(),
+        });
+
+        let impersonation_api_routes = Router::new()
+            .route("/admin/impersonation", get(impersonation::list_policies))
+            .route("/admin/impersonation/:domain", get(impersonation::get_policy))
+            .route("/admin/impersonation/:domain", put(impersonation::set_policy))
+            .route("/admin/impersonation/:domain", delete(impersonation::delete_policy))
+            .route("/admin/impersonation/:domain/names", post(impersonation::add_name))
+            .route(
+                "/admin/impersonation/:domain/names/:name",
+                delete(impersonation::remove_name),
+            )
+            .with_state(impersonation_state);
+
+        // Footer API routes (session-based auth via cookies)
+        let footers_state = Arc::new(footers::FootersState {
+            manager: self.footer_manager.clone(),
+        });
+
+        let footers_api_routes = Router::new()
+            .route("/admin/footers", get(footers::list_footers))
+            .route("/admin/footers/:domain", get(footers::get_footer))
+            .route("/admin/footers/:domain", put(footers::set_footer))
+            .route("/admin/footers/:domain", delete(footers::delete_footer))
+            .route("/admin/sender-profiles/:user", get(footers::get_profile))
+            .route("/admin/sender-profiles/:user", put(footers::set_profile))
+            .route("/admin/sender-profiles/:user", delete(footers::delete_profile))
+            .with_state(footers_state);
+
+        // Retention API routes (session-based auth via cookies)
+        let retention_state = Arc::new(retention::RetentionState {
+            manager: self.retention_manager.clone(),
+        });
+
+        let retention_api_routes = Router::new()
+            .route("/admin/retention/policies", get(retention::list_policies))
+            .route("/admin/retention/policies", post(retention::create_policy))
+            .route("/admin/retention/policies/:id", get(retention::get_policy))
+            .route("/admin/retention/policies/:id", put(retention::update_policy))
+            .route("/admin/retention/policies/:id", delete(retention::delete_policy))
+            .with_state(retention_state);
+
+        // Branding API routes (session-based auth via cookies)
+        let branding_state = Arc::new(branding::BrandingState {
+            manager: self.branding_manager.clone(),
+        });
+
+        let branding_api_routes = Router::new()
+            .route("/admin/branding", get(branding::list_branding))
+            .route("/admin/branding/:domain", get(branding::get_branding))
+            .route("/admin/branding/:domain", put(branding::set_branding))
+            .route("/admin/branding/:domain", delete(branding::delete_branding))
+            .with_state(branding_state);
+
+        // Burner alias API routes (session-based auth via cookies)
+        let aliases_state = Arc::new(aliases::AliasesState {
+            manager: self.alias_manager.clone(),
+            domain: self
+                .config
+                .as_ref()
+                .and_then(|config| config.aliases.domain.clone()),
+        });
+
+        let aliases_api_routes = Router::new()
+            .route("/aliases", get(aliases::list_aliases))
+            .route("/aliases", post(aliases::create_alias))
+            .route("/aliases/:address", patch(aliases::update_alias))
+            .route("/aliases/:address", delete(aliases::delete_alias))
+            .route("/aliases/:address/stats", get(aliases::alias_stats))
+            .with_state(aliases_state);
+
+        // Device API routes (session-based auth via cookies)
+        let devices_state = Arc::new(devices::DevicesState {
+            manager: self.device_manager.clone(),
+        });
+
+        let devices_api_routes = Router::new()
+            .route("/devices", get(devices::list_devices))
+            .route("/devices/app-passwords", post(devices::create_app_password))
+            .route("/devices/:id", delete(devices::revoke_device))
+            .with_state(devices_state);
+
+        // Share link API routes (session-based auth via cookies) and the
+        // public pages recipients open
+        let sharing_state = Arc::new(sharing::SharingState {
+            manager: self.share_manager.clone(),
+            maildir_root: std::path::PathBuf::from(&self.state.maildir_root),
+            residency: self.residency_manager.as_ref().map(|manager| manager.map()),
+            config: self
+                .config
+                .as_ref()
+                .map(|config| config.sharing.clone())
+                .unwrap_or_default(),
+        });
+
+        let sharing_api_routes = Router::new()
+            .route("/shares", get(sharing::list_shares))
+            .route("/shares", post(sharing::create_share))
+            .route("/shares/:id", delete(sharing::revoke_share))
+            .route("/shares/:id/access", get(sharing::share_access))
+            .with_state(sharing_state.clone());
+
+        let share_routes = Router::new()
+            .route("/share/:token", get(sharing::share_page))
+            .route("/share/:token/package", get(sharing::share_package))
+            .with_state(sharing_state);
+
+        // OpenPGP key routes (session-based auth via cookies)
+        let openpgp_state = Arc::new(openpgp::OpenPgpState {
+            manager: self.openpgp.clone(),
+            maildir_root: std::path::PathBuf::from(&self.state.maildir_root),
+            residency: self.residency_manager.as_ref().map(|manager| manager.map()),
+        });
+
+        let openpgp_api_routes = Router::new()
+            .route(
+                "/openpgp/keys",
+                get(openpgp::list_keys).post(openpgp::import_key),
+            )
+            .route("/openpgp/keys/:fingerprint", delete(openpgp::delete_key))
+            .route("/openpgp/lookup", get(openpgp::lookup_key))
+            .route("/openpgp/mails/:mailbox/:message", get(openpgp::open_mail))
+            .with_state(openpgp_state);
+
+        // Calendar invitation routes (session-based auth via cookies)
+        let invitations_state = Arc::new(invitations::InvitationState {
+            manager: self.caldav_manager.clone(),
+            queue: self.queue.clone(),
+        });
+
+        let invitations_api_routes = Router::new()
+            .route("/calendar/invitations", get(invitations::list_invitations))
+            .route("/calendar/invitations/:id", get(invitations::get_invitation))
+            .route(
+                "/calendar/invitations/:id/accept",
+                post(invitations::accept_invitation),
+            )
+            .route(
+                "/calendar/invitations/:id/tentative",
+                post(invitations::tentative_invitation),
+            )
+            .route(
+                "/calendar/invitations/:id/decline",
+                post(invitations::decline_invitation),
+            )
+            .with_state(invitations_state);
+
+        // Pages confirming held logins from their email links
+        let step_up_routes = Router::new()
+            .route(
+                "/login/verify/:token",
+                get(login_anomaly::verify_page).post(login_anomaly::verify_submit),
+            )
+            .with_state(login_state);
+
+        // Login anomaly overrides (session-based auth via cookies)
+        let login_anomaly_state = Arc::new(login_anomaly::LoginAnomalyState {
+            detector: self.login_anomalies.clone(),
+        });
+
+        let login_anomaly_api_routes = Router::new()
+            .route("/admin/login-anomalies/audit", get(login_anomaly::audit_log))
+            .route("/admin/login-anomalies/blocks", get(login_anomaly::list_blocks))
+            .route(
+                "/admin/login-anomalies/blocks/:user/lift",
+                post(login_anomaly::lift_blocks),
+            )
+            .route("/admin/login-anomalies/step-ups", get(login_anomaly::list_step_ups))
+            .route(
+                "/admin/login-anomalies/step-ups/:id/approve",
+                post(login_anomaly::approve_step_up),
+            )
+            .with_state(login_anomaly_state);
+
+        // Delivery hook API routes (session-based auth via cookies)
+        let hooks_state = Arc::new(hooks::HooksState {
+            manager: self.hook_manager.clone(),
+        });
+
+        let hooks_api_routes = Router::new()
+            .route("/admin/hooks", get(hooks::list_hooks))
+            .route("/admin/hooks/:target", get(hooks::get_hook))
+            .route("/admin/hooks/:target", delete(hooks::delete_hook))
+            .route("/admin/hooks/:target/versions", get(hooks::list_versions))
+            .route("/admin/hooks/:target/versions", post(hooks::upload_version))
+            .route(
+                "/admin/hooks/:target/versions/:version/activate",
+                post(hooks::activate_version),
+            )
+            .route("/admin/hooks/:target/deactivate", post(hooks::deactivate_hook))
+            .route("/admin/hooks/:target/dry-run", post(hooks::dry_run))
+            .with_state(hooks_state);
+
+        // Role account API routes (session-based auth via cookies)
+        let role_accounts_state = Arc::new(role_accounts::RoleAccountsState {
+            manager: self.role_accounts.clone(),
+        });
+
+        let role_accounts_api_routes = Router::new()
+            .route("/admin/role-accounts", get(role_accounts::list_accounts))
+            .route("/admin/role-accounts", post(role_accounts::provision))
+            .route("/admin/role-accounts/:address", get(role_accounts::get_account))
+            .route("/admin/role-accounts/:address", put(role_accounts::update_account))
+            .with_state(role_accounts_state);
+
+        // DNSBL metrics route (session-based auth via cookies)
+        let dnsbl_state = Arc::new(dnsbl::DnsblState {
+            checker: self.dnsbl.clone(),
+        });
+
+        let dnsbl_api_routes = Router::new()
+            .route("/admin/dnsbl", get(dnsbl::stats))
+            .with_state(dnsbl_state);
+
+        // SPF and DKIM cache metrics route (session-based auth via cookies)
+        let auth_cache_state = Arc::new(auth_cache::AuthCacheState {
+            spf: self.auth_caches.0.clone(),
+            dkim: self.auth_caches.1.clone(),
+        });
+
+        let auth_cache_api_routes = Router::new()
+            .route("/admin/auth-cache", get(auth_cache::stats))
+            .with_state(auth_cache_state);
+
+        // Virus scanning metrics route (session-based auth via cookies)
+        let antivirus_state = Arc::new(antivirus::AntivirusState {
+            scanner: self.antivirus.clone(),
+        });
+
+        let antivirus_api_routes = Router::new()
+            .route("/admin/antivirus", get(antivirus::stats))
+            .with_state(antivirus_state);
+
+        // Worker pool metrics route (session-based auth via cookies)
+        let workers_state = Arc::new(workers::WorkersState {
+            workers: self.workers.clone(),
+        });
+
+        let workers_api_routes = Router::new()
+            .route("/admin/workers", get(workers::stats))
+            .with_state(workers_state);
+
+        // Read-only mode routes (session-based auth via cookies)
+        let recovery_state = Arc::new(recovery::RecoveryState {
+            read_only: self.read_only.clone(),
+        });
+
+        let recovery_api_routes = Router::new()
+            .route("/admin/read-only", get(recovery::status))
+            .route("/admin/read-only", put(recovery::enter))
+            .route("/admin/read-only", delete(recovery::clear))
+            .with_state(recovery_state);
+
+        // Notification API routes (session-based auth via cookies)
+        let notifications_state = Arc::new(notifications::NotificationsState {
+            router: self.notification_router.clone(),
+        });
+
+        let notifications_api_routes = Router::new()
+            .route("/notifications/preferences", get(notifications::get_preferences))
+            .route("/notifications/preferences", put(notifications::set_preferences))
+            .route("/notifications/ws", get(notifications::websocket))
+            .with_state(notifications_state);
+
+        // Message flag API routes (session-based auth via cookies)
+        let flags_state = Arc::new(flags::FlagsState {
+            bus: self.flag_events.clone(),
+            maildir_root: std::path::PathBuf::from(&self.state.maildir_root),
+            residency: self.residency_manager.as_ref().map(|manager| manager.map()),
+        });
+
+        let flags_api_routes = Router::new()
+            .route("/messages/:message/flags", post(flags::update_flags))
+            .route("/messages/events", get(flags::websocket))
+            .with_state(flags_state);
+
+        // Throttled downloads (JWT auth) and session throughput (session-based auth via cookies)
+        let bandwidth_state = Arc::new(bandwidth::BandwidthState {
+            limiter: self.bandwidth.clone(),
+            maildir_root: std::path::PathBuf::from(&self.state.maildir_root),
+        });
+
+        let download_api_routes = Router::new()
+            .route("/mails/:id/raw", get(bandwidth::download_raw))
+            .route_layer(middleware::from_fn_with_state(
+                (self.state.clone(), self.device_manager.clone()),
+                auth_middleware,
+            ))
+            .with_state(bandwidth_state.clone());
+
+        let bandwidth_api_routes = Router::new()
+            .route("/admin/sessions", get(bandwidth::list_sessions))
+            .with_state(bandwidth_state);
+
+        let budgets_state = Arc::new(budgets::BudgetsState {
+            budget: self.delivery_budget.clone(),
+            post_delivery: self.post_delivery.clone(),
+        });
+        let budgets_routes = Router::new()
+            .route("/admin/delivery/usage", get(budgets::delivery_usage))
+            .route("/admin/delivery/workers", get(budgets::delivery_workers))
+            .with_state(budgets_state);
+
+        // Outbound queue API routes (session-based auth via cookies)
+        let queue_state = Arc::new(queue::QueueState {
+            queue: self.queue.clone(),
+        });
+
+        let queue_api_routes = Router::new()
+            .route("/admin/queue", get(queue::list_entries))
+            .route("/admin/queue/:id", get(queue::get_entry))
+            .route("/admin/queue/:id/release", post(queue::release_entry))
+            .with_state(queue_state);
+
+        // Captured mail API routes (session-based auth via cookies)
+        let capture_state = Arc::new(capture::CaptureState {
+            queue: self.queue.clone(),
+        });
+
+        let capture_api_routes = Router::new()
+            .route("/admin/capture", get(capture::list_messages))
+            .route("/admin/capture", delete(capture::clear_messages))
+            .route("/admin/capture/:id", get(capture::get_message))
+            .with_state(capture_state);
+
+        // Residency API routes (session-based auth via cookies)
+        let residency_state = Arc::new(residency::ResidencyState {
+            manager: self.residency_manager.clone(),
+            maildir_root: std::path::PathBuf::from(&self.state.maildir_root),
+        });
+
+        let residency_api_routes = Router::new()
+            .route("/admin/residency/regions", get(residency::list_regions))
+            .route("/admin/residency/regions/:region/backups", get(residency::list_backups))
+            .route("/admin/residency/regions/:region/backups", post(residency::create_backup))
+            .route("/admin/residency/assignments", get(residency::list_assignments))
+            .route("/admin/residency/assignments/:subject", put(residency::assign))
+            .route("/admin/residency/assignments/:subject", delete(residency::unassign))
+            .route("/admin/residency/audit", get(residency::audit_log))
+            .route("/admin/residency/restore", post(residency::restore))
+            .with_state(residency_state);
+
+        // Log level API routes (session-based auth via cookies)
+        let logging_api_routes = Router::new()
+            .route("/admin/logging", get(logging::get_levels))
+            .route("/admin/logging", put(logging::set_levels));
+
+        // Fault injection API routes (session-based auth via cookies)
+        let chaos_api_routes = Router::new()
+            .route("/admin/chaos", get(chaos::list_faults))
+            .route("/admin/chaos", delete(chaos::clear_faults))
+            .route("/admin/chaos/:fault", put(chaos::set_fault))
+            .route("/admin/chaos/:fault", delete(chaos::clear_fault));
+
+        // Config drift API routes
+        let config_drift_state = Arc::new(config_drift::ConfigDriftState {
+            config: self.config.clone(),
+        });
+
+        let config_drift_api_routes = Router::new()
+            .route("/admin/config/fingerprint", get(config_drift::fingerprint))
+            .route("/admin/config/compare", post(config_drift::compare))
+            .with_state(config_drift_state);
+
         // Web routes (HTML pages)
         let web_state = Arc::new(web::AppState {
             authenticator: self.state.authenticator.clone(),
+            branding: self.branding_manager.clone(),
         });
 
         let web_routes = Router::new()
@@ -498,48 +1451,136 @@ impl ApiServer {
             .route("/chat/app", get(web::chat_app))
             .with_state(web_state);
 
-        // Combine all routes
-        Router::new()
-            .nest(
-                "/api",
-                public_routes
-                    .merge(protected_routes)
-                    .merge(template_api_routes)
-                    .merge(auto_reply_api_routes)
-                    .merge(greylisting_api_routes)
-                    .merge(quotas_api_routes)
-                    .merge(security_api_routes)
-                    .merge(monitoring_api_routes)
-                    .merge(mfa_api_routes)
-                    .merge(sieve_api_routes)
-                    .merge(search_api_routes)
-                    .merge(spam_api_routes)
-                    .merge(import_export_api_routes)
-                    .merge(caldav_api_routes),
+        // Mail client autoconfiguration (public, served on autoconfig.<domain>)
+        let autoconfig_state = Arc::new(autoconfig::AutoconfigState {
+            branding: self.branding_manager.clone(),
+            config: self.config.clone().unwrap_or_else(|| Arc::new(Config::default())),
+        });
+
+        let autoconfig_routes = Router::new()
+            .route("/mail/config-v1.1.xml", get(autoconfig::config_xml))
+            .route(
+                "/.well-known/autoconfig/mail/config-v1.1.xml",
+                get(autoconfig::config_xml),
             )
-            .nest("/api/admin", admin_api_routes)
+            .with_state(autoconfig_state);
+
+        // MTA-STS policy (public, served on mta-sts.<domain>)
+        let mta_sts_routes = Router::new()
+            .route("/.well-known/mta-sts.txt", get(mta_sts::policy))
+            .with_state(Arc::new(mta_sts::MtaStsState {
+                config: self.config.clone().unwrap_or_else(|| Arc::new(Config::default())),
+            }));
+
+        // Combine all routes
+        let api_routes = public_routes
+            .merge(protected_routes)
+            .merge(send_routes)
+            .merge(template_api_routes)
+            .merge(auto_reply_api_routes)
+            .merge(greylisting_api_routes)
+            .merge(quotas_api_routes)
+            .merge(security_api_routes)
+            .merge(monitoring_api_routes)
+            .merge(mfa_api_routes)
+            .merge(sieve_api_routes)
+            .merge(search_api_routes)
+            .merge(spam_api_routes)
+            .merge(quarantine_api_routes)
+            .merge(dkim_api_routes)
+            .merge(import_export_api_routes)
+            .merge(caldav_api_routes)
+            .merge(migration_api_routes)
+            .merge(reports_api_routes)
+            .merge(billing_api_routes)
+            .merge(tls_reports_api_routes)
+            .merge(dmarc_reports_api_routes)
+            .merge(storage_jobs_api_routes)
+            .merge(impersonation_api_routes)
+            .merge(footers_api_routes)
+            .merge(retention_api_routes)
+            .merge(branding_api_routes)
+            .merge(queue_api_routes)
+            .merge(capture_api_routes)
+            .merge(residency_api_routes)
+            .merge(aliases_api_routes)
+            .merge(devices_api_routes)
+            .merge(sharing_api_routes)
+            .merge(openpgp_api_routes)
+            .merge(invitations_api_routes)
+            .merge(login_anomaly_api_routes)
+            .merge(hooks_api_routes)
+            .merge(role_accounts_api_routes)
+            .merge(dnsbl_api_routes)
+            .merge(auth_cache_api_routes)
+            .merge(antivirus_api_routes)
+            .merge(workers_api_routes)
+            .merge(recovery_api_routes)
+            .merge(notifications_api_routes)
+            .merge(flags_api_routes)
+            .merge(download_api_routes)
+            .merge(bandwidth_api_routes)
+            .merge(budgets_routes)
+            .merge(logging_api_routes)
+            .merge(chaos_api_routes)
+            .merge(config_drift_api_routes);
+        let api_routes = Router::new()
+            .nest("/api", api_routes)
+            .nest("/api/admin", admin_api_routes.merge(dns_api_routes));
+        let api_routes = match &self.read_only {
+            Some(mode) => api_routes.layer(middleware::from_fn_with_state(
+                mode.clone(),
+                read_only_middleware,
+            )),
+            None => api_routes,
+        };
+        let api_routes = if self.meter_api_calls {
+            api_routes.layer(middleware::from_fn_with_state(
+                (self.state.clone(), self.billing_manager.clone()),
+                billing_middleware,
+            ))
+        } else {
+            api_routes
+        };
+
+        api_routes
             .merge(web_routes)
             .merge(chat_routes)
+            .merge(autoconfig_routes)
+            .merge(mta_sts_routes)
+            .merge(share_routes)
+            .merge(step_up_routes)
             .layer(cors)
             .with_state(self.state.clone())
     }
 
     /// Start the API server
     pub async fn run(&self) -> std::io::Result<()> {
+        let listener = tokio::net::TcpListener::bind(&self.addr).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve the API on an already bound listener
+    pub async fn serve(&self, listener: tokio::net::TcpListener) -> std::io::Result<()> {
         let router = self.router();
 
-        info!("Starting API server on {}", self.addr);
+        info!("Starting API server on {}", listener.local_addr()?);
 
-        let listener = tokio::net::TcpListener::bind(&self.addr).await?;
-        axum::serve(listener, router).await?;
+        // Client addresses key the per-IP download limits
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
 
         Ok(())
     }
 }
 
-/// Authentication middleware - validates JWT token
+/// Authentication middleware - validates JWT token and that its device was
+/// not revoked
 async fn auth_middleware(
-    State(state): State<Arc<AppState>>,
+    State((state, devices)): State<(Arc<AppState>, Arc<DeviceManager>)>,
     mut req: axum::http::Request<axum::body::Body>,
     next: Next,
 ) -> Response {
@@ -564,6 +1605,27 @@ async fn auth_middleware(
     // Validate token
     match state.jwt_config.validate_token(token) {
         Ok(claims) => {
+            if let Some(device) = &claims.device {
+                match devices.is_active(&claims.sub, device).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("Token of revoked device {} of {}", device, claims.sub);
+                        return (
+                            StatusCode::UNAUTHORIZED,
+                            Json(ApiError::new("Device access revoked, sign in again")),
+                        )
+                            .into_response();
+                    }
+                    Err(e) => {
+                        error!("Failed to check device {} of {}: {}", device, claims.sub, e);
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(ApiError::new("Failed to check device")),
+                        )
+                            .into_response();
+                    }
+                }
+            }
             // Store claims in request extensions for handlers
             req.extensions_mut().insert(claims);
             next.run(req).await
@@ -579,6 +1641,72 @@ async fn auth_middleware(
     }
 }
 
+/// Paths still accepting changes in read-only mode: logins, and the
+/// endpoint clearing the flag
+const READ_ONLY_EXEMPT: [&str; 3] = [
+    "/api/auth/login",
+    "/api/auth/step-up",
+    "/api/admin/read-only",
+];
+
+/// Read-only middleware - refuses requests changing data while the server
+/// is read-only
+async fn read_only_middleware(
+    State(mode): State<Arc<ReadOnlyMode>>,
+    req: axum::http::Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let reads = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if reads || READ_ONLY_EXEMPT.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    match mode.state() {
+        Some(state) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ApiError::new(&format!(
+                "Server is in read-only mode ({}), changes are not possible",
+                state.reason
+            ))),
+        )
+            .into_response(),
+        None => next.run(req).await,
+    }
+}
+
+/// Billing middleware - counts requests of authenticated users
+///
+/// Users are identified by a valid JWT or the admin session cookie;
+/// anonymous and rejected requests are not counted.
+async fn billing_middleware(
+    State((state, billing)): State<(Arc<AppState>, Arc<BillingManager>)>,
+    req: axum::http::Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let user = match bearer {
+        Some(token) => state.jwt_config.validate_token(token).ok().map(|claims| claims.sub),
+        None => get_session_email(req.headers()),
+    };
+
+    let response = next.run(req).await;
+
+    if let Some(user) = user {
+        if response.status() != StatusCode::UNAUTHORIZED {
+            // Recorded in the background so metering never delays responses
+            tokio::spawn(async move {
+                if let Err(e) = billing.record(&user, BillingMetric::ApiCall).await {
+                    warn!("Failed to record API call for {}: {}", user, e);
+                }
+            });
+        }
+    }
+    response
+}
+
 /// Extract Claims from request (for handlers)
 #[axum::async_trait]
 impl<S> FromRequestParts<S> for Claims
@@ -0,0 +1,340 @@
+//! API endpoints for sharing messages through secure links
+//!
+//! A user shares one of their messages with `POST /api/shares`: the message,
+//! with or without its attachments, is encrypted with a one-time passphrase
+//! and an expiring link to it is returned along with the passphrase, which
+//! is not stored. The recipient opens the link, a public page that downloads
+//! the encrypted message and decrypts it in the browser. Every download is
+//! logged; links can be revoked, which also wipes the encrypted message.
+
+use crate::api::auth::get_session_email;
+use crate::config::SharingConfig;
+use crate::imap::uid::base_name;
+use crate::imap::Mailbox;
+use crate::mime::{decode_header, MimeEntity};
+use crate::residency::ResidencyMap;
+use crate::sharing::package::build_package;
+use crate::sharing::{CreateShare, ShareAccess, ShareFetch, ShareLink, ShareManager, ShareStatus};
+use askama_axum::Template;
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// App state containing the share manager
+pub struct SharingState {
+    pub manager: Arc<ShareManager>,
+    pub maildir_root: PathBuf,
+    pub residency: Option<Arc<ResidencyMap>>,
+    pub config: SharingConfig,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found(what: &str) -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, &format!("{} not found", what))
+}
+
+fn internal_error(e: impl std::fmt::Display) -> (StatusCode, Json<ApiError>) {
+    error!("Sharing API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access shared messages",
+    )
+}
+
+/// A new share link; the passphrase is only returned here
+#[derive(Debug, Serialize)]
+pub struct CreatedShare {
+    pub link: ShareLink,
+    pub url: String,
+    pub passphrase: String,
+}
+
+/// Encrypted message served to the recipient's browser
+#[derive(Debug, Serialize)]
+pub struct PackageResponse {
+    pub filename: String,
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Page decrypting a shared message in the browser
+#[derive(Template)]
+#[template(path = "share.html")]
+struct ShareTemplate {
+    token: String,
+    /// Why the link no longer works, if it doesn't
+    error: String,
+}
+
+/// POST /api/shares - Share one of the current user's messages
+pub async fn create_share(
+    State(state): State<Arc<SharingState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateShare>,
+) -> ApiResult<(StatusCode, Json<CreatedShare>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let hours = payload
+        .expires_in_hours
+        .unwrap_or(state.config.default_expiry_hours);
+    if hours == 0 || hours > state.config.max_expiry_hours {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "Links expire after 1 to {} hours",
+                state.config.max_expiry_hours
+            ),
+        ));
+    }
+    if payload.max_downloads == Some(0) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "A link must allow at least one download",
+        ));
+    }
+
+    let root = match &state.residency {
+        Some(residency) => residency.maildir_root(&email, &state.maildir_root),
+        None => state.maildir_root.clone(),
+    };
+    let mailbox =
+        Mailbox::open(&email, &payload.mailbox, &root).map_err(|_| not_found("Message"))?;
+    let message = mailbox
+        .messages()
+        .iter()
+        .find(|msg| base_name(&msg.filename) == base_name(&payload.message))
+        .ok_or_else(|| not_found("Message"))?;
+    let content = message.content().map_err(internal_error)?;
+
+    let package = build_package(content, payload.include_attachments);
+    if package.len() > state.config.max_message_size {
+        return Err(api_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!(
+                "Messages up to {} bytes can be shared",
+                state.config.max_message_size
+            ),
+        ));
+    }
+    let subject = MimeEntity::parse(content)
+        .header_value("subject")
+        .map(|subject| decode_header(&subject));
+
+    let share = state
+        .manager
+        .create(
+            &email,
+            &payload,
+            subject,
+            &package,
+            Utc::now() + Duration::hours(hours.into()),
+        )
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "{} shared message {} as link {}",
+        email, payload.message, share.link.id
+    );
+
+    let base_url = match &state.config.base_url {
+        Some(base_url) => base_url.trim_end_matches('/').to_string(),
+        None => format!("https://{}", request_host(&headers)),
+    };
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedShare {
+            url: format!("{}/share/{}", base_url, share.token),
+            link: share.link,
+            passphrase: share.passphrase,
+        }),
+    ))
+}
+
+/// GET /api/shares - Share links of the current user, newest first
+pub async fn list_shares(
+    State(state): State<Arc<SharingState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<ShareLink>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let links = state.manager.list(&email).await.map_err(internal_error)?;
+    Ok(Json(links))
+}
+
+/// GET /api/shares/:id/access - Downloads of a share link, newest first
+pub async fn share_access(
+    State(state): State<Arc<SharingState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<Vec<ShareAccess>>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let log = state
+        .manager
+        .access_log(&email, &id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| not_found("Share link"))?;
+    Ok(Json(log))
+}
+
+/// DELETE /api/shares/:id - Revoke a share link
+pub async fn revoke_share(
+    State(state): State<Arc<SharingState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ShareLink>> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let link = state
+        .manager
+        .revoke(&email, &id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| not_found("Share link"))?;
+    info!("{} revoked share link {}", email, link.id);
+    Ok(Json(link))
+}
+
+/// GET /share/:token - Page the recipient decrypts the message on
+pub async fn share_page(
+    State(state): State<Arc<SharingState>>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    let error = match state.manager.by_token(&token).await {
+        Ok(Some(link)) => match link.status(Utc::now()) {
+            ShareStatus::Active => String::new(),
+            status => refusal(status).to_string(),
+        },
+        Ok(None) => "This link does not exist.".to_string(),
+        Err(e) => {
+            warn!("Failed to look up share link: {}", e);
+            "This link cannot be opened right now.".to_string()
+        }
+    };
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        ShareTemplate { token, error },
+    )
+}
+
+/// GET /share/:token/package - Encrypted message of a link, counted as a
+/// download
+pub async fn share_package(
+    State(state): State<Arc<SharingState>>,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(token): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip().to_string());
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+
+    let fetch = state
+        .manager
+        .fetch(&token, ip.as_deref(), user_agent)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| not_found("Share link"))?;
+    let (link, package) = match fetch {
+        ShareFetch::Package(link, package) => (link, package),
+        ShareFetch::Refused(link) => {
+            return Err(api_error(
+                StatusCode::GONE,
+                refusal(link.status(Utc::now())),
+            ))
+        }
+    };
+    info!(
+        "Share link {} downloaded ({} of {})",
+        link.id,
+        link.downloads,
+        link.max_downloads
+            .map_or("unlimited".to_string(), |max| max.to_string())
+    );
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(PackageResponse {
+            filename: filename(link.subject.as_deref()),
+            iterations: package.iterations,
+            salt: STANDARD.encode(&package.salt),
+            nonce: STANDARD.encode(&package.nonce),
+            ciphertext: STANDARD.encode(&package.ciphertext),
+        }),
+    ))
+}
+
+fn refusal(status: ShareStatus) -> &'static str {
+    match status {
+        ShareStatus::Active => "This link can be downloaded.",
+        ShareStatus::Expired => "This link has expired.",
+        ShareStatus::Revoked => "This link was revoked by its sender.",
+        ShareStatus::Exhausted => "This link was downloaded as often as allowed.",
+    }
+}
+
+/// Name of the downloaded `.eml` file, from the subject
+fn filename(subject: Option<&str>) -> String {
+    let name: String = subject
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-");
+    let name: String = name.chars().take(80).collect();
+    if name.is_empty() {
+        "message.eml".to_string()
+    } else {
+        format!("{}.eml", name)
+    }
+}
+
+fn request_host(headers: &HeaderMap) -> String {
+    headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost")
+        .to_string()
+}
@@ -1,7 +1,10 @@
 //! API endpoints for Sieve script management
+//!
+//! Users manage their own scripts; admins manage the global scripts any
+//! user's script can `include :global`.
 
 use crate::api::auth::get_session_email;
-use crate::sieve::{CreateSieveScriptRequest, SieveManager, SieveScript, ValidateSieveScriptRequest, ValidationResult, SieveLog};
+use crate::sieve::{CreateSieveScriptRequest, SieveManager, SieveScript, SieveScriptDiff, SieveScriptVersion, SieveTestResult, TestSieveScriptRequest, ValidateSieveScriptRequest, ValidationResult, SieveLog, GLOBAL_SCRIPTS_OWNER};
 use axum::{
     extract::{Path, State},
     http::{HeaderMap, StatusCode},
@@ -9,6 +12,7 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use tracing::info;
 
 /// App state containing Sieve manager
 pub struct SieveState {
@@ -268,6 +272,156 @@ pub async fn deactivate_script(
     Ok(StatusCode::OK)
 }
 
+/// GET /api/sieve/scripts/:id/versions - List saved versions, newest first
+pub async fn list_versions(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<SieveScriptVersion>>, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "Not authenticated".to_string(),
+            }),
+        )
+    })?;
+
+    let versions = state
+        .manager
+        .list_versions(&email, &id)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: e.to_string(),
+                }),
+            )
+        })?;
+
+    Ok(Json(versions))
+}
+
+/// GET /api/sieve/scripts/:id/versions/:version - Get a saved version
+pub async fn get_version(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Path((id, version)): Path<(String, u32)>,
+) -> Result<Json<SieveScriptVersion>, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "Not authenticated".to_string(),
+            }),
+        )
+    })?;
+
+    let version = state
+        .manager
+        .get_version(&email, &id, version)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: e.to_string(),
+                }),
+            )
+        })?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ApiError {
+                    error: "Version not found".to_string(),
+                }),
+            )
+        })?;
+
+    Ok(Json(version))
+}
+
+/// Query params for diffs
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    pub from: u32,
+    /// Defaults to the latest version
+    pub to: Option<u32>,
+}
+
+/// GET /api/sieve/scripts/:id/diff?from=1&to=2 - Compare two versions
+pub async fn diff_versions(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    axum::extract::Query(query): axum::extract::Query<DiffQuery>,
+) -> Result<Json<SieveScriptDiff>, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "Not authenticated".to_string(),
+            }),
+        )
+    })?;
+
+    let diff = state
+        .manager
+        .diff_versions(&email, &id, query.from, query.to)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: e.to_string(),
+                }),
+            )
+        })?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ApiError {
+                    error: "Version not found".to_string(),
+                }),
+            )
+        })?;
+
+    Ok(Json(diff))
+}
+
+/// POST /api/sieve/scripts/:id/versions/:version/rollback - Restore a
+/// script to a saved version
+pub async fn rollback_script(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Path((id, version)): Path<(String, u32)>,
+) -> Result<Json<SieveScript>, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "Not authenticated".to_string(),
+            }),
+        )
+    })?;
+
+    let script = state
+        .manager
+        .rollback_script(&email, &id, version)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: e.to_string(),
+                }),
+            )
+        })?;
+
+    Ok(Json(script))
+}
+
 /// POST /api/sieve/validate - Validate a script without saving
 pub async fn validate_script(
     State(state): State<Arc<SieveState>>,
@@ -298,6 +452,23 @@ pub async fn validate_script(
     Ok(Json(result))
 }
 
+/// POST /api/sieve/test - Run a script on a sample message and report
+/// what it would do, without delivering anything
+pub async fn test_script(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Json(payload): Json<TestSieveScriptRequest>,
+) -> Result<Json<SieveTestResult>, (StatusCode, Json<ApiError>)> {
+    let email = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let result = state
+        .manager
+        .test_script(&email, &payload)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(result))
+}
+
 /// Query params for logs
 #[derive(Debug, Deserialize)]
 pub struct LogsQuery {
@@ -365,3 +536,92 @@ pub async fn clear_logs(
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+fn api_error(status: StatusCode, message: impl ToString) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+/// GET /api/admin/sieve/global - List the global scripts users can include
+pub async fn list_global_scripts(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SieveScript>>, (StatusCode, Json<ApiError>)> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let scripts = state
+        .manager
+        .list_scripts(GLOBAL_SCRIPTS_OWNER)
+        .await
+        .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(scripts))
+}
+
+/// POST /api/admin/sieve/global - Create a global script; global scripts
+/// only run when included, so they are never active
+pub async fn create_global_script(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateSieveScriptRequest>,
+) -> Result<Json<SieveScript>, (StatusCode, Json<ApiError>)> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let request = CreateSieveScriptRequest {
+        activate: false,
+        ..payload
+    };
+    let script = state
+        .manager
+        .create_script(GLOBAL_SCRIPTS_OWNER, &request)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    info!("Admin {}: Created global Sieve script {}", admin, script.name);
+    Ok(Json(script))
+}
+
+/// PUT /api/admin/sieve/global/:id - Update a global script
+pub async fn update_global_script(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(payload): Json<CreateSieveScriptRequest>,
+) -> Result<Json<SieveScript>, (StatusCode, Json<ApiError>)> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let request = CreateSieveScriptRequest {
+        activate: false,
+        ..payload
+    };
+    let script = state
+        .manager
+        .update_script(GLOBAL_SCRIPTS_OWNER, &id, &request)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    info!("Admin {}: Updated global Sieve script {}", admin, script.name);
+    Ok(Json(script))
+}
+
+/// DELETE /api/admin/sieve/global/:id - Delete a global script
+pub async fn delete_global_script(
+    State(state): State<Arc<SieveState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    let admin = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    state
+        .manager
+        .delete_script(GLOBAL_SCRIPTS_OWNER, &id)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    info!("Admin {}: Deleted global Sieve script {}", admin, id);
+    Ok(StatusCode::NO_CONTENT)
+}
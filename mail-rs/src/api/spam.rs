@@ -3,18 +3,27 @@
 //! REST API for spam configuration, rules, and Bayesian learning.
 
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
     Json,
 };
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::sync::Arc;
+use tracing::info;
 
-use crate::spam::{SpamAction, SpamConfig, SpamManager, SpamResult, SpamRule, SpamRuleType, SpamStats};
+use crate::api::auth::get_session_email;
+use crate::imap::uid::base_name;
+use crate::imap::Mailbox;
+use crate::residency::ResidencyMap;
+use crate::spam::{SpamAction, SpamClass, SpamConfig, SpamManager, SpamResult, SpamRule, SpamRuleType, SpamStats, TrainOutcome};
 
 /// Spam API state
 pub struct SpamState {
     pub spam_manager: Arc<SpamManager>,
+    /// Mailboxes of the messages trained on
+    pub maildir_root: PathBuf,
+    pub residency: Option<Arc<ResidencyMap>>,
 }
 
 /// API response wrapper
@@ -332,6 +341,68 @@ pub async fn learn_ham(
     }
 }
 
+fn default_mailbox() -> String {
+    "INBOX".to_string()
+}
+
+/// Request to train on a stored message
+#[derive(Debug, Deserialize)]
+pub struct TrainRequest {
+    /// Mailbox holding the message
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    pub class: SpamClass,
+}
+
+/// Result of training on a stored message
+#[derive(Debug, Serialize)]
+pub struct TrainResponse {
+    pub message: String,
+    pub class: SpamClass,
+    pub outcome: TrainOutcome,
+}
+
+/// POST /api/spam/train/:message - Train the classifier on one of the
+/// current user's messages, e.g. `{"class": "spam"}`
+///
+/// `message` is the Maildir base name. Training a message again as the
+/// other class replaces what it was learned as.
+pub async fn train_message(
+    State(state): State<Arc<SpamState>>,
+    headers: HeaderMap,
+    Path(message): Path<String>,
+    Json(req): Json<TrainRequest>,
+) -> Result<Json<ApiResponse<TrainResponse>>, StatusCode> {
+    let email = get_session_email(&headers).ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let root = match &state.residency {
+        Some(residency) => residency.maildir_root(&email, &state.maildir_root),
+        None => state.maildir_root.clone(),
+    };
+    let mailbox = Mailbox::open(&email, &req.mailbox, &root).map_err(|_| StatusCode::NOT_FOUND)?;
+    let msg = mailbox
+        .messages()
+        .iter()
+        .find(|msg| base_name(&msg.filename) == base_name(&message))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let content = match msg.content() {
+        Ok(content) => content,
+        Err(e) => return Ok(Json(ApiResponse::error(&format!("Failed to read message: {}", e)))),
+    };
+
+    match state.spam_manager.train_message(content, req.class).await {
+        Ok(outcome) => {
+            info!("{} trained {} as {:?}: {:?}", email, base_name(&msg.filename), req.class, outcome);
+            Ok(Json(ApiResponse::success(TrainResponse {
+                message: base_name(&msg.filename).to_string(),
+                class: req.class,
+                outcome,
+            })))
+        }
+        Err(e) => Ok(Json(ApiResponse::error(&format!("Failed to train: {}", e)))),
+    }
+}
+
 /// Get spam logs
 pub async fn get_logs(
     State(state): State<Arc<SpamState>>,
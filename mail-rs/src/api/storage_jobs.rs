@@ -0,0 +1,170 @@
+//! API endpoints for background storage maintenance jobs
+//!
+//! Admins queue jobs (recompression, file name migration), follow their
+//! progress and pause, resume or cancel them. Jobs run in the background
+//! of the mail server; see [`crate::storage::jobs`].
+
+use crate::api::auth::get_session_email;
+use crate::storage::jobs::{NewJob, StorageJob, StorageJobManager};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::path::{Component, Path as FsPath};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// App state containing the storage job manager
+pub struct StorageJobsState {
+    pub manager: Arc<StorageJobManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_found() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::NOT_FOUND, "Storage job not found")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("Storage jobs API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access storage jobs",
+    )
+}
+
+/// GET /api/admin/storage/jobs - All jobs with their progress, newest first
+pub async fn list_jobs(
+    State(state): State<Arc<StorageJobsState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<StorageJob>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let jobs = state.manager.list().await.map_err(internal_error)?;
+    Ok(Json(jobs))
+}
+
+/// POST /api/admin/storage/jobs - Queue a job
+pub async fn create_job(
+    State(state): State<Arc<StorageJobsState>>,
+    headers: HeaderMap,
+    Json(payload): Json<NewJob>,
+) -> ApiResult<(StatusCode, Json<StorageJob>)> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    if let Some(root) = &payload.root {
+        let path = FsPath::new(root);
+        if !path.is_absolute()
+            || path.components().any(|c| c == Component::ParentDir)
+            || !path.is_dir()
+        {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "root must be an existing absolute directory",
+            ));
+        }
+    }
+
+    let job = state
+        .manager
+        .create(&payload)
+        .await
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    info!(
+        "Admin {}: Queued storage job {} ({:?})",
+        actor, job.id, job.kind
+    );
+    Ok((StatusCode::CREATED, Json(job)))
+}
+
+/// GET /api/admin/storage/jobs/:id - One job and its progress
+pub async fn get_job(
+    State(state): State<Arc<StorageJobsState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<StorageJob>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let job = state.manager.get(id).await.map_err(internal_error)?;
+    job.map(Json).ok_or_else(not_found)
+}
+
+/// POST /api/admin/storage/jobs/:id/pause - Pause after the next checkpoint
+pub async fn pause_job(
+    State(state): State<Arc<StorageJobsState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<StorageJob>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let changed = state.manager.pause(id).await.map_err(internal_error)?;
+    change_result(&state, &actor, id, changed, "paused").await
+}
+
+/// POST /api/admin/storage/jobs/:id/resume - Continue a paused or failed job
+pub async fn resume_job(
+    State(state): State<Arc<StorageJobsState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<StorageJob>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let changed = state.manager.resume(id).await.map_err(internal_error)?;
+    change_result(&state, &actor, id, changed, "resumed").await
+}
+
+/// POST /api/admin/storage/jobs/:id/cancel - Give up on a job
+pub async fn cancel_job(
+    State(state): State<Arc<StorageJobsState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<StorageJob>> {
+    let actor = get_session_email(&headers).ok_or_else(unauthorized)?;
+    let changed = state.manager.cancel(id).await.map_err(internal_error)?;
+    change_result(&state, &actor, id, changed, "cancelled").await
+}
+
+/// The job after a status change, 404 if it doesn't exist or 409 if its
+/// status did not allow the change
+async fn change_result(
+    state: &StorageJobsState,
+    actor: &str,
+    id: i64,
+    changed: bool,
+    action: &str,
+) -> ApiResult<Json<StorageJob>> {
+    let job = state
+        .manager
+        .get(id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    if !changed {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            &format!("Job is {}", job.status.as_str()),
+        ));
+    }
+
+    info!("Admin {}: Storage job {} {}", actor, id, action);
+    Ok(Json(job))
+}
@@ -0,0 +1,84 @@
+//! API endpoints for pending TLS reports (RFC 8460)
+
+use crate::api::auth::get_session_email;
+use crate::tlsrpt::{PendingReport, PolicyResult, TlsRptManager};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{Days, NaiveDate, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::error;
+
+/// App state containing TLS-RPT manager
+pub struct TlsReportsState {
+    pub manager: Arc<TlsRptManager>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    error!("TLS reports API error: {}", e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access TLS reports",
+    )
+}
+
+/// GET /api/admin/tls-reports - Domains and days not reported yet,
+/// including today
+pub async fn list_pending(
+    State(state): State<Arc<TlsReportsState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<PendingReport>>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let tomorrow = Utc::now().date_naive() + Days::new(1);
+    let pending = state
+        .manager
+        .pending_reports(tomorrow)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(pending))
+}
+
+/// GET /api/admin/tls-reports/:domain/:day - Session summary and failure
+/// details for one domain and day (YYYY-MM-DD)
+pub async fn get_policy_result(
+    State(state): State<Arc<TlsReportsState>>,
+    headers: HeaderMap,
+    Path((domain, day)): Path<(String, String)>,
+) -> ApiResult<Json<PolicyResult>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let day: NaiveDate = day
+        .parse()
+        .map_err(|_| api_error(StatusCode::BAD_REQUEST, "Day must be YYYY-MM-DD"))?;
+    let result = state
+        .manager
+        .policy_result(&domain, day)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(result))
+}
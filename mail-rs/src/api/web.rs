@@ -9,6 +9,7 @@ use serde::Deserialize;
 use std::sync::Arc;
 
 use crate::api::auth::get_session_email;
+use crate::branding::{Branding, BrandingManager};
 use crate::security::Authenticator;
 
 // Session cookie names
@@ -19,11 +20,13 @@ const CHAT_SESSION_COOKIE: &str = "chat_session";
 #[template(path = "login.html")]
 struct LoginTemplate {
     error: String,
+    brand: Branding,
 }
 
 #[derive(Template)]
 #[template(path = "dashboard.html")]
 struct DashboardTemplate {
+    brand: Branding,
     email: String,
     total_users: i64,
     version: String,
@@ -59,16 +62,46 @@ pub struct CreateUserForm {
 
 pub struct AppState {
     pub authenticator: Authenticator,
+    pub branding: Arc<BrandingManager>,
+}
+
+impl AppState {
+    /// Branding of `domain`; the defaults if it can't be looked up
+    async fn brand(&self, domain: &str) -> Branding {
+        self.branding.resolve(domain).await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to look up branding of {}: {}", domain, e);
+            Branding::default_for(domain)
+        })
+    }
+}
+
+/// Domain the page was requested on, from the Host header without the port
+fn request_domain(headers: &axum::http::HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+        _ => host.to_string(),
+    }
 }
 
 // Login page (GET)
-pub async fn login_page() -> impl IntoResponse {
-    LoginTemplate { error: String::new() }
+pub async fn login_page(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+) -> impl IntoResponse {
+    LoginTemplate {
+        error: String::new(),
+        brand: state.brand(&request_domain(&headers)).await,
+    }
 }
 
 // Login form submission (POST)
 pub async fn login_submit(
     State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
     Form(form): Form<LoginForm>,
 ) -> Response {
     match state.authenticator.authenticate(&form.email, &form.password).await {
@@ -84,6 +117,7 @@ pub async fn login_submit(
         _ => {
             LoginTemplate {
                 error: "Invalid email or password".to_string(),
+                brand: state.brand(&request_domain(&headers)).await,
             }.into_response()
         }
     }
@@ -111,8 +145,10 @@ pub async fn dashboard(
 
     // Get user count
     let total_users = state.authenticator.count_users().await.unwrap_or(0);
+    let domain = email.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
 
     DashboardTemplate {
+        brand: state.brand(domain).await,
         email,
         total_users,
         version: env!("CARGO_PKG_VERSION").to_string(),
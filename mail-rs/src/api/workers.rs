@@ -0,0 +1,59 @@
+//! API endpoint for worker pool metrics
+//!
+//! Reports, per subsystem, the concurrency of its pool, the tasks running
+//! and waiting for a slot and their wait and run times, plus the use of the
+//! global task budget; see [`crate::workers`].
+
+use crate::api::auth::get_session_email;
+use crate::workers::{WorkerPools, WorkerStats};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// App state containing the pools shared with the other listeners
+pub struct WorkersState {
+    pub workers: Option<Arc<WorkerPools>>,
+}
+
+/// Response with error details
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<ApiError>) {
+    api_error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn unavailable() -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Worker pools are not shared with the API",
+    )
+}
+
+/// GET /api/admin/workers - Load and latency of each worker pool
+pub async fn stats(
+    State(state): State<Arc<WorkersState>>,
+    headers: HeaderMap,
+) -> ApiResult<Json<WorkerStats>> {
+    get_session_email(&headers).ok_or_else(unauthorized)?;
+
+    let workers = state.workers.as_ref().ok_or_else(unavailable)?;
+    Ok(Json(workers.stats()))
+}
@@ -0,0 +1,225 @@
+//! Short-TTL caches for authentication verdicts
+//!
+//! Repeated mail from the same sender triggers identical DNS lookups and
+//! crypto checks. These caches keep recent results for a bounded time:
+//! - SPF verdicts keyed by (client IP, MAIL FROM domain)
+//! - DKIM public keys keyed by (selector, domain)
+//! - DNSBL and URIBL answers
+//!
+//! Entries expire after their TTL and are never served stale. Hit/miss
+//! counters are kept for observability.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// Default TTL for cached authentication verdicts (5 minutes)
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Default maximum number of cached entries
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Cache hit/miss statistics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub hit_rate: f64,
+}
+
+struct CacheEntry<V> {
+    value: V,
+    expires_at: Instant,
+}
+
+/// Thread-safe cache with per-entry expiry
+pub struct TtlCache<K, V> {
+    entries: RwLock<HashMap<K, CacheEntry<V>>>,
+    ttl: Duration,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K, V> TtlCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Create a cache with the given TTL and capacity
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            ttl,
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up a live entry, counting a hit or miss
+    pub fn get(&self, key: &K) -> Option<V> {
+        let now = Instant::now();
+        let value = self
+            .entries
+            .read()
+            .ok()
+            .and_then(|entries| {
+                entries
+                    .get(key)
+                    .filter(|entry| entry.expires_at > now)
+                    .map(|entry| entry.value.clone())
+            });
+
+        match value {
+            Some(value) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                // Drop the expired entry if there was one
+                if let Ok(mut entries) = self.entries.write() {
+                    if entries.get(key).is_some_and(|entry| entry.expires_at <= now) {
+                        entries.remove(key);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Insert a value with the cache's default TTL
+    pub fn insert(&self, key: K, value: V) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        if let Ok(mut entries) = self.entries.write() {
+            if entries.len() >= self.capacity && !entries.contains_key(&key) {
+                let now = Instant::now();
+                entries.retain(|_, entry| entry.expires_at > now);
+
+                // Still full: evict the entry closest to expiry
+                if entries.len() >= self.capacity {
+                    if let Some(oldest) = entries
+                        .iter()
+                        .min_by_key(|(_, entry)| entry.expires_at)
+                        .map(|(k, _)| k.clone())
+                    {
+                        entries.remove(&oldest);
+                    }
+                }
+            }
+
+            entries.insert(
+                key,
+                CacheEntry {
+                    value,
+                    expires_at: Instant::now() + self.ttl,
+                },
+            );
+        }
+    }
+
+    /// Drop the entry of `key`, e.g. after the value it caches changed
+    pub fn remove(&self, key: &K) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.remove(key);
+        }
+    }
+
+    /// Remove all expired entries, returning how many were dropped
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        match self.entries.write() {
+            Ok(mut entries) => {
+                let before = entries.len();
+                entries.retain(|_, entry| entry.expires_at > now);
+                before - entries.len()
+            }
+            Err(_) => 0,
+        }
+    }
+
+    /// Current hit/miss statistics
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let total = hits + misses;
+
+        CacheStats {
+            hits,
+            misses,
+            entries: self.entries.read().map(|e| e.len()).unwrap_or(0),
+            hit_rate: if total > 0 {
+                hits as f64 / total as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_and_miss_counting() {
+        let cache: TtlCache<String, u32> = TtlCache::new(Duration::from_secs(60), 10);
+
+        assert!(cache.get(&"a".to_string()).is_none());
+        cache.insert("a".to_string(), 1);
+        assert_eq!(cache.get(&"a".to_string()), Some(1));
+        assert_eq!(cache.get(&"a".to_string()), Some(1));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.entries, 1);
+        assert!((stats.hit_rate - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_expired_entries_not_served() {
+        let cache: TtlCache<u32, u32> = TtlCache::new(Duration::from_millis(10), 10);
+        cache.insert(1, 1);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(cache.get(&1).is_none());
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let cache: TtlCache<u32, u32> = TtlCache::new(Duration::from_millis(10), 10);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(cache.purge_expired(), 2);
+    }
+
+    #[test]
+    fn test_capacity_eviction() {
+        let cache: TtlCache<u32, u32> = TtlCache::new(Duration::from_secs(60), 2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.get(&3), Some(3));
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_cache() {
+        let cache: TtlCache<u32, u32> = TtlCache::new(Duration::ZERO, 10);
+        cache.insert(1, 1);
+        assert!(cache.get(&1).is_none());
+    }
+}
@@ -1,15 +1,46 @@
+use super::cache::{CacheStats, TtlCache, DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL};
 use super::types::{AuthenticationStatus, DkimAuthResult};
+use crate::dkim_keys::crypto::dns_value;
+use crate::dkim_keys::DkimAlgorithm;
 use anyhow::{anyhow, Result};
-use mail_auth::common::crypto::{RsaKey, Sha256};
-use mail_auth::common::headers::HeaderWriter;
-use mail_auth::common::verify::VerifySignature;
-use mail_auth::{AuthenticatedMessage, DkimResult as MailAuthDkimResult, Resolver};
-use mail_auth::dkim::DkimSigner as MailAuthDkimSigner;
+use mail_auth::common::crypto::{HashAlgorithm, RsaKey, Sha256};
+use mail_auth::common::headers::{Header, HeaderWriter};
+use mail_auth::common::verify::{DomainKey, VerifySignature};
+use mail_auth::dkim::{DkimSigner as MailAuthDkimSigner, Signature};
+use mail_auth::{AuthenticatedMessage, DkimResult as MailAuthDkimResult, Error as MailAuthError, Resolver};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePublicKey};
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use std::fs;
+use std::future::Future;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{debug, info, warn};
 
+/// `t=s` flag of a key record: the i= domain must equal the d= domain
+const KEY_FLAG_MATCH_DOMAIN: u64 = 0x20;
+
+/// Cache key for DKIM public keys: (selector, domain)
+type KeyCacheKey = (String, String);
+
+/// DNS queries of DKIM verification
+pub trait DkimDns: Send + Sync {
+    /// Public key record published at `{selector}._domainkey.{domain}`
+    fn domain_key(
+        &self,
+        selector: &str,
+        domain: &str,
+    ) -> impl Future<Output = mail_auth::Result<Arc<DomainKey>>> + Send;
+}
+
+impl DkimDns for Resolver {
+    async fn domain_key(&self, selector: &str, domain: &str) -> mail_auth::Result<Arc<DomainKey>> {
+        self.txt_lookup::<DomainKey>(format!("{}._domainkey.{}.", selector, domain))
+            .await
+    }
+}
+
 /// DKIM signer for outgoing emails
 pub struct DkimSigner {
     domain: String,
@@ -18,8 +49,14 @@ pub struct DkimSigner {
 }
 
 /// DKIM validator for incoming emails
-pub struct DkimValidator {
-    resolver: Arc<Resolver>,
+///
+/// Public keys are cached per (selector, domain) and signatures are
+/// checked against the cached key, so repeated mail from a sender costs no
+/// DNS lookup. Third-party signature authorization (ATPS) is not
+/// evaluated.
+pub struct DkimValidator<D: DkimDns = Resolver> {
+    resolver: Arc<D>,
+    key_cache: TtlCache<KeyCacheKey, Arc<DomainKey>>,
 }
 
 /// DKIM validation result
@@ -98,28 +135,121 @@ impl DkimSigner {
     /// Returns the value that should be published in DNS at:
     /// `{selector}._domainkey.{domain}`
     pub fn get_public_key_dns_record(&self) -> Result<String> {
-        // For now, this is a placeholder. In a real implementation,
-        // you would extract the public key from the private key
-        // and format it for DNS TXT record.
-        //
-        // The format is: v=DKIM1; k=rsa; p=<base64_public_key>
-        Ok(format!(
-            "v=DKIM1; k=rsa; p=<public_key_here>; n=DKIM key for {}",
-            self.domain
-        ))
+        let pem = std::str::from_utf8(&self.private_key)?;
+        let private_key = RsaPrivateKey::from_pkcs8_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+            .map_err(|e| anyhow!("Failed to load RSA key: {}", e))?;
+        let public_key = RsaPublicKey::from(&private_key).to_public_key_der()?;
+        Ok(dns_value(DkimAlgorithm::Rsa, public_key.as_bytes()))
     }
 }
 
 impl DkimValidator {
     /// Create a new DKIM validator
     pub fn new() -> Self {
+        Self::with_cache_ttl(DEFAULT_CACHE_TTL)
+    }
+
+    /// Create a new DKIM validator with a custom public key cache TTL
+    ///
+    /// A zero TTL disables key caching.
+    pub fn with_cache_ttl(ttl: Duration) -> Self {
         let resolver = Resolver::new_system_conf().unwrap_or_else(|_| {
             warn!("Failed to load system DNS config, using default resolver");
             Resolver::new_cloudflare_tls().expect("Failed to create DNS resolver")
         });
 
+        Self::with_resolver(resolver, ttl)
+    }
+}
+
+impl<D: DkimDns> DkimValidator<D> {
+    /// Create a DKIM validator fetching keys from `resolver`
+    pub fn with_resolver(resolver: D, ttl: Duration) -> Self {
         Self {
             resolver: Arc::new(resolver),
+            key_cache: TtlCache::new(ttl, DEFAULT_CACHE_CAPACITY),
+        }
+    }
+
+    /// DKIM public key cache statistics
+    pub fn cache_stats(&self) -> CacheStats {
+        self.key_cache.stats()
+    }
+
+    /// Public key of (selector, domain), from the cache when still fresh
+    async fn domain_key(&self, selector: &str, domain: &str) -> mail_auth::Result<Arc<DomainKey>> {
+        let cache_key = (selector.to_lowercase(), domain.to_lowercase());
+        if let Some(key) = self.key_cache.get(&cache_key) {
+            debug!("DKIM key cache hit for {}._domainkey.{}", selector, domain);
+            return Ok(key);
+        }
+
+        let key = self.resolver.domain_key(selector, domain).await?;
+        self.key_cache.insert(cache_key, key.clone());
+        Ok(key)
+    }
+
+    /// Verify one DKIM-Signature header of `message`
+    async fn verify_signature<'x>(
+        &self,
+        message: &'x AuthenticatedMessage<'x>,
+        header: &'x Header<'x, mail_auth::Result<Signature>>,
+        now: u64,
+    ) -> (MailAuthDkimResult, Option<&'x Signature>) {
+        let signature = match &header.header {
+            Ok(signature) => signature,
+            Err(err) => return (MailAuthDkimResult::Neutral(err.clone()), None),
+        };
+        if signature.x != 0 && (signature.x <= signature.t || signature.x <= now) {
+            return (
+                MailAuthDkimResult::Neutral(MailAuthError::SignatureExpired),
+                Some(signature),
+            );
+        }
+
+        // Body hashes are computed while the message is parsed
+        let hash_algorithm = HashAlgorithm::from(signature.a);
+        let body_hash = message
+            .body_hashes
+            .iter()
+            .find(|(c, h, l, _)| *c == signature.cb && *h == hash_algorithm && *l == signature.l)
+            .map(|(_, _, _, hash)| hash);
+        if body_hash != Some(&signature.bh) {
+            return (
+                MailAuthDkimResult::Neutral(MailAuthError::FailedBodyHashMatch),
+                Some(signature),
+            );
+        }
+
+        let key = match self.domain_key(&signature.s, &signature.d).await {
+            Ok(key) => key,
+            Err(err @ MailAuthError::DnsError(_)) => {
+                return (MailAuthDkimResult::TempError(err), Some(signature))
+            }
+            Err(err) => return (MailAuthDkimResult::PermError(err), Some(signature)),
+        };
+
+        if key.has_flag(KEY_FLAG_MATCH_DOMAIN)
+            && signature
+                .i
+                .rsplit_once('@')
+                .is_some_and(|(_, auid)| !auid.eq_ignore_ascii_case(&signature.d))
+        {
+            return (
+                MailAuthDkimResult::Fail(MailAuthError::FailedAuidMatch),
+                Some(signature),
+            );
+        }
+
+        let unsigned = strip_signature(header.value);
+        let mut headers = message.signed_headers(&signature.h, header.name, &unsigned);
+        match key
+            .p
+            .verify(&mut headers, &signature.b, signature.ch, signature.a)
+        {
+            Ok(()) => (MailAuthDkimResult::Pass, Some(signature)),
+            Err(err) => (MailAuthDkimResult::Fail(err), Some(signature)),
         }
     }
 
@@ -148,7 +278,14 @@ impl DkimValidator {
         };
 
         // Verify DKIM signatures
-        let dkim_results = self.resolver.verify_dkim(&parsed_message).await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut dkim_results = Vec::with_capacity(parsed_message.dkim_headers.len());
+        for header in &parsed_message.dkim_headers {
+            dkim_results.push(self.verify_signature(&parsed_message, header, now).await);
+        }
 
         debug!("DKIM verification returned {} results", dkim_results.len());
 
@@ -165,8 +302,7 @@ impl DkimValidator {
 
         // Get the first result (emails can have multiple signatures)
         // In a real implementation, you'd want to check if ANY signature passes
-        let first_result = &dkim_results[0];
-        let dkim_result = first_result.result();
+        let (dkim_result, first_signature) = &dkim_results[0];
 
         debug!("DKIM verification result: {:?}", dkim_result);
 
@@ -217,11 +353,11 @@ impl DkimValidator {
         };
 
         // Extract domain from the signature (if available)
-        let domain = first_result.signature()
+        let domain = first_signature
             .map(|sig| sig.domain().to_string())
             .unwrap_or_else(|| self.extract_domain_from_message(message));
 
-        let selector = first_result.signature()
+        let selector = first_signature
             .map(|sig| sig.selector().to_string())
             .unwrap_or_else(|| "unknown".to_string());
 
@@ -281,6 +417,45 @@ impl Default for DkimValidator {
     }
 }
 
+/// DKIM-Signature header value with the b= tag emptied, as it was signed
+fn strip_signature(value: &[u8]) -> Vec<u8> {
+    let mut unsigned = Vec::with_capacity(value.len());
+    let mut iter = value.iter().enumerate();
+    let mut last = b';';
+    while let Some((position, &byte)) = iter.next() {
+        match byte {
+            b'=' if last == b'b' => {
+                unsigned.push(byte);
+                for (_, &byte) in iter.by_ref() {
+                    if byte == b';' {
+                        unsigned.push(b';');
+                        break;
+                    }
+                }
+                last = 0;
+            }
+            b'b' | b'B' if last == b';' => {
+                last = b'b';
+                unsigned.push(byte);
+            }
+            b';' => {
+                last = b';';
+                unsigned.push(byte);
+            }
+            // The header value ends with its line break
+            b'\r' if position + 2 == value.len() => {}
+            b'\n' if position + 1 == value.len() => {}
+            _ => {
+                unsigned.push(byte);
+                if !byte.is_ascii_whitespace() {
+                    last = 0;
+                }
+            }
+        }
+    }
+    unsigned
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -564,20 +739,30 @@ mod tests {
 
     #[test]
     fn test_dkim_signer_get_public_key_dns_record() {
+        let signer = DkimSigner::new(
+            "example.com".to_string(),
+            "selector1".to_string(),
+            Path::new("test_data/dkim/dkim_private.pem"),
+        )
+        .unwrap();
+
+        let dns_record = signer.get_public_key_dns_record().unwrap();
+        let public_pem = fs::read_to_string("test_data/dkim/dkim_public.pem").unwrap();
+        let public_key: String = public_pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        assert_eq!(dns_record, format!("v=DKIM1; k=rsa; p={}", public_key));
+
         let mut temp_file = NamedTempFile::new().unwrap();
         temp_file.write_all(b"dummy key for test").unwrap();
-
         let signer = DkimSigner::new(
             "example.com".to_string(),
             "selector1".to_string(),
             temp_file.path(),
         )
         .unwrap();
-
-        let dns_record = signer.get_public_key_dns_record().unwrap();
-        assert!(dns_record.contains("v=DKIM1"));
-        assert!(dns_record.contains("k=rsa"));
-        assert!(dns_record.contains("example.com"));
+        assert!(signer.get_public_key_dns_record().is_err());
     }
 
     #[test]
@@ -613,6 +798,79 @@ mod tests {
         );
     }
 
+    /// Serves one key record and counts the lookups
+    struct CountingDns {
+        record: String,
+        lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DkimDns for CountingDns {
+        async fn domain_key(
+            &self,
+            _selector: &str,
+            _domain: &str,
+        ) -> mail_auth::Result<Arc<DomainKey>> {
+            use mail_auth::common::parse::TxtRecordParser;
+            self.lookups
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            DomainKey::parse(self.record.as_bytes()).map(Arc::new)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_verification_uses_cached_key() {
+        // The signer reads PKCS#1 keys
+        use rsa::pkcs1::EncodeRsaPrivateKey;
+        let pem = fs::read_to_string("test_data/dkim/dkim_private.pem").unwrap();
+        let pkcs1 = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .unwrap()
+            .to_pkcs1_pem(rsa::pkcs8::LineEnding::LF)
+            .unwrap();
+        let mut key_file = NamedTempFile::new().unwrap();
+        key_file.write_all(pkcs1.as_bytes()).unwrap();
+        let signer = DkimSigner::new(
+            "example.com".to_string(),
+            "selector1".to_string(),
+            key_file.path(),
+        )
+        .unwrap();
+        let message = b"From: alice@example.com\r\n\
+                        To: bob@example.org\r\n\
+                        Subject: Cached\r\n\
+                        \r\n\
+                        Hello\r\n";
+        // The signature comes as a whole header line
+        let signed = [signer.sign(message).unwrap().as_bytes(), &message[..]].concat();
+
+        let dns = CountingDns {
+            record: signer.get_public_key_dns_record().unwrap(),
+            lookups: Default::default(),
+        };
+        let validator = DkimValidator::with_resolver(dns, Duration::from_secs(60));
+
+        for _ in 0..2 {
+            let result = validator.validate(&signed).await.unwrap();
+            assert_eq!(result.status, AuthenticationStatus::Pass, "{:?}", result.reason);
+            assert_eq!(result.selector, "selector1");
+        }
+        assert_eq!(
+            validator
+                .resolver
+                .lookups
+                .load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+        let stats = validator.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // A tampered body fails against the cached key
+        let mut tampered = signed.clone();
+        let last = tampered.len() - 3;
+        tampered[last] = b'!';
+        let result = validator.validate(&tampered).await.unwrap();
+        assert_ne!(result.status, AuthenticationStatus::Pass);
+    }
+
     #[test]
     fn test_dkim_signer_domain_and_selector() {
         let mut temp_file = NamedTempFile::new().unwrap();
@@ -291,6 +291,7 @@ mod tests {
             client_ip: "192.0.2.1".to_string(),
             envelope_from: "sender@example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         assert!(validator.check_spf_alignment("example.com", &spf_result));
@@ -306,6 +307,7 @@ mod tests {
             client_ip: "192.0.2.1".to_string(),
             envelope_from: "sender@mail.example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         // Relaxed alignment allows subdomain
@@ -411,6 +413,7 @@ mod tests {
             client_ip: "192.0.2.1".to_string(),
             envelope_from: "sender@example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         let dkim_result = DkimAuthResult {
@@ -439,6 +442,7 @@ mod tests {
             client_ip: "192.0.2.1".to_string(),
             envelope_from: "sender@other.com".to_string(),
             reason: Some("SPF failed".to_string()),
+            mechanism: None,
         };
 
         let dkim_result = DkimAuthResult {
@@ -467,6 +471,7 @@ mod tests {
             client_ip: "192.0.2.1".to_string(),
             envelope_from: "sender@other.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         let dkim_result = DkimAuthResult {
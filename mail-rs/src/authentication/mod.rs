@@ -3,11 +3,14 @@
 /// This module provides email authentication mechanisms to verify
 /// sender identity and prevent spam/spoofing.
 
+pub mod cache;
 pub mod spf;
+pub mod spf_check;
 pub mod dkim;
 pub mod dmarc;
 pub mod types;
 
+pub use cache::{CacheStats, TtlCache};
 pub use spf::{SpfValidator, SpfResult};
 pub use dkim::{DkimSigner, DkimValidator, DkimResult};
 pub use dmarc::{DmarcValidator, DmarcResult, DmarcPolicy};
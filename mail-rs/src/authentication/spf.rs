@@ -1,13 +1,28 @@
+use super::cache::{CacheStats, TtlCache, DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL};
+use super::spf_check::{check_host, SpfDns};
 use super::types::{AuthenticationStatus, SpfAuthResult};
 use anyhow::Result;
-use mail_auth::{Resolver, SpfResult as MailAuthSpfResult};
+use mail_auth::SpfResult as MailAuthSpfResult;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{debug, info, warn};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Cache key for SPF verdicts: (client IP, MAIL FROM domain)
+type SpfCacheKey = (IpAddr, String);
+
+/// Cached verdict: status, reason and matched mechanism
+type CachedVerdict = (AuthenticationStatus, Option<String>, Option<String>);
 
 /// SPF validator for incoming emails
-pub struct SpfValidator {
-    resolver: Arc<Resolver>,
+///
+/// Policies are evaluated by [`check_host`]: macros are expanded, at most
+/// 10 terms may query DNS and the mechanism that matched is reported.
+pub struct SpfValidator<D: SpfDns = TokioAsyncResolver> {
+    resolver: Arc<D>,
+    verdict_cache: TtlCache<SpfCacheKey, CachedVerdict>,
 }
 
 /// SPF validation result
@@ -16,16 +31,36 @@ pub type SpfResult = Result<SpfAuthResult>;
 impl SpfValidator {
     /// Create a new SPF validator
     pub fn new() -> Self {
-        let resolver = Resolver::new_system_conf().unwrap_or_else(|_| {
+        Self::with_cache_ttl(DEFAULT_CACHE_TTL)
+    }
+
+    /// Create a new SPF validator with a custom verdict cache TTL
+    ///
+    /// A zero TTL disables verdict caching.
+    pub fn with_cache_ttl(ttl: Duration) -> Self {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|_| {
             warn!("Failed to load system DNS config, using default resolver");
-            Resolver::new_cloudflare_tls().expect("Failed to create DNS resolver")
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
         });
 
+        Self::with_resolver(resolver, ttl)
+    }
+}
+
+impl<D: SpfDns> SpfValidator<D> {
+    /// Create an SPF validator querying `resolver`
+    pub fn with_resolver(resolver: D, ttl: Duration) -> Self {
         Self {
             resolver: Arc::new(resolver),
+            verdict_cache: TtlCache::new(ttl, DEFAULT_CACHE_CAPACITY),
         }
     }
 
+    /// SPF verdict cache statistics
+    pub fn cache_stats(&self) -> CacheStats {
+        self.verdict_cache.stats()
+    }
+
     /// Validate SPF for an incoming email
     ///
     /// # Arguments
@@ -46,20 +81,34 @@ impl SpfValidator {
             envelope_from, client_ip, helo_domain
         );
 
-        // Extract domain from envelope_from (email address)
-        let domain = envelope_from
-            .split('@')
-            .nth(1)
-            .unwrap_or(envelope_from);
-
-        // Perform SPF check using mail-auth Resolver
-        let spf_output = self.resolver
-            .verify_spf_sender(client_ip, helo_domain, domain, envelope_from)
-            .await;
+        // Bounces with an empty MAIL FROM are checked as postmaster of the
+        // HELO domain (RFC 7208 2.4)
+        let sender = if envelope_from.is_empty() {
+            format!("postmaster@{}", helo_domain)
+        } else {
+            envelope_from.to_string()
+        };
+        let domain = sender.rsplit_once('@').map_or(sender.as_str(), |(_, domain)| domain);
+
+        // Serve repeated (ip, domain) checks from the verdict cache
+        let cache_key = (client_ip, domain.to_lowercase());
+        if let Some((status, reason, mechanism)) = self.verdict_cache.get(&cache_key) {
+            debug!("SPF verdict cache hit for {} from {}", domain, client_ip);
+            return Ok(SpfAuthResult {
+                status,
+                client_ip: client_ip.to_string(),
+                envelope_from: envelope_from.to_string(),
+                reason,
+                mechanism,
+            });
+        }
 
-        // Get the SPF result
-        let spf_result = spf_output.result();
-        debug!("SPF result: {:?}", spf_result);
+        let outcome = check_host(&*self.resolver, client_ip, domain, &sender, helo_domain).await;
+        let spf_result = outcome.result;
+        debug!(
+            "SPF result: {:?} (mechanism: {:?}, {} DNS lookups)",
+            spf_result, outcome.mechanism, outcome.lookups
+        );
 
         // Convert mail-auth result to our AuthenticationStatus
         let status = match spf_result {
@@ -84,7 +133,10 @@ impl SpfValidator {
                 AuthenticationStatus::TempError
             }
             MailAuthSpfResult::PermError => {
-                warn!("SPF permanent error for {}", envelope_from);
+                warn!(
+                    "SPF permanent error for {} after {} DNS lookups",
+                    envelope_from, outcome.lookups
+                );
                 AuthenticationStatus::PermError
             }
             MailAuthSpfResult::None => {
@@ -93,11 +145,28 @@ impl SpfValidator {
             }
         };
 
+        // The domain's own explanation of a fail, if it publishes one
+        let reason = Some(
+            outcome
+                .explanation
+                .unwrap_or_else(|| self.get_reason_message(spf_result)),
+        );
+
+        // Temporary errors must be retried, never cached, nor are verdicts
+        // depending on the local part or HELO name
+        if status != AuthenticationStatus::TempError && outcome.cacheable {
+            self.verdict_cache.insert(
+                cache_key,
+                (status.clone(), reason.clone(), outcome.mechanism.clone()),
+            );
+        }
+
         Ok(SpfAuthResult {
             status,
             client_ip: client_ip.to_string(),
             envelope_from: envelope_from.to_string(),
-            reason: Some(self.get_reason_message(spf_result)),
+            reason,
+            mechanism: outcome.mechanism,
         })
     }
 
@@ -120,7 +189,7 @@ impl SpfValidator {
                 "Temporary DNS error during SPF check".to_string()
             }
             MailAuthSpfResult::PermError => {
-                "SPF record has a permanent error".to_string()
+                "SPF record has a permanent error or needs too many DNS lookups".to_string()
             }
             MailAuthSpfResult::None => {
                 "Domain has no SPF record".to_string()
@@ -184,6 +253,32 @@ mod tests {
         assert_ne!(spf_result.status, AuthenticationStatus::None);
     }
 
+    #[tokio::test]
+    async fn test_cached_verdict_served_for_same_ip_and_domain() {
+        let validator = SpfValidator::new();
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+        validator.verdict_cache.insert(
+            (ip, "example.com".to_string()),
+            (
+                AuthenticationStatus::Pass,
+                Some("cached".to_string()),
+                Some("ip4:192.0.2.0/24".to_string()),
+            ),
+        );
+
+        // Different local part, same domain: served from cache without DNS
+        let result = validator
+            .validate(ip, "alice@Example.com", "mail.example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(result.status, AuthenticationStatus::Pass);
+        assert_eq!(result.envelope_from, "alice@Example.com");
+        assert_eq!(result.reason.as_deref(), Some("cached"));
+        assert_eq!(result.mechanism.as_deref(), Some("ip4:192.0.2.0/24"));
+        assert_eq!(validator.cache_stats().hits, 1);
+    }
+
     #[test]
     fn test_should_reject() {
         let validator = SpfValidator::new();
@@ -193,6 +288,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         assert!(validator.should_reject(&fail_result));
@@ -202,6 +298,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         assert!(!validator.should_reject(&pass_result));
@@ -216,6 +313,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         assert!(validator.should_flag_as_spam(&softfail_result));
@@ -225,6 +323,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: None,
+            mechanism: None,
         };
 
         assert!(!validator.should_flag_as_spam(&pass_result));
@@ -239,6 +338,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: Some("Soft fail policy".to_string()),
+            mechanism: None,
         };
 
         // SoftFail should flag as spam but NOT reject
@@ -255,6 +355,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: Some("No assertion".to_string()),
+            mechanism: None,
         };
 
         assert!(!validator.should_reject(&neutral_result));
@@ -270,6 +371,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: Some("DNS timeout".to_string()),
+            mechanism: None,
         };
 
         // Temporary errors should not cause rejection
@@ -285,6 +387,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: Some("No SPF record".to_string()),
+            mechanism: None,
         };
 
         // Missing SPF record should not cause rejection
@@ -332,6 +435,7 @@ mod tests {
             client_ip: "2001:db8::1".to_string(),
             envelope_from: "test@example.com".to_string(),
             reason: Some("IPv6 address authorized".to_string()),
+            mechanism: None,
         };
 
         assert_eq!(result.client_ip, "2001:db8::1");
@@ -347,6 +451,7 @@ mod tests {
             client_ip: "1.2.3.4".to_string(),
             envelope_from: "spammer@evil.com".to_string(),
             reason: Some("IP not authorized".to_string()),
+            mechanism: None,
         };
 
         // Fail should both reject AND flag as spam
@@ -0,0 +1,771 @@
+//! SPF `check_host()` evaluation (RFC 7208)
+//!
+//! Records and macro strings are parsed with mail-auth; the evaluation is
+//! done here so the matching mechanism can be reported and the DNS lookup
+//! limits of section 4.6.4 are enforced exactly.
+
+use futures::future::BoxFuture;
+use mail_auth::common::parse::TxtRecordParser;
+use mail_auth::spf::{Macro, Mechanism, Qualifier, Spf, Variable, Variables};
+use mail_auth::SpfResult;
+use std::future::Future;
+use std::net::IpAddr;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Most terms querying DNS in one check: include, a, mx, ptr, exists and
+/// redirect
+pub const MAX_DNS_LOOKUPS: u32 = 10;
+
+/// Most of those queries answered with no records
+pub const MAX_VOID_LOOKUPS: u32 = 2;
+
+/// Most MX hosts of an `mx` mechanism, and PTR names considered
+const MAX_NAMES: usize = 10;
+
+/// Longest expanded domain; longer ones lose their leftmost labels
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Failed DNS query
+#[derive(Debug)]
+pub enum DnsError {
+    /// NXDOMAIN or no records of the type
+    NotFound,
+    /// Timeout or server failure
+    Temporary(String),
+}
+
+/// DNS queries of SPF evaluation
+pub trait SpfDns: Send + Sync {
+    /// TXT records of `name`, the strings of each joined
+    fn txt(&self, name: &str) -> impl Future<Output = Result<Vec<Vec<u8>>, DnsError>> + Send;
+
+    /// A records of `name`, or its AAAA records with `ipv6`
+    fn ips(
+        &self,
+        name: &str,
+        ipv6: bool,
+    ) -> impl Future<Output = Result<Vec<IpAddr>, DnsError>> + Send;
+
+    /// MX hosts of `name`, most preferred first
+    fn mx(&self, name: &str) -> impl Future<Output = Result<Vec<String>, DnsError>> + Send;
+
+    /// PTR names of `ip`
+    fn ptr(&self, ip: IpAddr) -> impl Future<Output = Result<Vec<String>, DnsError>> + Send;
+}
+
+impl SpfDns for TokioAsyncResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<Vec<u8>>, DnsError> {
+        let lookup = self.txt_lookup(fqdn(name)).await.map_err(dns_error)?;
+        Ok(lookup.iter().map(|txt| txt.txt_data().concat()).collect())
+    }
+
+    async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>, DnsError> {
+        if ipv6 {
+            let lookup = self.ipv6_lookup(fqdn(name)).await.map_err(dns_error)?;
+            Ok(lookup.iter().map(|aaaa| IpAddr::V6(aaaa.0)).collect())
+        } else {
+            let lookup = self.ipv4_lookup(fqdn(name)).await.map_err(dns_error)?;
+            Ok(lookup.iter().map(|a| IpAddr::V4(a.0)).collect())
+        }
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        let lookup = self.mx_lookup(fqdn(name)).await.map_err(dns_error)?;
+        let mut hosts: Vec<_> = lookup
+            .iter()
+            .map(|mx| (mx.preference(), mx.exchange().to_utf8()))
+            .collect();
+        hosts.sort();
+        Ok(hosts.into_iter().map(|(_, host)| host).collect())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, DnsError> {
+        let lookup = self.reverse_lookup(ip).await.map_err(dns_error)?;
+        Ok(lookup.iter().map(|ptr| ptr.0.to_utf8()).collect())
+    }
+}
+
+/// `name` as an absolute name, so search domains are never appended
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+fn dns_error(e: trust_dns_resolver::error::ResolveError) -> DnsError {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => DnsError::NotFound,
+        _ => DnsError::Temporary(e.to_string()),
+    }
+}
+
+/// Verdict of `check_host()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpfOutcome {
+    pub result: SpfResult,
+    /// Mechanism of the sender domain's record that decided the result,
+    /// e.g. `ip4:192.0.2.0/24` or `include:_spf.example.net`
+    pub mechanism: Option<String>,
+    /// Explanation the domain publishes with `exp=` for a fail
+    pub explanation: Option<String>,
+    /// Terms that queried DNS
+    pub lookups: u32,
+    /// Whether the verdict only depends on the client IP and the domain,
+    /// i.e. no macro used the sender's local part or the HELO name
+    pub cacheable: bool,
+}
+
+/// Evaluate the SPF policy of `domain` for mail from `sender` sent by `ip`
+pub async fn check_host<D: SpfDns>(
+    dns: &D,
+    ip: IpAddr,
+    domain: &str,
+    sender: &str,
+    helo: &str,
+) -> SpfOutcome {
+    // Clients on IPv4-mapped addresses are checked as IPv4 clients
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        _ => ip,
+    };
+    let mut vars = Variables::new();
+    vars.set_ip(&ip);
+    vars.set_sender(sender.as_bytes().to_vec());
+    vars.set_helo_domain(helo.as_bytes().to_vec());
+    vars.set_host_domain(b"unknown".to_vec());
+    let mut check = Check {
+        dns,
+        ip,
+        vars,
+        validated: false,
+        lookups: 0,
+        void_lookups: 0,
+        cacheable: true,
+    };
+
+    let evaluation = check
+        .check_host(domain.trim_end_matches('.').to_lowercase(), true)
+        .await;
+    SpfOutcome {
+        result: evaluation.result,
+        mechanism: evaluation.mechanism,
+        explanation: evaluation.explanation,
+        lookups: check.lookups,
+        cacheable: check.cacheable,
+    }
+}
+
+/// Result of evaluating one record
+struct Evaluation {
+    result: SpfResult,
+    mechanism: Option<String>,
+    explanation: Option<String>,
+}
+
+impl From<SpfResult> for Evaluation {
+    fn from(result: SpfResult) -> Self {
+        Self {
+            result,
+            mechanism: None,
+            explanation: None,
+        }
+    }
+}
+
+/// State of one check, shared by the records it includes
+struct Check<'a, D> {
+    dns: &'a D,
+    ip: IpAddr,
+    vars: Variables<'static>,
+    /// Whether `%{p}` was resolved
+    validated: bool,
+    lookups: u32,
+    void_lookups: u32,
+    cacheable: bool,
+}
+
+impl<D: SpfDns> Check<'_, D> {
+    /// Evaluate the record of `domain`; `top` is false for included and
+    /// redirected records, whose explanations are not used
+    fn check_host(&mut self, domain: String, top: bool) -> BoxFuture<'_, Evaluation> {
+        Box::pin(async move {
+            if !valid_domain(&domain) {
+                return SpfResult::None.into();
+            }
+            let record = match self.record(&domain).await {
+                Ok(Some(record)) => record,
+                Ok(None) => return SpfResult::None.into(),
+                Err(result) => return result.into(),
+            };
+
+            for directive in &record.directives {
+                match self.matches(&directive.mechanism, &domain).await {
+                    Ok(Some(mechanism)) => {
+                        let result = match directive.qualifier {
+                            Qualifier::Pass => SpfResult::Pass,
+                            Qualifier::Fail => SpfResult::Fail,
+                            Qualifier::SoftFail => SpfResult::SoftFail,
+                            Qualifier::Neutral => SpfResult::Neutral,
+                        };
+                        let explanation = if top && result == SpfResult::Fail {
+                            self.explain(&record, &domain).await
+                        } else {
+                            None
+                        };
+                        return Evaluation {
+                            result,
+                            mechanism: Some(mechanism),
+                            explanation,
+                        };
+                    }
+                    Ok(None) => {}
+                    Err(result) => return result.into(),
+                }
+            }
+
+            if let Some(redirect) = &record.redirect {
+                if let Err(result) = self.count_lookup() {
+                    return result.into();
+                }
+                let target = self.expand(redirect, &domain).await;
+                let mut evaluation = self.check_host(target, top).await;
+                if evaluation.result == SpfResult::None {
+                    evaluation.result = SpfResult::PermError;
+                }
+                return evaluation;
+            }
+
+            SpfResult::Neutral.into()
+        })
+    }
+
+    /// The SPF record of `domain`, None without one
+    async fn record(&mut self, domain: &str) -> Result<Option<Spf>, SpfResult> {
+        let records = match self.dns.txt(domain).await {
+            Ok(records) => records,
+            Err(DnsError::NotFound) => return Ok(None),
+            Err(DnsError::Temporary(_)) => return Err(SpfResult::TempError),
+        };
+
+        let mut spf = records.into_iter().filter(|record| is_spf_record(record));
+        let Some(mut record) = spf.next() else {
+            return Ok(None);
+        };
+        if spf.next().is_some() {
+            return Err(SpfResult::PermError);
+        }
+        // The version tag is case-insensitive
+        record[..6].make_ascii_lowercase();
+        Spf::parse(&record)
+            .map(Some)
+            .map_err(|_| SpfResult::PermError)
+    }
+
+    /// Text of `mechanism` if it matches the client IP
+    async fn matches(
+        &mut self,
+        mechanism: &Mechanism,
+        domain: &str,
+    ) -> Result<Option<String>, SpfResult> {
+        let ip = self.ip;
+        let matched = match mechanism {
+            Mechanism::All => Some("all".to_string()),
+            Mechanism::Ip4 { addr, mask } => in_network(ip, (*addr).into(), *mask as u128, 32)
+                .then(|| format!("ip4:{}{}", addr, cidr(*mask as u128, 32, "/"))),
+            Mechanism::Ip6 { addr, mask } => in_network(ip, (*addr).into(), *mask, 128)
+                .then(|| format!("ip6:{}{}", addr, cidr(*mask, 128, "/"))),
+            Mechanism::A {
+                macro_string,
+                ip4_mask,
+                ip6_mask,
+            } => {
+                self.count_lookup()?;
+                let target = self.expand(macro_string, domain).await;
+                let ips = self.lookup_ips(&target).await?;
+                ips.iter()
+                    .any(|addr| self.masked_match(*addr, *ip4_mask, *ip6_mask))
+                    .then(|| {
+                        describe("a", macro_string, &target) + &dual_cidr(*ip4_mask, *ip6_mask)
+                    })
+            }
+            Mechanism::Mx {
+                macro_string,
+                ip4_mask,
+                ip6_mask,
+            } => {
+                self.count_lookup()?;
+                let target = self.expand(macro_string, domain).await;
+                let hosts = match self.dns.mx(&target).await {
+                    Ok(hosts) if !hosts.is_empty() => hosts,
+                    Ok(_) | Err(DnsError::NotFound) => {
+                        self.count_void()?;
+                        Vec::new()
+                    }
+                    Err(DnsError::Temporary(_)) => return Err(SpfResult::TempError),
+                };
+                if hosts.len() > MAX_NAMES {
+                    return Err(SpfResult::PermError);
+                }
+                let mut matched = false;
+                for host in &hosts {
+                    let ips = match self.dns.ips(host, ip.is_ipv6()).await {
+                        Ok(ips) => ips,
+                        Err(DnsError::NotFound) => continue,
+                        Err(DnsError::Temporary(_)) => return Err(SpfResult::TempError),
+                    };
+                    if ips
+                        .iter()
+                        .any(|addr| self.masked_match(*addr, *ip4_mask, *ip6_mask))
+                    {
+                        matched = true;
+                        break;
+                    }
+                }
+                matched.then(|| {
+                    describe("mx", macro_string, &target) + &dual_cidr(*ip4_mask, *ip6_mask)
+                })
+            }
+            Mechanism::Ptr { macro_string } => {
+                self.count_lookup()?;
+                let target = self.expand(macro_string, domain).await;
+                let suffix = format!(".{}", target);
+                self.validated_names()
+                    .await?
+                    .iter()
+                    .any(|name| *name == target || name.ends_with(&suffix))
+                    .then(|| describe("ptr", macro_string, &target))
+            }
+            Mechanism::Exists { macro_string } => {
+                self.count_lookup()?;
+                let target = self.expand(macro_string, domain).await;
+                // Always an A query, whatever the client's address family
+                let exists = match self.dns.ips(&target, false).await {
+                    Ok(ips) if !ips.is_empty() => true,
+                    Ok(_) | Err(DnsError::NotFound) => {
+                        self.count_void()?;
+                        false
+                    }
+                    Err(DnsError::Temporary(_)) => return Err(SpfResult::TempError),
+                };
+                exists.then(|| format!("exists:{}", target))
+            }
+            Mechanism::Include { macro_string } => {
+                self.count_lookup()?;
+                let target = self.expand(macro_string, domain).await;
+                match self.check_host(target.clone(), false).await.result {
+                    SpfResult::Pass => Some(format!("include:{}", target)),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => None,
+                    SpfResult::TempError => return Err(SpfResult::TempError),
+                    SpfResult::PermError | SpfResult::None => return Err(SpfResult::PermError),
+                }
+            }
+        };
+        Ok(matched)
+    }
+
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        if self.lookups > MAX_DNS_LOOKUPS {
+            return Err(SpfResult::PermError);
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self) -> Result<(), SpfResult> {
+        self.void_lookups += 1;
+        if self.void_lookups > MAX_VOID_LOOKUPS {
+            return Err(SpfResult::PermError);
+        }
+        Ok(())
+    }
+
+    /// Addresses of `name` in the client's address family
+    async fn lookup_ips(&mut self, name: &str) -> Result<Vec<IpAddr>, SpfResult> {
+        match self.dns.ips(name, self.ip.is_ipv6()).await {
+            Ok(ips) if !ips.is_empty() => Ok(ips),
+            Ok(_) | Err(DnsError::NotFound) => {
+                self.count_void()?;
+                Ok(Vec::new())
+            }
+            Err(DnsError::Temporary(_)) => Err(SpfResult::TempError),
+        }
+    }
+
+    fn masked_match(&self, addr: IpAddr, ip4_mask: u32, ip6_mask: u128) -> bool {
+        match addr {
+            IpAddr::V4(_) => in_network(self.ip, addr, ip4_mask as u128, 32),
+            IpAddr::V6(_) => in_network(self.ip, addr, ip6_mask, 128),
+        }
+    }
+
+    /// PTR names of the client that resolve back to its address
+    async fn validated_names(&mut self) -> Result<Vec<String>, SpfResult> {
+        let names = match self.dns.ptr(self.ip).await {
+            Ok(names) => names,
+            // Errors make the mechanism not match (RFC 7208 5.5)
+            Err(_) => return Ok(Vec::new()),
+        };
+        let mut validated = Vec::new();
+        for name in names.iter().take(MAX_NAMES) {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if let Ok(ips) = self.dns.ips(&name, self.ip.is_ipv6()).await {
+                if ips.contains(&self.ip) {
+                    validated.push(name);
+                }
+            }
+        }
+        Ok(validated)
+    }
+
+    /// Expand `macro_string` in the context of `domain`, which it defaults
+    /// to when empty
+    async fn expand(&mut self, macro_string: &Macro, domain: &str) -> String {
+        if uses(macro_string, Variable::ValidatedDomain) && !self.validated {
+            let names = self.validated_names().await.unwrap_or_default();
+            let suffix = format!(".{}", domain);
+            let name = names
+                .iter()
+                .find(|name| *name == domain || name.ends_with(&suffix))
+                .or(names.first())
+                .map_or("unknown", String::as_str);
+            self.vars.set_validated_domain(name.as_bytes().to_vec());
+            self.validated = true;
+        }
+        if [
+            Variable::Sender,
+            Variable::SenderLocalPart,
+            Variable::HeloDomain,
+        ]
+        .iter()
+        .any(|letter| uses(macro_string, *letter))
+        {
+            self.cacheable = false;
+        }
+
+        self.vars.set_domain(domain.as_bytes().to_vec());
+        let expanded = macro_string.eval(&self.vars, domain, false);
+        truncate_domain(expanded.trim_end_matches('.')).to_lowercase()
+    }
+
+    /// Explanation of a fail published by `record`
+    async fn explain(&mut self, record: &Spf, domain: &str) -> Option<String> {
+        let target = self.expand(record.exp.as_ref()?, domain).await;
+        let records = self.dns.txt(&target).await.ok()?;
+        let [text] = records.as_slice() else {
+            return None;
+        };
+        let text = Macro::parse(text).ok()?;
+        self.vars.set_domain(domain.as_bytes().to_vec());
+        let explanation = text.eval(&self.vars, "", false).into_owned();
+        Some(explanation).filter(|explanation| !explanation.is_empty())
+    }
+}
+
+/// Whether a TXT record is an SPF version 1 record
+fn is_spf_record(record: &[u8]) -> bool {
+    record.len() >= 6
+        && record[..6].eq_ignore_ascii_case(b"v=spf1")
+        && record.get(6).is_none_or(|c| *c == b' ')
+}
+
+/// Whether `domain` can be looked up: labels of at most 63 characters and
+/// more than one of them
+fn valid_domain(domain: &str) -> bool {
+    domain.len() <= MAX_DOMAIN_LEN
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+/// `domain` without the leftmost labels that make it too long
+fn truncate_domain(mut domain: &str) -> &str {
+    while domain.len() > MAX_DOMAIN_LEN {
+        match domain.split_once('.') {
+            Some((_, rest)) => domain = rest,
+            None => break,
+        }
+    }
+    domain
+}
+
+/// Whether `macro_string` refers to `letter`
+fn uses(macro_string: &Macro, letter: Variable) -> bool {
+    match macro_string {
+        Macro::Variable { letter: used, .. } => *used == letter,
+        Macro::List(list) => list.iter().any(|item| uses(item, letter)),
+        Macro::Literal(_) | Macro::None => false,
+    }
+}
+
+/// Whether `ip` is in the network of `addr` with `mask`, `bits` being the
+/// width of the address family
+fn in_network(ip: IpAddr, addr: IpAddr, mask: u128, bits: u32) -> bool {
+    match (ip, addr) {
+        (IpAddr::V4(ip), IpAddr::V4(addr)) if bits == 32 => {
+            let mask = mask as u32;
+            u32::from(ip) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(addr)) if bits == 128 => {
+            u128::from(ip) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+/// `/len` suffix of a mask, empty for a full-length one
+fn cidr(mask: u128, bits: u32, separator: &str) -> String {
+    let len = mask.count_ones();
+    if len == bits {
+        String::new()
+    } else {
+        format!("{}{}", separator, len)
+    }
+}
+
+/// Suffix of the IPv4 and IPv6 prefix lengths of `a` and `mx`
+fn dual_cidr(ip4_mask: u32, ip6_mask: u128) -> String {
+    cidr(ip4_mask as u128, 32, "/") + &cidr(ip6_mask, 128, "//")
+}
+
+/// `name` with the domain it was given, if any
+fn describe(name: &str, macro_string: &Macro, target: &str) -> String {
+    match macro_string {
+        Macro::None => name.to_string(),
+        _ => format!("{}:{}", name, target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    /// DNS answering from fixed records
+    #[derive(Default)]
+    struct MockDns {
+        txt: HashMap<String, Vec<&'static str>>,
+        ips: HashMap<String, Vec<IpAddr>>,
+        mx: HashMap<String, Vec<String>>,
+        ptr: HashMap<IpAddr, Vec<String>>,
+        failing: Vec<String>,
+    }
+
+    impl MockDns {
+        fn txt(mut self, name: &str, record: &'static str) -> Self {
+            self.txt.entry(name.to_string()).or_default().push(record);
+            self
+        }
+
+        fn ip(mut self, name: &str, ip: &str) -> Self {
+            self.ips
+                .entry(name.to_string())
+                .or_default()
+                .push(ip.parse().unwrap());
+            self
+        }
+
+        fn answer<T: Clone>(
+            &self,
+            name: &str,
+            map: &HashMap<String, Vec<T>>,
+        ) -> Result<Vec<T>, DnsError> {
+            if self.failing.iter().any(|failing| failing == name) {
+                return Err(DnsError::Temporary("timeout".to_string()));
+            }
+            map.get(name).cloned().ok_or(DnsError::NotFound)
+        }
+    }
+
+    impl SpfDns for MockDns {
+        async fn txt(&self, name: &str) -> Result<Vec<Vec<u8>>, DnsError> {
+            let records = self.answer(name, &self.txt)?;
+            Ok(records.iter().map(|r| r.as_bytes().to_vec()).collect())
+        }
+
+        async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>, DnsError> {
+            let ips = self.answer(name, &self.ips)?;
+            Ok(ips.into_iter().filter(|ip| ip.is_ipv6() == ipv6).collect())
+        }
+
+        async fn mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+            self.answer(name, &self.mx)
+        }
+
+        async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, DnsError> {
+            self.ptr.get(&ip).cloned().ok_or(DnsError::NotFound)
+        }
+    }
+
+    async fn check(dns: &MockDns, ip: &str, sender: &str) -> SpfOutcome {
+        let domain = sender.rsplit_once('@').unwrap().1;
+        check_host(
+            dns,
+            IpAddr::from_str(ip).unwrap(),
+            domain,
+            sender,
+            "mail.example.org",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_ip_and_include_mechanisms() {
+        let dns = MockDns::default()
+            .txt(
+                "example.com",
+                "v=spf1 ip4:192.0.2.0/24 include:_spf.example.net -all",
+            )
+            .txt("example.com", "google-site-verification=abc")
+            .txt(
+                "_spf.example.net",
+                "v=spf1 ip6:2001:db8::/32 a:mail.example.net ~all",
+            )
+            .ip("mail.example.net", "198.51.100.7");
+
+        let outcome = check(&dns, "192.0.2.10", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::Pass);
+        assert_eq!(outcome.mechanism.as_deref(), Some("ip4:192.0.2.0/24"));
+        assert_eq!(outcome.lookups, 0);
+
+        let outcome = check(&dns, "198.51.100.7", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::Pass);
+        assert_eq!(
+            outcome.mechanism.as_deref(),
+            Some("include:_spf.example.net")
+        );
+        assert_eq!(outcome.lookups, 2);
+
+        let outcome = check(&dns, "2001:db8::1", "alice@example.com").await;
+        assert_eq!(
+            outcome.mechanism.as_deref(),
+            Some("include:_spf.example.net")
+        );
+
+        // The include's ~all doesn't match, the outer -all does
+        let outcome = check(&dns, "203.0.113.1", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::Fail);
+        assert_eq!(outcome.mechanism.as_deref(), Some("all"));
+        assert!(outcome.cacheable);
+    }
+
+    #[tokio::test]
+    async fn test_macros() {
+        let dns = MockDns::default()
+            .txt(
+                "example.com",
+                "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} a:%{h} -all exp=explain.%{d}",
+            )
+            .txt(
+                "explain.example.com",
+                "%{i} is not one of %{d}'s servers for %{s}",
+            )
+            .ip("10.2.0.192.alice._spf.example.com", "127.0.0.2")
+            .ip("mail.example.org", "198.51.100.25");
+
+        let outcome = check(&dns, "192.0.2.10", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::Pass);
+        assert_eq!(
+            outcome.mechanism.as_deref(),
+            Some("exists:10.2.0.192.alice._spf.example.com")
+        );
+        // Depends on the local part
+        assert!(!outcome.cacheable);
+
+        let outcome = check(&dns, "198.51.100.25", "bob-list@example.com").await;
+        assert_eq!(outcome.mechanism.as_deref(), Some("a:mail.example.org"));
+
+        let outcome = check(&dns, "203.0.113.9", "carol@example.com").await;
+        assert_eq!(outcome.result, SpfResult::Fail);
+        assert_eq!(
+            outcome.explanation.as_deref(),
+            Some("203.0.113.9 is not one of example.com's servers for carol@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_limit() {
+        let mut dns = MockDns::default().txt(
+            "example.com",
+            "v=spf1 include:a.example.com include:b.example.com -all",
+        );
+        // 2 + 2 * 5 terms querying DNS
+        for name in ["a", "b"] {
+            dns = dns.txt(
+                &format!("{}.example.com", name),
+                "v=spf1 a:h1.example.com a:h2.example.com a:h3.example.com \
+                 a:h4.example.com a:h5.example.com ?all",
+            );
+        }
+        for host in 1..=5 {
+            dns = dns.ip(&format!("h{}.example.com", host), "198.51.100.1");
+        }
+
+        let outcome = check(&dns, "198.51.100.1", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::Pass);
+        let outcome = check(&dns, "192.0.2.1", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::PermError);
+        assert_eq!(outcome.lookups, MAX_DNS_LOOKUPS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_void_lookup_limit() {
+        let dns = MockDns::default().txt(
+            "example.com",
+            "v=spf1 a:x.example.com a:y.example.com mx:z.example.com ip4:192.0.2.1 -all",
+        );
+        let outcome = check(&dns, "192.0.2.1", "alice@example.com").await;
+        assert_eq!(outcome.result, SpfResult::PermError);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let dns = MockDns {
+            failing: vec!["down.example.com".to_string()],
+            ..MockDns::default()
+        }
+        .txt("two.example.com", "v=spf1 -all")
+        .txt("two.example.com", "v=spf1 +all")
+        .txt("bad.example.com", "v=spf1 ip4 -all")
+        .txt(
+            "redirect.example.com",
+            "v=spf1 redirect=nowhere.example.com",
+        )
+        .txt(
+            "include.example.com",
+            "v=spf1 include:down.example.com -all",
+        )
+        .txt("neutral.example.com", "v=spf1 ip4:192.0.2.0/24");
+
+        for (sender, result) in [
+            ("a@none.example.com", SpfResult::None),
+            ("a@two.example.com", SpfResult::PermError),
+            ("a@bad.example.com", SpfResult::PermError),
+            ("a@redirect.example.com", SpfResult::PermError),
+            ("a@include.example.com", SpfResult::TempError),
+            ("a@down.example.com", SpfResult::TempError),
+            ("a@neutral.example.com", SpfResult::Neutral),
+        ] {
+            let outcome = check(&dns, "203.0.113.1", sender).await;
+            assert_eq!(outcome.result, result, "{}", sender);
+            assert_eq!(outcome.mechanism, None);
+        }
+    }
+
+    #[test]
+    fn test_helpers() {
+        assert!(is_spf_record(b"v=spf1"));
+        assert!(is_spf_record(b"V=SPF1 -all"));
+        assert!(!is_spf_record(b"v=spf10 -all"));
+        assert!(valid_domain("example.com"));
+        assert!(!valid_domain("localhost"));
+        assert!(!valid_domain("a..example.com"));
+
+        let long = format!("{}.example.com", "a.".repeat(200));
+        assert!(truncate_domain(&long).len() <= MAX_DOMAIN_LEN);
+        assert!(truncate_domain(&long).ends_with(".example.com"));
+
+        assert_eq!(dual_cidr(u32::MAX, u128::MAX), "");
+        assert_eq!(dual_cidr(0xffff_ff00, u128::MAX << 64), "/24//64");
+    }
+}
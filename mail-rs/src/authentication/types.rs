@@ -55,6 +55,9 @@ pub struct SpfAuthResult {
     pub envelope_from: String,
     /// Additional explanation
     pub reason: Option<String>,
+    /// Mechanism that matched, e.g. `ip4:192.0.2.0/24`
+    #[serde(default)]
+    pub mechanism: Option<String>,
 }
 
 /// DKIM authentication result details
@@ -79,6 +82,7 @@ impl AuthenticationResults {
                 client_ip: String::new(),
                 envelope_from: String::new(),
                 reason: None,
+                mechanism: None,
             },
             dkim: DkimAuthResult {
                 status: AuthenticationStatus::None,
@@ -96,9 +100,15 @@ impl AuthenticationResults {
 
         // Add SPF result
         if self.spf.status != AuthenticationStatus::None {
+            let mechanism = self
+                .spf
+                .mechanism
+                .as_ref()
+                .map(|mechanism| format!(" (mechanism {} matched)", mechanism))
+                .unwrap_or_default();
             parts.push(format!(
-                "spf={} smtp.mailfrom={}",
-                self.spf.status, self.spf.envelope_from
+                "spf={}{} smtp.mailfrom={}",
+                self.spf.status, mechanism, self.spf.envelope_from
             ));
         }
 
@@ -149,6 +159,20 @@ mod tests {
         assert!(header.contains("mail.example.com"));
     }
 
+    #[test]
+    fn test_authentication_results_header_spf_mechanism() {
+        let mut results = AuthenticationResults::new();
+        results.spf.status = AuthenticationStatus::Pass;
+        results.spf.envelope_from = "sender@example.com".to_string();
+        results.spf.mechanism = Some("include:_spf.example.net".to_string());
+
+        assert_eq!(
+            results.to_header("mail.test.com"),
+            "mail.test.com; spf=pass (mechanism include:_spf.example.net matched) \
+             smtp.mailfrom=sender@example.com"
+        );
+    }
+
     #[test]
     fn test_authentication_results_header_spf_only() {
         let mut results = AuthenticationResults::new();
@@ -233,6 +257,7 @@ mod tests {
             client_ip: "192.0.2.1".to_string(),
             envelope_from: "sender@example.com".to_string(),
             reason: Some("IP authorized in SPF record".to_string()),
+            mechanism: None,
         };
 
         assert_eq!(result.status, AuthenticationStatus::Pass);
@@ -277,6 +302,7 @@ mod tests {
                 client_ip: "192.0.2.1".to_string(),
                 envelope_from: "test@example.com".to_string(),
                 reason: Some("Test".to_string()),
+                mechanism: None,
             },
             dkim: DkimAuthResult {
                 status: AuthenticationStatus::Pass,
@@ -19,6 +19,13 @@ impl AutoReplyManager {
         Self { db }
     }
 
+    /// Open the database at `database_url` and create the tables
+    pub async fn connect(database_url: &str) -> Result<Self, MailError> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
     /// Initialize database tables
     pub async fn init_db(&self) -> Result<(), MailError> {
         // Auto-reply configurations table
@@ -66,6 +73,22 @@ impl AutoReplyManager {
         .execute(&self.db)
         .await?;
 
+        // Sieve vacation responses, per response handle; users answering
+        // from a script have no auto_reply_configs row to reference
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS vacation_sent (
+                user_email TEXT NOT NULL,
+                sent_to TEXT NOT NULL,
+                handle TEXT NOT NULL,
+                sent_at TEXT NOT NULL,
+                PRIMARY KEY (user_email, sent_to, handle)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
         Ok(())
     }
 
@@ -265,7 +288,60 @@ impl AutoReplyManager {
         })
     }
 
-    /// Clean up old auto-reply sent records (older than 30 days)
+    /// Whether a Sieve vacation response with `handle` from `user_email`
+    /// to `sender_email` is due, i.e. none was sent in the last `days`
+    pub async fn vacation_due(
+        &self,
+        user_email: &str,
+        sender_email: &str,
+        handle: &str,
+        days: u32,
+    ) -> Result<bool, MailError> {
+        let cutoff = Utc::now() - Duration::days(days as i64);
+
+        let recent_reply = sqlx::query(
+            r#"
+            SELECT sent_at FROM vacation_sent
+            WHERE user_email = ? AND sent_to = ? AND handle = ? AND sent_at > ?
+            "#,
+        )
+        .bind(user_email)
+        .bind(sender_email.to_lowercase())
+        .bind(handle)
+        .bind(cutoff.to_rfc3339())
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(recent_reply.is_none())
+    }
+
+    /// Record that a Sieve vacation response was sent
+    pub async fn record_vacation_sent(
+        &self,
+        user_email: &str,
+        sender_email: &str,
+        handle: &str,
+    ) -> Result<(), MailError> {
+        sqlx::query(
+            r#"
+            INSERT INTO vacation_sent (user_email, sent_to, handle, sent_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(user_email, sent_to, handle) DO UPDATE SET
+                sent_at = excluded.sent_at
+            "#,
+        )
+        .bind(user_email)
+        .bind(sender_email.to_lowercase())
+        .bind(handle)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Clean up old auto-reply and vacation sent records (older than 30
+    /// days, the longest vacation interval)
     pub async fn cleanup_old_records(&self) -> Result<u64, MailError> {
         let cutoff = Utc::now() - Duration::days(30);
 
@@ -273,8 +349,12 @@ impl AutoReplyManager {
             .bind(cutoff.to_rfc3339())
             .execute(&self.db)
             .await?;
+        let vacations = sqlx::query("DELETE FROM vacation_sent WHERE sent_at < ?")
+            .bind(cutoff.to_rfc3339())
+            .execute(&self.db)
+            .await?;
 
-        Ok(result.rows_affected())
+        Ok(result.rows_affected() + vacations.rows_affected())
     }
 
     /// Helper: Convert database row to AutoReplyConfig
@@ -391,6 +471,36 @@ mod tests {
         assert!(!should_send);
     }
 
+    #[tokio::test]
+    async fn test_vacation_tracking() {
+        let pool = setup_test_db().await;
+        let manager = AutoReplyManager::new(pool);
+
+        // No auto-reply config is needed for Sieve vacations
+        assert!(manager
+            .vacation_due("test@example.com", "sender@example.com", "away", 7)
+            .await
+            .unwrap());
+        manager
+            .record_vacation_sent("test@example.com", "Sender@Example.com", "away")
+            .await
+            .unwrap();
+
+        assert!(!manager
+            .vacation_due("test@example.com", "sender@example.com", "away", 7)
+            .await
+            .unwrap());
+        // Another response or another sender is answered
+        assert!(manager
+            .vacation_due("test@example.com", "sender@example.com", "sick", 7)
+            .await
+            .unwrap());
+        assert!(manager
+            .vacation_due("test@example.com", "other@example.com", "away", 7)
+            .await
+            .unwrap());
+    }
+
     #[tokio::test]
     async fn test_delete_config() {
         let pool = setup_test_db().await;
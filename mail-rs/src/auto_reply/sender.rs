@@ -2,7 +2,7 @@
 
 use crate::auto_reply::{AutoReplyConfig, AutoReplyManager};
 use crate::error::MailError;
-use chrono::Utc;
+use crate::mime::MessageBuilder;
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
@@ -122,42 +122,16 @@ impl AutoReplySender {
         subject: &str,
         html_body: &str,
         text_body: &str,
-    ) -> String {
-        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S %z");
-        let boundary = format!("----=_Part_{}", uuid::Uuid::new_v4().simple());
-
-        format!(
-            "From: <{}>\r\n\
-             To: <{}>\r\n\
-             Subject: {}\r\n\
-             Date: {}\r\n\
-             Auto-Submitted: auto-replied\r\n\
-             X-Auto-Response-Suppress: All\r\n\
-             MIME-Version: 1.0\r\n\
-             Content-Type: multipart/alternative; boundary=\"{}\"\r\n\
-             \r\n\
-             --{}\r\n\
-             Content-Type: text/plain; charset=\"UTF-8\"\r\n\
-             Content-Transfer-Encoding: 7bit\r\n\
-             \r\n\
-             {}\r\n\
-             --{}\r\n\
-             Content-Type: text/html; charset=\"UTF-8\"\r\n\
-             Content-Transfer-Encoding: 7bit\r\n\
-             \r\n\
-             {}\r\n\
-             --{}--",
-            from,
-            to,
-            subject,
-            date,
-            boundary,
-            boundary,
-            text_body,
-            boundary,
-            html_body,
-            boundary
-        )
+    ) -> Vec<u8> {
+        MessageBuilder::new()
+            .from(from)
+            .to(to)
+            .subject(subject)
+            .header("Auto-Submitted", "auto-replied")
+            .header("X-Auto-Response-Suppress", "All")
+            .text(text_body)
+            .html(html_body)
+            .build()
     }
 
     /// Send email via SMTP (simple implementation)
@@ -165,7 +139,7 @@ impl AutoReplySender {
         &self,
         from: &str,
         to: &str,
-        message: &str,
+        message: &[u8],
     ) -> Result<(), MailError> {
         let addr = format!("{}:{}", self.smtp_host, self.smtp_port);
 
@@ -217,7 +191,7 @@ impl AutoReplySender {
 
         // Send message
         stream
-            .write_all(message.as_bytes())
+            .write_all(message)
             .await
             .map_err(|e| MailError::Io(e))?;
         stream
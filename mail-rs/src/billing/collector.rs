@@ -0,0 +1,161 @@
+//! Scheduled storage sampling and period closing
+//!
+//! Once an hour the collector records the storage used by every mailbox,
+//! closes the billing periods that ended and posts each closed period's
+//! export to the configured webhook. Notifications that fail are retried
+//! on the next run.
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::manager::BillingManager;
+use super::types::*;
+use crate::config::Config;
+use crate::quota::maildirsize;
+
+/// Interval between collector runs
+const COLLECTOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Body of webhook requests
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    export: &'a BillingExport,
+}
+
+/// Samples storage, closes periods and notifies the billing webhook
+pub struct BillingCollector {
+    manager: Arc<BillingManager>,
+    /// Maildir roots of the default store and every residency region
+    roots: Vec<PathBuf>,
+    webhook_url: Option<String>,
+    http: reqwest::Client,
+}
+
+impl BillingCollector {
+    pub fn new(manager: Arc<BillingManager>, config: &Config) -> Self {
+        let mut roots = vec![PathBuf::from(&config.storage.maildir_path)];
+        roots.extend(
+            config
+                .residency
+                .regions
+                .iter()
+                .map(|region| PathBuf::from(&region.maildir_path)),
+        );
+
+        Self {
+            manager,
+            roots,
+            webhook_url: config.billing.webhook_url.clone(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Run forever, sampling and closing periods every hour
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(COLLECTOR_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.run_once().await {
+                error!("Billing collector run failed: {}", e);
+            }
+        }
+    }
+
+    /// Sample storage, then close and notify every ended period
+    pub async fn run_once(&self) -> Result<()> {
+        let roots = self.roots.clone();
+        let samples = tokio::task::spawn_blocking(move || {
+            roots
+                .iter()
+                .flat_map(|root| mailbox_sizes(root))
+                .collect::<Vec<_>>()
+        })
+        .await?;
+        for (email, bytes) in samples {
+            self.manager.record_storage(&email, bytes).await?;
+        }
+
+        for period in self.manager.close_ended_periods(Utc::now()).await? {
+            info!("Closed billing period {}", period);
+        }
+
+        let Some(url) = &self.webhook_url else {
+            return Ok(());
+        };
+        for period in self.manager.unnotified_periods().await? {
+            let export = self.manager.export(period).await?;
+            if let Err(e) = self.notify(url, &export).await {
+                warn!("Failed to notify close of billing period {}: {}", period, e);
+                continue;
+            }
+            self.manager.mark_notified(period).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn notify(&self, url: &str, export: &BillingExport) -> Result<()> {
+        self.http
+            .post(url)
+            .json(&WebhookPayload {
+                event: "billing.period_closed",
+                export,
+            })
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Storage used by every mailbox directly below `root`
+fn mailbox_sizes(root: &Path) -> Vec<(String, u64)> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .filter_map(|entry| {
+            let email = entry.file_name().to_string_lossy().to_string();
+            if !email.contains('@') {
+                return None;
+            }
+            match maildirsize::usage(&entry.path()) {
+                Ok(usage) => Some((email, usage.bytes)),
+                Err(e) => {
+                    warn!("Failed to measure mailbox of {}: {}", email, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mailbox_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("alice@example.com/new")).unwrap();
+        std::fs::create_dir_all(dir.path().join("alice@example.com/.Sent/cur")).unwrap();
+        std::fs::write(dir.path().join("alice@example.com/new/1"), b"12345").unwrap();
+        std::fs::write(dir.path().join("alice@example.com/.Sent/cur/2:2,S"), b"123").unwrap();
+        std::fs::create_dir_all(dir.path().join("exports")).unwrap();
+
+        assert_eq!(
+            mailbox_sizes(dir.path()),
+            vec![("alice@example.com".to_string(), 8)]
+        );
+        assert!(mailbox_sizes(&dir.path().join("missing")).is_empty());
+    }
+}
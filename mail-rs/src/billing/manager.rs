@@ -0,0 +1,371 @@
+//! Billing manager: per-user counters, storage samples and closed periods
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Billing manager
+pub struct BillingManager {
+    db: SqlitePool,
+}
+
+impl BillingManager {
+    /// Create a new billing manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the billing tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS billing_usage (
+                period TEXT NOT NULL,
+                email TEXT NOT NULL,
+                messages_sent INTEGER NOT NULL DEFAULT 0,
+                messages_received INTEGER NOT NULL DEFAULT 0,
+                api_calls INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (period, email)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS billing_storage (
+                email TEXT NOT NULL,
+                day TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                PRIMARY KEY (email, day)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS billing_periods (
+                period TEXT PRIMARY KEY,
+                closed_at TEXT NOT NULL,
+                notified_at TEXT
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Count one `metric` for `email` in the current period
+    pub async fn record(&self, email: &str, metric: BillingMetric) -> Result<()> {
+        self.record_at(email, metric, Utc::now()).await
+    }
+
+    async fn record_at(&self, email: &str, metric: BillingMetric, at: DateTime<Utc>) -> Result<()> {
+        // `column` is one of three fixed names, never user input
+        let query = format!(
+            r#"
+            INSERT INTO billing_usage (period, email, {column}) VALUES (?, ?, 1)
+            ON CONFLICT(period, email) DO UPDATE SET {column} = {column} + 1
+            "#,
+            column = metric.column()
+        );
+
+        sqlx::query(&query)
+            .bind(BillingPeriod::containing(at).to_string())
+            .bind(email.to_lowercase())
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Record the storage used by `email` today
+    ///
+    /// Later samples of the same day replace earlier ones, so each day
+    /// counts once towards the byte-days of its period.
+    pub async fn record_storage(&self, email: &str, bytes: u64) -> Result<()> {
+        self.record_storage_at(email, bytes, Utc::now()).await
+    }
+
+    async fn record_storage_at(&self, email: &str, bytes: u64, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO billing_storage (email, day, bytes) VALUES (?, ?, ?)")
+            .bind(email.to_lowercase())
+            .bind(at.format("%Y-%m-%d").to_string())
+            .bind(bytes as i64)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Usage of every user over `period`, sorted by address
+    pub async fn export(&self, period: BillingPeriod) -> Result<BillingExport> {
+        let rows = sqlx::query(
+            r#"
+            SELECT email,
+                   SUM(byte_days) AS byte_days,
+                   SUM(sent) AS sent,
+                   SUM(received) AS received,
+                   SUM(api_calls) AS api_calls
+            FROM (
+                SELECT email, 0 AS byte_days, messages_sent AS sent,
+                       messages_received AS received, api_calls
+                FROM billing_usage WHERE period = ?
+                UNION ALL
+                SELECT email, bytes, 0, 0, 0
+                FROM billing_storage WHERE day >= ? AND day < ?
+            )
+            GROUP BY email
+            ORDER BY email
+            "#,
+        )
+        .bind(period.to_string())
+        .bind(period.first_day().to_string())
+        .bind(period.next().first_day().to_string())
+        .fetch_all(&self.db)
+        .await?;
+
+        let closed_at = sqlx::query("SELECT closed_at FROM billing_periods WHERE period = ?")
+            .bind(period.to_string())
+            .fetch_optional(&self.db)
+            .await?
+            .map(|row| parse_time(row.get("closed_at")))
+            .transpose()?;
+
+        Ok(BillingExport {
+            period: period.to_string(),
+            period_start: period.start(),
+            period_end: period.end(),
+            closed_at,
+            users: rows
+                .iter()
+                .map(|row| UserUsage {
+                    email: row.get("email"),
+                    storage_byte_days: row.get("byte_days"),
+                    messages_sent: row.get("sent"),
+                    messages_received: row.get("received"),
+                    api_calls: row.get("api_calls"),
+                })
+                .collect(),
+        })
+    }
+
+    /// Close every period with usage that ended before `now`
+    ///
+    /// Returns the newly closed periods, oldest first.
+    pub async fn close_ended_periods(&self, now: DateTime<Utc>) -> Result<Vec<BillingPeriod>> {
+        let current = BillingPeriod::containing(now);
+        let rows = sqlx::query(
+            r#"
+            SELECT period FROM (
+                SELECT period FROM billing_usage
+                UNION
+                SELECT substr(day, 1, 7) AS period FROM billing_storage
+            )
+            WHERE period < ? AND period NOT IN (SELECT period FROM billing_periods)
+            ORDER BY period
+            "#,
+        )
+        .bind(current.to_string())
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut closed = Vec::new();
+        for row in rows {
+            let Some(period) = BillingPeriod::parse(row.get("period")) else {
+                continue;
+            };
+            sqlx::query("INSERT OR IGNORE INTO billing_periods (period, closed_at) VALUES (?, ?)")
+                .bind(period.to_string())
+                .bind(now.to_rfc3339())
+                .execute(&self.db)
+                .await?;
+            closed.push(period);
+        }
+
+        Ok(closed)
+    }
+
+    /// Closed periods whose webhook notification has not been sent yet
+    pub async fn unnotified_periods(&self) -> Result<Vec<BillingPeriod>> {
+        let rows = sqlx::query(
+            "SELECT period FROM billing_periods WHERE notified_at IS NULL ORDER BY period",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| BillingPeriod::parse(row.get("period")))
+            .collect())
+    }
+
+    /// Remember that the close of `period` was notified
+    pub async fn mark_notified(&self, period: BillingPeriod) -> Result<()> {
+        sqlx::query("UPDATE billing_periods SET notified_at = ? WHERE period = ?")
+            .bind(Utc::now().to_rfc3339())
+            .bind(period.to_string())
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    async fn manager() -> BillingManager {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = BillingManager::new(db);
+        manager.init_db().await.unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_export_period() {
+        let manager = manager().await;
+        let march = Utc.with_ymd_and_hms(2024, 3, 12, 10, 0, 0).unwrap();
+        let april = Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap();
+
+        manager
+            .record_at("Alice@example.com", BillingMetric::MessageSent, march)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            manager
+                .record_at("alice@example.com", BillingMetric::ApiCall, march)
+                .await
+                .unwrap();
+        }
+        manager
+            .record_at("bob@example.com", BillingMetric::MessageReceived, march)
+            .await
+            .unwrap();
+        manager
+            .record_at("bob@example.com", BillingMetric::MessageReceived, april)
+            .await
+            .unwrap();
+
+        // Two days sampled, the second sample of a day replaces the first
+        manager
+            .record_storage_at("alice@example.com", 500, march)
+            .await
+            .unwrap();
+        manager
+            .record_storage_at(
+                "alice@example.com",
+                1_000,
+                march + chrono::Duration::hours(5),
+            )
+            .await
+            .unwrap();
+        manager
+            .record_storage_at(
+                "alice@example.com",
+                2_000,
+                march + chrono::Duration::days(1),
+            )
+            .await
+            .unwrap();
+        manager
+            .record_storage_at("carol@example.com", 10, april)
+            .await
+            .unwrap();
+
+        let export = manager
+            .export(BillingPeriod::parse("2024-03").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(export.period_end, april);
+        assert!(export.closed_at.is_none());
+        assert_eq!(
+            export.users,
+            vec![
+                UserUsage {
+                    email: "alice@example.com".to_string(),
+                    storage_byte_days: 3_000,
+                    messages_sent: 1,
+                    messages_received: 0,
+                    api_calls: 3,
+                },
+                UserUsage {
+                    email: "bob@example.com".to_string(),
+                    storage_byte_days: 0,
+                    messages_sent: 0,
+                    messages_received: 1,
+                    api_calls: 0,
+                },
+            ]
+        );
+        assert_eq!(
+            export.to_csv(),
+            "period,email,storage_byte_days,messages_sent,messages_received,api_calls\r\n\
+             2024-03,alice@example.com,3000,1,0,3\r\n\
+             2024-03,bob@example.com,0,0,1,0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_ended_periods() {
+        let manager = manager().await;
+        let february = Utc.with_ymd_and_hms(2024, 2, 10, 0, 0, 0).unwrap();
+        let march = Utc.with_ymd_and_hms(2024, 3, 10, 0, 0, 0).unwrap();
+
+        manager
+            .record_at("alice@example.com", BillingMetric::ApiCall, february)
+            .await
+            .unwrap();
+        manager
+            .record_storage_at("alice@example.com", 1, march)
+            .await
+            .unwrap();
+
+        // March is still open on March 31st
+        let closed = manager
+            .close_ended_periods(Utc.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(closed, vec![BillingPeriod::parse("2024-02").unwrap()]);
+
+        let closed = manager
+            .close_ended_periods(Utc.with_ymd_and_hms(2024, 4, 2, 0, 0, 0).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(closed, vec![BillingPeriod::parse("2024-03").unwrap()]);
+        assert!(manager
+            .close_ended_periods(Utc.with_ymd_and_hms(2024, 4, 3, 0, 0, 0).unwrap())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let february = BillingPeriod::parse("2024-02").unwrap();
+        assert!(manager.export(february).await.unwrap().closed_at.is_some());
+        assert_eq!(manager.unnotified_periods().await.unwrap().len(), 2);
+        manager.mark_notified(february).await.unwrap();
+        assert_eq!(
+            manager.unnotified_periods().await.unwrap(),
+            vec![BillingPeriod::parse("2024-03").unwrap()]
+        );
+    }
+}
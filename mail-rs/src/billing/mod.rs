@@ -0,0 +1,15 @@
+//! Billing metrics for hosting providers
+//!
+//! Counts messages sent and received and authenticated API calls per user
+//! and calendar month, samples each mailbox's storage once a day to bill
+//! byte-days, and closes months once they end. Closed periods are posted
+//! to a webhook and every period can be exported as CSV or JSON through
+//! the admin API.
+
+pub mod collector;
+pub mod manager;
+pub mod types;
+
+pub use collector::BillingCollector;
+pub use manager::BillingManager;
+pub use types::*;
@@ -0,0 +1,200 @@
+//! Billing data types
+
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::reporting::render::csv_field;
+
+/// Billing period: one calendar month (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BillingPeriod {
+    year: i32,
+    month: u32,
+}
+
+impl BillingPeriod {
+    /// Parse a `YYYY-MM` period
+    pub fn parse(s: &str) -> Option<Self> {
+        let (year, month) = s.split_once('-')?;
+        let year = year.parse().ok()?;
+        let month = month.parse().ok()?;
+        NaiveDate::from_ymd_opt(year, month, 1)?;
+        Some(Self { year, month })
+    }
+
+    /// Period containing `at`
+    pub fn containing(at: DateTime<Utc>) -> Self {
+        Self {
+            year: at.year(),
+            month: at.month(),
+        }
+    }
+
+    /// Period before this one
+    pub fn previous(&self) -> Self {
+        match self.month {
+            1 => Self {
+                year: self.year - 1,
+                month: 12,
+            },
+            month => Self {
+                year: self.year,
+                month: month - 1,
+            },
+        }
+    }
+
+    /// Period after this one
+    pub fn next(&self) -> Self {
+        match self.month {
+            12 => Self {
+                year: self.year + 1,
+                month: 1,
+            },
+            month => Self {
+                year: self.year,
+                month: month + 1,
+            },
+        }
+    }
+
+    /// First day of the period
+    pub fn first_day(&self) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.year, self.month, 1).expect("first day of month is valid")
+    }
+
+    /// Start of the period (inclusive)
+    pub fn start(&self) -> DateTime<Utc> {
+        midnight(self.first_day())
+    }
+
+    /// End of the period (exclusive)
+    pub fn end(&self) -> DateTime<Utc> {
+        self.next().start()
+    }
+}
+
+impl fmt::Display for BillingPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("midnight is valid"))
+}
+
+/// Counter billed per user and period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BillingMetric {
+    /// Message submitted by the user (counted once for all recipients)
+    MessageSent,
+    /// Message delivered to the user's mailbox
+    MessageReceived,
+    /// Authenticated API request
+    ApiCall,
+}
+
+impl BillingMetric {
+    /// Column of `billing_usage` holding the counter
+    pub(crate) fn column(&self) -> &'static str {
+        match self {
+            Self::MessageSent => "messages_sent",
+            Self::MessageReceived => "messages_received",
+            Self::ApiCall => "api_calls",
+        }
+    }
+}
+
+/// Output format of a billing export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Usage of one user over a billing period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserUsage {
+    pub email: String,
+    /// Sum of the daily storage samples in bytes (one sample per day)
+    pub storage_byte_days: i64,
+    pub messages_sent: i64,
+    pub messages_received: i64,
+    pub api_calls: i64,
+}
+
+/// Billable usage of every user over one period
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BillingExport {
+    /// Period as `YYYY-MM`
+    pub period: String,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    /// When the period was closed; `None` while usage is still recorded
+    pub closed_at: Option<DateTime<Utc>>,
+    pub users: Vec<UserUsage>,
+}
+
+impl BillingExport {
+    /// CSV with one row per user
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "period,email,storage_byte_days,messages_sent,messages_received,api_calls\r\n",
+        );
+        for user in &self.users {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\r\n",
+                self.period,
+                csv_field(&user.email),
+                user.storage_byte_days,
+                user.messages_sent,
+                user.messages_received,
+                user.api_calls
+            ));
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_billing_period() {
+        let period = BillingPeriod::parse("2024-12").unwrap();
+        assert_eq!(period.to_string(), "2024-12");
+        assert_eq!(
+            period.start(),
+            Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            period.end(),
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(period.next().to_string(), "2025-01");
+        assert_eq!(period.next().previous(), period);
+        assert_eq!(
+            BillingPeriod::containing(Utc.with_ymd_and_hms(2024, 3, 31, 23, 59, 59).unwrap())
+                .to_string(),
+            "2024-03"
+        );
+
+        assert!(BillingPeriod::parse("2024-13").is_none());
+        assert!(BillingPeriod::parse("2024").is_none());
+        assert!(BillingPeriod::parse("march").is_none());
+    }
+}
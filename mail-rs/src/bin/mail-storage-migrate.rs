@@ -0,0 +1,155 @@
+//! CLI tool for moving mailboxes between storage backends
+//!
+//! Backends are given as `<kind>:<path>`, where kind is `maildir` or
+//! `compressed` (gzip-compressed Maildir).
+//!
+//! # Usage
+//!
+//! ```bash
+//! # Copy one user, resumable, at most 50 messages per second
+//! mail-storage-migrate --from maildir:/var/mail --to compressed:/srv/archive \
+//!     --rate 50 alice@example.com
+//!
+//! # Migrate everyone with cutover: lock, final sync, verify, delete source
+//! mail-storage-migrate --from maildir:/var/mail --to compressed:/srv/archive \
+//!     --all --cutover --remove-source
+//! ```
+
+use clap::Parser;
+use mail_rs::storage::{
+    CompressedMaildirStorage, MaildirStorage, MigrationOptions, MigrationReport, Storage,
+    StorageMigrator,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(name = "mail-storage-migrate")]
+#[command(about = "Move mailboxes between storage backends", long_about = None)]
+struct Cli {
+    /// Source backend, e.g. maildir:/var/mail
+    #[arg(long)]
+    from: String,
+
+    /// Destination backend, e.g. compressed:/srv/archive
+    #[arg(long)]
+    to: String,
+
+    /// Users to migrate
+    users: Vec<String>,
+
+    /// Migrate every user of the source backend
+    #[arg(long, conflicts_with = "users")]
+    all: bool,
+
+    /// Maximum messages copied per second
+    #[arg(long)]
+    rate: Option<u32>,
+
+    /// Lock the source mailbox, run a final sync, then verify
+    #[arg(long)]
+    cutover: bool,
+
+    /// Seconds given to IMAP sessions to close before the final sync
+    #[arg(long, default_value_t = 5)]
+    grace_secs: u64,
+
+    /// Keep the source locked after cutover (until the server is switched)
+    #[arg(long, requires = "cutover")]
+    keep_locked: bool,
+
+    /// Delete source messages after successful verification
+    #[arg(long)]
+    remove_source: bool,
+}
+
+enum Backend {
+    Maildir(MaildirStorage),
+    Compressed(CompressedMaildirStorage),
+}
+
+impl Backend {
+    fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':') {
+            Some(("maildir", path)) => Ok(Self::Maildir(MaildirStorage::new(path.to_string()))),
+            Some(("compressed", path)) => Ok(Self::Compressed(CompressedMaildirStorage::new(
+                path.to_string(),
+            ))),
+            _ => Err(format!(
+                "Invalid backend '{}', expected maildir:<path> or compressed:<path>",
+                spec
+            )),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    if cli.users.is_empty() && !cli.all {
+        eprintln!("Error: give at least one user or --all");
+        std::process::exit(2);
+    }
+
+    let options = MigrationOptions {
+        max_messages_per_sec: cli.rate,
+        cutover: cli.cutover,
+        cutover_grace: Duration::from_secs(cli.grace_secs),
+        keep_locked: cli.keep_locked,
+        remove_source: cli.remove_source,
+    };
+
+    let reports = match (Backend::parse(&cli.from)?, Backend::parse(&cli.to)?) {
+        (Backend::Maildir(s), Backend::Maildir(d)) => run(s, d, options, &cli).await?,
+        (Backend::Maildir(s), Backend::Compressed(d)) => run(s, d, options, &cli).await?,
+        (Backend::Compressed(s), Backend::Maildir(d)) => run(s, d, options, &cli).await?,
+        (Backend::Compressed(s), Backend::Compressed(d)) => run(s, d, options, &cli).await?,
+    };
+
+    let mut failed = false;
+    for report in &reports {
+        if report.verified {
+            println!(
+                "✓ {}: {} messages ({} copied, {} bytes), digest {}",
+                report.user, report.source_count, report.copied, report.bytes_copied, report.digest
+            );
+        } else {
+            failed = true;
+            eprintln!(
+                "✗ {}: verification failed ({} source, {} destination, {} mismatching)",
+                report.user,
+                report.source_count,
+                report.destination_count,
+                report.mismatches.len()
+            );
+            for id in &report.mismatches {
+                eprintln!("    {}", id);
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn run<S: Storage, D: Storage>(
+    source: S,
+    destination: D,
+    options: MigrationOptions,
+    cli: &Cli,
+) -> mail_rs::error::Result<Vec<MigrationReport>> {
+    let migrator = StorageMigrator::new(Arc::new(source), Arc::new(destination), options);
+
+    if cli.all {
+        return migrator.migrate_all().await;
+    }
+
+    let mut reports = Vec::new();
+    for user in &cli.users {
+        reports.push(migrator.migrate_user(user).await?);
+    }
+    Ok(reports)
+}
@@ -16,7 +16,13 @@
 //!
 //! # Check if user exists
 //! mail-user exists user@example.com --db sqlite://users.db
+//!
+//! # Users who still need a password login before SCRAM-SHA-256 works
+//! mail-user credentials --db sqlite://users.db
 //! ```
+//!
+//! Pass `--cram-md5` when the server has `smtp.allow_cram_md5` enabled, so
+//! new users also get the CRAM-MD5 secret.
 
 use clap::{Parser, Subcommand};
 use mail_rs::security::Authenticator;
@@ -29,6 +35,10 @@ struct Cli {
     #[arg(short, long, default_value = "sqlite://users.db")]
     db: String,
 
+    /// Store CRAM-MD5 secrets (match the server's smtp.allow_cram_md5)
+    #[arg(long)]
+    cram_md5: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -54,6 +64,10 @@ enum Commands {
         /// User email address
         email: String,
     },
+    /// List users without challenge-response credentials
+    ///
+    /// They are derived on the user's next password login.
+    Credentials,
 }
 
 #[tokio::main]
@@ -61,7 +75,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     // Initialize authenticator
-    let auth = Authenticator::new(&cli.db).await?;
+    let auth = Authenticator::new(&cli.db).await?.with_cram_md5(cli.cram_md5);
 
     match cli.command {
         Commands::Add { email, password } => {
@@ -115,6 +129,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 std::process::exit(1);
             }
         }
+        Commands::Credentials => {
+            let users = auth.users_missing_challenge_credentials().await?;
+
+            if users.is_empty() {
+                println!("✓ All users have challenge-response credentials");
+            } else {
+                for email in &users {
+                    println!("{}", email);
+                }
+                println!(
+                    "\n{} user(s) need to log in with their password once",
+                    users.len()
+                );
+            }
+        }
     }
 
     Ok(())
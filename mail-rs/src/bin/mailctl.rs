@@ -0,0 +1,339 @@
+//! Offline mailbox and configuration administration
+//!
+//! Works directly on mailboxes on disk, without a running server.
+//! Mailboxes are given as `<format>:<path>`, where format is `maildir`
+//! (nested folder directories), `maildir++` (dot-separated `.Folder`
+//! directories, the server's own layout) or `mbox`.
+//!
+//! Configurations are compared from config files, saved fingerprint exports
+//! (`.json`) or running nodes (`http(s)://` API addresses).
+//!
+//! # Usage
+//!
+//! ```bash
+//! # Restore an mbox export from another system into a user's mailbox
+//! mailctl convert --from mbox:/tmp/export --to maildir++:/var/mail/alice@example.com
+//!
+//! # Write one folder to a single mbox file
+//! mailctl convert --from maildir++:/var/mail/alice@example.com \
+//!     --to mbox:/tmp/sent.mbox --folder Sent
+//!
+//! # Rename folders on the way
+//! mailctl convert --from maildir:/backup/bob --to maildir++:/var/mail/bob@example.com \
+//!     --map "Sent Items=Sent" --map "Deleted Items=Trash"
+//!
+//! # Compare the effective config of two nodes before a rollout
+//! mailctl config-diff https://mx1.example.com:8080 https://mx2.example.com:8080 \
+//!     --session admin@example.com --fail-on-drift
+//!
+//! # Compare a staged config file with production
+//! mailctl config-diff config.staging.toml https://mx1.example.com:8080 --session admin@example.com
+//!
+//! # Fill a development server with demo users, mail, events and contacts
+//! mailctl seed --config config.toml --seed 7 --users 5
+//! ```
+
+use clap::{Parser, Subcommand};
+use mail_rs::admin::config_drift::{self, ConfigSnapshot};
+use mail_rs::caldav::CalDavManager;
+use mail_rs::config::Config;
+use mail_rs::import_export::convert::{self, ConvertOptions, MailboxLocation};
+use mail_rs::security::Authenticator;
+use mail_rs::seed::{self, DemoData, SeedOptions};
+use mail_rs::storage::MaildirStorage;
+
+#[derive(Parser)]
+#[command(name = "mailctl")]
+#[command(about = "Offline mailbox and configuration administration", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Convert a mailbox between Maildir, Maildir++ and mbox
+    Convert {
+        /// Source mailbox, e.g. mbox:/tmp/export
+        #[arg(long)]
+        from: String,
+
+        /// Destination mailbox, e.g. maildir++:/var/mail/alice@example.com
+        #[arg(long)]
+        to: String,
+
+        /// Only convert this folder (repeatable)
+        #[arg(long)]
+        folder: Vec<String>,
+
+        /// Rename a folder, as SOURCE=DESTINATION (repeatable)
+        #[arg(long, value_name = "SOURCE=DESTINATION")]
+        map: Vec<String>,
+    },
+    /// List the folders of a mailbox
+    Folders {
+        /// Mailbox, e.g. maildir++:/var/mail/alice@example.com
+        mailbox: String,
+    },
+    /// Print the normalized config and fingerprint of a node or config file
+    ConfigFingerprint {
+        /// Config file, fingerprint export (.json) or API address
+        source: String,
+
+        /// Admin session used to query an API address
+        #[arg(long)]
+        session: Option<String>,
+    },
+    /// Diff the effective configs of two nodes or config files
+    ConfigDiff {
+        /// Config file, fingerprint export (.json) or API address
+        left: String,
+
+        /// Config file, fingerprint export (.json) or API address
+        right: String,
+
+        /// Admin session used to query API addresses
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Print the diff as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Exit with status 1 when the configs differ
+        #[arg(long)]
+        fail_on_drift: bool,
+    },
+    /// Create demo users, mail, events and contacts for development
+    Seed {
+        /// Config file giving the Maildir, database and domain
+        #[arg(long)]
+        config: Option<String>,
+
+        /// Maildir root, overrides the config
+        #[arg(long)]
+        maildir: Option<String>,
+
+        /// User and CalDAV database, overrides the config
+        #[arg(long)]
+        database: Option<String>,
+
+        /// Only write mail, without users, events and contacts
+        #[arg(long)]
+        mail_only: bool,
+
+        /// Seed of the generator; the same seed gives the same data
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+
+        /// Number of demo users
+        #[arg(long, default_value_t = 3)]
+        users: usize,
+
+        /// Conversations started by each user
+        #[arg(long, default_value_t = 6)]
+        conversations: usize,
+
+        /// Domain of the demo users, overrides the config
+        #[arg(long)]
+        domain: Option<String>,
+
+        /// Password of every demo user
+        #[arg(long, default_value = "demo-password")]
+        password: String,
+    },
+}
+
+/// Snapshot of a config file, a saved export or a running node
+fn load_snapshot(source: &str, session: Option<&str>) -> anyhow::Result<ConfigSnapshot> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let session =
+            session.ok_or_else(|| anyhow::anyhow!("--session is required to query {}", source))?;
+        let url = format!(
+            "{}/api/admin/config/fingerprint",
+            source.trim_end_matches('/')
+        );
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(async {
+            let response = reqwest::Client::new()
+                .get(&url)
+                .header(
+                    reqwest::header::COOKIE,
+                    format!("admin_session={}", session),
+                )
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(response.json().await?)
+        });
+    }
+
+    if source.ends_with(".json") {
+        return Ok(serde_json::from_str(&std::fs::read_to_string(source)?)?);
+    }
+    let config = Config::from_file(source)
+        .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", source, e))?;
+    ConfigSnapshot::capture(&config)
+}
+
+fn display_value(value: Option<&serde_json::Value>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "(unset)".to_string(),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::Convert {
+            from,
+            to,
+            folder,
+            map,
+        } => {
+            let source = MailboxLocation::parse(&from)?;
+            let destination = MailboxLocation::parse(&to)?;
+
+            let mut options = ConvertOptions {
+                folders: folder,
+                ..Default::default()
+            };
+            for mapping in &map {
+                let (source, destination) = mapping.split_once('=').ok_or_else(|| {
+                    anyhow::anyhow!("Invalid mapping '{}', expected SOURCE=DESTINATION", mapping)
+                })?;
+                options
+                    .mapping
+                    .insert(source.to_string(), destination.to_string());
+            }
+
+            let report = convert::convert(&source, &destination, &options)?;
+            for folder in &report.folders {
+                if folder.source == folder.destination {
+                    println!("✓ {}: {} messages", folder.source, folder.messages);
+                } else {
+                    println!(
+                        "✓ {} -> {}: {} messages",
+                        folder.source, folder.destination, folder.messages
+                    );
+                }
+            }
+            println!(
+                "Converted {} messages from {} to {}",
+                report.messages(),
+                source.format.as_str(),
+                destination.format.as_str()
+            );
+        }
+        Commands::Folders { mailbox } => {
+            for folder in convert::list_folders(&MailboxLocation::parse(&mailbox)?)? {
+                println!("{}", folder);
+            }
+        }
+        Commands::ConfigFingerprint { source, session } => {
+            let snapshot = load_snapshot(&source, session.as_deref())?;
+            println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        }
+        Commands::ConfigDiff {
+            left,
+            right,
+            session,
+            json,
+            fail_on_drift,
+        } => {
+            let left = load_snapshot(&left, session.as_deref())?;
+            let right = load_snapshot(&right, session.as_deref())?;
+            let diff = config_drift::diff(&left, &right);
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else if diff.identical {
+                println!(
+                    "✓ {} and {} match (fingerprint {})",
+                    diff.left_node, diff.right_node, left.fingerprint
+                );
+            } else {
+                println!(
+                    "{} vs {}: {} differences ({} security-relevant)",
+                    diff.left_node,
+                    diff.right_node,
+                    diff.differences.len(),
+                    diff.security_differences()
+                );
+                println!("Drifted sections: {}", diff.drifted_sections.join(", "));
+                for difference in &diff.differences {
+                    println!(
+                        "{} {}: {} -> {}",
+                        if difference.security { "!" } else { " " },
+                        difference.path,
+                        display_value(difference.left.as_ref()),
+                        display_value(difference.right.as_ref())
+                    );
+                }
+            }
+
+            if fail_on_drift && !diff.identical {
+                std::process::exit(1);
+            }
+        }
+        Commands::Seed {
+            config,
+            maildir,
+            database,
+            mail_only,
+            seed,
+            users,
+            conversations,
+            domain,
+            password,
+        } => {
+            let config = match config {
+                Some(path) => Config::from_file(&path)
+                    .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", path, e))?,
+                None => Config::default(),
+            };
+            let options = SeedOptions {
+                seed,
+                domain: domain.unwrap_or_else(|| config.server.domain.clone()),
+                users,
+                conversations,
+                password,
+                ..Default::default()
+            };
+            let data = DemoData::generate(&options);
+            let storage = MaildirStorage::new(
+                maildir.unwrap_or_else(|| config.storage.maildir_path.clone()),
+            );
+            let database = database.unwrap_or_else(|| config.api_database_url());
+
+            let runtime = tokio::runtime::Runtime::new()?;
+            let report = runtime.block_on(async {
+                if mail_only {
+                    return seed::seed(&data, &storage, None, None).await;
+                }
+                let auth = Authenticator::new(&database).await?;
+                let caldav = CalDavManager::new(sqlx::SqlitePool::connect(&database).await?);
+                caldav.init_db().await?;
+                seed::seed(&data, &storage, Some(&auth), Some(&caldav)).await
+            })?;
+
+            for user in &data.users {
+                println!("✓ {} ({})", user.email, user.name);
+            }
+            if report.users_skipped > 0 {
+                println!(
+                    "Skipped {} users whose mailbox is not empty",
+                    report.users_skipped
+                );
+            }
+            println!(
+                "Seeded {} messages, {} events and {} contacts (seed {}, password '{}')",
+                report.messages, report.events, report.contacts, options.seed, options.password
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! Branding manager: per-domain branding settings
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Branding manager
+pub struct BrandingManager {
+    db: SqlitePool,
+}
+
+impl BrandingManager {
+    /// Create a new branding manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the branding table
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS branding (
+                domain TEXT PRIMARY KEY,
+                product_name TEXT NOT NULL,
+                logo_url TEXT,
+                primary_color TEXT NOT NULL,
+                accent_color TEXT NOT NULL,
+                support_email TEXT,
+                support_url TEXT,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Branding set for exactly `domain`
+    pub async fn get(&self, domain: &str) -> Result<Option<Branding>> {
+        let row = sqlx::query("SELECT * FROM branding WHERE domain = ?")
+            .bind(domain.to_lowercase())
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(branding_from_row).transpose()
+    }
+
+    /// Branding shown for a domain or host name
+    ///
+    /// `mail.example.com` uses the branding of `example.com` unless it has
+    /// its own; without any, the defaults are returned.
+    pub async fn resolve(&self, domain: &str) -> Result<Branding> {
+        let domain = domain.trim().trim_end_matches('.').to_lowercase();
+        let mut candidate = domain.as_str();
+        loop {
+            if let Some(branding) = self.get(candidate).await? {
+                return Ok(branding);
+            }
+            match candidate.split_once('.') {
+                // Stop before bare top-level domains
+                Some((_, parent)) if parent.contains('.') => candidate = parent,
+                _ => return Ok(Branding::default_for(&domain)),
+            }
+        }
+    }
+
+    /// Branding of every domain that has one
+    pub async fn list(&self) -> Result<Vec<Branding>> {
+        let rows = sqlx::query("SELECT * FROM branding ORDER BY domain")
+            .fetch_all(&self.db)
+            .await?;
+        rows.iter().map(branding_from_row).collect()
+    }
+
+    /// Change the branding of `domain`, starting from the defaults if it
+    /// has none yet
+    pub async fn set(&self, domain: &str, update: &BrandingUpdate) -> Result<Branding> {
+        self.set_at(domain, update, Utc::now()).await
+    }
+
+    async fn set_at(
+        &self,
+        domain: &str,
+        update: &BrandingUpdate,
+        at: DateTime<Utc>,
+    ) -> Result<Branding> {
+        let mut branding = match self.get(domain).await? {
+            Some(branding) => branding,
+            None => Branding::default_for(domain),
+        };
+        branding.apply(update);
+        branding.validate().map_err(|e| anyhow!(e))?;
+        branding.updated_at = Some(at);
+
+        sqlx::query(
+            r#"
+            INSERT INTO branding
+                (domain, product_name, logo_url, primary_color, accent_color,
+                 support_email, support_url, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(domain) DO UPDATE SET
+                product_name = excluded.product_name,
+                logo_url = excluded.logo_url,
+                primary_color = excluded.primary_color,
+                accent_color = excluded.accent_color,
+                support_email = excluded.support_email,
+                support_url = excluded.support_url,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&branding.domain)
+        .bind(&branding.product_name)
+        .bind(&branding.logo_url)
+        .bind(&branding.primary_color)
+        .bind(&branding.accent_color)
+        .bind(&branding.support_email)
+        .bind(&branding.support_url)
+        .bind(at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(branding)
+    }
+
+    /// Remove the branding of `domain`; returns whether it had one
+    pub async fn delete(&self, domain: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM branding WHERE domain = ?")
+            .bind(domain.to_lowercase())
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn branding_from_row(row: &SqliteRow) -> Result<Branding> {
+    Ok(Branding {
+        domain: row.get("domain"),
+        product_name: row.get("product_name"),
+        logo_url: row.get("logo_url"),
+        primary_color: row.get("primary_color"),
+        accent_color: row.get("accent_color"),
+        support_email: row.get("support_email"),
+        support_url: row.get("support_url"),
+        updated_at: Some(parse_time(row.get("updated_at"))?),
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn manager() -> BrandingManager {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = BrandingManager::new(db);
+        manager.init_db().await.unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_set_get_delete() {
+        let manager = manager().await;
+        assert!(manager.get("example.com").await.unwrap().is_none());
+
+        let branding = manager
+            .set(
+                "Example.com",
+                &BrandingUpdate {
+                    product_name: Some("Example Mail".to_string()),
+                    logo_url: Some("https://example.com/logo.svg".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(branding.domain, "example.com");
+        assert!(!branding.is_default());
+
+        // Later changes keep the fields they don't mention
+        let branding = manager
+            .set(
+                "example.com",
+                &BrandingUpdate {
+                    accent_color: Some("#00aa00".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(branding.product_name, "Example Mail");
+        assert_eq!(branding.accent_color, "#00aa00");
+        assert_eq!(manager.get("EXAMPLE.COM").await.unwrap(), Some(branding));
+
+        let invalid = BrandingUpdate {
+            primary_color: Some("blue".to_string()),
+            ..Default::default()
+        };
+        assert!(manager.set("example.com", &invalid).await.is_err());
+        assert!(manager.set("example.org", &invalid).await.is_err());
+        assert_eq!(manager.list().await.unwrap().len(), 1);
+
+        assert!(manager.delete("example.com").await.unwrap());
+        assert!(!manager.delete("example.com").await.unwrap());
+        assert!(manager.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_parent_domains() {
+        let manager = manager().await;
+        let update = |name: &str| BrandingUpdate {
+            product_name: Some(name.to_string()),
+            ..Default::default()
+        };
+        manager
+            .set("example.com", &update("Example"))
+            .await
+            .unwrap();
+        manager
+            .set("eu.example.com", &update("Example EU"))
+            .await
+            .unwrap();
+
+        let resolve = |domain: &'static str| {
+            let manager = &manager;
+            async move { manager.resolve(domain).await.unwrap().product_name }
+        };
+        assert_eq!(resolve("example.com").await, "Example");
+        assert_eq!(resolve("mail.example.com.").await, "Example");
+        assert_eq!(resolve("imap.eu.example.com").await, "Example EU");
+
+        let fallback = manager.resolve("Other.org").await.unwrap();
+        assert!(fallback.is_default());
+        assert_eq!(fallback.domain, "other.org");
+        assert_eq!(fallback.product_name, DEFAULT_PRODUCT_NAME);
+    }
+}
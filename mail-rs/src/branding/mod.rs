@@ -0,0 +1,13 @@
+//! Per-domain branding of system emails, web pages and autoconfig
+//!
+//! Each hosted organization can set a product name, logo, colors and
+//! support contact for its domain. Notification emails, the admin login and
+//! dashboard pages and the autoconfig document pick the branding of the
+//! user's domain, or of the nearest parent domain that has one, and fall
+//! back to the server's defaults otherwise.
+
+pub mod manager;
+pub mod types;
+
+pub use manager::BrandingManager;
+pub use types::*;
@@ -0,0 +1,226 @@
+//! Branding types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Product name used where a domain has no branding
+pub const DEFAULT_PRODUCT_NAME: &str = "GK Mail";
+
+/// Main color of buttons and headers
+pub const DEFAULT_PRIMARY_COLOR: &str = "#2563eb";
+
+/// Color of links and highlights
+pub const DEFAULT_ACCENT_COLOR: &str = "#4f46e5";
+
+/// Longest product name
+pub const MAX_PRODUCT_NAME_LEN: usize = 64;
+
+/// Branding of one domain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Branding {
+    pub domain: String,
+    pub product_name: String,
+    pub logo_url: Option<String>,
+    /// `#rrggbb`
+    pub primary_color: String,
+    /// `#rrggbb`
+    pub accent_color: String,
+    pub support_email: Option<String>,
+    pub support_url: Option<String>,
+    /// When the branding was last changed; `None` for the defaults
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl Branding {
+    /// Default branding, shown for `domain` when it has none of its own
+    pub fn default_for(domain: &str) -> Self {
+        Self {
+            domain: domain.to_lowercase(),
+            product_name: DEFAULT_PRODUCT_NAME.to_string(),
+            logo_url: None,
+            primary_color: DEFAULT_PRIMARY_COLOR.to_string(),
+            accent_color: DEFAULT_ACCENT_COLOR.to_string(),
+            support_email: None,
+            support_url: None,
+            updated_at: None,
+        }
+    }
+
+    /// Whether these are the defaults rather than settings of the domain
+    pub fn is_default(&self) -> bool {
+        self.updated_at.is_none()
+    }
+
+    /// Apply the fields set in `update`; empty strings clear optional fields
+    pub fn apply(&mut self, update: &BrandingUpdate) {
+        let optional = |value: &Option<String>, current: &mut Option<String>| {
+            if let Some(value) = value {
+                let value = value.trim();
+                *current = (!value.is_empty()).then(|| value.to_string());
+            }
+        };
+
+        if let Some(product_name) = &update.product_name {
+            self.product_name = product_name.trim().to_string();
+        }
+        if let Some(color) = &update.primary_color {
+            self.primary_color = color.trim().to_lowercase();
+        }
+        if let Some(color) = &update.accent_color {
+            self.accent_color = color.trim().to_lowercase();
+        }
+        optional(&update.logo_url, &mut self.logo_url);
+        optional(&update.support_email, &mut self.support_email);
+        optional(&update.support_url, &mut self.support_url);
+    }
+
+    /// Check the fields before saving
+    ///
+    /// Values end up in HTML attributes, CSS and mail headers, so colors
+    /// must be plain hex and URLs plain http(s).
+    pub fn validate(&self) -> Result<(), String> {
+        if self.product_name.is_empty() || self.product_name.len() > MAX_PRODUCT_NAME_LEN {
+            return Err(format!(
+                "product_name must be 1 to {} characters",
+                MAX_PRODUCT_NAME_LEN
+            ));
+        }
+        if self
+            .product_name
+            .chars()
+            .any(|c| c.is_control() || "<>\"".contains(c))
+        {
+            return Err("product_name contains invalid characters".to_string());
+        }
+        for (field, color) in [
+            ("primary_color", &self.primary_color),
+            ("accent_color", &self.accent_color),
+        ] {
+            if !is_hex_color(color) {
+                return Err(format!("{} must be a color like #2563eb", field));
+            }
+        }
+        for (field, url) in [
+            ("logo_url", &self.logo_url),
+            ("support_url", &self.support_url),
+        ] {
+            if url.as_deref().is_some_and(|url| !is_web_url(url)) {
+                return Err(format!("{} must be an http or https URL", field));
+            }
+        }
+        if let Some(email) = &self.support_email {
+            let valid = email
+                .split_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'))
+                && !email
+                    .chars()
+                    .any(|c| c.is_whitespace() || "<>\"".contains(c));
+            if !valid {
+                return Err("support_email must be an email address".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Support contact for footers, e.g. `help@example.com, https://help`
+    pub fn support_contact(&self) -> Option<String> {
+        let contact: Vec<&str> = [self.support_email.as_deref(), self.support_url.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect();
+        (!contact.is_empty()).then(|| contact.join(", "))
+    }
+}
+
+/// Change to a domain's branding; unset fields are left as they are
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BrandingUpdate {
+    pub product_name: Option<String>,
+    pub logo_url: Option<String>,
+    pub primary_color: Option<String>,
+    pub accent_color: Option<String>,
+    pub support_email: Option<String>,
+    pub support_url: Option<String>,
+}
+
+fn is_hex_color(value: &str) -> bool {
+    value.len() == 7 && value.starts_with('#') && value[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_web_url(value: &str) -> bool {
+    let rest = value
+        .strip_prefix("https://")
+        .or_else(|| value.strip_prefix("http://"));
+    rest.is_some_and(|rest| {
+        !rest.is_empty()
+            && !rest.starts_with('/')
+            && !rest
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || "<>\"'`".contains(c))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_and_validate() {
+        let mut branding = Branding::default_for("Example.COM");
+        assert_eq!(branding.domain, "example.com");
+        assert!(branding.validate().is_ok());
+        assert_eq!(branding.support_contact(), None);
+
+        branding.apply(&BrandingUpdate {
+            product_name: Some(" Acme Mail ".to_string()),
+            primary_color: Some("#FF0000".to_string()),
+            support_email: Some("help@acme.example".to_string()),
+            support_url: Some("https://help.acme.example".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(branding.product_name, "Acme Mail");
+        assert_eq!(branding.primary_color, "#ff0000");
+        assert!(branding.validate().is_ok());
+        assert_eq!(
+            branding.support_contact().as_deref(),
+            Some("help@acme.example, https://help.acme.example")
+        );
+
+        branding.apply(&BrandingUpdate {
+            support_url: Some(String::new()),
+            ..Default::default()
+        });
+        assert_eq!(branding.support_url, None);
+
+        for update in [
+            BrandingUpdate {
+                product_name: Some(String::new()),
+                ..Default::default()
+            },
+            BrandingUpdate {
+                product_name: Some("<script>".to_string()),
+                ..Default::default()
+            },
+            BrandingUpdate {
+                accent_color: Some("red; background: url(x)".to_string()),
+                ..Default::default()
+            },
+            BrandingUpdate {
+                logo_url: Some("javascript:alert(1)".to_string()),
+                ..Default::default()
+            },
+            BrandingUpdate {
+                logo_url: Some("https://x\" onerror=\"y".to_string()),
+                ..Default::default()
+            },
+            BrandingUpdate {
+                support_email: Some("nobody".to_string()),
+                ..Default::default()
+            },
+        ] {
+            let mut invalid = branding.clone();
+            invalid.apply(&update);
+            assert!(invalid.validate().is_err(), "{:?}", update);
+        }
+    }
+}
@@ -90,7 +90,7 @@ pub fn parse_ics(ics: &str, event_id: &str, calendar_id: &str) -> Result<Calenda
 }
 
 /// Parse iCalendar datetime format
-fn parse_ical_datetime(value: &str) -> Option<DateTime<Utc>> {
+pub fn parse_ical_datetime(value: &str) -> Option<DateTime<Utc>> {
     // Handle formats like: 20240115T100000Z or 20240115T100000
     let value = value.trim_end_matches('Z');
 
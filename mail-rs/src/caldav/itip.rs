@@ -0,0 +1,519 @@
+//! Calendar invitations (iTIP, RFC 5546) sent by mail (iMIP, RFC 6047)
+//!
+//! A `text/calendar` part with a `METHOD` invites its recipient to an
+//! event (`REQUEST`), withdraws an invitation (`CANCEL`) or answers one the
+//! recipient sent (`REPLY`). Attendees answer with [`reply_message`].
+
+use chrono::{DateTime, Utc};
+use mail_parser::MessageParser;
+
+use super::calendar::{format_ical_datetime, parse_ical_datetime};
+use super::types::Invitation;
+use crate::mime::{BodyPart, MessageBuilder, MimeEntity};
+
+/// Longest content line before folding (RFC 5545 §3.1)
+const MAX_LINE: usize = 75;
+
+/// iTIP method of a calendar object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItipMethod {
+    Request,
+    Reply,
+    Cancel,
+}
+
+impl ItipMethod {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "REQUEST" => Some(Self::Request),
+            "REPLY" => Some(Self::Reply),
+            "CANCEL" => Some(Self::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Answer of an attendee
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartStat {
+    Accepted,
+    Declined,
+    Tentative,
+}
+
+impl PartStat {
+    /// `PARTSTAT` parameter value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accepted => "ACCEPTED",
+            Self::Declined => "DECLINED",
+            Self::Tentative => "TENTATIVE",
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            Self::Accepted => "Accepted",
+            Self::Declined => "Declined",
+            Self::Tentative => "Tentatively accepted",
+        }
+    }
+}
+
+/// What a received iTIP message changed
+#[derive(Debug, Clone)]
+pub enum ItipOutcome {
+    /// Invitation recorded, its event added or updated unless declined
+    Invited(Invitation),
+    /// Invitation withdrawn and its event removed
+    Cancelled(Invitation),
+    /// Answer of an attendee recorded in the organizer's event
+    Replied { attendee: String, partstat: String },
+    /// Nothing changed, and why
+    Ignored(&'static str),
+}
+
+/// Attendee of an event
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attendee {
+    /// Lowercase address
+    pub email: String,
+    pub partstat: Option<String>,
+}
+
+/// The first VEVENT of a calendar object
+#[derive(Debug, Clone, PartialEq)]
+pub struct VEvent {
+    pub uid: String,
+    pub sequence: i64,
+    pub summary: Option<String>,
+    pub dtstart: Option<DateTime<Utc>>,
+    pub dtend: Option<DateTime<Utc>>,
+    /// Lowercase address
+    pub organizer: Option<String>,
+    pub attendees: Vec<Attendee>,
+}
+
+impl VEvent {
+    pub fn parse(ics: &str) -> Option<Self> {
+        let mut event: Option<VEvent> = None;
+        // Components nested in the VEVENT, such as VALARM
+        let mut nested = 0;
+        for line in unfold(ics) {
+            let (name, params, value) = property(&line);
+            match (name.as_str(), event.as_mut()) {
+                ("BEGIN", None) if value.eq_ignore_ascii_case("VEVENT") => {
+                    event = Some(VEvent {
+                        uid: String::new(),
+                        sequence: 0,
+                        summary: None,
+                        dtstart: None,
+                        dtend: None,
+                        organizer: None,
+                        attendees: Vec::new(),
+                    });
+                }
+                ("BEGIN", Some(_)) => nested += 1,
+                ("END", Some(_)) if nested > 0 => nested -= 1,
+                ("END", Some(_)) => break,
+                (_, Some(_)) if nested > 0 => {}
+                ("UID", Some(event)) => event.uid = value.to_string(),
+                ("SEQUENCE", Some(event)) => event.sequence = value.trim().parse().unwrap_or(0),
+                ("SUMMARY", Some(event)) => event.summary = Some(unescape(value)),
+                ("DTSTART", Some(event)) => event.dtstart = parse_ical_datetime(value),
+                ("DTEND", Some(event)) => event.dtend = parse_ical_datetime(value),
+                ("ORGANIZER", Some(event)) => event.organizer = mailto(value),
+                ("ATTENDEE", Some(event)) => {
+                    if let Some(email) = mailto(value) {
+                        let partstat = param(&params, "PARTSTAT").map(|v| v.to_ascii_uppercase());
+                        event.attendees.push(Attendee { email, partstat });
+                    }
+                }
+                _ => {}
+            }
+        }
+        event.filter(|event| !event.uid.is_empty())
+    }
+
+    /// The attendee with address `email`
+    pub fn attendee(&self, email: &str) -> Option<&Attendee> {
+        self.attendees
+            .iter()
+            .find(|attendee| attendee.email.eq_ignore_ascii_case(email))
+    }
+
+    /// Whether `email` organizes the event
+    pub fn is_organizer(&self, email: &str) -> bool {
+        self.organizer
+            .as_deref()
+            .is_some_and(|organizer| organizer.eq_ignore_ascii_case(email))
+    }
+}
+
+/// A calendar object with an iTIP method
+#[derive(Debug, Clone, PartialEq)]
+pub struct Itip {
+    pub method: ItipMethod,
+    pub event: VEvent,
+    /// The calendar object as received
+    pub ics: String,
+}
+
+impl Itip {
+    pub fn parse(ics: &str) -> Option<Self> {
+        let method = unfold(ics).iter().find_map(|line| {
+            let (name, _, value) = property(line);
+            if name == "METHOD" {
+                ItipMethod::parse(value)
+            } else {
+                None
+            }
+        })?;
+        Some(Self {
+            method,
+            event: VEvent::parse(ics)?,
+            ics: ics.to_string(),
+        })
+    }
+
+    /// The calendar object to store, without its method
+    pub fn calendar_object(&self) -> String {
+        fold(
+            unfold(&self.ics)
+                .into_iter()
+                .filter(|line| property(line).0 != "METHOD"),
+        )
+    }
+}
+
+/// The first iTIP calendar part of `message`
+pub fn find_in_message(message: &[u8]) -> Option<Itip> {
+    fn find(entity: &MimeEntity) -> Option<Itip> {
+        if matches!(
+            entity.content_type.as_str(),
+            "text/calendar" | "application/ics"
+        ) {
+            if let Some(itip) = Itip::parse(&entity.text()) {
+                return Some(itip);
+            }
+        }
+        entity.parts.iter().find_map(find)
+    }
+    find(&MimeEntity::parse(message))
+}
+
+/// Address of the From header, which must match the organizer of
+/// requests and cancellations and the attendee of replies
+pub fn sender(message: &[u8]) -> Option<String> {
+    let parsed = MessageParser::new().parse_headers(message)?;
+    let address = parsed.from()?.first()?.address()?;
+    Some(address.to_lowercase())
+}
+
+/// `ics` with the `PARTSTAT` of attendee `email` set to `partstat`
+pub fn set_partstat(ics: &str, email: &str, partstat: &str) -> String {
+    fold(unfold(ics).into_iter().map(|line| {
+        let (name, params, value) = property(&line);
+        if name != "ATTENDEE" || !mailto(value).is_some_and(|a| a.eq_ignore_ascii_case(email)) {
+            return line;
+        }
+        let mut attendee = String::from("ATTENDEE");
+        for (key, param) in params
+            .iter()
+            .filter(|(key, _)| key != "PARTSTAT" && key != "RSVP")
+        {
+            attendee.push_str(&format!(";{}={}", key, param));
+        }
+        format!("{};PARTSTAT={}:{}", attendee, partstat, value)
+    }))
+}
+
+/// The `METHOD:REPLY` of attendee `email` to `invitation`
+pub fn reply_ics(invitation: &VEvent, email: &str, partstat: PartStat) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//mail-rs//CalDAV//EN".to_string(),
+        "METHOD:REPLY".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}", invitation.uid),
+        format!("DTSTAMP:{}", format_ical_datetime(&Utc::now())),
+        format!("SEQUENCE:{}", invitation.sequence),
+    ];
+    if let Some(organizer) = &invitation.organizer {
+        lines.push(format!("ORGANIZER:mailto:{}", organizer));
+    }
+    lines.push(format!(
+        "ATTENDEE;PARTSTAT={}:mailto:{}",
+        partstat.as_str(),
+        email.to_lowercase()
+    ));
+    if let Some(summary) = &invitation.summary {
+        lines.push(format!("SUMMARY:{}", escape(summary)));
+    }
+    if let Some(dtstart) = &invitation.dtstart {
+        lines.push(format!("DTSTART:{}", format_ical_datetime(dtstart)));
+    }
+    if let Some(dtend) = &invitation.dtend {
+        lines.push(format!("DTEND:{}", format_ical_datetime(dtend)));
+    }
+    lines.push("END:VEVENT".to_string());
+    lines.push("END:VCALENDAR".to_string());
+    fold(lines)
+}
+
+/// The mail answering `invitation` from attendee `email` to its organizer
+pub fn reply_message(invitation: &VEvent, email: &str, partstat: PartStat) -> Option<Vec<u8>> {
+    let organizer = invitation.organizer.as_deref()?;
+    let summary = invitation.summary.as_deref().unwrap_or("(no title)");
+    Some(
+        MessageBuilder::new()
+            .from(email)
+            .to(organizer)
+            .subject(&format!("{}: {}", partstat.verb(), summary))
+            .text(&format!(
+                "{} has {} the invitation to \"{}\".",
+                email,
+                partstat.verb().to_lowercase(),
+                summary
+            ))
+            .part(BodyPart::new(
+                "text/calendar; charset=utf-8; method=REPLY",
+                reply_ics(invitation, email, partstat).into_bytes(),
+            ))
+            .build(),
+    )
+}
+
+/// Content lines with folded lines joined
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push(line.trim_end_matches('\r').to_string()),
+        }
+    }
+    lines
+}
+
+/// Content lines folded at 75 octets, CRLF terminated
+fn fold(lines: impl IntoIterator<Item = String>) -> String {
+    let mut out = String::new();
+    for line in lines {
+        let mut width = 0;
+        for c in line.chars() {
+            if width + c.len_utf8() > MAX_LINE {
+                out.push_str("\r\n ");
+                width = 1;
+            }
+            out.push(c);
+            width += c.len_utf8();
+        }
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Uppercase name, parameters and value of a content line
+fn property(line: &str) -> (String, Vec<(String, String)>, &str) {
+    // The value starts at the first colon outside a quoted parameter
+    let mut quoted = false;
+    let split = line
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c == ':' && !quoted
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(line.len());
+    let (head, value) = (&line[..split], line.get(split + 1..).unwrap_or(""));
+
+    let mut parts = head.split(';');
+    let name = parts.next().unwrap_or("").trim().to_ascii_uppercase();
+    let params = parts
+        .filter_map(|part| part.split_once('='))
+        .map(|(key, value)| (key.trim().to_ascii_uppercase(), value.to_string()))
+        .collect();
+    (name, params, value)
+}
+
+fn param<'p>(params: &'p [(String, String)], name: &str) -> Option<&'p str> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// Lowercase address of a `mailto:` URI
+fn mailto(value: &str) -> Option<String> {
+    let value = value.trim();
+    let address = value
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("mailto:"))
+        .map(|_| &value[7..])
+        .unwrap_or(value);
+    address.contains('@').then(|| address.to_lowercase())
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("\\n", "\n")
+        .replace("\\N", "\n")
+        .replace("\\,", ",")
+        .replace("\\;", ";")
+        .replace("\\\\", "\\")
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn request(sequence: i64) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\n\
+             VERSION:2.0\r\n\
+             PRODID:-//Example//EN\r\n\
+             METHOD:REQUEST\r\n\
+             BEGIN:VEVENT\r\n\
+             UID:meeting-1@example.com\r\n\
+             SEQUENCE:{sequence}\r\n\
+             DTSTAMP:20250101T090000Z\r\n\
+             DTSTART:20250115T100000Z\r\n\
+             DTEND:20250115T110000Z\r\n\
+             SUMMARY:Planning\\, Q1\r\n\
+             ORGANIZER;CN=\"Alice: Lead\":mailto:Alice@example.com\r\n\
+             ATTENDEE;CN=Bob;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE:\r\n \
+             mailto:bob@example.org\r\n\
+             ATTENDEE;PARTSTAT=ACCEPTED:mailto:alice@example.com\r\n\
+             BEGIN:VALARM\r\n\
+             ACTION:DISPLAY\r\n\
+             SUMMARY:Reminder\r\n\
+             END:VALARM\r\n\
+             END:VEVENT\r\n\
+             END:VCALENDAR\r\n"
+        )
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let itip = Itip::parse(&request(2)).unwrap();
+        assert_eq!(itip.method, ItipMethod::Request);
+        let event = &itip.event;
+        assert_eq!(event.uid, "meeting-1@example.com");
+        assert_eq!(event.sequence, 2);
+        assert_eq!(event.summary.as_deref(), Some("Planning, Q1"));
+        assert_eq!(event.organizer.as_deref(), Some("alice@example.com"));
+        assert_eq!(
+            event.dtstart.map(|dt| format_ical_datetime(&dt)).as_deref(),
+            Some("20250115T100000Z")
+        );
+        let bob = event.attendee("Bob@example.org").unwrap();
+        assert_eq!(bob.partstat.as_deref(), Some("NEEDS-ACTION"));
+        assert!(event.is_organizer("alice@example.com"));
+
+        let stored = itip.calendar_object();
+        assert!(!stored.contains("METHOD"));
+        assert!(stored.contains("UID:meeting-1@example.com"));
+
+        // Not an iTIP object without a method
+        assert!(Itip::parse(&stored).is_none());
+        assert!(VEvent::parse(&stored).is_some());
+    }
+
+    #[test]
+    fn test_find_in_message() {
+        let message = format!(
+            "From: Alice <alice@example.com>\r\n\
+             To: bob@example.org\r\n\
+             Subject: Invitation\r\n\
+             Content-Type: multipart/alternative; boundary=\"b\"\r\n\r\n\
+             --b\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             You are invited\r\n\
+             --b\r\n\
+             Content-Type: text/calendar; charset=utf-8; method=REQUEST\r\n\r\n\
+             {}\r\n\
+             --b--\r\n",
+            request(0)
+        );
+        let itip = find_in_message(message.as_bytes()).unwrap();
+        assert_eq!(itip.event.uid, "meeting-1@example.com");
+        assert_eq!(
+            sender(message.as_bytes()).as_deref(),
+            Some("alice@example.com")
+        );
+
+        assert!(find_in_message(b"Subject: Hello\r\n\r\nNo invitation\r\n").is_none());
+    }
+
+    #[test]
+    fn test_set_partstat() {
+        let updated = set_partstat(&request(0), "bob@example.org", "ACCEPTED");
+        let event = VEvent::parse(&updated).unwrap();
+        assert_eq!(
+            event
+                .attendee("bob@example.org")
+                .unwrap()
+                .partstat
+                .as_deref(),
+            Some("ACCEPTED")
+        );
+        assert!(updated.contains("CN=Bob;ROLE=REQ-PARTICIPANT;PARTSTAT=ACCEPTED"));
+        assert!(!updated.contains("RSVP"));
+        // Others untouched
+        assert_eq!(
+            event
+                .attendee("alice@example.com")
+                .unwrap()
+                .partstat
+                .as_deref(),
+            Some("ACCEPTED")
+        );
+    }
+
+    #[test]
+    fn test_reply_message() {
+        let event = Itip::parse(&request(3)).unwrap().event;
+        let reply = Itip::parse(&reply_ics(&event, "bob@example.org", PartStat::Declined)).unwrap();
+        assert_eq!(reply.method, ItipMethod::Reply);
+        assert_eq!(reply.event.uid, event.uid);
+        assert_eq!(reply.event.sequence, 3);
+        assert_eq!(reply.event.summary, event.summary);
+        assert_eq!(
+            reply.event.attendees,
+            vec![Attendee {
+                email: "bob@example.org".to_string(),
+                partstat: Some("DECLINED".to_string()),
+            }]
+        );
+
+        let message = reply_message(&event, "bob@example.org", PartStat::Declined).unwrap();
+        let text = String::from_utf8_lossy(&message);
+        assert!(text.contains("To: alice@example.com\r\n"));
+        assert!(text.contains("Subject: Declined: Planning, Q1\r\n"));
+        assert!(text.contains("method=REPLY"));
+        let found = find_in_message(&message).unwrap();
+        assert_eq!(found.method, ItipMethod::Reply);
+        assert_eq!(sender(&message).as_deref(), Some("bob@example.org"));
+    }
+
+    #[test]
+    fn test_fold() {
+        let long = format!("DESCRIPTION:{}", "x".repeat(100));
+        let folded = fold(vec![long.clone()]);
+        assert!(folded.lines().all(|line| line.len() <= MAX_LINE));
+        assert_eq!(unfold(&folded), vec![long]);
+    }
+}
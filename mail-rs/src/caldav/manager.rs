@@ -2,13 +2,14 @@
 //!
 //! Provides full management of calendars, events, address books, and contacts.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use sqlx::{FromRow, SqlitePool};
 use uuid::Uuid;
 
 use super::calendar::{create_ics, parse_ics};
 use super::contacts::{create_vcf, parse_vcf};
+use super::itip::{reply_message, set_partstat, Itip, ItipMethod, ItipOutcome, PartStat, VEvent};
 use super::types::*;
 
 /// CalDAV manager
@@ -41,6 +42,23 @@ struct EventRow {
     updated_at: String,
 }
 
+#[derive(FromRow)]
+struct InvitationRow {
+    id: String,
+    owner_email: String,
+    uid: String,
+    organizer: String,
+    summary: Option<String>,
+    dtstart: Option<String>,
+    dtend: Option<String>,
+    sequence: i64,
+    status: String,
+    event_id: Option<String>,
+    ics_data: String,
+    received_at: String,
+    updated_at: String,
+}
+
 #[derive(FromRow)]
 struct AddressBookRow {
     id: String,
@@ -70,6 +88,13 @@ impl CalDavManager {
         Self { db }
     }
 
+    /// Open the database at `database_url` and create the tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
     /// Initialize database tables
     pub async fn init_db(&self) -> Result<()> {
         // Calendars table
@@ -110,6 +135,30 @@ impl CalDavManager {
         .execute(&self.db)
         .await?;
 
+        // Calendar invitations received by mail
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS calendar_invitations (
+                id TEXT PRIMARY KEY,
+                owner_email TEXT NOT NULL,
+                uid TEXT NOT NULL,
+                organizer TEXT NOT NULL,
+                summary TEXT,
+                dtstart TEXT,
+                dtend TEXT,
+                sequence INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                event_id TEXT,
+                ics_data TEXT NOT NULL,
+                received_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                UNIQUE (owner_email, uid)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
         // Address books table
         sqlx::query(
             r#"
@@ -429,6 +478,298 @@ impl CalDavManager {
         Ok(())
     }
 
+    /// Replace the iCalendar data of `event`
+    async fn replace_event_ics(&self, event: &CalendarEvent, ics_data: &str) -> Result<CalendarEvent> {
+        let parsed = parse_ics(ics_data, &event.id, &event.calendar_id)?;
+        sqlx::query(
+            "UPDATE calendar_events SET uid = ?, ics_data = ?, summary = ?, dtstart = ?, dtend = ?, etag = ?, updated_at = ? WHERE id = ?",
+        )
+        .bind(&parsed.uid)
+        .bind(&parsed.ics_data)
+        .bind(&parsed.summary)
+        .bind(parsed.dtstart.map(|d| d.to_rfc3339()))
+        .bind(parsed.dtend.map(|d| d.to_rfc3339()))
+        .bind(&parsed.etag)
+        .bind(Utc::now().to_rfc3339())
+        .bind(&event.id)
+        .execute(&self.db)
+        .await?;
+
+        self.update_calendar_sync_token(&event.calendar_id).await?;
+
+        Ok(parsed)
+    }
+
+    /// Event with iCalendar `uid` in any calendar of `email`
+    async fn find_event_by_uid(&self, email: &str, uid: &str) -> Result<Option<CalendarEvent>> {
+        let row: Option<EventRow> = sqlx::query_as(
+            "SELECT ce.* FROM calendar_events ce
+             JOIN calendars c ON ce.calendar_id = c.id
+             WHERE c.owner_email = ? AND ce.uid = ?
+             LIMIT 1",
+        )
+        .bind(email)
+        .bind(uid)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(row_to_event))
+    }
+
+    // ==================== INVITATION METHODS ====================
+
+    /// Apply an iTIP message that `email` received from `sender`
+    ///
+    /// Requests add or update the event in the user's calendar, cancellations
+    /// remove it, and replies record the attendee's answer in the event the
+    /// user organizes. Only the organizer may request or cancel and only an
+    /// invited attendee may reply.
+    pub async fn process_itip(&self, email: &str, sender: &str, itip: &Itip) -> Result<ItipOutcome> {
+        match itip.method {
+            ItipMethod::Request => self.receive_request(email, sender, itip).await,
+            ItipMethod::Cancel => self.receive_cancel(email, sender, itip).await,
+            ItipMethod::Reply => self.receive_reply(email, sender, itip).await,
+        }
+    }
+
+    async fn receive_request(&self, email: &str, sender: &str, itip: &Itip) -> Result<ItipOutcome> {
+        let event = &itip.event;
+        if !event.is_organizer(sender) {
+            return Ok(ItipOutcome::Ignored("sender is not the organizer"));
+        }
+        let existing = self.find_invitation_row(email, &event.uid).await?;
+        let status = match &existing {
+            Some(row) if !row.organizer.eq_ignore_ascii_case(sender) => {
+                return Ok(ItipOutcome::Ignored("organizer changed"));
+            }
+            Some(row) if event.sequence < row.sequence => {
+                return Ok(ItipOutcome::Ignored("outdated sequence"));
+            }
+            // A resent request keeps the answer; a new version needs one
+            Some(row) if event.sequence == row.sequence => match InvitationStatus::parse(&row.status) {
+                InvitationStatus::Cancelled => InvitationStatus::NeedsAction,
+                status => status,
+            },
+            _ => InvitationStatus::NeedsAction,
+        };
+
+        let event_id = existing.as_ref().and_then(|row| row.event_id.clone());
+        let event_id = match answer(status) {
+            Some(PartStat::Declined) => None,
+            partstat => {
+                let mut ics = itip.calendar_object();
+                if let Some(partstat) = partstat {
+                    ics = set_partstat(&ics, email, partstat.as_str());
+                }
+                Some(self.store_invited_event(email, event_id.as_deref(), &ics).await?.id)
+            }
+        };
+
+        let now = Utc::now().to_rfc3339();
+        let id = existing.map(|row| row.id).unwrap_or_else(|| Uuid::new_v4().to_string());
+        sqlx::query(
+            "INSERT INTO calendar_invitations (id, owner_email, uid, organizer, summary, dtstart, dtend, sequence, status, event_id, ics_data, received_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET summary = excluded.summary, dtstart = excluded.dtstart,
+                 dtend = excluded.dtend, sequence = excluded.sequence, status = excluded.status,
+                 event_id = excluded.event_id, ics_data = excluded.ics_data, updated_at = excluded.updated_at",
+        )
+        .bind(&id)
+        .bind(email)
+        .bind(&event.uid)
+        .bind(sender.to_lowercase())
+        .bind(&event.summary)
+        .bind(event.dtstart.map(|d| d.to_rfc3339()))
+        .bind(event.dtend.map(|d| d.to_rfc3339()))
+        .bind(event.sequence)
+        .bind(status.as_str())
+        .bind(&event_id)
+        .bind(&itip.ics)
+        .bind(&now)
+        .bind(&now)
+        .execute(&self.db)
+        .await?;
+
+        let invitation = self
+            .get_invitation(email, &id)
+            .await?
+            .ok_or_else(|| anyhow!("Invitation {} not stored", id))?;
+        Ok(ItipOutcome::Invited(invitation))
+    }
+
+    async fn receive_cancel(&self, email: &str, sender: &str, itip: &Itip) -> Result<ItipOutcome> {
+        let Some(row) = self.find_invitation_row(email, &itip.event.uid).await? else {
+            return Ok(ItipOutcome::Ignored("unknown invitation"));
+        };
+        if !row.organizer.eq_ignore_ascii_case(sender) {
+            return Ok(ItipOutcome::Ignored("sender is not the organizer"));
+        }
+        if itip.event.sequence < row.sequence {
+            return Ok(ItipOutcome::Ignored("outdated sequence"));
+        }
+
+        if let Some(event_id) = &row.event_id {
+            self.delete_event(event_id).await?;
+        }
+        let invitation = self
+            .set_invitation_status(&row.id, InvitationStatus::Cancelled, None)
+            .await?;
+        Ok(ItipOutcome::Cancelled(invitation))
+    }
+
+    async fn receive_reply(&self, email: &str, sender: &str, itip: &Itip) -> Result<ItipOutcome> {
+        let Some(partstat) = itip
+            .event
+            .attendee(sender)
+            .and_then(|attendee| attendee.partstat.clone())
+        else {
+            return Ok(ItipOutcome::Ignored("sender is not an answering attendee"));
+        };
+        let Some(event) = self.find_event_by_uid(email, &itip.event.uid).await? else {
+            return Ok(ItipOutcome::Ignored("unknown event"));
+        };
+        let organized = VEvent::parse(&event.ics_data);
+        if !organized.as_ref().is_some_and(|organized| organized.is_organizer(email)) {
+            return Ok(ItipOutcome::Ignored("user is not the organizer"));
+        }
+        if organized.as_ref().and_then(|organized| organized.attendee(sender)).is_none() {
+            return Ok(ItipOutcome::Ignored("sender was not invited"));
+        }
+
+        let ics = set_partstat(&event.ics_data, sender, &partstat);
+        self.replace_event_ics(&event, &ics).await?;
+        Ok(ItipOutcome::Replied {
+            attendee: sender.to_lowercase(),
+            partstat,
+        })
+    }
+
+    /// List the invitations of a user
+    pub async fn list_invitations(&self, email: &str) -> Result<Vec<Invitation>> {
+        let rows: Vec<InvitationRow> = sqlx::query_as(
+            "SELECT * FROM calendar_invitations WHERE owner_email = ? ORDER BY dtstart",
+        )
+        .bind(email)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.into_iter().map(row_to_invitation).collect())
+    }
+
+    /// Get an invitation of a user by ID
+    pub async fn get_invitation(&self, email: &str, id: &str) -> Result<Option<Invitation>> {
+        Ok(self.get_invitation_row(email, id).await?.map(row_to_invitation))
+    }
+
+    /// Answer an invitation: keep its event for accepted and tentative
+    /// answers, remove it for declined ones, and return the iTIP reply to
+    /// send to the organizer
+    pub async fn respond_invitation(
+        &self,
+        email: &str,
+        id: &str,
+        partstat: PartStat,
+    ) -> Result<Option<(Invitation, Vec<u8>)>> {
+        let Some(row) = self.get_invitation_row(email, id).await? else {
+            return Ok(None);
+        };
+        let itip = Itip::parse(&row.ics_data).ok_or_else(|| anyhow!("Invitation {} is not iTIP", id))?;
+        let reply = reply_message(&itip.event, email, partstat)
+            .ok_or_else(|| anyhow!("Invitation {} has no organizer", id))?;
+
+        let event_id = match partstat {
+            PartStat::Declined => {
+                if let Some(event_id) = &row.event_id {
+                    self.delete_event(event_id).await?;
+                }
+                None
+            }
+            PartStat::Accepted | PartStat::Tentative => {
+                let ics = set_partstat(&itip.calendar_object(), email, partstat.as_str());
+                Some(self.store_invited_event(email, row.event_id.as_deref(), &ics).await?.id)
+            }
+        };
+        let status = match partstat {
+            PartStat::Accepted => InvitationStatus::Accepted,
+            PartStat::Declined => InvitationStatus::Declined,
+            PartStat::Tentative => InvitationStatus::Tentative,
+        };
+        let invitation = self.set_invitation_status(&row.id, status, event_id).await?;
+        Ok(Some((invitation, reply)))
+    }
+
+    /// Update the event of an invitation, or add it to the user's first
+    /// calendar, created if they have none
+    async fn store_invited_event(&self, email: &str, event_id: Option<&str>, ics_data: &str) -> Result<CalendarEvent> {
+        if let Some(event) = match event_id {
+            Some(id) => self.get_event(id).await?,
+            None => None,
+        } {
+            return self.replace_event_ics(&event, ics_data).await;
+        }
+
+        let oldest: Option<CalendarRow> = sqlx::query_as(
+            "SELECT * FROM calendars WHERE owner_email = ? ORDER BY created_at LIMIT 1",
+        )
+        .bind(email)
+        .fetch_optional(&self.db)
+        .await?;
+        let calendar = match oldest {
+            Some(row) => row_to_calendar(row),
+            None => {
+                let req = CreateCalendarRequest {
+                    name: "Calendar".to_string(),
+                    color: None,
+                };
+                self.create_calendar(email, req).await?
+            }
+        };
+        self.import_ics(&calendar.id, ics_data).await
+    }
+
+    async fn set_invitation_status(
+        &self,
+        id: &str,
+        status: InvitationStatus,
+        event_id: Option<String>,
+    ) -> Result<Invitation> {
+        sqlx::query(
+            "UPDATE calendar_invitations SET status = ?, event_id = ?, updated_at = ? WHERE id = ?",
+        )
+        .bind(status.as_str())
+        .bind(&event_id)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+
+        let row: InvitationRow = sqlx::query_as("SELECT * FROM calendar_invitations WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(row_to_invitation(row))
+    }
+
+    async fn get_invitation_row(&self, email: &str, id: &str) -> Result<Option<InvitationRow>> {
+        Ok(sqlx::query_as(
+            "SELECT * FROM calendar_invitations WHERE owner_email = ? AND id = ?",
+        )
+        .bind(email)
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn find_invitation_row(&self, email: &str, uid: &str) -> Result<Option<InvitationRow>> {
+        Ok(sqlx::query_as(
+            "SELECT * FROM calendar_invitations WHERE owner_email = ? AND uid = ?",
+        )
+        .bind(email)
+        .bind(uid)
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
     // ==================== ADDRESS BOOK METHODS ====================
 
     /// List address books for a user
@@ -750,6 +1091,33 @@ fn row_to_event(row: EventRow) -> CalendarEvent {
     }
 }
 
+fn row_to_invitation(row: InvitationRow) -> Invitation {
+    Invitation {
+        id: row.id,
+        owner_email: row.owner_email,
+        uid: row.uid,
+        organizer: row.organizer,
+        summary: row.summary,
+        dtstart: row.dtstart.as_ref().map(|s| parse_datetime(s)),
+        dtend: row.dtend.as_ref().map(|s| parse_datetime(s)),
+        sequence: row.sequence,
+        status: InvitationStatus::parse(&row.status),
+        event_id: row.event_id,
+        received_at: parse_datetime(&row.received_at),
+        updated_at: parse_datetime(&row.updated_at),
+    }
+}
+
+/// Answer already given to an invitation
+fn answer(status: InvitationStatus) -> Option<PartStat> {
+    match status {
+        InvitationStatus::Accepted => Some(PartStat::Accepted),
+        InvitationStatus::Declined => Some(PartStat::Declined),
+        InvitationStatus::Tentative => Some(PartStat::Tentative),
+        InvitationStatus::NeedsAction | InvitationStatus::Cancelled => None,
+    }
+}
+
 fn row_to_addressbook(row: AddressBookRow) -> AddressBook {
     AddressBook {
         id: row.id,
@@ -794,3 +1162,162 @@ fn generate_etag(content: &str) -> String {
     Utc::now().timestamp().hash(&mut hasher);
     format!("\"{}\"", hasher.finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caldav::itip::tests::request;
+
+    const ORGANIZER: &str = "alice@example.com";
+    const ATTENDEE: &str = "bob@example.org";
+
+    fn itip(ics: &str) -> Itip {
+        Itip::parse(ics).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_respond_cancel() {
+        let manager = CalDavManager::connect("sqlite::memory:").await.unwrap();
+
+        // Only the organizer may invite
+        let outcome = manager
+            .process_itip(ATTENDEE, "mallory@example.net", &itip(&request(0)))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, ItipOutcome::Ignored(_)));
+
+        let ItipOutcome::Invited(invitation) = manager
+            .process_itip(ATTENDEE, ORGANIZER, &itip(&request(0)))
+            .await
+            .unwrap()
+        else {
+            panic!("request not applied");
+        };
+        assert_eq!(invitation.status, InvitationStatus::NeedsAction);
+        assert_eq!(invitation.organizer, ORGANIZER);
+        let event = manager
+            .get_event(invitation.event_id.as_deref().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.uid, "meeting-1@example.com");
+        assert!(!event.ics_data.contains("METHOD"));
+        assert_eq!(manager.list_calendars(ATTENDEE).await.unwrap().len(), 1);
+
+        let (accepted, reply) = manager
+            .respond_invitation(ATTENDEE, &invitation.id, PartStat::Accepted)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(accepted.status, InvitationStatus::Accepted);
+        assert_eq!(accepted.event_id, invitation.event_id);
+        assert!(String::from_utf8_lossy(&reply).contains("PARTSTAT=ACCEPTED"));
+        let event = manager.get_event(&event.id).await.unwrap().unwrap();
+        assert!(event.ics_data.contains("PARTSTAT=ACCEPTED"));
+
+        // A resent request keeps the answer, an older one is ignored
+        let ItipOutcome::Invited(resent) = manager
+            .process_itip(ATTENDEE, ORGANIZER, &itip(&request(0)))
+            .await
+            .unwrap()
+        else {
+            panic!("request not applied");
+        };
+        assert_eq!(resent.id, invitation.id);
+        assert_eq!(resent.status, InvitationStatus::Accepted);
+
+        let (declined, _) = manager
+            .respond_invitation(ATTENDEE, &invitation.id, PartStat::Declined)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(declined.event_id, None);
+        assert!(manager.get_event(&event.id).await.unwrap().is_none());
+
+        // A new version needs a new answer
+        let ItipOutcome::Invited(updated) = manager
+            .process_itip(ATTENDEE, ORGANIZER, &itip(&request(1)))
+            .await
+            .unwrap()
+        else {
+            panic!("request not applied");
+        };
+        assert_eq!(updated.status, InvitationStatus::NeedsAction);
+        assert!(updated.event_id.is_some());
+
+        let cancel = request(1).replace("METHOD:REQUEST", "METHOD:CANCEL");
+        let outcome = manager
+            .process_itip(ATTENDEE, ATTENDEE, &itip(&cancel))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, ItipOutcome::Ignored(_)));
+        let ItipOutcome::Cancelled(cancelled) = manager
+            .process_itip(ATTENDEE, ORGANIZER, &itip(&cancel))
+            .await
+            .unwrap()
+        else {
+            panic!("cancel not applied");
+        };
+        assert_eq!(cancelled.status, InvitationStatus::Cancelled);
+        assert!(manager
+            .get_event(updated.event_id.as_deref().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(manager
+            .get_invitation("other@example.org", &invitation.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reply_updates_organized_event() {
+        let manager = CalDavManager::connect("sqlite::memory:").await.unwrap();
+        let calendar = manager
+            .create_calendar(
+                ORGANIZER,
+                CreateCalendarRequest {
+                    name: "Work".to_string(),
+                    color: None,
+                },
+            )
+            .await
+            .unwrap();
+        let event = manager
+            .import_ics(&calendar.id, &itip(&request(0)).calendar_object())
+            .await
+            .unwrap();
+
+        let reply = |partstat: &str| {
+            request(0)
+                .replace("METHOD:REQUEST", "METHOD:REPLY")
+                .replace("PARTSTAT=NEEDS-ACTION", &format!("PARTSTAT={}", partstat))
+        };
+        // Only the attendee may answer for themselves
+        let outcome = manager
+            .process_itip(ORGANIZER, "mallory@example.net", &itip(&reply("ACCEPTED")))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, ItipOutcome::Ignored(_)));
+
+        let outcome = manager
+            .process_itip(ORGANIZER, ATTENDEE, &itip(&reply("TENTATIVE")))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, ItipOutcome::Replied { ref partstat, .. } if partstat == "TENTATIVE"));
+        let stored = manager.get_event(&event.id).await.unwrap().unwrap();
+        let parsed = VEvent::parse(&stored.ics_data).unwrap();
+        assert_eq!(
+            parsed.attendee(ATTENDEE).unwrap().partstat.as_deref(),
+            Some("TENTATIVE")
+        );
+
+        // Nothing to update for the attendee
+        let outcome = manager
+            .process_itip(ATTENDEE, ATTENDEE, &itip(&reply("ACCEPTED")))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, ItipOutcome::Ignored(_)));
+    }
+}
@@ -1,9 +1,11 @@
 //! CalDAV/CardDAV module
 //!
-//! Provides calendar and contacts synchronization via WebDAV extensions.
+//! Provides calendar and contacts synchronization via WebDAV extensions,
+//! and adds the events of calendar invitations received by mail.
 
 pub mod calendar;
 pub mod contacts;
+pub mod itip;
 pub mod manager;
 pub mod types;
 
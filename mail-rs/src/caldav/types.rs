@@ -148,3 +148,66 @@ pub struct ImportDataRequest {
     /// Raw data (ICS or VCF)
     pub data: String,
 }
+
+/// Calendar invitation received by mail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invitation {
+    /// Unique ID
+    pub id: String,
+    /// Invited user
+    pub owner_email: String,
+    /// iCalendar UID of the event
+    pub uid: String,
+    /// Organizer address, where replies go
+    pub organizer: String,
+    /// Event summary/title
+    pub summary: Option<String>,
+    /// Start time
+    pub dtstart: Option<DateTime<Utc>>,
+    /// End time
+    pub dtend: Option<DateTime<Utc>>,
+    /// iTIP sequence of the latest request
+    pub sequence: i64,
+    /// Answer of the user
+    pub status: InvitationStatus,
+    /// Event in the user's calendar, unless declined or cancelled
+    pub event_id: Option<String>,
+    /// Received timestamp
+    pub received_at: DateTime<Utc>,
+    /// Last update timestamp
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Answer to a calendar invitation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvitationStatus {
+    NeedsAction,
+    Accepted,
+    Declined,
+    Tentative,
+    /// Withdrawn by the organizer
+    Cancelled,
+}
+
+impl InvitationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NeedsAction => "needs_action",
+            Self::Accepted => "accepted",
+            Self::Declined => "declined",
+            Self::Tentative => "tentative",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "accepted" => Self::Accepted,
+            "declined" => Self::Declined,
+            "tentative" => Self::Tentative,
+            "cancelled" => Self::Cancelled,
+            _ => Self::NeedsAction,
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Fault injection for resilience testing
+//!
+//! Built only with the `chaos` feature; without it [`inject`] never fires
+//! and the admin API reports the facility as unavailable. Operators and CI
+//! set a probability (and optional delay) per [`Fault`] through
+//! `/api/admin/chaos`, then check that retries, queueing and degraded
+//! modes behave. Rules live in memory and are gone after a restart.
+
+use crate::error::{MailError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::warn;
+
+static INJECTOR: OnceLock<FaultInjector> = OnceLock::new();
+
+/// Failure that can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// Message delivery to a mailbox fails with a storage error
+    StorageWrite,
+    /// MX lookups fail as if the resolver timed out, after the delay
+    DnsTimeout,
+    /// Outbound SMTP connections wait for the delay before connecting
+    SlowSmtp,
+    /// Calls to the AI runtime are treated as HTTP 500 responses
+    AiRuntimeError,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 4] = [
+        Fault::StorageWrite,
+        Fault::DnsTimeout,
+        Fault::SlowSmtp,
+        Fault::AiRuntimeError,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StorageWrite => "storage_write",
+            Self::DnsTimeout => "dns_timeout",
+            Self::SlowSmtp => "slow_smtp",
+            Self::AiRuntimeError => "ai_runtime_error",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|fault| fault.as_str() == s)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How often a fault fires and how long it stalls the operation
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FaultRule {
+    /// Chance per operation, 0.0 - 1.0
+    pub probability: f64,
+    /// Wait before the fault takes effect
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+impl FaultRule {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.probability) {
+            return Err(MailError::Config(
+                "Fault probability must be between 0 and 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Rule and injection count of one fault
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FaultStatus {
+    pub fault: Fault,
+    /// `None` while the fault is off
+    pub rule: Option<FaultRule>,
+    /// Times the fault fired since start
+    pub injected: u64,
+}
+
+#[derive(Default)]
+struct State {
+    rules: BTreeMap<Fault, FaultRule>,
+    injected: BTreeMap<Fault, u64>,
+}
+
+/// Fault rules with per-fault injection counters
+#[derive(Default)]
+pub struct FaultInjector {
+    state: Mutex<State>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn `fault` on with `rule`, replacing any previous rule
+    pub fn set(&self, fault: Fault, rule: FaultRule) -> Result<()> {
+        rule.validate()?;
+        self.state.lock().unwrap().rules.insert(fault, rule);
+        Ok(())
+    }
+
+    /// Turn `fault` off
+    pub fn clear(&self, fault: Fault) {
+        self.state.lock().unwrap().rules.remove(&fault);
+    }
+
+    /// Turn every fault off
+    pub fn clear_all(&self) {
+        self.state.lock().unwrap().rules.clear();
+    }
+
+    /// Every fault with its rule and count
+    pub fn status(&self) -> Vec<FaultStatus> {
+        let state = self.state.lock().unwrap();
+        Fault::ALL
+            .into_iter()
+            .map(|fault| FaultStatus {
+                fault,
+                rule: state.rules.get(&fault).copied(),
+                injected: state.injected.get(&fault).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// Decide whether `fault` fires for this operation; returns its rule
+    /// when it does
+    pub fn roll(&self, fault: Fault) -> Option<FaultRule> {
+        let mut state = self.state.lock().unwrap();
+        let rule = *state.rules.get(&fault)?;
+        if rand::random::<f64>() >= rule.probability {
+            return None;
+        }
+        *state.injected.entry(fault).or_default() += 1;
+        Some(rule)
+    }
+}
+
+/// The process-wide injector, `None` without the `chaos` feature
+pub fn injector() -> Option<&'static FaultInjector> {
+    cfg!(feature = "chaos").then(|| INJECTOR.get_or_init(FaultInjector::new))
+}
+
+/// Whether `fault` fires for this operation, after waiting for its delay
+pub async fn inject(fault: Fault) -> bool {
+    let Some(rule) = injector().and_then(|injector| injector.roll(fault)) else {
+        return false;
+    };
+
+    warn!("Injecting {} fault", fault);
+    if rule.delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(rule.delay_ms)).await;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roll_follows_probability() {
+        let injector = FaultInjector::new();
+        assert!(injector.roll(Fault::StorageWrite).is_none());
+
+        let always = FaultRule {
+            probability: 1.0,
+            delay_ms: 5,
+        };
+        injector.set(Fault::StorageWrite, always).unwrap();
+        injector
+            .set(
+                Fault::DnsTimeout,
+                FaultRule {
+                    probability: 0.0,
+                    delay_ms: 0,
+                },
+            )
+            .unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(injector.roll(Fault::StorageWrite), Some(always));
+            assert!(injector.roll(Fault::DnsTimeout).is_none());
+        }
+
+        let status = injector.status();
+        assert_eq!(status.len(), Fault::ALL.len());
+        assert_eq!(status[0].injected, 3);
+        assert_eq!(status[1].injected, 0);
+        assert!(status[2].rule.is_none());
+
+        injector.clear(Fault::StorageWrite);
+        assert!(injector.roll(Fault::StorageWrite).is_none());
+        injector.clear_all();
+        assert!(injector.status().iter().all(|status| status.rule.is_none()));
+        // Counts survive turning faults off
+        assert_eq!(injector.status()[0].injected, 3);
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        let injector = FaultInjector::new();
+        let rule = FaultRule {
+            probability: 1.5,
+            delay_ms: 0,
+        };
+        assert!(injector.set(Fault::SlowSmtp, rule).is_err());
+        assert!(injector.status()[2].rule.is_none());
+
+        assert_eq!(
+            Fault::parse("ai_runtime_error"),
+            Some(Fault::AiRuntimeError)
+        );
+        assert_eq!(Fault::parse("disk_full"), None);
+    }
+}
@@ -1,5 +1,13 @@
+use crate::admin::mta_sts::MtaStsMode;
+use crate::antispam::dnsbl::{DnsblAction, DnsblStage};
+use crate::antivirus::VirusAction;
 use crate::error::Result;
+use crate::hooks::HookLimits;
+use crate::reporting::{ReportFormat, ReportPeriod};
+use crate::smtp::routing::RoutingRule;
+use crate::spam::delivery::{SpamBackend, DEFAULT_REJECT_THRESHOLD};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -10,6 +18,80 @@ pub struct Config {
     pub storage: StorageConfig,
     pub logging: LoggingConfig,
     pub authentication: AuthenticationConfig,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub submission: SubmissionConfig,
+    #[serde(default)]
+    pub migration: MigrationConfig,
+    #[serde(default)]
+    pub oauth: OAuthConfig,
+    #[serde(default)]
+    pub srs: SrsConfig,
+    #[serde(default)]
+    pub reporting: ReportingConfig,
+    #[serde(default)]
+    pub tls_reporting: TlsReportingConfig,
+    #[serde(default)]
+    pub residency: ResidencyConfig,
+    #[serde(default)]
+    pub impersonation: ImpersonationConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub billing: BillingConfig,
+    #[serde(default)]
+    pub aliases: AliasesConfig,
+    #[serde(default)]
+    pub delivery_budget: DeliveryBudgetConfig,
+    #[serde(default)]
+    pub footers: FootersConfig,
+    #[serde(default)]
+    pub sharing: SharingConfig,
+    #[serde(default)]
+    pub sieve: SieveConfig,
+    #[serde(default)]
+    pub spam: SpamFilterConfig,
+    #[serde(default)]
+    pub role_accounts: RoleAccountsConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub post_delivery: PostDeliveryConfig,
+    #[serde(default)]
+    pub login_anomaly: LoginAnomalyConfig,
+    #[serde(default)]
+    pub dnsbl: DnsblConfig,
+    #[serde(default)]
+    pub greylisting: GreylistingConfig,
+    #[serde(default)]
+    pub uribl: UriblConfig,
+    #[serde(default)]
+    pub dmarc_reports: DmarcReportsConfig,
+    #[serde(default)]
+    pub dkim_signing: DkimSigningConfig,
+    #[serde(default)]
+    pub openpgp: OpenPgpConfig,
+    #[serde(default)]
+    pub calendar_invitations: CalendarInvitationsConfig,
+    #[serde(default)]
+    pub mta_sts: MtaStsConfig,
+    #[serde(default)]
+    pub bimi: BimiConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub antivirus: AntivirusConfig,
+    #[serde(default)]
+    pub workers: WorkersConfig,
+    #[serde(default)]
+    pub recovery: RecoveryConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub search: SearchIndexConfig,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -29,26 +111,1387 @@ pub struct SmtpConfig {
     pub auth_database_url: Option<String>,
     pub require_auth: bool,
     pub max_message_size: usize,
+    /// Expect a PROXY protocol (v1/v2) header on every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Proxy addresses allowed to send PROXY headers; required with
+    /// `proxy_protocol`
+    #[serde(default)]
+    pub proxy_trusted_ips: Vec<String>,
+    /// Wait this long before the 220 greeting and drop clients that talk
+    /// first (0 = greet immediately)
+    #[serde(default)]
+    pub greet_pause_secs: u64,
+    /// Refuse messages that already passed more relays than this
+    /// (counted by `Received:` headers) as mail loops
+    #[serde(default = "default_max_hops")]
+    pub max_hops: usize,
+    /// Offer the legacy CRAM-MD5 mechanism (SMTP and IMAP); stores the
+    /// HMAC-MD5 pads of each user's password
+    #[serde(default)]
+    pub allow_cram_md5: bool,
+}
+
+fn default_max_hops() -> usize {
+    crate::smtp::loop_detection::DEFAULT_MAX_HOPS
+}
+
+fn default_imaps_listen_addr() -> String {
+    "0.0.0.0:993".to_string()
+}
+
+fn default_idle_poll_interval() -> u64 {
+    crate::imap::idle::DEFAULT_POLL_INTERVAL.as_secs()
+}
+
+fn default_auto_create_folders() -> bool {
+    true
+}
+
+fn default_enable_compress() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImapConfig {
     pub listen_addr: String,
+    /// Offer STARTTLS on `listen_addr` and serve implicit TLS (IMAPS) on
+    /// `imaps_listen_addr`
     pub enable_tls: bool,
     pub tls_cert_path: Option<String>,
     pub tls_key_path: Option<String>,
+    /// Implicit TLS listener, used when `enable_tls` is set
+    #[serde(default = "default_imaps_listen_addr")]
+    pub imaps_listen_addr: String,
+    /// Expect a PROXY protocol (v1/v2) header on every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Proxy addresses allowed to send PROXY headers; required with
+    /// `proxy_protocol`
+    #[serde(default)]
+    pub proxy_trusted_ips: Vec<String>,
+    /// Seconds between mailbox rescans during IDLE, in case filesystem
+    /// notifications are unavailable or missed (e.g. on network storage)
+    #[serde(default = "default_idle_poll_interval")]
+    pub idle_poll_interval_secs: u64,
+    /// Create Sent, Drafts, Trash, Junk and Archive on a user's first login
+    #[serde(default = "default_auto_create_folders")]
+    pub auto_create_folders: bool,
+    /// Offer COMPRESS=DEFLATE, which saves bandwidth for clients syncing
+    /// large mailboxes over mobile links
+    #[serde(default = "default_enable_compress")]
+    pub enable_compress: bool,
+    /// Users allowed to change quotas with SETQUOTA and read other users'
+    /// quotas
+    #[serde(default)]
+    pub quota_admins: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StorageConfig {
     pub maildir_path: String,
     pub database_url: String,
+    /// Keep message metadata in SQLite for listings, counts and header
+    /// searches (see [`crate::storage::metadata`])
+    #[serde(default)]
+    pub metadata_index: bool,
+    /// Store identical deliveries to several mailboxes once, as hardlinks
+    /// (see [`crate::storage::single_instance`]); stored copies carry no
+    /// `Delivered-To` header, loop detection remembers them instead
+    #[serde(default)]
+    pub single_instance: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LoggingConfig {
+    /// Default level: `trace`, `debug`, `info`, `warn` or `error`
     pub level: String,
+    /// Output format: `pretty`, `compact` or `json`
     pub format: String,
+    /// Per-module levels overriding `level`, e.g. `"mail_rs::smtp" = "debug"`
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+/// Message submission listener (RFC 6409)
+///
+/// Reuses the TLS certificates and auth database from `[smtp]`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SubmissionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_submission_listen_addr")]
+    pub listen_addr: String,
+    /// Messages each user may submit per day
+    #[serde(default = "default_submission_daily_limit")]
+    pub daily_message_limit: u32,
+}
+
+impl Default for SubmissionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: default_submission_listen_addr(),
+            daily_message_limit: default_submission_daily_limit(),
+        }
+    }
+}
+
+fn default_submission_listen_addr() -> String {
+    "0.0.0.0:587".to_string()
+}
+
+fn default_submission_daily_limit() -> u32 {
+    500
+}
+
+/// OAuth 2.0 bearer token authentication (OAUTHBEARER/XOAUTH2)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Expected `iss` claim
+    #[serde(default)]
+    pub issuer: String,
+    /// Where to fetch the issuer's signing keys
+    #[serde(default)]
+    pub jwks_url: String,
+    /// Expected `aud` claim (not checked if unset)
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Claim holding the mailbox address
+    #[serde(default = "default_oauth_username_claim")]
+    pub username_claim: String,
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            issuer: String::new(),
+            jwks_url: String::new(),
+            audience: None,
+            username_claim: default_oauth_username_claim(),
+            jwks_refresh_secs: default_jwks_refresh_secs(),
+        }
+    }
+}
+
+fn default_oauth_username_claim() -> String {
+    "email".to_string()
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    3600
+}
+
+/// Sender Rewriting Scheme for forwarded mail (see [`crate::smtp::srs`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SrsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// HMAC secret for address hashes, keep it stable across restarts
+    #[serde(default)]
+    pub secret: String,
+    /// Domain of rewritten senders (defaults to `server.domain`)
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Refuse bounces to SRS addresses older than this
+    #[serde(default = "default_srs_max_age_days")]
+    pub max_age_days: u64,
+}
+
+impl Default for SrsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secret: String::new(),
+            domain: None,
+            max_age_days: default_srs_max_age_days(),
+        }
+    }
+}
+
+fn default_srs_max_age_days() -> u64 {
+    21
+}
+
+/// Scheduled usage reports (see [`crate::reporting`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReportingConfig {
+    /// Record usage events and generate reports
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_report_schedules")]
+    pub schedules: Vec<ReportPeriod>,
+    /// Admins receiving the reports by mail
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// Sender of report mails (defaults to postmaster@<domain>)
+    #[serde(default)]
+    pub from: Option<String>,
+    /// Attachments added to report mails
+    #[serde(default = "default_report_formats")]
+    pub formats: Vec<ReportFormat>,
+    /// Number of top senders/recipients listed
+    #[serde(default = "default_report_top_n")]
+    pub top_n: usize,
+    /// How long raw usage events are kept
+    #[serde(default = "default_report_retention_days")]
+    pub retention_days: u32,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            schedules: default_report_schedules(),
+            recipients: Vec::new(),
+            from: None,
+            formats: default_report_formats(),
+            top_n: default_report_top_n(),
+            retention_days: default_report_retention_days(),
+        }
+    }
+}
+
+fn default_report_schedules() -> Vec<ReportPeriod> {
+    vec![ReportPeriod::Weekly]
+}
+
+fn default_report_formats() -> Vec<ReportFormat> {
+    vec![ReportFormat::Pdf, ReportFormat::Csv]
+}
+
+fn default_report_top_n() -> usize {
+    10
+}
+
+fn default_report_retention_days() -> u32 {
+    400
+}
+
+/// Outbound TLS reporting (see [`crate::tlsrpt`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsReportingConfig {
+    /// Record outbound TLS sessions and send daily reports
+    #[serde(default)]
+    pub enabled: bool,
+    /// Organization named in reports (defaults to `server.domain`)
+    #[serde(default)]
+    pub organization_name: Option<String>,
+    /// Contact named in reports (defaults to the sender address)
+    #[serde(default)]
+    pub contact_info: Option<String>,
+    /// Sender of report mails (defaults to postmaster@<domain>)
+    #[serde(default)]
+    pub from: Option<String>,
+    /// How long recorded sessions are kept
+    #[serde(default = "default_tls_report_retention_days")]
+    pub retention_days: u32,
+}
+
+impl Default for TlsReportingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            organization_name: None,
+            contact_info: None,
+            from: None,
+            retention_days: default_tls_report_retention_days(),
+        }
+    }
+}
+
+fn default_tls_report_retention_days() -> u32 {
+    30
+}
+
+/// Staged migration settings
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MigrationConfig {
+    /// Proxy IMAP logins of not-yet-migrated users to the legacy server
+    #[serde(default)]
+    pub coexistence: bool,
+    /// Legacy IMAP server (`host:port`)
+    #[serde(default)]
+    pub legacy_imap_addr: Option<String>,
+}
+
+/// Data residency regions (see [`crate::residency`])
+///
+/// Without regions every mailbox lives under `storage.maildir_path`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ResidencyConfig {
+    #[serde(default)]
+    pub regions: Vec<RegionConfig>,
+    /// Region of domains and users without an assignment
+    /// (`storage.maildir_path` if unset)
+    #[serde(default)]
+    pub default_region: Option<String>,
+}
+
+/// Display-name impersonation protection (see
+/// [`crate::antispam::impersonation`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ImpersonationConfig {
+    /// Check inbound mail against the protected names of recipient domains
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Download bandwidth limits (see [`crate::security::bandwidth`])
+///
+/// Limits are in bytes per second; 0 leaves that limit off.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BandwidthConfig {
+    /// Throttle IMAP connections and API message downloads
+    #[serde(default)]
+    pub enabled: bool,
+    /// Limit for all connections together
+    #[serde(default)]
+    pub global_bytes_per_sec: u64,
+    /// Limit per client IP address
+    #[serde(default)]
+    pub per_ip_bytes_per_sec: u64,
+    /// Limit per authenticated user
+    #[serde(default)]
+    pub per_user_bytes_per_sec: u64,
+}
+
+/// Footers of outgoing mail (see [`crate::footers`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FootersConfig {
+    /// Append the footers of sender domains to submitted messages
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Sieve filtering of delivered mail (see [`crate::sieve::delivery`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SieveConfig {
+    /// Run the active script of each local recipient on mail delivered to
+    /// them
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Spam scoring of delivered mail (see [`crate::spam::delivery`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SpamFilterConfig {
+    /// Score mail from unauthenticated clients, filing spam into the Junk
+    /// folder of recipients with quarantine enabled
+    #[serde(default)]
+    pub enabled: bool,
+    /// Score at or above which the message is refused for all recipients
+    #[serde(default = "default_spam_reject_threshold")]
+    pub reject_threshold: f64,
+    /// Score with the built-in rules (`builtin`) or `rspamd`
+    #[serde(default)]
+    pub backend: SpamBackend,
+    #[serde(default)]
+    pub rspamd: RspamdConfig,
+    #[serde(default)]
+    pub quarantine: SpamQuarantineConfig,
+}
+
+fn default_spam_reject_threshold() -> f64 {
+    DEFAULT_REJECT_THRESHOLD
+}
+
+/// Local rspamd worker scoring mail (see [`crate::spam::rspamd`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RspamdConfig {
+    /// Base URL of the worker's HTTP protocol
+    #[serde(default = "default_rspamd_url")]
+    pub url: String,
+    /// Sent as the `Password` header, when the worker requires one
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Checks taking longer fail, leaving the message unscored
+    #[serde(default = "default_rspamd_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_rspamd_url() -> String {
+    "http://127.0.0.1:11333".to_string()
+}
+
+fn default_rspamd_timeout_ms() -> u64 {
+    5000
+}
+
+impl Default for RspamdConfig {
+    fn default() -> Self {
+        Self {
+            url: default_rspamd_url(),
+            password: None,
+            timeout_ms: default_rspamd_timeout_ms(),
+        }
+    }
+}
+
+impl Default for SpamFilterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            reject_threshold: default_spam_reject_threshold(),
+            backend: SpamBackend::default(),
+            rspamd: RspamdConfig::default(),
+            quarantine: SpamQuarantineConfig::default(),
+        }
+    }
+}
+
+/// Quarantine of spam with release and delete (see
+/// [`crate::spam::quarantine`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SpamQuarantineConfig {
+    /// Hold spam in quarantine instead of the recipients' Junk folder
+    #[serde(default)]
+    pub enabled: bool,
+    /// Also hold mail reaching `reject_threshold` instead of refusing it
+    #[serde(default)]
+    pub hold_rejected: bool,
+    /// Hours between digests of newly held messages
+    #[serde(default = "default_quarantine_digest_interval_hours")]
+    pub digest_interval_hours: u64,
+    /// Days held messages are kept before being deleted
+    #[serde(default = "default_quarantine_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_quarantine_digest_interval_hours() -> u64 {
+    24
+}
+
+fn default_quarantine_retention_days() -> u32 {
+    30
+}
+
+impl Default for SpamQuarantineConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hold_rejected: false,
+            digest_interval_hours: default_quarantine_digest_interval_hours(),
+            retention_days: default_quarantine_retention_days(),
+        }
+    }
+}
+
+/// `postmaster@` and `abuse@` of hosted domains (see
+/// [`crate::role_accounts`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RoleAccountsConfig {
+    /// Provision the role addresses of `server.domain` and of the users'
+    /// domains, and deliver their mail to `mailbox`
+    #[serde(default)]
+    pub enabled: bool,
+    /// Admin mailbox of role mail (defaults to `postmaster@<domain>` of
+    /// each domain)
+    #[serde(default)]
+    pub mailbox: Option<String>,
+    /// File role mail into the Junk folder of the mailbox for review
+    #[serde(default)]
+    pub quarantine: bool,
+}
+
+/// Sandboxed WebAssembly hooks of local delivery (see [`crate::hooks`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HooksConfig {
+    /// Run the active hooks of each local recipient's domain and mailbox
+    /// on mail delivered to them
+    #[serde(default)]
+    pub enabled: bool,
+    /// Fuel per hook run, roughly the instructions it may execute
+    #[serde(default = "default_hook_fuel")]
+    pub fuel: u64,
+    /// Memory a hook module may grow to
+    #[serde(default = "default_hook_memory_limit_kb")]
+    pub memory_limit_kb: usize,
+    #[serde(default = "default_hook_max_module_kb")]
+    pub max_module_kb: usize,
+    /// Header fields a hook may add per message
+    #[serde(default = "default_hook_max_headers")]
+    pub max_headers: usize,
+}
+
+fn default_hook_fuel() -> u64 {
+    HookLimits::default().fuel
+}
+
+fn default_hook_memory_limit_kb() -> usize {
+    HookLimits::default().memory_bytes / 1024
+}
+
+fn default_hook_max_module_kb() -> usize {
+    HookLimits::default().max_module_bytes / 1024
+}
+
+fn default_hook_max_headers() -> usize {
+    HookLimits::default().max_headers
+}
+
+impl HooksConfig {
+    pub fn limits(&self) -> HookLimits {
+        HookLimits {
+            fuel: self.fuel,
+            memory_bytes: self.memory_limit_kb * 1024,
+            max_module_bytes: self.max_module_kb * 1024,
+            max_headers: self.max_headers,
+        }
+    }
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fuel: default_hook_fuel(),
+            memory_limit_kb: default_hook_memory_limit_kb(),
+            max_module_kb: default_hook_max_module_kb(),
+            max_headers: default_hook_max_headers(),
+        }
+    }
+}
+
+/// Detection of logins from unusual locations (see
+/// [`crate::login_anomaly`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoginAnomalyConfig {
+    /// Assess IMAP, SMTP and API logins against the user's known locations
+    #[serde(default)]
+    pub enabled: bool,
+    /// CSV file of `network,country,asn,latitude,longitude` lines locating
+    /// client addresses; without it, a network the user never signed in
+    /// from is the only signal
+    #[serde(default)]
+    pub geoip_database: Option<String>,
+    /// Fastest plausible travel between two logins; faster is impossible
+    /// travel
+    #[serde(default = "default_max_travel_speed_kmh")]
+    pub max_travel_speed_kmh: f64,
+    /// Refuse IMAP and SMTP logins from an unusual location for
+    /// `block_minutes` instead of only recording them
+    #[serde(default)]
+    pub block_protocol_logins: bool,
+    #[serde(default = "default_login_block_minutes")]
+    pub block_minutes: u32,
+    /// Time API clients have to complete a step-up verification
+    #[serde(default = "default_step_up_expiry_minutes")]
+    pub step_up_expiry_minutes: u32,
+    /// Public URL of the API for email verification links, e.g.
+    /// `https://mail.example.com`; the request's host if unset
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+fn default_max_travel_speed_kmh() -> f64 {
+    1000.0
+}
+
+fn default_login_block_minutes() -> u32 {
+    60
+}
+
+fn default_step_up_expiry_minutes() -> u32 {
+    15
+}
+
+impl Default for LoginAnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            geoip_database: None,
+            max_travel_speed_kmh: default_max_travel_speed_kmh(),
+            block_protocol_logins: false,
+            block_minutes: default_login_block_minutes(),
+            step_up_expiry_minutes: default_step_up_expiry_minutes(),
+            base_url: None,
+        }
+    }
+}
+
+/// DNS blocklist checks of SMTP clients (see [`crate::antispam::dnsbl`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsblConfig {
+    /// Look unauthenticated SMTP clients up in `lists`
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub lists: Vec<DnsblListConfig>,
+    /// Refuse clients on a rejecting list at `connect` or `mail_from`
+    #[serde(default)]
+    pub reject_at: DnsblStage,
+    /// How long answers are cached, "not listed" included
+    #[serde(default = "default_dnsbl_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    /// Lookups taking longer count as not listed
+    #[serde(default = "default_dnsbl_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// A DNS blocklist, e.g. `zen.spamhaus.org`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsblListConfig {
+    pub zone: String,
+    /// `reject` listed clients, or `score` their messages
+    #[serde(default)]
+    pub action: DnsblAction,
+    /// Spam score added by a listing with the `score` action
+    #[serde(default = "default_dnsbl_weight")]
+    pub weight: f64,
+}
+
+/// URI blocklist checks of the links in messages (see
+/// [`crate::antispam::uribl`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UriblConfig {
+    /// Look up the domains linked from mail scored by the built-in filter
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub lists: Vec<UriblListConfig>,
+    /// Domains looked up per message, the first ones linked
+    #[serde(default = "default_uribl_max_domains")]
+    pub max_domains: usize,
+    /// How long answers are cached, "not listed" included
+    #[serde(default = "default_dnsbl_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    /// Lookups taking longer count as not listed
+    #[serde(default = "default_dnsbl_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// A URI blocklist, e.g. `multi.surbl.org`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UriblListConfig {
+    pub zone: String,
+    /// Spam score added when the message links to a listed domain
+    #[serde(default = "default_uribl_weight")]
+    pub weight: f64,
+}
+
+fn default_uribl_max_domains() -> usize {
+    20
+}
+
+fn default_uribl_weight() -> f64 {
+    3.0
+}
+
+impl Default for UriblConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lists: Vec::new(),
+            max_domains: default_uribl_max_domains(),
+            cache_ttl_secs: default_dnsbl_cache_ttl_secs(),
+            timeout_ms: default_dnsbl_timeout_ms(),
+        }
+    }
+}
+
+/// DKIM signing of outbound mail with per-domain keys managed through the
+/// admin API (see [`crate::dkim_keys`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DkimSigningConfig {
+    /// Sign queued mail whose From domain has an active key
+    #[serde(default)]
+    pub enabled: bool,
+    /// Secret the private keys are encrypted with; keys can't be read
+    /// back once it changes
+    #[serde(default)]
+    pub key_secret: String,
+    /// Size of generated RSA keys
+    #[serde(default = "default_dkim_rsa_bits")]
+    pub rsa_bits: usize,
+}
+
+fn default_dkim_rsa_bits() -> usize {
+    2048
+}
+
+impl Default for DkimSigningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key_secret: String::new(),
+            rsa_bits: default_dkim_rsa_bits(),
+        }
+    }
+}
+
+/// OpenPGP keys of users, for verifying, decrypting and encrypting mail
+/// (see [`crate::openpgp`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OpenPgpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Secret the uploaded private keys and their passphrases are
+    /// encrypted with
+    #[serde(default)]
+    pub key_secret: String,
+    /// Look up recipient keys with the Web Key Directory when the user has
+    /// none stored
+    #[serde(default = "default_openpgp_wkd")]
+    pub wkd: bool,
+}
+
+fn default_openpgp_wkd() -> bool {
+    true
+}
+
+impl Default for OpenPgpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key_secret: String::new(),
+            wkd: default_openpgp_wkd(),
+        }
+    }
+}
+
+/// Calendar invitations (iTIP) in delivered mail, added to the recipient's
+/// calendar (see [`crate::caldav::itip`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CalendarInvitationsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// MTA-STS policy served at `/.well-known/mta-sts.txt` and the TLS-RPT
+/// address published next to it (see [`crate::admin::mta_sts`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MtaStsConfig {
+    /// Serve the policy and list its DNS records
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub mode: MtaStsMode,
+    /// MX hosts of the policy (defaults to `server.hostname`)
+    #[serde(default)]
+    pub mx: Vec<String>,
+    /// Seconds senders may cache the policy
+    #[serde(default = "default_mta_sts_max_age")]
+    pub max_age: u64,
+    /// Address in the `_smtp._tls` record (defaults to
+    /// postmaster@<domain>)
+    #[serde(default)]
+    pub tls_rpt_address: Option<String>,
+}
+
+fn default_mta_sts_max_age() -> u64 {
+    604800
+}
+
+impl Default for MtaStsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: MtaStsMode::default(),
+            mx: Vec::new(),
+            max_age: default_mta_sts_max_age(),
+            tls_rpt_address: None,
+        }
+    }
+}
+
+/// BIMI logo announced in the `default._bimi` record
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BimiConfig {
+    /// HTTPS URL of the SVG Tiny PS logo (no record without it)
+    #[serde(default)]
+    pub logo_url: Option<String>,
+    /// HTTPS URL of the Verified Mark Certificate
+    #[serde(default)]
+    pub authority_url: Option<String>,
+}
+
+/// Ingestion of the DMARC and TLS aggregate reports other providers send to
+/// our own `rua` addresses (see [`crate::dmarc_reports`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DmarcReportsConfig {
+    /// Parse and store reports addressed to `addresses`
+    #[serde(default)]
+    pub enabled: bool,
+    /// Recipients that receive aggregate reports, e.g. `dmarc@example.com`
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Delete stored reports whose period ended this many days ago
+    #[serde(default = "default_dmarc_reports_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_dmarc_reports_retention_days() -> u32 {
+    180
+}
+
+impl Default for DmarcReportsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            retention_days: default_dmarc_reports_retention_days(),
+        }
+    }
+}
+
+/// Greylisting of mail from unknown SMTP clients (see
+/// [`crate::antispam::greylist`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GreylistingConfig {
+    /// Defer mail from unauthenticated clients until they retry
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds before a retry of the same sender, recipient and client IP
+    /// is accepted
+    #[serde(default = "default_greylist_delay_seconds")]
+    pub delay_seconds: i64,
+    /// Pending triplets not retried within this many hours start over
+    #[serde(default = "default_greylist_retry_window_hours")]
+    pub retry_window_hours: i64,
+    /// Whitelist a client IP after this many accepted retries (0 = never)
+    #[serde(default = "default_greylist_auto_whitelist_after")]
+    pub auto_whitelist_after: u32,
+    /// Accept mail passing SPF or DKIM without greylisting it
+    #[serde(default = "default_greylist_bypass_authenticated")]
+    pub bypass_authenticated: bool,
+}
+
+fn default_greylist_delay_seconds() -> i64 {
+    300
+}
+
+fn default_greylist_retry_window_hours() -> i64 {
+    24
+}
+
+fn default_greylist_auto_whitelist_after() -> u32 {
+    5
+}
+
+fn default_greylist_bypass_authenticated() -> bool {
+    true
+}
+
+impl Default for GreylistingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay_seconds: default_greylist_delay_seconds(),
+            retry_window_hours: default_greylist_retry_window_hours(),
+            auto_whitelist_after: default_greylist_auto_whitelist_after(),
+            bypass_authenticated: default_greylist_bypass_authenticated(),
+        }
+    }
+}
+
+/// Capture mode of staging setups (see [`crate::smtp::capture`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CaptureConfig {
+    /// Keep outbound mail in the queue instead of delivering it
+    #[serde(default)]
+    pub enabled: bool,
+    /// Recipient domains still delivered for real
+    #[serde(default)]
+    pub allow_domains: Vec<String>,
+}
+
+/// Virus scanning of received mail (see [`crate::antivirus`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AntivirusConfig {
+    /// Scan each received message with clamd
+    #[serde(default)]
+    pub enabled: bool,
+    /// clamd's `host:port`, or the path of its Unix socket
+    #[serde(default = "default_clamd_address")]
+    pub address: String,
+    /// `reject` infected mail, or `quarantine` it into Junk
+    #[serde(default)]
+    pub action: VirusAction,
+    /// Deliver mail unscanned when clamd can't be reached, instead of
+    /// deferring it with 451
+    #[serde(default = "default_antivirus_fail_open")]
+    pub fail_open: bool,
+    #[serde(default = "default_clamd_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_clamd_address() -> String {
+    "127.0.0.1:3310".to_string()
+}
+
+fn default_antivirus_fail_open() -> bool {
+    true
+}
+
+fn default_clamd_timeout_ms() -> u64 {
+    30000
+}
+
+impl Default for AntivirusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: default_clamd_address(),
+            action: VirusAction::default(),
+            fail_open: default_antivirus_fail_open(),
+            timeout_ms: default_clamd_timeout_ms(),
+        }
+    }
+}
+
+/// Concurrency of background work (see [`crate::workers`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WorkersConfig {
+    /// Background tasks running at the same time across all subsystems
+    #[serde(default = "default_max_tasks")]
+    pub max_tasks: usize,
+    /// Outbound deliveries in parallel
+    #[serde(default = "default_delivery_workers")]
+    pub delivery: usize,
+    /// Batches of the search index queue applied in parallel
+    #[serde(default = "default_indexing_workers")]
+    pub indexing: usize,
+    /// AI summary requests in parallel
+    #[serde(default = "default_ai_workers")]
+    pub ai: usize,
+    /// Notifications, alerts and auto-replies sent in parallel
+    #[serde(default = "default_notification_workers")]
+    pub notifications: usize,
+    /// Webhook calls in parallel
+    #[serde(default = "default_webhook_workers")]
+    pub webhooks: usize,
+}
+
+fn default_max_tasks() -> usize {
+    256
+}
+
+fn default_delivery_workers() -> usize {
+    16
+}
+
+fn default_indexing_workers() -> usize {
+    4
+}
+
+fn default_ai_workers() -> usize {
+    4
+}
+
+fn default_notification_workers() -> usize {
+    32
+}
+
+fn default_webhook_workers() -> usize {
+    8
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        Self {
+            max_tasks: default_max_tasks(),
+            delivery: default_delivery_workers(),
+            indexing: default_indexing_workers(),
+            ai: default_ai_workers(),
+            notifications: default_notification_workers(),
+            webhooks: default_webhook_workers(),
+        }
+    }
+}
+
+/// Read-only operation (see [`crate::recovery`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RecoveryConfig {
+    /// Start in read-only mode, e.g. after restoring a damaged store
+    #[serde(default)]
+    pub read_only: bool,
+    /// Switch to read-only mode on storage corruption or a full or
+    /// read-only disk
+    #[serde(default = "default_recovery_auto")]
+    pub auto: bool,
+}
+
+fn default_recovery_auto() -> bool {
+    true
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            auto: default_recovery_auto(),
+        }
+    }
+}
+
+/// Enforcement of the retention policies managed through the API (see
+/// [`crate::retention`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    /// Delete and archive messages past their folder's policy
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds between runs
+    #[serde(default = "default_retention_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_retention_interval_secs() -> u64 {
+    3600
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: default_retention_interval_secs(),
+        }
+    }
+}
+
+/// Limits applied to local recipients at delivery
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    /// Messages a mailbox may receive per day unless its quota says
+    /// otherwise
+    #[serde(default = "default_daily_delivery_limit")]
+    pub daily_delivery_limit: u32,
+    /// Percentages of the storage quota at which users are warned, once
+    /// per threshold
+    #[serde(default = "default_warning_thresholds")]
+    pub warning_thresholds: Vec<u8>,
+    /// Seconds between recalculations of every mailbox's usage from disk
+    #[serde(default = "default_recalculate_interval_secs")]
+    pub recalculate_interval_secs: u64,
+    /// Days usage samples are kept for `/api/admin/quotas/:email/history`
+    #[serde(default = "default_quota_history_days")]
+    pub history_days: u32,
+}
+
+fn default_daily_delivery_limit() -> u32 {
+    5000
+}
+
+fn default_warning_thresholds() -> Vec<u8> {
+    vec![80, 95]
+}
+
+fn default_recalculate_interval_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_quota_history_days() -> u32 {
+    90
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            daily_delivery_limit: default_daily_delivery_limit(),
+            warning_thresholds: default_warning_thresholds(),
+            recalculate_interval_secs: default_recalculate_interval_secs(),
+            history_days: default_quota_history_days(),
+        }
+    }
+}
+
+/// Keeping the full-text search index up to date
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SearchIndexConfig {
+    /// Index messages as they are stored and removed, instead of only on
+    /// a rebuild
+    #[serde(default = "default_search_incremental")]
+    pub incremental: bool,
+    /// Index changes waiting for the background worker before new ones are
+    /// dropped
+    #[serde(default = "default_search_queue_size")]
+    pub queue_size: usize,
+    /// Text of attachments indexed along with the message
+    #[serde(default)]
+    pub attachments: AttachmentIndexConfig,
+}
+
+/// Attachment types whose text is extracted for the search index
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AttachmentIndexConfig {
+    #[serde(default = "default_index_attachments")]
+    pub enabled: bool,
+    /// Larger attachments, after decoding, are not read
+    #[serde(default = "default_attachment_max_bytes")]
+    pub max_bytes: usize,
+    /// Characters of text indexed per attachment
+    #[serde(default = "default_attachment_max_chars")]
+    pub max_chars: usize,
+    #[serde(default = "default_index_attachments")]
+    pub pdf: bool,
+    /// Word documents (`.docx`)
+    #[serde(default = "default_index_attachments")]
+    pub docx: bool,
+    /// Plain text, CSV and other `text/*` files besides HTML
+    #[serde(default = "default_index_attachments")]
+    pub text: bool,
+    #[serde(default = "default_index_attachments")]
+    pub html: bool,
+}
+
+fn default_index_attachments() -> bool {
+    true
+}
+
+fn default_attachment_max_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_attachment_max_chars() -> usize {
+    100_000
+}
+
+impl Default for AttachmentIndexConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_index_attachments(),
+            max_bytes: default_attachment_max_bytes(),
+            max_chars: default_attachment_max_chars(),
+            pdf: default_index_attachments(),
+            docx: default_index_attachments(),
+            text: default_index_attachments(),
+            html: default_index_attachments(),
+        }
+    }
+}
+
+fn default_search_incremental() -> bool {
+    true
+}
+
+fn default_search_queue_size() -> usize {
+    10_000
+}
+
+impl Default for SearchIndexConfig {
+    fn default() -> Self {
+        Self {
+            incremental: default_search_incremental(),
+            queue_size: default_search_queue_size(),
+            attachments: AttachmentIndexConfig::default(),
+        }
+    }
+}
+
+fn default_dnsbl_cache_ttl_secs() -> u64 {
+    900
+}
+
+fn default_dnsbl_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_dnsbl_weight() -> f64 {
+    1.0
+}
+
+impl Default for DnsblConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lists: Vec::new(),
+            reject_at: DnsblStage::default(),
+            cache_ttl_secs: default_dnsbl_cache_ttl_secs(),
+            timeout_ms: default_dnsbl_timeout_ms(),
+        }
+    }
+}
+
+/// Processing of delivered mail in worker tasks (see
+/// [`crate::smtp::postdelivery`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PostDeliveryConfig {
+    /// Parse, check, index and summarize delivered mail after the SMTP
+    /// transaction instead of during it
+    #[serde(default)]
+    pub enabled: bool,
+    /// Messages processed at the same time
+    #[serde(default = "default_post_delivery_workers")]
+    pub workers: usize,
+    /// Messages above `large_message_bytes` processed at the same time
+    #[serde(default = "default_post_delivery_large_workers")]
+    pub large_workers: usize,
+    /// Size from which messages are processed by the large workers
+    #[serde(default = "default_large_message_bytes")]
+    pub large_message_bytes: usize,
+    /// Messages waiting per lane before deliveries wait for the workers
+    #[serde(default = "default_post_delivery_queue_size")]
+    pub queue_size: usize,
+    /// Request AI summaries of delivered mail
+    #[serde(default = "default_post_delivery_summaries")]
+    pub summaries: bool,
+    /// Attachment file extensions, like `exe`, whose messages are moved to
+    /// Junk
+    #[serde(default)]
+    pub blocked_attachment_extensions: Vec<String>,
+}
+
+fn default_post_delivery_workers() -> usize {
+    4
+}
+
+fn default_post_delivery_large_workers() -> usize {
+    1
+}
+
+fn default_large_message_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_post_delivery_queue_size() -> usize {
+    1000
+}
+
+fn default_post_delivery_summaries() -> bool {
+    true
+}
+
+impl Default for PostDeliveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            workers: default_post_delivery_workers(),
+            large_workers: default_post_delivery_large_workers(),
+            large_message_bytes: default_large_message_bytes(),
+            queue_size: default_post_delivery_queue_size(),
+            summaries: default_post_delivery_summaries(),
+            blocked_attachment_extensions: Vec::new(),
+        }
+    }
+}
+
+/// Encrypted links sharing single messages (see [`crate::sharing`])
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SharingConfig {
+    /// Public URL the links start with, e.g. `https://mail.example.com`;
+    /// the request's host if unset
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Lifetime of links created without one
+    #[serde(default = "default_share_expiry_hours")]
+    pub default_expiry_hours: u32,
+    /// Longest lifetime a link may be given
+    #[serde(default = "default_share_max_expiry_hours")]
+    pub max_expiry_hours: u32,
+    /// Largest message, after leaving out attachments, that can be shared
+    #[serde(default = "default_share_max_message_size")]
+    pub max_message_size: usize,
+}
+
+fn default_share_expiry_hours() -> u32 {
+    72
+}
+
+fn default_share_max_expiry_hours() -> u32 {
+    720
+}
+
+fn default_share_max_message_size() -> usize {
+    25 * 1024 * 1024
+}
+
+impl Default for SharingConfig {
+    fn default() -> Self {
+        Self {
+            base_url: None,
+            default_expiry_hours: default_share_expiry_hours(),
+            max_expiry_hours: default_share_max_expiry_hours(),
+            max_message_size: default_share_max_message_size(),
+        }
+    }
+}
+
+/// Per-user budgets in the delivery pipeline (see [`crate::smtp::budget`])
+///
+/// Usage is measured either way; 0 leaves that limit off.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeliveryBudgetConfig {
+    /// Enforce the limits below
+    #[serde(default)]
+    pub enabled: bool,
+    /// Longest an optional stage may run for one message before it is
+    /// skipped
+    #[serde(default = "default_stage_timeout_ms")]
+    pub stage_timeout_ms: u64,
+    /// Stage time per user and minute before optional stages are skipped
+    #[serde(default = "default_user_ms_per_minute")]
+    pub user_ms_per_minute: u64,
+    /// Mailbox writes per user at the same time
+    #[serde(default = "default_user_io_concurrency")]
+    pub user_io_concurrency: usize,
+}
+
+impl Default for DeliveryBudgetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stage_timeout_ms: default_stage_timeout_ms(),
+            user_ms_per_minute: default_user_ms_per_minute(),
+            user_io_concurrency: default_user_io_concurrency(),
+        }
+    }
+}
+
+fn default_stage_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_user_ms_per_minute() -> u64 {
+    20000
+}
+
+fn default_user_io_concurrency() -> usize {
+    4
+}
+
+/// Billing metrics (see [`crate::billing`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BillingConfig {
+    /// Count messages and API calls and sample storage per user and month
+    #[serde(default)]
+    pub enabled: bool,
+    /// Receives a JSON POST with the export of each period once it closes
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+/// Burner aliases (see [`crate::aliases`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AliasesConfig {
+    /// Deliver mail sent to aliases to their owners
+    #[serde(default)]
+    pub enabled: bool,
+    /// Domain of generated aliases (the owner's domain if unset)
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+/// Storage locations of one region
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegionConfig {
+    /// Short name, e.g. `eu` (letters, digits and `-`)
+    pub name: String,
+    pub maildir_path: String,
+    pub backup_dir: String,
+    pub export_path: String,
+}
+
+/// Inbound routing table (see [`crate::smtp::routing`])
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -65,6 +1508,14 @@ pub struct AuthenticationConfig {
 
     // DKIM validation for incoming emails
     pub dkim_validate_incoming: bool,
+
+    // TTL for cached SPF verdicts and DKIM public keys (0 = disabled)
+    #[serde(default = "default_auth_cache_ttl_secs")]
+    pub auth_cache_ttl_secs: u64,
+}
+
+fn default_auth_cache_ttl_secs() -> u64 {
+    300
 }
 
 impl Config {
@@ -76,6 +1527,16 @@ impl Config {
             .map_err(|e| crate::error::MailError::Config(e.to_string()))
     }
 
+    /// Database shared by the admin API and the services it manages
+    ///
+    /// This is the auth database, `sqlite://data/users.db` if unset.
+    pub fn api_database_url(&self) -> String {
+        self.smtp
+            .auth_database_url
+            .clone()
+            .unwrap_or_else(|| "sqlite://data/users.db".to_string())
+    }
+
     pub fn default() -> Self {
         Self {
             server: ServerConfig {
@@ -92,20 +1553,35 @@ impl Config {
                 auth_database_url: None,
                 require_auth: false,
                 max_message_size: 10 * 1024 * 1024, // 10MB
+                proxy_protocol: false,
+                proxy_trusted_ips: Vec::new(),
+                greet_pause_secs: 0,
+                max_hops: default_max_hops(),
+                allow_cram_md5: false,
             },
             imap: ImapConfig {
                 listen_addr: "0.0.0.0:1993".to_string(),
                 enable_tls: false,
                 tls_cert_path: None,
                 tls_key_path: None,
+                imaps_listen_addr: default_imaps_listen_addr(),
+                proxy_protocol: false,
+                proxy_trusted_ips: Vec::new(),
+                idle_poll_interval_secs: default_idle_poll_interval(),
+                auto_create_folders: default_auto_create_folders(),
+                enable_compress: default_enable_compress(),
+                quota_admins: Vec::new(),
             },
             storage: StorageConfig {
                 maildir_path: "/tmp/maildir".to_string(),
                 database_url: "sqlite://mail.db".to_string(),
+                metadata_index: false,
+                single_instance: false,
             },
             logging: LoggingConfig {
                 level: "info".to_string(),
                 format: "pretty".to_string(),
+                modules: BTreeMap::new(),
             },
             authentication: AuthenticationConfig {
                 spf_enabled: false,
@@ -115,7 +1591,45 @@ impl Config {
                 dkim_selector: "default".to_string(),
                 dkim_private_key_path: "test_data/dkim/dkim_private.pem".to_string(),
                 dkim_validate_incoming: false,
+                auth_cache_ttl_secs: default_auth_cache_ttl_secs(),
             },
+            routing: RoutingConfig::default(),
+            submission: SubmissionConfig::default(),
+            migration: MigrationConfig::default(),
+            oauth: OAuthConfig::default(),
+            srs: SrsConfig::default(),
+            reporting: ReportingConfig::default(),
+            tls_reporting: TlsReportingConfig::default(),
+            residency: ResidencyConfig::default(),
+            impersonation: ImpersonationConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            billing: BillingConfig::default(),
+            aliases: AliasesConfig::default(),
+            delivery_budget: DeliveryBudgetConfig::default(),
+            footers: FootersConfig::default(),
+            sharing: SharingConfig::default(),
+            sieve: SieveConfig::default(),
+            spam: SpamFilterConfig::default(),
+            role_accounts: RoleAccountsConfig::default(),
+            hooks: HooksConfig::default(),
+            post_delivery: PostDeliveryConfig::default(),
+            login_anomaly: LoginAnomalyConfig::default(),
+            dnsbl: DnsblConfig::default(),
+            greylisting: GreylistingConfig::default(),
+            uribl: UriblConfig::default(),
+            dmarc_reports: DmarcReportsConfig::default(),
+            dkim_signing: DkimSigningConfig::default(),
+            openpgp: OpenPgpConfig::default(),
+            calendar_invitations: CalendarInvitationsConfig::default(),
+            mta_sts: MtaStsConfig::default(),
+            bimi: BimiConfig::default(),
+            capture: CaptureConfig::default(),
+            antivirus: AntivirusConfig::default(),
+            workers: WorkersConfig::default(),
+            recovery: RecoveryConfig::default(),
+            retention: RetentionConfig::default(),
+            quota: QuotaConfig::default(),
+            search: SearchIndexConfig::default(),
         }
     }
 }
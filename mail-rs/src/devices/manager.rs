@@ -0,0 +1,640 @@
+//! Device manager: client devices, app passwords and sign-in alerts
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::types::*;
+use crate::notifications::{EventKind, NotificationRouter};
+
+/// Longest stored device name
+const MAX_NAME_LEN: usize = 100;
+
+/// Characters of generated app passwords, without look-alikes
+const APP_PASSWORD_CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Groups of four characters in an app password
+const APP_PASSWORD_GROUPS: usize = 4;
+
+/// Device manager
+pub struct DeviceManager {
+    db: SqlitePool,
+    /// Raises alerts for new devices and locations
+    alerts: Option<Arc<NotificationRouter>>,
+}
+
+impl DeviceManager {
+    /// Create a new device manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db, alerts: None }
+    }
+
+    /// Connect to `database_url` and create the device tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Alert users of sign-ins from new devices and networks through this
+    /// router
+    pub fn with_alerts(mut self, router: Arc<NotificationRouter>) -> Self {
+        self.alerts = Some(router);
+        self
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS devices (
+                id TEXT PRIMARY KEY,
+                user TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                name TEXT NOT NULL,
+                app_password_hash TEXT,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                last_ip TEXT,
+                last_network TEXT,
+                revoked_at TEXT
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_devices_user ON devices(user, kind, name)")
+            .execute(&self.db)
+            .await?;
+
+        // Networks each user signed in from, to spot unusual locations
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS device_networks (
+                user TEXT NOT NULL,
+                network TEXT NOT NULL,
+                first_seen TEXT NOT NULL,
+                PRIMARY KEY (user, network)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a password login of `user` from the client `name`
+    ///
+    /// The device is created on its first login; a revoked device is
+    /// restored, since the user just signed in again with the account
+    /// password. Users with other devices already are alerted of new
+    /// devices and of logins from networks they never used.
+    pub async fn record_login(
+        &self,
+        user: &str,
+        kind: DeviceKind,
+        name: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Device> {
+        self.record_login_at(user, kind, name, ip, Utc::now()).await
+    }
+
+    async fn record_login_at(
+        &self,
+        user: &str,
+        kind: DeviceKind,
+        name: &str,
+        ip: Option<IpAddr>,
+        at: DateTime<Utc>,
+    ) -> Result<Device> {
+        let user = user.to_lowercase();
+        let name = device_name(name, kind);
+        let first_login = !self.has_devices(&user).await?;
+
+        let existing =
+            sqlx::query("SELECT * FROM devices WHERE user = ? AND kind = ? AND name = ?")
+                .bind(&user)
+                .bind(kind.as_str())
+                .bind(&name)
+                .fetch_optional(&self.db)
+                .await?;
+        let device = match existing {
+            Some(row) => {
+                let mut device = device_from_row(&row)?;
+                if device.is_revoked() {
+                    info!("Device {} of {} signed in again", device.name, user);
+                    sqlx::query("UPDATE devices SET revoked_at = NULL WHERE id = ?")
+                        .bind(&device.id)
+                        .execute(&self.db)
+                        .await?;
+                    device.revoked_at = None;
+                }
+                device
+            }
+            None => {
+                let device = Device {
+                    id: Uuid::new_v4().to_string(),
+                    user: user.clone(),
+                    kind,
+                    name,
+                    first_seen: at,
+                    last_seen: at,
+                    last_ip: None,
+                    last_network: None,
+                    revoked_at: None,
+                };
+                self.insert(&device, None).await?;
+                if !first_login {
+                    self.alert(
+                        &device,
+                        ip,
+                        "New device signed in",
+                        "A new device signed in to your account",
+                    );
+                }
+                device
+            }
+        };
+
+        let new_network = self.touch(&device, ip, at).await?;
+        if new_network && !first_login && device.first_seen != at {
+            self.alert(
+                &device,
+                ip,
+                "Sign-in from a new location",
+                "One of your devices signed in from a network it never used",
+            );
+        }
+        self.get(&user, &device.id)
+            .await?
+            .ok_or_else(|| anyhow!("Device {} disappeared", device.id))
+    }
+
+    /// Create an app password for a new device of `user`
+    ///
+    /// The password is only returned here; the stored hash cannot give it
+    /// back.
+    pub async fn create_app_password(&self, user: &str, name: &str) -> Result<AppPassword> {
+        let now = Utc::now();
+        let password = generate_app_password();
+        let device = Device {
+            id: Uuid::new_v4().to_string(),
+            user: user.to_lowercase(),
+            kind: DeviceKind::AppPassword,
+            name: device_name(name, DeviceKind::AppPassword),
+            first_seen: now,
+            last_seen: now,
+            last_ip: None,
+            last_network: None,
+            revoked_at: None,
+        };
+        self.insert(&device, Some(&hash_app_password(&password)))
+            .await?;
+        info!("Created app password {} for {}", device.name, device.user);
+        Ok(AppPassword { device, password })
+    }
+
+    /// Device whose app password `password` is, if it is one of `user`'s
+    /// and not revoked; the login is recorded
+    pub async fn app_password_login(
+        &self,
+        user: &str,
+        password: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<Device>> {
+        let user = user.to_lowercase();
+        let row = sqlx::query(
+            r#"
+            SELECT * FROM devices
+            WHERE user = ? AND kind = ? AND app_password_hash = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(&user)
+        .bind(DeviceKind::AppPassword.as_str())
+        .bind(hash_app_password(password.trim()))
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let device = device_from_row(&row)?;
+        let now = Utc::now();
+        if self.touch(&device, ip, now).await? {
+            self.alert(
+                &device,
+                ip,
+                "Sign-in from a new location",
+                "An app password was used from a network your devices never used",
+            );
+        }
+        self.get(&user, &device.id).await
+    }
+
+    /// Device `id` of `user`
+    pub async fn get(&self, user: &str, id: &str) -> Result<Option<Device>> {
+        let row = sqlx::query("SELECT * FROM devices WHERE id = ? AND user = ?")
+            .bind(id)
+            .bind(user.to_lowercase())
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(device_from_row).transpose()
+    }
+
+    /// Devices of `user`, most recently used first
+    pub async fn list(&self, user: &str) -> Result<Vec<Device>> {
+        let rows = sqlx::query("SELECT * FROM devices WHERE user = ? ORDER BY last_seen DESC")
+            .bind(user.to_lowercase())
+            .fetch_all(&self.db)
+            .await?;
+        rows.iter().map(device_from_row).collect()
+    }
+
+    /// Revoke device `id` of `user`: its app password stops working and its
+    /// API tokens are refused, so the client has to sign in again
+    ///
+    /// Returns the revoked device, or `None` if the user has no such device.
+    pub async fn revoke(&self, user: &str, id: &str) -> Result<Option<Device>> {
+        let result = sqlx::query(
+            r#"
+            UPDATE devices SET app_password_hash = NULL, revoked_at = ?
+            WHERE id = ? AND user = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .bind(user.to_lowercase())
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() > 0 {
+            info!("Revoked device {} of {}", id, user);
+        }
+        self.get(user, id).await
+    }
+
+    /// Whether device `id` of `user` may still use its credentials
+    pub async fn is_active(&self, user: &str, id: &str) -> Result<bool> {
+        Ok(self
+            .get(user, id)
+            .await?
+            .is_some_and(|device| !device.is_revoked()))
+    }
+
+    async fn has_devices(&self, user: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM devices WHERE user = ? LIMIT 1")
+            .bind(user)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn insert(&self, device: &Device, app_password_hash: Option<&str>) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO devices
+                (id, user, kind, name, app_password_hash, first_seen, last_seen)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&device.id)
+        .bind(&device.user)
+        .bind(device.kind.as_str())
+        .bind(&device.name)
+        .bind(app_password_hash)
+        .bind(device.first_seen.to_rfc3339())
+        .bind(device.last_seen.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Update the last use of `device`; returns whether `ip` is in a
+    /// network the user never signed in from
+    async fn touch(&self, device: &Device, ip: Option<IpAddr>, at: DateTime<Utc>) -> Result<bool> {
+        let network = ip.map(network_of);
+        sqlx::query(
+            r#"
+            UPDATE devices
+            SET last_seen = ?, last_ip = COALESCE(?, last_ip),
+                last_network = COALESCE(?, last_network)
+            WHERE id = ?
+            "#,
+        )
+        .bind(at.to_rfc3339())
+        .bind(ip.map(|ip| ip.to_string()))
+        .bind(&network)
+        .bind(&device.id)
+        .execute(&self.db)
+        .await?;
+
+        let Some(network) = network else {
+            return Ok(false);
+        };
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO device_networks (user, network, first_seen) VALUES (?, ?, ?)",
+        )
+        .bind(&device.user)
+        .bind(&network)
+        .bind(at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Send a security alert about a sign-in of `device` in the
+    /// background, so alerts never delay logins
+    fn alert(&self, device: &Device, ip: Option<IpAddr>, title: &str, summary: &str) {
+        let Some(router) = self.alerts.clone() else {
+            return;
+        };
+        warn!("{} for {}: {} ({:?})", title, device.user, device.name, ip);
+
+        let from = match ip {
+            Some(ip) => format!(" from {} ({})", ip, network_of(ip)),
+            None => String::new(),
+        };
+        let body = format!(
+            "{}: {} ({}){}.\n\nIf this wasn't you, revoke the device and change your password.",
+            summary,
+            device.name,
+            device.kind.as_str(),
+            from
+        );
+        let user = device.user.clone();
+        let title = title.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = router
+                .notify(&user, EventKind::SecurityAlert, &title, &body)
+                .await
+            {
+                warn!("Failed to alert {} of a sign-in: {}", user, e);
+            }
+        });
+    }
+}
+
+/// Trimmed device name, or a default naming the kind of client
+fn device_name(name: &str, kind: DeviceKind) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim();
+    if !name.is_empty() {
+        return name.to_string();
+    }
+    match kind {
+        DeviceKind::Imap => "IMAP client",
+        DeviceKind::Smtp => "SMTP client",
+        DeviceKind::Api => "API client",
+        DeviceKind::AppPassword => "App password",
+    }
+    .to_string()
+}
+
+/// Random password like `abcd-efgh-jkmn-pqrs`
+fn generate_app_password() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+
+    (0..APP_PASSWORD_GROUPS)
+        .map(|_| {
+            (0..4)
+                .map(|_| APP_PASSWORD_CHARSET[rng.gen_range(0..APP_PASSWORD_CHARSET.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// App passwords are random, so a fast hash is enough to protect them
+fn hash_app_password(password: &str) -> String {
+    Sha256::digest(password.to_lowercase().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn device_from_row(row: &SqliteRow) -> Result<Device> {
+    let kind: String = row.get("kind");
+    Ok(Device {
+        id: row.get("id"),
+        user: row.get("user"),
+        kind: DeviceKind::parse(&kind).ok_or_else(|| anyhow!("Unknown device kind {}", kind))?,
+        name: row.get("name"),
+        first_seen: parse_time(row.get("first_seen"))?,
+        last_seen: parse_time(row.get("last_seen"))?,
+        last_ip: row.get("last_ip"),
+        last_network: row.get("last_network"),
+        revoked_at: row
+            .get::<Option<String>, _>("revoked_at")
+            .map(parse_time)
+            .transpose()?,
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::NotificationManager;
+    use crate::smtp::SmtpQueue;
+    use chrono::Duration;
+
+    async fn manager() -> DeviceManager {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = DeviceManager::new(db);
+        manager.init_db().await.unwrap();
+        manager
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_record_login() {
+        let manager = manager().await;
+        let start = Utc::now();
+
+        let laptop = manager
+            .record_login_at(
+                "Alice@example.com",
+                DeviceKind::Imap,
+                "Thunderbird 128.0",
+                ip("203.0.113.7"),
+                start,
+            )
+            .await
+            .unwrap();
+        assert_eq!(laptop.user, "alice@example.com");
+        assert_eq!(laptop.last_network.as_deref(), Some("203.0.0.0/16"));
+
+        // The same client again is the same device
+        let later = start + Duration::minutes(5);
+        let again = manager
+            .record_login_at(
+                "alice@example.com",
+                DeviceKind::Imap,
+                "Thunderbird 128.0",
+                ip("198.51.100.1"),
+                later,
+            )
+            .await
+            .unwrap();
+        assert_eq!(again.id, laptop.id);
+        assert_eq!(again.last_seen, later);
+        assert_eq!(again.last_ip.as_deref(), Some("198.51.100.1"));
+
+        manager
+            .record_login("alice@example.com", DeviceKind::Api, "", None)
+            .await
+            .unwrap();
+        let devices = manager.list("alice@example.com").await.unwrap();
+        assert_eq!(devices.len(), 2);
+        // Most recently used first
+        assert_eq!(devices[0].id, laptop.id);
+        assert_eq!(devices[1].name, "API client");
+        assert!(manager.list("bob@example.com").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_app_password_and_revoke() {
+        let manager = manager().await;
+        let created = manager
+            .create_app_password("alice@example.com", " Phone ")
+            .await
+            .unwrap();
+        assert_eq!(created.device.name, "Phone");
+        assert_eq!(created.password.len(), 19);
+
+        let used = manager
+            .app_password_login(
+                "ALICE@example.com",
+                &created.password.to_uppercase(),
+                ip("203.0.113.7"),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(used.id, created.device.id);
+        assert_eq!(used.last_ip.as_deref(), Some("203.0.113.7"));
+        for (user, password) in [
+            ("bob@example.com", created.password.as_str()),
+            ("alice@example.com", "abcd-efgh-jkmn-pqrs"),
+        ] {
+            assert!(manager
+                .app_password_login(user, password, None)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        // Only the owner can revoke a device
+        assert!(manager
+            .revoke("bob@example.com", &used.id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(manager
+            .is_active("alice@example.com", &used.id)
+            .await
+            .unwrap());
+        let revoked = manager
+            .revoke("alice@example.com", &used.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(revoked.is_revoked());
+        assert!(!manager
+            .is_active("alice@example.com", &used.id)
+            .await
+            .unwrap());
+        assert!(manager
+            .app_password_login("alice@example.com", &created.password, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Signing in with the account password restores a revoked client
+        let api = manager
+            .record_login("alice@example.com", DeviceKind::Api, "curl/8.0", None)
+            .await
+            .unwrap();
+        manager.revoke("alice@example.com", &api.id).await.unwrap();
+        let api = manager
+            .record_login("alice@example.com", DeviceKind::Api, "curl/8.0", None)
+            .await
+            .unwrap();
+        assert!(!api.is_revoked());
+    }
+
+    #[tokio::test]
+    async fn test_alerts() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("queue.db").display()
+        );
+        let queue = Arc::new(SmtpQueue::new(&url).await.unwrap());
+        let notifications = NotificationManager::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let router = NotificationRouter::new(Arc::new(notifications)).with_queue(queue.clone());
+        let manager = manager().await.with_alerts(Arc::new(router));
+
+        let login = |name: &'static str, address: &'static str| {
+            let manager = &manager;
+            async move {
+                manager
+                    .record_login("alice@example.com", DeviceKind::Imap, name, ip(address))
+                    .await
+                    .unwrap();
+                // Alerts are sent in the background
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        };
+        let alerts = || {
+            let queue = &queue;
+            async move {
+                queue
+                    .get_pending(10)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|email| String::from_utf8_lossy(&email.data).to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // The first device of a user is expected
+        login("Thunderbird", "203.0.113.7").await;
+        login("Thunderbird", "203.0.9.9").await;
+        assert!(alerts().await.is_empty());
+
+        login("iPhone Mail", "203.0.113.8").await;
+        let sent = alerts().await;
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains("Subject: New device signed in\r\n"));
+        assert!(sent[0].contains("iPhone Mail (imap) from 203.0.113.8"));
+
+        login("Thunderbird", "192.0.2.1").await;
+        let sent = alerts().await;
+        assert_eq!(sent.len(), 2);
+        assert!(sent
+            .iter()
+            .any(|text| text.contains("Subject: Sign-in from a new location\r\n")));
+    }
+}
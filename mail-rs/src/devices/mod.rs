@@ -0,0 +1,15 @@
+//! Client devices of users
+//!
+//! Every client that signs in is recorded as a device: IMAP clients by
+//! their IMAP ID, submission clients by their EHLO name, API clients by
+//! their user agent. Users can also create app passwords, each bound to a
+//! device of its own. Revoking a device invalidates its app password and
+//! API tokens, so the client has to sign in again with the account
+//! password. Sign-ins from new devices or from networks the user never
+//! used raise a security alert.
+
+pub mod manager;
+pub mod types;
+
+pub use manager::DeviceManager;
+pub use types::*;
@@ -0,0 +1,121 @@
+//! Device types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// How a device signs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    /// IMAP client with the account password, named by its IMAP ID
+    Imap,
+    /// SMTP submission client with the account password, named by its EHLO
+    Smtp,
+    /// REST API client, named by its user agent
+    Api,
+    /// Client with its own app password, named by the user
+    AppPassword,
+}
+
+impl DeviceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceKind::Imap => "imap",
+            DeviceKind::Smtp => "smtp",
+            DeviceKind::Api => "api",
+            DeviceKind::AppPassword => "app_password",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            DeviceKind::Imap,
+            DeviceKind::Smtp,
+            DeviceKind::Api,
+            DeviceKind::AppPassword,
+        ]
+        .into_iter()
+        .find(|kind| kind.as_str() == value)
+    }
+}
+
+/// A client device of a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Device {
+    pub id: String,
+    pub user: String,
+    pub kind: DeviceKind,
+    /// Client name, e.g. `Thunderbird 128.0` or the app password's label
+    pub name: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub last_ip: Option<String>,
+    /// Network of the last login, see [`network_of`]
+    pub last_network: Option<String>,
+    /// Revoked devices have lost their app password and API tokens
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Device {
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+}
+
+/// Coarse location of an address: its /16 IPv4 or /48 IPv6 network
+///
+/// No GeoIP database is bundled, so a login from a network the user never
+/// signed in from stands in for an unusual location.
+pub fn network_of(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            format!("{}.{}.0.0/16", a, b)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return network_of(IpAddr::V4(ip));
+            }
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    }
+}
+
+/// Request to create an app password
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateAppPassword {
+    /// Label of the device, e.g. `Phone`
+    pub name: String,
+}
+
+/// A new app password, shown once
+#[derive(Debug, Clone, Serialize)]
+pub struct AppPassword {
+    pub device: Device,
+    pub password: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_of() {
+        assert_eq!(network_of("203.0.113.7".parse().unwrap()), "203.0.0.0/16");
+        assert_eq!(
+            network_of("2001:db8:1:2::5".parse().unwrap()),
+            "2001:db8:1::/48"
+        );
+        assert_eq!(
+            network_of("::ffff:198.51.100.1".parse().unwrap()),
+            "198.51.0.0/16"
+        );
+        assert_eq!(
+            DeviceKind::parse("app_password"),
+            Some(DeviceKind::AppPassword)
+        );
+        assert_eq!(DeviceKind::parse("pop3"), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! DKIM key generation, encryption at rest and signing
+
+use super::types::DkimAlgorithm;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use mail_auth::common::crypto::{Ed25519Key, RsaKey, Sha256};
+use mail_auth::common::headers::HeaderWriter;
+use mail_auth::dkim::{DkimSigner, Done};
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+/// Headers covered by signatures; those missing from a message are skipped
+const SIGNED_HEADERS: [&str; 11] = [
+    "From",
+    "Sender",
+    "Reply-To",
+    "To",
+    "Cc",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+];
+
+/// A new key pair
+pub struct KeyPair {
+    /// PKCS#8 DER private key
+    pub private_key: Vec<u8>,
+    /// What the `p=` tag publishes: the SubjectPublicKeyInfo DER of RSA
+    /// keys, the raw 32 bytes of Ed25519 keys
+    pub public_key: Vec<u8>,
+}
+
+/// Generate a key pair; RSA keys of `rsa_bits` take a while, so call this
+/// off the async runtime
+pub fn generate(algorithm: DkimAlgorithm, rsa_bits: usize) -> Result<KeyPair> {
+    match algorithm {
+        DkimAlgorithm::Rsa => {
+            let private_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, rsa_bits)?;
+            let public_key = RsaPublicKey::from(&private_key).to_public_key_der()?;
+            Ok(KeyPair {
+                private_key: private_key.to_pkcs8_der()?.as_bytes().to_vec(),
+                public_key: public_key.as_bytes().to_vec(),
+            })
+        }
+        DkimAlgorithm::Ed25519 => {
+            let private_key = Ed25519Key::generate_pkcs8().map_err(|e| anyhow!("{}", e))?;
+            let public_key = Ed25519Key::from_pkcs8_der(&private_key)
+                .map_err(|e| anyhow!("{}", e))?
+                .public_key();
+            Ok(KeyPair {
+                private_key,
+                public_key,
+            })
+        }
+    }
+}
+
+/// TXT record value publishing `public_key`
+pub fn dns_value(algorithm: DkimAlgorithm, public_key: &[u8]) -> String {
+    format!(
+        "v=DKIM1; k={}; p={}",
+        algorithm.as_str(),
+        BASE64.encode(public_key)
+    )
+}
+
+/// Encrypts private keys at rest with AES-256-GCM under a key derived
+/// from the configured secret
+pub struct KeyCipher {
+    key: LessSafeKey,
+}
+
+impl KeyCipher {
+    pub fn new(secret: &str) -> Result<Self> {
+        let prk = Salt::new(HKDF_SHA256, b"mail-rs dkim keys").extract(secret.as_bytes());
+        let okm = prk
+            .expand(&[], &AES_256_GCM)
+            .map_err(|_| anyhow!("Failed to derive key encryption key"))?;
+        Ok(Self {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+        })
+    }
+
+    /// Nonce followed by the ciphertext and tag
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let mut sealed = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt key"))?;
+        sealed.splice(0..0, nonce);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted key is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce =
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid nonce"))?;
+        let mut plaintext = ciphertext.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut plaintext)
+            .map_err(|_| anyhow!("Wrong key secret or damaged key"))?
+            .len();
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+}
+
+/// Signs messages for one domain and selector
+pub enum Signer {
+    Rsa(DkimSigner<RsaKey<Sha256>, Done>),
+    Ed25519(DkimSigner<Ed25519Key, Done>),
+}
+
+impl Signer {
+    pub fn new(
+        algorithm: DkimAlgorithm,
+        private_key: &[u8],
+        domain: &str,
+        selector: &str,
+    ) -> Result<Self> {
+        Ok(match algorithm {
+            DkimAlgorithm::Rsa => Self::Rsa(
+                DkimSigner::from_key(
+                    RsaKey::<Sha256>::from_pkcs8_der(private_key).map_err(|e| anyhow!("{}", e))?,
+                )
+                .domain(domain)
+                .selector(selector)
+                .headers(SIGNED_HEADERS),
+            ),
+            DkimAlgorithm::Ed25519 => Self::Ed25519(
+                DkimSigner::from_key(
+                    Ed25519Key::from_pkcs8_der(private_key).map_err(|e| anyhow!("{}", e))?,
+                )
+                .domain(domain)
+                .selector(selector)
+                .headers(SIGNED_HEADERS),
+            ),
+        })
+    }
+
+    /// `DKIM-Signature` header of `message`, ending in CRLF
+    pub fn sign(&self, message: &[u8]) -> Result<String> {
+        let signature = match self {
+            Self::Rsa(signer) => signer.sign(message),
+            Self::Ed25519(signer) => signer.sign(message),
+        }
+        .map_err(|e| anyhow!("DKIM signing failed: {}", e))?;
+        Ok(signature.to_header())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cipher_round_trip() {
+        let cipher = KeyCipher::new("secret").unwrap();
+        let sealed = cipher.seal(b"private key").unwrap();
+        assert_eq!(sealed.len(), NONCE_LEN + 11 + 16);
+        assert_eq!(cipher.open(&sealed).unwrap(), b"private key");
+        assert!(KeyCipher::new("other").unwrap().open(&sealed).is_err());
+        assert!(cipher.open(&sealed[..4]).is_err());
+    }
+
+    #[test]
+    fn test_ed25519_dns_value() {
+        let pair = generate(DkimAlgorithm::Ed25519, 0).unwrap();
+        assert_eq!(pair.public_key.len(), 32);
+        let value = dns_value(DkimAlgorithm::Ed25519, &pair.public_key);
+        assert!(value.starts_with("v=DKIM1; k=ed25519; p="));
+        assert_eq!(value.len(), "v=DKIM1; k=ed25519; p=".len() + 44);
+
+        let signer = Signer::new(
+            DkimAlgorithm::Ed25519,
+            &pair.private_key,
+            "example.com",
+            "ed",
+        )
+        .unwrap();
+        let header = signer
+            .sign(b"From: a@example.com\r\nTo: b@example.net\r\nSubject: Hi\r\n\r\nHello\r\n")
+            .unwrap();
+        assert!(header.starts_with("DKIM-Signature: v=1; a=ed25519-sha256;"));
+        assert!(header.contains("d=example.com"));
+        assert!(header.contains("s=ed"));
+        assert!(header.ends_with("\r\n"));
+    }
+}
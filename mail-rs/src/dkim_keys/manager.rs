@@ -0,0 +1,416 @@
+//! DKIM key manager: stored keys and signing of outbound mail
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use mail_parser::MessageParser;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use super::crypto::{self, KeyCipher, Signer};
+use super::types::*;
+use crate::authentication::TtlCache;
+use crate::config::DkimSigningConfig;
+
+/// How long the active key of a domain is kept decrypted
+const SIGNER_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Most domains whose signer is cached
+const SIGNER_CACHE_CAPACITY: usize = 1_000;
+
+const COLUMNS: &str = "domain, selector, algorithm, public_key, active, created_at";
+
+/// DKIM key manager
+pub struct DkimKeyManager {
+    db: SqlitePool,
+    cipher: KeyCipher,
+    rsa_bits: usize,
+    /// Signer of each domain's active key, None for domains without one
+    signers: TtlCache<String, Option<Arc<Signer>>>,
+}
+
+impl DkimKeyManager {
+    /// Create a new key manager; fails without `key_secret`
+    pub fn new(db: SqlitePool, config: &DkimSigningConfig) -> Result<Self> {
+        if config.key_secret.is_empty() {
+            return Err(anyhow!("dkim_signing.key_secret is required"));
+        }
+        Ok(Self {
+            db,
+            cipher: KeyCipher::new(&config.key_secret)?,
+            rsa_bits: config.rsa_bits,
+            signers: TtlCache::new(SIGNER_CACHE_TTL, SIGNER_CACHE_CAPACITY),
+        })
+    }
+
+    /// Connect to `database_url` and create the key table
+    pub async fn connect(database_url: &str, config: &DkimSigningConfig) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?, config)?;
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // private_key is encrypted, see KeyCipher
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS dkim_keys (
+                domain TEXT NOT NULL,
+                selector TEXT NOT NULL,
+                algorithm TEXT NOT NULL,
+                private_key BLOB NOT NULL,
+                public_key BLOB NOT NULL,
+                active INTEGER NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (domain, selector)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Generate a key for `domain` and `selector` and make it the domain's
+    /// active key; None if the selector is taken
+    pub async fn generate(
+        &self,
+        domain: &str,
+        selector: &str,
+        algorithm: DkimAlgorithm,
+    ) -> Result<Option<DkimKey>> {
+        let domain = domain.to_lowercase();
+        let selector = selector.to_lowercase();
+        if self.get(&domain, &selector).await?.is_some() {
+            return Ok(None);
+        }
+
+        let rsa_bits = self.rsa_bits;
+        let pair =
+            tokio::task::spawn_blocking(move || crypto::generate(algorithm, rsa_bits)).await??;
+        let private_key = self.cipher.seal(&pair.private_key)?;
+
+        let mut tx = self.db.begin().await?;
+        sqlx::query("UPDATE dkim_keys SET active = 0 WHERE domain = ?")
+            .bind(&domain)
+            .execute(&mut *tx)
+            .await?;
+        let inserted = sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO dkim_keys
+                (domain, selector, algorithm, private_key, public_key, active, created_at)
+            VALUES (?, ?, ?, ?, ?, 1, ?)
+            "#,
+        )
+        .bind(&domain)
+        .bind(&selector)
+        .bind(algorithm.as_str())
+        .bind(private_key)
+        .bind(&pair.public_key)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            return Ok(None);
+        }
+        tx.commit().await?;
+        self.signers.remove(&domain);
+
+        info!(
+            "Generated {} DKIM key {} for {}",
+            algorithm.as_str(),
+            selector,
+            domain
+        );
+        self.get(&domain, &selector).await
+    }
+
+    /// Keys of `domain`, or of every domain, newest first
+    pub async fn list(&self, domain: Option<&str>) -> Result<Vec<DkimKey>> {
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT {} FROM dkim_keys
+            WHERE (?1 IS NULL OR domain = ?1)
+            ORDER BY domain, created_at DESC
+            "#,
+            COLUMNS
+        ))
+        .bind(domain.map(str::to_lowercase))
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(key_from_row).collect()
+    }
+
+    /// Key of `domain` and `selector`
+    pub async fn get(&self, domain: &str, selector: &str) -> Result<Option<DkimKey>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM dkim_keys WHERE domain = ? AND selector = ?",
+            COLUMNS
+        ))
+        .bind(domain.to_lowercase())
+        .bind(selector.to_lowercase())
+        .fetch_optional(&self.db)
+        .await?;
+        row.as_ref().map(key_from_row).transpose()
+    }
+
+    /// Sign mail of `domain` with `selector` from now on; false without
+    /// such a key
+    pub async fn activate(&self, domain: &str, selector: &str) -> Result<bool> {
+        let domain = domain.to_lowercase();
+        let mut tx = self.db.begin().await?;
+        let found = sqlx::query("SELECT 1 FROM dkim_keys WHERE domain = ? AND selector = ?")
+            .bind(&domain)
+            .bind(selector.to_lowercase())
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        if !found {
+            return Ok(false);
+        }
+        sqlx::query("UPDATE dkim_keys SET active = (selector = ?) WHERE domain = ?")
+            .bind(selector.to_lowercase())
+            .bind(&domain)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.signers.remove(&domain);
+        Ok(true)
+    }
+
+    /// Delete a key; mail of the domain is no longer signed if it was the
+    /// active one. False without such a key
+    pub async fn delete(&self, domain: &str, selector: &str) -> Result<bool> {
+        let domain = domain.to_lowercase();
+        let deleted = sqlx::query("DELETE FROM dkim_keys WHERE domain = ? AND selector = ?")
+            .bind(&domain)
+            .bind(selector.to_lowercase())
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        self.signers.remove(&domain);
+        Ok(deleted > 0)
+    }
+
+    /// `message` with a `DKIM-Signature` header prepended, signed with the
+    /// active key of its From domain; None if the domain has no key or the
+    /// message already carries a signature of the domain
+    pub async fn sign(&self, message: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(domain) = from_domain(message) else {
+            return Ok(None);
+        };
+        if signed_by(message, &domain) {
+            debug!("Message is already signed for {}", domain);
+            return Ok(None);
+        }
+        let Some(signer) = self.signer(&domain).await? else {
+            return Ok(None);
+        };
+
+        let header = signer.sign(message)?;
+        let mut signed = Vec::with_capacity(header.len() + message.len());
+        signed.extend_from_slice(header.as_bytes());
+        signed.extend_from_slice(message);
+        Ok(Some(signed))
+    }
+
+    /// Signer of the active key of `domain`
+    async fn signer(&self, domain: &str) -> Result<Option<Arc<Signer>>> {
+        if let Some(signer) = self.signers.get(&domain.to_string()) {
+            return Ok(signer);
+        }
+
+        let row = sqlx::query(
+            "SELECT selector, algorithm, private_key FROM dkim_keys WHERE domain = ? AND active = 1",
+        )
+        .bind(domain)
+        .fetch_optional(&self.db)
+        .await?;
+        let signer = match row {
+            Some(row) => {
+                let selector: String = row.get("selector");
+                let algorithm: String = row.get("algorithm");
+                let algorithm = DkimAlgorithm::parse(&algorithm)
+                    .ok_or_else(|| anyhow!("Unknown DKIM algorithm {}", algorithm))?;
+                let private_key = self.cipher.open(row.get("private_key"))?;
+                Some(Arc::new(Signer::new(
+                    algorithm,
+                    &private_key,
+                    domain,
+                    &selector,
+                )?))
+            }
+            None => None,
+        };
+        self.signers.insert(domain.to_string(), signer.clone());
+        Ok(signer)
+    }
+}
+
+fn key_from_row(row: &SqliteRow) -> Result<DkimKey> {
+    let domain: String = row.get("domain");
+    let selector: String = row.get("selector");
+    let algorithm: String = row.get("algorithm");
+    let algorithm = DkimAlgorithm::parse(&algorithm)
+        .ok_or_else(|| anyhow!("Unknown DKIM algorithm {}", algorithm))?;
+    let public_key: Vec<u8> = row.get("public_key");
+    let created_at: String = row.get("created_at");
+    Ok(DkimKey {
+        dns_name: format!("{}._domainkey.{}", selector, domain),
+        dns_value: crypto::dns_value(algorithm, &public_key),
+        domain,
+        selector,
+        algorithm,
+        active: row.get("active"),
+        created_at: DateTime::parse_from_rfc3339(&created_at)?.with_timezone(&Utc),
+    })
+}
+
+/// Domain of the From header, lowercase
+fn from_domain(message: &[u8]) -> Option<String> {
+    let parsed = MessageParser::new().parse_headers(message)?;
+    let address = parsed.from()?.first()?.address()?;
+    let (_, domain) = address.rsplit_once('@')?;
+    Some(domain.to_lowercase())
+}
+
+/// Whether a DKIM-Signature header of `message` has `d=domain`
+fn signed_by(message: &[u8], domain: &str) -> bool {
+    let Some(parsed) = MessageParser::new().parse_headers(message) else {
+        return false;
+    };
+    let signed = parsed
+        .header_values("DKIM-Signature")
+        .filter_map(|value| value.as_text())
+        .any(|value| {
+            value.split(';').any(|tag| {
+                let tag: String = tag.chars().filter(|c| !c.is_whitespace()).collect();
+                tag.strip_prefix("d=")
+                    .is_some_and(|d| d.eq_ignore_ascii_case(domain))
+            })
+        });
+    signed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: Alice <alice@Example.com>\r\n\
+        To: bob@example.net\r\n\
+        Subject: Hi\r\n\r\n\
+        Hello\r\n";
+
+    async fn manager() -> DkimKeyManager {
+        let config = DkimSigningConfig {
+            enabled: true,
+            key_secret: "secret".to_string(),
+            ..DkimSigningConfig::default()
+        };
+        DkimKeyManager::connect("sqlite::memory:", &config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_generate_rotate_and_sign() {
+        let manager = manager().await;
+        assert!(manager.sign(MESSAGE).await.unwrap().is_none());
+
+        let first = manager
+            .generate("Example.com", "ed1", DkimAlgorithm::Ed25519)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(first.active);
+        assert_eq!(first.dns_name, "ed1._domainkey.example.com");
+        assert!(first.dns_value.starts_with("v=DKIM1; k=ed25519; p="));
+        assert!(manager
+            .generate("example.com", "ED1", DkimAlgorithm::Ed25519)
+            .await
+            .unwrap()
+            .is_none());
+
+        let signed = manager.sign(MESSAGE).await.unwrap().unwrap();
+        let signed = String::from_utf8(signed).unwrap();
+        assert!(signed.starts_with("DKIM-Signature: "));
+        assert!(signed.contains("s=ed1"));
+        assert!(signed.ends_with("\r\n\r\nHello\r\n"));
+        // Not signed twice for the same domain
+        assert!(manager.sign(signed.as_bytes()).await.unwrap().is_none());
+
+        manager
+            .generate("example.com", "ed2", DkimAlgorithm::Ed25519)
+            .await
+            .unwrap()
+            .unwrap();
+        let keys = manager.list(Some("example.com")).await.unwrap();
+        let active: Vec<&str> = keys
+            .iter()
+            .filter(|key| key.active)
+            .map(|key| key.selector.as_str())
+            .collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(active, ["ed2"]);
+        let signed = manager.sign(MESSAGE).await.unwrap().unwrap();
+        assert!(String::from_utf8(signed).unwrap().contains("s=ed2"));
+
+        assert!(manager.activate("example.com", "ed1").await.unwrap());
+        assert!(!manager.activate("example.com", "nope").await.unwrap());
+        assert!(
+            manager
+                .get("example.com", "ed1")
+                .await
+                .unwrap()
+                .unwrap()
+                .active
+        );
+
+        assert!(manager.delete("example.com", "ed1").await.unwrap());
+        assert!(!manager.delete("example.com", "ed1").await.unwrap());
+        assert!(manager.sign(MESSAGE).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sign_with_rsa_key() {
+        let manager = manager().await;
+        // Generating an RSA key is slow in debug builds: store a fixture
+        let pem = include_str!("../../test_data/dkim/dkim_private.pem");
+        let der = rsa::pkcs8::DecodePrivateKey::from_pkcs8_pem(pem)
+            .map(|key: rsa::RsaPrivateKey| {
+                rsa::pkcs8::EncodePrivateKey::to_pkcs8_der(&key)
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec()
+            })
+            .unwrap();
+        sqlx::query("INSERT INTO dkim_keys VALUES ('example.com', 'rsa', 'rsa', ?, x'00', 1, ?)")
+            .bind(manager.cipher.seal(&der).unwrap())
+            .bind(Utc::now().to_rfc3339())
+            .execute(&manager.db)
+            .await
+            .unwrap();
+
+        let signed = manager.sign(MESSAGE).await.unwrap().unwrap();
+        let signed = String::from_utf8(signed).unwrap();
+        assert!(signed.starts_with("DKIM-Signature: v=1; a=rsa-sha256;"));
+        assert!(signed.contains("d=example.com"));
+    }
+
+    #[test]
+    fn test_from_domain_and_signed_by() {
+        assert_eq!(from_domain(MESSAGE).as_deref(), Some("example.com"));
+        assert_eq!(from_domain(b"Subject: x\r\n\r\n"), None);
+
+        let signed = b"DKIM-Signature: v=1; a=rsa-sha256;\r\n\td=example.com; s=old; b=abc\r\n\
+            From: alice@example.com\r\n\r\n";
+        assert!(signed_by(signed, "example.com"));
+        assert!(!signed_by(signed, "other.example"));
+        assert!(!signed_by(MESSAGE, "example.com"));
+    }
+}
@@ -0,0 +1,16 @@
+//! Per-domain DKIM keys signing outbound mail
+//!
+//! Admins generate RSA or Ed25519 keys per domain and selector through the
+//! admin API, which shows the TXT record to publish at
+//! `<selector>._domainkey.<domain>`. Private keys are stored encrypted with
+//! AES-256-GCM under `[dkim_signing] key_secret`. The newest key of a
+//! domain is its active one; with `[dkim_signing] enabled`, the outbound
+//! queue signs every message whose From domain has an active key, unless
+//! it already carries a signature of that domain.
+
+pub mod crypto;
+pub mod manager;
+pub mod types;
+
+pub use manager::DkimKeyManager;
+pub use types::*;
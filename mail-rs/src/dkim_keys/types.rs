@@ -0,0 +1,46 @@
+//! DKIM key types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Signing algorithm of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimAlgorithm {
+    /// rsa-sha256, understood by every verifier
+    Rsa,
+    /// ed25519-sha256 (RFC 8463), usually published next to an RSA key
+    Ed25519,
+}
+
+impl DkimAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rsa => "rsa",
+            Self::Ed25519 => "ed25519",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rsa" => Some(Self::Rsa),
+            "ed25519" => Some(Self::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+/// A domain's key, without its private part
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DkimKey {
+    pub domain: String,
+    pub selector: String,
+    pub algorithm: DkimAlgorithm,
+    /// Whether mail from the domain is signed with this key
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    /// Name of the TXT record, e.g. `mail._domainkey.example.com`
+    pub dns_name: String,
+    /// Value of the TXT record, e.g. `v=DKIM1; k=rsa; p=MIIB...`
+    pub dns_value: String,
+}
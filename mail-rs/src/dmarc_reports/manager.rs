@@ -0,0 +1,437 @@
+//! Received report manager: storage, listings and per-source breakdowns
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use super::parse;
+use super::types::*;
+
+/// Received report manager
+pub struct DmarcReportManager {
+    db: SqlitePool,
+    /// Recipients whose mail is ingested, lowercase
+    addresses: Vec<String>,
+    retention: Duration,
+}
+
+impl DmarcReportManager {
+    /// Create a new report manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self {
+            db,
+            addresses: Vec::new(),
+            retention: Duration::days(180),
+        }
+    }
+
+    /// Connect to `database_url` and create the report tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Ingest mail addressed to `addresses`
+    pub fn with_addresses(mut self, addresses: &[String]) -> Self {
+        self.addresses = addresses.iter().map(|a| a.trim().to_lowercase()).collect();
+        self
+    }
+
+    /// Delete reports whose period ended more than `days` ago on expiry
+    pub fn with_retention_days(mut self, days: u32) -> Self {
+        self.retention = Duration::days(days.into());
+        self
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS received_reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                org_name TEXT NOT NULL,
+                report_id TEXT NOT NULL,
+                domain TEXT NOT NULL,
+                begin_at TEXT NOT NULL,
+                end_at TEXT NOT NULL,
+                received_at TEXT NOT NULL,
+                UNIQUE(kind, org_name, report_id)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        // source_ip is NULL for the successful sessions of TLS reports
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS received_report_rows (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                report INTEGER NOT NULL,
+                source_ip TEXT,
+                count INTEGER NOT NULL,
+                passed INTEGER NOT NULL,
+                dkim TEXT,
+                spf TEXT,
+                disposition TEXT,
+                result_type TEXT
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_received_report_rows_report ON received_report_rows(report)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether mail to `recipient` carries reports
+    pub fn is_report_address(&self, recipient: &str) -> bool {
+        self.addresses
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(recipient))
+    }
+
+    /// Store the reports attached to `message`; returns how many were new
+    pub async fn ingest(&self, message: &[u8]) -> Result<usize> {
+        let mut stored = 0;
+        for report in parse::parse_message(message)? {
+            match self.store(&report).await? {
+                Some(_) => {
+                    info!(
+                        "Stored {} report {} from {} for {}",
+                        report.kind.as_str(),
+                        report.report_id,
+                        report.org_name,
+                        report.domain
+                    );
+                    stored += 1;
+                }
+                None => info!(
+                    "Ignoring duplicate {} report {} from {}",
+                    report.kind.as_str(),
+                    report.report_id,
+                    report.org_name
+                ),
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Store a report with its rows; returns its id, or None if the same
+    /// report was stored before
+    pub async fn store(&self, report: &ParsedReport) -> Result<Option<i64>> {
+        let mut tx = self.db.begin().await?;
+        let result = sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO received_reports
+                (kind, org_name, report_id, domain, begin_at, end_at, received_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(report.kind.as_str())
+        .bind(&report.org_name)
+        .bind(&report.report_id)
+        .bind(report.domain.to_lowercase())
+        .bind(report.begin.to_rfc3339())
+        .bind(report.end.to_rfc3339())
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let id = result.last_insert_rowid();
+        for row in &report.rows {
+            sqlx::query(
+                r#"
+                INSERT INTO received_report_rows
+                    (report, source_ip, count, passed, dkim, spf, disposition, result_type)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(id)
+            .bind(&row.source_ip)
+            .bind(row.count)
+            .bind(row.passed)
+            .bind(&row.dkim)
+            .bind(&row.spf)
+            .bind(&row.disposition)
+            .bind(&row.result_type)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(Some(id))
+    }
+
+    /// Reports matching `filter` with their totals, latest period first
+    pub async fn list(&self, filter: &ReportFilter, limit: i64) -> Result<Vec<ReceivedReport>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT r.*,
+                   COALESCE(SUM(CASE WHEN w.passed THEN w.count ELSE 0 END), 0) AS passed,
+                   COALESCE(SUM(CASE WHEN w.passed THEN 0 ELSE w.count END), 0) AS failed
+            FROM received_reports r
+            LEFT JOIN received_report_rows w ON w.report = r.id
+            WHERE (?1 IS NULL OR r.kind = ?1)
+              AND (?2 IS NULL OR r.domain = ?2)
+              AND (?3 IS NULL OR r.end_at >= ?3)
+            GROUP BY r.id
+            ORDER BY r.end_at DESC, r.id DESC
+            LIMIT ?4
+            "#,
+        )
+        .bind(filter.kind.map(|kind| kind.as_str()))
+        .bind(filter.domain.as_ref().map(|domain| domain.to_lowercase()))
+        .bind(filter.since.map(|since| since.to_rfc3339()))
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(report_from_row).collect()
+    }
+
+    /// Report `id` with its rows
+    pub async fn get(&self, id: i64) -> Result<Option<ReportDetail>> {
+        let row = sqlx::query(
+            r#"
+            SELECT r.*,
+                   COALESCE(SUM(CASE WHEN w.passed THEN w.count ELSE 0 END), 0) AS passed,
+                   COALESCE(SUM(CASE WHEN w.passed THEN 0 ELSE w.count END), 0) AS failed
+            FROM received_reports r
+            LEFT JOIN received_report_rows w ON w.report = r.id
+            WHERE r.id = ?
+            GROUP BY r.id
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let rows = sqlx::query("SELECT * FROM received_report_rows WHERE report = ? ORDER BY id")
+            .bind(id)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(Some(ReportDetail {
+            report: report_from_row(&row)?,
+            rows: rows
+                .iter()
+                .map(|row| ReportRow {
+                    source_ip: row.get("source_ip"),
+                    count: row.get("count"),
+                    passed: row.get("passed"),
+                    dkim: row.get("dkim"),
+                    spf: row.get("spf"),
+                    disposition: row.get("disposition"),
+                    result_type: row.get("result_type"),
+                })
+                .collect(),
+        }))
+    }
+
+    /// Pass and fail counts per source IP across the reports matching
+    /// `filter`, most failures first
+    pub async fn sources(&self, filter: &ReportFilter) -> Result<Vec<SourceBreakdown>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT w.source_ip,
+                   COUNT(DISTINCT w.report) AS reports,
+                   SUM(CASE WHEN w.passed THEN w.count ELSE 0 END) AS passed,
+                   SUM(CASE WHEN w.passed THEN 0 ELSE w.count END) AS failed
+            FROM received_report_rows w
+            JOIN received_reports r ON r.id = w.report
+            WHERE (?1 IS NULL OR r.kind = ?1)
+              AND (?2 IS NULL OR r.domain = ?2)
+              AND (?3 IS NULL OR r.end_at >= ?3)
+            GROUP BY w.source_ip
+            ORDER BY failed DESC, passed DESC, w.source_ip
+            "#,
+        )
+        .bind(filter.kind.map(|kind| kind.as_str()))
+        .bind(filter.domain.as_ref().map(|domain| domain.to_lowercase()))
+        .bind(filter.since.map(|since| since.to_rfc3339()))
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| SourceBreakdown {
+                source_ip: row.get("source_ip"),
+                reports: row.get("reports"),
+                passed: row.get("passed"),
+                failed: row.get("failed"),
+            })
+            .collect())
+    }
+
+    /// Delete reports whose period ended before `before`
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<u64> {
+        let before = before.to_rfc3339();
+        sqlx::query(
+            "DELETE FROM received_report_rows WHERE report IN (SELECT id FROM received_reports WHERE end_at < ?)",
+        )
+        .bind(&before)
+        .execute(&self.db)
+        .await?;
+
+        let result = sqlx::query("DELETE FROM received_reports WHERE end_at < ?")
+            .bind(&before)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Every `interval`, delete reports older than the retention period
+    pub async fn run_expiry(self: Arc<Self>, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.prune(Utc::now() - self.retention).await {
+                Ok(0) => {}
+                Ok(pruned) => info!("Removed {} expired aggregate reports", pruned),
+                Err(e) => warn!("Failed to expire aggregate reports: {}", e),
+            }
+        }
+    }
+}
+
+fn report_from_row(row: &SqliteRow) -> Result<ReceivedReport> {
+    let kind: String = row.get("kind");
+    let begin: String = row.get("begin_at");
+    let end: String = row.get("end_at");
+    let received: String = row.get("received_at");
+    Ok(ReceivedReport {
+        id: row.get("id"),
+        kind: ReportKind::parse(&kind).unwrap_or(ReportKind::Dmarc),
+        org_name: row.get("org_name"),
+        report_id: row.get("report_id"),
+        domain: row.get("domain"),
+        begin: DateTime::parse_from_rfc3339(&begin)?.with_timezone(&Utc),
+        end: DateTime::parse_from_rfc3339(&end)?.with_timezone(&Utc),
+        received_at: DateTime::parse_from_rfc3339(&received)?.with_timezone(&Utc),
+        passed: row.get("passed"),
+        failed: row.get("failed"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn row(source_ip: Option<&str>, count: i64, passed: bool) -> ReportRow {
+        ReportRow {
+            source_ip: source_ip.map(str::to_string),
+            count,
+            passed,
+            dkim: None,
+            spf: None,
+            disposition: None,
+            result_type: None,
+        }
+    }
+
+    fn report(kind: ReportKind, report_id: &str, day: u32, rows: Vec<ReportRow>) -> ParsedReport {
+        let begin = Utc.with_ymd_and_hms(2024, 3, day, 0, 0, 0).unwrap();
+        ParsedReport {
+            kind,
+            org_name: "google.com".to_string(),
+            report_id: report_id.to_string(),
+            domain: "example.com".to_string(),
+            begin,
+            end: begin + Duration::days(1),
+            rows,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_list_and_sources() {
+        let manager = DmarcReportManager::connect("sqlite::memory:")
+            .await
+            .unwrap()
+            .with_addresses(&[" DMARC@example.com".to_string()]);
+        assert!(manager.is_report_address("dmarc@Example.com"));
+        assert!(!manager.is_report_address("postmaster@example.com"));
+
+        let first = report(
+            ReportKind::Dmarc,
+            "r1",
+            1,
+            vec![
+                row(Some("192.0.2.10"), 12, true),
+                row(Some("198.51.100.7"), 3, false),
+            ],
+        );
+        let id = manager.store(&first).await.unwrap().unwrap();
+        assert_eq!(manager.store(&first).await.unwrap(), None);
+        manager
+            .store(&report(
+                ReportKind::Dmarc,
+                "r2",
+                2,
+                vec![
+                    row(Some("198.51.100.7"), 4, false),
+                    row(Some("192.0.2.10"), 1, true),
+                ],
+            ))
+            .await
+            .unwrap();
+        manager
+            .store(&report(ReportKind::Tls, "t1", 2, vec![row(None, 50, true)]))
+            .await
+            .unwrap();
+
+        let all = manager.list(&ReportFilter::default(), 10).await.unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].id, id);
+        assert_eq!((all[2].passed, all[2].failed), (12, 3));
+
+        let dmarc = ReportFilter {
+            kind: Some(ReportKind::Dmarc),
+            ..ReportFilter::default()
+        };
+        let sources = manager.sources(&dmarc).await.unwrap();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].source_ip.as_deref(), Some("198.51.100.7"));
+        assert_eq!(
+            (sources[0].reports, sources[0].passed, sources[0].failed),
+            (2, 0, 7)
+        );
+        assert_eq!((sources[1].passed, sources[1].failed), (13, 0));
+
+        let detail = manager.get(id).await.unwrap().unwrap();
+        assert_eq!(detail.rows, first.rows);
+
+        let since = ReportFilter {
+            since: Some(Utc.with_ymd_and_hms(2024, 3, 3, 0, 0, 0).unwrap()),
+            ..dmarc
+        };
+        assert_eq!(manager.list(&since, 10).await.unwrap().len(), 1);
+
+        let cutoff = Utc.with_ymd_and_hms(2024, 3, 2, 12, 0, 0).unwrap();
+        assert_eq!(manager.prune(cutoff).await.unwrap(), 1);
+        assert!(manager.get(id).await.unwrap().is_none());
+        assert_eq!(
+            manager
+                .list(&ReportFilter::default(), 10)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
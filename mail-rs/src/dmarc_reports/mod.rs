@@ -0,0 +1,15 @@
+//! DMARC and TLS aggregate reports received at our own `rua` addresses
+//!
+//! Providers mail daily aggregate reports to the addresses our DMARC
+//! (RFC 7489) and TLS-RPT (RFC 8460) records publish. With
+//! `[dmarc_reports] enabled`, messages to the configured `addresses` are
+//! still delivered, and their XML or JSON attachments (plain, gzip or zip)
+//! are parsed and stored. The admin API lists the reports and breaks the
+//! results down per source IP, showing which senders fail authentication.
+
+pub mod manager;
+pub mod parse;
+pub mod types;
+
+pub use manager::DmarcReportManager;
+pub use types::*;
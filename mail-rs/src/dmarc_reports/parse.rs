@@ -0,0 +1,332 @@
+//! Extraction of aggregate reports from received messages
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use mail_auth::report::tlsrpt::TlsReport;
+use mail_auth::report::{DmarcResult, Report};
+use std::io::{Cursor, Read};
+use tracing::debug;
+
+use super::types::{ParsedReport, ReportKind, ReportRow};
+use crate::mime::{MimeParser, MimePart};
+
+/// Largest decompressed report read, against compression bombs
+const MAX_REPORT_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Reports carried by `message`: DMARC XML and TLS-RPT JSON parts, plain,
+/// gzipped or zipped. Parts that are not reports are skipped.
+pub fn parse_message(message: &[u8]) -> Result<Vec<ParsedReport>> {
+    let parsed = MimeParser::parse(message)?;
+    let mut parts = parsed.attachments;
+    // Some senders mail the report as the only body of the message
+    if parts.is_empty() {
+        let content_type = parsed
+            .headers
+            .get("content-type")
+            .cloned()
+            .unwrap_or_default();
+        if !content_type.to_lowercase().starts_with("multipart/") {
+            // The text body is already decoded
+            parts.push(MimePart {
+                content_type,
+                body: parsed.text_body.unwrap_or_default().into_bytes(),
+                ..MimePart::default()
+            });
+        }
+    }
+
+    let mut reports = Vec::new();
+    for part in &parts {
+        let report = MimeParser::decode_body(part)
+            .and_then(|body| decompress(&body))
+            .and_then(|content| parse_report(&content));
+        match report {
+            Ok(report) => reports.push(report),
+            Err(e) => debug!("Skipping {} part: {}", part.content_type, e),
+        }
+    }
+    Ok(reports)
+}
+
+/// Content of a gzip or zip archive (its first file), or `body` itself
+fn decompress(body: &[u8]) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    if body.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(body)
+            .take(MAX_REPORT_SIZE)
+            .read_to_end(&mut content)?;
+    } else if body.starts_with(b"PK\x03\x04") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(body))?;
+        archive
+            .by_index(0)?
+            .take(MAX_REPORT_SIZE)
+            .read_to_end(&mut content)?;
+    } else {
+        content.extend_from_slice(body);
+    }
+    Ok(content)
+}
+
+/// Parse a DMARC XML or TLS-RPT JSON report
+pub fn parse_report(content: &[u8]) -> Result<ParsedReport> {
+    let content = content.trim_ascii_start();
+    if content.starts_with(b"{") {
+        let report = TlsReport::parse_json(content).map_err(|e| anyhow!("{:?}", e))?;
+        from_tls(&report)
+    } else if content.starts_with(b"<") {
+        let report = Report::parse_xml(content).map_err(|e| anyhow!(e))?;
+        from_dmarc(&report)
+    } else {
+        bail!("not an aggregate report")
+    }
+}
+
+fn from_dmarc(report: &Report) -> Result<ParsedReport> {
+    let rows = report
+        .records()
+        .iter()
+        .map(|record| {
+            let dkim = record.dmarc_dkim_result();
+            let spf = record.dmarc_spf_result();
+            ReportRow {
+                source_ip: record.source_ip().map(|ip| ip.to_string()),
+                count: record.count().into(),
+                passed: dkim == DmarcResult::Pass || spf == DmarcResult::Pass,
+                dkim: Some(result_name(dkim).to_string()),
+                spf: Some(result_name(spf).to_string()),
+                disposition: Some(record.action_disposition().to_string()),
+                result_type: None,
+            }
+        })
+        .collect();
+
+    Ok(ParsedReport {
+        kind: ReportKind::Dmarc,
+        org_name: report.org_name().to_string(),
+        report_id: report.report_id().to_string(),
+        domain: report.domain().to_lowercase(),
+        begin: timestamp(report.date_range_begin() as i64)?,
+        end: timestamp(report.date_range_end() as i64)?,
+        rows,
+    })
+}
+
+fn from_tls(report: &TlsReport) -> Result<ParsedReport> {
+    let mut rows = Vec::new();
+    for policy in &report.policies {
+        if policy.summary.total_success > 0 {
+            rows.push(ReportRow {
+                source_ip: None,
+                count: policy.summary.total_success.into(),
+                passed: true,
+                dkim: None,
+                spf: None,
+                disposition: None,
+                result_type: None,
+            });
+        }
+
+        let mut detailed = 0;
+        for failure in &policy.failure_details {
+            detailed += failure.failed_session_count;
+            rows.push(ReportRow {
+                source_ip: failure.sending_mta_ip.map(|ip| ip.to_string()),
+                count: failure.failed_session_count.into(),
+                passed: false,
+                dkim: None,
+                spf: None,
+                disposition: None,
+                result_type: serde_json::to_value(failure.result_type)
+                    .ok()
+                    .and_then(|value| value.as_str().map(str::to_string)),
+            });
+        }
+        // Failures the report counts without details
+        let undetailed = policy.summary.total_failure.saturating_sub(detailed);
+        if undetailed > 0 {
+            rows.push(ReportRow {
+                source_ip: None,
+                count: undetailed.into(),
+                passed: false,
+                dkim: None,
+                spf: None,
+                disposition: None,
+                result_type: None,
+            });
+        }
+    }
+
+    // A report covers the policies of one domain
+    let domain = report
+        .policies
+        .first()
+        .map(|policy| policy.policy.policy_domain.to_lowercase())
+        .unwrap_or_default();
+    Ok(ParsedReport {
+        kind: ReportKind::Tls,
+        org_name: report.organization_name.clone().unwrap_or_default(),
+        report_id: report.report_id.clone(),
+        domain,
+        begin: timestamp(report.date_range.start_datetime.to_timestamp())?,
+        end: timestamp(report.date_range.end_datetime.to_timestamp())?,
+        rows,
+    })
+}
+
+fn result_name(result: DmarcResult) -> &'static str {
+    match result {
+        DmarcResult::Pass => "pass",
+        DmarcResult::Fail => "fail",
+        DmarcResult::Unspecified => "none",
+    }
+}
+
+fn timestamp(secs: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0).ok_or_else(|| anyhow!("invalid report date {}", secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose, Engine as _};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const DMARC_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<feedback>
+  <report_metadata>
+    <org_name>google.com</org_name>
+    <email>noreply-dmarc-support@google.com</email>
+    <report_id>1234567890</report_id>
+    <date_range><begin>1709251200</begin><end>1709337599</end></date_range>
+  </report_metadata>
+  <policy_published>
+    <domain>Example.com</domain>
+    <adkim>r</adkim><aspf>r</aspf><p>reject</p><sp>reject</sp><pct>100</pct>
+  </policy_published>
+  <record>
+    <row>
+      <source_ip>192.0.2.10</source_ip>
+      <count>12</count>
+      <policy_evaluated><disposition>none</disposition><dkim>pass</dkim><spf>fail</spf></policy_evaluated>
+    </row>
+    <identifiers><header_from>example.com</header_from></identifiers>
+    <auth_results><dkim><domain>example.com</domain><result>pass</result></dkim></auth_results>
+  </record>
+  <record>
+    <row>
+      <source_ip>198.51.100.7</source_ip>
+      <count>3</count>
+      <policy_evaluated><disposition>reject</disposition><dkim>fail</dkim><spf>fail</spf></policy_evaluated>
+    </row>
+    <identifiers><header_from>example.com</header_from></identifiers>
+    <auth_results><spf><domain>spoof.example</domain><result>fail</result></spf></auth_results>
+  </record>
+</feedback>"#;
+
+    const TLS_JSON: &str = r#"{
+  "organization-name": "Company-X",
+  "date-range": {
+    "start-datetime": "2024-03-01T00:00:00Z",
+    "end-datetime": "2024-03-01T23:59:59Z"
+  },
+  "contact-info": "sts-reporting@company-x.example",
+  "report-id": "5065427c-23d3-47ca-b6e0-946ea0e8c4be",
+  "policies": [{
+    "policy": {
+      "policy-type": "sts",
+      "policy-string": ["version: STSv1", "mode: testing"],
+      "policy-domain": "example.com",
+      "mx-host": ["mx.example.com"]
+    },
+    "summary": {
+      "total-successful-session-count": 5326,
+      "total-failure-session-count": 303
+    },
+    "failure-details": [{
+      "result-type": "certificate-expired",
+      "sending-mta-ip": "2001:db8:abcd:0012::1",
+      "receiving-mx-hostname": "mx.example.com",
+      "failed-session-count": 300
+    }]
+  }]
+}"#;
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_parse_dmarc_report() {
+        let report = parse_report(DMARC_XML.as_bytes()).unwrap();
+        assert_eq!(report.kind, ReportKind::Dmarc);
+        assert_eq!(report.org_name, "google.com");
+        assert_eq!(report.domain, "example.com");
+        assert_eq!(report.begin.to_rfc3339(), "2024-03-01T00:00:00+00:00");
+        assert_eq!(report.rows.len(), 2);
+        assert_eq!(report.rows[0].source_ip.as_deref(), Some("192.0.2.10"));
+        assert_eq!(report.rows[0].count, 12);
+        assert!(report.rows[0].passed);
+        assert!(!report.rows[1].passed);
+        assert_eq!(report.rows[1].disposition.as_deref(), Some("reject"));
+        assert_eq!(report.rows[1].spf.as_deref(), Some("fail"));
+    }
+
+    #[test]
+    fn test_parse_tls_report() {
+        let report = parse_report(TLS_JSON.as_bytes()).unwrap();
+        assert_eq!(report.kind, ReportKind::Tls);
+        assert_eq!(report.org_name, "Company-X");
+        assert_eq!(report.domain, "example.com");
+        let counts: Vec<(i64, bool)> = report
+            .rows
+            .iter()
+            .map(|row| (row.count, row.passed))
+            .collect();
+        assert_eq!(counts, [(5326, true), (300, false), (3, false)]);
+        assert_eq!(
+            report.rows[1].source_ip.as_deref(),
+            Some("2001:db8:abcd:12::1")
+        );
+        assert_eq!(
+            report.rows[1].result_type.as_deref(),
+            Some("certificate-expired")
+        );
+    }
+
+    #[test]
+    fn test_parse_message_attachments() {
+        let gz = general_purpose::STANDARD.encode(gzip(DMARC_XML.as_bytes()));
+        let message = format!(
+            "From: noreply-dmarc-support@google.com\r\n\
+             Subject: Report domain: example.com\r\n\
+             Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+             --b\r\nContent-Type: text/plain\r\n\r\nThis is an aggregate report.\r\n\
+             --b\r\nContent-Type: application/gzip\r\n\
+             Content-Disposition: attachment; filename=\"google.com!example.com.xml.gz\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\r\n{}\r\n\
+             --b\r\nContent-Type: application/octet-stream\r\n\
+             Content-Disposition: attachment; filename=\"notes.bin\"\r\n\r\nnot a report\r\n\
+             --b--\r\n",
+            gz
+        );
+        let reports = parse_message(message.as_bytes()).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].report_id, "1234567890");
+
+        // The report as the only body of the message
+        let message = format!(
+            "From: tlsrpt@company-x.example\r\n\
+             Content-Type: application/tlsrpt+gzip\r\n\
+             Content-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+            general_purpose::STANDARD.encode(gzip(TLS_JSON.as_bytes()))
+        );
+        let reports = parse_message(message.as_bytes()).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].kind, ReportKind::Tls);
+    }
+}
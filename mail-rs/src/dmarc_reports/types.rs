@@ -0,0 +1,112 @@
+//! Received aggregate report types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Kind of aggregate report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportKind {
+    /// DMARC aggregate report (RFC 7489), XML
+    Dmarc,
+    /// SMTP TLS report (RFC 8460), JSON
+    Tls,
+}
+
+impl ReportKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dmarc => "dmarc",
+            Self::Tls => "tls",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "dmarc" => Some(Self::Dmarc),
+            "tls" => Some(Self::Tls),
+            _ => None,
+        }
+    }
+}
+
+/// Messages (DMARC) or sessions (TLS) of one source with the same results
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportRow {
+    /// IP that sent the mail, or the sending MTA of failed TLS sessions;
+    /// None for the successful sessions of a TLS report
+    pub source_ip: Option<String>,
+    pub count: i64,
+    /// DMARC passed (aligned DKIM or SPF), or TLS was negotiated
+    pub passed: bool,
+    /// DMARC-aligned DKIM result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dkim: Option<String>,
+    /// DMARC-aligned SPF result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spf: Option<String>,
+    /// Disposition the receiver applied: none, quarantine or reject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposition: Option<String>,
+    /// Why TLS sessions failed, e.g. `certificate-expired`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_type: Option<String>,
+}
+
+/// Report parsed from a received message
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedReport {
+    pub kind: ReportKind,
+    /// Organization that sent the report
+    pub org_name: String,
+    /// Id the sender gave the report
+    pub report_id: String,
+    /// Our domain the report covers
+    pub domain: String,
+    pub begin: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub rows: Vec<ReportRow>,
+}
+
+/// Stored report with its totals
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReceivedReport {
+    pub id: i64,
+    pub kind: ReportKind,
+    pub org_name: String,
+    pub report_id: String,
+    pub domain: String,
+    pub begin: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    pub passed: i64,
+    pub failed: i64,
+}
+
+/// Stored report with its rows
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportDetail {
+    #[serde(flatten)]
+    pub report: ReceivedReport,
+    pub rows: Vec<ReportRow>,
+}
+
+/// Pass and fail counts of one source IP across reports
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SourceBreakdown {
+    /// None for successful TLS sessions, which reports do not attribute
+    pub source_ip: Option<String>,
+    /// Reports mentioning the source
+    pub reports: i64,
+    pub passed: i64,
+    pub failed: i64,
+}
+
+/// Filter of report listings and breakdowns
+#[derive(Debug, Clone, Default)]
+pub struct ReportFilter {
+    pub kind: Option<ReportKind>,
+    pub domain: Option<String>,
+    /// Only reports whose period ended at or after this time
+    pub since: Option<DateTime<Utc>>,
+}
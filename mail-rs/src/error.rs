@@ -32,6 +32,18 @@ pub enum MailError {
     #[error("TLS error: {0}")]
     Tls(String),
 
+    /// The remote server gave a permanent (5xx) reply
+    #[error("{host} rejected the message: {reply}")]
+    SmtpRejected { host: String, reply: String },
+
+    /// The message was refused at the end of DATA; holds the SMTP reply
+    #[error("Message rejected: {}", .0.trim_end())]
+    MessageRejected(String),
+
+    /// A REQUIRETLS message could not be relayed over verified TLS
+    #[error("REQUIRETLS not satisfied: {0}")]
+    RequireTls(String),
+
     #[error("Resource not found: {0}")]
     NotFound(String),
 
@@ -0,0 +1,482 @@
+//! Appending footers to MIME messages
+//!
+//! The footer goes to the first body of a message: a `text/plain` or
+//! `text/html` body, each alternative of a `multipart/alternative` body, or
+//! the first part of other multipart bodies such as `multipart/mixed`.
+//! Signed and encrypted bodies are left alone, since a footer would break
+//! their signature. Bodies are decoded, extended and encoded again with
+//! their transfer encoding; 7bit bodies switch to quoted-printable and
+//! US-ASCII ones to UTF-8 when the footer needs it.
+
+use super::types::{RenderedFooter, FOOTER_HTML_CLASS};
+use crate::mime::entity::header_fields;
+use crate::mime::{MimeEntity, MimeParser};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+/// Longest line of encoded bodies, without the line break
+const MAX_LINE_LEN: usize = 76;
+
+/// Outcome of [`append_footer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FooterResult {
+    /// The message with the footer appended
+    Added(Vec<u8>),
+    /// A body already contains the footer, e.g. quoted in a reply
+    AlreadyPresent,
+    /// No body takes a footer: signed, encrypted, non-text or in a charset
+    /// the footer can't be written in
+    Unsupported,
+}
+
+/// A body the footer is appended to
+struct Target<'a> {
+    entity: &'a MimeEntity<'a>,
+    html: bool,
+    decoded: Vec<u8>,
+    encoding: String,
+}
+
+/// Append `footer` to the bodies of `message`
+pub fn append_footer(message: &[u8], footer: &RenderedFooter) -> FooterResult {
+    let root = MimeEntity::parse(message);
+    let mut entities = Vec::new();
+    collect_bodies(&root, &mut entities);
+
+    let mut targets = Vec::new();
+    for entity in entities {
+        let html = entity.content_type == "text/html";
+        let addition = if html { &footer.html } else { &footer.text };
+        if !addition.is_ascii() && !is_unicode_compatible(entity) {
+            continue;
+        }
+        let encoding = entity.encoding();
+        let decoded = match encoding.as_str() {
+            "7bit" | "8bit" | "binary" => entity.body.to_vec(),
+            "quoted-printable" => MimeParser::decode_quoted_printable(entity.body),
+            "base64" => match MimeParser::decode_base64(entity.body) {
+                Ok(decoded) => decoded,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        targets.push(Target {
+            entity,
+            html,
+            decoded,
+            encoding,
+        });
+    }
+
+    if targets.is_empty() {
+        return FooterResult::Unsupported;
+    }
+    if targets
+        .iter()
+        .any(|target| contains_footer(&target.decoded, target.html, footer))
+    {
+        return FooterResult::AlreadyPresent;
+    }
+
+    // Replace byte ranges of the message, last range first so that earlier
+    // offsets stay valid
+    let mut edits: Vec<(usize, usize, Vec<u8>)> = Vec::new();
+    for target in &targets {
+        let entity = target.entity;
+        let addition = if target.html {
+            &footer.html
+        } else {
+            &footer.text
+        };
+        let body = if target.html {
+            insert_html(&target.decoded, addition)
+        } else {
+            append_text(&target.decoded, addition)
+        };
+
+        let unicode = !addition.is_ascii();
+        let encoding = if unicode && target.encoding == "7bit" {
+            "quoted-printable"
+        } else {
+            target.encoding.as_str()
+        };
+        let mut encoded = encode(&body, encoding);
+        if encoding == "base64" && entity.body.ends_with(b"\n") {
+            encoded.extend_from_slice(b"\r\n");
+        }
+
+        let body_start = offset(message, entity.body);
+        edits.push((body_start, body_start + entity.body.len(), encoded));
+
+        let needs_charset =
+            unicode && !matches!(charset(entity).as_deref(), Some("utf-8" | "utf8"));
+        if needs_charset || encoding != target.encoding {
+            let header_start = offset(message, entity.header);
+            let header = rewrite_header(
+                entity,
+                header_start == 0,
+                needs_charset,
+                (encoding != target.encoding).then_some(encoding),
+            );
+            edits.push((header_start, header_start + entity.header.len(), header));
+        }
+    }
+
+    edits.sort_by_key(|(start, _, _)| std::cmp::Reverse(*start));
+    let mut result = message.to_vec();
+    for (start, end, replacement) in edits {
+        result.splice(start..end, replacement);
+    }
+    FooterResult::Added(result)
+}
+
+/// Text bodies of `entity` that take the footer
+fn collect_bodies<'a>(entity: &'a MimeEntity<'a>, bodies: &mut Vec<&'a MimeEntity<'a>>) {
+    match entity.content_type.as_str() {
+        "text/plain" | "text/html" => {
+            let attachment =
+                matches!(entity.disposition(), Some((kind, _)) if kind == "attachment");
+            if !attachment {
+                bodies.push(entity);
+            }
+        }
+        "multipart/alternative" => {
+            for part in &entity.parts {
+                collect_bodies(part, bodies);
+            }
+        }
+        "multipart/signed" | "multipart/encrypted" => {}
+        content_type if content_type.starts_with("multipart/") => {
+            if let Some(part) = entity.parts.first() {
+                collect_bodies(part, bodies);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Lowercase charset parameter of the entity, if any
+fn charset(entity: &MimeEntity) -> Option<String> {
+    entity
+        .content_type_params()
+        .into_iter()
+        .find(|(name, _)| name == "charset")
+        .map(|(_, value)| value.to_lowercase())
+}
+
+/// Whether UTF-8 text can be added: the body is UTF-8, or US-ASCII and can
+/// be relabeled as UTF-8
+fn is_unicode_compatible(entity: &MimeEntity) -> bool {
+    match charset(entity) {
+        None => true,
+        Some(charset) => matches!(charset.as_str(), "utf-8" | "utf8" | "us-ascii"),
+    }
+}
+
+/// Whether a body already shows the footer, possibly quoted
+fn contains_footer(body: &[u8], html: bool, footer: &RenderedFooter) -> bool {
+    let body = String::from_utf8_lossy(body);
+    if html && body.contains(&format!("class=\"{}\"", FOOTER_HTML_CLASS)) {
+        return true;
+    }
+
+    let needle = normalize(&footer.text);
+    if needle.is_empty() {
+        return false;
+    }
+    let text = if html {
+        strip_tags(&body)
+    } else {
+        body.to_string()
+    };
+    normalize(&text).contains(&needle)
+}
+
+/// Text with quote markers removed and whitespace collapsed
+fn normalize(text: &str) -> String {
+    text.lines()
+        .map(|line| line.trim_start_matches(|c: char| c == '>' || c.is_whitespace()))
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text of HTML markup, with a space where each tag was
+pub(crate) fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Body followed by a blank line and the footer; line breaks at the end of
+/// the body stay at the end
+fn append_text(body: &[u8], footer: &str) -> Vec<u8> {
+    let content_len = body
+        .iter()
+        .rposition(|&b| b != b'\r' && b != b'\n')
+        .map_or(0, |pos| pos + 1);
+    let (content, trailing) = body.split_at(content_len);
+
+    let mut result = content.to_vec();
+    if !content.is_empty() {
+        result.extend_from_slice(b"\r\n\r\n");
+    }
+    result.extend_from_slice(crlf(footer).as_bytes());
+    result.extend_from_slice(trailing);
+    result
+}
+
+/// HTML body with the footer before `</body>`, or at the end
+fn insert_html(body: &[u8], footer: &str) -> Vec<u8> {
+    let lowercase = body.to_ascii_lowercase();
+    match lowercase
+        .windows(7)
+        .rposition(|window| window == b"</body>")
+    {
+        Some(pos) => {
+            let mut result = body[..pos].to_vec();
+            result.extend_from_slice(crlf(footer).as_bytes());
+            result.extend_from_slice(b"\r\n");
+            result.extend_from_slice(&body[pos..]);
+            result
+        }
+        None => append_text(body, footer),
+    }
+}
+
+fn crlf(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+fn encode(body: &[u8], encoding: &str) -> Vec<u8> {
+    match encoding {
+        "base64" => BASE64
+            .encode(body)
+            .as_bytes()
+            .chunks(MAX_LINE_LEN)
+            .collect::<Vec<_>>()
+            .join(&b"\r\n"[..]),
+        "quoted-printable" => encode_quoted_printable(body),
+        _ => body.to_vec(),
+    }
+}
+
+/// Quoted-printable encoding (RFC 2045) keeping CRLF line breaks
+fn encode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(body.len() + body.len() / 8);
+    let mut lines = body.split(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut line_len = 0;
+        for (i, &b) in line.iter().enumerate() {
+            let last = i + 1 == line.len();
+            let literal =
+                matches!(b, b'!'..=b'<' | b'>'..=b'~') || (matches!(b, b' ' | b'\t') && !last);
+            let width = if literal { 1 } else { 3 };
+            // Leave room for the "=" of a soft line break
+            if line_len + width > MAX_LINE_LEN - 1 {
+                result.extend_from_slice(b"=\r\n");
+                line_len = 0;
+            }
+            if literal {
+                result.push(b);
+            } else {
+                result.extend_from_slice(format!("={:02X}", b).as_bytes());
+            }
+            line_len += width;
+        }
+        if lines.peek().is_some() {
+            result.extend_from_slice(b"\r\n");
+        }
+    }
+    result
+}
+
+/// Header of `entity` with a UTF-8 charset and/or a new transfer encoding
+fn rewrite_header(
+    entity: &MimeEntity,
+    top_level: bool,
+    utf8: bool,
+    encoding: Option<&str>,
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(entity.header.len() + 64);
+    let mut has_mime_version = false;
+    for (name, raw) in header_fields(entity.header) {
+        has_mime_version |= name == "mime-version";
+        let replaced = (utf8 && name == "content-type")
+            || (encoding.is_some() && name == "content-transfer-encoding");
+        if !replaced {
+            header.extend_from_slice(raw);
+        }
+    }
+
+    if top_level && !has_mime_version {
+        header.extend_from_slice(b"MIME-Version: 1.0\r\n");
+    }
+    if utf8 {
+        let mut content_type = format!("Content-Type: {}; charset=utf-8", entity.content_type);
+        for (name, value) in entity.content_type_params() {
+            if name != "charset" {
+                content_type.push_str(&format!("; {}=\"{}\"", name, value));
+            }
+        }
+        header.extend_from_slice(content_type.as_bytes());
+        header.extend_from_slice(b"\r\n");
+    }
+    if let Some(encoding) = encoding {
+        header.extend_from_slice(format!("Content-Transfer-Encoding: {}\r\n", encoding).as_bytes());
+    }
+    header.extend_from_slice(b"\r\n");
+    header
+}
+
+/// Position of `slice` within `message`, which it was parsed from
+fn offset(message: &[u8], slice: &[u8]) -> usize {
+    slice.as_ptr() as usize - message.as_ptr() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn footer(text: &str) -> RenderedFooter {
+        RenderedFooter {
+            text: text.to_string(),
+            html: format!("<div class=\"{}\">{}</div>", FOOTER_HTML_CLASS, text),
+        }
+    }
+
+    fn added(result: FooterResult) -> String {
+        match result {
+            FooterResult::Added(message) => String::from_utf8(message).unwrap(),
+            other => panic!("Expected a footer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let message = b"From: jane@example.com\r\nSubject: Hi\r\n\r\nHello\r\n";
+        assert_eq!(
+            added(append_footer(
+                message,
+                &footer("Confidential\nDo not forward")
+            )),
+            "From: jane@example.com\r\nSubject: Hi\r\n\r\n\
+             Hello\r\n\r\nConfidential\r\nDo not forward\r\n"
+        );
+
+        // Non-ASCII footers switch 7bit US-ASCII bodies to quoted-printable
+        // UTF-8
+        let result = added(append_footer(
+            message,
+            &footer("Vertraulich – nicht weiterleiten"),
+        ));
+        assert_eq!(
+            result,
+            "From: jane@example.com\r\nSubject: Hi\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+             Hello\r\n\r\nVertraulich =E2=80=93 nicht weiterleiten\r\n"
+        );
+
+        // A Latin-1 body can't take it
+        let message =
+            b"Content-Type: text/plain; charset=iso-8859-1\r\n\r\nGr\xfc\xdfe\r\n".to_vec();
+        assert_eq!(
+            append_footer(&message, &footer("Vertraulich – nicht weiterleiten")),
+            FooterResult::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_multipart_alternative() {
+        let message = concat!(
+            "Content-Type: multipart/mixed; boundary=\"outer\"\r\n\r\n",
+            "--outer\r\n",
+            "Content-Type: multipart/alternative; boundary=\"inner\"\r\n\r\n",
+            "--inner\r\n",
+            "Content-Type: text/plain; charset=utf-8\r\n",
+            "Content-Transfer-Encoding: base64\r\n\r\n",
+            "SGVsbG8=\r\n",
+            "--inner\r\n",
+            "Content-Type: text/html; charset=utf-8\r\n",
+            "Content-Transfer-Encoding: quoted-printable\r\n\r\n",
+            "<html><body><p style=3D\"x\">Hello</p></body></html>\r\n",
+            "--inner--\r\n",
+            "--outer\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Disposition: attachment; filename=\"notes.txt\"\r\n\r\n",
+            "Attachment\r\n",
+            "--outer--\r\n",
+        );
+
+        let result = added(append_footer(message.as_bytes(), &footer("Confidential")));
+        let root = MimeEntity::parse(result.as_bytes());
+        let alternative = &root.parts[0];
+        assert_eq!(
+            MimeParser::decode_base64(alternative.parts[0].body).unwrap(),
+            b"Hello\r\n\r\nConfidential"
+        );
+        assert_eq!(
+            MimeParser::decode_quoted_printable(alternative.parts[1].body),
+            b"<html><body><p style=\"x\">Hello</p><div class=\"mail-rs-footer\">Confidential</div>\r\n</body></html>"
+        );
+        assert_eq!(root.parts[1].body, b"Attachment");
+        assert!(result.ends_with("--outer--\r\n"));
+    }
+
+    #[test]
+    fn test_replies_and_signed_messages() {
+        // Quoted in a reply, with different line breaks
+        let reply = b"Subject: Re: Hi\r\n\r\nThanks!\r\n\r\n> Hello\r\n>\r\n> Confidential\r\n> Do not\r\n> forward\r\n";
+        assert_eq!(
+            append_footer(reply, &footer("Confidential\nDo not forward")),
+            FooterResult::AlreadyPresent
+        );
+
+        let reply = concat!(
+            "Content-Type: text/html\r\n\r\n",
+            "<p>Thanks!</p><blockquote><div class=\"mail-rs-footer\">Old</div></blockquote>\r\n",
+        );
+        assert_eq!(
+            append_footer(reply.as_bytes(), &footer("Confidential")),
+            FooterResult::AlreadyPresent
+        );
+
+        let signed = concat!(
+            "Content-Type: multipart/signed; boundary=\"b\"; protocol=\"application/pgp-signature\"\r\n\r\n",
+            "--b\r\n\r\nHello\r\n",
+            "--b\r\nContent-Type: application/pgp-signature\r\n\r\nsig\r\n",
+            "--b--\r\n",
+        );
+        assert_eq!(
+            append_footer(signed.as_bytes(), &footer("Confidential")),
+            FooterResult::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_encode_quoted_printable() {
+        assert_eq!(
+            encode_quoted_printable(b"a=b \r\ntab\t\r\n"),
+            b"a=3Db=20\r\ntab=09\r\n"
+        );
+        let long = encode_quoted_printable(&[b'x'; 100]);
+        assert_eq!(&long[..78], [&[b'x'; 75][..], b"=\r\n"].concat().as_slice());
+        assert_eq!(MimeParser::decode_quoted_printable(&long), vec![b'x'; 100]);
+    }
+}
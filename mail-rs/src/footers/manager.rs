@@ -0,0 +1,371 @@
+//! Footer manager: footers per domain and group, and sender profiles
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Footer manager
+pub struct FooterManager {
+    db: SqlitePool,
+}
+
+impl FooterManager {
+    /// Create a new footer manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the footer tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // The domain's own footer has the empty group
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS footers (
+                domain TEXT NOT NULL,
+                grp TEXT NOT NULL DEFAULT '',
+                text TEXT NOT NULL,
+                html TEXT,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (domain, grp)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS sender_profiles (
+                user TEXT PRIMARY KEY,
+                name TEXT,
+                department TEXT,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Footers of all domains and groups
+    pub async fn list(&self) -> Result<Vec<Footer>> {
+        let rows = sqlx::query("SELECT * FROM footers ORDER BY domain, grp")
+            .fetch_all(&self.db)
+            .await?;
+        rows.iter().map(footer_from_row).collect()
+    }
+
+    /// Footer of `domain`, or of one group in it
+    pub async fn get(&self, domain: &str, group: Option<&str>) -> Result<Option<Footer>> {
+        let row = sqlx::query("SELECT * FROM footers WHERE domain = ? AND grp = ?")
+            .bind(domain.to_lowercase())
+            .bind(group_key(group))
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(footer_from_row).transpose()
+    }
+
+    /// Set the footer of `domain`, or of one group in it
+    pub async fn set(
+        &self,
+        domain: &str,
+        group: Option<&str>,
+        update: &FooterUpdate,
+    ) -> Result<Footer> {
+        update.validate().map_err(|e| anyhow!(e))?;
+        let group = group_key(group);
+        let html = update
+            .html
+            .as_deref()
+            .map(str::trim)
+            .filter(|html| !html.is_empty());
+        let now = Utc::now();
+
+        sqlx::query(
+            r#"
+            INSERT INTO footers (domain, grp, text, html, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(domain, grp) DO UPDATE SET
+                text = excluded.text,
+                html = excluded.html,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(domain.to_lowercase())
+        .bind(&group)
+        .bind(update.text.trim())
+        .bind(html)
+        .bind(now.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(Footer {
+            domain: domain.to_lowercase(),
+            group: (!group.is_empty()).then_some(group),
+            text: update.text.trim().to_string(),
+            html: html.map(str::to_string),
+            updated_at: now,
+        })
+    }
+
+    /// Remove the footer of `domain` or of one group in it; returns whether
+    /// there was one
+    pub async fn delete(&self, domain: &str, group: Option<&str>) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM footers WHERE domain = ? AND grp = ?")
+            .bind(domain.to_lowercase())
+            .bind(group_key(group))
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Profile of `user`
+    pub async fn profile(&self, user: &str) -> Result<Option<SenderProfile>> {
+        let row = sqlx::query("SELECT * FROM sender_profiles WHERE user = ?")
+            .bind(user.to_lowercase())
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(profile_from_row).transpose()
+    }
+
+    /// Change the profile of `user`
+    pub async fn set_profile(
+        &self,
+        user: &str,
+        update: &SenderProfileUpdate,
+    ) -> Result<SenderProfile> {
+        let mut profile = match self.profile(user).await? {
+            Some(profile) => profile,
+            None => SenderProfile {
+                user: user.to_lowercase(),
+                name: None,
+                department: None,
+                updated_at: Utc::now(),
+            },
+        };
+        let optional = |value: &Option<String>, current: &mut Option<String>| {
+            if let Some(value) = value {
+                let value = value.trim();
+                *current = (!value.is_empty()).then(|| value.to_string());
+            }
+        };
+        optional(&update.name, &mut profile.name);
+        optional(&update.department, &mut profile.department);
+        profile.updated_at = Utc::now();
+
+        sqlx::query(
+            r#"
+            INSERT INTO sender_profiles (user, name, department, updated_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(user) DO UPDATE SET
+                name = excluded.name,
+                department = excluded.department,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&profile.user)
+        .bind(&profile.name)
+        .bind(&profile.department)
+        .bind(profile.updated_at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(profile)
+    }
+
+    /// Remove the profile of `user`; returns whether there was one
+    pub async fn delete_profile(&self, user: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM sender_profiles WHERE user = ?")
+            .bind(user.to_lowercase())
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Footer of the sender's group, or else of the sender's domain,
+    /// rendered for the sender
+    ///
+    /// `display_name` from the message's `From:` header names senders
+    /// without a name in their profile.
+    pub async fn footer_for(
+        &self,
+        sender: &str,
+        display_name: &str,
+    ) -> Result<Option<RenderedFooter>> {
+        let sender = sender.to_lowercase();
+        let Some((local_part, domain)) = sender.rsplit_once('@') else {
+            return Ok(None);
+        };
+        let profile = self.profile(&sender).await?;
+        let department = profile.as_ref().and_then(|p| p.department.clone());
+
+        let mut footer = None;
+        if let Some(department) = &department {
+            footer = self.get(domain, Some(department)).await?;
+        }
+        if footer.is_none() {
+            footer = self.get(domain, None).await?;
+        }
+        let Some(footer) = footer else {
+            return Ok(None);
+        };
+
+        let sender_name = profile
+            .and_then(|p| p.name)
+            .or_else(|| Some(display_name.trim().to_string()).filter(|name| !name.is_empty()))
+            .unwrap_or_else(|| local_part.to_string());
+        let vars = FooterVars {
+            sender_name,
+            sender_email: sender.clone(),
+            department: department.unwrap_or_default(),
+            domain: domain.to_string(),
+        };
+        Ok(Some(footer.render(&vars)))
+    }
+}
+
+/// Stored group of a footer: lowercase, empty for the domain's footer
+fn group_key(group: Option<&str>) -> String {
+    group
+        .map(|group| group.trim().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn footer_from_row(row: &SqliteRow) -> Result<Footer> {
+    let group: String = row.get("grp");
+    Ok(Footer {
+        domain: row.get("domain"),
+        group: (!group.is_empty()).then_some(group),
+        text: row.get("text"),
+        html: row.get("html"),
+        updated_at: parse_time(row.get("updated_at"))?,
+    })
+}
+
+fn profile_from_row(row: &SqliteRow) -> Result<SenderProfile> {
+    Ok(SenderProfile {
+        user: row.get("user"),
+        name: row.get("name"),
+        department: row.get("department"),
+        updated_at: parse_time(row.get("updated_at"))?,
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn manager() -> FooterManager {
+        FooterManager::connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn update(text: &str) -> FooterUpdate {
+        FooterUpdate {
+            text: text.to_string(),
+            html: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_footers_and_groups() {
+        let manager = manager().await;
+        assert_eq!(
+            manager.footer_for("jane@example.com", "").await.unwrap(),
+            None
+        );
+
+        manager
+            .set("Example.com", None, &update("{{sender_name}} - {{domain}}"))
+            .await
+            .unwrap();
+        let footer = manager
+            .set(
+                "example.com",
+                Some("Legal"),
+                &update("{{sender_name}}, {{department}}\nPrivileged"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(footer.group.as_deref(), Some("legal"));
+        assert!(manager
+            .set("example.com", None, &update("{{phone}}"))
+            .await
+            .is_err());
+        assert_eq!(manager.list().await.unwrap().len(), 2);
+
+        // Without a profile: the domain's footer and the From display name
+        let footer = manager
+            .footer_for("Jane@Example.com", "Jane Doe")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(footer.text, "Jane Doe - example.com");
+        let footer = manager
+            .footer_for("jane@example.com", "")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(footer.text, "jane - example.com");
+
+        // The department picks the group's footer
+        let profile = manager
+            .set_profile(
+                "jane@example.com",
+                &SenderProfileUpdate {
+                    name: Some("Jane Q. Doe".to_string()),
+                    department: Some("Legal".to_string()),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(profile.department.as_deref(), Some("Legal"));
+        let footer = manager
+            .footer_for("jane@example.com", "Jane Doe")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(footer.text, "Jane Q. Doe, Legal\nPrivileged");
+
+        // Departments without a footer fall back to the domain's
+        manager
+            .set_profile(
+                "jane@example.com",
+                &SenderProfileUpdate {
+                    name: None,
+                    department: Some("Sales".to_string()),
+                },
+            )
+            .await
+            .unwrap();
+        let footer = manager
+            .footer_for("jane@example.com", "")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(footer.text, "Jane Q. Doe - example.com");
+
+        assert!(manager.delete("example.com", None).await.unwrap());
+        assert!(!manager.delete("example.com", None).await.unwrap());
+        assert_eq!(
+            manager.footer_for("jane@example.com", "").await.unwrap(),
+            None
+        );
+        assert!(manager.delete_profile("jane@example.com").await.unwrap());
+    }
+}
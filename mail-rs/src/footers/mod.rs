@@ -0,0 +1,20 @@
+//! Footers and disclaimers of outgoing mail
+//!
+//! Admins set a footer per domain, and optionally per group of senders,
+//! as plain text with an optional HTML version. Messages submitted by
+//! users of the domain get the footer appended before they are DKIM-signed:
+//! the text version to `text/plain` bodies, the HTML version to `text/html`
+//! bodies, both to the alternatives of a `multipart/alternative` message.
+//!
+//! Footers may use the variables `{{sender_name}}`, `{{sender_email}}`,
+//! `{{department}}` and `{{domain}}`. Names and departments come from the
+//! sender's profile; a sender belongs to the group named by their
+//! department. Replies that already quote the footer don't get it again.
+
+pub mod inject;
+pub mod manager;
+pub mod types;
+
+pub use inject::{append_footer, FooterResult};
+pub use manager::FooterManager;
+pub use types::*;
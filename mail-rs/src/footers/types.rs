@@ -0,0 +1,187 @@
+//! Footer types
+
+use crate::templates::TemplateRenderer;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Longest footer text or HTML in bytes
+pub const MAX_FOOTER_LEN: usize = 8 * 1024;
+
+/// Variables footers may use
+pub const FOOTER_VARIABLES: [&str; 4] = ["sender_name", "sender_email", "department", "domain"];
+
+/// Footer of a domain or of a group of senders in it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Footer {
+    pub domain: String,
+    /// Lowercase department the footer applies to; `None` for the domain's
+    /// footer, used for senders without a group footer
+    pub group: Option<String>,
+    pub text: String,
+    /// HTML version; the escaped text is used when unset
+    pub html: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Footer {
+    /// Render both versions with the variables of a sender
+    pub fn render(&self, vars: &FooterVars) -> RenderedFooter {
+        let text = vars.substitute(&self.text, |value| value.to_string());
+        let html = match &self.html {
+            Some(html) => vars.substitute(html, html_escape),
+            None => html_escape(&text).replace('\n', "<br>\n"),
+        };
+        RenderedFooter {
+            text,
+            html: format!("<div class=\"{}\">{}</div>", FOOTER_HTML_CLASS, html),
+        }
+    }
+}
+
+/// Class of the `<div>` wrapping HTML footers, used to spot footers
+/// quoted in replies
+pub const FOOTER_HTML_CLASS: &str = "mail-rs-footer";
+
+/// Request to set a footer
+#[derive(Debug, Clone, Deserialize)]
+pub struct FooterUpdate {
+    pub text: String,
+    pub html: Option<String>,
+}
+
+impl FooterUpdate {
+    /// Check the sizes and variables before saving
+    pub fn validate(&self) -> Result<(), String> {
+        let versions = [Some(&self.text), self.html.as_ref()];
+        if self.text.trim().is_empty() {
+            return Err("Footer text must not be empty".to_string());
+        }
+        for version in versions.into_iter().flatten() {
+            if version.len() > MAX_FOOTER_LEN {
+                return Err(format!("Footers are limited to {} bytes", MAX_FOOTER_LEN));
+            }
+            for variable in TemplateRenderer::extract_variables(version) {
+                // Variables are only substituted when written without spaces
+                let written = version.contains(&format!("{{{{{}}}}}", variable));
+                if !written || !FOOTER_VARIABLES.contains(&variable.as_str()) {
+                    return Err(format!(
+                        "Unknown variable {{{{{}}}}}, use one of {}",
+                        variable,
+                        FOOTER_VARIABLES.join(", ")
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Name and department of a sender, used in footers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SenderProfile {
+    pub user: String,
+    pub name: Option<String>,
+    pub department: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Request to set a sender profile; empty strings clear a field
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SenderProfileUpdate {
+    pub name: Option<String>,
+    pub department: Option<String>,
+}
+
+/// Values of the footer variables for one sender
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FooterVars {
+    pub sender_name: String,
+    pub sender_email: String,
+    pub department: String,
+    pub domain: String,
+}
+
+impl FooterVars {
+    fn substitute(&self, template: &str, escape: impl Fn(&str) -> String) -> String {
+        [
+            ("sender_name", &self.sender_name),
+            ("sender_email", &self.sender_email),
+            ("department", &self.department),
+            ("domain", &self.domain),
+        ]
+        .into_iter()
+        .fold(template.to_string(), |result, (name, value)| {
+            result.replace(&format!("{{{{{}}}}}", name), &escape(value))
+        })
+    }
+}
+
+/// Footer with the variables of a sender filled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedFooter {
+    pub text: String,
+    /// HTML version wrapped in a `<div>` of class [`FOOTER_HTML_CLASS`]
+    pub html: String,
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn footer(text: &str, html: Option<&str>) -> Footer {
+        Footer {
+            domain: "example.com".to_string(),
+            group: None,
+            text: text.to_string(),
+            html: html.map(str::to_string),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let vars = FooterVars {
+            sender_name: "Jane <Doe>".to_string(),
+            sender_email: "jane@example.com".to_string(),
+            department: "Legal".to_string(),
+            domain: "example.com".to_string(),
+        };
+
+        let rendered = footer("{{sender_name}}, {{department}}\nConfidential", None).render(&vars);
+        assert_eq!(rendered.text, "Jane <Doe>, Legal\nConfidential");
+        assert_eq!(
+            rendered.html,
+            "<div class=\"mail-rs-footer\">Jane &lt;Doe&gt;, Legal<br>\nConfidential</div>"
+        );
+
+        let rendered =
+            footer("x", Some("<p>{{sender_name}} &middot; {{domain}}</p>")).render(&vars);
+        assert_eq!(
+            rendered.html,
+            "<div class=\"mail-rs-footer\"><p>Jane &lt;Doe&gt; &middot; example.com</p></div>"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let update = |text: &str| FooterUpdate {
+            text: text.to_string(),
+            html: None,
+        };
+        assert!(update("Sent by {{sender_name}} ({{department}})")
+            .validate()
+            .is_ok());
+        assert!(update("  ").validate().is_err());
+        assert!(update("{{phone}}").validate().is_err());
+        assert!(update("{{ domain }}").validate().is_err());
+        assert!(update(&"x".repeat(MAX_FOOTER_LEN + 1)).validate().is_err());
+    }
+}
@@ -0,0 +1,594 @@
+//! Versioned storage of delivery hooks
+//!
+//! Every upload of a hook's module is saved as a new version; the last
+//! [`DEFAULT_MAX_VERSIONS`] versions and the active one are kept. A version
+//! only becomes active after it ran cleanly on a sample message.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use super::runtime::{HookInput, HookLimits, HookRuntime};
+use super::types::*;
+
+/// Versions kept per hook unless configured otherwise
+pub const DEFAULT_MAX_VERSIONS: u32 = 20;
+
+/// Message a version runs on before it is activated
+const SAMPLE_MESSAGE: &[u8] = b"From: sender@example.org\r\n\
+To: recipient@example.com\r\n\
+Subject: Hook check\r\n\
+Message-ID: <hook-check@example.org>\r\n\
+\r\n\
+This message checks a delivery hook before it is activated.\r\n";
+
+/// Row of `delivery_hooks` with its latest version
+type HookRow = (String, String, Option<i64>, i64, String);
+
+/// Row of `delivery_hook_versions` without the module
+type VersionRow = (String, i64, String, i64, String, Option<String>, String);
+
+/// Delivery hook manager for module storage and execution
+pub struct HookManager {
+    db: SqlitePool,
+    runtime: HookRuntime,
+    max_versions: u32,
+}
+
+impl HookManager {
+    /// Create a new hook manager with the default limits
+    pub fn new(db: SqlitePool) -> Self {
+        Self {
+            db,
+            runtime: HookRuntime::new(HookLimits::default()),
+            max_versions: DEFAULT_MAX_VERSIONS,
+        }
+    }
+
+    /// Connect to `database_url` and create the hook tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Run hooks within these limits
+    pub fn with_limits(mut self, limits: HookLimits) -> Self {
+        self.runtime = HookRuntime::new(limits);
+        self
+    }
+
+    /// Keep this many versions per hook (at least one)
+    pub fn with_max_versions(mut self, max_versions: u32) -> Self {
+        self.max_versions = max_versions.max(1);
+        self
+    }
+
+    pub fn limits(&self) -> HookLimits {
+        self.runtime.limits()
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS delivery_hooks (
+                target TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                active_version INTEGER,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS delivery_hook_versions (
+                target TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                module BLOB NOT NULL,
+                sha256 TEXT NOT NULL,
+                author TEXT NOT NULL,
+                comment TEXT,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (target, version)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Save `module` as the next version of the hook of `scope`, making it
+    /// active if asked to
+    pub async fn upload(
+        &self,
+        scope: &HookScope,
+        module: &[u8],
+        author: &str,
+        comment: Option<&str>,
+        activate: bool,
+    ) -> Result<HookVersion> {
+        if activate {
+            self.check_module(module)?;
+        } else {
+            self.runtime.validate(module)?;
+        }
+
+        let target = scope.target();
+        let now = Utc::now();
+        let kind = match scope {
+            HookScope::Domain(_) => "domain",
+            HookScope::User(_) => "user",
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO delivery_hooks (target, kind, active_version, updated_at)
+            VALUES (?, ?, NULL, ?)
+            ON CONFLICT(target) DO UPDATE SET updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(target)
+        .bind(kind)
+        .bind(now.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        let version: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM delivery_hook_versions WHERE target = ?",
+        )
+        .bind(target)
+        .fetch_one(&self.db)
+        .await?;
+        let sha256 = hex(&Sha256::digest(module));
+        sqlx::query(
+            r#"
+            INSERT INTO delivery_hook_versions
+                (target, version, module, sha256, author, comment, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(target)
+        .bind(version)
+        .bind(module)
+        .bind(&sha256)
+        .bind(author)
+        .bind(comment)
+        .bind(now.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        if activate {
+            self.set_active(target, Some(version)).await?;
+        }
+        self.trim_versions(target, version).await?;
+
+        Ok(HookVersion {
+            target: target.to_string(),
+            version: version as u32,
+            sha256,
+            size: module.len(),
+            author: author.to_string(),
+            comment: comment.map(str::to_string),
+            created_at: now,
+        })
+    }
+
+    /// All hooks, domains first
+    pub async fn list_hooks(&self) -> Result<Vec<DeliveryHook>> {
+        let rows = sqlx::query_as::<_, HookRow>(
+            r#"
+            SELECT h.target, h.kind, h.active_version,
+                   (SELECT MAX(version) FROM delivery_hook_versions v WHERE v.target = h.target),
+                   h.updated_at
+            FROM delivery_hooks h
+            ORDER BY h.kind, h.target
+            "#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+        rows.into_iter().map(hook_from_row).collect()
+    }
+
+    pub async fn get_hook(&self, scope: &HookScope) -> Result<Option<DeliveryHook>> {
+        let row = sqlx::query_as::<_, HookRow>(
+            r#"
+            SELECT h.target, h.kind, h.active_version,
+                   (SELECT MAX(version) FROM delivery_hook_versions v WHERE v.target = h.target),
+                   h.updated_at
+            FROM delivery_hooks h
+            WHERE h.target = ?
+            "#,
+        )
+        .bind(scope.target())
+        .fetch_optional(&self.db)
+        .await?;
+        row.map(hook_from_row).transpose()
+    }
+
+    /// Saved versions of a hook, newest first
+    pub async fn list_versions(&self, scope: &HookScope) -> Result<Vec<HookVersion>> {
+        let rows = sqlx::query_as::<_, VersionRow>(
+            r#"
+            SELECT target, version, sha256, LENGTH(module), author, comment, created_at
+            FROM delivery_hook_versions
+            WHERE target = ?
+            ORDER BY version DESC
+            "#,
+        )
+        .bind(scope.target())
+        .fetch_all(&self.db)
+        .await?;
+        rows.into_iter().map(version_from_row).collect()
+    }
+
+    /// Make `version` the one that runs on delivery; `None` if the hook
+    /// has no such version
+    pub async fn activate(&self, scope: &HookScope, version: u32) -> Result<Option<DeliveryHook>> {
+        let Some(module) = self.load_module(scope.target(), version).await? else {
+            return Ok(None);
+        };
+        self.check_module(&module)?;
+        self.set_active(scope.target(), Some(version as i64)).await?;
+        self.get_hook(scope).await
+    }
+
+    /// Stop running the hook on delivery, keeping its versions
+    pub async fn deactivate(&self, scope: &HookScope) -> Result<Option<DeliveryHook>> {
+        self.set_active(scope.target(), None).await?;
+        self.get_hook(scope).await
+    }
+
+    /// Delete a hook and all its versions
+    pub async fn delete(&self, scope: &HookScope) -> Result<bool> {
+        sqlx::query("DELETE FROM delivery_hook_versions WHERE target = ?")
+            .bind(scope.target())
+            .execute(&self.db)
+            .await?;
+        let result = sqlx::query("DELETE FROM delivery_hooks WHERE target = ?")
+            .bind(scope.target())
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Run a module on `input` without delivering anything: `module` if
+    /// given, otherwise `version` of the hook or its active (or latest)
+    /// version; `None` if there is no such version
+    pub async fn dry_run(
+        &self,
+        scope: &HookScope,
+        version: Option<u32>,
+        module: Option<&[u8]>,
+        input: HookInput,
+    ) -> Result<Option<HookReport>> {
+        let module = match module {
+            Some(module) => module.to_vec(),
+            None => {
+                let version = match version {
+                    Some(version) => Some(version),
+                    None => self
+                        .get_hook(scope)
+                        .await?
+                        .map(|hook| hook.active_version.unwrap_or(hook.latest_version)),
+                };
+                let Some(version) = version else {
+                    return Ok(None);
+                };
+                match self.load_module(scope.target(), version).await? {
+                    Some(module) => module,
+                    None => return Ok(None),
+                }
+            }
+        };
+        Ok(Some(self.runtime.run(&module, Arc::new(input))))
+    }
+
+    /// Run the active hooks of `mailbox` and its domain on a message to
+    /// `recipient`, the mailbox or one of its aliases; hooks that fail are
+    /// skipped
+    pub async fn run_for_delivery(
+        &self,
+        sender: &str,
+        recipient: &str,
+        mailbox: &str,
+        message: &[u8],
+    ) -> Result<HookOutcome> {
+        let mut outcome = HookOutcome::default();
+        let mut input = None;
+        for scope in HookScope::for_recipient(mailbox) {
+            let Some(module) = self.active_module(scope.target()).await? else {
+                continue;
+            };
+            let input = input
+                .get_or_insert_with(|| Arc::new(HookInput::from_message(message, sender, recipient)))
+                .clone();
+            let report = self.runtime.run(&module, input);
+            for line in &report.logs {
+                debug!("Hook of {}: {}", scope.target(), line);
+            }
+            if let Some(error) = report.error {
+                warn!("Skipping hook of {} for {}: {}", scope.target(), mailbox, error);
+                continue;
+            }
+            outcome.merge(report.outcome);
+            if outcome.reject.is_some() {
+                break;
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Validate `module` and run it on the sample message
+    fn check_module(&self, module: &[u8]) -> Result<HookReport> {
+        self.runtime.validate(module)?;
+        let input = HookInput::from_message(
+            SAMPLE_MESSAGE,
+            "sender@example.org",
+            "recipient@example.com",
+        );
+        let report = self.runtime.run(module, Arc::new(input));
+        if let Some(error) = &report.error {
+            bail!("Hook failed dry run: {}", error);
+        }
+        Ok(report)
+    }
+
+    async fn active_module(&self, target: &str) -> Result<Option<Vec<u8>>> {
+        let module = sqlx::query_scalar::<_, Vec<u8>>(
+            r#"
+            SELECT v.module
+            FROM delivery_hooks h
+            JOIN delivery_hook_versions v
+              ON v.target = h.target AND v.version = h.active_version
+            WHERE h.target = ?
+            "#,
+        )
+        .bind(target)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(module)
+    }
+
+    async fn load_module(&self, target: &str, version: u32) -> Result<Option<Vec<u8>>> {
+        let module = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT module FROM delivery_hook_versions WHERE target = ? AND version = ?",
+        )
+        .bind(target)
+        .bind(version as i64)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(module)
+    }
+
+    async fn set_active(&self, target: &str, version: Option<i64>) -> Result<()> {
+        sqlx::query("UPDATE delivery_hooks SET active_version = ?, updated_at = ? WHERE target = ?")
+            .bind(version)
+            .bind(Utc::now().to_rfc3339())
+            .bind(target)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Drop versions older than the kept ones, except the active version
+    async fn trim_versions(&self, target: &str, latest: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM delivery_hook_versions
+            WHERE target = ? AND version <= ?
+              AND version IS NOT (SELECT active_version FROM delivery_hooks WHERE target = ?)
+            "#,
+        )
+        .bind(target)
+        .bind(latest - self.max_versions as i64)
+        .bind(target)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+fn hook_from_row(row: HookRow) -> Result<DeliveryHook> {
+    let (target, kind, active_version, latest_version, updated_at) = row;
+    let scope = match kind.as_str() {
+        "domain" => HookScope::Domain(target),
+        "user" => HookScope::User(target),
+        other => return Err(anyhow!("Unknown hook kind {}", other)),
+    };
+    Ok(DeliveryHook {
+        scope,
+        active_version: active_version.map(|version| version as u32),
+        latest_version: latest_version as u32,
+        updated_at: parse_time(&updated_at)?,
+    })
+}
+
+fn version_from_row(row: VersionRow) -> Result<HookVersion> {
+    let (target, version, sha256, size, author, comment, created_at) = row;
+    Ok(HookVersion {
+        target,
+        version: version as u32,
+        sha256,
+        size: size as usize,
+        author,
+        comment,
+        created_at: parse_time(&created_at)?,
+    })
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Files mail into `Hooked` and tags it
+    const TAGGING: &str = r#"(module
+        (import "mail" "add_header" (func $add_header (param i32 i32 i32 i32) (result i32)))
+        (import "mail" "set_folder" (func $set_folder (param i32 i32) (result i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) "X-Hooked")
+        (data (i32.const 16) "yes")
+        (data (i32.const 32) "Hooked")
+        (func (export "on_delivery")
+            (drop (call $add_header (i32.const 0) (i32.const 8) (i32.const 16) (i32.const 3)))
+            (drop (call $set_folder (i32.const 32) (i32.const 6)))))"#;
+
+    /// Rejects mail from senders under the `.example` TLD
+    const REJECTING: &str = r#"(module
+        (import "mail" "sender" (func $sender (param i32 i32) (result i32)))
+        (import "mail" "reject" (func $reject (param i32 i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) "No spam please")
+        (func (export "on_delivery")
+            (local $len i32)
+            (local.set $len (call $sender (i32.const 100) (i32.const 64)))
+            ;; senders ending in ".example"
+            (if (i32.and
+                    (i32.ge_s (local.get $len) (i32.const 8))
+                    (i64.eq (i64.load (i32.sub (i32.add (i32.const 100) (local.get $len)) (i32.const 8)))
+                            (i64.const 0x656c706d6178652e)))
+                (then (call $reject (i32.const 0) (i32.const 14))))))"#;
+
+    const FAILING: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "on_delivery") unreachable))"#;
+
+    async fn manager() -> HookManager {
+        HookManager::connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn wasm(wat: &str) -> Vec<u8> {
+        wat::parse_str(wat).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_versions_and_activation() {
+        let manager = manager().await.with_max_versions(1);
+        let user = HookScope::User("bob@example.com".to_string());
+
+        let first = manager
+            .upload(&user, &wasm(TAGGING), "bob@example.com", Some("tagging"), true)
+            .await
+            .unwrap();
+        assert_eq!(first.version, 1);
+        assert_eq!(first.sha256.len(), 64);
+
+        // A failing module is kept but cannot become active
+        assert!(manager
+            .upload(&user, &wasm(FAILING), "bob@example.com", None, true)
+            .await
+            .is_err());
+        let second = manager
+            .upload(&user, &wasm(FAILING), "bob@example.com", None, false)
+            .await
+            .unwrap();
+        assert_eq!(second.version, 2);
+        assert!(manager.activate(&user, 2).await.is_err());
+        assert!(manager.activate(&user, 9).await.unwrap().is_none());
+        assert!(manager
+            .upload(&user, b"garbage", "bob@example.com", None, false)
+            .await
+            .is_err());
+
+        // Trimming keeps the active version
+        manager
+            .upload(&user, &wasm(REJECTING), "bob@example.com", None, false)
+            .await
+            .unwrap();
+        let versions: Vec<u32> = manager
+            .list_versions(&user)
+            .await
+            .unwrap()
+            .iter()
+            .map(|version| version.version)
+            .collect();
+        assert_eq!(versions, vec![3, 1]);
+
+        let hook = manager.activate(&user, 3).await.unwrap().unwrap();
+        assert_eq!(hook.active_version, Some(3));
+        assert_eq!(hook.latest_version, 3);
+        let hook = manager.deactivate(&user).await.unwrap().unwrap();
+        assert_eq!(hook.active_version, None);
+        assert_eq!(manager.list_hooks().await.unwrap().len(), 1);
+
+        assert!(manager.delete(&user).await.unwrap());
+        assert!(manager.get_hook(&user).await.unwrap().is_none());
+        assert!(manager.list_versions(&user).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_for_delivery() {
+        let manager = manager().await;
+        let domain = HookScope::Domain("example.com".to_string());
+        let user = HookScope::User("bob@example.com".to_string());
+        manager
+            .upload(&domain, &wasm(TAGGING), "admin@example.com", None, true)
+            .await
+            .unwrap();
+        manager
+            .upload(&user, &wasm(REJECTING), "bob@example.com", None, true)
+            .await
+            .unwrap();
+        let message = b"Subject: Hi\r\n\r\nHello\r\n";
+
+        let outcome = manager
+            .run_for_delivery("alice@example.org", "bob@example.com", "bob@example.com", message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.headers, vec![("X-Hooked".to_string(), "yes".to_string())]);
+        assert_eq!(outcome.folder.as_deref(), Some("Hooked"));
+        assert_eq!(outcome.reject, None);
+
+        let outcome = manager
+            .run_for_delivery("eve@spam.example", "bob@example.com", "bob@example.com", message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.reject.as_deref(), Some("No spam please"));
+
+        // Other domains are untouched
+        let outcome = manager
+            .run_for_delivery("eve@spam.example", "carol@example.net", "carol@example.net", message)
+            .await
+            .unwrap();
+        assert!(outcome.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let manager = manager().await;
+        let user = HookScope::User("bob@example.com".to_string());
+        let input = || HookInput::from_message(b"Subject: Hi\r\n\r\nHello\r\n", "eve@spam.example", "bob@example.com");
+
+        assert!(manager.dry_run(&user, None, None, input()).await.unwrap().is_none());
+
+        manager
+            .upload(&user, &wasm(REJECTING), "bob@example.com", None, false)
+            .await
+            .unwrap();
+        let report = manager.dry_run(&user, None, None, input()).await.unwrap().unwrap();
+        assert_eq!(report.outcome.reject.as_deref(), Some("No spam please"));
+
+        let report = manager
+            .dry_run(&user, None, Some(&wasm(FAILING)), input())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(report.error.is_some());
+        assert!(manager.dry_run(&user, Some(5), None, input()).await.unwrap().is_none());
+    }
+}
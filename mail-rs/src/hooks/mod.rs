@@ -0,0 +1,39 @@
+//! Sandboxed delivery hooks
+//!
+//! A domain or a single user can attach a WebAssembly module to local
+//! delivery for logic Sieve cannot express. The domain's hook runs first,
+//! then the user's; each may add header fields, choose the folder the
+//! message is filed into and reject the message. Sieve runs afterwards
+//! and sees the added fields; its `keep` files into the chosen folder.
+//!
+//! Modules run in [`wasmi`] with a fuel budget and a memory limit. They
+//! export `memory` and a `on_delivery: () -> ()` function, and may only
+//! import these functions of the `mail` module (pointers and lengths are
+//! `i32` offsets into the module's memory):
+//!
+//! | Function | Effect |
+//! |----------|--------|
+//! | `header(name, name_len, out, cap) -> len` | First value of a header field, `-1` if absent |
+//! | `body_len() -> len` | Size of the raw message body |
+//! | `body(offset, out, cap) -> copied` | Body bytes from `offset` |
+//! | `sender(out, cap) -> len` | Envelope sender |
+//! | `recipient(out, cap) -> len` | Envelope recipient |
+//! | `add_header(name, name_len, value, value_len) -> status` | Prepend a header field |
+//! | `set_folder(name, name_len) -> status` | File into this folder instead of INBOX |
+//! | `reject(reason, reason_len)` | Refuse the message with `550 5.7.1` |
+//! | `log(text, text_len)` | Note shown by dry runs and debug logs |
+//!
+//! Functions returning a length copy at most `cap` bytes and return the
+//! full length, so a module can retry with a larger buffer. A status is
+//! `0` on success and `-1` if the call was refused.
+//!
+//! Every upload is kept as a version of its hook; only a version that
+//! instantiated and ran on a sample message becomes active.
+
+pub mod manager;
+pub mod runtime;
+pub mod types;
+
+pub use manager::HookManager;
+pub use runtime::{HookInput, HookLimits, HookRuntime};
+pub use types::*;
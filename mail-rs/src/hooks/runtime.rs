@@ -0,0 +1,523 @@
+//! Running hook modules in a wasmi sandbox
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+use wasmi::core::TrapCode;
+use wasmi::errors::LinkerError;
+use wasmi::{
+    Caller, Config, Engine, Error, ExternType, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::mime::entity::header_fields;
+use crate::mime::MimeEntity;
+
+use super::types::{HookOutcome, HookReport};
+
+/// Import module of the host functions
+const IMPORT_MODULE: &str = "mail";
+/// Export called on delivery
+const ENTRY_POINT: &str = "on_delivery";
+/// Host functions a module may import
+const HOST_FUNCTIONS: &[&str] = &[
+    "header",
+    "body_len",
+    "body",
+    "sender",
+    "recipient",
+    "add_header",
+    "set_folder",
+    "reject",
+    "log",
+];
+/// Longest string a module can pass to the host
+const MAX_STRING_BYTES: usize = 4096;
+/// Notes kept per run
+const MAX_LOGS: usize = 32;
+
+/// Resources a hook may use per run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookLimits {
+    /// Fuel per run, roughly the instructions it may execute
+    pub fuel: u64,
+    /// Linear memory a module may grow to
+    pub memory_bytes: usize,
+    pub max_module_bytes: usize,
+    /// Header fields a module may add per run
+    pub max_headers: usize,
+}
+
+impl Default for HookLimits {
+    fn default() -> Self {
+        Self {
+            fuel: 10_000_000,
+            memory_bytes: 4 * 1024 * 1024,
+            max_module_bytes: 1024 * 1024,
+            max_headers: 16,
+        }
+    }
+}
+
+/// Message and envelope a hook runs on
+#[derive(Debug, Clone, Default)]
+pub struct HookInput {
+    pub sender: String,
+    pub recipient: String,
+    /// Header fields with folded lines joined
+    pub headers: Vec<(String, String)>,
+    /// Raw body
+    pub body: Vec<u8>,
+}
+
+impl HookInput {
+    pub fn from_message(message: &[u8], sender: &str, recipient: &str) -> Self {
+        let entity = MimeEntity::parse(message);
+        let headers = header_fields(entity.header)
+            .into_iter()
+            .filter_map(|(_, raw)| {
+                let raw = String::from_utf8_lossy(raw);
+                let (name, value) = raw.split_once(':')?;
+                let value = value
+                    .split(['\r', '\n'])
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Some((name.trim().to_string(), value))
+            })
+            .collect();
+        Self {
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            headers,
+            body: entity.body.to_vec(),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// State of one run, reachable from host functions
+struct HostState {
+    input: Arc<HookInput>,
+    outcome: HookOutcome,
+    logs: Vec<String>,
+    max_headers: usize,
+    limits: StoreLimits,
+}
+
+/// Compiles and runs hook modules within [`HookLimits`]
+pub struct HookRuntime {
+    engine: Engine,
+    linker: Linker<HostState>,
+    limits: HookLimits,
+}
+
+impl HookRuntime {
+    pub fn new(limits: HookLimits) -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let linker = host_linker(&engine).expect("host functions have distinct names");
+        Self {
+            engine,
+            linker,
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> HookLimits {
+        self.limits
+    }
+
+    /// Check that `wasm` compiles, exports `memory` and `on_delivery` and
+    /// only imports host functions
+    pub fn validate(&self, wasm: &[u8]) -> Result<()> {
+        self.compile(wasm).map(|_| ())
+    }
+
+    /// Run the `on_delivery` export of `wasm` on `input`; a module that
+    /// traps, runs out of fuel or fails to instantiate is reported in
+    /// [`HookReport::error`]
+    pub fn run(&self, wasm: &[u8], input: Arc<HookInput>) -> HookReport {
+        let mut store = Store::new(
+            &self.engine,
+            HostState {
+                input,
+                outcome: HookOutcome::default(),
+                logs: Vec::new(),
+                max_headers: self.limits.max_headers,
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.limits.memory_bytes)
+                    .memories(1)
+                    .tables(1)
+                    .table_elements(10_000)
+                    .instances(1)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+
+        let result = self.call(wasm, &mut store);
+        let fuel_used = self
+            .limits
+            .fuel
+            .saturating_sub(store.get_fuel().unwrap_or(0));
+        let state = store.into_data();
+        match result {
+            Ok(()) => HookReport {
+                outcome: state.outcome,
+                fuel_used,
+                logs: state.logs,
+                error: None,
+            },
+            Err(e) => HookReport {
+                outcome: HookOutcome::default(),
+                fuel_used,
+                logs: state.logs,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+
+    fn call(&self, wasm: &[u8], store: &mut Store<HostState>) -> Result<()> {
+        let module = self.compile(wasm)?;
+        store
+            .set_fuel(self.limits.fuel)
+            .map_err(|e| anyhow!("Failed to set fuel: {}", e))?;
+        let instance = self
+            .linker
+            .instantiate(&mut *store, &module)
+            .and_then(|instance| instance.start(&mut *store))
+            .map_err(|e| anyhow!("Failed to instantiate hook: {}", e))?;
+        let entry: TypedFunc<(), ()> = instance
+            .get_typed_func(&*store, ENTRY_POINT)
+            .map_err(|e| anyhow!("Invalid {} export: {}", ENTRY_POINT, e))?;
+        entry.call(&mut *store, ()).map_err(|e| match e.as_trap_code() {
+            Some(TrapCode::OutOfFuel) => anyhow!("Hook ran out of fuel ({})", self.limits.fuel),
+            _ => anyhow!("Hook failed: {}", e),
+        })
+    }
+
+    fn compile(&self, wasm: &[u8]) -> Result<Module> {
+        if wasm.len() > self.limits.max_module_bytes {
+            bail!(
+                "Module of {} bytes exceeds the limit of {} bytes",
+                wasm.len(),
+                self.limits.max_module_bytes
+            );
+        }
+        let module =
+            Module::new(&self.engine, wasm).map_err(|e| anyhow!("Invalid module: {}", e))?;
+
+        for import in module.imports() {
+            if import.module() != IMPORT_MODULE || !HOST_FUNCTIONS.contains(&import.name()) {
+                bail!("Unknown import {}.{}", import.module(), import.name());
+            }
+        }
+        let mut has_memory = false;
+        let mut has_entry = false;
+        for export in module.exports() {
+            match (export.name(), export.ty()) {
+                ("memory", ExternType::Memory(_)) => has_memory = true,
+                (ENTRY_POINT, ExternType::Func(ty)) => {
+                    if !ty.params().is_empty() || !ty.results().is_empty() {
+                        bail!("{} must take and return nothing", ENTRY_POINT);
+                    }
+                    has_entry = true;
+                }
+                _ => {}
+            }
+        }
+        if !has_memory {
+            bail!("Module does not export its memory");
+        }
+        if !has_entry {
+            bail!("Module does not export {}", ENTRY_POINT);
+        }
+        Ok(module)
+    }
+}
+
+fn host_linker(engine: &Engine) -> std::result::Result<Linker<HostState>, LinkerError> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "header",
+        |mut caller: Caller<'_, HostState>, name: i32, name_len: i32, out: i32, cap: i32| {
+            let name = read_string(&caller, name, name_len)?;
+            let input = caller.data().input.clone();
+            match input.header(&name) {
+                Some(value) => write_out(&mut caller, value.as_bytes(), out, cap),
+                None => Ok(-1),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "body_len",
+        |caller: Caller<'_, HostState>| -> i32 { caller.data().input.body.len() as i32 },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "body",
+        |mut caller: Caller<'_, HostState>, offset: i32, out: i32, cap: i32| {
+            let input = caller.data().input.clone();
+            let start = (offset.max(0) as usize).min(input.body.len());
+            let end = start.saturating_add(cap.max(0) as usize).min(input.body.len());
+            write_out(&mut caller, &input.body[start..end], out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "sender",
+        |mut caller: Caller<'_, HostState>, out: i32, cap: i32| {
+            let input = caller.data().input.clone();
+            write_out(&mut caller, input.sender.as_bytes(), out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "recipient",
+        |mut caller: Caller<'_, HostState>, out: i32, cap: i32| {
+            let input = caller.data().input.clone();
+            write_out(&mut caller, input.recipient.as_bytes(), out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "add_header",
+        |mut caller: Caller<'_, HostState>, name: i32, name_len: i32, value: i32, value_len: i32| {
+            let name = read_string(&caller, name, name_len)?;
+            let value = read_string(&caller, value, value_len)?;
+            let state = caller.data_mut();
+            if !valid_header_name(&name)
+                || value.contains(['\r', '\n'])
+                || state.outcome.headers.len() >= state.max_headers
+            {
+                return Ok(-1);
+            }
+            state.outcome.headers.push((name, value.trim().to_string()));
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "set_folder",
+        |mut caller: Caller<'_, HostState>, name: i32, name_len: i32| {
+            let name = read_string(&caller, name, name_len)?;
+            let name = name.trim();
+            if name.is_empty() || name.len() > 255 || name.contains(|c: char| c.is_control()) {
+                return Ok(-1);
+            }
+            caller.data_mut().outcome.folder = Some(name.to_string());
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "reject",
+        |mut caller: Caller<'_, HostState>, reason: i32, reason_len: i32| {
+            let reason = read_string(&caller, reason, reason_len)?;
+            let reason: String = reason
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .take(200)
+                .collect();
+            let reason = match reason.trim() {
+                "" => "Message rejected".to_string(),
+                reason => reason.to_string(),
+            };
+            caller.data_mut().outcome.reject = Some(reason);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "log",
+        |mut caller: Caller<'_, HostState>, text: i32, text_len: i32| {
+            let text = read_string(&caller, text, text_len)?;
+            let logs = &mut caller.data_mut().logs;
+            if logs.len() < MAX_LOGS {
+                logs.push(text);
+            }
+            Ok(())
+        },
+    )?;
+    Ok(linker)
+}
+
+/// Field names are printable ASCII without colon (RFC 5322 section 2.2)
+fn valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 76
+        && name.bytes().all(|b| (33..=126).contains(&b) && b != b':')
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, Error> {
+    let len = usize::try_from(len).map_err(|_| Error::new("negative length"))?;
+    if len > MAX_STRING_BYTES {
+        return Err(Error::new(format!(
+            "string of {} bytes exceeds {} bytes",
+            len, MAX_STRING_BYTES
+        )));
+    }
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| Error::new("module exports no memory"))?;
+    let mut buffer = vec![0; len];
+    memory
+        .read(caller, ptr as u32 as usize, &mut buffer)
+        .map_err(|_| Error::new("string out of bounds"))?;
+    String::from_utf8(buffer).map_err(|_| Error::new("string is not UTF-8"))
+}
+
+/// Copy up to `cap` bytes of `value` to `out`; returns the full length
+fn write_out(
+    caller: &mut Caller<'_, HostState>,
+    value: &[u8],
+    out: i32,
+    cap: i32,
+) -> Result<i32, Error> {
+    let copied = value.len().min(cap.max(0) as usize);
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| Error::new("module exports no memory"))?;
+    memory
+        .write(&mut *caller, out as u32 as usize, &value[..copied])
+        .map_err(|_| Error::new("buffer out of bounds"))?;
+    Ok(value.len() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] =
+        b"From: alice@example.org\r\nSubject: Invoice\r\n  overdue\r\n\r\nPlease pay.\r\n";
+
+    fn run(wat: &str, limits: HookLimits) -> HookReport {
+        let wasm = wat::parse_str(wat).unwrap();
+        let input = HookInput::from_message(MESSAGE, "alice@example.org", "bob@example.com");
+        HookRuntime::new(limits).run(&wasm, Arc::new(input))
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let report = run(
+            r#"(module
+                (import "mail" "header" (func $header (param i32 i32 i32 i32) (result i32)))
+                (import "mail" "body" (func $body (param i32 i32 i32) (result i32)))
+                (import "mail" "add_header" (func $add_header (param i32 i32 i32 i32) (result i32)))
+                (import "mail" "set_folder" (func $set_folder (param i32 i32) (result i32)))
+                (import "mail" "log" (func $log (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "subject")
+                (data (i32.const 16) "X-Subject")
+                (data (i32.const 32) "Billing")
+                (func (export "on_delivery")
+                    (local $len i32)
+                    ;; copy the subject to offset 100 and add it as a header
+                    (local.set $len (call $header (i32.const 0) (i32.const 7) (i32.const 100) (i32.const 64)))
+                    (drop (call $add_header (i32.const 16) (i32.const 9) (i32.const 100) (local.get $len)))
+                    (call $log (i32.const 100) (call $body (i32.const 0) (i32.const 100) (i32.const 6)))
+                    (drop (call $set_folder (i32.const 32) (i32.const 7)))))"#,
+            HookLimits::default(),
+        );
+        assert_eq!(report.error, None);
+        assert_eq!(
+            report.outcome.headers,
+            vec![("X-Subject".to_string(), "Invoice overdue".to_string())]
+        );
+        assert_eq!(report.outcome.folder.as_deref(), Some("Billing"));
+        assert_eq!(report.logs, vec!["Please".to_string()]);
+        assert!(report.fuel_used > 0);
+    }
+
+    #[test]
+    fn test_reject_and_refused_calls() {
+        let report = run(
+            r#"(module
+                (import "mail" "add_header" (func $add_header (param i32 i32 i32 i32) (result i32)))
+                (import "mail" "reject" (func $reject (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "Bad Name")
+                (data (i32.const 16) "No thanks\r\n")
+                (func (export "on_delivery")
+                    (if (i32.ne (call $add_header (i32.const 0) (i32.const 8) (i32.const 16) (i32.const 2)) (i32.const -1))
+                        (then unreachable))
+                    (call $reject (i32.const 16) (i32.const 11))))"#,
+            HookLimits::default(),
+        );
+        assert_eq!(report.error, None);
+        assert!(report.outcome.headers.is_empty());
+        assert_eq!(report.outcome.reject.as_deref(), Some("No thanks"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let report = run(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "on_delivery") (loop (br 0))))"#,
+            HookLimits {
+                fuel: 10_000,
+                ..Default::default()
+            },
+        );
+        assert!(report.error.unwrap().contains("out of fuel"));
+        assert!(report.fuel_used > 9_000);
+
+        // Growing past the memory limit fails instead of allocating
+        let report = run(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "on_delivery")
+                    (if (i32.ne (memory.grow (i32.const 100)) (i32.const -1))
+                        (then unreachable))))"#,
+            HookLimits {
+                memory_bytes: 2 * 65536,
+                ..Default::default()
+            },
+        );
+        assert_eq!(report.error, None);
+
+        let report = run(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "on_delivery") unreachable))"#,
+            HookLimits::default(),
+        );
+        assert!(report.error.unwrap().contains("Hook failed"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let runtime = HookRuntime::new(HookLimits::default());
+        let validate = |wat: &str| runtime.validate(&wat::parse_str(wat).unwrap());
+
+        assert!(validate(r#"(module (memory (export "memory") 1) (func (export "on_delivery")))"#).is_ok());
+        let error = validate(
+            r#"(module
+                (import "wasi" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "on_delivery")))"#,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Unknown import wasi.fd_write");
+        assert!(validate(r#"(module (func (export "on_delivery")))"#).is_err());
+        assert!(validate(r#"(module (memory (export "memory") 1))"#).is_err());
+        assert!(validate(
+            r#"(module (memory (export "memory") 1) (func (export "on_delivery") (param i32)))"#
+        )
+        .is_err());
+        assert!(runtime.validate(b"not wasm").is_err());
+    }
+}
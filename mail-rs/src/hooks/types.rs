@@ -0,0 +1,196 @@
+//! Delivery hook types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Whose mail a hook runs on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "target", rename_all = "snake_case")]
+pub enum HookScope {
+    /// Every local recipient of the domain
+    Domain(String),
+    /// One mailbox
+    User(String),
+}
+
+impl HookScope {
+    /// Scope of `target`: an address is a user, anything else a domain
+    pub fn parse(target: &str) -> Option<Self> {
+        let target = target.trim().to_lowercase();
+        match target.rsplit_once('@') {
+            Some((local, domain)) if !local.is_empty() && !domain.is_empty() => {
+                Some(HookScope::User(target))
+            }
+            Some(_) => None,
+            None if !target.is_empty() => Some(HookScope::Domain(target)),
+            None => None,
+        }
+    }
+
+    /// Hooks that run on mail to `recipient`, in order
+    pub fn for_recipient(recipient: &str) -> Vec<Self> {
+        let recipient = recipient.to_lowercase();
+        let mut scopes = Vec::new();
+        if let Some((_, domain)) = recipient.rsplit_once('@') {
+            scopes.push(HookScope::Domain(domain.to_string()));
+        }
+        scopes.push(HookScope::User(recipient));
+        scopes
+    }
+
+    /// Domain or address the hook is attached to
+    pub fn target(&self) -> &str {
+        match self {
+            HookScope::Domain(target) | HookScope::User(target) => target,
+        }
+    }
+}
+
+/// Hook attached to a domain or user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeliveryHook {
+    pub scope: HookScope,
+    /// Version that runs on delivery; none while the hook is deactivated
+    pub active_version: Option<u32>,
+    pub latest_version: u32,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Saved version of a hook's module
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookVersion {
+    /// Domain or address of the hook
+    pub target: String,
+    /// Version number, starting at 1
+    pub version: u32,
+    /// Hex SHA-256 of the module
+    pub sha256: String,
+    pub size: usize,
+    /// User who uploaded this version
+    pub author: String,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Request to upload a new version of a hook
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadHookRequest {
+    /// Base64 of the WebAssembly module
+    pub module: String,
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// Make the new version the one that runs on delivery
+    #[serde(default)]
+    pub activate: bool,
+}
+
+/// Request to run a hook on a message without delivering it
+#[derive(Debug, Clone, Deserialize)]
+pub struct DryRunHookRequest {
+    /// Raw message
+    pub message: String,
+    #[serde(default)]
+    pub sender: Option<String>,
+    /// Envelope recipient; the hook's address, or `postmaster` of its
+    /// domain, if unset
+    #[serde(default)]
+    pub recipient: Option<String>,
+    /// Saved version to run; the active or latest one if unset
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Base64 of a module to try before uploading it
+    #[serde(default)]
+    pub module: Option<String>,
+}
+
+/// What hooks did to a message
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HookOutcome {
+    /// Header fields to prepend, in the order they were added
+    pub headers: Vec<(String, String)>,
+    /// Folder to file the message into instead of INBOX
+    pub folder: Option<String>,
+    /// Reason to refuse the message with
+    pub reject: Option<String>,
+}
+
+impl HookOutcome {
+    /// Whether the hooks left the message as it is
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.folder.is_none() && self.reject.is_none()
+    }
+
+    /// Add the effects of a later hook; its folder wins
+    pub fn merge(&mut self, later: HookOutcome) {
+        self.headers.extend(later.headers);
+        if later.folder.is_some() {
+            self.folder = later.folder;
+        }
+        if self.reject.is_none() {
+            self.reject = later.reject;
+        }
+    }
+
+    /// Added header fields as lines to prepend to the message
+    pub fn header_block(&self) -> String {
+        self.headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect()
+    }
+}
+
+/// Result of running one hook
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HookReport {
+    pub outcome: HookOutcome,
+    /// Fuel the module burnt, roughly its executed instructions
+    pub fuel_used: u64,
+    /// Notes the module logged
+    pub logs: Vec<String>,
+    /// Why the module failed; its outcome is then ignored on delivery
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(
+            HookScope::parse("Alice@Example.com"),
+            Some(HookScope::User("alice@example.com".to_string()))
+        );
+        assert_eq!(
+            HookScope::parse("example.com"),
+            Some(HookScope::Domain("example.com".to_string()))
+        );
+        assert_eq!(HookScope::parse("@example.com"), None);
+        assert_eq!(HookScope::parse(" "), None);
+        assert_eq!(
+            HookScope::for_recipient("bob@example.com"),
+            vec![
+                HookScope::Domain("example.com".to_string()),
+                HookScope::User("bob@example.com".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut outcome = HookOutcome {
+            headers: vec![("X-Domain".to_string(), "1".to_string())],
+            folder: Some("Lists".to_string()),
+            reject: None,
+        };
+        outcome.merge(HookOutcome {
+            headers: vec![("X-User".to_string(), "2".to_string())],
+            folder: None,
+            reject: Some("no".to_string()),
+        });
+        assert_eq!(outcome.folder.as_deref(), Some("Lists"));
+        assert_eq!(outcome.reject.as_deref(), Some("no"));
+        assert_eq!(outcome.header_block(), "X-Domain: 1\r\nX-User: 2\r\n");
+    }
+}
@@ -0,0 +1,256 @@
+//! BODYSTRUCTURE and ENVELOPE responses
+//!
+//! Built from the MIME entity tree of the message (see
+//! [`MimeParser::parse_tree`](crate::mime::MimeParser::parse_tree)), so
+//! clients can list attachments with their type, encoding, size and file
+//! name before fetching any part.
+
+use crate::imap::fetch::literal;
+use crate::mime::entity::{split_unquoted, unquote};
+use crate::mime::MimeEntity;
+
+/// BODYSTRUCTURE of `entity`, or the non-extensible BODY form when
+/// `extensible` is false
+pub fn body_structure(entity: &MimeEntity, extensible: bool) -> String {
+    let (media_type, subtype) = entity
+        .content_type
+        .split_once('/')
+        .unwrap_or((entity.content_type.as_str(), ""));
+
+    if media_type == "multipart" && !entity.parts.is_empty() {
+        let children: String = entity
+            .parts
+            .iter()
+            .map(|part| body_structure(part, extensible))
+            .collect();
+        let mut fields = vec![children, string(&subtype.to_uppercase())];
+        if extensible {
+            fields.push(params(&entity.content_type_params()));
+            fields.extend(extension_fields(entity));
+        }
+        return format!("({})", fields.join(" "));
+    }
+
+    let mut type_params = entity.content_type_params();
+    if media_type == "text" && !type_params.iter().any(|(key, _)| key == "charset") {
+        type_params.push(("charset".to_string(), "us-ascii".to_string()));
+    }
+
+    let mut fields = vec![
+        string(&media_type.to_uppercase()),
+        string(&subtype.to_uppercase()),
+        params(&type_params),
+        nstring(entity.header_value("content-id").as_deref()),
+        nstring(entity.header_value("content-description").as_deref()),
+        string(&entity.encoding().to_uppercase()),
+        entity.body.len().to_string(),
+    ];
+    if let Some(message) = entity.message.as_deref() {
+        fields.push(envelope(message));
+        fields.push(body_structure(message, extensible));
+        fields.push(entity.body_lines().to_string());
+    } else if media_type == "text" {
+        fields.push(entity.body_lines().to_string());
+    }
+    if extensible {
+        fields.push(nstring(entity.header_value("content-md5").as_deref()));
+        fields.extend(extension_fields(entity));
+    }
+    format!("({})", fields.join(" "))
+}
+
+/// Disposition, language and location
+fn extension_fields(entity: &MimeEntity) -> Vec<String> {
+    let disposition = match entity.disposition() {
+        Some((kind, disposition_params)) => {
+            format!("({} {})", string(&kind), params(&disposition_params))
+        }
+        None => "NIL".to_string(),
+    };
+    vec![
+        disposition,
+        nstring(entity.header_value("content-language").as_deref()),
+        nstring(entity.header_value("content-location").as_deref()),
+    ]
+}
+
+/// ENVELOPE of a message
+///
+/// Sender and Reply-To default to From, as RFC 3501 requires. Values are
+/// sent as they appear in the header, encoded words included: decoding is
+/// left to the client, and quoted strings can't carry 8-bit text.
+pub fn envelope(message: &MimeEntity) -> String {
+    let from = addresses(message.header_value("from").as_deref());
+    let or_from = |name: &str| {
+        let list = addresses(message.header_value(name).as_deref());
+        if list == "NIL" {
+            from.clone()
+        } else {
+            list
+        }
+    };
+
+    let fields = [
+        nstring(message.header_value("date").as_deref()),
+        nstring(message.header_value("subject").as_deref()),
+        from.clone(),
+        or_from("sender"),
+        or_from("reply-to"),
+        addresses(message.header_value("to").as_deref()),
+        addresses(message.header_value("cc").as_deref()),
+        addresses(message.header_value("bcc").as_deref()),
+        nstring(message.header_value("in-reply-to").as_deref()),
+        nstring(message.header_value("message-id").as_deref()),
+    ];
+    format!("({})", fields.join(" "))
+}
+
+/// Address list as `((name NIL mailbox host) ...)`, NIL when empty
+fn addresses(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "NIL".to_string();
+    };
+
+    let list: Vec<String> = split_unquoted(value, ',')
+        .into_iter()
+        .filter_map(address)
+        .collect();
+    if list.is_empty() {
+        "NIL".to_string()
+    } else {
+        format!("({})", list.concat())
+    }
+}
+
+fn address(item: &str) -> Option<String> {
+    let mut item = item.trim();
+    // Members of a group are listed as plain addresses
+    if let Some((group, members)) = item.split_once(':') {
+        if !group.contains('"') && !group.contains('<') {
+            item = members.trim();
+        }
+    }
+    let item = item.trim_end_matches(';').trim();
+    if item.is_empty() {
+        return None;
+    }
+
+    let (name, addr) = match (item.rfind('<'), item.rfind('>')) {
+        (Some(open), Some(close)) if open < close => {
+            let name = unquote(item[..open].trim());
+            (
+                Some(name).filter(|name| !name.is_empty()),
+                &item[open + 1..close],
+            )
+        }
+        _ => (None, item),
+    };
+    let (mailbox, host) = match addr.trim().rsplit_once('@') {
+        Some((mailbox, host)) => (mailbox, Some(host)),
+        None => (addr.trim(), None),
+    };
+
+    Some(format!(
+        "({} NIL {} {})",
+        nstring(name.as_deref()),
+        string(mailbox),
+        nstring(host)
+    ))
+}
+
+/// Body parameter list as `("NAME" "value" ...)`, NIL when empty
+fn params(params: &[(String, String)]) -> String {
+    if params.is_empty() {
+        return "NIL".to_string();
+    }
+    let fields: Vec<String> = params
+        .iter()
+        .flat_map(|(key, value)| [string(&key.to_uppercase()), string(value)])
+        .collect();
+    format!("({})", fields.join(" "))
+}
+
+fn nstring(value: Option<&str>) -> String {
+    value.map(string).unwrap_or_else(|| "NIL".to_string())
+}
+
+/// Quoted string, or a literal when `value` cannot be quoted
+fn string(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        literal(value.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"Date: Mon, 7 Feb 1994 21:52:25 -0800\r\n\
+From: \"Fred Foobar\" <foobar@example.com>\r\n\
+To: mooch@example.org, Team: alice@example.org, bob@example.org;\r\n\
+Subject: Report\r\n\
+Message-ID: <B27397-0100000@example.com>\r\n\
+Content-Type: multipart/mixed; boundary=b1\r\n\
+\r\n\
+--b1\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Hello,\r\n\
+see attached\r\n\
+--b1\r\n\
+Content-Type: application/pdf; name=report.pdf\r\n\
+Content-Disposition: attachment; filename=\"report.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0xLjQK\r\n\
+--b1\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+From: carol@example.net\r\n\
+Subject: Old\r\n\
+\r\n\
+Forwarded\r\n\
+--b1--\r\n";
+
+    #[test]
+    fn test_body_structure() {
+        let root = MimeEntity::parse(MESSAGE);
+        assert_eq!(
+            body_structure(&root, true),
+            "((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"QUOTED-PRINTABLE\" 20 2 NIL NIL NIL NIL)\
+(\"APPLICATION\" \"PDF\" (\"NAME\" \"report.pdf\") NIL NIL \"BASE64\" 12 NIL (\"attachment\" (\"FILENAME\" \"report.pdf\")) NIL NIL)\
+(\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 50 \
+(NIL \"Old\" ((NIL NIL \"carol\" \"example.net\")) ((NIL NIL \"carol\" \"example.net\")) ((NIL NIL \"carol\" \"example.net\")) NIL NIL NIL NIL NIL) \
+(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 9 1 NIL NIL NIL NIL) 4 NIL NIL NIL NIL) \
+\"MIXED\" (\"BOUNDARY\" \"b1\") NIL NIL NIL)"
+        );
+        assert_eq!(
+            body_structure(root.part(&[2]).unwrap(), false),
+            "(\"APPLICATION\" \"PDF\" (\"NAME\" \"report.pdf\") NIL NIL \"BASE64\" 12)"
+        );
+    }
+
+    #[test]
+    fn test_envelope() {
+        let root = MimeEntity::parse(MESSAGE);
+        assert_eq!(
+            envelope(&root),
+            "(\"Mon, 7 Feb 1994 21:52:25 -0800\" \"Report\" \
+((\"Fred Foobar\" NIL \"foobar\" \"example.com\")) \
+((\"Fred Foobar\" NIL \"foobar\" \"example.com\")) \
+((\"Fred Foobar\" NIL \"foobar\" \"example.com\")) \
+((NIL NIL \"mooch\" \"example.org\")(NIL NIL \"alice\" \"example.org\")(NIL NIL \"bob\" \"example.org\")) \
+NIL NIL NIL \"<B27397-0100000@example.com>\")"
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(string("café"), "{5}\r\ncafé");
+        assert_eq!(nstring(None), "NIL");
+    }
+}
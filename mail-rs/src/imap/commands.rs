@@ -4,6 +4,8 @@
 //! Example: A001 LOGIN john password
 
 use crate::error::MailError;
+use crate::imap::search;
+use chrono::{DateTime, FixedOffset, NaiveDate};
 
 /// Search criteria for IMAP SEARCH command
 #[derive(Debug, Clone, PartialEq)]
@@ -22,6 +24,74 @@ pub enum SearchCriteria {
 
     /// TEXT string - Messages with string in body or headers
     Text(String),
+
+    /// CC string - Messages with string in Cc
+    Cc(String),
+
+    /// BCC string - Messages with string in Bcc
+    Bcc(String),
+
+    /// BODY string - Messages with string in the body
+    Body(String),
+
+    /// HEADER field string - Messages with string in the named header; an
+    /// empty string matches every message that has the field
+    Header(String, String),
+
+    /// ANSWERED, DELETED, DRAFT, FLAGGED, SEEN or KEYWORD flag - Messages
+    /// with the flag set
+    Flag(String),
+
+    /// UNANSWERED, UNDELETED, UNDRAFT, UNFLAGGED, UNSEEN or UNKEYWORD flag
+    /// - Messages without the flag
+    Unflag(String),
+
+    /// RECENT - Messages that arrived since the last session (OLD is
+    /// `Not(Recent)`)
+    Recent,
+
+    /// NEW - Recent messages that were not read
+    New,
+
+    /// BEFORE date - Received before the day
+    Before(NaiveDate),
+
+    /// ON date - Received on the day
+    On(NaiveDate),
+
+    /// SINCE date - Received on or after the day
+    Since(NaiveDate),
+
+    /// SENTBEFORE date - Date header before the day
+    SentBefore(NaiveDate),
+
+    /// SENTON date - Date header on the day
+    SentOn(NaiveDate),
+
+    /// SENTSINCE date - Date header on or after the day
+    SentSince(NaiveDate),
+
+    /// LARGER n - Messages larger than n octets
+    Larger(usize),
+
+    /// SMALLER n - Messages smaller than n octets
+    Smaller(usize),
+
+    /// UID set - Messages with UIDs in the set
+    Uid(String),
+
+    /// set - Messages with sequence numbers in the set
+    Sequence(String),
+
+    /// NOT key - Messages that do not match the key
+    Not(Box<SearchCriteria>),
+
+    /// OR key1 key2 - Messages that match either key
+    Or(Box<SearchCriteria>, Box<SearchCriteria>),
+
+    /// Keys listed one after another or in parentheses - Messages that
+    /// match all of them
+    And(Vec<SearchCriteria>),
 }
 
 /// Store operation type
@@ -41,9 +111,18 @@ pub enum ImapCommand {
     /// CAPABILITY - List server capabilities
     Capability,
 
+    /// STARTTLS - Upgrade the connection to TLS
+    StartTls,
+
     /// LOGIN username password - Authenticate
     Login { username: String, password: String },
 
+    /// AUTHENTICATE mechanism [initial-response] - SASL authentication
+    Authenticate {
+        mechanism: String,
+        initial_response: Option<String>,
+    },
+
     /// SELECT mailbox - Select a mailbox
     Select { mailbox: String },
 
@@ -56,14 +135,49 @@ pub enum ImapCommand {
         items: Vec<String>,
     },
 
-    /// LIST reference mailbox - List mailboxes
+    /// LIST reference mailbox [RETURN (STATUS (items))] - List mailboxes,
+    /// with the status of each (LIST-STATUS, RFC 5819)
     List {
         reference: String,
         mailbox: String,
+        /// Upper-case STATUS items to return, empty without RETURN
+        status: Vec<String>,
+    },
+
+    /// STATUS mailbox (items) - Counts and UIDs of a mailbox
+    Status {
+        mailbox: String,
+        /// Upper-case item names, e.g. `MESSAGES` or `UIDNEXT`
+        items: Vec<String>,
+    },
+
+    /// LSUB reference mailbox - List subscribed mailboxes
+    Lsub {
+        reference: String,
+        mailbox: String,
     },
 
-    /// SEARCH criteria - Search for messages
-    Search { criteria: SearchCriteria },
+    /// SUBSCRIBE mailbox - Add a mailbox to the subscriptions
+    Subscribe { mailbox: String },
+
+    /// UNSUBSCRIBE mailbox - Remove a mailbox from the subscriptions
+    Unsubscribe { mailbox: String },
+
+    /// NAMESPACE - Personal, other users' and shared namespaces (RFC 2342)
+    Namespace,
+
+    /// COMPRESS mechanism - Compress the connection (RFC 4978)
+    Compress { mechanism: String },
+
+    /// ID (field value ...) - Client identification (RFC 2971); fields are
+    /// lowercased and NIL values left out
+    Id { fields: Vec<(String, String)> },
+
+    /// SEARCH [CHARSET charset] criteria - Search for messages
+    Search {
+        charset: Option<String>,
+        criteria: SearchCriteria,
+    },
 
     /// STORE sequence operation flags - Modify message flags
     Store {
@@ -75,12 +189,45 @@ pub enum ImapCommand {
     /// EXPUNGE - Permanently remove messages marked \Deleted
     Expunge,
 
+    /// UID EXPUNGE uid-set - Remove the messages marked \Deleted in a UID
+    /// set (UIDPLUS, RFC 4315)
+    UidExpunge { sequence: String },
+
     /// COPY sequence destination - Copy messages to another mailbox
     Copy {
         sequence: String,
         mailbox: String,
     },
 
+    /// APPEND mailbox [(flags)] [date-time] {size} - Save a message
+    ///
+    /// The message follows the command line as a literal of `size` bytes;
+    /// the server reads it into `message` before the command is handled.
+    Append {
+        mailbox: String,
+        flags: Vec<String>,
+        internal_date: Option<DateTime<FixedOffset>>,
+        size: usize,
+        message: Vec<u8>,
+    },
+
+    /// GETQUOTA root - Usage and limits of a quota root (RFC 2087)
+    GetQuota { root: String },
+
+    /// GETQUOTAROOT mailbox - Quota roots of a mailbox and their quotas
+    GetQuotaRoot { mailbox: String },
+
+    /// SETQUOTA root (resource limit ...) - Change the limits of a quota root
+    SetQuota {
+        root: String,
+        /// Resource name (upper case) and limit, e.g. `("STORAGE", 512)`
+        resources: Vec<(String, u64)>,
+    },
+
+    /// UID command - FETCH, SEARCH, STORE or COPY addressing messages by
+    /// UID instead of sequence number
+    Uid { command: Box<ImapCommand> },
+
     /// IDLE - Wait for server notifications
     Idle,
 
@@ -122,6 +269,8 @@ impl ImapCommand {
         let cmd = match command.as_str() {
             "CAPABILITY" => ImapCommand::Capability,
 
+            "STARTTLS" => ImapCommand::StartTls,
+
             "LOGIN" => {
                 if parts.len() < 4 {
                     return Err(MailError::ImapProtocol(
@@ -137,6 +286,19 @@ impl ImapCommand {
                 ImapCommand::Login { username, password }
             }
 
+            "AUTHENTICATE" => {
+                if parts.len() < 3 {
+                    return Err(MailError::ImapProtocol(
+                        "AUTHENTICATE requires a mechanism".to_string(),
+                    ));
+                }
+
+                ImapCommand::Authenticate {
+                    mechanism: parts[2].to_uppercase(),
+                    initial_response: parts.get(3).map(|s| s.to_string()),
+                }
+            }
+
             "SELECT" => {
                 if parts.len() < 3 {
                     return Err(MailError::ImapProtocol(
@@ -184,64 +346,95 @@ impl ImapCommand {
                     "*".to_string()
                 };
 
-                ImapCommand::List { reference, mailbox }
+                let upper = line.to_uppercase();
+                let status = match upper.find(" RETURN ") {
+                    Some(start) => Self::parse_return_status(&upper[start + 8..])?,
+                    None => Vec::new(),
+                };
+
+                ImapCommand::List {
+                    reference,
+                    mailbox,
+                    status,
+                }
             }
 
-            "SEARCH" => {
+            "STATUS" => {
+                let invalid = || {
+                    MailError::ImapProtocol("STATUS requires a mailbox and items".to_string())
+                };
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                let (mailbox, rest) = Self::parse_astring(arguments.trim()).ok_or_else(invalid)?;
+                let items = Self::parse_status_items(rest).ok_or_else(invalid)?;
+                if mailbox.is_empty() {
+                    return Err(invalid());
+                }
+                ImapCommand::Status { mailbox, items }
+            }
+
+            "LSUB" => {
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                let (reference, rest) = Self::parse_astring(arguments.trim()).unwrap_or_default();
+                let (mailbox, _) = Self::parse_astring(rest).unwrap_or_default();
+
+                ImapCommand::Lsub { reference, mailbox }
+            }
+
+            "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                let mailbox = Self::parse_astring(arguments.trim())
+                    .map(|(mailbox, _)| mailbox)
+                    .filter(|mailbox| !mailbox.is_empty())
+                    .ok_or_else(|| {
+                        MailError::ImapProtocol(format!("{} requires mailbox name", command))
+                    })?;
+
+                if command == "SUBSCRIBE" {
+                    ImapCommand::Subscribe { mailbox }
+                } else {
+                    ImapCommand::Unsubscribe { mailbox }
+                }
+            }
+
+            "NAMESPACE" => ImapCommand::Namespace,
+
+            "ID" => {
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                Self::parse_id(arguments)?
+            }
+
+            "COMPRESS" => {
                 if parts.len() < 3 {
                     return Err(MailError::ImapProtocol(
-                        "SEARCH requires criteria".to_string(),
+                        "COMPRESS requires a mechanism".to_string(),
                     ));
                 }
 
-                let criterion = parts[2].to_uppercase();
-                let criteria = match criterion.as_str() {
-                    "ALL" => SearchCriteria::All,
-                    "SUBJECT" => {
-                        if parts.len() < 4 {
-                            return Err(MailError::ImapProtocol(
-                                "SUBJECT requires a search string".to_string(),
-                            ));
-                        }
-                        let query = parts[3..].join(" ").trim_matches('"').to_string();
-                        SearchCriteria::Subject(query)
-                    }
-                    "FROM" => {
-                        if parts.len() < 4 {
-                            return Err(MailError::ImapProtocol(
-                                "FROM requires a search string".to_string(),
-                            ));
-                        }
-                        let query = parts[3..].join(" ").trim_matches('"').to_string();
-                        SearchCriteria::From(query)
-                    }
-                    "TO" => {
-                        if parts.len() < 4 {
-                            return Err(MailError::ImapProtocol(
-                                "TO requires a search string".to_string(),
-                            ));
-                        }
-                        let query = parts[3..].join(" ").trim_matches('"').to_string();
-                        SearchCriteria::To(query)
-                    }
-                    "TEXT" => {
-                        if parts.len() < 4 {
-                            return Err(MailError::ImapProtocol(
-                                "TEXT requires a search string".to_string(),
-                            ));
-                        }
-                        let query = parts[3..].join(" ").trim_matches('"').to_string();
-                        SearchCriteria::Text(query)
-                    }
-                    _ => {
-                        return Err(MailError::ImapProtocol(format!(
-                            "Unknown search criterion: {}",
-                            criterion
-                        )))
-                    }
-                };
+                ImapCommand::Compress {
+                    mechanism: parts[2].to_uppercase(),
+                }
+            }
+
+            "SEARCH" => {
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                let (charset, criteria) = search::parse(arguments)?;
 
-                ImapCommand::Search { criteria }
+                ImapCommand::Search { charset, criteria }
             }
 
             "STORE" => {
@@ -294,6 +487,48 @@ impl ImapCommand {
                 ImapCommand::Copy { sequence, mailbox }
             }
 
+            "APPEND" => {
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                Self::parse_append(arguments)?
+            }
+
+            "GETQUOTA" | "GETQUOTAROOT" | "SETQUOTA" => {
+                let arguments = line
+                    .splitn(3, char::is_whitespace)
+                    .nth(2)
+                    .unwrap_or_default();
+                Self::parse_quota(&command, arguments)?
+            }
+
+            "UID" => {
+                let subcommand = parts.get(2).map(|s| s.to_uppercase()).unwrap_or_default();
+                if subcommand == "EXPUNGE" {
+                    // Its own command, as plain EXPUNGE takes no arguments
+                    let sequence = parts.get(3).ok_or_else(|| {
+                        MailError::ImapProtocol("UID EXPUNGE requires a UID set".to_string())
+                    })?;
+                    ImapCommand::UidExpunge {
+                        sequence: sequence.to_string(),
+                    }
+                } else if !matches!(subcommand.as_str(), "FETCH" | "SEARCH" | "STORE" | "COPY") {
+                    return Err(MailError::ImapProtocol(
+                        "UID requires FETCH, SEARCH, STORE, COPY or EXPUNGE".to_string(),
+                    ));
+                } else {
+                    let arguments = line
+                        .splitn(3, char::is_whitespace)
+                        .nth(2)
+                        .unwrap_or_default();
+                    let (_, command) = Self::parse(&format!("{} {}", tag, arguments))?;
+                    ImapCommand::Uid {
+                        command: Box::new(command),
+                    }
+                }
+            }
+
             "IDLE" => ImapCommand::Idle,
 
             "LOGOUT" => ImapCommand::Logout,
@@ -311,6 +546,22 @@ impl ImapCommand {
         Ok((tag, cmd))
     }
 
+    /// Whether the command changes mailboxes, flags, subscriptions or quotas
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            ImapCommand::Store { .. }
+            | ImapCommand::Expunge
+            | ImapCommand::UidExpunge { .. }
+            | ImapCommand::Copy { .. }
+            | ImapCommand::Append { .. }
+            | ImapCommand::SetQuota { .. }
+            | ImapCommand::Subscribe { .. }
+            | ImapCommand::Unsubscribe { .. } => true,
+            ImapCommand::Uid { command } => command.is_mutation(),
+            _ => false,
+        }
+    }
+
     /// Parse LOGIN credentials handling quoted strings
     fn parse_login_credentials(input: &str) -> Result<(String, String), MailError> {
         let input = input.trim();
@@ -343,6 +594,172 @@ impl ImapCommand {
         }
     }
 
+    /// Parse APPEND arguments: `mailbox [(flags)] ["date-time"] {size}`
+    ///
+    /// Only synchronizing literals are accepted, as LITERAL+ is not
+    /// advertised.
+    fn parse_append(input: &str) -> Result<ImapCommand, MailError> {
+        let input = input.trim();
+        let invalid = || MailError::ImapProtocol("Invalid APPEND arguments".to_string());
+
+        // Literal size at the end of the line: {310}
+        let open = input.rfind('{').filter(|_| input.ends_with('}')).ok_or_else(|| {
+            MailError::ImapProtocol("APPEND requires a message literal".to_string())
+        })?;
+        let size = input[open + 1..input.len() - 1]
+            .parse::<usize>()
+            .map_err(|_| invalid())?;
+        let (mailbox, mut rest) = Self::parse_astring(input[..open].trim()).ok_or_else(invalid)?;
+        if mailbox.is_empty() {
+            return Err(MailError::ImapProtocol(
+                "APPEND requires mailbox name".to_string(),
+            ));
+        }
+
+        let mut flags = Vec::new();
+        if rest.starts_with('(') {
+            let end = rest.find(')').ok_or_else(invalid)?;
+            flags = rest[1..end].split_whitespace().map(|s| s.to_string()).collect();
+            rest = rest[end + 1..].trim_start();
+        }
+
+        let mut internal_date = None;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or_else(invalid)?;
+            internal_date = Some(Self::parse_date_time(&quoted[..end])?);
+            rest = quoted[end + 1..].trim_start();
+        }
+
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(ImapCommand::Append {
+            mailbox,
+            flags,
+            internal_date,
+            size,
+            message: Vec::new(),
+        })
+    }
+
+    /// Parse the arguments of GETQUOTA, GETQUOTAROOT and SETQUOTA
+    ///
+    /// Quota roots may be empty (`""`), mailbox names may not.
+    fn parse_quota(command: &str, input: &str) -> Result<ImapCommand, MailError> {
+        let invalid = || MailError::ImapProtocol(format!("Invalid {} arguments", command));
+        if input.trim().is_empty() {
+            return Err(invalid());
+        }
+        let (name, rest) = Self::parse_astring(input.trim()).ok_or_else(invalid)?;
+
+        match command {
+            "GETQUOTA" if rest.is_empty() => Ok(ImapCommand::GetQuota { root: name }),
+            "GETQUOTAROOT" if rest.is_empty() && !name.is_empty() => {
+                Ok(ImapCommand::GetQuotaRoot { mailbox: name })
+            }
+            "SETQUOTA" => {
+                let list = rest
+                    .strip_prefix('(')
+                    .and_then(|list| list.strip_suffix(')'))
+                    .ok_or_else(invalid)?;
+                let words: Vec<&str> = list.split_whitespace().collect();
+                if !words.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let resources = words
+                    .chunks(2)
+                    .map(|pair| Ok((pair[0].to_uppercase(), pair[1].parse().map_err(|_| invalid())?)))
+                    .collect::<Result<_, MailError>>()?;
+                Ok(ImapCommand::SetQuota {
+                    root: name,
+                    resources,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Parse the arguments of ID: `NIL` or `("name" "value" ...)`
+    fn parse_id(input: &str) -> Result<ImapCommand, MailError> {
+        let input = input.trim();
+        if input.eq_ignore_ascii_case("NIL") {
+            return Ok(ImapCommand::Id { fields: Vec::new() });
+        }
+        let mut rest = input
+            .strip_prefix('(')
+            .and_then(|list| list.strip_suffix(')'))
+            .ok_or_else(|| {
+                MailError::ImapProtocol("ID requires NIL or a parameter list".to_string())
+            })?
+            .trim();
+
+        let mut fields = Vec::new();
+        while !rest.is_empty() {
+            let invalid = || MailError::ImapProtocol("Invalid ID parameter list".to_string());
+            let (field, after_field) = Self::parse_astring(rest).ok_or_else(invalid)?;
+            if after_field.is_empty() {
+                return Err(invalid());
+            }
+            let (value, after_value) = Self::parse_astring(after_field).ok_or_else(invalid)?;
+            let nil = !after_field.starts_with('"') && value.eq_ignore_ascii_case("NIL");
+            if !nil {
+                fields.push((field.to_lowercase(), value));
+            }
+            rest = after_value;
+        }
+        Ok(ImapCommand::Id { fields })
+    }
+
+    /// Parse a parenthesized list of STATUS items, e.g. `(MESSAGES UNSEEN)`
+    fn parse_status_items(input: &str) -> Option<Vec<String>> {
+        let items: Vec<String> = input
+            .trim()
+            .strip_prefix('(')?
+            .strip_suffix(')')?
+            .split_whitespace()
+            .map(str::to_uppercase)
+            .collect();
+        let known = ["MESSAGES", "RECENT", "UIDNEXT", "UIDVALIDITY", "UNSEEN", "SIZE"];
+        if items.is_empty() || !items.iter().all(|item| known.contains(&item.as_str())) {
+            return None;
+        }
+        Some(items)
+    }
+
+    /// Parse the return options of LIST, `(STATUS (items))`; other options
+    /// are ignored
+    fn parse_return_status(input: &str) -> Result<Vec<String>, MailError> {
+        let Some(start) = input.find("STATUS") else {
+            return Ok(Vec::new());
+        };
+        let list = &input[start + 6..];
+        let end = list
+            .find(')')
+            .ok_or_else(|| MailError::ImapProtocol("Invalid LIST return options".to_string()))?;
+        Self::parse_status_items(&list[..=end])
+            .ok_or_else(|| MailError::ImapProtocol("Invalid STATUS return option".to_string()))
+    }
+
+    /// Split a quoted string or atom off the start of `input`; returns it
+    /// and the rest of the input with leading whitespace removed
+    fn parse_astring(input: &str) -> Option<(String, &str)> {
+        if let Some(quoted) = input.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            Some((quoted[..end].to_string(), quoted[end + 1..].trim_start()))
+        } else {
+            let end = input.find(char::is_whitespace).unwrap_or(input.len());
+            Some((input[..end].to_string(), input[end..].trim_start()))
+        }
+    }
+
+    /// Parse an IMAP date-time: `17-Jul-1996 02:44:25 -0700`
+    fn parse_date_time(input: &str) -> Result<DateTime<FixedOffset>, MailError> {
+        DateTime::parse_from_str(input.trim(), "%d-%b-%Y %H:%M:%S %z").map_err(|_| {
+            MailError::ImapProtocol(format!("Invalid date-time: {}", input))
+        })
+    }
+
     /// Parse flags from string, handling both "(flag1 flag2)" and "flag1" formats
     fn parse_flags(input: &str) -> Result<Vec<String>, MailError> {
         let input = input.trim();
@@ -381,6 +798,76 @@ mod tests {
         assert_eq!(cmd, ImapCommand::Capability);
     }
 
+    #[test]
+    fn test_parse_subscriptions() {
+        let (_, cmd) = ImapCommand::parse("A1 SUBSCRIBE \"Sent Items\"").unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Subscribe {
+                mailbox: "Sent Items".to_string()
+            }
+        );
+        let (_, cmd) = ImapCommand::parse("A2 unsubscribe Lists/rust").unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Unsubscribe {
+                mailbox: "Lists/rust".to_string()
+            }
+        );
+        assert!(ImapCommand::parse("A3 SUBSCRIBE").is_err());
+
+        let (_, cmd) = ImapCommand::parse("A4 LSUB \"\" \"Lists/%\"").unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Lsub {
+                reference: String::new(),
+                mailbox: "Lists/%".to_string()
+            }
+        );
+        let (_, cmd) = ImapCommand::parse("A5 NAMESPACE").unwrap();
+        assert_eq!(cmd, ImapCommand::Namespace);
+    }
+
+    #[test]
+    fn test_parse_compress() {
+        let (_, cmd) = ImapCommand::parse("a1 compress deflate").unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Compress {
+                mechanism: "DEFLATE".to_string()
+            }
+        );
+        assert!(ImapCommand::parse("a2 COMPRESS").is_err());
+    }
+
+    #[test]
+    fn test_parse_id() {
+        let (_, cmd) = ImapCommand::parse(
+            "a1 ID (\"name\" \"Thunderbird\" \"Version\" \"128.0\" \"os\" NIL)",
+        )
+        .unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Id {
+                fields: vec![
+                    ("name".to_string(), "Thunderbird".to_string()),
+                    ("version".to_string(), "128.0".to_string()),
+                ]
+            }
+        );
+        let (_, cmd) = ImapCommand::parse("a2 id nil").unwrap();
+        assert_eq!(cmd, ImapCommand::Id { fields: Vec::new() });
+        assert!(ImapCommand::parse("a3 ID").is_err());
+        assert!(ImapCommand::parse("a4 ID (\"name\")").is_err());
+    }
+
+    #[test]
+    fn test_parse_starttls() {
+        let (tag, cmd) = ImapCommand::parse("a1 starttls").unwrap();
+        assert_eq!(tag, "a1");
+        assert_eq!(cmd, ImapCommand::StartTls);
+    }
+
     #[test]
     fn test_parse_login() {
         let (tag, cmd) = ImapCommand::parse("A001 LOGIN john secret").unwrap();
@@ -407,6 +894,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_authenticate() {
+        let (tag, cmd) = ImapCommand::parse("A001 AUTHENTICATE xoauth2").unwrap();
+        assert_eq!(tag, "A001");
+        assert_eq!(
+            cmd,
+            ImapCommand::Authenticate {
+                mechanism: "XOAUTH2".to_string(),
+                initial_response: None
+            }
+        );
+
+        let (_, cmd) = ImapCommand::parse("A002 AUTHENTICATE OAUTHBEARER bixhPWE=").unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Authenticate {
+                mechanism: "OAUTHBEARER".to_string(),
+                initial_response: Some("bixhPWE=".to_string())
+            }
+        );
+
+        assert!(ImapCommand::parse("A003 AUTHENTICATE").is_err());
+    }
+
     #[test]
     fn test_parse_select() {
         let (tag, cmd) = ImapCommand::parse("A002 SELECT INBOX").unwrap();
@@ -426,6 +937,34 @@ mod tests {
         assert!(matches!(cmd, ImapCommand::Fetch { .. }));
     }
 
+    #[test]
+    fn test_parse_uid() {
+        let (tag, cmd) = ImapCommand::parse("A001 UID FETCH 4:* (FLAGS)").unwrap();
+        assert_eq!(tag, "A001");
+        assert_eq!(
+            cmd,
+            ImapCommand::Uid {
+                command: Box::new(ImapCommand::Fetch {
+                    sequence: "4:*".to_string(),
+                    items: vec!["(FLAGS)".to_string()],
+                }),
+            }
+        );
+
+        let (_, cmd) = ImapCommand::parse("A002 uid store 7 +FLAGS (\\Seen)").unwrap();
+        assert!(matches!(cmd, ImapCommand::Uid { command } if matches!(*command, ImapCommand::Store { .. })));
+
+        let (_, cmd) = ImapCommand::parse("A003 UID EXPUNGE 3:5,9").unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::UidExpunge {
+                sequence: "3:5,9".to_string()
+            }
+        );
+        assert!(ImapCommand::parse("A003 UID EXPUNGE").is_err());
+        assert!(ImapCommand::parse("A004 UID").is_err());
+    }
+
     #[test]
     fn test_parse_logout() {
         let (tag, cmd) = ImapCommand::parse("A004 LOGOUT").unwrap();
@@ -440,6 +979,7 @@ mod tests {
         assert_eq!(
             cmd,
             ImapCommand::Search {
+                charset: None,
                 criteria: SearchCriteria::All
             }
         );
@@ -452,6 +992,7 @@ mod tests {
         assert_eq!(
             cmd,
             ImapCommand::Search {
+                charset: None,
                 criteria: SearchCriteria::Subject("hello".to_string())
             }
         );
@@ -464,6 +1005,7 @@ mod tests {
         assert_eq!(
             cmd,
             ImapCommand::Search {
+                charset: None,
                 criteria: SearchCriteria::Subject("test email".to_string())
             }
         );
@@ -476,11 +1018,129 @@ mod tests {
         assert_eq!(
             cmd,
             ImapCommand::Search {
+                charset: None,
                 criteria: SearchCriteria::From("alice@example.com".to_string())
             }
         );
     }
 
+    #[test]
+    fn test_parse_append() {
+        let (tag, cmd) = ImapCommand::parse(
+            r#"A003 APPEND "Sent Items" (\Seen \Draft) "17-Jul-1996 02:44:25 -0700" {310}"#,
+        )
+        .unwrap();
+        assert_eq!(tag, "A003");
+        assert_eq!(
+            cmd,
+            ImapCommand::Append {
+                mailbox: "Sent Items".to_string(),
+                flags: vec!["\\Seen".to_string(), "\\Draft".to_string()],
+                internal_date: Some(
+                    DateTime::parse_from_rfc3339("1996-07-17T02:44:25-07:00").unwrap()
+                ),
+                size: 310,
+                message: Vec::new(),
+            }
+        );
+
+        let (_, cmd) = ImapCommand::parse("A004 APPEND Drafts {12}").unwrap();
+        assert!(matches!(
+            cmd,
+            ImapCommand::Append { ref mailbox, ref flags, internal_date: None, size: 12, .. }
+                if mailbox == "Drafts" && flags.is_empty()
+        ));
+
+        let (_, cmd) = ImapCommand::parse(r#"A005 APPEND INBOX () " 7-Jul-1996 02:44:25 +0000" {1}"#).unwrap();
+        assert!(matches!(cmd, ImapCommand::Append { internal_date: Some(_), .. }));
+
+        assert!(ImapCommand::parse("A006 APPEND INBOX").is_err());
+        assert!(ImapCommand::parse("A007 APPEND INBOX {12+}").is_err());
+        assert!(ImapCommand::parse(r#"A008 APPEND INBOX "yesterday" {12}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        let (_, cmd) = ImapCommand::parse(r#"A001 GETQUOTA """#).unwrap();
+        assert_eq!(cmd, ImapCommand::GetQuota { root: String::new() });
+
+        let (_, cmd) = ImapCommand::parse(r#"A002 GETQUOTAROOT "Sent Items""#).unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::GetQuotaRoot {
+                mailbox: "Sent Items".to_string()
+            }
+        );
+
+        let (_, cmd) = ImapCommand::parse(r#"A003 SETQUOTA "" (storage 512)"#).unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::SetQuota {
+                root: String::new(),
+                resources: vec![("STORAGE".to_string(), 512)],
+            }
+        );
+
+        assert!(ImapCommand::parse(r#"A004 GETQUOTAROOT """#).is_err());
+        assert!(ImapCommand::parse(r#"A005 SETQUOTA "" (STORAGE)"#).is_err());
+        assert!(ImapCommand::parse(r#"A006 SETQUOTA "" (STORAGE -1)"#).is_err());
+        assert!(ImapCommand::parse("A007 GETQUOTA a b").is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let (_, cmd) = ImapCommand::parse(r#"A001 STATUS "Sent Items" (messages UIDNEXT)"#).unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Status {
+                mailbox: "Sent Items".to_string(),
+                items: vec!["MESSAGES".to_string(), "UIDNEXT".to_string()],
+            }
+        );
+        assert!(ImapCommand::parse("A002 STATUS INBOX").is_err());
+        assert!(ImapCommand::parse("A003 STATUS INBOX (FOO)").is_err());
+
+        let (_, cmd) =
+            ImapCommand::parse(r#"A004 LIST "" "*" RETURN (STATUS (MESSAGES UNSEEN))"#).unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::List {
+                reference: String::new(),
+                mailbox: "*".to_string(),
+                status: vec!["MESSAGES".to_string(), "UNSEEN".to_string()],
+            }
+        );
+        let (_, cmd) = ImapCommand::parse(r#"A005 LIST "" *"#).unwrap();
+        assert!(matches!(cmd, ImapCommand::List { status, .. } if status.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_search_charset() {
+        let (_, cmd) =
+            ImapCommand::parse(r#"A010 SEARCH CHARSET UTF-8 NOT SEEN SUBJECT "café au lait""#)
+                .unwrap();
+        assert_eq!(
+            cmd,
+            ImapCommand::Search {
+                charset: Some("UTF-8".to_string()),
+                criteria: SearchCriteria::And(vec![
+                    SearchCriteria::Not(Box::new(SearchCriteria::Flag("\\Seen".to_string()))),
+                    SearchCriteria::Subject("café au lait".to_string()),
+                ]),
+            }
+        );
+
+        let (_, cmd) = ImapCommand::parse("A011 UID SEARCH UID 4:* SINCE 1-Feb-1994").unwrap();
+        assert!(matches!(
+            cmd,
+            ImapCommand::Uid { ref command }
+                if matches!(**command, ImapCommand::Search { charset: None, .. })
+        ));
+
+        assert!(ImapCommand::parse("A012 SEARCH").is_err());
+        assert!(ImapCommand::parse("A013 SEARCH CHARSET").is_err());
+    }
+
     #[test]
     fn test_parse_search_text() {
         let (tag, cmd) = ImapCommand::parse("A009 SEARCH TEXT meeting").unwrap();
@@ -488,6 +1148,7 @@ mod tests {
         assert_eq!(
             cmd,
             ImapCommand::Search {
+                charset: None,
                 criteria: SearchCriteria::Text("meeting".to_string())
             }
         );
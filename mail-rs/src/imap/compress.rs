@@ -0,0 +1,232 @@
+//! COMPRESS=DEFLATE (RFC 4978)
+//!
+//! After a successful `COMPRESS DEFLATE` both directions of the connection
+//! carry a raw deflate stream (RFC 1951, no zlib header). [`DeflateStream`]
+//! wraps the connection: reads are inflated, and every write is compressed
+//! and sync-flushed so each response reaches the client right away.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Compression mechanism announced as `COMPRESS=DEFLATE`
+pub const MECHANISM: &str = "DEFLATE";
+
+/// Compressed bytes read from the connection at a time
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Deflate-compressed view of a connection
+pub struct DeflateStream<S> {
+    inner: S,
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed bytes received but not yet inflated
+    input: Vec<u8>,
+    /// Compressed bytes not yet written to the connection
+    output: Vec<u8>,
+    /// Length of the caller's buffer that `output` holds
+    output_for: usize,
+    /// The connection reported end of stream
+    eof: bool,
+}
+
+impl<S> DeflateStream<S> {
+    /// Wrap `inner`; `received` are bytes that already arrived after the
+    /// COMPRESS command and are therefore compressed
+    pub fn new(inner: S, received: &[u8]) -> Self {
+        Self {
+            inner,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            input: received.to_vec(),
+            output: Vec::new(),
+            output_for: 0,
+            eof: false,
+        }
+    }
+
+    /// Bytes written so far, before and after compression
+    pub fn written_totals(&self) -> (u64, u64) {
+        (self.compress.total_in(), self.compress.total_out())
+    }
+
+    /// Compress all of `data` into `output`, ending with a sync flush
+    fn deflate(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut consumed = 0;
+        loop {
+            self.output.reserve(data.len() / 2 + 64);
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(&data[consumed..], &mut self.output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            consumed += (self.compress.total_in() - before) as usize;
+
+            // Done once the input is used up and the flush fit in the buffer
+            if consumed == data.len() && self.output.len() < self.output.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Write out compressed bytes left over from earlier writes
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.output.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            let (before_in, before_out) = (this.decompress.total_in(), this.decompress.total_out());
+            let status = this
+                .decompress
+                .decompress(
+                    &this.input,
+                    buf.initialize_unfilled(),
+                    FlushDecompress::Sync,
+                )
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let consumed = (this.decompress.total_in() - before_in) as usize;
+            let produced = (this.decompress.total_out() - before_out) as usize;
+            this.input.drain(..consumed);
+            buf.advance(produced);
+
+            if produced > 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if status == Status::StreamEnd || this.eof {
+                // Nothing more will be inflated
+                return Poll::Ready(Ok(()));
+            }
+            if consumed > 0 && !this.input.is_empty() {
+                continue;
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                this.eof = true;
+            }
+            this.input.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    /// Compresses the whole buffer, but only reports it written once the
+    /// compressed bytes went out; callers retry with the same buffer as
+    /// `write_all` does
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.output.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            this.deflate(buf)?;
+            this.output_for = buf.len();
+        }
+
+        ready!(this.poll_drain(cx))?;
+        Poll::Ready(Ok(std::mem::take(&mut this.output_for)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = DeflateStream::new(client, &[]);
+        let mut server = BufReader::new(DeflateStream::new(server, &[]));
+
+        let body = "Subject: hello\r\n".repeat(500);
+        let writer = tokio::spawn(async move {
+            client.write_all(b"a1 NOOP\r\n").await.unwrap();
+            client.write_all(body.as_bytes()).await.unwrap();
+            client.shutdown().await.unwrap();
+            client.written_totals()
+        });
+
+        // Each write is flushed, so the first line arrives on its own
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "a1 NOOP\r\n");
+
+        let mut rest = String::new();
+        server.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "Subject: hello\r\n".repeat(500));
+
+        let (plain, compressed) = writer.await.unwrap();
+        assert_eq!(plain, 9 + 16 * 500);
+        assert!(compressed < plain / 10);
+    }
+
+    #[tokio::test]
+    async fn test_bytes_received_with_the_command() {
+        // A client may send compressed data right behind COMPRESS; the
+        // server's line reader then already holds some of it
+        let mut compressor = DeflateStream::new(Vec::new(), &[]);
+        compressor.write_all(b"a2 NOOP\r\n").await.unwrap();
+        let compressed = compressor.inner;
+        let (head, tail) = compressed.split_at(3);
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = BufReader::new(DeflateStream::new(server, head));
+        client.write_all(tail).await.unwrap();
+
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "a2 NOOP\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_input_is_an_error() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = DeflateStream::new(server, &[]);
+        client.write_all(&[0xff; 16]).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,470 @@
+//! FETCH data items
+//!
+//! Parses the item list of a FETCH command, including `BODY[section]<partial>`
+//! and `BODY.PEEK`, and extracts the requested bytes from a raw message.
+//! Sections are addressed through the MIME entity tree (see
+//! [`MimeEntity::part`]), so a client can fetch the headers or a single text
+//! part of a large message without downloading its attachments.
+
+use crate::mime::entity::header_fields;
+use crate::mime::MimeEntity;
+
+/// One FETCH data item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchAttribute {
+    Uid,
+    Flags,
+    Rfc822Size,
+    Envelope,
+    /// `BODYSTRUCTURE`, or `BODY` without a section when not extensible
+    Structure {
+        extensible: bool,
+    },
+    /// Whole message, same as `BODY[]`
+    Rfc822,
+    /// Message header, same as `BODY.PEEK[HEADER]`
+    Rfc822Header,
+    /// Message body, same as `BODY[TEXT]`
+    Rfc822Text,
+    /// `BODY[section]<origin.count>` or `BODY.PEEK[...]`
+    Body(BodySection),
+}
+
+/// Section and byte range of a `BODY[...]` item
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BodySection {
+    /// `BODY.PEEK` instead of `BODY`
+    pub peek: bool,
+    /// Part number, empty for the whole message
+    pub part: Vec<u32>,
+    pub text: Option<SectionText>,
+    /// Origin and maximum length in octets
+    pub partial: Option<(usize, usize)>,
+}
+
+/// Section text following the part number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionText {
+    Header,
+    /// Only the listed fields, names as sent by the client
+    HeaderFields(Vec<String>),
+    /// Every field except the listed ones
+    HeaderFieldsNot(Vec<String>),
+    Text,
+    /// MIME header of a body part
+    Mime,
+}
+
+/// Parse the item list of a FETCH command
+///
+/// `items` are the whitespace-separated words of the command, so a section
+/// such as `BODY[HEADER.FIELDS (FROM TO)]` may span several of them. The
+/// ALL, FAST and FULL macros expand to the items this server supports
+/// (everything but INTERNALDATE).
+/// Unknown items are skipped.
+pub fn parse_items(items: &[String]) -> Vec<FetchAttribute> {
+    let joined = items.join(" ");
+    let mut list = joined.trim();
+    if list.starts_with('(') && list.ends_with(')') {
+        list = &list[1..list.len() - 1];
+    }
+
+    let mut attributes = Vec::new();
+    for token in tokenize(list) {
+        match token.to_uppercase().as_str() {
+            "FAST" => {
+                attributes.push(FetchAttribute::Flags);
+                attributes.push(FetchAttribute::Rfc822Size);
+            }
+            "ALL" => {
+                attributes.push(FetchAttribute::Flags);
+                attributes.push(FetchAttribute::Rfc822Size);
+                attributes.push(FetchAttribute::Envelope);
+            }
+            "FULL" => {
+                attributes.push(FetchAttribute::Flags);
+                attributes.push(FetchAttribute::Rfc822Size);
+                attributes.push(FetchAttribute::Envelope);
+                attributes.push(FetchAttribute::Structure { extensible: false });
+            }
+            _ => attributes.extend(parse_attribute(token)),
+        }
+    }
+    attributes
+}
+
+/// Split at spaces outside brackets and parentheses
+fn tokenize(list: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    for (i, c) in list.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = depth.saturating_sub(1),
+            ' ' if depth == 0 => {
+                if i > start {
+                    tokens.push(&list[start..i]);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < list.len() {
+        tokens.push(&list[start..]);
+    }
+    tokens
+}
+
+fn parse_attribute(token: &str) -> Option<FetchAttribute> {
+    let upper = token.to_uppercase();
+    match upper.as_str() {
+        "UID" => return Some(FetchAttribute::Uid),
+        "FLAGS" => return Some(FetchAttribute::Flags),
+        "RFC822.SIZE" => return Some(FetchAttribute::Rfc822Size),
+        "RFC822" => return Some(FetchAttribute::Rfc822),
+        "RFC822.HEADER" => return Some(FetchAttribute::Rfc822Header),
+        "RFC822.TEXT" => return Some(FetchAttribute::Rfc822Text),
+        "ENVELOPE" => return Some(FetchAttribute::Envelope),
+        "BODYSTRUCTURE" => return Some(FetchAttribute::Structure { extensible: true }),
+        "BODY" => return Some(FetchAttribute::Structure { extensible: false }),
+        _ => {}
+    }
+
+    let peek = upper.starts_with("BODY.PEEK[");
+    if !peek && !upper.starts_with("BODY[") {
+        return None;
+    }
+    let open = token.find('[')?;
+    let close = open + token[open..].find(']')?;
+
+    let mut section = parse_section(&token[open + 1..close])?;
+    section.peek = peek;
+
+    let partial = &token[close + 1..];
+    if !partial.is_empty() {
+        let range = partial.strip_prefix('<')?.strip_suffix('>')?;
+        let (origin, count) = range.split_once('.')?;
+        section.partial = Some((origin.parse().ok()?, count.parse().ok()?));
+    }
+
+    Some(FetchAttribute::Body(section))
+}
+
+fn parse_section(spec: &str) -> Option<BodySection> {
+    let mut part = Vec::new();
+    let mut rest = spec;
+    loop {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            break;
+        }
+        part.push(rest[..digits].parse().ok()?);
+        rest = &rest[digits..];
+        if rest.is_empty() {
+            break;
+        }
+        rest = rest.strip_prefix('.')?;
+    }
+
+    let upper = rest.to_uppercase();
+    let text = if upper.is_empty() {
+        None
+    } else if upper == "HEADER" {
+        Some(SectionText::Header)
+    } else if upper == "TEXT" {
+        Some(SectionText::Text)
+    } else if upper == "MIME" && !part.is_empty() {
+        Some(SectionText::Mime)
+    } else if let Some(fields) = strip_keyword(rest, "HEADER.FIELDS.NOT") {
+        Some(SectionText::HeaderFieldsNot(field_names(fields)?))
+    } else if let Some(fields) = strip_keyword(rest, "HEADER.FIELDS") {
+        Some(SectionText::HeaderFields(field_names(fields)?))
+    } else {
+        return None;
+    };
+
+    Some(BodySection {
+        peek: false,
+        part,
+        text,
+        partial: None,
+    })
+}
+
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = s.get(..keyword.len())?;
+    head.eq_ignore_ascii_case(keyword)
+        .then(|| &s[keyword.len()..])
+}
+
+fn field_names(list: &str) -> Option<Vec<String>> {
+    let names = list.trim().strip_prefix('(')?.strip_suffix(')')?;
+    Some(names.split_whitespace().map(str::to_string).collect())
+}
+
+impl FetchAttribute {
+    /// Item name used in the FETCH response
+    pub fn name(&self) -> String {
+        match self {
+            Self::Uid => "UID".to_string(),
+            Self::Flags => "FLAGS".to_string(),
+            Self::Rfc822Size => "RFC822.SIZE".to_string(),
+            Self::Rfc822 => "RFC822".to_string(),
+            Self::Rfc822Header => "RFC822.HEADER".to_string(),
+            Self::Rfc822Text => "RFC822.TEXT".to_string(),
+            Self::Envelope => "ENVELOPE".to_string(),
+            Self::Structure { extensible: true } => "BODYSTRUCTURE".to_string(),
+            Self::Structure { extensible: false } => "BODY".to_string(),
+            Self::Body(section) => section.name(),
+        }
+    }
+
+    /// Section returned by a message content item
+    pub fn section(&self) -> Option<BodySection> {
+        let text = match self {
+            Self::Rfc822 => None,
+            Self::Rfc822Header => Some(SectionText::Header),
+            Self::Rfc822Text => Some(SectionText::Text),
+            Self::Body(section) => return Some(section.clone()),
+            _ => return None,
+        };
+        Some(BodySection {
+            peek: matches!(self, Self::Rfc822Header),
+            text,
+            ..Default::default()
+        })
+    }
+}
+
+impl BodySection {
+    /// Item name used in the FETCH response
+    ///
+    /// `BODY.PEEK` is answered as `BODY`, and a partial fetch reports only
+    /// its origin.
+    pub fn name(&self) -> String {
+        let mut spec = self
+            .part
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+        if let Some(text) = &self.text {
+            if !spec.is_empty() {
+                spec.push('.');
+            }
+            spec.push_str(&match text {
+                SectionText::Header => "HEADER".to_string(),
+                SectionText::HeaderFields(fields) => {
+                    format!("HEADER.FIELDS ({})", fields.join(" "))
+                }
+                SectionText::HeaderFieldsNot(fields) => {
+                    format!("HEADER.FIELDS.NOT ({})", fields.join(" "))
+                }
+                SectionText::Text => "TEXT".to_string(),
+                SectionText::Mime => "MIME".to_string(),
+            });
+        }
+
+        match self.partial {
+            Some((origin, _)) => format!("BODY[{}]<{}>", spec, origin),
+            None => format!("BODY[{}]", spec),
+        }
+    }
+
+    /// Bytes of this section in the raw message `raw`
+    ///
+    /// A part that does not exist yields an empty section, as does a
+    /// partial range starting past the end.
+    pub fn extract(&self, raw: &[u8]) -> Vec<u8> {
+        let root = MimeEntity::parse(raw);
+        let Some(entity) = root.part(&self.part) else {
+            return Vec::new();
+        };
+
+        // HEADER, TEXT and HEADER.FIELDS apply to the message itself or to
+        // a part that encapsulates one
+        let message = if self.part.is_empty() {
+            Some(entity)
+        } else {
+            entity.message.as_deref()
+        };
+
+        let content = match &self.text {
+            None if self.part.is_empty() => raw.to_vec(),
+            None => entity.body.to_vec(),
+            Some(SectionText::Mime) => entity.header.to_vec(),
+            Some(SectionText::Header) => message.map(|m| m.header.to_vec()).unwrap_or_default(),
+            Some(SectionText::Text) => message.map(|m| m.body.to_vec()).unwrap_or_default(),
+            Some(SectionText::HeaderFields(fields)) => message
+                .map(|m| filter_fields(m.header, fields, true))
+                .unwrap_or_default(),
+            Some(SectionText::HeaderFieldsNot(fields)) => message
+                .map(|m| filter_fields(m.header, fields, false))
+                .unwrap_or_default(),
+        };
+
+        match self.partial {
+            Some((origin, count)) => {
+                let start = origin.min(content.len());
+                let end = origin.saturating_add(count).min(content.len());
+                content[start..end].to_vec()
+            }
+            None => content,
+        }
+    }
+}
+
+/// Header fields whose name is (or with `keep` false, is not) listed,
+/// followed by the blank line ending the header
+fn filter_fields(header: &[u8], fields: &[String], keep: bool) -> Vec<u8> {
+    let mut filtered = Vec::new();
+    for (name, raw) in header_fields(header) {
+        let listed = fields.iter().any(|field| field.eq_ignore_ascii_case(&name));
+        if listed == keep {
+            filtered.extend_from_slice(raw);
+            if !raw.ends_with(b"\n") {
+                filtered.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+    filtered.extend_from_slice(b"\r\n");
+    filtered
+}
+
+/// `bytes` as an IMAP literal
+pub fn literal(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    format!("{{{}}}\r\n{}", text.len(), text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: alice@example.com\r\n\
+To: bob@example.com\r\n\
+Subject: Photos\r\n\
+\x20from the trip\r\n\
+Content-Type: multipart/mixed; boundary=b1\r\n\
+\r\n\
+--b1\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+See attached\r\n\
+--b1\r\n\
+Content-Type: image/jpeg\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+/9j/4AAQSkZJRg==\r\n\
+--b1--\r\n";
+
+    fn items(list: &str) -> Vec<String> {
+        list.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn section(list: &str) -> BodySection {
+        match parse_items(&items(list)).remove(0) {
+            FetchAttribute::Body(section) => section,
+            other => panic!("not a body section: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_items() {
+        assert_eq!(
+            parse_items(&items("(UID RFC822.SIZE FLAGS BODY.PEEK[HEADER.FIELDS (From Subject)] BODY[1.2.MIME]<0.100>)")),
+            vec![
+                FetchAttribute::Uid,
+                FetchAttribute::Rfc822Size,
+                FetchAttribute::Flags,
+                FetchAttribute::Body(BodySection {
+                    peek: true,
+                    part: vec![],
+                    text: Some(SectionText::HeaderFields(vec![
+                        "From".to_string(),
+                        "Subject".to_string()
+                    ])),
+                    partial: None,
+                }),
+                FetchAttribute::Body(BodySection {
+                    peek: false,
+                    part: vec![1, 2],
+                    text: Some(SectionText::Mime),
+                    partial: Some((0, 100)),
+                }),
+            ]
+        );
+        assert_eq!(
+            parse_items(&items("FAST")),
+            vec![FetchAttribute::Flags, FetchAttribute::Rfc822Size]
+        );
+        assert_eq!(
+            parse_items(&items("(BODYSTRUCTURE body ENVELOPE)")),
+            vec![
+                FetchAttribute::Structure { extensible: true },
+                FetchAttribute::Structure { extensible: false },
+                FetchAttribute::Envelope,
+            ]
+        );
+        // Malformed sections are skipped
+        assert!(parse_items(&items("(BODY[MIME] BODY[1.BOGUS] BODY[]<5>)")).is_empty());
+    }
+
+    #[test]
+    fn test_section_names() {
+        assert_eq!(section("BODY.PEEK[]").name(), "BODY[]");
+        assert_eq!(section("body[2.text]<10.20>").name(), "BODY[2.TEXT]<10>");
+        assert_eq!(
+            section("BODY[HEADER.FIELDS.NOT (Received)]").name(),
+            "BODY[HEADER.FIELDS.NOT (Received)]"
+        );
+        assert_eq!(FetchAttribute::Rfc822Header.name(), "RFC822.HEADER");
+    }
+
+    #[test]
+    fn test_extract_sections() {
+        let header_end = MESSAGE.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+
+        assert_eq!(section("BODY[]").extract(MESSAGE), MESSAGE);
+        assert_eq!(
+            section("BODY[HEADER]").extract(MESSAGE),
+            &MESSAGE[..header_end]
+        );
+        assert_eq!(
+            section("BODY[TEXT]").extract(MESSAGE),
+            &MESSAGE[header_end..]
+        );
+        assert_eq!(section("BODY[1]").extract(MESSAGE), b"See attached");
+        assert_eq!(section("BODY[2]").extract(MESSAGE), b"/9j/4AAQSkZJRg==");
+        assert_eq!(
+            section("BODY[2.MIME]").extract(MESSAGE),
+            b"Content-Type: image/jpeg\r\nContent-Transfer-Encoding: base64\r\n\r\n"
+        );
+        assert!(section("BODY[3]").extract(MESSAGE).is_empty());
+        // TEXT of a part that is not a message
+        assert!(section("BODY[1.TEXT]").extract(MESSAGE).is_empty());
+    }
+
+    #[test]
+    fn test_extract_header_fields() {
+        assert_eq!(
+            section("BODY[HEADER.FIELDS (SUBJECT from)]").extract(MESSAGE),
+            b"From: alice@example.com\r\nSubject: Photos\r\n from the trip\r\n\r\n"
+        );
+        assert_eq!(
+            section("BODY[HEADER.FIELDS.NOT (From Subject Content-Type)]").extract(MESSAGE),
+            b"To: bob@example.com\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_extract_partial() {
+        assert_eq!(section("BODY[1]<4.4>").extract(MESSAGE), b"atta");
+        assert_eq!(section("BODY[1]<8.100>").extract(MESSAGE), b"ched");
+        assert!(section("BODY[1]<100.10>").extract(MESSAGE).is_empty());
+        assert_eq!(literal(b"abc"), "{3}\r\nabc");
+    }
+}
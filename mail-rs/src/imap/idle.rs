@@ -2,20 +2,37 @@
 //!
 //! RFC 2177 - IMAP4 IDLE command
 //! Allows clients to receive real-time notifications of mailbox changes
+//!
+//! The watcher uses the platform's notification API (inotify, kqueue,
+//! FSEvents) on the Maildir `new/` and `cur/` directories and wakes the
+//! idling connection as soon as a file appears, disappears or is renamed.
+//! Where no such API is available it polls the directories instead.
 
 use crate::error::MailError;
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tracing::debug;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+/// Interval of the polling fallback when none is configured
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Changes seen by the watcher and not yet consumed
+#[derive(Default)]
+struct Pending {
+    changed: AtomicBool,
+    notify: Notify,
+}
 
 /// Watches a maildir for changes and sends notifications
 pub struct IdleWatcher {
     /// Filesystem watcher
-    _watcher: RecommendedWatcher,
-    /// Channel to receive filesystem events
-    rx: Receiver<Result<Event, notify::Error>>,
+    _watcher: Box<dyn Watcher + Send + Sync>,
+    /// Set by the watcher callback, consumed by waiters
+    pending: Arc<Pending>,
     /// Path being watched
     watch_path: PathBuf,
 }
@@ -26,71 +43,89 @@ impl IdleWatcher {
     /// # Arguments
     /// * `maildir_path` - Path to the maildir to watch (e.g., /data/maildir/user@example.com)
     pub fn new(maildir_path: &Path) -> Result<Self, MailError> {
-        let (tx, rx): (Sender<Result<Event, notify::Error>>, Receiver<Result<Event, notify::Error>>) = channel();
-
-        // Create watcher
-        let mut watcher = RecommendedWatcher::new(
-            move |result| {
-                let _ = tx.send(result);
-            },
-            Config::default()
-                .with_poll_interval(Duration::from_secs(1))
-                .with_compare_contents(false),
-        )
-        .map_err(|e| MailError::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
-
-        // Watch both new/ and cur/ directories for changes
-        let new_path = maildir_path.join("new");
-        let cur_path = maildir_path.join("cur");
-
-        // Watch new/ directory (for incoming messages)
-        if new_path.exists() {
-            watcher
-                .watch(&new_path, RecursiveMode::NonRecursive)
-                .map_err(|e| MailError::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
-            debug!("Watching new/ directory: {:?}", new_path);
-        }
+        Self::with_poll_interval(maildir_path, DEFAULT_POLL_INTERVAL)
+    }
 
-        // Watch cur/ directory (for flag changes)
-        if cur_path.exists() {
-            watcher
-                .watch(&cur_path, RecursiveMode::NonRecursive)
-                .map_err(|e| MailError::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
-            debug!("Watching cur/ directory: {:?}", cur_path);
+    /// Create a watcher that falls back to polling every `poll_interval`
+    /// when filesystem notifications are unavailable
+    pub fn with_poll_interval(
+        maildir_path: &Path,
+        poll_interval: Duration,
+    ) -> Result<Self, MailError> {
+        let pending = Arc::new(Pending::default());
+
+        let handler = {
+            let pending = Arc::clone(&pending);
+            move |result: Result<Event, notify::Error>| match result {
+                // Reading messages must not wake the session that reads them
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                Ok(_) => {
+                    pending.changed.store(true, Ordering::Release);
+                    pending.notify.notify_one();
+                }
+                Err(e) => warn!("Maildir watch error: {}", e),
+            }
+        };
+        let config = Config::default()
+            .with_poll_interval(poll_interval)
+            .with_compare_contents(false);
+
+        let mut watcher: Box<dyn Watcher + Send + Sync> =
+            match RecommendedWatcher::new(handler.clone(), config) {
+                Ok(watcher) => Box::new(watcher),
+                Err(e) => {
+                    warn!(
+                        "Filesystem notifications unavailable ({}), polling every {:?}",
+                        e, poll_interval
+                    );
+                    Box::new(PollWatcher::new(handler, config).map_err(watch_error)?)
+                }
+            };
+
+        // Watch new/ (incoming messages) and cur/ (flag changes, expunges)
+        for dir in ["new", "cur"] {
+            let path = maildir_path.join(dir);
+            if path.exists() {
+                watcher
+                    .watch(&path, RecursiveMode::NonRecursive)
+                    .map_err(watch_error)?;
+                debug!("Watching {:?}", path);
+            }
         }
 
         Ok(Self {
             _watcher: watcher,
-            rx,
+            pending,
             watch_path: maildir_path.to_path_buf(),
         })
     }
 
-    /// Wait for filesystem changes with a timeout
+    /// Wait until the maildir changes
     ///
-    /// Returns true if changes were detected, false if timeout occurred
-    pub async fn wait_for_changes(&self, timeout_duration: Duration) -> Result<bool, MailError> {
-        let start = std::time::Instant::now();
-        let poll_interval = Duration::from_millis(100);
-
-        // Poll for changes until timeout
-        while start.elapsed() < timeout_duration {
-            // Check for any pending events (non-blocking)
+    /// Cancel safe: a change that arrives while nobody waits is kept for
+    /// the next call.
+    pub async fn changed(&self) {
+        loop {
+            let notified = self.pending.notify.notified();
             if self.check_changes_nonblocking() {
-                return Ok(true);
+                return;
             }
-
-            // Sleep briefly before next check
-            tokio::time::sleep(poll_interval).await;
+            notified.await;
         }
+    }
 
-        // Timeout - no changes detected
-        Ok(false)
+    /// Wait for filesystem changes with a timeout
+    ///
+    /// Returns true if changes were detected, false if timeout occurred
+    pub async fn wait_for_changes(&self, timeout_duration: Duration) -> Result<bool, MailError> {
+        Ok(tokio::time::timeout(timeout_duration, self.changed())
+            .await
+            .is_ok())
     }
 
     /// Check for changes without blocking (non-blocking check)
     pub fn check_changes_nonblocking(&self) -> bool {
-        self.rx.try_iter().next().is_some()
+        self.pending.changed.swap(false, Ordering::AcqRel)
     }
 
     /// Get the path being watched
@@ -99,6 +134,10 @@ impl IdleWatcher {
     }
 }
 
+fn watch_error(e: notify::Error) -> MailError {
+    MailError::Io(std::io::Error::other(e.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -153,4 +192,23 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), false); // No changes detected
     }
+
+    #[tokio::test]
+    async fn test_idle_watcher_reads_do_not_notify() {
+        let temp_dir = TempDir::new().unwrap();
+        let maildir = temp_dir.path().join("test@example.com");
+        fs::create_dir_all(maildir.join("new")).unwrap();
+        fs::create_dir_all(maildir.join("cur")).unwrap();
+        fs::write(maildir.join("cur/1.eml:2,S"), b"Test email").unwrap();
+
+        let watcher = IdleWatcher::new(&maildir).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        fs::read(maildir.join("cur/1.eml:2,S")).unwrap();
+        assert!(!watcher.wait_for_changes(Duration::from_millis(300)).await.unwrap());
+
+        // Flag changes rename the file
+        fs::rename(maildir.join("cur/1.eml:2,S"), maildir.join("cur/1.eml:2,FS")).unwrap();
+        assert!(watcher.wait_for_changes(Duration::from_secs(2)).await.unwrap());
+    }
 }
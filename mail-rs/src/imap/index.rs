@@ -0,0 +1,401 @@
+//! Persistent per-folder message index
+//!
+//! Opening a folder only lists its `new/` and `cur/` directories; message
+//! contents are read when a command needs them (see
+//! [`EmailMessage::content`](crate::imap::mailbox::EmailMessage::content)).
+//! The size and internal date of each message come from a `mail-rs-index`
+//! file next to the UID list, so a large folder can be selected without
+//! touching every message file:
+//!
+//! ```text
+//! 2 1700000000 43 2 1 2 1700000456000000000 1700000123000000000 1700000500000000000
+//! 41 2048 1700000123 cur 1700000123.M1P2Q0.mail.example.com:2,S
+//! 42 913 1700000456 new 1700000456.M7P2Q1.mail.example.com
+//! ```
+//!
+//! The first line holds the format version and a [`MailboxSummary`]:
+//! UIDVALIDITY, UIDNEXT, the message and unseen counts, the first
+//! unseen sequence number, and the modification times of `new/` and `cur/`
+//! and the time the folder was listed, in nanoseconds. The others hold the
+//! UID, size, internal date (Unix seconds), directory and current file name,
+//! whose suffix carries the flags.
+//!
+//! While neither directory changed, SELECT answers from the first line
+//! alone and the entries are only read when a command needs the messages.
+//! Otherwise the index is a cache: entries are looked up by base name and
+//! checked against the UID, messages missing from it are read from the file
+//! system, and a missing, outdated or corrupt index is rebuilt.
+
+use crate::error::MailError;
+use crate::imap::uid::base_name;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Index file name inside a Maildir folder
+pub const INDEX_FILE: &str = "mail-rs-index";
+
+const VERSION: u32 = 2;
+
+/// How long a directory must have been unchanged when it was listed for
+/// its modification time to prove later that it still is
+///
+/// File systems keep modification times with limited precision, so a
+/// change right after the listing could leave the time as it was.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Keeps temporary files of concurrent saves apart
+static SAVE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Cached metadata of one message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub uid: u32,
+    /// File name, including the flags suffix
+    pub filename: String,
+    pub size: usize,
+    pub internal_date: SystemTime,
+    /// In `new/` rather than `cur/`
+    pub recent: bool,
+}
+
+/// What SELECT reports about a folder, as of its last listing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxSummary {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    pub exists: usize,
+    pub unseen: usize,
+    /// Sequence number of the first unseen message, 0 if there is none
+    pub first_unseen: usize,
+    /// Modification times of `new/` and `cur/` before the listing, in
+    /// nanoseconds since the epoch, 0 for a missing directory
+    pub new_mtime: u128,
+    pub cur_mtime: u128,
+    /// When the folder was listed, in nanoseconds since the epoch
+    pub listed_at: u128,
+}
+
+impl MailboxSummary {
+    /// Modification times of the `new/` and `cur/` directories of `folder`
+    pub fn directory_times(folder: &Path) -> (u128, u128) {
+        let mtime = |dir: &str| {
+            fs::metadata(folder.join(dir))
+                .and_then(|metadata| metadata.modified())
+                .map(nanos)
+                .unwrap_or(0)
+        };
+        (mtime("new"), mtime("cur"))
+    }
+
+    /// Whether `folder` is still as it was listed: neither directory
+    /// changed, and both had settled when they were listed
+    pub fn is_current(&self, folder: &Path) -> bool {
+        let settled = |mtime: u128| mtime + SETTLE_TIME.as_nanos() <= self.listed_at;
+        Self::directory_times(folder) == (self.new_mtime, self.cur_mtime)
+            && settled(self.new_mtime)
+            && settled(self.cur_mtime)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 9 || fields[0].parse::<u32>().ok()? != VERSION {
+            return None;
+        }
+        Some(Self {
+            uid_validity: fields[1].parse().ok()?,
+            uid_next: fields[2].parse().ok()?,
+            exists: fields[3].parse().ok()?,
+            unseen: fields[4].parse().ok()?,
+            first_unseen: fields[5].parse().ok()?,
+            new_mtime: fields[6].parse().ok()?,
+            cur_mtime: fields[7].parse().ok()?,
+            listed_at: fields[8].parse().ok()?,
+        })
+    }
+
+    fn render(&self) -> String {
+        format!(
+            "{} {} {} {} {} {} {} {} {}",
+            VERSION,
+            self.uid_validity,
+            self.uid_next,
+            self.exists,
+            self.unseen,
+            self.first_unseen,
+            self.new_mtime,
+            self.cur_mtime,
+            self.listed_at
+        )
+    }
+}
+
+/// Nanoseconds since the epoch of `time`
+pub fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+/// Index file opened for its summary, whose entries are read later
+///
+/// The file stays open, so the entries match the summary even if another
+/// session rewrites the index meanwhile.
+pub struct PendingEntries {
+    reader: BufReader<fs::File>,
+}
+
+impl PendingEntries {
+    /// Entries of the index, in file order; `None` if it is corrupt
+    pub fn read(mut self) -> Option<Vec<IndexEntry>> {
+        let mut entries = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line).ok()? == 0 {
+                return Some(entries);
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if !line.is_empty() {
+                entries.push(parse_entry(line)?);
+            }
+        }
+    }
+}
+
+/// Index of one folder
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxIndex {
+    pub summary: MailboxSummary,
+    /// Base name -> entry
+    entries: HashMap<String, IndexEntry>,
+}
+
+impl MailboxIndex {
+    /// Index of `folder`; empty if there is none yet
+    pub fn load(folder: &Path) -> Self {
+        let path = folder.join(INDEX_FILE);
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).unwrap_or_else(|| {
+                warn!("Corrupt mailbox index {}, rebuilding", path.display());
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Summary of the index of `folder`, reading only its first line
+    pub fn open_summary(folder: &Path) -> Option<(MailboxSummary, PendingEntries)> {
+        let mut reader = BufReader::new(fs::File::open(folder.join(INDEX_FILE)).ok()?);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let summary = MailboxSummary::parse(line.trim_end())?;
+        Some((summary, PendingEntries { reader }))
+    }
+
+    /// Build an index from entries
+    pub fn from_entries(entries: impl IntoIterator<Item = IndexEntry>) -> Self {
+        Self {
+            summary: MailboxSummary::default(),
+            entries: entries
+                .into_iter()
+                .map(|entry| (base_name(&entry.filename).to_string(), entry))
+                .collect(),
+        }
+    }
+
+    /// Entry of a message file with the given UID
+    ///
+    /// A file may have been renamed with other flags since it was indexed,
+    /// so only the base name has to match.
+    pub fn get(&self, filename: &str, uid: u32) -> Option<&IndexEntry> {
+        self.entries
+            .get(base_name(filename))
+            .filter(|entry| entry.uid == uid)
+    }
+
+    pub fn with_summary(mut self, summary: MailboxSummary) -> Self {
+        self.summary = summary;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Write the index of `folder`, replacing the previous one atomically
+    ///
+    /// Sessions opening the same folder may save at the same time; the
+    /// last rename wins, which is fine for a cache.
+    pub fn save(&self, folder: &Path) -> Result<(), MailError> {
+        let tmp = folder.join(format!(
+            "{}.{}.{}.tmp",
+            INDEX_FILE,
+            std::process::id(),
+            SAVE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(self.render().as_bytes())?;
+        fs::rename(&tmp, folder.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let summary = MailboxSummary::parse(lines.next()?)?;
+        let entries = lines
+            .filter(|line| !line.is_empty())
+            .map(parse_entry)
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self::from_entries(entries).with_summary(summary))
+    }
+
+    fn render(&self) -> String {
+        let mut entries: Vec<&IndexEntry> = self.entries.values().collect();
+        entries.sort_unstable_by_key(|entry| entry.uid);
+
+        let mut text = format!("{}\n", self.summary.render());
+        for entry in entries {
+            let seconds = entry
+                .internal_date
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            text.push_str(&format!(
+                "{} {} {} {} {}\n",
+                entry.uid,
+                entry.size,
+                seconds,
+                if entry.recent { "new" } else { "cur" },
+                entry.filename
+            ));
+        }
+        text
+    }
+}
+
+fn parse_entry(line: &str) -> Option<IndexEntry> {
+    let mut fields = line.splitn(5, ' ');
+    let uid = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let seconds = fields.next()?.parse().ok()?;
+    let recent = match fields.next()? {
+        "new" => true,
+        "cur" => false,
+        _ => return None,
+    };
+    let filename = fields.next()?.to_string();
+    Some(IndexEntry {
+        uid,
+        filename,
+        size,
+        internal_date: UNIX_EPOCH + Duration::from_secs(seconds),
+        recent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(uid: u32, filename: &str, size: usize) -> IndexEntry {
+        IndexEntry {
+            uid,
+            filename: filename.to_string(),
+            size,
+            internal_date: UNIX_EPOCH + Duration::from_secs(1_700_000_000 + uid as u64),
+            recent: !filename.contains(":2,"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_by_base_name_and_uid() {
+        let index = MailboxIndex::from_entries([entry(1, "100.a:2,S", 10), entry(2, "200.b", 20)]);
+        assert_eq!(index.get("100.a:2,FS", 1).map(|e| e.size), Some(10));
+        assert_eq!(index.get("200.b:2,", 2).map(|e| e.size), Some(20));
+        // A reused file name under a new UID is not trusted
+        assert!(index.get("200.b", 3).is_none());
+        assert!(index.get("300.c", 3).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new().unwrap();
+        assert!(MailboxIndex::load(dir.path()).is_empty());
+
+        let summary = MailboxSummary {
+            uid_validity: 1_700_000_000,
+            uid_next: 8,
+            exists: 2,
+            unseen: 1,
+            first_unseen: 1,
+            new_mtime: 10,
+            cur_mtime: 20,
+            listed_at: 30,
+        };
+        let index = MailboxIndex::from_entries([entry(7, "1.x:2,RS", 512), entry(3, "2.y", 64)])
+            .with_summary(summary);
+        index.save(dir.path()).unwrap();
+        assert_eq!(MailboxIndex::load(dir.path()), index);
+        assert!(fs::read_to_string(dir.path().join(INDEX_FILE))
+            .unwrap()
+            .starts_with("2 1700000000 8 2 1 1 10 20 30\n3 64 1700000003 new 2.y\n"));
+
+        // The summary comes first, the entries stay readable afterwards
+        let (loaded, pending) = MailboxIndex::open_summary(dir.path()).unwrap();
+        assert_eq!(loaded, summary);
+        fs::write(dir.path().join(INDEX_FILE), "2 0 1 0 0 0 0 0 0\n").unwrap();
+        let entries = pending.read().unwrap();
+        assert_eq!(entries, vec![entry(3, "2.y", 64), entry(7, "1.x:2,RS", 512)]);
+
+        // Version 1 indexes are rebuilt
+        fs::write(dir.path().join(INDEX_FILE), "1\n3 64 1700000003 2.y\n").unwrap();
+        assert!(MailboxIndex::load(dir.path()).is_empty());
+        assert!(MailboxIndex::open_summary(dir.path()).is_none());
+
+        fs::write(dir.path().join(INDEX_FILE), "2 0 1 0 0 0 0 0 0\nnot an entry\n").unwrap();
+        assert!(MailboxIndex::load(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_summary_is_current() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("new")).unwrap();
+        fs::create_dir_all(dir.path().join("cur")).unwrap();
+        let past = SystemTime::now() - Duration::from_secs(60);
+        for sub in ["new", "cur"] {
+            fs::File::open(dir.path().join(sub))
+                .unwrap()
+                .set_modified(past)
+                .unwrap();
+        }
+
+        let (new_mtime, cur_mtime) = MailboxSummary::directory_times(dir.path());
+        let summary = MailboxSummary {
+            new_mtime,
+            cur_mtime,
+            listed_at: nanos(SystemTime::now()),
+            ..Default::default()
+        };
+        assert!(summary.is_current(dir.path()));
+
+        // Listed too soon after a change to trust the times
+        let unsettled = MailboxSummary {
+            listed_at: new_mtime.max(cur_mtime) + 1,
+            ..summary
+        };
+        assert!(!unsettled.is_current(dir.path()));
+
+        fs::write(dir.path().join("new/1.eml"), "Subject: 1\r\n\r\n").unwrap();
+        assert!(!summary.is_current(dir.path()));
+    }
+}
@@ -3,23 +3,119 @@
 //! Handles reading emails from Maildir storage
 
 use crate::error::MailError;
+use crate::imap::index::{nanos, IndexEntry, MailboxIndex, MailboxSummary, PendingEntries};
+use crate::imap::search::Matcher;
+use crate::imap::uid::UidList;
 use crate::imap::{SearchCriteria, StoreOperation};
+use crate::storage::maildir::account;
 use std::fs;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Distinguishes messages appended within the same microsecond
+static APPEND_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A message saved by APPEND, with the UID reported in APPENDUID
+/// (RFC 4315)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendedMessage {
+    /// Maildir file name, including the flags suffix
+    pub filename: String,
+    /// UIDVALIDITY of the destination mailbox
+    pub uid_validity: u32,
+    pub uid: u32,
+}
+
+/// Messages saved by COPY, with the UIDs reported in COPYUID (RFC 4315)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CopiedMessages {
+    /// UIDVALIDITY of the destination mailbox
+    pub uid_validity: u32,
+    /// UIDs of the copied messages in the source mailbox
+    pub source_uids: Vec<u32>,
+    /// UIDs of the copies, in the same order
+    pub uids: Vec<u32>,
+}
 
 /// Represents an email message in the mailbox
 #[derive(Debug, Clone)]
 pub struct EmailMessage {
     /// Sequence number (1-indexed)
     pub sequence: usize,
-    /// Unique ID
-    pub uid: String,
+    /// Unique ID, stable across sessions (see [`crate::imap::uid`])
+    pub uid: u32,
+    /// Maildir file name, including the flags suffix
+    pub filename: String,
     /// Message flags (e.g., \Seen, \Flagged)
     pub flags: Vec<String>,
-    /// RFC822 message content
-    pub content: Vec<u8>,
     /// Message size in bytes
     pub size: usize,
+    /// Internal date, the modification time of the file
+    pub internal_date: SystemTime,
+    /// Not yet moved out of new/ by a client
+    pub recent: bool,
+    /// Current location of the message file
+    path: PathBuf,
+    /// RFC822 message content, read on first use
+    content: OnceLock<Vec<u8>>,
+    /// Header block, read on first use unless the content was
+    header: OnceLock<Vec<u8>>,
+}
+
+impl EmailMessage {
+    /// Path of the message file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// RFC822 message content, read from disk on first use
+    pub fn content(&self) -> Result<&[u8], MailError> {
+        if let Some(content) = self.content.get() {
+            return Ok(content);
+        }
+        let content = fs::read(&self.path)?;
+        Ok(self.content.get_or_init(|| content))
+    }
+
+    /// Header block including the empty line ending it
+    ///
+    /// Reads only as far as the end of the header unless the content was
+    /// already loaded.
+    pub fn header(&self) -> Result<&[u8], MailError> {
+        if let Some(content) = self.content.get() {
+            let end = content
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|pos| pos + 4)
+                .or_else(|| content.windows(2).position(|w| w == b"\n\n").map(|pos| pos + 2))
+                .unwrap_or(content.len());
+            return Ok(&content[..end]);
+        }
+        if let Some(header) = self.header.get() {
+            return Ok(header);
+        }
+
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let mut header = Vec::new();
+        loop {
+            let start = header.len();
+            if reader.read_until(b'\n', &mut header)? == 0 {
+                break;
+            }
+            if matches!(&header[start..], b"\r\n" | b"\n") {
+                break;
+            }
+        }
+        Ok(self.header.get_or_init(|| header))
+    }
+}
+
+fn is_seen(message: &EmailMessage) -> bool {
+    message.flags.iter().any(|flag| flag == "\\Seen")
 }
 
 /// Mailbox containing emails
@@ -28,13 +124,22 @@ pub struct Mailbox {
     pub name: String,
     /// Path to maildir
     path: PathBuf,
-    /// Messages in this mailbox
-    messages: Vec<EmailMessage>,
+    /// Messages in this mailbox, loaded on first use when the folder was
+    /// opened from its index summary
+    messages: OnceLock<Vec<EmailMessage>>,
+    /// Index entries to load the messages from
+    pending: Mutex<Option<PendingEntries>>,
+    /// Counts, UIDVALIDITY and UIDNEXT as of opening
+    summary: MailboxSummary,
 }
 
 impl Mailbox {
     /// Open a mailbox for a given email address
     ///
+    /// A folder unchanged since its index was written is opened from the
+    /// index summary alone, so SELECT takes the same time whatever the
+    /// folder's size; the messages are read when a command needs them.
+    ///
     /// # Arguments
     /// * `email` - Email address (e.g., "john@example.com")
     /// * `mailbox_name` - Mailbox name (e.g., "INBOX", "Sent", "Drafts")
@@ -60,74 +165,162 @@ impl Mailbox {
             )));
         }
 
-        let new_path = folder_path.join("new");
-        let cur_path = folder_path.join("cur");
+        if let Some((summary, pending)) = MailboxIndex::open_summary(&folder_path) {
+            if summary.is_current(&folder_path) {
+                return Ok(Mailbox {
+                    name: mailbox_name.to_string(),
+                    path: folder_path,
+                    messages: OnceLock::new(),
+                    pending: Mutex::new(Some(pending)),
+                    summary,
+                });
+            }
+        }
 
-        // Read messages from both new/ and cur/ directories
-        let mut messages = Vec::new();
+        let (messages, summary) = Self::scan(&folder_path)?;
+        Ok(Mailbox {
+            name: mailbox_name.to_string(),
+            path: folder_path,
+            messages: OnceLock::from(messages),
+            pending: Mutex::new(None),
+            summary,
+        })
+    }
 
-        // Read from new/ directory (unread messages)
-        if new_path.exists() {
-            if let Ok(entries) = fs::read_dir(&new_path) {
+    /// List a folder, assign UIDs to new messages and rewrite its index
+    fn scan(folder_path: &Path) -> Result<(Vec<EmailMessage>, MailboxSummary), MailError> {
+        // Directory times are taken first, so changes during the listing
+        // show on the next open
+        let (new_mtime, cur_mtime) = MailboxSummary::directory_times(folder_path);
+        let listed_at = nanos(SystemTime::now());
+
+        // List messages in new/ (unread) and cur/ (with flags); contents
+        // are only read when needed
+        let mut files = Vec::new();
+        for (dir, recent) in [("new", true), ("cur", false)] {
+            if let Ok(entries) = fs::read_dir(folder_path.join(dir)) {
                 for entry in entries.flatten() {
-                    let path = entry.path();
-                    if path.is_file() {
-                        if let Ok(content) = fs::read(&path) {
-                            let size = content.len();
-                            let uid = entry.file_name().to_string_lossy().to_string();
-
-                            messages.push(EmailMessage {
-                                sequence: 0, // Will be set after sorting
-                                uid,
-                                flags: vec![], // No flags for messages in new/
-                                content,
-                                size,
-                            });
-                        }
+                    if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                        files.push((entry.file_name().to_string_lossy().to_string(), recent));
                     }
                 }
             }
         }
 
-        // Read from cur/ directory (read messages with flags)
-        if cur_path.exists() {
-            if let Ok(entries) = fs::read_dir(&cur_path) {
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    if path.is_file() {
-                        if let Ok(content) = fs::read(&path) {
-                            let size = content.len();
-                            let filename = entry.file_name().to_string_lossy().to_string();
-
-                            // Parse Maildir flags from filename (format: unique:2,FLAGS)
-                            let flags = Self::parse_maildir_flags(&filename);
-
-                            messages.push(EmailMessage {
-                                sequence: 0, // Will be set after sorting
-                                uid: filename.clone(),
-                                flags,
-                                content,
-                                size,
-                            });
-                        }
-                    }
+        // Assign persistent UIDs, new messages get the next ones
+        let filenames: Vec<&str> = files.iter().map(|(filename, _)| filename.as_str()).collect();
+        let uids = UidList::sync_folder(folder_path, &filenames)?;
+
+        // Size and internal date come from the index, or from the file
+        // system for messages not indexed yet
+        let index = MailboxIndex::load(folder_path);
+        let mut entries = Vec::new();
+        for (filename, recent) in files {
+            let uid = uids.uid(&filename).unwrap_or_default();
+            let (size, internal_date) = match index.get(&filename, uid) {
+                Some(entry) => (entry.size, entry.internal_date),
+                None => {
+                    let dir = if recent { "new" } else { "cur" };
+                    let Ok(metadata) = fs::metadata(folder_path.join(dir).join(&filename)) else {
+                        // Expunged meanwhile
+                        continue;
+                    };
+                    let internal_date = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    (metadata.len() as usize, internal_date)
                 }
-            }
+            };
+            entries.push(IndexEntry {
+                uid,
+                filename,
+                size,
+                internal_date,
+                recent,
+            });
         }
 
-        // Sort messages by UID for deterministic ordering
-        messages.sort_by(|a, b| a.uid.cmp(&b.uid));
+        // Sequence numbers follow UID order
+        entries.sort_by_key(|entry| entry.uid);
+        let messages = Self::messages_from(folder_path, entries.clone());
+
+        let summary = MailboxSummary {
+            uid_validity: uids.uid_validity,
+            uid_next: uids.uid_next,
+            exists: messages.len(),
+            unseen: messages.iter().filter(|m| !is_seen(m)).count(),
+            first_unseen: messages
+                .iter()
+                .find(|m| !is_seen(m))
+                .map_or(0, |m| m.sequence),
+            new_mtime,
+            cur_mtime,
+            listed_at,
+        };
 
-        // Assign sequence numbers (1-indexed)
-        for (idx, msg) in messages.iter_mut().enumerate() {
-            msg.sequence = idx + 1;
+        // The index is only a cache, the folder stays usable without it
+        let index = MailboxIndex::from_entries(entries).with_summary(summary);
+        if let Err(e) = index.save(folder_path) {
+            warn!("Failed to save index of {}: {}", folder_path.display(), e);
         }
 
-        Ok(Mailbox {
-            name: mailbox_name.to_string(),
-            path: folder_path,
-            messages,
-        })
+        Ok((messages, summary))
+    }
+
+    /// Messages of index entries sorted by UID, numbered from 1
+    fn messages_from(folder_path: &Path, entries: Vec<IndexEntry>) -> Vec<EmailMessage> {
+        entries
+            .into_iter()
+            .enumerate()
+            .map(|(idx, entry)| EmailMessage {
+                sequence: idx + 1,
+                uid: entry.uid,
+                flags: if entry.recent {
+                    vec![] // No flags for messages in new/
+                } else {
+                    Self::parse_maildir_flags(&entry.filename)
+                },
+                path: folder_path
+                    .join(if entry.recent { "new" } else { "cur" })
+                    .join(&entry.filename),
+                filename: entry.filename,
+                size: entry.size,
+                internal_date: entry.internal_date,
+                recent: entry.recent,
+                content: OnceLock::new(),
+                header: OnceLock::new(),
+            })
+            .collect()
+    }
+
+    /// Messages as of opening, from the index the summary came from
+    ///
+    /// Falls back to listing the folder if the index cannot be read.
+    fn load_messages(&self) -> Vec<EmailMessage> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(mut entries) = pending.and_then(PendingEntries::read) {
+            if entries.len() == self.summary.exists {
+                entries.sort_by_key(|entry| entry.uid);
+                return Self::messages_from(&self.path, entries);
+            }
+        }
+
+        warn!("Unreadable index of {}, listing the folder", self.path.display());
+        match Self::scan(&self.path) {
+            Ok((messages, _)) => messages,
+            Err(e) => {
+                warn!("Failed to list {}: {}", self.path.display(), e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Messages, loaded if needed, for changes
+    fn messages_mut(&mut self) -> &mut Vec<EmailMessage> {
+        self.messages();
+        self.messages.get_mut().expect("messages are loaded")
     }
 
     /// Parse Maildir flags from filename
@@ -138,7 +331,7 @@ impl Mailbox {
     /// - R (Replied)
     /// - S (Seen)
     /// - T (Trashed/Deleted)
-    fn parse_maildir_flags(filename: &str) -> Vec<String> {
+    pub(crate) fn parse_maildir_flags(filename: &str) -> Vec<String> {
         let mut flags = Vec::new();
 
         // Look for :2, prefix indicating flags section
@@ -193,40 +386,54 @@ impl Mailbox {
         Ok(mailboxes)
     }
 
+    /// Maildir folder of this mailbox
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Mailbox of the user this folder belongs to
+    fn user_dir(&self) -> &Path {
+        if self.name.eq_ignore_ascii_case("INBOX") {
+            &self.path
+        } else {
+            self.path.parent().unwrap_or(&self.path)
+        }
+    }
+
     /// Get total number of messages
     pub fn message_count(&self) -> usize {
-        self.messages.len()
+        self.messages.get().map_or(self.summary.exists, Vec::len)
     }
 
-    /// Get all messages
+    /// Get all messages, reading them from the index on first use
     pub fn messages(&self) -> &[EmailMessage] {
-        &self.messages
+        self.messages.get_or_init(|| self.load_messages())
     }
 
     /// Get number of recent messages (all in new/)
     pub fn recent_count(&self) -> usize {
-        self.messages.len()
+        self.message_count()
     }
 
     /// Get number of unseen messages
     pub fn unseen_count(&self) -> usize {
-        self.messages
-            .iter()
-            .filter(|m| !m.flags.contains(&"\\Seen".to_string()))
-            .count()
+        match self.messages.get() {
+            Some(messages) => messages.iter().filter(|m| !is_seen(m)).count(),
+            None => self.summary.unseen,
+        }
     }
 
     /// Get first unseen sequence number
     pub fn first_unseen(&self) -> Option<usize> {
-        self.messages
-            .iter()
-            .find(|m| !m.flags.contains(&"\\Seen".to_string()))
-            .map(|m| m.sequence)
+        match self.messages.get() {
+            Some(messages) => messages.iter().find(|m| !is_seen(m)).map(|m| m.sequence),
+            None => Some(self.summary.first_unseen).filter(|seq| *seq > 0),
+        }
     }
 
     /// Get message by sequence number
     pub fn get_message(&self, sequence: usize) -> Option<&EmailMessage> {
-        self.messages.get(sequence.saturating_sub(1))
+        self.messages().get(sequence.saturating_sub(1))
     }
 
     /// Get messages by sequence range (e.g., "1:3", "1:*", "1")
@@ -239,13 +446,14 @@ impl Mailbox {
                 let parts: Vec<&str> = part.split(':').collect();
                 if parts.len() == 2 {
                     let start = parts[0].parse::<usize>().unwrap_or(1);
+                    let count = self.messages().len();
                     let end = if parts[1] == "*" {
-                        self.messages.len()
+                        count
                     } else {
-                        parts[1].parse::<usize>().unwrap_or(self.messages.len())
+                        parts[1].parse::<usize>().unwrap_or(count)
                     };
 
-                    for seq in start..=end.min(self.messages.len()) {
+                    for seq in start..=end.min(count) {
                         if let Some(msg) = self.get_message(seq) {
                             result.push(msg);
                         }
@@ -264,70 +472,69 @@ impl Mailbox {
         result
     }
 
-    /// Get UID validity (constant for this implementation)
+    /// Get messages by UID set (e.g., "4:7", "10:*", "3,5")
+    ///
+    /// `*` stands for the highest UID in use, and ranges may be given in
+    /// either order, so `n:*` always includes the last message.
+    pub fn get_messages_by_uid(&self, uid_set: &str) -> Vec<&EmailMessage> {
+        let max_uid = self.messages().last().map(|m| m.uid).unwrap_or(0);
+        let parse = |value: &str| {
+            if value == "*" {
+                Some(max_uid)
+            } else {
+                value.parse::<u32>().ok()
+            }
+        };
+
+        let ranges: Vec<(u32, u32)> = uid_set
+            .split(',')
+            .filter_map(|part| match part.split_once(':') {
+                Some((start, end)) => {
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    Some((start.min(end), start.max(end)))
+                }
+                None => parse(part).map(|uid| (uid, uid)),
+            })
+            .collect();
+
+        self.messages()
+            .iter()
+            .filter(|m| ranges.iter().any(|(start, end)| (*start..=*end).contains(&m.uid)))
+            .collect()
+    }
+
+    /// Sequence set (e.g., "2,5") of the messages in a UID set, for the
+    /// sequence-based operations behind UID STORE and UID COPY
+    pub fn sequence_set_for_uids(&self, uid_set: &str) -> String {
+        self.get_messages_by_uid(uid_set)
+            .iter()
+            .map(|m| m.sequence.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Get UID validity, kept in the folder's UID list
     pub fn uid_validity(&self) -> u32 {
-        1 // Simple constant for now
+        self.summary.uid_validity
     }
 
     /// Get next UID
     pub fn uid_next(&self) -> u32 {
-        (self.messages.len() + 1) as u32
+        self.summary.uid_next
     }
 
     /// Search messages by criteria
     ///
     /// Returns sequence numbers of matching messages
     pub fn search(&self, criteria: &SearchCriteria) -> Result<Vec<usize>, MailError> {
-        let mut matches = Vec::new();
-
-        for msg in &self.messages {
-            // Convert message content to string for searching
-            let content_str = String::from_utf8_lossy(&msg.content);
-
-            // Extract headers (everything before first empty line)
-            let headers = if let Some(header_end) = content_str.find("\r\n\r\n") {
-                &content_str[..header_end]
-            } else {
-                &content_str
-            };
-
-            // Check if message matches criteria
-            let is_match = match criteria {
-                SearchCriteria::All => true,
+        let messages = self.messages();
+        let matcher = Matcher::new(messages.len(), messages.last().map(|m| m.uid).unwrap_or(0));
 
-                SearchCriteria::Subject(query) => {
-                    Self::extract_header(headers, "Subject:")
-                        .unwrap_or_default()
-                        .to_lowercase()
-                        .contains(&query.to_lowercase())
-                }
-
-                SearchCriteria::From(query) => {
-                    Self::extract_header(headers, "From:")
-                        .unwrap_or_default()
-                        .to_lowercase()
-                        .contains(&query.to_lowercase())
-                }
-
-                SearchCriteria::To(query) => {
-                    Self::extract_header(headers, "To:")
-                        .unwrap_or_default()
-                        .to_lowercase()
-                        .contains(&query.to_lowercase())
-                }
-
-                SearchCriteria::Text(query) => {
-                    // Search in entire message (headers + body)
-                    content_str.to_lowercase().contains(&query.to_lowercase())
-                }
-            };
-
-            if is_match {
-                matches.push(msg.sequence);
-            }
-        }
-
-        Ok(matches)
+        Ok(messages
+            .iter()
+            .filter(|msg| matcher.matches(criteria, msg))
+            .map(|msg| msg.sequence)
+            .collect())
     }
 
     /// Store flags on messages
@@ -341,6 +548,7 @@ impl Mailbox {
         flags: &[String],
     ) -> Result<Vec<usize>, MailError> {
         let mut modified_sequences = Vec::new();
+        let count = self.messages_mut().len();
 
         // Parse sequence set and get message indices
         for part in sequence_set.split(',') {
@@ -350,11 +558,11 @@ impl Mailbox {
                 if parts.len() == 2 {
                     let start = parts[0].parse::<usize>().unwrap_or(1);
                     let end = if parts[1] == "*" {
-                        self.messages.len()
+                        count
                     } else {
-                        parts[1].parse::<usize>().unwrap_or(self.messages.len())
+                        parts[1].parse::<usize>().unwrap_or(count)
                     };
-                    (start..=end.min(self.messages.len())).collect::<Vec<_>>()
+                    (start..=end.min(count)).collect::<Vec<_>>()
                 } else {
                     vec![]
                 }
@@ -369,9 +577,9 @@ impl Mailbox {
 
             // Modify flags for each message
             for seq in sequences {
-                if seq > 0 && seq <= self.messages.len() {
+                if seq > 0 && seq <= count {
                     let idx = seq - 1; // Convert to 0-indexed
-                    let msg = &mut self.messages[idx];
+                    let msg = &mut self.messages_mut()[idx];
 
                     // Store old flags for comparison
                     let old_flags = msg.flags.clone();
@@ -410,26 +618,26 @@ impl Mailbox {
 
     /// Persist message flags to disk by renaming the maildir file
     fn persist_message_flags(&mut self, idx: usize) -> Result<(), MailError> {
-        if idx >= self.messages.len() {
+        let Some(msg) = self.messages_mut().get(idx) else {
             return Ok(());
-        }
+        };
 
-        // Get the current UID (which might already have the old flags)
-        let old_uid = self.messages[idx].uid.clone();
-        let msg_flags = self.messages[idx].flags.clone();
+        // Get the current file name (which might already have the old flags)
+        let old_filename = msg.filename.clone();
+        let msg_flags = msg.flags.clone();
 
         // Build new filename with updated flags
-        let new_filename = self.build_maildir_filename_with_flags(&old_uid, &msg_flags);
+        let new_filename = Self::build_maildir_filename_with_flags(&old_filename, &msg_flags);
 
         // If filename hasn't changed, nothing to do
-        if old_uid == new_filename {
+        if old_filename == new_filename {
             return Ok(());
         }
 
         // Determine current file path
         // Files can be in new/ or cur/ directory
-        let new_path = self.path.join("new").join(&old_uid);
-        let cur_path = self.path.join("cur").join(&old_uid);
+        let new_path = self.path.join("new").join(&old_filename);
+        let cur_path = self.path.join("cur").join(&old_filename);
 
         let current_file = if new_path.exists() {
             new_path
@@ -449,15 +657,18 @@ impl Mailbox {
         // Rename the file to update flags
         fs::rename(&current_file, &dest_path)?;
 
-        // Update the UID in our in-memory structure
-        self.messages[idx].uid = new_filename;
+        // Update the file name in our in-memory structure; the base name,
+        // and with it the UID, is unchanged
+        let msg = &mut self.messages_mut()[idx];
+        msg.filename = new_filename;
+        msg.path = dest_path;
 
         Ok(())
     }
 
     /// Build maildir filename with flags
     /// Format: unique_id:2,FLAGS where FLAGS is sorted: DFPRS
-    fn build_maildir_filename_with_flags(&self, old_filename: &str, flags: &[String]) -> String {
+    pub(crate) fn build_maildir_filename_with_flags(old_filename: &str, flags: &[String]) -> String {
         // Extract base name (before :2,)
         let base = if let Some(pos) = old_filename.find(":2,") {
             &old_filename[..pos]
@@ -492,34 +703,66 @@ impl Mailbox {
     /// Permanently removes messages marked as \Deleted from the mailbox
     /// Returns the list of expunged sequence numbers
     pub fn expunge(&mut self) -> Result<Vec<usize>, MailError> {
+        self.expunge_matching(|_| true)
+    }
+
+    /// Expunge the messages marked with \Deleted flag in a UID set
+    /// (UID EXPUNGE, RFC 4315)
+    ///
+    /// Other deleted messages stay, so a client can't remove messages
+    /// another client marked meanwhile.
+    pub fn expunge_uids(&mut self, uid_set: &str) -> Result<Vec<usize>, MailError> {
+        let uids: Vec<u32> = self
+            .get_messages_by_uid(uid_set)
+            .iter()
+            .map(|msg| msg.uid)
+            .collect();
+        self.expunge_matching(|msg| uids.contains(&msg.uid))
+    }
+
+    fn expunge_matching(
+        &mut self,
+        selected: impl Fn(&EmailMessage) -> bool,
+    ) -> Result<Vec<usize>, MailError> {
         let mut expunged_sequences = Vec::new();
+        let path = self.path.clone();
+        let user_dir = self.user_dir().to_path_buf();
+        let (mut removed_bytes, mut removed) = (0u64, 0i64);
+        let messages = self.messages_mut();
 
         // Find all messages marked as \Deleted
         // Iterate in reverse order to avoid index issues when removing
-        let mut idx = self.messages.len();
+        let mut idx = messages.len();
         while idx > 0 {
             idx -= 1;
-            let msg = &self.messages[idx];
+            let msg = &messages[idx];
 
-            if msg.flags.contains(&"\\Deleted".to_string()) {
+            if msg.flags.contains(&"\\Deleted".to_string()) && selected(msg) {
                 // Delete the physical file from disk
-                let new_path = self.path.join("new").join(&msg.uid);
-                let cur_path = self.path.join("cur").join(&msg.uid);
+                let new_path = path.join("new").join(&msg.filename);
+                let cur_path = path.join("cur").join(&msg.filename);
 
                 // Try both new/ and cur/ directories
-                if new_path.exists() {
-                    fs::remove_file(&new_path)?;
-                } else if cur_path.exists() {
-                    fs::remove_file(&cur_path)?;
+                for file in [&new_path, &cur_path] {
+                    if let Ok(metadata) = fs::metadata(file) {
+                        fs::remove_file(file)?;
+                        removed_bytes += metadata.len();
+                        removed += 1;
+                        break;
+                    }
                 }
 
                 expunged_sequences.push(msg.sequence);
-                self.messages.remove(idx);
+                messages.remove(idx);
             }
         }
 
+        if removed > 0 {
+            account(&user_dir, -(removed_bytes as i64), -removed);
+        }
+
         // Re-number remaining messages (sequences must be continuous from 1..N)
-        for (idx, msg) in self.messages.iter_mut().enumerate() {
+        for (idx, msg) in messages.iter_mut().enumerate() {
             msg.sequence = idx + 1;
         }
 
@@ -532,14 +775,14 @@ impl Mailbox {
     /// Copy messages to another mailbox
     ///
     /// Copies the specified messages to the destination mailbox
-    /// Returns the number of messages copied
+    /// Returns the UIDs of the copied messages and of their copies
     pub fn copy_messages(
         &self,
         sequence_set: &str,
         destination: &str,
         email: &str,
         maildir_root: &Path,
-    ) -> Result<usize, MailError> {
+    ) -> Result<CopiedMessages, MailError> {
         let user_maildir = maildir_root.join(email);
 
         // Determine destination path
@@ -555,7 +798,10 @@ impl Mailbox {
         fs::create_dir_all(&dest_new)?;
         fs::create_dir_all(&dest_cur)?;
 
-        let mut copied_count = 0;
+        let messages = self.messages();
+        let mut source_uids = Vec::new();
+        let mut filenames = Vec::new();
+        let mut copied_bytes = 0u64;
 
         // Parse sequence set and copy messages
         for part in sequence_set.split(',') {
@@ -564,11 +810,11 @@ impl Mailbox {
                 if parts.len() == 2 {
                     let start = parts[0].parse::<usize>().unwrap_or(1);
                     let end = if parts[1] == "*" {
-                        self.messages.len()
+                        messages.len()
                     } else {
-                        parts[1].parse::<usize>().unwrap_or(self.messages.len())
+                        parts[1].parse::<usize>().unwrap_or(messages.len())
                     };
-                    (start..=end.min(self.messages.len())).collect::<Vec<_>>()
+                    (start..=end.min(messages.len())).collect::<Vec<_>>()
                 } else {
                     vec![]
                 }
@@ -582,9 +828,9 @@ impl Mailbox {
 
             // Copy each message
             for seq in sequences {
-                if seq > 0 && seq <= self.messages.len() {
+                if seq > 0 && seq <= messages.len() {
                     let idx = seq - 1;
-                    let msg = &self.messages[idx];
+                    let msg = &messages[idx];
 
                     // Generate unique filename for copied message
                     let timestamp = std::time::SystemTime::now()
@@ -599,7 +845,7 @@ impl Mailbox {
                         base_filename
                     } else {
                         // Has flags - build maildir filename with flags
-                        self.build_maildir_filename_with_flags(&base_filename, &msg.flags)
+                        Self::build_maildir_filename_with_flags(&base_filename, &msg.flags)
                     };
 
                     // Determine destination directory based on flags
@@ -610,32 +856,104 @@ impl Mailbox {
                     };
 
                     // Write message content to destination
-                    fs::write(&dest_file, &msg.content)?;
-                    copied_count += 1;
+                    let content = msg.content()?;
+                    fs::write(&dest_file, content)?;
+                    copied_bytes += content.len() as u64;
+                    source_uids.push(msg.uid);
+                    filenames.push(filename);
                 }
             }
         }
 
-        Ok(copied_count)
+        if !filenames.is_empty() {
+            account(&user_maildir, copied_bytes as i64, filenames.len() as i64);
+        }
+
+        let (list, uids) = UidList::add_messages(&dest_path, &filenames)?;
+        Ok(CopiedMessages {
+            uid_validity: list.uid_validity,
+            source_uids,
+            uids,
+        })
     }
 
-    /// Helper: Extract header value from headers string
-    fn extract_header(headers: &str, header_name: &str) -> Option<String> {
-        for line in headers.lines() {
-            if line.to_lowercase().starts_with(&header_name.to_lowercase()) {
-                // Extract everything after "Header: "
-                if let Some(value) = line.split_once(':') {
-                    return Some(value.1.trim().to_string());
-                }
+    /// Append a message to a mailbox (IMAP APPEND)
+    ///
+    /// The message is written to `tmp/` and renamed into `new/`, or into
+    /// `cur/` with its flags, so readers never see a partial file. The
+    /// internal date becomes the file's modification time. INBOX is created
+    /// on demand; other mailboxes must exist (`NotFound` otherwise, for a
+    /// `[TRYCREATE]` response). Returns the new message's file name and
+    /// UID.
+    pub fn append(
+        email: &str,
+        mailbox_name: &str,
+        maildir_root: &Path,
+        content: &[u8],
+        flags: &[String],
+        internal_date: Option<SystemTime>,
+    ) -> Result<AppendedMessage, MailError> {
+        let user_maildir = maildir_root.join(email);
+        let folder_path = if mailbox_name.to_uppercase() == "INBOX" {
+            user_maildir.clone()
+        } else {
+            let folder_path = user_maildir.join(format!(".{}", mailbox_name));
+            if !folder_path.exists() {
+                return Err(MailError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Mailbox '{}' not found", mailbox_name),
+                )));
             }
+            folder_path
+        };
+        for dir in ["tmp", "new", "cur"] {
+            fs::create_dir_all(folder_path.join(dir))?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let base = format!(
+            "{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            APPEND_COUNTER.fetch_add(1, Ordering::Relaxed),
+            gethostname::gethostname().to_string_lossy()
+        );
+
+        let tmp_path = folder_path.join("tmp").join(&base);
+        fs::write(&tmp_path, content)?;
+        if let Some(date) = internal_date {
+            fs::File::options()
+                .write(true)
+                .open(&tmp_path)?
+                .set_modified(date)?;
         }
-        None
+
+        let (filename, dest_dir) = if flags.is_empty() {
+            (base, "new")
+        } else {
+            (Self::build_maildir_filename_with_flags(&base, flags), "cur")
+        };
+        fs::rename(&tmp_path, folder_path.join(dest_dir).join(&filename))?;
+        account(&user_maildir, content.len() as i64, 1);
+
+        let (list, uids) = UidList::add_messages(&folder_path, &[&filename])?;
+        Ok(AppendedMessage {
+            filename,
+            uid_validity: list.uid_validity,
+            uid: uids[0],
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::imap::index::INDEX_FILE;
+    use crate::imap::search;
+    use crate::mime::MimeEntity;
     use std::fs;
     use tempfile::TempDir;
 
@@ -671,7 +989,98 @@ mod tests {
 
         let msg = mailbox.get_message(1).unwrap();
         assert_eq!(msg.sequence, 1);
-        assert!(msg.content.starts_with(b"Subject: Test"));
+        assert!(msg.content().unwrap().starts_with(b"Subject: Test"));
+    }
+
+    #[test]
+    fn test_append() {
+        let (_temp, root) = setup_test_maildir();
+        let date = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(837_596_665);
+
+        let appended = Mailbox::append(
+            "test@example.com",
+            "INBOX",
+            &root,
+            b"Subject: Draft\r\n\r\nBody",
+            &["\\Seen".to_string(), "\\Draft".to_string()],
+            Some(date),
+        )
+        .unwrap();
+        assert!(appended.filename.ends_with(":2,DS"));
+
+        let path = root.join("test@example.com/cur").join(&appended.filename);
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), date);
+        assert_eq!(
+            fs::read_dir(root.join("test@example.com/tmp")).unwrap().count(),
+            0
+        );
+
+        let mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert_eq!(mailbox.message_count(), 3);
+        assert_eq!(appended.uid_validity, mailbox.uid_validity());
+        let by_uid = mailbox.get_messages_by_uid(&appended.uid.to_string());
+        assert_eq!(by_uid[0].filename, appended.filename);
+
+        let err = Mailbox::append("test@example.com", "Drafts", &root, b"x", &[], None);
+        assert!(matches!(err, Err(MailError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_persistent_uids() {
+        let (_temp, root) = setup_test_maildir();
+        let mut mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        let validity = mailbox.uid_validity();
+        assert_eq!(mailbox.uid_next(), 3);
+        assert_eq!(mailbox.get_message(2).unwrap().uid, 2);
+
+        // Flag changes rename the file but keep the UID
+        mailbox
+            .store_flags("1", &StoreOperation::Add, &["\\Deleted".to_string()])
+            .unwrap();
+        mailbox.expunge().unwrap();
+        Mailbox::append("test@example.com", "INBOX", &root, b"Subject: 3\r\n\r\n3", &[], None)
+            .unwrap();
+
+        let mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert_eq!(mailbox.uid_validity(), validity);
+        let uids: Vec<u32> = mailbox.messages().iter().map(|m| m.uid).collect();
+        assert_eq!(uids, vec![2, 3]);
+        assert_eq!(mailbox.uid_next(), 4);
+
+        assert_eq!(mailbox.get_messages_by_uid("3").len(), 1);
+        assert_eq!(mailbox.get_messages_by_uid("1:2")[0].sequence, 1);
+        assert_eq!(mailbox.get_messages_by_uid("10:*").len(), 1);
+        assert_eq!(mailbox.sequence_set_for_uids("1,3"), "2");
+        assert!(mailbox.get_messages_by_uid("4").is_empty());
+    }
+
+    #[test]
+    fn test_copy_and_uid_expunge() {
+        let (_temp, root) = setup_test_maildir();
+        fs::create_dir_all(root.join("test@example.com/.Archive")).unwrap();
+        Mailbox::append("test@example.com", "Archive", &root, b"x", &[], None).unwrap();
+
+        let mut mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        let copied = mailbox
+            .copy_messages("2,1", "Archive", "test@example.com", &root)
+            .unwrap();
+        assert_eq!(copied.source_uids, vec![2, 1]);
+        assert_eq!(copied.uids, vec![2, 3]);
+
+        let archive = Mailbox::open("test@example.com", "Archive", &root).unwrap();
+        assert_eq!(archive.uid_validity(), copied.uid_validity);
+        assert_eq!(archive.uid_next(), 4);
+        let copy = archive.get_messages_by_uid("2")[0];
+        assert_eq!(copy.content().unwrap(), b"Subject: Test 2\r\n\r\nBody 2");
+
+        // UID EXPUNGE leaves deleted messages outside the set alone
+        mailbox
+            .store_flags("1:2", &StoreOperation::Add, &["\\Deleted".to_string()])
+            .unwrap();
+        assert_eq!(mailbox.expunge_uids("2:5").unwrap(), vec![2]);
+        assert_eq!(mailbox.message_count(), 1);
+        assert_eq!(mailbox.expunge_uids("2").unwrap(), Vec::<usize>::new());
+        assert_eq!(mailbox.expunge().unwrap(), vec![1]);
     }
 
     #[test]
@@ -688,4 +1097,146 @@ mod tests {
         let messages = mailbox.get_messages("1");
         assert_eq!(messages.len(), 1);
     }
+
+    #[test]
+    fn test_search() {
+        let (_temp, root) = setup_test_maildir();
+        let date = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(837_596_665);
+        Mailbox::append(
+            "test@example.com",
+            "INBOX",
+            &root,
+            "From: Zoë <zoe@example.com>\r\nDate: Wed, 17 Jul 1996 02:44:25 -0700\r\n\
+             Subject: Café\r\nX-Priority: 1\r\n\r\nSee you at the café"
+                .as_bytes(),
+            &["\\Seen".to_string(), "\\Flagged".to_string()],
+            Some(date),
+        )
+        .unwrap();
+        let mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+
+        let subjects = |program: &str| {
+            let (_, criteria) = search::parse(program).unwrap();
+            let mut subjects: Vec<String> = mailbox
+                .search(&criteria)
+                .unwrap()
+                .into_iter()
+                .map(|seq| {
+                    let msg = mailbox.get_message(seq).unwrap();
+                    MimeEntity::parse(msg.content().unwrap())
+                        .header_value("subject")
+                        .unwrap()
+                })
+                .collect();
+            subjects.sort();
+            subjects
+        };
+
+        assert_eq!(subjects("ALL").len(), 3);
+        assert_eq!(subjects("SUBJECT CAFÉ"), vec!["Café"]);
+        assert_eq!(subjects("FLAGGED SEEN"), vec!["Café"]);
+        assert_eq!(subjects("UNSEEN"), vec!["Test 1", "Test 2"]);
+        assert_eq!(subjects("NEW"), vec!["Test 1", "Test 2"]);
+        assert_eq!(subjects("OR SUBJECT \"Test 1\" FROM zoë"), vec!["Café", "Test 1"]);
+        assert_eq!(subjects("NOT (BODY café)"), vec!["Test 1", "Test 2"]);
+        assert_eq!(subjects("HEADER X-Priority \"\""), vec!["Café"]);
+        assert_eq!(subjects("BEFORE 1-Jan-2000"), vec!["Café"]);
+        assert_eq!(subjects("ON 17-Jul-1996 SENTON 17-Jul-1996"), vec!["Café"]);
+        assert_eq!(subjects("SENTSINCE 18-Jul-1996").len(), 0);
+        assert_eq!(subjects("SINCE 1-Jan-2000"), vec!["Test 1", "Test 2"]);
+        assert_eq!(subjects("LARGER 100"), vec!["Café"]);
+        assert_eq!(subjects("SMALLER 100"), vec!["Test 1", "Test 2"]);
+        assert_eq!(subjects("2:* 1,*").len(), 1);
+    }
+
+    /// Date the folder's directories back, as if nothing changed lately
+    fn settle(folder: &Path) {
+        let past = SystemTime::now() - std::time::Duration::from_secs(60);
+        for dir in ["new", "cur"] {
+            fs::create_dir_all(folder.join(dir)).unwrap();
+            fs::File::open(folder.join(dir))
+                .unwrap()
+                .set_modified(past)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_open_from_summary() {
+        let (_temp, root) = setup_test_maildir();
+        let folder = root.join("test@example.com");
+        let seen = ["\\Seen".to_string()];
+        Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        Mailbox::append("test@example.com", "INBOX", &root, b"Subject: 3\r\n\r\n3", &seen, None)
+            .unwrap();
+        settle(&folder);
+
+        // Listing the settled folder makes its summary trustworthy
+        let listed = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert!(listed.messages.get().is_some());
+
+        let mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert!(mailbox.messages.get().is_none());
+        assert_eq!(mailbox.message_count(), 3);
+        assert_eq!(mailbox.unseen_count(), 2);
+        assert_eq!(mailbox.first_unseen(), Some(1));
+        assert_eq!(mailbox.uid_validity(), listed.uid_validity());
+        assert_eq!(mailbox.uid_next(), 4);
+
+        // Changes after opening don't show in the messages read later
+        Mailbox::append("test@example.com", "INBOX", &root, b"Subject: 4\r\n\r\n4", &[], None)
+            .unwrap();
+        let reopened = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert_eq!(reopened.message_count(), 4);
+        assert!(reopened.messages.get().is_some());
+
+        let uids: Vec<u32> = mailbox.messages().iter().map(|m| m.uid).collect();
+        assert_eq!(uids, vec![1, 2, 3]);
+        assert_eq!(mailbox.get_message(3).unwrap().flags, seen);
+        assert_eq!(
+            mailbox.get_message(1).unwrap().content().unwrap(),
+            b"Subject: Test 1\r\n\r\nBody 1"
+        );
+
+        // A broken index falls back to listing the folder
+        settle(&folder);
+        Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        let index = fs::read_to_string(folder.join(INDEX_FILE)).unwrap();
+        let header = index.lines().next().unwrap();
+        fs::write(folder.join(INDEX_FILE), format!("{}\nbroken\n", header)).unwrap();
+        let mut mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert!(mailbox.messages.get().is_none());
+        mailbox
+            .store_flags("4", &StoreOperation::Add, &seen)
+            .unwrap();
+        assert_eq!(mailbox.message_count(), 4);
+        assert_eq!(mailbox.unseen_count(), 2);
+    }
+
+    #[test]
+    fn test_index_and_lazy_content() {
+        let (_temp, root) = setup_test_maildir();
+        let folder = root.join("test@example.com");
+
+        let mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        let index = fs::read_to_string(folder.join(INDEX_FILE)).unwrap();
+        assert_eq!(index.lines().count(), 3);
+
+        // Contents are read on demand, the header without the body
+        let msg = mailbox.get_message(1).unwrap();
+        assert_eq!(msg.header().unwrap(), b"Subject: Test 1\r\n\r\n");
+        fs::remove_file(mailbox.get_message(2).unwrap().path()).unwrap();
+        assert!(mailbox.get_message(2).unwrap().content().is_err());
+        assert_eq!(msg.content().unwrap(), b"Subject: Test 1\r\n\r\nBody 1");
+
+        // Reopening trusts the index instead of the file system
+        fs::write(folder.join(INDEX_FILE), index.replacen(" 25 ", " 999 ", 1)).unwrap();
+        let mailbox = Mailbox::open("test@example.com", "INBOX", &root).unwrap();
+        assert_eq!(mailbox.message_count(), 1);
+        assert_eq!(mailbox.get_message(1).unwrap().size, 999);
+        assert_eq!(
+            fs::read_to_string(folder.join(INDEX_FILE)).unwrap().lines().count(),
+            2
+        );
+    }
 }
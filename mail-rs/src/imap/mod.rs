@@ -1,13 +1,37 @@
 //! IMAP server implementation
 //!
 //! This module provides a full-featured IMAP server implementation
-//! supporting: LOGIN, SELECT, FETCH, SEARCH, STORE, COPY, EXPUNGE, IDLE
+//! supporting: LOGIN, SELECT, FETCH, SEARCH, STORE, COPY, APPEND, EXPUNGE, IDLE
+//! and the UID variants of FETCH, SEARCH, STORE and COPY. UIDs and
+//! UIDVALIDITY are persisted per folder (see [`uid`]); with UIDPLUS, APPEND
+//! and COPY report the UIDs they assign (APPENDUID/COPYUID) and UID EXPUNGE
+//! removes only the given deleted messages. A per-folder
+//! index caches message sizes and dates so SELECT does not read every
+//! message; contents are loaded on demand (see [`index`]). Standard folders
+//! are created on first login and advertised with their SPECIAL-USE
+//! attributes (see [`special_use`]). SUBSCRIBE, UNSUBSCRIBE and LSUB keep
+//! a per-user subscription list (see [`subscriptions`]), and NAMESPACE
+//! reports a single personal namespace. FETCH returns BODY sections and
+//! partial ranges (see [`fetch`]) and BODYSTRUCTURE and ENVELOPE built from
+//! the MIME tree (see [`bodystructure`]). COMPRESS=DEFLATE compresses the
+//! connection for clients on slow links (see [`compress`]). SEARCH accepts
+//! the full RFC 3501 criteria set with US-ASCII or UTF-8 strings (see
+//! [`search`]).
 
+pub mod bodystructure;
 pub mod commands;
+pub mod compress;
+pub mod fetch;
 pub mod idle;
+pub mod index;
 pub mod mailbox;
+pub mod proxy;
+pub mod search;
 pub mod server;
 pub mod session;
+pub mod special_use;
+pub mod subscriptions;
+pub mod uid;
 
 pub use commands::{ImapCommand, SearchCriteria, StoreOperation};
 pub use idle::IdleWatcher;
@@ -0,0 +1,185 @@
+//! Legacy IMAP proxying for migration coexistence
+//!
+//! During a staged migration, users not yet migrated still live on the
+//! old server. Their LOGIN is replayed against the legacy IMAP server with
+//! the same credentials and, on success, the rest of the connection is
+//! relayed byte-for-byte.
+
+use crate::error::MailError;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+/// Timeout for connecting and logging in to the legacy server
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tag used for the upstream LOGIN command
+const LOGIN_TAG: &str = "M1";
+
+/// Log in to the legacy IMAP server with the client's credentials
+///
+/// Returns the authenticated upstream connection, or `None` if the legacy
+/// server rejected the credentials.
+pub async fn login_upstream(
+    addr: &str,
+    username: &str,
+    password: &str,
+) -> Result<Option<BufReader<TcpStream>>, MailError> {
+    timeout(UPSTREAM_TIMEOUT, async {
+        let stream = TcpStream::connect(addr).await?;
+        let mut upstream = BufReader::new(stream);
+
+        let mut line = String::new();
+        upstream.read_line(&mut line).await?;
+        if !line.starts_with("* OK") {
+            return Err(MailError::ImapProtocol(format!(
+                "Unexpected legacy greeting: {}",
+                line.trim()
+            )));
+        }
+
+        let command = format!(
+            "{} LOGIN {} {}\r\n",
+            LOGIN_TAG,
+            quote(username)?,
+            quote(password)?
+        );
+        upstream.get_mut().write_all(command.as_bytes()).await?;
+
+        // Skip untagged responses until the tagged completion
+        let prefix = format!("{} ", LOGIN_TAG);
+        loop {
+            line.clear();
+            if upstream.read_line(&mut line).await? == 0 {
+                return Err(MailError::ImapProtocol(
+                    "Legacy server closed connection during LOGIN".to_string(),
+                ));
+            }
+            if let Some(status) = line.strip_prefix(&prefix) {
+                debug!("Legacy LOGIN for {}: {}", username, status.trim());
+                return Ok(status.starts_with("OK").then_some(upstream));
+            }
+        }
+    })
+    .await
+    .map_err(|_| MailError::ImapProtocol("Legacy server timeout".to_string()))?
+}
+
+/// Relay traffic between the client and the authenticated legacy connection
+///
+/// `pending` holds client bytes already buffered locally (pipelined
+/// commands) that must be forwarded before relaying.
+pub async fn relay<C>(
+    mut client: C,
+    pending: &[u8],
+    upstream: BufReader<TcpStream>,
+) -> Result<(), MailError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    // Bytes the legacy server sent after the LOGIN completion
+    let upstream_pending = upstream.buffer().to_vec();
+    let mut upstream = upstream.into_inner();
+
+    if !pending.is_empty() {
+        upstream.write_all(pending).await?;
+    }
+    if !upstream_pending.is_empty() {
+        client.write_all(&upstream_pending).await?;
+    }
+
+    let (to_upstream, to_client) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    info!(
+        "Legacy proxy session closed ({} bytes up, {} bytes down)",
+        to_upstream, to_client
+    );
+    Ok(())
+}
+
+/// Quote a LOGIN argument as an IMAP quoted string
+fn quote(value: &str) -> Result<String, MailError> {
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(MailError::ImapProtocol(
+            "Credentials contain invalid characters".to_string(),
+        ));
+    }
+    Ok(format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Minimal legacy server accepting a single user
+    async fn spawn_legacy_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    stream.get_mut().write_all(b"* OK legacy ready\r\n").await.unwrap();
+
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.unwrap() > 0 {
+                        let (tag, rest) = line.trim_end().split_once(' ').unwrap();
+                        let reply = if rest == "LOGIN \"bob@example.com\" \"p\\\"w\"" {
+                            format!("* CAPABILITY IMAP4rev1\r\n{} OK LOGIN done\r\n", tag)
+                        } else if rest.starts_with("LOGIN") {
+                            format!("{} NO bad credentials\r\n", tag)
+                        } else {
+                            format!("{} OK {} from legacy\r\n", tag, rest)
+                        };
+                        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a\"b\\c").unwrap(), "\"a\\\"b\\\\c\"");
+        assert!(quote("evil\r\nA2 DELETE INBOX").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login_upstream() {
+        let addr = spawn_legacy_server().await;
+
+        assert!(login_upstream(&addr, "bob@example.com", "p\"w")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(login_upstream(&addr, "bob@example.com", "wrong")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_relay_forwards_pending_commands() {
+        let addr = spawn_legacy_server().await;
+        let upstream = login_upstream(&addr, "bob@example.com", "p\"w")
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (client, server_side) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let _ = relay(server_side, b"a2 NOOP\r\n", upstream).await;
+        });
+
+        let mut client = BufReader::new(client);
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "a2 OK NOOP from legacy\r\n");
+    }
+}
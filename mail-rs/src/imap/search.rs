@@ -0,0 +1,474 @@
+//! SEARCH criteria (RFC 3501 section 6.4.4)
+//!
+//! The search program is parsed into a [`SearchCriteria`] tree: keys listed
+//! one after another must all match, `OR`, `NOT` and parenthesized groups
+//! combine them. Strings may be quoted, and non-ASCII strings arrive as
+//! UTF-8 (literals are inlined as quoted strings by the server). Only the
+//! US-ASCII and UTF-8 charsets are accepted; others get a `BADCHARSET`
+//! response listing these.
+//!
+//! Criteria are evaluated against the messages of the selected mailbox.
+//! String matches are case-insensitive substring matches on unfolded header
+//! values or the raw body.
+
+use crate::error::MailError;
+use crate::imap::mailbox::EmailMessage;
+use crate::imap::SearchCriteria;
+use crate::mime::MimeEntity;
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Charsets accepted in `SEARCH CHARSET`
+pub const SUPPORTED_CHARSETS: &[&str] = &["US-ASCII", "UTF-8"];
+
+/// Whether `SEARCH CHARSET charset` is supported
+pub fn is_supported_charset(charset: &str) -> bool {
+    SUPPORTED_CHARSETS
+        .iter()
+        .any(|supported| supported.eq_ignore_ascii_case(charset))
+}
+
+/// Tagged `NO` for an unsupported charset
+pub fn bad_charset(tag: &str) -> String {
+    format!(
+        "{} NO [BADCHARSET ({})] Unsupported charset\r\n",
+        tag,
+        SUPPORTED_CHARSETS.join(" ")
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Atom(String),
+    Quoted(String),
+}
+
+/// Parse the arguments of SEARCH into the optional charset and the
+/// criteria
+pub fn parse(input: &str) -> Result<(Option<String>, SearchCriteria), MailError> {
+    let mut tokens = tokenize(input)?;
+    tokens.reverse();
+
+    let mut charset = None;
+    if matches!(tokens.last(), Some(Token::Atom(atom)) if atom.eq_ignore_ascii_case("CHARSET")) {
+        tokens.pop();
+        charset = Some(string(&mut tokens, "CHARSET")?);
+    }
+
+    let mut keys = Vec::new();
+    while !tokens.is_empty() {
+        keys.push(parse_key(&mut tokens)?);
+    }
+    Ok((charset, all_of(keys)?))
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, MailError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => value.push(escaped),
+                            None => return Err(invalid("Unterminated quoted string")),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err(invalid("Unterminated quoted string")),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            _ => {
+                let mut atom = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(atom));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// One search key; `tokens` is reversed so the next token is last
+fn parse_key(tokens: &mut Vec<Token>) -> Result<SearchCriteria, MailError> {
+    let atom = match tokens.pop() {
+        Some(Token::Open) => {
+            let mut keys = Vec::new();
+            loop {
+                match tokens.last() {
+                    Some(Token::Close) => {
+                        tokens.pop();
+                        break;
+                    }
+                    Some(_) => keys.push(parse_key(tokens)?),
+                    None => return Err(invalid("Unbalanced parenthesis")),
+                }
+            }
+            return all_of(keys);
+        }
+        Some(Token::Atom(atom)) => atom,
+        Some(Token::Close) => return Err(invalid("Unbalanced parenthesis")),
+        Some(Token::Quoted(value)) => {
+            return Err(invalid(&format!("Unexpected string \"{}\"", value)))
+        }
+        None => return Err(invalid("SEARCH requires criteria")),
+    };
+
+    let key = atom.to_uppercase();
+    Ok(match key.as_str() {
+        "ALL" => SearchCriteria::All,
+        "ANSWERED" => SearchCriteria::Flag("\\Answered".to_string()),
+        "DELETED" => SearchCriteria::Flag("\\Deleted".to_string()),
+        "DRAFT" => SearchCriteria::Flag("\\Draft".to_string()),
+        "FLAGGED" => SearchCriteria::Flag("\\Flagged".to_string()),
+        "SEEN" => SearchCriteria::Flag("\\Seen".to_string()),
+        "UNANSWERED" => SearchCriteria::Unflag("\\Answered".to_string()),
+        "UNDELETED" => SearchCriteria::Unflag("\\Deleted".to_string()),
+        "UNDRAFT" => SearchCriteria::Unflag("\\Draft".to_string()),
+        "UNFLAGGED" => SearchCriteria::Unflag("\\Flagged".to_string()),
+        "UNSEEN" => SearchCriteria::Unflag("\\Seen".to_string()),
+        "KEYWORD" => SearchCriteria::Flag(string(tokens, &key)?),
+        "UNKEYWORD" => SearchCriteria::Unflag(string(tokens, &key)?),
+        "RECENT" => SearchCriteria::Recent,
+        "NEW" => SearchCriteria::New,
+        "OLD" => SearchCriteria::Not(Box::new(SearchCriteria::Recent)),
+        "SUBJECT" => SearchCriteria::Subject(string(tokens, &key)?),
+        "FROM" => SearchCriteria::From(string(tokens, &key)?),
+        "TO" => SearchCriteria::To(string(tokens, &key)?),
+        "CC" => SearchCriteria::Cc(string(tokens, &key)?),
+        "BCC" => SearchCriteria::Bcc(string(tokens, &key)?),
+        "BODY" => SearchCriteria::Body(string(tokens, &key)?),
+        "TEXT" => SearchCriteria::Text(string(tokens, &key)?),
+        "HEADER" => {
+            let field = string(tokens, &key)?;
+            SearchCriteria::Header(field, string(tokens, &key)?)
+        }
+        "BEFORE" => SearchCriteria::Before(date(tokens, &key)?),
+        "ON" => SearchCriteria::On(date(tokens, &key)?),
+        "SINCE" => SearchCriteria::Since(date(tokens, &key)?),
+        "SENTBEFORE" => SearchCriteria::SentBefore(date(tokens, &key)?),
+        "SENTON" => SearchCriteria::SentOn(date(tokens, &key)?),
+        "SENTSINCE" => SearchCriteria::SentSince(date(tokens, &key)?),
+        "LARGER" => SearchCriteria::Larger(number(tokens, &key)?),
+        "SMALLER" => SearchCriteria::Smaller(number(tokens, &key)?),
+        "UID" => SearchCriteria::Uid(sequence_set(&string(tokens, &key)?)?),
+        "NOT" => SearchCriteria::Not(Box::new(parse_key(tokens)?)),
+        "OR" => {
+            let left = parse_key(tokens)?;
+            SearchCriteria::Or(Box::new(left), Box::new(parse_key(tokens)?))
+        }
+        _ if atom.starts_with(|c: char| c.is_ascii_digit() || c == '*') => {
+            SearchCriteria::Sequence(sequence_set(&atom)?)
+        }
+        _ => return Err(invalid(&format!("Unknown search criterion: {}", atom))),
+    })
+}
+
+/// Keys that must all match; a single key stands for itself
+fn all_of(mut keys: Vec<SearchCriteria>) -> Result<SearchCriteria, MailError> {
+    match keys.len() {
+        0 => Err(invalid("SEARCH requires criteria")),
+        1 => Ok(keys.remove(0)),
+        _ => Ok(SearchCriteria::And(keys)),
+    }
+}
+
+/// Argument of `key`, quoted or as an atom
+fn string(tokens: &mut Vec<Token>, key: &str) -> Result<String, MailError> {
+    match tokens.pop() {
+        Some(Token::Atom(value)) | Some(Token::Quoted(value)) => Ok(value),
+        _ => Err(invalid(&format!("{} requires an argument", key))),
+    }
+}
+
+/// Date argument, e.g. `1-Feb-1994`
+fn date(tokens: &mut Vec<Token>, key: &str) -> Result<NaiveDate, MailError> {
+    let value = string(tokens, key)?;
+    NaiveDate::parse_from_str(&value, "%d-%b-%Y")
+        .map_err(|_| invalid(&format!("Invalid date for {}: {}", key, value)))
+}
+
+fn number(tokens: &mut Vec<Token>, key: &str) -> Result<usize, MailError> {
+    let value = string(tokens, key)?;
+    value
+        .parse()
+        .map_err(|_| invalid(&format!("Invalid number for {}: {}", key, value)))
+}
+
+/// Validated sequence set such as `1:4,7,9:*`
+fn sequence_set(value: &str) -> Result<String, MailError> {
+    let valid = value.split(',').all(|part| {
+        part.split(':').count() <= 2
+            && part
+                .split(':')
+                .all(|n| n == "*" || (!n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())))
+    });
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(invalid(&format!("Invalid sequence set: {}", value)))
+    }
+}
+
+fn invalid(message: &str) -> MailError {
+    MailError::ImapProtocol(message.to_string())
+}
+
+/// Evaluates criteria against the messages of one mailbox
+pub struct Matcher {
+    /// Highest sequence number, `*` in sequence sets
+    last_sequence: usize,
+    /// Highest UID, `*` in UID sets
+    last_uid: u32,
+}
+
+impl Matcher {
+    pub fn new(last_sequence: usize, last_uid: u32) -> Self {
+        Self {
+            last_sequence,
+            last_uid,
+        }
+    }
+
+    /// Whether `message` matches `criteria`
+    ///
+    /// The message file is only read as far as the criteria look; a file
+    /// that can't be read matches no criteria on its content.
+    pub fn matches(&self, criteria: &SearchCriteria, message: &EmailMessage) -> bool {
+        let raw = match needs(criteria) {
+            Needs::Metadata => None,
+            Needs::Header => message.header().ok(),
+            Needs::Content => message.content().ok(),
+        };
+        let entity = raw.map(MimeEntity::parse);
+        self.evaluate(criteria, message, raw, entity.as_ref())
+    }
+
+    fn evaluate(
+        &self,
+        criteria: &SearchCriteria,
+        message: &EmailMessage,
+        raw: Option<&[u8]>,
+        entity: Option<&MimeEntity>,
+    ) -> bool {
+        let header = |name: &str| {
+            entity
+                .and_then(|entity| entity.header_value(name))
+                .unwrap_or_default()
+        };
+
+        match criteria {
+            SearchCriteria::All => true,
+            SearchCriteria::Flag(flag) => has_flag(message, flag),
+            SearchCriteria::Unflag(flag) => !has_flag(message, flag),
+            SearchCriteria::Recent => message.recent,
+            SearchCriteria::New => message.recent && !has_flag(message, "\\Seen"),
+            SearchCriteria::Subject(query) => contains(&header("subject"), query),
+            SearchCriteria::From(query) => contains(&header("from"), query),
+            SearchCriteria::To(query) => contains(&header("to"), query),
+            SearchCriteria::Cc(query) => contains(&header("cc"), query),
+            SearchCriteria::Bcc(query) => contains(&header("bcc"), query),
+            SearchCriteria::Header(field, query) => entity
+                .and_then(|entity| entity.header_value(field))
+                .is_some_and(|value| contains(&value, query)),
+            SearchCriteria::Body(query) => {
+                entity.is_some_and(|entity| contains(&String::from_utf8_lossy(entity.body), query))
+            }
+            SearchCriteria::Text(query) => {
+                raw.is_some_and(|raw| contains(&String::from_utf8_lossy(raw), query))
+            }
+            SearchCriteria::Before(date) => internal_date(message) < *date,
+            SearchCriteria::On(date) => internal_date(message) == *date,
+            SearchCriteria::Since(date) => internal_date(message) >= *date,
+            SearchCriteria::SentBefore(date) => {
+                sent_date(&header("date")).is_some_and(|d| d < *date)
+            }
+            SearchCriteria::SentOn(date) => sent_date(&header("date")).is_some_and(|d| d == *date),
+            SearchCriteria::SentSince(date) => {
+                sent_date(&header("date")).is_some_and(|d| d >= *date)
+            }
+            SearchCriteria::Larger(size) => message.size > *size,
+            SearchCriteria::Smaller(size) => message.size < *size,
+            SearchCriteria::Uid(set) => set_contains(set, message.uid as u64, self.last_uid as u64),
+            SearchCriteria::Sequence(set) => {
+                set_contains(set, message.sequence as u64, self.last_sequence as u64)
+            }
+            SearchCriteria::Not(inner) => !self.evaluate(inner, message, raw, entity),
+            SearchCriteria::Or(left, right) => {
+                self.evaluate(left, message, raw, entity)
+                    || self.evaluate(right, message, raw, entity)
+            }
+            SearchCriteria::And(keys) => keys
+                .iter()
+                .all(|key| self.evaluate(key, message, raw, entity)),
+        }
+    }
+}
+
+/// How much of a message evaluating criteria needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Needs {
+    /// Flags, dates, size and numbers from the index
+    Metadata,
+    Header,
+    Content,
+}
+
+fn needs(criteria: &SearchCriteria) -> Needs {
+    match criteria {
+        SearchCriteria::Subject(_)
+        | SearchCriteria::From(_)
+        | SearchCriteria::To(_)
+        | SearchCriteria::Cc(_)
+        | SearchCriteria::Bcc(_)
+        | SearchCriteria::Header(_, _)
+        | SearchCriteria::SentBefore(_)
+        | SearchCriteria::SentOn(_)
+        | SearchCriteria::SentSince(_) => Needs::Header,
+        SearchCriteria::Body(_) | SearchCriteria::Text(_) => Needs::Content,
+        SearchCriteria::Not(inner) => needs(inner),
+        SearchCriteria::Or(left, right) => needs(left).max(needs(right)),
+        SearchCriteria::And(keys) => keys.iter().map(needs).max().unwrap_or(Needs::Metadata),
+        _ => Needs::Metadata,
+    }
+}
+
+fn has_flag(message: &EmailMessage, flag: &str) -> bool {
+    message.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+}
+
+/// Case-insensitive substring match; an empty query matches anything
+fn contains(haystack: &str, query: &str) -> bool {
+    haystack.to_lowercase().contains(&query.to_lowercase())
+}
+
+/// Day the message was received, in UTC
+fn internal_date(message: &EmailMessage) -> NaiveDate {
+    DateTime::<Utc>::from(message.internal_date).date_naive()
+}
+
+/// Day of the Date header, in the sender's timezone as RFC 3501 asks
+fn sent_date(value: &str) -> Option<NaiveDate> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.date_naive())
+}
+
+/// Whether a sequence or UID set contains `value`; `*` stands for `last`
+/// and ranges may be given in either order
+fn set_contains(set: &str, value: u64, last: u64) -> bool {
+    let number = |n: &str| if n == "*" { Some(last) } else { n.parse().ok() };
+    set.split(',').any(|part| match part.split_once(':') {
+        Some((start, end)) => match (number(start), number(end)) {
+            (Some(start), Some(end)) => (start.min(end)..=start.max(end)).contains(&value),
+            _ => false,
+        },
+        None => number(part) == Some(value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_program() {
+        let (charset, criteria) =
+            parse("CHARSET UTF-8 OR (FROM alice SINCE 1-Feb-1994) NOT SEEN LARGER 1024").unwrap();
+        assert_eq!(charset.as_deref(), Some("UTF-8"));
+        assert_eq!(
+            criteria,
+            SearchCriteria::And(vec![
+                SearchCriteria::Or(
+                    Box::new(SearchCriteria::And(vec![
+                        SearchCriteria::From("alice".to_string()),
+                        SearchCriteria::Since(NaiveDate::from_ymd_opt(1994, 2, 1).unwrap()),
+                    ])),
+                    Box::new(SearchCriteria::Not(Box::new(SearchCriteria::Flag(
+                        "\\Seen".to_string()
+                    )))),
+                ),
+                SearchCriteria::Larger(1024),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_strings() {
+        let (charset, criteria) =
+            parse(r#"HEADER X-Mailer "" SUBJECT "caf\"é" UID 2:* 1,3"#).unwrap();
+        assert_eq!(charset, None);
+        assert_eq!(
+            criteria,
+            SearchCriteria::And(vec![
+                SearchCriteria::Header("X-Mailer".to_string(), String::new()),
+                SearchCriteria::Subject("caf\"é".to_string()),
+                SearchCriteria::Uid("2:*".to_string()),
+                SearchCriteria::Sequence("1,3".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for input in [
+            "",
+            "SUBJECT",
+            "(SEEN",
+            "SEEN)",
+            "SINCE 1994-02-01",
+            "LARGER big",
+            "FOO",
+            "UID 1:2:3",
+            "SUBJECT \"open",
+        ] {
+            assert!(parse(input).is_err(), "{:?} should not parse", input);
+        }
+    }
+
+    #[test]
+    fn test_set_contains() {
+        assert!(set_contains("1:3,7", 2, 9));
+        assert!(set_contains("1:3,7", 7, 9));
+        assert!(!set_contains("1:3,7", 5, 9));
+        assert!(set_contains("*", 9, 9));
+        assert!(set_contains("*:4", 6, 9));
+        assert!(!set_contains("12:*", 8, 9));
+        assert!(set_contains("12:*", 9, 9));
+    }
+
+    #[test]
+    fn test_charsets() {
+        assert!(is_supported_charset("utf-8"));
+        assert!(is_supported_charset("US-ASCII"));
+        assert!(!is_supported_charset("ISO-8859-1"));
+        assert_eq!(
+            bad_charset("A1"),
+            "A1 NO [BADCHARSET (US-ASCII UTF-8)] Unsupported charset\r\n"
+        );
+    }
+}
@@ -1,42 +1,342 @@
 //! IMAP server implementation
 //!
-//! Handles TCP connections and IMAP protocol
+//! Handles TCP connections and IMAP protocol, in plaintext with optional
+//! STARTTLS or with implicit TLS (IMAPS), and deflate-compressed after
+//! COMPRESS
 
 use crate::config::Config;
 use crate::error::MailError;
+use crate::imap::compress::DeflateStream;
+use crate::imap::proxy;
 use crate::imap::{ImapCommand, ImapSession, SessionState};
-use crate::security::Authenticator;
+use crate::devices::DeviceManager;
+use crate::login_anomaly::LoginAnomalyDetector;
+use crate::mfa::MfaManager;
+use crate::openpgp::OpenPgpManager;
+use crate::migration::MigrationManager;
+use crate::quota::QuotaManager;
+use crate::recovery::ReadOnlyMode;
+use crate::reporting::ReportingManager;
+use crate::residency::ResidencyMap;
+use crate::security::proxy_protocol::{is_trusted_proxy, read_proxy_header};
+use crate::security::{
+    Authenticator, BandwidthLimiter, OAuthValidator, ThrottledWriter, TlsConfig,
+};
+use crate::smtp::server::{build_oauth_validator, build_reporting_manager};
+use crate::spam::SpamManager;
+use crate::storage::{FlagEventBus, MetadataIndex};
+use sqlx::SqlitePool;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+    ReadHalf, WriteHalf,
+};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::MissedTickBehavior;
+use tokio_rustls::server::TlsStream;
 use tracing::{debug, error, info, warn};
 
+/// Largest literal accepted in a SEARCH command
+const MAX_SEARCH_LITERAL: usize = 64 * 1024;
+
+/// Plain or TLS connection, so STARTTLS can swap one for the other, and
+/// COMPRESS can wrap either
+enum ImapStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Compressed(Box<DeflateStream<ImapStream>>),
+}
+
+impl AsyncRead for ImapStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ImapStream::Compressed(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ImapStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ImapStream::Compressed(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ImapStream::Compressed(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            ImapStream::Compressed(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+type ImapReader = BufReader<ReadHalf<ImapStream>>;
+type ImapWriter = ThrottledWriter<WriteHalf<ImapStream>>;
+
 /// IMAP server
 pub struct ImapServer {
     config: Arc<Config>,
+    /// Shared authenticator; each connection opens its own if unset
+    authenticator: Option<Authenticator>,
+    /// Region-specific maildir roots
+    residency: Option<Arc<ResidencyMap>>,
+    /// Storage quotas reported and enforced over IMAP
+    quotas: Option<Arc<QuotaManager>>,
+    /// Flag changes shared with the other frontends
+    flag_events: Option<Arc<FlagEventBus>>,
+    /// Message metadata answering STATUS and LIST-STATUS
+    metadata: Option<Arc<MetadataIndex>>,
+    /// Download limits; responses are written unthrottled if unset
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    /// Certificates for STARTTLS, or for every connection with implicit TLS
+    tls: Option<Arc<TlsConfig>>,
+    /// Handshake before the greeting (IMAPS) instead of offering STARTTLS
+    implicit_tls: bool,
+    /// Two-factor state of users
+    mfa: Option<Arc<MfaManager>>,
+    /// Client devices and app passwords of users
+    devices: Option<Arc<DeviceManager>>,
+    /// Assesses logins against the places users signed in from
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    /// Classifier trained on messages moved into or out of Junk
+    spam_training: Option<Arc<SpamManager>>,
+    /// OpenPGP keys that encrypted messages are shown decrypted with
+    openpgp: Option<Arc<OpenPgpManager>>,
+    /// Refuses changes while the server is read-only
+    read_only: Arc<ReadOnlyMode>,
 }
 
 impl ImapServer {
     /// Create a new IMAP server
     pub fn new(config: Arc<Config>) -> Self {
-        Self { config }
+        let read_only = Arc::new(ReadOnlyMode::from_config(&config.recovery));
+        Self {
+            config,
+            authenticator: None,
+            residency: None,
+            quotas: None,
+            flag_events: None,
+            metadata: None,
+            bandwidth: None,
+            tls: None,
+            implicit_tls: false,
+            mfa: None,
+            devices: None,
+            login_anomalies: None,
+            spam_training: None,
+            openpgp: None,
+            read_only,
+        }
+    }
+
+    /// Authenticate every connection against this authenticator
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Resolve mailbox locations through region assignments
+    pub fn with_residency(mut self, residency: Arc<ResidencyMap>) -> Self {
+        self.residency = Some(residency);
+        self
+    }
+
+    /// Report these quotas with the QUOTA extension and enforce them on
+    /// APPEND
+    pub fn with_quotas(mut self, quotas: Arc<QuotaManager>) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Share flag changes with the other frontends through this bus
+    pub fn with_flag_events(mut self, bus: Arc<FlagEventBus>) -> Self {
+        self.flag_events = Some(bus);
+        self
+    }
+
+    /// Answer STATUS and LIST-STATUS counts from the metadata index
+    pub fn with_metadata(mut self, index: Arc<MetadataIndex>) -> Self {
+        self.metadata = Some(index);
+        self
+    }
+
+    /// Hold downloads to the limiter's global, per-IP and per-user rates
+    pub fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> Self {
+        self.bandwidth = Some(limiter);
+        self
+    }
+
+    /// Require the TOTP code of users with two-factor authentication
+    /// enabled on password logins (`password:code`)
+    pub fn with_mfa(mut self, mfa: Arc<MfaManager>) -> Self {
+        self.mfa = Some(mfa);
+        self
+    }
+
+    /// Record the client devices of users and accept their app passwords
+    pub fn with_devices(mut self, devices: Arc<DeviceManager>) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Refuse logins from unusual locations while the anomaly detector
+    /// blocks them
+    pub fn with_login_anomalies(mut self, detector: Arc<LoginAnomalyDetector>) -> Self {
+        self.login_anomalies = Some(detector);
+        self
+    }
+
+    /// Train the spam classifier on messages users copy into or out of
+    /// their Junk folder
+    pub fn with_spam_training(mut self, spam: Arc<SpamManager>) -> Self {
+        self.spam_training = Some(spam);
+        self
+    }
+
+    /// Show encrypted messages decrypted with the private keys users
+    /// uploaded
+    pub fn with_openpgp(mut self, manager: Arc<OpenPgpManager>) -> Self {
+        self.openpgp = Some(manager);
+        self
+    }
+
+    /// Share this read-only flag, e.g. the one the API toggles, instead of
+    /// a flag of its own
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = mode;
+        self
+    }
+
+    /// Offer STARTTLS with these certificates; LOGIN is refused before it
+    pub fn with_tls(mut self, tls: Arc<TlsConfig>) -> Self {
+        self.tls = Some(tls);
+        self.implicit_tls = false;
+        self
+    }
+
+    /// Serve IMAPS: every connection starts with a TLS handshake
+    pub fn with_implicit_tls(mut self, tls: Arc<TlsConfig>) -> Self {
+        self.tls = Some(tls);
+        self.implicit_tls = true;
+        self
     }
 
     /// Start the IMAP server
     pub async fn start(&self) -> Result<(), MailError> {
-        let addr = &self.config.imap.listen_addr;
+        let addr = if self.implicit_tls {
+            &self.config.imap.imaps_listen_addr
+        } else {
+            &self.config.imap.listen_addr
+        };
         let listener = TcpListener::bind(addr).await?;
+        self.serve(listener).await
+    }
 
-        info!("🌐 IMAP server listening on {}", addr);
+    /// Accept connections on an already bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), MailError> {
+        if self.implicit_tls {
+            info!("🌐 IMAPS server listening on {}", listener.local_addr()?);
+        } else {
+            info!("🌐 IMAP server listening on {}", listener.local_addr()?);
+            if self.tls.is_some() {
+                info!("STARTTLS enabled, LOGIN refused before it");
+            }
+        }
+
+        let coexistence = self.coexistence().await?;
+        let components = SessionComponents {
+            oauth_validator: build_oauth_validator(&self.config),
+            reporting: build_reporting_manager(&self.config).await?,
+            authenticator: self.authenticator.clone(),
+            residency: self.residency.clone(),
+            quotas: self.quotas.clone(),
+            flag_events: self.flag_events.clone(),
+            metadata: self.metadata.clone(),
+            bandwidth: self.bandwidth.clone(),
+            tls: self.tls.clone(),
+            mfa: self.mfa.clone(),
+            devices: self.devices.clone(),
+            login_anomalies: self.login_anomalies.clone(),
+            spam_training: self.spam_training.clone(),
+            openpgp: self.openpgp.clone(),
+            read_only: self.read_only.clone(),
+        };
+        let implicit_tls = self.implicit_tls;
 
         loop {
             match listener.accept().await {
-                Ok((stream, peer_addr)) => {
+                Ok((mut stream, peer_addr)) => {
                     info!("📨 New IMAP connection from {}", peer_addr);
                     let config = Arc::clone(&self.config);
+                    let coexistence = coexistence.clone();
+                    let components = components.clone();
+
+                    let proxy_protocol = config.imap.proxy_protocol;
+                    if proxy_protocol
+                        && !is_trusted_proxy(peer_addr.ip(), &config.imap.proxy_trusted_ips)
+                    {
+                        warn!("Rejecting PROXY connection from untrusted peer {}", peer_addr);
+                        continue;
+                    }
 
                     tokio::spawn(async move {
-                        if let Err(e) = handle_connection(stream, config).await {
+                        let client_addr = if proxy_protocol {
+                            match read_proxy_header(&mut stream).await {
+                                Ok(header) => header.client_addr(peer_addr),
+                                Err(e) => {
+                                    warn!("Invalid PROXY header from {}: {}", peer_addr, e);
+                                    return;
+                                }
+                            }
+                        } else {
+                            peer_addr
+                        };
+
+                        let stream = match (&components.tls, implicit_tls) {
+                            (Some(tls), true) => match tls.acceptor().accept(stream).await {
+                                Ok(stream) => ImapStream::Tls(Box::new(stream)),
+                                Err(e) => {
+                                    warn!("TLS handshake with {} failed: {}", client_addr, e);
+                                    return;
+                                }
+                            },
+                            _ => ImapStream::Plain(stream),
+                        };
+
+                        if let Err(e) = handle_connection(
+                            stream,
+                            client_addr,
+                            config,
+                            coexistence,
+                            components,
+                        )
+                        .await
+                        {
                             error!("Error handling IMAP connection: {}", e);
                         }
                     });
@@ -49,11 +349,80 @@ impl ImapServer {
     }
 }
 
+impl ImapServer {
+    /// Set up migration coexistence if enabled
+    async fn coexistence(&self) -> Result<Option<Coexistence>, MailError> {
+        let migration = &self.config.migration;
+        if !migration.coexistence {
+            return Ok(None);
+        }
+
+        let legacy_addr = migration.legacy_imap_addr.clone().ok_or_else(|| {
+            MailError::Config("migration.coexistence requires legacy_imap_addr".to_string())
+        })?;
+
+        // Flags are managed through the admin API, read them from its database
+        let db = SqlitePool::connect(&self.config.api_database_url()).await?;
+        let manager = MigrationManager::new(db);
+        manager
+            .init_db()
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))?;
+
+        info!("Migration coexistence enabled, legacy IMAP server: {}", legacy_addr);
+        Ok(Some(Coexistence {
+            manager: Arc::new(manager),
+            legacy_addr: Arc::new(legacy_addr),
+        }))
+    }
+}
+
+/// Migration coexistence state shared by connections
+#[derive(Clone)]
+struct Coexistence {
+    manager: Arc<MigrationManager>,
+    legacy_addr: Arc<String>,
+}
+
+/// Optional components every session is built with
+#[derive(Clone)]
+struct SessionComponents {
+    oauth_validator: Option<Arc<OAuthValidator>>,
+    reporting: Option<Arc<ReportingManager>>,
+    /// Shared authenticator; each connection opens its own if unset
+    authenticator: Option<Authenticator>,
+    residency: Option<Arc<ResidencyMap>>,
+    quotas: Option<Arc<QuotaManager>>,
+    flag_events: Option<Arc<FlagEventBus>>,
+    metadata: Option<Arc<MetadataIndex>>,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    /// Certificates for STARTTLS
+    tls: Option<Arc<TlsConfig>>,
+    mfa: Option<Arc<MfaManager>>,
+    devices: Option<Arc<DeviceManager>>,
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    spam_training: Option<Arc<SpamManager>>,
+    openpgp: Option<Arc<OpenPgpManager>>,
+    read_only: Arc<ReadOnlyMode>,
+}
+
 /// Handle a single IMAP connection
-async fn handle_connection(stream: TcpStream, config: Arc<Config>) -> Result<(), MailError> {
-    let peer_addr = stream.peer_addr()?;
-    let (reader, mut writer) = stream.into_split();
+///
+/// `peer_addr` is the real client address (from the PROXY header when enabled).
+async fn handle_connection(
+    stream: ImapStream,
+    peer_addr: SocketAddr,
+    config: Arc<Config>,
+    coexistence: Option<Coexistence>,
+    components: SessionComponents,
+) -> Result<(), MailError> {
+    let encrypted = matches!(stream, ImapStream::Tls(_));
+    let (reader, writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
+    let throttle = components
+        .bandwidth
+        .map(|limiter| limiter.connect("imap", Some(peer_addr.ip())));
+    let mut writer = ThrottledWriter::new(writer, throttle);
 
     // Send greeting
     writer
@@ -61,16 +430,71 @@ async fn handle_connection(stream: TcpStream, config: Arc<Config>) -> Result<(),
         .await?;
 
     // Create session
-    let authenticator = Authenticator::new(&config.storage.database_url).await?;
+    let authenticator = match components.authenticator {
+        Some(authenticator) => authenticator,
+        None => Authenticator::new(&config.storage.database_url)
+            .await?
+            .with_cram_md5(config.smtp.allow_cram_md5),
+    };
     let mut session = ImapSession::new(authenticator, config.storage.maildir_path.clone());
+    if let Some(validator) = components.oauth_validator {
+        session = session.with_oauth(validator);
+    }
+    if let Some(reporting) = components.reporting {
+        session = session.with_reporting(reporting);
+    }
+    if let Some(residency) = components.residency {
+        session = session.with_residency(residency);
+    }
+    if let Some(quotas) = components.quotas {
+        session = session.with_quotas(quotas, config.imap.quota_admins.clone());
+    }
+    if let Some(bus) = components.flag_events {
+        session = session.with_flag_events(bus);
+    }
+    if let Some(index) = components.metadata {
+        session = session.with_metadata(index);
+    }
+    if let Some(mfa) = components.mfa {
+        session = session.with_mfa(mfa);
+    }
+    if let Some(devices) = components.devices {
+        session = session.with_devices(devices);
+    }
+    if let Some(detector) = components.login_anomalies {
+        session = session.with_login_anomalies(detector);
+    }
+    if let Some(spam) = components.spam_training {
+        session = session.with_spam_training(spam);
+    }
+    if let Some(openpgp) = components.openpgp {
+        session = session.with_openpgp(openpgp);
+    }
+    session = session.with_read_only(components.read_only);
+    session = session.with_client_ip(peer_addr.ip());
+    session = session.with_idle_poll_interval(Duration::from_secs(
+        config.imap.idle_poll_interval_secs.max(1),
+    ));
+    session = session.with_folder_provisioning(config.imap.auto_create_folders);
+    session = session.with_compression(config.imap.enable_compress);
+    if encrypted {
+        session.set_encrypted();
+    } else if components.tls.is_some() {
+        session = session.with_starttls();
+    }
 
     let mut line = String::new();
 
     loop {
         line.clear();
 
-        // Read command
-        match reader.read_line(&mut line).await {
+        // Read command; while idling, push mailbox changes until DONE
+        let read = if session.is_idle() {
+            read_line_idling(&mut session, &mut reader, &mut writer, &mut line).await
+        } else {
+            reader.read_line(&mut line).await.map_err(MailError::from)
+        };
+        match read {
             Ok(0) => {
                 // Connection closed
                 info!("Connection closed by {}", peer_addr);
@@ -79,15 +503,134 @@ async fn handle_connection(stream: TcpStream, config: Arc<Config>) -> Result<(),
             Ok(_) => {
                 debug!("Received from {}: {}", peer_addr, line.trim());
 
+                // The mailbox is being moved to another storage backend
+                if session.mailbox_locked() {
+                    info!("Closing {}: mailbox migration in progress", peer_addr);
+                    writer
+                        .write_all(b"* BYE [UNAVAILABLE] Mailbox migration in progress\r\n")
+                        .await?;
+                    break;
+                }
+
+                // AUTHENTICATE continuation lines carry no tag
+                if session.awaiting_auth_response() {
+                    let response = session.handle_auth_response(&line).await?;
+                    writer.write_all(response.as_bytes()).await?;
+                    continue;
+                }
+
+                // SEARCH strings may be sent as literals, e.g. UTF-8 text
+                if is_search(&line) {
+                    if let Err(e) = inline_literals(&mut reader, &mut writer, &mut line).await {
+                        let tag = line.split_whitespace().next().unwrap_or("*");
+                        writer
+                            .write_all(format!("{} BAD {}\r\n", tag, e).as_bytes())
+                            .await?;
+                        continue;
+                    }
+                }
+
                 // Parse command
                 match ImapCommand::parse(&line) {
                     Ok((tag, command)) => {
+                        // Not-yet-migrated users are served by the legacy server,
+                        // once LOGIN is allowed: the session refuses it before STARTTLS
+                        if let (Some(coexistence), ImapCommand::Login { username, password }) =
+                            (&coexistence, &command)
+                        {
+                            if matches!(session.state(), SessionState::NotAuthenticated)
+                                && !session.login_disabled()
+                                && !coexistence
+                                    .manager
+                                    .is_user_migrated(username)
+                                    .await
+                                    .map_err(|e| MailError::Storage(e.to_string()))?
+                            {
+                                return proxy_to_legacy(
+                                    reader,
+                                    writer.into_inner(),
+                                    &tag,
+                                    username,
+                                    password,
+                                    coexistence,
+                                    peer_addr,
+                                )
+                                .await;
+                            }
+                        }
+
+                        // APPEND is followed by the message as a literal
+                        let command = match command {
+                            ImapCommand::Append {
+                                mailbox,
+                                flags,
+                                internal_date,
+                                size,
+                                ..
+                            } => {
+                                if !session.is_authenticated() {
+                                    writer
+                                        .write_all(
+                                            format!("{} BAD Command not allowed in current state\r\n", tag)
+                                                .as_bytes(),
+                                        )
+                                        .await?;
+                                    continue;
+                                }
+                                if size > config.smtp.max_message_size {
+                                    writer
+                                        .write_all(
+                                            format!("{} NO [TOOBIG] Message too large\r\n", tag)
+                                                .as_bytes(),
+                                        )
+                                        .await?;
+                                    continue;
+                                }
+                                writer.write_all(b"+ Ready for literal data\r\n").await?;
+                                let message = read_literal(&mut reader, size).await?;
+                                ImapCommand::Append {
+                                    mailbox,
+                                    flags,
+                                    internal_date,
+                                    size,
+                                    message,
+                                }
+                            }
+                            command => command,
+                        };
+
                         // Handle command
+                        let starttls = command == ImapCommand::StartTls;
+                        let compress = matches!(command, ImapCommand::Compress { .. });
                         match session.handle_command(tag.clone(), command).await {
                             Ok(response) => {
+                                // Count the rest of the connection against the user's limit
+                                if let (Some(throttle), Some(username)) =
+                                    (writer.throttle(), session.username())
+                                {
+                                    if !throttle.has_user() {
+                                        throttle.set_user(username);
+                                    }
+                                }
                                 debug!("Sending to {}: {}", peer_addr, response.trim());
                                 writer.write_all(response.as_bytes()).await?;
 
+                                if starttls && response.starts_with(&format!("{} OK", tag)) {
+                                    let Some(tls) = &components.tls else {
+                                        break;
+                                    };
+                                    (reader, writer) =
+                                        start_tls(reader, writer, tls, peer_addr).await?;
+                                    session.set_encrypted();
+                                    continue;
+                                }
+                                if compress && response.starts_with(&format!("{} OK", tag)) {
+                                    (reader, writer) =
+                                        start_compression(reader, writer, peer_addr).await?;
+                                    session.set_compressed();
+                                    continue;
+                                }
+
                                 // Check if we should close connection
                                 if matches!(session.state(), SessionState::Logout) {
                                     info!("Logging out connection from {}", peer_addr);
@@ -120,3 +663,201 @@ async fn handle_connection(stream: TcpStream, config: Arc<Config>) -> Result<(),
     info!("IMAP connection from {} closed", peer_addr);
     Ok(())
 }
+
+/// Upgrade the connection after the STARTTLS OK was sent
+///
+/// Input the client pipelined after STARTTLS is dropped, so plaintext
+/// commands cannot be injected into the encrypted session.
+async fn start_tls(
+    reader: ImapReader,
+    mut writer: ImapWriter,
+    tls: &TlsConfig,
+    peer_addr: SocketAddr,
+) -> Result<(ImapReader, ImapWriter), MailError> {
+    writer.flush().await?;
+    if !reader.buffer().is_empty() {
+        warn!("Discarding input pipelined after STARTTLS by {}", peer_addr);
+    }
+
+    let throttle = writer.throttle().cloned();
+    let tcp = match reader.into_inner().unsplit(writer.into_inner()) {
+        ImapStream::Plain(tcp) => tcp,
+        ImapStream::Tls(_) | ImapStream::Compressed(_) => {
+            return Err(MailError::ImapProtocol("TLS already active".to_string()))
+        }
+    };
+    let stream = tls.acceptor().accept(tcp).await.map_err(|e| {
+        warn!("STARTTLS handshake with {} failed: {}", peer_addr, e);
+        MailError::Tls(format!("TLS handshake failed: {}", e))
+    })?;
+    info!("STARTTLS completed for {}", peer_addr);
+
+    let (reader, writer) = tokio::io::split(ImapStream::Tls(Box::new(stream)));
+    Ok((BufReader::new(reader), ThrottledWriter::new(writer, throttle)))
+}
+
+/// Compress both directions after the COMPRESS OK was sent (RFC 4978)
+///
+/// Input the client sent after the command is already compressed, so
+/// whatever the line reader buffered is inflated first.
+async fn start_compression(
+    reader: ImapReader,
+    mut writer: ImapWriter,
+    peer_addr: SocketAddr,
+) -> Result<(ImapReader, ImapWriter), MailError> {
+    writer.flush().await?;
+    let received = reader.buffer().to_vec();
+
+    let throttle = writer.throttle().cloned();
+    let stream = reader.into_inner().unsplit(writer.into_inner());
+    info!("COMPRESS=DEFLATE active for {}", peer_addr);
+
+    let stream = ImapStream::Compressed(Box::new(DeflateStream::new(stream, &received)));
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((BufReader::new(reader), ThrottledWriter::new(writer, throttle)))
+}
+
+/// Read the next client line while the session idles
+///
+/// Untagged updates are sent whenever the watcher reports a change to the
+/// selected mailbox, and after every poll interval in case it missed one.
+async fn read_line_idling<W: AsyncWrite + Unpin>(
+    session: &mut ImapSession,
+    reader: &mut ImapReader,
+    writer: &mut W,
+    line: &mut String,
+) -> Result<usize, MailError> {
+    let mut buf = Vec::new();
+    let mut poll = tokio::time::interval(session.idle_poll_interval());
+    poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    poll.tick().await;
+
+    loop {
+        // read_until keeps partially read bytes in buf when cancelled
+        tokio::select! {
+            read = reader.read_until(b'\n', &mut buf) => {
+                read?;
+                line.push_str(&String::from_utf8_lossy(&buf));
+                return Ok(buf.len());
+            }
+            _ = session.idle_changed() => {}
+            _ = poll.tick() => {}
+        }
+
+        let updates = session.mailbox_updates()?;
+        if !updates.is_empty() {
+            debug!("Sending IDLE updates: {}", updates.trim());
+            writer.write_all(updates.as_bytes()).await?;
+        }
+    }
+}
+
+/// Whether a command line is SEARCH or UID SEARCH
+fn is_search(line: &str) -> bool {
+    let mut words = line.split_whitespace().skip(1).map(str::to_uppercase);
+    match words.next().as_deref() {
+        Some("SEARCH") => true,
+        Some("UID") => words.next().as_deref() == Some("SEARCH"),
+        _ => false,
+    }
+}
+
+/// Size of the literal announced at the end of a command line
+fn literal_size(line: &str) -> Option<usize> {
+    let line = line.trim_end();
+    let start = line.strip_suffix('}')?.rfind('{')?;
+    line[start + 1..line.len() - 1].parse().ok()
+}
+
+/// Read the literals a command line announces and inline each as a quoted
+/// string, so the command parser sees a single line
+async fn inline_literals(
+    reader: &mut ImapReader,
+    writer: &mut ImapWriter,
+    line: &mut String,
+) -> Result<(), MailError> {
+    while let Some(size) = literal_size(line) {
+        if size > MAX_SEARCH_LITERAL {
+            return Err(MailError::ImapProtocol("Literal too large".to_string()));
+        }
+        writer.write_all(b"+ Ready for literal data\r\n").await?;
+
+        let mut literal = vec![0u8; size];
+        reader.read_exact(&mut literal).await?;
+        let literal = String::from_utf8(literal)
+            .map_err(|_| MailError::ImapProtocol("Literal is not UTF-8".to_string()))?;
+        let mut rest = String::new();
+        reader.read_line(&mut rest).await?;
+
+        let start = line.trim_end().rfind('{').unwrap_or(line.len());
+        line.truncate(start);
+        line.push('"');
+        line.push_str(&literal.replace('\\', "\\\\").replace('"', "\\\""));
+        line.push('"');
+        line.push_str(&rest);
+    }
+    Ok(())
+}
+
+/// Read a `size`-byte literal and the rest of its command line
+async fn read_literal(
+    reader: &mut ImapReader,
+    size: usize,
+) -> Result<Vec<u8>, MailError> {
+    let mut literal = vec![0u8; size];
+    reader.read_exact(&mut literal).await?;
+
+    let mut rest = String::new();
+    reader.read_line(&mut rest).await?;
+    if !rest.trim().is_empty() {
+        return Err(MailError::ImapProtocol(
+            "Unexpected data after APPEND literal".to_string(),
+        ));
+    }
+    Ok(literal)
+}
+
+/// Replay LOGIN against the legacy server and relay the connection
+///
+/// On rejected credentials the client gets a NO and the connection is
+/// closed, as the legacy server is authoritative for this user.
+async fn proxy_to_legacy(
+    reader: ImapReader,
+    mut writer: WriteHalf<ImapStream>,
+    tag: &str,
+    username: &str,
+    password: &str,
+    coexistence: &Coexistence,
+    peer_addr: SocketAddr,
+) -> Result<(), MailError> {
+    info!("Proxying {} from {} to legacy server", username, peer_addr);
+
+    match proxy::login_upstream(&coexistence.legacy_addr, username, password).await {
+        Ok(Some(upstream)) => {
+            writer
+                .write_all(format!("{} OK LOGIN completed\r\n", tag).as_bytes())
+                .await?;
+
+            let pending = reader.buffer().to_vec();
+            let client = reader.into_inner().unsplit(writer);
+            proxy::relay(client, &pending, upstream).await
+        }
+        Ok(None) => {
+            info!("Legacy LOGIN failed for: {} (invalid credentials)", username);
+            writer
+                .write_all(format!("{} NO LOGIN failed - invalid credentials\r\n", tag).as_bytes())
+                .await?;
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Legacy server unavailable for {}: {}", username, e);
+            writer
+                .write_all(
+                    format!("{} NO [UNAVAILABLE] Mailbox temporarily unavailable\r\n", tag)
+                        .as_bytes(),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+}
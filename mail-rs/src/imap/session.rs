@@ -2,12 +2,38 @@
 //!
 //! Handles IMAP protocol state machine and command execution
 
+use crate::devices::{DeviceKind, DeviceManager};
 use crate::error::MailError;
+use crate::imap::idle::DEFAULT_POLL_INTERVAL;
+use crate::imap::bodystructure::{body_structure, envelope};
+use crate::imap::compress;
+use crate::imap::fetch::{self, literal, FetchAttribute};
+use crate::imap::mailbox::{CopiedMessages, EmailMessage};
+use crate::imap::search;
+use crate::imap::special_use::{self, SpecialUse};
+use crate::imap::subscriptions;
 use crate::imap::{IdleWatcher, ImapCommand, Mailbox, SearchCriteria, StoreOperation};
-use crate::security::Authenticator;
-use std::path::Path;
-use std::time::Duration;
-use tracing::{debug, info};
+use crate::login_anomaly::{Decision, LoginAnomalyDetector, LoginProtocol};
+use crate::mfa::{MfaManager, MfaVerifyResult};
+use crate::mime::MimeParser;
+use crate::openpgp::{mime as pgp_mime, Keyring, OpenPgpManager};
+use crate::quota::{maildirsize, QuotaManager, QuotaStatus, UserQuota};
+use crate::recovery::ReadOnlyMode;
+use crate::reporting::{ReportingManager, UsageEventKind};
+use crate::residency::ResidencyMap;
+use crate::security::oauth::{self, OAuthValidator};
+use crate::security::sasl::{self, ScramServer};
+use crate::security::{AuthMechanism, Authenticator};
+use crate::spam::{SpamClass, SpamManager};
+use crate::storage::maildir::is_mailbox_locked;
+use crate::storage::{FlagEvent, FlagEventBus, FlagSource, MetadataIndex};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
 
 /// IMAP session states
 #[derive(Debug, Clone, PartialEq)]
@@ -25,6 +51,38 @@ pub enum SessionState {
     Logout,
 }
 
+/// Progress of a multi-step AUTHENTICATE exchange
+enum PendingAuth {
+    /// Waiting for the client's SASL response
+    Response { tag: String, mechanism: AuthMechanism },
+    /// Error challenge sent, waiting for the client to acknowledge it
+    ErrorAck { tag: String },
+    /// CRAM-MD5 challenge sent, waiting for `username digest`
+    CramMd5 { tag: String, challenge: String },
+    /// SCRAM server-first sent, waiting for the client-final message
+    ScramFinal { tag: String, scram: Box<ScramServer> },
+    /// SCRAM server-final sent, waiting for the client's empty response
+    ScramAck { tag: String, username: String },
+    /// LOGIN `Username:` prompt sent
+    LoginUsername { tag: String },
+    /// LOGIN `Password:` prompt sent
+    LoginPassword { tag: String, username: String },
+}
+
+impl PendingAuth {
+    fn tag(self) -> String {
+        match self {
+            PendingAuth::Response { tag, .. }
+            | PendingAuth::ErrorAck { tag }
+            | PendingAuth::CramMd5 { tag, .. }
+            | PendingAuth::ScramFinal { tag, .. }
+            | PendingAuth::ScramAck { tag, .. }
+            | PendingAuth::LoginUsername { tag }
+            | PendingAuth::LoginPassword { tag, .. } => tag,
+        }
+    }
+}
+
 /// IMAP session
 pub struct ImapSession {
     /// Current state
@@ -37,6 +95,68 @@ pub struct ImapSession {
     current_mailbox: Option<Mailbox>,
     /// IDLE mode tag (if in IDLE mode)
     idle_tag: Option<String>,
+    /// Watches the selected mailbox while idling
+    idle_watcher: Option<IdleWatcher>,
+    /// Rescan interval while idling, in case notifications are missed
+    idle_poll_interval: Duration,
+    /// OAuth token validator (enables OAUTHBEARER and XOAUTH2)
+    oauth_validator: Option<Arc<OAuthValidator>>,
+    /// AUTHENTICATE exchange waiting for a continuation line
+    pending_auth: Option<PendingAuth>,
+    /// Usage reporting (records failed logins)
+    reporting: Option<Arc<ReportingManager>>,
+    /// Region-specific maildir roots
+    residency: Option<Arc<ResidencyMap>>,
+    /// Create the special-use folders on first login
+    provision_folders: bool,
+    /// Storage quotas (enables the QUOTA extension)
+    quotas: Option<Arc<QuotaManager>>,
+    /// Users allowed to change quotas and read other users' quotas
+    quota_admins: Vec<String>,
+    /// Flag changes shared with the other frontends
+    flag_events: Option<Arc<FlagEventBus>>,
+    /// Flag changes received while idling
+    idle_events: Option<broadcast::Receiver<FlagEvent>>,
+    /// Message metadata answering STATUS counts without opening folders
+    metadata: Option<Arc<MetadataIndex>>,
+    /// STARTTLS is offered on this connection
+    starttls: bool,
+    /// The connection is encrypted (implicit TLS or after STARTTLS)
+    encrypted: bool,
+    /// COMPRESS=DEFLATE is offered on this connection
+    compression: bool,
+    /// COMPRESS completed
+    compressed: bool,
+    /// Two-factor state of users; passwords then need a TOTP code
+    mfa: Option<Arc<MfaManager>>,
+    /// Client devices and app passwords of users
+    devices: Option<Arc<DeviceManager>>,
+    /// Assesses logins against the places users signed in from
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    /// Classifier trained on messages copied into or out of Junk
+    spam_training: Option<Arc<SpamManager>>,
+    /// OpenPGP keys of users; encrypted messages are fetched decrypted
+    openpgp: Option<Arc<OpenPgpManager>>,
+    /// Private keys of the user, loaded on SELECT
+    keyring: Option<Arc<Keyring>>,
+    /// Refuses changes while the server is read-only
+    read_only: Option<Arc<ReadOnlyMode>>,
+    /// Address of the client
+    client_ip: Option<IpAddr>,
+    /// Client name and version from ID
+    client_name: Option<String>,
+    /// Logged in, but the device is not recorded yet since the client may
+    /// still send ID
+    device_pending: bool,
+}
+
+/// How a password login was verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Credential {
+    /// The account password, with its second factor if enabled
+    Account,
+    /// An app password of one of the user's devices
+    AppPassword,
 }
 
 impl ImapSession {
@@ -48,14 +168,459 @@ impl ImapSession {
             maildir_root,
             current_mailbox: None,
             idle_tag: None,
+            idle_watcher: None,
+            idle_poll_interval: DEFAULT_POLL_INTERVAL,
+            oauth_validator: None,
+            pending_auth: None,
+            reporting: None,
+            residency: None,
+            provision_folders: true,
+            quotas: None,
+            quota_admins: Vec::new(),
+            flag_events: None,
+            idle_events: None,
+            metadata: None,
+            starttls: false,
+            encrypted: false,
+            compression: false,
+            compressed: false,
+            mfa: None,
+            devices: None,
+            login_anomalies: None,
+            spam_training: None,
+            openpgp: None,
+            keyring: None,
+            read_only: None,
+            client_ip: None,
+            client_name: None,
+            device_pending: false,
+        }
+    }
+
+    /// Enable OAuth bearer token authentication
+    pub fn with_oauth(mut self, validator: Arc<OAuthValidator>) -> Self {
+        self.oauth_validator = Some(validator);
+        self
+    }
+
+    /// Record failed logins for usage reports
+    pub fn with_reporting(mut self, reporting: Arc<ReportingManager>) -> Self {
+        self.reporting = Some(reporting);
+        self
+    }
+
+    /// Resolve mailbox locations through region assignments
+    pub fn with_residency(mut self, residency: Arc<ResidencyMap>) -> Self {
+        self.residency = Some(residency);
+        self
+    }
+
+    /// Rescan the selected mailbox this often while idling
+    pub fn with_idle_poll_interval(mut self, interval: Duration) -> Self {
+        self.idle_poll_interval = interval;
+        self
+    }
+
+    /// Offer STARTTLS, and refuse LOGIN (LOGINDISABLED) until the
+    /// connection is encrypted
+    pub fn with_starttls(mut self) -> Self {
+        self.starttls = true;
+        self
+    }
+
+    /// Mark the connection as encrypted, on the implicit TLS port or after
+    /// STARTTLS completed
+    pub fn set_encrypted(&mut self) {
+        self.encrypted = true;
+    }
+
+    /// Check whether the connection is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Require the TOTP code of users with two-factor authentication
+    /// enabled, appended to the password as `password:code`
+    ///
+    /// Backup codes are accepted in place of the TOTP code. CRAM-MD5 and
+    /// SCRAM-SHA-256 cannot carry a code and are refused for these users.
+    pub fn with_mfa(mut self, mfa: Arc<MfaManager>) -> Self {
+        self.mfa = Some(mfa);
+        self
+    }
+
+    /// Record client devices of users and accept their app passwords in
+    /// place of the account password
+    pub fn with_devices(mut self, devices: Arc<DeviceManager>) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Refuse logins from unusual locations while the anomaly detector
+    /// blocks them
+    pub fn with_login_anomalies(mut self, detector: Arc<LoginAnomalyDetector>) -> Self {
+        self.login_anomalies = Some(detector);
+        self
+    }
+
+    /// Train the spam classifier on messages copied into the Junk folder
+    /// as spam, and out of it (except to Trash) as ham
+    pub fn with_spam_training(mut self, spam: Arc<SpamManager>) -> Self {
+        self.spam_training = Some(spam);
+        self
+    }
+
+    /// Fetch encrypted messages decrypted with the private keys the user
+    /// uploaded
+    pub fn with_openpgp(mut self, manager: Arc<OpenPgpManager>) -> Self {
+        self.openpgp = Some(manager);
+        self
+    }
+
+    /// Refuse commands changing mailboxes, and open them READ-ONLY, while
+    /// the server is in read-only mode
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(mode);
+        self
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.as_ref().is_some_and(|mode| mode.is_read_only())
+    }
+
+    /// Address of the client, for device records and sign-in alerts
+    pub fn with_client_ip(mut self, ip: IpAddr) -> Self {
+        self.client_ip = Some(ip);
+        self
+    }
+
+    /// Offer COMPRESS=DEFLATE (RFC 4978)
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// COMPRESS completed; the connection is deflate-compressed from now on
+    pub fn set_compressed(&mut self) {
+        self.compressed = true;
+    }
+
+    /// Plaintext passwords are refused until STARTTLS
+    pub(crate) fn login_disabled(&self) -> bool {
+        self.starttls && !self.encrypted
+    }
+
+    /// Create the standard special-use folders on first login (default on)
+    pub fn with_folder_provisioning(mut self, enabled: bool) -> Self {
+        self.provision_folders = enabled;
+        self
+    }
+
+    /// Report and enforce storage quotas (RFC 2087); `admins` may change
+    /// quotas with SETQUOTA and address other users' quota roots by address
+    pub fn with_quotas(mut self, quotas: Arc<QuotaManager>, admins: Vec<String>) -> Self {
+        self.quotas = Some(quotas);
+        self.quota_admins = admins;
+        self
+    }
+
+    /// Publish flag changes and expunges, and wake up IDLE on changes
+    /// published by other frontends
+    pub fn with_flag_events(mut self, bus: Arc<FlagEventBus>) -> Self {
+        self.flag_events = Some(bus);
+        self
+    }
+
+    /// Answer STATUS and LIST-STATUS counts from the metadata index
+    pub fn with_metadata(mut self, index: Arc<MetadataIndex>) -> Self {
+        self.metadata = Some(index);
+        self
+    }
+
+    /// Maildir root holding the mailbox of `username`
+    fn root(&self, username: &str) -> PathBuf {
+        let root = Path::new(&self.maildir_root);
+        match &self.residency {
+            Some(residency) => residency.maildir_root(username, root),
+            None => root.to_path_buf(),
         }
     }
 
+    /// Enter the authenticated state, creating the user's standard folders
+    /// on first login; provisioning errors are logged and otherwise ignored
+    fn login(&mut self, username: String) {
+        if self.provision_folders {
+            match special_use::provision(&username, &self.root(&username)) {
+                Ok(created) if !created.is_empty() => {
+                    info!("Created folders {:?} for {}", created, username)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to create folders for {}: {}", username, e),
+            }
+        }
+        self.state = SessionState::Authenticated { username };
+        self.device_pending = self.devices.is_some();
+    }
+
+    /// Record the device of the logged in user, named by ID if the client
+    /// sent it; errors are logged and otherwise ignored
+    async fn record_device(&mut self) {
+        self.device_pending = false;
+        let (Some(devices), Some(username)) = (&self.devices, self.username()) else {
+            return;
+        };
+        let name = self.client_name.clone().unwrap_or_default();
+        if let Err(e) = devices
+            .record_login(username, DeviceKind::Imap, &name, self.client_ip)
+            .await
+        {
+            warn!("Failed to record device of {}: {}", username, e);
+        }
+    }
+
+    /// Whether the anomaly detector blocks this login of `username`;
+    /// errors are logged and the login goes on
+    async fn login_blocked(&self, username: &str) -> bool {
+        let Some(detector) = &self.login_anomalies else {
+            return false;
+        };
+        match detector
+            .assess(username, LoginProtocol::Imap, self.client_ip)
+            .await
+        {
+            Ok(assessment) => assessment.decision == Decision::Block,
+            Err(e) => {
+                warn!("Failed to assess login of {}: {}", username, e);
+                false
+            }
+        }
+    }
+
+    /// Check whether `username` has two-factor authentication enabled
+    async fn mfa_required(&self, username: &str) -> Result<bool, MailError> {
+        match &self.mfa {
+            Some(mfa) => mfa
+                .is_enabled(username)
+                .await
+                .map_err(|e| MailError::Storage(e.to_string())),
+            None => Ok(false),
+        }
+    }
+
+    /// Verify the account password or an app password of `username`
+    ///
+    /// App passwords stand in for the account password and its second
+    /// factor, since the clients they are made for cannot ask for codes.
+    async fn verify_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Credential>, MailError> {
+        if self.verify_account_password(username, password).await? {
+            return Ok(Some(Credential::Account));
+        }
+        let Some(devices) = &self.devices else {
+            return Ok(None);
+        };
+        let device = devices
+            .app_password_login(username, password, self.client_ip)
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))?;
+        Ok(device.map(|device| {
+            info!("App password {} of {} used", device.name, username);
+            Credential::AppPassword
+        }))
+    }
+
+    /// Verify the account password, followed by `:code` when the user has
+    /// two-factor authentication enabled
+    async fn verify_account_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<bool, MailError> {
+        let Some(mfa) = &self.mfa else {
+            return self.authenticator.verify_login(username, password).await;
+        };
+        if !self.mfa_required(username).await? {
+            return self.authenticator.verify_login(username, password).await;
+        }
+
+        let Some((password, code)) = password.rsplit_once(':') else {
+            info!("Missing two-factor code for {}", username);
+            return Ok(false);
+        };
+        if !self.authenticator.verify_login(username, password).await? {
+            return Ok(false);
+        }
+        let result = mfa
+            .verify(username, code)
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))?;
+        Ok(result == MfaVerifyResult::Valid)
+    }
+
+    /// Record a failed login; errors are logged and otherwise ignored
+    async fn record_auth_failure(&self, username: Option<&str>) {
+        if let Some(reporting) = &self.reporting {
+            if let Err(e) = reporting
+                .record(UsageEventKind::AuthFailure, None, username, 0)
+                .await
+            {
+                warn!("Failed to record auth failure: {}", e);
+            }
+        }
+    }
+
+    /// Check whether the logged-in user's mailbox is locked for a storage
+    /// cutover, in which case the connection should be closed
+    pub fn mailbox_locked(&self) -> bool {
+        match &self.state {
+            SessionState::Authenticated { username } | SessionState::Selected { username, .. } => {
+                is_mailbox_locked(&self.root(username), username)
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if the next client line is an AUTHENTICATE continuation
+    /// rather than a tagged command
+    pub fn awaiting_auth_response(&self) -> bool {
+        self.pending_auth.is_some()
+    }
+
     /// Check if session is in IDLE mode
     pub fn is_idle(&self) -> bool {
         self.idle_tag.is_some()
     }
 
+    /// Rescan interval while idling
+    pub fn idle_poll_interval(&self) -> Duration {
+        self.idle_poll_interval
+    }
+
+    /// Wait until the selected mailbox changes on disk, or another
+    /// frontend publishes a change to it, while idling
+    ///
+    /// Never completes without a watcher or event bus; the caller still
+    /// rescans every [`idle_poll_interval`](Self::idle_poll_interval).
+    pub async fn idle_changed(&mut self) {
+        let selected = match &self.state {
+            SessionState::Selected { username, mailbox } => Some((username, mailbox)),
+            _ => None,
+        };
+        let watcher = async {
+            match &self.idle_watcher {
+                Some(watcher) => watcher.changed().await,
+                None => std::future::pending().await,
+            }
+        };
+        let events = async {
+            match (&mut self.idle_events, selected) {
+                (Some(events), Some((username, mailbox))) => loop {
+                    match events.recv().await {
+                        Ok(event) if event.concerns(username, mailbox) => return,
+                        Ok(_) => {}
+                        // Missed events may have concerned this mailbox
+                        Err(broadcast::error::RecvError::Lagged(_)) => return,
+                        Err(broadcast::error::RecvError::Closed) => {
+                            std::future::pending::<()>().await
+                        }
+                    }
+                },
+                _ => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = watcher => {}
+            _ = events => {}
+        }
+    }
+
+    /// Publish the flags of the selected mailbox's messages at `sequences`
+    fn publish_flags(&self, sequences: &[usize]) {
+        let (Some(bus), Some(mailbox), SessionState::Selected { username, .. }) =
+            (&self.flag_events, &self.current_mailbox, &self.state)
+        else {
+            return;
+        };
+        for seq in sequences {
+            if let Some(msg) = mailbox.get_message(*seq) {
+                bus.publish(FlagEvent::flags(
+                    username,
+                    &mailbox.name,
+                    &msg.filename,
+                    &msg.flags,
+                    FlagSource::Imap,
+                ));
+            }
+        }
+    }
+
+    /// Reload the selected mailbox and describe what changed since it was
+    /// last loaded as untagged responses (EXPUNGE, EXISTS, RECENT, FETCH)
+    pub fn mailbox_updates(&mut self) -> Result<String, MailError> {
+        let (username, name) = match &self.state {
+            SessionState::Selected { username, mailbox } => (username.clone(), mailbox.clone()),
+            _ => return Ok(String::new()),
+        };
+        let Some(old) = &self.current_mailbox else {
+            return Ok(String::new());
+        };
+        let new = Mailbox::open(&username, &name, &self.root(&username))?;
+
+        let mut response = String::new();
+
+        // Highest sequence numbers first, so the others stay valid
+        let mut remaining = old.message_count();
+        for msg in old.messages().iter().rev() {
+            if new.get_messages_by_uid(&msg.uid.to_string()).is_empty() {
+                response.push_str(&format!("* {} EXPUNGE\r\n", msg.sequence));
+                remaining -= 1;
+            }
+        }
+
+        if new.message_count() != remaining {
+            response.push_str(&format!("* {} EXISTS\r\n", new.message_count()));
+            response.push_str(&format!("* {} RECENT\r\n", new.recent_count()));
+        }
+
+        for msg in new.messages() {
+            let flags_changed = old
+                .get_messages_by_uid(&msg.uid.to_string())
+                .first()
+                .is_some_and(|previous| previous.flags != msg.flags);
+            if flags_changed {
+                response.push_str(&format!(
+                    "* {} FETCH (FLAGS ({}))\r\n",
+                    msg.sequence,
+                    msg.flags.join(" ")
+                ));
+            }
+        }
+
+        self.current_mailbox = Some(new);
+        Ok(response)
+    }
+
+    /// Check if a user is logged in (APPEND literals are only read then)
+    pub fn is_authenticated(&self) -> bool {
+        matches!(
+            self.state,
+            SessionState::Authenticated { .. } | SessionState::Selected { .. }
+        )
+    }
+
+    /// Logged-in user, if any
+    pub fn username(&self) -> Option<&str> {
+        match &self.state {
+            SessionState::Authenticated { username } | SessionState::Selected { username, .. } => {
+                Some(username)
+            }
+            _ => None,
+        }
+    }
+
     /// Get current state
     pub fn state(&self) -> &SessionState {
         &self.state
@@ -69,15 +634,51 @@ impl ImapSession {
     ) -> Result<String, MailError> {
         debug!("Handling IMAP command: {:?} in state {:?}", command, self.state);
 
+        // Clients send ID right after logging in, if at all
+        if self.device_pending && !matches!(command, ImapCommand::Id { .. }) {
+            self.record_device().await;
+        }
+
+        if command.is_mutation() && self.is_read_only() {
+            return Ok(format!(
+                "{} NO [CANNOT] Server is in read-only mode, changes are not possible\r\n",
+                tag
+            ));
+        }
+
         match (&self.state, &command) {
             // CAPABILITY - allowed in any state
             (_, ImapCommand::Capability) => Ok(self.handle_capability(tag)),
 
-            // LOGIN - only in NotAuthenticated state
+            // ID - allowed in any state
+            (_, ImapCommand::Id { fields }) => Ok(self.handle_id(tag, fields).await),
+
+            // STARTTLS - only in NotAuthenticated state; the server upgrades
+            // the stream once the OK is sent
+            (SessionState::NotAuthenticated, ImapCommand::StartTls) => {
+                Ok(self.handle_starttls(tag))
+            }
+
+            // LOGIN - only in NotAuthenticated state, and over TLS when offered
+            (SessionState::NotAuthenticated, ImapCommand::Login { .. }) if self.login_disabled() => {
+                Ok(format!(
+                    "{} NO [PRIVACYREQUIRED] LOGIN disabled, use STARTTLS first\r\n",
+                    tag
+                ))
+            }
             (SessionState::NotAuthenticated, ImapCommand::Login { username, password }) => {
                 self.handle_login(tag, username, password).await
             }
 
+            // AUTHENTICATE - only in NotAuthenticated state
+            (
+                SessionState::NotAuthenticated,
+                ImapCommand::Authenticate {
+                    mechanism,
+                    initial_response,
+                },
+            ) => self.handle_authenticate(tag, mechanism, initial_response.as_deref()).await,
+
             // SELECT/EXAMINE - only in Authenticated or Selected state
             (SessionState::Authenticated { .. }, ImapCommand::Select { mailbox })
             | (SessionState::Selected { .. }, ImapCommand::Select { mailbox }) => {
@@ -91,27 +692,98 @@ impl ImapSession {
 
             // FETCH - only in Selected state
             (SessionState::Selected { .. }, ImapCommand::Fetch { sequence, items }) => {
-                self.handle_fetch(tag, sequence, items)
+                self.handle_fetch(tag, sequence, items, false)
             }
 
             // SEARCH - only in Selected state
-            (SessionState::Selected { .. }, ImapCommand::Search { criteria }) => {
-                self.handle_search(tag, criteria)
+            (SessionState::Selected { .. }, ImapCommand::Search { charset, criteria }) => {
+                self.handle_search(tag, charset.as_deref(), criteria, false)
             }
 
             // STORE - only in Selected state
             (SessionState::Selected { .. }, ImapCommand::Store { sequence, operation, flags }) => {
-                self.handle_store(tag, sequence, operation, flags)
+                self.handle_store(tag, sequence, operation, flags, false)
             }
 
             // EXPUNGE - only in Selected state
             (SessionState::Selected { .. }, ImapCommand::Expunge) => {
-                self.handle_expunge(tag)
+                self.handle_expunge(tag, None)
+            }
+
+            // UID EXPUNGE - only in Selected state
+            (SessionState::Selected { .. }, ImapCommand::UidExpunge { sequence }) => {
+                self.handle_expunge(tag, Some(sequence))
             }
 
             // COPY - only in Selected state
             (SessionState::Selected { username, .. }, ImapCommand::Copy { sequence, mailbox }) => {
-                self.handle_copy(tag, sequence, mailbox, username)
+                self.handle_copy(tag, sequence, mailbox, username, false)
+            }
+
+            // UID FETCH/SEARCH/STORE/COPY - only in Selected state
+            (SessionState::Selected { username, .. }, ImapCommand::Uid { command }) => {
+                match command.as_ref() {
+                    ImapCommand::Fetch { sequence, items } => {
+                        self.handle_fetch(tag, sequence, items, true)
+                    }
+                    ImapCommand::Search { charset, criteria } => {
+                        self.handle_search(tag, charset.as_deref(), criteria, true)
+                    }
+                    ImapCommand::Store { sequence, operation, flags } => {
+                        self.handle_store(tag, sequence, operation, flags, true)
+                    }
+                    ImapCommand::Copy { sequence, mailbox } => {
+                        let username = username.clone();
+                        self.handle_copy(tag, sequence, mailbox, &username, true)
+                    }
+                    _ => Ok(format!("{} BAD Invalid UID command\r\n", tag)),
+                }
+            }
+
+            // APPEND - in Authenticated or Selected state
+            (
+                SessionState::Authenticated { username } | SessionState::Selected { username, .. },
+                ImapCommand::Append {
+                    mailbox,
+                    flags,
+                    internal_date,
+                    message,
+                    ..
+                },
+            ) => {
+                let username = username.clone();
+                self.handle_append(
+                    tag,
+                    &username,
+                    mailbox,
+                    flags,
+                    internal_date.map(Into::into),
+                    message,
+                )
+                .await
+            }
+
+            // GETQUOTA/GETQUOTAROOT/SETQUOTA - in Authenticated or Selected state
+            (
+                SessionState::Authenticated { username } | SessionState::Selected { username, .. },
+                ImapCommand::GetQuota { root },
+            ) => {
+                let username = username.clone();
+                self.handle_getquota(tag, &username, root).await
+            }
+            (
+                SessionState::Authenticated { username } | SessionState::Selected { username, .. },
+                ImapCommand::GetQuotaRoot { mailbox },
+            ) => {
+                let username = username.clone();
+                self.handle_getquotaroot(tag, &username, mailbox).await
+            }
+            (
+                SessionState::Authenticated { username } | SessionState::Selected { username, .. },
+                ImapCommand::SetQuota { root, resources },
+            ) => {
+                let username = username.clone();
+                self.handle_setquota(tag, &username, root, resources).await
             }
 
             // IDLE - only in Selected state
@@ -125,15 +797,64 @@ impl ImapSession {
             }
 
             // LIST - in Authenticated or Selected state
-            (SessionState::Authenticated { .. }, ImapCommand::List { reference, mailbox })
-            | (SessionState::Selected { .. }, ImapCommand::List { reference, mailbox }) => {
-                Ok(self.handle_list(tag, reference, mailbox))
+            (
+                SessionState::Authenticated { .. },
+                ImapCommand::List {
+                    reference,
+                    mailbox,
+                    status,
+                },
+            )
+            | (
+                SessionState::Selected { .. },
+                ImapCommand::List {
+                    reference,
+                    mailbox,
+                    status,
+                },
+            ) => Ok(self.handle_list(tag, reference, mailbox, status).await),
+
+            // STATUS - in Authenticated or Selected state
+            (SessionState::Authenticated { .. }, ImapCommand::Status { mailbox, items })
+            | (SessionState::Selected { .. }, ImapCommand::Status { mailbox, items }) => {
+                Ok(self.handle_status(tag, mailbox, items).await)
+            }
+
+            // LSUB, SUBSCRIBE, UNSUBSCRIBE and NAMESPACE - in Authenticated
+            // or Selected state
+            (SessionState::Authenticated { .. }, ImapCommand::Lsub { reference, mailbox })
+            | (SessionState::Selected { .. }, ImapCommand::Lsub { reference, mailbox }) => {
+                self.handle_lsub(tag, reference, mailbox)
+            }
+            (SessionState::Authenticated { .. }, ImapCommand::Subscribe { mailbox })
+            | (SessionState::Selected { .. }, ImapCommand::Subscribe { mailbox }) => {
+                self.handle_subscribe(tag, mailbox)
+            }
+            (SessionState::Authenticated { .. }, ImapCommand::Unsubscribe { mailbox })
+            | (SessionState::Selected { .. }, ImapCommand::Unsubscribe { mailbox }) => {
+                self.handle_unsubscribe(tag, mailbox)
+            }
+            (SessionState::Authenticated { .. }, ImapCommand::Namespace)
+            | (SessionState::Selected { .. }, ImapCommand::Namespace) => {
+                Ok(self.handle_namespace(tag))
+            }
+
+            // COMPRESS - in Authenticated or Selected state; the server
+            // wraps the stream once the OK is sent
+            (SessionState::Authenticated { .. }, ImapCommand::Compress { mechanism })
+            | (SessionState::Selected { .. }, ImapCommand::Compress { mechanism }) => {
+                Ok(self.handle_compress(tag, mechanism))
             }
 
             // NOOP - allowed in any state except Logout
             (SessionState::Logout, ImapCommand::Noop) => {
                 Ok(format!("{} BAD Command not allowed in LOGOUT state\r\n", tag))
             }
+            // In the selected state, report changes made by others
+            (SessionState::Selected { .. }, ImapCommand::Noop) => {
+                let updates = self.mailbox_updates()?;
+                Ok(format!("{}{} OK NOOP completed\r\n", updates, tag))
+            }
             (_, ImapCommand::Noop) => Ok(format!("{} OK NOOP completed\r\n", tag)),
 
             // LOGOUT - allowed in any state
@@ -149,12 +870,387 @@ impl ImapSession {
 
     /// Handle CAPABILITY command
     fn handle_capability(&self, tag: String) -> String {
+        // Mechanisms sending a reusable secret wait for STARTTLS too
+        let mut auth = String::new();
+        if !self.login_disabled() {
+            auth.push_str(" AUTH=PLAIN AUTH=LOGIN");
+        }
+        auth.push_str(" AUTH=SCRAM-SHA-256");
+        if self.authenticator.cram_md5_enabled() {
+            auth.push_str(" AUTH=CRAM-MD5");
+        }
+        if self.oauth_validator.is_some() && !self.login_disabled() {
+            auth.push_str(" AUTH=OAUTHBEARER AUTH=XOAUTH2");
+        }
+
+        let quota = if self.quotas.is_some() {
+            " QUOTA QUOTA=RES-STORAGE"
+        } else {
+            ""
+        };
+
+        let login = if self.login_disabled() {
+            "STARTTLS LOGINDISABLED"
+        } else {
+            "LOGIN"
+        };
+
+        let compress = if self.compression {
+            " COMPRESS=DEFLATE"
+        } else {
+            ""
+        };
+
+        format!(
+            "* CAPABILITY IMAP4rev1 {} IDLE ID NAMESPACE SPECIAL-USE UIDPLUS LIST-STATUS STATUS=SIZE{}{}{}\r\n{} OK CAPABILITY completed\r\n",
+            login, quota, compress, auth, tag
+        )
+    }
+
+    /// Handle ID command (RFC 2971): remember the client's name for its
+    /// device record and identify the server
+    async fn handle_id(&mut self, tag: String, fields: &[(String, String)]) -> String {
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.as_str())
+        };
+        if let Some(name) = field("name") {
+            let name = match field("version") {
+                Some(version) => format!("{} {}", name, version),
+                None => name.to_string(),
+            };
+            debug!("Client identified as {}", name);
+            self.client_name = Some(name);
+        }
+        if self.device_pending {
+            self.record_device().await;
+        }
+
         format!(
-            "* CAPABILITY IMAP4rev1 LOGIN\r\n{} OK CAPABILITY completed\r\n",
+            "* ID (\"name\" \"{}\" \"version\" \"{}\")\r\n{} OK ID completed\r\n",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
             tag
         )
     }
 
+    /// Handle STARTTLS command
+    ///
+    /// Only answers; the caller performs the handshake after an OK.
+    fn handle_starttls(&self, tag: String) -> String {
+        if self.encrypted {
+            format!("{} BAD TLS already active\r\n", tag)
+        } else if !self.starttls {
+            format!("{} BAD STARTTLS not available\r\n", tag)
+        } else {
+            format!("{} OK Begin TLS negotiation now\r\n", tag)
+        }
+    }
+
+    /// Handle COMPRESS command
+    ///
+    /// Only answers; the caller compresses the stream after an OK.
+    fn handle_compress(&self, tag: String, mechanism: &str) -> String {
+        if !self.compression {
+            format!("{} BAD COMPRESS not available\r\n", tag)
+        } else if self.compressed {
+            format!("{} NO [COMPRESSIONACTIVE] DEFLATE active via COMPRESS\r\n", tag)
+        } else if mechanism != compress::MECHANISM {
+            format!("{} NO Unsupported compression mechanism\r\n", tag)
+        } else {
+            format!("{} OK DEFLATE active\r\n", tag)
+        }
+    }
+
+    /// Handle AUTHENTICATE command
+    ///
+    /// Without an initial response (SASL-IR), the client is sent an empty
+    /// continuation and the response arrives on the next line.
+    async fn handle_authenticate(
+        &mut self,
+        tag: String,
+        mechanism: &str,
+        initial_response: Option<&str>,
+    ) -> Result<String, MailError> {
+        let mechanism = match AuthMechanism::from_str(mechanism) {
+            Some(m) if m.requires_tls() && self.login_disabled() => {
+                return Ok(format!(
+                    "{} NO [PRIVACYREQUIRED] {} disabled, use STARTTLS first\r\n",
+                    tag,
+                    m.as_str()
+                ))
+            }
+            Some(AuthMechanism::Plain) => AuthMechanism::Plain,
+            Some(AuthMechanism::Login) => {
+                // Username and password prompted separately; an initial
+                // response carries the username
+                let Some(response) = initial_response else {
+                    self.pending_auth = Some(PendingAuth::LoginUsername { tag });
+                    return Ok(format!("+ {}\r\n", BASE64.encode("Username:")));
+                };
+                return Ok(self.login_username(tag, response));
+            }
+            Some(m) if m.is_oauth() && self.oauth_validator.is_some() => m,
+            Some(AuthMechanism::ScramSha256) => AuthMechanism::ScramSha256,
+            Some(AuthMechanism::CramMd5) if self.authenticator.cram_md5_enabled() => {
+                // Server-first mechanism: no initial response (RFC 4959)
+                if initial_response.is_some() {
+                    return Ok(format!("{} BAD CRAM-MD5 takes no initial response\r\n", tag));
+                }
+                let challenge = sasl::cram_md5_challenge(&hostname());
+                let continuation = format!("+ {}\r\n", BASE64.encode(&challenge));
+                self.pending_auth = Some(PendingAuth::CramMd5 { tag, challenge });
+                return Ok(continuation);
+            }
+            _ => {
+                return Ok(format!(
+                    "{} NO Unsupported authentication mechanism\r\n",
+                    tag
+                ))
+            }
+        };
+
+        match initial_response {
+            Some(response) => self.complete_authenticate(tag, mechanism, response).await,
+            None => {
+                self.pending_auth = Some(PendingAuth::Response { tag, mechanism });
+                Ok("+ \r\n".to_string())
+            }
+        }
+    }
+
+    /// Handle a continuation line of a pending AUTHENTICATE exchange
+    pub async fn handle_auth_response(&mut self, line: &str) -> Result<String, MailError> {
+        let line = line.trim();
+
+        match self.pending_auth.take() {
+            Some(pending) if line == "*" => {
+                Ok(format!("{} BAD AUTHENTICATE cancelled\r\n", pending.tag()))
+            }
+            Some(PendingAuth::Response { tag, mechanism }) => {
+                self.complete_authenticate(tag, mechanism, line).await
+            }
+            Some(PendingAuth::ErrorAck { tag }) => Ok(format!(
+                "{} NO AUTHENTICATE failed - invalid token\r\n",
+                tag
+            )),
+            Some(PendingAuth::CramMd5 { tag, challenge }) => {
+                self.complete_cram_md5(tag, &challenge, line).await
+            }
+            Some(PendingAuth::ScramFinal { tag, scram }) => {
+                self.complete_scram(tag, &scram, line).await
+            }
+            Some(PendingAuth::ScramAck { tag, username }) => {
+                self.authenticated(tag, username, AuthMechanism::ScramSha256)
+                    .await
+            }
+            Some(PendingAuth::LoginUsername { tag }) => Ok(self.login_username(tag, line)),
+            Some(PendingAuth::LoginPassword { tag, username }) => {
+                let Some(password) = decode_text(line) else {
+                    return Ok(format!("{} BAD Invalid LOGIN response\r\n", tag));
+                };
+                self.password_login(tag, "AUTHENTICATE", &username, &password)
+                    .await
+            }
+            None => Err(MailError::ImapProtocol(
+                "No authentication in progress".to_string(),
+            )),
+        }
+    }
+
+    /// Validate the client's SASL response and authenticate the session
+    async fn complete_authenticate(
+        &mut self,
+        tag: String,
+        mechanism: AuthMechanism,
+        response: &str,
+    ) -> Result<String, MailError> {
+        let decoded = match BASE64.decode(response) {
+            Ok(decoded) => decoded,
+            Err(_) => return Ok(format!("{} BAD Invalid base64 response\r\n", tag)),
+        };
+
+        if mechanism == AuthMechanism::ScramSha256 {
+            return self.start_scram(tag, &decoded).await;
+        }
+
+        if mechanism == AuthMechanism::Plain {
+            // authzid NUL authcid NUL password (RFC 4616); acting as
+            // another user is not supported
+            let message = String::from_utf8(decoded).unwrap_or_default();
+            let parts: Vec<&str> = message.split('\0').collect();
+            let [authzid, username, password] = parts[..] else {
+                return Ok(format!("{} BAD Invalid PLAIN response\r\n", tag));
+            };
+            if !authzid.is_empty() && authzid != username {
+                info!("AUTHENTICATE PLAIN refused: {} cannot act as {}", username, authzid);
+                self.record_auth_failure(Some(username)).await;
+                return Ok(format!("{} NO [AUTHORIZATIONFAILED] AUTHENTICATE failed\r\n", tag));
+            }
+            return self
+                .password_login(tag, "AUTHENTICATE", username, password)
+                .await;
+        }
+
+        let parsed = match mechanism {
+            AuthMechanism::OAuthBearer => oauth::parse_oauthbearer(&decoded),
+            _ => oauth::parse_xoauth2(&decoded).map(|(user, token)| (Some(user), token)),
+        };
+        let (claimed_user, token) = match parsed {
+            Ok(parsed) => parsed,
+            Err(_) => return Ok(format!("{} BAD Invalid {} response\r\n", tag, mechanism.as_str())),
+        };
+
+        let validator = match &self.oauth_validator {
+            Some(validator) => validator.clone(),
+            None => return Ok(format!("{} NO Unsupported authentication mechanism\r\n", tag)),
+        };
+
+        match validator.authenticate(claimed_user.as_deref(), &token).await {
+            Ok(username) if is_mailbox_locked(&self.root(&username), &username) => {
+                info!("AUTHENTICATE refused for {}: mailbox migration in progress", username);
+                Ok(format!("{} NO [UNAVAILABLE] Mailbox migration in progress\r\n", tag))
+            }
+            Ok(username) if self.login_blocked(&username).await => {
+                Ok(anomaly_refused(tag, &username, "AUTHENTICATE"))
+            }
+            Ok(username) => {
+                info!("AUTHENTICATE {} successful for: {}", mechanism.as_str(), username);
+                self.login(username);
+                Ok(format!("{} OK AUTHENTICATE completed\r\n", tag))
+            }
+            Err(e) => {
+                info!("AUTHENTICATE {} failed: {}", mechanism.as_str(), e);
+                self.record_auth_failure(claimed_user.as_deref()).await;
+                // RFC 7628: send the error as a challenge, then fail once
+                // the client acknowledges it
+                self.pending_auth = Some(PendingAuth::ErrorAck { tag });
+                Ok(format!("+ {}\r\n", BASE64.encode(oauth::OAUTHBEARER_ERROR)))
+            }
+        }
+    }
+
+    /// Answer a CRAM-MD5 challenge response (`username digest`)
+    async fn complete_cram_md5(
+        &mut self,
+        tag: String,
+        challenge: &str,
+        response: &str,
+    ) -> Result<String, MailError> {
+        let parsed = BASE64
+            .decode(response)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| sasl::parse_cram_md5_response(&decoded).ok());
+        let Some((username, digest)) = parsed else {
+            return Ok(format!("{} BAD Invalid CRAM-MD5 response\r\n", tag));
+        };
+
+        if self
+            .authenticator
+            .authenticate_cram_md5(&username, challenge, &digest)
+            .await?
+        {
+            if self.mfa_required(&username).await? {
+                return Ok(mfa_refused(tag, &username, AuthMechanism::CramMd5));
+            }
+            self.authenticated(tag, username, AuthMechanism::CramMd5)
+                .await
+        } else {
+            info!("AUTHENTICATE CRAM-MD5 failed for: {}", username);
+            self.record_auth_failure(Some(&username)).await;
+            Ok(format!("{} NO AUTHENTICATE failed\r\n", tag))
+        }
+    }
+
+    /// Answer a SCRAM-SHA-256 client-first message with the server-first
+    async fn start_scram(&mut self, tag: String, client_first: &[u8]) -> Result<String, MailError> {
+        let scram = std::str::from_utf8(client_first)
+            .ok()
+            .and_then(|message| ScramServer::parse_client_first(message).ok());
+        let Some(mut scram) = scram else {
+            return Ok(format!("{} BAD Invalid SCRAM-SHA-256 response\r\n", tag));
+        };
+
+        let credentials = self
+            .authenticator
+            .get_scram_credentials(scram.username())
+            .await?;
+        let server_first = scram.server_first(credentials);
+        self.pending_auth = Some(PendingAuth::ScramFinal {
+            tag,
+            scram: Box::new(scram),
+        });
+        Ok(format!("+ {}\r\n", BASE64.encode(server_first)))
+    }
+
+    /// Check the SCRAM-SHA-256 client proof and send the server signature
+    async fn complete_scram(
+        &mut self,
+        tag: String,
+        scram: &ScramServer,
+        response: &str,
+    ) -> Result<String, MailError> {
+        let verified = BASE64
+            .decode(response)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .map(|client_final| scram.verify_client_final(&client_final));
+        let username = scram.username().to_string();
+
+        match verified {
+            Some(Ok(Some(_))) if self.mfa_required(&username).await? => {
+                Ok(mfa_refused(tag, &username, AuthMechanism::ScramSha256))
+            }
+            Some(Ok(Some(server_final))) => {
+                self.authenticator.record_login(&username).await?;
+                // The client acknowledges the server signature with an empty line
+                self.pending_auth = Some(PendingAuth::ScramAck { tag, username });
+                Ok(format!("+ {}\r\n", BASE64.encode(server_final)))
+            }
+            Some(Ok(None)) => {
+                info!("AUTHENTICATE SCRAM-SHA-256 failed for: {}", username);
+                self.record_auth_failure(Some(&username)).await;
+                Ok(format!("{} NO AUTHENTICATE failed\r\n", tag))
+            }
+            _ => Ok(format!("{} BAD Invalid SCRAM-SHA-256 response\r\n", tag)),
+        }
+    }
+
+    /// Log the session in after a successful challenge-response exchange
+    async fn authenticated(
+        &mut self,
+        tag: String,
+        username: String,
+        mechanism: AuthMechanism,
+    ) -> Result<String, MailError> {
+        if is_mailbox_locked(&self.root(&username), &username) {
+            info!("AUTHENTICATE refused for {}: mailbox migration in progress", username);
+            return Ok(format!("{} NO [UNAVAILABLE] Mailbox migration in progress\r\n", tag));
+        }
+        if self.login_blocked(&username).await {
+            return Ok(anomaly_refused(tag, &username, "AUTHENTICATE"));
+        }
+
+        info!("AUTHENTICATE {} successful for: {}", mechanism.as_str(), username);
+        self.login(username);
+        Ok(format!("{} OK AUTHENTICATE completed\r\n", tag))
+    }
+
+    /// Take the username of an AUTHENTICATE LOGIN exchange and prompt for
+    /// the password
+    fn login_username(&mut self, tag: String, response: &str) -> String {
+        match decode_text(response) {
+            Some(username) => {
+                self.pending_auth = Some(PendingAuth::LoginPassword { tag, username });
+                format!("+ {}\r\n", BASE64.encode("Password:"))
+            }
+            None => format!("{} BAD Invalid LOGIN response\r\n", tag),
+        }
+    }
+
     /// Handle LOGIN command
     async fn handle_login(
         &mut self,
@@ -163,26 +1259,46 @@ impl ImapSession {
         password: &str,
     ) -> Result<String, MailError> {
         info!("LOGIN attempt for user: {}", username);
+        self.password_login(tag, "LOGIN", username, password).await
+    }
 
+    /// Log in with a password, from LOGIN or AUTHENTICATE PLAIN/LOGIN
+    async fn password_login(
+        &mut self,
+        tag: String,
+        command: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<String, MailError> {
         // Verify credentials
-        match self.authenticator.verify_login(username, password).await {
-            Ok(true) => {
-                info!("LOGIN successful for: {}", username);
-                self.state = SessionState::Authenticated {
-                    username: username.to_string(),
-                };
-                Ok(format!("{} OK LOGIN completed\r\n", tag))
+        match self.verify_password(username, password).await {
+            Ok(Some(_)) if is_mailbox_locked(&self.root(username), username) => {
+                info!("{} refused for {}: mailbox migration in progress", command, username);
+                Ok(format!("{} NO [UNAVAILABLE] Mailbox migration in progress\r\n", tag))
+            }
+            Ok(Some(_)) if self.login_blocked(username).await => {
+                Ok(anomaly_refused(tag, username, command))
+            }
+            Ok(Some(credential)) => {
+                info!("{} successful for: {}", command, username);
+                self.login(username.to_string());
+                // App password logins already updated their device
+                if credential == Credential::AppPassword {
+                    self.device_pending = false;
+                }
+                Ok(format!("{} OK {} completed\r\n", tag, command))
             }
-            Ok(false) => {
-                info!("LOGIN failed for: {} (invalid credentials)", username);
+            Ok(None) => {
+                info!("{} failed for: {} (invalid credentials)", command, username);
+                self.record_auth_failure(Some(username)).await;
                 Ok(format!(
-                    "{} NO LOGIN failed - invalid credentials\r\n",
-                    tag
+                    "{} NO {} failed - invalid credentials\r\n",
+                    tag, command
                 ))
             }
             Err(e) => {
-                info!("LOGIN error for {}: {}", username, e);
-                Ok(format!("{} NO LOGIN failed - {}\r\n", tag, e))
+                info!("{} error for {}: {}", command, username, e);
+                Ok(format!("{} NO {} failed - {}\r\n", tag, command, e))
             }
         }
     }
@@ -198,8 +1314,19 @@ impl ImapSession {
 
         info!("SELECT {} for user {}", mailbox, username);
 
+        if let Some(openpgp) = &self.openpgp {
+            self.keyring = match openpgp.keyring(&username).await {
+                Ok(keyring) if !keyring.secret.is_empty() => Some(Arc::new(keyring)),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("Failed to load OpenPGP keys of {}: {}", username, e);
+                    None
+                }
+            };
+        }
+
         // Open mailbox
-        match Mailbox::open(&username, mailbox, Path::new(&self.maildir_root)) {
+        match Mailbox::open(&username, mailbox, &self.root(&username)) {
             Ok(mb) => {
                 let exists = mb.message_count();
                 let recent = mb.recent_count();
@@ -220,7 +1347,8 @@ impl ImapSession {
                     response.push_str(&format!("* OK [UNSEEN {}] First unseen\r\n", unseen));
                 }
                 response.push_str("* FLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)\r\n");
-                response.push_str(&format!("{} OK [READ-WRITE] SELECT completed\r\n", tag));
+                let access = if self.is_read_only() { "READ-ONLY" } else { "READ-WRITE" };
+                response.push_str(&format!("{} OK [{}] SELECT completed\r\n", tag, access));
 
                 self.current_mailbox = Some(mb);
                 self.state = SessionState::Selected {
@@ -243,41 +1371,77 @@ impl ImapSession {
     }
 
     /// Handle FETCH command
-    fn handle_fetch(&self, tag: String, sequence: &str, items: &[String]) -> Result<String, MailError> {
+    ///
+    /// With `by_uid` (UID FETCH), `sequence` is a UID set and every response
+    /// includes the UID.
+    fn handle_fetch(
+        &self,
+        tag: String,
+        sequence: &str,
+        items: &[String],
+        by_uid: bool,
+    ) -> Result<String, MailError> {
         let mailbox = match &self.current_mailbox {
             Some(mb) => mb,
             None => return Ok(format!("{} BAD No mailbox selected\r\n", tag)),
         };
 
-        let messages = mailbox.get_messages(sequence);
+        let messages = if by_uid {
+            mailbox.get_messages_by_uid(sequence)
+        } else {
+            mailbox.get_messages(sequence)
+        };
+        let attributes = fetch::parse_items(items);
         let mut response = String::new();
 
         for msg in messages {
             response.push_str(&format!("* {} FETCH (", msg.sequence));
+            let decrypted = self.decrypted(msg, &attributes)?;
 
-            // Parse fetch items
             let mut fetch_parts = Vec::new();
+            if by_uid {
+                fetch_parts.push(format!("UID {}", msg.uid));
+            }
 
-            for item in items {
-                let item_upper = item.to_uppercase();
-                if item_upper.contains("BODY[]") || item_upper == "RFC822" {
-                    // Return full message
-                    let body = String::from_utf8_lossy(&msg.content);
-                    fetch_parts.push(format!("BODY[] {{{}}}\r\n{}", msg.size, body));
-                } else if item_upper.contains("BODY[HEADER]") || item_upper == "RFC822.HEADER" {
-                    // Return headers only
-                    let body = String::from_utf8_lossy(&msg.content);
-                    if let Some(header_end) = body.find("\r\n\r\n") {
-                        let headers = &body[..header_end + 4];
-                        fetch_parts.push(format!("BODY[HEADER] {{{}}}\r\n{}", headers.len(), headers));
+            for attribute in &attributes {
+                match attribute {
+                    FetchAttribute::Uid => {
+                        if !by_uid {
+                            fetch_parts.push(format!("UID {}", msg.uid));
+                        }
+                    }
+                    FetchAttribute::Flags => {
+                        fetch_parts.push(format!("FLAGS ({})", msg.flags.join(" ")));
+                    }
+                    FetchAttribute::Rfc822Size => {
+                        let size = decrypted.as_ref().map_or(msg.size, Vec::len);
+                        fetch_parts.push(format!("RFC822.SIZE {}", size));
+                    }
+                    FetchAttribute::Envelope => {
+                        let entity = MimeParser::parse_tree(msg.header()?);
+                        fetch_parts.push(format!("ENVELOPE {}", envelope(&entity)));
+                    }
+                    FetchAttribute::Structure { extensible } => {
+                        let content = match &decrypted {
+                            Some(decrypted) => decrypted.as_slice(),
+                            None => msg.content()?,
+                        };
+                        let entity = MimeParser::parse_tree(content);
+                        fetch_parts.push(format!(
+                            "{} {}",
+                            attribute.name(),
+                            body_structure(&entity, *extensible)
+                        ));
+                    }
+                    _ => {
+                        if let Some(section) = attribute.section() {
+                            let content = match &decrypted {
+                                Some(decrypted) => section.extract(decrypted),
+                                None => section.extract(msg.content()?),
+                            };
+                            fetch_parts.push(format!("{} {}", attribute.name(), literal(&content)));
+                        }
                     }
-                } else if item_upper == "RFC822.SIZE" {
-                    fetch_parts.push(format!("RFC822.SIZE {}", msg.size));
-                } else if item_upper == "UID" {
-                    fetch_parts.push(format!("UID {}", msg.sequence));
-                } else if item_upper == "FLAGS" {
-                    let flags = msg.flags.join(" ");
-                    fetch_parts.push(format!("FLAGS ({})", flags));
                 }
             }
 
@@ -285,17 +1449,54 @@ impl ImapSession {
             response.push_str(")\r\n");
         }
 
-        response.push_str(&format!("{} OK FETCH completed\r\n", tag));
+        response.push_str(&format!("{} OK {}FETCH completed\r\n", tag, uid_prefix(by_uid)));
         Ok(response)
     }
 
+    /// Content of an encrypted message decrypted with the user's keys,
+    /// when `attributes` need the content
+    fn decrypted(
+        &self,
+        msg: &EmailMessage,
+        attributes: &[FetchAttribute],
+    ) -> Result<Option<Vec<u8>>, MailError> {
+        let Some(keyring) = &self.keyring else {
+            return Ok(None);
+        };
+        let needs_content = attributes.iter().any(|attribute| {
+            matches!(
+                attribute,
+                FetchAttribute::Rfc822Size | FetchAttribute::Structure { .. }
+            ) || attribute.section().is_some()
+        });
+        if !needs_content || !pgp_mime::is_encrypted(msg.content()?) {
+            return Ok(None);
+        }
+        Ok(pgp_mime::open(msg.content()?, keyring).message)
+    }
+
     /// Handle SEARCH command
-    fn handle_search(&self, tag: String, criteria: &SearchCriteria) -> Result<String, MailError> {
+    ///
+    /// With `by_uid` (UID SEARCH), UIDs are returned instead of sequence
+    /// numbers.
+    fn handle_search(
+        &self,
+        tag: String,
+        charset: Option<&str>,
+        criteria: &SearchCriteria,
+        by_uid: bool,
+    ) -> Result<String, MailError> {
         let mailbox = match &self.current_mailbox {
             Some(mb) => mb,
             None => return Ok(format!("{} BAD No mailbox selected\r\n", tag)),
         };
 
+        if let Some(charset) = charset {
+            if !search::is_supported_charset(charset) {
+                return Ok(search::bad_charset(&tag));
+            }
+        }
+
         debug!("Searching with criteria: {:?}", criteria);
 
         // Get matching message sequence numbers
@@ -304,22 +1505,31 @@ impl ImapSession {
         // Format response: "* SEARCH <sequence numbers>\r\n<tag> OK SEARCH completed\r\n"
         let mut response = String::from("* SEARCH");
         for seq in matches {
+            let number = if by_uid {
+                mailbox.get_message(seq).map(|msg| msg.uid as usize).unwrap_or(seq)
+            } else {
+                seq
+            };
             response.push(' ');
-            response.push_str(&seq.to_string());
+            response.push_str(&number.to_string());
         }
         response.push_str("\r\n");
-        response.push_str(&format!("{} OK SEARCH completed\r\n", tag));
+        response.push_str(&format!("{} OK {}SEARCH completed\r\n", tag, uid_prefix(by_uid)));
 
         Ok(response)
     }
 
     /// Handle STORE command
+    ///
+    /// With `by_uid` (UID STORE), `sequence` is a UID set and the FETCH
+    /// responses include the UID.
     fn handle_store(
         &mut self,
         tag: String,
         sequence: &str,
         operation: &StoreOperation,
         flags: &[String],
+        by_uid: bool,
     ) -> Result<String, MailError> {
         let mailbox = match &mut self.current_mailbox {
             Some(mb) => mb,
@@ -329,23 +1539,42 @@ impl ImapSession {
         debug!("Storing flags {:?} on sequence {} with operation {:?}", flags, sequence, operation);
 
         // Modify flags on messages
-        let modified_sequences = mailbox.store_flags(sequence, operation, flags)?;
+        let sequence = if by_uid {
+            mailbox.sequence_set_for_uids(sequence)
+        } else {
+            sequence.to_string()
+        };
+        let modified_sequences = if sequence.is_empty() {
+            Vec::new()
+        } else {
+            mailbox.store_flags(&sequence, operation, flags)?
+        };
 
         // Build response with FLAG updates for each modified message
         let mut response = String::new();
         for seq in &modified_sequences {
             if let Some(msg) = mailbox.get_message(*seq) {
                 let flags_str = msg.flags.join(" ");
-                response.push_str(&format!("* {} FETCH (FLAGS ({}))\r\n", seq, flags_str));
+                if by_uid {
+                    response.push_str(&format!(
+                        "* {} FETCH (UID {} FLAGS ({}))\r\n",
+                        seq, msg.uid, flags_str
+                    ));
+                } else {
+                    response.push_str(&format!("* {} FETCH (FLAGS ({}))\r\n", seq, flags_str));
+                }
             }
         }
-        response.push_str(&format!("{} OK STORE completed\r\n", tag));
+        response.push_str(&format!("{} OK {}STORE completed\r\n", tag, uid_prefix(by_uid)));
+        self.publish_flags(&modified_sequences);
 
         Ok(response)
     }
 
     /// Handle EXPUNGE command
-    fn handle_expunge(&mut self, tag: String) -> Result<String, MailError> {
+    ///
+    /// With `uid_set` (UID EXPUNGE), only deleted messages in the set go.
+    fn handle_expunge(&mut self, tag: String, uid_set: Option<&str>) -> Result<String, MailError> {
         let mailbox = match &mut self.current_mailbox {
             Some(mb) => mb,
             None => return Ok(format!("{} BAD No mailbox selected\r\n", tag)),
@@ -353,26 +1582,59 @@ impl ImapSession {
 
         debug!("Expunging messages marked as \\Deleted");
 
+        let selected: Vec<&EmailMessage> = match uid_set {
+            Some(uid_set) => mailbox.get_messages_by_uid(uid_set),
+            None => mailbox.messages().iter().collect(),
+        };
+        let deleted: Vec<String> = selected
+            .iter()
+            .filter(|msg| msg.flags.iter().any(|flag| flag == "\\Deleted"))
+            .map(|msg| msg.filename.clone())
+            .collect();
+
         // Expunge messages marked as \Deleted
-        let expunged_sequences = mailbox.expunge()?;
+        let expunged_sequences = match uid_set {
+            Some(uid_set) => mailbox.expunge_uids(uid_set)?,
+            None => mailbox.expunge()?,
+        };
 
         // Build response with expunge notifications for each removed message
         let mut response = String::new();
         for seq in &expunged_sequences {
             response.push_str(&format!("* {} EXPUNGE\r\n", seq));
         }
-        response.push_str(&format!("{} OK EXPUNGE completed\r\n", tag));
+        response.push_str(&format!(
+            "{} OK {}EXPUNGE completed\r\n",
+            tag,
+            uid_prefix(uid_set.is_some())
+        ));
+
+        if let (Some(bus), SessionState::Selected { username, mailbox }) =
+            (&self.flag_events, &self.state)
+        {
+            for filename in &deleted {
+                bus.publish(FlagEvent::expunged(
+                    username,
+                    mailbox,
+                    filename,
+                    FlagSource::Imap,
+                ));
+            }
+        }
 
         Ok(response)
     }
 
     /// Handle COPY command
+    ///
+    /// With `by_uid` (UID COPY), `sequence` is a UID set.
     fn handle_copy(
         &self,
         tag: String,
         sequence: &str,
         destination: &str,
         username: &str,
+        by_uid: bool,
     ) -> Result<String, MailError> {
         let source_mailbox = match &self.current_mailbox {
             Some(mb) => mb,
@@ -382,14 +1644,268 @@ impl ImapSession {
         debug!("Copying messages {} to {}", sequence, destination);
 
         // Copy messages to destination
-        let copied_count = source_mailbox.copy_messages(
-            sequence,
-            destination,
-            username,
-            Path::new(&self.maildir_root),
-        )?;
+        let sequence = if by_uid {
+            source_mailbox.sequence_set_for_uids(sequence)
+        } else {
+            sequence.to_string()
+        };
+        let copied = if sequence.is_empty() {
+            CopiedMessages::default()
+        } else {
+            source_mailbox.copy_messages(
+                &sequence,
+                destination,
+                username,
+                &self.root(username),
+            )?
+        };
+        if !copied.uids.is_empty() {
+            self.train_on_copy(username, source_mailbox, &sequence, destination);
+        }
 
-        Ok(format!("{} OK COPY completed ({} messages)\r\n", tag, copied_count))
+        // UIDPLUS: tell the client where the copies went, so it needn't
+        // resync the destination
+        let code = if copied.uids.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "[COPYUID {} {} {}] ",
+                copied.uid_validity,
+                uid_set(&copied.source_uids),
+                uid_set(&copied.uids)
+            )
+        };
+        Ok(format!(
+            "{} OK {}{}COPY completed ({} messages)\r\n",
+            tag,
+            code,
+            uid_prefix(by_uid),
+            copied.uids.len()
+        ))
+    }
+
+    /// Train the spam classifier in the background on messages copied
+    /// between the Junk folder and another one, unless the user turned
+    /// learning off
+    fn train_on_copy(&self, username: &str, source: &Mailbox, sequence: &str, destination: &str) {
+        let Some(spam) = &self.spam_training else {
+            return;
+        };
+        let mailboxes = Mailbox::list_mailboxes(username, &self.root(username)).unwrap_or_default();
+        let roles = special_use::roles(&mailboxes);
+        let role = |name: &str| roles.get(name).copied();
+        let class = match (role(&source.name), role(destination)) {
+            (Some(SpecialUse::Junk), Some(SpecialUse::Junk | SpecialUse::Trash)) => return,
+            (Some(SpecialUse::Junk), _) => SpamClass::Ham,
+            (_, Some(SpecialUse::Junk)) => SpamClass::Spam,
+            _ => return,
+        };
+
+        let messages: Vec<Vec<u8>> = source
+            .get_messages(sequence)
+            .into_iter()
+            .filter_map(|msg| msg.content().ok().map(<[u8]>::to_vec))
+            .collect();
+        let spam = spam.clone();
+        let username = username.to_string();
+        tokio::spawn(async move {
+            match spam.config_for(&username).await {
+                Ok(config) if !config.learning_enabled => return,
+                Err(e) => {
+                    warn!("Failed to read spam config of {}: {}", username, e);
+                    return;
+                }
+                Ok(_) => {}
+            }
+            for message in &messages {
+                if let Err(e) = spam.train_message(message, class).await {
+                    warn!("Failed to train on a message of {}: {}", username, e);
+                }
+            }
+            debug!("Trained on {} messages of {} as {:?}", messages.len(), username, class);
+        });
+    }
+
+    /// Handle APPEND command
+    ///
+    /// Appending to the selected mailbox reloads it and reports the new
+    /// message count.
+    async fn handle_append(
+        &mut self,
+        tag: String,
+        username: &str,
+        mailbox: &str,
+        flags: &[String],
+        internal_date: Option<SystemTime>,
+        message: &[u8],
+    ) -> Result<String, MailError> {
+        if let Some(quotas) = &self.quotas {
+            self.measure_usage(quotas, username).await?;
+            match quotas.check_storage(username, message.len() as u64).await {
+                QuotaStatus::StorageExceeded => {
+                    return Ok(format!("{} NO [OVERQUOTA] Storage quota exceeded\r\n", tag));
+                }
+                QuotaStatus::MessageSizeExceeded => {
+                    return Ok(format!("{} NO [TOOBIG] Message too large\r\n", tag));
+                }
+                _ => {}
+            }
+        }
+
+        let root = self.root(username);
+        let appended = match Mailbox::append(username, mailbox, &root, message, flags, internal_date) {
+            Ok(appended) => appended,
+            Err(MailError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(format!("{} NO [TRYCREATE] Mailbox does not exist\r\n", tag));
+            }
+            Err(e) => return Ok(format!("{} NO APPEND failed - {}\r\n", tag, e)),
+        };
+        info!("APPEND {} bytes to {} for user {}", message.len(), mailbox, username);
+
+        let mut response = String::new();
+        let selected = match &self.current_mailbox {
+            Some(current) if current.name.eq_ignore_ascii_case("INBOX") => {
+                mailbox.eq_ignore_ascii_case("INBOX")
+            }
+            Some(current) => current.name == mailbox,
+            None => false,
+        };
+        if selected {
+            let reopened = Mailbox::open(username, mailbox, &root)?;
+            response.push_str(&format!("* {} EXISTS\r\n", reopened.message_count()));
+            self.current_mailbox = Some(reopened);
+        }
+        response.push_str(&format!(
+            "{} OK [APPENDUID {} {}] APPEND completed\r\n",
+            tag, appended.uid_validity, appended.uid
+        ));
+        Ok(response)
+    }
+
+    /// Record the storage the mailbox of `user` currently uses, from its
+    /// `maildirsize` file
+    async fn measure_usage(&self, quotas: &QuotaManager, user: &str) -> Result<(), MailError> {
+        let user_dir = self.root(user).join(user);
+        let usage = tokio::task::spawn_blocking(move || maildirsize::usage(&user_dir))
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))??;
+        quotas.set_storage_used(user, usage.bytes).await;
+        Ok(())
+    }
+
+    /// User whose quota root `root` names, if `username` may access it
+    ///
+    /// Every user has one quota root, `""`, covering all their mailboxes.
+    /// Quota admins name other users' roots by address.
+    fn quota_user(&self, username: &str, root: &str) -> Option<String> {
+        if root.is_empty() || root.eq_ignore_ascii_case(username) {
+            Some(username.to_string())
+        } else if self.is_quota_admin(username) && root.contains('@') {
+            Some(root.to_lowercase())
+        } else {
+            None
+        }
+    }
+
+    fn is_quota_admin(&self, username: &str) -> bool {
+        self.quota_admins
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(username))
+    }
+
+    /// Handle GETQUOTA command
+    async fn handle_getquota(
+        &self,
+        tag: String,
+        username: &str,
+        root: &str,
+    ) -> Result<String, MailError> {
+        let Some(quotas) = &self.quotas else {
+            return Ok(format!("{} NO Quotas are not enabled\r\n", tag));
+        };
+        let Some(user) = self.quota_user(username, root) else {
+            return Ok(format!("{} NO No such quota root\r\n", tag));
+        };
+
+        self.measure_usage(quotas, &user).await?;
+        let quota = quotas.get_quota(&user).await;
+        Ok(format!(
+            "{}{} OK GETQUOTA completed\r\n",
+            quota_response(root, &quota),
+            tag
+        ))
+    }
+
+    /// Handle GETQUOTAROOT command
+    async fn handle_getquotaroot(
+        &self,
+        tag: String,
+        username: &str,
+        mailbox: &str,
+    ) -> Result<String, MailError> {
+        let Some(quotas) = &self.quotas else {
+            return Ok(format!("{} NO Quotas are not enabled\r\n", tag));
+        };
+        let exists = Mailbox::list_mailboxes(username, &self.root(username))?
+            .iter()
+            .any(|name| {
+                name == mailbox
+                    || (name == "INBOX" && mailbox.eq_ignore_ascii_case("INBOX"))
+            });
+        if !exists {
+            return Ok(format!("{} NO Mailbox does not exist\r\n", tag));
+        }
+
+        self.measure_usage(quotas, username).await?;
+        let quota = quotas.get_quota(username).await;
+        Ok(format!(
+            "* QUOTAROOT \"{}\" \"\"\r\n{}{} OK GETQUOTAROOT completed\r\n",
+            mailbox,
+            quota_response("", &quota),
+            tag
+        ))
+    }
+
+    /// Handle SETQUOTA command (quota admins only)
+    ///
+    /// STORAGE is the only resource; its limit is in units of 1024 bytes.
+    async fn handle_setquota(
+        &self,
+        tag: String,
+        username: &str,
+        root: &str,
+        resources: &[(String, u64)],
+    ) -> Result<String, MailError> {
+        let Some(quotas) = &self.quotas else {
+            return Ok(format!("{} NO Quotas are not enabled\r\n", tag));
+        };
+        if !self.is_quota_admin(username) {
+            return Ok(format!("{} NO [NOPERM] Not allowed to change quotas\r\n", tag));
+        }
+        let Some(user) = self.quota_user(username, root) else {
+            return Ok(format!("{} NO No such quota root\r\n", tag));
+        };
+
+        let mut quota = quotas.get_quota(&user).await;
+        for (resource, limit) in resources {
+            if resource != "STORAGE" {
+                return Ok(format!("{} NO Unsupported resource {}\r\n", tag, resource));
+            }
+            quota.storage_limit = limit.saturating_mul(1024);
+        }
+        quotas
+            .set_quota(quota)
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))?;
+        info!("Quota admin {} changed the quota of {}", username, user);
+
+        self.measure_usage(quotas, &user).await?;
+        let quota = quotas.get_quota(&user).await;
+        Ok(format!(
+            "{}{} OK SETQUOTA completed\r\n",
+            quota_response(root, &quota),
+            tag
+        ))
     }
 
     /// Handle IDLE command
@@ -397,17 +1913,24 @@ impl ImapSession {
     /// Puts the session in IDLE mode and returns a continuation response.
     /// The client should send DONE to exit IDLE mode.
     ///
-    /// Note: This is a synchronous stub. In a real async IMAP server,
-    /// IDLE would be handled in the connection loop with proper async/await.
+    /// The connection loop then waits on [`idle_changed`](Self::idle_changed)
+    /// and sends [`mailbox_updates`](Self::mailbox_updates) to the client.
     fn handle_idle(&mut self, tag: String) -> Result<String, MailError> {
         debug!("Entering IDLE mode");
 
         // Store the tag for when DONE is received
         self.idle_tag = Some(tag);
+        self.idle_events = self.flag_events.as_ref().map(|bus| bus.subscribe());
+
+        // Without a watcher, changes are still picked up by rescans
+        if let Some(mailbox) = &self.current_mailbox {
+            match IdleWatcher::with_poll_interval(mailbox.path(), self.idle_poll_interval) {
+                Ok(watcher) => self.idle_watcher = Some(watcher),
+                Err(e) => warn!("Cannot watch {:?} during IDLE: {}", mailbox.path(), e),
+            }
+        }
 
         // Return continuation response
-        // The actual filesystem watching and notifications are handled
-        // in the IMAP server connection loop using IdleWatcher
         Ok("+ idling\r\n".to_string())
     }
 
@@ -415,6 +1938,8 @@ impl ImapSession {
     fn handle_done(&mut self) -> Result<String, MailError> {
         debug!("Exiting IDLE mode");
 
+        self.idle_watcher = None;
+        self.idle_events = None;
         if let Some(tag) = self.idle_tag.take() {
             Ok(format!("{} OK IDLE terminated\r\n", tag))
         } else {
@@ -422,8 +1947,15 @@ impl ImapSession {
         }
     }
 
-    /// Handle LIST command
-    fn handle_list(&self, tag: String, _reference: &str, pattern: &str) -> String {
+    /// Handle LIST command, with the status of each listed mailbox when
+    /// asked for (LIST-STATUS)
+    async fn handle_list(
+        &self,
+        tag: String,
+        _reference: &str,
+        pattern: &str,
+        status: &[String],
+    ) -> String {
         let username = match &self.state {
             SessionState::Authenticated { username } | SessionState::Selected { username, .. } => {
                 username.clone()
@@ -432,11 +1964,12 @@ impl ImapSession {
         };
 
         // Get all available mailboxes
-        let mailboxes = match Mailbox::list_mailboxes(&username, Path::new(&self.maildir_root)) {
+        let mailboxes = match Mailbox::list_mailboxes(&username, &self.root(&username)) {
             Ok(mboxes) => mboxes,
             Err(_) => vec!["INBOX".to_string()], // Fallback to INBOX only
         };
 
+        let roles = special_use::roles(&mailboxes);
         let mut response = String::new();
 
         // Filter mailboxes based on pattern
@@ -447,8 +1980,18 @@ impl ImapSession {
                 || pattern.is_empty();
 
             if matches {
-                // Format: * LIST (flags) "hierarchy_delimiter" "mailbox_name"
-                response.push_str(&format!("* LIST () \"/\" \"{}\"\r\n", mailbox));
+                // Format: * LIST (flags) "hierarchy_delimiter" "mailbox_name",
+                // with the RFC 6154 special-use attribute among the flags
+                let attributes = roles.get(&mailbox).map_or("", |role| role.attribute());
+                response.push_str(&format!(
+                    "* LIST ({}) \"/\" \"{}\"\r\n",
+                    attributes, mailbox
+                ));
+                if !status.is_empty() {
+                    if let Some(line) = self.mailbox_status(&username, &mailbox, status).await {
+                        response.push_str(&line);
+                    }
+                }
             }
         }
 
@@ -456,6 +1999,155 @@ impl ImapSession {
         response
     }
 
+    /// Handle STATUS command
+    async fn handle_status(&self, tag: String, mailbox: &str, items: &[String]) -> String {
+        let Some(username) = self.username() else {
+            return format!("{} BAD Not authenticated\r\n", tag);
+        };
+        match self.mailbox_status(username, mailbox, items).await {
+            Some(status) => format!("{}{} OK STATUS completed\r\n", status, tag),
+            None => format!("{} NO [NONEXISTENT] Mailbox does not exist\r\n", tag),
+        }
+    }
+
+    /// `* STATUS` response of `mailbox`, None if it doesn't exist
+    ///
+    /// Counts come from the metadata index once the user's mailbox is
+    /// indexed; UIDs, and counts without an index, from the folder itself.
+    async fn mailbox_status(
+        &self,
+        username: &str,
+        mailbox: &str,
+        items: &[String],
+    ) -> Option<String> {
+        let root = self.root(username);
+        let name = Mailbox::list_mailboxes(username, &root)
+            .ok()?
+            .into_iter()
+            .find(|name| {
+                name == mailbox || (name == "INBOX" && mailbox.eq_ignore_ascii_case("INBOX"))
+            })?;
+
+        let counts = match &self.metadata {
+            Some(index) => match index.is_indexed(username).await {
+                Ok(true) => index.folder_counts(username, &name).await.ok(),
+                Ok(false) => None,
+                Err(e) => {
+                    warn!("Failed to read metadata index of {}: {}", username, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let needs_folder =
+            counts.is_none() || items.iter().any(|item| item.starts_with("UID"));
+        let folder = if needs_folder {
+            Some(Mailbox::open(username, &name, &root).ok()?)
+        } else {
+            None
+        };
+
+        let mut values = Vec::new();
+        for item in items {
+            let value = match (item.as_str(), &counts, &folder) {
+                ("MESSAGES", Some(counts), _) => counts.messages as u64,
+                ("UNSEEN", Some(counts), _) => counts.unseen as u64,
+                ("RECENT", Some(counts), _) => counts.recent as u64,
+                ("SIZE", Some(counts), _) => counts.size,
+                ("MESSAGES", None, Some(folder)) => folder.message_count() as u64,
+                ("UNSEEN", None, Some(folder)) => folder.unseen_count() as u64,
+                ("RECENT", None, Some(folder)) => {
+                    folder.messages().iter().filter(|msg| msg.recent).count() as u64
+                }
+                ("SIZE", None, Some(folder)) => {
+                    folder.messages().iter().map(|msg| msg.size as u64).sum()
+                }
+                ("UIDNEXT", _, Some(folder)) => folder.uid_next() as u64,
+                ("UIDVALIDITY", _, Some(folder)) => folder.uid_validity() as u64,
+                _ => continue,
+            };
+            values.push(format!("{} {}", item, value));
+        }
+        Some(format!("* STATUS \"{}\" ({})\r\n", name, values.join(" ")))
+    }
+
+    /// Folders of `username` and the directory holding its subscriptions
+    fn folders(&self, username: &str) -> (Vec<String>, PathBuf) {
+        let root = self.root(username);
+        let folders =
+            Mailbox::list_mailboxes(username, &root).unwrap_or_else(|_| vec!["INBOX".to_string()]);
+        (folders, root.join(username))
+    }
+
+    /// Handle LSUB command
+    fn handle_lsub(&self, tag: String, reference: &str, pattern: &str) -> Result<String, MailError> {
+        let Some(username) = self.username() else {
+            return Ok(format!("{} BAD Not authenticated\r\n", tag));
+        };
+        let (folders, user_dir) = self.folders(username);
+
+        let pattern = format!("{}{}", reference, pattern);
+        let mut response = String::new();
+        for mailbox in subscriptions::subscribed(&user_dir, &folders)? {
+            if subscriptions::matches(&pattern, &mailbox) {
+                // Subscribed names of folders that no longer exist can't be
+                // selected
+                let attributes = if folders.contains(&mailbox) {
+                    ""
+                } else {
+                    "\\Noselect"
+                };
+                response.push_str(&format!(
+                    "* LSUB ({}) \"{}\" \"{}\"\r\n",
+                    attributes,
+                    subscriptions::DELIMITER,
+                    mailbox
+                ));
+            }
+        }
+
+        response.push_str(&format!("{} OK LSUB completed\r\n", tag));
+        Ok(response)
+    }
+
+    /// Handle SUBSCRIBE command
+    fn handle_subscribe(&self, tag: String, mailbox: &str) -> Result<String, MailError> {
+        let Some(username) = self.username() else {
+            return Ok(format!("{} BAD Not authenticated\r\n", tag));
+        };
+        let (folders, user_dir) = self.folders(username);
+
+        subscriptions::subscribe(&user_dir, &folders, mailbox)?;
+        debug!("{} subscribed to {}", username, mailbox);
+        Ok(format!("{} OK SUBSCRIBE completed\r\n", tag))
+    }
+
+    /// Handle UNSUBSCRIBE command
+    fn handle_unsubscribe(&self, tag: String, mailbox: &str) -> Result<String, MailError> {
+        let Some(username) = self.username() else {
+            return Ok(format!("{} BAD Not authenticated\r\n", tag));
+        };
+        let (folders, user_dir) = self.folders(username);
+
+        if !subscriptions::unsubscribe(&user_dir, &folders, mailbox)? {
+            return Ok(format!("{} NO Not subscribed to {}\r\n", tag, mailbox));
+        }
+        debug!("{} unsubscribed from {}", username, mailbox);
+        Ok(format!("{} OK UNSUBSCRIBE completed\r\n", tag))
+    }
+
+    /// Handle NAMESPACE command
+    ///
+    /// Every folder lives in the personal namespace; there are no other
+    /// users' or shared namespaces.
+    fn handle_namespace(&self, tag: String) -> String {
+        format!(
+            "* NAMESPACE ((\"\" \"{}\")) NIL NIL\r\n{} OK NAMESPACE completed\r\n",
+            subscriptions::DELIMITER,
+            tag
+        )
+    }
+
     /// Handle LOGOUT command
     fn handle_logout(&mut self, tag: String) -> String {
         info!("LOGOUT");
@@ -464,3 +2156,81 @@ impl ImapSession {
         format!("* BYE IMAP4rev1 Server logging out\r\n{} OK LOGOUT completed\r\n", tag)
     }
 }
+
+/// Untagged QUOTA response with usage and limit in units of 1024 bytes
+fn quota_response(root: &str, quota: &UserQuota) -> String {
+    format!(
+        "* QUOTA \"{}\" (STORAGE {} {})\r\n",
+        root,
+        quota.storage_used.div_ceil(1024),
+        quota.storage_limit / 1024
+    )
+}
+
+/// UID set for COPYUID, in the given order with ascending runs joined,
+/// e.g. `4:6,2`
+fn uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if uid == *end + 1 => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}:{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Command name prefix in completion responses of UID commands
+fn uid_prefix(by_uid: bool) -> &'static str {
+    if by_uid {
+        "UID "
+    } else {
+        ""
+    }
+}
+
+/// Host name for CRAM-MD5 challenges
+fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+/// Decode a base64 SASL response holding UTF-8 text
+fn decode_text(response: &str) -> Option<String> {
+    BASE64
+        .decode(response)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+}
+
+/// Refusal of a login the anomaly detector blocks
+fn anomaly_refused(tag: String, username: &str, command: &str) -> String {
+    info!("{} refused for {}: sign-in from an unusual location blocked", command, username);
+    format!(
+        "{} NO [AUTHENTICATIONFAILED] Sign-in from an unusual location blocked, try again later\r\n",
+        tag
+    )
+}
+
+/// Refusal of a challenge-response mechanism for a user with two-factor
+/// authentication, which only password mechanisms can carry
+fn mfa_refused(tag: String, username: &str, mechanism: AuthMechanism) -> String {
+    info!(
+        "AUTHENTICATE {} refused for {}: two-factor authentication enabled",
+        mechanism.as_str(),
+        username
+    );
+    format!(
+        "{} NO [AUTHENTICATIONFAILED] Two-factor authentication required, use PLAIN or LOGIN with password:code\r\n",
+        tag
+    )
+}
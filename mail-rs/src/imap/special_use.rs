@@ -0,0 +1,181 @@
+//! Special-use mailboxes (RFC 6154)
+//!
+//! The first time a user logs in, the standard folders Sent, Drafts, Trash,
+//! Junk and Archive are created, and LIST marks them with their special-use
+//! attribute (`\Sent`, `\Drafts`, ...) so clients use them without manual
+//! configuration. Folders other clients created under common alternative
+//! names, such as "Sent Items" or "Spam", are recognized as well and keep
+//! a second copy from being created.
+//!
+//! A `mail-rs-provisioned` marker in the user's maildir records that the
+//! folders were created, so folders the user deletes later stay deleted.
+
+use crate::error::MailError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Marker file written once the standard folders were created
+pub const PROVISIONED_FILE: &str = "mail-rs-provisioned";
+
+/// Role of a special-use mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpecialUse {
+    Sent,
+    Drafts,
+    Trash,
+    Junk,
+    Archive,
+}
+
+/// Folder names recognized per role, preferred name first
+const NAMES: &[(SpecialUse, &[&str])] = &[
+    (SpecialUse::Sent, &["Sent", "Sent Items", "Sent Messages"]),
+    (SpecialUse::Drafts, &["Drafts"]),
+    (
+        SpecialUse::Trash,
+        &["Trash", "Deleted Items", "Deleted Messages"],
+    ),
+    (SpecialUse::Junk, &["Junk", "Spam", "Junk E-mail"]),
+    (SpecialUse::Archive, &["Archive", "Archives"]),
+];
+
+impl SpecialUse {
+    /// All roles, in the order the folders are created
+    pub const ALL: [SpecialUse; 5] = [
+        SpecialUse::Sent,
+        SpecialUse::Drafts,
+        SpecialUse::Trash,
+        SpecialUse::Junk,
+        SpecialUse::Archive,
+    ];
+
+    /// LIST attribute of the role
+    pub fn attribute(self) -> &'static str {
+        match self {
+            SpecialUse::Sent => "\\Sent",
+            SpecialUse::Drafts => "\\Drafts",
+            SpecialUse::Trash => "\\Trash",
+            SpecialUse::Junk => "\\Junk",
+            SpecialUse::Archive => "\\Archive",
+        }
+    }
+
+    /// Name of the folder created for the role
+    pub fn folder(self) -> &'static str {
+        self.names()[0]
+    }
+
+    fn names(self) -> &'static [&'static str] {
+        NAMES
+            .iter()
+            .find(|(role, _)| *role == self)
+            .map(|(_, names)| *names)
+            .unwrap_or_default()
+    }
+}
+
+/// Special-use roles of `mailboxes`, by mailbox name
+///
+/// Each role goes to at most one mailbox: the first of its recognized
+/// names present (compared case-insensitively), so a user with both
+/// "Sent" and "Sent Items" gets `\Sent` on "Sent" only.
+pub fn roles(mailboxes: &[String]) -> HashMap<String, SpecialUse> {
+    let mut roles = HashMap::new();
+    for &(role, names) in NAMES {
+        let found = names.iter().find_map(|name| {
+            mailboxes
+                .iter()
+                .find(|mailbox| mailbox.eq_ignore_ascii_case(name))
+        });
+        if let Some(mailbox) = found {
+            roles.insert(mailbox.clone(), role);
+        }
+    }
+    roles
+}
+
+/// Create the standard folders of `email` unless done before
+///
+/// Roles already covered by an existing folder are skipped. Returns the
+/// roles whose folder was created.
+pub fn provision(email: &str, maildir_root: &Path) -> Result<Vec<SpecialUse>, MailError> {
+    let user_maildir = maildir_root.join(email);
+    let marker = user_maildir.join(PROVISIONED_FILE);
+    if marker.exists() {
+        return Ok(Vec::new());
+    }
+
+    for dir in ["tmp", "new", "cur"] {
+        fs::create_dir_all(user_maildir.join(dir))?;
+    }
+
+    let existing = crate::imap::Mailbox::list_mailboxes(email, maildir_root)?;
+    let covered = roles(&existing);
+    let mut created = Vec::new();
+    for role in SpecialUse::ALL {
+        if covered.values().any(|covered| *covered == role) {
+            continue;
+        }
+        let folder = user_maildir.join(format!(".{}", role.folder()));
+        for dir in ["tmp", "new", "cur"] {
+            fs::create_dir_all(folder.join(dir))?;
+        }
+        created.push(role);
+    }
+
+    fs::write(&marker, b"")?;
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imap::Mailbox;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_provision_once() {
+        let root = TempDir::new().unwrap();
+        let user = root.path().join("alice@example.com");
+        fs::create_dir_all(user.join(".Sent Items/cur")).unwrap();
+
+        let created = provision("alice@example.com", root.path()).unwrap();
+        assert_eq!(
+            created,
+            [
+                SpecialUse::Drafts,
+                SpecialUse::Trash,
+                SpecialUse::Junk,
+                SpecialUse::Archive
+            ]
+        );
+        assert!(user.join(".Archive/tmp").is_dir());
+        assert!(!user.join(".Sent").exists());
+
+        // Deleted folders are not recreated on the next login
+        fs::remove_dir_all(user.join(".Junk")).unwrap();
+        assert!(provision("alice@example.com", root.path())
+            .unwrap()
+            .is_empty());
+        assert!(!user.join(".Junk").exists());
+
+        let mailboxes = Mailbox::list_mailboxes("alice@example.com", root.path()).unwrap();
+        let roles = roles(&mailboxes);
+        assert_eq!(roles.get("Sent Items"), Some(&SpecialUse::Sent));
+        assert_eq!(roles.get("Trash"), Some(&SpecialUse::Trash));
+        assert_eq!(roles.get("INBOX"), None);
+        assert_eq!(roles.len(), 4);
+    }
+
+    #[test]
+    fn test_roles_prefer_standard_names() {
+        let mailboxes = ["sent items", "SENT", "Spam", "Work"].map(String::from);
+        let roles = roles(&mailboxes);
+        assert_eq!(roles.get("SENT"), Some(&SpecialUse::Sent));
+        assert_eq!(roles.get("Spam"), Some(&SpecialUse::Junk));
+        assert_eq!(roles.len(), 2);
+        assert_eq!(SpecialUse::Junk.attribute(), "\\Junk");
+        assert_eq!(SpecialUse::Archive.folder(), "Archive");
+    }
+}
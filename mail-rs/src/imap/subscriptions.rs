@@ -0,0 +1,151 @@
+//! Folder subscriptions (SUBSCRIBE, UNSUBSCRIBE, LSUB)
+//!
+//! Subscribed folder names are kept one per line in a
+//! `mail-rs-subscriptions` file in the user's maildir. Until the user
+//! subscribes or unsubscribes for the first time there is no file, and every
+//! existing folder counts as subscribed, so clients that only show
+//! subscribed folders see the standard folders right away.
+
+use crate::error::MailError;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Subscription list file name inside a user's maildir
+pub const SUBSCRIPTIONS_FILE: &str = "mail-rs-subscriptions";
+
+/// Hierarchy delimiter announced in LIST, LSUB and NAMESPACE
+pub const DELIMITER: char = '/';
+
+/// Subscribed folders of the user whose maildir is `user_dir`, sorted
+///
+/// `existing` are the user's folders, subscribed by default.
+pub fn subscribed(user_dir: &Path, existing: &[String]) -> Result<Vec<String>, MailError> {
+    Ok(load(user_dir, existing)?.into_iter().collect())
+}
+
+/// Add `mailbox` to the subscriptions
+///
+/// Names of folders that don't exist (yet) are accepted, as RFC 3501
+/// allows.
+pub fn subscribe(user_dir: &Path, existing: &[String], mailbox: &str) -> Result<(), MailError> {
+    let mut names = load(user_dir, existing)?;
+    names.insert(normalize(mailbox));
+    save(user_dir, &names)
+}
+
+/// Remove `mailbox` from the subscriptions; returns whether it was
+/// subscribed
+pub fn unsubscribe(user_dir: &Path, existing: &[String], mailbox: &str) -> Result<bool, MailError> {
+    let mut names = load(user_dir, existing)?;
+    if !names.remove(&normalize(mailbox)) {
+        return Ok(false);
+    }
+    save(user_dir, &names)?;
+    Ok(true)
+}
+
+/// Whether `name` matches the LIST/LSUB `pattern`
+///
+/// `*` matches any characters, `%` any characters except the hierarchy
+/// delimiter. INBOX matches case-insensitively.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = if name.eq_ignore_ascii_case("INBOX") {
+        (pattern.to_uppercase(), name.to_uppercase())
+    } else {
+        (pattern.to_string(), name.to_string())
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_from(&pattern, &name)
+}
+
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches_from(&pattern[1..], &name[skip..])),
+        Some('%') => (0..=name.len())
+            .take_while(|&skip| skip == 0 || name[skip - 1] != DELIMITER)
+            .any(|skip| matches_from(&pattern[1..], &name[skip..])),
+        Some(c) => name.first() == Some(c) && matches_from(&pattern[1..], &name[1..]),
+    }
+}
+
+/// INBOX is case-insensitive, every other name is kept as given
+fn normalize(mailbox: &str) -> String {
+    if mailbox.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        mailbox.to_string()
+    }
+}
+
+fn load(user_dir: &Path, existing: &[String]) -> Result<BTreeSet<String>, MailError> {
+    match fs::read_to_string(user_dir.join(SUBSCRIPTIONS_FILE)) {
+        Ok(text) => Ok(text
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(normalize)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(existing.iter().map(|name| normalize(name)).collect())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(user_dir: &Path, names: &BTreeSet<String>) -> Result<(), MailError> {
+    fs::create_dir_all(user_dir)?;
+    let tmp = user_dir.join(format!("{}.tmp", SUBSCRIPTIONS_FILE));
+    let mut file = fs::File::create(&tmp)?;
+    for name in names {
+        writeln!(file, "{}", name)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, user_dir.join(SUBSCRIPTIONS_FILE))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscriptions_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = vec!["INBOX".to_string(), "Sent".to_string()];
+
+        // Every folder is subscribed until the list is first changed
+        assert_eq!(subscribed(dir.path(), &existing).unwrap(), existing);
+
+        subscribe(dir.path(), &existing, "Lists/rust").unwrap();
+        assert!(unsubscribe(dir.path(), &existing, "Sent").unwrap());
+        assert!(!unsubscribe(dir.path(), &existing, "Sent").unwrap());
+
+        // Later folders are no longer subscribed implicitly
+        let existing = vec!["INBOX".to_string(), "Sent".to_string(), "Work".to_string()];
+        assert_eq!(
+            subscribed(dir.path(), &existing).unwrap(),
+            vec!["INBOX", "Lists/rust"]
+        );
+        assert!(unsubscribe(dir.path(), &existing, "inbox").unwrap());
+        assert_eq!(
+            subscribed(dir.path(), &existing).unwrap(),
+            vec!["Lists/rust"]
+        );
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert!(matches("*", "Lists/rust"));
+        assert!(matches("Lists/*", "Lists/rust"));
+        assert!(matches("%", "Sent"));
+        assert!(!matches("%", "Lists/rust"));
+        assert!(matches("Lists/%", "Lists/rust"));
+        assert!(matches("S%t", "Sent"));
+        assert!(matches("inbox", "INBOX"));
+        assert!(!matches("sent", "Sent"));
+        assert!(!matches("Sent", "Sent Items"));
+    }
+}
@@ -0,0 +1,287 @@
+//! Persistent IMAP UIDs
+//!
+//! Every Maildir folder keeps a `mail-rs-uidlist` file next to its `new/`
+//! and `cur/` directories. It maps message base names (the file name
+//! without the `:2,` flags suffix, which changes with the flags) to UIDs
+//! and records the folder's UIDVALIDITY and next UID:
+//!
+//! ```text
+//! 1 1700000000 43
+//! 41 1700000123.M1P2Q0.mail.example.com
+//! 42 1700000456.M7P2Q1.mail.example.com
+//! ```
+//!
+//! The first line holds the format version, UIDVALIDITY and UIDNEXT. New
+//! messages get UIDs in file name order (Maildir names start with the
+//! delivery time) when a folder is opened; UIDs are never reused. A missing
+//! or unreadable list starts over with a new UIDVALIDITY, which tells
+//! clients to drop their caches.
+
+use crate::error::MailError;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// UID list file name inside a Maildir folder
+pub const UIDLIST_FILE: &str = "mail-rs-uidlist";
+
+/// Lock serializing UID assignment between sessions and processes
+const LOCK_FILE: &str = "mail-rs-uidlist.lock";
+
+const VERSION: u32 = 1;
+
+/// UIDs of one folder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UidList {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    /// Base name -> UID
+    uids: BTreeMap<String, u32>,
+}
+
+impl UidList {
+    /// Empty list with the given UIDVALIDITY
+    pub fn new(uid_validity: u32) -> Self {
+        Self {
+            uid_validity,
+            uid_next: 1,
+            uids: BTreeMap::new(),
+        }
+    }
+
+    /// UID of a message file, with or without its flags suffix
+    pub fn uid(&self, filename: &str) -> Option<u32> {
+        self.uids.get(base_name(filename)).copied()
+    }
+
+    /// Assign UIDs to `filenames` not seen before and forget those no
+    /// longer present; returns whether the list changed
+    pub fn sync<S: AsRef<str>>(&mut self, filenames: &[S]) -> bool {
+        let present: HashSet<&str> = filenames.iter().map(|f| base_name(f.as_ref())).collect();
+
+        let before = self.uids.len();
+        self.uids.retain(|base, _| present.contains(base.as_str()));
+        let mut changed = self.uids.len() != before;
+
+        let mut new: Vec<&str> = present
+            .into_iter()
+            .filter(|base| !self.uids.contains_key(*base))
+            .collect();
+        new.sort_unstable();
+        for base in new {
+            self.uids.insert(base.to_string(), self.uid_next);
+            self.uid_next += 1;
+            changed = true;
+        }
+        changed
+    }
+
+    /// UIDs of `filenames`, assigning the next ones, in the given order, to
+    /// files that have none yet
+    pub fn assign<S: AsRef<str>>(&mut self, filenames: &[S]) -> Vec<u32> {
+        filenames
+            .iter()
+            .map(|filename| {
+                let base = base_name(filename.as_ref());
+                *self.uids.entry(base.to_string()).or_insert_with(|| {
+                    self.uid_next += 1;
+                    self.uid_next - 1
+                })
+            })
+            .collect()
+    }
+
+    /// Move the UID of message file `old` to file name `new`; returns
+    /// whether `old` had one
+    pub fn rename(&mut self, old: &str, new: &str) -> bool {
+        match self.uids.remove(base_name(old)) {
+            Some(uid) => {
+                self.uids.insert(base_name(new).to_string(), uid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Load the list of `folder`, sync it with `filenames` and save it
+    ///
+    /// Runs under an exclusive lock, so concurrent sessions never hand out
+    /// the same UID twice.
+    pub fn sync_folder<S: AsRef<str>>(folder: &Path, filenames: &[S]) -> Result<Self, MailError> {
+        Self::update_folder(folder, |list| list.sync(filenames))
+    }
+
+    /// Assign UIDs to messages just saved to `folder`, in order, and return
+    /// the list with their UIDs
+    ///
+    /// APPEND and COPY report the UIDs to the client (UIDPLUS). A session
+    /// opening the folder meanwhile may have assigned them already, in
+    /// which case those are returned.
+    pub fn add_messages<S: AsRef<str>>(
+        folder: &Path,
+        filenames: &[S],
+    ) -> Result<(Self, Vec<u32>), MailError> {
+        let mut uids = Vec::new();
+        let list = Self::update_folder(folder, |list| {
+            let uid_next = list.uid_next;
+            uids = list.assign(filenames);
+            list.uid_next != uid_next
+        })?;
+        Ok((list, uids))
+    }
+
+    /// Rename a message file of `folder` (`from` and `to` are paths in its
+    /// `new/` or `cur/`) without changing its UID
+    ///
+    /// The rename happens under the list's lock, so a session opening the
+    /// folder meanwhile can't mistake the file for a new message.
+    pub fn rename_message(folder: &Path, from: &Path, to: &Path) -> Result<(), MailError> {
+        let name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let (old, new) = (name(from), name(to));
+
+        let mut result = Ok(());
+        Self::update_folder(folder, |list| {
+            result = fs::rename(from, to);
+            result.is_ok() && list.rename(&old, &new)
+        })?;
+        Ok(result?)
+    }
+
+    /// Load the list of `folder` under its lock, apply `update` and save the
+    /// list if `update` reports a change
+    fn update_folder(
+        folder: &Path,
+        update: impl FnOnce(&mut Self) -> bool,
+    ) -> Result<Self, MailError> {
+        let lock = fs::File::create(folder.join(LOCK_FILE))?;
+        lock.lock()?;
+
+        let path = folder.join(UIDLIST_FILE);
+        let mut list = match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).unwrap_or_else(|| {
+                warn!("Corrupt UID list {}, assigning new UIDs", path.display());
+                Self::new(new_uid_validity())
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::new(new_uid_validity()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if update(&mut list) || !path.exists() {
+            let tmp = folder.join(format!("{}.tmp", UIDLIST_FILE));
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(list.render().as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+        }
+
+        Ok(list)
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let mut header = lines.next()?.split_whitespace().map(str::parse::<u32>);
+        if header.next()?.ok()? != VERSION {
+            return None;
+        }
+        let mut list = Self::new(header.next()?.ok()?);
+        list.uid_next = header.next()?.ok()?;
+
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (uid, base) = line.split_once(' ')?;
+            let uid: u32 = uid.parse().ok()?;
+            if uid == 0 || uid >= list.uid_next {
+                return None;
+            }
+            list.uids.insert(base.to_string(), uid);
+        }
+        Some(list)
+    }
+
+    fn render(&self) -> String {
+        let mut entries: Vec<(&u32, &String)> = self.uids.iter().map(|(b, u)| (u, b)).collect();
+        entries.sort_unstable();
+
+        let mut text = format!("{} {} {}\n", VERSION, self.uid_validity, self.uid_next);
+        for (uid, base) in entries {
+            text.push_str(&format!("{} {}\n", uid, base));
+        }
+        text
+    }
+}
+
+/// Message file name without the Maildir flags suffix
+pub fn base_name(filename: &str) -> &str {
+    filename.split(":2,").next().unwrap_or(filename)
+}
+
+/// UIDVALIDITY for a new list: the current time, like most servers
+fn new_uid_validity() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(1)
+        .max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sync_assigns_stable_uids() {
+        let mut list = UidList::new(7);
+        assert!(list.sync(&["200.b", "100.a:2,S"]));
+        assert_eq!(list.uid("100.a"), Some(1));
+        assert_eq!(list.uid("200.b:2,FS"), Some(2));
+        assert!(!list.sync(&["100.a:2,RS", "200.b"]));
+
+        // Expunged UIDs are not reused
+        assert!(list.sync(&["200.b", "300.c"]));
+        assert_eq!(list.uid("100.a"), None);
+        assert_eq!(list.uid("300.c"), Some(3));
+        assert_eq!(list.uid_next, 4);
+
+        assert_eq!(UidList::parse(&list.render()), Some(list));
+        assert_eq!(UidList::parse("1 7 2\n5 too.high\n"), None);
+        assert_eq!(UidList::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_sync_folder_persists() {
+        let dir = TempDir::new().unwrap();
+
+        let first = UidList::sync_folder(dir.path(), &["1.a", "2.b"]).unwrap();
+        let second = UidList::sync_folder(dir.path(), &["2.b:2,S", "3.c"]).unwrap();
+        assert_eq!(second.uid_validity, first.uid_validity);
+        assert_eq!(second.uid("2.b"), Some(2));
+        assert_eq!(second.uid("3.c"), Some(3));
+
+        // Renamed files keep their UID
+        fs::create_dir(dir.path().join("cur")).unwrap();
+        fs::write(dir.path().join("cur/3.c"), "").unwrap();
+        let cur = dir.path().join("cur");
+        UidList::rename_message(dir.path(), &cur.join("3.c"), &cur.join("4.d:2,S")).unwrap();
+        let renamed = UidList::sync_folder(dir.path(), &["2.b", "4.d:2,S"]).unwrap();
+        assert_eq!(renamed.uid("4.d"), Some(3));
+        assert_eq!(renamed.uid_next, 4);
+
+        // Saved messages get UIDs in the order given
+        let (list, uids) = UidList::add_messages(dir.path(), &["9.z", "5.e", "2.b"]).unwrap();
+        assert_eq!(uids, vec![4, 5, 2]);
+        assert_eq!(list.uid_next, 6);
+        let synced = UidList::sync_folder(dir.path(), &["2.b", "4.d", "5.e", "9.z"]).unwrap();
+        assert_eq!(synced.uid("9.z"), Some(4));
+
+        fs::write(dir.path().join(UIDLIST_FILE), "garbage").unwrap();
+        let reset = UidList::sync_folder(dir.path(), &["4.d"]).unwrap();
+        assert_eq!(reset.uid("4.d"), Some(1));
+    }
+}
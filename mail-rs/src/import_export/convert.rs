@@ -0,0 +1,708 @@
+//! Offline mailbox format conversion
+//!
+//! Converts a mailbox on disk between Maildir, Maildir++ and mbox without
+//! going through the HTTP import pipeline, e.g. to restore mail taken from
+//! another system. Folders are named with `/` as hierarchy separator and
+//! laid out as follows:
+//!
+//! | Format      | INBOX          | Folder `Work/Projects`     |
+//! |-------------|----------------|----------------------------|
+//! | `maildir`   | `<root>/`      | `<root>/Work/Projects/`    |
+//! | `maildir++` | `<root>/`      | `<root>/.Work.Projects/`   |
+//! | `mbox`      | `<root>/INBOX` | `<root>/Work.sbd/Projects` |
+//!
+//! `maildir++` is the layout the server itself uses. An mbox source may
+//! also be a single file, read as INBOX; an mbox destination is written as
+//! a single file when only one folder is converted.
+//!
+//! Flags map between Maildir info letters and the `Status:`/`X-Status:`
+//! headers of mbox clients, and internal dates between file modification
+//! times and the mbox `From ` line.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use super::manager::{extract_date_header, extract_from_header};
+use super::mbox::{MboxReader, MboxWriter};
+
+/// Distinguishes messages written within the same microsecond
+static FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// On-disk mailbox format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxFormat {
+    /// One Maildir per folder, nested in directories
+    Maildir,
+    /// Folders as dot-separated `.Name` Maildirs inside the INBOX Maildir
+    MaildirPlusPlus,
+    /// One mbox file per folder
+    Mbox,
+}
+
+impl MailboxFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "maildir" => Some(Self::Maildir),
+            "maildir++" => Some(Self::MaildirPlusPlus),
+            "mbox" => Some(Self::Mbox),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Maildir => "maildir",
+            Self::MaildirPlusPlus => "maildir++",
+            Self::Mbox => "mbox",
+        }
+    }
+}
+
+/// A mailbox on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxLocation {
+    pub format: MailboxFormat,
+    pub path: PathBuf,
+}
+
+impl MailboxLocation {
+    /// Parse `<format>:<path>`, e.g. `maildir++:/var/mail/alice@example.com`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let format = spec
+            .split_once(':')
+            .and_then(|(format, path)| Some((MailboxFormat::parse(format)?, path)));
+        match format {
+            Some((format, path)) if !path.is_empty() => Ok(Self {
+                format,
+                path: PathBuf::from(path),
+            }),
+            _ => Err(anyhow!(
+                "Invalid mailbox '{}', expected maildir:<path>, maildir++:<path> or mbox:<path>",
+                spec
+            )),
+        }
+    }
+}
+
+/// Conversion options
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Only convert these source folders (all when empty)
+    pub folders: Vec<String>,
+    /// Source folder -> destination folder renames
+    pub mapping: HashMap<String, String>,
+}
+
+/// A converted folder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConvertedFolder {
+    pub source: String,
+    pub destination: String,
+    pub messages: u64,
+}
+
+/// Result of a conversion
+#[derive(Debug, Clone, Default)]
+pub struct ConvertReport {
+    pub folders: Vec<ConvertedFolder>,
+}
+
+impl ConvertReport {
+    /// Messages converted in all folders
+    pub fn messages(&self) -> u64 {
+        self.folders.iter().map(|folder| folder.messages).sum()
+    }
+}
+
+/// A message and the state both formats can carry
+struct Message {
+    content: Vec<u8>,
+    /// Maildir info letters, e.g. `FS`
+    flags: String,
+    /// Not yet seen by any client (Maildir `new/`)
+    recent: bool,
+    internal_date: Option<SystemTime>,
+}
+
+/// Convert the mailbox at `source` into `destination`
+///
+/// Messages are added to whatever the destination already holds; mbox
+/// files are appended to.
+pub fn convert(
+    source: &MailboxLocation,
+    destination: &MailboxLocation,
+    options: &ConvertOptions,
+) -> Result<ConvertReport> {
+    let mut folders = list_folders(source)?;
+    if fs::canonicalize(&source.path)? == fs::canonicalize(&destination.path).unwrap_or_default() {
+        bail!("Source and destination are the same");
+    }
+    for folder in &options.folders {
+        if !folders.contains(folder) {
+            bail!("Folder not found: {}", folder);
+        }
+    }
+    if !options.folders.is_empty() {
+        folders.retain(|folder| options.folders.contains(folder));
+    }
+
+    let single_file = destination.format == MailboxFormat::Mbox && folders.len() == 1;
+    let mut report = ConvertReport::default();
+    for folder in folders {
+        let target = canonical_name(options.mapping.get(&folder).unwrap_or(&folder));
+        validate_folder_name(&target)?;
+
+        let mut writer = FolderWriter::open(destination, &target, single_file)?;
+        let mut messages = 0;
+        read_folder(source, &folder, |message| {
+            writer.write(message)?;
+            messages += 1;
+            Ok(())
+        })?;
+        writer.finish()?;
+
+        report.folders.push(ConvertedFolder {
+            source: folder,
+            destination: target,
+            messages,
+        });
+    }
+    Ok(report)
+}
+
+/// Folders of a mailbox, INBOX first
+pub fn list_folders(location: &MailboxLocation) -> Result<Vec<String>> {
+    let root = &location.path;
+    if !root.exists() {
+        bail!("Mailbox not found: {}", root.display());
+    }
+
+    let mut folders = Vec::new();
+    match location.format {
+        MailboxFormat::Maildir => {
+            if is_maildir(root) {
+                folders.push("INBOX".to_string());
+            }
+            list_nested_maildirs(root, "", &mut folders)?;
+        }
+        MailboxFormat::MaildirPlusPlus => {
+            if is_maildir(root) {
+                folders.push("INBOX".to_string());
+            }
+            for entry in fs::read_dir(root)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if let Some(name) = name.strip_prefix('.') {
+                    if !name.is_empty() && is_maildir(&entry.path()) {
+                        folders.push(name.replace('.', "/"));
+                    }
+                }
+            }
+        }
+        MailboxFormat::Mbox if root.is_file() => folders.push("INBOX".to_string()),
+        MailboxFormat::Mbox => list_mbox_files(root, "", &mut folders)?,
+    }
+
+    folders.sort_by(|a, b| (a != "INBOX", a).cmp(&(b != "INBOX", b)));
+    Ok(folders)
+}
+
+fn is_maildir(path: &Path) -> bool {
+    path.join("cur").is_dir() || path.join("new").is_dir()
+}
+
+fn list_nested_maildirs(dir: &Path, prefix: &str, folders: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.path().is_dir()
+            || name.starts_with('.')
+            || ["cur", "new", "tmp"].contains(&name.as_str())
+        {
+            continue;
+        }
+
+        let folder = format!("{}{}", prefix, name);
+        if is_maildir(&entry.path()) {
+            folders.push(folder.clone());
+        }
+        list_nested_maildirs(&entry.path(), &format!("{}/", folder), folders)?;
+    }
+    Ok(())
+}
+
+fn list_mbox_files(dir: &Path, prefix: &str, folders: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Skip hidden files and Thunderbird's summary files
+        if name.starts_with('.') || name.ends_with(".msf") {
+            continue;
+        }
+
+        if entry.path().is_dir() {
+            if let Some(parent) = name.strip_suffix(".sbd") {
+                list_mbox_files(&entry.path(), &format!("{}{}/", prefix, parent), folders)?;
+            }
+        } else {
+            folders.push(canonical_name(&format!("{}{}", prefix, name)));
+        }
+    }
+    Ok(())
+}
+
+/// Folder name with INBOX spelled in upper case
+fn canonical_name(folder: &str) -> String {
+    if folder.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        folder.to_string()
+    }
+}
+
+fn validate_folder_name(folder: &str) -> Result<()> {
+    let valid = folder
+        .split('/')
+        .all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains(['\\', '\0']));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid folder name: {}", folder))
+    }
+}
+
+/// Path of `folder` in a mailbox
+fn folder_path(location: &MailboxLocation, folder: &str, single_file: bool) -> PathBuf {
+    let root = &location.path;
+    match location.format {
+        _ if folder == "INBOX" && location.format != MailboxFormat::Mbox => root.clone(),
+        MailboxFormat::Maildir => root.join(folder),
+        MailboxFormat::MaildirPlusPlus => root.join(format!(".{}", folder.replace('/', "."))),
+        MailboxFormat::Mbox if single_file => root.clone(),
+        MailboxFormat::Mbox => {
+            let mut parts: Vec<String> = folder.split('/').map(str::to_string).collect();
+            let name = parts.pop().unwrap_or_default();
+            let mut path = root.clone();
+            for parent in parts {
+                path.push(format!("{}.sbd", parent));
+            }
+            path.join(name)
+        }
+    }
+}
+
+/// Call `visit` with every message of `folder`, oldest file name first
+fn read_folder(
+    location: &MailboxLocation,
+    folder: &str,
+    mut visit: impl FnMut(Message) -> Result<()>,
+) -> Result<()> {
+    let path = folder_path(location, folder, location.path.is_file());
+
+    if location.format == MailboxFormat::Mbox {
+        let mut reader = MboxReader::new(File::open(&path)?);
+        while let Some(message) = reader.read_message()? {
+            let (content, flags, recent) = take_status_headers(&message.content);
+            let date = message.date.or_else(|| extract_date_header(&content));
+            visit(Message {
+                content,
+                flags,
+                recent,
+                internal_date: date.map(SystemTime::from),
+            })?;
+        }
+        return Ok(());
+    }
+
+    for subdir in ["new", "cur"] {
+        let dir = path.join(subdir);
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut entries: Vec<_> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let flags = match name.split_once(":2,") {
+                Some((_, flags)) if subdir == "cur" => flags.to_string(),
+                _ => String::new(),
+            };
+            visit(Message {
+                content: fs::read(entry.path())?,
+                flags,
+                recent: subdir == "new",
+                internal_date: entry.metadata()?.modified().ok(),
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Destination of one folder
+enum FolderWriter {
+    Maildir(PathBuf),
+    Mbox(MboxWriter<BufWriter<File>>),
+}
+
+impl FolderWriter {
+    fn open(location: &MailboxLocation, folder: &str, single_file: bool) -> Result<Self> {
+        let path = folder_path(location, folder, single_file);
+        if location.format != MailboxFormat::Mbox {
+            for dir in ["tmp", "new", "cur"] {
+                fs::create_dir_all(path.join(dir))?;
+            }
+            return Ok(Self::Maildir(path));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self::Mbox(MboxWriter::new(BufWriter::new(file))))
+    }
+
+    fn write(&mut self, message: Message) -> Result<()> {
+        match self {
+            Self::Maildir(dir) => {
+                let name = unique_name();
+                let tmp = dir.join("tmp").join(&name);
+                fs::write(&tmp, &message.content)?;
+                if let Some(date) = message.internal_date {
+                    File::options().write(true).open(&tmp)?.set_modified(date)?;
+                }
+
+                let target = if message.recent && message.flags.is_empty() {
+                    dir.join("new").join(name)
+                } else {
+                    dir.join("cur")
+                        .join(format!("{}:2,{}", name, sorted_flags(&message.flags)))
+                };
+                fs::rename(&tmp, target)?;
+            }
+            Self::Mbox(writer) => {
+                let from = extract_from_header(&message.content).unwrap_or_default();
+                let content = add_status_headers(&message.content, &message.flags, message.recent);
+                let date = message.internal_date.map(DateTime::<Utc>::from);
+                writer.write_message(&from, date, &content)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Self::Mbox(writer) = self {
+            writer.finish().flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Maildir file name unique to this host and process
+fn unique_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+        gethostname::gethostname()
+            .to_string_lossy()
+            .replace(['/', ':'], "_")
+    )
+}
+
+/// Maildir info letters in the required ASCII order, without duplicates
+fn sorted_flags(flags: &str) -> String {
+    let mut flags: Vec<char> = flags.chars().filter(char::is_ascii_alphabetic).collect();
+    flags.sort_unstable();
+    flags.dedup();
+    flags.into_iter().collect()
+}
+
+/// Maildir flag <-> mbox `X-Status:` letter
+const X_STATUS_FLAGS: [(char, char); 4] = [('R', 'A'), ('F', 'F'), ('D', 'T'), ('T', 'D')];
+
+/// Remove the `Status:` and `X-Status:` headers from an mbox message and
+/// return it with its Maildir flags and whether it is new
+fn take_status_headers(content: &[u8]) -> (Vec<u8>, String, bool) {
+    let mut stripped = Vec::with_capacity(content.len());
+    let mut flags = String::new();
+    let mut status = None;
+    let mut in_headers = true;
+
+    for line in content.split_inclusive(|&b| b == b'\n') {
+        if in_headers && (line == b"\n" || line == b"\r\n") {
+            in_headers = false;
+        }
+        let text = String::from_utf8_lossy(line);
+        let header = |name: &str| {
+            text.get(..name.len())
+                .filter(|prefix| prefix.eq_ignore_ascii_case(name))
+                .map(|_| text[name.len()..].trim().to_string())
+        };
+
+        if in_headers {
+            if let Some(value) = header("Status:") {
+                if value.contains('R') {
+                    flags.push('S');
+                }
+                status = Some(value);
+                continue;
+            }
+            if let Some(value) = header("X-Status:") {
+                for (flag, letter) in X_STATUS_FLAGS {
+                    if value.contains(letter) {
+                        flags.push(flag);
+                    }
+                }
+                continue;
+            }
+        }
+        stripped.extend_from_slice(line);
+    }
+
+    let recent = status.is_none_or(|status| !status.contains(['O', 'R']));
+    (stripped, sorted_flags(&flags), recent)
+}
+
+/// Prepend the `Status:` and `X-Status:` headers mbox clients read flags from
+fn add_status_headers(content: &[u8], flags: &str, recent: bool) -> Vec<u8> {
+    let eol = if content.windows(2).any(|w| w == b"\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+
+    let mut headers = String::new();
+    let mut status = String::new();
+    if flags.contains('S') {
+        status.push('R');
+    }
+    if !recent {
+        status.push('O');
+    }
+    if !status.is_empty() {
+        headers.push_str(&format!("Status: {}{}", status, eol));
+    }
+    let x_status: String = X_STATUS_FLAGS
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, letter)| *letter)
+        .collect();
+    if !x_status.is_empty() {
+        headers.push_str(&format!("X-Status: {}{}", x_status, eol));
+    }
+
+    let mut result = headers.into_bytes();
+    result.extend_from_slice(content);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn location(format: MailboxFormat, path: &Path) -> MailboxLocation {
+        MailboxLocation {
+            format,
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn date(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn deliver(dir: &Path, subdir: &str, name: &str, body: &str, mtime: u64) {
+        fs::create_dir_all(dir.join("tmp")).unwrap();
+        fs::create_dir_all(dir.join(subdir)).unwrap();
+        let path = dir.join(subdir).join(name);
+        fs::write(
+            &path,
+            format!(
+                "From: Alice <alice@example.com>\r\nSubject: {}\r\n\r\n{}\r\n",
+                body, body
+            ),
+        )
+        .unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(date(mtime))
+            .unwrap();
+    }
+
+    /// (subdir, flags, mtime) of the messages of a Maildir folder
+    fn messages(dir: &Path) -> Vec<(String, String, u64)> {
+        let mut messages = Vec::new();
+        for subdir in ["new", "cur"] {
+            for entry in fs::read_dir(dir.join(subdir)).unwrap() {
+                let entry = entry.unwrap();
+                let name = entry.file_name().to_string_lossy().to_string();
+                let mtime = entry.metadata().unwrap().modified().unwrap();
+                messages.push((
+                    subdir.to_string(),
+                    name.split_once(":2,")
+                        .map(|(_, f)| f.to_string())
+                        .unwrap_or_default(),
+                    mtime
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                ));
+            }
+        }
+        messages.sort();
+        messages
+    }
+
+    #[test]
+    fn test_round_trip_preserves_flags_dates_and_folders() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("alice");
+        deliver(&source, "new", "1.a", "unread", 1_700_000_000);
+        deliver(&source, "cur", "2.b:2,FS", "flagged", 1_700_000_100);
+        deliver(
+            &source.join(".Work.Projects"),
+            "cur",
+            "3.c:2,RS",
+            "answered",
+            1_700_000_200,
+        );
+        deliver(
+            &source.join(".Sent Items"),
+            "cur",
+            "4.d:2,",
+            "old",
+            1_700_000_300,
+        );
+
+        // Maildir++ -> mbox directory
+        let mbox = dir.path().join("mbox");
+        let mut options = ConvertOptions::default();
+        options
+            .mapping
+            .insert("Sent Items".to_string(), "Sent".to_string());
+        let report = convert(
+            &location(MailboxFormat::MaildirPlusPlus, &source),
+            &location(MailboxFormat::Mbox, &mbox),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(report.messages(), 4);
+        assert_eq!(report.folders[0].source, "INBOX");
+        assert_eq!(report.folders[1].destination, "Sent");
+        assert!(mbox.join("Work.sbd/Projects").is_file());
+        let inbox = fs::read_to_string(mbox.join("INBOX")).unwrap();
+        assert!(inbox.contains("Status: RO\r\nX-Status: F\r\nFrom: Alice"));
+        assert!(inbox.starts_with("From alice@example.com Tue Nov 14 22:13:20 2023\n"));
+
+        // mbox directory -> nested Maildir
+        let maildir = dir.path().join("maildir");
+        let report = convert(
+            &location(MailboxFormat::Mbox, &mbox),
+            &location(MailboxFormat::Maildir, &maildir),
+            &ConvertOptions::default(),
+        )
+        .unwrap();
+        let folders: Vec<&str> = report.folders.iter().map(|f| f.source.as_str()).collect();
+        assert_eq!(folders, ["INBOX", "Sent", "Work/Projects"]);
+
+        assert_eq!(
+            messages(&maildir),
+            [
+                ("cur".to_string(), "FS".to_string(), 1_700_000_100),
+                ("new".to_string(), String::new(), 1_700_000_000),
+            ]
+        );
+        assert_eq!(
+            messages(&maildir.join("Work/Projects")),
+            [("cur".to_string(), "RS".to_string(), 1_700_000_200)]
+        );
+        assert_eq!(
+            messages(&maildir.join("Sent")),
+            [("cur".to_string(), String::new(), 1_700_000_300)]
+        );
+        let content = fs::read_dir(maildir.join("Sent/cur"))
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .next()
+            .unwrap();
+        assert_eq!(
+            content,
+            "From: Alice <alice@example.com>\r\nSubject: old\r\n\r\nold\r\n"
+        );
+    }
+
+    #[test]
+    fn test_single_mbox_file() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("alice");
+        deliver(
+            &source.join(".Archive"),
+            "cur",
+            "1.a:2,S",
+            "kept",
+            1_700_000_000,
+        );
+        fs::create_dir_all(source.join("new")).unwrap();
+
+        let file = dir.path().join("archive.mbox");
+        let options = ConvertOptions {
+            folders: vec!["Archive".to_string()],
+            ..Default::default()
+        };
+        let source = location(MailboxFormat::MaildirPlusPlus, &source);
+        let destination = location(MailboxFormat::Mbox, &file);
+        convert(&source, &destination, &options).unwrap();
+        assert!(file.is_file());
+
+        // A single file reads back as INBOX, into the server's layout
+        let restored = dir.path().join("bob");
+        let mut options = ConvertOptions::default();
+        options
+            .mapping
+            .insert("INBOX".to_string(), "Old/Archive".to_string());
+        convert(
+            &destination,
+            &location(MailboxFormat::MaildirPlusPlus, &restored),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            messages(&restored.join(".Old.Archive")),
+            [("cur".to_string(), "S".to_string(), 1_700_000_000)]
+        );
+
+        options.folders = vec!["Drafts".to_string()];
+        assert!(convert(&destination, &source, &options).is_err());
+        options.folders.clear();
+        options
+            .mapping
+            .insert("INBOX".to_string(), "../escape".to_string());
+        assert!(convert(&destination, &source, &options).is_err());
+        assert!(MailboxLocation::parse("pst:/tmp/x").is_err());
+        assert_eq!(
+            MailboxLocation::parse("Maildir++:/var/mail/a")
+                .unwrap()
+                .format,
+            MailboxFormat::MaildirPlusPlus
+        );
+    }
+}
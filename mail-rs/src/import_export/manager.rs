@@ -16,6 +16,7 @@ use zip::{ZipArchive, ZipWriter};
 
 use super::mbox::{MboxReader, MboxWriter, count_messages};
 use super::types::*;
+use crate::residency::ResidencyMap;
 
 /// Import/Export manager
 pub struct ImportExportManager {
@@ -29,6 +30,8 @@ pub struct ImportExportManager {
     export_path: PathBuf,
     /// Maildir root path
     maildir_root: PathBuf,
+    /// Region-specific maildir and export locations
+    residency: Option<Arc<ResidencyMap>>,
 }
 
 impl ImportExportManager {
@@ -40,6 +43,30 @@ impl ImportExportManager {
             stats: Arc::new(RwLock::new(ImportExportStats::default())),
             export_path,
             maildir_root,
+            residency: None,
+        }
+    }
+
+    /// Read mailboxes from, and write exports to, the user's region
+    pub fn with_residency(mut self, residency: Arc<ResidencyMap>) -> Self {
+        self.residency = Some(residency);
+        self
+    }
+
+    /// Maildir of a user
+    fn user_maildir(&self, email: &str) -> PathBuf {
+        match &self.residency {
+            Some(residency) => residency.maildir_root(email, &self.maildir_root),
+            None => self.maildir_root.clone(),
+        }
+        .join(email)
+    }
+
+    /// Directory a user's exports are written to
+    fn export_dir(&self, email: &str) -> PathBuf {
+        match &self.residency {
+            Some(residency) => residency.export_dir(email, &self.export_path),
+            None => self.export_path.clone(),
         }
     }
 
@@ -56,10 +83,11 @@ impl ImportExportManager {
         let job_id = Uuid::new_v4().to_string();
 
         // Get maildir path for user
-        let user_maildir = self.maildir_root.join(&request.email);
+        let user_maildir = self.user_maildir(&request.email);
         if !user_maildir.exists() {
             return Err(anyhow!("Maildir not found for user: {}", request.email));
         }
+        fs::create_dir_all(self.export_dir(&request.email))?;
 
         // Count messages to export
         let total_messages = self.count_user_messages(&user_maildir, &request.folders)?;
@@ -102,6 +130,7 @@ impl ImportExportManager {
             stats: self.stats.clone(),
             export_path: self.export_path.clone(),
             maildir_root: self.maildir_root.clone(),
+            residency: self.residency.clone(),
         }
     }
 
@@ -110,7 +139,7 @@ impl ImportExportManager {
         // Update status to running
         self.update_export_status(job_id, OperationStatus::Running, None).await;
 
-        let user_maildir = self.maildir_root.join(&request.email);
+        let user_maildir = self.user_maildir(&request.email);
         let output_filename = format!(
             "{}_{}.{}",
             request.email.replace('@', "_"),
@@ -121,7 +150,7 @@ impl ImportExportManager {
                 ExportFormat::EmlZip => "zip",
             }
         );
-        let output_path = self.export_path.join(&output_filename);
+        let output_path = self.export_dir(&request.email).join(&output_filename);
 
         let result = match request.format {
             ExportFormat::Mbox => {
@@ -342,7 +371,7 @@ impl ImportExportManager {
     async fn run_import(&self, job_id: &str, request: ImportRequest, data: Vec<u8>) -> Result<()> {
         self.update_import_status(job_id, OperationStatus::Running, None).await;
 
-        let user_maildir = self.maildir_root.join(&request.email);
+        let user_maildir = self.user_maildir(&request.email);
         let target_folder = request.target_folder.clone().unwrap_or_else(|| "INBOX".to_string());
         let target_path = if target_folder == "INBOX" {
             user_maildir.clone()
@@ -701,7 +730,7 @@ fn generate_maildir_filename() -> String {
 }
 
 /// Extract From header from raw message
-fn extract_from_header(content: &[u8]) -> Option<String> {
+pub(super) fn extract_from_header(content: &[u8]) -> Option<String> {
     let content_str = String::from_utf8_lossy(content);
     for line in content_str.lines() {
         if line.is_empty() {
@@ -722,7 +751,7 @@ fn extract_from_header(content: &[u8]) -> Option<String> {
 }
 
 /// Extract Date header from raw message
-fn extract_date_header(content: &[u8]) -> Option<chrono::DateTime<Utc>> {
+pub(super) fn extract_date_header(content: &[u8]) -> Option<chrono::DateTime<Utc>> {
     let content_str = String::from_utf8_lossy(content);
     for line in content_str.lines() {
         if line.is_empty() {
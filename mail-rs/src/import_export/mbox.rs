@@ -35,7 +35,7 @@ impl<W: Write> MboxWriter<W> {
         // Write message content, escaping From_ lines
         let mut in_body = false;
         for line in raw_message.split(|&b| b == b'\n') {
-            if line.is_empty() && !in_body {
+            if (line.is_empty() || line == b"\r") && !in_body {
                 in_body = true;
             }
 
@@ -1,7 +1,9 @@
 //! Import/Export module
 //!
-//! Provides mailbox import and export functionality supporting MBOX and EML formats.
+//! Provides mailbox import and export functionality supporting MBOX and EML formats,
+//! and offline conversion between Maildir, Maildir++ and mbox (see [`convert`]).
 
+pub mod convert;
 pub mod manager;
 pub mod mbox;
 pub mod types;
@@ -40,37 +40,89 @@
 //!
 //! # Modules
 //!
+//! - [`server`]: Embeddable [`MailServer`] running all listeners
 //! - [`config`]: Configuration management
 //! - [`error`]: Error types and handling
+//! - [`logging`]: Log subscriber setup and runtime level control
 //! - [`smtp`]: SMTP protocol implementation
 //! - [`storage`]: Email storage backends
 //! - [`security`]: TLS and authentication
 //! - [`utils`]: Utility functions (validation, etc.)
 //! - [`admin`]: Mail-in-a-Box administration tools
+//! - [`reporting`]: Scheduled usage reports for admins
+//! - [`billing`]: Per-user billing metrics and period exports
+//! - [`aliases`]: Burner aliases with expiry, mute/block and sender statistics
+//! - [`branding`]: Per-domain branding of system emails, web pages and autoconfig
+//! - [`devices`]: Client devices, app passwords and new sign-in alerts
+//! - [`login_anomaly`]: Unusual login detection, step-up verification and blocks
+//! - [`footers`]: Per-domain and per-group footers of outgoing mail
+//! - [`hooks`]: Sandboxed WebAssembly hooks run on local delivery
+//! - [`tlsrpt`]: SMTP TLS reporting (RFC 8460)
+//! - [`dmarc_reports`]: DMARC and TLS aggregate reports received at our `rua` addresses
+//! - [`dkim_keys`]: Per-domain DKIM keys signing outbound mail
+//! - [`openpgp`]: Verifying, decrypting and encrypting OpenPGP mail
+//! - [`notifications`]: Per-user notification channels, quiet hours and digests
+//! - [`residency`]: Regional data residency for mailboxes, backups and exports
+//! - [`role_accounts`]: `postmaster@`/`abuse@` of hosted domains and their volume
+//! - [`antivirus`]: clamd scanning of received mail
+//! - [`sharing`]: Encrypted, expiring links sharing single messages
+//! - [`seed`]: Deterministic demo users, mail, events and contacts for development
+//! - [`recovery`]: Read-only mode after storage corruption or a failed migration
+//! - [`retention`]: Per-folder and per-domain retention policies and their enforcement
+//! - [`workers`]: Bounded worker pools of background work, with backpressure
+//! - [`chaos`]: Fault injection for resilience tests (`chaos` feature)
+//! - `testing`: In-memory storage and authenticator doubles (`testing` feature)
 
 pub mod admin;
+pub mod aliases;
 pub mod antispam;
+pub mod antivirus;
 pub mod api;
 pub mod authentication;
 pub mod auto_reply;
+pub mod billing;
+pub mod branding;
+pub mod chaos;
 pub mod config;
+pub mod devices;
+pub mod dkim_keys;
+pub mod dmarc_reports;
 pub mod error;
+pub mod footers;
+pub mod hooks;
 pub mod imap;
 pub mod import_export;
+pub mod logging;
+pub mod login_anomaly;
 pub mod mfa;
 pub mod mime;
+pub mod notifications;
+pub mod openpgp;
 pub mod quota;
+pub mod recovery;
+pub mod reporting;
+pub mod residency;
+pub mod retention;
+pub mod role_accounts;
 pub mod search;
 pub mod security;
+pub mod seed;
+pub mod server;
+pub mod sharing;
 pub mod sieve;
 pub mod smtp;
 pub mod spam;
 pub mod storage;
 pub mod templates;
+pub mod tlsrpt;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod utils;
+pub mod workers;
 pub mod migration;
 pub mod caldav;
 
 // Re-export commonly used types
 pub use config::Config;
 pub use error::{MailError, Result};
+pub use server::{Listener, MailServer, MailServerHandle};
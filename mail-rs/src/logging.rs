@@ -0,0 +1,173 @@
+//! Logging setup with per-module levels that can be changed at runtime
+//!
+//! [`init`] installs the global `tracing` subscriber from [`LoggingConfig`].
+//! Levels are applied through a reloadable [`EnvFilter`], so the admin API
+//! can raise or lower them without a restart; changes last until the
+//! process exits.
+
+use crate::config::LoggingConfig;
+use crate::error::{MailError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+static LOG_CONTROL: OnceLock<LogControl> = OnceLock::new();
+
+/// Default level and per-module overrides
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLevels {
+    pub level: String,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+impl LogLevels {
+    /// `EnvFilter` directives, e.g. `info,mail_rs::smtp=debug`
+    pub fn directives(&self) -> String {
+        std::iter::once(self.level.to_lowercase())
+            .chain(
+                self.modules
+                    .iter()
+                    .map(|(module, level)| format!("{}={}", module, level.to_lowercase())),
+            )
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Check every level and module name
+    pub fn validate(&self) -> Result<()> {
+        parse_level(&self.level)?;
+        for (module, level) in &self.modules {
+            let valid = !module.is_empty()
+                && module
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+            if !valid {
+                return Err(MailError::Config(format!(
+                    "Invalid module name: {}",
+                    module
+                )));
+            }
+            parse_level(level)?;
+        }
+        Ok(())
+    }
+
+    fn filter(&self) -> Result<EnvFilter> {
+        self.validate()?;
+        EnvFilter::try_new(self.directives())
+            .map_err(|e| MailError::Config(format!("Invalid log levels: {}", e)))
+    }
+}
+
+impl From<&LoggingConfig> for LogLevels {
+    fn from(config: &LoggingConfig) -> Self {
+        Self {
+            level: config.level.clone(),
+            modules: config.modules.clone(),
+        }
+    }
+}
+
+/// Handle to the installed subscriber's level filter
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    levels: Mutex<LogLevels>,
+}
+
+impl LogControl {
+    /// Levels currently applied
+    pub fn levels(&self) -> LogLevels {
+        self.levels.lock().unwrap().clone()
+    }
+
+    /// Replace the applied levels
+    pub fn set_levels(&self, levels: LogLevels) -> Result<()> {
+        let filter = levels.filter()?;
+        self.handle
+            .reload(filter)
+            .map_err(|e| MailError::Config(format!("Failed to reload log filter: {}", e)))?;
+        *self.levels.lock().unwrap() = levels;
+        Ok(())
+    }
+}
+
+/// Install the global subscriber described by `config`
+///
+/// Fails if the configuration is invalid or a subscriber is already set.
+pub fn init(config: &LoggingConfig) -> Result<&'static LogControl> {
+    let levels = LogLevels::from(config);
+    let filter = levels.filter()?;
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let format = config.format.to_lowercase();
+    let (pretty, compact, json) = match format.as_str() {
+        "pretty" => (Some(fmt::layer().pretty()), None, None),
+        "compact" => (None, Some(fmt::layer().compact()), None),
+        "json" => (None, None, Some(fmt::layer().json())),
+        other => {
+            return Err(MailError::Config(format!("Unknown log format: {}", other)));
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(pretty)
+        .with(compact)
+        .with(json)
+        .try_init()
+        .map_err(|e| MailError::Config(format!("Failed to set tracing subscriber: {}", e)))?;
+
+    Ok(LOG_CONTROL.get_or_init(|| LogControl {
+        handle,
+        levels: Mutex::new(levels),
+    }))
+}
+
+/// Control of the subscriber installed by [`init`], if any
+pub fn control() -> Option<&'static LogControl> {
+    LOG_CONTROL.get()
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    level
+        .parse()
+        .map_err(|_| MailError::Config(format!("Invalid log level: {}", level)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directives() {
+        let levels = LogLevels {
+            level: "INFO".to_string(),
+            modules: BTreeMap::from([
+                ("mail_rs::smtp".to_string(), "debug".to_string()),
+                ("sqlx".to_string(), "warn".to_string()),
+            ]),
+        };
+        assert!(levels.validate().is_ok());
+        assert_eq!(levels.directives(), "info,mail_rs::smtp=debug,sqlx=warn");
+    }
+
+    #[test]
+    fn test_invalid_levels() {
+        let bad_level = LogLevels {
+            level: "loud".to_string(),
+            modules: BTreeMap::new(),
+        };
+        assert!(bad_level.validate().is_err());
+
+        let bad_module = LogLevels {
+            level: "info".to_string(),
+            modules: BTreeMap::from([("mail_rs=trace,x".to_string(), "debug".to_string())]),
+        };
+        assert!(bad_module.validate().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! GeoIP lookups from a CSV database
+//!
+//! Each line maps a network to its location:
+//!
+//! ```text
+//! # network,country,asn,latitude,longitude
+//! 203.0.113.0/24,FR,64500,48.85,2.35
+//! 2001:db8::/32,US,64501,,
+//! ```
+//!
+//! Fields after the network may be empty. The most specific network
+//! containing an address wins.
+
+use anyhow::{anyhow, Context, Result};
+use std::net::IpAddr;
+use std::path::Path;
+
+use super::types::GeoLocation;
+
+/// Mean radius of the earth
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// A network of the database
+#[derive(Debug, Clone)]
+struct Entry {
+    network: IpAddr,
+    prefix_len: u8,
+    location: GeoLocation,
+}
+
+/// Networks and their locations
+#[derive(Debug, Clone, Default)]
+pub struct GeoDatabase {
+    entries: Vec<Entry>,
+}
+
+impl GeoDatabase {
+    /// Load the database at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read GeoIP database {}", path.display()))?;
+        Self::parse(&content)
+    }
+
+    /// Parse database lines; blank lines and `#` comments are skipped
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_entry(line).with_context(|| format!("line {}", number + 1))?;
+            entries.push(entry);
+        }
+        // Most specific first, so the first match wins
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.prefix_len));
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Location of `ip`, if a network of the database contains it
+    pub fn lookup(&self, ip: IpAddr) -> Option<&GeoLocation> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        self.entries
+            .iter()
+            .find(|entry| contains(entry.network, entry.prefix_len, ip))
+            .map(|entry| &entry.location)
+    }
+}
+
+fn parse_entry(line: &str) -> Result<Entry> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let (network, prefix_len) = parse_network(fields[0])?;
+    let field = |index: usize| fields.get(index).copied().filter(|field| !field.is_empty());
+    let number = |index: usize| -> Result<Option<f64>> {
+        field(index)
+            .map(|value| value.parse::<f64>().map_err(|_| anyhow!("Invalid coordinate {}", value)))
+            .transpose()
+    };
+
+    Ok(Entry {
+        network,
+        prefix_len,
+        location: GeoLocation {
+            country: field(1).map(str::to_uppercase),
+            asn: field(2)
+                .map(|asn| {
+                    let asn = asn.trim_start_matches("AS").trim_start_matches("as");
+                    asn.parse().map_err(|_| anyhow!("Invalid ASN {}", asn))
+                })
+                .transpose()?,
+            latitude: number(3)?,
+            longitude: number(4)?,
+        },
+    })
+}
+
+/// `network/prefix_len`; a bare address is a single host
+fn parse_network(value: &str) -> Result<(IpAddr, u8)> {
+    let (address, prefix_len) = match value.split_once('/') {
+        Some((address, prefix_len)) => (address, Some(prefix_len)),
+        None => (value, None),
+    };
+    let address: IpAddr = address
+        .parse()
+        .map_err(|_| anyhow!("Invalid network {}", value))?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(prefix_len) => prefix_len
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix_len| *prefix_len <= max)
+            .ok_or_else(|| anyhow!("Invalid network {}", value))?,
+        None => max,
+    };
+    Ok((address, prefix_len))
+}
+
+fn contains(network: IpAddr, prefix_len: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Great-circle distance between two `(latitude, longitude)` points
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let database = GeoDatabase::parse(
+            "# network,country,asn,latitude,longitude\n\
+             203.0.0.0/8,us,AS64501,,\n\
+             203.0.113.0/24,FR,64500,48.85,2.35\n\
+             \n\
+             2001:db8::/32,DE,,52.52,13.40\n",
+        )
+        .unwrap();
+        assert_eq!(database.len(), 3);
+
+        let paris = database.lookup("203.0.113.7".parse().unwrap()).unwrap();
+        assert_eq!(paris.country.as_deref(), Some("FR"));
+        assert_eq!(paris.asn, Some(64500));
+        assert_eq!(paris.coordinates(), Some((48.85, 2.35)));
+        // Less specific network, mapped IPv6 addresses included
+        let other = database.lookup("::ffff:203.0.9.1".parse().unwrap()).unwrap();
+        assert_eq!(other.country.as_deref(), Some("US"));
+        assert_eq!(other.coordinates(), None);
+        assert_eq!(
+            database
+                .lookup("2001:db8:1::5".parse().unwrap())
+                .unwrap()
+                .country
+                .as_deref(),
+            Some("DE")
+        );
+        assert!(database.lookup("198.51.100.1".parse().unwrap()).is_none());
+
+        assert!(GeoDatabase::parse("203.0.113.0/33,FR").is_err());
+        assert!(GeoDatabase::parse("203.0.113.0/24,FR,64500,north").is_err());
+    }
+
+    #[test]
+    fn test_distance() {
+        let paris = (48.85, 2.35);
+        let new_york = (40.71, -74.01);
+        let distance = distance_km(paris, new_york);
+        assert!((5800.0..5900.0).contains(&distance), "{}", distance);
+        assert_eq!(distance_km(paris, paris), 0.0);
+    }
+}
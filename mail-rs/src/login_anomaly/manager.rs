@@ -0,0 +1,1054 @@
+//! Login anomaly detector: assessments, step-up challenges, blocks and the
+//! decision audit log
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::geo::{distance_km, GeoDatabase};
+use super::types::*;
+use crate::config::LoginAnomalyConfig;
+use crate::devices::network_of;
+use crate::notifications::{EventKind, NotificationRouter};
+
+/// Shortest distance between two logins that can be impossible travel;
+/// GeoIP coordinates of nearby networks are often this far apart
+const MIN_TRAVEL_KM: f64 = 300.0;
+
+/// Random bytes of email verification tokens
+const TOKEN_BYTES: usize = 32;
+
+/// Login anomaly detector
+pub struct LoginAnomalyDetector {
+    db: SqlitePool,
+    config: LoginAnomalyConfig,
+    geo: GeoDatabase,
+    /// Tells users of blocked logins
+    alerts: Option<Arc<NotificationRouter>>,
+}
+
+impl LoginAnomalyDetector {
+    /// Create a new detector without a GeoIP database
+    pub fn new(db: SqlitePool, config: LoginAnomalyConfig) -> Self {
+        Self {
+            db,
+            config,
+            geo: GeoDatabase::default(),
+            alerts: None,
+        }
+    }
+
+    /// Connect to `database_url`, create the tables and load the GeoIP
+    /// database of `config`
+    pub async fn connect(database_url: &str, config: LoginAnomalyConfig) -> Result<Self> {
+        let geo = match &config.geoip_database {
+            Some(path) => GeoDatabase::load(path)?,
+            None => GeoDatabase::default(),
+        };
+        let detector = Self::new(SqlitePool::connect(database_url).await?, config)
+            .with_geo_database(geo);
+        detector.init_db().await?;
+        Ok(detector)
+    }
+
+    /// Locate client addresses with this database
+    pub fn with_geo_database(mut self, geo: GeoDatabase) -> Self {
+        self.geo = geo;
+        self
+    }
+
+    /// Alert users of blocked logins through this router
+    pub fn with_alerts(mut self, router: Arc<NotificationRouter>) -> Self {
+        self.alerts = Some(router);
+        self
+    }
+
+    pub fn config(&self) -> &LoginAnomalyConfig {
+        &self.config
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // Countries, ASNs and networks each user signed in from
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS login_locations (
+                user TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                value TEXT NOT NULL,
+                first_seen TEXT NOT NULL,
+                PRIMARY KEY (user, kind, value)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        // Last allowed login, for impossible travel
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS login_last (
+                user TEXT PRIMARY KEY,
+                ip TEXT NOT NULL,
+                latitude REAL,
+                longitude REAL,
+                at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS login_blocks (
+                user TEXT NOT NULL,
+                network TEXT NOT NULL,
+                ip TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                PRIMARY KEY (user, network)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS login_step_ups (
+                id TEXT PRIMARY KEY,
+                user TEXT NOT NULL,
+                device_name TEXT NOT NULL,
+                ip TEXT,
+                token_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                verified_at TEXT,
+                completed_at TEXT
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS login_audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user TEXT NOT NULL,
+                protocol TEXT,
+                ip TEXT,
+                action TEXT NOT NULL,
+                anomalies TEXT NOT NULL,
+                actor TEXT,
+                reason TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_login_audit_user ON login_audit(user, id)")
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Assess a successful login of `user` from `ip` and record the
+    /// decision in the audit log
+    ///
+    /// The first located login of a user is trusted. Later ones from a new
+    /// country, ASN or (unlocated) network, or too far from the previous
+    /// login, are anomalies: API logins then need a step-up verification,
+    /// and mail protocol logins are blocked for a while if the config says
+    /// so. Allowed logins teach the detector their location.
+    pub async fn assess(
+        &self,
+        user: &str,
+        protocol: LoginProtocol,
+        ip: Option<IpAddr>,
+    ) -> Result<Assessment> {
+        self.assess_at(user, protocol, ip, Utc::now()).await
+    }
+
+    async fn assess_at(
+        &self,
+        user: &str,
+        protocol: LoginProtocol,
+        ip: Option<IpAddr>,
+        at: DateTime<Utc>,
+    ) -> Result<Assessment> {
+        let user = user.to_lowercase();
+        let Some(ip) = ip else {
+            let assessment = Assessment {
+                decision: Decision::Allow,
+                anomalies: vec![],
+                location: GeoLocation::default(),
+            };
+            self.record_audit(&user, Some(protocol), None, "allow", &[], None, None)
+                .await?;
+            return Ok(assessment);
+        };
+        let network = network_of(ip);
+        let location = self.geo.lookup(ip).cloned().unwrap_or_default();
+
+        if protocol.is_mail_protocol() {
+            if let Some(block) = self.active_block(&user, &network, at).await? {
+                let reason = format!("Blocked until {}", block.expires_at.to_rfc3339());
+                self.record_audit(
+                    &user,
+                    Some(protocol),
+                    Some(ip),
+                    "block",
+                    &[],
+                    None,
+                    Some(&reason),
+                )
+                .await?;
+                return Ok(Assessment {
+                    decision: Decision::Block,
+                    anomalies: vec![],
+                    location,
+                });
+            }
+        }
+
+        let anomalies = if self.knows_user(&user).await? {
+            self.anomalies(&user, ip, &network, &location, at).await?
+        } else {
+            vec![]
+        };
+        let decision = if anomalies.is_empty() {
+            Decision::Allow
+        } else if !protocol.is_mail_protocol() {
+            Decision::StepUp
+        } else if self.config.block_protocol_logins {
+            Decision::Block
+        } else {
+            Decision::Allow
+        };
+
+        match decision {
+            Decision::Allow => self.learn(&user, ip, &location, at).await?,
+            Decision::Block => {
+                self.block(&user, &network, ip, at).await?;
+                self.alert(&user, protocol, ip, &anomalies);
+            }
+            Decision::StepUp => {}
+        }
+        if !anomalies.is_empty() {
+            warn!(
+                "{} login of {} from {}: {:?} ({})",
+                protocol.as_str(),
+                user,
+                ip,
+                decision,
+                describe(&anomalies)
+            );
+        }
+        self.record_audit(
+            &user,
+            Some(protocol),
+            Some(ip),
+            decision.as_str(),
+            &anomalies,
+            None,
+            None,
+        )
+        .await?;
+
+        Ok(Assessment {
+            decision,
+            anomalies,
+            location,
+        })
+    }
+
+    /// Hold an API login of `user` for step-up verification
+    ///
+    /// Returns the challenge and the token of its email verification link,
+    /// which is only returned here.
+    pub async fn start_step_up(
+        &self,
+        user: &str,
+        device_name: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(StepUpChallenge, String)> {
+        let now = Utc::now();
+        let token = generate_token();
+        let challenge = StepUpChallenge {
+            id: Uuid::new_v4().to_string(),
+            user: user.to_lowercase(),
+            device_name: device_name.to_string(),
+            ip: ip.map(|ip| ip.to_string()),
+            created_at: now,
+            expires_at: now + Duration::minutes(self.config.step_up_expiry_minutes as i64),
+            verified_at: None,
+            completed_at: None,
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO login_step_ups
+                (id, user, device_name, ip, token_hash, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&challenge.id)
+        .bind(&challenge.user)
+        .bind(&challenge.device_name)
+        .bind(&challenge.ip)
+        .bind(hash_token(&token))
+        .bind(challenge.created_at.to_rfc3339())
+        .bind(challenge.expires_at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok((challenge, token))
+    }
+
+    /// Step-up challenge `id`
+    pub async fn challenge(&self, id: &str) -> Result<Option<StepUpChallenge>> {
+        let row = sqlx::query("SELECT * FROM login_step_ups WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(challenge_from_row).transpose()
+    }
+
+    /// Challenges that can still be verified, newest first
+    pub async fn pending_challenges(&self) -> Result<Vec<StepUpChallenge>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT * FROM login_step_ups
+            WHERE completed_at IS NULL AND expires_at > ?
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(challenge_from_row).collect()
+    }
+
+    /// Mark the pending challenge of the email link `token` as verified
+    pub async fn verify_email(&self, token: &str) -> Result<Option<StepUpChallenge>> {
+        let row = sqlx::query("SELECT * FROM login_step_ups WHERE token_hash = ?")
+            .bind(hash_token(token.trim()))
+            .fetch_optional(&self.db)
+            .await?;
+        let Some(challenge) = row.as_ref().map(challenge_from_row).transpose()? else {
+            return Ok(None);
+        };
+        if !challenge.is_pending(Utc::now()) {
+            return Ok(None);
+        }
+        self.mark_verified(&challenge.id).await?;
+        info!("Sign-in of {} verified by email link", challenge.user);
+        self.challenge(&challenge.id).await
+    }
+
+    /// Admin override: verify pending challenge `id` on the user's behalf
+    pub async fn approve(
+        &self,
+        id: &str,
+        actor: &str,
+        reason: Option<&str>,
+    ) -> Result<Option<StepUpChallenge>> {
+        let Some(challenge) = self.challenge(id).await? else {
+            return Ok(None);
+        };
+        if !challenge.is_pending(Utc::now()) {
+            return Ok(None);
+        }
+        self.mark_verified(id).await?;
+        self.record_audit(
+            &challenge.user,
+            None,
+            parse_ip(challenge.ip.as_deref()),
+            "step_up_approved",
+            &[],
+            Some(actor),
+            reason,
+        )
+        .await?;
+        self.challenge(id).await
+    }
+
+    /// Complete challenge `id` if it was verified, or the client proved
+    /// its second factor (`totp_verified`); the location of the login is
+    /// learned and the challenge cannot be used again
+    ///
+    /// Returns the completed challenge, or `None` if it is unknown,
+    /// expired, used or not verified.
+    pub async fn complete_step_up(
+        &self,
+        id: &str,
+        totp_verified: bool,
+    ) -> Result<Option<StepUpChallenge>> {
+        let now = Utc::now();
+        let Some(challenge) = self.challenge(id).await? else {
+            return Ok(None);
+        };
+        if !challenge.is_pending(now) || !(totp_verified || challenge.verified_at.is_some()) {
+            return Ok(None);
+        }
+
+        // Only one client can complete a challenge
+        let result = sqlx::query(
+            "UPDATE login_step_ups SET completed_at = ? WHERE id = ? AND completed_at IS NULL",
+        )
+        .bind(now.to_rfc3339())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let ip = parse_ip(challenge.ip.as_deref());
+        if let Some(ip) = ip {
+            let location = self.geo.lookup(ip).cloned().unwrap_or_default();
+            self.learn(&challenge.user, ip, &location, now).await?;
+        }
+        let method = if totp_verified { "TOTP" } else { "email link" };
+        self.record_audit(
+            &challenge.user,
+            Some(LoginProtocol::Api),
+            ip,
+            "step_up_passed",
+            &[],
+            None,
+            Some(method),
+        )
+        .await?;
+        self.challenge(id).await
+    }
+
+    /// Record a failed step-up attempt of challenge `id`
+    pub async fn record_step_up_failure(&self, challenge: &StepUpChallenge, reason: &str) -> Result<()> {
+        self.record_audit(
+            &challenge.user,
+            Some(LoginProtocol::Api),
+            parse_ip(challenge.ip.as_deref()),
+            "step_up_failed",
+            &[],
+            None,
+            Some(reason),
+        )
+        .await
+    }
+
+    /// Blocks in force, newest first
+    pub async fn blocks(&self) -> Result<Vec<LoginBlock>> {
+        let rows = sqlx::query(
+            "SELECT * FROM login_blocks WHERE expires_at > ? ORDER BY created_at DESC",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// Admin override: lift the blocks of `user` and trust the locations
+    /// they were raised for, so the next logins from there pass
+    ///
+    /// Returns the lifted blocks.
+    pub async fn unblock(
+        &self,
+        user: &str,
+        actor: &str,
+        reason: Option<&str>,
+    ) -> Result<Vec<LoginBlock>> {
+        let user = user.to_lowercase();
+        let now = Utc::now();
+        let rows = sqlx::query("SELECT * FROM login_blocks WHERE user = ? AND expires_at > ?")
+            .bind(&user)
+            .bind(now.to_rfc3339())
+            .fetch_all(&self.db)
+            .await?;
+        let blocks = rows
+            .iter()
+            .map(block_from_row)
+            .collect::<Result<Vec<_>>>()?;
+
+        sqlx::query("DELETE FROM login_blocks WHERE user = ?")
+            .bind(&user)
+            .execute(&self.db)
+            .await?;
+        for block in &blocks {
+            let ip = parse_ip(Some(&block.ip));
+            if let Some(ip) = ip {
+                let location = self.geo.lookup(ip).cloned().unwrap_or_default();
+                self.learn(&user, ip, &location, now).await?;
+            }
+            self.record_audit(&user, None, ip, "unblock", &[], Some(actor), reason)
+                .await?;
+        }
+        if !blocks.is_empty() {
+            info!("{} lifted {} login block(s) of {}", actor, blocks.len(), user);
+        }
+        Ok(blocks)
+    }
+
+    /// Most recent audit entries, of `user` or everyone, newest first
+    pub async fn audit_log(&self, user: Option<&str>, limit: i64) -> Result<Vec<AuditEntry>> {
+        let rows = match user {
+            Some(user) => {
+                sqlx::query("SELECT * FROM login_audit WHERE user = ? ORDER BY id DESC LIMIT ?")
+                    .bind(user.to_lowercase())
+                    .bind(limit)
+                    .fetch_all(&self.db)
+                    .await?
+            }
+            None => {
+                sqlx::query("SELECT * FROM login_audit ORDER BY id DESC LIMIT ?")
+                    .bind(limit)
+                    .fetch_all(&self.db)
+                    .await?
+            }
+        };
+        rows.iter().map(audit_from_row).collect()
+    }
+
+    /// Append to the audit log
+    #[allow(clippy::too_many_arguments)]
+    async fn record_audit(
+        &self,
+        user: &str,
+        protocol: Option<LoginProtocol>,
+        ip: Option<IpAddr>,
+        action: &str,
+        anomalies: &[Anomaly],
+        actor: Option<&str>,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO login_audit
+                (user, protocol, ip, action, anomalies, actor, reason, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(user)
+        .bind(protocol.map(|protocol| protocol.as_str()))
+        .bind(ip.map(|ip| ip.to_string()))
+        .bind(action)
+        .bind(serde_json::to_string(anomalies)?)
+        .bind(actor)
+        .bind(reason)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn knows_user(&self, user: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM login_locations WHERE user = ? LIMIT 1")
+            .bind(user)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn knows(&self, user: &str, kind: &str, value: &str) -> Result<bool> {
+        let row =
+            sqlx::query("SELECT 1 FROM login_locations WHERE user = ? AND kind = ? AND value = ?")
+                .bind(user)
+                .bind(kind)
+                .bind(value)
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(row.is_some())
+    }
+
+    /// What is unusual about a login of a known user
+    async fn anomalies(
+        &self,
+        user: &str,
+        ip: IpAddr,
+        network: &str,
+        location: &GeoLocation,
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Anomaly>> {
+        let mut anomalies = Vec::new();
+        if let Some(country) = &location.country {
+            if !self.knows(user, "country", country).await? {
+                anomalies.push(Anomaly::NewCountry {
+                    country: country.clone(),
+                });
+            }
+        }
+        if let Some(asn) = location.asn {
+            if !self.knows(user, "asn", &asn.to_string()).await? {
+                anomalies.push(Anomaly::NewAsn { asn });
+            }
+        }
+        if location.country.is_none()
+            && location.asn.is_none()
+            && !self.knows(user, "network", network).await?
+        {
+            anomalies.push(Anomaly::NewNetwork {
+                network: network.to_string(),
+            });
+        }
+
+        let last = sqlx::query("SELECT * FROM login_last WHERE user = ?")
+            .bind(user)
+            .fetch_optional(&self.db)
+            .await?;
+        if let (Some(row), Some(here)) = (last, location.coordinates()) {
+            let previous_ip: String = row.get("ip");
+            let previous_at = parse_time(row.get("at"))?;
+            let latitude: Option<f64> = row.get("latitude");
+            let longitude: Option<f64> = row.get("longitude");
+            if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+                let distance = distance_km((latitude, longitude), here);
+                let seconds = (at - previous_at).num_seconds().max(1) as f64;
+                let speed = distance / (seconds / 3600.0);
+                if previous_ip != ip.to_string()
+                    && distance >= MIN_TRAVEL_KM
+                    && speed > self.config.max_travel_speed_kmh
+                {
+                    anomalies.push(Anomaly::ImpossibleTravel {
+                        distance_km: distance.round() as u32,
+                        minutes: (at - previous_at).num_minutes(),
+                        previous_ip,
+                    });
+                }
+            }
+        }
+        Ok(anomalies)
+    }
+
+    /// Trust the location of a login of `user`
+    async fn learn(
+        &self,
+        user: &str,
+        ip: IpAddr,
+        location: &GeoLocation,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut known = vec![("network", network_of(ip))];
+        if let Some(country) = &location.country {
+            known.push(("country", country.clone()));
+        }
+        if let Some(asn) = location.asn {
+            known.push(("asn", asn.to_string()));
+        }
+        for (kind, value) in known {
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO login_locations (user, kind, value, first_seen)
+                VALUES (?, ?, ?, ?)
+                "#,
+            )
+            .bind(user)
+            .bind(kind)
+            .bind(value)
+            .bind(at.to_rfc3339())
+            .execute(&self.db)
+            .await?;
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO login_last (user, ip, latitude, longitude, at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(user) DO UPDATE SET
+                ip = excluded.ip, latitude = excluded.latitude,
+                longitude = excluded.longitude, at = excluded.at
+            "#,
+        )
+        .bind(user)
+        .bind(ip.to_string())
+        .bind(location.latitude)
+        .bind(location.longitude)
+        .bind(at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn active_block(
+        &self,
+        user: &str,
+        network: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<LoginBlock>> {
+        let row = sqlx::query(
+            "SELECT * FROM login_blocks WHERE user = ? AND network = ? AND expires_at > ?",
+        )
+        .bind(user)
+        .bind(network)
+        .bind(at.to_rfc3339())
+        .fetch_optional(&self.db)
+        .await?;
+        row.as_ref().map(block_from_row).transpose()
+    }
+
+    async fn block(&self, user: &str, network: &str, ip: IpAddr, at: DateTime<Utc>) -> Result<()> {
+        let expires_at = at + Duration::minutes(self.config.block_minutes as i64);
+        sqlx::query(
+            r#"
+            INSERT INTO login_blocks (user, network, ip, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(user, network) DO UPDATE SET
+                ip = excluded.ip, created_at = excluded.created_at,
+                expires_at = excluded.expires_at
+            "#,
+        )
+        .bind(user)
+        .bind(network)
+        .bind(ip.to_string())
+        .bind(at.to_rfc3339())
+        .bind(expires_at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_verified(&self, id: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE login_step_ups SET verified_at = COALESCE(verified_at, ?) WHERE id = ?",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Tell `user` of a blocked login in the background, so alerts never
+    /// delay the refusal
+    fn alert(&self, user: &str, protocol: LoginProtocol, ip: IpAddr, anomalies: &[Anomaly]) {
+        let Some(router) = self.alerts.clone() else {
+            return;
+        };
+        let body = format!(
+            "An {} sign-in to your account from {} was blocked for {} minutes: {}.\n\n\
+             If this was you, ask an administrator to lift the block. If it wasn't, \
+             change your password.",
+            protocol.as_str().to_uppercase(),
+            ip,
+            self.config.block_minutes,
+            describe(anomalies)
+        );
+        let user = user.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = router
+                .notify(&user, EventKind::SecurityAlert, "Sign-in blocked", &body)
+                .await
+            {
+                warn!("Failed to alert {} of a blocked sign-in: {}", user, e);
+            }
+        });
+    }
+}
+
+/// Anomalies as one line of text
+pub fn describe(anomalies: &[Anomaly]) -> String {
+    anomalies
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn generate_token() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Tokens are random, so a fast hash is enough to protect them
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_ip(value: Option<&str>) -> Option<IpAddr> {
+    value.and_then(|value| value.parse().ok())
+}
+
+fn challenge_from_row(row: &SqliteRow) -> Result<StepUpChallenge> {
+    Ok(StepUpChallenge {
+        id: row.get("id"),
+        user: row.get("user"),
+        device_name: row.get("device_name"),
+        ip: row.get("ip"),
+        created_at: parse_time(row.get("created_at"))?,
+        expires_at: parse_time(row.get("expires_at"))?,
+        verified_at: row
+            .get::<Option<String>, _>("verified_at")
+            .map(parse_time)
+            .transpose()?,
+        completed_at: row
+            .get::<Option<String>, _>("completed_at")
+            .map(parse_time)
+            .transpose()?,
+    })
+}
+
+fn block_from_row(row: &SqliteRow) -> Result<LoginBlock> {
+    Ok(LoginBlock {
+        user: row.get("user"),
+        network: row.get("network"),
+        ip: row.get("ip"),
+        created_at: parse_time(row.get("created_at"))?,
+        expires_at: parse_time(row.get("expires_at"))?,
+    })
+}
+
+fn audit_from_row(row: &SqliteRow) -> Result<AuditEntry> {
+    let protocol: Option<String> = row.get("protocol");
+    let anomalies: String = row.get("anomalies");
+    Ok(AuditEntry {
+        id: row.get("id"),
+        user: row.get("user"),
+        protocol: protocol
+            .map(|protocol| {
+                LoginProtocol::parse(&protocol)
+                    .ok_or_else(|| anyhow!("Unknown login protocol {}", protocol))
+            })
+            .transpose()?,
+        ip: row.get("ip"),
+        action: row.get("action"),
+        anomalies: serde_json::from_str(&anomalies)?,
+        actor: row.get("actor"),
+        reason: row.get("reason"),
+        created_at: parse_time(row.get("created_at"))?,
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GEO: &str = "\
+        203.0.113.0/24,FR,64500,48.85,2.35\n\
+        198.51.100.0/24,FR,64501,45.76,4.84\n\
+        192.0.2.0/24,US,64502,40.71,-74.01\n";
+
+    async fn detector(block_protocol_logins: bool) -> LoginAnomalyDetector {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let config = LoginAnomalyConfig {
+            enabled: true,
+            block_protocol_logins,
+            ..Default::default()
+        };
+        let detector = LoginAnomalyDetector::new(db, config)
+            .with_geo_database(GeoDatabase::parse(GEO).unwrap());
+        detector.init_db().await.unwrap();
+        detector
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_assess() {
+        let detector = detector(false).await;
+        let start = Utc::now();
+        let assess = |protocol, address: &'static str, minutes: i64| {
+            let detector = &detector;
+            async move {
+                detector
+                    .assess_at(
+                        "Alice@example.com",
+                        protocol,
+                        ip(address),
+                        start + Duration::minutes(minutes),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // The first login is trusted, the same place again is usual
+        assert!(assess(LoginProtocol::Imap, "203.0.113.7", 0).await.anomalies.is_empty());
+        assert!(assess(LoginProtocol::Api, "203.0.113.8", 10).await.allowed());
+
+        // Same country, other network: an API login needs a step-up
+        let lyon = assess(LoginProtocol::Api, "198.51.100.1", 40).await;
+        assert_eq!(lyon.decision, Decision::StepUp);
+        assert_eq!(lyon.anomalies, vec![Anomaly::NewAsn { asn: 64501 }]);
+        assert_eq!(lyon.location.country.as_deref(), Some("FR"));
+
+        // Paris to New York in an hour; IMAP is only recorded
+        let new_york = assess(LoginProtocol::Imap, "192.0.2.1", 70).await;
+        assert_eq!(new_york.decision, Decision::Allow);
+        assert_eq!(new_york.anomalies.len(), 3);
+        assert!(matches!(
+            new_york.anomalies[2],
+            Anomaly::ImpossibleTravel { minutes: 60, .. }
+        ));
+        // Learned once allowed
+        assert!(assess(LoginProtocol::Api, "192.0.2.2", 80).await.allowed());
+
+        // Unlocated addresses are compared by network
+        let unknown = assess(LoginProtocol::Smtp, "10.1.2.3", 90).await;
+        assert_eq!(
+            unknown.anomalies,
+            vec![Anomaly::NewNetwork {
+                network: "10.1.0.0/16".to_string()
+            }]
+        );
+        assert!(assess(LoginProtocol::Smtp, "10.1.9.9", 95).await.anomalies.is_empty());
+
+        let audit = detector.audit_log(Some("alice@example.com"), 100).await.unwrap();
+        assert_eq!(audit.len(), 7);
+        assert_eq!(audit[4].action, "step_up");
+        assert_eq!(audit[4].protocol, Some(LoginProtocol::Api));
+        assert_eq!(audit[4].anomalies, lyon.anomalies);
+        assert!(detector.audit_log(Some("bob@example.com"), 100).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocks() {
+        let detector = detector(true).await;
+        detector
+            .assess("alice@example.com", LoginProtocol::Imap, ip("203.0.113.7"))
+            .await
+            .unwrap();
+
+        let blocked = detector
+            .assess("alice@example.com", LoginProtocol::Imap, ip("192.0.2.1"))
+            .await
+            .unwrap();
+        assert_eq!(blocked.decision, Decision::Block);
+        // Blocked by network for every mail protocol, not for the user's
+        // usual places
+        let again = detector
+            .assess("alice@example.com", LoginProtocol::Smtp, ip("192.0.2.9"))
+            .await
+            .unwrap();
+        assert_eq!(again.decision, Decision::Block);
+        assert!(detector
+            .assess("alice@example.com", LoginProtocol::Imap, ip("203.0.113.7"))
+            .await
+            .unwrap()
+            .allowed());
+        let blocks = detector.blocks().await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].network, "192.0.0.0/16");
+
+        // Lifting the block trusts the location
+        let lifted = detector
+            .unblock("Alice@example.com", "admin@example.com", Some("Travelling"))
+            .await
+            .unwrap();
+        assert_eq!(lifted.len(), 1);
+        assert!(detector.blocks().await.unwrap().is_empty());
+        assert!(detector
+            .assess("alice@example.com", LoginProtocol::Imap, ip("192.0.2.1"))
+            .await
+            .unwrap()
+            .allowed());
+
+        let audit = detector.audit_log(None, 1).await.unwrap();
+        assert_eq!(audit[0].action, "allow");
+        let audit = detector.audit_log(None, 2).await.unwrap();
+        assert_eq!(audit[1].action, "unblock");
+        assert_eq!(audit[1].actor.as_deref(), Some("admin@example.com"));
+        assert_eq!(audit[1].reason.as_deref(), Some("Travelling"));
+    }
+
+    #[tokio::test]
+    async fn test_step_up() {
+        let detector = detector(false).await;
+        detector
+            .assess("alice@example.com", LoginProtocol::Api, ip("203.0.113.7"))
+            .await
+            .unwrap();
+        let held = detector
+            .assess("alice@example.com", LoginProtocol::Api, ip("192.0.2.1"))
+            .await
+            .unwrap();
+        assert_eq!(held.decision, Decision::StepUp);
+
+        let (challenge, token) = detector
+            .start_step_up("alice@example.com", "curl/8.0", ip("192.0.2.1"))
+            .await
+            .unwrap();
+        assert_eq!(detector.pending_challenges().await.unwrap().len(), 1);
+        // Not verified yet
+        assert!(detector
+            .complete_step_up(&challenge.id, false)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(detector.verify_email("bogus").await.unwrap().is_none());
+        let verified = detector.verify_email(&token).await.unwrap().unwrap();
+        assert!(verified.verified_at.is_some());
+
+        let completed = detector
+            .complete_step_up(&challenge.id, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(completed.completed_at.is_some());
+        // Used once only, and the location is now known
+        assert!(detector
+            .complete_step_up(&challenge.id, true)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(detector.verify_email(&token).await.unwrap().is_none());
+        assert!(detector.pending_challenges().await.unwrap().is_empty());
+        assert!(detector
+            .assess("alice@example.com", LoginProtocol::Api, ip("192.0.2.1"))
+            .await
+            .unwrap()
+            .allowed());
+
+        // TOTP codes and admin approvals complete challenges as well
+        let (by_totp, _) = detector
+            .start_step_up("alice@example.com", "", None)
+            .await
+            .unwrap();
+        assert!(detector
+            .complete_step_up(&by_totp.id, true)
+            .await
+            .unwrap()
+            .is_some());
+        let (by_admin, _) = detector
+            .start_step_up("alice@example.com", "", None)
+            .await
+            .unwrap();
+        detector
+            .approve(&by_admin.id, "admin@example.com", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(detector
+            .complete_step_up(&by_admin.id, false)
+            .await
+            .unwrap()
+            .is_some());
+
+        let actions: Vec<String> = detector
+            .audit_log(None, 3)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.action)
+            .collect();
+        assert_eq!(actions, ["step_up_passed", "step_up_approved", "step_up_passed"]);
+    }
+}
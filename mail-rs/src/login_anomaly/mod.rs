@@ -0,0 +1,19 @@
+//! Login anomaly detection
+//!
+//! Successful IMAP, SMTP and API logins are compared with the places the
+//! user signed in from before: a new country or ASN from the optional
+//! GeoIP database, a new network for addresses it does not locate, or
+//! travel faster than plausible since the previous login. API logins with
+//! an anomaly are held for a step-up verification, by a link sent to the
+//! user's mailbox or a TOTP code. Mail protocol clients cannot answer one,
+//! so their logins are only recorded, or blocked for a while from that
+//! network if the config says so. Every decision goes to an audit log, and
+//! admins can approve held logins and lift blocks.
+
+pub mod geo;
+pub mod manager;
+pub mod types;
+
+pub use geo::GeoDatabase;
+pub use manager::LoginAnomalyDetector;
+pub use types::*;
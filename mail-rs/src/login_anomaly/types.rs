@@ -0,0 +1,210 @@
+//! Login anomaly types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Protocol a user signs in with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginProtocol {
+    /// IMAP and IMAPS
+    Imap,
+    /// SMTP AUTH on the MX and submission listeners
+    Smtp,
+    /// REST API and web clients
+    Api,
+}
+
+impl LoginProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginProtocol::Imap => "imap",
+            LoginProtocol::Smtp => "smtp",
+            LoginProtocol::Api => "api",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [LoginProtocol::Imap, LoginProtocol::Smtp, LoginProtocol::Api]
+            .into_iter()
+            .find(|protocol| protocol.as_str() == value)
+    }
+
+    /// Protocol clients cannot answer a step-up verification, so unusual
+    /// logins are blocked instead
+    pub fn is_mail_protocol(&self) -> bool {
+        !matches!(self, LoginProtocol::Api)
+    }
+}
+
+/// Where a client address is, as far as the GeoIP database knows
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeoLocation {
+    /// ISO 3166 country code, e.g. `FR`
+    pub country: Option<String>,
+    /// Autonomous system number of the network
+    pub asn: Option<u32>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl GeoLocation {
+    /// Coordinates, if both are known
+    pub fn coordinates(&self) -> Option<(f64, f64)> {
+        Some((self.latitude?, self.longitude?))
+    }
+}
+
+/// Something unusual about a login
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    /// A country the user never signed in from
+    NewCountry { country: String },
+    /// An autonomous system the user never signed in from
+    NewAsn { asn: u32 },
+    /// A network the user never signed in from, for addresses the GeoIP
+    /// database does not locate
+    NewNetwork { network: String },
+    /// Too far from the previous login for the time between them
+    ImpossibleTravel {
+        distance_km: u32,
+        minutes: i64,
+        previous_ip: String,
+    },
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::NewCountry { country } => write!(f, "first sign-in from country {}", country),
+            Anomaly::NewAsn { asn } => write!(f, "first sign-in from network AS{}", asn),
+            Anomaly::NewNetwork { network } => write!(f, "first sign-in from network {}", network),
+            Anomaly::ImpossibleTravel {
+                distance_km,
+                minutes,
+                previous_ip,
+            } => write!(
+                f,
+                "{} km from the sign-in from {} {} minutes earlier",
+                distance_km, previous_ip, minutes
+            ),
+        }
+    }
+}
+
+/// What happens to a login
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    /// API login held until the user verifies it by email link or TOTP
+    StepUp,
+    /// Protocol login refused until the block expires or an admin lifts it
+    Block,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Allow => "allow",
+            Decision::StepUp => "step_up",
+            Decision::Block => "block",
+        }
+    }
+}
+
+/// Outcome of assessing a login
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Assessment {
+    pub decision: Decision,
+    pub anomalies: Vec<Anomaly>,
+    pub location: GeoLocation,
+}
+
+impl Assessment {
+    pub fn allowed(&self) -> bool {
+        self.decision == Decision::Allow
+    }
+}
+
+/// How a held API login can be verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepUpMethod {
+    /// Link sent to the user's mailbox
+    Email,
+    /// Code of the user's authenticator app
+    Totp,
+}
+
+/// API login held for step-up verification
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StepUpChallenge {
+    pub id: String,
+    pub user: String,
+    /// User agent of the client, named as its device once verified
+    pub device_name: String,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Set when the email link was followed or an admin approved the login
+    pub verified_at: Option<DateTime<Utc>>,
+    /// Set once the client got its token
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl StepUpChallenge {
+    /// Whether the challenge can still be verified or completed at `now`
+    pub fn is_pending(&self, now: DateTime<Utc>) -> bool {
+        self.completed_at.is_none() && self.expires_at > now
+    }
+}
+
+/// A mail protocol login block of a user from one network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoginBlock {
+    pub user: String,
+    pub network: String,
+    /// Address of the blocked login
+    pub ip: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Entry of the login decision audit log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub user: String,
+    /// Protocol of the login; unset for admin actions
+    pub protocol: Option<LoginProtocol>,
+    pub ip: Option<String>,
+    /// `allow`, `step_up`, `block`, `step_up_passed`, `step_up_failed`,
+    /// `step_up_approved` or `unblock`
+    pub action: String,
+    pub anomalies: Vec<Anomaly>,
+    /// Admin who overrode a decision
+    pub actor: Option<String>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_types() {
+        assert_eq!(LoginProtocol::parse("imap"), Some(LoginProtocol::Imap));
+        assert_eq!(LoginProtocol::parse("pop3"), None);
+        assert!(!LoginProtocol::Api.is_mail_protocol());
+
+        let anomaly = Anomaly::NewCountry {
+            country: "FR".to_string(),
+        };
+        let json = serde_json::to_string(&anomaly).unwrap();
+        assert_eq!(json, r#"{"kind":"new_country","country":"FR"}"#);
+        assert_eq!(serde_json::from_str::<Anomaly>(&json).unwrap(), anomaly);
+    }
+}
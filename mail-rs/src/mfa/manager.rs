@@ -30,6 +30,13 @@ impl MfaManager {
         }
     }
 
+    /// Connect to `database_url` and create the MFA tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
     /// Initialize database tables
     pub async fn init_db(&self) -> Result<()> {
         sqlx::query(
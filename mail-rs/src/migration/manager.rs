@@ -1,13 +1,13 @@
 //! Migration manager for database persistence and job orchestration
 
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use sqlx::SqlitePool;
 
 use super::types::*;
 
 /// Migration manager
 pub struct MigrationManager {
-    #[allow(dead_code)]
     db: SqlitePool,
 }
 
@@ -44,9 +44,73 @@ impl MigrationManager {
         .execute(&self.db)
         .await?;
 
+        // Coexistence mode: which users are already served locally
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS migration_users (
+                email TEXT PRIMARY KEY,
+                migrated INTEGER NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Set the migration flag for a user
+    pub async fn set_user_migrated(&self, email: &str, migrated: bool) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO migration_users (email, migrated, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(email) DO UPDATE SET migrated = excluded.migrated, updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(email.to_lowercase())
+        .bind(migrated)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
         Ok(())
     }
 
+    /// Check whether a user has been migrated
+    ///
+    /// Users without a flag are considered not migrated, so in coexistence
+    /// mode they stay on the legacy server until explicitly switched.
+    pub async fn is_user_migrated(&self, email: &str) -> Result<bool> {
+        let row: Option<(bool,)> =
+            sqlx::query_as("SELECT migrated FROM migration_users WHERE email = ?")
+                .bind(email.to_lowercase())
+                .fetch_optional(&self.db)
+                .await?;
+
+        Ok(row.map(|(migrated,)| migrated).unwrap_or(false))
+    }
+
+    /// List migration flags for all known users
+    pub async fn list_user_status(&self) -> Result<Vec<UserMigrationStatus>> {
+        let rows: Vec<(String, bool, String)> = sqlx::query_as(
+            "SELECT email, migrated, updated_at FROM migration_users ORDER BY email",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter()
+            .map(|(email, migrated, updated_at)| {
+                Ok(UserMigrationStatus {
+                    email,
+                    migrated,
+                    updated_at: DateTime::parse_from_rfc3339(&updated_at)?.with_timezone(&Utc),
+                })
+            })
+            .collect()
+    }
+
     /// List jobs for a user
     pub async fn list_jobs(&self, _email: &str) -> Result<Vec<MigrationJob>> {
         // TODO: Implement
@@ -81,3 +145,27 @@ impl MigrationManager {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_user_migration_flag() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = MigrationManager::new(db);
+        manager.init_db().await.unwrap();
+
+        assert!(!manager.is_user_migrated("alice@example.com").await.unwrap());
+
+        manager.set_user_migrated("Alice@example.com", true).await.unwrap();
+        assert!(manager.is_user_migrated("alice@example.com").await.unwrap());
+
+        manager.set_user_migrated("alice@example.com", false).await.unwrap();
+        assert!(!manager.is_user_migrated("alice@example.com").await.unwrap());
+
+        let status = manager.list_user_status().await.unwrap();
+        assert_eq!(status.len(), 1);
+        assert!(!status[0].migrated);
+    }
+}
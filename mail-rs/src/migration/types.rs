@@ -98,3 +98,14 @@ pub struct ExportRequest {
     /// Date range end
     pub to_date: Option<DateTime<Utc>>,
 }
+
+/// Per-user migration flag for coexistence mode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserMigrationStatus {
+    /// User email
+    pub email: String,
+    /// True once the mailbox is served locally
+    pub migrated: bool,
+    /// Last change timestamp
+    pub updated_at: DateTime<Utc>,
+}
@@ -0,0 +1,577 @@
+//! Message composition
+//!
+//! [`MessageBuilder`] turns headers, bodies and attachments into an RFC 5322
+//! message, picking the MIME structure from what was added: text and HTML
+//! become `multipart/alternative`, inline images wrap the bodies in
+//! `multipart/related`, and attachments wrap everything in
+//! `multipart/mixed` (or `multipart/report`). Text is sent as 7bit when it
+//! can be and quoted-printable otherwise, everything else as base64.
+//! Non-ASCII header values are written as RFC 2047 encoded words.
+
+use super::header::{encode_address, encode_header};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use uuid::Uuid;
+
+/// Longest line allowed outside of base64 and quoted-printable
+const MAX_LINE: usize = 998;
+
+/// A leaf part of a composed message
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyPart {
+    /// Content-Type, parameters included
+    pub content_type: String,
+    pub data: Vec<u8>,
+    /// `attachment` or `inline`
+    pub disposition: Option<&'static str>,
+    pub filename: Option<String>,
+    /// Content-ID, without angle brackets
+    pub content_id: Option<String>,
+}
+
+impl BodyPart {
+    pub fn new(content_type: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            content_type: content_type.into(),
+            data: data.into(),
+            disposition: None,
+            filename: None,
+            content_id: None,
+        }
+    }
+
+    /// UTF-8 `text/<subtype>` with CRLF line endings
+    pub fn text(subtype: &str, body: &str) -> Self {
+        Self::new(
+            format!("text/{}; charset=utf-8", subtype),
+            crlf(body).into_bytes(),
+        )
+    }
+
+    /// File offered as a download
+    pub fn attachment(filename: &str, content_type: &str, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            disposition: Some("attachment"),
+            filename: Some(filename.to_string()),
+            ..Self::new(content_type, data)
+        }
+    }
+
+    /// Inline content such as an image referenced as `cid:<content_id>`
+    pub fn inline(content_id: &str, content_type: &str, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            disposition: Some("inline"),
+            content_id: Some(content_id.trim_matches(['<', '>']).to_string()),
+            ..Self::new(content_type, data)
+        }
+    }
+
+    fn encoding(&self) -> Encoding {
+        let media_type = self.content_type.to_ascii_lowercase();
+        let plain = self.data.is_ascii()
+            && !self.data.contains(&0)
+            && self
+                .data
+                .split(|&b| b == b'\n')
+                .all(|line| line.len() <= MAX_LINE + 1)
+            && is_crlf(&self.data);
+        if media_type.starts_with("message/") {
+            if plain {
+                Encoding::SevenBit
+            } else {
+                Encoding::EightBit
+            }
+        } else if media_type.starts_with("text/") {
+            if plain {
+                Encoding::SevenBit
+            } else {
+                Encoding::QuotedPrintable
+            }
+        } else {
+            Encoding::Base64
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let encoding = self.encoding();
+        out.extend_from_slice(format!("Content-Type: {}\r\n", self.content_type).as_bytes());
+        if let Some(disposition) = self.disposition {
+            let mut value = disposition.to_string();
+            if let Some(filename) = &self.filename {
+                value.push_str("; ");
+                value.push_str(&filename_parameter(filename));
+            }
+            out.extend_from_slice(format!("Content-Disposition: {}\r\n", value).as_bytes());
+        }
+        if let Some(content_id) = &self.content_id {
+            out.extend_from_slice(format!("Content-ID: <{}>\r\n", content_id).as_bytes());
+        }
+        if let Some(name) = encoding.name() {
+            out.extend_from_slice(format!("Content-Transfer-Encoding: {}\r\n", name).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        match encoding {
+            Encoding::SevenBit | Encoding::EightBit => out.extend_from_slice(&self.data),
+            Encoding::QuotedPrintable => {
+                out.extend_from_slice(encode_quoted_printable(&self.data).as_bytes())
+            }
+            Encoding::Base64 => out.extend_from_slice(encode_base64(&self.data).as_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    SevenBit,
+    EightBit,
+    QuotedPrintable,
+    Base64,
+}
+
+impl Encoding {
+    /// Content-Transfer-Encoding, `None` for the 7bit default
+    fn name(self) -> Option<&'static str> {
+        match self {
+            Encoding::SevenBit => None,
+            Encoding::EightBit => Some("8bit"),
+            Encoding::QuotedPrintable => Some("quoted-printable"),
+            Encoding::Base64 => Some("base64"),
+        }
+    }
+}
+
+enum Node {
+    Leaf(BodyPart),
+    Multipart {
+        subtype: &'static str,
+        parameters: Vec<(&'static str, String)>,
+        parts: Vec<Node>,
+    },
+}
+
+impl Node {
+    fn multipart(subtype: &'static str, parts: Vec<Node>) -> Self {
+        Node::Multipart {
+            subtype,
+            parameters: Vec::new(),
+            parts,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Node::Leaf(part) => part.write(out),
+            Node::Multipart {
+                subtype,
+                parameters,
+                parts,
+            } => {
+                let boundary = format!("=_part_{}", Uuid::new_v4().simple());
+                let mut content_type = format!("multipart/{}", subtype);
+                for (name, value) in parameters {
+                    content_type.push_str(&format!("; {}={}", name, value));
+                }
+                out.extend_from_slice(
+                    format!(
+                        "Content-Type: {};\r\n\tboundary=\"{}\"\r\n\r\n",
+                        content_type, boundary
+                    )
+                    .as_bytes(),
+                );
+                for part in parts {
+                    out.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                    part.write(out);
+                    out.extend_from_slice(b"\r\n");
+                }
+                out.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+            }
+        }
+    }
+}
+
+/// Builder for outgoing messages
+///
+/// Date, Message-ID and MIME-Version are added unless set explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct MessageBuilder {
+    headers: Vec<(String, String)>,
+    text: Option<BodyPart>,
+    html: Option<BodyPart>,
+    inline: Vec<BodyPart>,
+    attachments: Vec<BodyPart>,
+    report_type: Option<String>,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sender, `address` or `Name <address>`
+    pub fn from(self, from: &str) -> Self {
+        self.address_header("From", from)
+    }
+
+    /// Add recipients, joined into one To field
+    pub fn to(self, to: &str) -> Self {
+        self.address_header("To", to)
+    }
+
+    /// Add copy recipients, joined into one Cc field
+    pub fn cc(self, cc: &str) -> Self {
+        self.address_header("Cc", cc)
+    }
+
+    pub fn reply_to(self, reply_to: &str) -> Self {
+        self.address_header("Reply-To", reply_to)
+    }
+
+    pub fn subject(self, subject: &str) -> Self {
+        self.header("Subject", subject)
+    }
+
+    /// Message-ID, angle brackets included
+    pub fn message_id(self, message_id: &str) -> Self {
+        self.header("Message-ID", message_id)
+    }
+
+    /// Any other header; non-ASCII values are encoded
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .push((name.to_string(), encode_header(value.trim())));
+        self
+    }
+
+    /// Plain text body
+    pub fn text(mut self, body: &str) -> Self {
+        self.text = Some(BodyPart::text("plain", body));
+        self
+    }
+
+    /// HTML body, sent as an alternative to the text body when both are set
+    pub fn html(mut self, body: &str) -> Self {
+        self.html = Some(BodyPart::text("html", body));
+        self
+    }
+
+    /// Attach a file
+    pub fn attachment(
+        mut self,
+        filename: &str,
+        content_type: &str,
+        data: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.attachments
+            .push(BodyPart::attachment(filename, content_type, data));
+        self
+    }
+
+    /// Add an image the HTML body shows as `<img src="cid:<content_id>">`
+    pub fn inline_image(
+        mut self,
+        content_id: &str,
+        content_type: &str,
+        data: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.inline
+            .push(BodyPart::inline(content_id, content_type, data));
+        self
+    }
+
+    /// Add a part after the bodies, such as the status of a report
+    pub fn part(mut self, part: BodyPart) -> Self {
+        self.attachments.push(part);
+        self
+    }
+
+    /// Send as `multipart/report` (RFC 6522) of `report_type`
+    pub fn report(mut self, report_type: &str) -> Self {
+        self.report_type = Some(report_type.to_string());
+        self
+    }
+
+    /// The message, with CRLF line endings
+    pub fn build(self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, value) in &self.headers {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if !self.has_header("Date") {
+            out.extend_from_slice(format!("Date: {}\r\n", Utc::now().to_rfc2822()).as_bytes());
+        }
+        if !self.has_header("Message-ID") {
+            out.extend_from_slice(
+                format!(
+                    "Message-ID: <{}@{}>\r\n",
+                    Uuid::new_v4(),
+                    self.sender_domain()
+                )
+                .as_bytes(),
+            );
+        }
+        out.extend_from_slice(b"MIME-Version: 1.0\r\n");
+        self.body().write(&mut out);
+        out
+    }
+
+    fn body(self) -> Node {
+        let mut body = match (self.text, self.html) {
+            (Some(text), Some(html)) => Some(Node::multipart(
+                "alternative",
+                vec![Node::Leaf(text), Node::Leaf(html)],
+            )),
+            (text, html) => text.or(html).map(Node::Leaf),
+        };
+        if !self.inline.is_empty() {
+            let mut parts: Vec<Node> = body.into_iter().collect();
+            parts.extend(self.inline.into_iter().map(Node::Leaf));
+            body = Some(Node::multipart("related", parts));
+        }
+        if !self.attachments.is_empty() || self.report_type.is_some() {
+            let mut parts: Vec<Node> = body.into_iter().collect();
+            parts.extend(self.attachments.into_iter().map(Node::Leaf));
+            body = Some(match self.report_type {
+                Some(report_type) => Node::Multipart {
+                    subtype: "report",
+                    parameters: vec![("report-type", report_type)],
+                    parts,
+                },
+                None => Node::multipart("mixed", parts),
+            });
+        }
+        body.unwrap_or_else(|| Node::Leaf(BodyPart::text("plain", "")))
+    }
+
+    fn address_header(mut self, name: &str, value: &str) -> Self {
+        let value = encode_address(value.trim());
+        match self
+            .headers
+            .iter_mut()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+        {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            None => self.headers.push((name.to_string(), value)),
+        }
+        self
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(field, _)| field.eq_ignore_ascii_case(name))
+    }
+
+    /// Domain of the From address, for generated Message-IDs
+    fn sender_domain(&self) -> String {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case("From"))
+            .and_then(|(_, from)| from.rsplit_once('@'))
+            .map(|(_, domain)| domain.trim_end_matches('>').trim().to_string())
+            .filter(|domain| !domain.is_empty())
+            .unwrap_or_else(|| "localhost".to_string())
+    }
+}
+
+/// Text with every line ending turned into CRLF
+fn crlf(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', "\r\n")
+}
+
+/// Whether every CR and LF of `data` belongs to a CRLF pair
+fn is_crlf(data: &[u8]) -> bool {
+    data.iter().enumerate().all(|(i, &b)| match b {
+        b'\r' => data.get(i + 1) == Some(&b'\n'),
+        b'\n' => i > 0 && data[i - 1] == b'\r',
+        _ => true,
+    })
+}
+
+/// `filename="..."`, or the RFC 2231 form when it isn't plain ASCII
+fn filename_parameter(filename: &str) -> String {
+    let plain = filename
+        .chars()
+        .all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\');
+    if plain {
+        return format!("filename=\"{}\"", filename);
+    }
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("filename*=utf-8''{}", encoded)
+}
+
+/// Base64 in lines of 76 characters
+fn encode_base64(data: &[u8]) -> String {
+    let encoded = BASE64.encode(data);
+    let mut out = String::with_capacity(encoded.len() + encoded.len() / 38);
+    for line in encoded.as_bytes().chunks(76) {
+        out.push_str(std::str::from_utf8(line).unwrap_or_default());
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Quoted-printable (RFC 2045 section 6.7), line breaks kept as CRLF
+fn encode_quoted_printable(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut length = 0;
+        for (j, &b) in line.iter().enumerate() {
+            let last = j + 1 == line.len();
+            let literal = match b {
+                b' ' | b'\t' => !last,
+                b'=' => false,
+                33..=126 => true,
+                _ => false,
+            };
+            let token = if literal {
+                (b as char).to_string()
+            } else {
+                format!("={:02X}", b)
+            };
+            if length + token.len() > 75 {
+                out.push_str("=\r\n");
+                length = 0;
+            }
+            out.push_str(&token);
+            length += token.len();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::{MimeEntity, MimeParser};
+
+    #[test]
+    fn test_build_plain_text() {
+        let message = MessageBuilder::new()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .subject("Hello")
+            .text("Hi Bob\nSee you")
+            .build();
+        let text = String::from_utf8(message).unwrap();
+
+        assert!(text.starts_with(
+            "From: alice@example.com\r\nTo: bob@example.com\r\nSubject: Hello\r\nDate: "
+        ));
+        assert!(text.contains("Message-ID: <"));
+        assert!(text.contains("@example.com>\r\n"));
+        assert!(text.ends_with(
+            "MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nHi Bob\r\nSee you"
+        ));
+    }
+
+    #[test]
+    fn test_build_encodes_non_ascii() {
+        let message = MessageBuilder::new()
+            .from("José Martín <jose@example.com>")
+            .to("bob@example.com")
+            .subject("Réunion demain")
+            .text("Ça marche, à demain")
+            .build();
+        assert!(message.is_ascii());
+
+        let parsed = MimeParser::parse(&message).unwrap();
+        assert_eq!(
+            crate::mime::decode_header(&parsed.headers["from"]),
+            "José Martín <jose@example.com>"
+        );
+        assert_eq!(
+            crate::mime::decode_header(&parsed.headers["subject"]),
+            "Réunion demain"
+        );
+        assert_eq!(parsed.text_body.as_deref(), Some("Ça marche, à demain"));
+    }
+
+    #[test]
+    fn test_build_alternative_with_attachments() {
+        let pdf = vec![0x25, 0x50, 0x44, 0x46, 0x00, 0xff];
+        let message = MessageBuilder::new()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .to("carol@example.com")
+            .subject("Report")
+            .text("See the chart")
+            .html("<p>See <img src=\"cid:chart\"></p>")
+            .inline_image("chart", "image/png", vec![0x89, b'P', b'N', b'G'])
+            .attachment("résumé.pdf", "application/pdf", pdf.clone())
+            .build();
+        let text = String::from_utf8_lossy(&message);
+        assert!(text.contains("To: bob@example.com, carol@example.com\r\n"));
+        assert!(text.contains("filename*=utf-8''r%C3%A9sum%C3%A9.pdf"));
+        assert!(text.contains("Content-ID: <chart>\r\n"));
+
+        let root = MimeEntity::parse(&message);
+        assert_eq!(root.content_type, "multipart/mixed");
+        let related = &root.parts[0];
+        assert_eq!(related.content_type, "multipart/related");
+        assert_eq!(related.parts[0].content_type, "multipart/alternative");
+        assert_eq!(related.parts[0].parts[0].content_type, "text/plain");
+        assert_eq!(related.parts[0].parts[1].content_type, "text/html");
+        assert_eq!(related.parts[1].content_type, "image/png");
+        assert_eq!(
+            related.parts[1].decoded_body(),
+            vec![0x89, b'P', b'N', b'G']
+        );
+
+        let attachment = &root.parts[1];
+        assert_eq!(attachment.filename().as_deref(), Some("résumé.pdf"));
+        assert!(attachment.is_attachment());
+        assert_eq!(attachment.decoded_body(), pdf);
+    }
+
+    #[test]
+    fn test_build_report() {
+        let message = MessageBuilder::new()
+            .from("MAILER-DAEMON@example.com")
+            .to("<alice@example.com>")
+            .text("Undelivered")
+            .part(BodyPart::new(
+                "message/delivery-status",
+                "Reporting-MTA: dns; example.com\r\n",
+            ))
+            .report("delivery-status")
+            .build();
+
+        let root = MimeEntity::parse(&message);
+        assert_eq!(root.content_type, "multipart/report");
+        assert_eq!(root.parts.len(), 2);
+        assert!(String::from_utf8_lossy(root.header).contains("report-type=delivery-status"));
+        assert_eq!(root.parts[1].content_type, "message/delivery-status");
+    }
+
+    #[test]
+    fn test_encode_quoted_printable() {
+        assert_eq!(
+            encode_quoted_printable(b"a=b \r\nend "),
+            "a=3Db=20\r\nend=20"
+        );
+        assert_eq!(encode_quoted_printable("é".as_bytes()), "=C3=A9");
+
+        let long = "x".repeat(100);
+        let encoded = encode_quoted_printable(long.as_bytes());
+        assert!(encoded.lines().all(|line| line.len() <= 76));
+        assert_eq!(
+            MimeParser::decode_quoted_printable(encoded.as_bytes()),
+            long.as_bytes()
+        );
+    }
+}
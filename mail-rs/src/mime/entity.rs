@@ -0,0 +1,629 @@
+//! Byte-level MIME entity tree
+//!
+//! Unlike [`MimeParser::parse`](super::MimeParser::parse), which decodes a
+//! message into text and attachments, this keeps every entity as slices of
+//! the raw message so that IMAP can return sections byte for byte. Bodies
+//! are decoded on demand by [`MimeEntity::decoded_body`] and
+//! [`MimeEntity::text`].
+
+use super::header::decode_header;
+use super::parser::MimeParser;
+use mail_parser::decoders::charsets::map::charset_decoder;
+
+/// Multipart nesting deeper than this is treated as a leaf
+const MAX_DEPTH: usize = 32;
+
+/// One MIME entity: a message, a body part or an encapsulated message
+#[derive(Debug, Clone, PartialEq)]
+pub struct MimeEntity<'a> {
+    /// Header lines including the blank line that ends them
+    pub header: &'a [u8],
+    /// Everything after the header
+    pub body: &'a [u8],
+    /// Lowercase `type/subtype`
+    pub content_type: String,
+    /// Children of a multipart entity
+    pub parts: Vec<MimeEntity<'a>>,
+    /// Encapsulated message of a `message/rfc822` entity
+    pub message: Option<Box<MimeEntity<'a>>>,
+}
+
+impl<'a> MimeEntity<'a> {
+    /// Parse `raw` into an entity tree
+    pub fn parse(raw: &'a [u8]) -> Self {
+        Self::parse_entity(raw, "text/plain", 0)
+    }
+
+    fn parse_entity(raw: &'a [u8], default_type: &str, depth: usize) -> Self {
+        let (header, body) = split_header(raw);
+        let content_type_value = find_header(header, "content-type");
+        let content_type = content_type_value
+            .as_deref()
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_lowercase())
+            .filter(|value| value.contains('/'))
+            .unwrap_or_else(|| default_type.to_string());
+
+        let mut entity = Self {
+            header,
+            body,
+            content_type,
+            parts: Vec::new(),
+            message: None,
+        };
+        if depth >= MAX_DEPTH {
+            return entity;
+        }
+
+        if entity.content_type.starts_with("multipart/") {
+            let boundary = content_type_value
+                .as_deref()
+                .and_then(|value| parameter(value, "boundary"));
+            if let Some(boundary) = boundary {
+                // Parts of a digest are messages unless they say otherwise
+                let child_type = if entity.content_type == "multipart/digest" {
+                    "message/rfc822"
+                } else {
+                    "text/plain"
+                };
+                entity.parts = split_multipart(body, boundary.as_bytes())
+                    .into_iter()
+                    .map(|part| Self::parse_entity(part, child_type, depth + 1))
+                    .collect();
+            }
+        } else if entity.content_type == "message/rfc822" {
+            entity.message = Some(Box::new(Self::parse_entity(body, "text/plain", depth + 1)));
+        }
+
+        entity
+    }
+
+    /// Unfolded value of the first `name` header field
+    pub fn header_value(&self, name: &str) -> Option<String> {
+        find_header(self.header, name)
+    }
+
+    /// Parameters of the Content-Type field
+    pub fn content_type_params(&self) -> Vec<(String, String)> {
+        self.header_value("content-type")
+            .map(|value| parameters(&value))
+            .unwrap_or_default()
+    }
+
+    /// Lowercase disposition type and parameters of the
+    /// Content-Disposition field
+    pub fn disposition(&self) -> Option<(String, Vec<(String, String)>)> {
+        let value = self.header_value("content-disposition")?;
+        let kind = value.split(';').next()?.trim().to_lowercase();
+        if kind.is_empty() {
+            return None;
+        }
+        Some((kind, parameters(&value)))
+    }
+
+    /// Lowercase Content-Transfer-Encoding, `7bit` when absent
+    pub fn encoding(&self) -> String {
+        self.header_value("content-transfer-encoding")
+            .map(|value| value.trim().to_lowercase())
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| "7bit".to_string())
+    }
+
+    /// Lowercase `charset` parameter of the Content-Type field
+    pub fn charset(&self) -> Option<String> {
+        self.content_type_params()
+            .into_iter()
+            .find(|(key, _)| key == "charset")
+            .map(|(_, value)| value.to_lowercase())
+    }
+
+    /// Filename of the Content-Disposition field or, failing that, the
+    /// `name` parameter of the Content-Type field, decoded from RFC 2231
+    /// or RFC 2047 form
+    pub fn filename(&self) -> Option<String> {
+        let from_disposition = self
+            .disposition()
+            .and_then(|(_, params)| decoded_parameter(&params, "filename"));
+        from_disposition.or_else(|| decoded_parameter(&self.content_type_params(), "name"))
+    }
+
+    /// Whether the entity is an attachment rather than inline content
+    pub fn is_attachment(&self) -> bool {
+        matches!(self.disposition(), Some((kind, _)) if kind == "attachment")
+    }
+
+    /// Body with its Content-Transfer-Encoding undone; a body that fails
+    /// to decode is returned as it is
+    pub fn decoded_body(&self) -> Vec<u8> {
+        match self.encoding().as_str() {
+            "base64" => {
+                MimeParser::decode_base64(self.body).unwrap_or_else(|_| self.body.to_vec())
+            }
+            "quoted-printable" => MimeParser::decode_quoted_printable(self.body),
+            _ => self.body.to_vec(),
+        }
+    }
+
+    /// Decoded body converted from its charset to UTF-8
+    pub fn text(&self) -> String {
+        to_utf8(&self.decoded_body(), self.charset().as_deref())
+    }
+
+    /// Entity addressed by a dotted part id such as `1.2`, the IMAP
+    /// section number; an empty id is the entity itself
+    pub fn part_by_id(&self, id: &str) -> Option<&MimeEntity<'a>> {
+        if id.is_empty() {
+            return Some(self);
+        }
+        let path = id
+            .split('.')
+            .map(|number| number.parse().ok())
+            .collect::<Option<Vec<u32>>>()?;
+        self.part(&path)
+    }
+
+    /// Number of lines in the body
+    pub fn body_lines(&self) -> usize {
+        let newlines = self.body.iter().filter(|&&b| b == b'\n').count();
+        match self.body.last() {
+            Some(b'\n') | None => newlines,
+            Some(_) => newlines + 1,
+        }
+    }
+
+    /// Entity addressed by an IMAP section part number such as `[1, 2]`
+    ///
+    /// An empty path is the entity itself. Numbers index the parts of a
+    /// multipart entity; a non-multipart entity only has part 1, itself.
+    /// Below the top level, an encapsulated message is entered before its
+    /// parts are numbered.
+    pub fn part(&self, path: &[u32]) -> Option<&MimeEntity<'a>> {
+        let mut entity = self;
+        for (level, &number) in path.iter().enumerate() {
+            if level > 0 {
+                if let Some(message) = &entity.message {
+                    entity = message;
+                }
+            }
+            if number == 0 {
+                return None;
+            }
+            if entity.content_type.starts_with("multipart/") {
+                entity = entity.parts.get(number as usize - 1)?;
+            } else if number != 1 {
+                return None;
+            }
+        }
+        Some(entity)
+    }
+}
+
+/// `bytes` in `charset` as UTF-8, malformed sequences replaced; unknown
+/// charsets are read as UTF-8
+pub(crate) fn to_utf8(bytes: &[u8], charset: Option<&str>) -> String {
+    match charset {
+        None | Some("utf-8" | "utf8" | "us-ascii") => String::from_utf8_lossy(bytes).into_owned(),
+        Some(charset) => match charset_decoder(charset.as_bytes()) {
+            Some(decode) => decode(bytes),
+            None => String::from_utf8_lossy(bytes).into_owned(),
+        },
+    }
+}
+
+/// Value of parameter `name`, preferring its RFC 2231 `name*` form
+/// (`charset'language'percent-encoded`)
+fn decoded_parameter(params: &[(String, String)], name: &str) -> Option<String> {
+    let extended = format!("{}*", name);
+    let from_extended = params
+        .iter()
+        .find(|(key, _)| *key == extended)
+        .and_then(|(_, value)| {
+            let mut pieces = value.splitn(3, '\'');
+            let charset = pieces.next()?.to_lowercase();
+            let encoded = pieces.nth(1)?.as_bytes();
+            let mut bytes = Vec::with_capacity(encoded.len());
+            let mut i = 0;
+            while i < encoded.len() {
+                let hex = encoded
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match (encoded[i], hex) {
+                    (b'%', Some(byte)) => {
+                        bytes.push(byte);
+                        i += 3;
+                    }
+                    (byte, _) => {
+                        bytes.push(byte);
+                        i += 1;
+                    }
+                }
+            }
+            Some(to_utf8(&bytes, Some(charset.as_str()).filter(|c| !c.is_empty())))
+        });
+    from_extended.or_else(|| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| decode_header(value))
+    })
+}
+
+/// Split at the blank line ending the header; the header keeps it
+fn split_header(raw: &[u8]) -> (&[u8], &[u8]) {
+    // A part without header fields starts with the blank line
+    if raw.starts_with(b"\r\n") {
+        return raw.split_at(2);
+    }
+    if raw.starts_with(b"\n") {
+        return raw.split_at(1);
+    }
+
+    let crlf = find(raw, b"\r\n\r\n").map(|pos| pos + 4);
+    let lf = find(raw, b"\n\n").map(|pos| pos + 2);
+    let end = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => crlf.min(lf),
+        (Some(end), None) | (None, Some(end)) => end,
+        (None, None) => raw.len(),
+    };
+    raw.split_at(end)
+}
+
+/// Body of a multipart entity split into its parts
+///
+/// The line break before a delimiter belongs to the delimiter, so parts
+/// do not end with it. The preamble and epilogue are dropped.
+fn split_multipart<'a>(body: &'a [u8], boundary: &[u8]) -> Vec<&'a [u8]> {
+    let mut delimiter = b"--".to_vec();
+    delimiter.extend_from_slice(boundary);
+
+    let mut parts = Vec::new();
+    let mut part_start: Option<usize> = None;
+    let mut line_start = 0;
+
+    while line_start < body.len() {
+        let line_end = body[line_start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|pos| line_start + pos + 1)
+            .unwrap_or(body.len());
+        let line = &body[line_start..line_end];
+
+        if let Some(rest) = line.strip_prefix(delimiter.as_slice()) {
+            let close = rest.starts_with(b"--");
+            let rest = if close { &rest[2..] } else { rest };
+            if rest.iter().all(|b| b.is_ascii_whitespace()) {
+                if let Some(start) = part_start {
+                    let mut end = line_start.max(start);
+                    if end > start && body[end - 1] == b'\n' {
+                        end -= 1;
+                        if end > start && body[end - 1] == b'\r' {
+                            end -= 1;
+                        }
+                    }
+                    parts.push(&body[start..end]);
+                }
+                if close {
+                    return parts;
+                }
+                part_start = Some(line_end);
+            }
+        }
+        line_start = line_end;
+    }
+
+    // Missing close delimiter: the last part runs to the end
+    if let Some(start) = part_start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Header fields as (name, raw field including continuation lines)
+pub(crate) fn header_fields(header: &[u8]) -> Vec<(String, &[u8])> {
+    let mut fields: Vec<(String, &[u8])> = Vec::new();
+    let mut field_start: Option<usize> = None;
+    let mut line_start = 0;
+
+    while line_start < header.len() {
+        let line_end = header[line_start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|pos| line_start + pos + 1)
+            .unwrap_or(header.len());
+        let line = &header[line_start..line_end];
+
+        let continuation = matches!(line.first(), Some(b' ') | Some(b'\t'));
+        if !continuation {
+            if let Some(start) = field_start.take() {
+                fields.push(field(&header[start..line_start]));
+            }
+            if !line.iter().all(|b| b.is_ascii_whitespace()) {
+                field_start = Some(line_start);
+            }
+        }
+        line_start = line_end;
+    }
+    if let Some(start) = field_start {
+        fields.push(field(&header[start..]));
+    }
+
+    fields
+}
+
+/// `message` without its header fields named in `names`; unchanged when it
+/// has none of them
+pub(crate) fn strip_header_fields(message: &[u8], names: &[&str]) -> Vec<u8> {
+    let entity = MimeEntity::parse(message);
+    let fields = header_fields(entity.header);
+    let strip = |field: &str| names.iter().any(|name| name.eq_ignore_ascii_case(field));
+    if !fields.iter().any(|(field, _)| strip(field)) {
+        return message.to_vec();
+    }
+
+    let mut stripped: Vec<u8> = fields
+        .into_iter()
+        .filter(|(field, _)| !strip(field))
+        .flat_map(|(_, raw)| raw.to_vec())
+        .collect();
+    stripped.extend_from_slice(b"\r\n");
+    stripped.extend_from_slice(entity.body);
+    stripped
+}
+
+fn field(raw: &[u8]) -> (String, &[u8]) {
+    let name = raw
+        .iter()
+        .position(|&b| b == b':')
+        .map(|pos| String::from_utf8_lossy(&raw[..pos]).trim().to_lowercase())
+        .unwrap_or_default();
+    (name, raw)
+}
+
+fn find_header(header: &[u8], name: &str) -> Option<String> {
+    let name = name.to_lowercase();
+    header_fields(header)
+        .into_iter()
+        .find(|(field_name, _)| *field_name == name)
+        .map(|(_, raw)| {
+            let raw = String::from_utf8_lossy(raw);
+            let value = raw.split_once(':').map(|(_, value)| value).unwrap_or("");
+            value
+                .split(['\r', '\n'])
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+}
+
+/// Value of parameter `name` in a structured header value
+fn parameter(value: &str, name: &str) -> Option<String> {
+    parameters(value)
+        .into_iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+/// Parameters of a structured header value such as
+/// `attachment; filename="a; b.pdf"`, names lowercased, values unquoted
+pub fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    for param in split_unquoted(value, ';').into_iter().skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        if key.is_empty() {
+            continue;
+        }
+        params.push((key, unquote(value.trim())));
+    }
+    params
+}
+
+/// Split `value` at `separator` outside double quotes
+pub(crate) fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            pieces.push(&value[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    pieces.push(&value[start..]);
+    pieces
+}
+
+/// Contents of a quoted string, or `value` itself when it is not quoted
+pub(crate) fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: alice@example.com\r\n\
+Subject: Report\r\n\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+preamble\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+\r\n\
+Plain text\r\n\
+--inner\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>HTML</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: Forwarded\r\n\
+\r\n\
+Forwarded body\r\n\
+--outer--\r\n\
+epilogue\r\n";
+
+    #[test]
+    fn test_parse_tree() {
+        let root = MimeEntity::parse(MESSAGE);
+        assert_eq!(root.content_type, "multipart/mixed");
+        assert_eq!(root.header_value("Subject").as_deref(), Some("Report"));
+        assert_eq!(root.parts.len(), 2);
+
+        let alternative = &root.parts[0];
+        assert_eq!(alternative.content_type, "multipart/alternative");
+        assert_eq!(alternative.parts[0].content_type, "text/plain");
+        assert_eq!(alternative.parts[0].header, b"\r\n");
+        assert_eq!(alternative.parts[0].body, b"Plain text");
+        assert_eq!(alternative.parts[1].body, b"<p>HTML</p>");
+
+        let forwarded = root.parts[1].message.as_ref().unwrap();
+        assert_eq!(
+            forwarded.header_value("subject").as_deref(),
+            Some("Forwarded")
+        );
+        assert_eq!(forwarded.body, b"Forwarded body");
+    }
+
+    #[test]
+    fn test_part_numbers() {
+        let root = MimeEntity::parse(MESSAGE);
+        assert_eq!(root.part(&[]).unwrap().content_type, "multipart/mixed");
+        assert_eq!(root.part(&[1, 2]).unwrap().body, b"<p>HTML</p>");
+        assert_eq!(root.part(&[2]).unwrap().content_type, "message/rfc822");
+        // Part 1 of the encapsulated message is its text body
+        assert_eq!(root.part(&[2, 1]).unwrap().body, b"Forwarded body");
+        assert!(root.part(&[3]).is_none());
+        assert!(root.part(&[0]).is_none());
+
+        let single = MimeEntity::parse(b"Subject: Hi\r\n\r\nHello");
+        assert_eq!(single.part(&[1]).unwrap().body, b"Hello");
+        assert!(single.part(&[2]).is_none());
+    }
+
+    #[test]
+    fn test_folded_header() {
+        let root = MimeEntity::parse(
+            b"Subject: A long\r\n  subject line\nContent-Type: TEXT/HTML;\r\n\tcharset=utf-8\r\n\r\nbody",
+        );
+        assert_eq!(
+            root.header_value("subject").as_deref(),
+            Some("A long subject line")
+        );
+        assert_eq!(root.content_type, "text/html");
+        assert_eq!(header_fields(root.header).len(), 2);
+    }
+
+    #[test]
+    fn test_decoding() {
+        let message = b"Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=ISO-8859-1\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Caf=E9 cr=E8me=\r\n\
+=20br=FBl=E9e\r\n\
+--b\r\n\
+Content-Type: application/octet-stream; name=data.bin\r\n\
+Content-Disposition: attachment\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+AAEC\r\n\
+Aw==\r\n\
+--b--\r\n";
+        let root = MimeEntity::parse(message);
+
+        let text = root.part_by_id("1").unwrap();
+        assert_eq!(text.charset().as_deref(), Some("iso-8859-1"));
+        assert_eq!(text.text(), "Café crème brûlée");
+        assert!(!text.is_attachment());
+
+        let data = root.part_by_id("2").unwrap();
+        assert_eq!(data.decoded_body(), vec![0, 1, 2, 3]);
+        assert_eq!(data.filename().as_deref(), Some("data.bin"));
+        assert!(data.is_attachment());
+
+        assert_eq!(root.part_by_id("").unwrap().parts.len(), 2);
+        assert!(root.part_by_id("1.x").is_none());
+        assert!(root.part_by_id("3").is_none());
+    }
+
+    #[test]
+    fn test_parameters() {
+        let part = MimeEntity::parse(
+            b"Content-Type: application/pdf; name=\"a;b.pdf\"\r\n\
+Content-Disposition: ATTACHMENT; filename=\"say \\\"hi\\\".pdf\"; size=42\r\n\
+Content-Transfer-Encoding: Base64\r\n\
+\r\n\
+JVBERi0=\r\nJVBERi0=",
+        );
+        assert_eq!(
+            part.content_type_params(),
+            vec![("name".to_string(), "a;b.pdf".to_string())]
+        );
+        assert_eq!(
+            part.disposition(),
+            Some((
+                "attachment".to_string(),
+                vec![
+                    ("filename".to_string(), "say \"hi\".pdf".to_string()),
+                    ("size".to_string(), "42".to_string()),
+                ]
+            ))
+        );
+        assert_eq!(part.encoding(), "base64");
+        assert_eq!(part.body_lines(), 2);
+        assert_eq!(MimeEntity::parse(b"\r\n").encoding(), "7bit");
+    }
+
+    #[test]
+    fn test_encoded_filename() {
+        let extended = MimeEntity::parse(
+            b"Content-Disposition: attachment; filename*=iso-8859-1'fr'r%E9sum%E9.pdf\r\n\r\n",
+        );
+        assert_eq!(extended.filename().as_deref(), Some("r\u{e9}sum\u{e9}.pdf"));
+
+        let encoded_word = MimeEntity::parse(
+            b"Content-Type: application/pdf; name=\"=?UTF-8?B?csOpc3Vtw6kucGRm?=\"\r\n\r\n",
+        );
+        assert_eq!(encoded_word.filename().as_deref(), Some("r\u{e9}sum\u{e9}.pdf"));
+    }
+}
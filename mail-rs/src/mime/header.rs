@@ -0,0 +1,151 @@
+//! Header value decoding
+//!
+//! Subjects and display names of non-ASCII mail arrive as RFC 2047 encoded
+//! words (`=?UTF-8?B?...?=`). Everything that shows a header to a user or
+//! hands it to another service decodes it here first, and composed mail is
+//! encoded here.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use mail_parser::parsers::MessageStream;
+use mail_parser::HeaderValue;
+
+/// Most UTF-8 bytes per encoded word, keeping words within 75 characters
+const WORD_BYTES: usize = 45;
+
+/// Decode the encoded words of a header value and unfold it
+///
+/// Malformed words and unknown charsets are left as they are, so the
+/// result is never worse than the raw value.
+pub fn decode_header(value: &str) -> String {
+    if !value.contains("=?") && !value.contains(['\r', '\n']) {
+        return value.trim().to_string();
+    }
+
+    let mut raw = value.trim().to_string();
+    raw.push('\n');
+    match MessageStream::new(raw.as_bytes()).parse_unstructured() {
+        HeaderValue::Text(text) => text.into_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Unstructured header value as encoded words when it isn't ASCII
+pub fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        if chunk.len() + c.len_utf8() > WORD_BYTES {
+            words.push(encoded_word(&chunk));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        words.push(encoded_word(&chunk));
+    }
+    words.join("\r\n ")
+}
+
+/// Address list with non-ASCII display names encoded
+pub fn encode_address(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+
+    let mut mailboxes = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                mailboxes.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    mailboxes.push(current);
+
+    mailboxes
+        .iter()
+        .map(|mailbox| match mailbox.rfind('<') {
+            Some(at) => {
+                let name = mailbox[..at].trim().trim_matches('"');
+                format!("{} {}", encode_header(name), mailbox[at..].trim())
+            }
+            None => mailbox.trim().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn encoded_word(text: &str) -> String {
+    format!("=?UTF-8?B?{}?=", BASE64.encode(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_plain() {
+        assert_eq!(decode_header("  Hello world "), "Hello world");
+        assert_eq!(decode_header(""), "");
+    }
+
+    #[test]
+    fn test_decode_encoded_words() {
+        assert_eq!(decode_header("=?UTF-8?B?Q2Fmw6k=?="), "Café");
+        assert_eq!(decode_header("=?ISO-8859-1?Q?Andr=E9_Pirard?="), "André Pirard");
+        // Whitespace between adjacent encoded words is dropped
+        assert_eq!(
+            decode_header("=?UTF-8?Q?R=C3=A9union?= =?UTF-8?Q?_demain?="),
+            "Réunion demain"
+        );
+        assert_eq!(
+            decode_header("=?UTF-8?B?Sm9zw6k=?= <jose@example.com>"),
+            "José <jose@example.com>"
+        );
+    }
+
+    #[test]
+    fn test_decode_folded() {
+        assert_eq!(
+            decode_header("Quarterly\r\n report =?UTF-8?Q?=E2=9C=93?="),
+            "Quarterly report ✓"
+        );
+    }
+
+    #[test]
+    fn test_decode_malformed_is_kept() {
+        assert_eq!(decode_header("=?bogus"), "=?bogus");
+        assert_eq!(decode_header("1 + 1 =? 2"), "1 + 1 =? 2");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        assert_eq!(encode_header("Hello"), "Hello");
+        let subject = "Compte rendu de la réunion du comité de direction élargi";
+        let encoded = encode_header(subject);
+        assert!(encoded.is_ascii());
+        assert!(encoded.split("\r\n ").all(|word| word.len() <= 75));
+        assert_eq!(decode_header(&encoded), subject);
+    }
+
+    #[test]
+    fn test_encode_address() {
+        assert_eq!(encode_address("bob@example.com"), "bob@example.com");
+        let encoded = encode_address("\"Martín, José\" <jose@example.com>, bob@example.com");
+        assert_eq!(encoded, "=?UTF-8?B?TWFydMOtbiwgSm9zw6k=?= <jose@example.com>, bob@example.com");
+        assert_eq!(
+            decode_header(&encoded),
+            "Martín, José <jose@example.com>, bob@example.com"
+        );
+    }
+}
@@ -3,8 +3,14 @@
 /// This module provides functionality to parse MIME multipart messages
 /// and extract attachments.
 
+pub mod builder;
+pub mod entity;
+pub mod header;
 pub mod parser;
 pub mod types;
 
+pub use builder::{BodyPart, MessageBuilder};
+pub use entity::MimeEntity;
+pub use header::{decode_header, encode_header};
 pub use parser::MimeParser;
-pub use types::{MimePart, ParsedEmail};
+pub use types::{MimeNode, MimePart, ParsedEmail};
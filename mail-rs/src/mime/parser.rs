@@ -2,65 +2,130 @@ use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose, Engine as _};
 use std::collections::HashMap;
 
-use super::types::{MimePart, ParsedEmail};
+use super::entity::{to_utf8, MimeEntity};
+use super::types::{MimeNode, MimePart, ParsedEmail};
 
 /// MIME message parser
 pub struct MimeParser;
 
 impl MimeParser {
     /// Parse a raw email message into structured parts
+    ///
+    /// The first inline text/plain and text/html parts found depth first,
+    /// through any nesting of multiparts, become the decoded bodies; every
+    /// other leaf part is an attachment, encapsulated messages included.
     pub fn parse(message: &[u8]) -> Result<ParsedEmail> {
-        let message_str = String::from_utf8_lossy(message);
-
-        let (headers_str, body_str) = Self::split_headers_body(&message_str)?;
-        let headers = Self::parse_headers(&headers_str);
+        let root = MimeEntity::parse(message);
 
         let mut parsed = ParsedEmail {
-            headers: headers.clone(),
+            headers: Self::parse_headers(&String::from_utf8_lossy(root.header)),
             ..Default::default()
         };
+        Self::collect(&root, &Self::root_id(&root), &mut parsed);
 
-        // Check if this is a multipart message
-        if let Some(content_type) = headers.get("content-type") {
-            if content_type.to_lowercase().contains("multipart/") {
-                // Extract boundary
-                if let Some(boundary) = Self::extract_boundary(content_type) {
-                    let parts = Self::parse_multipart(&boundary, &body_str);
-
-                    for part in parts {
-                        Self::categorize_part(&mut parsed, part);
-                    }
-                } else {
-                    // No boundary found, treat as plain text
-                    parsed.text_body = Some(body_str.to_string());
-                }
-            } else {
-                // Single-part message
-                parsed.text_body = Some(body_str.to_string());
-            }
+        Ok(parsed)
+    }
+
+    /// Parse a raw email message into its MIME entity tree without decoding,
+    /// keeping every part as a slice of `message`
+    pub fn parse_tree(message: &[u8]) -> MimeEntity<'_> {
+        MimeEntity::parse(message)
+    }
+
+    /// Parse a raw email message into its decoded part tree, numbered like
+    /// IMAP sections
+    pub fn parse_parts(message: &[u8]) -> MimeNode {
+        let root = MimeEntity::parse(message);
+        Self::node(&root, Self::root_id(&root))
+    }
+
+    /// Part id of the message itself: a single-part message is its part 1
+    fn root_id(root: &MimeEntity) -> String {
+        if root.parts.is_empty() {
+            "1".to_string()
         } else {
-            // No Content-Type, assume plain text
-            parsed.text_body = Some(body_str.to_string());
+            String::new()
         }
+    }
 
-        Ok(parsed)
+    fn child_id(parent: &str, number: usize) -> String {
+        if parent.is_empty() {
+            number.to_string()
+        } else {
+            format!("{}.{}", parent, number)
+        }
+    }
+
+    fn node(entity: &MimeEntity, part_id: String) -> MimeNode {
+        let children = match &entity.message {
+            // The encapsulated message's parts, or its body as part 1
+            Some(message) if message.parts.is_empty() => {
+                vec![Self::node(message, Self::child_id(&part_id, 1))]
+            }
+            Some(message) => Self::children(message, &part_id),
+            None => Self::children(entity, &part_id),
+        };
+        let decoded = entity.decoded_body();
+        let inline_text = entity.content_type.starts_with("text/") && !entity.is_attachment();
+
+        MimeNode {
+            content_type: entity.content_type.clone(),
+            charset: entity.charset(),
+            filename: entity.filename(),
+            is_attachment: entity.is_attachment(),
+            size: decoded.len(),
+            text: inline_text.then(|| to_utf8(&decoded, entity.charset().as_deref())),
+            children,
+            part_id,
+        }
+    }
+
+    fn children(entity: &MimeEntity, part_id: &str) -> Vec<MimeNode> {
+        entity
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| Self::node(part, Self::child_id(part_id, i + 1)))
+            .collect()
     }
 
-    /// Split message into headers and body
-    fn split_headers_body(message: &str) -> Result<(String, String)> {
-        // Headers end with double CRLF or double LF
-        if let Some(pos) = message.find("\r\n\r\n") {
-            let headers = message[..pos].to_string();
-            let body = message[pos + 4..].to_string();
-            Ok((headers, body))
-        } else if let Some(pos) = message.find("\n\n") {
-            let headers = message[..pos].to_string();
-            let body = message[pos + 2..].to_string();
-            Ok((headers, body))
+    /// Sort the leaf parts below `entity` into bodies and attachments
+    fn collect(entity: &MimeEntity, part_id: &str, parsed: &mut ParsedEmail) {
+        if !entity.parts.is_empty() {
+            for (i, part) in entity.parts.iter().enumerate() {
+                Self::collect(part, &Self::child_id(part_id, i + 1), parsed);
+            }
+            return;
+        }
+
+        // A multipart without parts (e.g. no boundary) is read as text
+        let content_type = if entity.content_type.starts_with("multipart/") {
+            "text/plain"
         } else {
-            // No body separator found, treat entire message as headers
-            Ok((message.to_string(), String::new()))
+            entity.content_type.as_str()
+        };
+        if !entity.is_attachment() {
+            if content_type == "text/plain" && parsed.text_body.is_none() {
+                parsed.text_body = Some(entity.text());
+                return;
+            }
+            if content_type == "text/html" && parsed.html_body.is_none() {
+                parsed.html_body = Some(entity.text());
+                return;
+            }
         }
+
+        parsed.attachments.push(MimePart {
+            part_id: part_id.to_string(),
+            content_type: entity
+                .header_value("content-type")
+                .unwrap_or_else(|| entity.content_type.clone()),
+            content_disposition: entity.header_value("content-disposition"),
+            filename: entity.filename(),
+            encoding: entity.header_value("content-transfer-encoding"),
+            body: entity.body.to_vec(),
+            is_attachment: entity.is_attachment(),
+        });
     }
 
     /// Parse email headers into HashMap
@@ -96,118 +161,8 @@ impl MimeParser {
         headers
     }
 
-    /// Extract boundary from Content-Type header
-    fn extract_boundary(content_type: &str) -> Option<String> {
-        for part in content_type.split(';') {
-            let part = part.trim();
-            if part.starts_with("boundary=") {
-                let boundary = &part[9..]; // Skip "boundary="
-                                           // Remove quotes if present
-                let boundary = boundary.trim_matches('"').trim_matches('\'');
-                return Some(boundary.to_string());
-            }
-        }
-        None
-    }
-
-    /// Parse multipart message body
-    fn parse_multipart(boundary: &str, body: &str) -> Vec<MimePart> {
-        let mut parts = Vec::new();
-        let boundary_marker = format!("--{}", boundary);
-        let end_marker = format!("--{}--", boundary);
-
-        let sections: Vec<&str> = body.split(&boundary_marker).collect();
-
-        for section in sections {
-            let section = section.trim();
-
-            // Skip empty sections and end marker
-            if section.is_empty() || section.starts_with("--") {
-                continue;
-            }
-
-            // Each section has headers and body
-            if let Ok((part_headers_str, part_body)) = Self::split_headers_body(section) {
-                let part_headers = Self::parse_headers(&part_headers_str);
-
-                let mut part = MimePart::default();
-
-                // Extract Content-Type
-                if let Some(content_type) = part_headers.get("content-type") {
-                    part.content_type = content_type.clone();
-
-                    // Extract filename from Content-Type if present
-                    if let Some(name) = Self::extract_parameter(content_type, "name") {
-                        part.filename = Some(name);
-                    }
-                }
-
-                // Extract Content-Disposition
-                if let Some(disposition) = part_headers.get("content-disposition") {
-                    part.content_disposition = Some(disposition.clone());
-
-                    // Check if attachment
-                    if disposition.to_lowercase().contains("attachment") {
-                        part.is_attachment = true;
-                    }
-
-                    // Extract filename from Content-Disposition
-                    if let Some(filename) = Self::extract_parameter(disposition, "filename") {
-                        part.filename = Some(filename);
-                    }
-                }
-
-                // Extract Content-Transfer-Encoding
-                if let Some(encoding) = part_headers.get("content-transfer-encoding") {
-                    part.encoding = Some(encoding.clone());
-                }
-
-                // Store body (may be encoded)
-                part.body = part_body.as_bytes().to_vec();
-
-                parts.push(part);
-            }
-        }
-
-        parts
-    }
-
-    /// Extract parameter value from header (e.g., filename="file.txt")
-    fn extract_parameter(header: &str, param_name: &str) -> Option<String> {
-        for part in header.split(';') {
-            let part = part.trim();
-            if part.to_lowercase().starts_with(&format!("{}=", param_name)) {
-                let value = &part[param_name.len() + 1..]; // Skip "param="
-                                                            // Remove quotes
-                let value = value.trim_matches('"').trim_matches('\'');
-                return Some(value.to_string());
-            }
-        }
-        None
-    }
-
-    /// Categorize MIME part into text/HTML/attachment
-    fn categorize_part(parsed: &mut ParsedEmail, part: MimePart) {
-        if part.is_attachment {
-            parsed.attachments.push(part);
-        } else if part.content_type.to_lowercase().contains("text/html") {
-            // Decode body if needed
-            if let Ok(decoded) = Self::decode_body(&part) {
-                parsed.html_body = Some(String::from_utf8_lossy(&decoded).to_string());
-            }
-        } else if part.content_type.to_lowercase().contains("text/plain") {
-            // Decode body if needed
-            if let Ok(decoded) = Self::decode_body(&part) {
-                parsed.text_body = Some(String::from_utf8_lossy(&decoded).to_string());
-            }
-        } else {
-            // Unknown type, treat as attachment
-            parsed.attachments.push(part);
-        }
-    }
-
     /// Decode message body based on Content-Transfer-Encoding
-    fn decode_body(part: &MimePart) -> Result<Vec<u8>> {
+    pub(crate) fn decode_body(part: &MimePart) -> Result<Vec<u8>> {
         if let Some(ref encoding) = part.encoding {
             let encoding_lower = encoding.to_lowercase();
             if encoding_lower.contains("base64") {
@@ -224,7 +179,7 @@ impl MimeParser {
     }
 
     /// Decode base64 content
-    fn decode_base64(content: &[u8]) -> Result<Vec<u8>> {
+    pub(crate) fn decode_base64(content: &[u8]) -> Result<Vec<u8>> {
         // Remove whitespace and newlines
         let cleaned: Vec<u8> = content
             .iter()
@@ -238,40 +193,39 @@ impl MimeParser {
     }
 
     /// Decode quoted-printable content
-    fn decode_quoted_printable(content: &[u8]) -> Vec<u8> {
-        let mut result = Vec::new();
-        let text = String::from_utf8_lossy(content);
-        let mut chars = text.chars().peekable();
-
-        while let Some(ch) = chars.next() {
-            if ch == '=' {
-                // Soft line break
-                if chars.peek() == Some(&'\n') || chars.peek() == Some(&'\r') {
-                    chars.next();
-                    if chars.peek() == Some(&'\n') {
-                        chars.next();
-                    }
-                    continue;
-                }
+    pub(crate) fn decode_quoted_printable(content: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(content.len());
+        let mut i = 0;
+
+        while i < content.len() {
+            if content[i] != b'=' {
+                result.push(content[i]);
+                i += 1;
+                continue;
+            }
 
-                // Hex-encoded character
-                let mut hex = String::new();
-                if let Some(c1) = chars.next() {
-                    hex.push(c1);
-                }
-                if let Some(c2) = chars.next() {
-                    hex.push(c2);
-                }
+            // Soft line break
+            let rest = &content[i + 1..];
+            if rest.starts_with(b"\r\n") {
+                i += 3;
+                continue;
+            }
+            if rest.starts_with(b"\n") || rest.starts_with(b"\r") {
+                i += 2;
+                continue;
+            }
 
-                if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                    result.push(byte);
-                } else {
-                    // Invalid encoding, keep as-is
+            // Hex-encoded byte; anything else is kept as-is
+            let digit = |pos: usize| rest.get(pos).and_then(|&b| (b as char).to_digit(16));
+            match (digit(0), digit(1)) {
+                (Some(high), Some(low)) => {
+                    result.push((high * 16 + low) as u8);
+                    i += 3;
+                }
+                _ => {
                     result.push(b'=');
-                    result.extend(hex.as_bytes());
+                    i += 1;
                 }
-            } else {
-                result.push(ch as u8);
             }
         }
 
@@ -284,19 +238,14 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_split_headers_body_crlf() {
-        let message = "From: test@example.com\r\nSubject: Test\r\n\r\nBody content";
-        let (headers, body) = MimeParser::split_headers_body(message).unwrap();
-        assert!(headers.contains("From:"));
-        assert_eq!(body, "Body content");
-    }
-
-    #[test]
-    fn test_split_headers_body_lf() {
-        let message = "From: test@example.com\nSubject: Test\n\nBody content";
-        let (headers, body) = MimeParser::split_headers_body(message).unwrap();
-        assert!(headers.contains("From:"));
-        assert_eq!(body, "Body content");
+    fn test_parse_crlf_and_lf() {
+        let parsed = MimeParser::parse(b"From: test@example.com\r\nSubject: Test\r\n\r\nBody content").unwrap();
+        assert_eq!(parsed.headers.get("subject"), Some(&"Test".to_string()));
+        assert_eq!(parsed.text_body, Some("Body content".to_string()));
+
+        let parsed = MimeParser::parse(b"From: test@example.com\nSubject: Test\n\nBody content").unwrap();
+        assert_eq!(parsed.headers.get("from"), Some(&"test@example.com".to_string()));
+        assert_eq!(parsed.text_body, Some("Body content".to_string()));
     }
 
     #[test]
@@ -321,24 +270,12 @@ mod tests {
     }
 
     #[test]
-    fn test_extract_boundary() {
-        let content_type = "multipart/mixed; boundary=\"----=_Part_123\"";
-        let boundary = MimeParser::extract_boundary(content_type);
-        assert_eq!(boundary, Some("----=_Part_123".to_string()));
-    }
+    fn test_boundary_quoted_and_unquoted() {
+        let parsed = MimeParser::parse(b"Content-Type: multipart/mixed; boundary=\"----=_Part_123\"\n\n------=_Part_123\n\nQuoted\n------=_Part_123--").unwrap();
+        assert_eq!(parsed.text_body, Some("Quoted".to_string()));
 
-    #[test]
-    fn test_extract_boundary_no_quotes() {
-        let content_type = "multipart/mixed; boundary=simple_boundary";
-        let boundary = MimeParser::extract_boundary(content_type);
-        assert_eq!(boundary, Some("simple_boundary".to_string()));
-    }
-
-    #[test]
-    fn test_extract_parameter() {
-        let header = "attachment; filename=\"document.pdf\"";
-        let filename = MimeParser::extract_parameter(header, "filename");
-        assert_eq!(filename, Some("document.pdf".to_string()));
+        let parsed = MimeParser::parse(b"Content-Type: multipart/mixed; boundary=simple_boundary\n\n--simple_boundary\n\nUnquoted\n--simple_boundary--").unwrap();
+        assert_eq!(parsed.text_body, Some("Unquoted".to_string()));
     }
 
     #[test]
@@ -400,4 +337,96 @@ mod tests {
         assert_eq!(parsed.attachments[0].filename, Some("file.pdf".to_string()));
         assert!(parsed.attachments[0].is_attachment);
     }
+
+    #[test]
+    fn test_decode_quoted_printable_bytes() {
+        // Non-ASCII bytes pass through, invalid escapes are kept
+        let decoded = MimeParser::decode_quoted_printable("é=3D=ZZ=\r\nx=".as_bytes());
+        assert_eq!(decoded, "é==ZZx=".as_bytes());
+    }
+
+    const NESTED: &[u8] = b"Subject: Nested\r\n\
+Content-Type: multipart/mixed; boundary=outer\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/related; boundary=related\r\n\
+\r\n\
+--related\r\n\
+Content-Type: multipart/alternative; boundary=alt\r\n\
+\r\n\
+--alt\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+SGFsbMO2IFdlbHQ=\r\n\
+--alt\r\n\
+Content-Type: text/html; charset=windows-1252\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+<p>Hall=F6 Welt</p>\r\n\
+--alt--\r\n\
+--related\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <logo>\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBORw==\r\n\
+--related--\r\n\
+--outer\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: Forwarded\r\n\
+\r\n\
+Forwarded body\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+Content-Disposition: attachment\r\n\
+\r\n\
+%PDF\r\n\
+--outer--\r\n";
+
+    #[test]
+    fn test_parse_nested_email() {
+        let parsed = MimeParser::parse(NESTED).unwrap();
+
+        assert_eq!(parsed.text_body, Some("Hallö Welt".to_string()));
+        assert_eq!(parsed.html_body, Some("<p>Hallö Welt</p>".to_string()));
+        let ids: Vec<_> = parsed.attachments.iter().map(|a| a.part_id.as_str()).collect();
+        assert_eq!(ids, vec!["1.2", "2", "3"]);
+        assert_eq!(parsed.attachments[2].filename, Some("report.pdf".to_string()));
+        assert!(parsed.attachments[2].is_attachment);
+        assert_eq!(
+            MimeParser::decode_body(&parsed.attachments[0]).unwrap(),
+            b"\x89PNG"
+        );
+    }
+
+    #[test]
+    fn test_parse_parts() {
+        let root = MimeParser::parse_parts(NESTED);
+        assert_eq!(root.part_id, "");
+        assert_eq!(root.content_type, "multipart/mixed");
+
+        let ids: Vec<_> = root.walk().iter().map(|node| node.part_id.as_str()).collect();
+        assert_eq!(ids, vec!["", "1", "1.1", "1.1.1", "1.1.2", "1.2", "2", "2.1", "3"]);
+
+        let html = root.find("1.1.2").unwrap();
+        assert_eq!(html.charset.as_deref(), Some("windows-1252"));
+        assert_eq!(html.text.as_deref(), Some("<p>Hallö Welt</p>"));
+        assert_eq!(root.find("2.1").unwrap().text.as_deref(), Some("Forwarded body"));
+        let pdf = root.find("3").unwrap();
+        assert_eq!(pdf.size, 4);
+        assert_eq!(pdf.text, None);
+
+        // The ids address the same parts as IMAP sections
+        let tree = MimeParser::parse_tree(NESTED);
+        for node in root.walk() {
+            let entity = tree.part_by_id(&node.part_id).unwrap();
+            assert_eq!(entity.content_type, node.content_type);
+        }
+
+        let single = MimeParser::parse_parts(b"Subject: Hi\r\n\r\nHello");
+        assert_eq!(single.part_id, "1");
+        assert_eq!(single.text.as_deref(), Some("Hello"));
+    }
 }
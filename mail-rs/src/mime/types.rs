@@ -1,8 +1,11 @@
+use serde::Serialize;
 use std::collections::HashMap;
 
 /// A MIME part (can be text, HTML, or attachment)
 #[derive(Debug, Clone)]
 pub struct MimePart {
+    /// IMAP section number of the part, e.g. `1.2`
+    pub part_id: String,
     /// Content-Type header value
     pub content_type: String,
     /// Content-Disposition header value (e.g., "attachment")
@@ -20,6 +23,7 @@ pub struct MimePart {
 impl Default for MimePart {
     fn default() -> Self {
         MimePart {
+            part_id: "1".to_string(),
             content_type: "text/plain".to_string(),
             content_disposition: None,
             filename: None,
@@ -30,6 +34,43 @@ impl Default for MimePart {
     }
 }
 
+/// One entity of a message's MIME tree with its decoded content
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MimeNode {
+    /// IMAP section number, e.g. `1.2`, fetched with `BODY[1.2]`; empty
+    /// for the multipart message itself
+    pub part_id: String,
+    /// Lowercase `type/subtype`
+    pub content_type: String,
+    pub charset: Option<String>,
+    pub filename: Option<String>,
+    pub is_attachment: bool,
+    /// Size of the decoded content
+    pub size: usize,
+    /// Content as UTF-8, for inline text parts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Parts of a multipart entity, or the encapsulated message's of a
+    /// `message/rfc822` entity
+    pub children: Vec<MimeNode>,
+}
+
+impl MimeNode {
+    /// This node and all below it, depth first
+    pub fn walk(&self) -> Vec<&MimeNode> {
+        let mut nodes = vec![self];
+        for child in &self.children {
+            nodes.extend(child.walk());
+        }
+        nodes
+    }
+
+    /// Node with `part_id` in this tree
+    pub fn find(&self, part_id: &str) -> Option<&MimeNode> {
+        self.walk().into_iter().find(|node| node.part_id == part_id)
+    }
+}
+
 /// Parsed email with separated parts
 #[derive(Debug, Clone, Default)]
 pub struct ParsedEmail {
@@ -0,0 +1,289 @@
+//! Notification manager: user preferences and held notifications
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Notifications due on one channel for one user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingBatch {
+    pub email: String,
+    pub channel: Channel,
+    pub notifications: Vec<Notification>,
+}
+
+/// Notification manager
+pub struct NotificationManager {
+    db: SqlitePool,
+}
+
+impl NotificationManager {
+    /// Create a new notification manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the notification tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS notification_preferences (
+                email TEXT PRIMARY KEY,
+                preferences TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        // Notifications held for a digest or until quiet hours end
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS notification_pending (
+                id TEXT NOT NULL,
+                email TEXT NOT NULL,
+                channel TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                title TEXT NOT NULL,
+                body TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                deliver_at TEXT NOT NULL,
+                PRIMARY KEY (id, channel)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_notification_pending_due
+            ON notification_pending(deliver_at)
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Saved preferences of `email`, or the defaults
+    pub async fn preferences(&self, email: &str) -> Result<NotificationPreferences> {
+        let row = sqlx::query("SELECT preferences FROM notification_preferences WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&self.db)
+            .await?;
+
+        match row {
+            Some(row) => {
+                let mut preferences: NotificationPreferences =
+                    serde_json::from_str(row.get("preferences"))?;
+                preferences.email = email.to_string();
+                Ok(preferences)
+            }
+            None => Ok(NotificationPreferences::new(email)),
+        }
+    }
+
+    /// Validate and save preferences
+    pub async fn set_preferences(&self, preferences: &NotificationPreferences) -> Result<()> {
+        preferences.validate().map_err(|e| anyhow!(e))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO notification_preferences (email, preferences, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(email) DO UPDATE SET
+                preferences = excluded.preferences,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&preferences.email)
+        .bind(serde_json::to_string(preferences)?)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Hold a notification for `channel` until `deliver_at`
+    pub async fn hold(
+        &self,
+        notification: &Notification,
+        channel: Channel,
+        deliver_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO notification_pending
+                (id, email, channel, kind, title, body, created_at, deliver_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&notification.id)
+        .bind(&notification.email)
+        .bind(channel.as_str())
+        .bind(notification.kind.as_str())
+        .bind(&notification.title)
+        .bind(&notification.body)
+        .bind(notification.created_at.to_rfc3339())
+        .bind(timestamp(deliver_at))
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Take every held notification due at `now`, grouped per user and
+    /// channel in creation order
+    ///
+    /// Taken notifications are removed; a batch that fails to send is not
+    /// retried.
+    pub async fn take_due(&self, now: DateTime<Utc>) -> Result<Vec<PendingBatch>> {
+        let mut tx = self.db.begin().await?;
+
+        let rows = sqlx::query(
+            r#"
+            SELECT id, email, channel, kind, title, body, created_at
+            FROM notification_pending
+            WHERE deliver_at <= ?
+            ORDER BY email, channel, created_at
+            "#,
+        )
+        .bind(timestamp(now))
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM notification_pending WHERE deliver_at <= ?")
+            .bind(timestamp(now))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        let mut batches: Vec<PendingBatch> = Vec::new();
+        for row in rows {
+            let email: String = row.get("email");
+            let channel: String = row.get("channel");
+            let kind: String = row.get("kind");
+            let created_at: String = row.get("created_at");
+            let (Some(channel), Some(kind)) = (Channel::parse(&channel), EventKind::parse(&kind))
+            else {
+                continue;
+            };
+
+            let notification = Notification {
+                id: row.get("id"),
+                email: email.clone(),
+                kind,
+                title: row.get("title"),
+                body: row.get("body"),
+                created_at: DateTime::parse_from_rfc3339(&created_at)?.with_timezone(&Utc),
+            };
+
+            match batches.last_mut() {
+                Some(batch) if batch.email == email && batch.channel == channel => {
+                    batch.notifications.push(notification)
+                }
+                _ => batches.push(PendingBatch {
+                    email,
+                    channel,
+                    notifications: vec![notification],
+                }),
+            }
+        }
+
+        Ok(batches)
+    }
+}
+
+/// Fixed-width timestamps so they compare correctly as text
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn notification(email: &str, title: &str) -> Notification {
+        Notification {
+            id: uuid::Uuid::new_v4().to_string(),
+            email: email.to_string(),
+            kind: EventKind::NewMail,
+            title: title.to_string(),
+            body: String::new(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preferences_roundtrip() {
+        let manager = NotificationManager::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let defaults = manager.preferences("user@example.com").await.unwrap();
+        assert_eq!(defaults, NotificationPreferences::new("user@example.com"));
+
+        let mut preferences = defaults;
+        preferences.events.insert(
+            EventKind::QuotaWarning,
+            EventPreference {
+                channels: vec![],
+                digest: DigestMode::Immediate,
+            },
+        );
+        manager.set_preferences(&preferences).await.unwrap();
+        assert_eq!(
+            manager.preferences("user@example.com").await.unwrap(),
+            preferences
+        );
+
+        preferences.quiet_hours = Some(QuietHours {
+            start_hour: 30,
+            end_hour: 7,
+        });
+        assert!(manager.set_preferences(&preferences).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_take_due_batches() {
+        let manager = NotificationManager::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let now = Utc::now();
+
+        let first = notification("a@example.com", "first");
+        let second = notification("a@example.com", "second");
+        let later = notification("a@example.com", "later");
+        manager.hold(&first, Channel::Email, now).await.unwrap();
+        manager.hold(&second, Channel::Email, now).await.unwrap();
+        manager.hold(&second, Channel::Webhook, now).await.unwrap();
+        manager
+            .hold(&later, Channel::Email, now + Duration::hours(1))
+            .await
+            .unwrap();
+
+        let batches = manager.take_due(now).await.unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].channel, Channel::Email);
+        assert_eq!(batches[0].notifications, vec![first, second.clone()]);
+        assert_eq!(batches[1].channel, Channel::Webhook);
+        assert_eq!(batches[1].notifications, vec![second]);
+
+        assert!(manager.take_due(now).await.unwrap().is_empty());
+        let batches = manager.take_due(now + Duration::hours(1)).await.unwrap();
+        assert_eq!(batches[0].notifications, vec![later]);
+    }
+}
@@ -0,0 +1,15 @@
+//! User notifications for new mail, quota warnings and security alerts
+//!
+//! Each user chooses, per event kind, which channels are used (email,
+//! webhook, WebSocket, or none) and whether events are sent immediately or
+//! batched into hourly or daily digests. Notifications raised during the
+//! user's quiet hours are held until the quiet hours end, except security
+//! alerts.
+
+pub mod manager;
+pub mod router;
+pub mod types;
+
+pub use manager::NotificationManager;
+pub use router::NotificationRouter;
+pub use types::*;
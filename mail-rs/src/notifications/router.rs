@@ -0,0 +1,456 @@
+//! Fan-out of notifications to each user's channels
+//!
+//! [`NotificationRouter::notify`] looks up the user's preferences and either
+//! sends the notification right away or holds it in the database until its
+//! digest slot or the end of the user's quiet hours. [`NotificationRouter::run`]
+//! sends held notifications once they are due, one message per user and
+//! channel.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use super::manager::NotificationManager;
+use super::types::*;
+use crate::branding::{Branding, BrandingManager};
+use crate::smtp::SmtpQueue;
+use crate::workers::{Subsystem, WorkerPools};
+
+/// Notifications buffered for slow WebSocket subscribers
+const WEBSOCKET_BUFFER: usize = 256;
+
+/// Body of webhook requests
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    email: &'a str,
+    notifications: &'a [Notification],
+}
+
+/// Routes notifications to email, webhooks and WebSocket subscribers
+pub struct NotificationRouter {
+    manager: Arc<NotificationManager>,
+    /// Queue for the email channel; without one, email notifications are dropped
+    queue: Option<Arc<SmtpQueue>>,
+    /// Host name notification emails are sent from
+    hostname: String,
+    /// Branding of notification emails by recipient domain
+    branding: Option<Arc<BrandingManager>>,
+    http: reqwest::Client,
+    websocket: broadcast::Sender<Notification>,
+    /// Pools bounding webhook calls; unbounded without them
+    workers: Option<Arc<WorkerPools>>,
+}
+
+impl NotificationRouter {
+    pub fn new(manager: Arc<NotificationManager>) -> Self {
+        let (websocket, _) = broadcast::channel(WEBSOCKET_BUFFER);
+        Self {
+            manager,
+            queue: None,
+            hostname: "localhost".to_string(),
+            branding: None,
+            http: reqwest::Client::new(),
+            websocket,
+            workers: None,
+        }
+    }
+
+    /// Deliver email notifications through this queue
+    pub fn with_queue(mut self, queue: Arc<SmtpQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Set the host name notification emails are sent from
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+
+    /// Brand notification emails with the recipient domain's branding
+    pub fn with_branding(mut self, branding: Arc<BrandingManager>) -> Self {
+        self.branding = Some(branding);
+        self
+    }
+
+    /// Call webhooks in the webhook pool of `workers`, waiting while it is
+    /// full
+    pub fn with_workers(mut self, workers: Arc<WorkerPools>) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    pub fn manager(&self) -> Arc<NotificationManager> {
+        self.manager.clone()
+    }
+
+    /// Notifications for the WebSocket channel, for every user
+    ///
+    /// Subscribers filter on [`Notification::email`].
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.websocket.subscribe()
+    }
+
+    /// Raise an event for `email`
+    pub async fn notify(
+        &self,
+        email: &str,
+        kind: EventKind,
+        title: &str,
+        body: &str,
+    ) -> Result<()> {
+        let notification = Notification {
+            id: Uuid::new_v4().to_string(),
+            email: email.to_string(),
+            kind,
+            title: title.to_string(),
+            body: body.to_string(),
+            created_at: Utc::now(),
+        };
+        self.route(notification).await
+    }
+
+    /// Send or hold a notification according to its user's preferences
+    pub async fn route(&self, notification: Notification) -> Result<()> {
+        let preferences = self.manager.preferences(&notification.email).await?;
+        let event = preferences.event(notification.kind);
+        if event.channels.is_empty() {
+            debug!(
+                "{} notifications are off for {}",
+                notification.kind.as_str(),
+                notification.email
+            );
+            return Ok(());
+        }
+
+        let deliver_at = preferences.deliver_at(notification.kind, notification.created_at);
+        for channel in event.channels {
+            if deliver_at > notification.created_at {
+                self.manager
+                    .hold(&notification, channel, deliver_at)
+                    .await?;
+                continue;
+            }
+            let sent = self
+                .send(channel, &preferences, std::slice::from_ref(&notification))
+                .await;
+            if let Err(e) = sent {
+                warn!(
+                    "Failed to send {} notification to {}: {}",
+                    channel.as_str(),
+                    notification.email,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Send every held notification due at `now`
+    ///
+    /// Returns the number of batches sent.
+    pub async fn flush(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut sent = 0;
+        for batch in self.manager.take_due(now).await? {
+            let preferences = self.manager.preferences(&batch.email).await?;
+            match self
+                .send(batch.channel, &preferences, &batch.notifications)
+                .await
+            {
+                Ok(()) => sent += 1,
+                Err(e) => warn!(
+                    "Failed to send {} digest to {}: {}",
+                    batch.channel.as_str(),
+                    batch.email,
+                    e
+                ),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Run forever, sending held notifications every `interval`
+    pub async fn run(self: Arc<Self>, interval: std::time::Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match self.flush(Utc::now()).await {
+                Ok(0) => {}
+                Ok(sent) => info!("Sent {} notification digests", sent),
+                Err(e) => error!("Notification flush failed: {}", e),
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        channel: Channel,
+        preferences: &NotificationPreferences,
+        notifications: &[Notification],
+    ) -> Result<()> {
+        match channel {
+            Channel::Email => {
+                let queue = self
+                    .queue
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no delivery queue configured"))?;
+                let branding = self.branding_for(&preferences.email).await;
+                let message = self.email_message(&branding, &preferences.email, notifications);
+                // Null reverse-path: notifications never bounce or trigger
+                // further notifications
+                queue.enqueue("", &preferences.email, &message).await?;
+            }
+            Channel::Webhook => {
+                let url = preferences
+                    .webhook_url
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("no webhook URL"))?;
+                let request = self
+                    .http
+                    .post(url)
+                    .json(&WebhookPayload {
+                        email: &preferences.email,
+                        notifications,
+                    })
+                    .timeout(std::time::Duration::from_secs(10))
+                    .send();
+                let response = match &self.workers {
+                    Some(workers) => workers.pool(Subsystem::Webhooks).run(request).await,
+                    None => request.await,
+                };
+                response?.error_for_status()?;
+            }
+            Channel::WebSocket => {
+                for notification in notifications {
+                    // No subscriber is not an error: the user isn't connected
+                    let _ = self.websocket.send(notification.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Branding of the recipient's domain; the defaults if it can't be
+    /// looked up, as a notification is better sent unbranded than not at all
+    async fn branding_for(&self, email: &str) -> Branding {
+        let domain = email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or_default();
+        let Some(manager) = &self.branding else {
+            return Branding::default_for(domain);
+        };
+        manager.resolve(domain).await.unwrap_or_else(|e| {
+            warn!("Failed to look up branding of {}: {}", domain, e);
+            Branding::default_for(domain)
+        })
+    }
+
+    /// Plain text message listing `notifications`, signed with the product
+    /// name and support contact of `branding`
+    fn email_message(
+        &self,
+        branding: &Branding,
+        to: &str,
+        notifications: &[Notification],
+    ) -> Vec<u8> {
+        let subject = match notifications {
+            [single] => single.title.clone(),
+            _ => format!("{} notifications", notifications.len()),
+        };
+
+        let mut message = format!(
+            "From: {product} Notifications <notifications@{host}>\r\n\
+             To: <{to}>\r\n\
+             Subject: {subject}\r\n\
+             Date: {date}\r\n\
+             Message-ID: <{id}@{host}>\r\n\
+             Auto-Submitted: auto-generated\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             \r\n",
+            product = single_line(&branding.product_name),
+            host = self.hostname,
+            subject = single_line(&subject),
+            date = Utc::now().to_rfc2822(),
+            id = Uuid::new_v4(),
+        );
+        for notification in notifications {
+            message.push_str(&format!(
+                "[{}] {}\r\n",
+                notification.created_at.format("%Y-%m-%d %H:%M UTC"),
+                single_line(&notification.title)
+            ));
+            for line in notification.body.lines() {
+                message.push_str(line.trim_end_matches('\r'));
+                message.push_str("\r\n");
+            }
+            message.push_str("\r\n");
+        }
+        message.push_str(&format!(
+            "-- \r\n{}\r\n",
+            single_line(&branding.product_name)
+        ));
+        if let Some(contact) = branding.support_contact() {
+            message.push_str(&format!("Support: {}\r\n", single_line(&contact)));
+        }
+        message.into_bytes()
+    }
+}
+
+/// Header-safe text: line breaks folded into spaces
+fn single_line(text: &str) -> String {
+    text.split(['\r', '\n'])
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn router() -> (NotificationRouter, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("queue.db").display()
+        );
+        let queue = SmtpQueue::new(&url).await.unwrap();
+        let manager = NotificationManager::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let router = NotificationRouter::new(Arc::new(manager))
+            .with_queue(Arc::new(queue))
+            .with_hostname("mail.example.com");
+        (router, dir)
+    }
+
+    #[tokio::test]
+    async fn test_routes_to_websocket_and_email() {
+        let (router, _dir) = router().await;
+        let mut subscriber = router.subscribe();
+
+        router
+            .notify(
+                "user@example.com",
+                EventKind::NewMail,
+                "New message",
+                "From alice",
+            )
+            .await
+            .unwrap();
+        let pushed = subscriber.try_recv().unwrap();
+        assert_eq!(pushed.email, "user@example.com");
+        assert_eq!(pushed.kind, EventKind::NewMail);
+
+        router
+            .notify(
+                "user@example.com",
+                EventKind::SecurityAlert,
+                "New sign-in",
+                "From 192.0.2.1",
+            )
+            .await
+            .unwrap();
+        let queued = router
+            .queue
+            .as_ref()
+            .unwrap()
+            .get_pending(10)
+            .await
+            .unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].from_addr, "");
+        assert_eq!(queued[0].to_addr, "user@example.com");
+        let text = String::from_utf8_lossy(&queued[0].data);
+        assert!(text.contains("Subject: New sign-in\r\n"));
+        assert!(text.contains("Auto-Submitted: auto-generated\r\n"));
+        assert!(text.contains("From 192.0.2.1\r\n"));
+        assert!(text.contains("From: GK Mail Notifications <notifications@mail.example.com>\r\n"));
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_branded_email() {
+        let (router, _dir) = router().await;
+        let branding = BrandingManager::connect("sqlite::memory:").await.unwrap();
+        branding
+            .set(
+                "example.com",
+                &crate::branding::BrandingUpdate {
+                    product_name: Some("Acme Mail".to_string()),
+                    support_email: Some("help@example.com".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let router = router.with_branding(Arc::new(branding));
+
+        router
+            .notify(
+                "user@example.com",
+                EventKind::SecurityAlert,
+                "New sign-in",
+                "",
+            )
+            .await
+            .unwrap();
+        let queued = router.queue.clone().unwrap().get_pending(10).await.unwrap();
+        let text = String::from_utf8_lossy(&queued[0].data);
+        assert!(text.contains("From: Acme Mail Notifications <notifications@mail.example.com>\r\n"));
+        assert!(text.ends_with("-- \r\nAcme Mail\r\nSupport: help@example.com\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_digest_and_disabled_events() {
+        let (router, _dir) = router().await;
+        let mut preferences = NotificationPreferences::new("user@example.com");
+        preferences.events.insert(
+            EventKind::NewMail,
+            EventPreference {
+                channels: vec![Channel::Email],
+                digest: DigestMode::Hourly,
+            },
+        );
+        preferences.events.insert(
+            EventKind::QuotaWarning,
+            EventPreference {
+                channels: vec![],
+                digest: DigestMode::Immediate,
+            },
+        );
+        router.manager.set_preferences(&preferences).await.unwrap();
+
+        for title in ["first", "second"] {
+            router
+                .notify("user@example.com", EventKind::NewMail, title, "")
+                .await
+                .unwrap();
+        }
+        router
+            .notify("user@example.com", EventKind::QuotaWarning, "90% full", "")
+            .await
+            .unwrap();
+        let queue = router.queue.clone().unwrap();
+        assert!(queue.get_pending(10).await.unwrap().is_empty());
+
+        // Nothing due yet; one digest at the top of the hour
+        assert_eq!(router.flush(Utc::now()).await.unwrap(), 0);
+        let next_hour = Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(router.flush(next_hour).await.unwrap(), 1);
+
+        let queued = queue.get_pending(10).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        let text = String::from_utf8_lossy(&queued[0].data);
+        assert!(text.contains("Subject: 2 notifications\r\n"));
+        assert!(text.contains("] first\r\n"));
+        assert!(text.contains("] second\r\n"));
+        assert!(!text.contains("90% full"));
+    }
+}
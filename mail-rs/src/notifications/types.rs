@@ -0,0 +1,314 @@
+//! Notification types
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveTime, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What a notification is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A message was delivered to the user's mailbox
+    NewMail,
+    /// The user's mailbox is close to its storage quota
+    QuotaWarning,
+    /// Sign-in or account security event
+    SecurityAlert,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 3] = [
+        EventKind::NewMail,
+        EventKind::QuotaWarning,
+        EventKind::SecurityAlert,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::NewMail => "new_mail",
+            EventKind::QuotaWarning => "quota_warning",
+            EventKind::SecurityAlert => "security_alert",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+}
+
+/// Where a notification is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// Message to the user's own mailbox
+    Email,
+    /// JSON POST to the user's webhook URL
+    Webhook,
+    /// Push to the user's open WebSocket connections
+    WebSocket,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Email => "email",
+            Channel::Webhook => "webhook",
+            Channel::WebSocket => "web_socket",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [Channel::Email, Channel::Webhook, Channel::WebSocket]
+            .into_iter()
+            .find(|channel| channel.as_str() == value)
+    }
+}
+
+/// When notifications of an event kind are sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestMode {
+    /// One notification per event
+    #[default]
+    Immediate,
+    /// Batched at the top of every hour
+    Hourly,
+    /// Batched at midnight, user time
+    Daily,
+}
+
+/// Channels and batching for one event kind
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventPreference {
+    /// An empty list turns the event kind off
+    pub channels: Vec<Channel>,
+    #[serde(default)]
+    pub digest: DigestMode,
+}
+
+/// Daily window during which notifications are held, in user time
+///
+/// The window may wrap around midnight (e.g. 22 to 7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// First quiet hour (0-23)
+    pub start_hour: u32,
+    /// First hour after the window (0-23)
+    pub end_hour: u32,
+}
+
+/// A user's notification settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationPreferences {
+    pub email: String,
+    /// Settings per event kind; missing kinds use [`EventPreference::default_for`]
+    #[serde(default)]
+    pub events: BTreeMap<EventKind, EventPreference>,
+    /// Target of the webhook channel
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// User time zone as an offset from UTC, for quiet hours and daily digests
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+/// A single event addressed to a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    pub id: String,
+    /// Recipient user
+    pub email: String,
+    pub kind: EventKind,
+    pub title: String,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl EventPreference {
+    /// Settings used until the user chooses otherwise
+    pub fn default_for(kind: EventKind) -> Self {
+        let channels = match kind {
+            EventKind::NewMail => vec![Channel::WebSocket],
+            EventKind::QuotaWarning | EventKind::SecurityAlert => vec![Channel::Email],
+        };
+        Self {
+            channels,
+            digest: DigestMode::Immediate,
+        }
+    }
+}
+
+impl QuietHours {
+    fn contains(&self, hour: u32) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+impl NotificationPreferences {
+    /// Defaults for a user without saved preferences
+    pub fn new(email: impl Into<String>) -> Self {
+        Self {
+            email: email.into(),
+            events: BTreeMap::new(),
+            webhook_url: None,
+            quiet_hours: None,
+            utc_offset_minutes: 0,
+        }
+    }
+
+    /// Settings for one event kind
+    pub fn event(&self, kind: EventKind) -> EventPreference {
+        self.events
+            .get(&kind)
+            .cloned()
+            .unwrap_or_else(|| EventPreference::default_for(kind))
+    }
+
+    /// Check hours, offset and webhook settings
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(quiet) = &self.quiet_hours {
+            if quiet.start_hour > 23 || quiet.end_hour > 23 {
+                return Err("Quiet hours must be between 0 and 23".to_string());
+            }
+        }
+        if self.utc_offset_minutes.abs() > 14 * 60 {
+            return Err("UTC offset must be within 14 hours".to_string());
+        }
+        let uses_webhook = self
+            .events
+            .values()
+            .any(|event| event.channels.contains(&Channel::Webhook));
+        match &self.webhook_url {
+            Some(url) if !url.starts_with("https://") => {
+                Err("Webhook URL must use https".to_string())
+            }
+            None if uses_webhook => Err("Webhook channel requires a webhook URL".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// When a notification of `kind` raised at `now` may be sent: `now`,
+    /// the next digest slot, or the end of quiet hours, whichever is latest
+    ///
+    /// Security alerts are never held by quiet hours.
+    pub fn deliver_at(&self, kind: EventKind, now: DateTime<Utc>) -> DateTime<Utc> {
+        let offset = FixedOffset::east_opt(self.utc_offset_minutes * 60)
+            .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+        let local = now.with_timezone(&offset);
+
+        let mut at = match self.event(kind).digest {
+            DigestMode::Immediate => now,
+            DigestMode::Hourly => start_of_hour(now) + Duration::hours(1),
+            DigestMode::Daily => {
+                let midnight = local.date_naive().and_time(NaiveTime::MIN);
+                offset
+                    .from_local_datetime(&midnight)
+                    .single()
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .unwrap_or(now)
+                    + Duration::days(1)
+            }
+        };
+
+        if let Some(quiet) = self
+            .quiet_hours
+            .filter(|_| kind != EventKind::SecurityAlert)
+        {
+            let local_at = at.with_timezone(&offset);
+            if quiet.contains(local_at.hour()) {
+                let hours = (quiet.end_hour + 24 - local_at.hour()) % 24;
+                at = start_of_hour(at) + Duration::hours(hours as i64);
+            }
+        }
+
+        at
+    }
+}
+
+fn start_of_hour(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.with_minute(0)
+        .and_then(|at| at.with_second(0))
+        .and_then(|at| at.with_nanosecond(0))
+        .unwrap_or(at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_deliver_at() {
+        let mut prefs = NotificationPreferences::new("user@example.com");
+        let now = at("2024-03-10T23:30:00Z");
+        assert_eq!(prefs.deliver_at(EventKind::NewMail, now), now);
+
+        prefs.events.insert(
+            EventKind::NewMail,
+            EventPreference {
+                channels: vec![Channel::Email],
+                digest: DigestMode::Hourly,
+            },
+        );
+        assert_eq!(
+            prefs.deliver_at(EventKind::NewMail, now),
+            at("2024-03-11T00:00:00Z")
+        );
+
+        // 00:30 user time is inside 22-7, so held until 07:00 user time
+        prefs.quiet_hours = Some(QuietHours {
+            start_hour: 22,
+            end_hour: 7,
+        });
+        prefs.utc_offset_minutes = 60;
+        assert_eq!(
+            prefs.deliver_at(EventKind::QuotaWarning, now),
+            at("2024-03-11T06:00:00Z")
+        );
+        assert_eq!(prefs.deliver_at(EventKind::SecurityAlert, now), now);
+
+        prefs.events.get_mut(&EventKind::NewMail).unwrap().digest = DigestMode::Daily;
+        assert_eq!(
+            prefs.deliver_at(EventKind::NewMail, at("2024-03-10T12:00:00Z")),
+            at("2024-03-10T23:00:00Z") + Duration::hours(7)
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut prefs = NotificationPreferences::new("user@example.com");
+        assert!(prefs.validate().is_ok());
+
+        prefs.events.insert(
+            EventKind::SecurityAlert,
+            EventPreference {
+                channels: vec![Channel::Webhook],
+                digest: DigestMode::Immediate,
+            },
+        );
+        assert!(prefs.validate().is_err());
+
+        prefs.webhook_url = Some("http://example.com/hook".to_string());
+        assert!(prefs.validate().is_err());
+
+        prefs.webhook_url = Some("https://example.com/hook".to_string());
+        assert!(prefs.validate().is_ok());
+
+        prefs.quiet_hours = Some(QuietHours {
+            start_hour: 22,
+            end_hour: 24,
+        });
+        assert!(prefs.validate().is_err());
+    }
+}
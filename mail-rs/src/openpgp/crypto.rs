@@ -0,0 +1,324 @@
+//! OpenPGP operations on keyrings: signature checks, decryption and
+//! encryption
+
+use anyhow::{anyhow, Result};
+use pgp::cleartext::CleartextSignedMessage;
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::packet::Signature;
+use pgp::types::{CompressionAlgorithm, KeyId, PublicKeyTrait, SecretKeyTrait};
+use pgp::{
+    ArmorOptions, Deserializable, Message, SignedPublicKey, SignedPublicSubKey, SignedSecretKey,
+    StandaloneSignature,
+};
+
+use super::types::{SignatureCheck, SignatureStatus};
+
+/// An uploaded key
+pub enum ParsedKey {
+    Public(SignedPublicKey),
+    Secret(SignedSecretKey),
+}
+
+impl ParsedKey {
+    /// Parse an armored public or private key
+    pub fn from_armored(armored: &str) -> Result<Self> {
+        let armored = armored.trim();
+        if armored.contains("PRIVATE KEY BLOCK") {
+            let (key, _) = SignedSecretKey::from_string(armored)?;
+            key.verify()?;
+            Ok(Self::Secret(key))
+        } else {
+            let (key, _) = SignedPublicKey::from_string(armored)?;
+            key.verify()?;
+            Ok(Self::Public(key))
+        }
+    }
+
+    /// The public part
+    pub fn public_key(&self) -> SignedPublicKey {
+        match self {
+            Self::Public(key) => key.clone(),
+            Self::Secret(key) => SignedPublicKey::from(key.clone()),
+        }
+    }
+}
+
+/// Uppercase hex fingerprint
+pub fn fingerprint(key: &impl PublicKeyTrait) -> String {
+    key.fingerprint()
+        .as_bytes()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+/// User IDs of a key, like `Alice <alice@example.com>`
+pub fn user_ids(key: &SignedPublicKey) -> Vec<String> {
+    key.details
+        .users
+        .iter()
+        .map(|user| String::from_utf8_lossy(user.id.id()).into_owned())
+        .collect()
+}
+
+/// Whether one of the user IDs of `key` has address `email`
+pub fn has_address(key: &SignedPublicKey, email: &str) -> bool {
+    user_ids(key).iter().any(|id| {
+        let address = match (id.rfind('<'), id.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &id[start + 1..end],
+            _ => id.as_str(),
+        };
+        address.trim().eq_ignore_ascii_case(email.trim())
+    })
+}
+
+/// Fail unless `passphrase` unlocks `key`
+pub fn check_passphrase(key: &SignedSecretKey, passphrase: &str) -> Result<()> {
+    let passphrase = passphrase.to_string();
+    key.unlock(move || passphrase, |_| Ok(()))
+        .map_err(|_| anyhow!("Wrong passphrase for the private key"))
+}
+
+/// Armored message encrypted to the encryption subkeys of `keys`
+pub fn encrypt(data: &[u8], keys: &[SignedPublicKey]) -> Result<String> {
+    let mut subkeys: Vec<&SignedPublicSubKey> = Vec::new();
+    for key in keys {
+        let subkey = key
+            .public_subkeys
+            .iter()
+            .find(|subkey| subkey.is_encryption_key())
+            .ok_or_else(|| anyhow!("Key {} can't encrypt", fingerprint(key)))?;
+        subkeys.push(subkey);
+    }
+
+    let message = Message::new_literal_bytes("", data)
+        .compress(CompressionAlgorithm::ZLIB)?
+        .encrypt_to_keys_seipdv1(rand::thread_rng(), SymmetricKeyAlgorithm::AES256, &subkeys)?;
+    Ok(message.to_armored_string(ArmorOptions::default())?)
+}
+
+/// Public keys to check signatures with and private keys to decrypt with
+#[derive(Default)]
+pub struct Keyring {
+    pub public: Vec<SignedPublicKey>,
+    /// Private keys with their passphrases
+    pub secret: Vec<(SignedSecretKey, String)>,
+}
+
+impl Keyring {
+    pub fn is_empty(&self) -> bool {
+        self.public.is_empty() && self.secret.is_empty()
+    }
+
+    /// Check a detached signature of `data`
+    pub fn verify_detached(&self, data: &[u8], armored: &str) -> SignatureCheck {
+        match StandaloneSignature::from_string(armored.trim()) {
+            Ok((signature, _)) => self.check(&Signed::Detached(&signature, data)),
+            Err(_) => invalid(None),
+        }
+    }
+
+    /// Check an inline `BEGIN PGP SIGNED MESSAGE` block
+    pub fn verify_cleartext(&self, armored: &str) -> Option<SignatureCheck> {
+        let (message, _) = CleartextSignedMessage::from_string(armored.trim()).ok()?;
+        let text = message.signed_text();
+        let signature = message.signatures().first()?;
+        Some(self.check(&Signed::Detached(signature, text.as_bytes())))
+    }
+
+    /// Decrypt an armored message, returning its content and the check of
+    /// the signature inside, if signed
+    pub fn decrypt(&self, armored: &str) -> Result<(Vec<u8>, Option<SignatureCheck>)> {
+        let (message, _) = Message::from_string(armored.trim())?;
+        for (key, passphrase) in &self.secret {
+            let passphrase = passphrase.clone();
+            let Ok((decrypted, _)) = message.decrypt(move || passphrase, &[key]) else {
+                continue;
+            };
+            let decrypted = decrypted.decompress()?;
+            let content = decrypted
+                .get_content()?
+                .ok_or_else(|| anyhow!("Decrypted message has no content"))?;
+            let check = match &decrypted {
+                Message::Signed { .. } => Some(self.check(&Signed::Message(&decrypted))),
+                _ => None,
+            };
+            return Ok((content, check));
+        }
+        Err(anyhow!("No private key for this message"))
+    }
+
+    /// Check a signature with the known key that issued it
+    fn check(&self, signed: &Signed) -> SignatureCheck {
+        let issuers: Vec<&KeyId> = signed
+            .signature()
+            .map(Signature::issuer)
+            .unwrap_or_default();
+        let issued = |key_id: KeyId| issuers.is_empty() || issuers.contains(&&key_id);
+        let outcome = |ok: bool, signer: String| match ok {
+            true => valid(Some(signer)),
+            false => invalid(Some(signer)),
+        };
+
+        let public: Vec<SignedPublicKey> = self
+            .public
+            .iter()
+            .cloned()
+            .chain(
+                self.secret
+                    .iter()
+                    .map(|(key, _)| SignedPublicKey::from(key.clone())),
+            )
+            .collect();
+        for key in &public {
+            if issued(key.key_id()) {
+                return outcome(signed.verify(key), fingerprint(key));
+            }
+            if let Some(subkey) = key
+                .public_subkeys
+                .iter()
+                .find(|subkey| issued(subkey.key_id()))
+            {
+                return outcome(signed.verify(subkey), fingerprint(key));
+            }
+        }
+
+        SignatureCheck {
+            status: SignatureStatus::UnknownKey,
+            signer: issuers.first().map(|key_id| format!("{:X}", key_id)),
+        }
+    }
+}
+
+/// A signature and what it signs
+enum Signed<'a> {
+    Detached(&'a StandaloneSignature, &'a [u8]),
+    Message(&'a Message),
+}
+
+impl Signed<'_> {
+    fn signature(&self) -> Option<&Signature> {
+        match self {
+            Self::Detached(signature, _) => Some(&signature.signature),
+            Self::Message(Message::Signed { signature, .. }) => Some(signature),
+            Self::Message(_) => None,
+        }
+    }
+
+    fn verify(&self, key: &impl PublicKeyTrait) -> bool {
+        match self {
+            Self::Detached(signature, data) => signature.verify(key, data).is_ok(),
+            Self::Message(message) => message.verify(key).is_ok(),
+        }
+    }
+}
+
+fn valid(signer: Option<String>) -> SignatureCheck {
+    SignatureCheck {
+        status: SignatureStatus::Valid,
+        signer,
+    }
+}
+
+fn invalid(signer: Option<String>) -> SignatureCheck {
+    SignatureCheck {
+        status: SignatureStatus::Invalid,
+        signer,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::SubsecRound;
+    use pgp::crypto::ecc_curve::ECCCurve;
+    use pgp::packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData};
+    use pgp::{KeyType, SecretKeyParamsBuilder, SubkeyParamsBuilder};
+
+    /// Unprotected signing key with an encryption subkey
+    pub(crate) fn generate_key(user_id: &str) -> SignedSecretKey {
+        let mut rng = rand::thread_rng();
+        let subkey = SubkeyParamsBuilder::default()
+            .key_type(KeyType::ECDH(ECCCurve::Curve25519))
+            .can_encrypt(true)
+            .passphrase(None)
+            .build()
+            .unwrap();
+        SecretKeyParamsBuilder::default()
+            .key_type(KeyType::EdDSALegacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id(user_id.to_string())
+            .passphrase(None)
+            .subkey(subkey)
+            .build()
+            .unwrap()
+            .generate(&mut rng)
+            .unwrap()
+            .sign(&mut rng, String::new)
+            .unwrap()
+    }
+
+    /// Armored public key of `key`
+    pub(crate) fn public_armored(key: &SignedSecretKey) -> String {
+        key.public_key()
+            .sign(rand::thread_rng(), key, String::new)
+            .unwrap()
+            .to_armored_string(ArmorOptions::default())
+            .unwrap()
+    }
+
+    /// Armored detached signature of `data`
+    pub(crate) fn sign_detached(key: &SignedSecretKey, data: &[u8]) -> String {
+        let mut config =
+            SignatureConfig::v4(SignatureType::Binary, key.algorithm(), key.hash_alg());
+        config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::IssuerFingerprint(key.fingerprint())),
+            Subpacket::regular(SubpacketData::SignatureCreationTime(
+                chrono::Utc::now().trunc_subsecs(0),
+            )),
+        ];
+        config.unhashed_subpackets = vec![Subpacket::regular(SubpacketData::Issuer(key.key_id()))];
+        let signature = config.sign(key, String::new, data).unwrap();
+        StandaloneSignature::new(signature)
+            .to_armored_string(ArmorOptions::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = generate_key("Alice <alice@example.com>");
+        let armored = key.to_armored_string(ArmorOptions::default()).unwrap();
+        let ParsedKey::Secret(parsed) = ParsedKey::from_armored(&armored).unwrap() else {
+            panic!("expected a private key");
+        };
+        assert_eq!(fingerprint(&parsed), fingerprint(&key));
+        assert!(check_passphrase(&parsed, "").is_ok());
+
+        let public = ParsedKey::from_armored(&public_armored(&key)).unwrap();
+        assert!(matches!(public, ParsedKey::Public(_)));
+        let public = public.public_key();
+        assert_eq!(user_ids(&public), vec!["Alice <alice@example.com>"]);
+        assert!(has_address(&public, "ALICE@example.com"));
+        assert!(!has_address(&public, "bob@example.com"));
+        assert_eq!(fingerprint(&public).len(), 40);
+
+        assert!(ParsedKey::from_armored("not a key").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let bob = generate_key("Bob <bob@example.com>");
+        let armored = encrypt(b"secret", &[SignedPublicKey::from(bob.clone())]).unwrap();
+        assert!(armored.starts_with("-----BEGIN PGP MESSAGE-----"));
+
+        assert!(Keyring::default().decrypt(&armored).is_err());
+        let keyring = Keyring {
+            public: Vec::new(),
+            secret: vec![(bob, String::new())],
+        };
+        let (content, signature) = keyring.decrypt(&armored).unwrap();
+        assert_eq!(content, b"secret");
+        assert!(signature.is_none());
+    }
+}
@@ -0,0 +1,412 @@
+//! OpenPGP key manager: stored keys of users and WKD lookups
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use mail_parser::MessageParser;
+use pgp::{ArmorOptions, Deserializable, SignedPublicKey, SignedSecretKey};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::time::Duration;
+use tracing::info;
+
+use super::crypto::{self, Keyring, ParsedKey};
+use super::mime::{self, Opened};
+use super::types::*;
+use super::wkd::WkdClient;
+use crate::authentication::TtlCache;
+use crate::config::OpenPgpConfig;
+use crate::dkim_keys::crypto::KeyCipher;
+
+/// How long keys found (or not found) in a Web Key Directory are kept
+const WKD_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Most addresses whose WKD keys are cached
+const WKD_CACHE_CAPACITY: usize = 10_000;
+
+/// OpenPGP key manager
+pub struct OpenPgpManager {
+    db: SqlitePool,
+    cipher: KeyCipher,
+    wkd: Option<WkdClient>,
+    wkd_keys: TtlCache<String, Vec<SignedPublicKey>>,
+}
+
+impl OpenPgpManager {
+    /// Create a new key manager; fails without `key_secret`
+    pub fn new(db: SqlitePool, config: &OpenPgpConfig) -> Result<Self> {
+        if config.key_secret.is_empty() {
+            return Err(anyhow!("openpgp.key_secret is required"));
+        }
+        Ok(Self {
+            db,
+            cipher: KeyCipher::new(&config.key_secret)?,
+            wkd: if config.wkd {
+                Some(WkdClient::new()?)
+            } else {
+                None
+            },
+            wkd_keys: TtlCache::new(WKD_CACHE_TTL, WKD_CACHE_CAPACITY),
+        })
+    }
+
+    /// Connect to `database_url` and create the key table
+    pub async fn connect(database_url: &str, config: &OpenPgpConfig) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?, config)?;
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // secret_key and passphrase are encrypted, see KeyCipher
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS openpgp_keys (
+                owner TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                secret_key BLOB,
+                passphrase BLOB,
+                user_ids TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (owner, fingerprint)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Store a key of `owner`; `passphrase` must unlock a private key (see
+    /// [`crypto::check_passphrase`]). A private key replaces the public
+    /// key with the same fingerprint, a public key keeps the private one
+    pub async fn import(&self, owner: &str, key: &ParsedKey, passphrase: &str) -> Result<PgpKey> {
+        let public = key.public_key();
+        let fingerprint = crypto::fingerprint(&public);
+        let (secret_key, passphrase) = match key {
+            ParsedKey::Secret(secret) => {
+                let armored = secret.to_armored_string(ArmorOptions::default())?;
+                (
+                    Some(self.cipher.seal(armored.as_bytes())?),
+                    Some(self.cipher.seal(passphrase.as_bytes())?),
+                )
+            }
+            ParsedKey::Public(_) => (None, None),
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO openpgp_keys
+                (owner, fingerprint, public_key, secret_key, passphrase, user_ids, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (owner, fingerprint) DO UPDATE SET
+                public_key = excluded.public_key,
+                secret_key = COALESCE(excluded.secret_key, secret_key),
+                passphrase = COALESCE(excluded.passphrase, passphrase),
+                user_ids = excluded.user_ids
+            "#,
+        )
+        .bind(owner.to_lowercase())
+        .bind(&fingerprint)
+        .bind(public.to_armored_string(ArmorOptions::default())?)
+        .bind(secret_key)
+        .bind(passphrase)
+        .bind(serde_json::to_string(&crypto::user_ids(&public))?)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        info!("Imported OpenPGP key {} for {}", fingerprint, owner);
+        self.get(owner, &fingerprint)
+            .await?
+            .ok_or_else(|| anyhow!("Imported key {} not found", fingerprint))
+    }
+
+    /// Keys of `owner`, oldest first
+    pub async fn list(&self, owner: &str) -> Result<Vec<PgpKey>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT fingerprint, secret_key IS NOT NULL AS secret, user_ids, created_at
+            FROM openpgp_keys WHERE owner = ?
+            ORDER BY created_at
+            "#,
+        )
+        .bind(owner.to_lowercase())
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(key_from_row).collect()
+    }
+
+    /// Key of `owner` with `fingerprint`
+    pub async fn get(&self, owner: &str, fingerprint: &str) -> Result<Option<PgpKey>> {
+        let row = sqlx::query(
+            r#"
+            SELECT fingerprint, secret_key IS NOT NULL AS secret, user_ids, created_at
+            FROM openpgp_keys WHERE owner = ? AND fingerprint = ?
+            "#,
+        )
+        .bind(owner.to_lowercase())
+        .bind(fingerprint.to_uppercase())
+        .fetch_optional(&self.db)
+        .await?;
+        row.as_ref().map(key_from_row).transpose()
+    }
+
+    /// Delete a key of `owner`; false without such a key
+    pub async fn delete(&self, owner: &str, fingerprint: &str) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM openpgp_keys WHERE owner = ? AND fingerprint = ?")
+            .bind(owner.to_lowercase())
+            .bind(fingerprint.to_uppercase())
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    /// Every stored key of `owner`, private ones unlocked by their
+    /// passphrase
+    pub async fn keyring(&self, owner: &str) -> Result<Keyring> {
+        let rows = sqlx::query(
+            "SELECT public_key, secret_key, passphrase FROM openpgp_keys WHERE owner = ?",
+        )
+        .bind(owner.to_lowercase())
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut keyring = Keyring::default();
+        for row in rows {
+            let public_key: String = row.get("public_key");
+            let (public, _) = SignedPublicKey::from_string(&public_key)?;
+            keyring.public.push(public);
+
+            let secret_key: Option<Vec<u8>> = row.get("secret_key");
+            let passphrase: Option<Vec<u8>> = row.get("passphrase");
+            if let (Some(secret_key), Some(passphrase)) = (secret_key, passphrase) {
+                let armored = String::from_utf8(self.cipher.open(&secret_key)?)?;
+                let (secret, _) = SignedSecretKey::from_string(&armored)?;
+                let passphrase = String::from_utf8(self.cipher.open(&passphrase)?)?;
+                keyring.secret.push((secret, passphrase));
+            }
+        }
+        Ok(keyring)
+    }
+
+    /// Public keys of `email`: those `owner` stored, else those its domain
+    /// publishes in a Web Key Directory
+    pub async fn public_keys_for(&self, owner: &str, email: &str) -> Result<Vec<SignedPublicKey>> {
+        let stored: Vec<SignedPublicKey> = self
+            .keyring(owner)
+            .await?
+            .public
+            .into_iter()
+            .filter(|key| crypto::has_address(key, email))
+            .collect();
+        if !stored.is_empty() {
+            return Ok(stored);
+        }
+
+        let Some(wkd) = &self.wkd else {
+            return Ok(Vec::new());
+        };
+        let email = email.trim().to_lowercase();
+        if let Some(keys) = self.wkd_keys.get(&email) {
+            return Ok(keys);
+        }
+        let keys = wkd.lookup(&email).await;
+        self.wkd_keys.insert(email, keys.clone());
+        Ok(keys)
+    }
+
+    /// Keys to encrypt a message of `owner` to `recipients` with, including
+    /// the owner's own so they can read what they sent, and the recipients
+    /// without a key
+    pub async fn recipient_keys(
+        &self,
+        owner: &str,
+        recipients: &[String],
+    ) -> Result<(Vec<SignedPublicKey>, Vec<String>)> {
+        let mut keys = Vec::new();
+        let mut missing = Vec::new();
+        for recipient in recipients {
+            let found = self.public_keys_for(owner, recipient).await?;
+            if found.is_empty() {
+                missing.push(recipient.clone());
+            }
+            keys.extend(found);
+        }
+        let own = self.keyring(owner).await?;
+        keys.extend(
+            own.secret
+                .iter()
+                .map(|(key, _)| SignedPublicKey::from(key.clone())),
+        );
+        Ok((keys, missing))
+    }
+
+    /// Decrypt a message of `owner` and check its signature, looking up the
+    /// sender's key when the signer isn't stored
+    pub async fn open(&self, owner: &str, message: &[u8]) -> Result<Opened> {
+        let mut keyring = self.keyring(owner).await?;
+        let opened = mime::open(message, &keyring);
+        let unknown = opened
+            .report
+            .signature
+            .as_ref()
+            .is_some_and(|check| check.status == SignatureStatus::UnknownKey);
+        if !unknown {
+            return Ok(opened);
+        }
+
+        let Some(sender) = sender(message) else {
+            return Ok(opened);
+        };
+        let sender_keys = self.public_keys_for(owner, &sender).await?;
+        if sender_keys.is_empty() {
+            return Ok(opened);
+        }
+        keyring.public.extend(sender_keys);
+        Ok(mime::open(message, &keyring))
+    }
+}
+
+fn key_from_row(row: &SqliteRow) -> Result<PgpKey> {
+    let user_ids: String = row.get("user_ids");
+    let created_at: String = row.get("created_at");
+    Ok(PgpKey {
+        fingerprint: row.get("fingerprint"),
+        user_ids: serde_json::from_str(&user_ids)?,
+        secret: row.get("secret"),
+        created_at: DateTime::parse_from_rfc3339(&created_at)?.with_timezone(&Utc),
+    })
+}
+
+/// Address of the From header
+fn sender(message: &[u8]) -> Option<String> {
+    let parsed = MessageParser::new().parse_headers(message)?;
+    let address = parsed.from()?.first()?.address()?;
+    Some(address.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openpgp::crypto::tests::{generate_key, public_armored};
+
+    async fn manager() -> OpenPgpManager {
+        let config = OpenPgpConfig {
+            enabled: true,
+            key_secret: "secret".to_string(),
+            wkd: false,
+        };
+        OpenPgpManager::connect("sqlite::memory:", &config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_import_list_delete() {
+        let manager = manager().await;
+        let alice = generate_key("Alice <alice@example.com>");
+        let fingerprint = crypto::fingerprint(&alice);
+
+        let public = ParsedKey::from_armored(&public_armored(&alice)).unwrap();
+        let key = manager
+            .import("Bob@example.com", &public, "")
+            .await
+            .unwrap();
+        assert_eq!(key.fingerprint, fingerprint);
+        assert_eq!(key.user_ids, vec!["Alice <alice@example.com>"]);
+        assert!(!key.secret);
+
+        let secret = ParsedKey::Secret(alice);
+        assert!(
+            manager
+                .import("bob@example.com", &secret, "")
+                .await
+                .unwrap()
+                .secret
+        );
+        // Uploading the public key again keeps the private one
+        assert!(
+            manager
+                .import("bob@example.com", &public, "")
+                .await
+                .unwrap()
+                .secret
+        );
+
+        let keys = manager.list("bob@example.com").await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(manager.list("carol@example.com").await.unwrap().is_empty());
+
+        let keyring = manager.keyring("bob@example.com").await.unwrap();
+        assert_eq!(keyring.public.len(), 1);
+        assert_eq!(keyring.secret.len(), 1);
+
+        assert!(manager
+            .delete("bob@example.com", &fingerprint.to_lowercase())
+            .await
+            .unwrap());
+        assert!(!manager
+            .delete("bob@example.com", &fingerprint)
+            .await
+            .unwrap());
+        assert!(manager.keyring("bob@example.com").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_to_recipients_and_open() {
+        let manager = manager().await;
+        let alice = generate_key("Alice <alice@example.com>");
+        let bob = generate_key("Bob <bob@example.com>");
+        manager
+            .import("alice@example.com", &ParsedKey::Secret(alice.clone()), "")
+            .await
+            .unwrap();
+        manager
+            .import(
+                "alice@example.com",
+                &ParsedKey::from_armored(&public_armored(&bob)).unwrap(),
+                "",
+            )
+            .await
+            .unwrap();
+        manager
+            .import("bob@example.com", &ParsedKey::Secret(bob), "")
+            .await
+            .unwrap();
+
+        let recipients = vec![
+            "bob@example.com".to_string(),
+            "carol@example.com".to_string(),
+        ];
+        let (keys, missing) = manager
+            .recipient_keys("alice@example.com", &recipients)
+            .await
+            .unwrap();
+        assert_eq!(missing, vec!["carol@example.com"]);
+        // Bob's key and Alice's own
+        assert_eq!(keys.len(), 2);
+
+        let message = b"From: alice@example.com\r\nTo: bob@example.com\r\n\r\nHi Bob\r\n";
+        let encrypted = mime::encrypt_message(message, &keys).unwrap();
+        for owner in ["alice@example.com", "bob@example.com"] {
+            let opened = manager.open(owner, &encrypted).await.unwrap();
+            assert!(opened.report.decrypted, "{} can't decrypt", owner);
+            assert!(String::from_utf8(opened.message.unwrap())
+                .unwrap()
+                .ends_with("Hi Bob\r\n"));
+        }
+
+        let opened = manager.open("carol@example.com", &encrypted).await.unwrap();
+        assert!(opened.report.encrypted && !opened.report.decrypted);
+    }
+
+    #[tokio::test]
+    async fn test_requires_key_secret() {
+        let db = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        assert!(OpenPgpManager::new(db, &OpenPgpConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+//! PGP/MIME (RFC 3156) and inline OpenPGP messages
+
+use anyhow::Result;
+use pgp::SignedPublicKey;
+
+use super::crypto::{self, Keyring};
+use super::types::PgpReport;
+use crate::mime::entity::{header_fields, MimeEntity};
+
+/// A message after its OpenPGP protection was looked at
+#[derive(Debug, Default)]
+pub struct Opened {
+    pub report: PgpReport,
+    /// The decrypted message, when it was encrypted and could be decrypted
+    pub message: Option<Vec<u8>>,
+}
+
+/// Whether `message` is encrypted, PGP/MIME or inline
+pub fn is_encrypted(message: &[u8]) -> bool {
+    let entity = MimeEntity::parse(message);
+    entity.content_type == "multipart/encrypted"
+        || (entity.content_type == "text/plain" && inline_block(&entity.text(), MESSAGE).is_some())
+}
+
+/// Decrypt `message` and check its signatures with `keyring`
+pub fn open(message: &[u8], keyring: &Keyring) -> Opened {
+    let entity = MimeEntity::parse(message);
+    let mut opened = Opened::default();
+
+    match entity.content_type.as_str() {
+        "multipart/encrypted" => {
+            opened.report.encrypted = true;
+            let Some(armored) = entity.parts.get(1).map(|part| part.text()) else {
+                opened.report.error = Some("Malformed PGP/MIME message".to_string());
+                return opened;
+            };
+            match keyring.decrypt(&armored) {
+                Ok((content, signature)) => {
+                    let mut decrypted = outer_fields(entity.header);
+                    decrypted.extend_from_slice(&content);
+                    opened.report.decrypted = true;
+                    // Signed then encrypted, or signed and encrypted at once
+                    opened.report.signature =
+                        signature.or_else(|| open(&decrypted, keyring).report.signature);
+                    opened.message = Some(decrypted);
+                }
+                Err(e) => opened.report.error = Some(e.to_string()),
+            }
+        }
+        "multipart/signed" => {
+            if let [signed, signature, ..] = entity.parts.as_slice() {
+                if signature.content_type == "application/pgp-signature" {
+                    let mut data = signed.header.to_vec();
+                    data.extend_from_slice(signed.body);
+                    opened.report.signature =
+                        Some(keyring.verify_detached(&canonical(&data), &signature.text()));
+                }
+            }
+        }
+        _ => {
+            let Some(text) = first_text(&entity).map(|part| part.text()) else {
+                return opened;
+            };
+            if let Some(block) = inline_block(&text, SIGNED_MESSAGE) {
+                opened.report.signature = keyring.verify_cleartext(block);
+            } else if let Some(block) = inline_block(&text, MESSAGE) {
+                opened.report.encrypted = true;
+                match keyring.decrypt(block) {
+                    Ok((content, signature)) => {
+                        opened.report.decrypted = true;
+                        opened.report.signature = signature;
+                        // Only a plain text message can be rewritten in place
+                        if entity.content_type == "text/plain" {
+                            let mut decrypted = outer_fields(entity.header);
+                            decrypted.extend_from_slice(
+                                b"Content-Type: text/plain; charset=utf-8\r\n\r\n",
+                            );
+                            decrypted.extend_from_slice(&content);
+                            opened.message = Some(decrypted);
+                        }
+                    }
+                    Err(e) => opened.report.error = Some(e.to_string()),
+                }
+            }
+        }
+    }
+
+    opened
+}
+
+/// `message` encrypted to `keys` as a PGP/MIME message
+///
+/// The routing and display header fields stay in clear; the content
+/// fields and the body are encrypted.
+pub fn encrypt_message(message: &[u8], keys: &[SignedPublicKey]) -> Result<Vec<u8>> {
+    let entity = MimeEntity::parse(message);
+    let mut inner = Vec::new();
+    for (name, raw) in header_fields(entity.header) {
+        if name.starts_with("content-") {
+            inner.extend_from_slice(raw);
+        }
+    }
+    inner.extend_from_slice(b"\r\n");
+    inner.extend_from_slice(entity.body);
+    let armored = crypto::encrypt(&canonical(&inner), keys)?;
+
+    let boundary = format!("=_pgp_{}", uuid::Uuid::new_v4().simple());
+    let mut encrypted = outer_fields(entity.header);
+    if !header_fields(entity.header)
+        .iter()
+        .any(|(name, _)| name == "mime-version")
+    {
+        encrypted.extend_from_slice(b"MIME-Version: 1.0\r\n");
+    }
+    encrypted.extend_from_slice(
+        format!(
+            "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\";\r\n \
+             boundary=\"{boundary}\"\r\n\r\n\
+             This is an OpenPGP/MIME encrypted message (RFC 4880 and 3156)\r\n\
+             --{boundary}\r\n\
+             Content-Type: application/pgp-encrypted\r\n\
+             Content-Description: PGP/MIME version identification\r\n\r\n\
+             Version: 1\r\n\r\n\
+             --{boundary}\r\n\
+             Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+             Content-Description: OpenPGP encrypted message\r\n\
+             Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    encrypted.extend_from_slice(&canonical(armored.trim_end().as_bytes()));
+    encrypted.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    Ok(encrypted)
+}
+
+const SIGNED_MESSAGE: (&str, &str) = (
+    "-----BEGIN PGP SIGNED MESSAGE-----",
+    "-----END PGP SIGNATURE-----",
+);
+const MESSAGE: (&str, &str) = ("-----BEGIN PGP MESSAGE-----", "-----END PGP MESSAGE-----");
+
+/// The armored block of `text` between `markers`
+fn inline_block<'a>(text: &'a str, (begin, end): (&str, &str)) -> Option<&'a str> {
+    let start = text.find(begin)?;
+    let stop = text[start..].find(end)? + start + end.len();
+    Some(&text[start..stop])
+}
+
+/// The first text/plain leaf, where inline OpenPGP blocks are
+fn first_text<'e, 'a>(entity: &'e MimeEntity<'a>) -> Option<&'e MimeEntity<'a>> {
+    if entity.content_type == "text/plain" && !entity.is_attachment() {
+        return Some(entity);
+    }
+    entity.parts.iter().find_map(first_text)
+}
+
+/// Header fields other than the `Content-*` ones
+fn outer_fields(header: &[u8]) -> Vec<u8> {
+    header_fields(header)
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with("content-"))
+        .flat_map(|(_, raw)| raw.to_vec())
+        .collect()
+}
+
+/// Line breaks as CRLF, the form signatures are computed over
+fn canonical(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openpgp::crypto::tests::{generate_key, sign_detached};
+    use crate::openpgp::types::SignatureStatus;
+    use pgp::ArmorOptions;
+
+    const MESSAGE_TEXT: &[u8] = b"From: alice@example.com\r\n\
+        To: bob@example.com\r\n\
+        Subject: Plans\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\r\n\
+        Meet at noon.\r\n";
+
+    #[test]
+    fn test_encrypt_and_open() {
+        let bob = generate_key("Bob <bob@example.com>");
+        let encrypted =
+            encrypt_message(MESSAGE_TEXT, &[SignedPublicKey::from(bob.clone())]).unwrap();
+        let text = String::from_utf8_lossy(&encrypted);
+        assert!(text.contains("Subject: Plans\r\n"));
+        assert!(text.contains("multipart/encrypted"));
+        assert!(!text.contains("Meet at noon"));
+        assert!(is_encrypted(&encrypted));
+
+        // Without the private key
+        let opened = open(&encrypted, &Keyring::default());
+        assert!(opened.report.encrypted && !opened.report.decrypted);
+        assert!(opened.report.error.is_some());
+        assert!(opened.message.is_none());
+
+        let keyring = Keyring {
+            public: Vec::new(),
+            secret: vec![(bob, String::new())],
+        };
+        let opened = open(&encrypted, &keyring);
+        assert!(opened.report.decrypted);
+        let decrypted = opened.message.unwrap();
+        let entity = MimeEntity::parse(&decrypted);
+        assert_eq!(entity.content_type, "text/plain");
+        assert_eq!(entity.header_value("subject").as_deref(), Some("Plans"));
+        assert_eq!(entity.text().trim(), "Meet at noon.");
+    }
+
+    #[test]
+    fn test_multipart_signed() {
+        let alice = generate_key("Alice <alice@example.com>");
+        let signed_part = "Content-Type: text/plain\r\n\r\nSigned text\r\n";
+        let signature = sign_detached(&alice, signed_part.as_bytes());
+
+        let message = format!(
+            "From: alice@example.com\r\n\
+             Content-Type: multipart/signed; micalg=pgp-sha256;\r\n \
+             protocol=\"application/pgp-signature\"; boundary=\"b\"\r\n\r\n\
+             --b\r\n{signed_part}\r\n--b\r\n\
+             Content-Type: application/pgp-signature\r\n\r\n{signature}\r\n--b--\r\n"
+        );
+
+        let report = open(message.as_bytes(), &Keyring::default()).report;
+        assert_eq!(
+            report.signature.unwrap().status,
+            SignatureStatus::UnknownKey
+        );
+
+        let keyring = Keyring {
+            public: vec![SignedPublicKey::from(alice.clone())],
+            secret: Vec::new(),
+        };
+        let check = open(message.as_bytes(), &keyring).report.signature.unwrap();
+        assert_eq!(check.status, SignatureStatus::Valid);
+        assert_eq!(check.signer, Some(crypto::fingerprint(&alice)));
+
+        let tampered = message.replace("Signed text", "Changed text");
+        let check = open(tampered.as_bytes(), &keyring)
+            .report
+            .signature
+            .unwrap();
+        assert_eq!(check.status, SignatureStatus::Invalid);
+    }
+
+    #[test]
+    fn test_inline_signed() {
+        let alice = generate_key("Alice <alice@example.com>");
+        let cleartext = pgp::cleartext::CleartextSignedMessage::sign(
+            rand::thread_rng(),
+            "Hello Bob\n",
+            &alice,
+            String::new,
+        )
+        .unwrap()
+        .to_armored_string(ArmorOptions::default())
+        .unwrap();
+        let message = format!("From: alice@example.com\r\n\r\n{cleartext}");
+
+        let keyring = Keyring {
+            public: vec![SignedPublicKey::from(alice)],
+            secret: Vec::new(),
+        };
+        let report = open(message.as_bytes(), &keyring).report;
+        assert!(!report.encrypted);
+        assert_eq!(report.signature.unwrap().status, SignatureStatus::Valid);
+    }
+
+    #[test]
+    fn test_plain_message() {
+        let opened = open(MESSAGE_TEXT, &Keyring::default());
+        assert!(opened.report.is_empty());
+        assert!(opened.message.is_none());
+        assert!(!is_encrypted(MESSAGE_TEXT));
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(canonical(b"a\nb\r\nc\n"), b"a\r\nb\r\nc\r\n");
+    }
+}
@@ -0,0 +1,19 @@
+//! OpenPGP: signed and encrypted mail of users with keys
+//!
+//! Users upload their keys through `/api/openpgp/keys`: their own private
+//! keys, stored encrypted under `[openpgp] key_secret`, and the public keys
+//! of correspondents. PGP/MIME (RFC 3156) and inline messages are decrypted
+//! with the private keys when the API or IMAP shows them, and their
+//! signatures are checked with the stored public keys or, failing those,
+//! the sender's Web Key Directory. Mail sent through the API with
+//! `"encrypt": true` is encrypted to every recipient's public key.
+
+pub mod crypto;
+pub mod manager;
+pub mod mime;
+pub mod types;
+pub mod wkd;
+
+pub use crypto::Keyring;
+pub use manager::OpenPgpManager;
+pub use types::*;
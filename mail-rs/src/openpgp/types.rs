@@ -0,0 +1,62 @@
+//! OpenPGP types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A key of a user's keyring
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PgpKey {
+    /// Uppercase hex fingerprint of the primary key
+    pub fingerprint: String,
+    pub user_ids: Vec<String>,
+    /// The private key is stored and decrypts the user's mail
+    pub secret: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Key upload, armored
+#[derive(Debug, Deserialize)]
+pub struct ImportKey {
+    pub armored: String,
+    /// Passphrase of a protected private key
+    #[serde(default)]
+    pub passphrase: Option<String>,
+}
+
+/// Outcome of checking a signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    Valid,
+    /// Made by a known key but doesn't match the content
+    Invalid,
+    /// None of the known keys made it
+    UnknownKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SignatureCheck {
+    pub status: SignatureStatus,
+    /// Fingerprint of the signing key when known, else the issuer key id
+    pub signer: Option<String>,
+}
+
+/// OpenPGP protection of a message and what came of it
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PgpReport {
+    /// PGP/MIME or inline encrypted
+    pub encrypted: bool,
+    /// Decrypted with one of the user's keys
+    pub decrypted: bool,
+    /// Signature of the message, or of its plaintext when encrypted
+    pub signature: Option<SignatureCheck>,
+    /// Why the message couldn't be decrypted
+    pub error: Option<String>,
+}
+
+impl PgpReport {
+    /// Whether the message uses OpenPGP at all
+    pub fn is_empty(&self) -> bool {
+        !self.encrypted && self.signature.is_none()
+    }
+}
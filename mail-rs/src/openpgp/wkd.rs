@@ -0,0 +1,146 @@
+//! Web Key Directory lookups
+//!
+//! Recipients publish their public keys under
+//! `https://openpgpkey.<domain>/.well-known/openpgpkey/<domain>/hu/<hash>`
+//! (the advanced method) or `https://<domain>/.well-known/openpgpkey/hu/<hash>`
+//! (the direct method), where the hash is the z-base-32 SHA-1 of the
+//! lowercased local part.
+
+use std::time::Duration;
+
+use pgp::{Deserializable, SignedPublicKey};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use tracing::debug;
+
+use super::crypto::has_address;
+
+/// How long fetching a key may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ZBASE32: &[u8] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+/// Fetches recipients' public keys from their domain
+pub struct WkdClient {
+    http: reqwest::Client,
+}
+
+impl WkdClient {
+    pub fn new() -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+        Ok(Self { http })
+    }
+
+    /// Keys published for `email` that carry its address
+    pub async fn lookup(&self, email: &str) -> Vec<SignedPublicKey> {
+        for url in urls(email) {
+            let response = match self.http.get(&url).send().await {
+                Ok(response) if response.status().is_success() => response,
+                Ok(response) => {
+                    debug!("WKD {} returned {}", url, response.status());
+                    continue;
+                }
+                Err(e) => {
+                    debug!("WKD {} failed: {}", url, e);
+                    continue;
+                }
+            };
+            let Ok(body) = response.bytes().await else {
+                continue;
+            };
+            let keys: Vec<SignedPublicKey> = parse_keys(&body)
+                .into_iter()
+                .filter(|key| key.verify().is_ok() && has_address(key, email))
+                .collect();
+            if !keys.is_empty() {
+                return keys;
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// Lookup URLs of `email`, advanced method first
+pub fn urls(email: &str) -> Vec<String> {
+    let Some((local, domain)) = email.trim().rsplit_once('@') else {
+        return Vec::new();
+    };
+    let domain = domain.to_lowercase();
+    let hash = local_part_hash(local);
+    let local = percent_encode(local);
+    vec![
+        format!("https://openpgpkey.{domain}/.well-known/openpgpkey/{domain}/hu/{hash}?l={local}"),
+        format!("https://{domain}/.well-known/openpgpkey/hu/{hash}?l={local}"),
+    ]
+}
+
+/// z-base-32 of the SHA-1 of the lowercased local part
+pub fn local_part_hash(local: &str) -> String {
+    let hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, local.to_lowercase().as_bytes());
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in hash.as_ref() {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ZBASE32[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ZBASE32[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Binary keys as served by WKD, or armored ones
+fn parse_keys(body: &[u8]) -> Vec<SignedPublicKey> {
+    if body.starts_with(b"-----BEGIN") {
+        return std::str::from_utf8(body)
+            .ok()
+            .and_then(|armored| SignedPublicKey::from_string(armored).ok())
+            .map(|(key, _)| vec![key])
+            .unwrap_or_default();
+    }
+    SignedPublicKey::from_bytes_many(body)
+        .filter_map(Result::ok)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_part_hash() {
+        // Example of draft-koch-openpgp-webkey-service
+        assert_eq!(
+            local_part_hash("Joe.Doe"),
+            "iy9q119eutrkn8s1mk4r39qejnbu3n5q"
+        );
+    }
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(
+            urls("Joe.Doe@Example.ORG"),
+            vec![
+                "https://openpgpkey.example.org/.well-known/openpgpkey/example.org/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q?l=Joe.Doe",
+                "https://example.org/.well-known/openpgpkey/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q?l=Joe.Doe",
+            ]
+        );
+        assert!(urls("not-an-address").is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+//! Storage usage history: one sample per mailbox and recalculation
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+/// Usage of one mailbox as measured by a recalculation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageSample {
+    pub email: String,
+    pub recorded_at: DateTime<Utc>,
+    /// Total size of all messages in bytes
+    pub bytes: u64,
+    pub messages: u64,
+    /// Storage limit of the user's quota at the time
+    pub storage_limit: u64,
+    /// Measured minus accounted bytes; non-zero when the `maildirsize`
+    /// ledger had drifted
+    pub drift_bytes: i64,
+}
+
+/// Usage history manager
+pub struct QuotaHistory {
+    db: SqlitePool,
+}
+
+impl QuotaHistory {
+    /// Create a new usage history manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the history table
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let history = Self::new(SqlitePool::connect(database_url).await?);
+        history.init_db().await?;
+        Ok(history)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS quota_usage_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL,
+                recorded_at TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                messages INTEGER NOT NULL,
+                storage_limit INTEGER NOT NULL,
+                drift_bytes INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_quota_usage_history_email ON quota_usage_history (email, recorded_at)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Save the samples of one recalculation
+    pub async fn record(&self, samples: &[UsageSample]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        for sample in samples {
+            sqlx::query(
+                r#"
+                INSERT INTO quota_usage_history
+                    (email, recorded_at, bytes, messages, storage_limit, drift_bytes)
+                VALUES (?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&sample.email)
+            .bind(sample.recorded_at.to_rfc3339())
+            .bind(sample.bytes as i64)
+            .bind(sample.messages as i64)
+            .bind(sample.storage_limit as i64)
+            .bind(sample.drift_bytes)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Samples of `email` since `since`, oldest first
+    pub async fn history(&self, email: &str, since: DateTime<Utc>) -> Result<Vec<UsageSample>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT email, recorded_at, bytes, messages, storage_limit, drift_bytes
+            FROM quota_usage_history
+            WHERE email = ? AND recorded_at >= ?
+            ORDER BY recorded_at
+            "#,
+        )
+        .bind(email)
+        .bind(since.to_rfc3339())
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(sample_from_row).collect()
+    }
+
+    /// Samples of the latest recalculation, so removed mailboxes drop out
+    pub async fn latest(&self) -> Result<Vec<UsageSample>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT email, recorded_at, bytes, messages, storage_limit, drift_bytes
+            FROM quota_usage_history
+            WHERE recorded_at = (SELECT MAX(recorded_at) FROM quota_usage_history)
+            ORDER BY email
+            "#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(sample_from_row).collect()
+    }
+
+    /// Remove samples older than `before`; returns how many
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM quota_usage_history WHERE recorded_at < ?")
+            .bind(before.to_rfc3339())
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+fn sample_from_row(row: &SqliteRow) -> Result<UsageSample> {
+    let recorded_at: String = row.get("recorded_at");
+    Ok(UsageSample {
+        email: row.get("email"),
+        recorded_at: DateTime::parse_from_rfc3339(&recorded_at)?.with_timezone(&Utc),
+        bytes: row.get::<i64, _>("bytes") as u64,
+        messages: row.get::<i64, _>("messages") as u64,
+        storage_limit: row.get::<i64, _>("storage_limit") as u64,
+        drift_bytes: row.get("drift_bytes"),
+    })
+}
+
+/// Storage consumption in the Prometheus text format: totals and one
+/// series per mailbox
+pub fn to_prometheus(samples: &[UsageSample]) -> String {
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, values: Vec<(Option<&str>, String)>| {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n",
+            name, help, name
+        ));
+        for (user, value) in values {
+            match user {
+                Some(user) => out.push_str(&format!(
+                    "{}{{user=\"{}\"}} {}\n",
+                    name,
+                    escape_label(user),
+                    value
+                )),
+                None => out.push_str(&format!("{} {}\n", name, value)),
+            }
+        }
+        out.push('\n');
+    };
+
+    let total = |value: fn(&UsageSample) -> u64| samples.iter().map(value).sum::<u64>().to_string();
+    let per_user = |value: fn(&UsageSample) -> String| {
+        samples
+            .iter()
+            .map(|sample| (Some(sample.email.as_str()), value(sample)))
+            .collect::<Vec<_>>()
+    };
+    gauge(
+        "mail_rs_mailboxes",
+        "Mailboxes measured by the last recalculation",
+        vec![(None, samples.len().to_string())],
+    );
+    gauge(
+        "mail_rs_storage_used_bytes_total",
+        "Storage used by all mailboxes",
+        vec![(None, total(|sample| sample.bytes))],
+    );
+    gauge(
+        "mail_rs_storage_messages_total",
+        "Messages stored in all mailboxes",
+        vec![(None, total(|sample| sample.messages))],
+    );
+    gauge(
+        "mail_rs_storage_limit_bytes_total",
+        "Sum of the storage limits of all mailboxes",
+        vec![(None, total(|sample| sample.storage_limit))],
+    );
+    gauge(
+        "mail_rs_mailbox_storage_used_bytes",
+        "Storage used by a mailbox",
+        per_user(|sample| sample.bytes.to_string()),
+    );
+    gauge(
+        "mail_rs_mailbox_storage_limit_bytes",
+        "Storage limit of a mailbox",
+        per_user(|sample| sample.storage_limit.to_string()),
+    );
+    gauge(
+        "mail_rs_mailbox_quota_drift_bytes",
+        "Bytes the quota ledger of a mailbox was off by at the last recalculation",
+        per_user(|sample| sample.drift_bytes.to_string()),
+    );
+    gauge(
+        "mail_rs_storage_recalculated_timestamp_seconds",
+        "Time of the last recalculation",
+        vec![(
+            None,
+            samples
+                .iter()
+                .map(|sample| sample.recorded_at.timestamp())
+                .max()
+                .unwrap_or(0)
+                .to_string(),
+        )],
+    );
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn sample(email: &str, recorded_at: DateTime<Utc>, bytes: u64) -> UsageSample {
+        UsageSample {
+            email: email.to_string(),
+            recorded_at,
+            bytes,
+            messages: bytes / 100,
+            storage_limit: 1000,
+            drift_bytes: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_latest_and_prune() {
+        let history = QuotaHistory::connect("sqlite::memory:").await.unwrap();
+        let now = Utc::now();
+        let before = now - Duration::days(1);
+        history
+            .record(&[
+                sample("a@example.com", before, 100),
+                sample("b@example.com", before, 200),
+            ])
+            .await
+            .unwrap();
+        history
+            .record(&[sample("a@example.com", now, 300)])
+            .await
+            .unwrap();
+
+        let a = history
+            .history("a@example.com", now - Duration::days(7))
+            .await
+            .unwrap();
+        assert_eq!(
+            a.iter().map(|s| s.bytes).collect::<Vec<_>>(),
+            vec![100, 300]
+        );
+
+        let latest = history.latest().await.unwrap();
+        assert_eq!(
+            latest
+                .iter()
+                .map(|s| (s.email.as_str(), s.bytes))
+                .collect::<Vec<_>>(),
+            vec![("a@example.com", 300)]
+        );
+
+        assert_eq!(history.prune(now - Duration::hours(1)).await.unwrap(), 2);
+        assert_eq!(history.latest().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_prometheus_output() {
+        let now = Utc::now();
+        let metrics = to_prometheus(&[
+            sample("a@example.com", now, 100),
+            sample("b\"x@example.com", now, 200),
+        ]);
+        assert!(metrics.contains(
+            "# TYPE mail_rs_storage_used_bytes_total gauge\nmail_rs_storage_used_bytes_total 300\n"
+        ));
+        assert!(
+            metrics.contains("mail_rs_mailbox_storage_used_bytes{user=\"a@example.com\"} 100\n")
+        );
+        assert!(metrics
+            .contains("mail_rs_mailbox_storage_used_bytes{user=\"b\\\"x@example.com\"} 200\n"));
+        assert!(metrics.contains("mail_rs_mailboxes 2\n"));
+    }
+}
@@ -0,0 +1,191 @@
+//! Maildir++ `maildirsize` accounting
+//!
+//! Each mailbox keeps a `maildirsize` file: a quota definition line, then
+//! one `<bytes> <messages>` line per change. Writers append a line with the
+//! size they added or removed, so usage is the sum of the lines instead of
+//! a walk over every folder. The file is recalculated from disk when it is
+//! missing, unreadable or grows past [`MAX_SIZE`], as Maildir++ specifies.
+
+use super::usage::{mailbox_usage, MailboxUsage};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Name of the accounting file in a user's mailbox
+pub const MAILDIRSIZE_FILE: &str = "maildirsize";
+
+/// Size past which the file is rewritten with the measured usage
+pub const MAX_SIZE: u64 = 5120;
+
+/// Usage of the mailbox at `user_dir`, from its `maildirsize` file
+///
+/// The file is recalculated first if it can't be trusted.
+pub fn usage(user_dir: &Path) -> io::Result<MailboxUsage> {
+    match read(user_dir)? {
+        Some(usage) => Ok(usage),
+        None => recalculate(user_dir),
+    }
+}
+
+/// Record that `bytes` and `messages` were added to (or, negative, removed
+/// from) the mailbox at `user_dir`
+///
+/// Call after the change is on disk: a mailbox without a `maildirsize`
+/// file is measured instead, which already includes it.
+pub fn record(user_dir: &Path, bytes: i64, messages: i64) -> io::Result<()> {
+    let path = user_dir.join(MAILDIRSIZE_FILE);
+    let file = fs::OpenOptions::new().append(true).open(&path);
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if user_dir.exists() {
+                recalculate(user_dir)?;
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    // One short write, so concurrent writers don't interleave
+    file.write_all(format!("{} {}\n", bytes, messages).as_bytes())?;
+    if file.metadata()?.len() > MAX_SIZE {
+        recalculate(user_dir)?;
+    }
+    Ok(())
+}
+
+/// Measure the mailbox at `user_dir` and rewrite its `maildirsize` file
+/// with the result, keeping the quota definition
+pub fn recalculate(user_dir: &Path) -> io::Result<MailboxUsage> {
+    let usage = mailbox_usage(user_dir)?;
+    if !user_dir.exists() {
+        return Ok(usage);
+    }
+
+    let path = user_dir.join(MAILDIRSIZE_FILE);
+    let definition = fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| content.lines().next().map(str::to_string))
+        .unwrap_or_default();
+    let tmp_path = user_dir.join(format!(
+        "{}.{}",
+        MAILDIRSIZE_FILE,
+        uuid::Uuid::new_v4().simple()
+    ));
+    fs::write(
+        &tmp_path,
+        format!("{}\n{} {}\n", definition, usage.bytes, usage.messages),
+    )?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(usage)
+}
+
+/// Sum of the `maildirsize` lines; `None` if the file is missing, too
+/// large or malformed
+fn read(user_dir: &Path) -> io::Result<Option<MailboxUsage>> {
+    let content = match fs::read_to_string(user_dir.join(MAILDIRSIZE_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if content.len() as u64 > MAX_SIZE {
+        return Ok(None);
+    }
+
+    let (mut bytes, mut messages) = (0i64, 0i64);
+    for line in content
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+    {
+        let mut fields = line.split_whitespace();
+        let parsed = fields
+            .next()
+            .and_then(|value| value.parse::<i64>().ok())
+            .zip(fields.next().and_then(|value| value.parse::<i64>().ok()));
+        let Some((line_bytes, line_messages)) = parsed else {
+            return Ok(None);
+        };
+        bytes += line_bytes;
+        messages += line_messages;
+    }
+    if bytes < 0 || messages < 0 {
+        return Ok(None);
+    }
+    Ok(Some(MailboxUsage {
+        bytes: bytes as u64,
+        messages: messages as u64,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_usage_follows_recorded_changes() {
+        let root = TempDir::new().unwrap();
+        let user = root.path().join("alice@example.com");
+        fs::create_dir_all(user.join("new")).unwrap();
+        fs::write(user.join("new/1.a"), [0u8; 100]).unwrap();
+
+        // Measured on first use
+        assert_eq!(
+            usage(&user).unwrap(),
+            MailboxUsage {
+                bytes: 100,
+                messages: 1
+            }
+        );
+
+        // Later changes are only recorded, not measured
+        fs::write(user.join("new/2.b"), [0u8; 50]).unwrap();
+        record(&user, 50, 1).unwrap();
+        fs::remove_file(user.join("new/1.a")).unwrap();
+        record(&user, -100, -1).unwrap();
+        record(&user, 999, 0).unwrap();
+        assert_eq!(
+            usage(&user).unwrap(),
+            MailboxUsage {
+                bytes: 1049,
+                messages: 1
+            }
+        );
+        assert_eq!(
+            fs::read_to_string(user.join(MAILDIRSIZE_FILE)).unwrap(),
+            "\n100 1\n50 1\n-100 -1\n999 0\n"
+        );
+
+        // Recalculating keeps the definition and fixes the drift
+        let content = fs::read_to_string(user.join(MAILDIRSIZE_FILE)).unwrap();
+        fs::write(user.join(MAILDIRSIZE_FILE), format!("1000S{}", content)).unwrap();
+        assert_eq!(recalculate(&user).unwrap().bytes, 50);
+        assert_eq!(
+            fs::read_to_string(user.join(MAILDIRSIZE_FILE)).unwrap(),
+            "1000S\n50 1\n"
+        );
+    }
+
+    #[test]
+    fn test_untrusted_files_are_recalculated() {
+        let root = TempDir::new().unwrap();
+        let user = root.path().join("alice@example.com");
+        fs::create_dir_all(user.join("cur")).unwrap();
+        fs::write(user.join("cur/1.a:2,S"), [0u8; 10]).unwrap();
+
+        for content in [
+            "\n-50 -1\n",
+            "\nbroken\n",
+            &format!("\n{}", "1 0\n".repeat(2000)),
+        ] {
+            fs::write(user.join(MAILDIRSIZE_FILE), content).unwrap();
+            assert_eq!(usage(&user).unwrap().bytes, 10);
+        }
+
+        // Nothing is created for mailboxes that don't exist
+        let bob = root.path().join("bob@example.com");
+        record(&bob, 10, 1).unwrap();
+        assert!(!bob.exists());
+    }
+}
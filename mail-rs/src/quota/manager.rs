@@ -2,13 +2,21 @@ use anyhow::Result;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::warn;
 
 use super::types::{QuotaStatus, UserQuota};
+use crate::storage::MaildirStorage;
 
 /// Quota manager for enforcing user limits
 pub struct QuotaManager {
     quotas: Arc<RwLock<HashMap<String, UserQuota>>>,
     default_quota: UserQuota,
+    /// Storage whose `maildirsize` accounting gives the storage used
+    storage: Option<Arc<MaildirStorage>>,
+    /// Percentages of the storage limit users are warned at
+    warning_thresholds: Vec<u8>,
+    /// Highest threshold each user was last warned at
+    warned: RwLock<HashMap<String, u8>>,
 }
 
 impl QuotaManager {
@@ -17,6 +25,9 @@ impl QuotaManager {
         QuotaManager {
             quotas: Arc::new(RwLock::new(HashMap::new())),
             default_quota: UserQuota::default(),
+            storage: None,
+            warning_thresholds: Vec::new(),
+            warned: RwLock::new(HashMap::new()),
         }
     }
 
@@ -25,11 +36,45 @@ impl QuotaManager {
         QuotaManager {
             quotas: Arc::new(RwLock::new(HashMap::new())),
             default_quota,
+            storage: None,
+            warning_thresholds: Vec::new(),
+            warned: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Take the storage used from the mailboxes' `maildirsize` files in
+    /// `storage` whenever a quota is read, so enforcement and reports
+    /// follow every store and delete
+    pub fn with_storage(mut self, storage: Arc<MaildirStorage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Warn users when their mailbox reaches these percentages of its
+    /// storage limit, see [`Self::storage_warning`]
+    pub fn with_warning_thresholds(mut self, thresholds: Vec<u8>) -> Self {
+        self.warning_thresholds = thresholds;
+        self
+    }
+
+    /// Bytes the mailbox of `email` uses, if read from a storage
+    async fn stored_bytes(&self, email: &str) -> Option<u64> {
+        let storage = self.storage.as_ref()?;
+        match storage.usage(email).await {
+            Ok(usage) => Some(usage.bytes),
+            Err(e) => {
+                warn!("Failed to read storage usage of {}: {}", email, e);
+                None
+            }
         }
     }
 
     /// Get quota for user (creates default if not exists)
     pub async fn get_quota(&self, email: &str) -> UserQuota {
+        if let Some(bytes) = self.stored_bytes(email).await {
+            self.set_storage_used(email, bytes).await;
+        }
+
         let quotas = self.quotas.read().await;
 
         if let Some(quota) = quotas.get(email) {
@@ -102,6 +147,50 @@ impl QuotaManager {
         Ok(())
     }
 
+    /// Record the storage a user's mailbox was measured to use
+    pub async fn set_storage_used(&self, email: &str, bytes: u64) {
+        let mut quotas = self.quotas.write().await;
+
+        quotas
+            .entry(email.to_string())
+            .or_insert_with(|| {
+                let mut quota = self.default_quota.clone();
+                quota.email = email.to_string();
+                quota
+            })
+            .storage_used = bytes;
+    }
+
+    /// Highest warning threshold the mailbox of `email` has reached since
+    /// its user was last warned, if any
+    ///
+    /// Each threshold is reported once; falling back below one re-arms it.
+    pub async fn storage_warning(&self, email: &str) -> Option<u8> {
+        let percent = self.get_quota(email).await.storage_usage_percent();
+        let reached = self
+            .warning_thresholds
+            .iter()
+            .copied()
+            .filter(|threshold| percent >= *threshold as f64)
+            .max();
+
+        let mut warned = self.warned.write().await;
+        match reached {
+            Some(threshold) if warned.get(email).is_none_or(|last| *last < threshold) => {
+                warned.insert(email.to_string(), threshold);
+                Some(threshold)
+            }
+            Some(threshold) => {
+                warned.insert(email.to_string(), threshold);
+                None
+            }
+            None => {
+                warned.remove(email);
+                None
+            }
+        }
+    }
+
     /// Increment message count for today
     pub async fn increment_message_count(&self, email: &str) -> Result<()> {
         let mut quotas = self.quotas.write().await;
@@ -129,9 +218,33 @@ impl QuotaManager {
         Ok(())
     }
 
+    /// Run [`Self::reset_daily_counts`] every `interval`, starting one
+    /// interval from now
+    pub async fn run_daily_reset(self: Arc<Self>, interval: std::time::Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.reset_daily_counts().await {
+                warn!("Failed to reset daily message counts: {}", e);
+            }
+        }
+    }
+
     /// Get all quotas (for admin view)
     pub async fn list_quotas(&self) -> Vec<UserQuota> {
-        let quotas = self.quotas.read().await;
+        let emails: Vec<String> = self.quotas.read().await.keys().cloned().collect();
+        let mut stored = Vec::with_capacity(emails.len());
+        for email in emails {
+            if let Some(bytes) = self.stored_bytes(&email).await {
+                stored.push((email, bytes));
+            }
+        }
+
+        let mut quotas = self.quotas.write().await;
+        for (email, bytes) in stored {
+            if let Some(quota) = quotas.get_mut(&email) {
+                quota.storage_used = bytes;
+            }
+        }
         quotas.values().cloned().collect()
     }
 
@@ -255,6 +368,17 @@ mod tests {
         assert_eq!(quota.storage_used, 600);
     }
 
+    #[tokio::test]
+    async fn test_set_storage_used() {
+        let manager = QuotaManager::new();
+
+        manager.set_storage_used("test@example.com", 700).await;
+        manager.set_storage_used("test@example.com", 400).await;
+        let quota = manager.get_quota("test@example.com").await;
+        assert_eq!(quota.storage_used, 400);
+        assert_eq!(quota.storage_limit, UserQuota::default().storage_limit);
+    }
+
     #[tokio::test]
     async fn test_increment_message_count() {
         let manager = QuotaManager::new();
@@ -307,6 +431,47 @@ mod tests {
         assert_eq!(quotas.len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_storage_used_from_maildirsize() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(MaildirStorage::new(dir.path().to_string_lossy().to_string()));
+        let mut default_quota = UserQuota::default();
+        default_quota.storage_limit = 1000;
+        let manager = QuotaManager::with_defaults(default_quota).with_storage(storage.clone());
+
+        storage.store("test@example.com", &[b'x'; 600]).await.unwrap();
+        assert_eq!(manager.get_quota("test@example.com").await.storage_used, 600);
+        assert_eq!(
+            manager.check_storage("test@example.com", 500).await,
+            QuotaStatus::StorageExceeded
+        );
+
+        storage.store("test@example.com", &[b'x'; 100]).await.unwrap();
+        assert_eq!(manager.list_quotas().await[0].storage_used, 700);
+    }
+
+    #[tokio::test]
+    async fn test_storage_warning_once_per_threshold() {
+        let mut default_quota = UserQuota::default();
+        default_quota.storage_limit = 1000;
+        let manager = QuotaManager::with_defaults(default_quota).with_warning_thresholds(vec![80, 95]);
+        let user = "test@example.com";
+
+        manager.set_storage_used(user, 500).await;
+        assert_eq!(manager.storage_warning(user).await, None);
+        manager.set_storage_used(user, 850).await;
+        assert_eq!(manager.storage_warning(user).await, Some(80));
+        assert_eq!(manager.storage_warning(user).await, None);
+        manager.set_storage_used(user, 990).await;
+        assert_eq!(manager.storage_warning(user).await, Some(95));
+
+        // Back below 95% and over it again warns again, not at 80%
+        manager.set_storage_used(user, 900).await;
+        assert_eq!(manager.storage_warning(user).await, None);
+        manager.set_storage_used(user, 960).await;
+        assert_eq!(manager.storage_warning(user).await, Some(95));
+    }
+
     #[tokio::test]
     async fn test_with_defaults() {
         let mut default_quota = UserQuota::default();
@@ -4,9 +4,21 @@
 /// - Storage limits per user
 /// - Message count limits per day
 /// - Message size limits
+///
+/// Storage usage of a mailbox is measured on disk (see [`usage`]) and
+/// kept up to date in its Maildir++ `maildirsize` file (see
+/// [`maildirsize`]). A periodic recalculation corrects the files and keeps
+/// a usage history (see [`reconciler`]).
 
+pub mod history;
+pub mod maildirsize;
 pub mod manager;
+pub mod reconciler;
 pub mod types;
+pub mod usage;
 
+pub use history::{QuotaHistory, UsageSample};
 pub use manager::QuotaManager;
+pub use reconciler::QuotaReconciler;
 pub use types::{UserQuota, QuotaStatus};
+pub use usage::{mailbox_usage, MailboxUsage};
@@ -0,0 +1,167 @@
+//! Periodic recalculation of storage usage from disk
+//!
+//! The `maildirsize` ledgers only see changes made through the server;
+//! mail removed by hand or a crash between a write and its ledger line
+//! leave them off. A recalculation measures every mailbox, rewrites its
+//! ledger, updates the quota manager and keeps a sample in the history.
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::history::{QuotaHistory, UsageSample};
+use super::manager::QuotaManager;
+use crate::storage::{MaildirStorage, Storage};
+
+/// Recalculates the usage of every mailbox of a storage
+pub struct QuotaReconciler {
+    storage: Arc<MaildirStorage>,
+    quotas: Arc<QuotaManager>,
+    history: Option<Arc<QuotaHistory>>,
+    /// Days samples are kept in the history
+    history_days: u32,
+}
+
+impl QuotaReconciler {
+    pub fn new(storage: Arc<MaildirStorage>, quotas: Arc<QuotaManager>) -> Self {
+        Self {
+            storage,
+            quotas,
+            history: None,
+            history_days: 0,
+        }
+    }
+
+    /// Keep each recalculation's samples in `history` for `days` days
+    pub fn with_history(mut self, history: Arc<QuotaHistory>, days: u32) -> Self {
+        self.history = Some(history);
+        self.history_days = days;
+        self
+    }
+
+    /// Run [`Self::reconcile`] every `interval`
+    pub async fn run(self: Arc<Self>, interval: std::time::Duration) {
+        loop {
+            match self.reconcile().await {
+                Ok(samples) => {
+                    let drifted = samples
+                        .iter()
+                        .filter(|sample| sample.drift_bytes != 0)
+                        .count();
+                    info!(
+                        "Recalculated storage of {} mailboxes: {} bytes, {} ledgers corrected",
+                        samples.len(),
+                        samples.iter().map(|sample| sample.bytes).sum::<u64>(),
+                        drifted
+                    );
+                }
+                Err(e) => error!("Storage recalculation failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Measure every mailbox, correct its ledger and quota, and record the
+    /// result; mailboxes locked for a cutover are skipped
+    pub async fn reconcile(&self) -> Result<Vec<UsageSample>> {
+        let now = Utc::now();
+        let mut samples = Vec::new();
+        for user in self.storage.list_users().await? {
+            if self.storage.is_locked(&user) {
+                continue;
+            }
+            let ledger = match self.storage.usage(&user).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    warn!("Failed to read storage ledger of {}: {}", user, e);
+                    continue;
+                }
+            };
+            let measured = match self.storage.recalculate_usage(&user).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    warn!("Failed to measure mailbox of {}: {}", user, e);
+                    continue;
+                }
+            };
+
+            let drift_bytes = measured.bytes as i64 - ledger.bytes as i64;
+            if drift_bytes != 0 {
+                warn!(
+                    "Storage ledger of {} was off by {} bytes",
+                    user, drift_bytes
+                );
+            }
+            self.quotas.set_storage_used(&user, measured.bytes).await;
+            samples.push(UsageSample {
+                storage_limit: self.quotas.get_quota(&user).await.storage_limit,
+                email: user,
+                recorded_at: now,
+                bytes: measured.bytes,
+                messages: measured.messages,
+                drift_bytes,
+            });
+        }
+
+        if let Some(history) = &self.history {
+            history.record(&samples).await?;
+            history
+                .prune(now - Duration::days(self.history_days as i64))
+                .await?;
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::MailboxUsage;
+
+    #[tokio::test]
+    async fn test_reconcile_corrects_drifted_ledgers() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(MaildirStorage::new(
+            dir.path().to_string_lossy().to_string(),
+        ));
+        let quotas = Arc::new(QuotaManager::new().with_storage(storage.clone()));
+        let history = Arc::new(QuotaHistory::connect("sqlite::memory:").await.unwrap());
+        let reconciler =
+            QuotaReconciler::new(storage.clone(), quotas.clone()).with_history(history.clone(), 30);
+
+        storage
+            .store("alice@example.com", &[b'a'; 100])
+            .await
+            .unwrap();
+        storage.store("bob@example.com", &[b'b'; 50]).await.unwrap();
+        // Delivered behind the server's back
+        std::fs::write(dir.path().join("alice@example.com/new/1.2.host"), [0u8; 25]).unwrap();
+        assert_eq!(
+            quotas.get_quota("alice@example.com").await.storage_used,
+            100
+        );
+
+        let samples = reconciler.reconcile().await.unwrap();
+        let drift: Vec<(&str, u64, i64)> = samples
+            .iter()
+            .map(|s| (s.email.as_str(), s.bytes, s.drift_bytes))
+            .collect();
+        assert_eq!(
+            drift,
+            vec![("alice@example.com", 125, 25), ("bob@example.com", 50, 0)]
+        );
+        assert_eq!(
+            storage.usage("alice@example.com").await.unwrap(),
+            MailboxUsage {
+                bytes: 125,
+                messages: 2
+            }
+        );
+        assert_eq!(
+            quotas.get_quota("alice@example.com").await.storage_used,
+            125
+        );
+        assert_eq!(history.latest().await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+//! Storage usage of Maildir mailboxes
+//!
+//! Usage is measured on disk: the sizes of all message files in `new/`
+//! and `cur/` of INBOX and every `.Folder`, so it also covers mail stored
+//! or removed outside the running server.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Storage used by one mailbox
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxUsage {
+    /// Total size of all messages in bytes
+    pub bytes: u64,
+    /// Number of messages
+    pub messages: u64,
+}
+
+/// Usage of the mailbox at `user_dir` (e.g. `<maildir>/alice@example.com`)
+///
+/// A missing mailbox uses nothing.
+pub fn mailbox_usage(user_dir: &Path) -> io::Result<MailboxUsage> {
+    let mut usage = MailboxUsage::default();
+    if !user_dir.exists() {
+        return Ok(usage);
+    }
+
+    add_folder(user_dir, &mut usage)?;
+    for entry in fs::read_dir(user_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') && entry.file_type()?.is_dir() {
+            add_folder(&entry.path(), &mut usage)?;
+        }
+    }
+    Ok(usage)
+}
+
+fn add_folder(folder: &Path, usage: &mut MailboxUsage) -> io::Result<()> {
+    for dir in ["new", "cur"] {
+        let entries = match fs::read_dir(folder.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                usage.bytes += metadata.len();
+                usage.messages += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_mailbox_usage() {
+        let root = TempDir::new().unwrap();
+        let user = root.path().join("alice@example.com");
+        assert_eq!(mailbox_usage(&user).unwrap(), MailboxUsage::default());
+
+        for dir in ["new", "cur", "tmp", ".Sent/cur"] {
+            fs::create_dir_all(user.join(dir)).unwrap();
+        }
+        fs::write(user.join("new/1.a"), [0u8; 100]).unwrap();
+        fs::write(user.join("cur/2.b:2,S"), [0u8; 50]).unwrap();
+        fs::write(user.join(".Sent/cur/3.c:2,S"), [0u8; 25]).unwrap();
+        // Not yet delivered, and bookkeeping files
+        fs::write(user.join("tmp/4.d"), [0u8; 1000]).unwrap();
+        fs::write(user.join("mail-rs-provisioned"), [0u8; 1000]).unwrap();
+
+        assert_eq!(
+            mailbox_usage(&user).unwrap(),
+            MailboxUsage {
+                bytes: 175,
+                messages: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Read-only operation after storage failures
+//!
+//! When the store is damaged (corrupt database, full or read-only disk) or a
+//! migration failed, the server keeps serving mail for reading while nothing
+//! new is written. In read-only mode SMTP defers incoming mail with 451,
+//! IMAP refuses commands changing mailboxes with `NO [CANNOT]`, the API
+//! answers changes with 503, and the outbound queue, search indexing and
+//! storage jobs pause.
+//!
+//! The mode is entered with `[recovery] read_only = true`, through
+//! `PUT /api/admin/read-only`, or automatically when a write fails with one
+//! of the errors of [`is_storage_failure`] (unless `auto = false`). It lasts
+//! until an operator clears it with `DELETE /api/admin/read-only`.
+
+use crate::config::RecoveryConfig;
+use crate::error::MailError;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io::ErrorKind;
+use tokio::sync::watch;
+use tracing::{error, info};
+
+/// What put the server in read-only mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// `[recovery] read_only` at startup
+    Config,
+    /// An operator through the API
+    Admin,
+    /// A storage failure
+    Automatic,
+}
+
+/// Why and since when the server is read-only
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReadOnlyState {
+    pub reason: String,
+    pub trigger: Trigger,
+    pub since: DateTime<Utc>,
+}
+
+/// Read-only flag shared by every listener and background writer
+pub struct ReadOnlyMode {
+    state: watch::Sender<Option<ReadOnlyState>>,
+    auto: bool,
+}
+
+impl ReadOnlyMode {
+    pub fn from_config(config: &RecoveryConfig) -> Self {
+        let mode = Self {
+            state: watch::Sender::new(None),
+            auto: config.auto,
+        };
+        if config.read_only {
+            mode.enter("read_only set in the configuration", Trigger::Config);
+        }
+        mode
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.state.borrow().is_some()
+    }
+
+    /// Current state, `None` when writable
+    pub fn state(&self) -> Option<ReadOnlyState> {
+        self.state.borrow().clone()
+    }
+
+    /// Switch to read-only mode; the first reason is kept while it lasts
+    pub fn enter(&self, reason: &str, trigger: Trigger) {
+        let entered = self.state.send_if_modified(|state| {
+            if state.is_some() {
+                return false;
+            }
+            *state = Some(ReadOnlyState {
+                reason: reason.to_string(),
+                trigger,
+                since: Utc::now(),
+            });
+            true
+        });
+        if entered {
+            error!("Entering read-only mode: {}", reason);
+        }
+    }
+
+    /// Leave read-only mode, resuming paused writers
+    pub fn clear(&self) {
+        if self.state.send_replace(None).is_some() {
+            info!("Read-only mode cleared");
+        }
+    }
+
+    /// Wait until the server is writable
+    pub async fn writable(&self) {
+        let mut state = self.state.subscribe();
+        // The sender lives as long as `self`
+        let _ = state.wait_for(|state| state.is_none()).await;
+    }
+
+    /// Enter read-only mode if `err` is a storage failure and automatic
+    /// switching is on; returns whether the server is now read-only
+    pub fn trip_on(&self, err: &MailError) -> bool {
+        if self.auto && is_storage_failure(err) {
+            self.enter(&err.to_string(), Trigger::Automatic);
+        }
+        self.is_read_only()
+    }
+}
+
+/// Errors after which writing more would make things worse: a corrupt or
+/// read-only database, or a full or read-only disk
+pub fn is_storage_failure(err: &MailError) -> bool {
+    match err {
+        MailError::Io(e) => is_io_failure(e.kind()),
+        MailError::Database(sqlx::Error::Io(e)) => is_io_failure(e.kind()),
+        MailError::Database(sqlx::Error::Database(e)) => is_failure_message(e.message()),
+        MailError::Storage(message) => is_failure_message(message),
+        _ => false,
+    }
+}
+
+fn is_io_failure(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ReadOnlyFilesystem | ErrorKind::StorageFull | ErrorKind::InvalidData
+    )
+}
+
+/// Messages of SQLite and the OS for corruption and unwritable storage
+fn is_failure_message(message: &str) -> bool {
+    const MARKERS: [&str; 5] = [
+        "malformed",
+        "not a database",
+        "readonly database",
+        "read-only file system",
+        "no space left",
+    ];
+    let message = message.to_lowercase();
+    MARKERS.iter().any(|marker| message.contains(marker))
+}
+
+/// SMTP reply deferring mail while read-only
+pub fn smtp_reply() -> &'static str {
+    "451 4.3.2 Server is in read-only mode, try again later\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_enter_keeps_first_reason() {
+        let mode = ReadOnlyMode::from_config(&RecoveryConfig::default());
+        assert!(!mode.is_read_only());
+
+        mode.enter("disk full", Trigger::Automatic);
+        mode.enter("maintenance", Trigger::Admin);
+        let state = mode.state().unwrap();
+        assert_eq!(
+            (state.reason.as_str(), state.trigger),
+            ("disk full", Trigger::Automatic)
+        );
+
+        mode.clear();
+        assert!(mode.state().is_none());
+    }
+
+    #[test]
+    fn test_config_starts_read_only() {
+        let mode = ReadOnlyMode::from_config(&RecoveryConfig {
+            read_only: true,
+            ..Default::default()
+        });
+        assert_eq!(mode.state().unwrap().trigger, Trigger::Config);
+    }
+
+    #[test]
+    fn test_trip_on_storage_failures() {
+        let mode = ReadOnlyMode::from_config(&RecoveryConfig::default());
+        assert!(!mode.trip_on(&MailError::NotFound("mailbox".to_string())));
+        assert!(!mode.trip_on(&std::io::Error::from(ErrorKind::NotFound).into()));
+        assert!(mode.trip_on(&std::io::Error::from(ErrorKind::StorageFull).into()));
+
+        let manual = ReadOnlyMode::from_config(&RecoveryConfig {
+            auto: false,
+            ..Default::default()
+        });
+        let corrupt = MailError::Storage("database disk image is malformed".to_string());
+        assert!(is_storage_failure(&corrupt));
+        assert!(!manual.trip_on(&corrupt));
+    }
+
+    #[tokio::test]
+    async fn test_writable_waits_for_clear() {
+        let mode = Arc::new(ReadOnlyMode::from_config(&RecoveryConfig::default()));
+        mode.enter("maintenance", Trigger::Admin);
+
+        let waiter = tokio::spawn({
+            let mode = mode.clone();
+            async move { mode.writable().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        mode.clear();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
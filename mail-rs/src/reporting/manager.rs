@@ -0,0 +1,404 @@
+//! Reporting manager: usage event log and stored reports
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Reporting manager
+pub struct ReportingManager {
+    db: SqlitePool,
+}
+
+impl ReportingManager {
+    /// Create a new reporting manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the reporting tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS usage_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                sender TEXT,
+                recipient TEXT,
+                size INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_usage_events_time ON usage_events(created_at)")
+            .execute(&self.db)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS storage_samples (
+                sampled_at TEXT PRIMARY KEY,
+                bytes INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS usage_reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                period TEXT NOT NULL,
+                period_start TEXT NOT NULL,
+                period_end TEXT NOT NULL,
+                generated_at TEXT NOT NULL,
+                data TEXT NOT NULL,
+                UNIQUE(period, period_start)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a usage event
+    pub async fn record(
+        &self,
+        kind: UsageEventKind,
+        sender: Option<&str>,
+        recipient: Option<&str>,
+        size: usize,
+    ) -> Result<()> {
+        self.record_at(kind, sender, recipient, size, Utc::now()).await
+    }
+
+    async fn record_at(
+        &self,
+        kind: UsageEventKind,
+        sender: Option<&str>,
+        recipient: Option<&str>,
+        size: usize,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO usage_events (kind, sender, recipient, size, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(kind.as_str())
+        .bind(sender.map(str::to_lowercase))
+        .bind(recipient.map(str::to_lowercase))
+        .bind(size as i64)
+        .bind(at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record the total mail storage size
+    pub async fn record_storage_sample(&self, bytes: u64) -> Result<()> {
+        self.record_storage_sample_at(bytes, Utc::now()).await
+    }
+
+    async fn record_storage_sample_at(&self, bytes: u64, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO storage_samples (sampled_at, bytes) VALUES (?, ?)")
+            .bind(at.to_rfc3339())
+            .bind(bytes as i64)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Check whether a report was already generated for a period
+    pub async fn report_exists(&self, period: ReportPeriod, start: DateTime<Utc>) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM usage_reports WHERE period = ? AND period_start = ?")
+            .bind(period.as_str())
+            .bind(start.to_rfc3339())
+            .fetch_optional(&self.db)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Build and store the report for `[start, end)`
+    ///
+    /// Regenerating an existing period replaces the stored report.
+    pub async fn generate(
+        &self,
+        period: ReportPeriod,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        top_n: usize,
+    ) -> Result<UsageReport> {
+        let count = |kind: UsageEventKind| {
+            sqlx::query(
+                "SELECT COUNT(*) AS n FROM usage_events WHERE kind = ? AND created_at >= ? AND created_at < ?",
+            )
+            .bind(kind.as_str())
+            .bind(start.to_rfc3339())
+            .bind(end.to_rfc3339())
+            .fetch_one(&self.db)
+        };
+
+        let messages_received: i64 = count(UsageEventKind::Received).await?.get("n");
+        let messages_sent: i64 = count(UsageEventKind::Sent).await?.get("n");
+        let spam_messages: i64 = count(UsageEventKind::Spam).await?.get("n");
+        let auth_failures: i64 = count(UsageEventKind::AuthFailure).await?.get("n");
+
+        let inbound = messages_received + spam_messages;
+        let spam_rate = if inbound > 0 {
+            spam_messages as f64 / inbound as f64
+        } else {
+            0.0
+        };
+
+        let mut report = UsageReport {
+            id: 0,
+            period,
+            period_start: start,
+            period_end: end,
+            generated_at: Utc::now(),
+            messages_received,
+            messages_sent,
+            spam_messages,
+            spam_rate,
+            auth_failures,
+            storage_start_bytes: self.storage_at(start).await?,
+            storage_end_bytes: self.storage_at(end).await?,
+            top_senders: self.top_addresses("sender", start, end, top_n).await?,
+            top_recipients: self.top_addresses("recipient", start, end, top_n).await?,
+        };
+
+        let row = sqlx::query(
+            r#"
+            INSERT INTO usage_reports (period, period_start, period_end, generated_at, data)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(period, period_start) DO UPDATE SET
+                period_end = excluded.period_end,
+                generated_at = excluded.generated_at,
+                data = excluded.data
+            RETURNING id
+            "#,
+        )
+        .bind(period.as_str())
+        .bind(start.to_rfc3339())
+        .bind(end.to_rfc3339())
+        .bind(report.generated_at.to_rfc3339())
+        .bind(serde_json::to_string(&report)?)
+        .fetch_one(&self.db)
+        .await?;
+
+        report.id = row.get("id");
+        Ok(report)
+    }
+
+    /// List stored reports, newest first
+    pub async fn list_reports(
+        &self,
+        period: Option<ReportPeriod>,
+        limit: i64,
+    ) -> Result<Vec<UsageReport>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, data FROM usage_reports
+            WHERE ? IS NULL OR period = ?
+            ORDER BY period_start DESC, id DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(period.map(|p| p.as_str()))
+        .bind(period.map(|p| p.as_str()))
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter().map(row_to_report).collect()
+    }
+
+    /// Get a stored report
+    pub async fn get_report(&self, id: i64) -> Result<Option<UsageReport>> {
+        let row = sqlx::query("SELECT id, data FROM usage_reports WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+
+        row.as_ref().map(row_to_report).transpose()
+    }
+
+    /// Delete events older than `before` (reports are kept)
+    pub async fn prune_events(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM usage_events WHERE created_at < ?")
+            .bind(before.to_rfc3339())
+            .execute(&self.db)
+            .await?;
+
+        sqlx::query("DELETE FROM storage_samples WHERE sampled_at < ?")
+            .bind(before.to_rfc3339())
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Latest storage sample taken at or before `at`
+    async fn storage_at(&self, at: DateTime<Utc>) -> Result<Option<i64>> {
+        let row = sqlx::query(
+            "SELECT bytes FROM storage_samples WHERE sampled_at <= ? ORDER BY sampled_at DESC LIMIT 1",
+        )
+        .bind(at.to_rfc3339())
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(|row| row.get("bytes")))
+    }
+
+    async fn top_addresses(
+        &self,
+        column: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<AddressCount>> {
+        // `column` is one of two fixed names, never user input
+        let query = format!(
+            r#"
+            SELECT {column} AS address, COUNT(*) AS n FROM usage_events
+            WHERE kind IN ('received', 'sent') AND {column} IS NOT NULL AND {column} != ''
+              AND created_at >= ? AND created_at < ?
+            GROUP BY {column}
+            ORDER BY n DESC, address
+            LIMIT ?
+            "#
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(start.to_rfc3339())
+            .bind(end.to_rfc3339())
+            .bind(limit as i64)
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| AddressCount {
+                address: row.get("address"),
+                count: row.get("n"),
+            })
+            .collect())
+    }
+}
+
+fn row_to_report(row: &sqlx::sqlite::SqliteRow) -> Result<UsageReport> {
+    let mut report: UsageReport = serde_json::from_str(row.get("data"))?;
+    report.id = row.get("id");
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    async fn manager() -> ReportingManager {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = ReportingManager::new(db);
+        manager.init_db().await.unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_generate_report() {
+        let manager = manager().await;
+        let start = Utc.with_ymd_and_hms(2024, 3, 12, 0, 0, 0).unwrap();
+        let end = start + Duration::days(1);
+        let during = start + Duration::hours(5);
+
+        for recipient in ["bob@example.com", "bob@example.com", "carol@example.com"] {
+            manager
+                .record_at(UsageEventKind::Received, Some("alice@remote.org"), Some(recipient), 100, during)
+                .await
+                .unwrap();
+        }
+        manager
+            .record_at(UsageEventKind::Sent, Some("bob@example.com"), Some("x@remote.org"), 50, during)
+            .await
+            .unwrap();
+        manager
+            .record_at(UsageEventKind::Spam, Some("spam@bad.org"), Some("bob@example.com"), 10, during)
+            .await
+            .unwrap();
+        manager
+            .record_at(UsageEventKind::AuthFailure, Some("bob@example.com"), None, 0, during)
+            .await
+            .unwrap();
+        // Outside the period
+        manager
+            .record_at(UsageEventKind::Received, Some("a@b.org"), Some("bob@example.com"), 1, end)
+            .await
+            .unwrap();
+
+        manager.record_storage_sample_at(1_000, start).await.unwrap();
+        manager.record_storage_sample_at(1_500, end).await.unwrap();
+
+        let report = manager
+            .generate(ReportPeriod::Daily, start, end, 10)
+            .await
+            .unwrap();
+
+        assert_eq!(report.messages_received, 3);
+        assert_eq!(report.messages_sent, 1);
+        assert_eq!(report.spam_messages, 1);
+        assert_eq!(report.spam_rate, 0.25);
+        assert_eq!(report.auth_failures, 1);
+        assert_eq!(report.storage_growth_bytes(), 500);
+        assert_eq!(report.top_senders[0].address, "alice@remote.org");
+        assert_eq!(report.top_senders[0].count, 3);
+        assert_eq!(
+            report.top_recipients[0],
+            AddressCount {
+                address: "bob@example.com".to_string(),
+                count: 2
+            }
+        );
+
+        assert!(manager.report_exists(ReportPeriod::Daily, start).await.unwrap());
+        let stored = manager.get_report(report.id).await.unwrap().unwrap();
+        assert_eq!(stored.messages_received, 3);
+    }
+
+    #[tokio::test]
+    async fn test_regenerate_replaces_report() {
+        let manager = manager().await;
+        let start = Utc.with_ymd_and_hms(2024, 3, 11, 0, 0, 0).unwrap();
+        let end = start + Duration::weeks(1);
+
+        let first = manager.generate(ReportPeriod::Weekly, start, end, 5).await.unwrap();
+        let second = manager.generate(ReportPeriod::Weekly, start, end, 5).await.unwrap();
+        assert_eq!(first.id, second.id);
+
+        manager.generate(ReportPeriod::Daily, start, start + Duration::days(1), 5).await.unwrap();
+        assert_eq!(manager.list_reports(None, 10).await.unwrap().len(), 2);
+        assert_eq!(
+            manager
+                .list_reports(Some(ReportPeriod::Weekly), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
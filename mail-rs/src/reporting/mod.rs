@@ -0,0 +1,16 @@
+//! Usage reporting for admins
+//!
+//! Records usage events (deliveries, submissions, spam, failed logins) and
+//! storage samples, then builds daily/weekly/monthly reports with message
+//! volumes, top senders/recipients, spam rate, storage growth and auth
+//! failures. Reports are rendered as CSV, HTML or PDF, mailed to admins on
+//! schedule and kept for the admin API.
+
+pub mod manager;
+pub mod render;
+pub mod scheduler;
+pub mod types;
+
+pub use manager::ReportingManager;
+pub use scheduler::ReportScheduler;
+pub use types::*;
@@ -0,0 +1,267 @@
+//! Report rendering (CSV, HTML, PDF)
+//!
+//! The PDF output is a plain single-font text document written by hand, so
+//! reports can be attached to mails without pulling in a PDF library.
+
+use super::types::*;
+
+/// Lines of text per PDF page (A4, 10pt)
+const PDF_LINES_PER_PAGE: usize = 60;
+
+/// Render a report in the given format
+pub fn render(report: &UsageReport, format: ReportFormat) -> Vec<u8> {
+    match format {
+        ReportFormat::Csv => to_csv(report).into_bytes(),
+        ReportFormat::Html => to_html(report).into_bytes(),
+        ReportFormat::Pdf => to_pdf(report),
+    }
+}
+
+/// Summary metrics as (label, value) pairs
+fn metrics(report: &UsageReport) -> Vec<(&'static str, String)> {
+    let bytes = |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or_default();
+
+    vec![
+        ("period", report.period.as_str().to_string()),
+        ("period_start", report.period_start.to_rfc3339()),
+        ("period_end", report.period_end.to_rfc3339()),
+        ("messages_received", report.messages_received.to_string()),
+        ("messages_sent", report.messages_sent.to_string()),
+        ("spam_messages", report.spam_messages.to_string()),
+        ("spam_rate_percent", format!("{:.2}", report.spam_rate * 100.0)),
+        ("auth_failures", report.auth_failures.to_string()),
+        ("storage_start_bytes", bytes(report.storage_start_bytes)),
+        ("storage_end_bytes", bytes(report.storage_end_bytes)),
+        ("storage_growth_bytes", report.storage_growth_bytes().to_string()),
+    ]
+}
+
+/// CSV with one `section,key,value` row per metric and top address
+pub fn to_csv(report: &UsageReport) -> String {
+    let mut csv = String::from("section,key,value\r\n");
+
+    for (key, value) in metrics(report) {
+        csv.push_str(&format!("summary,{},{}\r\n", key, csv_field(&value)));
+    }
+    for entry in &report.top_senders {
+        csv.push_str(&format!("top_sender,{},{}\r\n", csv_field(&entry.address), entry.count));
+    }
+    for entry in &report.top_recipients {
+        csv.push_str(&format!("top_recipient,{},{}\r\n", csv_field(&entry.address), entry.count));
+    }
+
+    csv
+}
+
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Simple standalone HTML page
+pub fn to_html(report: &UsageReport) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<table border=\"1\" cellpadding=\"4\">\n",
+        title = html_escape(&report.title())
+    );
+
+    for (key, value) in metrics(report) {
+        html.push_str(&format!(
+            "<tr><th align=\"left\">{}</th><td>{}</td></tr>\n",
+            key,
+            html_escape(&value)
+        ));
+    }
+    html.push_str("</table>\n");
+
+    for (heading, entries) in [
+        ("Top senders", &report.top_senders),
+        ("Top recipients", &report.top_recipients),
+    ] {
+        html.push_str(&format!("<h2>{}</h2>\n<table border=\"1\" cellpadding=\"4\">\n", heading));
+        for entry in entries {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                html_escape(&entry.address),
+                entry.count
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Plain text lines used for the PDF
+fn text_lines(report: &UsageReport) -> Vec<String> {
+    let mut lines = vec![report.title(), String::new()];
+
+    for (key, value) in metrics(report) {
+        lines.push(format!("{:<24}{}", key, value));
+    }
+    for (heading, entries) in [
+        ("Top senders", &report.top_senders),
+        ("Top recipients", &report.top_recipients),
+    ] {
+        lines.push(String::new());
+        lines.push(heading.to_string());
+        for entry in entries {
+            lines.push(format!("  {:>6}  {}", entry.count, entry.address));
+        }
+    }
+
+    lines
+}
+
+/// Minimal PDF 1.4 document with the report as monospaced text
+pub fn to_pdf(report: &UsageReport) -> Vec<u8> {
+    let lines = text_lines(report);
+    let pages: Vec<&[String]> = lines.chunks(PDF_LINES_PER_PAGE).collect();
+
+    // Objects: 1 catalog, 2 page tree, 3 font, then a page and its content per page
+    let page_ids: Vec<usize> = (0..pages.len()).map(|i| 4 + i * 2).collect();
+    let mut objects: Vec<String> = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            page_ids
+                .iter()
+                .map(|id| format!("{} 0 R", id))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>".to_string(),
+    ];
+
+    for (page, page_id) in pages.iter().zip(&page_ids) {
+        let mut content = String::from("BT /F1 10 Tf 12 TL 50 800 Td\n");
+        for line in page.iter() {
+            content.push_str(&format!("({}) Tj T*\n", pdf_escape(line)));
+        }
+        content.push_str("ET");
+
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            page_id + 1
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}\nendstream",
+            content.len(),
+            content
+        ));
+    }
+
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+    }
+
+    let xref_offset = pdf.len();
+    pdf.push_str(&format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1));
+    for offset in offsets {
+        pdf.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    pdf.push_str(&format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref_offset
+    ));
+
+    pdf.into_bytes()
+}
+
+/// Escape a PDF string literal, replacing non-ASCII characters
+fn pdf_escape(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '(' | ')' | '\\' => format!("\\{}", c),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "?".to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn report() -> UsageReport {
+        UsageReport {
+            id: 1,
+            period: ReportPeriod::Weekly,
+            period_start: Utc.with_ymd_and_hms(2024, 3, 4, 0, 0, 0).unwrap(),
+            period_end: Utc.with_ymd_and_hms(2024, 3, 11, 0, 0, 0).unwrap(),
+            generated_at: Utc.with_ymd_and_hms(2024, 3, 11, 1, 0, 0).unwrap(),
+            messages_received: 120,
+            messages_sent: 40,
+            spam_messages: 30,
+            spam_rate: 0.2,
+            auth_failures: 3,
+            storage_start_bytes: Some(1_000),
+            storage_end_bytes: Some(4_000),
+            top_senders: vec![AddressCount {
+                address: "\"odd,name\"@example.com".to_string(),
+                count: 12,
+            }],
+            top_recipients: vec![AddressCount {
+                address: "bob@example.com".to_string(),
+                count: 20,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = to_csv(&report());
+        assert!(csv.starts_with("section,key,value\r\n"));
+        assert!(csv.contains("summary,spam_rate_percent,20.00\r\n"));
+        assert!(csv.contains("summary,storage_growth_bytes,3000\r\n"));
+        assert!(csv.contains("top_sender,\"\"\"odd,name\"\"@example.com\",12\r\n"));
+        assert!(csv.contains("top_recipient,bob@example.com,20\r\n"));
+    }
+
+    #[test]
+    fn test_html_escapes() {
+        let html = to_html(&report());
+        assert!(html.contains("<title>Weekly usage report 2024-03-04 - 2024-03-11</title>"));
+        assert!(html.contains("&quot;odd,name&quot;@example.com"));
+    }
+
+    #[test]
+    fn test_pdf_structure() {
+        let pdf = String::from_utf8(to_pdf(&report())).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.ends_with("%%EOF\n"));
+        assert!(pdf.contains("(Weekly usage report 2024-03-04 - 2024-03-11) Tj"));
+
+        // The xref offset must point at the xref table
+        let startxref: usize = pdf
+            .rsplit("startxref\n")
+            .next()
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(pdf[startxref..].starts_with("xref\n"));
+        assert_eq!(pdf_escape("a(b)\\é"), "a\\(b\\)\\\\?");
+    }
+}
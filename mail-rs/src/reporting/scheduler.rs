@@ -0,0 +1,262 @@
+//! Scheduled report generation and delivery
+//!
+//! Once an hour the scheduler samples mail storage size, generates reports
+//! for periods that ended since the last run, and mails them to the
+//! configured admins with the rendered reports attached.
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{Duration, Utc};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::manager::ReportingManager;
+use super::render;
+use super::types::*;
+use crate::config::{Config, ReportingConfig};
+use crate::smtp::SmtpQueue;
+use crate::storage::MaildirStorage;
+
+/// Interval between scheduler runs
+const SCHEDULER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Generates due reports and mails them to admins
+pub struct ReportScheduler {
+    manager: Arc<ReportingManager>,
+    config: ReportingConfig,
+    storage: Arc<MaildirStorage>,
+    maildir_root: PathBuf,
+    local_domain: String,
+    /// Outbound queue for admins outside the local domain
+    queue: Option<Arc<SmtpQueue>>,
+}
+
+impl ReportScheduler {
+    pub fn new(
+        manager: Arc<ReportingManager>,
+        config: &Config,
+        storage: Arc<MaildirStorage>,
+        queue: Option<Arc<SmtpQueue>>,
+    ) -> Self {
+        Self {
+            manager,
+            config: config.reporting.clone(),
+            storage,
+            maildir_root: PathBuf::from(&config.storage.maildir_path),
+            local_domain: config.server.domain.to_lowercase(),
+            queue,
+        }
+    }
+
+    /// Run forever, checking for due reports every hour
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.run_once().await {
+                error!("Report scheduler run failed: {}", e);
+            }
+        }
+    }
+
+    /// Sample storage, then generate and deliver every due report
+    pub async fn run_once(&self) -> Result<()> {
+        let root = self.maildir_root.clone();
+        let bytes = tokio::task::spawn_blocking(move || directory_size(&root)).await?;
+        self.manager.record_storage_sample(bytes).await?;
+
+        let now = Utc::now();
+        for period in &self.config.schedules {
+            let (start, end) = period.last_complete(now);
+            if self.manager.report_exists(*period, start).await? {
+                continue;
+            }
+
+            let report = self
+                .manager
+                .generate(*period, start, end, self.config.top_n)
+                .await?;
+            info!("Generated {}", report.title());
+
+            if let Err(e) = self.deliver(&report).await {
+                warn!("Failed to deliver {}: {}", report.title(), e);
+            }
+        }
+
+        let pruned = self
+            .manager
+            .prune_events(now - Duration::days(self.config.retention_days as i64))
+            .await?;
+        if pruned > 0 {
+            info!("Pruned {} old usage events", pruned);
+        }
+
+        Ok(())
+    }
+
+    /// Mail a report to every configured admin
+    async fn deliver(&self, report: &UsageReport) -> Result<()> {
+        if self.config.recipients.is_empty() {
+            return Ok(());
+        }
+
+        let from = self
+            .config
+            .from
+            .clone()
+            .unwrap_or_else(|| format!("postmaster@{}", self.local_domain));
+        let message = build_report_email(&from, &self.config.recipients, report, &self.config.formats);
+
+        for recipient in &self.config.recipients {
+            let is_local = recipient
+                .rsplit_once('@')
+                .is_some_and(|(_, domain)| domain.eq_ignore_ascii_case(&self.local_domain));
+
+            if is_local {
+                self.storage.store(recipient, &message).await?;
+            } else if let Some(queue) = &self.queue {
+                queue.enqueue(&from, recipient, &message).await?;
+            } else {
+                warn!("No outbound queue to send report to {}", recipient);
+                continue;
+            }
+            info!("Sent {} to {}", report.title(), recipient);
+        }
+
+        Ok(())
+    }
+}
+
+/// Build the report mail: HTML body with the rendered reports attached
+pub fn build_report_email(
+    from: &str,
+    recipients: &[String],
+    report: &UsageReport,
+    formats: &[ReportFormat],
+) -> Vec<u8> {
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S %z");
+    let boundary = format!("----=_Report_{}", uuid::Uuid::new_v4().simple());
+    let to = recipients
+        .iter()
+        .map(|r| format!("<{}>", r))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut message = format!(
+        "From: <{from}>\r\n\
+         To: {to}\r\n\
+         Subject: {subject}\r\n\
+         Date: {date}\r\n\
+         Auto-Submitted: auto-generated\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/html; charset=\"UTF-8\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         {body}",
+        subject = report.title(),
+        body = wrap_base64(&render::to_html(report).into_bytes()),
+    );
+
+    let filename = format!(
+        "usage-report-{}-{}",
+        report.period.as_str(),
+        report.period_start.format("%Y-%m-%d")
+    );
+    for format in formats {
+        message.push_str(&format!(
+            "--{boundary}\r\n\
+             Content-Type: {content_type}; name=\"{filename}.{ext}\"\r\n\
+             Content-Disposition: attachment; filename=\"{filename}.{ext}\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {data}",
+            content_type = format.content_type(),
+            ext = format.extension(),
+            data = wrap_base64(&render::render(report, *format)),
+        ));
+    }
+
+    message.push_str(&format!("--{}--\r\n", boundary));
+    message.into_bytes()
+}
+
+/// Base64 with 76-character lines (RFC 2045)
+pub(crate) fn wrap_base64(data: &[u8]) -> String {
+    let encoded = BASE64.encode(data);
+    let mut wrapped = String::with_capacity(encoded.len() + encoded.len() / 38);
+    for chunk in encoded.as_bytes().chunks(76) {
+        wrapped.push_str(std::str::from_utf8(chunk).expect("base64 is ASCII"));
+        wrapped.push_str("\r\n");
+    }
+    wrapped
+}
+
+/// Total size of the files below `path` (0 if it does not exist)
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            Ok(file_type) if file_type.is_file() => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_build_report_email() {
+        let report = UsageReport {
+            id: 1,
+            period: ReportPeriod::Daily,
+            period_start: Utc.with_ymd_and_hms(2024, 3, 12, 0, 0, 0).unwrap(),
+            period_end: Utc.with_ymd_and_hms(2024, 3, 13, 0, 0, 0).unwrap(),
+            generated_at: Utc::now(),
+            messages_received: 1,
+            messages_sent: 0,
+            spam_messages: 0,
+            spam_rate: 0.0,
+            auth_failures: 0,
+            storage_start_bytes: None,
+            storage_end_bytes: None,
+            top_senders: Vec::new(),
+            top_recipients: Vec::new(),
+        };
+
+        let message = String::from_utf8(build_report_email(
+            "postmaster@example.com",
+            &["admin@example.com".to_string()],
+            &report,
+            &[ReportFormat::Csv, ReportFormat::Pdf],
+        ))
+        .unwrap();
+
+        assert!(message.contains("Subject: Daily usage report 2024-03-12 - 2024-03-13\r\n"));
+        assert!(message.contains("filename=\"usage-report-daily-2024-03-12.csv\""));
+        assert!(message.contains("Content-Type: application/pdf;"));
+        assert!(message.lines().all(|line| line.len() <= 998));
+    }
+
+    #[test]
+    fn test_directory_size() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/new")).unwrap();
+        std::fs::write(dir.path().join("a/new/1"), b"12345").unwrap();
+        std::fs::write(dir.path().join("b"), b"123").unwrap();
+
+        assert_eq!(directory_size(dir.path()), 8);
+        assert_eq!(directory_size(&dir.path().join("missing")), 0);
+    }
+}
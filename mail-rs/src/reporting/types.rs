@@ -0,0 +1,202 @@
+//! Reporting data types
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Report period
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportPeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl ReportPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "daily" => Some(Self::Daily),
+            "weekly" => Some(Self::Weekly),
+            "monthly" => Some(Self::Monthly),
+            _ => None,
+        }
+    }
+
+    /// Bounds of the last complete period before `now` (UTC)
+    ///
+    /// Days start at midnight, weeks on Monday, months on the 1st.
+    pub fn last_complete(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date_naive();
+
+        let end = match self {
+            Self::Daily => today,
+            Self::Weekly => today - Duration::days(today.weekday().num_days_from_monday() as i64),
+            Self::Monthly => first_of_month(today.year(), today.month()),
+        };
+        let start = match self {
+            Self::Daily => end - Duration::days(1),
+            Self::Weekly => end - Duration::weeks(1),
+            Self::Monthly => {
+                let previous = end - Duration::days(1);
+                first_of_month(previous.year(), previous.month())
+            }
+        };
+
+        (midnight(start), midnight(end))
+    }
+}
+
+fn first_of_month(year: i32, month: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, 1).expect("first day of month is valid")
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("midnight is valid"))
+}
+
+/// Kind of recorded usage event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageEventKind {
+    /// Message delivered to a local mailbox
+    Received,
+    /// Message submitted by a local user for outbound delivery
+    Sent,
+    /// Message rejected or classified as spam
+    Spam,
+    /// Failed SMTP or IMAP login
+    AuthFailure,
+}
+
+impl UsageEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Received => "received",
+            Self::Sent => "sent",
+            Self::Spam => "spam",
+            Self::AuthFailure => "auth_failure",
+        }
+    }
+}
+
+/// Output format of a rendered report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Csv,
+    Html,
+    Pdf,
+}
+
+impl ReportFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "html" => Some(Self::Html),
+            "pdf" => Some(Self::Pdf),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Html => "text/html; charset=utf-8",
+            Self::Pdf => "application/pdf",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Html => "html",
+            Self::Pdf => "pdf",
+        }
+    }
+}
+
+/// Address with a message count
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddressCount {
+    pub address: String,
+    pub count: i64,
+}
+
+/// Usage report for one period
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub id: i64,
+    pub period: ReportPeriod,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub generated_at: DateTime<Utc>,
+    pub messages_received: i64,
+    pub messages_sent: i64,
+    pub spam_messages: i64,
+    /// Share of inbound messages that were spam (0.0 - 1.0)
+    pub spam_rate: f64,
+    pub auth_failures: i64,
+    /// Maildir size at the start and end of the period, if sampled
+    pub storage_start_bytes: Option<i64>,
+    pub storage_end_bytes: Option<i64>,
+    pub top_senders: Vec<AddressCount>,
+    pub top_recipients: Vec<AddressCount>,
+}
+
+impl UsageReport {
+    /// Storage growth over the period in bytes (0 without samples)
+    pub fn storage_growth_bytes(&self) -> i64 {
+        match (self.storage_start_bytes, self.storage_end_bytes) {
+            (Some(start), Some(end)) => end - start,
+            _ => 0,
+        }
+    }
+
+    /// Human-readable title, e.g. "Weekly usage report 2024-01-01 - 2024-01-08"
+    pub fn title(&self) -> String {
+        let period = self.period.as_str();
+        format!(
+            "{}{} usage report {} - {}",
+            period[..1].to_uppercase(),
+            &period[1..],
+            self.period_start.format("%Y-%m-%d"),
+            self.period_end.format("%Y-%m-%d")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_complete_period() {
+        // Wednesday 2024-03-13 15:30 UTC
+        let now = Utc.with_ymd_and_hms(2024, 3, 13, 15, 30, 0).unwrap();
+
+        let (start, end) = ReportPeriod::Daily.last_complete(now);
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 3, 12, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 3, 13, 0, 0, 0).unwrap());
+
+        let (start, end) = ReportPeriod::Weekly.last_complete(now);
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 3, 4, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 3, 11, 0, 0, 0).unwrap());
+
+        let (start, end) = ReportPeriod::Monthly.last_complete(now);
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap());
+
+        // January rolls back to December of the previous year
+        let now = Utc.with_ymd_and_hms(2024, 1, 5, 0, 0, 0).unwrap();
+        let (start, _) = ReportPeriod::Monthly.last_complete(now);
+        assert_eq!(start, Utc.with_ymd_and_hms(2023, 12, 1, 0, 0, 0).unwrap());
+    }
+}
@@ -0,0 +1,340 @@
+//! Residency manager: region assignments and audit log
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+use std::sync::Arc;
+
+use super::map::ResidencyMap;
+use super::types::*;
+
+/// Residency manager
+///
+/// Assignments are stored in the database and mirrored in the shared
+/// [`ResidencyMap`] used by storage, IMAP, backups and exports.
+pub struct ResidencyManager {
+    db: SqlitePool,
+    map: Arc<ResidencyMap>,
+}
+
+impl ResidencyManager {
+    /// Create a new residency manager
+    pub fn new(db: SqlitePool, map: Arc<ResidencyMap>) -> Self {
+        Self { db, map }
+    }
+
+    /// Connect to `database_url`, create the tables and load assignments
+    /// into `map`
+    pub async fn connect(database_url: &str, map: Arc<ResidencyMap>) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?, map);
+        manager.init_db().await?;
+        manager.load().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS residency_assignments (
+                subject TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                region TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS residency_audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                from_region TEXT,
+                to_region TEXT,
+                reason TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Copy stored assignments into the map
+    ///
+    /// Assignments to regions no longer configured are skipped, so those
+    /// users fall back to their domain's region or the default.
+    pub async fn load(&self) -> Result<()> {
+        for assignment in self.assignments().await? {
+            if self.map.region(&assignment.region).is_some() {
+                self.map.set(&assignment.subject, &assignment.region);
+            } else {
+                tracing::warn!(
+                    "Ignoring residency assignment of {} to unknown region {}",
+                    assignment.subject,
+                    assignment.region
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn map(&self) -> Arc<ResidencyMap> {
+        self.map.clone()
+    }
+
+    /// Pin a domain or user to a configured region
+    pub async fn assign(&self, subject: &str, region: &str, actor: &str) -> Result<Assignment> {
+        if self.map.region(region).is_none() {
+            return Err(anyhow!("Unknown region: {}", region));
+        }
+
+        let assignment = Assignment {
+            subject: subject.to_lowercase(),
+            kind: SubjectKind::of(subject),
+            region: region.to_string(),
+            updated_at: Utc::now(),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO residency_assignments (subject, kind, region, updated_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(subject) DO UPDATE SET
+                kind = excluded.kind,
+                region = excluded.region,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&assignment.subject)
+        .bind(assignment.kind.as_str())
+        .bind(&assignment.region)
+        .bind(assignment.updated_at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        let previous = self.map.assigned(&assignment.subject);
+        self.map.set(&assignment.subject, region);
+        self.record_audit(
+            actor,
+            "assign",
+            &assignment.subject,
+            previous.as_deref(),
+            Some(region),
+            None,
+        )
+        .await?;
+
+        Ok(assignment)
+    }
+
+    /// Remove the assignment of a domain or user; returns whether there was one
+    pub async fn unassign(&self, subject: &str, actor: &str) -> Result<bool> {
+        let subject = subject.to_lowercase();
+        let result = sqlx::query("DELETE FROM residency_assignments WHERE subject = ?")
+            .bind(&subject)
+            .execute(&self.db)
+            .await?;
+
+        let previous = self.map.remove(&subject);
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.record_audit(actor, "unassign", &subject, previous.as_deref(), None, None)
+            .await?;
+        Ok(true)
+    }
+
+    /// All stored assignments, domains first
+    pub async fn assignments(&self) -> Result<Vec<Assignment>> {
+        let rows = sqlx::query(
+            "SELECT subject, kind, region, updated_at FROM residency_assignments ORDER BY kind, subject",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let kind: String = row.get("kind");
+                Ok(Assignment {
+                    subject: row.get("subject"),
+                    kind: if kind == "user" {
+                        SubjectKind::User
+                    } else {
+                        SubjectKind::Domain
+                    },
+                    region: row.get("region"),
+                    updated_at: parse_timestamp(row.get("updated_at"))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Append to the audit log
+    pub async fn record_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        subject: &str,
+        from_region: Option<&str>,
+        to_region: Option<&str>,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO residency_audit
+                (actor, action, subject, from_region, to_region, reason, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(actor)
+        .bind(action)
+        .bind(subject)
+        .bind(from_region)
+        .bind(to_region)
+        .bind(reason)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        tracing::info!(
+            "Residency audit: {} {} {} ({:?} -> {:?})",
+            actor,
+            action,
+            subject,
+            from_region,
+            to_region
+        );
+        Ok(())
+    }
+
+    /// Most recent audit entries, newest first
+    pub async fn audit_log(&self, limit: i64) -> Result<Vec<AuditEntry>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, actor, action, subject, from_region, to_region, reason, created_at
+            FROM residency_audit
+            ORDER BY id DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: row.get("id"),
+                    actor: row.get("actor"),
+                    action: row.get("action"),
+                    subject: row.get("subject"),
+                    from_region: row.get("from_region"),
+                    to_region: row.get("to_region"),
+                    reason: row.get("reason"),
+                    created_at: parse_timestamp(row.get("created_at"))?,
+                })
+            })
+            .collect()
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RegionConfig, ResidencyConfig};
+
+    fn map() -> Arc<ResidencyMap> {
+        let regions = ["eu", "us"]
+            .into_iter()
+            .map(|name| RegionConfig {
+                name: name.to_string(),
+                maildir_path: format!("/srv/{}/mail", name),
+                backup_dir: format!("/srv/{}/backups", name),
+                export_path: format!("/srv/{}/exports", name),
+            })
+            .collect();
+        Arc::new(
+            ResidencyMap::new(&ResidencyConfig {
+                regions,
+                default_region: None,
+            })
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_assign_and_audit() {
+        let manager = ResidencyManager::connect("sqlite::memory:", map())
+            .await
+            .unwrap();
+
+        manager
+            .assign("example.de", "eu", "admin@example.com")
+            .await
+            .unwrap();
+        manager
+            .assign("Boss@example.de", "us", "admin@example.com")
+            .await
+            .unwrap();
+        manager
+            .assign("boss@example.de", "eu", "admin@example.com")
+            .await
+            .unwrap();
+        assert!(manager
+            .assign("example.fr", "fr", "admin@example.com")
+            .await
+            .is_err());
+
+        let assignments = manager.assignments().await.unwrap();
+        assert_eq!(assignments.len(), 2);
+        assert_eq!(assignments[0].kind, SubjectKind::Domain);
+        assert_eq!(assignments[1].subject, "boss@example.de");
+        assert_eq!(assignments[1].region, "eu");
+
+        assert!(manager
+            .unassign("example.de", "admin@example.com")
+            .await
+            .unwrap());
+        assert!(!manager
+            .unassign("example.de", "admin@example.com")
+            .await
+            .unwrap());
+        assert!(manager.map().region_of("alice@example.de").is_none());
+
+        let log = manager.audit_log(10).await.unwrap();
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[0].action, "unassign");
+        assert_eq!(log[1].from_region.as_deref(), Some("us"));
+        assert_eq!(log[1].to_region.as_deref(), Some("eu"));
+    }
+
+    #[tokio::test]
+    async fn test_load_skips_unknown_regions() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("api.db").display());
+        let manager = ResidencyManager::connect(&url, map()).await.unwrap();
+        manager.assign("example.de", "eu", "admin").await.unwrap();
+        manager.assign("example.com", "us", "admin").await.unwrap();
+
+        // Restart with the us region removed from the configuration
+        let config = ResidencyConfig {
+            regions: map().regions()[..1].to_vec(),
+            default_region: None,
+        };
+        let map = Arc::new(ResidencyMap::new(&config).unwrap());
+        ResidencyManager::connect(&url, map.clone()).await.unwrap();
+        assert_eq!(map.region_of("a@example.de").unwrap().name, "eu");
+        assert!(map.region_of("a@example.com").is_none());
+    }
+}
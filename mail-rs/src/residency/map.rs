@@ -0,0 +1,197 @@
+//! In-memory view of regions and assignments
+//!
+//! Storage and IMAP resolve mailbox locations on every access, so lookups
+//! are synchronous. [`ResidencyManager`](super::ResidencyManager) keeps the
+//! map in sync with the database.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::config::{RegionConfig, ResidencyConfig};
+use crate::error::{MailError, Result};
+
+/// Regions and the domains and users assigned to them
+#[derive(Debug)]
+pub struct ResidencyMap {
+    regions: Vec<RegionConfig>,
+    default_region: Option<String>,
+    /// Lowercase domain or address -> region name
+    assignments: RwLock<HashMap<String, String>>,
+}
+
+impl ResidencyMap {
+    /// Build the map from configuration, checking region names
+    pub fn new(config: &ResidencyConfig) -> Result<Self> {
+        for (i, region) in config.regions.iter().enumerate() {
+            let valid = !region.name.is_empty()
+                && region
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-');
+            if !valid {
+                return Err(MailError::Config(format!(
+                    "Invalid residency region name: {:?}",
+                    region.name
+                )));
+            }
+            if config.regions[..i]
+                .iter()
+                .any(|other| other.name == region.name)
+            {
+                return Err(MailError::Config(format!(
+                    "Duplicate residency region: {}",
+                    region.name
+                )));
+            }
+        }
+        if let Some(default) = &config.default_region {
+            if !config.regions.iter().any(|region| &region.name == default) {
+                return Err(MailError::Config(format!(
+                    "Unknown default residency region: {}",
+                    default
+                )));
+            }
+        }
+
+        Ok(Self {
+            regions: config.regions.clone(),
+            default_region: config.default_region.clone(),
+            assignments: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn regions(&self) -> &[RegionConfig] {
+        &self.regions
+    }
+
+    pub fn default_region(&self) -> Option<&str> {
+        self.default_region.as_deref()
+    }
+
+    pub fn region(&self, name: &str) -> Option<&RegionConfig> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    /// Region of a user: their own assignment, their domain's, or the default
+    pub fn region_of(&self, email: &str) -> Option<&RegionConfig> {
+        let email = email.to_lowercase();
+        let name = {
+            let assignments = self.assignments.read().unwrap();
+            let domain = email.rsplit_once('@').map(|(_, domain)| domain);
+            assignments
+                .get(&email)
+                .or_else(|| domain.and_then(|domain| assignments.get(domain)))
+                .cloned()
+        };
+        name.as_deref()
+            .or(self.default_region.as_deref())
+            .and_then(|name| self.region(name))
+    }
+
+    /// Directory holding the mailbox of `email`, below `fallback` when the
+    /// user has no region
+    pub fn maildir_root(&self, email: &str, fallback: &Path) -> PathBuf {
+        self.region_of(email)
+            .map(|region| PathBuf::from(&region.maildir_path))
+            .unwrap_or_else(|| fallback.to_path_buf())
+    }
+
+    /// Directory exports of `email` are written to
+    pub fn export_dir(&self, email: &str, fallback: &Path) -> PathBuf {
+        self.region_of(email)
+            .map(|region| PathBuf::from(&region.export_path))
+            .unwrap_or_else(|| fallback.to_path_buf())
+    }
+
+    /// Every maildir root: the regions' and `fallback`
+    pub fn maildir_roots(&self, fallback: &Path) -> Vec<PathBuf> {
+        let mut roots = vec![fallback.to_path_buf()];
+        for region in &self.regions {
+            let root = PathBuf::from(&region.maildir_path);
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+
+    pub(crate) fn set(&self, subject: &str, region: &str) {
+        self.assignments
+            .write()
+            .unwrap()
+            .insert(subject.to_lowercase(), region.to_string());
+    }
+
+    pub(crate) fn remove(&self, subject: &str) -> Option<String> {
+        self.assignments
+            .write()
+            .unwrap()
+            .remove(&subject.to_lowercase())
+    }
+
+    pub(crate) fn assigned(&self, subject: &str) -> Option<String> {
+        self.assignments
+            .read()
+            .unwrap()
+            .get(&subject.to_lowercase())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &str) -> RegionConfig {
+        RegionConfig {
+            name: name.to_string(),
+            maildir_path: format!("/srv/{}/mail", name),
+            backup_dir: format!("/srv/{}/backups", name),
+            export_path: format!("/srv/{}/exports", name),
+        }
+    }
+
+    #[test]
+    fn test_region_lookup() {
+        let map = ResidencyMap::new(&ResidencyConfig {
+            regions: vec![region("eu"), region("us")],
+            default_region: None,
+        })
+        .unwrap();
+        let fallback = Path::new("/var/mail");
+
+        map.set("Example.DE", "eu");
+        map.set("boss@example.de", "us");
+        assert_eq!(map.region_of("alice@example.de").unwrap().name, "eu");
+        assert_eq!(map.region_of("BOSS@example.de").unwrap().name, "us");
+        assert!(map.region_of("bob@example.com").is_none());
+        assert_eq!(
+            map.maildir_root("alice@example.de", fallback),
+            PathBuf::from("/srv/eu/mail")
+        );
+        assert_eq!(map.maildir_root("bob@example.com", fallback), fallback);
+        assert_eq!(map.maildir_roots(fallback).len(), 3);
+
+        assert_eq!(map.remove("boss@example.de").as_deref(), Some("us"));
+        assert_eq!(
+            map.export_dir("boss@example.de", fallback),
+            PathBuf::from("/srv/eu/exports")
+        );
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = |regions, default_region: Option<&str>| ResidencyConfig {
+            regions,
+            default_region: default_region.map(str::to_string),
+        };
+
+        let map = ResidencyMap::new(&config(vec![region("eu")], Some("eu"))).unwrap();
+        assert_eq!(map.region_of("anyone@example.com").unwrap().name, "eu");
+
+        assert!(ResidencyMap::new(&config(vec![region("eu")], Some("us"))).is_err());
+        assert!(ResidencyMap::new(&config(vec![region("eu"), region("eu")], None)).is_err());
+        assert!(ResidencyMap::new(&config(vec![region("../eu")], None)).is_err());
+    }
+}
@@ -0,0 +1,16 @@
+//! Regional data residency
+//!
+//! Domains and users can be pinned to a region defined under `[residency]`
+//! in the configuration. Their mailboxes are stored below the region's
+//! maildir path, and their backups and exports go to the region's backup and
+//! export locations. Restoring a backup into a different region is refused
+//! unless an admin overrides it with a reason; overrides and assignment
+//! changes are recorded in an audit log.
+
+pub mod manager;
+pub mod map;
+pub mod types;
+
+pub use manager::ResidencyManager;
+pub use map::ResidencyMap;
+pub use types::*;
@@ -0,0 +1,117 @@
+//! Data residency types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// What a region assignment applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectKind {
+    /// Every user of a domain without an assignment of their own
+    Domain,
+    User,
+}
+
+impl SubjectKind {
+    /// Users are addresses, domains are not
+    pub fn of(subject: &str) -> Self {
+        if subject.contains('@') {
+            SubjectKind::User
+        } else {
+            SubjectKind::Domain
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubjectKind::Domain => "domain",
+            SubjectKind::User => "user",
+        }
+    }
+}
+
+/// Region a domain or user is pinned to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assignment {
+    /// Domain or email address, lowercase
+    pub subject: String,
+    pub kind: SubjectKind,
+    pub region: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Residency-relevant admin action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// Admin who performed the action
+    pub actor: String,
+    /// `assign`, `unassign` or `cross_region_restore`
+    pub action: String,
+    pub subject: String,
+    pub from_region: Option<String>,
+    pub to_region: Option<String>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Outcome of checking a restore against residency rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreCheck {
+    /// Backup and target are in the same region
+    SameRegion,
+    /// Cross-region, allowed by an explicit override with a reason
+    Overridden,
+    /// Cross-region without an override
+    Denied,
+}
+
+impl RestoreCheck {
+    /// Check restoring a backup taken in `from` into `to`
+    ///
+    /// `None` stands for the unregioned default storage. A cross-region
+    /// restore needs a non-empty override reason.
+    pub fn evaluate(from: Option<&str>, to: Option<&str>, override_reason: Option<&str>) -> Self {
+        if from == to {
+            RestoreCheck::SameRegion
+        } else if override_reason.is_some_and(|reason| !reason.trim().is_empty()) {
+            RestoreCheck::Overridden
+        } else {
+            RestoreCheck::Denied
+        }
+    }
+
+    pub fn allowed(&self) -> bool {
+        !matches!(self, RestoreCheck::Denied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_check() {
+        assert_eq!(
+            RestoreCheck::evaluate(Some("eu"), Some("eu"), None),
+            RestoreCheck::SameRegion
+        );
+        assert_eq!(
+            RestoreCheck::evaluate(None, None, None),
+            RestoreCheck::SameRegion
+        );
+        assert_eq!(
+            RestoreCheck::evaluate(Some("eu"), Some("us"), None),
+            RestoreCheck::Denied
+        );
+        assert_eq!(
+            RestoreCheck::evaluate(Some("eu"), None, Some("  ")),
+            RestoreCheck::Denied
+        );
+        assert_eq!(
+            RestoreCheck::evaluate(Some("eu"), Some("us"), Some("Customer moved, ticket 42")),
+            RestoreCheck::Overridden
+        );
+        assert!(!RestoreCheck::Denied.allowed());
+    }
+}
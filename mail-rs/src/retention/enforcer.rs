@@ -0,0 +1,249 @@
+//! Retention enforcement: deletes and archives messages past their policy
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs;
+use tracing::{error, info, warn};
+
+use super::manager::RetentionManager;
+use super::types::*;
+use crate::imap::uid::base_name;
+use crate::recovery::ReadOnlyMode;
+use crate::storage::{MaildirStorage, Storage};
+
+/// Applies retention policies to every mailbox of a storage
+pub struct RetentionEnforcer {
+    manager: Arc<RetentionManager>,
+    storage: Arc<MaildirStorage>,
+    /// Skip runs while the server is read-only
+    read_only: Option<Arc<ReadOnlyMode>>,
+}
+
+impl RetentionEnforcer {
+    pub fn new(manager: Arc<RetentionManager>, storage: Arc<MaildirStorage>) -> Self {
+        Self {
+            manager,
+            storage,
+            read_only: None,
+        }
+    }
+
+    /// Don't change mailboxes while `mode` is read-only
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(mode);
+        self
+    }
+
+    /// Run [`Self::enforce`] every `interval`
+    pub async fn run(self, interval: std::time::Duration) {
+        loop {
+            if self
+                .read_only
+                .as_ref()
+                .is_some_and(|mode| mode.is_read_only())
+            {
+                info!("Read-only mode, skipping retention run");
+            } else {
+                match self.enforce(Utc::now()).await {
+                    Ok(report) if report.deleted + report.archived > 0 => info!(
+                        "Retention: {} messages deleted, {} archived in {} mailboxes",
+                        report.deleted, report.archived, report.users
+                    ),
+                    Ok(_) => {}
+                    Err(e) => error!("Retention run failed: {}", e),
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Delete or archive every message older than its folder's policy
+    /// allows at `now`
+    ///
+    /// A message's age is the modification time of its file, which Maildir
+    /// keeps as the delivery or IMAP internal date.
+    pub async fn enforce(&self, now: DateTime<Utc>) -> Result<RetentionReport> {
+        let mut report = RetentionReport::default();
+        let policies = self.manager.list().await?;
+        if !policies.iter().any(|policy| policy.enabled) {
+            return Ok(report);
+        }
+
+        for user in self.storage.list_users().await? {
+            let applicable = policies_for(&policies, &user);
+            if applicable.is_empty() {
+                continue;
+            }
+            // Changes during a cutover would miss the final sync
+            if self.storage.is_locked(&user) {
+                continue;
+            }
+            report.users += 1;
+            for policy in applicable {
+                let cutoff = now - Duration::days(policy.max_age_days as i64);
+                if let Err(e) = self.apply(&user, policy, cutoff, &mut report).await {
+                    warn!(
+                        "Failed to apply retention policy {} to {}: {}",
+                        policy.id, user, e
+                    );
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn apply(
+        &self,
+        user: &str,
+        policy: &RetentionPolicy,
+        cutoff: DateTime<Utc>,
+        report: &mut RetentionReport,
+    ) -> Result<()> {
+        let inbox = same_folder(&policy.folder, "INBOX");
+        let prefix = if inbox {
+            String::new()
+        } else {
+            format!(".{}/", policy.folder)
+        };
+        let folder_dir = self.storage.root_for(user).join(user).join(&prefix);
+        let cutoff = SystemTime::from(cutoff);
+
+        for subdir in ["new", "cur"] {
+            let Ok(mut entries) = fs::read_dir(folder_dir.join(subdir)).await else {
+                continue;
+            };
+            let mut expired = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let metadata = entry.metadata().await?;
+                if name.starts_with('.') || !metadata.is_file() {
+                    continue;
+                }
+                if metadata.modified()? < cutoff {
+                    expired.push(name);
+                }
+            }
+
+            for name in expired {
+                match policy.action {
+                    RetentionAction::Delete => {
+                        let id = format!("{}{}/{}", prefix, subdir, name);
+                        self.storage.delete_message(user, &id).await?;
+                        report.deleted += 1;
+                    }
+                    RetentionAction::Archive => {
+                        let folder = (!inbox).then_some(policy.folder.as_str());
+                        let moved = self
+                            .storage
+                            .move_message(user, folder, base_name(&name), policy.archive_folder())
+                            .await?;
+                        if moved {
+                            report.archived += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn stored(
+        storage: &MaildirStorage,
+        user: &str,
+        folder: Option<&str>,
+        days_old: i64,
+    ) -> String {
+        let filename = storage
+            .store_in_folder(user, folder, b"Subject: x\r\n\r\nbody")
+            .await
+            .unwrap();
+        let dir = match folder {
+            Some(folder) => format!(".{}/new", folder),
+            None => "new".to_string(),
+        };
+        let path = storage.root_for(user).join(user).join(dir).join(&filename);
+        let mtime = SystemTime::now() - std::time::Duration::from_secs(days_old as u64 * 86400);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        filename
+    }
+
+    #[tokio::test]
+    async fn test_enforce_deletes_and_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(MaildirStorage::new(
+            dir.path().to_string_lossy().to_string(),
+        ));
+        let manager = Arc::new(RetentionManager::connect("sqlite::memory:").await.unwrap());
+        let policy = |domain: Option<&str>, folder: &str, days, action| RetentionPolicyRequest {
+            domain: domain.map(str::to_string),
+            folder: folder.to_string(),
+            max_age_days: days,
+            action,
+            archive_folder: None,
+            enabled: true,
+        };
+        manager
+            .create(&policy(None, "Trash", 30, RetentionAction::Delete))
+            .await
+            .unwrap();
+        manager
+            .create(&policy(
+                Some("example.com"),
+                "Trash",
+                7,
+                RetentionAction::Delete,
+            ))
+            .await
+            .unwrap();
+        manager
+            .create(&policy(None, "INBOX", 365, RetentionAction::Archive))
+            .await
+            .unwrap();
+
+        let jane = "jane@example.com";
+        let joe = "joe@other.org";
+        stored(&storage, jane, Some("Trash"), 10).await;
+        let recent = stored(&storage, jane, Some("Trash"), 1).await;
+        let kept = stored(&storage, joe, Some("Trash"), 10).await;
+        stored(&storage, joe, Some("Trash"), 40).await;
+        let old = stored(&storage, joe, None, 400).await;
+        stored(&storage, joe, None, 10).await;
+
+        let enforcer = RetentionEnforcer::new(manager, storage.clone());
+        let report = enforcer.enforce(Utc::now()).await.unwrap();
+        assert_eq!(
+            report,
+            RetentionReport {
+                users: 2,
+                deleted: 2,
+                archived: 1,
+            }
+        );
+
+        let ids = |user| {
+            let storage = storage.clone();
+            async move { storage.list_messages(user).await.unwrap() }
+        };
+        assert_eq!(ids(jane).await, vec![format!(".Trash/new/{}", recent)]);
+        let joe_ids = ids(joe).await;
+        assert_eq!(joe_ids.len(), 3);
+        assert!(joe_ids.contains(&format!(".Archive/new/{}", old)));
+        assert!(joe_ids.contains(&format!(".Trash/new/{}", kept)));
+
+        // Nothing left to do
+        let report = enforcer.enforce(Utc::now()).await.unwrap();
+        assert_eq!(report.deleted + report.archived, 0);
+    }
+}
@@ -0,0 +1,260 @@
+//! Retention manager: policies per folder and domain
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+const COLUMNS: &str =
+    "id, domain, folder, max_age_days, action, archive_folder, enabled, created_at, updated_at";
+
+/// Retention manager
+pub struct RetentionManager {
+    db: SqlitePool,
+}
+
+impl RetentionManager {
+    /// Create a new retention manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the retention tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // Policies for every domain have the empty domain
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS retention_policies (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                domain TEXT NOT NULL DEFAULT '',
+                folder TEXT NOT NULL,
+                max_age_days INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                archive_folder TEXT,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                UNIQUE (domain, folder)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// All policies, global ones first
+    pub async fn list(&self) -> Result<Vec<RetentionPolicy>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM retention_policies ORDER BY domain, folder",
+            COLUMNS
+        ))
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(policy_from_row).collect()
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<RetentionPolicy>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM retention_policies WHERE id = ?",
+            COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        row.as_ref().map(policy_from_row).transpose()
+    }
+
+    /// Add a policy; fails if the domain already has one for the folder
+    pub async fn create(&self, request: &RetentionPolicyRequest) -> Result<RetentionPolicy> {
+        request.validate().map_err(|e| anyhow!(e))?;
+        if self.find(request).await?.is_some() {
+            return Err(anyhow!(
+                "A policy for this folder and domain already exists"
+            ));
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let result = sqlx::query(
+            r#"
+            INSERT INTO retention_policies
+                (domain, folder, max_age_days, action, archive_folder, enabled, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(request.domain().unwrap_or_default())
+        .bind(folder_key(&request.folder))
+        .bind(request.max_age_days)
+        .bind(request.action.as_str())
+        .bind(request.archive_folder())
+        .bind(request.enabled)
+        .bind(&now)
+        .bind(&now)
+        .execute(&self.db)
+        .await?;
+
+        self.get(result.last_insert_rowid())
+            .await?
+            .ok_or_else(|| anyhow!("Retention policy vanished after insert"))
+    }
+
+    /// Replace a policy; `None` if it doesn't exist
+    pub async fn update(
+        &self,
+        id: i64,
+        request: &RetentionPolicyRequest,
+    ) -> Result<Option<RetentionPolicy>> {
+        request.validate().map_err(|e| anyhow!(e))?;
+        if self
+            .find(request)
+            .await?
+            .is_some_and(|existing| existing != id)
+        {
+            return Err(anyhow!(
+                "A policy for this folder and domain already exists"
+            ));
+        }
+
+        let result = sqlx::query(
+            r#"
+            UPDATE retention_policies SET
+                domain = ?, folder = ?, max_age_days = ?, action = ?,
+                archive_folder = ?, enabled = ?, updated_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(request.domain().unwrap_or_default())
+        .bind(folder_key(&request.folder))
+        .bind(request.max_age_days)
+        .bind(request.action.as_str())
+        .bind(request.archive_folder())
+        .bind(request.enabled)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        self.get(id).await
+    }
+
+    /// Remove a policy; returns whether there was one
+    pub async fn delete(&self, id: i64) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM retention_policies WHERE id = ?")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Id of the policy for the domain and folder of `request`
+    async fn find(&self, request: &RetentionPolicyRequest) -> Result<Option<i64>> {
+        let id =
+            sqlx::query_scalar("SELECT id FROM retention_policies WHERE domain = ? AND folder = ?")
+                .bind(request.domain().unwrap_or_default())
+                .bind(folder_key(&request.folder))
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(id)
+    }
+}
+
+/// Folder as stored: trimmed, with INBOX in its canonical case
+fn folder_key(folder: &str) -> String {
+    let folder = folder.trim();
+    if folder.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        folder.to_string()
+    }
+}
+
+fn policy_from_row(row: &SqliteRow) -> Result<RetentionPolicy> {
+    let domain: String = row.get("domain");
+    let action: String = row.get("action");
+    Ok(RetentionPolicy {
+        id: row.get("id"),
+        domain: (!domain.is_empty()).then_some(domain),
+        folder: row.get("folder"),
+        max_age_days: row.get::<i64, _>("max_age_days") as u32,
+        action: RetentionAction::parse(&action)
+            .ok_or_else(|| anyhow!("Unknown retention action: {}", action))?,
+        archive_folder: row.get("archive_folder"),
+        enabled: row.get("enabled"),
+        created_at: parse_time(row.get("created_at"))?,
+        updated_at: parse_time(row.get("updated_at"))?,
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(domain: Option<&str>, folder: &str, days: u32) -> RetentionPolicyRequest {
+        RetentionPolicyRequest {
+            domain: domain.map(str::to_string),
+            folder: folder.to_string(),
+            max_age_days: days,
+            action: RetentionAction::Delete,
+            archive_folder: None,
+            enabled: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_crud() {
+        let manager = RetentionManager::connect("sqlite::memory:").await.unwrap();
+
+        let trash = manager.create(&request(None, "Trash", 30)).await.unwrap();
+        let junk = manager
+            .create(&request(Some("Example.com"), "Junk", 14))
+            .await
+            .unwrap();
+        assert_eq!(junk.domain.as_deref(), Some("example.com"));
+        assert!(manager.create(&request(None, "Trash", 7)).await.is_err());
+        assert!(manager.create(&request(None, "Trash", 0)).await.is_err());
+
+        let mut archive = request(None, "inbox", 365);
+        archive.action = RetentionAction::Archive;
+        let archive = manager.update(trash.id, &archive).await.unwrap().unwrap();
+        assert_eq!(archive.folder, "INBOX");
+        assert_eq!(archive.archive_folder(), DEFAULT_ARCHIVE_FOLDER);
+        assert!(manager
+            .update(archive.id, &request(Some("example.com"), "Junk", 1))
+            .await
+            .is_err());
+        assert!(manager
+            .update(99, &request(None, "Sent", 1))
+            .await
+            .unwrap()
+            .is_none());
+
+        let folders: Vec<String> = manager
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|policy| policy.folder)
+            .collect();
+        assert_eq!(folders, vec!["INBOX", "Junk"]);
+
+        assert!(manager.delete(junk.id).await.unwrap());
+        assert!(!manager.delete(junk.id).await.unwrap());
+        assert_eq!(manager.list().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,15 @@
+//! Message retention policies
+//!
+//! Admins set how long messages stay in a folder, for every domain or for
+//! one domain, e.g. 30 days in Trash and 14 in Junk. Older messages are
+//! deleted or moved to an archive folder by a background run when
+//! `[retention]` is enabled. A domain's policy for a folder replaces the
+//! policy for every domain.
+
+pub mod enforcer;
+pub mod manager;
+pub mod types;
+
+pub use enforcer::RetentionEnforcer;
+pub use manager::RetentionManager;
+pub use types::*;
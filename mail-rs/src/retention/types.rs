@@ -0,0 +1,274 @@
+//! Retention policy types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Folder messages are archived to when a policy names none
+pub const DEFAULT_ARCHIVE_FOLDER: &str = "Archive";
+
+/// Longest age a policy may keep messages for, in days
+pub const MAX_AGE_DAYS: u32 = 36500;
+
+/// What happens to messages older than a policy allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionAction {
+    /// Expunge them
+    Delete,
+    /// Move them to the archive folder
+    Archive,
+}
+
+impl RetentionAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetentionAction::Delete => "delete",
+            RetentionAction::Archive => "archive",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "delete" => Some(RetentionAction::Delete),
+            "archive" => Some(RetentionAction::Archive),
+            _ => None,
+        }
+    }
+}
+
+/// Rule for how long messages stay in a folder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RetentionPolicy {
+    pub id: i64,
+    /// Domain whose mailboxes the policy applies to; `None` for every
+    /// domain without its own policy for the folder
+    pub domain: Option<String>,
+    pub folder: String,
+    /// Messages older than this are deleted or archived
+    pub max_age_days: u32,
+    pub action: RetentionAction,
+    /// Target of [`RetentionAction::Archive`]
+    pub archive_folder: Option<String>,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl RetentionPolicy {
+    /// Folder archived messages are moved to
+    pub fn archive_folder(&self) -> &str {
+        self.archive_folder
+            .as_deref()
+            .unwrap_or(DEFAULT_ARCHIVE_FOLDER)
+    }
+}
+
+/// Request to create or replace a policy
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetentionPolicyRequest {
+    #[serde(default)]
+    pub domain: Option<String>,
+    pub folder: String,
+    pub max_age_days: u32,
+    pub action: RetentionAction,
+    #[serde(default)]
+    pub archive_folder: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl RetentionPolicyRequest {
+    /// Check the folders, domain and age before saving
+    pub fn validate(&self) -> Result<(), String> {
+        validate_folder(&self.folder)?;
+        if let Some(domain) = self.domain() {
+            let valid = domain.len() <= 253
+                && domain.split('.').all(|label| {
+                    !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+                });
+            if !valid {
+                return Err(format!("Invalid domain: {}", domain));
+            }
+        }
+        if self.max_age_days == 0 || self.max_age_days > MAX_AGE_DAYS {
+            return Err(format!(
+                "max_age_days must be between 1 and {}",
+                MAX_AGE_DAYS
+            ));
+        }
+        match (self.action, self.archive_folder()) {
+            (RetentionAction::Delete, Some(_)) => {
+                Err("archive_folder only applies to the archive action".to_string())
+            }
+            (RetentionAction::Archive, target) => {
+                let target = target.unwrap_or(DEFAULT_ARCHIVE_FOLDER);
+                validate_folder(target)?;
+                if target.eq_ignore_ascii_case("INBOX") {
+                    return Err("Messages can't be archived to INBOX".to_string());
+                }
+                if same_folder(target, self.folder.trim()) {
+                    return Err("archive_folder must differ from folder".to_string());
+                }
+                Ok(())
+            }
+            (RetentionAction::Delete, None) => Ok(()),
+        }
+    }
+
+    /// Lowercase domain, `None` for every domain
+    pub fn domain(&self) -> Option<String> {
+        self.domain
+            .as_deref()
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+    }
+
+    /// Archive folder, `None` for the default
+    pub fn archive_folder(&self) -> Option<&str> {
+        self.archive_folder
+            .as_deref()
+            .map(str::trim)
+            .filter(|folder| !folder.is_empty())
+    }
+}
+
+/// Folder names as Maildir++ stores them: `INBOX` or a `.Folder` directory
+fn validate_folder(folder: &str) -> Result<(), String> {
+    let folder = folder.trim();
+    if folder.is_empty() || folder.contains(['/', '\\']) || folder.starts_with('.') {
+        return Err(format!("Invalid folder name: {}", folder));
+    }
+    Ok(())
+}
+
+/// Whether two folder names are the same folder; INBOX is case-insensitive
+pub fn same_folder(a: &str, b: &str) -> bool {
+    a == b || (a.eq_ignore_ascii_case("INBOX") && b.eq_ignore_ascii_case("INBOX"))
+}
+
+/// Enabled policies that apply to the mailbox of `user`, one per folder:
+/// the policy of the user's domain, else the one for every domain
+pub fn policies_for<'a>(policies: &'a [RetentionPolicy], user: &str) -> Vec<&'a RetentionPolicy> {
+    let domain = user
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase());
+    let enabled: Vec<&RetentionPolicy> = policies.iter().filter(|policy| policy.enabled).collect();
+    let mut applicable: Vec<&RetentionPolicy> = enabled
+        .iter()
+        .copied()
+        .filter(|policy| domain.is_some() && policy.domain == domain)
+        .collect();
+    for policy in enabled.into_iter().filter(|policy| policy.domain.is_none()) {
+        if !applicable
+            .iter()
+            .any(|chosen| same_folder(&chosen.folder, &policy.folder))
+        {
+            applicable.push(policy);
+        }
+    }
+    applicable
+}
+
+/// Messages a retention run deleted and archived
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RetentionReport {
+    /// Mailboxes with at least one policy
+    pub users: usize,
+    pub deleted: usize,
+    pub archived: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(id: i64, domain: Option<&str>, folder: &str, days: u32) -> RetentionPolicy {
+        RetentionPolicy {
+            id,
+            domain: domain.map(str::to_string),
+            folder: folder.to_string(),
+            max_age_days: days,
+            action: RetentionAction::Delete,
+            archive_folder: None,
+            enabled: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn request(
+        folder: &str,
+        action: RetentionAction,
+        archive: Option<&str>,
+    ) -> RetentionPolicyRequest {
+        RetentionPolicyRequest {
+            domain: None,
+            folder: folder.to_string(),
+            max_age_days: 30,
+            action,
+            archive_folder: archive.map(str::to_string),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_domain_policies_override_global_ones() {
+        let mut disabled = policy(4, Some("example.com"), "Sent", 1);
+        disabled.enabled = false;
+        let policies = vec![
+            policy(1, None, "Trash", 30),
+            policy(2, None, "Junk", 14),
+            policy(3, Some("example.com"), "Trash", 7),
+            disabled,
+            policy(5, None, "Sent", 365),
+        ];
+
+        let ids = |user| {
+            let mut ids: Vec<i64> = policies_for(&policies, user).iter().map(|p| p.id).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids("jane@Example.com"), vec![2, 3, 5]);
+        assert_eq!(ids("joe@other.org"), vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn test_validate_request() {
+        assert!(request("Trash", RetentionAction::Delete, None)
+            .validate()
+            .is_ok());
+        assert!(request("INBOX", RetentionAction::Archive, None)
+            .validate()
+            .is_ok());
+        assert!(request("INBOX", RetentionAction::Archive, Some("Old"))
+            .validate()
+            .is_ok());
+
+        assert!(request("", RetentionAction::Delete, None)
+            .validate()
+            .is_err());
+        assert!(request("../x", RetentionAction::Delete, None)
+            .validate()
+            .is_err());
+        assert!(request("Trash", RetentionAction::Delete, Some("Old"))
+            .validate()
+            .is_err());
+        assert!(request("Archive", RetentionAction::Archive, None)
+            .validate()
+            .is_err());
+        assert!(request("Sent", RetentionAction::Archive, Some("inbox"))
+            .validate()
+            .is_err());
+
+        let mut forever = request("Trash", RetentionAction::Delete, None);
+        forever.max_age_days = 0;
+        assert!(forever.validate().is_err());
+        forever.max_age_days = 30;
+        forever.domain = Some("bad domain".to_string());
+        assert!(forever.validate().is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Role account manager: provisioned role addresses and their daily volume
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// Role account manager
+pub struct RoleAccountManager {
+    db: SqlitePool,
+    /// Admin mailbox of role mail; `postmaster@<domain>` if unset
+    mailbox: Option<String>,
+    /// File role mail into the Junk folder of its mailbox
+    quarantine: bool,
+}
+
+impl RoleAccountManager {
+    /// Create a new role account manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self {
+            db,
+            mailbox: None,
+            quarantine: false,
+        }
+    }
+
+    /// Connect to `database_url` and create the role account tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Deliver role mail of every domain to `mailbox`
+    pub fn with_mailbox(mut self, mailbox: &str) -> Self {
+        self.mailbox = Some(mailbox.to_lowercase());
+        self
+    }
+
+    /// File role mail into the Junk folder of its mailbox for review
+    pub fn with_quarantine(mut self, quarantine: bool) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS role_accounts (
+                address TEXT PRIMARY KEY,
+                domain TEXT NOT NULL,
+                role TEXT NOT NULL,
+                mailbox TEXT,
+                quarantine INTEGER,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS role_account_volume (
+                address TEXT NOT NULL,
+                day TEXT NOT NULL,
+                messages INTEGER NOT NULL DEFAULT 0,
+                bytes INTEGER NOT NULL DEFAULT 0,
+                spam INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (address, day)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Create the role addresses of `domain` that don't exist yet and
+    /// return them
+    pub async fn provision(&self, domain: &str) -> Result<Vec<RoleAccount>> {
+        let domain = domain.trim().to_lowercase();
+        if domain.is_empty() || domain.contains('@') {
+            bail!("Invalid domain: {:?}", domain);
+        }
+
+        let mut created = Vec::new();
+        for role in ROLES {
+            let account = RoleAccount {
+                address: format!("{}@{}", role, domain),
+                domain: domain.clone(),
+                role: role.to_string(),
+                mailbox: None,
+                quarantine: None,
+                created_at: Utc::now(),
+            };
+            let result = sqlx::query(
+                "INSERT OR IGNORE INTO role_accounts (address, domain, role, created_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(&account.address)
+            .bind(&account.domain)
+            .bind(&account.role)
+            .bind(account.created_at.to_rfc3339())
+            .execute(&self.db)
+            .await?;
+            if result.rows_affected() > 0 {
+                created.push(account);
+            }
+        }
+        Ok(created)
+    }
+
+    /// Role addresses of all domains
+    pub async fn list(&self) -> Result<Vec<RoleAccount>> {
+        let rows = sqlx::query("SELECT * FROM role_accounts ORDER BY domain, role")
+            .fetch_all(&self.db)
+            .await?;
+        rows.iter().map(account_from_row).collect()
+    }
+
+    /// The provisioned role address `address`
+    pub async fn get(&self, address: &str) -> Result<Option<RoleAccount>> {
+        let Some((role, domain)) = role_of(address) else {
+            return Ok(None);
+        };
+        let row = sqlx::query("SELECT * FROM role_accounts WHERE address = ?")
+            .bind(format!("{}@{}", role, domain))
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(account_from_row).transpose()
+    }
+
+    /// Where mail to `address` goes, if it is a provisioned role address
+    pub async fn resolve(&self, address: &str) -> Result<Option<RoleTarget>> {
+        Ok(self.get(address).await?.map(|account| self.target(&account)))
+    }
+
+    /// Where mail to `account` goes, with the configuration filling in
+    /// what it doesn't override
+    pub fn target(&self, account: &RoleAccount) -> RoleTarget {
+        RoleTarget {
+            mailbox: account
+                .mailbox
+                .clone()
+                .or_else(|| self.mailbox.clone())
+                .unwrap_or_else(|| format!("postmaster@{}", account.domain)),
+            quarantine: account.quarantine.unwrap_or(self.quarantine),
+        }
+    }
+
+    /// Set where mail to `address` goes; None if it isn't provisioned
+    pub async fn update(&self, address: &str, update: &UpdateRoleAccount) -> Result<Option<RoleAccount>> {
+        let Some(account) = self.get(address).await? else {
+            return Ok(None);
+        };
+        let mailbox = update.mailbox.as_deref().map(str::trim).map(str::to_lowercase);
+        if mailbox.as_ref().is_some_and(|mailbox| !mailbox.contains('@')) {
+            bail!("Invalid mailbox: {:?}", update.mailbox);
+        }
+
+        sqlx::query("UPDATE role_accounts SET mailbox = ?, quarantine = ? WHERE address = ?")
+            .bind(&mailbox)
+            .bind(update.quarantine)
+            .bind(&account.address)
+            .execute(&self.db)
+            .await?;
+        Ok(Some(RoleAccount {
+            mailbox,
+            quarantine: update.quarantine,
+            ..account
+        }))
+    }
+
+    /// Count a message of `bytes` received by `address`
+    pub async fn record(&self, address: &str, bytes: usize, spam: bool) -> Result<()> {
+        self.record_at(address, bytes, spam, Utc::now()).await
+    }
+
+    async fn record_at(&self, address: &str, bytes: usize, spam: bool, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO role_account_volume (address, day, messages, bytes, spam)
+            VALUES (?, ?, 1, ?, ?)
+            ON CONFLICT(address, day) DO UPDATE SET
+                messages = messages + 1,
+                bytes = bytes + excluded.bytes,
+                spam = spam + excluded.spam
+            "#,
+        )
+        .bind(address.to_lowercase())
+        .bind(at.date_naive().to_string())
+        .bind(bytes as i64)
+        .bind(spam as i64)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Daily volume of `address` since `since`, oldest first
+    pub async fn volume(&self, address: &str, since: NaiveDate) -> Result<Vec<RoleVolume>> {
+        let rows = sqlx::query(
+            "SELECT * FROM role_account_volume WHERE address = ? AND day >= ? ORDER BY day",
+        )
+        .bind(address.to_lowercase())
+        .bind(since.to_string())
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(RoleVolume {
+                    day: row.get::<String, _>("day").parse()?,
+                    messages: row.get::<i64, _>("messages") as u64,
+                    bytes: row.get::<i64, _>("bytes") as u64,
+                    spam: row.get::<i64, _>("spam") as u64,
+                })
+            })
+            .collect()
+    }
+}
+
+fn account_from_row(row: &SqliteRow) -> Result<RoleAccount> {
+    Ok(RoleAccount {
+        address: row.get("address"),
+        domain: row.get("domain"),
+        role: row.get("role"),
+        mailbox: row.get("mailbox"),
+        quarantine: row.get("quarantine"),
+        created_at: DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at"))?
+            .with_timezone(&Utc),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    async fn manager() -> RoleAccountManager {
+        RoleAccountManager::connect("sqlite::memory:").await.unwrap()
+    }
+
+    #[test]
+    fn test_role_of() {
+        assert_eq!(role_of("PostMaster@Example.com"), Some(("postmaster", "example.com".to_string())));
+        assert_eq!(role_of("abuse@example.com"), Some(("abuse", "example.com".to_string())));
+        assert_eq!(role_of("bob@example.com"), None);
+        assert_eq!(role_of("postmaster"), None);
+    }
+
+    #[tokio::test]
+    async fn test_provision_and_resolve() {
+        let manager = manager().await.with_mailbox("Admin@example.com");
+        assert_eq!(manager.provision("Example.com").await.unwrap().len(), 2);
+        assert!(manager.provision("example.com").await.unwrap().is_empty());
+        assert!(manager.provision("bad@domain").await.is_err());
+        assert_eq!(manager.list().await.unwrap().len(), 2);
+
+        let target = manager.resolve("ABUSE@example.com").await.unwrap().unwrap();
+        assert_eq!(
+            target,
+            RoleTarget {
+                mailbox: "admin@example.com".to_string(),
+                quarantine: false
+            }
+        );
+        assert!(manager.resolve("abuse@other.example").await.unwrap().is_none());
+        assert!(manager.resolve("bob@example.com").await.unwrap().is_none());
+
+        let update = UpdateRoleAccount {
+            mailbox: Some("Security@example.com".to_string()),
+            quarantine: Some(true),
+        };
+        manager.update("abuse@example.com", &update).await.unwrap().unwrap();
+        let target = manager.resolve("abuse@example.com").await.unwrap().unwrap();
+        assert_eq!(target.mailbox, "security@example.com");
+        assert!(target.quarantine);
+        assert!(manager.update("abuse@other.example", &update).await.unwrap().is_none());
+
+        // Without a configured mailbox the domain's postmaster gets it
+        let account = manager.get("postmaster@example.com").await.unwrap().unwrap();
+        let defaults = RoleAccountManager::new(manager.db.clone());
+        assert_eq!(defaults.target(&account).mailbox, "postmaster@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_volume() {
+        let manager = manager().await;
+        let day = |d| Utc.with_ymd_and_hms(2026, 3, d, 12, 0, 0).unwrap();
+        manager.record_at("abuse@example.com", 100, false, day(1)).await.unwrap();
+        manager.record_at("abuse@example.com", 50, true, day(1)).await.unwrap();
+        manager.record_at("abuse@example.com", 10, true, day(2)).await.unwrap();
+        manager.record_at("postmaster@example.com", 10, false, day(2)).await.unwrap();
+
+        let volume = manager
+            .volume("abuse@example.com", day(1).date_naive())
+            .await
+            .unwrap();
+        assert_eq!(
+            volume,
+            vec![
+                RoleVolume {
+                    day: day(1).date_naive(),
+                    messages: 2,
+                    bytes: 150,
+                    spam: 1
+                },
+                RoleVolume {
+                    day: day(2).date_naive(),
+                    messages: 1,
+                    bytes: 10,
+                    spam: 1
+                },
+            ]
+        );
+        let recent = manager
+            .volume("abuse@example.com", day(2).date_naive())
+            .await
+            .unwrap();
+        assert_eq!(recent.len(), 1);
+    }
+}
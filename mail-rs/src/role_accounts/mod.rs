@@ -0,0 +1,13 @@
+//! Role accounts: `postmaster@` and `abuse@` of every hosted domain
+//!
+//! RFC 5321 requires accepting mail for `postmaster`, and abuse reports
+//! are expected at `abuse@`. Both are provisioned for each hosted domain
+//! and delivered to an admin mailbox, or into its Junk folder for review.
+//! Spam filtering only tags mail to them, never refuses it, and the
+//! messages each role address receives are counted per day.
+
+pub mod manager;
+pub mod types;
+
+pub use manager::RoleAccountManager;
+pub use types::*;
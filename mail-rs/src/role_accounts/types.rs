@@ -0,0 +1,57 @@
+//! Role account data types
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Local parts provisioned in every hosted domain
+pub const ROLES: [&str; 2] = ["postmaster", "abuse"];
+
+/// A role address of a hosted domain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoleAccount {
+    /// Address, e.g. `abuse@example.com`
+    pub address: String,
+    pub domain: String,
+    /// One of [`ROLES`]
+    pub role: String,
+    /// Mailbox receiving the role's mail instead of the configured one
+    pub mailbox: Option<String>,
+    /// Overrides whether the mail is filed into the Junk folder
+    pub quarantine: Option<bool>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Where mail to a role address is delivered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoleTarget {
+    pub mailbox: String,
+    /// Filed into the Junk folder of the mailbox for review
+    pub quarantine: bool,
+}
+
+/// Messages a role address received on one day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoleVolume {
+    pub day: NaiveDate,
+    pub messages: u64,
+    pub bytes: u64,
+    /// Messages scored as spam
+    pub spam: u64,
+}
+
+/// Request to change where a role address's mail goes; unset fields fall
+/// back to the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UpdateRoleAccount {
+    pub mailbox: Option<String>,
+    pub quarantine: Option<bool>,
+}
+
+/// Role and domain of `address` if its local part is a role
+pub fn role_of(address: &str) -> Option<(&'static str, String)> {
+    let (local, domain) = address.rsplit_once('@')?;
+    let role = ROLES
+        .into_iter()
+        .find(|role| role.eq_ignore_ascii_case(local))?;
+    Some((role, domain.to_lowercase()))
+}
@@ -0,0 +1,572 @@
+//! Text of attachments for the search index
+//!
+//! Attachments are found with the MIME parser and read by type: the text
+//! shown by the page content streams of a PDF, the paragraphs of a DOCX
+//! document, HTML without its markup, and other text files as they are.
+//! Each type can be turned off in `[search.attachments]`; attachments above
+//! `max_bytes` are skipped and text beyond `max_chars` is cut off.
+
+use crate::config::AttachmentIndexConfig;
+use crate::footers::inject::strip_tags;
+use crate::mime::{MimeEntity, MimeParser};
+use flate2::read::ZlibDecoder;
+use std::io::{Cursor, Read};
+use tracing::debug;
+
+/// Content type of Word documents
+const DOCX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+/// TJ offsets, in thousandths of the font size, wide enough to be a space
+const PDF_SPACE_OFFSET: f32 = -200.0;
+
+/// Attachment types text is extracted from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentKind {
+    Pdf,
+    Docx,
+    Html,
+    Text,
+}
+
+impl AttachmentKind {
+    /// Type of an attachment, by content type or else file extension
+    pub fn detect(content_type: &str, filename: Option<&str>) -> Option<Self> {
+        let extension = filename
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_lowercase());
+        match (content_type, extension.as_deref()) {
+            ("application/pdf", _) | (_, Some("pdf")) => Some(Self::Pdf),
+            (DOCX_CONTENT_TYPE, _) | (_, Some("docx")) => Some(Self::Docx),
+            ("text/html", _) | (_, Some("html" | "htm")) => Some(Self::Html),
+            (content_type, _) if content_type.starts_with("text/") => Some(Self::Text),
+            (_, Some("txt" | "csv" | "md" | "log")) => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    fn is_enabled(self, config: &AttachmentIndexConfig) -> bool {
+        match self {
+            Self::Pdf => config.pdf,
+            Self::Docx => config.docx,
+            Self::Html => config.html,
+            Self::Text => config.text,
+        }
+    }
+}
+
+/// Text of each attachment of `message` that `config` allows, in order
+pub fn attachment_texts(message: &[u8], config: &AttachmentIndexConfig) -> Vec<String> {
+    let mut texts = Vec::new();
+    if config.enabled {
+        collect(&MimeParser::parse_tree(message), config, &mut texts);
+    }
+    texts
+}
+
+fn collect(entity: &MimeEntity, config: &AttachmentIndexConfig, texts: &mut Vec<String>) {
+    if let Some(message) = &entity.message {
+        collect(message, config, texts);
+        return;
+    }
+    if !entity.parts.is_empty() {
+        for part in &entity.parts {
+            collect(part, config, texts);
+        }
+        return;
+    }
+
+    // Inline bodies are indexed as the message body
+    let filename = entity.filename();
+    if !entity.is_attachment() && filename.is_none() {
+        return;
+    }
+    let Some(kind) = AttachmentKind::detect(&entity.content_type, filename.as_deref()) else {
+        return;
+    };
+    if !kind.is_enabled(config) {
+        return;
+    }
+    let data = entity.decoded_body();
+    if data.len() > config.max_bytes {
+        debug!(
+            "Not indexing attachment {:?} of {} bytes",
+            filename,
+            data.len()
+        );
+        return;
+    }
+
+    let text = match kind {
+        AttachmentKind::Pdf => pdf_text(&data, config.max_bytes, config.max_chars),
+        AttachmentKind::Docx => docx_text(&data, config.max_bytes),
+        AttachmentKind::Html => html_text(&entity.text()),
+        AttachmentKind::Text => entity.text(),
+    };
+    let text: String = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(config.max_chars)
+        .collect();
+    if !text.is_empty() {
+        texts.push(text);
+    }
+}
+
+/// Text shown by the content streams of a PDF
+///
+/// Uncompressed and Flate-compressed streams are read, and strings are
+/// taken as single-byte text. That covers documents using standard fonts;
+/// text drawn with embedded CID fonts is not recovered. At most
+/// `max_bytes` are inflated over all streams together, and reading stops
+/// once `max_chars` characters of text are found.
+fn pdf_text(data: &[u8], max_bytes: usize, max_chars: usize) -> String {
+    // Strings are single-byte, so each byte is one character
+    let mut text = Vec::new();
+    let mut budget = max_bytes as u64;
+    let mut position = 0;
+    while let Some(keyword) = find(data, b"stream", position) {
+        if text.len() >= max_chars || budget == 0 {
+            break;
+        }
+        let mut start = keyword + b"stream".len();
+        if data.get(start) == Some(&b'\r') {
+            start += 1;
+        }
+        if data.get(start) == Some(&b'\n') {
+            start += 1;
+        }
+        let Some(end) = find(data, b"endstream", start) else {
+            break;
+        };
+        // The stream dictionary follows the object header
+        let dictionary = &data[position..keyword];
+        let dictionary = match rfind(dictionary, b"obj") {
+            Some(header) => &dictionary[header..],
+            None => dictionary,
+        };
+        position = end + b"endstream".len();
+
+        let content = if find(dictionary, b"/FlateDecode", 0).is_some() {
+            // A truncated stream still yields the text before the damage
+            let mut inflated = Vec::new();
+            let _ = ZlibDecoder::new(&data[start..end])
+                .take(budget)
+                .read_to_end(&mut inflated);
+            budget -= inflated.len() as u64;
+            inflated
+        } else if find(dictionary, b"/Filter", 0).is_some() {
+            continue;
+        } else {
+            data[start..end].to_vec()
+        };
+        // Images, fonts and other streams without text objects
+        if find(&content, b"BT", 0).is_some() {
+            show_text(&content, &mut text, max_chars);
+        }
+    }
+    text.into_iter().map(char::from).collect()
+}
+
+/// Append the strings a content stream shows with `Tj`, `TJ`, `'` and `"`,
+/// until `text` holds `max_chars` bytes
+fn show_text(content: &[u8], text: &mut Vec<u8>, max_chars: usize) {
+    let mut operands: Vec<Vec<u8>> = Vec::new();
+    let mut in_array = false;
+    let mut i = 0;
+    while i < content.len() {
+        match content[i] {
+            b'(' => {
+                let (string, next) = literal_string(content, i + 1);
+                operands.push(string);
+                i = next;
+            }
+            b'<' if content.get(i + 1) == Some(&b'<') => i += 2,
+            b'<' => {
+                let (string, next) = hex_string(content, i + 1);
+                operands.push(string);
+                i = next;
+            }
+            b'[' => {
+                in_array = true;
+                i += 1;
+            }
+            b']' => {
+                in_array = false;
+                i += 1;
+            }
+            b'%' => {
+                while i < content.len() && !matches!(content[i], b'\r' | b'\n') {
+                    i += 1;
+                }
+            }
+            b'/' => {
+                i += 1;
+                while i < content.len() && is_regular(content[i]) {
+                    i += 1;
+                }
+            }
+            b'-' | b'+' | b'.' | b'0'..=b'9' => {
+                let start = i;
+                i += 1;
+                while i < content.len() && matches!(content[i], b'.' | b'0'..=b'9') {
+                    i += 1;
+                }
+                let number = std::str::from_utf8(&content[start..i])
+                    .ok()
+                    .and_then(|number| number.parse::<f32>().ok());
+                if in_array && number.is_some_and(|offset| offset < PDF_SPACE_OFFSET) {
+                    operands.push(b" ".to_vec());
+                }
+            }
+            c if is_regular(c) => {
+                let start = i;
+                while i < content.len() && is_regular(content[i]) {
+                    i += 1;
+                }
+                match &content[start..i] {
+                    b"Tj" | b"TJ" | b"'" | b"\"" => {
+                        if content[start] != b'T' {
+                            separate(text);
+                        }
+                        for string in operands.drain(..) {
+                            let room = max_chars.saturating_sub(text.len());
+                            text.extend(
+                                string
+                                    .into_iter()
+                                    .filter(|&byte| {
+                                        let c = char::from(byte);
+                                        !c.is_control() || c.is_whitespace()
+                                    })
+                                    .take(room),
+                            );
+                        }
+                        if text.len() >= max_chars {
+                            return;
+                        }
+                    }
+                    b"T*" | b"Td" | b"TD" | b"Tm" | b"ET" => {
+                        operands.clear();
+                        separate(text);
+                    }
+                    _ => operands.clear(),
+                }
+            }
+            _ => i += 1,
+        }
+    }
+}
+
+/// Bytes of a literal string starting after its `(`, and the position
+/// after its `)`
+fn literal_string(content: &[u8], mut i: usize) -> (Vec<u8>, usize) {
+    let mut string = Vec::new();
+    let mut depth = 0;
+    while i < content.len() {
+        match content[i] {
+            b'\\' => {
+                i += 1;
+                match content.get(i) {
+                    Some(b'n') => string.push(b'\n'),
+                    Some(b'r') => string.push(b'\r'),
+                    Some(b't') => string.push(b'\t'),
+                    Some(b'b' | b'f') => {}
+                    Some(b'0'..=b'7') => {
+                        let start = i;
+                        while i < content.len()
+                            && i < start + 3
+                            && matches!(content[i], b'0'..=b'7')
+                        {
+                            i += 1;
+                        }
+                        let octal = std::str::from_utf8(&content[start..i]).unwrap_or("0");
+                        string.push(u16::from_str_radix(octal, 8).unwrap_or(0) as u8);
+                        continue;
+                    }
+                    // A backslash at the end of a line continues the string
+                    Some(b'\r' | b'\n') => {}
+                    Some(&other) => string.push(other),
+                    None => break,
+                }
+            }
+            b'(' => {
+                depth += 1;
+                string.push(b'(');
+            }
+            b')' if depth == 0 => return (string, i + 1),
+            b')' => {
+                depth -= 1;
+                string.push(b')');
+            }
+            byte => string.push(byte),
+        }
+        i += 1;
+    }
+    (string, i)
+}
+
+/// Bytes of a hex string starting after its `<`, and the position after
+/// its `>`
+fn hex_string(content: &[u8], mut i: usize) -> (Vec<u8>, usize) {
+    let mut digits = Vec::new();
+    while i < content.len() && content[i] != b'>' {
+        if content[i].is_ascii_hexdigit() {
+            digits.push(content[i]);
+        }
+        i += 1;
+    }
+    // An odd last digit is followed by an implied 0
+    if digits.len() % 2 == 1 {
+        digits.push(b'0');
+    }
+    let string = digits
+        .chunks(2)
+        .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect();
+    (string, i + 1)
+}
+
+/// Whether a byte belongs to a PDF name or operator
+fn is_regular(byte: u8) -> bool {
+    !byte.is_ascii_whitespace()
+        && !matches!(
+            byte,
+            b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+        )
+}
+
+/// Separate the text of the next text operation with a space
+fn separate(text: &mut Vec<u8>) {
+    if text
+        .last()
+        .is_some_and(|&byte| !char::from(byte).is_whitespace())
+    {
+        text.push(b' ');
+    }
+}
+
+/// Paragraphs of the body of a DOCX document
+fn docx_text(data: &[u8], max_bytes: usize) -> String {
+    let Ok(mut archive) = zip::ZipArchive::new(Cursor::new(data)) else {
+        return String::new();
+    };
+    let Ok(document) = archive.by_name("word/document.xml") else {
+        return String::new();
+    };
+    // The declared size can't be trusted, so inflating stops at max_bytes
+    let mut xml = Vec::new();
+    if document
+        .take(max_bytes as u64)
+        .read_to_end(&mut xml)
+        .is_err()
+    {
+        return String::new();
+    }
+    let xml = String::from_utf8_lossy(&xml)
+        .replace("</w:p>", "\n")
+        .replace("<w:tab/>", "\t")
+        .replace("<w:br/>", "\n");
+
+    // Runs split words anywhere, so tags are dropped without a space
+    let mut text = String::with_capacity(xml.len());
+    let mut in_tag = false;
+    for c in xml.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Text of an HTML document, without scripts and style sheets
+fn html_text(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut visible = String::with_capacity(html.len());
+    let mut position = 0;
+    while let Some(start) = ["<script", "<style"]
+        .iter()
+        .filter_map(|tag| lower[position..].find(tag).map(|at| (position + at, *tag)))
+        .min()
+    {
+        visible.push_str(&html[position..start.0]);
+        let close = format!("</{}", &start.1[1..]);
+        position = match lower[start.0..].find(&close) {
+            Some(end) => lower[start.0 + end..]
+                .find('>')
+                .map_or(html.len(), |gt| start.0 + end + gt + 1),
+            None => html.len(),
+        };
+    }
+    visible.push_str(&html[position..]);
+    strip_tags(&visible)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|at| from + at)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::{BodyPart, MessageBuilder};
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    /// A one-page PDF drawing `content`, compressed or not
+    fn pdf(content: &str, compressed: bool) -> Vec<u8> {
+        pdf_streams(&[content], compressed)
+    }
+
+    /// A PDF with one content stream per entry of `contents`
+    fn pdf_streams<S: AsRef<str>>(contents: &[S], compressed: bool) -> Vec<u8> {
+        let mut pdf = b"%PDF-1.4\n1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n".to_vec();
+        for (number, content) in contents.iter().enumerate() {
+            let (filter, stream) = if compressed {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(content.as_ref().as_bytes()).unwrap();
+                (" /Filter /FlateDecode", encoder.finish().unwrap())
+            } else {
+                ("", content.as_ref().as_bytes().to_vec())
+            };
+            pdf.extend_from_slice(
+                format!(
+                    "{} 0 obj\n<< /Length {}{} >>\nstream\n",
+                    number + 4,
+                    stream.len(),
+                    filter
+                )
+                .as_bytes(),
+            );
+            pdf.extend_from_slice(&stream);
+            pdf.extend_from_slice(b"\nendstream\nendobj\n");
+        }
+        pdf.extend_from_slice(b"%%EOF\n");
+        pdf
+    }
+
+    fn docx(paragraphs: &[&str]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(
+            "word/document.xml",
+            zip::write::SimpleFileOptions::default(),
+        )
+        .unwrap();
+        let body: String = paragraphs
+            .iter()
+            .map(|p| format!("<w:p><w:r><w:t>{}</w:t></w:r></w:p>", p))
+            .collect();
+        zip.write_all(format!("<w:document><w:body>{}</w:body></w:document>", body).as_bytes())
+            .unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn message(attachments: &[(&str, &str, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = MessageBuilder::new()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .subject("Files")
+            .text("See the attached files");
+        for (filename, content_type, data) in attachments {
+            builder = builder.part(BodyPart::attachment(filename, content_type, data.clone()));
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_pdf_text() {
+        let content = "BT /F1 12 Tf 72 712 Td (Quarterly \\(draft\\)) Tj T* \
+                       [(Rev) 30 (enue) -250 (grew)] TJ T* <4F4B> Tj ET";
+        for compressed in [false, true] {
+            assert_eq!(
+                pdf_text(&pdf(content, compressed), 1 << 20, 1000).trim(),
+                "Quarterly (draft) Revenue grew OK"
+            );
+        }
+        assert_eq!(pdf_text(b"not a pdf", 1 << 20, 1000), "");
+    }
+
+    #[test]
+    fn test_pdf_limits_span_all_streams() {
+        // Each stream inflates to about 10 kB from a few dozen bytes
+        let padding = " ".repeat(10_000);
+        let contents: Vec<String> = (0..2000)
+            .map(|n| format!("BT (page{}) Tj ET{}", n, padding))
+            .collect();
+        let data = pdf_streams(&contents, true);
+
+        let text = pdf_text(&data, 50_000, 100_000);
+        assert!(text.contains("page0 "));
+        assert!(text.contains("page4"));
+        assert!(!text.contains("page5"));
+
+        let text = pdf_text(&data, 1 << 30, 20);
+        assert_eq!(text, "page0 page1 page2 pa");
+    }
+
+    #[test]
+    fn test_attachment_texts() {
+        let raw = message(&[
+            (
+                "report.pdf",
+                "application/pdf",
+                pdf("BT (Invoice 42) Tj ET", true),
+            ),
+            (
+                "notes.docx",
+                DOCX_CONTENT_TYPE,
+                docx(&["First", "Sec&amp;ond"]),
+            ),
+            (
+                "page.html",
+                "text/html",
+                b"<p>Hello <b>world</b></p><script>var x;</script><STYLE>p{}</STYLE>".to_vec(),
+            ),
+            ("data.csv", "text/csv", b"a,b\n1,2\n".to_vec()),
+            ("image.png", "image/png", vec![0x89, b'P', b'N', b'G']),
+        ]);
+        let config = AttachmentIndexConfig::default();
+        assert_eq!(
+            attachment_texts(&raw, &config),
+            ["Invoice 42", "First Sec&ond", "Hello world", "a,b 1,2"]
+        );
+
+        let config = AttachmentIndexConfig {
+            pdf: false,
+            html: false,
+            max_chars: 5,
+            ..Default::default()
+        };
+        assert_eq!(attachment_texts(&raw, &config), ["First", "a,b 1"]);
+
+        let config = AttachmentIndexConfig {
+            max_bytes: 10,
+            ..Default::default()
+        };
+        assert_eq!(attachment_texts(&raw, &config), ["a,b 1,2"]);
+
+        let config = AttachmentIndexConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        assert!(attachment_texts(&raw, &config).is_empty());
+    }
+}
@@ -1,25 +1,40 @@
 //! Email indexer using Tantivy
 //!
-//! Provides full-text search indexing for email messages.
+//! Provides full-text search indexing for email messages. The listener a
+//! message arrived on, from its ingress header, is indexed for filtering.
+//! Text extracted from attachments is searched along with the body (see
+//! [`super::extract`]). Queries use the syntax of [`super::query`]; results
+//! come with highlighted snippets and match counts by folder and sender.
 
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
+use std::ops::Bound;
 use std::path::Path;
 use std::sync::Arc;
 use tantivy::{
-    collector::TopDocs,
+    collector::{Count, FacetCollector, TopDocs},
     directory::MmapDirectory,
     doc,
-    query::{BooleanQuery, Occur, QueryParser, TermQuery},
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery},
     schema::{
-        Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, STORED, STRING,
+        Facet, FacetOptions, Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED,
+        STORED, STRING,
     },
+    snippet::SnippetGenerator,
     tokenizer::{LowerCaser, RemoveLongFilter, SimpleTokenizer, TextAnalyzer},
     Index, IndexReader, IndexWriter, IndexSettings, ReloadPolicy, TantivyDocument, Term,
 };
 use tokio::sync::RwLock;
 
-use super::types::{SearchQuery, SearchResult, SearchResults};
+use super::extract::attachment_texts;
+use super::query::ParsedQuery;
+use super::types::{FacetCount, SearchFacets, SearchQuery, SearchResult, SearchResults};
+use crate::config::AttachmentIndexConfig;
+use crate::imap::uid::base_name;
+use crate::smtp::ingress::Ingress;
+
+/// Senders counted in the facets of a search
+const SENDER_FACETS: usize = 10;
 
 /// Schema fields for email documents
 pub struct EmailFields {
@@ -31,6 +46,29 @@ pub struct EmailFields {
     pub subject: Field,
     pub body: Field,
     pub date_timestamp: Field,
+    /// Listener of the message's ingress header
+    pub listener: Field,
+    /// Text extracted from attachments, indexed but not stored
+    pub attachments: Field,
+    pub has_attachment: Field,
+    /// `/folder/<name>` and `/sender/<address>`, counted for each search
+    pub facets: Field,
+}
+
+/// A message as it is indexed
+pub struct EmailDocument<'a> {
+    pub message_id: &'a str,
+    pub owner_email: &'a str,
+    pub folder: &'a str,
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub body: &'a str,
+    pub date: DateTime<Utc>,
+    pub ingress: Option<&'a Ingress>,
+    /// Text of each attachment, see [`super::extract`]
+    pub attachments: &'a [String],
+    pub has_attachment: bool,
 }
 
 /// Email indexer for full-text search
@@ -51,12 +89,26 @@ impl EmailIndexer {
         // Build schema
         let (schema, fields) = Self::build_schema();
 
-        // Open or create index
-        let index = if index_path.join("meta.json").exists() {
-            Index::open_in_dir(index_path)?
-        } else {
-            let dir = MmapDirectory::open(index_path)?;
-            Index::create(dir, schema.clone(), IndexSettings::default())?
+        // Open or create index; an index with another schema is rebuilt,
+        // as documents can always be reindexed from the mailboxes
+        let existing = match index_path.join("meta.json").exists() {
+            true => Some(Index::open_in_dir(index_path)?),
+            false => None,
+        };
+        let index = match existing {
+            Some(index) if index.schema() == schema => index,
+            existing => {
+                if existing.is_some() {
+                    tracing::warn!(
+                        "Search index at {} has an outdated schema; recreating it, reindex to search existing mail",
+                        index_path.display()
+                    );
+                    std::fs::remove_dir_all(index_path)?;
+                    std::fs::create_dir_all(index_path)?;
+                }
+                let dir = MmapDirectory::open(index_path)?;
+                Index::create(dir, schema.clone(), IndexSettings::default())?
+            }
         };
 
         // Register custom tokenizer for better search
@@ -76,7 +128,7 @@ impl EmailIndexer {
         let writer = index.writer(50_000_000)?;
 
         // Create query parser searching across subject and body
-        let query_parser = QueryParser::for_index(&index, vec![fields.subject, fields.body, fields.from, fields.to]);
+        let query_parser = QueryParser::for_index(&index, vec![fields.subject, fields.body, fields.from, fields.to, fields.attachments]);
 
         Ok(Self {
             index,
@@ -95,6 +147,7 @@ impl EmailIndexer {
         let text_indexing = TextFieldIndexing::default()
             .set_tokenizer("email_tokenizer")
             .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        let attachment_options = TextOptions::default().set_indexing_options(text_indexing.clone());
         let text_options = TextOptions::default()
             .set_indexing_options(text_indexing)
             .set_stored();
@@ -108,6 +161,10 @@ impl EmailIndexer {
         let subject = schema_builder.add_text_field("subject", text_options.clone());
         let body = schema_builder.add_text_field("body", text_options);
         let date_timestamp = schema_builder.add_i64_field("date_timestamp", FAST | STORED);
+        let listener = schema_builder.add_text_field("listener", STRING | STORED);
+        let attachments = schema_builder.add_text_field("attachments", attachment_options);
+        let has_attachment = schema_builder.add_bool_field("has_attachment", INDEXED | STORED);
+        let facets = schema_builder.add_facet_field("facets", FacetOptions::default());
 
         let schema = schema_builder.build();
 
@@ -120,6 +177,10 @@ impl EmailIndexer {
             subject,
             body,
             date_timestamp,
+            listener,
+            attachments,
+            has_attachment,
+            facets,
         };
 
         (schema, fields)
@@ -136,26 +197,113 @@ impl EmailIndexer {
         subject: &str,
         body: &str,
         date: DateTime<Utc>,
+        ingress: Option<&Ingress>,
     ) -> Result<()> {
-        // First remove any existing document with this message_id
-        self.remove_email(message_id).await?;
+        self.add(EmailDocument {
+            message_id,
+            owner_email,
+            folder,
+            from,
+            to,
+            subject,
+            body,
+            date,
+            ingress,
+            attachments: &[],
+            has_attachment: false,
+        })
+        .await
+    }
+
+    /// Index a message, replacing the document it had
+    pub async fn add(&self, email: EmailDocument<'_>) -> Result<()> {
+        // First remove any existing document of this message
+        self.remove_message(email.owner_email, email.folder, email.message_id).await?;
 
         // Create document
+        let mut document = doc!(
+            self.fields.message_id => email.message_id,
+            self.fields.owner_email => email.owner_email,
+            self.fields.folder => email.folder,
+            self.fields.from => email.from,
+            self.fields.to => email.to,
+            self.fields.subject => email.subject,
+            self.fields.body => email.body,
+            self.fields.date_timestamp => email.date.timestamp(),
+            self.fields.has_attachment => email.has_attachment,
+        );
+        document.add_facet(self.fields.facets, Facet::from_path(["folder", email.folder]));
+        if !email.from.is_empty() {
+            document.add_facet(self.fields.facets, Facet::from_path(["sender", email.from.to_lowercase().as_str()]));
+        }
+        if let Some(ingress) = email.ingress {
+            document.add_text(self.fields.listener, ingress.listener.as_str());
+        }
+        for text in email.attachments {
+            document.add_text(self.fields.attachments, text);
+        }
         let mut writer = self.writer.write().await;
-        writer.add_document(doc!(
-            self.fields.message_id => message_id,
-            self.fields.owner_email => owner_email,
-            self.fields.folder => folder,
-            self.fields.from => from,
-            self.fields.to => to,
-            self.fields.subject => subject,
-            self.fields.body => body,
-            self.fields.date_timestamp => date.timestamp(),
-        ))?;
+        writer.add_document(document)?;
 
         Ok(())
     }
 
+    /// Parse a raw message and index it as `message_id` in `folder`, with
+    /// the text of the attachments `attachments` allows
+    pub async fn index_message(
+        &self,
+        message_id: &str,
+        owner_email: &str,
+        folder: &str,
+        content: &[u8],
+        attachments: &AttachmentIndexConfig,
+    ) -> Result<()> {
+        // Decompressing documents must not hold up the runtime
+        let attachment_texts = {
+            let (content, config) = (content.to_vec(), attachments.clone());
+            tokio::task::spawn_blocking(move || attachment_texts(&content, &config)).await?
+        };
+
+        let parsed = mail_parser::MessageParser::default()
+            .parse(content)
+            .ok_or_else(|| anyhow!("Unparseable message {}", message_id))?;
+
+        let from = parsed.from()
+            .and_then(|f| f.first())
+            .map(|a| a.address().map(|s| s.to_string()).unwrap_or_default())
+            .unwrap_or_default();
+
+        let to = parsed.to()
+            .and_then(|t| t.first())
+            .map(|a| a.address().map(|s| s.to_string()).unwrap_or_default())
+            .unwrap_or_default();
+
+        let subject = parsed.subject().unwrap_or("").to_string();
+
+        let body = parsed.body_text(0)
+            .map(|b| b.to_string())
+            .unwrap_or_default();
+
+        let date = parsed.date()
+            .map(|d| DateTime::from_timestamp(d.to_timestamp(), 0).unwrap_or_else(Utc::now))
+            .unwrap_or_else(Utc::now);
+
+        self.add(EmailDocument {
+            message_id,
+            owner_email,
+            folder,
+            from: &from,
+            to: &to,
+            subject: &subject,
+            body: &body,
+            date,
+            ingress: Ingress::from_message(content).as_ref(),
+            attachments: &attachment_texts,
+            has_attachment: parsed.attachment_count() > 0,
+        })
+        .await
+    }
+
     /// Remove an email from index
     pub async fn remove_email(&self, message_id: &str) -> Result<()> {
         let mut writer = self.writer.write().await;
@@ -164,6 +312,51 @@ impl EmailIndexer {
         Ok(())
     }
 
+    /// Remove message `message_id` of `folder` of a user from the index
+    ///
+    /// Copies of a message keep its id, so the folder tells them apart.
+    pub async fn remove_message(&self, owner_email: &str, folder: &str, message_id: &str) -> Result<()> {
+        let query = self.message_query(owner_email, folder, message_id);
+        let writer = self.writer.write().await;
+        writer.delete_query(Box::new(query))?;
+        Ok(())
+    }
+
+    /// Remove every message of a user from the index
+    pub async fn remove_owner(&self, owner_email: &str) -> Result<()> {
+        let writer = self.writer.write().await;
+        writer.delete_term(Term::from_field_text(self.fields.owner_email, owner_email));
+        Ok(())
+    }
+
+    /// Check whether message `message_id` of `folder` of a user is indexed,
+    /// as of the last commit
+    pub fn contains(&self, owner_email: &str, folder: &str, message_id: &str) -> Result<bool> {
+        let query = self.message_query(owner_email, folder, message_id);
+        Ok(self.reader.searcher().search(&query, &Count)? > 0)
+    }
+
+    /// Query matching one message of one user's folder
+    fn message_query(&self, owner_email: &str, folder: &str, message_id: &str) -> BooleanQuery {
+        let terms = [
+            (self.fields.owner_email, owner_email),
+            (self.fields.folder, folder),
+            (self.fields.message_id, message_id),
+        ];
+        BooleanQuery::new(
+            terms
+                .into_iter()
+                .map(|(field, value)| {
+                    let query: Box<dyn tantivy::query::Query> = Box::new(TermQuery::new(
+                        Term::from_field_text(field, value),
+                        IndexRecordOption::Basic,
+                    ));
+                    (Occur::Must, query)
+                })
+                .collect(),
+        )
+    }
+
     /// Commit pending changes
     pub async fn commit(&self) -> Result<()> {
         let mut writer = self.writer.write().await;
@@ -192,18 +385,73 @@ impl EmailIndexer {
             subqueries.push((Occur::Must, Box::new(TermQuery::new(folder_term, IndexRecordOption::Basic))));
         }
 
-        // Parse text query
-        if !query.query.is_empty() {
-            let parsed_query = self.query_parser.parse_query(&query.query)
-                .map_err(|e| anyhow!("Query parse error: {}", e))?;
-            subqueries.push((Occur::Must, Box::new(parsed_query)));
+        // Listener filter if specified
+        if let Some(listener) = &query.listener {
+            let listener_term = Term::from_field_text(self.fields.listener, &listener.to_lowercase());
+            subqueries.push((Occur::Must, Box::new(TermQuery::new(listener_term, IndexRecordOption::Basic))));
+        }
+
+        // Criteria of the query string
+        let parsed = ParsedQuery::parse(&query.query)?;
+        if let Some(folder) = &parsed.folder {
+            let folder_term = Term::from_field_text(self.fields.folder, folder);
+            subqueries.push((Occur::Must, Box::new(TermQuery::new(folder_term, IndexRecordOption::Basic))));
+        }
+        if parsed.has_attachment {
+            let attachment_term = Term::from_field_bool(self.fields.has_attachment, true);
+            subqueries.push((Occur::Must, Box::new(TermQuery::new(attachment_term, IndexRecordOption::Basic))));
+        }
+        for text in &parsed.text {
+            subqueries.push((Occur::Must, self.phrase_query(&self.query_parser, text)?));
+        }
+        for text in &parsed.excluded {
+            subqueries.push((Occur::MustNot, self.phrase_query(&self.query_parser, text)?));
+        }
+        for (field, values) in [
+            (self.fields.from, &parsed.from),
+            (self.fields.to, &parsed.to),
+            (self.fields.subject, &parsed.subject),
+        ] {
+            let parser = QueryParser::for_index(&self.index, vec![field]);
+            for value in values {
+                subqueries.push((Occur::Must, self.phrase_query(&parser, value)?));
+            }
+        }
+
+        // Date range, from both the query string and the parameters
+        let lower = [parsed.after, query.from_date]
+            .into_iter()
+            .flatten()
+            .map(|date| date.timestamp())
+            .max();
+        let upper = [parsed.before.map(|date| date.timestamp()), query.to_date.map(|date| date.timestamp() + 1)]
+            .into_iter()
+            .flatten()
+            .min();
+        if lower.is_some() || upper.is_some() {
+            subqueries.push((
+                Occur::Must,
+                Box::new(RangeQuery::new_i64_bounds(
+                    "date_timestamp".to_string(),
+                    lower.map_or(Bound::Unbounded, Bound::Included),
+                    upper.map_or(Bound::Unbounded, Bound::Excluded),
+                )),
+            ));
         }
 
         let combined_query = BooleanQuery::new(subqueries);
 
-        // Execute search
-        let top_docs = searcher.search(&combined_query, &TopDocs::with_limit(limit + offset))?;
-        let total = top_docs.len();
+        // Execute search, counting all matches by folder and sender
+        let mut facet_collector = FacetCollector::for_field("facets");
+        facet_collector.add_facet("/folder");
+        facet_collector.add_facet("/sender");
+        let (top_docs, total, facet_counts) = searcher.search(
+            &combined_query,
+            &(TopDocs::with_limit(limit + offset), Count, facet_collector),
+        )?;
+
+        let mut snippets = SnippetGenerator::create(&searcher, &combined_query, self.fields.body)?;
+        snippets.set_max_num_chars(150);
 
         // Convert results
         let mut results = Vec::new();
@@ -245,11 +493,26 @@ impl EmailIndexer {
                 .and_then(|v| v.as_i64())
                 .unwrap_or(0);
 
+            let listener = retrieved_doc
+                .get_first(self.fields.listener)
+                .and_then(|v| v.as_str())
+                .map(str::to_string);
+
+            let has_attachment = retrieved_doc
+                .get_first(self.fields.has_attachment)
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+
             let date = DateTime::from_timestamp(date_timestamp, 0)
                 .unwrap_or_else(|| Utc::now());
 
-            // Create snippet from body
-            let snippet = Self::create_snippet(&body, &query.query, 150);
+            // Highlight matched terms; messages matched on other fields
+            // show the start of their body
+            let snippet = snippets.snippet(&body);
+            let snippet = match snippet.is_empty() {
+                true => html_escape(&Self::create_snippet(&body, "", 150)),
+                false => snippet.to_html(),
+            };
 
             results.push(SearchResult {
                 message_id,
@@ -258,7 +521,9 @@ impl EmailIndexer {
                 date,
                 folder,
                 snippet,
+                has_attachment,
                 score,
+                listener,
             });
         }
 
@@ -268,9 +533,20 @@ impl EmailIndexer {
             results,
             total,
             query_time_ms,
+            facets: SearchFacets {
+                folders: facet_values(facet_counts.get("/folder")),
+                senders: facet_values(facet_counts.top_k("/sender", SENDER_FACETS)),
+            },
         })
     }
 
+    /// Query matching `text` as a phrase in the fields of `parser`
+    fn phrase_query(&self, parser: &QueryParser, text: &str) -> Result<Box<dyn Query>> {
+        parser
+            .parse_query(&format!("\"{}\"", text.replace('"', "")))
+            .map_err(|e| anyhow!("Query parse error: {}", e))
+    }
+
     /// Create a search snippet with highlighted terms
     fn create_snippet(body: &str, query: &str, max_len: usize) -> String {
         let body_lower = body.to_lowercase();
@@ -292,8 +568,15 @@ impl EmailIndexer {
             0
         };
 
-        let end = std::cmp::min(start + max_len, body.len());
-        let end = body[..end].rfind(' ').unwrap_or(end);
+        let mut end = std::cmp::min(start + max_len, body.len());
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        // Cut at a word boundary unless the rest of the body fits
+        let end = match end < body.len() {
+            true => body[..end].rfind(' ').filter(|&space| space > start).unwrap_or(end),
+            false => end,
+        };
 
         let mut snippet = String::new();
         if start > 0 {
@@ -320,28 +603,36 @@ impl EmailIndexer {
     }
 
     /// Re-index all emails for a user from their mailbox
-    pub async fn reindex_mailbox(&self, mailbox_path: &Path, owner_email: &str) -> Result<u64> {
+    ///
+    /// `mailbox_path` is the user's Maildir: INBOX in its `new/` and `cur/`
+    /// directories, other folders in `.Folder` subdirectories.
+    pub async fn reindex_mailbox(
+        &self,
+        mailbox_path: &Path,
+        owner_email: &str,
+        attachments: &AttachmentIndexConfig,
+    ) -> Result<u64> {
         let mut indexed = 0u64;
 
-        // Walk through mailbox directories
         if !mailbox_path.exists() {
             return Ok(0);
         }
 
+        let mut folders = vec![("INBOX".to_string(), mailbox_path.to_path_buf())];
         for folder_entry in std::fs::read_dir(mailbox_path)? {
             let folder_entry = folder_entry?;
-            let folder_path = folder_entry.path();
-
-            if !folder_path.is_dir() {
-                continue;
-            }
-
             let folder_name = folder_entry.file_name().to_string_lossy().to_string();
+            if let Some(folder) = folder_name.strip_prefix('.') {
+                if !folder.is_empty() && folder_entry.path().is_dir() {
+                    folders.push((folder.to_string(), folder_entry.path()));
+                }
+            }
+        }
 
-            // Look for email files (typically in cur/ or new/ subdirectories)
-            for subdir in &["cur", "new", "."] {
+        for (folder_name, folder_path) in folders {
+            for subdir in &["cur", "new"] {
                 let mail_dir = folder_path.join(subdir);
-                if !mail_dir.exists() || !mail_dir.is_dir() {
+                if !mail_dir.is_dir() {
                     continue;
                 }
 
@@ -353,48 +644,16 @@ impl EmailIndexer {
                         continue;
                     }
 
-                    // Try to parse and index the email
-                    if let Ok(content) = std::fs::read(&mail_path) {
-                        if let Some(parsed) = mail_parser::MessageParser::default().parse(&content) {
-                            let message_id = mail_path.file_name()
-                                .map(|n| n.to_string_lossy().to_string())
-                                .unwrap_or_default();
-
-                            let from = parsed.from()
-                                .and_then(|f| f.first())
-                                .map(|a| a.address().map(|s| s.to_string()).unwrap_or_default())
-                                .unwrap_or_default();
-
-                            let to = parsed.to()
-                                .and_then(|t| t.first())
-                                .map(|a| a.address().map(|s| s.to_string()).unwrap_or_default())
-                                .unwrap_or_default();
-
-                            let subject = parsed.subject().unwrap_or("").to_string();
-
-                            let body = parsed.body_text(0)
-                                .map(|b| b.to_string())
-                                .unwrap_or_default();
-
-                            let date = parsed.date()
-                                .map(|d| DateTime::from_timestamp(d.to_timestamp(), 0).unwrap_or_else(|| Utc::now()))
-                                .unwrap_or_else(|| Utc::now());
-
-                            if let Err(e) = self.index_email(
-                                &message_id,
-                                owner_email,
-                                &folder_name,
-                                &from,
-                                &to,
-                                &subject,
-                                &body,
-                                date,
-                            ).await {
-                                tracing::warn!("Failed to index email {}: {}", message_id, e);
-                            } else {
-                                indexed += 1;
-                            }
-                        }
+                    // Messages are indexed under their Maildir base name,
+                    // which stays the same when flags change
+                    let file_name = mail_entry.file_name().to_string_lossy().to_string();
+                    let message_id = base_name(&file_name);
+                    let Ok(content) = std::fs::read(&mail_path) else {
+                        continue;
+                    };
+                    match self.index_message(message_id, owner_email, &folder_name, &content, attachments).await {
+                        Ok(()) => indexed += 1,
+                        Err(e) => tracing::warn!("Failed to index email {}: {}", message_id, e),
                     }
                 }
             }
@@ -406,3 +665,215 @@ impl EmailIndexer {
         Ok(indexed)
     }
 }
+
+/// Facet values with their counts, most matches first
+fn facet_values<'a>(counts: impl IntoIterator<Item = (&'a Facet, u64)>) -> Vec<FacetCount> {
+    let mut values: Vec<FacetCount> = counts
+        .into_iter()
+        .filter_map(|(facet, count)| {
+            let value = facet.to_path().last()?.to_string();
+            Some(FacetCount { value, count })
+        })
+        .collect();
+    values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    values
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::ingress::IngressListener;
+
+    #[tokio::test]
+    async fn test_listener_filter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let indexer = EmailIndexer::new(dir.path()).unwrap();
+        let ingress = Ingress {
+            listener: IngressListener::Submission,
+            protocol: "ESMTPSA".to_string(),
+            tls: None,
+            auth: Some("alice@example.com".to_string()),
+            ip: None,
+        };
+        for (id, ingress) in [("1", Some(&ingress)), ("2", None)] {
+            indexer
+                .index_email(id, "bob@example.com", "INBOX", "alice@example.com", "bob@example.com", "Report", "Quarterly report", Utc::now(), ingress)
+                .await
+                .unwrap();
+        }
+        indexer.commit().await.unwrap();
+        indexer.reader.reload().unwrap();
+
+        let search = |listener: Option<&str>| SearchQuery {
+            query: "report".to_string(),
+            folder: None,
+            listener: listener.map(str::to_string),
+            from_date: None,
+            to_date: None,
+            limit: None,
+            offset: None,
+        };
+        let results = indexer.search("bob@example.com", search(None)).await.unwrap();
+        assert_eq!(results.total, 2);
+
+        let results = indexer
+            .search("bob@example.com", search(Some("Submission")))
+            .await
+            .unwrap();
+        assert_eq!(results.total, 1);
+        assert_eq!(results.results[0].message_id, "1");
+        assert_eq!(results.results[0].listener.as_deref(), Some("submission"));
+
+        let results = indexer.search("bob@example.com", search(Some("mx"))).await.unwrap();
+        assert_eq!(results.total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_words_in_pdf_attachments_are_found() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let indexer = EmailIndexer::new(dir.path()).unwrap();
+        let pdf = b"%PDF-1.4\n4 0 obj\n<< /Length 31 >>\nstream\nBT (Confidential roadmap) Tj ET\nendstream\nendobj\n";
+        let message = crate::mime::MessageBuilder::new()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .subject("Plans")
+            .text("See attached")
+            .attachment("plans.pdf", "application/pdf", pdf.to_vec())
+            .build();
+
+        let search = |query: &str| SearchQuery {
+            query: query.to_string(),
+            folder: None,
+            listener: None,
+            from_date: None,
+            to_date: None,
+            limit: None,
+            offset: None,
+        };
+        for (id, pdf_enabled) in [("1", true), ("2", false)] {
+            let config = AttachmentIndexConfig {
+                pdf: pdf_enabled,
+                ..Default::default()
+            };
+            indexer
+                .index_message(id, "bob@example.com", "INBOX", &message, &config)
+                .await
+                .unwrap();
+        }
+        indexer.commit().await.unwrap();
+        indexer.reader.reload().unwrap();
+
+        let results = indexer.search("bob@example.com", search("roadmap")).await.unwrap();
+        assert_eq!(results.total, 1);
+        assert_eq!(results.results[0].message_id, "1");
+        assert_eq!(results.results[0].subject, "Plans");
+        let results = indexer.search("bob@example.com", search("attached")).await.unwrap();
+        assert_eq!(results.total, 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_operators_facets_and_snippets() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let indexer = EmailIndexer::new(dir.path()).unwrap();
+        let date = |day: &str| format!("{}T10:00:00Z", day).parse::<DateTime<Utc>>().unwrap();
+        let messages = [
+            ("1", "INBOX", "alice@example.com", "Budget review", "The budget for <Q3> is attached", "2024-03-10", true),
+            ("2", "INBOX", "alice@example.com", "Lunch", "Lunch on Friday? No budget talk", "2024-03-20", false),
+            ("3", "Archive", "carol@example.com", "Old budget", "Last year's budget", "2023-11-05", false),
+        ];
+        for (id, folder, from, subject, body, day, has_attachment) in messages {
+            indexer
+                .add(EmailDocument {
+                    message_id: id,
+                    owner_email: "bob@example.com",
+                    folder,
+                    from,
+                    to: "bob@example.com",
+                    subject,
+                    body,
+                    date: date(day),
+                    ingress: None,
+                    attachments: &[],
+                    has_attachment,
+                })
+                .await
+                .unwrap();
+        }
+        indexer.commit().await.unwrap();
+        indexer.reader.reload().unwrap();
+
+        let search = |query: &str| SearchQuery {
+            query: query.to_string(),
+            folder: None,
+            listener: None,
+            from_date: None,
+            to_date: None,
+            limit: Some(1),
+            offset: None,
+        };
+        let ids = |results: &SearchResults| {
+            let mut ids: Vec<String> = results.results.iter().map(|r| r.message_id.clone()).collect();
+            ids.sort();
+            ids
+        };
+
+        let results = indexer.search("bob@example.com", search("budget")).await.unwrap();
+        assert_eq!(results.total, 3);
+        assert_eq!(results.results.len(), 1);
+        assert_eq!(
+            results.facets.folders,
+            vec![
+                FacetCount { value: "INBOX".to_string(), count: 2 },
+                FacetCount { value: "Archive".to_string(), count: 1 },
+            ]
+        );
+        assert_eq!(results.facets.senders[0], FacetCount { value: "alice@example.com".to_string(), count: 2 });
+
+        let results = indexer
+            .search("bob@example.com", search("budget from:alice@example.com has:attachment"))
+            .await
+            .unwrap();
+        assert_eq!(ids(&results), vec!["1"]);
+        assert!(results.results[0].has_attachment);
+        assert_eq!(results.results[0].snippet, "The <b>budget</b> for &lt;Q3&gt; is attached");
+
+        for (query, expected) in [
+            ("subject:budget in:archive", vec![]),
+            ("subject:budget folder:Archive", vec!["3"]),
+            ("budget after:2024-03-15", vec!["2"]),
+            ("budget before:2024/01/01", vec!["3"]),
+            ("budget -lunch in:inbox", vec!["1"]),
+            ("subject:\"budget review\"", vec!["1"]),
+        ] {
+            let results = indexer
+                .search("bob@example.com", SearchQuery { limit: None, ..search(query) })
+                .await
+                .unwrap();
+            assert_eq!(ids(&results), expected, "{}", query);
+        }
+
+        // Matched on the subject only: the body is shown as is
+        let results = indexer.search("bob@example.com", search("subject:lunch")).await.unwrap();
+        assert_eq!(results.results[0].snippet, "Lunch on Friday? No budget talk");
+        assert!(indexer.search("bob@example.com", search("after:soon")).await.is_err());
+    }
+
+    #[test]
+    fn test_outdated_index_is_recreated() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut builder = Schema::builder();
+        builder.add_text_field("message_id", STRING | STORED);
+        Index::create_in_dir(dir.path(), builder.build()).unwrap();
+
+        let indexer = EmailIndexer::new(dir.path()).unwrap();
+        assert_eq!(indexer.index.schema(), EmailIndexer::build_schema().0);
+    }
+}
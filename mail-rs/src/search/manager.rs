@@ -10,13 +10,17 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use super::indexer::EmailIndexer;
+use super::queue::IndexJob;
 use super::types::*;
+use crate::config::AttachmentIndexConfig;
+use crate::imap::uid::base_name;
+use crate::smtp::ingress::Ingress;
 
 /// Search manager configuration
 pub struct SearchConfig {
     /// Path to the search index directory
     pub index_path: PathBuf,
-    /// Path to the mailbox root directory
+    /// Path to the Maildir root, holding one directory per user address
     pub mailbox_path: PathBuf,
 }
 
@@ -35,6 +39,9 @@ pub struct SearchManager {
     config: SearchConfig,
     is_indexing: Arc<AtomicBool>,
     last_indexed_at: Arc<RwLock<Option<chrono::DateTime<Utc>>>>,
+    /// Attachment types whose text is indexed, applied with the server
+    /// configuration
+    attachments: std::sync::RwLock<AttachmentIndexConfig>,
 }
 
 impl SearchManager {
@@ -50,9 +57,23 @@ impl SearchManager {
             config,
             is_indexing: Arc::new(AtomicBool::new(false)),
             last_indexed_at: Arc::new(RwLock::new(None)),
+            attachments: std::sync::RwLock::new(AttachmentIndexConfig::default()),
         }
     }
 
+    /// Extract and index the text of these attachment types from now on;
+    /// messages indexed before keep theirs until they are reindexed
+    pub fn set_attachment_config(&self, config: AttachmentIndexConfig) {
+        *self.attachments.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+
+    fn attachment_config(&self) -> AttachmentIndexConfig {
+        self.attachments
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
     /// Initialize the search index
     pub async fn init(&self) -> Result<()> {
         let indexer = EmailIndexer::new(&self.config.index_path)?;
@@ -71,6 +92,7 @@ impl SearchManager {
                 results: vec![],
                 total: 0,
                 query_time_ms: 0,
+                facets: SearchFacets::default(),
             })
         }
     }
@@ -86,10 +108,11 @@ impl SearchManager {
         subject: &str,
         body: &str,
         date: chrono::DateTime<Utc>,
+        ingress: Option<&Ingress>,
     ) -> Result<()> {
         let guard = self.indexer.read().await;
         if let Some(indexer) = guard.as_ref() {
-            indexer.index_email(message_id, owner_email, folder, from, to, subject, body, date).await?;
+            indexer.index_email(message_id, owner_email, folder, from, to, subject, body, date, ingress).await?;
             indexer.commit().await?;
         }
         Ok(())
@@ -105,7 +128,8 @@ impl SearchManager {
         Ok(())
     }
 
-    /// Re-index all emails for a user
+    /// Rebuild the index of a user from their mailbox, dropping documents
+    /// of messages that are gone
     pub async fn reindex_user(&self, email: &str) -> Result<u64> {
         if self.is_indexing.load(Ordering::SeqCst) {
             return Err(anyhow::anyhow!("Indexing already in progress"));
@@ -116,11 +140,10 @@ impl SearchManager {
         let result = async {
             let guard = self.indexer.read().await;
             if let Some(indexer) = guard.as_ref() {
-                // Construct mailbox path for user
-                let local_part = email.split('@').next().unwrap_or(email);
-                let mailbox_path = self.config.mailbox_path.join(local_part);
+                let mailbox_path = crate::storage::maildir::user_path(&self.config.mailbox_path, email)?;
 
-                let count = indexer.reindex_mailbox(&mailbox_path, email).await?;
+                indexer.remove_owner(email).await?;
+                let count = indexer.reindex_mailbox(&mailbox_path, email, &self.attachment_config()).await?;
 
                 let mut last_indexed = self.last_indexed_at.write().await;
                 *last_indexed = Some(Utc::now());
@@ -136,6 +159,45 @@ impl SearchManager {
         result
     }
 
+    /// Apply queued changes with a single commit; returns how many
+    /// messages were indexed or removed
+    pub async fn apply(&self, jobs: Vec<IndexJob>) -> Result<usize> {
+        let guard = self.indexer.read().await;
+        let Some(indexer) = guard.as_ref() else {
+            return Ok(0);
+        };
+
+        let attachments = self.attachment_config();
+        let mut applied = 0;
+        for job in jobs {
+            match job {
+                IndexJob::Index { user, folder, path, if_missing } => {
+                    let Some(message) = path.file_name().map(|name| name.to_string_lossy().to_string()) else {
+                        continue;
+                    };
+                    let message = base_name(&message);
+                    if if_missing && indexer.contains(&user, &folder, message)? {
+                        continue;
+                    }
+                    // Gone again by now; its removal is queued too
+                    let Ok(content) = tokio::fs::read(&path).await else {
+                        continue;
+                    };
+                    match indexer.index_message(message, &user, &folder, &content, &attachments).await {
+                        Ok(()) => applied += 1,
+                        Err(e) => tracing::warn!("Failed to index {} of {}: {}", path.display(), user, e),
+                    }
+                }
+                IndexJob::Remove { user, folder, message } => {
+                    indexer.remove_message(&user, &folder, &message).await?;
+                    applied += 1;
+                }
+            }
+        }
+        indexer.commit().await?;
+        Ok(applied)
+    }
+
     /// Re-index all users
     pub async fn reindex_all(&self) -> Result<u64> {
         if self.is_indexing.load(Ordering::SeqCst) {
@@ -148,6 +210,7 @@ impl SearchManager {
             let guard = self.indexer.read().await;
             if let Some(indexer) = guard.as_ref() {
                 let mut total_indexed = 0u64;
+                let attachments = self.attachment_config();
 
                 // List all user directories in mailbox path
                 if self.config.mailbox_path.exists() {
@@ -156,14 +219,15 @@ impl SearchManager {
                         let path = entry.path();
 
                         if path.is_dir() {
-                            let username = entry.file_name().to_string_lossy().to_string();
+                            // Mailboxes are named after the user's address
+                            let email = entry.file_name().to_string_lossy().to_string();
                             // Skip system directories
-                            if username.starts_with('.') {
+                            if email.starts_with('.') {
                                 continue;
                             }
 
-                            let email = format!("{}@localhost", username);
-                            match indexer.reindex_mailbox(&path, &email).await {
+                            indexer.remove_owner(&email).await?;
+                            match indexer.reindex_mailbox(&path, &email, &attachments).await {
                                 Ok(count) => {
                                     total_indexed += count;
                                     tracing::info!("Indexed {} emails for {}", count, email);
@@ -1,11 +1,18 @@
 //! Full-text search module
 //!
 //! Provides email content indexing and search capabilities using Tantivy.
+//! Stored and removed messages are indexed as they change, through the
+//! write-behind [`IndexQueue`].
 
+pub mod extract;
 pub mod indexer;
 pub mod manager;
+pub mod queue;
+pub mod query;
 pub mod types;
 
-pub use indexer::EmailIndexer;
+pub use indexer::{EmailDocument, EmailIndexer};
 pub use manager::{SearchConfig, SearchManager};
+pub use query::ParsedQuery;
+pub use queue::{IndexJob, IndexQueue};
 pub use types::*;
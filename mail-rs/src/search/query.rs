@@ -0,0 +1,162 @@
+//! Search query language
+//!
+//! Queries follow the syntax of webmail search boxes:
+//!
+//! - `word`, `"exact phrase"`: match the subject, body, addresses and
+//!   attachment text; `-word` excludes messages containing it
+//! - `from:`, `to:`, `subject:`: match one field, e.g. `subject:"Q3 report"`
+//! - `folder:` (or `in:`): only messages of one folder
+//! - `has:attachment`: only messages with attachments
+//! - `after:`, `before:`: only messages sent on or after, or before, a day
+//!   given as `YYYY-MM-DD` or `YYYY/MM/DD`
+//!
+//! Every criterion must match. Unknown operators are searched as words.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// A parsed search query
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedQuery {
+    /// Words and phrases to match anywhere
+    pub text: Vec<String>,
+    /// Words and phrases messages must not contain
+    pub excluded: Vec<String>,
+    pub from: Vec<String>,
+    pub to: Vec<String>,
+    pub subject: Vec<String>,
+    pub folder: Option<String>,
+    pub has_attachment: bool,
+    /// Sent at or after
+    pub after: Option<DateTime<Utc>>,
+    /// Sent before
+    pub before: Option<DateTime<Utc>>,
+}
+
+impl ParsedQuery {
+    /// Parse a query; fails on malformed dates and `has:` values other
+    /// than `attachment`
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut query = Self::default();
+        for token in tokens(input) {
+            if token.starts_with('"') {
+                push(&mut query.text, &token);
+                continue;
+            }
+            if let Some(excluded) = token.strip_prefix('-').filter(|rest| !rest.is_empty()) {
+                push(&mut query.excluded, excluded);
+                continue;
+            }
+            let Some((operator, value)) = token.split_once(':') else {
+                push(&mut query.text, &token);
+                continue;
+            };
+            match operator.to_lowercase().as_str() {
+                "from" => push(&mut query.from, value),
+                "to" => push(&mut query.to, value),
+                "subject" => push(&mut query.subject, value),
+                "folder" | "in" => {
+                    let folder = unquote(value);
+                    query.folder = match folder.eq_ignore_ascii_case("inbox") {
+                        true => Some("INBOX".to_string()),
+                        false => Some(folder.to_string()).filter(|f| !f.is_empty()),
+                    };
+                }
+                "has" => match unquote(value).to_lowercase().as_str() {
+                    "attachment" | "attachments" => query.has_attachment = true,
+                    other => return Err(anyhow!("Unsupported has:{}", other)),
+                },
+                "after" => query.after = Some(day(value)?),
+                "before" => query.before = Some(day(value)?),
+                _ => push(&mut query.text, &token),
+            }
+        }
+        Ok(query)
+    }
+}
+
+/// Split on whitespace outside double quotes, keeping the quotes
+fn tokens(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim_matches('"').trim()
+}
+
+/// Add a value unless nothing in it can be searched for
+fn push(values: &mut Vec<String>, value: &str) {
+    let value = unquote(value);
+    if value.chars().any(char::is_alphanumeric) {
+        values.push(value.replace('"', ""));
+    }
+}
+
+/// Start of a day, in UTC
+fn day(value: &str) -> Result<DateTime<Utc>> {
+    let value = unquote(value);
+    ["%Y-%m-%d", "%Y/%m/%d"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc())
+        .ok_or_else(|| anyhow!("Invalid date {:?}, expected YYYY-MM-DD", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_operators() {
+        let query = ParsedQuery::parse(
+            r#"invoice "due date" -draft from:alice@example.com subject:"Q3 report" in:inbox has:attachment after:2024-01-01 before:2024/02/01 label:x"#,
+        )
+        .unwrap();
+        assert_eq!(query.text, vec!["invoice", "due date", "label:x"]);
+        assert_eq!(query.excluded, vec!["draft"]);
+        assert_eq!(query.from, vec!["alice@example.com"]);
+        assert_eq!(query.subject, vec!["Q3 report"]);
+        assert_eq!(query.folder.as_deref(), Some("INBOX"));
+        assert!(query.has_attachment);
+        assert_eq!(
+            query.after.unwrap().to_rfc3339(),
+            "2024-01-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            query.before.unwrap().to_rfc3339(),
+            "2024-02-01T00:00:00+00:00"
+        );
+        assert!(query.to.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors_and_empty_values() {
+        assert!(ParsedQuery::parse("before:yesterday").is_err());
+        assert!(ParsedQuery::parse("has:star").is_err());
+        assert_eq!(
+            ParsedQuery::parse("  - \"\" from: ").unwrap(),
+            ParsedQuery::default()
+        );
+    }
+}
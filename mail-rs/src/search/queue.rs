@@ -0,0 +1,301 @@
+//! Write-behind queue keeping the search index in step with the mailboxes
+//!
+//! Storing, moving and deleting a message through [`MaildirStorage`] only
+//! queues a job, so deliveries never wait for the index. A worker applies
+//! the queued jobs in batches, with one commit per batch. Messages that
+//! IMAP and the other frontends append or expunge reach the queue through
+//! the [`FlagEventBus`]. When the queue is full, jobs are dropped with a
+//! warning; rebuilding the user's index catches up.
+//!
+//! Given the server's pools, every batch takes a slot of the indexing pool
+//! in [`crate::workers`], so it waits while the server is read-only.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::manager::SearchManager;
+use crate::storage::metadata::{find_file, folder_path};
+use crate::storage::{FlagEventBus, MaildirStorage};
+use crate::workers::{Subsystem, WorkerPools};
+
+/// Jobs applied with one commit
+const MAX_BATCH: usize = 256;
+
+/// A change to apply to the search index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexJob {
+    /// Index the message file at `path`, in `folder` of `user`'s mailbox
+    Index {
+        user: String,
+        folder: String,
+        path: PathBuf,
+        /// Leave the message alone if it is indexed already, e.g. after a
+        /// flag change
+        if_missing: bool,
+    },
+    /// Remove a message, by its Maildir base name
+    Remove {
+        user: String,
+        folder: String,
+        message: String,
+    },
+}
+
+/// Queue of index changes, shared by the storage and the flag event bus
+pub struct IndexQueue {
+    sender: mpsc::Sender<IndexJob>,
+    search: Arc<OnceLock<Arc<SearchManager>>>,
+    workers: Arc<OnceLock<Arc<WorkerPools>>>,
+    dropped: AtomicU64,
+}
+
+impl IndexQueue {
+    /// Start the worker applying up to `capacity` queued jobs
+    pub fn start(capacity: usize) -> Arc<Self> {
+        let (sender, mut receiver) = mpsc::channel::<IndexJob>(capacity.max(1));
+        let search: Arc<OnceLock<Arc<SearchManager>>> = Arc::new(OnceLock::new());
+        let workers: Arc<OnceLock<Arc<WorkerPools>>> = Arc::new(OnceLock::new());
+
+        let index = search.clone();
+        let pools = workers.clone();
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                let mut jobs = vec![job];
+                while jobs.len() < MAX_BATCH {
+                    match receiver.try_recv() {
+                        Ok(job) => jobs.push(job),
+                        Err(_) => break,
+                    }
+                }
+                // Without an index there is nothing to keep up to date
+                let Some(search) = index.get() else {
+                    continue;
+                };
+                let applied = match pools.get() {
+                    Some(pools) => {
+                        pools
+                            .pool(Subsystem::Indexing)
+                            .run(search.apply(jobs))
+                            .await
+                    }
+                    None => search.apply(jobs).await,
+                };
+                match applied {
+                    Ok(applied) => debug!("Applied {} search index changes", applied),
+                    Err(e) => warn!("Failed to update search index: {}", e),
+                }
+            }
+        });
+
+        Arc::new(Self {
+            sender,
+            search,
+            workers,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Apply batches in the indexing pool of `workers`; only the first
+    /// pools given are used
+    pub fn run_in(&self, workers: Arc<WorkerPools>) {
+        if self.workers.set(workers).is_err() {
+            debug!("Search index queue already runs in worker pools");
+        }
+    }
+
+    /// Apply the queued jobs to the search index of the API
+    ///
+    /// The index has a single writer, so the queue uses the API's instead
+    /// of opening its own; only the first index given is used.
+    pub fn index_into(&self, search: Arc<SearchManager>) {
+        if self.search.set(search).is_err() {
+            debug!("Search index queue already indexes into a search index");
+        }
+    }
+
+    /// Queue a job without waiting; dropped if the queue is full
+    pub fn push(&self, job: IndexJob) {
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(job)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                warn!("Search index queue is full, dropping {:?}", job);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                warn!("Search index worker stopped")
+            }
+        }
+    }
+
+    /// Jobs waiting for the worker
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Jobs dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Queue the messages appended and expunged by other frontends, as
+    /// published on `bus`
+    pub fn follow(
+        self: &Arc<Self>,
+        bus: &FlagEventBus,
+        storage: Arc<MaildirStorage>,
+    ) -> JoinHandle<()> {
+        let queue = self.clone();
+        let mut events = bus.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.expunged => queue.push(IndexJob::Remove {
+                        user: event.user,
+                        folder: event.mailbox,
+                        message: event.message,
+                    }),
+                    Ok(event) => {
+                        let root = storage.root_for(&event.user);
+                        let folder = folder_path(&root, &event.user, &event.mailbox);
+                        if let Some(path) = find_file(&folder, &event.message).await {
+                            queue.push(IndexJob::Index {
+                                user: event.user,
+                                folder: event.mailbox,
+                                path,
+                                if_missing: true,
+                            });
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!(
+                        "Search index skipped {} changes; rebuild the index of affected users",
+                        skipped
+                    ),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WorkersConfig;
+    use crate::recovery::{ReadOnlyMode, Trigger};
+    use crate::search::{SearchConfig, SearchQuery};
+    use crate::storage::Storage;
+    use std::time::Duration;
+
+    async fn total(search: &SearchManager, user: &str, folder: Option<&str>) -> usize {
+        search
+            .search(
+                user,
+                SearchQuery {
+                    query: "invoice".to_string(),
+                    folder: folder.map(str::to_string),
+                    listener: None,
+                    from_date: None,
+                    to_date: None,
+                    limit: None,
+                    offset: None,
+                },
+            )
+            .await
+            .unwrap()
+            .total
+    }
+
+    async fn wait_for(search: &SearchManager, user: &str, folder: Option<&str>, expected: usize) {
+        for _ in 0..200 {
+            if total(search, user, folder).await == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!(
+            "Expected {} results, got {}",
+            expected,
+            total(search, user, folder).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_storage_changes_reach_the_index() {
+        let maildir = tempfile::tempdir().unwrap();
+        let index = tempfile::tempdir().unwrap();
+        let search = Arc::new(SearchManager::with_config(SearchConfig {
+            index_path: index.path().to_path_buf(),
+            mailbox_path: maildir.path().to_path_buf(),
+        }));
+        search.init().await.unwrap();
+        let queue = IndexQueue::start(100);
+        queue.index_into(search.clone());
+        let storage = Arc::new(
+            MaildirStorage::new(maildir.path().display().to_string()).with_search(queue.clone()),
+        );
+        let user = "bob@example.com";
+        let message = b"From: alice@example.com\r\nSubject: Invoice\r\n\r\nInvoice attached\r\n";
+
+        let id = storage.store(user, message).await.unwrap();
+        storage.store(user, message).await.unwrap();
+        wait_for(&search, user, None, 2).await;
+
+        assert!(storage
+            .move_message(user, None, &id, "Archive")
+            .await
+            .unwrap());
+        wait_for(&search, user, Some("Archive"), 1).await;
+        wait_for(&search, user, Some("INBOX"), 1).await;
+
+        Storage::delete_message(&*storage, user, &format!(".Archive/new/{}", id))
+            .await
+            .unwrap();
+        wait_for(&search, user, None, 1).await;
+
+        // Files removed behind the server's back drop out on a rebuild
+        for entry in std::fs::read_dir(maildir.path().join(user).join("new")).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        assert_eq!(search.reindex_user(user).await.unwrap(), 0);
+        wait_for(&search, user, None, 0).await;
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_indexing_pauses_while_read_only() {
+        let maildir = tempfile::tempdir().unwrap();
+        let index = tempfile::tempdir().unwrap();
+        let search = Arc::new(SearchManager::with_config(SearchConfig {
+            index_path: index.path().to_path_buf(),
+            mailbox_path: maildir.path().to_path_buf(),
+        }));
+        search.init().await.unwrap();
+        let mode = Arc::new(ReadOnlyMode::from_config(&Default::default()));
+        mode.enter("maintenance", Trigger::Admin);
+        let workers = Arc::new(WorkerPools::pausing_writers(
+            &WorkersConfig::default(),
+            mode.clone(),
+        ));
+        let queue = IndexQueue::start(100);
+        queue.index_into(search.clone());
+        queue.run_in(workers.clone());
+        let storage = Arc::new(
+            MaildirStorage::new(maildir.path().display().to_string()).with_search(queue.clone()),
+        );
+        let user = "bob@example.com";
+        let message = b"From: alice@example.com\r\nSubject: Invoice\r\n\r\nInvoice attached\r\n";
+
+        storage.store(user, message).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(total(&search, user, None).await, 0);
+        assert_eq!(workers.pool(Subsystem::Indexing).stats().waiting, 1);
+
+        mode.clear();
+        wait_for(&search, user, None, 1).await;
+        assert_eq!(workers.pool(Subsystem::Indexing).stats().completed, 1);
+    }
+}
@@ -6,10 +6,12 @@ use serde::{Deserialize, Serialize};
 /// Search query parameters
 #[derive(Debug, Clone, Deserialize)]
 pub struct SearchQuery {
-    /// The search query string
+    /// The search query, see [`super::query`] for the syntax
     pub query: String,
     /// Folder to search in (None = all folders)
     pub folder: Option<String>,
+    /// Listener the messages arrived on, e.g. `mx` (None = any)
+    pub listener: Option<String>,
     /// Date range start
     pub from_date: Option<DateTime<Utc>>,
     /// Date range end
@@ -33,10 +35,14 @@ pub struct SearchResult {
     pub date: DateTime<Utc>,
     /// Folder containing the email
     pub folder: String,
-    /// Highlighted snippet from body
+    /// Snippet of the body as HTML, matched terms in `<b>` tags
     pub snippet: String,
+    /// Whether the message has attachments
+    pub has_attachment: bool,
     /// Relevance score
     pub score: f32,
+    /// Listener the message arrived on, if known
+    pub listener: Option<String>,
 }
 
 /// Search results response
@@ -48,6 +54,24 @@ pub struct SearchResults {
     pub total: usize,
     /// Query time in milliseconds
     pub query_time_ms: u64,
+    /// Matches by folder and sender
+    pub facets: SearchFacets,
+}
+
+/// Match counts of all results, not just the returned page
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchFacets {
+    /// Every folder with matches, most matches first
+    pub folders: Vec<FacetCount>,
+    /// Senders with the most matches
+    pub senders: Vec<FacetCount>,
+}
+
+/// Number of matches sharing a value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64,
 }
 
 /// Index status
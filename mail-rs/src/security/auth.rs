@@ -5,10 +5,15 @@
 //! # Supported mechanisms
 //! - PLAIN (RFC 4616)
 //! - LOGIN (common but not standardized)
+//! - CRAM-MD5 (RFC 2195), only with [`Authenticator::with_cram_md5`]
+//! - SCRAM-SHA-256 (RFC 7677)
+//! - OAUTHBEARER (RFC 7628) and XOAUTH2, see [`crate::security::oauth`]
 //!
 //! # Security
 //! - Passwords hashed with Argon2
-//! - AUTH only allowed after STARTTLS
+//! - PLAIN/LOGIN only allowed after STARTTLS
+//! - Challenge-response credentials derived when the password is set, and
+//!   on the next password login for accounts created without them
 //! - Rate limiting on failed attempts
 //!
 //! # Usage
@@ -32,12 +37,14 @@
 //! ```
 
 use crate::error::{MailError, Result};
+use crate::security::sasl::{self, CramMd5Secret, ScramCredentials};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use sqlx::SqlitePool;
+use std::future::Future;
 use std::sync::Arc;
 use tracing::{debug, info, warn};
 
@@ -48,6 +55,14 @@ pub enum AuthMechanism {
     Plain,
     /// LOGIN mechanism
     Login,
+    /// CRAM-MD5 challenge-response (RFC 2195)
+    CramMd5,
+    /// SCRAM-SHA-256 (RFC 7677)
+    ScramSha256,
+    /// OAuth 2.0 bearer token (RFC 7628)
+    OAuthBearer,
+    /// OAuth 2.0 bearer token, Google/Microsoft format
+    XOAuth2,
 }
 
 impl AuthMechanism {
@@ -56,6 +71,10 @@ impl AuthMechanism {
         match s.to_uppercase().as_str() {
             "PLAIN" => Some(Self::Plain),
             "LOGIN" => Some(Self::Login),
+            "CRAM-MD5" => Some(Self::CramMd5),
+            "SCRAM-SHA-256" => Some(Self::ScramSha256),
+            "OAUTHBEARER" => Some(Self::OAuthBearer),
+            "XOAUTH2" => Some(Self::XOAuth2),
             _ => None,
         }
     }
@@ -65,14 +84,67 @@ impl AuthMechanism {
         match self {
             Self::Plain => "PLAIN",
             Self::Login => "LOGIN",
+            Self::CramMd5 => "CRAM-MD5",
+            Self::ScramSha256 => "SCRAM-SHA-256",
+            Self::OAuthBearer => "OAUTHBEARER",
+            Self::XOAuth2 => "XOAUTH2",
         }
     }
+
+    /// True if the client sends a reusable secret (password or bearer
+    /// token) and the mechanism must only be used over TLS
+    pub fn requires_tls(&self) -> bool {
+        !matches!(self, Self::CramMd5 | Self::ScramSha256)
+    }
+
+    /// True for OAuth bearer token mechanisms
+    pub fn is_oauth(&self) -> bool {
+        matches!(self, Self::OAuthBearer | Self::XOAuth2)
+    }
+}
+
+/// User account and credential backend
+///
+/// Implemented by the SQLite-backed [`Authenticator`] and, with the
+/// `testing` feature, by `mail_rs::testing::InMemoryAuthenticator`.
+pub trait AuthBackend: Send + Sync {
+    /// Create a user with the given password
+    fn add_user(&self, email: &str, password: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Delete a user
+    fn delete_user(&self, email: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Check whether a user exists
+    fn user_exists(&self, email: &str) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Email addresses of all users, sorted
+    fn usernames(&self) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// Verify a username and password (PLAIN, LOGIN, IMAP LOGIN)
+    fn authenticate(&self, username: &str, password: &str)
+        -> impl Future<Output = Result<bool>> + Send;
+
+    /// Stored SCRAM-SHA-256 credentials of a user
+    fn get_scram_credentials(
+        &self,
+        email: &str,
+    ) -> impl Future<Output = Result<Option<ScramCredentials>>> + Send;
+
+    /// Verify a CRAM-MD5 response to the given challenge
+    fn authenticate_cram_md5(
+        &self,
+        username: &str,
+        challenge: &str,
+        digest_hex: &str,
+    ) -> impl Future<Output = Result<bool>> + Send;
 }
 
 /// SMTP authenticator
 #[derive(Clone)]
 pub struct Authenticator {
     pub db: Arc<SqlitePool>,
+    /// Accept CRAM-MD5 and keep the password-equivalent secret it needs
+    cram_md5: bool,
 }
 
 impl Authenticator {
@@ -107,7 +179,54 @@ impl Authenticator {
         .execute(&db)
         .await?;
 
-        Ok(Self { db: Arc::new(db) })
+        // Challenge-response credentials (SCRAM/CRAM-MD5), derived when
+        // the password is set since they cannot come from the Argon2 hash
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS smtp_credentials (
+                email TEXT PRIMARY KEY,
+                scram_sha256 TEXT,
+                cram_md5_secret TEXT
+            )
+            "#,
+        )
+        .execute(&db)
+        .await?;
+
+        // Earlier versions kept the CRAM-MD5 secret as the encoded
+        // password; drop those, users get precomputed pads on their next
+        // password login
+        let legacy = sqlx::query(
+            "UPDATE smtp_credentials SET cram_md5_secret = NULL WHERE cram_md5_secret NOT LIKE '{CRAM-MD5}%'",
+        )
+        .execute(&db)
+        .await?;
+        if legacy.rows_affected() > 0 {
+            warn!(
+                "Removed {} stored CRAM-MD5 passwords; CRAM-MD5 works again for those users after their next password login",
+                legacy.rows_affected()
+            );
+        }
+
+        Ok(Self {
+            db: Arc::new(db),
+            cram_md5: false,
+        })
+    }
+
+    /// Enable the legacy CRAM-MD5 mechanism
+    ///
+    /// CRAM-MD5 needs the HMAC-MD5 pads of the password, a secret that is
+    /// only stored while the mechanism is enabled. Secrets are added and removed on the
+    /// user's next password login after the setting changes.
+    pub fn with_cram_md5(mut self, enabled: bool) -> Self {
+        self.cram_md5 = enabled;
+        self
+    }
+
+    /// Whether CRAM-MD5 is accepted
+    pub fn cram_md5_enabled(&self) -> bool {
+        self.cram_md5
     }
 
     /// Add a new user
@@ -131,6 +250,8 @@ impl Authenticator {
         .execute(&*self.db)
         .await?;
 
+        self.store_challenge_credentials(email, password).await?;
+
         info!("User added: {}", email);
         Ok(())
     }
@@ -151,6 +272,148 @@ impl Authenticator {
         self.authenticate(username, password).await
     }
 
+    /// Derive and store SCRAM-SHA-256 and, if enabled, CRAM-MD5 credentials
+    /// for a user
+    pub async fn store_challenge_credentials(&self, email: &str, password: &str) -> Result<()> {
+        let scram = ScramCredentials::new(password).encode();
+        let cram_md5_secret = self.cram_md5.then(|| CramMd5Secret::new(password).encode());
+
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO smtp_credentials (email, scram_sha256, cram_md5_secret)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(email)
+        .bind(&scram)
+        .bind(cram_md5_secret)
+        .execute(&*self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Bring a user's challenge-response credentials up to date after a
+    /// successful password login
+    ///
+    /// This is the migration path for accounts created before the
+    /// credentials existed, SCRAM credentials derived with fewer iterations
+    /// than [`sasl::SCRAM_ITERATIONS`], and changes of the CRAM-MD5 setting.
+    /// Returns whether anything was rewritten.
+    pub async fn upgrade_challenge_credentials(&self, email: &str, password: &str) -> Result<bool> {
+        let row = sqlx::query_as::<_, (Option<String>, Option<String>)>(
+            "SELECT scram_sha256, cram_md5_secret FROM smtp_credentials WHERE email = ?",
+        )
+        .bind(email)
+        .fetch_optional(&*self.db)
+        .await?;
+        let (scram, cram_md5_secret) = row.unwrap_or((None, None));
+
+        let scram_current = scram
+            .and_then(|scram| ScramCredentials::decode(&scram).ok())
+            .is_some_and(|scram| scram.iterations >= sasl::SCRAM_ITERATIONS);
+        if scram_current && cram_md5_secret.is_some() == self.cram_md5 {
+            return Ok(false);
+        }
+
+        info!("Updating challenge-response credentials of {}", email);
+        self.store_challenge_credentials(email, password).await?;
+        Ok(true)
+    }
+
+    /// Users who can't use SCRAM-SHA-256 (or CRAM-MD5, if enabled) until
+    /// they log in with their password or get a new one
+    pub async fn users_missing_challenge_credentials(&self) -> Result<Vec<String>> {
+        let users = sqlx::query_as::<_, (String,)>(
+            r#"
+            SELECT u.email FROM smtp_users u
+            LEFT JOIN smtp_credentials c ON c.email = u.email
+            WHERE c.scram_sha256 IS NULL OR (? AND c.cram_md5_secret IS NULL)
+            ORDER BY u.email
+            "#,
+        )
+        .bind(self.cram_md5)
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(users.into_iter().map(|(email,)| email).collect())
+    }
+
+    /// Get stored SCRAM-SHA-256 credentials for a user
+    pub async fn get_scram_credentials(&self, email: &str) -> Result<Option<ScramCredentials>> {
+        let row = sqlx::query_as::<_, (Option<String>,)>(
+            r#"
+            SELECT c.scram_sha256 FROM smtp_credentials c
+            JOIN smtp_users u ON u.email = c.email
+            WHERE c.email = ?
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(&*self.db)
+        .await?;
+
+        row.and_then(|(scram,)| scram)
+            .map(|scram| ScramCredentials::decode(&scram))
+            .transpose()
+    }
+
+    /// Verify a CRAM-MD5 response to the given challenge
+    pub async fn authenticate_cram_md5(
+        &self,
+        username: &str,
+        challenge: &str,
+        digest_hex: &str,
+    ) -> Result<bool> {
+        debug!("CRAM-MD5 authentication attempt for {}", username);
+        if !self.cram_md5 {
+            warn!("CRAM-MD5 refused for {}: mechanism disabled", username);
+            return Ok(false);
+        }
+
+        let row = sqlx::query_as::<_, (Option<String>,)>(
+            r#"
+            SELECT c.cram_md5_secret FROM smtp_credentials c
+            JOIN smtp_users u ON u.email = c.email
+            WHERE c.email = ?
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&*self.db)
+        .await?;
+
+        let Some(secret) = row.and_then(|(secret,)| secret) else {
+            warn!("CRAM-MD5 failed: no credentials for {}", username);
+            return Ok(false);
+        };
+
+        let secret = CramMd5Secret::decode(&secret).map_err(|_| MailError::AuthenticationFailed)?;
+
+        if secret.verify(challenge, digest_hex) {
+            info!("Authentication successful for {}", username);
+            self.record_login(username).await?;
+            Ok(true)
+        } else {
+            warn!("CRAM-MD5 failed: invalid digest for {}", username);
+            Ok(false)
+        }
+    }
+
+    /// Update the last login timestamp for a user
+    pub async fn record_login(&self, email: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE smtp_users
+            SET last_login = datetime('now')
+            WHERE email = ?
+            "#,
+        )
+        .bind(email)
+        .execute(&*self.db)
+        .await?;
+
+        Ok(())
+    }
+
     /// Decode PLAIN authentication data
     ///
     /// Format: `\0username\0password` (base64 encoded)
@@ -215,18 +478,28 @@ impl Authenticator {
     }
 
     /// Delete user
+    ///
+    /// The account and its challenge-response credentials are removed in one
+    /// transaction so SCRAM and CRAM-MD5 logins stop working with it.
     pub async fn delete_user(&self, email: &str) -> Result<()> {
         info!("Deleting user: {}", email);
 
+        let mut tx = self.db.begin().await?;
         sqlx::query(
             r#"
             DELETE FROM smtp_users WHERE email = ?
             "#,
         )
         .bind(email)
-        .execute(&*self.db)
+        .execute(&mut *tx)
         .await?;
 
+        sqlx::query("DELETE FROM smtp_credentials WHERE email = ?")
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
         Ok(())
     }
 
@@ -286,15 +559,31 @@ impl Authenticator {
     pub async fn delete_user_by_id(&self, rowid: i32) -> Result<()> {
         info!("Deleting user by ID: {}", rowid);
 
+        let mut tx = self.db.begin().await?;
+        let email: Option<(String,)> =
+            sqlx::query_as("SELECT email FROM smtp_users WHERE rowid = ?")
+                .bind(rowid)
+                .fetch_optional(&mut *tx)
+                .await?;
+        let Some((email,)) = email else {
+            return Ok(());
+        };
+
         sqlx::query(
             r#"
             DELETE FROM smtp_users WHERE rowid = ?
             "#,
         )
         .bind(rowid)
-        .execute(&*self.db)
+        .execute(&mut *tx)
         .await?;
 
+        sqlx::query("DELETE FROM smtp_credentials WHERE email = ?")
+            .bind(&email)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
         Ok(())
     }
 
@@ -330,16 +619,8 @@ impl Authenticator {
             info!("Authentication successful for {}", email);
 
             // Update last login
-            sqlx::query(
-                r#"
-                UPDATE smtp_users
-                SET last_login = datetime('now')
-                WHERE email = ?
-                "#,
-            )
-            .bind(&email)
-            .execute(&*self.db)
-            .await?;
+            self.record_login(&email).await?;
+            self.upgrade_challenge_credentials(&email, password).await?;
 
             Ok(true)
         } else {
@@ -368,6 +649,48 @@ impl Authenticator {
     }
 }
 
+impl AuthBackend for Authenticator {
+    async fn add_user(&self, email: &str, password: &str) -> Result<()> {
+        Authenticator::add_user(self, email, password).await
+    }
+
+    async fn delete_user(&self, email: &str) -> Result<()> {
+        Authenticator::delete_user(self, email).await
+    }
+
+    async fn user_exists(&self, email: &str) -> Result<bool> {
+        Authenticator::user_exists(self, email).await
+    }
+
+    async fn usernames(&self) -> Result<Vec<String>> {
+        let mut emails: Vec<String> = self
+            .list_users_detailed()
+            .await?
+            .into_iter()
+            .map(|(email, _, _)| email)
+            .collect();
+        emails.sort();
+        Ok(emails)
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool> {
+        Authenticator::authenticate(self, username, password).await
+    }
+
+    async fn get_scram_credentials(&self, email: &str) -> Result<Option<ScramCredentials>> {
+        Authenticator::get_scram_credentials(self, email).await
+    }
+
+    async fn authenticate_cram_md5(
+        &self,
+        username: &str,
+        challenge: &str,
+        digest_hex: &str,
+    ) -> Result<bool> {
+        Authenticator::authenticate_cram_md5(self, username, challenge, digest_hex).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -422,6 +745,182 @@ mod tests {
         assert!(!auth.user_exists("test@example.com").await.unwrap());
     }
 
+    /// Run a full SCRAM-SHA-256 exchange against the stored credentials
+    async fn scram_login(auth: &Authenticator, email: &str, password: &str) -> bool {
+        let client_first_bare = format!("n={},r=clientnonce", email);
+        let mut server =
+            sasl::ScramServer::parse_client_first(&format!("n,,{}", client_first_bare)).unwrap();
+        let server_first = server.server_first(auth.get_scram_credentials(email).await.unwrap());
+        let client_final =
+            sasl::tests::client_final(password, &client_first_bare, &server_first);
+        server.verify_client_final(&client_final).unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn test_deleted_user_cannot_use_challenge_response() {
+        let auth = Authenticator::new("sqlite::memory:")
+            .await
+            .unwrap()
+            .with_cram_md5(true);
+        auth.add_user("gone@example.com", "password123")
+            .await
+            .unwrap();
+        let challenge = "<123.456@mail.example.com>";
+        let digest = sasl::cram_md5_digest(b"password123", challenge);
+
+        // Same path as the web admin: rowid from the listing, then delete by id
+        let (rowid, _, _) = auth
+            .list_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|(_, email, _)| email == "gone@example.com")
+            .unwrap();
+        auth.delete_user_by_id(rowid).await.unwrap();
+
+        assert!(!scram_login(&auth, "gone@example.com", "password123").await);
+        assert!(!auth
+            .authenticate_cram_md5("gone@example.com", challenge, &digest)
+            .await
+            .unwrap());
+
+        // A credentials row left without its account logs nobody in
+        auth.add_user("gone@example.com", "password123")
+            .await
+            .unwrap();
+        assert!(scram_login(&auth, "gone@example.com", "password123").await);
+        sqlx::query("DELETE FROM smtp_users WHERE email = ?")
+            .bind("gone@example.com")
+            .execute(&*auth.db)
+            .await
+            .unwrap();
+        assert!(!scram_login(&auth, "gone@example.com", "password123").await);
+        assert!(!auth
+            .authenticate_cram_md5("gone@example.com", challenge, &digest)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_challenge_credentials_stored_on_add_user() {
+        let auth = Authenticator::new("sqlite::memory:")
+            .await
+            .unwrap()
+            .with_cram_md5(true);
+        auth.add_user("test@example.com", "password123")
+            .await
+            .unwrap();
+
+        let scram = auth.get_scram_credentials("test@example.com").await.unwrap();
+        assert!(scram.is_some());
+
+        let challenge = "<123.456@mail.example.com>";
+        let digest = sasl::cram_md5_digest(b"password123", challenge);
+        assert!(auth
+            .authenticate_cram_md5("test@example.com", challenge, &digest)
+            .await
+            .unwrap());
+        assert!(!auth
+            .authenticate_cram_md5("test@example.com", challenge, "00")
+            .await
+            .unwrap());
+
+        // Only the HMAC pads are stored, never the password
+        let (secret,): (String,) =
+            sqlx::query_as("SELECT cram_md5_secret FROM smtp_credentials WHERE email = ?")
+                .bind("test@example.com")
+                .fetch_one(&*auth.db)
+                .await
+                .unwrap();
+        assert!(secret.starts_with("{CRAM-MD5}"));
+        assert!(!secret.contains(&BASE64.encode("password123")));
+
+        auth.delete_user("test@example.com").await.unwrap();
+        assert!(auth.get_scram_credentials("test@example.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_challenge_credentials_migration() {
+        let auth = Authenticator::new("sqlite::memory:").await.unwrap();
+        let challenge = "<123.456@mail.example.com>";
+        let digest = sasl::cram_md5_digest(b"password123", challenge);
+
+        // Account from before challenge-response credentials existed
+        auth.add_user("old@example.com", "password123").await.unwrap();
+        sqlx::query("DELETE FROM smtp_credentials")
+            .execute(&*auth.db)
+            .await
+            .unwrap();
+        assert_eq!(
+            auth.users_missing_challenge_credentials().await.unwrap(),
+            vec!["old@example.com"]
+        );
+
+        assert!(auth.authenticate("old@example.com", "password123").await.unwrap());
+        assert!(auth.get_scram_credentials("old@example.com").await.unwrap().is_some());
+        assert!(!auth
+            .upgrade_challenge_credentials("old@example.com", "password123")
+            .await
+            .unwrap());
+
+        // CRAM-MD5 is off by default and its secret is not kept
+        assert!(!auth
+            .authenticate_cram_md5("old@example.com", challenge, &digest)
+            .await
+            .unwrap());
+
+        let legacy = auth.clone().with_cram_md5(true);
+        assert!(!legacy
+            .authenticate_cram_md5("old@example.com", challenge, &digest)
+            .await
+            .unwrap());
+        assert_eq!(
+            legacy.users_missing_challenge_credentials().await.unwrap(),
+            vec!["old@example.com"]
+        );
+        assert!(legacy.authenticate("old@example.com", "password123").await.unwrap());
+        assert!(legacy
+            .authenticate_cram_md5("old@example.com", challenge, &digest)
+            .await
+            .unwrap());
+
+        // Disabling it again drops the secret on the next login
+        assert!(auth
+            .upgrade_challenge_credentials("old@example.com", "password123")
+            .await
+            .unwrap());
+        assert!(!legacy
+            .authenticate_cram_md5("old@example.com", challenge, &digest)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stored_cram_md5_passwords_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("auth.db").display());
+        let auth = Authenticator::new(&url).await.unwrap().with_cram_md5(true);
+        auth.add_user("old@example.com", "password123").await.unwrap();
+        sqlx::query("UPDATE smtp_credentials SET cram_md5_secret = ?")
+            .bind(BASE64.encode("password123"))
+            .execute(&*auth.db)
+            .await
+            .unwrap();
+
+        let auth = Authenticator::new(&url).await.unwrap().with_cram_md5(true);
+        assert_eq!(
+            auth.users_missing_challenge_credentials().await.unwrap(),
+            vec!["old@example.com"]
+        );
+        let challenge = "<123.456@mail.example.com>";
+        let digest = sasl::cram_md5_digest(b"password123", challenge);
+        assert!(auth.authenticate("old@example.com", "password123").await.unwrap());
+        assert!(auth
+            .authenticate_cram_md5("old@example.com", challenge, &digest)
+            .await
+            .unwrap());
+    }
+
     #[test]
     fn test_decode_plain_auth() {
         // \0username\0password encoded in base64
@@ -444,6 +943,14 @@ mod tests {
         assert_eq!(AuthMechanism::from_str("PLAIN"), Some(AuthMechanism::Plain));
         assert_eq!(AuthMechanism::from_str("plain"), Some(AuthMechanism::Plain));
         assert_eq!(AuthMechanism::from_str("LOGIN"), Some(AuthMechanism::Login));
+        assert_eq!(AuthMechanism::from_str("cram-md5"), Some(AuthMechanism::CramMd5));
+        assert_eq!(
+            AuthMechanism::from_str("SCRAM-SHA-256"),
+            Some(AuthMechanism::ScramSha256)
+        );
+        assert_eq!(AuthMechanism::from_str("xoauth2"), Some(AuthMechanism::XOAuth2));
+        assert!(AuthMechanism::OAuthBearer.requires_tls());
+        assert!(!AuthMechanism::ScramSha256.requires_tls());
         assert_eq!(AuthMechanism::from_str("UNKNOWN"), None);
     }
 }
@@ -0,0 +1,484 @@
+//! Bandwidth throttling for mailbox downloads
+//!
+//! A single client syncing a large mailbox can saturate the uplink for
+//! everyone else. [`BandwidthLimiter`] holds token buckets for the whole
+//! server, for each client IP and for each user; every connection draws
+//! from all buckets that apply to it, so the tightest limit wins.
+//!
+//! IMAP connections write through a [`ThrottledWriter`], and API downloads
+//! stream their body through [`Throttle::acquire`]. Buckets let a client
+//! burst one second's worth of traffic, then hold it to the configured
+//! rate. Every throttled connection is listed by
+//! [`BandwidthLimiter::sessions`] with its current throughput.
+
+use crate::config::BandwidthConfig;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::time::Sleep;
+
+/// Largest write passed through at once, so waits stay short and smooth
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Seconds over which current throughput is averaged
+const THROUGHPUT_WINDOW_SECS: u64 = 5;
+
+/// Token bucket refilled at `rate` bytes per second
+///
+/// Consuming may overdraw the bucket; the caller then waits until the
+/// debt is paid back, which keeps the long-run rate exact for writes of
+/// any size.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    /// Available bytes (negative while in debt) and time of last refill
+    state: Mutex<(f64, tokio::time::Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate as f64, tokio::time::Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket and return how long to wait before
+    /// sending more
+    fn consume(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = &mut *state;
+        let now = tokio::time::Instant::now();
+        let capacity = self.rate as f64;
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * capacity).min(capacity);
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / capacity)
+        }
+    }
+}
+
+/// Bytes sent per second over the last few seconds
+#[derive(Debug, Default)]
+struct Meter {
+    total: AtomicU64,
+    /// (second since start, bytes) for the last seconds with traffic
+    recent: Mutex<VecDeque<(u64, u64)>>,
+}
+
+impl Meter {
+    fn record(&self, started: Instant, bytes: u64) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        let second = started.elapsed().as_secs();
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        match recent.back_mut() {
+            Some((last, sum)) if *last == second => *sum += bytes,
+            _ => recent.push_back((second, bytes)),
+        }
+        while recent
+            .front()
+            .is_some_and(|(first, _)| *first + THROUGHPUT_WINDOW_SECS <= second)
+        {
+            recent.pop_front();
+        }
+    }
+
+    fn bytes_per_sec(&self, started: Instant) -> u64 {
+        let now = started.elapsed().as_secs();
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let bytes: u64 = recent
+            .iter()
+            .filter(|(second, _)| second + THROUGHPUT_WINDOW_SECS > now)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes / THROUGHPUT_WINDOW_SECS
+    }
+}
+
+/// Configured limits in bytes per second, 0 meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BandwidthLimits {
+    pub global: u64,
+    pub per_ip: u64,
+    pub per_user: u64,
+}
+
+impl From<&BandwidthConfig> for BandwidthLimits {
+    fn from(config: &BandwidthConfig) -> Self {
+        Self {
+            global: config.global_bytes_per_sec,
+            per_ip: config.per_ip_bytes_per_sec,
+            per_user: config.per_user_bytes_per_sec,
+        }
+    }
+}
+
+/// A throttled connection as listed in the sessions admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    /// `imap` or `api`
+    pub protocol: &'static str,
+    pub ip: Option<IpAddr>,
+    /// Authenticated user, once known
+    pub user: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub bytes_sent: u64,
+    /// Average over the last few seconds
+    pub bytes_per_sec: u64,
+}
+
+/// Server-wide throughput next to the throttled connections
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthStatus {
+    pub limits: BandwidthLimits,
+    /// Sum of all connections' current throughput
+    pub bytes_per_sec: u64,
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// Buckets shared by every connection they apply to
+pub struct BandwidthLimiter {
+    limits: BandwidthLimits,
+    global: Option<TokenBucket>,
+    per_ip: Mutex<HashMap<IpAddr, Weak<TokenBucket>>>,
+    per_user: Mutex<HashMap<String, Weak<TokenBucket>>>,
+    sessions: Mutex<HashMap<u64, Weak<Throttle>>>,
+    next_id: AtomicU64,
+}
+
+impl BandwidthLimiter {
+    /// Create a limiter; limits of 0 are not enforced, but connections are
+    /// still metered
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Self {
+            limits,
+            global: (limits.global > 0).then(|| TokenBucket::new(limits.global)),
+            per_ip: Mutex::new(HashMap::new()),
+            per_user: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Create a limiter from the `[bandwidth]` section
+    pub fn from_config(config: &BandwidthConfig) -> Self {
+        Self::new(config.into())
+    }
+
+    /// Configured limits
+    pub fn limits(&self) -> BandwidthLimits {
+        self.limits
+    }
+
+    /// Start throttling a new connection from `ip`
+    ///
+    /// The connection is listed until the returned handle is dropped.
+    pub fn connect(self: &Arc<Self>, protocol: &'static str, ip: Option<IpAddr>) -> Arc<Throttle> {
+        let ip_bucket = match ip {
+            Some(ip) if self.limits.per_ip > 0 => {
+                Some(shared_bucket(&self.per_ip, ip, self.limits.per_ip))
+            }
+            _ => None,
+        };
+        let throttle = Arc::new(Throttle {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            limiter: Arc::clone(self),
+            protocol,
+            ip,
+            ip_bucket,
+            user: Mutex::new(None),
+            started: Instant::now(),
+            started_at: Utc::now(),
+            meter: Meter::default(),
+        });
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, session| session.strong_count() > 0);
+        sessions.insert(throttle.id, Arc::downgrade(&throttle));
+        throttle
+    }
+
+    /// Throttled connections and their current throughput
+    pub fn status(&self) -> BandwidthStatus {
+        let sessions = self.sessions();
+        BandwidthStatus {
+            limits: self.limits,
+            bytes_per_sec: sessions.iter().map(|session| session.bytes_per_sec).sum(),
+            sessions,
+        }
+    }
+
+    /// Throttled connections, oldest first
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        // Upgrade under the lock, report outside it: dropping the last
+        // reference unregisters the throttle, which takes the lock again
+        let throttles: Vec<Arc<Throttle>> = self
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut sessions: Vec<SessionInfo> =
+            throttles.iter().map(|throttle| throttle.info()).collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    fn unregister(&self, id: u64) {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&id);
+    }
+}
+
+/// Bucket of `key`, shared with the other live connections of the same key
+fn shared_bucket<K: std::hash::Hash + Eq>(
+    buckets: &Mutex<HashMap<K, Weak<TokenBucket>>>,
+    key: K,
+    rate: u64,
+) -> Arc<TokenBucket> {
+    let mut buckets = buckets.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(bucket) = buckets.get(&key).and_then(Weak::upgrade) {
+        return bucket;
+    }
+    buckets.retain(|_, bucket| bucket.strong_count() > 0);
+    let bucket = Arc::new(TokenBucket::new(rate));
+    buckets.insert(key, Arc::downgrade(&bucket));
+    bucket
+}
+
+/// Throttling state of one connection
+pub struct Throttle {
+    id: u64,
+    limiter: Arc<BandwidthLimiter>,
+    protocol: &'static str,
+    ip: Option<IpAddr>,
+    ip_bucket: Option<Arc<TokenBucket>>,
+    user: Mutex<Option<(String, Option<Arc<TokenBucket>>)>>,
+    started: Instant,
+    started_at: DateTime<Utc>,
+    meter: Meter,
+}
+
+impl Throttle {
+    /// Apply the per-user limit of `user` from now on
+    pub fn set_user(&self, user: &str) {
+        let mut current = self.user.lock().unwrap_or_else(|e| e.into_inner());
+        if current.as_ref().is_some_and(|(name, _)| name == user) {
+            return;
+        }
+        let limits = self.limiter.limits;
+        let bucket = (limits.per_user > 0)
+            .then(|| shared_bucket(&self.limiter.per_user, user.to_string(), limits.per_user));
+        *current = Some((user.to_string(), bucket));
+    }
+
+    /// Check whether a user was set
+    pub fn has_user(&self) -> bool {
+        self.user
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    /// Count `bytes` as sent and return how long to wait before sending
+    /// more
+    pub fn consume(&self, bytes: usize) -> Duration {
+        let bytes = bytes as u64;
+        self.meter.record(self.started, bytes);
+
+        let user_bucket = self
+            .user
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .and_then(|(_, bucket)| bucket.clone());
+        [
+            self.limiter.global.as_ref(),
+            self.ip_bucket.as_deref(),
+            user_bucket.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|bucket| bucket.consume(bytes))
+        .max()
+        .unwrap_or(Duration::ZERO)
+    }
+
+    /// Wait until `bytes` may be sent
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = self.consume(bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Current state as listed in the sessions admin API
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            protocol: self.protocol,
+            ip: self.ip,
+            user: self
+                .user
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .as_ref()
+                .map(|(name, _)| name.clone()),
+            started_at: self.started_at,
+            bytes_sent: self.meter.total.load(Ordering::Relaxed),
+            bytes_per_sec: self.meter.bytes_per_sec(self.started),
+        }
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.limiter.unregister(self.id);
+    }
+}
+
+/// Writer that holds its connection to the bandwidth limits
+///
+/// Without a throttle it passes writes straight through.
+pub struct ThrottledWriter<W> {
+    inner: W,
+    throttle: Option<Arc<Throttle>>,
+    /// Wait owed for the previous write
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> ThrottledWriter<W> {
+    pub fn new(inner: W, throttle: Option<Arc<Throttle>>) -> Self {
+        Self {
+            inner,
+            throttle,
+            delay: None,
+        }
+    }
+
+    /// Throttle of the connection, if limited
+    pub fn throttle(&self) -> Option<&Arc<Throttle>> {
+        self.throttle.as_ref()
+    }
+
+    /// Unwrap the writer, ending the connection's throttling
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(throttle) = &this.throttle else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        if let Some(delay) = &mut this.delay {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.delay = None;
+        }
+
+        let len = buf.len().min(CHUNK_SIZE);
+        let written = match Pin::new(&mut this.inner).poll_write(cx, &buf[..len]) {
+            Poll::Ready(Ok(written)) => written,
+            other => return other,
+        };
+        let wait = throttle.consume(written);
+        if !wait.is_zero() {
+            this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_token_bucket_debt() {
+        let bucket = TokenBucket::new(1000);
+        // One second of burst is free, the rest has to be waited for
+        assert_eq!(bucket.consume(1000), Duration::ZERO);
+        let wait = bucket.consume(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_limits_are_shared_and_reported() {
+        let limiter = Arc::new(BandwidthLimiter::new(BandwidthLimits {
+            global: 0,
+            per_ip: 0,
+            per_user: 1000,
+        }));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = limiter.connect("imap", Some(ip));
+        let second = limiter.connect("api", Some(ip));
+        first.set_user("alice@example.com");
+        second.set_user("alice@example.com");
+
+        // Both connections of the user draw from the same bucket
+        assert_eq!(first.consume(600), Duration::ZERO);
+        assert!(second.consume(600) > Duration::ZERO);
+
+        let status = limiter.status();
+        assert_eq!(status.sessions.len(), 2);
+        assert_eq!(status.sessions[0].bytes_sent, 600);
+        assert_eq!(
+            status.sessions[1].user.as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(status.bytes_per_sec, 1200 / THROUGHPUT_WINDOW_SECS);
+
+        drop(first);
+        assert_eq!(limiter.sessions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_writer_holds_rate() {
+        let limiter = Arc::new(BandwidthLimiter::new(BandwidthLimits {
+            global: 10_000,
+            per_ip: 0,
+            per_user: 0,
+        }));
+        let mut writer = ThrottledWriter::new(Vec::new(), Some(limiter.connect("imap", None)));
+
+        let started = tokio::time::Instant::now();
+        writer.write_all(&[0u8; 15_000]).await.unwrap();
+        writer.write_all(b"x").await.unwrap();
+        // 10 KB burst, then 5 KB at 10 KB/s
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!(writer.inner.len(), 15_001);
+    }
+}
@@ -1,14 +1,26 @@
 //! Security module
 //!
 //! Provides authentication, rate limiting, and TLS functionality:
-//! - [`auth`]: SMTP authentication mechanisms (LOGIN, PLAIN)
+//! - [`auth`]: SMTP authentication mechanisms (LOGIN, PLAIN, CRAM-MD5, SCRAM-SHA-256)
+//! - [`bandwidth`]: Download bandwidth limits per server, IP and user
+//! - [`oauth`]: OAuth 2.0 bearer token validation (OAUTHBEARER, XOAUTH2)
+//! - [`proxy_protocol`]: PROXY protocol v1/v2 header parsing
 //! - [`rate_limit`]: Connection and request rate limiting
+//! - [`sasl`]: Challenge-response SASL mechanisms (CRAM-MD5, SCRAM-SHA-256)
 //! - [`tls`]: TLS/STARTTLS configuration and handling
 
 pub mod auth;
+pub mod bandwidth;
+pub mod oauth;
+pub mod proxy_protocol;
 pub mod rate_limit;
+pub mod sasl;
 pub mod tls;
 
-pub use auth::{AuthMechanism, Authenticator};
+pub use auth::{AuthBackend, AuthMechanism, Authenticator};
+pub use bandwidth::{BandwidthLimiter, Throttle, ThrottledWriter};
+pub use oauth::OAuthValidator;
+pub use proxy_protocol::ProxyHeader;
 pub use rate_limit::{RateLimit, RateLimiter};
+pub use sasl::{ScramCredentials, ScramServer};
 pub use tls::TlsConfig;
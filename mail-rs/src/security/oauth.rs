@@ -0,0 +1,374 @@
+//! OAuth 2.0 bearer token authentication
+//!
+//! Supports the two SASL mechanisms used by modern mail clients:
+//! - OAUTHBEARER (RFC 7628)
+//! - XOAUTH2 (Google/Microsoft legacy format)
+//!
+//! Tokens are JWTs validated against the issuer's JWKS: signature, expiry,
+//! issuer and (optionally) audience. The mailbox user is taken from a
+//! configurable claim (`email` by default).
+//!
+//! # Configuration
+//! ```toml
+//! [oauth]
+//! enabled = true
+//! issuer = "https://accounts.example.com"
+//! jwks_url = "https://accounts.example.com/.well-known/jwks.json"
+//! audience = "mail.example.com"
+//! ```
+
+use crate::authentication::TtlCache;
+use crate::config::OAuthConfig;
+use crate::error::{MailError, Result};
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+/// Error challenge sent to the client when a token is rejected (RFC 7628)
+pub const OAUTHBEARER_ERROR: &str = r#"{"status":"invalid_token","schemes":"bearer"}"#;
+
+/// Shortest time between two JWKS fetches, whatever tokens clients send
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(60);
+
+/// Most key ids remembered as unknown
+const UNKNOWN_KIDS_CAPACITY: usize = 10_000;
+
+/// Validates bearer tokens against an issuer's JWKS
+///
+/// Tokens signed with a key id the JWKS lacks trigger a refetch, at most
+/// once per [`JWKS_MIN_REFETCH`]; the unknown id is then refused without
+/// fetching again for that long.
+pub struct OAuthValidator {
+    config: OAuthConfig,
+    jwks: RwLock<Option<(JwkSet, Instant)>>,
+    /// Time of the last fetch attempt, held while fetching
+    last_fetch: Mutex<Option<Instant>>,
+    unknown_kids: TtlCache<String, ()>,
+}
+
+impl OAuthValidator {
+    /// Create a validator that fetches keys from `config.jwks_url`
+    pub fn new(config: OAuthConfig) -> Self {
+        Self {
+            config,
+            jwks: RwLock::new(None),
+            last_fetch: Mutex::new(None),
+            unknown_kids: TtlCache::new(JWKS_MIN_REFETCH, UNKNOWN_KIDS_CAPACITY),
+        }
+    }
+
+    /// Create a validator with a fixed key set (no fetching)
+    pub fn with_jwks(config: OAuthConfig, jwks: JwkSet) -> Self {
+        Self {
+            config,
+            jwks: RwLock::new(Some((jwks, Instant::now()))),
+            last_fetch: Mutex::new(None),
+            unknown_kids: TtlCache::new(JWKS_MIN_REFETCH, UNKNOWN_KIDS_CAPACITY),
+        }
+    }
+
+    /// Validate a token and return the mailbox user it grants access to
+    pub async fn validate(&self, token: &str) -> Result<String> {
+        let header = decode_header(token).map_err(|_| MailError::AuthenticationFailed)?;
+        let kid = header.kid.ok_or(MailError::AuthenticationFailed)?;
+
+        let jwk = self.find_key(&kid).await?;
+        if !algorithm_matches_key(header.alg, &jwk) {
+            warn!("OAuth token algorithm {:?} does not match key {}", header.alg, kid);
+            return Err(MailError::AuthenticationFailed);
+        }
+
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| MailError::AuthenticationFailed)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = decode::<HashMap<String, Value>>(token, &key, &validation)
+            .map_err(|e| {
+                debug!("OAuth token rejected: {}", e);
+                MailError::AuthenticationFailed
+            })?
+            .claims;
+
+        claims
+            .get(&self.config.username_claim)
+            .and_then(Value::as_str)
+            .map(|user| user.to_string())
+            .ok_or(MailError::AuthenticationFailed)
+    }
+
+    /// Validate a token and check it belongs to the user the client claimed
+    pub async fn authenticate(&self, claimed_user: Option<&str>, token: &str) -> Result<String> {
+        let user = self.validate(token).await?;
+
+        match claimed_user {
+            Some(claimed) if !claimed.eq_ignore_ascii_case(&user) => {
+                warn!("OAuth token for {} presented as {}", user, claimed);
+                Err(MailError::AuthenticationFailed)
+            }
+            _ => Ok(user),
+        }
+    }
+
+    /// Look up a signing key, refreshing the JWKS when stale or unknown
+    async fn find_key(&self, kid: &str) -> Result<Jwk> {
+        let refresh_after = Duration::from_secs(self.config.jwks_refresh_secs);
+
+        let cached = self.cached_key(kid).await;
+        if let Some((jwk, fetched_at)) = &cached {
+            if fetched_at.elapsed() < refresh_after || self.config.jwks_url.is_empty() {
+                return Ok(jwk.clone());
+            }
+        }
+
+        if self.config.jwks_url.is_empty() || self.unknown_kids.get(&kid.to_string()).is_some() {
+            return Err(MailError::AuthenticationFailed);
+        }
+
+        // Concurrent lookups wait for one fetch instead of starting their own
+        let mut last_fetch = self.last_fetch.lock().await;
+        if last_fetch.is_some_and(|at| at.elapsed() < JWKS_MIN_REFETCH) {
+            // Fetched meanwhile or too recently: answer from what is cached
+            if let Some((jwk, _)) = self.cached_key(kid).await.or(cached) {
+                return Ok(jwk);
+            }
+            debug!("Key {} not in JWKS, refetch throttled", kid);
+            self.unknown_kids.insert(kid.to_string(), ());
+            return Err(MailError::AuthenticationFailed);
+        }
+        *last_fetch = Some(Instant::now());
+
+        let jwks = self.fetch_jwks().await?;
+        let jwk = jwks.find(kid).cloned();
+        *self.jwks.write().await = Some((jwks, Instant::now()));
+
+        jwk.ok_or_else(|| {
+            self.unknown_kids.insert(kid.to_string(), ());
+            MailError::AuthenticationFailed
+        })
+    }
+
+    /// Key `kid` of the cached JWKS and when that JWKS was fetched
+    async fn cached_key(&self, kid: &str) -> Option<(Jwk, Instant)> {
+        let jwks = self.jwks.read().await;
+        let (jwks, fetched_at) = jwks.as_ref()?;
+        jwks.find(kid).map(|jwk| (jwk.clone(), *fetched_at))
+    }
+
+    async fn fetch_jwks(&self) -> Result<JwkSet> {
+        info!("Fetching JWKS from {}", self.config.jwks_url);
+
+        reqwest::get(&self.config.jwks_url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| MailError::Config(format!("Failed to fetch JWKS: {}", e)))?
+            .json::<JwkSet>()
+            .await
+            .map_err(|e| MailError::Config(format!("Invalid JWKS: {}", e)))
+    }
+}
+
+/// Parse an OAUTHBEARER initial response (RFC 7628)
+///
+/// Format: `n,a=user,\x01auth=Bearer <token>\x01\x01`
+///
+/// Returns the optional authorization identity and the token.
+pub fn parse_oauthbearer(data: &[u8]) -> Result<(Option<String>, String)> {
+    let invalid = || MailError::SmtpProtocol("Invalid OAUTHBEARER response".to_string());
+    let message = std::str::from_utf8(data).map_err(|_| invalid())?;
+
+    let (gs2_header, kvpairs) = message.split_once('\x01').ok_or_else(invalid)?;
+    let authzid = gs2_header
+        .split(',')
+        .find_map(|part| part.strip_prefix("a="))
+        .map(|user| user.replace("=2C", ",").replace("=3D", "="));
+
+    let token = bearer_token(kvpairs.split('\x01')).ok_or_else(invalid)?;
+    Ok((authzid, token))
+}
+
+/// Parse an XOAUTH2 initial response
+///
+/// Format: `user=<user>\x01auth=Bearer <token>\x01\x01`
+pub fn parse_xoauth2(data: &[u8]) -> Result<(String, String)> {
+    let invalid = || MailError::SmtpProtocol("Invalid XOAUTH2 response".to_string());
+    let message = std::str::from_utf8(data).map_err(|_| invalid())?;
+
+    let user = message
+        .split('\x01')
+        .find_map(|part| part.strip_prefix("user="))
+        .ok_or_else(invalid)?;
+    let token = bearer_token(message.split('\x01')).ok_or_else(invalid)?;
+
+    Ok((user.to_string(), token))
+}
+
+fn bearer_token<'a>(mut pairs: impl Iterator<Item = &'a str>) -> Option<String> {
+    pairs.find_map(|pair| {
+        let value = pair.strip_prefix("auth=")?;
+        let (scheme, token) = value.split_once(' ')?;
+        scheme
+            .eq_ignore_ascii_case("bearer")
+            .then(|| token.trim().to_string())
+    })
+}
+
+/// Prevent algorithm confusion: the token algorithm must fit the key type
+fn algorithm_matches_key(alg: Algorithm, jwk: &Jwk) -> bool {
+    use Algorithm::*;
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(alg, RS256 | RS384 | RS512 | PS256 | PS384 | PS512),
+        AlgorithmParameters::EllipticCurve(_) => matches!(alg, ES256 | ES384),
+        AlgorithmParameters::OctetKey(_) => matches!(alg, HS256 | HS384 | HS512),
+        AlgorithmParameters::OctetKeyPair(_) => matches!(alg, EdDSA),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    const SECRET: &[u8] = b"test-oauth-signing-secret";
+
+    fn config() -> OAuthConfig {
+        OAuthConfig {
+            enabled: true,
+            issuer: "https://issuer.example.com".to_string(),
+            jwks_url: String::new(),
+            audience: Some("mail.example.com".to_string()),
+            username_claim: "email".to_string(),
+            jwks_refresh_secs: 3600,
+        }
+    }
+
+    fn validator() -> OAuthValidator {
+        let jwks: JwkSet = serde_json::from_value(serde_json::json!({
+            "keys": [{
+                "kty": "oct",
+                "kid": "k1",
+                "k": URL_SAFE_NO_PAD.encode(SECRET),
+            }]
+        }))
+        .unwrap();
+        OAuthValidator::with_jwks(config(), jwks)
+    }
+
+    fn token(claims: Value, kid: &str) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn valid_claims() -> Value {
+        serde_json::json!({
+            "iss": "https://issuer.example.com",
+            "aud": "mail.example.com",
+            "exp": chrono::Utc::now().timestamp() + 600,
+            "email": "alice@example.com",
+        })
+    }
+
+    #[tokio::test]
+    async fn test_valid_token() {
+        let user = validator()
+            .authenticate(Some("alice@example.com"), &token(valid_claims(), "k1"))
+            .await
+            .unwrap();
+        assert_eq!(user, "alice@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_wrong_user_issuer_and_kid() {
+        let validator = validator();
+
+        assert!(validator
+            .authenticate(Some("bob@example.com"), &token(valid_claims(), "k1"))
+            .await
+            .is_err());
+
+        let mut claims = valid_claims();
+        claims["iss"] = "https://evil.example.com".into();
+        assert!(validator.validate(&token(claims, "k1")).await.is_err());
+
+        let mut claims = valid_claims();
+        claims["exp"] = (chrono::Utc::now().timestamp() - 3600).into();
+        assert!(validator.validate(&token(claims, "k1")).await.is_err());
+
+        assert!(validator.validate(&token(valid_claims(), "unknown")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_fetched_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Issuer serving the test key set and counting fetches
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let app = axum::Router::new().route(
+            "/jwks.json",
+            axum::routing::get(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    axum::Json(serde_json::json!({
+                        "keys": [{
+                            "kty": "oct",
+                            "kid": "k1",
+                            "k": URL_SAFE_NO_PAD.encode(SECRET),
+                        }]
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let jwks_url = format!("http://{}/jwks.json", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let validator = OAuthValidator::new(OAuthConfig {
+            jwks_url,
+            ..config()
+        });
+        for _ in 0..2 {
+            assert!(validator.validate(&token(valid_claims(), "rogue")).await.is_err());
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Other unknown ids wait for the refetch interval too
+        assert!(validator.validate(&token(valid_claims(), "other")).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Known keys are served from the set fetched for the unknown one
+        assert!(validator.validate(&token(valid_claims(), "k1")).await.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_parse_oauthbearer() {
+        let (authzid, token) =
+            parse_oauthbearer(b"n,a=alice@example.com,\x01host=mail\x01auth=Bearer abc.def\x01\x01")
+                .unwrap();
+        assert_eq!(authzid.as_deref(), Some("alice@example.com"));
+        assert_eq!(token, "abc.def");
+
+        assert!(parse_oauthbearer(b"n,,\x01\x01").is_err());
+    }
+
+    #[test]
+    fn test_parse_xoauth2() {
+        let (user, token) =
+            parse_xoauth2(b"user=alice@example.com\x01auth=Bearer abc.def\x01\x01").unwrap();
+        assert_eq!(user, "alice@example.com");
+        assert_eq!(token, "abc.def");
+    }
+}
@@ -0,0 +1,351 @@
+//! PROXY protocol v1/v2 support
+//!
+//! When mail-rs runs behind a load balancer or reverse proxy (proxy-rs,
+//! HAProxy, ...), the TCP peer address is the proxy, not the real client.
+//! The PROXY protocol prepends a small header to the connection carrying the
+//! original source and destination addresses.
+//!
+//! # Supported formats
+//! - v1: human-readable `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`
+//! - v2: binary header starting with the 12-byte signature
+//!
+//! # Security
+//! - The header is only honoured on listeners where it is explicitly enabled
+//! - Only accepted from the addresses in a non-empty `proxy_trusted_ips`;
+//!   with an empty list every PROXY header is refused
+//! - Header size is bounded (107 bytes for v1, 536 bytes for v2 addresses)
+//! - Reads are bounded by a timeout to prevent slowloris on accept
+
+use crate::error::{MailError, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+/// v2 binary signature (PROXY protocol spec, section 2.2)
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Maximum length of a v1 header including CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Maximum accepted length of the v2 address block
+const V2_MAX_ADDRESS_LENGTH: usize = 536;
+
+/// Timeout for reading the PROXY header after accept
+pub const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Addresses carried by a PROXY protocol header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Original client address (None for `UNKNOWN`/`LOCAL` connections)
+    pub source: Option<SocketAddr>,
+    /// Original destination address on the proxy
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Resolve the effective client address, falling back to the TCP peer
+    pub fn client_addr(&self, peer: SocketAddr) -> SocketAddr {
+        self.source.unwrap_or(peer)
+    }
+}
+
+/// Check whether a TCP peer is allowed to send a PROXY header
+///
+/// An empty trust list accepts no peer: a PROXY header from an untrusted
+/// client would let it claim any source address.
+pub fn is_trusted_proxy(peer: IpAddr, trusted: &[String]) -> bool {
+    trusted
+        .iter()
+        .filter_map(|entry| entry.parse::<IpAddr>().ok())
+        .any(|ip| ip == peer)
+}
+
+/// Read a PROXY protocol header (v1 or v2) from the start of a stream
+///
+/// Only the header bytes are consumed, so the stream can be handed to the
+/// protocol handler afterwards without losing client data.
+pub async fn read_proxy_header<R>(reader: &mut R) -> Result<ProxyHeader>
+where
+    R: AsyncRead + Unpin,
+{
+    timeout(PROXY_HEADER_TIMEOUT, read_header_inner(reader))
+        .await
+        .map_err(|_| MailError::Parse("Timeout reading PROXY header".to_string()))?
+}
+
+async fn read_header_inner<R>(reader: &mut R) -> Result<ProxyHeader>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 5];
+    reader.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY" {
+        read_v1(reader).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(reader).await
+    } else {
+        Err(MailError::Parse(
+            "Missing PROXY protocol header".to_string(),
+        ))
+    }
+}
+
+/// Read the remainder of a v1 header (after the `PROXY` prefix)
+async fn read_v1<R>(reader: &mut R) -> Result<ProxyHeader>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = b"PROXY".to_vec();
+
+    // Read byte-by-byte so nothing past CRLF is consumed
+    loop {
+        let byte = reader.read_u8().await?;
+        line.push(byte);
+
+        if line.ends_with(b"\r\n") {
+            break;
+        }
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(MailError::Parse("PROXY v1 header too long".to_string()));
+        }
+    }
+
+    let text = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| MailError::Parse("PROXY v1 header is not ASCII".to_string()))?;
+
+    parse_v1(text)
+}
+
+/// Parse a v1 header line (without trailing CRLF)
+pub fn parse_v1(line: &str) -> Result<ProxyHeader> {
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    if parts.first() != Some(&"PROXY") || parts.len() < 2 {
+        return Err(MailError::Parse("Invalid PROXY v1 header".to_string()));
+    }
+
+    match parts[1] {
+        "UNKNOWN" => Ok(ProxyHeader {
+            source: None,
+            destination: None,
+        }),
+        "TCP4" | "TCP6" => {
+            if parts.len() != 6 {
+                return Err(MailError::Parse(
+                    "Invalid PROXY v1 field count".to_string(),
+                ));
+            }
+
+            let parse_ip = |s: &str| -> Result<IpAddr> {
+                let ip: IpAddr = s
+                    .parse()
+                    .map_err(|_| MailError::Parse(format!("Invalid PROXY address: {}", s)))?;
+                match (parts[1], ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+                    _ => Err(MailError::Parse(format!(
+                        "Address family mismatch in PROXY header: {}",
+                        s
+                    ))),
+                }
+            };
+            let parse_port = |s: &str| -> Result<u16> {
+                s.parse()
+                    .map_err(|_| MailError::Parse(format!("Invalid PROXY port: {}", s)))
+            };
+
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(parse_ip(parts[2])?, parse_port(parts[4])?)),
+                destination: Some(SocketAddr::new(parse_ip(parts[3])?, parse_port(parts[5])?)),
+            })
+        }
+        other => Err(MailError::Parse(format!(
+            "Unsupported PROXY v1 protocol: {}",
+            other
+        ))),
+    }
+}
+
+/// Read the remainder of a v2 header (after the first 5 signature bytes)
+async fn read_v2<R>(reader: &mut R) -> Result<ProxyHeader>
+where
+    R: AsyncRead + Unpin,
+{
+    // Remaining 7 signature bytes + ver/cmd + fam/proto + 2-byte length
+    let mut rest = [0u8; 11];
+    reader.read_exact(&mut rest).await?;
+
+    if rest[..7] != V2_SIGNATURE[5..] {
+        return Err(MailError::Parse("Invalid PROXY v2 signature".to_string()));
+    }
+
+    let ver_cmd = rest[7];
+    let fam_proto = rest[8];
+    let length = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+
+    if length > V2_MAX_ADDRESS_LENGTH {
+        return Err(MailError::Parse("PROXY v2 header too long".to_string()));
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+
+    parse_v2(ver_cmd, fam_proto, &body)
+}
+
+/// Parse the v2 address block
+pub fn parse_v2(ver_cmd: u8, fam_proto: u8, body: &[u8]) -> Result<ProxyHeader> {
+    if ver_cmd >> 4 != 0x2 {
+        return Err(MailError::Parse("Unsupported PROXY v2 version".to_string()));
+    }
+
+    let unknown = ProxyHeader {
+        source: None,
+        destination: None,
+    };
+
+    match ver_cmd & 0x0F {
+        // LOCAL: health checks from the proxy itself, keep the peer address
+        0x0 => return Ok(unknown),
+        // PROXY
+        0x1 => {}
+        _ => return Err(MailError::Parse("Unsupported PROXY v2 command".to_string())),
+    }
+
+    match fam_proto >> 4 {
+        // AF_INET
+        0x1 => {
+            if body.len() < 12 {
+                return Err(MailError::Parse("Truncated PROXY v2 IPv4 block".to_string()));
+            }
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            let sport = u16::from_be_bytes([body[8], body[9]]);
+            let dport = u16::from_be_bytes([body[10], body[11]]);
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(IpAddr::V4(src), sport)),
+                destination: Some(SocketAddr::new(IpAddr::V4(dst), dport)),
+            })
+        }
+        // AF_INET6
+        0x2 => {
+            if body.len() < 36 {
+                return Err(MailError::Parse("Truncated PROXY v2 IPv6 block".to_string()));
+            }
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&body[0..16]);
+            dst.copy_from_slice(&body[16..32]);
+            let sport = u16::from_be_bytes([body[32], body[33]]);
+            let dport = u16::from_be_bytes([body[34], body[35]]);
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(src)), sport)),
+                destination: Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(dst)), dport)),
+            })
+        }
+        // AF_UNSPEC / AF_UNIX: no usable address
+        _ => Ok(unknown),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_parse_v1_tcp4() {
+        let header = parse_v1("PROXY TCP4 203.0.113.7 10.0.0.1 51234 25").unwrap();
+        assert_eq!(
+            header.source,
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        assert_eq!(header.destination, Some("10.0.0.1:25".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_v1_tcp6() {
+        let header = parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 4000 143").unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_v1_unknown() {
+        let header = parse_v1("PROXY UNKNOWN").unwrap();
+        assert!(header.source.is_none());
+
+        let peer: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+        assert_eq!(header.client_addr(peer), peer);
+    }
+
+    #[test]
+    fn test_parse_v1_rejects_family_mismatch() {
+        assert!(parse_v1("PROXY TCP4 2001:db8::1 10.0.0.1 1 2").is_err());
+        assert!(parse_v1("PROXY TCP4 1.2.3.4").is_err());
+        assert!(parse_v1("PROXY UDP4 1.2.3.4 1.2.3.5 1 2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_does_not_consume_payload() {
+        let data = b"PROXY TCP4 198.51.100.4 10.0.0.1 40000 25\r\nEHLO client\r\n".to_vec();
+        let mut reader = &data[..];
+
+        let header = read_proxy_header(&mut reader).await.unwrap();
+        assert_eq!(header.source, Some("198.51.100.4:40000".parse().unwrap()));
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "EHLO client\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_ipv4() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.push(0x21); // v2, PROXY
+        data.push(0x11); // AF_INET, STREAM
+        data.extend_from_slice(&12u16.to_be_bytes());
+        data.extend_from_slice(&[192, 0, 2, 10, 10, 0, 0, 1]);
+        data.extend_from_slice(&5000u16.to_be_bytes());
+        data.extend_from_slice(&993u16.to_be_bytes());
+        data.extend_from_slice(b"a001 CAPABILITY\r\n");
+        let mut reader = &data[..];
+
+        let header = read_proxy_header(&mut reader).await.unwrap();
+        assert_eq!(header.source, Some("192.0.2.10:5000".parse().unwrap()));
+        assert_eq!(header.destination, Some("10.0.0.1:993".parse().unwrap()));
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "a001 CAPABILITY\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_local() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.push(0x20); // v2, LOCAL
+        data.push(0x00);
+        data.extend_from_slice(&0u16.to_be_bytes());
+        let mut reader = &data[..];
+
+        let header = read_proxy_header(&mut reader).await.unwrap();
+        assert!(header.source.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_missing_header_rejected() {
+        let data = b"EHLO client\r\n".to_vec();
+        let mut reader = &data[..];
+        assert!(read_proxy_header(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn test_trusted_proxy() {
+        let proxy: IpAddr = "10.0.0.5".parse().unwrap();
+        assert!(!is_trusted_proxy(proxy, &[]));
+        assert!(is_trusted_proxy(proxy, &["10.0.0.5".to_string()]));
+        assert!(!is_trusted_proxy(proxy, &["10.0.0.6".to_string()]));
+    }
+}
@@ -0,0 +1,591 @@
+//! Challenge-response SASL mechanisms
+//!
+//! Lets clients authenticate without sending the password itself:
+//! - CRAM-MD5 (RFC 2195): HMAC-MD5 over a server challenge
+//! - SCRAM-SHA-256 (RFC 5802, RFC 7677): salted challenge-response with
+//!   mutual authentication
+//!
+//! Both need credentials derived at password-set time, because an Argon2
+//! hash cannot be used to check a challenge response. SCRAM credentials are
+//! stored in the RFC 5803 format and are not password-equivalent. The
+//! CRAM-MD5 secret is password-equivalent by design of the mechanism.
+
+use crate::error::{MailError, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+type HmacSha256 = Hmac<Sha256>;
+type HmacMd5 = Hmac<Md5>;
+
+/// PBKDF2 iteration count for newly derived SCRAM credentials
+pub const SCRAM_ITERATIONS: u32 = 4096;
+
+/// Length of random salts and server nonces, in bytes
+const RANDOM_LEN: usize = 18;
+
+/// Key the salts of unknown users are derived from, random per process
+static DUMMY_SALT_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Stored SCRAM-SHA-256 credentials for one user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /// Derive credentials from a password with a fresh random salt
+    pub fn new(password: &str) -> Self {
+        Self::derive(password, &random_bytes(RANDOM_LEN), SCRAM_ITERATIONS)
+    }
+
+    /// Derive credentials from a password, salt and iteration count
+    pub fn derive(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+        Self {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(&client_key).to_vec(),
+            server_key,
+        }
+    }
+
+    /// Serialize as `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>` (RFC 5803)
+    pub fn encode(&self) -> String {
+        format!(
+            "SCRAM-SHA-256${}:{}${}:{}",
+            self.iterations,
+            BASE64.encode(&self.salt),
+            BASE64.encode(&self.stored_key),
+            BASE64.encode(&self.server_key)
+        )
+    }
+
+    /// Parse the RFC 5803 format produced by [`ScramCredentials::encode`]
+    pub fn decode(s: &str) -> Result<Self> {
+        let invalid = || MailError::Parse("Invalid SCRAM credentials".to_string());
+
+        let rest = s.strip_prefix("SCRAM-SHA-256$").ok_or_else(invalid)?;
+        let (params, keys) = rest.split_once('$').ok_or_else(invalid)?;
+        let (iterations, salt) = params.split_once(':').ok_or_else(invalid)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            salt: BASE64.decode(salt).map_err(|_| invalid())?,
+            iterations: iterations.parse().map_err(|_| invalid())?,
+            stored_key: BASE64.decode(stored_key).map_err(|_| invalid())?,
+            server_key: BASE64.decode(server_key).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Server side of a SCRAM-SHA-256 exchange
+///
+/// 1. [`ScramServer::parse_client_first`] extracts the username
+/// 2. [`ScramServer::server_first`] answers with salt and combined nonce
+/// 3. [`ScramServer::verify_client_final`] checks the proof and returns the
+///    server-final message
+pub struct ScramServer {
+    username: String,
+    client_first_bare: String,
+    gs2_header: String,
+    nonce: String,
+    server_first: Option<String>,
+    credentials: Option<ScramCredentials>,
+}
+
+impl ScramServer {
+    /// Parse the client-first message
+    ///
+    /// Channel binding and mandatory extensions are not supported.
+    pub fn parse_client_first(message: &str) -> Result<Self> {
+        let invalid = |msg: &str| MailError::SmtpProtocol(format!("SCRAM: {}", msg));
+
+        let mut parts = message.splitn(3, ',');
+        let cbind_flag = parts.next().ok_or_else(|| invalid("empty message"))?;
+        let authzid = parts.next().ok_or_else(|| invalid("missing authzid"))?;
+        let client_first_bare = parts.next().ok_or_else(|| invalid("missing bare message"))?;
+
+        if cbind_flag.starts_with("p=") {
+            return Err(invalid("channel binding not supported"));
+        }
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return Err(invalid("invalid gs2 header"));
+        }
+
+        let mut username = None;
+        let mut client_nonce = None;
+        for attr in client_first_bare.split(',') {
+            match attr.split_once('=') {
+                Some(("n", value)) => username = Some(decode_saslname(value)?),
+                Some(("r", value)) => client_nonce = Some(value.to_string()),
+                Some(("m", _)) => return Err(invalid("unsupported extension")),
+                _ => {}
+            }
+        }
+
+        let username = username.ok_or_else(|| invalid("missing username"))?;
+        let client_nonce = client_nonce
+            .filter(|nonce| !nonce.is_empty())
+            .ok_or_else(|| invalid("missing nonce"))?;
+
+        Ok(Self {
+            username,
+            client_first_bare: client_first_bare.to_string(),
+            gs2_header: format!("{},{},", cbind_flag, authzid),
+            nonce: format!("{}{}", client_nonce, BASE64.encode(random_bytes(RANDOM_LEN))),
+            server_first: None,
+            credentials: None,
+        })
+    }
+
+    /// Username sent by the client
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Build the server-first message
+    ///
+    /// Pass `None` for unknown users: the exchange continues with random
+    /// credentials so the failure is indistinguishable from a bad password.
+    /// Their salt stays the same across attempts, like a real user's.
+    pub fn server_first(&mut self, credentials: Option<ScramCredentials>) -> String {
+        let credentials = credentials.unwrap_or_else(|| Self::dummy_credentials(&self.username));
+
+        let message = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            BASE64.encode(&credentials.salt),
+            credentials.iterations
+        );
+
+        self.server_first = Some(message.clone());
+        self.credentials = Some(credentials);
+        message
+    }
+
+    /// Verify the client-final message
+    ///
+    /// Returns the server-final message (`v=<signature>`) on success, or
+    /// `None` if the proof does not match.
+    pub fn verify_client_final(&self, message: &str) -> Result<Option<String>> {
+        let invalid = |msg: &str| MailError::SmtpProtocol(format!("SCRAM: {}", msg));
+
+        let (server_first, credentials) = match (&self.server_first, &self.credentials) {
+            (Some(server_first), Some(credentials)) => (server_first, credentials),
+            _ => return Err(invalid("client-final before server-first")),
+        };
+
+        let (without_proof, proof) = message
+            .rsplit_once(",p=")
+            .ok_or_else(|| invalid("missing proof"))?;
+
+        let mut channel_binding = None;
+        let mut nonce = None;
+        for attr in without_proof.split(',') {
+            match attr.split_once('=') {
+                Some(("c", value)) => channel_binding = Some(value),
+                Some(("r", value)) => nonce = Some(value),
+                _ => {}
+            }
+        }
+
+        if channel_binding != Some(BASE64.encode(&self.gs2_header).as_str()) {
+            return Err(invalid("channel binding mismatch"));
+        }
+        if nonce != Some(self.nonce.as_str()) {
+            return Err(invalid("nonce mismatch"));
+        }
+
+        let proof = BASE64.decode(proof).map_err(|_| invalid("invalid proof encoding"))?;
+        if proof.len() != credentials.stored_key.len() {
+            return Ok(None);
+        }
+
+        let auth_message = format!("{},{},{}", self.client_first_bare, server_first, without_proof);
+        let client_signature = hmac_sha256(&credentials.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(p, s)| p ^ s)
+            .collect();
+
+        if !constant_time_eq(&Sha256::digest(&client_key), &credentials.stored_key) {
+            return Ok(None);
+        }
+
+        let server_signature = hmac_sha256(&credentials.server_key, auth_message.as_bytes());
+        Ok(Some(format!("v={}", BASE64.encode(server_signature))))
+    }
+
+    fn dummy_credentials(username: &str) -> ScramCredentials {
+        let key = DUMMY_SALT_KEY.get_or_init(|| random_bytes(32));
+        let mut salt = hmac_sha256(key, username.as_bytes());
+        salt.truncate(RANDOM_LEN);
+        ScramCredentials {
+            salt,
+            iterations: SCRAM_ITERATIONS,
+            stored_key: random_bytes(32),
+            server_key: random_bytes(32),
+        }
+    }
+}
+
+/// Generate a CRAM-MD5 challenge (`<random.timestamp@hostname>`)
+pub fn cram_md5_challenge(hostname: &str) -> String {
+    let mut rng = rand::thread_rng();
+    format!(
+        "<{}.{}@{}>",
+        rng.next_u64(),
+        chrono::Utc::now().timestamp(),
+        hostname
+    )
+}
+
+/// Parse a CRAM-MD5 response (`username hexdigest`)
+pub fn parse_cram_md5_response(response: &str) -> Result<(String, String)> {
+    let (username, digest) = response
+        .trim()
+        .rsplit_once(' ')
+        .ok_or_else(|| MailError::SmtpProtocol("Invalid CRAM-MD5 response".to_string()))?;
+
+    Ok((username.to_string(), digest.to_lowercase()))
+}
+
+/// Check a CRAM-MD5 digest against the stored secret
+pub fn verify_cram_md5(secret: &[u8], challenge: &str, digest_hex: &str) -> bool {
+    let expected = cram_md5_digest(secret, challenge);
+    constant_time_eq(expected.as_bytes(), digest_hex.as_bytes())
+}
+
+/// Compute the hex HMAC-MD5 digest a client sends for a challenge
+pub fn cram_md5_digest(secret: &[u8], challenge: &str) -> String {
+    let mut mac = HmacMd5::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(challenge.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Stored CRAM-MD5 credentials: the MD5 states after the inner and outer
+/// HMAC key pads, as Dovecot's `CRAM-MD5` scheme keeps them
+///
+/// They are enough to answer challenges but, unlike the password, can't
+/// be used for any other mechanism.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CramMd5Secret {
+    inner: [u32; 4],
+    outer: [u32; 4],
+}
+
+impl CramMd5Secret {
+    const PREFIX: &'static str = "{CRAM-MD5}";
+
+    /// Precompute the HMAC-MD5 pads of a password
+    pub fn new(password: &str) -> Self {
+        let mut key = [0u8; 64];
+        if password.len() > key.len() {
+            key[..16].copy_from_slice(&Md5::digest(password.as_bytes()));
+        } else {
+            key[..password.len()].copy_from_slice(password.as_bytes());
+        }
+        let pad = |byte: u8| {
+            let mut state = MD5_INIT;
+            md5_compress(&mut state, &key.map(|k| k ^ byte));
+            state
+        };
+        Self {
+            inner: pad(0x36),
+            outer: pad(0x5c),
+        }
+    }
+
+    /// `{CRAM-MD5}` followed by both states in hex
+    pub fn encode(&self) -> String {
+        let hex: String = self
+            .inner
+            .iter()
+            .chain(&self.outer)
+            .flat_map(|word| word.to_le_bytes())
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}{}", Self::PREFIX, hex)
+    }
+
+    /// Parse the format produced by [`CramMd5Secret::encode`]
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let invalid = || MailError::Parse("Invalid CRAM-MD5 credentials".to_string());
+        let hex = encoded.strip_prefix(Self::PREFIX).ok_or_else(invalid)?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut words = [0u32; 8];
+        for (word, chunk) in words.iter_mut().zip(hex.as_bytes().chunks(8)) {
+            let chunk = std::str::from_utf8(chunk).map_err(|_| invalid())?;
+            *word = u32::from_str_radix(chunk, 16).map_err(|_| invalid())?.swap_bytes();
+        }
+        Ok(Self {
+            inner: [words[0], words[1], words[2], words[3]],
+            outer: [words[4], words[5], words[6], words[7]],
+        })
+    }
+
+    /// The hex digest a client holding the password sends for `challenge`
+    pub fn digest(&self, challenge: &str) -> String {
+        let inner = md5_resume(self.inner, challenge.as_bytes());
+        md5_resume(self.outer, &inner)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Check a client's digest for `challenge`
+    pub fn verify(&self, challenge: &str, digest_hex: &str) -> bool {
+        constant_time_eq(self.digest(challenge).as_bytes(), digest_hex.as_bytes())
+    }
+}
+
+const MD5_INIT: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+const MD5_SHIFTS: [[u32; 4]; 4] = [[7, 12, 17, 22], [5, 9, 14, 20], [4, 11, 16, 23], [6, 10, 15, 21]];
+
+/// The MD5 block function (RFC 1321), which the `md-5` crate keeps private
+fn md5_compress(state: &mut [u32; 4], block: &[u8; 64]) {
+    let mut words = [0u32; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let [mut a, mut b, mut c, mut d] = *state;
+    for round in 0..64 {
+        let (f, g) = match round / 16 {
+            0 => ((b & c) | (!b & d), round),
+            1 => ((d & b) | (!d & c), (5 * round + 1) % 16),
+            2 => (b ^ c ^ d, (3 * round + 5) % 16),
+            _ => (c ^ (b | !d), (7 * round) % 16),
+        };
+        let constant = (((round + 1) as f64).sin().abs() * 4294967296.0) as u32;
+        let f = f
+            .wrapping_add(a)
+            .wrapping_add(constant)
+            .wrapping_add(words[g]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[round / 16][round % 4]));
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// Finish an MD5 digest from the state after one 64-byte block
+fn md5_resume(mut state: [u32; 4], data: &[u8]) -> [u8; 16] {
+    let bits = ((64 + data.len()) as u64) * 8;
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bits.to_le_bytes());
+    for block in message.chunks_exact(64) {
+        md5_compress(&mut state, block.try_into().expect("64-byte chunk"));
+    }
+    let mut digest = [0u8; 16];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// Decode a SCRAM saslname (`=2C` -> `,`, `=3D` -> `=`)
+fn decode_saslname(value: &str) -> Result<String> {
+    let mut decoded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '=' {
+            decoded.push(c);
+            continue;
+        }
+        match (chars.next(), chars.next()) {
+            (Some('2'), Some('C')) => decoded.push(','),
+            (Some('3'), Some('D')) => decoded.push('='),
+            _ => {
+                return Err(MailError::SmtpProtocol(
+                    "SCRAM: invalid username encoding".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Client side of SCRAM-SHA-256, for driving the server in tests
+    pub(crate) fn client_final(
+        password: &str,
+        client_first_bare: &str,
+        server_first: &str,
+    ) -> String {
+        let attrs: Vec<(&str, &str)> = server_first
+            .split(',')
+            .filter_map(|a| a.split_once('='))
+            .collect();
+        let nonce = attrs.iter().find(|(k, _)| *k == "r").unwrap().1;
+        let salt = BASE64.decode(attrs.iter().find(|(k, _)| *k == "s").unwrap().1).unwrap();
+        let iterations: u32 = attrs.iter().find(|(k, _)| *k == "i").unwrap().1.parse().unwrap();
+
+        let mut salted = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted);
+        let client_key = hmac_sha256(&salted, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+
+        let without_proof = format!("c={},r={}", BASE64.encode("n,,"), nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.iter().zip(signature).map(|(k, s)| k ^ s).collect();
+
+        format!("{},p={}", without_proof, BASE64.encode(proof))
+    }
+
+    #[test]
+    fn test_scram_exchange() {
+        let credentials = ScramCredentials::derive("pencil", b"saltsalt", 4096);
+        let mut server = ScramServer::parse_client_first("n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        assert_eq!(server.username(), "user");
+
+        let server_first = server.server_first(Some(credentials.clone()));
+        assert!(server_first.starts_with("r=rOprNGfwEbeRWgbNEkqO"));
+
+        let good = client_final("pencil", "n=user,r=rOprNGfwEbeRWgbNEkqO", &server_first);
+        let server_final = server.verify_client_final(&good).unwrap().unwrap();
+
+        // Client can check the server signature
+        let auth_message = format!(
+            "n=user,r=rOprNGfwEbeRWgbNEkqO,{},{}",
+            server_first,
+            good.rsplit_once(",p=").unwrap().0
+        );
+        let expected = hmac_sha256(&credentials.server_key, auth_message.as_bytes());
+        assert_eq!(server_final, format!("v={}", BASE64.encode(expected)));
+
+        let bad = client_final("wrong", "n=user,r=rOprNGfwEbeRWgbNEkqO", &server_first);
+        assert!(server.verify_client_final(&bad).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scram_unknown_user_fails() {
+        let mut server = ScramServer::parse_client_first("n,,n=ghost,r=abc").unwrap();
+        let server_first = server.server_first(None);
+        let proof = client_final("anything", "n=ghost,r=abc", &server_first);
+        assert!(server.verify_client_final(&proof).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scram_unknown_user_salt_is_stable() {
+        let salt = |client_first: &str| {
+            let mut server = ScramServer::parse_client_first(client_first).unwrap();
+            let server_first = server.server_first(None);
+            let salt = server_first.split(',').find_map(|a| a.strip_prefix("s=")).unwrap();
+            BASE64.decode(salt).unwrap()
+        };
+
+        let first = salt("n,,n=ghost,r=abc");
+        assert_eq!(first.len(), RANDOM_LEN);
+        assert_eq!(salt("n,,n=ghost,r=def"), first);
+        assert_ne!(salt("n,,n=phantom,r=abc"), first);
+    }
+
+    #[test]
+    fn test_scram_rejects_channel_binding_and_bad_nonce() {
+        assert!(ScramServer::parse_client_first("p=tls-unique,,n=user,r=abc").is_err());
+
+        let mut server = ScramServer::parse_client_first("n,,n=user,r=abc").unwrap();
+        server.server_first(Some(ScramCredentials::new("pw")));
+        let message = format!("c={},r=abc-tampered,p=AAAA", BASE64.encode("n,,"));
+        assert!(server.verify_client_final(&message).is_err());
+    }
+
+    #[test]
+    fn test_scram_credentials_roundtrip() {
+        let credentials = ScramCredentials::new("secret");
+        let encoded = credentials.encode();
+        assert!(encoded.starts_with("SCRAM-SHA-256$4096:"));
+        assert_eq!(ScramCredentials::decode(&encoded).unwrap(), credentials);
+        assert!(ScramCredentials::decode("PLAIN$abc").is_err());
+    }
+
+    #[test]
+    fn test_cram_md5_rfc2195_example() {
+        let challenge = "<1896.697170952@postoffice.reston.mci.net>";
+        let digest = cram_md5_digest(b"tanstaaftanstaaf", challenge);
+        assert_eq!(digest, "b913a602c7eda7a495b4e6e7334d3890");
+
+        let (username, digest) =
+            parse_cram_md5_response("tim b913a602c7eda7a495b4e6e7334d3890").unwrap();
+        assert_eq!(username, "tim");
+        assert!(verify_cram_md5(b"tanstaaftanstaaf", challenge, &digest));
+        assert!(!verify_cram_md5(b"wrong", challenge, &digest));
+    }
+
+    #[test]
+    fn test_cram_md5_secret_matches_hmac() {
+        let challenge = "<1896.697170952@postoffice.reston.mci.net>";
+        let secret = CramMd5Secret::new("tanstaaftanstaaf");
+        assert_eq!(secret.digest(challenge), "b913a602c7eda7a495b4e6e7334d3890");
+
+        let encoded = secret.encode();
+        assert!(encoded.starts_with("{CRAM-MD5}"));
+        assert!(!encoded.contains(&hex_encode(b"tanstaaftanstaaf")));
+        let decoded = CramMd5Secret::decode(&encoded).unwrap();
+        assert_eq!(decoded, secret);
+        assert!(decoded.verify(challenge, "b913a602c7eda7a495b4e6e7334d3890"));
+        assert!(CramMd5Secret::decode("dGFuc3RhYWY=").is_err());
+
+        // Keys longer than a block are hashed first
+        let long = "x".repeat(100);
+        for challenge in ["", "<1.2@host>", &"c".repeat(200)] {
+            assert_eq!(
+                CramMd5Secret::new(&long).digest(challenge),
+                cram_md5_digest(long.as_bytes(), challenge)
+            );
+        }
+    }
+
+    fn hex_encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
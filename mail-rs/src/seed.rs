@@ -0,0 +1,767 @@
+//! Demo data for development
+//!
+//! [`DemoData::generate`] builds a realistic set of mailboxes from a seed:
+//! demo users, threaded conversations between them and outside
+//! correspondents, attachments of several types, newsletters, spam in
+//! Junk, calendar events and contacts. The same seed and options always
+//! give the same data, so UI work, AI features and integration tests can
+//! rely on it. [`seed`] writes the data to a Maildir, the user database
+//! and CalDAV/CardDAV.
+//!
+//! ```bash
+//! mailctl seed --config config.toml --seed 7 --users 5
+//! ```
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use tracing::info;
+
+use crate::caldav::{
+    CalDavManager, CreateCalendarRequest, CreateContactRequest, CreateEventRequest,
+};
+use crate::security::Authenticator;
+use crate::storage::{MaildirStorage, Storage};
+
+/// Name of the calendar and address book demo data goes into
+pub const DEMO_COLLECTION: &str = "Demo";
+
+/// 1x1 transparent PNG
+const PNG_PIXEL: &str =
+    "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+const FIRST_NAMES: &[&str] = &[
+    "Alice", "Bruno", "Chloe", "David", "Emma", "Farid", "Grace", "Hugo", "Ines", "Jonas",
+    "Karima", "Lucas", "Maya", "Nathan", "Olivia", "Pablo",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Martin", "Bernard", "Dubois", "Laurent", "Moreau", "Garcia", "Nguyen", "Roux", "Fontaine",
+    "Mercier", "Lambert", "Bonnet",
+];
+
+/// Outside correspondents, as display name and address
+const EXTERNAL_CONTACTS: &[(&str, &str)] = &[
+    ("Sophie Carter", "sophie.carter@partner.example"),
+    ("Liam Walsh", "liam@vendor.example"),
+    ("Accounts Payable", "billing@supplier.example"),
+    ("Noor Haddad", "noor.haddad@client.example"),
+    ("Tom Becker", "t.becker@agency.example"),
+];
+
+/// Conversation topics: subject and the messages of the thread, in order
+const TOPICS: &[(&str, &[&str])] = &[
+    (
+        "Q3 roadmap review",
+        &[
+            "Hi,\r\n\r\nI put together a first draft of the Q3 roadmap. Could you have a look before Thursday's review?\r\n",
+            "Thanks, looks good overall. I would move the search work before the mobile release, it blocks two other teams.\r\n",
+            "Fair point, I swapped them. Updated version attached.\r\n",
+            "Perfect, let's go with this one.\r\n",
+        ],
+    ),
+    (
+        "Invoice 2024-117",
+        &[
+            "Hello,\r\n\r\nPlease find attached invoice 2024-117 for the March services. Payment is due within 30 days.\r\n",
+            "Thanks, forwarded to accounting. Could you add our PO number next time?\r\n",
+            "Of course, noted for the next invoices.\r\n",
+        ],
+    ),
+    (
+        "Lunch on Friday?",
+        &[
+            "Anyone up for lunch at the new Thai place on Friday?\r\n",
+            "Count me in! 12:30?\r\n",
+            "12:30 works, I'll book a table for four.\r\n",
+        ],
+    ),
+    (
+        "Production incident follow-up",
+        &[
+            "Hi all,\r\n\r\nYesterday's outage was caused by an expired certificate on the load balancer. Timeline and action items below.\r\n\r\n- 14:02 alerts fire\r\n- 14:10 root cause found\r\n- 14:25 certificate renewed\r\n",
+            "Thanks for the write-up. Can we add certificate expiry to the monitoring dashboard?\r\n",
+            "Already on it, the check ships this afternoon.\r\n",
+            "Great, closing the incident then.\r\n",
+        ],
+    ),
+    (
+        "Contract renewal",
+        &[
+            "Dear team,\r\n\r\nOur agreement ends next month. We would be happy to renew under the same terms, the draft is attached.\r\n",
+            "Thank you. Legal is reviewing the draft, we should get back to you by Wednesday.\r\n",
+        ],
+    ),
+    (
+        "Design mockups for the settings page",
+        &[
+            "Here are the mockups for the new settings page. Feedback welcome!\r\n",
+            "Love the layout. The danger zone section could use a bit more spacing.\r\n",
+            "Good catch, I'll update it.\r\n",
+        ],
+    ),
+    (
+        "Team offsite planning",
+        &[
+            "We're planning the offsite for early June. Please fill in your availability and dietary needs in the attached sheet.\r\n",
+            "Done. I can also help with the agenda if needed.\r\n",
+        ],
+    ),
+    (
+        "Quick question about the API",
+        &[
+            "Hi,\r\n\r\nIs there a way to filter messages by label through the REST API? I couldn't find it in the docs.\r\n",
+            "Yes, pass `label=` to the search endpoint. I'll make the docs clearer.\r\n",
+            "Works, thanks!\r\n",
+        ],
+    ),
+];
+
+/// Newsletters, as list name, list address and article titles
+const NEWSLETTERS: &[(&str, &str, &[&str])] = &[
+    (
+        "Rust Weekly",
+        "news@rustweekly.example",
+        &[
+            "Async closures are stable",
+            "Profiling allocations",
+            "Crate of the week: tracing",
+        ],
+    ),
+    (
+        "Product Updates",
+        "updates@saas.example",
+        &["Dark mode is here", "Faster search", "New integrations"],
+    ),
+];
+
+/// Spam, as sender, subject and body
+const SPAM: &[(&str, &str, &str)] = &[
+    (
+        "winner@lottery-prize.example",
+        "You have WON $1,000,000!!!",
+        "Congratulations! Click here to claim your prize now. Offer expires in 24 hours!\r\n",
+    ),
+    (
+        "security@paypa1-verify.example",
+        "Urgent: verify your account",
+        "Your account has been suspended. Log in within 48 hours at http://paypa1-verify.example to avoid closure.\r\n",
+    ),
+    (
+        "deals@cheap-meds.example",
+        "Best prices on pills",
+        "Buy now, no prescription needed. 90% off today only.\r\n",
+    ),
+];
+
+/// How much demo data to generate
+#[derive(Debug, Clone)]
+pub struct SeedOptions {
+    /// Seed of the generator
+    pub seed: u64,
+    /// Domain of the demo users
+    pub domain: String,
+    pub users: usize,
+    /// Conversations started by each user
+    pub conversations: usize,
+    /// Password of every demo user
+    pub password: String,
+    /// Date of the first message; events are planned in the weeks after
+    pub start: DateTime<Utc>,
+}
+
+impl Default for SeedOptions {
+    fn default() -> Self {
+        Self {
+            seed: 42,
+            domain: "example.test".to_string(),
+            users: 3,
+            conversations: 6,
+            password: "demo-password".to_string(),
+            start: Utc.with_ymd_and_hms(2024, 3, 4, 9, 0, 0).unwrap(),
+        }
+    }
+}
+
+/// Demo user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DemoUser {
+    pub email: String,
+    pub name: String,
+    pub password: String,
+}
+
+/// Message to store in a demo user's mailbox
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemoMessage {
+    pub user: String,
+    /// Folder, `None` for INBOX
+    pub folder: Option<String>,
+    /// IMAP system flags
+    pub flags: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+/// Calendar event of a demo user
+#[derive(Debug, Clone)]
+pub struct DemoEvent {
+    pub user: String,
+    pub event: CreateEventRequest,
+}
+
+/// Contact of a demo user
+#[derive(Debug, Clone)]
+pub struct DemoContact {
+    pub user: String,
+    pub contact: CreateContactRequest,
+}
+
+/// Everything one seed generates
+#[derive(Debug, Clone)]
+pub struct DemoData {
+    pub users: Vec<DemoUser>,
+    pub messages: Vec<DemoMessage>,
+    pub events: Vec<DemoEvent>,
+    pub contacts: Vec<DemoContact>,
+}
+
+/// What [`seed`] wrote
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SeedReport {
+    pub users_created: usize,
+    /// Users whose mailbox already had messages, left as they were
+    pub users_skipped: usize,
+    pub messages: usize,
+    pub events: usize,
+    pub contacts: usize,
+}
+
+/// Kind of file attached to a demo message
+#[derive(Debug, Clone, Copy)]
+enum Attachment {
+    Pdf,
+    Png,
+    Csv,
+    Invite,
+}
+
+/// Participant of a conversation
+struct Person {
+    name: String,
+    address: String,
+}
+
+impl DemoData {
+    /// Generate the demo data of `options`
+    pub fn generate(options: &SeedOptions) -> Self {
+        let mut rng = StdRng::seed_from_u64(options.seed);
+        let mut data = DemoData {
+            users: Vec::new(),
+            messages: Vec::new(),
+            events: Vec::new(),
+            contacts: Vec::new(),
+        };
+
+        let mut names: Vec<(&str, &str)> = FIRST_NAMES
+            .iter()
+            .flat_map(|first| LAST_NAMES.iter().map(move |last| (*first, *last)))
+            .collect();
+        names.shuffle(&mut rng);
+        for (first, last) in names.into_iter().take(options.users) {
+            data.users.push(DemoUser {
+                email: format!("{}.{}@{}", first, last, options.domain).to_lowercase(),
+                name: format!("{} {}", first, last),
+                password: options.password.clone(),
+            });
+        }
+
+        let people: Vec<Person> = data
+            .users
+            .iter()
+            .map(|user| Person {
+                name: user.name.clone(),
+                address: user.email.clone(),
+            })
+            .chain(EXTERNAL_CONTACTS.iter().map(|(name, address)| Person {
+                name: name.to_string(),
+                address: address.to_string(),
+            }))
+            .collect();
+
+        let mut clock = options.start;
+        for (owner, user) in data.users.clone().iter().enumerate() {
+            for conversation in 0..options.conversations {
+                clock += Duration::minutes(rng.gen_range(20..240));
+                let thread_id = format!("{}.{}.{}", options.seed, owner, conversation);
+                data.conversation(&mut rng, &people, owner, &thread_id, clock, &options.domain);
+            }
+            data.newsletters(&mut rng, user, options.start);
+            data.spam(&mut rng, user, options.start);
+            data.calendar(&mut rng, user, options.start);
+            data.address_book(&people, user);
+        }
+
+        data
+    }
+
+    /// Add a thread started by `people[owner]` to the mailboxes of its
+    /// demo participants
+    fn conversation(
+        &mut self,
+        rng: &mut StdRng,
+        people: &[Person],
+        owner: usize,
+        thread_id: &str,
+        start: DateTime<Utc>,
+        domain: &str,
+    ) {
+        let (subject, bodies) = TOPICS[rng.gen_range(0..TOPICS.len())];
+        let mut participants = vec![owner];
+        let others = rng.gen_range(1..=2);
+        while participants.len() < 1 + others.min(people.len() - 1) {
+            let other = rng.gen_range(0..people.len());
+            if !participants.contains(&other) {
+                participants.push(other);
+            }
+        }
+        // Some threads are started by the other side
+        if rng.gen_bool(0.5) {
+            participants.swap(0, 1);
+        }
+        let attachment = match rng.gen_range(0..6) {
+            0 => Some(Attachment::Pdf),
+            1 => Some(Attachment::Png),
+            2 => Some(Attachment::Csv),
+            3 => Some(Attachment::Invite),
+            _ => None,
+        };
+
+        let length = rng.gen_range(1..=bodies.len());
+        let mut references: Vec<String> = Vec::new();
+        let mut date = start;
+        for (n, body) in bodies.iter().take(length).enumerate() {
+            let sender = &people[participants[n % participants.len()]];
+            let recipients: Vec<&Person> = participants
+                .iter()
+                .map(|&index| &people[index])
+                .filter(|person| person.address != sender.address)
+                .collect();
+            let message_id = format!("<{}.{}@{}>", thread_id, n, domain);
+
+            let mut header = format!(
+                "From: {} <{}>\r\nTo: {}\r\nDate: {}\r\nSubject: {}{}\r\nMessage-ID: {}\r\n",
+                sender.name,
+                sender.address,
+                recipients
+                    .iter()
+                    .map(|person| format!("{} <{}>", person.name, person.address))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                date.to_rfc2822(),
+                if n == 0 { "" } else { "Re: " },
+                subject,
+                message_id,
+            );
+            if let Some(parent) = references.last() {
+                header.push_str(&format!(
+                    "In-Reply-To: {}\r\nReferences: {}\r\n",
+                    parent,
+                    references.join(" ")
+                ));
+            }
+            header.push_str("MIME-Version: 1.0\r\n");
+
+            let text = format!("{}\r\n-- \r\n{}\r\n", body, sender.name);
+            let message = match attachment.filter(|_| n == 0) {
+                Some(kind) => with_attachment(header, &text, kind, subject, date, thread_id),
+                None => format!(
+                    "{}Content-Type: text/plain; charset=utf-8\r\n\r\n{}",
+                    header, text
+                ),
+            };
+
+            let last = n + 1 == length;
+            for person in std::iter::once(sender).chain(recipients.iter().copied()) {
+                if !self.users.iter().any(|user| user.email == person.address) {
+                    continue;
+                }
+                let sent = person.address == sender.address;
+                let mut flags = Vec::new();
+                if sent || !last || rng.gen_bool(0.4) {
+                    flags.push("\\Seen".to_string());
+                }
+                if sent && n > 0 {
+                    flags.push("\\Answered".to_string());
+                }
+                if !sent && rng.gen_bool(0.15) {
+                    flags.push("\\Flagged".to_string());
+                }
+                self.messages.push(DemoMessage {
+                    user: person.address.clone(),
+                    folder: sent.then(|| "Sent".to_string()),
+                    flags,
+                    data: message.clone().into_bytes(),
+                });
+            }
+
+            references.push(message_id);
+            date += Duration::minutes(rng.gen_range(5..600));
+        }
+    }
+
+    /// Add an HTML newsletter issue of each list
+    fn newsletters(&mut self, rng: &mut StdRng, user: &DemoUser, start: DateTime<Utc>) {
+        for (list, address, titles) in NEWSLETTERS {
+            let date = start + Duration::hours(rng.gen_range(1..72));
+            let boundary = format!("=_news_{}", rng.gen::<u32>());
+            let text: String = titles
+                .iter()
+                .map(|title| format!("* {}\r\n", title))
+                .collect();
+            let html: String = titles
+                .iter()
+                .map(|title| {
+                    format!(
+                        "<li><a href=\"https://{}/\">{}</a></li>",
+                        domain_of(address),
+                        title
+                    )
+                })
+                .collect();
+            let message = format!(
+                "From: {list} <{address}>\r\nTo: {to}\r\nDate: {date}\r\nSubject: {list}: {headline}\r\n\
+                 Message-ID: <{id}@{domain}>\r\nList-Id: {list} <{list_id}.{domain}>\r\n\
+                 List-Unsubscribe: <https://{domain}/unsubscribe>\r\nPrecedence: bulk\r\n\
+                 MIME-Version: 1.0\r\nContent-Type: multipart/alternative; boundary=\"{boundary}\"\r\n\r\n\
+                 --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nThis week:\r\n{text}\r\n\
+                 --{boundary}\r\nContent-Type: text/html; charset=utf-8\r\n\r\n\
+                 <html><body><h1>{list}</h1><ul>{html}</ul></body></html>\r\n\
+                 --{boundary}--\r\n",
+                list = list,
+                address = address,
+                to = user.email,
+                date = date.to_rfc2822(),
+                headline = titles[0],
+                id = rng.gen::<u64>(),
+                domain = domain_of(address),
+                list_id = list.to_lowercase().replace(' ', "-"),
+                boundary = boundary,
+                text = text,
+                html = html,
+            );
+            self.messages.push(DemoMessage {
+                user: user.email.clone(),
+                folder: None,
+                flags: vec![],
+                data: message.into_bytes(),
+            });
+        }
+    }
+
+    /// Add spam already moved to Junk
+    fn spam(&mut self, rng: &mut StdRng, user: &DemoUser, start: DateTime<Utc>) {
+        for (from, subject, body) in SPAM.choose_multiple(rng, 2) {
+            let date = start + Duration::hours(rng.gen_range(1..96));
+            let message = format!(
+                "From: {}\r\nTo: {}\r\nDate: {}\r\nSubject: {}\r\nMessage-ID: <{}@{}>\r\n\
+                 X-Spam-Flag: YES\r\nX-Spam-Score: {}.{}\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\n\r\n{}",
+                from,
+                user.email,
+                date.to_rfc2822(),
+                subject,
+                rng.gen::<u64>(),
+                domain_of(from),
+                rng.gen_range(8..20),
+                rng.gen_range(0..10),
+                body,
+            );
+            self.messages.push(DemoMessage {
+                user: user.email.clone(),
+                folder: Some("Junk".to_string()),
+                flags: vec![],
+                data: message.into_bytes(),
+            });
+        }
+    }
+
+    /// Plan a few meetings in the weeks after `start`
+    fn calendar(&mut self, rng: &mut StdRng, user: &DemoUser, start: DateTime<Utc>) {
+        let meetings = [
+            ("Team standup", "Daily sync", "Meeting room 2"),
+            ("1:1", "Weekly one-on-one", "Video call"),
+            ("Sprint review", "Demo of the sprint's work", "Auditorium"),
+            ("Dentist", "", "Rue de la Paix 12"),
+        ];
+        for (summary, description, location) in meetings.choose_multiple(rng, 3) {
+            let dtstart =
+                start + Duration::days(rng.gen_range(0..21)) + Duration::hours(rng.gen_range(0..8));
+            self.events.push(DemoEvent {
+                user: user.email.clone(),
+                event: CreateEventRequest {
+                    summary: summary.to_string(),
+                    dtstart,
+                    dtend: dtstart + Duration::minutes(rng.gen_range(1..=4) * 15),
+                    description: (!description.is_empty()).then(|| description.to_string()),
+                    location: Some(location.to_string()),
+                },
+            });
+        }
+    }
+
+    /// Add the other demo users and the outside correspondents as contacts
+    fn address_book(&mut self, people: &[Person], user: &DemoUser) {
+        for (n, person) in people
+            .iter()
+            .filter(|person| person.address != user.email)
+            .enumerate()
+        {
+            self.contacts.push(DemoContact {
+                user: user.email.clone(),
+                contact: CreateContactRequest {
+                    full_name: person.name.clone(),
+                    email: Some(person.address.clone()),
+                    phone: Some(format!(
+                        "+33 1 55 {:02} {:02} {:02}",
+                        n,
+                        n * 7 % 100,
+                        n * 13 % 100
+                    )),
+                },
+            });
+        }
+    }
+}
+
+/// Domain part of `address`
+fn domain_of(address: &str) -> &str {
+    address
+        .rsplit_once('@')
+        .map_or(address, |(_, domain)| domain)
+}
+
+/// Multipart message of `header` with `text` and a `kind` attachment
+fn with_attachment(
+    header: String,
+    text: &str,
+    kind: Attachment,
+    subject: &str,
+    date: DateTime<Utc>,
+    thread_id: &str,
+) -> String {
+    let (content_type, filename, content) = match kind {
+        Attachment::Pdf => (
+            "application/pdf",
+            "document.pdf",
+            format!(
+                "%PDF-1.4\n1 0 obj << /Title ({}) >> endobj\ntrailer << /Info 1 0 R >>\n%%EOF\n",
+                subject
+            )
+            .into_bytes(),
+        ),
+        Attachment::Png => (
+            "image/png",
+            "screenshot.png",
+            BASE64.decode(PNG_PIXEL).expect("valid PNG"),
+        ),
+        Attachment::Csv => (
+            "text/csv",
+            "figures.csv",
+            "month,revenue,costs\r\nJanuary,12000,8000\r\nFebruary,13500,8200\r\nMarch,15100,8900\r\n"
+                .as_bytes()
+                .to_vec(),
+        ),
+        Attachment::Invite => {
+            let stamp = date.format("%Y%m%dT%H%M%SZ");
+            let start = (date + Duration::days(2)).format("%Y%m%dT%H%M%SZ");
+            let end = (date + Duration::days(2) + Duration::hours(1)).format("%Y%m%dT%H%M%SZ");
+            (
+                "text/calendar; method=REQUEST",
+                "invite.ics",
+                format!(
+                    "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//mail-rs//seed//EN\r\nMETHOD:REQUEST\r\n\
+                     BEGIN:VEVENT\r\nUID:{}@seed\r\nDTSTAMP:{}\r\nDTSTART:{}\r\nDTEND:{}\r\n\
+                     SUMMARY:{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+                    thread_id, stamp, start, end, subject
+                )
+                .into_bytes(),
+            )
+        }
+    };
+
+    let boundary = format!("=_part_{}", thread_id);
+    let encoded = BASE64.encode(content);
+    let lines: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(76)
+        .map(|chunk| std::str::from_utf8(chunk).expect("base64 is ASCII"))
+        .collect();
+    format!(
+        "{header}Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\r\n\
+         --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{text}\r\n\
+         --{boundary}\r\nContent-Type: {content_type}; name=\"{filename}\"\r\n\
+         Content-Disposition: attachment; filename=\"{filename}\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\r\n{content}\r\n\
+         --{boundary}--\r\n",
+        header = header,
+        boundary = boundary,
+        text = text,
+        content_type = content_type,
+        filename = filename,
+        content = lines.join("\r\n"),
+    )
+}
+
+/// Write `data` to the mailboxes, user database and CalDAV/CardDAV
+///
+/// Users whose mailbox already holds messages are left alone, so seeding
+/// twice does not duplicate anything.
+pub async fn seed(
+    data: &DemoData,
+    storage: &MaildirStorage,
+    auth: Option<&Authenticator>,
+    caldav: Option<&CalDavManager>,
+) -> anyhow::Result<SeedReport> {
+    let mut report = SeedReport::default();
+
+    for user in &data.users {
+        if !Storage::list_messages(storage, &user.email)
+            .await?
+            .is_empty()
+        {
+            info!("Not seeding {}: mailbox is not empty", user.email);
+            report.users_skipped += 1;
+            continue;
+        }
+        if let Some(auth) = auth {
+            if !auth.user_exists(&user.email).await? {
+                auth.add_user(&user.email, &user.password).await?;
+                report.users_created += 1;
+            }
+        }
+
+        for message in data
+            .messages
+            .iter()
+            .filter(|message| message.user == user.email)
+        {
+            storage
+                .store_with_flags(
+                    &user.email,
+                    message.folder.as_deref(),
+                    &message.flags,
+                    &message.data,
+                )
+                .await?;
+            report.messages += 1;
+        }
+
+        let Some(caldav) = caldav else {
+            continue;
+        };
+        let calendar = caldav
+            .create_calendar(
+                &user.email,
+                CreateCalendarRequest {
+                    name: DEMO_COLLECTION.to_string(),
+                    color: Some("#3b82f6".to_string()),
+                },
+            )
+            .await?;
+        for event in data.events.iter().filter(|event| event.user == user.email) {
+            caldav
+                .create_event(&calendar.id, event.event.clone())
+                .await?;
+            report.events += 1;
+        }
+        let addressbook = caldav
+            .create_addressbook(&user.email, DEMO_COLLECTION)
+            .await?;
+        for contact in data
+            .contacts
+            .iter()
+            .filter(|contact| contact.user == user.email)
+        {
+            caldav
+                .create_contact(&addressbook.id, contact.contact.clone())
+                .await?;
+            report.contacts += 1;
+        }
+    }
+
+    info!(
+        "Seeded {} messages, {} events and {} contacts for {} users",
+        report.messages,
+        report.events,
+        report.contacts,
+        data.users.len() - report.users_skipped
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::MimeEntity;
+
+    #[test]
+    fn test_generate_is_deterministic() {
+        let options = SeedOptions::default();
+        let first = DemoData::generate(&options);
+        let second = DemoData::generate(&options);
+        assert_eq!(first.users, second.users);
+        assert_eq!(first.messages, second.messages);
+
+        let other = DemoData::generate(&SeedOptions {
+            seed: 7,
+            ..options.clone()
+        });
+        assert_ne!(first.messages, other.messages);
+
+        assert_eq!(first.users.len(), 3);
+        assert!(first
+            .users
+            .iter()
+            .all(|user| user.email.ends_with("@example.test")));
+        assert_eq!(first.events.len(), 9);
+        // Two other users and every outside correspondent
+        assert_eq!(first.contacts.len(), 3 * (2 + EXTERNAL_CONTACTS.len()));
+    }
+
+    #[test]
+    fn test_generated_messages() {
+        let data = DemoData::generate(&SeedOptions {
+            conversations: 20,
+            ..Default::default()
+        });
+
+        let folder = |name: &'static str| {
+            data.messages
+                .iter()
+                .filter(move |message| message.folder.as_deref() == Some(name))
+        };
+        assert!(folder("Sent").all(|message| message.flags.contains(&"\\Seen".to_string())));
+        assert_eq!(folder("Junk").count(), 3 * 2);
+
+        let mut replies = 0;
+        let mut attachments = 0;
+        for message in &data.messages {
+            let entity = MimeEntity::parse(&message.data);
+            let text = String::from_utf8_lossy(entity.header);
+            assert!(text.contains("Message-ID: <"), "{}", text);
+            if text.contains("In-Reply-To:") {
+                replies += 1;
+                assert!(text.contains("Subject: Re: "));
+            }
+            if entity.content_type == "multipart/mixed" {
+                attachments += 1;
+                assert_eq!(entity.parts.len(), 2);
+            }
+        }
+        assert!(replies > 0);
+        assert!(attachments > 0);
+    }
+}
@@ -0,0 +1,1426 @@
+//! Embeddable mail server
+//!
+//! [`MailServer`] composes storage, authentication, TLS and the chosen
+//! listeners the way the `mail-rs` binary does, so the whole server can be
+//! embedded in another program or started from a test:
+//!
+//! ```no_run
+//! use mail_rs::config::Config;
+//! use mail_rs::{Listener, MailServer};
+//!
+//! # async fn example() -> mail_rs::error::Result<()> {
+//! let mut config = Config::default();
+//! config.smtp.listen_addr = "127.0.0.1:0".to_string();
+//! config.imap.listen_addr = "127.0.0.1:0".to_string();
+//!
+//! let handle = MailServer::builder(config)
+//!     .listeners([Listener::Smtp, Listener::Imap])
+//!     .build()
+//!     .await?
+//!     .start()
+//!     .await?;
+//!
+//! println!("SMTP on {:?}", handle.local_addr(Listener::Smtp));
+//! assert!(handle.health().is_healthy());
+//! handle.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Listeners are bound by [`MailServer::start`], so address errors surface
+//! there, and `:0` addresses get an ephemeral port reported by
+//! [`MailServerHandle::local_addr`].
+//!
+//! LMTP is out of scope: the crate has no LMTP session handler, so there is
+//! no LMTP listener to select.
+
+use crate::antispam::greylist::GreylistConfig;
+use crate::antispam::GreylistManager;
+use crate::api::ApiServer;
+use crate::billing::{BillingCollector, BillingManager};
+use crate::branding::BrandingManager;
+use crate::config::Config;
+use crate::error::{MailError, Result};
+use crate::imap::ImapServer;
+use crate::devices::DeviceManager;
+use crate::login_anomaly::LoginAnomalyDetector;
+use crate::mfa::MfaManager;
+use crate::notifications::{NotificationManager, NotificationRouter};
+use crate::quota::{QuotaHistory, QuotaManager, QuotaReconciler, UserQuota};
+use crate::reporting::{ReportScheduler, ReportingManager};
+use crate::residency::{ResidencyManager, ResidencyMap};
+use crate::search::IndexQueue;
+use crate::security::{Authenticator, BandwidthLimiter, TlsConfig};
+use crate::smtp::budget::DeliveryBudget;
+use crate::smtp::postdelivery::PostDeliveryQueue;
+use crate::smtp::server::{
+    build_billing_manager, build_dmarc_report_manager, build_dnsbl_checker, build_hook_manager,
+    build_dkim_key_manager, build_openpgp_manager, build_quarantine, build_role_account_manager,
+    build_virus_scanner,
+};
+use crate::smtp::{SmtpQueue, SmtpServer, SmtpSession, SubmissionServer};
+use crate::spam::SpamManager;
+use crate::retention::{RetentionEnforcer, RetentionManager};
+use crate::storage::jobs::{StorageJobManager, StorageJobRunner};
+use crate::storage::{FlagEventBus, MaildirStorage, MetadataIndex};
+use crate::tlsrpt::{TlsReportSender, TlsRptManager};
+use crate::workers::WorkerPools;
+use crate::recovery::ReadOnlyMode;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{error, info, warn};
+
+/// API listen address used by the binary
+const DEFAULT_API_ADDR: &str = "0.0.0.0:8080";
+
+/// JWT secret used by the binary until it becomes configurable
+const DEFAULT_JWT_SECRET: &str = "dev-secret-key-change-in-production";
+
+/// How often stale greylist entries are expired
+const GREYLIST_CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often expired aggregate reports are deleted
+const DMARC_REPORT_EXPIRY_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// How often held notifications are checked for delivery
+const NOTIFICATION_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the storage job runner looks for queued jobs
+const STORAGE_JOB_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often shared copies no mailbox links anymore are removed
+const SINGLE_INSTANCE_GC_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often the daily delivery counts of the recipient quotas are reset
+const DELIVERY_COUNT_RESET_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// A network service the server can run
+///
+/// There is no LMTP listener; see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Listener {
+    /// Inbound SMTP (MX)
+    Smtp,
+    /// Message submission (RFC 6409)
+    Submission,
+    Imap,
+    /// IMAP over implicit TLS
+    Imaps,
+    /// REST API and web UI
+    Api,
+}
+
+impl Listener {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Listener::Smtp => "smtp",
+            Listener::Submission => "submission",
+            Listener::Imap => "imap",
+            Listener::Imaps => "imaps",
+            Listener::Api => "api",
+        }
+    }
+}
+
+/// Builder for [`MailServer`]
+///
+/// Everything not set explicitly comes from the [`Config`], as in the
+/// binary.
+pub struct MailServerBuilder {
+    config: Config,
+    storage: Option<Arc<MaildirStorage>>,
+    authenticator: Option<Authenticator>,
+    tls_config: Option<Arc<TlsConfig>>,
+    listeners: Option<Vec<Listener>>,
+    api_addr: String,
+    jwt_secret: String,
+    background_tasks: bool,
+}
+
+impl MailServerBuilder {
+    fn new(config: Config) -> Self {
+        Self {
+            config,
+            storage: None,
+            authenticator: None,
+            tls_config: None,
+            listeners: None,
+            api_addr: DEFAULT_API_ADDR.to_string(),
+            jwt_secret: DEFAULT_JWT_SECRET.to_string(),
+            background_tasks: true,
+        }
+    }
+
+    /// Message storage (default: Maildir at `storage.maildir_path`)
+    pub fn storage(mut self, storage: Arc<MaildirStorage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Authenticator shared by all listeners
+    ///
+    /// By default each listener opens the database named in the config,
+    /// and SMTP AUTH follows `smtp.enable_auth`. Setting one enables SMTP
+    /// AUTH.
+    pub fn authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// TLS configuration for SMTP STARTTLS and submission
+    /// (default: the certificates in `smtp.tls_cert_path`/`tls_key_path`),
+    /// and for IMAP when `imap.enable_tls` is set without certificates
+    pub fn tls(mut self, tls_config: Arc<TlsConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Listeners to run (default: SMTP, IMAP and API, plus submission
+    /// when `submission.enabled` and IMAPS when `imap.enable_tls`). LMTP
+    /// is not available.
+    pub fn listeners(mut self, listeners: impl IntoIterator<Item = Listener>) -> Self {
+        let mut selected: Vec<Listener> = Vec::new();
+        for listener in listeners {
+            if !selected.contains(&listener) {
+                selected.push(listener);
+            }
+        }
+        self.listeners = Some(selected);
+        self
+    }
+
+    /// API listen address (default: `0.0.0.0:8080`)
+    pub fn api_addr(mut self, addr: impl Into<String>) -> Self {
+        self.api_addr = addr.into();
+        self
+    }
+
+    /// Secret used to sign API tokens
+    pub fn jwt_secret(mut self, secret: impl Into<String>) -> Self {
+        self.jwt_secret = secret.into();
+        self
+    }
+
+    /// Don't run the report scheduler and TLS report sender, even when
+    /// enabled in the config
+    pub fn without_background_tasks(mut self) -> Self {
+        self.background_tasks = false;
+        self
+    }
+
+    /// Create the listeners' servers
+    ///
+    /// Databases are opened and certificates loaded here, so configuration
+    /// errors are reported before anything is bound.
+    pub async fn build(self) -> Result<MailServer> {
+        let config = self.config;
+        for (listener, enabled, trusted) in [
+            ("smtp", config.smtp.proxy_protocol, &config.smtp.proxy_trusted_ips),
+            ("imap", config.imap.proxy_protocol, &config.imap.proxy_trusted_ips),
+        ] {
+            if enabled && trusted.is_empty() {
+                return Err(MailError::Config(format!(
+                    "{}.proxy_protocol requires {}.proxy_trusted_ips",
+                    listener, listener
+                )));
+            }
+        }
+        if config.capture.enabled {
+            warn!(
+                "Capture mode: outbound mail is kept in the queue unless sent to {:?}",
+                config.capture.allow_domains
+            );
+        }
+        // Region assignments are managed through the API and shared by the
+        // default storage, IMAP and the API's backups and exports
+        let residency = if config.residency.regions.is_empty() {
+            None
+        } else {
+            let map = Arc::new(ResidencyMap::new(&config.residency)?);
+            let manager = ResidencyManager::connect(&config.api_database_url(), map)
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open residency database: {}", e)))?;
+            Some(Arc::new(manager))
+        };
+        // Message metadata kept in the API database for listings, counts and
+        // STATUS, updated by the default storage and from flag events
+        let metadata = if config.storage.metadata_index {
+            let index = MetadataIndex::connect(&config.api_database_url())
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open metadata index: {}", e)))?;
+            Some(Arc::new(index))
+        } else {
+            None
+        };
+        // Stored and removed messages are queued for the API's search index
+        let search_queue = config
+            .search
+            .incremental
+            .then(|| IndexQueue::start(config.search.queue_size));
+        let storage = self.storage.unwrap_or_else(|| {
+            let mut storage = MaildirStorage::new(config.storage.maildir_path.clone());
+            if let Some(manager) = &residency {
+                storage = storage.with_residency(manager.map());
+            }
+            if let Some(index) = &metadata {
+                storage = storage.with_metadata(index.clone());
+            }
+            if config.storage.single_instance {
+                storage = storage.with_single_instance();
+            }
+            if let Some(queue) = &search_queue {
+                storage = storage.with_search(queue.clone());
+            }
+            Arc::new(storage)
+        });
+        let listeners = self.listeners.unwrap_or_else(|| {
+            let mut listeners = vec![Listener::Smtp, Listener::Imap, Listener::Api];
+            if config.submission.enabled {
+                listeners.insert(1, Listener::Submission);
+            }
+            if config.imap.enable_tls {
+                // Right after IMAP, before the API
+                listeners.insert(listeners.len() - 1, Listener::Imaps);
+            }
+            listeners
+        });
+        let shared_auth = self
+            .authenticator
+            .map(|authenticator| Arc::new(authenticator.with_cram_md5(config.smtp.allow_cram_md5)));
+        // Storage quotas and daily delivery limits managed through the API,
+        // checked at RCPT TO and reported and enforced over IMAP, with usage
+        // from the mailboxes' maildirsize files
+        let quotas = Arc::new(
+            QuotaManager::with_defaults(UserQuota {
+                max_message_size: config.smtp.max_message_size as u64,
+                message_limit_daily: config.quota.daily_delivery_limit,
+                ..Default::default()
+            })
+            .with_storage(storage.clone())
+            .with_warning_thresholds(config.quota.warning_thresholds.clone()),
+        );
+        // Usage is recalculated from disk periodically, keeping samples in
+        // the API database for the admin API
+        let mut quota_reconciler = QuotaReconciler::new(storage.clone(), quotas.clone());
+        if listeners.contains(&Listener::Api) {
+            let history = QuotaHistory::connect(&config.api_database_url())
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open quota history database: {}", e)))?;
+            quota_reconciler =
+                quota_reconciler.with_history(Arc::new(history), config.quota.history_days);
+        }
+        // Read-only mode is toggled through the API, entered by any listener
+        // failing to write, and pauses the writing pools
+        let read_only = Arc::new(ReadOnlyMode::from_config(&config.recovery));
+        // Every listener runs background work in the pools whose load the
+        // API reports
+        let workers = Arc::new(WorkerPools::pausing_writers(&config.workers, read_only.clone()));
+        // Notification preferences live in the API database, so new mail
+        // notifications are only raised alongside the API
+        let notifications = if listeners.contains(&Listener::Api) {
+            let manager = NotificationManager::connect(&config.api_database_url())
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open notification database: {}", e)))?;
+            let branding = BrandingManager::connect(&config.api_database_url())
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open branding database: {}", e)))?;
+            let queue = SmtpQueue::from_config(&config).await?;
+            Some(Arc::new(
+                NotificationRouter::new(Arc::new(manager))
+                    .with_queue(Arc::new(queue))
+                    .with_hostname(&config.server.hostname)
+                    .with_branding(Arc::new(branding))
+                    .with_workers(workers.clone()),
+            ))
+        } else {
+            None
+        };
+        // Flag changes are shared between IMAP sessions and the API, and
+        // picked up from disk when other processes make them
+        let flag_events = Arc::new(FlagEventBus::new());
+        let roots = std::iter::once(&config.storage.maildir_path)
+            .chain(config.residency.regions.iter().map(|region| &region.maildir_path));
+        for root in roots {
+            if let Err(e) = flag_events.watch_maildir(std::path::Path::new(root)) {
+                warn!("Cannot watch {} for flag changes: {}", root, e);
+            }
+        }
+        // IMAP and API downloads draw from the same buckets; with limits
+        // disabled connections are still metered for the sessions API
+        let bandwidth = Arc::new(if config.bandwidth.enabled {
+            BandwidthLimiter::from_config(&config.bandwidth)
+        } else {
+            BandwidthLimiter::new(Default::default())
+        });
+        // SMTP checks clients against the blocklists whose counters the API
+        // reports
+        let dnsbl = build_dnsbl_checker(&config);
+        // SMTP checks SPF and DKIM with the validators whose cache counters
+        // the API reports
+        let (spf_validator, dkim_validator) =
+            SmtpSession::build_validators(&config.authentication);
+        // Both SMTP listeners scan with the clamd client whose counters the
+        // API reports
+        let antivirus = build_virus_scanner(&config);
+        // SMTP delivery accounts per-user pipeline usage that the API reports
+        let delivery_budget = Arc::new(DeliveryBudget::from_config(&config.delivery_budget));
+        // Post-delivery workers of SMTP mail, whose load the API reports
+        let post_delivery = config
+            .post_delivery
+            .enabled
+            .then(|| PostDeliveryQueue::start(&config.post_delivery, storage.clone()));
+        if let Some(queue) = &post_delivery {
+            queue.run_in(workers.clone());
+        }
+        if let Some(queue) = &search_queue {
+            queue.run_in(workers.clone());
+        }
+        // STARTTLS on the IMAP port and the IMAPS listener share certificates
+        let imap_tls = if config.imap.enable_tls {
+            match (&config.imap.tls_cert_path, &config.imap.tls_key_path) {
+                (Some(cert_path), Some(key_path)) => {
+                    Some(Arc::new(TlsConfig::from_pem_files(cert_path, key_path)?))
+                }
+                _ => Some(self.tls_config.clone().ok_or_else(|| {
+                    MailError::Config(
+                        "imap.enable_tls requires imap.tls_cert_path and imap.tls_key_path"
+                            .to_string(),
+                    )
+                })?),
+            }
+        } else {
+            None
+        };
+        // Two-factor authentication is set up through the API, so IMAP
+        // asks for codes when running alongside it
+        let mfa = if listeners.contains(&Listener::Api) {
+            let manager = MfaManager::connect(&config.api_database_url())
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open MFA database: {}", e)))?;
+            Some(Arc::new(manager))
+        } else {
+            None
+        };
+        // Devices and app passwords are managed through the API as well;
+        // sign-in alerts go out through its notification router
+        let devices = match &notifications {
+            Some(router) => {
+                let manager = DeviceManager::connect(&config.api_database_url())
+                    .await
+                    .map_err(|e| MailError::Storage(format!("Failed to open device database: {}", e)))?;
+                Some(Arc::new(manager.with_alerts(router.clone())))
+            }
+            None => None,
+        };
+        // Logins of every protocol are assessed against the same history
+        let login_anomalies = if config.login_anomaly.enabled {
+            let mut detector =
+                LoginAnomalyDetector::connect(&config.api_database_url(), config.login_anomaly.clone())
+                    .await
+                    .map_err(|e| {
+                        MailError::Storage(format!("Failed to open login anomaly database: {}", e))
+                    })?;
+            if let Some(router) = &notifications {
+                detector = detector.with_alerts(router.clone());
+            }
+            Some(Arc::new(detector))
+        } else {
+            None
+        };
+        // Moving mail into or out of Junk over IMAP trains the classifier
+        // that scores delivered mail
+        let spam_training = if config.spam.enabled {
+            let manager = SpamManager::connect(&config.api_database_url())
+                .await
+                .map_err(|e| MailError::Storage(format!("Failed to open spam database: {}", e)))?;
+            Some(Arc::new(manager))
+        } else {
+            None
+        };
+        // IMAP shows mail decrypted with the keys users upload through the API
+        let openpgp = build_openpgp_manager(&config).await?;
+        let imap_server = || {
+            let mut server = ImapServer::new(Arc::new(config.clone()));
+            if let Some(mfa) = &mfa {
+                server = server.with_mfa(mfa.clone());
+            }
+            if let Some(devices) = &devices {
+                server = server.with_devices(devices.clone());
+            }
+            if let Some(detector) = &login_anomalies {
+                server = server.with_login_anomalies(detector.clone());
+            }
+            if let Some(spam) = &spam_training {
+                server = server.with_spam_training(spam.clone());
+            }
+            if let Some(authenticator) = &shared_auth {
+                server = server.with_authenticator((**authenticator).clone());
+            }
+            if let Some(manager) = &residency {
+                server = server.with_residency(manager.map());
+            }
+            if let Some(manager) = &openpgp {
+                server = server.with_openpgp(manager.clone());
+            }
+            if let Some(index) = &metadata {
+                server = server.with_metadata(index.clone());
+            }
+            server
+                .with_quotas(quotas.clone())
+                .with_flag_events(flag_events.clone())
+                .with_bandwidth(bandwidth.clone())
+                .with_read_only(read_only.clone())
+        };
+
+        let mut services = Vec::new();
+        for listener in listeners {
+            let server = match listener {
+                Listener::Smtp => {
+                    let mut server =
+                        SmtpServer::with_security(config.clone(), storage.clone()).await?;
+                    if let Some(tls_config) = &self.tls_config {
+                        server = server.with_tls(tls_config.clone());
+                    }
+                    if let Some(authenticator) = &shared_auth {
+                        server = server.with_authenticator(authenticator.clone());
+                    }
+                    if let Some(router) = &notifications {
+                        server = server.with_notifications(router.clone());
+                    }
+                    if let Some(devices) = &devices {
+                        server = server.with_devices(devices.clone());
+                    }
+                    if let Some(detector) = &login_anomalies {
+                        server = server.with_login_anomalies(detector.clone());
+                    }
+                    if let Some(queue) = &post_delivery {
+                        server = server.with_post_delivery(queue.clone());
+                    }
+                    if let Some(checker) = &dnsbl {
+                        server = server.with_dnsbl(checker.clone());
+                    }
+                    if let Some(scanner) = &antivirus {
+                        server = server.with_antivirus(scanner.clone());
+                    }
+                    server = server
+                        .with_validators(spf_validator.clone(), dkim_validator.clone())
+                        .with_workers(workers.clone())
+                        .with_read_only(read_only.clone());
+                    Server::Smtp(
+                        server
+                            .with_recipient_quotas(quotas.clone())
+                            .with_delivery_budget(delivery_budget.clone()),
+                    )
+                }
+                Listener::Submission => {
+                    let mut server = SubmissionServer::with_components(
+                        config.clone(),
+                        storage.clone(),
+                        self.tls_config.clone(),
+                        shared_auth.clone(),
+                    )
+                    .await?;
+                    if let Some(devices) = &devices {
+                        server = server.with_devices(devices.clone());
+                    }
+                    if let Some(detector) = &login_anomalies {
+                        server = server.with_login_anomalies(detector.clone());
+                    }
+                    if let Some(scanner) = &antivirus {
+                        server = server.with_antivirus(scanner.clone());
+                    }
+                    Server::Submission(
+                        server
+                            .with_workers(workers.clone())
+                            .with_read_only(read_only.clone()),
+                    )
+                }
+                Listener::Imap => Server::Imap(match &imap_tls {
+                    Some(tls) => imap_server().with_tls(tls.clone()),
+                    None => imap_server(),
+                }),
+                Listener::Imaps => {
+                    let tls = imap_tls.clone().ok_or_else(|| {
+                        MailError::Config("IMAPS requires imap.enable_tls".to_string())
+                    })?;
+                    Server::Imap(imap_server().with_implicit_tls(tls))
+                }
+                Listener::Api => {
+                    let database_url = config.api_database_url();
+                    let authenticator = match &shared_auth {
+                        Some(authenticator) => (**authenticator).clone(),
+                        None => Authenticator::new(&database_url)
+                            .await?
+                            .with_cram_md5(config.smtp.allow_cram_md5),
+                    };
+                    let server = ApiServer::new(
+                        authenticator,
+                        self.jwt_secret.clone(),
+                        config.storage.maildir_path.clone(),
+                        database_url,
+                        self.api_addr.clone(),
+                    )
+                    .await
+                    .map_err(|e| MailError::Storage(format!("Failed to create API server: {}", e)))?;
+                    let queue = SmtpQueue::from_config(&config).await?;
+                    let mut server = server
+                        .with_queue(Arc::new(queue))
+                        .with_quota_manager(quotas.clone())
+                        .with_flag_events(flag_events.clone())
+                        .with_bandwidth(bandwidth.clone())
+                        .with_delivery_budget(delivery_budget.clone())
+                        .with_workers(workers.clone())
+                        .with_read_only(read_only.clone())
+                        .with_config(Arc::new(config.clone()));
+                    if let Some(router) = &notifications {
+                        server = server.with_notifications(router.clone());
+                    }
+                    if let Some(devices) = &devices {
+                        server = server.with_devices(devices.clone());
+                    }
+                    if let Some(detector) = &login_anomalies {
+                        server = server.with_login_anomalies(detector.clone());
+                    }
+                    if let Some(manager) = &residency {
+                        server = server.with_residency(manager.clone());
+                    }
+                    if let Some(index) = &metadata {
+                        server = server.with_metadata(index.clone());
+                    }
+                    if let Some(queue) = &post_delivery {
+                        server = server.with_post_delivery(queue.clone());
+                    }
+                    if let Some(queue) = &search_queue {
+                        server = server.with_search_queue(queue.clone());
+                    }
+                    if let Some(billing) = build_billing_manager(&config).await? {
+                        server = server.with_billing(billing);
+                    }
+                    if let Some(hooks) = build_hook_manager(&config).await? {
+                        server = server.with_hooks(hooks);
+                    }
+                    if let Some(checker) = &dnsbl {
+                        server = server.with_dnsbl(checker.clone());
+                    }
+                    if let Some(scanner) = &antivirus {
+                        server = server.with_antivirus(scanner.clone());
+                    }
+                    server =
+                        server.with_auth_caches(spf_validator.clone(), dkim_validator.clone());
+                    if let Some(roles) = build_role_account_manager(&config).await? {
+                        server = server.with_role_accounts(roles);
+                    }
+                    if let Some(store) = build_quarantine(&config).await? {
+                        server = server.with_quarantine(store, storage.clone());
+                    }
+                    if let Some(keys) = build_dkim_key_manager(&config).await? {
+                        server = server.with_dkim_keys(keys);
+                    }
+                    if let Some(manager) = &openpgp {
+                        server = server.with_openpgp(manager.clone());
+                    }
+                    Server::Api(server)
+                }
+            };
+            services.push((listener, server));
+        }
+
+        Ok(MailServer {
+            config,
+            storage,
+            api_addr: self.api_addr,
+            services,
+            notifications,
+            flag_events,
+            read_only,
+            metadata,
+            quotas,
+            quota_reconciler: Arc::new(quota_reconciler),
+            search_queue,
+            background_tasks: self.background_tasks,
+        })
+    }
+}
+
+/// A listener's server, created but not yet running
+enum Server {
+    Smtp(SmtpServer),
+    Submission(SubmissionServer),
+    Imap(ImapServer),
+    Api(ApiServer),
+}
+
+impl Server {
+    fn serve(self, listener: TcpListener) -> BoxFuture<'static, Result<()>> {
+        match self {
+            Server::Smtp(server) => Box::pin(async move { server.serve(listener).await }),
+            Server::Submission(server) => Box::pin(async move { server.serve(listener).await }),
+            Server::Imap(server) => Box::pin(async move { server.serve(listener).await }),
+            Server::Api(server) => {
+                Box::pin(async move { server.serve(listener).await.map_err(Into::into) })
+            }
+        }
+    }
+}
+
+/// A configured mail server, ready to start
+pub struct MailServer {
+    config: Config,
+    storage: Arc<MaildirStorage>,
+    api_addr: String,
+    services: Vec<(Listener, Server)>,
+    notifications: Option<Arc<NotificationRouter>>,
+    flag_events: Arc<FlagEventBus>,
+    read_only: Arc<ReadOnlyMode>,
+    metadata: Option<Arc<MetadataIndex>>,
+    quotas: Arc<QuotaManager>,
+    quota_reconciler: Arc<QuotaReconciler>,
+    search_queue: Option<Arc<IndexQueue>>,
+    background_tasks: bool,
+}
+
+impl MailServer {
+    pub fn builder(config: Config) -> MailServerBuilder {
+        MailServerBuilder::new(config)
+    }
+
+    /// Listeners that [`Self::start`] will run
+    pub fn listeners(&self) -> Vec<Listener> {
+        self.services.iter().map(|(listener, _)| *listener).collect()
+    }
+
+    /// Bind every listener, then run the servers and background tasks
+    pub async fn start(self) -> Result<MailServerHandle> {
+        let api = self.listeners().contains(&Listener::Api);
+
+        // Bind everything first so a taken port doesn't leave half a server running
+        let mut bound = Vec::new();
+        for (listener, server) in self.services {
+            let addr = match listener {
+                Listener::Smtp => &self.config.smtp.listen_addr,
+                Listener::Submission => &self.config.submission.listen_addr,
+                Listener::Imap => &self.config.imap.listen_addr,
+                Listener::Imaps => &self.config.imap.imaps_listen_addr,
+                Listener::Api => &self.api_addr,
+            };
+            let socket = TcpListener::bind(addr).await.map_err(|e| {
+                MailError::Config(format!("Failed to bind {} on {}: {}", listener.as_str(), addr, e))
+            })?;
+            bound.push((listener, server, socket));
+        }
+
+        let (exit_tx, exit_rx) = mpsc::unbounded_channel();
+        let mut services = Vec::new();
+        for (listener, server, socket) in bound {
+            let addr = socket.local_addr()?;
+            let status = Arc::new(Mutex::new(ServiceStatus::Running));
+
+            let task = tokio::spawn(server.serve(socket));
+            let abort = task.abort_handle();
+            let monitor = {
+                let status = status.clone();
+                let exit_tx = exit_tx.clone();
+                tokio::spawn(async move {
+                    let result = match task.await {
+                        Ok(Ok(())) => ServiceStatus::Stopped,
+                        Ok(Err(e)) => ServiceStatus::Failed(e.to_string()),
+                        Err(e) if e.is_cancelled() => ServiceStatus::Stopped,
+                        Err(e) => ServiceStatus::Failed(format!("task panicked: {}", e)),
+                    };
+                    if let ServiceStatus::Failed(reason) = &result {
+                        error!("{} server failed: {}", listener.as_str(), reason);
+                    }
+                    *status.lock().unwrap() = result;
+                    let _ = exit_tx.send(listener);
+                })
+            };
+
+            services.push(Service {
+                listener,
+                addr,
+                status,
+                abort,
+                monitor,
+            });
+        }
+
+        let background = if self.background_tasks {
+            let mut tasks = spawn_background_tasks(
+                &self.config,
+                &self.storage,
+                api,
+                self.notifications,
+                &self.flag_events,
+                &self.read_only,
+                self.metadata,
+            );
+            // Daily delivery limits start over every day
+            tasks.push(tokio::spawn(
+                self.quotas.run_daily_reset(DELIVERY_COUNT_RESET_INTERVAL),
+            ));
+            tasks.push(tokio::spawn(self.quota_reconciler.run(Duration::from_secs(
+                self.config.quota.recalculate_interval_secs,
+            ))));
+            // Messages IMAP clients append or expunge reach the search index
+            if let Some(queue) = &self.search_queue {
+                tasks.push(queue.follow(&self.flag_events, self.storage.clone()));
+            }
+            tasks
+        } else {
+            Vec::new()
+        };
+
+        Ok(MailServerHandle {
+            services,
+            background,
+            exits: exit_rx,
+            storage: self.storage,
+            started_at: Instant::now(),
+        })
+    }
+}
+
+/// State of a running listener
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "state", content = "reason")]
+pub enum ServiceStatus {
+    Running,
+    Stopped,
+    Failed(String),
+}
+
+/// Health of one listener
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceHealth {
+    pub listener: Listener,
+    pub addr: SocketAddr,
+    pub status: ServiceStatus,
+}
+
+/// Health of the whole server
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerHealth {
+    pub uptime_secs: u64,
+    pub services: Vec<ServiceHealth>,
+}
+
+impl ServerHealth {
+    /// Whether every listener is still running
+    pub fn is_healthy(&self) -> bool {
+        self.services
+            .iter()
+            .all(|service| service.status == ServiceStatus::Running)
+    }
+}
+
+struct Service {
+    listener: Listener,
+    addr: SocketAddr,
+    status: Arc<Mutex<ServiceStatus>>,
+    abort: AbortHandle,
+    monitor: JoinHandle<()>,
+}
+
+/// Handle to a started [`MailServer`]
+///
+/// Dropping the handle leaves the servers running; call
+/// [`Self::shutdown`] to stop them.
+pub struct MailServerHandle {
+    services: Vec<Service>,
+    background: Vec<JoinHandle<()>>,
+    exits: mpsc::UnboundedReceiver<Listener>,
+    storage: Arc<MaildirStorage>,
+    started_at: Instant,
+}
+
+impl MailServerHandle {
+    /// Address a listener is bound to
+    pub fn local_addr(&self, listener: Listener) -> Option<SocketAddr> {
+        self.services
+            .iter()
+            .find(|service| service.listener == listener)
+            .map(|service| service.addr)
+    }
+
+    /// Storage the listeners deliver to and read from
+    pub fn storage(&self) -> Arc<MaildirStorage> {
+        self.storage.clone()
+    }
+
+    /// Current state of every listener
+    pub fn health(&self) -> ServerHealth {
+        ServerHealth {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            services: self
+                .services
+                .iter()
+                .map(|service| ServiceHealth {
+                    listener: service.listener,
+                    addr: service.addr,
+                    status: service.status.lock().unwrap().clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Wait until a listener stops, returning which one and why
+    pub async fn wait(&mut self) -> Option<(Listener, ServiceStatus)> {
+        let listener = self.exits.recv().await?;
+        let status = self
+            .services
+            .iter()
+            .find(|service| service.listener == listener)
+            .map(|service| service.status.lock().unwrap().clone())?;
+        Some((listener, status))
+    }
+
+    /// Stop accepting connections and stop the background tasks
+    ///
+    /// Sessions already in progress are not interrupted.
+    pub async fn shutdown(self) {
+        for task in &self.background {
+            task.abort();
+        }
+        for service in &self.services {
+            service.abort.abort();
+        }
+        for service in self.services {
+            let _ = service.monitor.await;
+        }
+        info!("Mail server stopped");
+    }
+}
+
+/// Start forwarding flag changes to the AI runtime, the usage report
+/// scheduler, billing collector, TLS report sender and retention enforcement when enabled, the metadata index, shared copy collection, and greylist and aggregate report expiry, notification and quarantine digests alongside the admin API
+fn spawn_background_tasks(
+    config: &Config,
+    storage: &Arc<MaildirStorage>,
+    api: bool,
+    notifications: Option<Arc<NotificationRouter>>,
+    flag_events: &Arc<FlagEventBus>,
+    read_only: &Arc<ReadOnlyMode>,
+    metadata: Option<Arc<MetadataIndex>>,
+) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+
+    if let Some(index) = metadata {
+        // Changes made by other processes arrive as flag events; mail stored
+        // before the index existed is picked up by a first reconcile
+        tasks.push(index.follow(flag_events, storage.clone()));
+        let storage = storage.clone();
+        tasks.push(tokio::spawn(async move {
+            index.reconcile_storage(&storage).await;
+        }));
+    }
+
+    if storage.is_single_instance() {
+        tasks.push(tokio::spawn(
+            storage.clone().run_single_instance_gc(SINGLE_INSTANCE_GC_INTERVAL),
+        ));
+    }
+
+    // Keeps the read state of AI summaries in line with the mailboxes
+    let ai_url =
+        std::env::var("AI_RUNTIME_URL").unwrap_or_else(|_| "http://127.0.0.1:8888".to_string());
+    tasks.push(flag_events.forward_to(&ai_url));
+
+    if let Some(router) = notifications {
+        info!("Starting notification digest sender...");
+        tasks.push(tokio::spawn(router.run(NOTIFICATION_FLUSH_INTERVAL)));
+    }
+
+    if api {
+        let database_url = config.api_database_url();
+        let greylisting = GreylistConfig::from(&config.greylisting);
+        tasks.push(tokio::spawn(async move {
+            let manager = match GreylistManager::connect_with(&database_url, greylisting).await {
+                Ok(manager) => Arc::new(manager),
+                Err(e) => {
+                    error!("Failed to open greylist database: {}", e);
+                    return;
+                }
+            };
+
+            info!("Starting greylist expiry...");
+            manager.run_cleanup(GREYLIST_CLEANUP_INTERVAL).await;
+        }));
+
+        // Retention policies are managed through the API
+        if config.retention.enabled {
+            let database_url = config.api_database_url();
+            let interval = Duration::from_secs(config.retention.interval_secs);
+            let storage = storage.clone();
+            let paused = read_only.clone();
+            tasks.push(tokio::spawn(async move {
+                let manager = match RetentionManager::connect(&database_url).await {
+                    Ok(manager) => Arc::new(manager),
+                    Err(e) => {
+                        error!("Failed to open retention database: {}", e);
+                        return;
+                    }
+                };
+
+                info!("Starting retention enforcement...");
+                RetentionEnforcer::new(manager, storage)
+                    .with_read_only(paused)
+                    .run(interval)
+                    .await;
+            }));
+        }
+
+        // Storage jobs are queued through the API and cover every region
+        let database_url = config.api_database_url();
+        let mut roots = vec![std::path::PathBuf::from(&config.storage.maildir_path)];
+        roots.extend(
+            config
+                .residency
+                .regions
+                .iter()
+                .map(|region| std::path::PathBuf::from(&region.maildir_path)),
+        );
+        let hostname = config.server.hostname.clone();
+        let read_only = read_only.clone();
+        tasks.push(tokio::spawn(async move {
+            let manager = match StorageJobManager::connect(&database_url).await {
+                Ok(manager) => Arc::new(manager),
+                Err(e) => {
+                    error!("Failed to open storage job database: {}", e);
+                    return;
+                }
+            };
+
+            info!("Starting storage job runner...");
+            StorageJobRunner::new(manager, roots, &hostname)
+                .with_read_only(read_only)
+                .run(STORAGE_JOB_POLL_INTERVAL)
+                .await;
+        }));
+
+        // Received reports are read through the API
+        if config.dmarc_reports.enabled {
+            let config = config.clone();
+            tasks.push(tokio::spawn(async move {
+                let manager = match build_dmarc_report_manager(&config).await {
+                    Ok(Some(manager)) => manager,
+                    Ok(None) => return,
+                    Err(e) => {
+                        error!("Failed to open DMARC report database: {}", e);
+                        return;
+                    }
+                };
+
+                info!("Starting aggregate report expiry...");
+                manager.run_expiry(DMARC_REPORT_EXPIRY_INTERVAL).await;
+            }));
+        }
+
+        // Users release held spam through the API
+        if config.spam.enabled && config.spam.quarantine.enabled {
+            let config = config.clone();
+            let storage = storage.clone();
+            tasks.push(tokio::spawn(async move {
+                let store = match build_quarantine(&config).await {
+                    Ok(Some(store)) => store,
+                    Ok(None) => return,
+                    Err(e) => {
+                        error!("Failed to open spam quarantine: {}", e);
+                        return;
+                    }
+                };
+
+                info!("Starting quarantine digest sender...");
+                let from = format!("postmaster@{}", config.server.domain);
+                let interval =
+                    Duration::from_secs(config.spam.quarantine.digest_interval_hours.max(1) * 3600);
+                store.run_digests(storage, from, interval).await;
+            }));
+        }
+    }
+
+    if config.reporting.enabled {
+        let config = config.clone();
+        let storage = storage.clone();
+        tasks.push(tokio::spawn(async move {
+            let manager = match ReportingManager::connect(&config.api_database_url()).await {
+                Ok(manager) => Arc::new(manager),
+                Err(e) => {
+                    error!("Failed to open reporting database: {}", e);
+                    return;
+                }
+            };
+
+            // Admins outside the local domain get their reports via the outbound queue
+            let domain = config.server.domain.to_lowercase();
+            let queue = if config
+                .reporting
+                .recipients
+                .iter()
+                .any(|r| !r.to_lowercase().ends_with(&format!("@{}", domain)))
+            {
+                match SmtpQueue::from_config(&config).await {
+                    Ok(queue) => {
+                        let queue = Arc::new(queue);
+                        tokio::spawn(queue.clone().start_worker());
+                        Some(queue)
+                    }
+                    Err(e) => {
+                        error!("Failed to create report delivery queue: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            info!("Starting usage report scheduler...");
+            ReportScheduler::new(manager, &config, storage, queue)
+                .run()
+                .await;
+        }));
+    }
+
+    if config.billing.enabled {
+        let config = config.clone();
+        tasks.push(tokio::spawn(async move {
+            let manager = match BillingManager::connect(&config.api_database_url()).await {
+                Ok(manager) => Arc::new(manager),
+                Err(e) => {
+                    error!("Failed to open billing database: {}", e);
+                    return;
+                }
+            };
+
+            info!("Starting billing collector...");
+            BillingCollector::new(manager, &config).run().await;
+        }));
+    }
+
+    if config.tls_reporting.enabled {
+        let config = config.clone();
+        tasks.push(tokio::spawn(async move {
+            let manager = match TlsRptManager::connect(&config.api_database_url()).await {
+                Ok(manager) => Arc::new(manager),
+                Err(e) => {
+                    error!("Failed to open TLS reporting database: {}", e);
+                    return;
+                }
+            };
+
+            // Reports go to the recipient domains' published addresses
+            let queue = match SmtpQueue::from_config(&config).await {
+                Ok(queue) => Arc::new(queue),
+                Err(e) => {
+                    error!("Failed to create TLS report delivery queue: {}", e);
+                    return;
+                }
+            };
+            tokio::spawn(queue.clone().start_worker());
+
+            info!("Starting TLS report sender...");
+            TlsReportSender::new(manager, &config, queue).run().await;
+        }));
+    }
+
+    tasks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    fn test_config(dir: &std::path::Path) -> Config {
+        let mut config = Config::default();
+        config.smtp.listen_addr = "127.0.0.1:0".to_string();
+        config.imap.listen_addr = "127.0.0.1:0".to_string();
+        config.storage.maildir_path = dir.join("mail").to_string_lossy().to_string();
+        config.storage.database_url = "sqlite::memory:".to_string();
+        config
+    }
+
+    async fn greeting(addr: SocketAddr) -> String {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        reader.get_mut().write_all(b"QUIT\r\n").await.unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn test_start_health_and_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MailServer::builder(test_config(dir.path()))
+            .listeners([Listener::Smtp, Listener::Imap, Listener::Smtp])
+            .authenticator(Authenticator::new("sqlite::memory:").await.unwrap())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(server.listeners(), vec![Listener::Smtp, Listener::Imap]);
+
+        let handle = server.start().await.unwrap();
+        let smtp = handle.local_addr(Listener::Smtp).unwrap();
+        let imap = handle.local_addr(Listener::Imap).unwrap();
+        assert_ne!(smtp.port(), 0);
+        assert!(handle.local_addr(Listener::Api).is_none());
+
+        assert!(greeting(smtp).await.starts_with("220"));
+        assert!(greeting(imap).await.starts_with("* OK"));
+
+        let health = handle.health();
+        assert!(health.is_healthy());
+        assert_eq!(health.services.len(), 2);
+
+        handle.shutdown().await;
+        assert!(TcpStream::connect(smtp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_start_fails_on_taken_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = test_config(dir.path());
+        config.imap.listen_addr = taken.local_addr().unwrap().to_string();
+
+        let result = MailServer::builder(config)
+            .listeners([Listener::Smtp, Listener::Imap])
+            .build()
+            .await
+            .unwrap()
+            .start()
+            .await;
+        assert!(matches!(result, Err(MailError::Config(_))));
+    }
+
+    /// Send an IMAP command and return the response up to its tagged line
+    async fn command<S>(stream: &mut BufReader<S>, tag: &str, command: &str) -> String
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let request = format!("{} {}\r\n", tag, command);
+        stream.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            response.push_str(&line);
+            if line.starts_with(tag) || line.is_empty() {
+                return response;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coexistence_login_refused_before_starttls() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem").to_string_lossy().to_string();
+        let key_path = dir.path().join("key.pem").to_string_lossy().to_string();
+        crate::security::tls::generate_self_signed_cert("localhost", &cert_path, &key_path)
+            .unwrap();
+
+        // A legacy server that must never see the password
+        let legacy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let legacy_addr = legacy.local_addr().unwrap();
+        let (connected_tx, mut connected) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = legacy.accept().await {
+                let _ = connected_tx.send(socket);
+            }
+        });
+
+        let mut config = test_config(dir.path());
+        config.imap.enable_tls = true;
+        config.imap.tls_cert_path = Some(cert_path);
+        config.imap.tls_key_path = Some(key_path);
+        config.migration.coexistence = true;
+        config.migration.legacy_imap_addr = Some(legacy_addr.to_string());
+        let db_url = format!("sqlite://{}?mode=rwc", dir.path().join("users.db").display());
+        config.smtp.auth_database_url = Some(db_url.clone());
+        let authenticator = Authenticator::new(&db_url).await.unwrap();
+
+        let handle = MailServer::builder(config)
+            .listeners([Listener::Imap])
+            .authenticator(authenticator)
+            .build()
+            .await
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        let imap = handle.local_addr(Listener::Imap).unwrap();
+        let mut plain = BufReader::new(TcpStream::connect(imap).await.unwrap());
+        let mut greeting = String::new();
+        plain.read_line(&mut greeting).await.unwrap();
+        let login = command(&mut plain, "a1", "LOGIN legacy@example.com secret").await;
+        assert!(login.starts_with("a1 NO [PRIVACYREQUIRED]"), "{}", login);
+        assert!(connected.try_recv().is_err());
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_imap_starttls_and_imaps() {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+        use rustls::{ClientConfig, RootCertStore, ServerName};
+        use tokio_rustls::TlsConnector;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let cert = Certificate::from_params(CertificateParams::new(vec!["localhost".into()])).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&rustls::Certificate(ca.serialize_der().unwrap())).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let localhost = ServerName::try_from("localhost").unwrap();
+
+        let mut config = test_config(dir.path());
+        config.imap.enable_tls = true;
+        config.imap.tls_cert_path = Some(cert_path.to_string_lossy().to_string());
+        config.imap.tls_key_path = Some(key_path.to_string_lossy().to_string());
+        config.imap.imaps_listen_addr = "127.0.0.1:0".to_string();
+        let db_url = format!("sqlite://{}?mode=rwc", dir.path().join("users.db").display());
+        let authenticator = Authenticator::new(&db_url).await.unwrap();
+        authenticator.add_user("user@example.com", "secret").await.unwrap();
+
+        let handle = MailServer::builder(config)
+            .listeners([Listener::Imap, Listener::Imaps])
+            .authenticator(authenticator)
+            .build()
+            .await
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        // Plaintext: LOGIN is refused until STARTTLS
+        let imap = handle.local_addr(Listener::Imap).unwrap();
+        let mut plain = BufReader::new(TcpStream::connect(imap).await.unwrap());
+        let mut greeting = String::new();
+        plain.read_line(&mut greeting).await.unwrap();
+        let capability = command(&mut plain, "a1", "CAPABILITY").await;
+        assert!(capability.contains("STARTTLS LOGINDISABLED"), "{}", capability);
+        let login = command(&mut plain, "a2", "LOGIN user@example.com secret").await;
+        assert!(login.starts_with("a2 NO [PRIVACYREQUIRED]"), "{}", login);
+        assert!(command(&mut plain, "a3", "STARTTLS").await.starts_with("a3 OK"));
+
+        let tls = connector
+            .connect(localhost.clone(), plain.into_inner())
+            .await
+            .unwrap();
+        let mut tls = BufReader::new(tls);
+        let capability = command(&mut tls, "a4", "CAPABILITY").await;
+        assert!(!capability.contains("STARTTLS"), "{}", capability);
+        assert!(command(&mut tls, "a5", "STARTTLS").await.starts_with("a5 BAD"));
+        let login = command(&mut tls, "a6", "LOGIN user@example.com secret").await;
+        assert!(login.starts_with("a6 OK"), "{}", login);
+
+        // IMAPS: TLS from the first byte
+        let imaps = handle.local_addr(Listener::Imaps).unwrap();
+        let tls = connector
+            .connect(localhost, TcpStream::connect(imaps).await.unwrap())
+            .await
+            .unwrap();
+        let mut tls = BufReader::new(tls);
+        let mut greeting = String::new();
+        tls.read_line(&mut greeting).await.unwrap();
+        assert!(greeting.starts_with("* OK"));
+        let login = command(&mut tls, "b1", "LOGIN user@example.com secret").await;
+        assert!(login.starts_with("b1 OK"), "{}", login);
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_requires_trusted_proxies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.imap.proxy_protocol = true;
+        let error = match MailServer::builder(config).listeners([Listener::Imap]).build().await {
+            Ok(_) => panic!("PROXY headers accepted from any peer"),
+            Err(e) => e.to_string(),
+        };
+        assert!(error.contains("imap.proxy_trusted_ips"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_api_login_keeps_cram_md5_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}?mode=rwc", dir.path().join("users.db").display());
+        let mut config = test_config(dir.path());
+        config.storage.database_url = db_url.clone();
+        config.smtp.auth_database_url = Some(db_url.clone());
+        config.smtp.allow_cram_md5 = true;
+        Authenticator::new(&db_url)
+            .await
+            .unwrap()
+            .with_cram_md5(true)
+            .add_user("user@example.com", "secret")
+            .await
+            .unwrap();
+
+        let handle = MailServer::builder(config)
+            .listeners([Listener::Imap, Listener::Api])
+            .api_addr("127.0.0.1:0")
+            .build()
+            .await
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        let api = handle.local_addr(Listener::Api).unwrap();
+        let login = reqwest::Client::new()
+            .post(format!("http://{}/api/auth/login", api))
+            .json(&serde_json::json!({ "email": "user@example.com", "password": "secret" }))
+            .send()
+            .await
+            .unwrap();
+        assert!(login.status().is_success(), "{}", login.status());
+
+        let imap = handle.local_addr(Listener::Imap).unwrap();
+        let mut imap = BufReader::new(TcpStream::connect(imap).await.unwrap());
+        let mut greeting = String::new();
+        imap.read_line(&mut greeting).await.unwrap();
+        imap.get_mut()
+            .write_all(b"a1 AUTHENTICATE CRAM-MD5\r\n")
+            .await
+            .unwrap();
+        let mut continuation = String::new();
+        imap.read_line(&mut continuation).await.unwrap();
+        let challenge = BASE64
+            .decode(continuation.trim_start_matches("+ ").trim())
+            .unwrap();
+        let challenge = String::from_utf8(challenge).unwrap();
+        let digest = crate::security::sasl::cram_md5_digest(b"secret", &challenge);
+        let answer = BASE64.encode(format!("user@example.com {}", digest));
+        imap.get_mut()
+            .write_all(format!("{}\r\n", answer).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        imap.read_line(&mut response).await.unwrap();
+        assert!(response.starts_with("a1 OK"), "{}", response);
+    }
+}
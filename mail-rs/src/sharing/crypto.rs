@@ -0,0 +1,113 @@
+//! Encryption of shared messages
+//!
+//! The parameters match what browsers offer through WebCrypto: PBKDF2 with
+//! SHA-256 derives a 256-bit AES-GCM key from the passphrase, and the tag
+//! is appended to the ciphertext.
+
+use super::types::SealedPackage;
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use sha2::Sha256;
+
+/// PBKDF2 iterations of new packages
+pub const PBKDF2_ITERATIONS: u32 = 210_000;
+
+const SALT_LEN: usize = 16;
+
+/// Letters of generated passphrases, without look-alikes
+const PASSPHRASE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Random passphrase like `k3xq9-...`: four groups of five characters,
+/// about 99 bits
+pub fn generate_passphrase() -> String {
+    let mut rng = rand::thread_rng();
+    (0..4)
+        .map(|_| {
+            (0..5)
+                .map(|_| PASSPHRASE_ALPHABET[rng.gen_range(0..PASSPHRASE_ALPHABET.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Encrypt `plaintext` under `passphrase`
+pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<SealedPackage> {
+    seal_with(plaintext, passphrase, PBKDF2_ITERATIONS)
+}
+
+fn seal_with(plaintext: &[u8], passphrase: &str, iterations: u32) -> Result<SealedPackage> {
+    let mut rng = rand::thread_rng();
+    let salt: [u8; SALT_LEN] = rng.gen();
+    let nonce: [u8; NONCE_LEN] = rng.gen();
+    let key = derive_key(passphrase, &salt, iterations)?;
+
+    let mut ciphertext = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut ciphertext,
+    )
+    .map_err(|_| anyhow!("Failed to encrypt message"))?;
+
+    Ok(SealedPackage {
+        salt: salt.to_vec(),
+        nonce: nonce.to_vec(),
+        iterations,
+        ciphertext,
+    })
+}
+
+/// Decrypt a package, as the recipient's browser does
+pub fn open(package: &SealedPackage, passphrase: &str) -> Result<Vec<u8>> {
+    let key = derive_key(passphrase, &package.salt, package.iterations)?;
+    let nonce =
+        Nonce::try_assume_unique_for_key(&package.nonce).map_err(|_| anyhow!("Invalid nonce"))?;
+
+    let mut plaintext = package.ciphertext.clone();
+    let len = key
+        .open_in_place(nonce, Aad::empty(), &mut plaintext)
+        .map_err(|_| anyhow!("Wrong passphrase or damaged package"))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+/// Passphrases are compared trimmed and lowercased, as generated
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let passphrase = passphrase.trim().to_lowercase();
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow!("Invalid key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let passphrase = generate_passphrase();
+        assert_eq!(passphrase.len(), 23);
+        assert!(passphrase.split('-').all(
+            |group| group.len() == 5 && group.bytes().all(|b| PASSPHRASE_ALPHABET.contains(&b))
+        ));
+
+        let package = seal_with(b"Subject: Hi\r\n\r\nHello\r\n", &passphrase, 1000).unwrap();
+        assert_eq!(package.ciphertext.len(), 22 + 16);
+        assert_eq!(
+            open(&package, &format!(" {} ", passphrase.to_uppercase())).unwrap(),
+            b"Subject: Hi\r\n\r\nHello\r\n"
+        );
+        assert!(open(&package, "wrong").is_err());
+
+        let mut damaged = package.clone();
+        damaged.ciphertext[0] ^= 1;
+        assert!(open(&damaged, &passphrase).is_err());
+        let package = seal(b"x", &passphrase).unwrap();
+        assert_eq!(package.iterations, PBKDF2_ITERATIONS);
+        assert_eq!(open(&package, &passphrase).unwrap(), b"x");
+    }
+}
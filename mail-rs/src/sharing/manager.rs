@@ -0,0 +1,542 @@
+//! Share manager: share links, their encrypted messages and access log
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use uuid::Uuid;
+
+use super::crypto;
+use super::types::*;
+
+/// Longest stored user agent
+const MAX_USER_AGENT_LEN: usize = 200;
+
+/// Share manager
+pub struct ShareManager {
+    db: SqlitePool,
+}
+
+impl ShareManager {
+    /// Create a new share manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the share tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // Only a hash of the link token is stored; the ciphertext is wiped
+        // once the link stops working
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS share_links (
+                id TEXT PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                user TEXT NOT NULL,
+                mailbox TEXT NOT NULL,
+                message TEXT NOT NULL,
+                subject TEXT,
+                include_attachments INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                salt BLOB NOT NULL,
+                nonce BLOB NOT NULL,
+                iterations INTEGER NOT NULL,
+                ciphertext BLOB,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                revoked_at TEXT,
+                max_downloads INTEGER,
+                downloads INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_share_links_user ON share_links(user)")
+            .execute(&self.db)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS share_access (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                link_id TEXT NOT NULL,
+                at TEXT NOT NULL,
+                ip TEXT,
+                user_agent TEXT,
+                outcome TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_share_access_link ON share_access(link_id)")
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Encrypt `package`, a message of `user` built for `request`, and
+    /// create a link to it
+    pub async fn create(
+        &self,
+        user: &str,
+        request: &CreateShare,
+        subject: Option<String>,
+        package: &[u8],
+        expires_at: DateTime<Utc>,
+    ) -> Result<IssuedShare> {
+        self.wipe_inactive(Utc::now()).await?;
+
+        let passphrase = crypto::generate_passphrase();
+        let sealed = crypto::seal(package, &passphrase)?;
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token = URL_SAFE_NO_PAD.encode(token);
+
+        let link = ShareLink {
+            id: Uuid::new_v4().to_string(),
+            user: user.to_lowercase(),
+            mailbox: request.mailbox.clone(),
+            message: request.message.clone(),
+            subject,
+            include_attachments: request.include_attachments,
+            size: package.len(),
+            created_at: Utc::now(),
+            expires_at,
+            revoked_at: None,
+            max_downloads: request.max_downloads,
+            downloads: 0,
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO share_links
+                (id, token_hash, user, mailbox, message, subject, include_attachments,
+                 size, salt, nonce, iterations, ciphertext, created_at, expires_at,
+                 max_downloads)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&link.id)
+        .bind(hash_token(&token))
+        .bind(&link.user)
+        .bind(&link.mailbox)
+        .bind(&link.message)
+        .bind(&link.subject)
+        .bind(link.include_attachments)
+        .bind(link.size as i64)
+        .bind(&sealed.salt)
+        .bind(&sealed.nonce)
+        .bind(sealed.iterations as i64)
+        .bind(&sealed.ciphertext)
+        .bind(link.created_at.to_rfc3339())
+        .bind(link.expires_at.to_rfc3339())
+        .bind(link.max_downloads.map(i64::from))
+        .execute(&self.db)
+        .await?;
+
+        Ok(IssuedShare {
+            link,
+            token,
+            passphrase,
+        })
+    }
+
+    /// Share links of `user`, newest first
+    pub async fn list(&self, user: &str) -> Result<Vec<ShareLink>> {
+        let rows = sqlx::query("SELECT * FROM share_links WHERE user = ? ORDER BY created_at DESC")
+            .bind(user.to_lowercase())
+            .fetch_all(&self.db)
+            .await?;
+        rows.iter().map(link_from_row).collect()
+    }
+
+    /// Share link `id` of `user`
+    pub async fn get(&self, user: &str, id: &str) -> Result<Option<ShareLink>> {
+        let row = sqlx::query("SELECT * FROM share_links WHERE id = ? AND user = ?")
+            .bind(id)
+            .bind(user.to_lowercase())
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(link_from_row).transpose()
+    }
+
+    /// Share link with the token of its URL
+    pub async fn by_token(&self, token: &str) -> Result<Option<ShareLink>> {
+        let row = sqlx::query("SELECT * FROM share_links WHERE token_hash = ?")
+            .bind(hash_token(token))
+            .fetch_optional(&self.db)
+            .await?;
+        row.as_ref().map(link_from_row).transpose()
+    }
+
+    /// Revoke share link `id` of `user` and wipe its message
+    pub async fn revoke(&self, user: &str, id: &str) -> Result<Option<ShareLink>> {
+        sqlx::query(
+            r#"
+            UPDATE share_links SET revoked_at = ?, ciphertext = NULL
+            WHERE id = ? AND user = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .bind(user.to_lowercase())
+        .execute(&self.db)
+        .await?;
+        self.get(user, id).await
+    }
+
+    /// Downloads of share link `id` of `user`, newest first
+    pub async fn access_log(&self, user: &str, id: &str) -> Result<Option<Vec<ShareAccess>>> {
+        if self.get(user, id).await?.is_none() {
+            return Ok(None);
+        }
+        let rows = sqlx::query("SELECT * FROM share_access WHERE link_id = ? ORDER BY id DESC")
+            .bind(id)
+            .fetch_all(&self.db)
+            .await?;
+        rows.iter()
+            .map(access_from_row)
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    /// Download the encrypted message of a link, logging the attempt;
+    /// `None` for unknown tokens
+    pub async fn fetch(
+        &self,
+        token: &str,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> Result<Option<ShareFetch>> {
+        self.fetch_at(token, ip, user_agent, Utc::now()).await
+    }
+
+    async fn fetch_at(
+        &self,
+        token: &str,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<ShareFetch>> {
+        let Some(row) = sqlx::query("SELECT * FROM share_links WHERE token_hash = ?")
+            .bind(hash_token(token))
+            .fetch_optional(&self.db)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut link = link_from_row(&row)?;
+        let ciphertext: Option<Vec<u8>> = row.get("ciphertext");
+
+        let mut status = link.status(now);
+        if status == ShareStatus::Active {
+            // Count the download unless another one took the last slot
+            let counted = sqlx::query(
+                "UPDATE share_links SET downloads = downloads + 1 WHERE id = ? AND downloads = ?",
+            )
+            .bind(&link.id)
+            .bind(link.downloads as i64)
+            .execute(&self.db)
+            .await?
+            .rows_affected()
+                > 0;
+            if counted {
+                link.downloads += 1;
+            } else {
+                link = self
+                    .by_token(token)
+                    .await?
+                    .ok_or_else(|| anyhow!("Share link vanished"))?;
+                status = match link.status(now) {
+                    ShareStatus::Active => ShareStatus::Exhausted,
+                    status => status,
+                };
+            }
+        }
+        self.log_access(&link.id, now, ip, user_agent, status)
+            .await?;
+
+        if status != ShareStatus::Active {
+            self.wipe_inactive(now).await?;
+            return Ok(Some(ShareFetch::Refused(link)));
+        }
+        let ciphertext = ciphertext.ok_or_else(|| anyhow!("Shared message was wiped"))?;
+        let package = SealedPackage {
+            salt: row.get("salt"),
+            nonce: row.get("nonce"),
+            iterations: row.get::<i64, _>("iterations") as u32,
+            ciphertext,
+        };
+        Ok(Some(ShareFetch::Package(link, package)))
+    }
+
+    /// Wipe the messages of links that expired or reached their download
+    /// limit; returns how many were wiped
+    pub async fn wipe_inactive(&self, now: DateTime<Utc>) -> Result<usize> {
+        let rows = sqlx::query("SELECT * FROM share_links WHERE ciphertext IS NOT NULL")
+            .fetch_all(&self.db)
+            .await?;
+        let mut wiped = 0;
+        for row in &rows {
+            let link = link_from_row(row)?;
+            if link.status(now) != ShareStatus::Active {
+                sqlx::query("UPDATE share_links SET ciphertext = NULL WHERE id = ?")
+                    .bind(&link.id)
+                    .execute(&self.db)
+                    .await?;
+                wiped += 1;
+            }
+        }
+        Ok(wiped)
+    }
+
+    async fn log_access(
+        &self,
+        link_id: &str,
+        at: DateTime<Utc>,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+        outcome: ShareStatus,
+    ) -> Result<()> {
+        let user_agent =
+            user_agent.map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+        sqlx::query(
+            "INSERT INTO share_access (link_id, at, ip, user_agent, outcome) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(link_id)
+        .bind(at.to_rfc3339())
+        .bind(ip)
+        .bind(user_agent)
+        .bind(outcome.as_str())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Link tokens are random, so a fast hash is enough to protect them
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn link_from_row(row: &SqliteRow) -> Result<ShareLink> {
+    Ok(ShareLink {
+        id: row.get("id"),
+        user: row.get("user"),
+        mailbox: row.get("mailbox"),
+        message: row.get("message"),
+        subject: row.get("subject"),
+        include_attachments: row.get("include_attachments"),
+        size: row.get::<i64, _>("size") as usize,
+        created_at: parse_time(row.get("created_at"))?,
+        expires_at: parse_time(row.get("expires_at"))?,
+        revoked_at: row
+            .get::<Option<String>, _>("revoked_at")
+            .map(parse_time)
+            .transpose()?,
+        max_downloads: row
+            .get::<Option<i64>, _>("max_downloads")
+            .map(|max| max as u32),
+        downloads: row.get::<i64, _>("downloads") as u32,
+    })
+}
+
+fn access_from_row(row: &SqliteRow) -> Result<ShareAccess> {
+    let outcome: String = row.get("outcome");
+    Ok(ShareAccess {
+        at: parse_time(row.get("at"))?,
+        ip: row.get("ip"),
+        user_agent: row.get("user_agent"),
+        outcome: ShareStatus::parse(&outcome)
+            .ok_or_else(|| anyhow!("Unknown share access outcome: {}", outcome))?,
+    })
+}
+
+fn parse_time(value: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    async fn manager() -> ShareManager {
+        ShareManager::connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn request(max_downloads: Option<u32>) -> CreateShare {
+        CreateShare {
+            mailbox: "INBOX".to_string(),
+            message: "1700000000.M1P1.host".to_string(),
+            include_attachments: false,
+            expires_in_hours: None,
+            max_downloads,
+        }
+    }
+
+    fn package(fetch: Option<ShareFetch>) -> SealedPackage {
+        match fetch {
+            Some(ShareFetch::Package(_, package)) => package,
+            other => panic!("Expected a package, got {:?}", other),
+        }
+    }
+
+    fn refused(fetch: Option<ShareFetch>) -> ShareLink {
+        match fetch {
+            Some(ShareFetch::Refused(link)) => link,
+            other => panic!("Expected a refusal, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share_and_download() {
+        let manager = manager().await;
+        let now = Utc::now();
+        let message = b"Subject: Hi\r\n\r\nHello\r\n";
+        let share = manager
+            .create(
+                "Jane@example.com",
+                &request(Some(2)),
+                Some("Hi".to_string()),
+                message,
+                now + Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(share.link.user, "jane@example.com");
+        assert_eq!(share.link.size, message.len());
+
+        assert!(manager
+            .fetch("unknown", None, None)
+            .await
+            .unwrap()
+            .is_none());
+        let sealed = package(
+            manager
+                .fetch(&share.token, Some("203.0.113.7"), Some("Firefox"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(crypto::open(&sealed, &share.passphrase).unwrap(), message);
+        package(manager.fetch(&share.token, None, None).await.unwrap());
+
+        // The download limit is reached and the message wiped
+        let link = refused(
+            manager
+                .fetch(&share.token, Some("198.51.100.1"), None)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(link.downloads, 2);
+        assert_eq!(link.status(now), ShareStatus::Exhausted);
+        let ciphertext: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT ciphertext FROM share_links WHERE id = ?")
+                .bind(&link.id)
+                .fetch_one(&manager.db)
+                .await
+                .unwrap();
+        assert_eq!(ciphertext, None);
+
+        let log = manager
+            .access_log("jane@example.com", &link.id)
+            .await
+            .unwrap()
+            .unwrap();
+        let outcomes: Vec<_> = log.iter().map(|access| access.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [
+                ShareStatus::Exhausted,
+                ShareStatus::Active,
+                ShareStatus::Active
+            ]
+        );
+        assert_eq!(log[2].ip.as_deref(), Some("203.0.113.7"));
+        assert_eq!(log[2].user_agent.as_deref(), Some("Firefox"));
+        assert_eq!(
+            manager
+                .access_log("bob@example.com", &link.id)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expiry_and_revocation() {
+        let manager = manager().await;
+        let now = Utc::now();
+        let share = manager
+            .create(
+                "jane@example.com",
+                &request(None),
+                None,
+                b"x",
+                now + Duration::hours(1),
+            )
+            .await
+            .unwrap();
+
+        package(
+            manager
+                .fetch_at(&share.token, None, None, now)
+                .await
+                .unwrap(),
+        );
+        let link = refused(
+            manager
+                .fetch_at(&share.token, None, None, now + Duration::hours(2))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(link.status(now + Duration::hours(2)), ShareStatus::Expired);
+
+        let share = manager
+            .create(
+                "jane@example.com",
+                &request(None),
+                None,
+                b"x",
+                now + Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            manager
+                .revoke("bob@example.com", &share.link.id)
+                .await
+                .unwrap(),
+            None
+        );
+        let link = manager
+            .revoke("jane@example.com", &share.link.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.status(now), ShareStatus::Revoked);
+        refused(manager.fetch(&share.token, None, None).await.unwrap());
+
+        let links = manager.list("jane@example.com").await.unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].id, share.link.id);
+    }
+}
@@ -0,0 +1,21 @@
+//! Sharing single messages with people outside the system
+//!
+//! A user shares one of their messages, with or without its attachments,
+//! through an expiring link. The message is encrypted with AES-256-GCM
+//! under a key derived with PBKDF2-SHA256 from a one-time passphrase. The
+//! passphrase is shown to the user once and never stored, so the server
+//! only keeps ciphertext. The recipient opens the link, enters the
+//! passphrase the user sent them through another channel, and their browser
+//! decrypts the message with WebCrypto.
+//!
+//! Every download of the encrypted package is logged with the client's
+//! address. Links stop working when they expire, reach their download
+//! limit or are revoked, and their ciphertext is wiped then.
+
+pub mod crypto;
+pub mod manager;
+pub mod package;
+pub mod types;
+
+pub use manager::ShareManager;
+pub use types::*;
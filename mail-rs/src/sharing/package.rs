@@ -0,0 +1,146 @@
+//! Contents of shared messages
+//!
+//! A message shared without attachments keeps its bytes except for the
+//! parts that are attachments, which are cut out together with their
+//! delimiter lines. Text, HTML and inline parts stay as they are.
+
+use crate::mime::MimeEntity;
+
+/// The message to encrypt for a share
+pub fn build_package(message: &[u8], include_attachments: bool) -> Vec<u8> {
+    if include_attachments {
+        return message.to_vec();
+    }
+
+    let root = MimeEntity::parse(message);
+    let mut ranges = Vec::new();
+    collect_attachments(message, &root, &mut ranges);
+
+    let mut result = message.to_vec();
+    for (start, end) in ranges.into_iter().rev() {
+        result.drain(start..end);
+    }
+    result
+}
+
+/// Byte ranges of attachment parts with their delimiter lines, in order
+fn collect_attachments(message: &[u8], entity: &MimeEntity, ranges: &mut Vec<(usize, usize)>) {
+    if let Some(inner) = &entity.message {
+        collect_attachments(message, inner, ranges);
+    }
+    for (index, part) in entity.parts.iter().enumerate() {
+        if !is_attachment(part) {
+            collect_attachments(message, part, ranges);
+            continue;
+        }
+
+        let part_start = offset(message, part.header);
+        let part_end = part_start + part.header.len() + part.body.len();
+        // The delimiter line ends right before the part
+        let line_start = message[..part_start.saturating_sub(1)]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |pos| pos + 1);
+        // The line break before a delimiter belongs to it; the first part
+        // takes the one after it instead, since the one before may end the
+        // header or preamble
+        let (start, end) = if index > 0 {
+            (
+                line_start - line_break_before(message, line_start),
+                part_end,
+            )
+        } else {
+            (line_start, part_end + line_break_after(message, part_end))
+        };
+        ranges.push((start, end));
+    }
+}
+
+/// Parts with an attachment disposition, and named non-text parts
+fn is_attachment(part: &MimeEntity) -> bool {
+    if let Some((kind, _)) = part.disposition() {
+        return kind == "attachment";
+    }
+    let named = part
+        .content_type_params()
+        .iter()
+        .any(|(name, _)| name == "name");
+    named && !part.content_type.starts_with("text/") && !part.content_type.starts_with("multipart/")
+}
+
+fn line_break_before(message: &[u8], pos: usize) -> usize {
+    if message[..pos].ends_with(b"\r\n") {
+        2
+    } else if message[..pos].ends_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+fn line_break_after(message: &[u8], pos: usize) -> usize {
+    if message[pos..].starts_with(b"\r\n") {
+        2
+    } else if message[pos..].starts_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+/// Position of `slice` within `message`, which it was parsed from
+fn offset(message: &[u8], slice: &[u8]) -> usize {
+    slice.as_ptr() as usize - message.as_ptr() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_package() {
+        let message = concat!(
+            "Subject: Report\r\n",
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n",
+            "--b\r\n",
+            "Content-Type: application/pdf; name=\"first.pdf\"\r\n\r\n",
+            "JVBERi0=\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "See attached\r\n",
+            "--b\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-Disposition: inline\r\n\r\n",
+            "iVBORw0=\r\n",
+            "--b\r\n",
+            "Content-Type: text/csv\r\n",
+            "Content-Disposition: attachment; filename=\"data.csv\"\r\n\r\n",
+            "a,b\r\n",
+            "--b--\r\n",
+        );
+        assert_eq!(build_package(message.as_bytes(), true), message.as_bytes());
+
+        let package = build_package(message.as_bytes(), false);
+        assert_eq!(
+            String::from_utf8(package.clone()).unwrap(),
+            concat!(
+                "Subject: Report\r\n",
+                "Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n",
+                "--b\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "See attached\r\n",
+                "--b\r\n",
+                "Content-Type: image/png\r\n",
+                "Content-Disposition: inline\r\n\r\n",
+                "iVBORw0=\r\n",
+                "--b--\r\n",
+            )
+        );
+        let root = MimeEntity::parse(&package);
+        assert_eq!(root.parts.len(), 2);
+        assert_eq!(root.parts[0].body, b"See attached");
+
+        let plain = b"Subject: Hi\r\n\r\nHello\r\n";
+        assert_eq!(build_package(plain, false), plain);
+    }
+}
@@ -0,0 +1,133 @@
+//! Share link types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A link sharing one message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShareLink {
+    pub id: String,
+    pub user: String,
+    pub mailbox: String,
+    /// Maildir base name of the shared message
+    pub message: String,
+    pub subject: Option<String>,
+    pub include_attachments: bool,
+    /// Size of the shared message in bytes
+    pub size: usize,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Downloads allowed, unlimited if unset
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+}
+
+impl ShareLink {
+    /// Whether the link can be downloaded at `now`
+    pub fn status(&self, now: DateTime<Utc>) -> ShareStatus {
+        if self.revoked_at.is_some() {
+            ShareStatus::Revoked
+        } else if self.expires_at <= now {
+            ShareStatus::Expired
+        } else if self
+            .max_downloads
+            .is_some_and(|max_downloads| self.downloads >= max_downloads)
+        {
+            ShareStatus::Exhausted
+        } else {
+            ShareStatus::Active
+        }
+    }
+}
+
+/// State of a share link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareStatus {
+    Active,
+    Expired,
+    Revoked,
+    /// The download limit was reached
+    Exhausted,
+}
+
+impl ShareStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareStatus::Active => "active",
+            ShareStatus::Expired => "expired",
+            ShareStatus::Revoked => "revoked",
+            ShareStatus::Exhausted => "exhausted",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            ShareStatus::Active,
+            ShareStatus::Expired,
+            ShareStatus::Revoked,
+            ShareStatus::Exhausted,
+        ]
+        .into_iter()
+        .find(|status| status.as_str() == value)
+    }
+}
+
+/// One attempt to download a shared message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShareAccess {
+    pub at: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// `active` for a download, else why it was refused
+    pub outcome: ShareStatus,
+}
+
+/// Request to share a message
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateShare {
+    /// Mailbox holding the message
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    /// Maildir base name of the message, as returned on delivery
+    pub message: String,
+    #[serde(default)]
+    pub include_attachments: bool,
+    /// Lifetime of the link, `[sharing] default_expiry_hours` if unset
+    pub expires_in_hours: Option<u32>,
+    pub max_downloads: Option<u32>,
+}
+
+fn default_mailbox() -> String {
+    "INBOX".to_string()
+}
+
+/// A new share link with its secrets, which are only returned once
+#[derive(Debug, Clone)]
+pub struct IssuedShare {
+    pub link: ShareLink,
+    /// Token of the link URL
+    pub token: String,
+    /// Passphrase the recipient decrypts the message with
+    pub passphrase: String,
+}
+
+/// Encrypted message with what the recipient needs besides the passphrase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedPackage {
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
+    /// PBKDF2 iterations
+    pub iterations: u32,
+    /// AES-256-GCM ciphertext followed by the tag, as WebCrypto expects
+    pub ciphertext: Vec<u8>,
+}
+
+/// Outcome of a download of a shared message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShareFetch {
+    Package(ShareLink, SealedPackage),
+    /// The link is no longer active
+    Refused(ShareLink),
+}
@@ -0,0 +1,431 @@
+//! Applying Sieve results at final delivery
+//!
+//! Turns an incoming message into the [`MessageContext`] scripts run on,
+//! and the actions of a [`SieveResult`] into where the message is stored,
+//! which addresses it is redirected to, which Maildir flags it gets and
+//! how its header is edited.
+
+use chrono::Utc;
+
+use crate::mime::entity::header_fields;
+use crate::mime::{decode_header, MimeEntity, MimeParser};
+
+use super::types::*;
+
+/// IMAP system flags a delivered message can be stored with
+const MAILDIR_FLAGS: &[&str] = &["\\Seen", "\\Answered", "\\Flagged", "\\Deleted", "\\Draft"];
+
+/// What to do with a message after its recipient's script ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SieveDelivery {
+    /// Folders to store a copy in, `None` for INBOX; empty when the message
+    /// is discarded
+    pub folders: Vec<Option<String>>,
+    /// Addresses to redirect the message to
+    pub redirects: Vec<String>,
+    /// IMAP system flags to store the copies with
+    pub flags: Vec<String>,
+    /// Vacation response to send; a script sends at most one (RFC 5230)
+    pub vacation: Option<VacationConfig>,
+    /// Header edits to make to the stored copies, in order
+    pub header_edits: Vec<HeaderEdit>,
+}
+
+/// Edit of the header of a delivered message (RFC 5293)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderEdit {
+    /// Prepend, or with `last` append, a field
+    Add {
+        name: String,
+        value: String,
+        last: bool,
+    },
+    /// Remove the field at this 1-based position among those named `name`
+    Delete { name: String, index: usize },
+}
+
+impl SieveDelivery {
+    /// Plan the delivery for the actions of a script
+    ///
+    /// As in RFC 5228, `fileinto`, `redirect`, `keep` and `discard` cancel
+    /// the implicit keep, so a message a script only flags or stops on is
+    /// still delivered to INBOX.
+    pub fn from_result(result: &SieveResult) -> Self {
+        let mut delivery = Self::default();
+        let mut keep = false;
+        let mut cancelled = false;
+
+        for action in &result.actions {
+            match action {
+                SieveAction::Keep => {
+                    keep = true;
+                    cancelled = true;
+                }
+                SieveAction::Discard => cancelled = true,
+                SieveAction::FileInto(folder) => {
+                    cancelled = true;
+                    let folder = normalize_folder(folder);
+                    if !delivery.folders.contains(&folder) {
+                        delivery.folders.push(folder);
+                    }
+                }
+                SieveAction::Redirect(address) => {
+                    cancelled = true;
+                    let address = address.trim().to_string();
+                    if !address.is_empty() && !delivery.redirects.contains(&address) {
+                        delivery.redirects.push(address);
+                    }
+                }
+                SieveAction::Flag(flags) => {
+                    for flag in flags.iter().filter_map(|flag| maildir_flag(flag)) {
+                        if !delivery.flags.iter().any(|known| known == flag) {
+                            delivery.flags.push(flag.to_string());
+                        }
+                    }
+                }
+                SieveAction::Vacation(vacation) => {
+                    delivery.vacation.get_or_insert_with(|| vacation.clone());
+                }
+                SieveAction::AddHeader(add) => delivery.header_edits.push(HeaderEdit::Add {
+                    name: add.name.clone(),
+                    value: add.value.clone(),
+                    last: add.last,
+                }),
+                SieveAction::DeleteHeader(delete) => {
+                    if let Some(index) = delete.index {
+                        delivery.header_edits.push(HeaderEdit::Delete {
+                            name: delete.name.clone(),
+                            index,
+                        });
+                    }
+                }
+                // Handled while the script runs
+                SieveAction::Set(_)
+                | SieveAction::Global(_)
+                | SieveAction::Include(_)
+                | SieveAction::Return
+                | SieveAction::Stop => {}
+            }
+        }
+
+        if (keep || !cancelled) && !delivery.folders.contains(&None) {
+            delivery.folders.insert(0, None);
+        }
+        delivery
+    }
+
+    /// Whether the message is stored nowhere and redirected nowhere
+    pub fn is_discard(&self) -> bool {
+        self.folders.is_empty() && self.redirects.is_empty()
+    }
+
+    /// `message` with the header edits made; the body is left as it is
+    pub fn edit_header(&self, message: &[u8]) -> Vec<u8> {
+        if self.header_edits.is_empty() {
+            return message.to_vec();
+        }
+        let entity = MimeEntity::parse(message);
+        let mut fields: Vec<(String, Vec<u8>)> = header_fields(entity.header)
+            .into_iter()
+            .map(|(name, raw)| {
+                let mut raw = raw.to_vec();
+                if !raw.ends_with(b"\n") {
+                    raw.extend_from_slice(b"\r\n");
+                }
+                (name, raw)
+            })
+            .collect();
+
+        for edit in &self.header_edits {
+            match edit {
+                HeaderEdit::Add { name, value, last } => {
+                    let field = (
+                        name.to_lowercase(),
+                        format!("{}: {}\r\n", name, value).into_bytes(),
+                    );
+                    match last {
+                        true => fields.push(field),
+                        false => fields.insert(0, field),
+                    }
+                }
+                HeaderEdit::Delete { name, index } => {
+                    let position = fields
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (field, _))| field.eq_ignore_ascii_case(name))
+                        .map(|(position, _)| position)
+                        .nth(index.saturating_sub(1))
+                        .filter(|_| *index > 0);
+                    if let Some(position) = position {
+                        fields.remove(position);
+                    }
+                }
+            }
+        }
+
+        let mut edited: Vec<u8> = fields.into_iter().flat_map(|(_, raw)| raw).collect();
+        edited.extend_from_slice(b"\r\n");
+        edited.extend_from_slice(entity.body);
+        edited
+    }
+}
+
+impl MessageContext {
+    /// Context of a raw RFC 5322 message
+    pub fn from_message(message: &[u8]) -> Self {
+        let entity = MimeEntity::parse(message);
+        let headers: Vec<(String, String)> = header_fields(entity.header)
+            .into_iter()
+            .filter_map(|(_, raw)| {
+                let raw = String::from_utf8_lossy(raw);
+                let (name, value) = raw.split_once(':')?;
+                let value = value
+                    .split(['\r', '\n'])
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Some((name.trim().to_string(), decode_header(&value)))
+            })
+            .collect();
+        let first = |name: &str| {
+            headers
+                .iter()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default()
+        };
+        let addresses = |name: &str| -> Vec<String> {
+            headers
+                .iter()
+                .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+                .flat_map(|(_, value)| value.split(','))
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        let mut body_parts = Vec::new();
+        collect_parts(&entity, &mut body_parts);
+
+        Self {
+            from: first("from"),
+            to: addresses("to"),
+            cc: addresses("cc"),
+            subject: first("subject"),
+            body: String::from_utf8_lossy(entity.body).into_owned(),
+            size: message.len() as u64,
+            headers,
+            body_parts,
+            envelope_from: None,
+            envelope_to: vec![],
+            received_at: Utc::now(),
+        }
+    }
+
+    /// Add the SMTP envelope the message was received with
+    pub fn with_envelope(mut self, from: &str, to: &str) -> Self {
+        self.envelope_from = Some(from.to_string());
+        self.envelope_to = vec![to.to_string()];
+        self
+    }
+}
+
+/// Decoded leaf parts of `entity`, encapsulated messages included
+fn collect_parts(entity: &MimeEntity, parts: &mut Vec<(String, String)>) {
+    if let Some(message) = &entity.message {
+        collect_parts(message, parts);
+    } else if !entity.parts.is_empty() {
+        for part in &entity.parts {
+            collect_parts(part, parts);
+        }
+    } else if !entity.content_type.starts_with("multipart/") {
+        let decoded = match entity.encoding().as_str() {
+            "quoted-printable" => MimeParser::decode_quoted_printable(entity.body),
+            "base64" => MimeParser::decode_base64(entity.body).unwrap_or_default(),
+            _ => entity.body.to_vec(),
+        };
+        parts.push((
+            entity.content_type.clone(),
+            String::from_utf8_lossy(&decoded).into_owned(),
+        ));
+    }
+}
+
+/// `INBOX` in any case means the inbox; other names are kept as written
+fn normalize_folder(folder: &str) -> Option<String> {
+    let folder = folder.trim();
+    if folder.is_empty() || folder.eq_ignore_ascii_case("INBOX") {
+        None
+    } else {
+        Some(folder.to_string())
+    }
+}
+
+/// IMAP system flag a Sieve flag stands for; keywords have no Maildir
+/// equivalent
+fn maildir_flag(flag: &str) -> Option<&'static str> {
+    let name = flag.trim().trim_start_matches('\\');
+    MAILDIR_FLAGS
+        .iter()
+        .find(|known| known[1..].eq_ignore_ascii_case(name))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(actions: Vec<SieveAction>) -> SieveDelivery {
+        SieveDelivery::from_result(&SieveResult {
+            actions,
+            implicit_keep: true,
+        })
+    }
+
+    #[test]
+    fn test_delivery_plan() {
+        assert_eq!(plan(vec![]).folders, vec![None]);
+        assert_eq!(plan(vec![SieveAction::Stop]).folders, vec![None]);
+        assert!(plan(vec![SieveAction::Discard]).is_discard());
+
+        let delivery = plan(vec![
+            SieveAction::FileInto("Lists".to_string()),
+            SieveAction::FileInto("Lists".to_string()),
+            SieveAction::Flag(vec!["\\seen".to_string(), "$Important".to_string()]),
+            SieveAction::Flag(vec!["Flagged".to_string(), "\\Seen".to_string()]),
+        ]);
+        assert_eq!(delivery.folders, vec![Some("Lists".to_string())]);
+        assert_eq!(delivery.flags, vec!["\\Seen", "\\Flagged"]);
+
+        let delivery = plan(vec![
+            SieveAction::Redirect("bob@example.org".to_string()),
+            SieveAction::Keep,
+            SieveAction::FileInto("inbox".to_string()),
+        ]);
+        assert_eq!(delivery.folders, vec![None]);
+        assert_eq!(delivery.redirects, vec!["bob@example.org"]);
+
+        let delivery = plan(vec![
+            SieveAction::Discard,
+            SieveAction::Redirect("bob@example.org".to_string()),
+        ]);
+        assert!(delivery.folders.is_empty());
+        assert!(!delivery.is_discard());
+
+        // Vacation keeps the implicit keep and can go with a discard
+        let vacation = |body: &str| VacationConfig {
+            subject: String::new(),
+            body: body.to_string(),
+            days: 7,
+            addresses: vec![],
+            from: None,
+            handle: None,
+            mime: false,
+        };
+        let delivery = plan(vec![SieveAction::Vacation(vacation("Away"))]);
+        assert_eq!(delivery.folders, vec![None]);
+        let delivery = plan(vec![
+            SieveAction::Vacation(vacation("Away")),
+            SieveAction::Vacation(vacation("Gone")),
+            SieveAction::Discard,
+        ]);
+        assert!(delivery.is_discard());
+        assert_eq!(delivery.vacation, Some(vacation("Away")));
+    }
+
+    #[test]
+    fn test_message_context() {
+        let message = b"From: Alice <alice@example.com>\r\n\
+            To: bob@example.com, carol@example.com\r\n\
+            Subject: Weekly\r\n  report\r\n\
+            List-Id: <team.example.com>\r\n\
+            \r\n\
+            Numbers inside\r\n";
+        let context = MessageContext::from_message(message);
+        assert_eq!(context.from, "Alice <alice@example.com>");
+        assert_eq!(context.to, vec!["bob@example.com", "carol@example.com"]);
+        assert!(context.cc.is_empty());
+        assert_eq!(context.subject, "Weekly report");
+        assert!(context
+            .headers
+            .contains(&("List-Id".to_string(), "<team.example.com>".to_string())));
+        assert_eq!(context.body, "Numbers inside\r\n");
+        assert_eq!(context.size, message.len() as u64);
+        assert_eq!(
+            context.body_parts,
+            vec![("text/plain".to_string(), "Numbers inside\r\n".to_string())]
+        );
+        assert_eq!(context.envelope_from, None);
+    }
+
+    #[test]
+    fn test_message_context_decodes_headers() {
+        let message = b"From: =?UTF-8?Q?Andr=C3=A9?= <andre@example.com>\r\n\
+            Subject: =?UTF-8?B?UsOpdW5pb24=?= urgent\r\n\
+            \r\n\
+            Body\r\n";
+        let context = MessageContext::from_message(message);
+        assert_eq!(context.from, "André <andre@example.com>");
+        assert_eq!(context.subject, "Réunion urgent");
+    }
+
+    #[test]
+    fn test_message_context_parts() {
+        let message = b"From: alice@example.com\r\n\
+            Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Caf=C3=A9\r\n\
+            --b\r\n\
+            Content-Type: text/html\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            PGI+Q2Fmw6k8L2I+\r\n\
+            --b--\r\n";
+        let context = MessageContext::from_message(message)
+            .with_envelope("alice@example.com", "bob@example.com");
+        assert_eq!(context.body_parts.len(), 2);
+        assert_eq!(context.body_parts[0].0, "text/plain");
+        assert!(context.body_parts[0].1.starts_with("Café"));
+        assert_eq!(context.body_parts[1], ("text/html".to_string(), "<b>Café</b>".to_string()));
+        assert_eq!(context.envelope_from.as_deref(), Some("alice@example.com"));
+        assert_eq!(context.envelope_to, vec!["bob@example.com"]);
+    }
+
+    #[test]
+    fn test_edit_header() {
+        let delivery = plan(vec![
+            SieveAction::AddHeader(AddHeader {
+                name: "X-Sieve".to_string(),
+                value: "seen".to_string(),
+                last: false,
+            }),
+            SieveAction::AddHeader(AddHeader {
+                name: "X-Last".to_string(),
+                value: "1".to_string(),
+                last: true,
+            }),
+            SieveAction::DeleteHeader(DeleteHeader {
+                name: "x-spam".to_string(),
+                index: Some(2),
+                last: false,
+                values: vec![],
+                match_type: MatchType::Is,
+                comparator: Comparator::default(),
+            }),
+        ]);
+        assert_eq!(delivery.folders, vec![None]);
+
+        let message = b"X-Spam: 1\r\nSubject: Hi\r\nX-Spam: 2\r\n folded\r\n\r\nBody\r\n";
+        assert_eq!(
+            delivery.edit_header(message),
+            b"X-Sieve: seen\r\nX-Spam: 1\r\nSubject: Hi\r\nX-Last: 1\r\n\r\nBody\r\n".to_vec()
+        );
+        assert_eq!(plan(vec![]).edit_header(message), message.to_vec());
+    }
+}
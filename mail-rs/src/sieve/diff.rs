@@ -0,0 +1,103 @@
+//! Line diff between script versions
+
+use serde::{Deserialize, Serialize};
+
+/// Kind of a diff line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    /// Line present in both versions
+    Equal,
+    /// Line only in the newer version
+    Insert,
+    /// Line only in the older version
+    Delete,
+}
+
+/// One line of a diff
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub text: String,
+}
+
+/// Lines of `old` and `new`, in order, marked as kept, inserted or deleted
+///
+/// Uses the longest common subsequence, so unchanged lines between two
+/// edits stay unchanged in the output.
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // lcs[i][j]: length of the common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let line = |op, text: &str| DiffLine {
+        op,
+        text: text.to_string(),
+    };
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(line(DiffOp::Equal, old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(line(DiffOp::Delete, old[i]));
+            i += 1;
+        } else {
+            lines.push(line(DiffOp::Insert, new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|text| line(DiffOp::Delete, text)));
+    lines.extend(new[j..].iter().map(|text| line(DiffOp::Insert, text)));
+    lines
+}
+
+/// Render diff lines with ` `, `+` and `-` prefixes
+pub fn unified(lines: &[DiffLine]) -> String {
+    lines
+        .iter()
+        .map(|line| {
+            let prefix = match line.op {
+                DiffOp::Equal => ' ',
+                DiffOp::Insert => '+',
+                DiffOp::Delete => '-',
+            };
+            format!("{}{}\n", prefix, line.text)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_lines() {
+        let old =
+            "require \"fileinto\";\nif header :contains \"subject\" \"sale\" {\n  discard;\n}\n";
+        let new = "require \"fileinto\";\nif header :contains \"subject\" \"sale\" {\n  fileinto \"Promotions\";\n}\nkeep;\n";
+
+        let lines = diff_lines(old, new);
+        assert_eq!(
+            unified(&lines),
+            " require \"fileinto\";\n if header :contains \"subject\" \"sale\" {\n-  discard;\n+  fileinto \"Promotions\";\n }\n+keep;\n"
+        );
+        assert!(diff_lines(old, old)
+            .iter()
+            .all(|line| line.op == DiffOp::Equal));
+        assert_eq!(diff_lines("", "keep;").len(), 1);
+    }
+}
@@ -1,171 +1,206 @@
 //! Sieve script executor
 //!
-//! Evaluates conditions and executes actions on messages.
+//! Evaluates conditions and executes actions on messages. Strings in tests
+//! and actions are expanded with the script's variables (RFC 5229), and
+//! `include` runs scripts loaded beforehand into [`IncludedScripts`]
+//! (RFC 6609). Header edits (RFC 5293) are seen by later tests, and end up
+//! in the result with the fields they removed numbered.
+//!
+//! `:matches` and `:regex` keys (draft-ietf-sieve-regex) are compiled with
+//! size limits and matched in linear time, so a script cannot stall
+//! delivery with a pathological pattern.
 
-use anyhow::Result;
-use regex::Regex;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Timelike, Utc};
+use regex::{Regex, RegexBuilder};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
+use super::parser::{is_field_name, is_variable_name};
 use super::types::*;
 
+/// Deepest nesting of included scripts
+pub const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Most scripts one run includes, counting repeats
+pub const MAX_INCLUDES: usize = 100;
+
+/// Longest value of a variable, in characters
+pub const MAX_VARIABLE_LENGTH: usize = 4096;
+
+/// Longest `:matches` or `:regex` key, in characters
+pub const MAX_PATTERN_LENGTH: usize = 1024;
+
+/// Largest compiled program of a `:matches` or `:regex` key, in bytes
+pub const MAX_REGEX_SIZE: usize = 256 * 1024;
+
+/// Longest part of a value `:matches` and `:regex` keys look at, in bytes
+///
+/// Matching takes time linear in the input, so this bounds the time a
+/// key can take together with [`MAX_REGEX_SIZE`].
+pub const MAX_MATCH_INPUT: usize = 1024 * 1024;
+
+/// Header fields scripts cannot delete (RFC 5293)
+const PROTECTED_HEADERS: &[&str] = &["received", "auto-submitted"];
+
+/// Scripts a script can include, by location and name
+#[derive(Debug, Clone, Default)]
+pub struct IncludedScripts {
+    scripts: HashMap<(IncludeLocation, String), Vec<SieveRule>>,
+}
+
+impl IncludedScripts {
+    pub fn insert(&mut self, location: IncludeLocation, name: &str, rules: Vec<SieveRule>) {
+        self.scripts.insert((location, name.to_string()), rules);
+    }
+
+    pub fn get(&self, location: IncludeLocation, name: &str) -> Option<&[SieveRule]> {
+        self.scripts
+            .get(&(location, name.to_string()))
+            .map(Vec::as_slice)
+    }
+
+    pub fn contains(&self, location: IncludeLocation, name: &str) -> bool {
+        self.scripts.contains_key(&(location, name.to_string()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+}
+
 /// Sieve rule executor
 pub struct SieveExecutor;
 
 impl SieveExecutor {
     /// Execute a list of rules on a message
     pub fn execute(rules: &[SieveRule], message: &MessageContext) -> Result<SieveResult> {
-        let mut result = SieveResult::default();
-
-        for rule in rules {
-            let matched = Self::evaluate_condition(&rule.condition, message)?;
-
-            if matched {
-                // Execute main actions
-                for action in &rule.actions {
-                    result.actions.push(action.clone());
-                    if *action == SieveAction::Stop {
-                        result.implicit_keep = false;
-                        return Ok(result);
-                    }
-                    if *action == SieveAction::Discard {
-                        result.implicit_keep = false;
-                    }
-                    if *action == SieveAction::Keep {
-                        result.implicit_keep = false;
-                    }
-                }
-            } else {
-                // Check elsif branches
-                let mut elsif_matched = false;
-                for (elsif_cond, elsif_actions) in &rule.elsif_branches {
-                    if Self::evaluate_condition(elsif_cond, message)? {
-                        elsif_matched = true;
-                        for action in elsif_actions {
-                            result.actions.push(action.clone());
-                            if *action == SieveAction::Stop {
-                                result.implicit_keep = false;
-                                return Ok(result);
-                            }
-                            if *action == SieveAction::Discard {
-                                result.implicit_keep = false;
-                            }
-                            if *action == SieveAction::Keep {
-                                result.implicit_keep = false;
-                            }
-                        }
-                        break;
-                    }
-                }
+        Self::execute_with_includes(rules, message, &IncludedScripts::default())
+    }
 
-                // Check else
-                if !elsif_matched {
-                    if let Some(else_actions) = &rule.else_actions {
-                        for action in else_actions {
-                            result.actions.push(action.clone());
-                            if *action == SieveAction::Stop {
-                                result.implicit_keep = false;
-                                return Ok(result);
-                            }
-                            if *action == SieveAction::Discard {
-                                result.implicit_keep = false;
-                            }
-                            if *action == SieveAction::Keep {
-                                result.implicit_keep = false;
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    /// Execute a list of rules on a message, running the `includes` they
+    /// ask for; a missing script fails the run unless its include is
+    /// `:optional`
+    pub fn execute_with_includes(
+        rules: &[SieveRule],
+        message: &MessageContext,
+        includes: &IncludedScripts,
+    ) -> Result<SieveResult> {
+        let mut run = Run::new(message, includes);
+        run.script(rules, 0, None)?;
+        Ok(run.result)
+    }
 
-        Ok(result)
+    /// Execute rules like [`Self::execute_with_includes`], recording the
+    /// branch each rule took and the actions it added
+    pub fn trace(
+        rules: &[SieveRule],
+        message: &MessageContext,
+        includes: &IncludedScripts,
+    ) -> Result<SieveTestResult> {
+        let mut run = Run::new(message, includes);
+        run.steps = Some(Vec::new());
+        run.script(rules, 0, None)?;
+        Ok(SieveTestResult {
+            actions: run.result.actions,
+            implicit_keep: run.result.implicit_keep,
+            steps: run.steps.unwrap_or_default(),
+        })
     }
 
-    /// Evaluate a condition against a message
-    fn evaluate_condition(condition: &SieveCondition, message: &MessageContext) -> Result<bool> {
-        match condition {
-            SieveCondition::True => Ok(true),
-            SieveCondition::False => Ok(false),
-            SieveCondition::Not(inner) => Ok(!Self::evaluate_condition(inner, message)?),
-            SieveCondition::AllOf(conditions) => {
-                for cond in conditions {
-                    if !Self::evaluate_condition(cond, message)? {
-                        return Ok(false);
-                    }
-                }
-                Ok(true)
-            }
-            SieveCondition::AnyOf(conditions) => {
-                for cond in conditions {
-                    if Self::evaluate_condition(cond, message)? {
-                        return Ok(true);
-                    }
-                }
-                Ok(false)
-            }
-            SieveCondition::Header(test) => Self::evaluate_header_test(test, message),
-            SieveCondition::Address(test) => Self::evaluate_address_test(test, message),
-            SieveCondition::Size(test) => Self::evaluate_size_test(test, message),
-            SieveCondition::Exists(headers) => Self::evaluate_exists_test(headers, message),
-        }
+    /// Scripts the rules include, in script order
+    pub fn includes(rules: &[SieveRule]) -> Vec<&IncludeScript> {
+        rules
+            .iter()
+            .flat_map(|rule| {
+                rule.actions
+                    .iter()
+                    .chain(rule.elsif_branches.iter().flat_map(|(_, actions)| actions))
+                    .chain(rule.else_actions.iter().flatten())
+            })
+            .filter_map(|action| match action {
+                SieveAction::Include(include) => Some(include),
+                _ => None,
+            })
+            .collect()
     }
 
-    /// Evaluate a header test
-    fn evaluate_header_test(test: &HeaderTest, message: &MessageContext) -> Result<bool> {
-        for header_name in &test.headers {
-            let header_name_lower = header_name.to_lowercase();
+    /// Evaluate a condition against a message, without variables
+    #[cfg(test)]
+    fn evaluate_condition(condition: &SieveCondition, message: &MessageContext) -> Result<bool> {
+        let includes = IncludedScripts::default();
+        Run::new(message, &includes).evaluate(condition, &Scope::default())
+    }
 
-            // Find matching headers
-            for (name, value) in &message.headers {
-                if name.to_lowercase() != header_name_lower {
-                    continue;
-                }
+    /// Values of the `name` header fields
+    fn header_values<'a>(name: &str, message: &'a MessageContext) -> Vec<&'a str> {
+        message
+            .headers
+            .iter()
+            .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
 
-                // Test against each value
-                for test_value in &test.values {
-                    if Self::string_match(value, test_value, &test.match_type, &test.comparator) {
-                        return Ok(true);
-                    }
-                }
-            }
+    /// Whether `content_type` is `wanted`, a type of any subtype, or any
+    /// type when `wanted` is empty
+    fn content_type_match(content_type: &str, wanted: &str) -> bool {
+        let wanted = wanted.trim().to_lowercase();
+        if wanted.is_empty() {
+            return true;
+        }
+        match wanted.contains('/') {
+            true => content_type == wanted,
+            false => content_type.split('/').next() == Some(wanted.as_str()),
         }
-
-        Ok(false)
     }
 
-    /// Evaluate an address test
-    fn evaluate_address_test(test: &AddressTest, message: &MessageContext) -> Result<bool> {
-        for header_name in &test.headers {
-            let header_name_lower = header_name.to_lowercase();
-
-            // Get addresses from appropriate header
-            let addresses: Vec<&str> = match header_name_lower.as_str() {
-                "from" => vec![message.from.as_str()],
-                "to" => message.to.iter().map(|s| s.as_str()).collect(),
-                "cc" => message.cc.iter().map(|s| s.as_str()).collect(),
-                _ => {
-                    // Look up in headers
-                    message
-                        .headers
-                        .iter()
-                        .filter(|(n, _)| n.to_lowercase() == header_name_lower)
-                        .map(|(_, v)| v.as_str())
-                        .collect()
-                }
-            };
-
-            for address in addresses {
-                let test_part = Self::extract_address_part(address, &test.address_part);
-
-                for test_value in &test.values {
-                    if Self::string_match(&test_part, test_value, &test.match_type, &test.comparator)
-                    {
-                        return Ok(true);
-                    }
-                }
-            }
+    /// Whether any of `values` matches any of `keys`; `:count` compares
+    /// the number of values with the keys instead
+    fn match_values(
+        values: &[String],
+        keys: &[String],
+        match_type: &MatchType,
+        comparator: &Comparator,
+    ) -> bool {
+        if let MatchType::Count(op) = match_type {
+            let count = values.len().to_string();
+            return keys
+                .iter()
+                .any(|key| op.holds(Self::compare(&count, key, &Comparator::AsciiNumeric)));
         }
+        values.iter().any(|value| {
+            keys.iter()
+                .any(|key| Self::string_match(value, key, match_type, comparator))
+        })
+    }
 
-        Ok(false)
+    /// Match variables of `value` against a `:matches` or `:regex` key:
+    /// the whole value, then what each wildcard or group matched
+    fn captures(
+        value: &str,
+        key: &str,
+        match_type: &MatchType,
+        comparator: &Comparator,
+    ) -> Option<Vec<String>> {
+        let case_insensitive = *comparator != Comparator::Octet;
+        let re = match match_type {
+            MatchType::Matches => Self::wildcard_regex(key, case_insensitive)?,
+            MatchType::Regex => compile_regex(key, case_insensitive).ok()?,
+            _ => return None,
+        };
+        let captures = re.captures(match_input(value))?;
+        Some(
+            captures
+                .iter()
+                .take(10)
+                .map(|group| group.map(|m| m.as_str().to_string()).unwrap_or_default())
+                .collect(),
+        )
     }
 
     /// Extract part of an email address
@@ -233,8 +268,24 @@ impl SieveExecutor {
         match_type: &MatchType,
         comparator: &Comparator,
     ) -> bool {
+        if *comparator == Comparator::AsciiNumeric {
+            match match_type {
+                MatchType::Is => {
+                    return Self::compare(value, pattern, comparator) == Ordering::Equal
+                }
+                MatchType::Value(op) => return op.holds(Self::compare(value, pattern, comparator)),
+                _ => {}
+            }
+        }
+        // Lowercasing would change the meaning of escapes such as `\D`
+        if *match_type == MatchType::Regex {
+            return compile_regex(pattern, *comparator != Comparator::Octet)
+                .is_ok_and(|re| re.is_match(match_input(value)));
+        }
         let (value, pattern) = match comparator {
-            Comparator::AsciiCasemap => (value.to_lowercase(), pattern.to_lowercase()),
+            Comparator::AsciiCasemap | Comparator::AsciiNumeric => {
+                (value.to_lowercase(), pattern.to_lowercase())
+            }
             Comparator::Octet => (value.to_string(), pattern.to_string()),
         };
 
@@ -242,39 +293,638 @@ impl SieveExecutor {
             MatchType::Is => value == pattern,
             MatchType::Contains => value.contains(&pattern),
             MatchType::Matches => Self::wildcard_match(&value, &pattern),
-            MatchType::Regex => {
-                if let Ok(re) = Regex::new(&pattern) {
-                    re.is_match(&value)
-                } else {
-                    false
+            MatchType::Value(op) => op.holds(value.cmp(&pattern)),
+            // Regexes are matched above, counts in match_values
+            MatchType::Regex | MatchType::Count(_) => false,
+        }
+    }
+
+    /// Order `value` against `key` with `comparator`
+    fn compare(value: &str, key: &str, comparator: &Comparator) -> Ordering {
+        match comparator {
+            Comparator::AsciiNumeric => {
+                // Strings without a leading number compare as infinity
+                let number = |s: &str| -> Option<u128> {
+                    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+                    digits.parse().ok()
+                };
+                match (number(value), number(key)) {
+                    (Some(value), Some(key)) => value.cmp(&key),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
                 }
             }
+            Comparator::AsciiCasemap => value.to_lowercase().cmp(&key.to_lowercase()),
+            Comparator::Octet => value.cmp(key),
         }
     }
 
     /// Wildcard match (* and ?)
     fn wildcard_match(value: &str, pattern: &str) -> bool {
-        // Convert Sieve wildcards to regex
+        Self::wildcard_regex(pattern, false).is_some_and(|re| re.is_match(match_input(value)))
+    }
+
+    /// Regex of a `:matches` key; `*` matches as little as it can, and
+    /// each wildcard is a group so it can be a match variable
+    fn wildcard_regex(pattern: &str, case_insensitive: bool) -> Option<Regex> {
+        if pattern.chars().count() > MAX_PATTERN_LENGTH {
+            return None;
+        }
         let mut regex_pattern = String::from("^");
-        for c in pattern.chars() {
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
             match c {
-                '*' => regex_pattern.push_str(".*"),
-                '?' => regex_pattern.push('.'),
-                '.' | '+' | '(' | ')' | '[' | ']' | '{' | '}' | '^' | '$' | '|' | '\\' => {
-                    regex_pattern.push('\\');
-                    regex_pattern.push(c);
+                '*' => regex_pattern.push_str("(.*?)"),
+                '?' => regex_pattern.push_str("(.)"),
+                // `\` quotes the next character
+                '\\' => {
+                    if let Some(quoted) = chars.next() {
+                        regex_pattern.push_str(&regex::escape(&quoted.to_string()));
+                    }
                 }
-                _ => regex_pattern.push(c),
+                _ => regex_pattern.push_str(&regex::escape(&c.to_string())),
             }
         }
         regex_pattern.push('$');
 
-        if let Ok(re) = Regex::new(&regex_pattern) {
-            re.is_match(value)
-        } else {
-            false
+        RegexBuilder::new(&regex_pattern)
+            .case_insensitive(case_insensitive)
+            .dot_matches_new_line(true)
+            .size_limit(MAX_REGEX_SIZE)
+            .dfa_size_limit(MAX_REGEX_SIZE)
+            .build()
+            .ok()
+    }
+}
+
+/// Compile a `:regex` key within the size limits
+pub(crate) fn compile_regex(pattern: &str, case_insensitive: bool) -> Result<Regex> {
+    if pattern.chars().count() > MAX_PATTERN_LENGTH {
+        return Err(anyhow!(
+            "Regex longer than {} characters",
+            MAX_PATTERN_LENGTH
+        ));
+    }
+    RegexBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .size_limit(MAX_REGEX_SIZE)
+        .dfa_size_limit(MAX_REGEX_SIZE)
+        .nest_limit(64)
+        .build()
+        .map_err(|e| anyhow!("Invalid regex {}: {}", pattern, e))
+}
+
+/// The part of `value` keys are matched against
+fn match_input(value: &str) -> &str {
+    if value.len() <= MAX_MATCH_INPUT {
+        return value;
+    }
+    let mut end = MAX_MATCH_INPUT;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Variables of one script; names are lowercase
+#[derive(Default)]
+struct Scope {
+    locals: HashMap<String, String>,
+    /// Names the script declared global
+    globals: HashSet<String>,
+}
+
+/// How a script or action ended
+enum Flow {
+    Continue,
+    /// `return`: back to the including script
+    Return,
+    /// `stop`: no more actions at all
+    Stop,
+}
+
+/// State of one run of a script and the scripts it includes
+struct Run<'a> {
+    /// The message with the header edits made so far
+    message: MessageContext,
+    includes: &'a IncludedScripts,
+    /// Values of the global variables
+    globals: HashMap<String, String>,
+    /// Match variables of the last successful `:matches` or `:regex` test
+    matched: Vec<String>,
+    /// Scripts included so far
+    included: HashSet<(IncludeLocation, String)>,
+    include_count: usize,
+    result: SieveResult,
+    /// Rules reached so far, when tracing
+    steps: Option<Vec<SieveTraceStep>>,
+}
+
+impl<'a> Run<'a> {
+    fn new(message: &MessageContext, includes: &'a IncludedScripts) -> Self {
+        Self {
+            message: message.clone(),
+            includes,
+            globals: HashMap::new(),
+            matched: Vec::new(),
+            included: HashSet::new(),
+            include_count: 0,
+            result: SieveResult::default(),
+            steps: None,
+        }
+    }
+
+    /// Run the rules of one script, `name` when included; returns whether
+    /// it stopped
+    fn script(&mut self, rules: &[SieveRule], depth: usize, name: Option<&str>) -> Result<bool> {
+        let mut scope = Scope::default();
+
+        for (index, rule) in rules.iter().enumerate() {
+            let (branch, condition, actions) = if self.evaluate(&rule.condition, &scope)? {
+                (Some(SieveBranch::If), Some(&rule.condition), Some(&rule.actions))
+            } else {
+                let mut taken = None;
+                for (i, (elsif_cond, elsif_actions)) in rule.elsif_branches.iter().enumerate() {
+                    if self.evaluate(elsif_cond, &scope)? {
+                        taken = Some((SieveBranch::Elsif(i), elsif_cond, elsif_actions));
+                        break;
+                    }
+                }
+                match (taken, &rule.else_actions) {
+                    (Some((branch, condition, actions)), _) => {
+                        (Some(branch), Some(condition), Some(actions))
+                    }
+                    (None, Some(actions)) => (Some(SieveBranch::Else), None, Some(actions)),
+                    (None, None) => (None, None, None),
+                }
+            };
+
+            let step = self.steps.as_mut().map(|steps| {
+                steps.push(SieveTraceStep {
+                    script: name.map(str::to_string),
+                    rule: index,
+                    branch,
+                    condition: condition.cloned(),
+                    actions: vec![],
+                });
+                steps.len() - 1
+            });
+            let first_action = self.result.actions.len();
+
+            let mut flow = Flow::Continue;
+            for action in actions.into_iter().flatten() {
+                flow = self.perform(action, &mut scope, depth)?;
+                if !matches!(flow, Flow::Continue) {
+                    break;
+                }
+            }
+
+            if let (Some(steps), Some(step)) = (self.steps.as_mut(), step) {
+                steps[step].actions = self.result.actions[first_action..].to_vec();
+            }
+            match flow {
+                Flow::Continue => {}
+                Flow::Return => return Ok(false),
+                Flow::Stop => return Ok(true),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Take an action, with its strings expanded
+    fn perform(&mut self, action: &SieveAction, scope: &mut Scope, depth: usize) -> Result<Flow> {
+        let action = match action {
+            SieveAction::Keep | SieveAction::Discard => {
+                self.result.implicit_keep = false;
+                action.clone()
+            }
+            SieveAction::Stop => {
+                self.result.actions.push(SieveAction::Stop);
+                self.result.implicit_keep = false;
+                return Ok(Flow::Stop);
+            }
+            SieveAction::FileInto(folder) => SieveAction::FileInto(self.expand(folder, scope)),
+            SieveAction::Redirect(address) => {
+                SieveAction::Redirect(self.expand(address, scope))
+            }
+            SieveAction::Flag(flags) => SieveAction::Flag(self.expand_all(flags, scope)),
+            SieveAction::Vacation(vacation) => SieveAction::Vacation(VacationConfig {
+                subject: self.expand(&vacation.subject, scope),
+                body: self.expand(&vacation.body, scope),
+                addresses: self.expand_all(&vacation.addresses, scope),
+                from: vacation.from.as_ref().map(|from| self.expand(from, scope)),
+                handle: vacation.handle.as_ref().map(|handle| self.expand(handle, scope)),
+                ..vacation.clone()
+            }),
+            SieveAction::Set(set) => {
+                let value = set
+                    .modifiers
+                    .iter()
+                    .fold(self.expand(&set.value, scope), |value, modifier| {
+                        modifier.apply(&value)
+                    });
+                let value: String = value.chars().take(MAX_VARIABLE_LENGTH).collect();
+                match scope.globals.contains(&set.name) {
+                    true => self.globals.insert(set.name.clone(), value),
+                    false => scope.locals.insert(set.name.clone(), value),
+                };
+                return Ok(Flow::Continue);
+            }
+            SieveAction::Global(names) => {
+                scope.globals.extend(names.iter().cloned());
+                return Ok(Flow::Continue);
+            }
+            SieveAction::Include(include) => return self.include(include, depth),
+            SieveAction::Return => return Ok(Flow::Return),
+            SieveAction::AddHeader(add) => {
+                self.add_header(add, scope)?;
+                return Ok(Flow::Continue);
+            }
+            SieveAction::DeleteHeader(delete) => {
+                self.delete_header(delete, scope);
+                return Ok(Flow::Continue);
+            }
+        };
+        self.result.actions.push(action);
+        Ok(Flow::Continue)
+    }
+
+    /// Run an included script; returns whether it stopped
+    fn include(&mut self, include: &IncludeScript, depth: usize) -> Result<Flow> {
+        let key = (include.location, include.name.clone());
+        if include.once && self.included.contains(&key) {
+            return Ok(Flow::Continue);
+        }
+        let Some(rules) = self.includes.get(include.location, &include.name) else {
+            if include.optional {
+                return Ok(Flow::Continue);
+            }
+            return Err(anyhow!("Included script {} not found", include.name));
+        };
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(anyhow!(
+                "Scripts are included more than {} levels deep",
+                MAX_INCLUDE_DEPTH
+            ));
+        }
+        self.include_count += 1;
+        if self.include_count > MAX_INCLUDES {
+            return Err(anyhow!("Scripts are included more than {} times", MAX_INCLUDES));
+        }
+
+        self.included.insert(key);
+        match self.script(rules, depth + 1, Some(&include.name))? {
+            true => Ok(Flow::Stop),
+            false => Ok(Flow::Continue),
         }
     }
+
+    /// Add a header field; line breaks in the value are folded into spaces
+    fn add_header(&mut self, add: &AddHeader, scope: &Scope) -> Result<()> {
+        let name = self.expand(&add.name, scope).trim().to_string();
+        if !is_field_name(&name) {
+            return Err(anyhow!("Invalid header name {}", name));
+        }
+        let value = self
+            .expand(&add.value, scope)
+            .split(['\r', '\n'])
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let field = (name.clone(), value.clone());
+        match add.last {
+            true => self.message.headers.push(field),
+            false => self.message.headers.insert(0, field),
+        }
+        self.result.actions.push(SieveAction::AddHeader(AddHeader {
+            name,
+            value,
+            last: add.last,
+        }));
+        Ok(())
+    }
+
+    /// Delete the header fields `delete` selects, except protected ones
+    fn delete_header(&mut self, delete: &DeleteHeader, scope: &Scope) {
+        let name = self.expand(&delete.name, scope);
+        if PROTECTED_HEADERS.contains(&name.to_lowercase().as_str()) {
+            return;
+        }
+        let keys = self.expand_all(&delete.values, scope);
+
+        // Positions of the fields, numbered from 1 among those named `name`
+        let fields: Vec<(usize, usize)> = self
+            .message
+            .headers
+            .iter()
+            .enumerate()
+            .filter(|(_, (field, _))| field.eq_ignore_ascii_case(&name))
+            .enumerate()
+            .map(|(occurrence, (position, _))| (occurrence + 1, position))
+            .collect();
+        let selected: Vec<(usize, usize)> = match delete.index {
+            Some(index) if delete.last => fields
+                .len()
+                .checked_sub(index)
+                .and_then(|i| fields.get(i))
+                .into_iter()
+                .copied()
+                .collect(),
+            Some(index) => fields.get(index - 1).into_iter().copied().collect(),
+            None => fields,
+        };
+
+        // From the last field, so the numbers of the others still hold
+        for (occurrence, position) in selected.into_iter().rev() {
+            let value = self.message.headers[position].1.clone();
+            if !keys.is_empty()
+                && !SieveExecutor::match_values(
+                    &[value],
+                    &keys,
+                    &delete.match_type,
+                    &delete.comparator,
+                )
+            {
+                continue;
+            }
+            self.message.headers.remove(position);
+            self.result
+                .actions
+                .push(SieveAction::DeleteHeader(DeleteHeader {
+                    name: name.clone(),
+                    index: Some(occurrence),
+                    last: false,
+                    values: vec![],
+                    match_type: MatchType::Is,
+                    comparator: Comparator::default(),
+                }));
+        }
+    }
+
+    /// `text` with `${name}` replaced by the variable's value, `${n}` by
+    /// a match variable and `${global.name}` by a global variable; unset
+    /// variables are empty, and references to anything else are kept
+    fn expand(&self, text: &str, scope: &Scope) -> String {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let Some(end) = after.find('}') else {
+                rest = &rest[start..];
+                break;
+            };
+            let name = after[..end].to_lowercase();
+            let value = if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) {
+                name.parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.matched.get(n))
+                    .cloned()
+                    .or(Some(String::new()))
+            } else if let Some(global) = name.strip_prefix("global.") {
+                is_variable_name(global)
+                    .then(|| self.globals.get(global).cloned().unwrap_or_default())
+            } else if is_variable_name(&name) {
+                let value = match scope.globals.contains(&name) {
+                    true => self.globals.get(&name),
+                    false => scope.locals.get(&name),
+                };
+                Some(value.cloned().unwrap_or_default())
+            } else {
+                None
+            };
+            match value {
+                Some(value) => {
+                    expanded.push_str(&value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    expanded.push_str("${");
+                    rest = after;
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    fn expand_all(&self, texts: &[String], scope: &Scope) -> Vec<String> {
+        texts.iter().map(|text| self.expand(text, scope)).collect()
+    }
+
+    /// Evaluate a condition against the message
+    fn evaluate(&mut self, condition: &SieveCondition, scope: &Scope) -> Result<bool> {
+        match condition {
+            SieveCondition::True => Ok(true),
+            SieveCondition::False => Ok(false),
+            SieveCondition::Not(inner) => Ok(!self.evaluate(inner, scope)?),
+            SieveCondition::AllOf(conditions) => {
+                for cond in conditions {
+                    if !self.evaluate(cond, scope)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            SieveCondition::AnyOf(conditions) => {
+                for cond in conditions {
+                    if self.evaluate(cond, scope)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            SieveCondition::Header(test) => self.evaluate_header_test(test, scope),
+            SieveCondition::Address(test) => self.evaluate_address_test(test, scope),
+            SieveCondition::Size(test) => SieveExecutor::evaluate_size_test(test, &self.message),
+            SieveCondition::Exists(headers) => {
+                let headers = self.expand_all(headers, scope);
+                SieveExecutor::evaluate_exists_test(&headers, &self.message)
+            }
+            SieveCondition::Body(test) => self.evaluate_body_test(test, scope),
+            SieveCondition::Envelope(test) => self.evaluate_envelope_test(test, scope),
+            SieveCondition::Date(test) => self.evaluate_date_test(test, scope),
+            SieveCondition::CurrentDate(test) => self.evaluate_date_test(test, scope),
+            SieveCondition::String(test) => {
+                let sources = self.expand_all(&test.sources, scope);
+                let keys = self.expand_all(&test.values, scope);
+                Ok(self.match_values(&sources, &keys, &test.match_type, &test.comparator))
+            }
+        }
+    }
+
+    /// Evaluate a header test
+    fn evaluate_header_test(&mut self, test: &HeaderTest, scope: &Scope) -> Result<bool> {
+        let values: Vec<String> = self
+            .expand_all(&test.headers, scope)
+            .iter()
+            .flat_map(|header_name| SieveExecutor::header_values(header_name, &self.message))
+            .map(str::to_string)
+            .collect();
+        let keys = self.expand_all(&test.values, scope);
+
+        Ok(self.match_values(&values, &keys, &test.match_type, &test.comparator))
+    }
+
+    /// Evaluate an address test
+    fn evaluate_address_test(&mut self, test: &AddressTest, scope: &Scope) -> Result<bool> {
+        let message = &self.message;
+        let mut values = Vec::new();
+        for header_name in self.expand_all(&test.headers, scope) {
+            // Get addresses from appropriate header
+            let addresses: Vec<&str> = match header_name.to_lowercase().as_str() {
+                "from" => vec![message.from.as_str()],
+                "to" => message.to.iter().map(|s| s.as_str()).collect(),
+                "cc" => message.cc.iter().map(|s| s.as_str()).collect(),
+                _ => SieveExecutor::header_values(&header_name, message),
+            };
+
+            values.extend(addresses.into_iter().map(|address| {
+                SieveExecutor::extract_address_part(address, &test.address_part)
+            }));
+        }
+        let keys = self.expand_all(&test.values, scope);
+
+        Ok(self.match_values(&values, &keys, &test.match_type, &test.comparator))
+    }
+
+    /// Evaluate an envelope test; without an envelope nothing matches
+    fn evaluate_envelope_test(&mut self, test: &EnvelopeTest, scope: &Scope) -> Result<bool> {
+        let message = &self.message;
+        let mut values = Vec::new();
+        for part in &test.parts {
+            let addresses: Vec<&str> = match part.to_lowercase().as_str() {
+                "from" => message.envelope_from.iter().map(|s| s.as_str()).collect(),
+                "to" => message.envelope_to.iter().map(|s| s.as_str()).collect(),
+                _ => vec![],
+            };
+            values.extend(addresses.into_iter().map(|address| {
+                SieveExecutor::extract_address_part(address, &test.address_part)
+            }));
+        }
+        let keys = self.expand_all(&test.values, scope);
+
+        Ok(self.match_values(&values, &keys, &test.match_type, &test.comparator))
+    }
+
+    /// Evaluate a body test
+    fn evaluate_body_test(&mut self, test: &BodyTest, scope: &Scope) -> Result<bool> {
+        let message = &self.message;
+        let texts: Vec<String> = match &test.transform {
+            BodyTransform::Raw => vec![message.body.clone()],
+            BodyTransform::Text if message.body_parts.is_empty() => vec![message.body.clone()],
+            BodyTransform::Text => message
+                .body_parts
+                .iter()
+                .filter(|(content_type, _)| content_type.starts_with("text/"))
+                .map(|(_, text)| text.clone())
+                .collect(),
+            BodyTransform::Content(types) => message
+                .body_parts
+                .iter()
+                .filter(|(content_type, _)| {
+                    types
+                        .iter()
+                        .any(|wanted| SieveExecutor::content_type_match(content_type, wanted))
+                })
+                .map(|(_, text)| text.clone())
+                .collect(),
+        };
+        let keys = self.expand_all(&test.values, scope);
+
+        Ok(self.match_values(&texts, &keys, &test.match_type, &test.comparator))
+    }
+
+    /// Evaluate a date or currentdate test; a missing or unparseable date
+    /// header matches nothing
+    fn evaluate_date_test(&mut self, test: &DateTest, scope: &Scope) -> Result<bool> {
+        let date = match &test.header {
+            Some(header) => {
+                let header = self.expand(header, scope);
+                let parsed = SieveExecutor::header_values(&header, &self.message)
+                    .first()
+                    .and_then(|value| parse_date(value));
+                match parsed {
+                    Some(date) => date,
+                    None => return Ok(false),
+                }
+            }
+            None => self.message.received_at.fixed_offset(),
+        };
+        let date = match test.zone {
+            DateZone::Local => date.with_timezone(&Utc).fixed_offset(),
+            DateZone::Offset(seconds) => match FixedOffset::east_opt(seconds) {
+                Some(zone) => date.with_timezone(&zone),
+                None => date,
+            },
+            DateZone::Original => date,
+        };
+        let keys = self.expand_all(&test.values, scope);
+
+        Ok(self.match_values(
+            &[date_part(&date, test.date_part)],
+            &keys,
+            &test.match_type,
+            &test.comparator,
+        ))
+    }
+
+    /// Match values as [`SieveExecutor::match_values`] does, keeping the
+    /// match variables of a successful `:matches` or `:regex` test
+    fn match_values(
+        &mut self,
+        values: &[String],
+        keys: &[String],
+        match_type: &MatchType,
+        comparator: &Comparator,
+    ) -> bool {
+        if !matches!(match_type, MatchType::Matches | MatchType::Regex) {
+            return SieveExecutor::match_values(values, keys, match_type, comparator);
+        }
+        for value in values {
+            for key in keys {
+                if let Some(matched) = SieveExecutor::captures(value, key, match_type, comparator)
+                {
+                    self.matched = matched;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Date of a header field; comments such as `(CEST)` after the zone are
+/// ignored
+fn parse_date(value: &str) -> Option<DateTime<FixedOffset>> {
+    let value = value.split('(').next().unwrap_or(value).trim();
+    DateTime::parse_from_rfc2822(value).ok()
+}
+
+/// `part` of `date` as RFC 5260 formats it
+fn date_part(date: &DateTime<FixedOffset>, part: DatePart) -> String {
+    match part {
+        DatePart::Year => format!("{:04}", date.year()),
+        DatePart::Month => format!("{:02}", date.month()),
+        DatePart::Day => format!("{:02}", date.day()),
+        DatePart::Date => date.format("%Y-%m-%d").to_string(),
+        DatePart::Julian => {
+            let epoch = NaiveDate::from_ymd_opt(1858, 11, 17).expect("valid date");
+            (date.date_naive() - epoch).num_days().to_string()
+        }
+        DatePart::Hour => format!("{:02}", date.hour()),
+        DatePart::Minute => format!("{:02}", date.minute()),
+        DatePart::Second => format!("{:02}", date.second()),
+        DatePart::Time => date.format("%H:%M:%S").to_string(),
+        DatePart::Iso8601 => date.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+        DatePart::Std11 => date.to_rfc2822(),
+        DatePart::Zone => date.format("%z").to_string(),
+        DatePart::Weekday => date.weekday().num_days_from_sunday().to_string(),
+    }
 }
 
 #[cfg(test)]
@@ -298,9 +948,21 @@ mod tests {
             ],
             body: "This is a test message.".to_string(),
             size: 1024,
+            body_parts: vec![(
+                "text/plain".to_string(),
+                "This is a test message.".to_string(),
+            )],
+            envelope_from: Some("bounces@lists.example.org".to_string()),
+            envelope_to: vec!["recipient@example.com".to_string()],
+            received_at: Utc::now(),
         }
     }
 
+    fn matches(script: &str, message: &MessageContext) -> bool {
+        let rules = crate::sieve::parse_script(script).unwrap();
+        SieveExecutor::evaluate_condition(&rules[0].condition, message).unwrap()
+    }
+
     #[test]
     fn test_header_contains() {
         let message = create_test_message();
@@ -370,5 +1032,417 @@ mod tests {
         assert!(SieveExecutor::wildcard_match("hello world", "*lo wo*"));
         assert!(SieveExecutor::wildcard_match("test", "t?st"));
         assert!(!SieveExecutor::wildcard_match("test", "t?t"));
+        assert!(SieveExecutor::wildcard_match("", "*"));
+        assert!(!SieveExecutor::wildcard_match("", "?"));
+        assert!(SieveExecutor::wildcard_match("a*b", "a\\*b"));
+        assert!(!SieveExecutor::wildcard_match("axb", "a\\*b"));
+        assert!(SieveExecutor::wildcard_match("line\nbreak", "line?break"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let message = create_test_message();
+        assert!(matches(
+            r#"if header :regex "Subject" "^test (news|mail)" { keep; }"#,
+            &message
+        ));
+        // Escapes keep their meaning under i;ascii-casemap
+        assert!(matches(
+            r#"if header :regex "X-Custom" "^\\S+\\s\\D+$" { keep; }"#,
+            &message
+        ));
+        assert!(!matches(
+            r#"if header :regex :comparator "i;octet" "Subject" "^test" { keep; }"#,
+            &message
+        ));
+        assert!(!matches(
+            r#"if header :regex "Subject" "^Newsletter" { keep; }"#,
+            &message
+        ));
+
+        // Oversized patterns never match
+        assert!(compile_regex(&"a".repeat(MAX_PATTERN_LENGTH + 1), false).is_err());
+        assert!(compile_regex("(a{1000}){1000}", false).is_err());
+        assert!(!SieveExecutor::string_match(
+            "aaaa",
+            "(a{1000}){1000}",
+            &MatchType::Regex,
+            &Comparator::Octet
+        ));
+        assert!(!SieveExecutor::wildcard_match("a", &"*".repeat(MAX_PATTERN_LENGTH + 1)));
+
+        let long = "a".repeat(MAX_MATCH_INPUT + 10);
+        assert_eq!(match_input(&long).len(), MAX_MATCH_INPUT);
+        let multibyte = "é".repeat(MAX_MATCH_INPUT);
+        assert!(match_input(&multibyte).len() <= MAX_MATCH_INPUT);
+    }
+
+    #[test]
+    fn test_body() {
+        let message = MessageContext::from_message(
+            b"From: alice@example.com\r\n\
+              Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+              \r\n\
+              --b\r\n\
+              Content-Type: text/plain\r\n\
+              Content-Transfer-Encoding: quoted-printable\r\n\
+              \r\n\
+              Your invoice is =\r\n\
+              attached\r\n\
+              --b\r\n\
+              Content-Type: application/pdf\r\n\
+              \r\n\
+              %PDF-1.4 secret\r\n\
+              --b--\r\n",
+        );
+
+        assert!(matches(r#"if body :contains "invoice is attached" { keep; }"#, &message));
+        assert!(!matches(r#"if body :text :contains "secret" { keep; }"#, &message));
+        assert!(matches(
+            r#"if body :raw :contains "quoted-printable" { keep; }"#,
+            &message
+        ));
+        assert!(!matches(r#"if body :contains "quoted-printable" { keep; }"#, &message));
+        assert!(matches(r#"if body :content "application" :contains "secret" { keep; }"#, &message));
+        assert!(!matches(r#"if body :content "text/html" :contains "invoice" { keep; }"#, &message));
+    }
+
+    #[test]
+    fn test_envelope() {
+        let message = create_test_message();
+        assert!(matches(
+            r#"if envelope :domain :is "from" "lists.example.org" { keep; }"#,
+            &message
+        ));
+        assert!(matches(
+            r#"if envelope :localpart "to" "recipient" { keep; }"#,
+            &message
+        ));
+        assert!(!matches(r#"if envelope :is "from" "sender@example.com" { keep; }"#, &message));
+
+        // No envelope outside SMTP delivery
+        let message = MessageContext {
+            envelope_from: None,
+            envelope_to: vec![],
+            ..message
+        };
+        assert!(!matches(r#"if envelope :matches "from" "*" { keep; }"#, &message));
+    }
+
+    #[test]
+    fn test_relational() {
+        let mut message = create_test_message();
+        message.headers.push(("X-Spam-Score".to_string(), "12".to_string()));
+        message
+            .headers
+            .push(("Received".to_string(), "from a".to_string()));
+        message
+            .headers
+            .push(("Received".to_string(), "from b".to_string()));
+
+        let score = |op: &str, key: &str| {
+            format!(
+                r#"if header :value "{}" :comparator "i;ascii-numeric" "X-Spam-Score" "{}" {{ keep; }}"#,
+                op, key
+            )
+        };
+        assert!(matches(&score("ge", "5"), &message));
+        assert!(!matches(&score("lt", "9"), &message));
+        // 12 > 9 numerically, not as strings
+        assert!(matches(&score("gt", "9"), &message));
+        assert!(matches(
+            r#"if header :count "eq" :comparator "i;ascii-numeric" "Received" "2" { keep; }"#,
+            &message
+        ));
+        assert!(matches(
+            r#"if address :count "ge" ["to", "cc"] "1" { keep; }"#,
+            &message
+        ));
+        assert!(matches(r#"if header :value "lt" "Subject" "u" { keep; }"#, &message));
+    }
+
+    #[test]
+    fn test_date() {
+        let mut message = create_test_message();
+        message.headers.push((
+            "Date".to_string(),
+            "Sun, 31 Mar 2024 23:30:00 -0100 (CET)".to_string(),
+        ));
+        message.received_at = "2024-04-15T08:00:00Z".parse().unwrap();
+
+        // Dates are compared in UTC unless asked otherwise
+        assert!(matches(r#"if date :is "date" "date" "2024-04-01" { keep; }"#, &message));
+        assert!(matches(
+            r#"if date :originalzone :is "date" "date" "2024-03-31" { keep; }"#,
+            &message
+        ));
+        assert!(matches(r#"if date :zone "+0200" "date" "hour" "02" { keep; }"#, &message));
+        assert!(matches(r#"if date :is "date" "weekday" "1" { keep; }"#, &message));
+        assert!(matches(
+            r#"if date :value "lt" "date" "date" "2024-04-02" { keep; }"#,
+            &message
+        ));
+        // Older than two weeks at delivery, in Modified Julian Days
+        assert!(matches(
+            r#"if currentdate :value "ge" :comparator "i;ascii-numeric" "julian" "60415" { keep; }"#,
+            &message
+        ));
+        assert!(matches(
+            r#"if date :value "lt" :comparator "i;ascii-numeric" "date" "julian" "60402" { keep; }"#,
+            &message
+        ));
+        assert!(!matches(r#"if date "X-Missing" "year" "2024" { keep; }"#, &message));
+    }
+
+    fn run(script: &str, message: &MessageContext) -> SieveResult {
+        let rules = crate::sieve::parse_script(script).unwrap();
+        SieveExecutor::execute(&rules, message).unwrap()
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut message = create_test_message();
+        message
+            .headers
+            .push(("List-Id".to_string(), "Rust Users <rust-users.lists.example.org>".to_string()));
+
+        let result = run(
+            r#"
+            require ["variables", "fileinto"];
+            if header :matches "List-Id" "*<*.lists.*>" {
+                set :lower "list" "${2}";
+            }
+            set "prefix" "Lists";
+            set :length "count" "${list}";
+            if string :is "${count}" "10" {
+                fileinto "${prefix}/${list}";
+            }
+            set :quotewildcard "pattern" "a*b?";
+            fileinto "${pattern}/${unset}/${bad-name}";
+            "#,
+            &message,
+        );
+        assert_eq!(
+            result.actions,
+            vec![
+                SieveAction::FileInto("Lists/rust-users".to_string()),
+                SieveAction::FileInto("a\\*b\\?//${bad-name}".to_string()),
+            ]
+        );
+
+        // Keys are expanded too, and `\` quotes wildcards
+        assert!(!run(
+            r#"set "key" "Test\\*"; if header :matches "Subject" "${key}" { discard; }"#,
+            &message
+        )
+        .actions
+        .contains(&SieveAction::Discard));
+        assert!(run(
+            r#"set "key" "Newsletter"; if header :contains "Subject" "${key}" { discard; }"#,
+            &message
+        )
+        .actions
+        .contains(&SieveAction::Discard));
+    }
+
+    #[test]
+    fn test_match_variables() {
+        let mut message = create_test_message();
+        message
+            .headers
+            .push(("X-Path".to_string(), "a.b.c.d".to_string()));
+
+        // Wildcards match as little as possible, left to right
+        let result = run(
+            r#"
+            if header :matches "X-Path" "*.*" { set "glob" "${1}|${2}|${0}"; }
+            if header :matches "Subject" "T?st *" { set "word" "${1}-${2}"; }
+            if header :regex "X-Path" "^([a-z])\\.(.*)\\.([a-z])$" {
+                set "re" "${1}|${2}|${3}";
+            }
+            set :quoteregex "quoted" "a.b.c.d";
+            if header :regex "X-Path" "^${quoted}$" { set "literal" "yes"; }
+            set :quoteregex "dots" "a+b";
+            if header :regex "X-Path" "${dots}" { set "literal" "no"; }
+            fileinto "${glob}/${word}/${re}/${literal}";
+            "#,
+            &message,
+        );
+        assert_eq!(
+            result.actions,
+            vec![SieveAction::FileInto(
+                "a|b.c.d|a.b.c.d/e-Newsletter/a|b.c|d/yes".to_string()
+            )]
+        );
+
+        // A failed match keeps the previous match variables
+        let result = run(
+            r#"
+            if header :matches "X-Path" "*.d" { set "first" "${1}"; }
+            if header :matches "X-Path" "x*" { keep; }
+            fileinto "${first}/${1}";
+            "#,
+            &message,
+        );
+        assert_eq!(
+            result.actions,
+            vec![SieveAction::FileInto("a.b.c/a.b.c".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let message = create_test_message();
+        let parse = |script: &str| crate::sieve::parse_script(script).unwrap();
+        let mut includes = IncludedScripts::default();
+        includes.insert(
+            IncludeLocation::Global,
+            "lists",
+            parse(
+                r#"
+                global "folder";
+                set "local" "hidden";
+                if header :contains "Subject" "Newsletter" {
+                    set "folder" "Newsletters";
+                    return;
+                }
+                set "folder" "Other";
+                "#,
+            ),
+        );
+        includes.insert(IncludeLocation::Personal, "loop", parse(r#"include "loop";"#));
+        includes.insert(IncludeLocation::Personal, "tag", parse(r#"addflag "\\Flagged";"#));
+        includes.insert(IncludeLocation::Personal, "end", parse("stop;"));
+
+        let execute = |script: &str| SieveExecutor::execute_with_includes(&parse(script), &message, &includes);
+
+        let result = execute(
+            r#"
+            global "folder";
+            include :global "lists";
+            include :once "tag";
+            include :once "tag";
+            include :optional "missing";
+            fileinto "${folder}${local}";
+            include "end";
+            keep;
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            result.actions,
+            vec![
+                SieveAction::Flag(vec!["\\Flagged".to_string()]),
+                SieveAction::FileInto("Newsletters".to_string()),
+                SieveAction::Stop,
+            ]
+        );
+
+        assert!(execute(r#"include "missing";"#).is_err());
+        assert!(execute(r#"include "loop";"#).is_err());
+        // Personal and global scripts are separate
+        assert!(execute(r#"include :global "tag";"#).is_err());
+    }
+
+    #[test]
+    fn test_trace() {
+        let message = create_test_message();
+        let parse = |script: &str| crate::sieve::parse_script(script).unwrap();
+        let mut includes = IncludedScripts::default();
+        includes.insert(IncludeLocation::Personal, "tag", parse(r#"addflag "\\Flagged";"#));
+
+        let rules = parse(
+            r#"
+            if header :is "Subject" "Invoice" {
+                discard;
+            } elsif header :contains "Subject" "Newsletter" {
+                fileinto "Newsletters";
+                include "tag";
+            }
+            if size :over 1M {
+                discard;
+            }
+            "#,
+        );
+        let traced = SieveExecutor::trace(&rules, &message, &includes).unwrap();
+        let result = SieveExecutor::execute_with_includes(&rules, &message, &includes).unwrap();
+        assert_eq!(traced.actions, result.actions);
+        assert_eq!(traced.implicit_keep, result.implicit_keep);
+
+        let steps: Vec<_> = traced
+            .steps
+            .iter()
+            .map(|step| (step.script.as_deref(), step.rule, step.branch, step.actions.len()))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (None, 0, Some(SieveBranch::Elsif(0)), 2),
+                (Some("tag"), 0, Some(SieveBranch::If), 1),
+                (None, 1, None, 0),
+            ]
+        );
+        assert!(matches!(
+            &traced.steps[0].condition,
+            Some(SieveCondition::Header(test)) if test.values == ["Newsletter"]
+        ));
+        assert!(traced.steps[2].condition.is_none());
+    }
+
+    #[test]
+    fn test_editheader() {
+        let mut message = create_test_message();
+        for hop in ["a", "b", "c"] {
+            message
+                .headers
+                .push(("X-Hop".to_string(), hop.to_string()));
+        }
+
+        let result = run(
+            r#"
+            require ["editheader", "variables"];
+            addheader "X-Sieve" "seen";
+            addheader :last "X-Note" "line one
+             line two";
+            deleteheader :index 1 :last "X-Hop";
+            deleteheader :matches "X-Hop" "a";
+            deleteheader "Received";
+            if exists "X-Sieve" {
+                set "hops" "found";
+            }
+            if header :is "X-Hop" "b" {
+                fileinto "${hops}";
+            }
+            "#,
+            &message,
+        );
+        let delete = |index| {
+            SieveAction::DeleteHeader(DeleteHeader {
+                name: "X-Hop".to_string(),
+                index: Some(index),
+                last: false,
+                values: vec![],
+                match_type: MatchType::Is,
+                comparator: Comparator::AsciiCasemap,
+            })
+        };
+        assert_eq!(
+            result.actions,
+            vec![
+                SieveAction::AddHeader(AddHeader {
+                    name: "X-Sieve".to_string(),
+                    value: "seen".to_string(),
+                    last: false,
+                }),
+                SieveAction::AddHeader(AddHeader {
+                    name: "X-Note".to_string(),
+                    value: "line one line two".to_string(),
+                    last: true,
+                }),
+                delete(3),
+                delete(1),
+                SieveAction::FileInto("found".to_string()),
+            ]
+        );
     }
 }
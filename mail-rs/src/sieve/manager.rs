@@ -1,23 +1,61 @@
 //! Sieve manager for database persistence
+//!
+//! Every time a script's name or content changes, the new state is saved
+//! as a version. The last [`DEFAULT_MAX_VERSIONS`] versions of each script
+//! are kept, and a script can be rolled back to any of them. A script only
+//! becomes active after it parsed and ran on a sample message.
+//!
+//! Scripts `include` the other scripts of their owner by name, and global
+//! scripts, which admins keep under [`GLOBAL_SCRIPTS_OWNER`].
 
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
-use super::executor::SieveExecutor;
+use super::diff::{diff_lines, unified};
+use super::executor::{IncludedScripts, SieveExecutor};
 use super::parser::{parse_script, validate_script};
 use super::types::*;
 
+/// Versions kept per script unless configured otherwise
+pub const DEFAULT_MAX_VERSIONS: u32 = 20;
+
+/// Owner of the global scripts every user's scripts can include
+pub const GLOBAL_SCRIPTS_OWNER: &str = "@global";
+
+/// Most distinct scripts a script and its includes load
+pub const MAX_LOADED_INCLUDES: usize = 32;
+
+/// Row of `sieve_script_versions`
+type VersionRow = (String, i64, String, String, String, Option<String>, String);
+
 /// Sieve manager for script storage and execution
 pub struct SieveManager {
     db: SqlitePool,
+    max_versions: u32,
 }
 
 impl SieveManager {
     /// Create a new Sieve manager
     pub fn new(db: SqlitePool) -> Self {
-        Self { db }
+        Self {
+            db,
+            max_versions: DEFAULT_MAX_VERSIONS,
+        }
+    }
+
+    /// Connect to `database_url` and create the Sieve tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Keep this many versions per script (at least one)
+    pub fn with_max_versions(mut self, max_versions: u32) -> Self {
+        self.max_versions = max_versions.max(1);
+        self
     }
 
     /// Initialize database tables
@@ -46,6 +84,24 @@ impl SieveManager {
         .execute(&self.db)
         .await?;
 
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS sieve_script_versions (
+                script_id TEXT NOT NULL,
+                owner_email TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                script_content TEXT NOT NULL,
+                author TEXT NOT NULL,
+                comment TEXT,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (script_id, version)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS sieve_logs (
@@ -78,13 +134,11 @@ impl SieveManager {
         email: &str,
         request: &CreateSieveScriptRequest,
     ) -> Result<SieveScript> {
-        // Validate the script first
-        let validation = validate_script(&request.script_content)?;
-        if !validation.valid {
-            return Err(anyhow!(
-                "Invalid script: {}",
-                validation.error.unwrap_or_default()
-            ));
+        // Validate the script first, and dry-run it if it becomes active
+        if request.activate {
+            self.check_script(email, &request.script_content).await?;
+        } else {
+            ensure_valid(&request.script_content)?;
         }
 
         let id = Uuid::new_v4().to_string();
@@ -114,6 +168,16 @@ impl SieveManager {
         .execute(&self.db)
         .await?;
 
+        self.record_version(
+            email,
+            &id,
+            &request.name,
+            &request.script_content,
+            None,
+            now,
+        )
+        .await?;
+
         Ok(SieveScript {
             id,
             owner_email: email.to_string(),
@@ -239,19 +303,31 @@ impl SieveManager {
         script_id: &str,
         request: &CreateSieveScriptRequest,
     ) -> Result<SieveScript> {
-        // Validate the script first
-        let validation = validate_script(&request.script_content)?;
-        if !validation.valid {
-            return Err(anyhow!(
-                "Invalid script: {}",
-                validation.error.unwrap_or_default()
-            ));
+        // Validate the script first, and dry-run it if it becomes active
+        if request.activate {
+            self.check_script(email, &request.script_content).await?;
+        } else {
+            ensure_valid(&request.script_content)?;
         }
 
         // Check if script exists
-        let existing = self.get_script(email, script_id).await?;
-        if existing.is_none() {
-            return Err(anyhow!("Script not found"));
+        let existing = self
+            .get_script(email, script_id)
+            .await?
+            .ok_or_else(|| anyhow!("Script not found"))?;
+
+        // Scripts saved before versioning get their current state as the
+        // first version, so the update can be rolled back
+        if self.latest_version(email, script_id).await?.is_none() {
+            self.record_version(
+                email,
+                script_id,
+                &existing.name,
+                &existing.script_content,
+                None,
+                existing.updated_at,
+            )
+            .await?;
         }
 
         let now = Utc::now();
@@ -280,6 +356,18 @@ impl SieveManager {
         .execute(&self.db)
         .await?;
 
+        if existing.name != request.name || existing.script_content != request.script_content {
+            self.record_version(
+                email,
+                script_id,
+                &request.name,
+                &request.script_content,
+                None,
+                now,
+            )
+            .await?;
+        }
+
         self.get_script(email, script_id)
             .await?
             .ok_or_else(|| anyhow!("Script not found after update"))
@@ -297,16 +385,26 @@ impl SieveManager {
             return Err(anyhow!("Script not found"));
         }
 
+        sqlx::query("DELETE FROM sieve_script_versions WHERE script_id = ? AND owner_email = ?")
+            .bind(script_id)
+            .bind(email)
+            .execute(&self.db)
+            .await?;
+
         Ok(())
     }
 
     /// Activate a script
+    ///
+    /// Fails without changing the active script if the script does not
+    /// pass [`check_script`](Self::check_script).
     pub async fn activate_script(&self, email: &str, script_id: &str) -> Result<()> {
         // Check if script exists
-        let existing = self.get_script(email, script_id).await?;
-        if existing.is_none() {
-            return Err(anyhow!("Script not found"));
-        }
+        let existing = self
+            .get_script(email, script_id)
+            .await?
+            .ok_or_else(|| anyhow!("Script not found"))?;
+        self.check_script(email, &existing.script_content).await?;
 
         // Deactivate all scripts for this user
         sqlx::query("UPDATE sieve_scripts SET is_active = 0 WHERE owner_email = ?")
@@ -346,6 +444,284 @@ impl SieveManager {
         validate_script(script)
     }
 
+    /// Check that a script of `email` parses and runs on a sample message,
+    /// with the scripts it includes
+    ///
+    /// Returns what the script does with the sample message.
+    pub async fn check_script(&self, email: &str, script: &str) -> Result<SieveResult> {
+        ensure_valid(script)?;
+        let rules = parse_script(script)?;
+        let includes = self.load_includes(email, &rules).await?;
+        SieveExecutor::execute_with_includes(&rules, &dry_run_message(), &includes)
+            .map_err(|e| anyhow!("Script failed dry run: {}", e))
+    }
+
+    /// Run a script of `email` on a sample message without delivering it
+    ///
+    /// Returns the actions the script would take and, rule by rule, the
+    /// conditions that matched. Nothing is logged.
+    pub async fn test_script(
+        &self,
+        email: &str,
+        request: &TestSieveScriptRequest,
+    ) -> Result<SieveTestResult> {
+        ensure_valid(&request.script_content)?;
+        let rules = parse_script(&request.script_content)?;
+        let includes = self.load_includes(email, &rules).await?;
+
+        let message = MessageContext::from_message(request.message.as_bytes());
+        let envelope_from = request
+            .envelope_from
+            .clone()
+            .unwrap_or_else(|| message.from.clone());
+        let envelope_to = request.envelope_to.as_deref().unwrap_or(email);
+        let message = message.with_envelope(&envelope_from, envelope_to);
+
+        SieveExecutor::trace(&rules, &message, &includes)
+    }
+
+    /// Parse the scripts `rules` of `email` include, and the ones those
+    /// include; missing scripts are left out
+    pub async fn load_includes(&self, email: &str, rules: &[SieveRule]) -> Result<IncludedScripts> {
+        let mut includes = IncludedScripts::default();
+        let mut missing = std::collections::HashSet::new();
+        let mut pending: Vec<IncludeScript> =
+            SieveExecutor::includes(rules).into_iter().cloned().collect();
+
+        while let Some(include) = pending.pop() {
+            let key = (include.location, include.name.clone());
+            if includes.contains(include.location, &include.name) || missing.contains(&key) {
+                continue;
+            }
+            if includes.len() >= MAX_LOADED_INCLUDES {
+                return Err(anyhow!(
+                    "Scripts include more than {} scripts",
+                    MAX_LOADED_INCLUDES
+                ));
+            }
+
+            let owner = match include.location {
+                IncludeLocation::Personal => email,
+                IncludeLocation::Global => GLOBAL_SCRIPTS_OWNER,
+            };
+            let content: Option<String> = sqlx::query_scalar(
+                r#"
+                SELECT script_content FROM sieve_scripts
+                WHERE owner_email = ? AND name = ?
+                ORDER BY updated_at DESC
+                LIMIT 1
+                "#,
+            )
+            .bind(owner)
+            .bind(&include.name)
+            .fetch_optional(&self.db)
+            .await?;
+            let Some(content) = content else {
+                missing.insert(key);
+                continue;
+            };
+
+            let included = parse_script(&content)
+                .map_err(|e| anyhow!("Included script {} is invalid: {}", include.name, e))?;
+            pending.extend(SieveExecutor::includes(&included).into_iter().cloned());
+            includes.insert(include.location, &include.name, included);
+        }
+
+        Ok(includes)
+    }
+
+    /// Saved versions of a script, newest first
+    pub async fn list_versions(
+        &self,
+        email: &str,
+        script_id: &str,
+    ) -> Result<Vec<SieveScriptVersion>> {
+        let rows = sqlx::query_as::<_, VersionRow>(
+            r#"
+            SELECT script_id, version, name, script_content, author, comment, created_at
+            FROM sieve_script_versions
+            WHERE script_id = ? AND owner_email = ?
+            ORDER BY version DESC
+            "#,
+        )
+        .bind(script_id)
+        .bind(email)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.into_iter().map(version_from_row).collect())
+    }
+
+    /// A saved version of a script
+    pub async fn get_version(
+        &self,
+        email: &str,
+        script_id: &str,
+        version: u32,
+    ) -> Result<Option<SieveScriptVersion>> {
+        let row = sqlx::query_as::<_, VersionRow>(
+            r#"
+            SELECT script_id, version, name, script_content, author, comment, created_at
+            FROM sieve_script_versions
+            WHERE script_id = ? AND owner_email = ? AND version = ?
+            "#,
+        )
+        .bind(script_id)
+        .bind(email)
+        .bind(version as i64)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(version_from_row))
+    }
+
+    /// Differences from version `from` to version `to` of a script, or to
+    /// its latest version
+    ///
+    /// Returns `None` if either version does not exist.
+    pub async fn diff_versions(
+        &self,
+        email: &str,
+        script_id: &str,
+        from: u32,
+        to: Option<u32>,
+    ) -> Result<Option<SieveScriptDiff>> {
+        let to = match to {
+            Some(to) => Some(to),
+            None => self.latest_version(email, script_id).await?,
+        };
+        let (Some(old), Some(new)) = (
+            self.get_version(email, script_id, from).await?,
+            match to {
+                Some(to) => self.get_version(email, script_id, to).await?,
+                None => None,
+            },
+        ) else {
+            return Ok(None);
+        };
+
+        let lines = diff_lines(&old.script_content, &new.script_content);
+        Ok(Some(SieveScriptDiff {
+            script_id: script_id.to_string(),
+            from_version: old.version,
+            to_version: new.version,
+            unified: unified(&lines),
+            lines,
+        }))
+    }
+
+    /// Restore a script to a saved version
+    ///
+    /// The restored state is saved as a new version, so the rollback itself
+    /// can be undone. An active script stays active and must pass
+    /// [`check_script`](Self::check_script) first.
+    pub async fn rollback_script(
+        &self,
+        email: &str,
+        script_id: &str,
+        version: u32,
+    ) -> Result<SieveScript> {
+        let script = self
+            .get_script(email, script_id)
+            .await?
+            .ok_or_else(|| anyhow!("Script not found"))?;
+        let target = self
+            .get_version(email, script_id, version)
+            .await?
+            .ok_or_else(|| anyhow!("Version not found"))?;
+        if script.is_active {
+            self.check_script(email, &target.script_content).await?;
+        } else {
+            ensure_valid(&target.script_content)?;
+        }
+
+        let now = Utc::now();
+        sqlx::query(
+            r#"
+            UPDATE sieve_scripts
+            SET name = ?, script_content = ?, updated_at = ?
+            WHERE id = ? AND owner_email = ?
+            "#,
+        )
+        .bind(&target.name)
+        .bind(&target.script_content)
+        .bind(now.to_rfc3339())
+        .bind(script_id)
+        .bind(email)
+        .execute(&self.db)
+        .await?;
+
+        self.record_version(
+            email,
+            script_id,
+            &target.name,
+            &target.script_content,
+            Some(format!("Rollback to version {}", version)),
+            now,
+        )
+        .await?;
+
+        self.get_script(email, script_id)
+            .await?
+            .ok_or_else(|| anyhow!("Script not found after rollback"))
+    }
+
+    /// Number of the latest saved version of a script
+    async fn latest_version(&self, email: &str, script_id: &str) -> Result<Option<u32>> {
+        let latest: Option<i64> = sqlx::query_scalar(
+            "SELECT MAX(version) FROM sieve_script_versions WHERE script_id = ? AND owner_email = ?",
+        )
+        .bind(script_id)
+        .bind(email)
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(latest.map(|version| version as u32))
+    }
+
+    /// Save a new version of a script and drop the oldest ones beyond
+    /// `max_versions`
+    async fn record_version(
+        &self,
+        email: &str,
+        script_id: &str,
+        name: &str,
+        script_content: &str,
+        comment: Option<String>,
+        created_at: DateTime<Utc>,
+    ) -> Result<u32> {
+        let version = self.latest_version(email, script_id).await?.unwrap_or(0) + 1;
+
+        sqlx::query(
+            r#"
+            INSERT INTO sieve_script_versions
+                (script_id, owner_email, version, name, script_content, author, comment, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(script_id)
+        .bind(email)
+        .bind(version as i64)
+        .bind(name)
+        .bind(script_content)
+        .bind(email)
+        .bind(&comment)
+        .bind(created_at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "DELETE FROM sieve_script_versions WHERE script_id = ? AND owner_email = ? AND version <= ?",
+        )
+        .bind(script_id)
+        .bind(email)
+        .bind(version as i64 - self.max_versions as i64)
+        .execute(&self.db)
+        .await?;
+
+        Ok(version)
+    }
+
     /// Execute the active script on a message
     pub async fn execute_for_message(
         &self,
@@ -358,7 +734,8 @@ impl SieveManager {
         let result = match script {
             Some(script) => {
                 let rules = parse_script(&script.script_content)?;
-                let result = SieveExecutor::execute(&rules, message)?;
+                let includes = self.load_includes(email, &rules).await?;
+                let result = SieveExecutor::execute_with_includes(&rules, message, &includes)?;
 
                 // Log the execution
                 self.log_execution(
@@ -454,3 +831,234 @@ impl SieveManager {
         Ok(())
     }
 }
+
+/// Fail with the parser's error if a script is not valid Sieve
+fn ensure_valid(script: &str) -> Result<()> {
+    let validation = validate_script(script)?;
+    if !validation.valid {
+        return Err(anyhow!(
+            "Invalid script: {}",
+            validation.error.unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+/// Ordinary message a script runs on before it becomes active
+fn dry_run_message() -> MessageContext {
+    let body = "This message checks a Sieve script before activation.\r\n".to_string();
+    MessageContext {
+        from: "sender@example.com".to_string(),
+        to: vec!["recipient@example.com".to_string()],
+        cc: vec![],
+        subject: "Sieve dry run".to_string(),
+        headers: vec![
+            ("From".to_string(), "sender@example.com".to_string()),
+            ("To".to_string(), "recipient@example.com".to_string()),
+            ("Subject".to_string(), "Sieve dry run".to_string()),
+        ],
+        size: body.len() as u64,
+        body_parts: vec![("text/plain".to_string(), body.clone())],
+        body,
+        envelope_from: Some("sender@example.com".to_string()),
+        envelope_to: vec!["recipient@example.com".to_string()],
+        received_at: Utc::now(),
+    }
+}
+
+fn version_from_row(
+    (script_id, version, name, script_content, author, comment, created_at): VersionRow,
+) -> SieveScriptVersion {
+    SieveScriptVersion {
+        script_id,
+        version: version as u32,
+        name,
+        script_content,
+        author,
+        comment,
+        created_at: chrono::DateTime::parse_from_rfc3339(&created_at)
+            .map(|d| d.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(script_content: &str, activate: bool) -> CreateSieveScriptRequest {
+        CreateSieveScriptRequest {
+            name: "filters".to_string(),
+            script_content: script_content.to_string(),
+            activate,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_versions_and_rollback() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = SieveManager::new(db).with_max_versions(3);
+        manager.init_db().await.unwrap();
+        let email = "alice@example.com";
+
+        let script = manager
+            .create_script(email, &request("keep;", true))
+            .await
+            .unwrap();
+        for content in ["discard;", "discard;", "keep;\nstop;", "stop;"] {
+            manager
+                .update_script(email, &script.id, &request(content, true))
+                .await
+                .unwrap();
+        }
+
+        // Saving unchanged content adds no version; only 3 are kept
+        let versions = manager.list_versions(email, &script.id).await.unwrap();
+        let numbers: Vec<u32> = versions.iter().map(|v| v.version).collect();
+        assert_eq!(numbers, [4, 3, 2]);
+        assert_eq!(versions[0].author, email);
+
+        let diff = manager
+            .diff_versions(email, &script.id, 2, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.to_version, 4);
+        assert_eq!(diff.unified, "-discard;\n+stop;\n");
+        assert!(manager
+            .diff_versions(email, &script.id, 1, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        let restored = manager.rollback_script(email, &script.id, 3).await.unwrap();
+        assert_eq!(restored.script_content, "keep;\nstop;");
+        assert!(restored.is_active);
+        let latest = manager.get_version(email, &script.id, 5).await.unwrap().unwrap();
+        assert_eq!(latest.comment.as_deref(), Some("Rollback to version 3"));
+        assert!(manager.rollback_script(email, &script.id, 1).await.is_err());
+
+        manager.delete_script(email, &script.id).await.unwrap();
+        assert!(manager.list_versions(email, &script.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_activation_requires_valid_script() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let manager = SieveManager::new(db);
+        manager.init_db().await.unwrap();
+        let email = "bob@example.com";
+
+        assert!(manager
+            .create_script(email, &request("if {", true))
+            .await
+            .is_err());
+        assert!(manager.get_active_script(email).await.unwrap().is_none());
+
+        let result = manager.check_script(email, "discard;").await.unwrap();
+        assert!(!result.implicit_keep);
+    }
+
+    #[tokio::test]
+    async fn test_includes() {
+        let manager = SieveManager::connect("sqlite::memory:").await.unwrap();
+        let email = "carol@example.com";
+
+        // Activation needs the included scripts
+        let main = CreateSieveScriptRequest {
+            name: "main".to_string(),
+            script_content: r#"include :global "common"; include "mine"; fileinto "${folder}";"#
+                .to_string(),
+            activate: true,
+        };
+        assert!(manager.create_script(email, &main).await.is_err());
+
+        let common = CreateSieveScriptRequest {
+            name: "common".to_string(),
+            script_content: r#"global "folder"; set "folder" "Shared";"#.to_string(),
+            activate: false,
+        };
+        manager
+            .create_script(GLOBAL_SCRIPTS_OWNER, &common)
+            .await
+            .unwrap();
+        let mine = CreateSieveScriptRequest {
+            name: "mine".to_string(),
+            script_content: r#"include :global :once "common"; addflag "\\Seen";"#.to_string(),
+            activate: false,
+        };
+        manager.create_script(email, &mine).await.unwrap();
+        let main = CreateSieveScriptRequest {
+            script_content: format!(r#"global "folder"; {}"#, main.script_content),
+            ..main
+        };
+        manager.create_script(email, &main).await.unwrap();
+
+        let result = manager
+            .execute_for_message(email, &dry_run_message(), "<1@example.com>")
+            .await
+            .unwrap();
+        assert_eq!(
+            result.actions,
+            vec![
+                SieveAction::Flag(vec!["\\Seen".to_string()]),
+                SieveAction::FileInto("Shared".to_string()),
+            ]
+        );
+
+        // Another user's scripts are not personal to carol
+        let includes = manager
+            .load_includes("dave@example.com", &parse_script(r#"include "mine";"#).unwrap())
+            .await
+            .unwrap();
+        assert!(includes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_test_script() {
+        let manager = SieveManager::connect("sqlite::memory:").await.unwrap();
+        let email = "erin@example.com";
+
+        let request = TestSieveScriptRequest {
+            script_content: r#"
+                if envelope :is "to" "erin@example.com" {
+                    fileinto "Direct";
+                }
+                if address :domain "from" "lists.example.org" {
+                    discard;
+                }
+            "#
+            .to_string(),
+            message: "From: Lists <news@lists.example.org>\r\nSubject: Hi\r\n\r\nBody\r\n"
+                .to_string(),
+            envelope_from: None,
+            envelope_to: None,
+        };
+        let result = manager.test_script(email, &request).await.unwrap();
+        assert_eq!(
+            result.actions,
+            vec![SieveAction::FileInto("Direct".to_string()), SieveAction::Discard]
+        );
+        assert!(!result.implicit_keep);
+        assert_eq!(result.steps.len(), 2);
+        assert!(result.steps.iter().all(|step| step.condition.is_some()));
+
+        // For another recipient only the second rule matches
+        let request = TestSieveScriptRequest {
+            envelope_to: Some("frank@example.com".to_string()),
+            ..request
+        };
+        let result = manager.test_script(email, &request).await.unwrap();
+        assert_eq!(result.actions, vec![SieveAction::Discard]);
+        assert_eq!(result.steps[0].branch, None);
+
+        // Nothing is logged
+        assert!(manager.get_logs(email, 10).await.unwrap().is_empty());
+
+        let invalid = TestSieveScriptRequest {
+            script_content: "fileinto".to_string(),
+            ..request
+        };
+        assert!(manager.test_script(email, &invalid).await.is_err());
+    }
+}
@@ -1,13 +1,21 @@
 //! Sieve email filtering module (RFC 5228)
 //!
-//! Provides server-side email filtering rules.
+//! Provides server-side email filtering rules. Every saved script is kept as
+//! a version, so users can compare versions and roll back a broken filter.
+//! The active script of a user runs when SMTP delivers mail to them (see
+//! [`delivery`]), and can answer senders with a [`vacation`] response.
 
+pub mod delivery;
+pub mod diff;
 pub mod executor;
 pub mod manager;
 pub mod parser;
 pub mod types;
+pub mod vacation;
 
-pub use executor::SieveExecutor;
-pub use manager::SieveManager;
+pub use delivery::{HeaderEdit, SieveDelivery};
+pub use diff::{DiffLine, DiffOp};
+pub use executor::{IncludedScripts, SieveExecutor};
+pub use manager::{SieveManager, GLOBAL_SCRIPTS_OWNER};
 pub use parser::{parse_script, validate_script};
 pub use types::*;
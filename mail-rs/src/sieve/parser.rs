@@ -5,8 +5,15 @@
 use anyhow::{anyhow, Result};
 use regex::Regex;
 
+use super::executor::compile_regex;
 use super::types::*;
 
+/// Days between vacation responses to a sender when a script gives none
+pub const DEFAULT_VACATION_DAYS: u32 = 7;
+
+/// Longest period a script can ask for between vacation responses
+pub const MAX_VACATION_DAYS: u32 = 30;
+
 /// Token types for lexer
 #[derive(Debug, Clone, PartialEq)]
 enum Token {
@@ -23,11 +30,23 @@ enum Token {
     Stop,
     SetFlag,
     AddFlag,
+    Vacation,
+    Set,
+    Global,
+    Include,
+    Return,
+    AddHeader,
+    DeleteHeader,
     // Tests
     Header,
     Address,
     Size,
     Exists,
+    Body,
+    Envelope,
+    Date,
+    CurrentDate,
+    StringTest,
     AllOf,
     AnyOf,
     Not,
@@ -63,6 +82,56 @@ enum Token {
     Identifier(String),
 }
 
+/// Tags shared by the tests
+#[derive(Default)]
+struct TestTags {
+    match_type: MatchType,
+    comparator: Comparator,
+    address_part: AddressPart,
+}
+
+impl TestTags {
+    /// Reject `:regex` keys that do not compile; keys with variables are
+    /// only known when the script runs
+    fn check_keys(&self, keys: &[String]) -> Result<()> {
+        if self.match_type != MatchType::Regex {
+            return Ok(());
+        }
+        for key in keys.iter().filter(|key| !key.contains("${")) {
+            compile_regex(key, self.comparator != Comparator::Octet)?;
+        }
+        Ok(())
+    }
+}
+
+/// Seconds east of UTC of a `+hhmm` or `-hhmm` zone
+fn parse_zone(value: &str) -> Option<i32> {
+    let (sign, digits) = match value.as_bytes().first()? {
+        b'+' => (1, &value[1..]),
+        b'-' => (-1, &value[1..]),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Whether `name` can name a variable: a letter or `_`, then letters,
+/// digits and `_`
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whether `name` is a valid header field name (RFC 5322)
+pub fn is_field_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+}
+
 /// Sieve script parser
 pub struct SieveParser {
     tokens: Vec<Token>,
@@ -248,10 +317,22 @@ impl SieveParser {
                     "stop" => Token::Stop,
                     "setflag" => Token::SetFlag,
                     "addflag" => Token::AddFlag,
+                    "vacation" => Token::Vacation,
+                    "set" => Token::Set,
+                    "global" => Token::Global,
+                    "include" => Token::Include,
+                    "return" => Token::Return,
+                    "addheader" => Token::AddHeader,
+                    "deleteheader" => Token::DeleteHeader,
                     "header" => Token::Header,
                     "address" => Token::Address,
                     "size" => Token::Size,
                     "exists" => Token::Exists,
+                    "body" => Token::Body,
+                    "envelope" => Token::Envelope,
+                    "date" => Token::Date,
+                    "currentdate" => Token::CurrentDate,
+                    "string" => Token::StringTest,
                     "allof" => Token::AllOf,
                     "anyof" => Token::AnyOf,
                     "not" => Token::Not,
@@ -296,6 +377,15 @@ impl SieveParser {
                 | Token::Discard
                 | Token::FileInto
                 | Token::Redirect
+                | Token::SetFlag
+                | Token::AddFlag
+                | Token::Vacation
+                | Token::Set
+                | Token::Global
+                | Token::Include
+                | Token::Return
+                | Token::AddHeader
+                | Token::DeleteHeader
                 | Token::Stop => {
                     let action = self.parse_action()?;
                     rules.push(SieveRule {
@@ -402,6 +492,10 @@ impl SieveParser {
                 let headers = self.parse_string_list()?;
                 Ok(SieveCondition::Exists(headers))
             }
+            Token::Body => self.parse_body_test(),
+            Token::Envelope => self.parse_envelope_test(),
+            Token::Date | Token::CurrentDate => self.parse_date_test(),
+            Token::StringTest => self.parse_string_test(),
             _ => Err(anyhow!("Expected condition")),
         }
     }
@@ -410,46 +504,18 @@ impl SieveParser {
     fn parse_header_test(&mut self) -> Result<SieveCondition> {
         self.expect(Token::Header)?;
 
-        let mut match_type = MatchType::Is;
-        let mut comparator = Comparator::AsciiCasemap;
-
-        // Parse optional modifiers
-        while self.pos < self.tokens.len() {
-            match &self.tokens[self.pos] {
-                Token::Is => {
-                    match_type = MatchType::Is;
-                    self.pos += 1;
-                }
-                Token::Contains => {
-                    match_type = MatchType::Contains;
-                    self.pos += 1;
-                }
-                Token::Matches => {
-                    match_type = MatchType::Matches;
-                    self.pos += 1;
-                }
-                Token::Comparator => {
-                    self.pos += 1;
-                    if let Token::String(s) = &self.tokens[self.pos] {
-                        comparator = match s.as_str() {
-                            "i;octet" => Comparator::Octet,
-                            _ => Comparator::AsciiCasemap,
-                        };
-                        self.pos += 1;
-                    }
-                }
-                _ => break,
-            }
-        }
+        let mut tags = TestTags::default();
+        while self.parse_test_tag(&mut tags)? {}
 
         let headers = self.parse_string_list()?;
         let values = self.parse_string_list()?;
+        tags.check_keys(&values)?;
 
         Ok(SieveCondition::Header(HeaderTest {
             headers,
             values,
-            match_type,
-            comparator,
+            match_type: tags.match_type,
+            comparator: tags.comparator,
         }))
     }
 
@@ -457,63 +523,202 @@ impl SieveParser {
     fn parse_address_test(&mut self) -> Result<SieveCondition> {
         self.expect(Token::Address)?;
 
-        let mut match_type = MatchType::Is;
-        let mut comparator = Comparator::AsciiCasemap;
-        let mut address_part = AddressPart::All;
+        let mut tags = TestTags::default();
+        while self.parse_test_tag(&mut tags)? {}
 
-        // Parse optional modifiers
-        while self.pos < self.tokens.len() {
-            match &self.tokens[self.pos] {
-                Token::Is => {
-                    match_type = MatchType::Is;
-                    self.pos += 1;
-                }
-                Token::Contains => {
-                    match_type = MatchType::Contains;
-                    self.pos += 1;
-                }
-                Token::Matches => {
-                    match_type = MatchType::Matches;
+        let headers = self.parse_string_list()?;
+        let values = self.parse_string_list()?;
+        tags.check_keys(&values)?;
+
+        Ok(SieveCondition::Address(AddressTest {
+            headers,
+            values,
+            match_type: tags.match_type,
+            address_part: tags.address_part,
+            comparator: tags.comparator,
+        }))
+    }
+
+    /// Parse envelope test
+    fn parse_envelope_test(&mut self) -> Result<SieveCondition> {
+        self.expect(Token::Envelope)?;
+
+        let mut tags = TestTags::default();
+        while self.parse_test_tag(&mut tags)? {}
+
+        let parts = self.parse_string_list()?;
+        if let Some(part) = parts
+            .iter()
+            .find(|part| !matches!(part.to_lowercase().as_str(), "from" | "to"))
+        {
+            return Err(anyhow!("Unknown envelope part {}", part));
+        }
+        let values = self.parse_string_list()?;
+        tags.check_keys(&values)?;
+
+        Ok(SieveCondition::Envelope(EnvelopeTest {
+            parts,
+            values,
+            match_type: tags.match_type,
+            address_part: tags.address_part,
+            comparator: tags.comparator,
+        }))
+    }
+
+    /// Parse body test
+    fn parse_body_test(&mut self) -> Result<SieveCondition> {
+        self.expect(Token::Body)?;
+
+        let mut tags = TestTags::default();
+        let mut transform = BodyTransform::default();
+        loop {
+            if self.parse_test_tag(&mut tags)? {
+                continue;
+            }
+            match self.tokens.get(self.pos) {
+                Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(":raw") => {
                     self.pos += 1;
+                    transform = BodyTransform::Raw;
                 }
-                Token::LocalPart => {
-                    address_part = AddressPart::LocalPart;
+                Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(":text") => {
                     self.pos += 1;
+                    transform = BodyTransform::Text;
                 }
-                Token::Domain => {
-                    address_part = AddressPart::Domain;
+                Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(":content") => {
                     self.pos += 1;
+                    transform = BodyTransform::Content(self.parse_string_list()?);
                 }
-                Token::All => {
-                    address_part = AddressPart::All;
+                _ => break,
+            }
+        }
+
+        let values = self.parse_string_list()?;
+        tags.check_keys(&values)?;
+
+        Ok(SieveCondition::Body(BodyTest {
+            transform,
+            values,
+            match_type: tags.match_type,
+            comparator: tags.comparator,
+        }))
+    }
+
+    /// Parse date or currentdate test
+    fn parse_date_test(&mut self) -> Result<SieveCondition> {
+        let current = self.tokens.get(self.pos) == Some(&Token::CurrentDate);
+        self.pos += 1;
+
+        let mut tags = TestTags::default();
+        let mut zone = DateZone::Local;
+        loop {
+            if self.parse_test_tag(&mut tags)? {
+                continue;
+            }
+            match self.tokens.get(self.pos) {
+                Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(":zone") => {
                     self.pos += 1;
+                    let value = self.parse_string()?;
+                    zone = DateZone::Offset(
+                        parse_zone(&value).ok_or_else(|| anyhow!("Invalid zone {}", value))?,
+                    );
                 }
-                Token::Comparator => {
+                Some(Token::Identifier(tag))
+                    if !current && tag.eq_ignore_ascii_case(":originalzone") =>
+                {
                     self.pos += 1;
-                    if let Token::String(s) = &self.tokens[self.pos] {
-                        comparator = match s.as_str() {
-                            "i;octet" => Comparator::Octet,
-                            _ => Comparator::AsciiCasemap,
-                        };
-                        self.pos += 1;
-                    }
+                    zone = DateZone::Original;
                 }
                 _ => break,
             }
         }
 
-        let headers = self.parse_string_list()?;
+        let header = if current {
+            None
+        } else {
+            Some(self.parse_string()?)
+        };
+        let part = self.parse_string()?;
+        let date_part =
+            DatePart::parse(&part).ok_or_else(|| anyhow!("Unknown date part {}", part))?;
         let values = self.parse_string_list()?;
+        tags.check_keys(&values)?;
 
-        Ok(SieveCondition::Address(AddressTest {
-            headers,
+        let test = DateTest {
+            header,
+            date_part,
             values,
-            match_type,
-            address_part,
-            comparator,
+            match_type: tags.match_type,
+            comparator: tags.comparator,
+            zone,
+        };
+        Ok(match current {
+            true => SieveCondition::CurrentDate(test),
+            false => SieveCondition::Date(test),
+        })
+    }
+
+    /// Parse string test
+    fn parse_string_test(&mut self) -> Result<SieveCondition> {
+        self.expect(Token::StringTest)?;
+
+        let mut tags = TestTags::default();
+        while self.parse_test_tag(&mut tags)? {}
+
+        let sources = self.parse_string_list()?;
+        let values = self.parse_string_list()?;
+        tags.check_keys(&values)?;
+
+        Ok(SieveCondition::String(StringTest {
+            sources,
+            values,
+            match_type: tags.match_type,
+            comparator: tags.comparator,
         }))
     }
 
+    /// Consume a match type, relational, comparator or address part tag
+    ///
+    /// Returns whether there was one.
+    fn parse_test_tag(&mut self, tags: &mut TestTags) -> Result<bool> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Is) => tags.match_type = MatchType::Is,
+            Some(Token::Contains) => tags.match_type = MatchType::Contains,
+            Some(Token::Matches) => tags.match_type = MatchType::Matches,
+            Some(Token::LocalPart) => tags.address_part = AddressPart::LocalPart,
+            Some(Token::Domain) => tags.address_part = AddressPart::Domain,
+            Some(Token::All) => tags.address_part = AddressPart::All,
+            Some(Token::Comparator) => {
+                self.pos += 1;
+                let name = self.parse_string()?;
+                tags.comparator = match name.to_lowercase().as_str() {
+                    "i;octet" => Comparator::Octet,
+                    "i;ascii-numeric" => Comparator::AsciiNumeric,
+                    _ => Comparator::AsciiCasemap,
+                };
+                return Ok(true);
+            }
+            Some(Token::Identifier(tag)) => match tag.to_lowercase().as_str() {
+                ":regex" => tags.match_type = MatchType::Regex,
+                relational @ (":value" | ":count") => {
+                    let count = relational == ":count";
+                    self.pos += 1;
+                    let value = self.parse_string()?;
+                    let op = RelationalOp::parse(&value)
+                        .ok_or_else(|| anyhow!("Unknown relational operator {}", value))?;
+                    tags.match_type = match count {
+                        true => MatchType::Count(op),
+                        false => MatchType::Value(op),
+                    };
+                    return Ok(true);
+                }
+                _ => return Ok(false),
+            },
+            _ => return Ok(false),
+        }
+        self.pos += 1;
+        Ok(true)
+    }
+
     /// Parse size test
     fn parse_size_test(&mut self) -> Result<SieveCondition> {
         self.expect(Token::Size)?;
@@ -607,12 +812,206 @@ impl SieveParser {
                 self.expect(Token::Semicolon)?;
                 SieveAction::Flag(flags)
             }
+            Token::Vacation => {
+                self.pos += 1;
+                let vacation = self.parse_vacation()?;
+                self.expect(Token::Semicolon)?;
+                SieveAction::Vacation(vacation)
+            }
+            Token::Set => {
+                self.pos += 1;
+                let set = self.parse_set()?;
+                self.expect(Token::Semicolon)?;
+                SieveAction::Set(set)
+            }
+            Token::Global => {
+                self.pos += 1;
+                let names = self.parse_string_list()?;
+                if let Some(name) = names.iter().find(|name| !is_variable_name(name)) {
+                    return Err(anyhow!("Invalid variable name {}", name));
+                }
+                self.expect(Token::Semicolon)?;
+                SieveAction::Global(names.iter().map(|name| name.to_lowercase()).collect())
+            }
+            Token::Include => {
+                self.pos += 1;
+                let include = self.parse_include()?;
+                self.expect(Token::Semicolon)?;
+                SieveAction::Include(include)
+            }
+            Token::Return => {
+                self.pos += 1;
+                self.expect(Token::Semicolon)?;
+                SieveAction::Return
+            }
+            Token::AddHeader => {
+                self.pos += 1;
+                let last = self.parse_flag_tag(":last");
+                let name = self.parse_string()?;
+                if !name.contains("${") && !is_field_name(&name) {
+                    return Err(anyhow!("Invalid header name {}", name));
+                }
+                let value = self.parse_string()?;
+                self.expect(Token::Semicolon)?;
+                SieveAction::AddHeader(AddHeader { name, value, last })
+            }
+            Token::DeleteHeader => {
+                self.pos += 1;
+                let delete = self.parse_deleteheader()?;
+                self.expect(Token::Semicolon)?;
+                SieveAction::DeleteHeader(delete)
+            }
             _ => return Err(anyhow!("Expected action")),
         };
 
         Ok(action)
     }
 
+    /// Parse the arguments of a vacation action (RFC 5230)
+    fn parse_vacation(&mut self) -> Result<VacationConfig> {
+        let mut vacation = VacationConfig {
+            subject: String::new(),
+            body: String::new(),
+            days: DEFAULT_VACATION_DAYS,
+            addresses: vec![],
+            from: None,
+            handle: None,
+            mime: false,
+        };
+
+        while let Some(Token::Identifier(tag)) = self.tokens.get(self.pos) {
+            let tag = tag.to_lowercase();
+            self.pos += 1;
+            match tag.as_str() {
+                ":days" => match self.tokens.get(self.pos) {
+                    Some(Token::Number(days)) => {
+                        vacation.days = (*days).clamp(1, MAX_VACATION_DAYS as u64) as u32;
+                        self.pos += 1;
+                    }
+                    _ => return Err(anyhow!("Expected number of days")),
+                },
+                ":subject" => vacation.subject = self.parse_string()?,
+                ":from" => vacation.from = Some(self.parse_string()?),
+                ":handle" => vacation.handle = Some(self.parse_string()?),
+                ":addresses" => vacation.addresses = self.parse_string_list()?,
+                ":mime" => vacation.mime = true,
+                _ => return Err(anyhow!("Unknown vacation argument {}", tag)),
+            }
+        }
+
+        vacation.body = self.parse_string()?;
+        Ok(vacation)
+    }
+
+    /// Parse the arguments of a set action (RFC 5229)
+    fn parse_set(&mut self) -> Result<SetVariable> {
+        let mut modifiers = Vec::new();
+        while let Some(Token::Identifier(tag)) = self.tokens.get(self.pos) {
+            let modifier =
+                SetModifier::parse(tag).ok_or_else(|| anyhow!("Unknown set modifier {}", tag))?;
+            self.pos += 1;
+            if !modifiers.contains(&modifier) {
+                modifiers.push(modifier);
+            }
+        }
+        modifiers.sort();
+
+        let name = self.parse_string()?;
+        if !is_variable_name(&name) {
+            return Err(anyhow!("Invalid variable name {}", name));
+        }
+        let value = self.parse_string()?;
+        Ok(SetVariable {
+            name: name.to_lowercase(),
+            value,
+            modifiers,
+        })
+    }
+
+    /// Parse the arguments of an include action (RFC 6609)
+    fn parse_include(&mut self) -> Result<IncludeScript> {
+        let mut include = IncludeScript {
+            name: String::new(),
+            location: IncludeLocation::Personal,
+            once: false,
+            optional: false,
+        };
+        while let Some(Token::Identifier(tag)) = self.tokens.get(self.pos) {
+            match tag.to_lowercase().as_str() {
+                ":personal" => include.location = IncludeLocation::Personal,
+                ":global" => include.location = IncludeLocation::Global,
+                ":once" => include.once = true,
+                ":optional" => include.optional = true,
+                _ => return Err(anyhow!("Unknown include argument {}", tag)),
+            }
+            self.pos += 1;
+        }
+
+        include.name = self.parse_string()?;
+        if include.name.trim().is_empty() || include.name.contains('/') {
+            return Err(anyhow!("Invalid script name {}", include.name));
+        }
+        Ok(include)
+    }
+
+    /// Parse the arguments of a deleteheader action (RFC 5293)
+    fn parse_deleteheader(&mut self) -> Result<DeleteHeader> {
+        let mut tags = TestTags::default();
+        let mut index = None;
+        let mut last = false;
+        loop {
+            if self.parse_test_tag(&mut tags)? {
+                continue;
+            }
+            match self.tokens.get(self.pos) {
+                Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(":index") => {
+                    self.pos += 1;
+                    match self.tokens.get(self.pos) {
+                        Some(Token::Number(n)) if *n > 0 => {
+                            index = Some(*n as usize);
+                            self.pos += 1;
+                        }
+                        _ => return Err(anyhow!("Expected a positive index")),
+                    }
+                }
+                Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(":last") => {
+                    self.pos += 1;
+                    last = true;
+                }
+                _ => break,
+            }
+        }
+        if last && index.is_none() {
+            return Err(anyhow!(":last needs :index"));
+        }
+
+        let name = self.parse_string()?;
+        let values = match self.tokens.get(self.pos) {
+            Some(Token::String(_)) | Some(Token::OpenBracket) => self.parse_string_list()?,
+            _ => vec![],
+        };
+        tags.check_keys(&values)?;
+        Ok(DeleteHeader {
+            name,
+            index,
+            last,
+            values,
+            match_type: tags.match_type,
+            comparator: tags.comparator,
+        })
+    }
+
+    /// Consume the tag `name` if it comes next
+    fn parse_flag_tag(&mut self, name: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Identifier(tag)) if tag.eq_ignore_ascii_case(name) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Parse string list or single string
     fn parse_string_list(&mut self) -> Result<Vec<String>> {
         if self.pos >= self.tokens.len() {
@@ -649,7 +1048,7 @@ impl SieveParser {
 
     /// Parse single string
     fn parse_string(&mut self) -> Result<String> {
-        if let Token::String(s) = &self.tokens[self.pos] {
+        if let Some(Token::String(s)) = self.tokens.get(self.pos) {
             let s = s.clone();
             self.pos += 1;
             Ok(s)
@@ -745,4 +1144,126 @@ mod tests {
             panic!("Expected AllOf condition");
         }
     }
+
+    #[test]
+    fn test_parse_vacation() {
+        let script = r#"
+            require "vacation";
+            vacation :days 60 :subject "Away" :addresses ["bob@example.org"]
+                :handle "summer" "Back in August.";
+            if header :contains "subject" "urgent" {
+                vacation "Call me.";
+            }
+        "#;
+
+        let rules = parse_script(script).unwrap();
+        assert_eq!(
+            rules[0].actions,
+            vec![SieveAction::Vacation(VacationConfig {
+                subject: "Away".to_string(),
+                body: "Back in August.".to_string(),
+                days: MAX_VACATION_DAYS,
+                addresses: vec!["bob@example.org".to_string()],
+                from: None,
+                handle: Some("summer".to_string()),
+                mime: false,
+            })]
+        );
+        match &rules[1].actions[0] {
+            SieveAction::Vacation(vacation) => {
+                assert_eq!(vacation.days, DEFAULT_VACATION_DAYS);
+                assert!(vacation.subject.is_empty());
+            }
+            action => panic!("Expected vacation, got {:?}", action),
+        }
+
+        assert!(parse_script(r#"vacation :days "x" "Away";"#).is_err());
+        assert!(parse_script(r#"vacation :seconds 5 "Away";"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_regex() {
+        let rules = parse_script(r#"if header :regex "Subject" "^\\[(\\w+)\\]" { keep; }"#).unwrap();
+        match &rules[0].condition {
+            SieveCondition::Header(test) => {
+                assert_eq!(test.match_type, MatchType::Regex);
+                assert_eq!(test.values, vec!["^\\[(\\w+)\\]".to_string()]);
+            }
+            other => panic!("unexpected condition {:?}", other),
+        }
+
+        // Invalid and oversized keys are errors, keys with variables wait
+        assert!(parse_script(r#"if header :regex "Subject" "(unclosed" { keep; }"#).is_err());
+        assert!(parse_script(r#"if body :regex "(a{1000}){1000}" { keep; }"#).is_err());
+        assert!(parse_script(r#"deleteheader :regex "X-Spam" "[" ;"#).is_err());
+        assert!(parse_script(r#"if header :regex "Subject" "${pattern}" { keep; }"#).is_ok());
+        assert!(parse_script(r#"if header :matches "Subject" "(unclosed" { keep; }"#).is_ok());
+
+        let rules = parse_script(r#"set :quoteregex "key" "a.b";"#).unwrap();
+        match &rules[0].actions[0] {
+            SieveAction::Set(set) => {
+                assert_eq!(set.modifiers, vec![SetModifier::QuoteRegex]);
+                assert_eq!(SetModifier::QuoteRegex.apply("a.b*"), "a\\.b\\*");
+            }
+            other => panic!("unexpected action {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_variables_include_editheader() {
+        let script = r#"
+            require ["variables", "include", "editheader"];
+            set :upperfirst :lower "folder" "${1}";
+            global "list";
+            include :global :once :optional "spam";
+            addheader :last "X-Filtered" "yes";
+            if true {
+                deleteheader :index 1 :last :contains "Received" "internal";
+                return;
+            }
+        "#;
+
+        let rules = parse_script(script).unwrap();
+        assert_eq!(
+            rules[0].actions,
+            vec![SieveAction::Set(SetVariable {
+                name: "folder".to_string(),
+                value: "${1}".to_string(),
+                modifiers: vec![SetModifier::Lower, SetModifier::UpperFirst],
+            })]
+        );
+        assert_eq!(rules[1].actions, vec![SieveAction::Global(vec!["list".to_string()])]);
+        assert_eq!(
+            rules[2].actions,
+            vec![SieveAction::Include(IncludeScript {
+                name: "spam".to_string(),
+                location: IncludeLocation::Global,
+                once: true,
+                optional: true,
+            })]
+        );
+        assert_eq!(
+            rules[3].actions,
+            vec![SieveAction::AddHeader(AddHeader {
+                name: "X-Filtered".to_string(),
+                value: "yes".to_string(),
+                last: true,
+            })]
+        );
+        match &rules[4].actions[..] {
+            [SieveAction::DeleteHeader(delete), SieveAction::Return] => {
+                assert_eq!(delete.index, Some(1));
+                assert!(delete.last);
+                assert_eq!(delete.match_type, MatchType::Contains);
+                assert_eq!(delete.values, vec!["internal"]);
+            }
+            actions => panic!("Expected deleteheader and return, got {:?}", actions),
+        }
+
+        assert!(parse_script(r#"set "1x" "value";"#).is_err());
+        assert!(parse_script(r#"set :shout "x" "value";"#).is_err());
+        assert!(parse_script(r#"include "../other";"#).is_err());
+        assert!(parse_script(r#"addheader "Bad Name" "value";"#).is_err());
+        assert!(parse_script(r#"deleteheader :last "X-Spam";"#).is_err());
+    }
 }
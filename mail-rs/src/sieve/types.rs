@@ -3,6 +3,8 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::diff::DiffLine;
+
 /// Sieve script
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SieveScript {
@@ -25,6 +27,37 @@ pub struct SieveScript {
     pub updated_at: DateTime<Utc>,
 }
 
+/// Saved version of a Sieve script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SieveScriptVersion {
+    /// Script this is a version of
+    pub script_id: String,
+    /// Version number, starting at 1
+    pub version: u32,
+    /// Script name at this version
+    pub name: String,
+    /// Script content at this version
+    pub script_content: String,
+    /// User who saved this version
+    pub author: String,
+    /// Why the version was saved, e.g. a rollback
+    pub comment: Option<String>,
+    /// When this version was saved
+    pub created_at: DateTime<Utc>,
+}
+
+/// Differences between two versions of a script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SieveScriptDiff {
+    pub script_id: String,
+    pub from_version: u32,
+    pub to_version: u32,
+    /// Lines of both versions, marked as kept, inserted or deleted
+    pub lines: Vec<DiffLine>,
+    /// The same lines with ` `, `+` and `-` prefixes
+    pub unified: String,
+}
+
 /// A single Sieve rule (if condition then action)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SieveRule {
@@ -59,6 +92,16 @@ pub enum SieveCondition {
     Size(SizeTest),
     /// Exists test (header exists)
     Exists(Vec<String>),
+    /// Body test (RFC 5173)
+    Body(BodyTest),
+    /// Envelope test against MAIL FROM and RCPT TO
+    Envelope(EnvelopeTest),
+    /// Date test on a header field (RFC 5260)
+    Date(DateTest),
+    /// Date test on the time of delivery (RFC 5260 `currentdate`)
+    CurrentDate(DateTest),
+    /// Test on expanded strings, usually variables (RFC 5229 `string`)
+    String(StringTest),
 }
 
 /// Header test configuration
@@ -89,6 +132,141 @@ pub struct AddressTest {
     pub comparator: Comparator,
 }
 
+/// Body test configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BodyTest {
+    /// Which form of the body is compared
+    pub transform: BodyTransform,
+    /// Values to compare against
+    pub values: Vec<String>,
+    /// Match type
+    pub match_type: MatchType,
+    /// Comparator
+    pub comparator: Comparator,
+}
+
+/// Form of the body a body test compares (RFC 5173)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub enum BodyTransform {
+    /// Undecoded body, MIME structure included
+    Raw,
+    /// Decoded text parts (default)
+    #[default]
+    Text,
+    /// Decoded parts of these content types; `text` matches any `text/*`
+    /// type and an empty string any part
+    Content(Vec<String>),
+}
+
+/// Envelope test configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvelopeTest {
+    /// Envelope parts to test (`from`, `to`)
+    pub parts: Vec<String>,
+    /// Values to compare against
+    pub values: Vec<String>,
+    /// Match type
+    pub match_type: MatchType,
+    /// Address part to compare
+    pub address_part: AddressPart,
+    /// Comparator
+    pub comparator: Comparator,
+}
+
+/// String test configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StringTest {
+    /// Strings to test, after variable expansion
+    pub sources: Vec<String>,
+    /// Values to compare against
+    pub values: Vec<String>,
+    /// Match type
+    pub match_type: MatchType,
+    /// Comparator
+    pub comparator: Comparator,
+}
+
+/// Date test configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DateTest {
+    /// Header holding the date; unset for `currentdate`
+    pub header: Option<String>,
+    /// Part of the date to compare
+    pub date_part: DatePart,
+    /// Values to compare against
+    pub values: Vec<String>,
+    /// Match type
+    pub match_type: MatchType,
+    /// Comparator
+    pub comparator: Comparator,
+    /// Zone the date is converted to
+    pub zone: DateZone,
+}
+
+/// Part of a date a date test compares (RFC 5260)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DatePart {
+    /// `yyyy`
+    Year,
+    /// `mm`
+    Month,
+    /// `dd`
+    Day,
+    /// `yyyy-mm-dd`
+    Date,
+    /// Days since 1858-11-17 (Modified Julian Day)
+    Julian,
+    /// `hh`
+    Hour,
+    /// `mm`
+    Minute,
+    /// `ss`
+    Second,
+    /// `hh:mm:ss`
+    Time,
+    /// `yyyy-mm-ddThh:mm:ss+hh:mm`
+    Iso8601,
+    /// RFC 2822 date
+    Std11,
+    /// `+hhmm`
+    Zone,
+    /// `0` (Sunday) to `6`
+    Weekday,
+}
+
+impl DatePart {
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value.to_lowercase().as_str() {
+            "year" => Self::Year,
+            "month" => Self::Month,
+            "day" => Self::Day,
+            "date" => Self::Date,
+            "julian" => Self::Julian,
+            "hour" => Self::Hour,
+            "minute" => Self::Minute,
+            "second" => Self::Second,
+            "time" => Self::Time,
+            "iso8601" => Self::Iso8601,
+            "std11" => Self::Std11,
+            "zone" => Self::Zone,
+            "weekday" => Self::Weekday,
+            _ => return None,
+        })
+    }
+}
+
+/// Zone a date test converts dates to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum DateZone {
+    /// The server's zone, UTC
+    #[default]
+    Local,
+    /// A `+hhmm` or `-hhmm` offset (`:zone`)
+    Offset(i32),
+    /// The zone the header was written in (`:originalzone`)
+    Original,
+}
+
 /// Size test configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SizeTest {
@@ -118,6 +296,49 @@ pub enum MatchType {
     Matches,
     /// Regex match
     Regex,
+    /// Relational comparison of the values (RFC 5231 `:value`)
+    Value(RelationalOp),
+    /// Relational comparison of the number of values (RFC 5231 `:count`)
+    Count(RelationalOp),
+}
+
+/// Relational operator of `:value` and `:count`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RelationalOp {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl RelationalOp {
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value.to_lowercase().as_str() {
+            "gt" => Self::Gt,
+            "ge" => Self::Ge,
+            "lt" => Self::Lt,
+            "le" => Self::Le,
+            "eq" => Self::Eq,
+            "ne" => Self::Ne,
+            _ => return None,
+        })
+    }
+
+    /// Whether a value ordered `ordering` against the key satisfies the
+    /// operator
+    pub fn holds(&self, ordering: std::cmp::Ordering) -> bool {
+        use std::cmp::Ordering::*;
+        match self {
+            Self::Gt => ordering == Greater,
+            Self::Ge => ordering != Less,
+            Self::Lt => ordering == Less,
+            Self::Le => ordering != Greater,
+            Self::Eq => ordering == Equal,
+            Self::Ne => ordering != Equal,
+        }
+    }
 }
 
 /// Address part to extract
@@ -138,6 +359,9 @@ pub enum Comparator {
     AsciiCasemap,
     /// Octet (byte-by-byte)
     Octet,
+    /// Numbers at the start of the strings; strings without one compare
+    /// as infinity (RFC 4790)
+    AsciiNumeric,
 }
 
 impl Default for Comparator {
@@ -175,12 +399,145 @@ pub enum SieveAction {
     Stop,
     /// Send vacation reply
     Vacation(VacationConfig),
+    /// Set a variable (RFC 5229)
+    Set(SetVariable),
+    /// Share variables with included scripts (RFC 6609 `global`)
+    Global(Vec<String>),
+    /// Run another script (RFC 6609)
+    Include(IncludeScript),
+    /// End the current script and go back to the including one
+    Return,
+    /// Add a header field (RFC 5293)
+    AddHeader(AddHeader),
+    /// Delete header fields (RFC 5293)
+    DeleteHeader(DeleteHeader),
 }
 
-/// Vacation auto-reply configuration
+/// Arguments of a `set` action
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SetVariable {
+    /// Variable name, lowercase
+    pub name: String,
+    pub value: String,
+    /// Modifiers, in the order they are applied
+    pub modifiers: Vec<SetModifier>,
+}
+
+/// Modifier of a `set` action
+///
+/// The variants are ordered by precedence, highest first, which is the
+/// order they are applied in.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SetModifier {
+    Lower,
+    Upper,
+    LowerFirst,
+    UpperFirst,
+    /// Escape `*`, `?` and `\` for use in a `:matches` key
+    QuoteWildcard,
+    /// Escape regex metacharacters for use in a `:regex` key
+    QuoteRegex,
+    /// Replace the value with its length in characters
+    Length,
+}
+
+impl SetModifier {
+    pub fn parse(tag: &str) -> Option<Self> {
+        Some(match tag.to_lowercase().as_str() {
+            ":lower" => Self::Lower,
+            ":upper" => Self::Upper,
+            ":lowerfirst" => Self::LowerFirst,
+            ":upperfirst" => Self::UpperFirst,
+            ":quotewildcard" => Self::QuoteWildcard,
+            ":quoteregex" => Self::QuoteRegex,
+            ":length" => Self::Length,
+            _ => return None,
+        })
+    }
+
+    /// `value` with the modifier applied
+    pub fn apply(&self, value: &str) -> String {
+        let mut chars = value.chars();
+        match self {
+            Self::Lower => value.to_lowercase(),
+            Self::Upper => value.to_uppercase(),
+            Self::LowerFirst => match chars.next() {
+                Some(first) => first.to_lowercase().chain(chars).collect(),
+                None => String::new(),
+            },
+            Self::UpperFirst => match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            },
+            Self::QuoteWildcard => {
+                let mut quoted = String::with_capacity(value.len());
+                for c in chars {
+                    if matches!(c, '*' | '?' | '\\') {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c);
+                }
+                quoted
+            }
+            Self::QuoteRegex => regex::escape(value),
+            Self::Length => value.chars().count().to_string(),
+        }
+    }
+}
+
+/// Where an included script is stored
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+pub enum IncludeLocation {
+    /// Scripts of the user the script runs for
+    #[default]
+    Personal,
+    /// Scripts shared by all users, managed by admins
+    Global,
+}
+
+/// Arguments of an `include` action
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct IncludeScript {
+    /// Name of the script
+    pub name: String,
+    pub location: IncludeLocation,
+    /// Skip the script if this run already included it
+    pub once: bool,
+    /// Skip the script if it does not exist
+    pub optional: bool,
+}
+
+/// Arguments of an `addheader` action
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AddHeader {
+    pub name: String,
+    pub value: String,
+    /// Append the field instead of prepending it
+    pub last: bool,
+}
+
+/// Arguments of a `deleteheader` action
+///
+/// In a [`SieveResult`] every deleted field is its own action with an
+/// `index` and no `values`, in the order the fields are removed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DeleteHeader {
+    pub name: String,
+    /// Only the field at this 1-based position among the fields named
+    /// `name`
+    pub index: Option<usize>,
+    /// Count `index` from the last field
+    pub last: bool,
+    /// Only fields with a value matching one of these; all if empty
+    pub values: Vec<String>,
+    pub match_type: MatchType,
+    pub comparator: Comparator,
+}
+
+/// Vacation auto-reply configuration
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct VacationConfig {
-    /// Subject line
+    /// Subject line (empty: "Auto: " and the original subject)
     pub subject: String,
     /// Message body
     pub body: String,
@@ -188,6 +545,15 @@ pub struct VacationConfig {
     pub days: u32,
     /// Addresses to respond to
     pub addresses: Vec<String>,
+    /// From address of the response (default: the recipient)
+    #[serde(default)]
+    pub from: Option<String>,
+    /// Responses with the same handle share the reply interval
+    #[serde(default)]
+    pub handle: Option<String>,
+    /// Whether the body is a MIME entity with its own headers
+    #[serde(default)]
+    pub mime: bool,
 }
 
 /// Sieve execution result
@@ -208,6 +574,42 @@ impl Default for SieveResult {
     }
 }
 
+/// Branch of a rule a run took
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SieveBranch {
+    If,
+    /// `elsif` branch, numbered from 0
+    Elsif(usize),
+    Else,
+}
+
+/// A rule a traced run reached, and what it did
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SieveTraceStep {
+    /// Included script the rule is in; `None` for the script run
+    pub script: Option<String>,
+    /// Position of the rule in its script, from 0
+    pub rule: usize,
+    /// Branch taken; `None` when no condition matched and there is no `else`
+    pub branch: Option<SieveBranch>,
+    /// Condition that matched, for `if` and `elsif` branches
+    pub condition: Option<SieveCondition>,
+    /// Actions of the branch, with those of the scripts it included
+    pub actions: Vec<SieveAction>,
+}
+
+/// What a script would do with a message, rule by rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SieveTestResult {
+    /// Actions to take
+    pub actions: Vec<SieveAction>,
+    /// Whether implicit keep is enabled
+    pub implicit_keep: bool,
+    /// Rules reached, in the order they ran
+    pub steps: Vec<SieveTraceStep>,
+}
+
 /// Message context for Sieve evaluation
 #[derive(Debug, Clone)]
 pub struct MessageContext {
@@ -225,6 +627,16 @@ pub struct MessageContext {
     pub body: String,
     /// Message size in bytes
     pub size: u64,
+    /// Decoded leaf parts of the body as lowercase content type and text,
+    /// in message order
+    pub body_parts: Vec<(String, String)>,
+    /// Envelope sender (MAIL FROM), empty for bounces; unset when the
+    /// message was not received over SMTP
+    pub envelope_from: Option<String>,
+    /// Envelope recipient the script runs for (RCPT TO)
+    pub envelope_to: Vec<String>,
+    /// Time of delivery, the current date of `currentdate`
+    pub received_at: DateTime<Utc>,
 }
 
 /// Sieve execution log
@@ -262,6 +674,21 @@ pub struct ValidateSieveScriptRequest {
     pub script_content: String,
 }
 
+/// API request to run a Sieve script on a sample message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestSieveScriptRequest {
+    /// Script content to run
+    pub script_content: String,
+    /// Raw RFC 5322 message
+    pub message: String,
+    /// Envelope sender; defaults to the From header
+    #[serde(default)]
+    pub envelope_from: Option<String>,
+    /// Envelope recipient; defaults to the user running the test
+    #[serde(default)]
+    pub envelope_to: Option<String>,
+}
+
 /// Validation result
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ValidationResult {
@@ -0,0 +1,292 @@
+//! Vacation responses (RFC 5230)
+//!
+//! Decides whether a delivered message gets the vacation response its
+//! recipient's script asked for, following the RFC 3834 rules against
+//! answering automated mail, and builds the response. How often a sender
+//! is answered is tracked by the caller, per [`handle`].
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::types::*;
+
+/// Local parts of senders that are lists or mail systems (RFC 5230 4.6)
+const AUTOMATED_SENDERS: &[&str] = &["mailer-daemon", "listserv", "majordomo"];
+
+/// Whether `message`, received from envelope sender `sender` for
+/// `recipient`, gets the vacation response
+pub fn should_respond(
+    vacation: &VacationConfig,
+    recipient: &str,
+    sender: &str,
+    message: &MessageContext,
+) -> bool {
+    // Bounces and other mail with a null reverse-path
+    let sender = sender.trim().to_lowercase();
+    if sender.is_empty() {
+        return false;
+    }
+
+    let own: Vec<String> = std::iter::once(recipient)
+        .chain(vacation.addresses.iter().map(String::as_str))
+        .map(|address| address.trim().to_lowercase())
+        .collect();
+    if own.contains(&sender) {
+        return false;
+    }
+
+    let local = sender.split('@').next().unwrap_or_default();
+    if AUTOMATED_SENDERS.contains(&local)
+        || local.starts_with("owner-")
+        || local.ends_with("-request")
+    {
+        return false;
+    }
+
+    for (name, value) in &message.headers {
+        let name = name.to_lowercase();
+        let value = value.trim().to_lowercase();
+        // Auto-generated or auto-replied mail, mailing lists and bulk mail
+        if (name == "auto-submitted" && value != "no")
+            || name.starts_with("list-")
+            || (name == "precedence" && ["bulk", "list", "junk"].contains(&value.as_str()))
+        {
+            return false;
+        }
+    }
+
+    // Only mail addressed to the recipient, not to a list it is on
+    message
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            ["to", "cc", "bcc", "resent-to", "resent-cc", "resent-bcc"]
+                .contains(&name.to_lowercase().as_str())
+        })
+        .flat_map(|(_, value)| value.split(','))
+        .map(|address| address_of(address).to_lowercase())
+        .any(|address| own.contains(&address))
+}
+
+/// What identifies a response for the reply interval: the `:handle` of
+/// the script, or a digest of the response otherwise
+pub fn handle(vacation: &VacationConfig) -> String {
+    if let Some(handle) = &vacation.handle {
+        return handle.clone();
+    }
+    let mut hasher = Sha256::new();
+    for part in [
+        vacation.subject.as_str(),
+        vacation.from.as_deref().unwrap_or_default(),
+        vacation.body.as_str(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// RFC 3834 response to `message` from `recipient` to envelope sender
+/// `sender`, to be sent with a null reverse-path
+pub fn build_response(
+    vacation: &VacationConfig,
+    recipient: &str,
+    sender: &str,
+    message: &MessageContext,
+    hostname: &str,
+) -> Vec<u8> {
+    let subject = if vacation.subject.is_empty() {
+        format!("Auto: {}", message.subject)
+    } else {
+        vacation.subject.clone()
+    };
+    let from = match &vacation.from {
+        Some(from) => single_line(from),
+        None => format!("<{}>", recipient),
+    };
+
+    let mut response = format!(
+        "From: {from}\r\n\
+         To: <{sender}>\r\n\
+         Subject: {subject}\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@{hostname}>\r\n",
+        sender = single_line(sender),
+        subject = single_line(&subject),
+        date = Utc::now().to_rfc2822(),
+        id = Uuid::new_v4(),
+    );
+
+    let header = |name: &str| {
+        message
+            .headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    if let Some(message_id) = header("message-id") {
+        let references = match header("references") {
+            Some(references) => format!("{} {}", references, message_id),
+            None => message_id.clone(),
+        };
+        response.push_str(&format!(
+            "In-Reply-To: {}\r\nReferences: {}\r\n",
+            message_id, references
+        ));
+    }
+
+    response.push_str("Auto-Submitted: auto-replied (vacation)\r\nMIME-Version: 1.0\r\n");
+    if !vacation.mime {
+        response.push_str(
+            "Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\
+             \r\n",
+        );
+    }
+    // A :mime reason starts with its own headers
+    for line in vacation.body.lines() {
+        response.push_str(line);
+        response.push_str("\r\n");
+    }
+    response.into_bytes()
+}
+
+/// Bare address of a header address, without display name
+fn address_of(address: &str) -> &str {
+    match (address.find('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address,
+    }
+    .trim()
+}
+
+/// Header value with line breaks a script or sender could inject removed
+fn single_line(value: &str) -> String {
+    value
+        .split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vacation() -> VacationConfig {
+        VacationConfig {
+            subject: String::new(),
+            body: "Back next week.".to_string(),
+            days: 7,
+            addresses: vec!["robert@example.com".to_string()],
+            from: None,
+            handle: None,
+            mime: false,
+        }
+    }
+
+    fn message(headers: &str) -> MessageContext {
+        MessageContext::from_message(format!("{}\r\n\r\nHello\r\n", headers).as_bytes())
+    }
+
+    #[test]
+    fn test_should_respond() {
+        let vacation = vacation();
+        let direct = "From: Alice <alice@example.org>\r\nTo: Bob <bob@example.com>\r\nSubject: Lunch";
+        let respond = |sender: &str, headers: &str| {
+            should_respond(&vacation, "bob@example.com", sender, &message(headers))
+        };
+
+        assert!(respond("alice@example.org", direct));
+        // Other addresses of the recipient count as addressed to it
+        assert!(respond(
+            "alice@example.org",
+            "From: alice@example.org\r\nCc: robert@example.com"
+        ));
+
+        assert!(!respond("", direct));
+        assert!(!respond("bob@example.com", direct));
+        assert!(!respond("MAILER-DAEMON@example.org", direct));
+        assert!(!respond("owner-staff@example.org", direct));
+        assert!(!respond("staff-request@example.org", direct));
+        assert!(!respond(
+            "alice@example.org",
+            &format!("{}\r\nAuto-Submitted: auto-generated", direct)
+        ));
+        assert!(respond(
+            "alice@example.org",
+            &format!("{}\r\nAuto-Submitted: no", direct)
+        ));
+        assert!(!respond(
+            "alice@example.org",
+            &format!("{}\r\nList-Id: <staff.example.org>", direct)
+        ));
+        assert!(!respond(
+            "alice@example.org",
+            &format!("{}\r\nPrecedence: bulk", direct)
+        ));
+        // Not addressed to the recipient, e.g. sent to a list
+        assert!(!respond(
+            "alice@example.org",
+            "From: alice@example.org\r\nTo: staff@example.com"
+        ));
+    }
+
+    #[test]
+    fn test_build_response() {
+        let original = message(
+            "From: alice@example.org\r\nTo: bob@example.com\r\nSubject: Lunch\r\n\
+             Message-ID: <2@example.org>\r\nReferences: <1@example.org>",
+        );
+
+        let response = build_response(
+            &vacation(),
+            "bob@example.com",
+            "alice@example.org",
+            &original,
+            "mx.example.com",
+        );
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("From: <bob@example.com>\r\nTo: <alice@example.org>\r\n"));
+        assert!(response.contains("Subject: Auto: Lunch\r\n"));
+        assert!(response.contains("In-Reply-To: <2@example.org>\r\n"));
+        assert!(response.contains("References: <1@example.org> <2@example.org>\r\n"));
+        assert!(response.contains("Auto-Submitted: auto-replied (vacation)\r\n"));
+        assert!(response.ends_with("\r\n\r\nBack next week.\r\n"));
+
+        let custom = VacationConfig {
+            subject: "Away\r\nBcc: eve@example.net".to_string(),
+            from: Some("Bob <bob@example.com>".to_string()),
+            ..vacation()
+        };
+        let response = build_response(
+            &custom,
+            "bob@example.com",
+            "alice@example.org",
+            &original,
+            "mx.example.com",
+        );
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("From: Bob <bob@example.com>\r\n"));
+        assert!(response.contains("Subject: Away Bcc: eve@example.net\r\n"));
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut vacation = vacation();
+        let derived = handle(&vacation);
+        assert_eq!(derived, handle(&vacation.clone()));
+
+        vacation.body = "Back tomorrow.".to_string();
+        assert_ne!(handle(&vacation), derived);
+
+        vacation.handle = Some("away".to_string());
+        assert_eq!(handle(&vacation), "away");
+    }
+}
@@ -0,0 +1,274 @@
+//! Bounce generation (RFC 3464 delivery status notifications)
+//!
+//! When a message we accepted for relay can't be delivered, its sender gets a
+//! `multipart/report` message with three parts: a human readable
+//! explanation, a `message/delivery-status` part per RFC 3464 and the
+//! original message headers as `text/rfc822-headers`. The explanation ends
+//! with the SMTP transcript of the failed attempt when one is available.
+//!
+//! Bounces are never sent to the null reverse-path, and are themselves sent
+//! from the null reverse-path so they can't cause further bounces
+//! (RFC 5321 section 4.5.5).
+
+use crate::error::MailError;
+use crate::mime::{BodyPart, MessageBuilder};
+use crate::smtp::Transcript;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Why delivery to one recipient failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryFailure {
+    pub recipient: String,
+    /// Enhanced status code (RFC 3463), e.g. `5.1.1`
+    pub status: String,
+    /// Server that rejected the message, if one answered
+    pub remote_mta: Option<String>,
+    /// SMTP reply of the remote server
+    pub diagnostic: Option<String>,
+    /// Explanation shown to the sender
+    pub reason: String,
+    /// SMTP dialogue of the failed attempt, without message content
+    pub transcript: Option<Transcript>,
+}
+
+impl DeliveryFailure {
+    /// Describe the final error of a delivery attempt
+    pub fn from_error(recipient: &str, error: &MailError) -> Self {
+        let (status, remote_mta, diagnostic) = match error {
+            MailError::SmtpRejected { host, reply } => (
+                enhanced_status(reply).unwrap_or_else(|| "5.0.0".to_string()),
+                Some(host.clone()),
+                Some(reply.clone()),
+            ),
+            MailError::RequireTls(_) => ("5.7.30".to_string(), None, None),
+            MailError::DnsLookup(_) => ("5.4.4".to_string(), None, None),
+            // Anything else only becomes permanent once retries run out
+            _ => ("5.4.7".to_string(), None, None),
+        };
+
+        Self {
+            recipient: recipient.to_string(),
+            status,
+            remote_mta,
+            diagnostic,
+            reason: error.to_string(),
+            transcript: None,
+        }
+    }
+
+    /// Attach the transcript of the failed attempt
+    pub fn with_transcript(mut self, transcript: Transcript) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
+}
+
+/// Builds bounce messages on behalf of the local MTA
+#[derive(Debug, Clone)]
+pub struct BounceGenerator {
+    hostname: String,
+}
+
+impl BounceGenerator {
+    /// # Arguments
+    /// * `hostname` - Reporting MTA, bounces come from `MAILER-DAEMON@hostname`
+    pub fn new(hostname: impl Into<String>) -> Self {
+        Self {
+            hostname: hostname.into(),
+        }
+    }
+
+    /// Build the bounce for `original`, or `None` when `sender` is the null
+    /// reverse-path
+    ///
+    /// # Arguments
+    /// * `sender` - Reverse-path of the original message
+    /// * `original` - Original message (headers + body)
+    /// * `failures` - Recipients that could not be reached
+    /// * `arrival` - When the original message was accepted
+    pub fn generate(
+        &self,
+        sender: &str,
+        original: &[u8],
+        failures: &[DeliveryFailure],
+        arrival: DateTime<Utc>,
+    ) -> Option<Vec<u8>> {
+        if sender.is_empty() || failures.is_empty() {
+            return None;
+        }
+
+        // Human readable explanation
+        let mut explanation = format!(
+            "This is the mail system at host {host}.\r\n\
+             \r\n\
+             Your message could not be delivered to one or more recipients.\r\n\
+             It has been returned without its content; the original headers\r\n\
+             are attached.\r\n\
+             \r\n",
+            host = self.hostname,
+        );
+        for failure in failures {
+            explanation.push_str(&format!("<{}>: {}\r\n", failure.recipient, failure.reason));
+        }
+        explanation.push_str("\r\n");
+        for failure in failures {
+            if let Some(transcript) = &failure.transcript {
+                explanation.push_str(&format!(
+                    "Transcript of the session with {} for <{}>:\r\n\r\n{}\r\n",
+                    transcript.server,
+                    failure.recipient,
+                    transcript.render()
+                ));
+            }
+        }
+
+        // Machine readable status (RFC 3464 section 2)
+        let mut status = format!(
+            "Reporting-MTA: dns; {host}\r\n\
+             Arrival-Date: {arrival}\r\n",
+            host = self.hostname,
+            arrival = arrival.to_rfc2822(),
+        );
+        for failure in failures {
+            status.push_str(&format!(
+                "\r\n\
+                 Final-Recipient: rfc822; {}\r\n\
+                 Action: failed\r\n\
+                 Status: {}\r\n",
+                failure.recipient, failure.status
+            ));
+            if let Some(remote_mta) = &failure.remote_mta {
+                status.push_str(&format!("Remote-MTA: dns; {}\r\n", remote_mta));
+            }
+            if let Some(diagnostic) = &failure.diagnostic {
+                status.push_str(&format!(
+                    "Diagnostic-Code: smtp; {}\r\n",
+                    single_line(diagnostic)
+                ));
+            }
+        }
+
+        // Report with the original headers, unchanged
+        let message = MessageBuilder::new()
+            .from(&format!("Mail Delivery System <MAILER-DAEMON@{}>", self.hostname))
+            .to(&format!("<{}>", sender))
+            .subject("Undelivered Mail Returned to Sender")
+            .message_id(&format!("<{}@{}>", Uuid::new_v4(), self.hostname))
+            .header("Auto-Submitted", "auto-replied")
+            .text(&explanation)
+            .part(BodyPart::new("message/delivery-status", status))
+            .part(BodyPart::new("text/rfc822-headers", original_headers(original)))
+            .report("delivery-status")
+            .build();
+
+        Some(message)
+    }
+}
+
+/// Header section of a message, with CRLF line endings
+fn original_headers(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+    let end = [("\r\n\r\n", 2), ("\n\n", 1)]
+        .iter()
+        .filter_map(|(separator, keep)| text.find(separator).map(|i| i + keep))
+        .min()
+        .unwrap_or(text.len());
+
+    text[..end]
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Enhanced status code of an SMTP reply (`550 5.1.1 ...`)
+fn enhanced_status(reply: &str) -> Option<String> {
+    let code = reply.get(4..)?.split_whitespace().next()?;
+    let mut parts = code.split('.');
+    let class = parts.next()?;
+    let valid = matches!(class, "2" | "4" | "5")
+        && parts.clone().count() == 2
+        && parts.all(|part| {
+            !part.is_empty() && part.len() <= 3 && part.bytes().all(|b| b.is_ascii_digit())
+        });
+    valid.then(|| code.to_string())
+}
+
+/// Multi-line replies folded onto one line
+fn single_line(reply: &str) -> String {
+    reply
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounce_report() {
+        let original =
+            b"From: alice@example.com\r\nSubject: Hello\r\nX-Custom: kept\r\n\r\nsecret body\r\n";
+        let failure = DeliveryFailure::from_error(
+            "bob@example.net",
+            &MailError::SmtpRejected {
+                host: "mx.example.net".to_string(),
+                reply: "550 5.1.1 <bob@example.net>: User unknown\r\n".to_string(),
+            },
+        );
+        assert_eq!(failure.status, "5.1.1");
+
+        let bounce = BounceGenerator::new("mail.example.com")
+            .generate("alice@example.com", original, &[failure], Utc::now())
+            .unwrap();
+        let text = String::from_utf8(bounce).unwrap();
+
+        assert!(text.starts_with("From: Mail Delivery System <MAILER-DAEMON@mail.example.com>\r\n"));
+        assert!(text.contains("To: <alice@example.com>\r\n"));
+        assert!(text.contains("report-type=delivery-status"));
+        assert!(text.contains("Reporting-MTA: dns; mail.example.com\r\n"));
+        assert!(text.contains(
+            "Final-Recipient: rfc822; bob@example.net\r\nAction: failed\r\nStatus: 5.1.1\r\n"
+        ));
+        assert!(text.contains("Remote-MTA: dns; mx.example.net\r\n"));
+        assert!(
+            text.contains("Diagnostic-Code: smtp; 550 5.1.1 <bob@example.net>: User unknown\r\n")
+        );
+        assert!(text.contains(
+            "Content-Type: text/rfc822-headers\r\n\r\nFrom: alice@example.com\r\nSubject: Hello\r\nX-Custom: kept\r\n"
+        ));
+        assert!(!text.contains("secret body"));
+    }
+
+    #[test]
+    fn test_no_bounce_to_null_reverse_path() {
+        let failure = DeliveryFailure::from_error(
+            "bob@example.net",
+            &MailError::DnsLookup("No MX records for example.net".to_string()),
+        );
+        assert_eq!(failure.status, "5.4.4");
+
+        let generator = BounceGenerator::new("mail.example.com");
+        assert!(generator
+            .generate("", b"Subject: bounce\r\n\r\n", &[failure], Utc::now())
+            .is_none());
+    }
+
+    #[test]
+    fn test_enhanced_status() {
+        assert_eq!(
+            enhanced_status("550 5.7.1 Denied").as_deref(),
+            Some("5.7.1")
+        );
+        assert_eq!(
+            enhanced_status("552-5.2.2 Mailbox full\r\n").as_deref(),
+            Some("5.2.2")
+        );
+        assert_eq!(enhanced_status("550 User unknown"), None);
+        assert_eq!(enhanced_status("550"), None);
+    }
+}
@@ -0,0 +1,460 @@
+//! Per-user resource budgets for the delivery pipeline
+//!
+//! A single recipient whose mail is expensive to process (huge messages,
+//! slow checks, many concurrent deliveries) must not hold up delivery for
+//! everyone else. [`DeliveryBudget`] accounts the time each user's mail
+//! spends in the pipeline stages and bounds it:
+//!
+//! - Optional stages, which inspect the content but aren't needed to
+//!   deliver it (like the impersonation check), run through
+//!   [`DeliveryBudget::run_stage`] with a timeout per message. A stage that times out, or whose users have
+//!   used up their stage time for the last minute, is skipped and the
+//!   message is delivered with an `X-Delivery-Warning` header instead.
+//! - Required stages, which protect the recipient (like the spam check),
+//!   run through [`DeliveryBudget::run_required_stage`]: their time is
+//!   charged the same way, but they run even for users over budget, and a
+//!   timeout defers the message rather than delivering it unchecked.
+//! - Mailbox writes take one of a limited number of IO slots per user
+//!   ([`DeliveryBudget::io_slot`]), so one user's burst of large deliveries
+//!   queues behind itself rather than in front of other users.
+//!
+//! Usage is measured even when the budgets are not enforced, and
+//! [`DeliveryBudget::heaviest`] lists the users costing the most.
+
+use crate::config::DeliveryBudgetConfig;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+/// Window over which a user's stage time counts against the budget
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Enforced limits; `None` leaves a limit off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BudgetLimits {
+    /// Longest one optional stage may run for one message
+    pub stage_timeout: Option<Duration>,
+    /// Stage time per user and minute before optional stages are skipped
+    pub user_time_per_minute: Option<Duration>,
+    /// Mailbox writes per user at the same time
+    pub user_io_concurrency: Option<usize>,
+}
+
+impl From<&DeliveryBudgetConfig> for BudgetLimits {
+    fn from(config: &DeliveryBudgetConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+        let millis = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+        Self {
+            stage_timeout: millis(config.stage_timeout_ms),
+            user_time_per_minute: millis(config.user_ms_per_minute),
+            user_io_concurrency: (config.user_io_concurrency > 0)
+                .then_some(config.user_io_concurrency),
+        }
+    }
+}
+
+/// Why a stage was skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The stage ran longer than the stage timeout
+    TimedOut,
+    /// Every user of the message used up their stage time
+    OverBudget,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::TimedOut => write!(f, "timed out"),
+            SkipReason::OverBudget => write!(f, "over budget"),
+        }
+    }
+}
+
+/// Result of a stage run through [`DeliveryBudget::run_stage`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum StageOutcome<T> {
+    Done(T),
+    Skipped(SkipReason),
+}
+
+/// Header telling the recipient that `stage` was skipped for `reason`
+pub fn warning_header(stage: &str, reason: SkipReason) -> String {
+    format!("X-Delivery-Warning: {} skipped ({})\r\n", stage, reason)
+}
+
+/// Resource usage of one user since the server started
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UserUsage {
+    pub user: String,
+    /// Messages delivered to the user's mailbox
+    pub messages: u64,
+    pub bytes: u64,
+    /// Time spent in optional stages
+    pub stage_time_ms: u64,
+    /// Stage time in the last minute, the part counted against the budget
+    pub recent_stage_time_ms: u64,
+    /// Time spent waiting for an IO slot
+    pub io_wait_ms: u64,
+    pub skipped_stages: u64,
+    pub timeouts: u64,
+}
+
+/// Accounting of one user
+struct Account {
+    usage: UserUsage,
+    /// Stage time in the current window
+    recent: VecDeque<(Instant, Duration)>,
+    io: Option<Arc<Semaphore>>,
+}
+
+impl Account {
+    fn recent_time(&mut self, now: Instant) -> Duration {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.iter().map(|(_, time)| *time).sum()
+    }
+}
+
+/// Budgets shared by every SMTP session
+pub struct DeliveryBudget {
+    limits: BudgetLimits,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl DeliveryBudget {
+    /// Create budgets with the given limits; usage is measured even
+    /// without any
+    pub fn new(limits: BudgetLimits) -> Self {
+        Self {
+            limits,
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create budgets from the `[delivery_budget]` section
+    pub fn from_config(config: &DeliveryBudgetConfig) -> Self {
+        Self::new(config.into())
+    }
+
+    /// Enforced limits
+    pub fn limits(&self) -> BudgetLimits {
+        self.limits
+    }
+
+    /// Run an optional stage for a message to `users`, charging its time
+    /// to each of them
+    ///
+    /// The stage is skipped if every user is over budget, so one heavy
+    /// recipient doesn't degrade mail to the others, and abandoned once it
+    /// exceeds the stage timeout.
+    pub async fn run_stage<T>(
+        &self,
+        users: &[String],
+        stage: &str,
+        future: impl Future<Output = T>,
+    ) -> StageOutcome<T> {
+        if !users.is_empty() && users.iter().all(|user| self.over_budget(user)) {
+            warn!("Skipping {} for {:?}: over budget", stage, users);
+            self.charge(users, Duration::ZERO, Some(SkipReason::OverBudget));
+            return StageOutcome::Skipped(SkipReason::OverBudget);
+        }
+
+        let started = Instant::now();
+        let result = match self.limits.stage_timeout {
+            Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+            None => Some(future.await),
+        };
+        let elapsed = started.elapsed();
+        match result {
+            Some(value) => {
+                self.charge(users, elapsed, None);
+                StageOutcome::Done(value)
+            }
+            None => {
+                warn!(
+                    "Skipping {} for {:?}: timed out after {:?}",
+                    stage, users, elapsed
+                );
+                self.charge(users, elapsed, Some(SkipReason::TimedOut));
+                StageOutcome::Skipped(SkipReason::TimedOut)
+            }
+        }
+    }
+
+    /// Run a required stage for a message to `users`, charging its time
+    /// to each of them
+    ///
+    /// Unlike [`Self::run_stage`] the stage runs even if every user is
+    /// over budget. `None` means it exceeded the stage timeout, and the
+    /// message must not be delivered without it.
+    pub async fn run_required_stage<T>(
+        &self,
+        users: &[String],
+        stage: &str,
+        future: impl Future<Output = T>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let result = match self.limits.stage_timeout {
+            Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+            None => Some(future.await),
+        };
+        let elapsed = started.elapsed();
+        if result.is_none() {
+            warn!(
+                "Deferring message for {:?}: {} timed out after {:?}",
+                users, stage, elapsed
+            );
+        }
+        let timed_out = result.is_none().then_some(SkipReason::TimedOut);
+        self.charge(users, elapsed, timed_out);
+        result
+    }
+
+    /// Whether `user` used up their stage time for the last minute
+    pub fn over_budget(&self, user: &str) -> bool {
+        let Some(limit) = self.limits.user_time_per_minute else {
+            return false;
+        };
+        let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+        accounts
+            .get_mut(&user.to_lowercase())
+            .is_some_and(|account| account.recent_time(Instant::now()) >= limit)
+    }
+
+    /// Wait for an IO slot of `user`, held until the returned slot is
+    /// dropped
+    pub async fn io_slot(&self, user: &str) -> IoSlot {
+        let semaphore = self.with_account(user, |account| account.io.clone());
+        let Some(semaphore) = semaphore else {
+            return IoSlot { _permit: None };
+        };
+
+        let started = Instant::now();
+        let permit = semaphore.acquire_owned().await.ok();
+        let waited = started.elapsed();
+        if !waited.is_zero() {
+            self.with_account(user, |account| {
+                account.usage.io_wait_ms += waited.as_millis() as u64;
+            });
+        }
+        IoSlot { _permit: permit }
+    }
+
+    /// Count a message of `bytes` delivered to `user`
+    pub fn record_delivery(&self, user: &str, bytes: usize) {
+        self.with_account(user, |account| {
+            account.usage.messages += 1;
+            account.usage.bytes += bytes as u64;
+        });
+    }
+
+    /// The `limit` users with the most stage time in the last minute, then
+    /// overall
+    pub fn heaviest(&self, limit: usize) -> Vec<UserUsage> {
+        let now = Instant::now();
+        let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut usage: Vec<UserUsage> = accounts
+            .values_mut()
+            .map(|account| {
+                let mut usage = account.usage.clone();
+                usage.recent_stage_time_ms = account.recent_time(now).as_millis() as u64;
+                usage
+            })
+            .collect();
+        usage.sort_by(|a, b| {
+            (b.recent_stage_time_ms, b.stage_time_ms, b.io_wait_ms)
+                .cmp(&(a.recent_stage_time_ms, a.stage_time_ms, a.io_wait_ms))
+                .then_with(|| a.user.cmp(&b.user))
+        });
+        usage.truncate(limit);
+        usage
+    }
+
+    fn charge(&self, users: &[String], time: Duration, skipped: Option<SkipReason>) {
+        let now = Instant::now();
+        for user in users {
+            self.with_account(user, |account| {
+                account.usage.stage_time_ms += time.as_millis() as u64;
+                if !time.is_zero() {
+                    account.recent.push_back((now, time));
+                }
+                match skipped {
+                    Some(SkipReason::TimedOut) => {
+                        account.usage.skipped_stages += 1;
+                        account.usage.timeouts += 1;
+                    }
+                    Some(SkipReason::OverBudget) => account.usage.skipped_stages += 1,
+                    None => {}
+                }
+            });
+        }
+    }
+
+    fn with_account<T>(&self, user: &str, f: impl FnOnce(&mut Account) -> T) -> T {
+        let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+        let account = accounts
+            .entry(user.to_lowercase())
+            .or_insert_with(|| Account {
+                usage: UserUsage {
+                    user: user.to_lowercase(),
+                    ..Default::default()
+                },
+                recent: VecDeque::new(),
+                io: self
+                    .limits
+                    .user_io_concurrency
+                    .map(|slots| Arc::new(Semaphore::new(slots))),
+            });
+        f(account)
+    }
+}
+
+/// An IO slot of one user, see [`DeliveryBudget::io_slot`]
+pub struct IoSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[cfg(test)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(timeout_ms: u64, ms_per_minute: u64, io: usize) -> DeliveryBudget {
+        DeliveryBudget::from_config(&DeliveryBudgetConfig {
+            enabled: true,
+            stage_timeout_ms: timeout_ms,
+            user_ms_per_minute: ms_per_minute,
+            user_io_concurrency: io,
+        })
+    }
+
+    fn users(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn sleep(ms: u64) -> tokio::time::Sleep {
+        tokio::time::sleep(Duration::from_millis(ms))
+    }
+
+    #[tokio::test]
+    async fn test_required_stage_ignores_budget() {
+        let budget = budget(50, 20, 0);
+        let heavy = users(&["heavy@example.com"]);
+
+        assert_eq!(budget.run_stage(&heavy, "sieve", sleep(30)).await, StageOutcome::Done(()));
+        assert!(budget.over_budget("heavy@example.com"));
+        assert_eq!(budget.run_required_stage(&heavy, "spam", async { 1 }).await, Some(1));
+        assert_eq!(budget.run_required_stage(&heavy, "spam", sleep(5000)).await, None);
+
+        let usage = &budget.heaviest(1)[0];
+        assert_eq!(usage.timeouts, 1);
+        assert_eq!(usage.skipped_stages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stage_timeout_and_budget() {
+        let budget = budget(50, 80, 0);
+        let heavy = users(&["heavy@example.com"]);
+
+        assert_eq!(
+            budget.run_stage(&heavy, "spam", async { 1 }).await,
+            StageOutcome::Done(1)
+        );
+        assert_eq!(
+            budget.run_stage(&heavy, "sieve", sleep(5000)).await,
+            StageOutcome::Skipped(SkipReason::TimedOut)
+        );
+        assert_eq!(
+            budget.run_stage(&heavy, "sieve", sleep(30)).await,
+            StageOutcome::Done(())
+        );
+        assert!(budget.over_budget("Heavy@example.com"));
+        assert_eq!(
+            budget.run_stage(&heavy, "sieve", async {}).await,
+            StageOutcome::Skipped(SkipReason::OverBudget)
+        );
+
+        // Mail also addressed to a light user still gets the stage
+        let both = users(&["heavy@example.com", "light@example.com"]);
+        assert_eq!(
+            budget.run_stage(&both, "sieve", async {}).await,
+            StageOutcome::Done(())
+        );
+
+        let heaviest = budget.heaviest(10);
+        assert_eq!(heaviest.len(), 2);
+        assert_eq!(heaviest[0].user, "heavy@example.com");
+        assert!(heaviest[0].recent_stage_time_ms >= 80);
+        assert_eq!(heaviest[0].skipped_stages, 2);
+        assert_eq!(heaviest[0].timeouts, 1);
+        assert_eq!(heaviest[1].user, "light@example.com");
+        assert_eq!(
+            warning_header("sieve", SkipReason::OverBudget),
+            "X-Delivery-Warning: sieve skipped (over budget)\r\n"
+        );
+    }
+
+    #[test]
+    fn test_budget_window() {
+        let budget = budget(0, 100, 0);
+        let now = Instant::now();
+        budget.with_account("user@example.com", |account| {
+            account
+                .recent
+                .push_back((now - WINDOW, Duration::from_secs(5)));
+            account.recent.push_back((now, Duration::from_millis(40)));
+        });
+
+        // Only the last minute counts against the budget
+        assert!(!budget.over_budget("user@example.com"));
+        assert_eq!(budget.heaviest(1)[0].recent_stage_time_ms, 40);
+    }
+
+    #[tokio::test]
+    async fn test_io_slots() {
+        let budget = Arc::new(budget(0, 0, 1));
+        let first = budget.io_slot("user@example.com").await;
+        // Other users are not held up
+        let _other = budget.io_slot("other@example.com").await;
+
+        let waiting = tokio::spawn({
+            let budget = budget.clone();
+            async move {
+                let _slot = budget.io_slot("USER@example.com").await;
+                budget.record_delivery("user@example.com", 2048);
+            }
+        });
+        sleep(30).await;
+        assert!(!waiting.is_finished());
+        drop(first);
+        waiting.await.unwrap();
+
+        let usage = &budget.heaviest(1)[0];
+        assert_eq!(usage.user, "user@example.com");
+        assert!(usage.io_wait_ms >= 20);
+        assert_eq!((usage.messages, usage.bytes), (1, 2048));
+    }
+
+    #[test]
+    fn test_limits_from_config() {
+        let mut config = DeliveryBudgetConfig::default();
+        assert_eq!(BudgetLimits::from(&config), BudgetLimits::default());
+        config.enabled = true;
+        let limits = BudgetLimits::from(&config);
+        assert_eq!(limits.stage_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(limits.user_io_concurrency, Some(4));
+    }
+}
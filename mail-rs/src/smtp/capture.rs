@@ -0,0 +1,92 @@
+//! Capture mode for staging and development
+//!
+//! With `[capture] enabled`, the outbound queue keeps messages to any
+//! recipient outside `allow_domains` with the `captured` status instead of
+//! delivering them, so an integration environment never mails real
+//! customers. Captured messages can be read and cleared through
+//! `/api/admin/capture`.
+//!
+//! # Configuration
+//! ```toml
+//! [capture]
+//! enabled = true
+//! allow_domains = ["staging.example.com"]
+//! ```
+//!
+//! Domains are matched exactly against the recipient's domain.
+
+use crate::config::CaptureConfig;
+
+/// Which outbound messages are captured instead of delivered
+#[derive(Debug, Clone, Default)]
+pub struct CapturePolicy {
+    /// Recipient domains still delivered, lowercase
+    allow_domains: Vec<String>,
+}
+
+impl CapturePolicy {
+    /// Policy of the `[capture]` section; None when capture is disabled
+    pub fn from_config(config: &CaptureConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        Some(Self {
+            allow_domains: config
+                .allow_domains
+                .iter()
+                .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        })
+    }
+
+    /// Recipient domains still delivered
+    pub fn allow_domains(&self) -> &[String] {
+        &self.allow_domains
+    }
+
+    /// Whether mail to `recipient` is captured; addresses without a domain
+    /// always are
+    pub fn captures(&self, recipient: &str) -> bool {
+        match recipient.rsplit_once('@') {
+            Some((_, domain)) => !self
+                .allow_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain)),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_captures_outside_allowlist() {
+        let config = CaptureConfig {
+            enabled: true,
+            allow_domains: vec![
+                " Staging.Example.com".to_string(),
+                "@qa.example.net".to_string(),
+            ],
+        };
+        let policy = CapturePolicy::from_config(&config).unwrap();
+        assert_eq!(
+            policy.allow_domains(),
+            ["staging.example.com", "qa.example.net"]
+        );
+
+        assert!(!policy.captures("dev@staging.example.com"));
+        assert!(!policy.captures("dev@QA.example.net"));
+        assert!(policy.captures("customer@example.com"));
+        assert!(policy.captures("dev@sub.staging.example.com"));
+        assert!(policy.captures("postmaster"));
+
+        let disabled = CaptureConfig {
+            enabled: false,
+            ..config
+        };
+        assert!(CapturePolicy::from_config(&disabled).is_none());
+    }
+}
@@ -9,14 +9,41 @@
 //! - Retry logic
 //!
 //! # Security
-//! - TLS support (future)
+//! - Opportunistic STARTTLS, verified against the webpki roots; a failed
+//!   handshake falls back to plaintext (RFC 3207 has no mandatory TLS)
+//! - TLS outcomes recorded for TLS-RPT (see [`crate::tlsrpt`])
+//! - Session transcript kept for diagnostics, without message content (see
+//!   [`Transcript`])
 //! - DKIM signing (future)
 //! - SPF validation (future)
 
+use crate::chaos::{self, Fault};
 use crate::error::{MailError, Result};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use crate::smtp::transcript::Transcript;
+use crate::tlsrpt::{TlsFailure, TlsResultType, TlsRptManager};
+use rustls::{CertificateError, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, warn};
+
+/// TLS connector trusting the Mozilla root certificates
+static TLS_CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
 
 /// SMTP client for sending emails to external servers
 ///
@@ -36,16 +63,59 @@ use tracing::{debug, error, info};
 /// ```
 pub struct SmtpClient {
     server_addr: String,
+    /// Upgrade with STARTTLS when the server offers it
+    tls: bool,
+    /// Relay as a REQUIRETLS message (RFC 8689)
+    require_tls: bool,
+    tls_reporting: Option<Arc<TlsRptManager>>,
+    /// Dialogue of the last `send_mail` call
+    transcript: Mutex<Transcript>,
 }
 
 impl SmtpClient {
     /// Create a new SMTP client
     pub fn new(server_addr: String) -> Self {
-        Self { server_addr }
+        Self {
+            tls: true,
+            require_tls: false,
+            tls_reporting: None,
+            transcript: Mutex::new(Transcript::new(server_addr.clone())),
+            server_addr,
+        }
+    }
+
+    /// Never attempt STARTTLS
+    pub fn without_tls(mut self) -> Self {
+        self.tls = false;
+        self
+    }
+
+    /// Only deliver over verified TLS to a server supporting REQUIRETLS
+    /// (RFC 8689), passing the requirement on; anything else fails with
+    /// [`MailError::RequireTls`] instead of falling back to plaintext
+    pub fn require_tls(mut self) -> Self {
+        self.require_tls = true;
+        self
+    }
+
+    /// Record TLS negotiation outcomes against the recipient domain
+    pub fn with_tls_reporting(mut self, manager: Arc<TlsRptManager>) -> Self {
+        self.tls_reporting = Some(manager);
+        self
+    }
+
+    /// Commands, replies and connection details of the last
+    /// [`Self::send_mail`] call, including a failed TLS attempt
+    pub fn transcript(&self) -> Transcript {
+        self.transcript.lock().unwrap().clone()
     }
 
     /// Send an email to the specified recipient
     ///
+    /// The connection is upgraded with STARTTLS when offered. If the TLS
+    /// handshake fails the message is sent again over a new plaintext
+    /// connection, unless [`Self::require_tls`] is set.
+    ///
     /// # Arguments
     /// * `from` - Sender email address
     /// * `to` - Recipient email address
@@ -58,11 +128,37 @@ impl SmtpClient {
     /// - Timeout occurs
     pub async fn send_mail(&self, from: &str, to: &str, data: &[u8]) -> Result<()> {
         info!("Sending mail from {} to {} via {}", from, to, self.server_addr);
+        *self.transcript.lock().unwrap() = Transcript::new(self.server_addr.clone());
+
+        match self.deliver(from, to, data, self.tls || self.require_tls).await {
+            Err(MailError::Tls(e)) => {
+                warn!(
+                    "TLS negotiation with {} failed ({}), retrying without TLS",
+                    self.server_addr, e
+                );
+                self.note("retrying without TLS");
+                self.deliver(from, to, data, false).await
+            }
+            result => result,
+        }
+    }
+
+    /// One delivery attempt over a fresh connection
+    async fn deliver(&self, from: &str, to: &str, data: &[u8], tls: bool) -> Result<()> {
+        if chaos::inject(Fault::SlowSmtp).await {
+            self.note("connection delayed by injected fault");
+        }
 
         // Connect to server
-        let stream = TcpStream::connect(&self.server_addr).await?;
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
+        let stream = TcpStream::connect(&self.server_addr)
+            .await
+            .inspect_err(|e| self.note(&format!("connection failed: {}", e)))?;
+        let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip().to_string());
+        if let Some(ip) = &peer_ip {
+            self.note(&format!("connected to {}", ip));
+            self.transcript.lock().unwrap().remote_ip = Some(ip.clone());
+        }
+        let mut reader = BufReader::new(stream);
 
         // Read greeting
         let greeting = self.read_line(&mut reader).await?;
@@ -73,22 +169,106 @@ impl SmtpClient {
         debug!("Received greeting: {}", greeting.trim());
 
         // Send EHLO
-        self.write_line(&mut writer, &format!("EHLO {}", self.get_hostname())).await?;
-        self.read_response(&mut reader, "250").await?;
+        let ehlo = format!("EHLO {}", self.get_hostname());
+        self.write_line(reader.get_mut(), &ehlo).await?;
+        let capabilities = self.read_response(&mut reader, "250").await?;
+
+        if !tls {
+            return self.transaction(&mut reader, from, to, data).await;
+        }
+
+        if !has_capability(&capabilities, "STARTTLS") {
+            self.record_tls_failure(to, TlsResultType::StarttlsNotSupported, peer_ip, None)
+                .await;
+            if self.require_tls {
+                self.quit(&mut reader).await;
+                return Err(MailError::RequireTls(format!(
+                    "{} does not offer STARTTLS",
+                    self.host()
+                )));
+            }
+            return self.transaction(&mut reader, from, to, data).await;
+        }
+
+        // STARTTLS
+        self.write_line(reader.get_mut(), "STARTTLS").await?;
+        self.read_response(&mut reader, "220").await?;
 
+        let server_name = ServerName::try_from(self.host())
+            .map_err(|e| MailError::Tls(format!("Invalid server name {}: {}", self.host(), e)))?;
+        let stream = match TLS_CONNECTOR.connect(server_name, reader.into_inner()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.note(&format!("TLS handshake failed: {}", e));
+                self.record_tls_failure(to, classify_tls_error(&e), peer_ip, Some(e.to_string()))
+                    .await;
+                if self.require_tls {
+                    return Err(MailError::RequireTls(format!(
+                        "TLS handshake with {} failed: {}",
+                        self.host(),
+                        e
+                    )));
+                }
+                return Err(MailError::Tls(e.to_string()));
+            }
+        };
+        let (_, session) = stream.get_ref();
+        if let (Some(version), Some(suite)) =
+            (session.protocol_version(), session.negotiated_cipher_suite())
+        {
+            let tls = format!("{:?} {:?}", version, suite.suite());
+            self.note(&format!("TLS established: {}", tls));
+            self.transcript.lock().unwrap().tls = Some(tls);
+        }
+        self.record_tls_success(to).await;
+
+        // Capabilities must be discarded after the upgrade
+        let mut reader = BufReader::new(stream);
+        self.write_line(reader.get_mut(), &ehlo).await?;
+        let capabilities = self.read_response(&mut reader, "250").await?;
+
+        if self.require_tls && !has_capability(&capabilities, "REQUIRETLS") {
+            self.quit(&mut reader).await;
+            return Err(MailError::RequireTls(format!(
+                "{} does not support REQUIRETLS",
+                self.host()
+            )));
+        }
+
+        self.transaction(&mut reader, from, to, data).await
+    }
+
+    /// MAIL FROM, RCPT TO, DATA and QUIT on an established session
+    async fn transaction<S>(
+        &self,
+        reader: &mut BufReader<S>,
+        from: &str,
+        to: &str,
+        data: &[u8],
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         // MAIL FROM
-        self.write_line(&mut writer, &format!("MAIL FROM:<{}>", from)).await?;
-        self.read_response(&mut reader, "250").await?;
+        let mail_from = if self.require_tls {
+            format!("MAIL FROM:<{}> REQUIRETLS", from)
+        } else {
+            format!("MAIL FROM:<{}>", from)
+        };
+        self.write_line(reader.get_mut(), &mail_from).await?;
+        self.read_response(reader, "250").await?;
 
         // RCPT TO
-        self.write_line(&mut writer, &format!("RCPT TO:<{}>", to)).await?;
-        self.read_response(&mut reader, "250").await?;
+        self.write_line(reader.get_mut(), &format!("RCPT TO:<{}>", to)).await?;
+        self.read_response(reader, "250").await?;
 
         // DATA
-        self.write_line(&mut writer, "DATA").await?;
-        self.read_response(&mut reader, "354").await?;
+        self.write_line(reader.get_mut(), "DATA").await?;
+        self.read_response(reader, "354").await?;
 
-        // Send email content
+        // Send email content, which stays out of the transcript
+        self.note(&format!("[message content redacted: {} bytes]", data.len()));
+        let writer = reader.get_mut();
         writer.write_all(data).await?;
 
         // End with CRLF.CRLF if not already present
@@ -98,17 +278,69 @@ impl SmtpClient {
             }
             writer.write_all(b".\r\n").await?;
         }
+        writer.flush().await?;
 
-        self.read_response(&mut reader, "250").await?;
+        self.read_response(reader, "250").await?;
 
         // QUIT
-        self.write_line(&mut writer, "QUIT").await?;
-        let _response = self.read_line(&mut reader).await?;
+        self.write_line(reader.get_mut(), "QUIT").await?;
+        let _response = self.read_line(reader).await?;
 
         info!("Mail sent successfully to {}", to);
         Ok(())
     }
 
+    /// End a session that won't carry the message
+    async fn quit<S>(&self, reader: &mut BufReader<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.write_line(reader.get_mut(), "QUIT").await.is_ok() {
+            let _ = self.read_line(reader).await;
+        }
+    }
+
+    /// Host part of the server address, used for certificate validation
+    fn host(&self) -> &str {
+        let host = match self.server_addr.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => &self.server_addr,
+        };
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+    }
+
+    async fn record_tls_success(&self, to: &str) {
+        let (Some(manager), Some(domain)) = (&self.tls_reporting, recipient_domain(to)) else {
+            return;
+        };
+        if let Err(e) = manager.record_success(domain, self.host()).await {
+            warn!("Failed to record TLS session for {}: {}", domain, e);
+        }
+    }
+
+    async fn record_tls_failure(
+        &self,
+        to: &str,
+        result_type: TlsResultType,
+        receiving_ip: Option<String>,
+        additional_information: Option<String>,
+    ) {
+        let (Some(manager), Some(domain)) = (&self.tls_reporting, recipient_domain(to)) else {
+            return;
+        };
+        let failure = TlsFailure {
+            result_type,
+            receiving_mx_hostname: self.host().to_string(),
+            receiving_ip,
+            additional_information,
+        };
+        if let Err(e) = manager.record_failure(domain, &failure).await {
+            warn!("Failed to record TLS failure for {}: {}", domain, e);
+        }
+    }
+
     /// Read a line from the stream
     async fn read_line<R>(&self, reader: &mut BufReader<R>) -> Result<String>
     where
@@ -116,6 +348,7 @@ impl SmtpClient {
     {
         let mut line = String::new();
         reader.read_line(&mut line).await?;
+        self.transcript.lock().unwrap().received(&line);
         Ok(line)
     }
 
@@ -140,6 +373,12 @@ impl SmtpClient {
 
         if !full_response.starts_with(expected) {
             error!("Unexpected response: {}", full_response);
+            if full_response.starts_with('5') {
+                return Err(MailError::SmtpRejected {
+                    host: self.host().to_string(),
+                    reply: full_response.trim_end().to_string(),
+                });
+            }
             return Err(MailError::SmtpProtocol(format!(
                 "Expected {}, got: {}",
                 expected, full_response
@@ -155,10 +394,17 @@ impl SmtpClient {
         W: tokio::io::AsyncWrite + Unpin,
     {
         debug!("> {}", line);
+        self.transcript.lock().unwrap().sent(line);
         writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        writer.flush().await?;
         Ok(())
     }
 
+    /// Add a connection event to the transcript
+    fn note(&self, text: &str) {
+        self.transcript.lock().unwrap().info(text);
+    }
+
     /// Get local hostname
     fn get_hostname(&self) -> String {
         gethostname::gethostname()
@@ -167,6 +413,39 @@ impl SmtpClient {
     }
 }
 
+/// Whether an EHLO response advertises an extension keyword
+fn has_capability(capabilities: &str, keyword: &str) -> bool {
+    capabilities.lines().any(|line| {
+        line.get(4..)
+            .and_then(|cap| cap.split_whitespace().next())
+            .is_some_and(|cap| cap.eq_ignore_ascii_case(keyword))
+    })
+}
+
+fn recipient_domain(to: &str) -> Option<&str> {
+    to.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+/// Map a failed handshake to its RFC 8460 result type
+fn classify_tls_error(error: &std::io::Error) -> TlsResultType {
+    let certificate_error = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .and_then(|e| match e {
+            rustls::Error::InvalidCertificate(cert) => Some(cert),
+            _ => None,
+        });
+
+    match certificate_error {
+        Some(CertificateError::Expired) => TlsResultType::CertificateExpired,
+        Some(CertificateError::NotValidForName) => TlsResultType::CertificateHostMismatch,
+        Some(CertificateError::UnknownIssuer | CertificateError::BadSignature) => {
+            TlsResultType::CertificateNotTrusted
+        }
+        _ => TlsResultType::ValidationFailure,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -175,5 +454,106 @@ mod tests {
     fn test_client_creation() {
         let client = SmtpClient::new("mail.example.com:25".to_string());
         assert_eq!(client.server_addr, "mail.example.com:25");
+        assert_eq!(client.host(), "mail.example.com");
+    }
+
+    #[test]
+    fn test_has_capability() {
+        let capabilities = "250-mx.example.net\r\n250-STARTTLS\r\n250-SIZE 1000\r\n250 8BITMIME\r\n";
+        assert!(has_capability(capabilities, "STARTTLS"));
+        assert!(has_capability(capabilities, "size"));
+        assert!(!has_capability(capabilities, "REQUIRETLS"));
+        assert!(!has_capability("250-mx.example.net\r\n250 8BITMIME\r\n", "STARTTLS"));
+    }
+
+    #[tokio::test]
+    async fn test_records_missing_starttls() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            reader.get_mut().write_all(b"220 mx ESMTP\r\n").await.unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = match line.trim_end() {
+                    l if l.starts_with("EHLO") => b"250-mx\r\n250 8BITMIME\r\n",
+                    "DATA" => b"354 go ahead\r\n",
+                    "." => b"250 queued\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    l if l.starts_with("MAIL") || l.starts_with("RCPT") => b"250 ok\r\n",
+                    _ => b"",
+                };
+                reader.get_mut().write_all(reply).await.unwrap();
+                line.clear();
+            }
+        });
+
+        let manager = Arc::new(TlsRptManager::connect("sqlite::memory:").await.unwrap());
+        let client = SmtpClient::new(addr.to_string()).with_tls_reporting(manager.clone());
+        client
+            .send_mail("a@example.com", "b@example.net", b"Subject: hi\r\n\r\nbody\r\n")
+            .await
+            .unwrap();
+
+        // The transcript shows the dialogue but not the message
+        let transcript = client.transcript();
+        assert_eq!(transcript.remote_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(transcript.tls, None);
+        let rendered = transcript.render();
+        assert!(rendered.contains("< 220 mx ESMTP\r\n"));
+        assert!(rendered.contains("> RCPT TO:<b@example.net>\r\n< 250 ok\r\n"));
+        assert!(rendered.contains("* [message content redacted: 21 bytes]\r\n"));
+        assert!(!rendered.contains("Subject: hi"));
+
+        let today = chrono::Utc::now().date_naive();
+        let result = manager.policy_result("example.net", today).await.unwrap();
+        assert_eq!(result.summary.total_failure_session_count, 1);
+        assert_eq!(
+            result.failure_details[0].result_type,
+            TlsResultType::StarttlsNotSupported
+        );
+        assert_eq!(result.failure_details[0].receiving_ip.as_deref(), Some("127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_require_tls_refuses_plaintext() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            reader.get_mut().write_all(b"220 mx ESMTP\r\n").await.unwrap();
+            let mut commands = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                let reply: &[u8] = match command.as_str() {
+                    l if l.starts_with("EHLO") => b"250-mx\r\n250 8BITMIME\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                reader.get_mut().write_all(reply).await.unwrap();
+                commands.push(command);
+                line.clear();
+            }
+            commands
+        });
+
+        let result = SmtpClient::new(addr.to_string())
+            .require_tls()
+            .send_mail("a@example.com", "b@example.net", b"Subject: hi\r\n\r\nbody\r\n")
+            .await;
+        assert!(matches!(result, Err(MailError::RequireTls(_))));
+
+        // Nothing beyond EHLO was sent in the clear
+        let commands = server.await.unwrap();
+        assert!(commands.iter().all(|c| !c.starts_with("MAIL")));
     }
 }
@@ -9,6 +9,35 @@
 
 use crate::error::{MailError, Result};
 
+/// ESMTP parameters of MAIL FROM (RFC 5321 section 4.1.2)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MailParameters {
+    /// REQUIRETLS (RFC 8689): the message must only be relayed over TLS
+    pub require_tls: bool,
+    /// SIZE (RFC 1870): declared message size in octets
+    pub size: Option<u64>,
+    /// Parameters without dedicated handling, keywords uppercased
+    pub other: Vec<(String, Option<String>)>,
+}
+
+impl MailParameters {
+    fn parse(params: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        for param in params.split_whitespace() {
+            let (keyword, value) = match param.split_once('=') {
+                Some((keyword, value)) => (keyword.to_uppercase(), Some(value.to_string())),
+                None => (param.to_uppercase(), None),
+            };
+            match (keyword.as_str(), value) {
+                ("REQUIRETLS", None) => parsed.require_tls = true,
+                ("SIZE", Some(value)) => parsed.size = Some(parse_size(&value)?),
+                (_, value) => parsed.other.push((keyword, value)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
 /// SMTP protocol commands as defined in RFC 5321
 ///
 /// # Examples
@@ -22,7 +51,8 @@ use crate::error::{MailError, Result};
 pub enum SmtpCommand {
     Helo(String),
     Ehlo(String),
-    MailFrom(String),
+    /// MAIL FROM with the reverse-path and its ESMTP parameters
+    MailFrom(String, MailParameters),
     RcptTo(String),
     Data,
     Rset,
@@ -61,9 +91,9 @@ impl SmtpCommand {
                 Ok(SmtpCommand::Ehlo(args.to_string()))
             }
             "MAIL" => {
-                // Parse MAIL FROM:<address>
-                let from = Self::parse_mail_from(args)?;
-                Ok(SmtpCommand::MailFrom(from))
+                // Parse MAIL FROM:<address> [parameters]
+                let (from, params) = Self::parse_mail_from(args)?;
+                Ok(SmtpCommand::MailFrom(from, params))
             }
             "RCPT" => {
                 // Parse RCPT TO:<address>
@@ -89,20 +119,24 @@ impl SmtpCommand {
         }
     }
 
-    fn parse_mail_from(args: &str) -> Result<String> {
-        // Expected format: FROM:<email@domain.com>
+    fn parse_mail_from(args: &str) -> Result<(String, MailParameters)> {
+        // Expected format: FROM:<email@domain.com> [KEYWORD[=VALUE] ...]
         if !args.to_uppercase().starts_with("FROM:") {
             return Err(MailError::SmtpProtocol("Invalid MAIL FROM syntax".to_string()));
         }
 
-        let email = args[5..].trim();
-        let email = if email.starts_with('<') && email.ends_with('>') {
-            &email[1..email.len() - 1]
-        } else {
-            email
+        let rest = args[5..].trim();
+        let (email, params) = match rest.strip_prefix('<') {
+            Some(inner) => match inner.find('>') {
+                Some(end) => (&inner[..end], &inner[end + 1..]),
+                None => {
+                    return Err(MailError::SmtpProtocol("Invalid MAIL FROM syntax".to_string()))
+                }
+            },
+            None => rest.split_once(' ').unwrap_or((rest, "")),
         };
 
-        Ok(email.to_string())
+        Ok((email.to_string(), MailParameters::parse(params)?))
     }
 
     fn parse_rcpt_to(args: &str) -> Result<String> {
@@ -122,6 +156,15 @@ impl SmtpCommand {
     }
 }
 
+/// SIZE value: 1 to 20 digits (RFC 1870 section 5)
+fn parse_size(value: &str) -> Result<u64> {
+    if value.is_empty() || value.len() > 20 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(MailError::SmtpProtocol(format!("Invalid SIZE parameter: {}", value)));
+    }
+    // 20 digits may overflow; such a size exceeds any limit anyway
+    Ok(value.parse().unwrap_or(u64::MAX))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,7 +184,30 @@ mod tests {
     #[test]
     fn test_parse_mail_from() {
         let cmd = SmtpCommand::parse("MAIL FROM:<sender@example.com>").unwrap();
-        assert_eq!(cmd, SmtpCommand::MailFrom("sender@example.com".to_string()));
+        assert_eq!(
+            cmd,
+            SmtpCommand::MailFrom("sender@example.com".to_string(), MailParameters::default())
+        );
+    }
+
+    #[test]
+    fn test_parse_mail_from_parameters() {
+        let cmd = SmtpCommand::parse("MAIL FROM:<sender@example.com> SIZE=1000 requiretls").unwrap();
+        let SmtpCommand::MailFrom(from, params) = cmd else {
+            panic!("expected MAIL FROM");
+        };
+        assert_eq!(from, "sender@example.com");
+        assert!(params.require_tls);
+        assert_eq!(params.size, Some(1000));
+        assert!(params.other.is_empty());
+
+        let cmd = SmtpCommand::parse("MAIL FROM:<> BODY=8BITMIME").unwrap();
+        assert!(matches!(cmd, SmtpCommand::MailFrom(from, params) if from.is_empty() && !params.require_tls));
+
+        let cmd = SmtpCommand::parse("MAIL FROM:<a@example.com> SIZE=99999999999999999999").unwrap();
+        assert!(matches!(cmd, SmtpCommand::MailFrom(_, params) if params.size == Some(u64::MAX)));
+        assert!(SmtpCommand::parse("MAIL FROM:<a@example.com> SIZE=10k").is_err());
+        assert!(SmtpCommand::parse("MAIL FROM:<a@example.com> SIZE=").is_err());
     }
 
     #[test]
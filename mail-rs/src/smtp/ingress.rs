@@ -0,0 +1,233 @@
+//! Ingress annotation of stored messages
+//!
+//! With MX, submission, LMTP and HTTP-inbound listeners, operators need to
+//! know how each message arrived. Messages stored in a local mailbox get an
+//! `X-Mail-Ingress:` header naming the listener, the protocol (`WITH` type
+//! from RFC 3848), the TLS parameters, the authenticated identity and the
+//! client address:
+//!
+//! ```text
+//! X-Mail-Ingress: listener=mx; protocol=ESMTPS; ip=192.0.2.1;
+//!     tls="TLSv1_3 TLS13_AES_256_GCM_SHA384"
+//! ```
+//!
+//! Copies of the header in an incoming message are removed before the
+//! server adds its own, so the topmost one is always authentic. The
+//! listener is also indexed for search.
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use super::trace::TraceInfo;
+use crate::mime::entity::{header_fields, strip_header_fields, MimeEntity};
+
+/// Header carrying the ingress descriptor
+pub const INGRESS_HEADER: &str = "X-Mail-Ingress";
+
+/// Listener a message was received on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IngressListener {
+    /// Inbound SMTP from other servers
+    Mx,
+    /// Message submission (RFC 6409)
+    Submission,
+    Lmtp,
+    /// Messages posted over HTTP
+    Http,
+}
+
+impl IngressListener {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IngressListener::Mx => "mx",
+            IngressListener::Submission => "submission",
+            IngressListener::Lmtp => "lmtp",
+            IngressListener::Http => "http",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "mx" => Some(IngressListener::Mx),
+            "submission" => Some(IngressListener::Submission),
+            "lmtp" => Some(IngressListener::Lmtp),
+            "http" => Some(IngressListener::Http),
+            _ => None,
+        }
+    }
+}
+
+/// How a message arrived
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ingress {
+    pub listener: IngressListener,
+    /// `WITH` protocol type, e.g. `ESMTPSA`
+    pub protocol: String,
+    /// Negotiated TLS version and cipher suite, if encrypted
+    pub tls: Option<String>,
+    /// Identity the client authenticated as
+    pub auth: Option<String>,
+    /// Address of the client
+    pub ip: Option<IpAddr>,
+}
+
+impl Ingress {
+    /// Descriptor of a message received on `listener` in the hop `trace`
+    pub fn from_trace(listener: IngressListener, trace: &TraceInfo) -> Self {
+        Self {
+            listener,
+            protocol: trace.with_type(),
+            tls: trace.tls.clone(),
+            auth: trace.authenticated_user.clone(),
+            ip: trace.client_ip,
+        }
+    }
+
+    /// `X-Mail-Ingress:` header line, with the trailing CRLF
+    pub fn header(&self) -> String {
+        let mut header = format!(
+            "{}: listener={}; protocol={}",
+            INGRESS_HEADER,
+            self.listener.as_str(),
+            quote(&self.protocol)
+        );
+        if let Some(ip) = self.ip {
+            header.push_str(&format!("; ip={}", ip));
+        }
+        if let Some(auth) = &self.auth {
+            header.push_str(&format!(";\r\n\tauth={}", quote(auth)));
+        }
+        if let Some(tls) = &self.tls {
+            header.push_str(&format!(";\r\n\ttls={}", quote(tls)));
+        }
+        header.push_str("\r\n");
+        header
+    }
+
+    /// Descriptor from the value of an `X-Mail-Ingress:` header
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut listener = None;
+        let mut ingress = Self {
+            listener: IngressListener::Mx,
+            protocol: String::new(),
+            tls: None,
+            auth: None,
+            ip: None,
+        };
+        for parameter in value.split(';') {
+            let Some((name, value)) = parameter.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match name.trim().to_lowercase().as_str() {
+                "listener" => listener = IngressListener::parse(&value),
+                "protocol" => ingress.protocol = value,
+                "tls" => ingress.tls = Some(value),
+                "auth" => ingress.auth = Some(value),
+                "ip" => ingress.ip = value.parse().ok(),
+                _ => {}
+            }
+        }
+        ingress.listener = listener?;
+        Some(ingress)
+    }
+
+    /// Descriptor of a stored message, from its topmost ingress header
+    pub fn from_message(message: &[u8]) -> Option<Self> {
+        let entity = MimeEntity::parse(message);
+        let name = INGRESS_HEADER.to_lowercase();
+        let (_, raw) = header_fields(entity.header)
+            .into_iter()
+            .find(|(field, _)| *field == name)?;
+        let raw = String::from_utf8_lossy(raw);
+        let (_, value) = raw.split_once(':')?;
+        let value: Vec<&str> = value.split(['\r', '\n']).map(str::trim).collect();
+        Self::parse(&value.join(" "))
+    }
+}
+
+/// `message` without the ingress headers it came with; unchanged when it
+/// has none
+pub fn strip_headers(message: &[u8]) -> Vec<u8> {
+    strip_header_fields(message, &[INGRESS_HEADER])
+}
+
+/// Parameter value, quoted when it has spaces; characters that would end
+/// the value are removed
+fn quote(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '"' | '\\' | ';'))
+        .collect();
+    match value.contains(' ') || value.is_empty() {
+        true => format!("\"{}\"", value),
+        false => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::trace::Protocol;
+
+    fn submission() -> Ingress {
+        let mut trace = TraceInfo::new("mx.example.com", Protocol::Esmtp);
+        trace.client_ip = Some("2001:db8::1".parse().unwrap());
+        trace.tls = Some("TLSv1_3 TLS13_AES_256_GCM_SHA384".to_string());
+        trace.authenticated_user = Some("alice@example.com".to_string());
+        Ingress::from_trace(IngressListener::Submission, &trace)
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let ingress = submission();
+        assert_eq!(ingress.protocol, "ESMTPSA");
+        assert_eq!(
+            ingress.header(),
+            "X-Mail-Ingress: listener=submission; protocol=ESMTPSA; ip=2001:db8::1;\r\n\
+             \tauth=alice@example.com;\r\n\
+             \ttls=\"TLSv1_3 TLS13_AES_256_GCM_SHA384\"\r\n"
+        );
+
+        let message = format!("{}Subject: Hi\r\n\r\nBody\r\n", ingress.header());
+        assert_eq!(Ingress::from_message(message.as_bytes()), Some(ingress));
+
+        let plain = Ingress::from_trace(
+            IngressListener::Mx,
+            &TraceInfo::new("mx.example.com", Protocol::Smtp),
+        );
+        assert_eq!(
+            plain.header(),
+            "X-Mail-Ingress: listener=mx; protocol=SMTP\r\n"
+        );
+        assert_eq!(Ingress::parse(plain.header().split_once(':').unwrap().1), Some(plain));
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_listener() {
+        assert_eq!(Ingress::parse("listener=carrier-pigeon; protocol=SMTP"), None);
+        assert_eq!(Ingress::parse("protocol=SMTP"), None);
+        assert!(Ingress::from_message(b"Subject: Hi\r\n\r\nBody").is_none());
+    }
+
+    #[test]
+    fn test_values_cannot_inject_parameters() {
+        let mut ingress = submission();
+        ingress.auth = Some("eve\"; listener=lmtp; x=\"@example.com".to_string());
+        let parsed = Ingress::parse(ingress.header().split_once(':').unwrap().1).unwrap();
+        assert_eq!(parsed.listener, IngressListener::Submission);
+        assert_eq!(parsed.auth.as_deref(), Some("eve listener=lmtp x=@example.com"));
+    }
+
+    #[test]
+    fn test_strip_headers() {
+        let forged = b"X-Mail-Ingress: listener=submission;\r\n\tauth=ceo@example.com\r\n\
+                       Subject: Hi\r\n\
+                       x-mail-ingress: listener=lmtp\r\n\r\nBody\r\n";
+        assert_eq!(strip_headers(forged), b"Subject: Hi\r\n\r\nBody\r\n");
+
+        let clean = b"Subject: Hi\n\nBody\n";
+        assert_eq!(strip_headers(clean), clean);
+    }
+}
@@ -0,0 +1,321 @@
+//! Mail loop detection
+//!
+//! A misconfigured forward chain can pass a message between servers
+//! forever. A message is treated as looping when it already carries more
+//! `Received:` headers than the configured hop limit, or when a
+//! `Delivered-To:` header shows it was already delivered or forwarded here
+//! for one of its recipients. Looping messages are refused at the end of
+//! DATA with `554 5.4.6`.
+//!
+//! Copies stored in single-instance storage carry no per-recipient
+//! `Delivered-To:` header, which would make every copy different. Those
+//! deliveries are remembered in a [`DeliveryLog`] instead, by the id of the
+//! `Received:` header this server added, so the same message coming back
+//! for the same recipient is still refused.
+
+use crate::authentication::TtlCache;
+use std::fmt;
+use std::time::Duration;
+
+/// Hop limit used when none is configured
+pub const DEFAULT_MAX_HOPS: usize = 50;
+
+/// How long a delivery without `Delivered-To:` is remembered
+const DELIVERY_LOG_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Deliveries remembered at most
+const DELIVERY_LOG_CAPACITY: usize = 100_000;
+
+/// Why a message is considered looping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailLoop {
+    /// More `Received:` headers than allowed
+    TooManyHops { hops: usize, route: Vec<String> },
+    /// A `Delivered-To:` header names one of the recipients
+    AlreadyDelivered {
+        recipient: String,
+        route: Vec<String>,
+    },
+}
+
+impl MailLoop {
+    /// Hosts the message passed through, oldest first
+    pub fn route(&self) -> &[String] {
+        match self {
+            MailLoop::TooManyHops { route, .. } | MailLoop::AlreadyDelivered { route, .. } => route,
+        }
+    }
+
+    /// SMTP reply refusing the message
+    pub fn reply(&self) -> String {
+        match self {
+            MailLoop::TooManyHops { hops, .. } => {
+                format!("554 5.4.6 Mail loop detected: too many hops ({})\r\n", hops)
+            }
+            MailLoop::AlreadyDelivered { .. } => {
+                "554 5.4.6 Mail loop detected: message already delivered to recipient\r\n"
+                    .to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for MailLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailLoop::TooManyHops { hops, .. } => write!(f, "{} hops", hops)?,
+            MailLoop::AlreadyDelivered { recipient, .. } => {
+                write!(f, "already delivered to {}", recipient)?
+            }
+        }
+        if !self.route().is_empty() {
+            write!(f, ", route: {}", self.route().join(" -> "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Check a received message for a loop
+///
+/// Only the header section is inspected; the `Received:` header of the
+/// current hop must not have been added yet.
+pub fn detect(message: &[u8], recipients: &[String], max_hops: usize) -> Option<MailLoop> {
+    let fields = header_fields(message);
+    let route = || route(&fields);
+
+    let hops = fields
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Received"))
+        .count();
+    if hops > max_hops {
+        return Some(MailLoop::TooManyHops {
+            hops,
+            route: route(),
+        });
+    }
+
+    let delivered = fields
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Delivered-To"))
+        .map(|(_, value)| value.trim().trim_matches(|c| c == '<' || c == '>'));
+    for address in delivered {
+        if let Some(recipient) = recipients
+            .iter()
+            .find(|recipient| recipient.eq_ignore_ascii_case(address))
+        {
+            return Some(MailLoop::AlreadyDelivered {
+                recipient: recipient.clone(),
+                route: route(),
+            });
+        }
+    }
+
+    None
+}
+
+/// Deliveries stored without a `Delivered-To:` header
+///
+/// Keyed by the id of this server's `Received:` header and the recipient;
+/// kept in memory, so after a restart only the hop limit catches loops of
+/// earlier deliveries.
+pub struct DeliveryLog {
+    deliveries: TtlCache<(String, String), ()>,
+}
+
+impl Default for DeliveryLog {
+    fn default() -> Self {
+        Self {
+            deliveries: TtlCache::new(DELIVERY_LOG_TTL, DELIVERY_LOG_CAPACITY),
+        }
+    }
+}
+
+impl DeliveryLog {
+    /// Remember that the message received as `id` was stored for
+    /// `recipient`
+    pub fn record(&self, id: &str, recipient: &str) {
+        self.deliveries
+            .insert((id.to_string(), recipient.to_lowercase()), ());
+    }
+
+    /// Check a received message for one already stored here for one of
+    /// its recipients
+    ///
+    /// `hostname` is the name this server puts in its `Received:` headers.
+    pub fn detect(&self, message: &[u8], recipients: &[String], hostname: &str) -> Option<MailLoop> {
+        let fields = header_fields(message);
+        let ids: Vec<&str> = fields
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Received"))
+            .filter_map(|(_, value)| {
+                let words: Vec<&str> = value.split_whitespace().collect();
+                let by = words.iter().position(|word| word.eq_ignore_ascii_case("by"))?;
+                if !words.get(by + 1)?.eq_ignore_ascii_case(hostname) {
+                    return None;
+                }
+                let id = words.iter().position(|word| word.eq_ignore_ascii_case("id"))?;
+                words.get(id + 1).map(|id| id.trim_end_matches(';'))
+            })
+            .collect();
+
+        for id in ids {
+            if let Some(recipient) = recipients.iter().find(|recipient| {
+                self.deliveries
+                    .get(&(id.to_string(), recipient.to_lowercase()))
+                    .is_some()
+            }) {
+                return Some(MailLoop::AlreadyDelivered {
+                    recipient: recipient.clone(),
+                    route: route(&fields),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// `Delivered-To:` header line added when delivering or forwarding for
+/// `recipient`
+pub fn delivered_to_header(recipient: &str) -> String {
+    let recipient: String = recipient
+        .chars()
+        .filter(|c| !c.is_control() && *c != '<' && *c != '>')
+        .collect();
+    format!("Delivered-To: {}\r\n", recipient)
+}
+
+/// Unfolded header fields of a message, in order
+fn header_fields(message: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(message);
+    let mut fields: Vec<(String, String)> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+/// Receiving hosts of the `Received:` headers, oldest first
+fn route(fields: &[(String, String)]) -> Vec<String> {
+    let mut route: Vec<String> = fields
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Received"))
+        .filter_map(|(_, value)| {
+            let mut words = value.split_whitespace();
+            words.find(|word| word.eq_ignore_ascii_case("by"))?;
+            words
+                .next()
+                .map(|host| host.trim_end_matches(';').to_string())
+        })
+        .collect();
+    route.reverse();
+    route
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(received: usize, extra: &str) -> Vec<u8> {
+        let mut message = String::new();
+        for hop in (0..received).rev() {
+            message.push_str(&format!(
+                "Received: from a{} (a{} [192.0.2.1])\r\n\tby mx{}.example.com with ESMTP;\r\n\tMon, 1 Jan 2024 00:00:00 +0000\r\n",
+                hop, hop, hop
+            ));
+        }
+        message.push_str(extra);
+        message.push_str("Subject: test\r\n\r\nReceived: by body.example.com\r\n");
+        message.into_bytes()
+    }
+
+    #[test]
+    fn test_hop_limit() {
+        let recipients = vec!["bob@example.com".to_string()];
+        assert_eq!(detect(&message(3, ""), &recipients, 3), None);
+
+        let mail_loop = detect(&message(4, ""), &recipients, 3).unwrap();
+        assert!(matches!(mail_loop, MailLoop::TooManyHops { hops: 4, .. }));
+        assert_eq!(
+            mail_loop.route(),
+            [
+                "mx0.example.com",
+                "mx1.example.com",
+                "mx2.example.com",
+                "mx3.example.com"
+            ]
+        );
+        assert!(mail_loop.reply().starts_with("554 5.4.6 "));
+        assert!(mail_loop.to_string().ends_with(
+            "route: mx0.example.com -> mx1.example.com -> mx2.example.com -> mx3.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_delivered_to() {
+        let recipients = vec![
+            "alice@example.com".to_string(),
+            "Bob@Example.com".to_string(),
+        ];
+        let header = delivered_to_header("bob@example.com");
+        assert_eq!(header, "Delivered-To: bob@example.com\r\n");
+
+        let mail_loop = detect(&message(2, &header), &recipients, DEFAULT_MAX_HOPS).unwrap();
+        assert_eq!(
+            mail_loop,
+            MailLoop::AlreadyDelivered {
+                recipient: "Bob@Example.com".to_string(),
+                route: vec!["mx0.example.com".to_string(), "mx1.example.com".to_string()],
+            }
+        );
+
+        let other = delivered_to_header("carol@example.com");
+        assert_eq!(
+            detect(&message(2, &other), &recipients, DEFAULT_MAX_HOPS),
+            None
+        );
+    }
+
+    #[test]
+    fn test_delivery_log() {
+        let log = DeliveryLog::default();
+        log.record("A1B2C3", "Bob@Example.com");
+        let recipients = vec!["bob@example.com".to_string()];
+        let received = |by: &str, id: &str| {
+            format!(
+                "Received: from relay.example.org\r\n\tby {} (mail-rs) with ESMTP id {};\r\n\tMon, 1 Jan 2024 00:00:00 +0000\r\n",
+                by, id
+            )
+        };
+
+        let back = message(1, &received("mx.example.com", "A1B2C3"));
+        let mail_loop = log.detect(&back, &recipients, "mx.example.com").unwrap();
+        assert_eq!(
+            mail_loop,
+            MailLoop::AlreadyDelivered {
+                recipient: "bob@example.com".to_string(),
+                route: vec!["mx.example.com".to_string(), "mx0.example.com".to_string()],
+            }
+        );
+
+        // Another delivery, another server or another recipient
+        let other = message(1, &received("mx.example.com", "D4E5F6"));
+        assert_eq!(log.detect(&other, &recipients, "mx.example.com"), None);
+        assert_eq!(log.detect(&back, &recipients, "mx.example.org"), None);
+        let carol = vec!["carol@example.com".to_string()];
+        assert_eq!(log.detect(&back, &carol, "mx.example.com"), None);
+    }
+}
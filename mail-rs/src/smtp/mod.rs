@@ -6,15 +6,48 @@
 //! - [`session`]: SMTP session state machine
 //! - [`commands`]: SMTP command parsing and handling
 //! - [`queue`]: Message queue for outgoing emails
+//! - [`capture`]: Capture mode keeping outbound mail from staging setups
+//! - [`routing`]: Operator-defined routing rules for inbound mail
+//! - [`submission`]: Authenticated message submission listener (port 587)
+//! - [`srs`]: Sender Rewriting Scheme for forwarded mail
+//! - [`bounce`]: Delivery status notifications for undeliverable mail
+//! - [`requiretls`]: REQUIRETLS and `TLS-Required` handling (RFC 8689)
+//! - [`transcript`]: Transcripts of outbound sessions for diagnostics
+//! - [`trace`]: `Received:` and `Return-Path:` trace headers
+//! - [`ingress`]: `X-Mail-Ingress:` header recording how stored mail arrived
+//! - [`loop_detection`]: Hop counting and `Delivered-To:` loop checks
+//! - [`budget`]: Per-user time and IO budgets in the delivery pipeline
 
+pub mod bounce;
+pub mod budget;
+pub mod capture;
 pub mod client;
 pub mod commands;
+pub mod ingress;
+pub mod loop_detection;
+pub mod postdelivery;
 pub mod queue;
+pub mod requiretls;
+pub mod routing;
 pub mod server;
 pub mod session;
+pub mod srs;
+pub mod submission;
+pub mod trace;
+pub mod transcript;
 
+pub use bounce::{BounceGenerator, DeliveryFailure};
+pub use capture::CapturePolicy;
 pub use client::SmtpClient;
-pub use commands::SmtpCommand;
+pub use commands::{MailParameters, SmtpCommand};
+pub use ingress::{Ingress, IngressListener};
+pub use postdelivery::{PostDeliveryJob, PostDeliveryQueue};
 pub use queue::{QueueStatus, QueuedEmail, SmtpQueue};
+pub use requiretls::TlsRequirement;
+pub use routing::{RouteAction, RoutingRule, RoutingTable};
 pub use server::SmtpServer;
 pub use session::SmtpSession;
+pub use srs::Srs;
+pub use submission::SubmissionServer;
+pub use trace::TraceInfo;
+pub use transcript::Transcript;
@@ -0,0 +1,527 @@
+//! Processing of delivered mail in worker tasks
+//!
+//! With `[post_delivery]` enabled, an SMTP session only writes the raw
+//! message to the mailbox before acknowledging the transaction. Everything
+//! that needs the parsed message runs afterwards in worker tasks, one stage
+//! after the other:
+//!
+//! - `parse`: MIME parsing, on the blocking thread pool
+//! - `attachments`: the attachment policy; messages carrying an attachment
+//!   with a blocked file extension are moved to Junk
+//! - `summary`: the AI summary requested from the ai-runtime
+//!
+//! Messages are routed by size: those above `large_message_bytes` go to a
+//! separate lane with its own workers, so a burst of huge messages queues
+//! behind itself instead of delaying ordinary mail. Both lanes are bounded;
+//! a full lane holds up new deliveries rather than growing without limit.
+//! The `summary` stage also takes a slot of its pool in
+//! [`crate::workers`] when the queue is given the server's pools. The time
+//! every stage takes is measured for the admin API.
+
+use crate::chaos::{self, Fault};
+use crate::config::PostDeliveryConfig;
+use crate::imap::special_use::SpecialUse;
+use crate::mime::{decode_header, MimeParser, ParsedEmail};
+use crate::storage::MaildirStorage;
+use crate::workers::{Subsystem, WorkerPools};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, info, warn};
+
+/// Stages in the order they run
+pub const STAGES: [&str; 3] = ["parse", "attachments", "summary"];
+
+/// Longest body text sent for a summary
+const SUMMARY_BODY_CHARS: usize = 1000;
+
+/// A delivered message waiting to be processed
+#[derive(Debug, Clone)]
+pub struct PostDeliveryJob {
+    /// Mailbox the message was stored in
+    pub user: String,
+    /// Folder of the mailbox, `None` for INBOX
+    pub folder: Option<String>,
+    /// Maildir file name the message was stored as, in `new/`
+    pub email_id: String,
+    /// Envelope sender
+    pub from: String,
+    pub message: Vec<u8>,
+}
+
+/// Worker lane of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lane {
+    Small,
+    /// Messages above `large_message_bytes`
+    Large,
+}
+
+/// Load of one lane
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LaneStats {
+    pub lane: Lane,
+    pub workers: usize,
+    /// Messages waiting for a worker
+    pub queued: usize,
+    /// Messages being processed
+    pub running: usize,
+    pub processed: u64,
+}
+
+/// Latency of one stage since the server started
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StageStats {
+    pub stage: String,
+    pub runs: u64,
+    pub failures: u64,
+    pub total_ms: u64,
+    pub avg_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Lanes and stages of the post-delivery queue
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PostDeliveryStats {
+    pub lanes: Vec<LaneStats>,
+    pub stages: Vec<StageStats>,
+}
+
+/// One lane: its queue and the permits of its workers
+struct LaneHandle {
+    lane: Lane,
+    sender: mpsc::Sender<PostDeliveryJob>,
+    workers: Arc<Semaphore>,
+    size: usize,
+}
+
+/// Queue of delivered messages, shared by every SMTP session
+pub struct PostDeliveryQueue {
+    small: LaneHandle,
+    large: LaneHandle,
+    large_message_bytes: usize,
+    processor: Arc<Processor>,
+}
+
+impl PostDeliveryQueue {
+    /// Start the workers of both lanes
+    pub fn start(config: &PostDeliveryConfig, storage: Arc<MaildirStorage>) -> Arc<Self> {
+        let summary_url = config.summaries.then(|| {
+            std::env::var("AI_RUNTIME_URL").unwrap_or_else(|_| "http://127.0.0.1:8888".to_string())
+        });
+        let processor = Arc::new(Processor {
+            storage,
+            blocked_extensions: config
+                .blocked_attachment_extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                .collect(),
+            workers: OnceLock::new(),
+            summary_url,
+            stages: Mutex::new(HashMap::new()),
+            processed: Mutex::new(HashMap::new()),
+        });
+        let capacity = config.queue_size.max(1);
+
+        Arc::new(Self {
+            small: spawn_lane(Lane::Small, config.workers, capacity, processor.clone()),
+            large: spawn_lane(
+                Lane::Large,
+                config.large_workers,
+                capacity,
+                processor.clone(),
+            ),
+            large_message_bytes: config.large_message_bytes,
+            processor,
+        })
+    }
+
+    /// Request summaries in the pools of `workers`; only the first pools
+    /// given are used
+    pub fn run_in(&self, workers: Arc<WorkerPools>) {
+        if self.processor.workers.set(workers).is_err() {
+            debug!("Post-delivery queue already runs in worker pools");
+        }
+    }
+
+    /// Lane of a message of `size` bytes
+    pub fn lane_for(&self, size: usize) -> Lane {
+        if size > self.large_message_bytes {
+            Lane::Large
+        } else {
+            Lane::Small
+        }
+    }
+
+    /// Queue a delivered message, waiting while its lane is full
+    pub async fn submit(&self, job: PostDeliveryJob) {
+        let lane = match self.lane_for(job.message.len()) {
+            Lane::Small => &self.small,
+            Lane::Large => &self.large,
+        };
+        if let Err(e) = lane.sender.send(job).await {
+            warn!(
+                "Post-delivery workers stopped, not processing {} for {}",
+                e.0.email_id, e.0.user
+            );
+        }
+    }
+
+    /// Load of the lanes and latency of the stages
+    pub fn stats(&self) -> PostDeliveryStats {
+        let processed = self
+            .processor
+            .processed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let lanes = [&self.small, &self.large]
+            .into_iter()
+            .map(|lane| LaneStats {
+                lane: lane.lane,
+                workers: lane.size,
+                queued: lane.sender.max_capacity() - lane.sender.capacity(),
+                running: lane.size - lane.workers.available_permits(),
+                processed: processed.get(&lane.lane).copied().unwrap_or(0),
+            })
+            .collect();
+
+        let stages = self
+            .processor
+            .stages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let stages = STAGES
+            .iter()
+            .map(|stage| {
+                let mut stats = stages.get(stage).cloned().unwrap_or_default();
+                stats.stage = stage.to_string();
+                stats.avg_ms = stats.total_ms.checked_div(stats.runs).unwrap_or(0);
+                stats
+            })
+            .collect();
+
+        PostDeliveryStats { lanes, stages }
+    }
+}
+
+/// Run up to `workers` jobs of a lane at the same time
+fn spawn_lane(
+    lane: Lane,
+    workers: usize,
+    capacity: usize,
+    processor: Arc<Processor>,
+) -> LaneHandle {
+    let size = workers.max(1);
+    let (sender, mut receiver) = mpsc::channel::<PostDeliveryJob>(capacity);
+    let permits = Arc::new(Semaphore::new(size));
+
+    let semaphore = permits.clone();
+    tokio::spawn(async move {
+        while let Some(job) = receiver.recv().await {
+            let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                break;
+            };
+            let processor = processor.clone();
+            tokio::spawn(async move {
+                processor.process(job).await;
+                processor.count_processed(lane);
+                drop(permit);
+            });
+        }
+    });
+
+    LaneHandle {
+        lane,
+        sender,
+        workers: permits,
+        size,
+    }
+}
+
+/// The stages and their measurements
+struct Processor {
+    storage: Arc<MaildirStorage>,
+    /// Lowercase extensions without the dot
+    blocked_extensions: Vec<String>,
+    workers: OnceLock<Arc<WorkerPools>>,
+    summary_url: Option<String>,
+    stages: Mutex<HashMap<&'static str, StageStats>>,
+    processed: Mutex<HashMap<Lane, u64>>,
+}
+
+impl Processor {
+    async fn process(&self, job: PostDeliveryJob) {
+        let PostDeliveryJob {
+            user,
+            folder,
+            email_id,
+            from,
+            message,
+        } = job;
+
+        let started = Instant::now();
+        let parsed = tokio::task::spawn_blocking(move || MimeParser::parse(&message))
+            .await
+            .map_err(|e| anyhow!("Parser task failed: {}", e))
+            .and_then(|parsed| parsed);
+        let parsed = match self.record("parse", started, parsed) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+
+        if !self.blocked_extensions.is_empty() {
+            let started = Instant::now();
+            let moved = self.apply_attachment_policy(&user, folder.as_deref(), &email_id, &parsed);
+            self.record("attachments", started, moved.await);
+        }
+
+        if let Some(url) = &self.summary_url {
+            let started = Instant::now();
+            let subject = match header(&parsed, "subject") {
+                subject if subject.is_empty() => "(no subject)".to_string(),
+                subject => subject,
+            };
+            let body = summary_body(parsed.text_body.as_deref().unwrap_or_default());
+            let request = request_summary(url, &user, &email_id, &from, &subject, &body);
+            let requested = self.limited(Subsystem::Ai, request).await;
+            self.record("summary", started, requested);
+        }
+    }
+
+    /// Run `task` in the pool of `subsystem`, if the queue has pools
+    async fn limited<F: std::future::Future>(&self, subsystem: Subsystem, task: F) -> F::Output {
+        match self.workers.get() {
+            Some(workers) => workers.pool(subsystem).run(task).await,
+            None => task.await,
+        }
+    }
+
+    /// Move the message to Junk if it carries a blocked attachment; whether
+    /// it was moved
+    async fn apply_attachment_policy(
+        &self,
+        user: &str,
+        folder: Option<&str>,
+        email_id: &str,
+        parsed: &ParsedEmail,
+    ) -> Result<bool> {
+        let junk = SpecialUse::Junk.folder();
+        let Some(blocked) = parsed
+            .attachments
+            .iter()
+            .filter_map(|attachment| attachment.filename.as_deref())
+            .find(|name| self.is_blocked(name))
+        else {
+            return Ok(false);
+        };
+        if folder == Some(junk) {
+            return Ok(false);
+        }
+
+        info!(
+            "Moving {} of {} to {}: blocked attachment {}",
+            email_id, user, junk, blocked
+        );
+        Ok(self
+            .storage
+            .move_message(user, folder, email_id, junk)
+            .await?)
+    }
+
+    fn is_blocked(&self, filename: &str) -> bool {
+        filename
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.trim().to_lowercase())
+            .is_some_and(|extension| self.blocked_extensions.contains(&extension))
+    }
+
+    /// Measure a finished stage; the stage's value if it succeeded
+    fn record<T>(&self, stage: &'static str, started: Instant, result: Result<T>) -> Option<T> {
+        let elapsed = started.elapsed();
+        let mut stages = self.stages.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = stages.entry(stage).or_default();
+        stats.runs += 1;
+        stats.total_ms += elapsed.as_millis() as u64;
+        stats.max_ms = stats.max_ms.max(elapsed.as_millis() as u64);
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                stats.failures += 1;
+                warn!("Post-delivery {} stage failed: {}", stage, e);
+                None
+            }
+        }
+    }
+
+    fn count_processed(&self, lane: Lane) {
+        let mut processed = self.processed.lock().unwrap_or_else(|e| e.into_inner());
+        *processed.entry(lane).or_default() += 1;
+    }
+}
+
+/// Decoded value of a header of a parsed message, empty if missing
+fn header(parsed: &ParsedEmail, name: &str) -> String {
+    parsed
+        .headers
+        .get(name)
+        .map(|value| decode_header(value))
+        .unwrap_or_default()
+}
+
+/// Start of a body, as much as a summary is requested for
+pub(crate) fn summary_body(body: &str) -> String {
+    if body.chars().count() <= SUMMARY_BODY_CHARS {
+        return body.to_string();
+    }
+    let mut body: String = body.chars().take(SUMMARY_BODY_CHARS).collect();
+    body.push_str("...");
+    body
+}
+
+/// Ask the ai-runtime at `url` to summarize a delivered message
+pub(crate) async fn request_summary(
+    url: &str,
+    user_email: &str,
+    email_id: &str,
+    from: &str,
+    subject: &str,
+    body: &str,
+) -> Result<()> {
+    if chaos::inject(Fault::AiRuntimeError).await {
+        return Err(anyhow!("500 Internal Server Error (injected)"));
+    }
+
+    let payload = serde_json::json!({
+        "user_email": user_email,
+        "email_id": email_id,
+        "from": from,
+        "subject": subject,
+        "body": body
+    });
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/generate-summary", url))
+        .json(&payload)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("ai-runtime answered {}", response.status()));
+    }
+    info!("✅ Summary generation triggered for {}", user_email);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(blocked: &[&str]) -> PostDeliveryConfig {
+        PostDeliveryConfig {
+            enabled: true,
+            workers: 2,
+            large_workers: 1,
+            large_message_bytes: 200,
+            queue_size: 10,
+            summaries: false,
+            blocked_attachment_extensions: blocked.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    async fn wait_for(queue: &PostDeliveryQueue, processed: u64) -> PostDeliveryStats {
+        for _ in 0..200 {
+            let stats = queue.stats();
+            if stats.lanes.iter().map(|lane| lane.processed).sum::<u64>() >= processed {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Messages were not processed: {:?}", queue.stats());
+    }
+
+    #[tokio::test]
+    async fn test_lanes_and_attachment_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(MaildirStorage::new(dir.path().display().to_string()));
+        let queue = PostDeliveryQueue::start(&config(&[".EXE"]), storage.clone());
+        let user = "bob@example.com";
+
+        let small = b"Subject: Hi\r\n\r\nHello\r\n".to_vec();
+        let infected = concat!(
+            "Subject: Invoice\r\n",
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "See attached\r\n",
+            "--b\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "Content-Disposition: attachment; filename=\"invoice.pdf.exe\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "TVqQAAMAAAAEAAAA//8AALgAAAAAAAAAQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\r\n",
+            "--b--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+        assert_eq!(queue.lane_for(small.len()), Lane::Small);
+        assert_eq!(queue.lane_for(infected.len()), Lane::Large);
+
+        for message in [small, infected] {
+            let email_id = storage.store(user, &message).await.unwrap();
+            queue
+                .submit(PostDeliveryJob {
+                    user: user.to_string(),
+                    folder: None,
+                    email_id,
+                    from: "sender@example.org".to_string(),
+                    message,
+                })
+                .await;
+        }
+        let stats = wait_for(&queue, 2).await;
+
+        let mailbox = dir.path().join(user);
+        assert_eq!(std::fs::read_dir(mailbox.join("new")).unwrap().count(), 1);
+        assert_eq!(
+            std::fs::read_dir(mailbox.join(".Junk/new"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        let processed: Vec<_> = stats
+            .lanes
+            .iter()
+            .map(|lane| (lane.lane, lane.processed))
+            .collect();
+        assert_eq!(processed, [(Lane::Small, 1), (Lane::Large, 1)]);
+        let runs: Vec<_> = stats
+            .stages
+            .iter()
+            .map(|stage| (stage.stage.as_str(), stage.runs, stage.failures))
+            .collect();
+        assert_eq!(
+            runs,
+            [
+                ("parse", 2, 0),
+                ("attachments", 2, 0),
+                ("summary", 0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summary_body() {
+        assert_eq!(summary_body("short"), "short");
+        let long = "é".repeat(SUMMARY_BODY_CHARS + 1);
+        let body = summary_body(&long);
+        assert_eq!(body.chars().count(), SUMMARY_BODY_CHARS + 3);
+        assert!(body.ends_with("é..."));
+    }
+}
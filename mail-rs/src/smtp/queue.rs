@@ -7,6 +7,18 @@
 //! - Retry with exponential backoff
 //! - Maximum retry attempts
 //! - Bounce handling
+//! - Transcript of the last failed attempt kept with the entry
+//! - Poison messages quarantined after repeated processing failures
+//! - Capture mode keeping mail to non-allowlisted domains (see
+//!   [`crate::smtp::capture`])
+//! - Parallel deliveries in the delivery pool of [`crate::workers`]
+//! - DKIM signing with the keys of [`crate::dkim_keys`]
+//!
+//! A delivery that fails is retried with backoff and eventually bounced. A
+//! message that breaks the queue itself, by failing to load or by making
+//! processing error out or panic, is set aside after [`POISON_THRESHOLD`]
+//! attempts with the captured error and admins are alerted. Other messages
+//! keep flowing in the meantime.
 //!
 //! # Architecture
 //! ```text
@@ -16,12 +28,24 @@
 //!                  └──── Failed ←─────────────────────── X Failed
 //! ```
 
+use crate::config::Config;
+use crate::dkim_keys::DkimKeyManager;
 use crate::error::{MailError, Result};
-use crate::smtp::SmtpClient;
+use crate::notifications::{EventKind, NotificationRouter};
+use crate::smtp::server::build_dkim_key_manager;
+use crate::smtp::{
+    BounceGenerator, CapturePolicy, DeliveryFailure, SmtpClient, TlsRequirement, Transcript,
+};
+use crate::tlsrpt::TlsRptManager;
 use crate::utils::dns::lookup_mx;
+use crate::workers::WorkerPool;
 use chrono::{DateTime, Duration, Utc};
+use futures::future::join_all;
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
@@ -33,15 +57,46 @@ const MAX_RETRY_ATTEMPTS: i32 = 5;
 /// Base delay for retry (2 minutes)
 const RETRY_BASE_DELAY_SECS: i64 = 120;
 
+/// Entries taken per pass, more when the delivery pool runs more at once
+const BATCH_SIZE: i64 = 10;
+
+/// Processing failures after which a message is quarantined
+pub const POISON_THRESHOLD: i32 = 3;
+
+/// Columns of a queue entry, in [`QueueRow`] order
+const COLUMNS: &str = "id, from_addr, to_addr, data, status, retry_count, last_error, \
+     created_at, next_retry_at, relay_host, tls_requirement, transcript, failure_count";
+
+type QueueRow = (
+    String,
+    String,
+    String,
+    Vec<u8>,
+    String,
+    i32,
+    Option<String>,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    i32,
+);
+
 /// Queue entry status
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum QueueStatus {
     Pending,
     Sending,
     Sent,
     Failed,
     Bounced,
+    /// Set aside after repeated processing failures
+    Quarantined,
+    /// Kept instead of delivered in capture mode
+    Captured,
 }
 
 /// A queued email
@@ -56,11 +111,87 @@ pub struct QueuedEmail {
     pub last_error: Option<String>,
     pub created_at: DateTime<Utc>,
     pub next_retry_at: Option<DateTime<Utc>>,
+    /// Fixed next hop (`host[:port]`) instead of MX lookup
+    pub relay_host: Option<String>,
+    /// TLS requirement of the message (RFC 8689)
+    pub tls_requirement: TlsRequirement,
+    /// SMTP dialogue of the last failed delivery attempt
+    pub transcript: Option<Transcript>,
+    /// Attempts that failed to process the message, as opposed to
+    /// deliveries the next hop refused
+    pub failure_count: i32,
+}
+
+impl QueuedEmail {
+    fn from_row(row: QueueRow) -> Result<Self> {
+        let (
+            id,
+            from,
+            to,
+            data,
+            status,
+            retry,
+            error,
+            created,
+            next_retry,
+            relay_host,
+            tls,
+            transcript,
+            failure_count,
+        ) = row;
+        Ok(Self {
+            id,
+            from_addr: from,
+            to_addr: to,
+            data,
+            status: match status.as_str() {
+                "pending" => QueueStatus::Pending,
+                "sending" => QueueStatus::Sending,
+                "sent" => QueueStatus::Sent,
+                "failed" => QueueStatus::Failed,
+                "bounced" => QueueStatus::Bounced,
+                "quarantined" => QueueStatus::Quarantined,
+                "captured" => QueueStatus::Captured,
+                _ => QueueStatus::Pending,
+            },
+            retry_count: retry,
+            last_error: error,
+            created_at: DateTime::parse_from_rfc3339(&created)
+                .map_err(|e| MailError::Storage(e.to_string()))?
+                .with_timezone(&Utc),
+            next_retry_at: next_retry
+                .map(|s| DateTime::parse_from_rfc3339(&s).map(|dt| dt.with_timezone(&Utc)))
+                .transpose()
+                .map_err(|e| MailError::Storage(e.to_string()))?,
+            relay_host,
+            tls_requirement: tls
+                .as_deref()
+                .and_then(TlsRequirement::parse)
+                .unwrap_or_default(),
+            transcript: transcript
+                .map(|json| serde_json::from_str(&json))
+                .transpose()
+                .map_err(|e| MailError::Storage(e.to_string()))?,
+            failure_count,
+        })
+    }
 }
 
 /// SMTP queue manager
 pub struct SmtpQueue {
     db: Arc<SqlitePool>,
+    /// Records TLS outcomes of MX deliveries for TLS-RPT
+    tls_reporting: Option<Arc<TlsRptManager>>,
+    /// Host name used as the sender of bounces
+    hostname: String,
+    /// Alerts admins of quarantined messages
+    notifications: Option<Arc<NotificationRouter>>,
+    /// Admins alerted of quarantined messages
+    alert_to: Vec<String>,
+    /// Keep mail to non-allowlisted domains instead of delivering it
+    capture: Option<CapturePolicy>,
+    /// Signs queued mail with the active key of its From domain
+    dkim: Option<Arc<DkimKeyManager>>,
 }
 
 impl SmtpQueue {
@@ -87,7 +218,83 @@ impl SmtpQueue {
         .execute(&db)
         .await?;
 
-        Ok(Self { db: Arc::new(db) })
+        // Added after the initial schema
+        for (column, definition) in [
+            ("relay_host", "TEXT"),
+            ("tls_requirement", "TEXT"),
+            ("transcript", "TEXT"),
+            ("failure_count", "INTEGER NOT NULL DEFAULT 0"),
+        ] {
+            add_column(&db, column, definition).await?;
+        }
+
+        Ok(Self {
+            db: Arc::new(db),
+            tls_reporting: None,
+            hostname: "localhost".to_string(),
+            notifications: None,
+            alert_to: Vec::new(),
+            capture: None,
+            dkim: None,
+        })
+    }
+
+    /// Queue of the server configured by `config`, with its host name,
+    /// capture mode and DKIM signing
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let mut queue = Self::new(&config.storage.database_url)
+            .await?
+            .with_hostname(&config.server.hostname);
+        if let Some(policy) = CapturePolicy::from_config(&config.capture) {
+            queue = queue.with_capture(policy);
+        }
+        if let Some(manager) = build_dkim_key_manager(config).await? {
+            queue = queue.with_dkim(manager);
+        }
+        Ok(queue)
+    }
+
+    /// Set the host name bounces are sent from (`MAILER-DAEMON@hostname`)
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+
+    /// Record the TLS outcome of deliveries to recipient MX hosts
+    pub fn with_tls_reporting(mut self, manager: Arc<TlsRptManager>) -> Self {
+        self.tls_reporting = Some(manager);
+        self
+    }
+
+    /// Keep mail `policy` captures with the `captured` status instead of
+    /// delivering it
+    pub fn with_capture(mut self, policy: CapturePolicy) -> Self {
+        self.capture = Some(policy);
+        self
+    }
+
+    /// Sign queued mail with the DKIM keys of `manager`
+    pub fn with_dkim(mut self, manager: Arc<DkimKeyManager>) -> Self {
+        self.dkim = Some(manager);
+        self
+    }
+
+    /// Whether mail to `recipient` is kept instead of delivered
+    pub fn captures(&self, recipient: &str) -> bool {
+        self.capture
+            .as_ref()
+            .is_some_and(|policy| policy.captures(recipient))
+    }
+
+    /// Alert `alert_to` through `router` when a message is quarantined
+    pub fn with_quarantine_alerts(
+        mut self,
+        router: Arc<NotificationRouter>,
+        alert_to: Vec<String>,
+    ) -> Self {
+        self.notifications = Some(router);
+        self.alert_to = alert_to;
+        self
     }
 
     /// Enqueue an email for sending
@@ -100,25 +307,74 @@ impl SmtpQueue {
     /// # Returns
     /// ID of the queued email
     pub async fn enqueue(&self, from: &str, to: &str, data: &[u8]) -> Result<String> {
+        self.enqueue_via(from, to, data, None).await
+    }
+
+    /// Enqueue an email for delivery through a fixed relay host
+    ///
+    /// # Arguments
+    /// * `relay_host` - Next hop (`host`, `host:port` or `[IPv6]:port`), or
+    ///   `None` for MX lookup
+    pub async fn enqueue_via(
+        &self,
+        from: &str,
+        to: &str,
+        data: &[u8],
+        relay_host: Option<&str>,
+    ) -> Result<String> {
+        let tls_requirement = TlsRequirement::for_message(false, data);
+        self.enqueue_with(from, to, data, relay_host, tls_requirement)
+            .await
+    }
+
+    /// Enqueue an email with an explicit TLS requirement, e.g. one received
+    /// with the REQUIRETLS MAIL FROM parameter
+    pub async fn enqueue_with(
+        &self,
+        from: &str,
+        to: &str,
+        data: &[u8],
+        relay_host: Option<&str>,
+        tls_requirement: TlsRequirement,
+    ) -> Result<String> {
         let id = Uuid::new_v4().to_string();
         let now = Utc::now();
 
-        info!("Enqueuing email from {} to {}: {}", from, to, id);
+        let status = if self.captures(to) {
+            info!("Capturing email from {} to {}: {}", from, to, id);
+            "captured"
+        } else {
+            info!("Enqueuing email from {} to {}: {}", from, to, id);
+            "pending"
+        };
+
+        // Mail is still sent unsigned rather than lost if signing fails
+        let signed = match &self.dkim {
+            Some(dkim) => dkim.sign(data).await.unwrap_or_else(|e| {
+                warn!("Failed to DKIM sign email {}: {}", id, e);
+                None
+            }),
+            None => None,
+        };
+        let data = signed.as_deref().unwrap_or(data);
 
         sqlx::query(
             r#"
             INSERT INTO smtp_queue (
                 id, from_addr, to_addr, data, status,
-                retry_count, created_at, next_retry_at
-            ) VALUES (?, ?, ?, ?, 'pending', 0, ?, ?)
+                retry_count, created_at, next_retry_at, relay_host, tls_requirement
+            ) VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?, ?)
             "#,
         )
         .bind(&id)
         .bind(from)
         .bind(to)
         .bind(data)
+        .bind(status)
         .bind(now.to_rfc3339())
         .bind(now.to_rfc3339())
+        .bind(relay_host)
+        .bind(tls_requirement.as_str())
         .execute(&*self.db)
         .await?;
 
@@ -127,54 +383,90 @@ impl SmtpQueue {
 
     /// Get pending emails ready for sending
     pub async fn get_pending(&self, limit: i64) -> Result<Vec<QueuedEmail>> {
+        self.pending_rows(limit)
+            .await?
+            .into_iter()
+            .map(QueuedEmail::from_row)
+            .collect()
+    }
+
+    /// Rows of the pending emails ready for sending, not yet parsed so a
+    /// corrupt one can't hold up the others
+    async fn pending_rows(&self, limit: i64) -> Result<Vec<QueueRow>> {
         let now = Utc::now();
 
-        let rows = sqlx::query_as::<_, (String, String, String, Vec<u8>, String, i32, Option<String>, String, Option<String>)>(
+        let rows = sqlx::query_as::<_, QueueRow>(&format!(
             r#"
-            SELECT id, from_addr, to_addr, data, status, retry_count, last_error, created_at, next_retry_at
+            SELECT {COLUMNS}
             FROM smtp_queue
             WHERE status = 'pending'
               AND (next_retry_at IS NULL OR next_retry_at <= ?)
             ORDER BY created_at ASC
             LIMIT ?
-            "#,
-        )
+            "#
+        ))
         .bind(now.to_rfc3339())
         .bind(limit)
         .fetch_all(&*self.db)
         .await?;
 
-        let emails: Result<Vec<QueuedEmail>> = rows
+        Ok(rows)
+    }
+
+    /// Most recent entries, optionally only those with `status`
+    ///
+    /// Entries that can't be read are skipped.
+    pub async fn list(&self, status: Option<&str>, limit: i64) -> Result<Vec<QueuedEmail>> {
+        let rows = sqlx::query_as::<_, QueueRow>(&format!(
+            r#"
+            SELECT {COLUMNS}
+            FROM smtp_queue
+            WHERE ? IS NULL OR status = ?
+            ORDER BY created_at DESC
+            LIMIT ?
+            "#
+        ))
+        .bind(status)
+        .bind(status)
+        .bind(limit)
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(rows
             .into_iter()
-            .map(|(id, from, to, data, status, retry, error, created, next_retry)| {
-                Ok(QueuedEmail {
-                    id,
-                    from_addr: from,
-                    to_addr: to,
-                    data,
-                    status: match status.as_str() {
-                        "pending" => QueueStatus::Pending,
-                        "sending" => QueueStatus::Sending,
-                        "sent" => QueueStatus::Sent,
-                        "failed" => QueueStatus::Failed,
-                        "bounced" => QueueStatus::Bounced,
-                        _ => QueueStatus::Pending,
-                    },
-                    retry_count: retry,
-                    last_error: error,
-                    created_at: DateTime::parse_from_rfc3339(&created)
-                        .map_err(|e| MailError::Storage(e.to_string()))?
-                        .with_timezone(&Utc),
-                    next_retry_at: next_retry
-                        .map(|s| DateTime::parse_from_rfc3339(&s)
-                            .map(|dt| dt.with_timezone(&Utc)))
-                        .transpose()
-                        .map_err(|e| MailError::Storage(e.to_string()))?,
-                })
+            .filter_map(|row| {
+                let id = row.0.clone();
+                QueuedEmail::from_row(row)
+                    .map_err(|e| warn!("Skipping unreadable queue entry {}: {}", id, e))
+                    .ok()
             })
-            .collect();
+            .collect())
+    }
+
+    /// Look up one entry
+    pub async fn get(&self, id: &str) -> Result<Option<QueuedEmail>> {
+        let row = sqlx::query_as::<_, QueueRow>(&format!(
+            "SELECT {COLUMNS} FROM smtp_queue WHERE id = ?"
+        ))
+        .bind(id)
+        .fetch_optional(&*self.db)
+        .await?;
+
+        row.map(QueuedEmail::from_row).transpose()
+    }
+
+    /// Keep the transcript of a failed delivery attempt
+    pub async fn set_transcript(&self, id: &str, transcript: &Transcript) -> Result<()> {
+        let json =
+            serde_json::to_string(transcript).map_err(|e| MailError::Storage(e.to_string()))?;
+
+        sqlx::query("UPDATE smtp_queue SET transcript = ? WHERE id = ?")
+            .bind(json)
+            .bind(id)
+            .execute(&*self.db)
+            .await?;
 
-        emails
+        Ok(())
     }
 
     /// Mark email as sent
@@ -195,6 +487,27 @@ impl SmtpQueue {
         Ok(())
     }
 
+    /// Keep an email instead of delivering it
+    pub async fn mark_captured(&self, id: &str) -> Result<()> {
+        info!("Capturing email {} instead of delivering it", id);
+
+        sqlx::query("UPDATE smtp_queue SET status = 'captured', next_retry_at = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&*self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove all captured emails; returns how many there were
+    pub async fn clear_captured(&self) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM smtp_queue WHERE status = 'captured'")
+            .execute(&*self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
     /// Mark email as failed and schedule retry
     pub async fn mark_failed(&self, id: &str, error_msg: &str, retry_count: i32) -> Result<()> {
         if retry_count >= MAX_RETRY_ATTEMPTS {
@@ -251,19 +564,128 @@ impl SmtpQueue {
         Ok(())
     }
 
+    /// Count a failed attempt to process an entry
+    ///
+    /// The entry is retried after the base retry delay, or quarantined once
+    /// it reached [`POISON_THRESHOLD`] failures. Returns whether it was
+    /// quarantined.
+    pub async fn record_failure(&self, id: &str, error_msg: &str) -> Result<bool> {
+        let next_retry = Utc::now() + Duration::seconds(RETRY_BASE_DELAY_SECS);
+        sqlx::query(
+            r#"
+            UPDATE smtp_queue
+            SET failure_count = failure_count + 1,
+                last_error = ?,
+                next_retry_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(error_msg)
+        .bind(next_retry.to_rfc3339())
+        .bind(id)
+        .execute(&*self.db)
+        .await?;
+
+        let (failures,): (i32,) = sqlx::query_as("SELECT failure_count FROM smtp_queue WHERE id = ?")
+            .bind(id)
+            .fetch_one(&*self.db)
+            .await?;
+        if failures < POISON_THRESHOLD {
+            warn!(
+                "Processing email {} failed ({} of {} attempts): {}",
+                id, failures, POISON_THRESHOLD, error_msg
+            );
+            return Ok(false);
+        }
+
+        self.quarantine(id, error_msg, failures).await?;
+        Ok(true)
+    }
+
+    /// Set an entry aside and alert admins
+    async fn quarantine(&self, id: &str, error_msg: &str, failures: i32) -> Result<()> {
+        error!(
+            "Quarantining email {} after {} processing failures: {}",
+            id, failures, error_msg
+        );
+
+        sqlx::query("UPDATE smtp_queue SET status = 'quarantined', next_retry_at = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&*self.db)
+            .await?;
+
+        if let Some(router) = &self.notifications {
+            let body = format!(
+                "Queued message {} was quarantined after {} failed processing attempts.\n\n\
+                 Last error: {}\n\n\
+                 It stays in the queue with status \"quarantined\" until it is released \
+                 or removed.",
+                id, failures, error_msg
+            );
+            for admin in &self.alert_to {
+                if let Err(e) = router
+                    .notify(admin, EventKind::SecurityAlert, "Queued message quarantined", &body)
+                    .await
+                {
+                    warn!("Failed to alert {} of quarantined email {}: {}", admin, id, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put a quarantined entry back into the queue with a clean failure
+    /// count; returns whether the entry was quarantined
+    pub async fn release(&self, id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE smtp_queue
+            SET status = 'pending',
+                failure_count = 0,
+                next_retry_at = ?
+            WHERE id = ? AND status = 'quarantined'
+            "#,
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&*self.db)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            info!("Released quarantined email {}", id);
+        }
+        Ok(result.rows_affected() > 0)
+    }
+
     /// Process queue - send pending emails
+    ///
+    /// Each entry is handled in isolation: an entry that can't be read, or
+    /// whose processing fails or panics, is counted towards its quarantine
+    /// and the remaining entries are still processed.
     pub async fn process_queue(&self) -> Result<usize> {
+        self.process_queue_in(None).await
+    }
+
+    /// Process queue, delivering entries in parallel in `pool` if given
+    pub async fn process_queue_in(&self, pool: Option<&WorkerPool>) -> Result<usize> {
         debug!("Processing queue");
 
-        let pending = self.get_pending(10).await?;
+        let batch = pool.map_or(BATCH_SIZE, |pool| (pool.concurrency() as i64).max(BATCH_SIZE));
+        let pending = self.pending_rows(batch).await?;
         let count = pending.len();
 
-        for email in pending {
-            if let Err(e) = self.process_email(&email).await {
-                error!("Failed to process email {}: {}", email.id, e);
-                self.mark_failed(&email.id, &e.to_string(), email.retry_count).await?;
-            } else {
-                self.mark_sent(&email.id).await?;
+        match pool {
+            Some(pool) => {
+                let entries = pending.into_iter().map(|row| pool.run(self.process_entry(row)));
+                for result in join_all(entries).await {
+                    result?;
+                }
+            }
+            None => {
+                for row in pending {
+                    self.process_entry(row).await?;
+                }
             }
         }
 
@@ -274,10 +696,77 @@ impl SmtpQueue {
         Ok(count)
     }
 
+    async fn process_entry(&self, row: QueueRow) -> Result<()> {
+        let id = row.0.clone();
+        let result = match QueuedEmail::from_row(row) {
+            Ok(email) => AssertUnwindSafe(self.deliver(&email))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| {
+                    Err(MailError::Storage(format!(
+                        "processing panicked: {}",
+                        panic_message(panic.as_ref())
+                    )))
+                }),
+            Err(e) => Err(MailError::Storage(format!("unreadable queue entry: {}", e))),
+        };
+
+        if let Err(e) = result {
+            self.record_failure(&id, &e.to_string()).await?;
+        }
+        Ok(())
+    }
+
+    /// Attempt delivery of one email and record the outcome
+    ///
+    /// Delivery failures are retried or bounced; errors are returned only
+    /// when the email itself could not be processed.
+    async fn deliver(&self, email: &QueuedEmail) -> Result<()> {
+        // Entries queued before capture mode was turned on
+        if self.captures(&email.to_addr) {
+            return self.mark_captured(&email.id).await;
+        }
+
+        let mut transcript = None;
+        if let Err(e) = self.process_email(email, &mut transcript).await {
+            error!("Failed to process email {}: {}", email.id, e);
+            if let Some(transcript) = &transcript {
+                self.set_transcript(&email.id, transcript).await?;
+            }
+            if is_permanent(&e) || email.retry_count >= MAX_RETRY_ATTEMPTS {
+                self.mark_bounced(&email.id, &e.to_string()).await?;
+                self.bounce(email, &e, transcript).await?;
+            } else {
+                self.mark_failed(&email.id, &e.to_string(), email.retry_count).await?;
+            }
+        } else {
+            self.mark_sent(&email.id).await?;
+        }
+        Ok(())
+    }
+
     /// Process a single email
-    async fn process_email(&self, email: &QueuedEmail) -> Result<()> {
+    ///
+    /// `transcript` receives the dialogue of the last server contacted.
+    async fn process_email(
+        &self,
+        email: &QueuedEmail,
+        transcript: &mut Option<Transcript>,
+    ) -> Result<()> {
         info!("Processing email {}: {} -> {}", email.id, email.from_addr, email.to_addr);
 
+        // Routed mail goes straight to its configured next hop
+        if let Some(relay_host) = &email.relay_host {
+            let server = relay_address(relay_host);
+            info!("Relaying email {} via {}", email.id, server);
+            let client = self.client(server, email, false);
+            let result = client
+                .send_mail(&email.from_addr, &email.to_addr, &email.data)
+                .await;
+            *transcript = Some(client.transcript()).filter(|t| !t.is_empty());
+            return result;
+        }
+
         // Extract domain from recipient
         let domain = email
             .to_addr
@@ -297,12 +786,19 @@ impl SmtpQueue {
         for server in &mx_servers {
             info!("Trying to send via {}", server);
 
-            let client = SmtpClient::new(server.clone());
-            match client.send_mail(&email.from_addr, &email.to_addr, &email.data).await {
+            let client = self.client(server.clone(), email, true);
+            let result = client.send_mail(&email.from_addr, &email.to_addr, &email.data).await;
+            *transcript = Some(client.transcript()).filter(|t| !t.is_empty());
+            match result {
                 Ok(_) => {
                     info!("Email {} sent successfully via {}", email.id, server);
                     return Ok(());
                 }
+                Err(e) if is_permanent(&e) => {
+                    // Other MX hosts of the domain would answer the same
+                    warn!("Delivery via {} failed permanently: {}", server, e);
+                    return Err(e);
+                }
                 Err(e) => {
                     warn!("Failed to send via {}: {}", server, e);
                     last_error = Some(e);
@@ -316,12 +812,65 @@ impl SmtpQueue {
         }))
     }
 
+    /// Client for one delivery attempt of `email`
+    fn client(&self, server: String, email: &QueuedEmail, mx: bool) -> SmtpClient {
+        let mut client = SmtpClient::new(server);
+        if mx {
+            if let Some(manager) = &self.tls_reporting {
+                client = client.with_tls_reporting(manager.clone());
+            }
+        }
+        if email.tls_requirement == TlsRequirement::Required {
+            client = client.require_tls();
+        }
+        client
+    }
+
+    /// Return an undeliverable message to its sender
+    async fn bounce(
+        &self,
+        email: &QueuedEmail,
+        error: &MailError,
+        transcript: Option<Transcript>,
+    ) -> Result<()> {
+        let mut failure = DeliveryFailure::from_error(&email.to_addr, error);
+        if let Some(transcript) = transcript {
+            failure = failure.with_transcript(transcript);
+        }
+        let Some(message) = BounceGenerator::new(&self.hostname).generate(
+            &email.from_addr,
+            &email.data,
+            &[failure],
+            email.created_at,
+        ) else {
+            debug!("Not bouncing email {} with null reverse-path", email.id);
+            return Ok(());
+        };
+
+        // A bounce quoting a REQUIRETLS message's headers inherits the
+        // requirement (RFC 8689 section 5)
+        let tls_requirement = match email.tls_requirement {
+            TlsRequirement::Required => TlsRequirement::Required,
+            _ => TlsRequirement::Opportunistic,
+        };
+
+        info!("Returning email {} to {}", email.id, email.from_addr);
+        self.enqueue_with("", &email.from_addr, &message, None, tls_requirement)
+            .await?;
+        Ok(())
+    }
+
     /// Start queue worker loop
     pub async fn start_worker(self: Arc<Self>) {
+        self.start_worker_in(None).await
+    }
+
+    /// Start queue worker loop, delivering in `pool` if given
+    pub async fn start_worker_in(self: Arc<Self>, pool: Option<Arc<WorkerPool>>) {
         info!("Starting queue worker");
 
         loop {
-            match self.process_queue().await {
+            match self.process_queue_in(pool.as_deref()).await {
                 Ok(count) => {
                     if count == 0 {
                         // No emails processed, sleep longer
@@ -339,3 +888,389 @@ impl SmtpQueue {
         }
     }
 }
+
+/// Text of a caught panic
+/// Add `column` to the queue table of a database created before it existed
+async fn add_column(db: &SqlitePool, column: &str, definition: &str) -> Result<()> {
+    let columns: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM pragma_table_info('smtp_queue')")
+            .fetch_all(db)
+            .await?;
+    if !columns.iter().any(|name| name == column) {
+        sqlx::query(&format!(
+            "ALTER TABLE smtp_queue ADD COLUMN {} {}",
+            column, definition
+        ))
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Address to connect to for a relay host given as `host`, `host:port`, an
+/// IPv6 address or `[IPv6]:port`; the port defaults to 25
+fn relay_address(relay_host: &str) -> String {
+    if let Some((host, rest)) = relay_host
+        .strip_prefix('[')
+        .and_then(|bracketed| bracketed.split_once(']'))
+    {
+        let port = rest.strip_prefix(':').and_then(|port| port.parse::<u16>().ok());
+        return format!("[{}]:{}", host, port.unwrap_or(25));
+    }
+    if relay_host.parse::<std::net::Ipv6Addr>().is_ok() {
+        return format!("[{}]:25", relay_host);
+    }
+    match relay_host.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => {
+            relay_host.to_string()
+        }
+        _ => format!("{}:25", relay_host),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Whether retrying `error` later can't succeed
+///
+/// Covers 5xx replies at any stage, including recipients the next hop
+/// rejects at RCPT TO after we accepted the message for relay.
+fn is_permanent(error: &MailError) -> bool {
+    matches!(error, MailError::SmtpRejected { .. } | MailError::RequireTls(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Plaintext next hop answering RCPT TO with `rcpt_reply`
+    async fn next_hop(rcpt_reply: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            reader.get_mut().write_all(b"220 mx ESMTP\r\n").await.unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = match line.trim_end() {
+                    l if l.starts_with("EHLO") => b"250-mx\r\n250 8BITMIME\r\n",
+                    l if l.starts_with("RCPT") => rcpt_reply,
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                reader.get_mut().write_all(reply).await.unwrap();
+                line.clear();
+            }
+        });
+        addr.to_string()
+    }
+
+    async fn queue(dir: &tempfile::TempDir) -> SmtpQueue {
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("queue.db").display());
+        SmtpQueue::new(&url).await.unwrap().with_hostname("mx.example.com")
+    }
+
+    async fn status(queue: &SmtpQueue, from: &str) -> String {
+        let (status,): (String,) = sqlx::query_as("SELECT status FROM smtp_queue WHERE from_addr = ?")
+            .bind(from)
+            .fetch_one(&*queue.db)
+            .await
+            .unwrap();
+        status
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_rejection_bounces_to_sender() {
+        let hop = next_hop(b"550 5.1.1 No such user\r\n").await;
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir).await;
+
+        let data = b"From: alice@example.com\r\nSubject: hello\r\n\r\nbody\r\n";
+        queue
+            .enqueue_via("alice@example.com", "bob@example.net", data, Some(&hop))
+            .await
+            .unwrap();
+        queue.process_queue().await.unwrap();
+
+        // The original is bounced at once rather than retried
+        assert_eq!(status(&queue, "alice@example.com").await, "bounced");
+
+        // The failed dialogue is kept with the entry
+        let original = queue.list(Some("bounced"), 10).await.unwrap();
+        assert_eq!(original.len(), 1);
+        let transcript = original[0].transcript.as_ref().unwrap();
+        assert_eq!(transcript.server, hop);
+        assert!(transcript
+            .render()
+            .contains("> RCPT TO:<bob@example.net>\r\n< 550 5.1.1 No such user\r\n"));
+        let entry = queue.get(&original[0].id).await.unwrap().unwrap();
+        assert_eq!(entry.transcript.as_ref(), Some(transcript));
+
+        let pending = queue.get_pending(10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let bounce = &pending[0];
+        assert_eq!(bounce.from_addr, "");
+        assert_eq!(bounce.to_addr, "alice@example.com");
+        let text = String::from_utf8_lossy(&bounce.data);
+        assert!(text.starts_with("From: Mail Delivery System <MAILER-DAEMON@mx.example.com>\r\n"));
+        assert!(text.contains("Status: 5.1.1\r\n"));
+        assert!(text.contains("Diagnostic-Code: smtp; 550 5.1.1 No such user\r\n"));
+        assert!(text.contains("< 550 5.1.1 No such user\r\n"));
+        assert!(text.contains("Subject: hello"));
+        assert!(!text.contains("body"));
+    }
+
+    #[tokio::test]
+    async fn test_undeliverable_bounce_is_not_bounced() {
+        let hop = next_hop(b"550 5.1.1 No such user\r\n").await;
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir).await;
+
+        queue
+            .enqueue_via("", "alice@example.com", b"Subject: bounce\r\n\r\n", Some(&hop))
+            .await
+            .unwrap();
+        queue.process_queue().await.unwrap();
+
+        assert_eq!(status(&queue, "").await, "bounced");
+        assert!(queue.get_pending(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_poison_message_is_quarantined() {
+        let hop = next_hop(b"550 5.1.1 No such user\r\n").await;
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir).await;
+
+        // An entry that can't be loaded doesn't hold up the regular one
+        sqlx::query(
+            "INSERT INTO smtp_queue (id, from_addr, to_addr, data, status, created_at) \
+             VALUES ('poison', 'mallory@example.com', 'bob@example.net', x'00', 'pending', 'garbage')",
+        )
+        .execute(&*queue.db)
+        .await
+        .unwrap();
+        queue
+            .enqueue_via("", "bob@example.net", b"Subject: hi\r\n\r\n", Some(&hop))
+            .await
+            .unwrap();
+
+        queue.process_queue().await.unwrap();
+        assert_eq!(status(&queue, "").await, "bounced");
+        assert_eq!(status(&queue, "mallory@example.com").await, "pending");
+
+        for _ in 1..POISON_THRESHOLD {
+            sqlx::query("UPDATE smtp_queue SET next_retry_at = NULL WHERE id = 'poison'")
+                .execute(&*queue.db)
+                .await
+                .unwrap();
+            queue.process_queue().await.unwrap();
+        }
+        assert_eq!(status(&queue, "mallory@example.com").await, "quarantined");
+        let (failures, error): (i32, String) =
+            sqlx::query_as("SELECT failure_count, last_error FROM smtp_queue WHERE id = 'poison'")
+                .fetch_one(&*queue.db)
+                .await
+                .unwrap();
+        assert_eq!(failures, POISON_THRESHOLD);
+        assert!(error.starts_with("Storage error: unreadable queue entry"), "{}", error);
+
+        // Quarantined entries are left alone until released
+        assert_eq!(queue.process_queue().await.unwrap(), 0);
+        assert!(queue.release("poison").await.unwrap());
+        assert!(!queue.release("poison").await.unwrap());
+        assert_eq!(status(&queue, "mallory@example.com").await, "pending");
+    }
+
+    #[tokio::test]
+    async fn test_require_tls_bounces_without_starttls() {
+        let hop = next_hop(b"250 ok\r\n").await;
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir).await;
+
+        let data = b"From: alice@example.com\r\nSubject: secret\r\n\r\nbody\r\n";
+        queue
+            .enqueue_with(
+                "alice@example.com",
+                "bob@example.net",
+                data,
+                Some(&hop),
+                TlsRequirement::Required,
+            )
+            .await
+            .unwrap();
+        queue.process_queue().await.unwrap();
+
+        assert_eq!(status(&queue, "alice@example.com").await, "bounced");
+
+        let pending = queue.get_pending(10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let bounce = &pending[0];
+        assert_eq!(bounce.to_addr, "alice@example.com");
+        assert_eq!(bounce.tls_requirement, TlsRequirement::Required);
+        assert!(String::from_utf8_lossy(&bounce.data).contains("Status: 5.7.30\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_keeps_mail_outside_allowlist() {
+        let hop = next_hop(b"250 ok\r\n").await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::CaptureConfig {
+            enabled: true,
+            allow_domains: vec!["example.net".to_string()],
+        };
+        let policy = CapturePolicy::from_config(&config).unwrap();
+
+        // Queued before capture mode was turned on
+        queue(&dir)
+            .await
+            .enqueue_via(
+                "dave@example.com",
+                "erin@customer.test",
+                b"Subject: old\r\n\r\n",
+                Some(&hop),
+            )
+            .await
+            .unwrap();
+
+        let queue = queue(&dir).await.with_capture(policy);
+        queue
+            .enqueue_via(
+                "alice@example.com",
+                "bob@example.net",
+                b"Subject: hi\r\n\r\n",
+                Some(&hop),
+            )
+            .await
+            .unwrap();
+        queue
+            .enqueue_via(
+                "carol@example.com",
+                "frank@customer.test",
+                b"Subject: hi\r\n\r\n",
+                Some(&hop),
+            )
+            .await
+            .unwrap();
+        assert_eq!(status(&queue, "carol@example.com").await, "captured");
+
+        assert_eq!(queue.process_queue().await.unwrap(), 2);
+        assert_eq!(status(&queue, "dave@example.com").await, "captured");
+
+        // Allowlisted mail went to the next hop
+        let allowed = queue.list(Some("pending"), 10).await.unwrap();
+        assert_eq!(allowed.len(), 1);
+        assert_eq!(allowed[0].to_addr, "bob@example.net");
+        assert_eq!(allowed[0].retry_count, 1);
+
+        let captured = queue.list(Some("captured"), 10).await.unwrap();
+        assert_eq!(captured.len(), 2);
+        assert_eq!(queue.clear_captured().await.unwrap(), 2);
+        assert!(queue.list(Some("captured"), 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_signs_with_dkim() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::DkimSigningConfig {
+            enabled: true,
+            key_secret: "secret".to_string(),
+            ..Default::default()
+        };
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("keys.db").display());
+        let keys = DkimKeyManager::connect(&url, &config).await.unwrap();
+        keys.generate("example.com", "mail", crate::dkim_keys::DkimAlgorithm::Ed25519)
+            .await
+            .unwrap();
+        let queue = queue(&dir).await.with_dkim(Arc::new(keys));
+
+        let message = b"From: alice@example.com\r\nSubject: hi\r\n\r\nHello\r\n";
+        let signed = queue
+            .enqueue("alice@example.com", "bob@example.net", message)
+            .await
+            .unwrap();
+        let other = queue
+            .enqueue("carol@other.test", "bob@example.net", b"From: carol@other.test\r\n\r\n")
+            .await
+            .unwrap();
+
+        let signed = queue.get(&signed).await.unwrap().unwrap().data;
+        assert!(signed.starts_with(b"DKIM-Signature: v=1; a=ed25519-sha256;"));
+        assert!(signed.ends_with(message));
+        let other = queue.get(&other).await.unwrap().unwrap().data;
+        assert_eq!(other, b"From: carol@other.test\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_run_in_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir).await;
+        // Nothing listens there, so every attempt fails and is retried
+        for from in ["a@example.com", "b@example.com", "c@example.com"] {
+            queue
+                .enqueue_via(
+                    from,
+                    "bob@example.net",
+                    b"Subject: hi\r\n\r\n",
+                    Some("127.0.0.1:1"),
+                )
+                .await
+                .unwrap();
+        }
+
+        let workers = crate::workers::WorkerPools::from_config(&Default::default());
+        let pool = workers.pool(crate::workers::Subsystem::Delivery);
+        assert_eq!(queue.process_queue_in(Some(pool)).await.unwrap(), 3);
+
+        let stats = pool.stats();
+        assert_eq!((stats.completed, stats.running), (3, 0));
+        let retried = queue.list(Some("pending"), 10).await.unwrap();
+        assert!(retried.iter().all(|email| email.retry_count == 1));
+    }
+
+    #[test]
+    fn test_relay_address() {
+        assert_eq!(relay_address("mx.example.com"), "mx.example.com:25");
+        assert_eq!(relay_address("mx.example.com:2525"), "mx.example.com:2525");
+        assert_eq!(relay_address("192.0.2.1:587"), "192.0.2.1:587");
+        assert_eq!(relay_address("2001:db8::1"), "[2001:db8::1]:25");
+        assert_eq!(relay_address("[2001:db8::1]"), "[2001:db8::1]:25");
+        assert_eq!(relay_address("[2001:db8::1]:2525"), "[2001:db8::1]:2525");
+    }
+
+    #[tokio::test]
+    async fn test_new_upgrades_old_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("queue.db").display());
+        let db = SqlitePool::connect(&url).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE smtp_queue (id TEXT PRIMARY KEY, from_addr TEXT NOT NULL,
+             to_addr TEXT NOT NULL, data BLOB NOT NULL, status TEXT NOT NULL,
+             retry_count INTEGER NOT NULL DEFAULT 0, last_error TEXT,
+             created_at TEXT NOT NULL, next_retry_at TEXT)",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        db.close().await;
+
+        // Upgrading twice leaves the columns in place
+        SmtpQueue::new(&url).await.unwrap();
+        let queue = SmtpQueue::new(&url).await.unwrap();
+        let id = queue
+            .enqueue_via("a@example.com", "b@example.net", b"hi", Some("[::1]:2525"))
+            .await
+            .unwrap();
+        let email = queue.get(&id).await.unwrap().unwrap();
+        assert_eq!(email.relay_host.as_deref(), Some("[::1]:2525"));
+        assert_eq!(email.failure_count, 0);
+    }
+}
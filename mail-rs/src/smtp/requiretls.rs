@@ -0,0 +1,97 @@
+//! TLS requirements of relayed messages (RFC 8689)
+//!
+//! A sender can ask for its message to be relayed only over verified TLS
+//! by passing `REQUIRETLS` on MAIL FROM, or, with a `TLS-Required: No`
+//! header, ask relays to deliver even when TLS policies fail. The
+//! requirement is stored with each queue entry so every later hop honours
+//! it.
+
+use serde::{Deserialize, Serialize};
+
+/// How a queued message may be relayed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsRequirement {
+    /// STARTTLS when offered, plaintext otherwise
+    #[default]
+    Opportunistic,
+    /// REQUIRETLS: verified TLS to a next hop that supports REQUIRETLS,
+    /// or the message bounces
+    Required,
+    /// `TLS-Required: No`: deliver even if TLS policies fail
+    NotRequired,
+}
+
+impl TlsRequirement {
+    /// Requirement of a message received with or without the REQUIRETLS
+    /// parameter; the header is ignored when the parameter is present
+    pub fn for_message(require_tls: bool, data: &[u8]) -> Self {
+        if require_tls {
+            TlsRequirement::Required
+        } else if has_tls_required_no(data) {
+            TlsRequirement::NotRequired
+        } else {
+            TlsRequirement::Opportunistic
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsRequirement::Opportunistic => "opportunistic",
+            TlsRequirement::Required => "required",
+            TlsRequirement::NotRequired => "not-required",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "opportunistic" => Some(TlsRequirement::Opportunistic),
+            "required" => Some(TlsRequirement::Required),
+            "not-required" => Some(TlsRequirement::NotRequired),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the message header carries `TLS-Required: No`
+fn has_tls_required_no(data: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(data);
+    let headers = text
+        .split("\r\n\r\n")
+        .next()
+        .unwrap_or_default()
+        .split("\n\n")
+        .next()
+        .unwrap_or_default();
+
+    headers.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("TLS-Required")
+                && value.trim().eq_ignore_ascii_case("No")
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requirement_from_parameter_and_header() {
+        let plain = b"Subject: hi\r\n\r\nTLS-Required: No\r\n";
+        let header = b"Subject: hi\r\ntls-required:  no \r\n\r\nbody\r\n";
+
+        assert_eq!(TlsRequirement::for_message(false, plain), TlsRequirement::Opportunistic);
+        assert_eq!(TlsRequirement::for_message(false, header), TlsRequirement::NotRequired);
+        // REQUIRETLS wins over the header (RFC 8689 section 5)
+        assert_eq!(TlsRequirement::for_message(true, header), TlsRequirement::Required);
+
+        for requirement in [
+            TlsRequirement::Opportunistic,
+            TlsRequirement::Required,
+            TlsRequirement::NotRequired,
+        ] {
+            assert_eq!(TlsRequirement::parse(requirement.as_str()), Some(requirement));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Operator-defined routing rules for inbound mail
+//!
+//! A transport table mapping recipients to delivery actions, evaluated at
+//! RCPT TO time before any local delivery. This enables gateway and staged
+//! migration setups where some users are hosted on another server.
+//!
+//! # Pattern syntax (most specific wins)
+//! - `user@example.com` - exact address
+//! - `example.com` or `@example.com` - every address in the domain
+//! - `.example.com` - every subdomain of example.com
+//! - `*` - catch-all
+//!
+//! # Configuration
+//! ```toml
+//! [[routing.rules]]
+//! pattern = "legacy.example.com"
+//! action = "forward"
+//! host = "old-mx.example.com:25"
+//!
+//! [[routing.rules]]
+//! pattern = "noreply@example.com"
+//! action = "reject"
+//! message = "This address does not accept mail"
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Delivery action for a matched recipient
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum RouteAction {
+    /// Deliver to the local Maildir
+    Local,
+    /// Relay to another SMTP host (`host` or `host:port`)
+    Forward { host: String },
+    /// Refuse the recipient at RCPT TO with the given message
+    Reject { message: String },
+}
+
+/// A single routing table entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingRule {
+    pub pattern: String,
+    #[serde(flatten)]
+    pub action: RouteAction,
+}
+
+/// Compiled routing table
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    addresses: HashMap<String, RouteAction>,
+    domains: HashMap<String, RouteAction>,
+    subdomains: HashMap<String, RouteAction>,
+    catch_all: Option<RouteAction>,
+}
+
+impl RoutingTable {
+    /// Build a routing table from configured rules
+    ///
+    /// Patterns are case-insensitive. When two rules use the same pattern,
+    /// the first one wins.
+    pub fn new(rules: &[RoutingRule]) -> Self {
+        let mut table = Self::default();
+
+        for rule in rules {
+            let pattern = rule.pattern.trim().to_lowercase();
+            let action = rule.action.clone();
+
+            if pattern == "*" {
+                table.catch_all.get_or_insert(action);
+            } else if let Some(suffix) = pattern.strip_prefix('.') {
+                table.subdomains.entry(suffix.to_string()).or_insert(action);
+            } else if let Some(domain) = pattern.strip_prefix('@') {
+                table.domains.entry(domain.to_string()).or_insert(action);
+            } else if pattern.contains('@') {
+                table.addresses.entry(pattern).or_insert(action);
+            } else {
+                table.domains.entry(pattern).or_insert(action);
+            }
+        }
+
+        table
+    }
+
+    /// True if no rules are configured
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+            && self.domains.is_empty()
+            && self.subdomains.is_empty()
+            && self.catch_all.is_none()
+    }
+
+    /// True if any rule forwards mail to another host
+    pub fn has_forward_rules(&self) -> bool {
+        self.addresses
+            .values()
+            .chain(self.domains.values())
+            .chain(self.subdomains.values())
+            .chain(self.catch_all.iter())
+            .any(|action| matches!(action, RouteAction::Forward { .. }))
+    }
+
+    /// Resolve the delivery action for a recipient (defaults to local)
+    pub fn resolve(&self, recipient: &str) -> RouteAction {
+        let recipient = recipient.trim().to_lowercase();
+
+        if let Some(action) = self.addresses.get(&recipient) {
+            return action.clone();
+        }
+
+        let domain = match recipient.rsplit_once('@') {
+            Some((_, domain)) => domain,
+            None => return self.catch_all.clone().unwrap_or(RouteAction::Local),
+        };
+
+        if let Some(action) = self.domains.get(domain) {
+            return action.clone();
+        }
+
+        // Walk up parent domains: a.b.example.com -> b.example.com -> example.com
+        let mut parent = domain;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(action) = self.subdomains.get(rest) {
+                return action.clone();
+            }
+            parent = rest;
+        }
+
+        self.catch_all.clone().unwrap_or(RouteAction::Local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, action: RouteAction) -> RoutingRule {
+        RoutingRule {
+            pattern: pattern.to_string(),
+            action,
+        }
+    }
+
+    fn forward(host: &str) -> RouteAction {
+        RouteAction::Forward {
+            host: host.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_default_is_local() {
+        let table = RoutingTable::new(&[]);
+        assert!(table.is_empty());
+        assert_eq!(table.resolve("user@example.com"), RouteAction::Local);
+    }
+
+    #[test]
+    fn test_specificity_order() {
+        let table = RoutingTable::new(&[
+            rule("*", forward("catchall:25")),
+            rule(".example.com", forward("sub:25")),
+            rule("example.com", forward("domain:25")),
+            rule("ceo@example.com", RouteAction::Local),
+        ]);
+
+        assert_eq!(table.resolve("CEO@Example.com"), RouteAction::Local);
+        assert_eq!(table.resolve("bob@example.com"), forward("domain:25"));
+        assert_eq!(table.resolve("bob@eu.mail.example.com"), forward("sub:25"));
+        assert_eq!(table.resolve("bob@other.org"), forward("catchall:25"));
+    }
+
+    #[test]
+    fn test_at_domain_pattern_and_first_rule_wins() {
+        let table = RoutingTable::new(&[
+            rule("@legacy.org", forward("old:25")),
+            rule("legacy.org", RouteAction::Local),
+        ]);
+
+        assert_eq!(table.resolve("a@legacy.org"), forward("old:25"));
+        assert!(table.has_forward_rules());
+    }
+
+    #[test]
+    fn test_reject_rule() {
+        let table = RoutingTable::new(&[rule(
+            "noreply@example.com",
+            RouteAction::Reject {
+                message: "No mail here".to_string(),
+            },
+        )]);
+
+        assert_eq!(
+            table.resolve("noreply@example.com"),
+            RouteAction::Reject {
+                message: "No mail here".to_string()
+            }
+        );
+        assert!(!table.has_forward_rules());
+    }
+
+    #[test]
+    fn test_rules_deserialize_from_toml() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            rules: Vec<RoutingRule>,
+        }
+
+        let wrapper: Wrapper = toml::from_str(
+            r#"
+            [[rules]]
+            pattern = "legacy.example.com"
+            action = "forward"
+            host = "old-mx.example.com:25"
+
+            [[rules]]
+            pattern = "noreply@example.com"
+            action = "reject"
+            message = "Go away"
+
+            [[rules]]
+            pattern = "*"
+            action = "local"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(wrapper.rules.len(), 3);
+        assert_eq!(wrapper.rules[0].action, forward("old-mx.example.com:25"));
+        assert_eq!(wrapper.rules[2].action, RouteAction::Local);
+    }
+}
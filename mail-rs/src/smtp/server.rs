@@ -1,9 +1,42 @@
+use crate::aliases::AliasManager;
+use crate::antispam::dnsbl::DnsblAction;
+use crate::antispam::greylist::GreylistConfig;
+use crate::antispam::{DnsblChecker, GreylistManager, ImpersonationGuard, UriblChecker};
+use crate::antivirus::{VirusAction, VirusScanner};
+use crate::authentication::{DkimValidator, SpfValidator};
+use crate::auto_reply::AutoReplyManager;
+use crate::billing::BillingManager;
+use crate::caldav::CalDavManager;
 use crate::config::Config;
-use crate::error::Result;
-use crate::security::{Authenticator, TlsConfig};
+use crate::devices::DeviceManager;
+use crate::dkim_keys::DkimKeyManager;
+use crate::dmarc_reports::DmarcReportManager;
+use crate::error::{MailError, Result};
+use crate::footers::FooterManager;
+use crate::hooks::HookManager;
+use crate::login_anomaly::LoginAnomalyDetector;
+use crate::notifications::NotificationRouter;
+use crate::openpgp::OpenPgpManager;
+use crate::quota::QuotaManager;
+use crate::recovery::ReadOnlyMode;
+use crate::reporting::ReportingManager;
+use crate::role_accounts::RoleAccountManager;
+use crate::security::proxy_protocol::{is_trusted_proxy, read_proxy_header};
+use crate::security::{Authenticator, OAuthValidator, TlsConfig};
+use crate::sieve::SieveManager;
+use crate::smtp::budget::DeliveryBudget;
+use crate::smtp::loop_detection::DeliveryLog;
+use crate::smtp::postdelivery::PostDeliveryQueue;
+use crate::smtp::queue::SmtpQueue;
+use crate::smtp::routing::RoutingTable;
 use crate::smtp::session::SmtpSession;
+use crate::smtp::srs::Srs;
+use crate::spam::{QuarantineStore, RspamdClient, SpamBackend, SpamFilter, SpamManager};
 use crate::storage::MaildirStorage;
+use crate::tlsrpt::TlsRptManager;
+use crate::workers::{Subsystem, WorkerPools};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tracing::{error, info, warn};
 
@@ -12,15 +45,56 @@ pub struct SmtpServer {
     storage: Arc<MaildirStorage>,
     tls_config: Option<Arc<TlsConfig>>,
     authenticator: Option<Arc<Authenticator>>,
+    spf_validator: Option<Arc<SpfValidator>>,
+    dkim_validator: Option<Arc<DkimValidator>>,
+    routing: Arc<RoutingTable>,
+    oauth_validator: Option<Arc<OAuthValidator>>,
+    recipient_quotas: Option<Arc<QuotaManager>>,
+    notifications: Option<Arc<NotificationRouter>>,
+    delivery_budget: Arc<DeliveryBudget>,
+    // Single-instance deliveries of all sessions, for loop detection
+    delivery_log: Arc<DeliveryLog>,
+    devices: Option<Arc<DeviceManager>>,
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    post_delivery: Option<Arc<PostDeliveryQueue>>,
+    dnsbl: Option<Arc<DnsblChecker>>,
+    antivirus: Option<Arc<VirusScanner>>,
+    workers: Arc<WorkerPools>,
+    read_only: Arc<ReadOnlyMode>,
 }
 
 impl SmtpServer {
     pub fn new(config: Config, storage: Arc<MaildirStorage>) -> Self {
+        let (spf_validator, dkim_validator) =
+            SmtpSession::build_validators(&config.authentication);
+        let routing = Arc::new(RoutingTable::new(&config.routing.rules));
+        let oauth_validator = build_oauth_validator(&config);
+        let delivery_budget = Arc::new(DeliveryBudget::from_config(&config.delivery_budget));
+        let dnsbl = build_dnsbl_checker(&config);
+        let antivirus = build_virus_scanner(&config);
+        let read_only = Arc::new(ReadOnlyMode::from_config(&config.recovery));
+        let workers = Arc::new(WorkerPools::pausing_writers(&config.workers, read_only.clone()));
+
         Self {
             config,
             storage,
             tls_config: None,
             authenticator: None,
+            spf_validator,
+            dkim_validator,
+            routing,
+            oauth_validator,
+            recipient_quotas: None,
+            notifications: None,
+            delivery_budget,
+            delivery_log: Arc::new(DeliveryLog::default()),
+            devices: None,
+            login_anomalies: None,
+            post_delivery: None,
+            dnsbl,
+            antivirus,
+            workers,
+            read_only,
         }
     }
 
@@ -57,7 +131,7 @@ impl SmtpServer {
                 Some(db_url) => {
                     info!("Initializing SMTP authenticator");
                     match Authenticator::new(db_url).await {
-                        Ok(auth) => Some(Arc::new(auth)),
+                        Ok(auth) => Some(Arc::new(auth.with_cram_md5(config.smtp.allow_cram_md5))),
                         Err(e) => {
                             warn!("Failed to initialize authenticator: {}", e);
                             None
@@ -73,17 +147,142 @@ impl SmtpServer {
             None
         };
 
+        // Shared across sessions so SPF verdicts and DKIM keys stay cached
+        let (spf_validator, dkim_validator) =
+            SmtpSession::build_validators(&config.authentication);
+        let routing = Arc::new(RoutingTable::new(&config.routing.rules));
+        let oauth_validator = build_oauth_validator(&config);
+        let delivery_budget = Arc::new(DeliveryBudget::from_config(&config.delivery_budget));
+        let dnsbl = build_dnsbl_checker(&config);
+        let antivirus = build_virus_scanner(&config);
+        let read_only = Arc::new(ReadOnlyMode::from_config(&config.recovery));
+        let workers = Arc::new(WorkerPools::pausing_writers(&config.workers, read_only.clone()));
+
         Ok(Self {
             config,
             storage,
             tls_config,
             authenticator,
+            spf_validator,
+            dkim_validator,
+            routing,
+            oauth_validator,
+            recipient_quotas: None,
+            notifications: None,
+            delivery_budget,
+            delivery_log: Arc::new(DeliveryLog::default()),
+            devices: None,
+            login_anomalies: None,
+            post_delivery: None,
+            dnsbl,
+            antivirus,
+            workers,
+            read_only,
         })
     }
 
+    /// Check SPF and DKIM with these validators, e.g. ones whose cache
+    /// counters the API reports, instead of validators of its own
+    pub fn with_validators(
+        mut self,
+        spf_validator: Option<Arc<SpfValidator>>,
+        dkim_validator: Option<Arc<DkimValidator>>,
+    ) -> Self {
+        self.spf_validator = spf_validator;
+        self.dkim_validator = dkim_validator;
+        self
+    }
+
+    /// Check clients against this DNS blocklist checker, e.g. one whose
+    /// counters the API reports, instead of a checker of its own
+    pub fn with_dnsbl(mut self, checker: Arc<DnsblChecker>) -> Self {
+        self.dnsbl = Some(checker);
+        self
+    }
+
+    /// Scan messages with this scanner, e.g. one whose counters the API
+    /// reports, instead of a scanner of its own
+    pub fn with_antivirus(mut self, scanner: Arc<VirusScanner>) -> Self {
+        self.antivirus = Some(scanner);
+        self
+    }
+
+    /// Run background work in these pools, e.g. ones whose load the API
+    /// reports, instead of pools of its own
+    pub fn with_workers(mut self, workers: Arc<WorkerPools>) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Share this read-only flag, e.g. the one the API toggles, instead of
+    /// a flag of its own; pools given to [`Self::with_workers`] should
+    /// pause on the same flag
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = mode;
+        self
+    }
+
+    /// Use this TLS configuration for STARTTLS instead of the configured certificates
+    pub fn with_tls(mut self, tls_config: Arc<TlsConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Enable SMTP AUTH against this authenticator
+    pub fn with_authenticator(mut self, authenticator: Arc<Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Check sizes declared with MAIL FROM SIZE against local recipients'
+    /// storage quotas
+    pub fn with_recipient_quotas(mut self, quotas: Arc<QuotaManager>) -> Self {
+        self.recipient_quotas = Some(quotas);
+        self
+    }
+
+    /// Notify local recipients of delivered mail through this router
+    pub fn with_notifications(mut self, router: Arc<NotificationRouter>) -> Self {
+        self.notifications = Some(router);
+        self
+    }
+
+    /// Account pipeline usage in these budgets, e.g. to share them with the
+    /// admin API, instead of ones of this server alone
+    pub fn with_delivery_budget(mut self, budget: Arc<DeliveryBudget>) -> Self {
+        self.delivery_budget = budget;
+        self
+    }
+
+    /// Process delivered mail in these post-delivery workers, e.g. to share
+    /// them with the admin API, instead of ones started by this server
+    pub fn with_post_delivery(mut self, queue: Arc<PostDeliveryQueue>) -> Self {
+        self.post_delivery = Some(queue);
+        self
+    }
+
+    /// Record the clients of users that log in as devices and accept their
+    /// app passwords
+    pub fn with_devices(mut self, devices: Arc<DeviceManager>) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Refuse logins from unusual locations while the anomaly detector
+    /// blocks them
+    pub fn with_login_anomalies(mut self, detector: Arc<LoginAnomalyDetector>) -> Self {
+        self.login_anomalies = Some(detector);
+        self
+    }
+
     pub async fn run(&self) -> Result<()> {
         let listener = TcpListener::bind(&self.config.smtp.listen_addr).await?;
-        info!("SMTP server listening on {}", self.config.smtp.listen_addr);
+        self.serve(listener).await
+    }
+
+    /// Accept connections on an already bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("SMTP server listening on {}", listener.local_addr()?);
 
         // Log security features
         if self.tls_config.is_some() {
@@ -92,30 +291,127 @@ impl SmtpServer {
                 info!("TLS is REQUIRED for all connections (enforced before MAIL FROM)");
             }
         }
-        if self.authenticator.is_some() {
-            info!("SMTP AUTH support enabled (PLAIN, LOGIN)");
+        if self.config.smtp.proxy_protocol {
+            info!("PROXY protocol enabled, expecting header on every connection");
+        }
+        if self.oauth_validator.is_some() {
+            info!("OAuth bearer token authentication enabled (OAUTHBEARER, XOAUTH2)");
+        }
+        if let Some(authenticator) = &self.authenticator {
+            let cram_md5 = if authenticator.cram_md5_enabled() { ", CRAM-MD5" } else { "" };
+            info!("SMTP AUTH support enabled (PLAIN, LOGIN, SCRAM-SHA-256{})", cram_md5);
             if self.config.smtp.require_auth {
                 info!("Authentication is REQUIRED for sending mail");
             }
         }
 
+        let srs = if self.config.srs.enabled {
+            let srs = Arc::new(Srs::new(&self.config.srs, &self.config.server.domain)?);
+            info!("Sender Rewriting Scheme enabled for forwarded mail");
+            Some(srs)
+        } else {
+            None
+        };
+
+        if self.routing.has_forward_rules() {
+            info!("Inbound routing enabled with forward rules");
+        } else if !self.routing.is_empty() {
+            info!("Inbound routing enabled");
+        }
+
+        // Forward rules, SRS bounces and Sieve redirects need an outbound
+        // queue to relay through
+        let mut relayed = Vec::new();
+        if self.routing.has_forward_rules() {
+            relayed.push("forward rules");
+        }
+        if srs.is_some() {
+            relayed.push("SRS bounces");
+        }
+        if self.config.sieve.enabled {
+            relayed.push("Sieve redirects");
+        }
+        let relay_queue = if relayed.is_empty() {
+            None
+        } else {
+            let mut queue = SmtpQueue::from_config(&self.config).await?;
+            if let Some(tls_reporting) = build_tls_reporting(&self.config).await? {
+                queue = queue.with_tls_reporting(tls_reporting);
+            }
+            if let Some(router) = &self.notifications {
+                queue = queue.with_quarantine_alerts(
+                    router.clone(),
+                    vec![format!("postmaster@{}", self.config.server.domain)],
+                );
+            }
+            let queue = Arc::new(queue);
+            let pool = self.workers.pool(Subsystem::Delivery).clone();
+            tokio::spawn(queue.clone().start_worker_in(Some(pool)));
+            info!("Outbound relay queue started for {}", relayed.join(", "));
+            Some(queue)
+        };
+
+        let roles = build_role_account_manager(&self.config).await?;
+        if let (Some(roles), Some(authenticator)) = (&roles, &self.authenticator) {
+            provision_user_domains(roles, authenticator).await;
+        }
+        let post_delivery = self.post_delivery.clone().or_else(|| {
+            self.config.post_delivery.enabled.then(|| {
+                let queue = PostDeliveryQueue::start(&self.config.post_delivery, self.storage.clone());
+                queue.run_in(self.workers.clone());
+                queue
+            })
+        });
+        let components = SessionComponents {
+            srs,
+            relay_queue,
+            reporting: build_reporting_manager(&self.config).await?,
+            billing: build_billing_manager(&self.config).await?,
+            impersonation: build_impersonation_guard(&self.config).await?,
+            aliases: build_alias_manager(&self.config).await?,
+            sieve: build_sieve_manager(&self.config).await?,
+            spam: build_spam_filter(&self.config).await?,
+            greylist: build_greylist_manager(&self.config).await?,
+            dmarc_reports: build_dmarc_report_manager(&self.config).await?,
+            invitations: build_invitation_manager(&self.config).await?,
+            roles,
+            hooks: build_hook_manager(&self.config).await?,
+            vacations: build_vacation_tracker(&self.config).await?,
+            post_delivery,
+        };
+
         loop {
             match listener.accept().await {
-                Ok((socket, addr)) => {
+                Ok((mut socket, addr)) => {
                     info!("New SMTP connection from {}", addr);
 
-                    let session = SmtpSession::with_security(
-                        self.config.server.hostname.clone(),
-                        self.storage.clone(),
-                        self.config.smtp.max_message_size,
-                        self.tls_config.clone(),
-                        self.authenticator.clone(),
-                        self.config.smtp.require_auth,
-                        self.config.smtp.require_tls,
-                        self.config.authentication.clone(),
-                    );
+                    let proxy_protocol = self.config.smtp.proxy_protocol;
+                    if proxy_protocol
+                        && !is_trusted_proxy(addr.ip(), &self.config.smtp.proxy_trusted_ips)
+                    {
+                        warn!("Rejecting PROXY connection from untrusted peer {}", addr);
+                        continue;
+                    }
+
+                    let session = self.new_session(&components);
 
                     tokio::spawn(async move {
+                        let session = if proxy_protocol {
+                            match read_proxy_header(&mut socket).await {
+                                Ok(header) => {
+                                    let client = header.client_addr(addr);
+                                    info!("PROXY header: client {} via {}", client, addr);
+                                    session.with_client_ip(client.ip())
+                                }
+                                Err(e) => {
+                                    warn!("Invalid PROXY header from {}: {}", addr, e);
+                                    return;
+                                }
+                            }
+                        } else {
+                            session
+                        };
+
                         if let Err(e) = session.handle(socket).await {
                             error!("Session error: {}", e);
                         }
@@ -127,4 +423,496 @@ impl SmtpServer {
             }
         }
     }
+
+    /// Session for a new connection, with the server's components and
+    /// those built in `components`
+    fn new_session(&self, components: &SessionComponents) -> SmtpSession {
+        let mut session = SmtpSession::with_security(
+            self.config.server.hostname.clone(),
+            self.storage.clone(),
+            self.config.smtp.max_message_size,
+            self.tls_config.clone(),
+            self.authenticator.clone(),
+            self.config.smtp.require_auth,
+            self.config.smtp.require_tls,
+            self.config.authentication.clone(),
+        )
+        .with_validators(self.spf_validator.clone(), self.dkim_validator.clone())
+        .with_routing(self.routing.clone(), components.relay_queue.clone())
+        .with_greet_pause(Duration::from_secs(self.config.smtp.greet_pause_secs))
+        .with_loop_detection(
+            self.config.smtp.max_hops,
+            Some(format!("postmaster@{}", self.config.server.domain)),
+        )
+        .with_delivery_budget(self.delivery_budget.clone())
+        .with_delivery_log(self.delivery_log.clone())
+        .with_workers(self.workers.clone())
+        .with_read_only(self.read_only.clone());
+        if let Some(validator) = &self.oauth_validator {
+            session = session.with_oauth(validator.clone());
+        }
+        if let Some(quotas) = &self.recipient_quotas {
+            session = session.with_recipient_quotas(quotas.clone());
+        }
+        if let Some(router) = &self.notifications {
+            session = session.with_notifications(router.clone());
+        }
+        if let Some(devices) = &self.devices {
+            session = session.with_devices(devices.clone());
+        }
+        if let Some(detector) = &self.login_anomalies {
+            session = session.with_login_anomalies(detector.clone());
+        }
+        if let Some(checker) = &self.dnsbl {
+            session = session.with_dnsbl(checker.clone());
+        }
+        if let Some(scanner) = &self.antivirus {
+            session = session.with_antivirus(scanner.clone());
+        }
+        if let Some(srs) = &components.srs {
+            session = session.with_srs(srs.clone());
+        }
+        if let Some(reporting) = &components.reporting {
+            session = session.with_reporting(reporting.clone());
+        }
+        if let Some(billing) = &components.billing {
+            session = session.with_billing(billing.clone());
+        }
+        if let Some(guard) = &components.impersonation {
+            session = session.with_impersonation_guard(guard.clone());
+        }
+        if let Some(aliases) = &components.aliases {
+            session = session.with_aliases(aliases.clone());
+        }
+        if let Some(sieve) = &components.sieve {
+            session = session.with_sieve(sieve.clone());
+        }
+        if let Some(roles) = &components.roles {
+            session = session.with_role_accounts(roles.clone());
+        }
+        if let Some(filter) = &components.spam {
+            session = session.with_spam_filter(filter.clone());
+        }
+        if let Some(manager) = &components.greylist {
+            session = session.with_greylist(manager.clone());
+        }
+        if let Some(manager) = &components.dmarc_reports {
+            session = session.with_dmarc_reports(manager.clone());
+        }
+        if let Some(manager) = &components.invitations {
+            session = session.with_invitations(manager.clone());
+        }
+        if let Some(hooks) = &components.hooks {
+            session = session.with_hooks(hooks.clone());
+        }
+        if let Some(tracker) = &components.vacations {
+            session = session.with_vacations(tracker.clone());
+        }
+        if let Some(queue) = &components.post_delivery {
+            session = session.with_post_delivery(queue.clone());
+        }
+        session
+    }
+}
+
+/// Optional components built when the server starts serving, shared by
+/// every session
+struct SessionComponents {
+    srs: Option<Arc<Srs>>,
+    /// Queue relaying forwarded, bounced and redirected mail
+    relay_queue: Option<Arc<SmtpQueue>>,
+    reporting: Option<Arc<ReportingManager>>,
+    billing: Option<Arc<BillingManager>>,
+    impersonation: Option<Arc<ImpersonationGuard>>,
+    aliases: Option<Arc<AliasManager>>,
+    sieve: Option<Arc<SieveManager>>,
+    spam: Option<Arc<SpamFilter>>,
+    greylist: Option<Arc<GreylistManager>>,
+    dmarc_reports: Option<Arc<DmarcReportManager>>,
+    invitations: Option<Arc<CalDavManager>>,
+    roles: Option<Arc<RoleAccountManager>>,
+    hooks: Option<Arc<HookManager>>,
+    vacations: Option<Arc<AutoReplyManager>>,
+    post_delivery: Option<Arc<PostDeliveryQueue>>,
+}
+
+/// Create the shared OAuth token validator if enabled in the config
+pub(crate) fn build_oauth_validator(config: &Config) -> Option<Arc<OAuthValidator>> {
+    config
+        .oauth
+        .enabled
+        .then(|| Arc::new(OAuthValidator::new(config.oauth.clone())))
+}
+
+/// Open the usage reporting database if reporting is enabled in the config
+pub(crate) async fn build_reporting_manager(
+    config: &Config,
+) -> Result<Option<Arc<ReportingManager>>> {
+    if !config.reporting.enabled {
+        return Ok(None);
+    }
+
+    let manager = ReportingManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open reporting database: {}", e)))?;
+    info!("Usage reporting enabled");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the billing database if billing metrics are enabled in the config
+pub(crate) async fn build_billing_manager(config: &Config) -> Result<Option<Arc<BillingManager>>> {
+    if !config.billing.enabled {
+        return Ok(None);
+    }
+
+    let manager = BillingManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open billing database: {}", e)))?;
+    info!("Billing metrics enabled");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// DNS blocklist checker if enabled in the config with at least one list
+pub(crate) fn build_dnsbl_checker(config: &Config) -> Option<Arc<DnsblChecker>> {
+    if !config.dnsbl.enabled {
+        return None;
+    }
+    if config.dnsbl.lists.is_empty() {
+        warn!("DNSBL checks enabled without lists");
+        return None;
+    }
+
+    let zones: Vec<&str> = config.dnsbl.lists.iter().map(|list| list.zone.as_str()).collect();
+    info!("DNSBL checks enabled: {}", zones.join(", "));
+    let scored = config.spam.enabled && config.spam.backend == SpamBackend::Builtin;
+    if !scored && config.dnsbl.lists.iter().any(|list| list.action == DnsblAction::Score) {
+        warn!("Scoring DNSBL listings has no effect without the built-in [spam] scorer");
+    }
+    Some(Arc::new(DnsblChecker::from_config(&config.dnsbl)))
+}
+
+/// URI blocklist checker if enabled in the config with at least one list
+pub(crate) fn build_uribl_checker(config: &Config) -> Option<Arc<UriblChecker>> {
+    if !config.uribl.enabled {
+        return None;
+    }
+    if config.uribl.lists.is_empty() {
+        warn!("URIBL checks enabled without lists");
+        return None;
+    }
+    if config.spam.backend != SpamBackend::Builtin {
+        warn!("URIBL checks have no effect with the rspamd backend");
+        return None;
+    }
+
+    let zones: Vec<&str> = config.uribl.lists.iter().map(|list| list.zone.as_str()).collect();
+    info!("URIBL checks enabled: {}", zones.join(", "));
+    Some(Arc::new(UriblChecker::from_config(&config.uribl)))
+}
+
+/// clamd scanner if virus scanning is enabled in the config
+pub(crate) fn build_virus_scanner(config: &Config) -> Option<Arc<VirusScanner>> {
+    if !config.antivirus.enabled {
+        return None;
+    }
+    let scanner = VirusScanner::from_config(&config.antivirus);
+    let action = match scanner.action() {
+        VirusAction::Reject => "rejecting",
+        VirusAction::Quarantine => "quarantining",
+    };
+    info!("Virus scanning enabled with clamd at {}, {} infected mail", scanner.address(), action);
+    if scanner.fail_open() {
+        info!("Mail is delivered unscanned while clamd is unreachable");
+    }
+    Some(Arc::new(scanner))
+}
+
+/// Open the impersonation policies if the check is enabled in the config
+pub(crate) async fn build_impersonation_guard(
+    config: &Config,
+) -> Result<Option<Arc<ImpersonationGuard>>> {
+    if !config.impersonation.enabled {
+        return Ok(None);
+    }
+
+    let guard = ImpersonationGuard::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open impersonation database: {}", e)))?;
+    info!("Display-name impersonation check enabled");
+    Ok(Some(Arc::new(guard)))
+}
+
+/// Open the footer database if footers are enabled in the config
+pub(crate) async fn build_footer_manager(config: &Config) -> Result<Option<Arc<FooterManager>>> {
+    if !config.footers.enabled {
+        return Ok(None);
+    }
+
+    let manager = FooterManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open footer database: {}", e)))?;
+    info!("Footers of outgoing mail enabled");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the burner alias database if aliases are enabled in the config
+pub(crate) async fn build_alias_manager(config: &Config) -> Result<Option<Arc<AliasManager>>> {
+    if !config.aliases.enabled {
+        return Ok(None);
+    }
+
+    let manager = AliasManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open alias database: {}", e)))?;
+    info!("Burner aliases enabled");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the Sieve scripts if filtering of delivered mail is enabled in the
+/// config
+pub(crate) async fn build_sieve_manager(config: &Config) -> Result<Option<Arc<SieveManager>>> {
+    if !config.sieve.enabled {
+        return Ok(None);
+    }
+
+    let manager = SieveManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open Sieve database: {}", e)))?;
+    info!("Sieve filtering of delivered mail enabled");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the spam rules if scoring of delivered mail is enabled in the
+/// config
+pub(crate) async fn build_spam_filter(config: &Config) -> Result<Option<Arc<SpamFilter>>> {
+    if !config.spam.enabled {
+        return Ok(None);
+    }
+
+    let manager = SpamManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open spam database: {}", e)))?;
+    let filter =
+        SpamFilter::new(Arc::new(manager)).with_reject_threshold(config.spam.reject_threshold);
+    let filter = match config.spam.backend {
+        SpamBackend::Builtin => {
+            info!(
+                "Spam scoring of delivered mail enabled (reject at {:.1})",
+                config.spam.reject_threshold
+            );
+            filter
+        }
+        SpamBackend::Rspamd => {
+            let client = RspamdClient::from_config(&config.spam.rspamd);
+            info!("Spam scoring of delivered mail by rspamd at {}", client.url());
+            filter.with_rspamd(client)
+        }
+    };
+    let filter = match build_uribl_checker(config) {
+        Some(checker) => filter.with_uribl(checker),
+        None => filter,
+    };
+    let filter = match build_quarantine(config).await? {
+        Some(store) => filter.with_quarantine(store),
+        None => filter,
+    };
+    Ok(Some(Arc::new(filter)))
+}
+
+/// Open the greylist if greylisting is enabled in the config
+pub(crate) async fn build_greylist_manager(
+    config: &Config,
+) -> Result<Option<Arc<GreylistManager>>> {
+    if !config.greylisting.enabled {
+        return Ok(None);
+    }
+
+    let manager =
+        GreylistManager::connect_with(&config.api_database_url(), GreylistConfig::from(&config.greylisting))
+            .await
+            .map_err(|e| MailError::Storage(format!("Failed to open greylist database: {}", e)))?;
+    info!(
+        "Greylisting enabled ({}s delay{})",
+        config.greylisting.delay_seconds,
+        if config.greylisting.bypass_authenticated {
+            ", SPF/DKIM pass bypasses"
+        } else {
+            ""
+        }
+    );
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the received report store if ingestion of aggregate reports is
+/// enabled in the config
+pub(crate) async fn build_dmarc_report_manager(
+    config: &Config,
+) -> Result<Option<Arc<DmarcReportManager>>> {
+    let reports = &config.dmarc_reports;
+    if !reports.enabled {
+        return Ok(None);
+    }
+    if reports.addresses.is_empty() {
+        warn!("DMARC report ingestion is enabled without addresses");
+        return Ok(None);
+    }
+
+    let manager = DmarcReportManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open DMARC report database: {}", e)))?
+        .with_addresses(&reports.addresses)
+        .with_retention_days(reports.retention_days);
+    info!(
+        "Ingesting aggregate reports sent to {}",
+        reports.addresses.join(", ")
+    );
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the calendars if processing of calendar invitations is enabled in
+/// the config
+pub(crate) async fn build_invitation_manager(
+    config: &Config,
+) -> Result<Option<Arc<CalDavManager>>> {
+    if !config.calendar_invitations.enabled {
+        return Ok(None);
+    }
+
+    let manager = CalDavManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open calendar database: {}", e)))?;
+    info!("Adding calendar invitations to the calendars of recipients");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the spam quarantine if spam scoring and the quarantine are enabled
+/// in the config
+pub(crate) async fn build_quarantine(config: &Config) -> Result<Option<Arc<QuarantineStore>>> {
+    if !config.spam.enabled || !config.spam.quarantine.enabled {
+        return Ok(None);
+    }
+
+    let store = QuarantineStore::connect(&config.api_database_url(), &config.spam.quarantine)
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open spam quarantine: {}", e)))?;
+    info!(
+        "Spam quarantine enabled (kept {} days)",
+        config.spam.quarantine.retention_days
+    );
+    Ok(Some(Arc::new(store)))
+}
+
+/// Open the DKIM keys if signing is enabled in the config
+pub(crate) async fn build_dkim_key_manager(
+    config: &Config,
+) -> Result<Option<Arc<DkimKeyManager>>> {
+    if !config.dkim_signing.enabled {
+        return Ok(None);
+    }
+
+    let manager = DkimKeyManager::connect(&config.api_database_url(), &config.dkim_signing)
+        .await
+        .map_err(|e| MailError::Config(format!("Failed to open DKIM keys: {}", e)))?;
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the OpenPGP keys of users if OpenPGP is enabled in the config
+pub(crate) async fn build_openpgp_manager(config: &Config) -> Result<Option<Arc<OpenPgpManager>>> {
+    if !config.openpgp.enabled {
+        return Ok(None);
+    }
+
+    let manager = OpenPgpManager::connect(&config.api_database_url(), &config.openpgp)
+        .await
+        .map_err(|e| MailError::Config(format!("Failed to open OpenPGP keys: {}", e)))?;
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the role accounts if they are enabled in the config, provisioning
+/// those of `server.domain`
+pub(crate) async fn build_role_account_manager(
+    config: &Config,
+) -> Result<Option<Arc<RoleAccountManager>>> {
+    if !config.role_accounts.enabled {
+        return Ok(None);
+    }
+
+    let mut manager = RoleAccountManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open role account database: {}", e)))?
+        .with_quarantine(config.role_accounts.quarantine);
+    if let Some(mailbox) = &config.role_accounts.mailbox {
+        manager = manager.with_mailbox(mailbox);
+    }
+    manager
+        .provision(&config.server.domain)
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to provision role accounts: {}", e)))?;
+    info!("Role accounts enabled");
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Provision the role accounts of every domain with users
+async fn provision_user_domains(roles: &RoleAccountManager, authenticator: &Authenticator) {
+    let users = match authenticator.list_users().await {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Failed to list users for role accounts: {}", e);
+            return;
+        }
+    };
+    let mut domains: Vec<String> = users
+        .into_iter()
+        .filter_map(|(_, email, _)| email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase()))
+        .collect();
+    domains.sort();
+    domains.dedup();
+    for domain in domains {
+        match roles.provision(&domain).await {
+            Ok(created) if !created.is_empty() => {
+                info!("Provisioned {} role accounts of {}", created.len(), domain)
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to provision role accounts of {}: {}", domain, e),
+        }
+    }
+}
+
+/// Open the delivery hooks if they are enabled in the config
+pub(crate) async fn build_hook_manager(config: &Config) -> Result<Option<Arc<HookManager>>> {
+    if !config.hooks.enabled {
+        return Ok(None);
+    }
+
+    let manager = HookManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open hook database: {}", e)))?;
+    info!("Delivery hooks enabled");
+    Ok(Some(Arc::new(manager.with_limits(config.hooks.limits()))))
+}
+
+/// Tracking of Sieve vacation responses, shared with auto-replies
+pub(crate) async fn build_vacation_tracker(
+    config: &Config,
+) -> Result<Option<Arc<AutoReplyManager>>> {
+    if !config.sieve.enabled {
+        return Ok(None);
+    }
+
+    let manager = AutoReplyManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open auto-reply database: {}", e)))?;
+    Ok(Some(Arc::new(manager)))
+}
+
+/// Open the TLS-RPT database if TLS reporting is enabled in the config
+pub(crate) async fn build_tls_reporting(config: &Config) -> Result<Option<Arc<TlsRptManager>>> {
+    if !config.tls_reporting.enabled {
+        return Ok(None);
+    }
+
+    let manager = TlsRptManager::connect(&config.api_database_url())
+        .await
+        .map_err(|e| MailError::Storage(format!("Failed to open TLS reporting database: {}", e)))?;
+    Ok(Some(Arc::new(manager)))
 }
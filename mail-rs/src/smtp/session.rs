@@ -1,11 +1,47 @@
+use crate::aliases::{AliasDelivery, AliasManager};
+use crate::caldav::{itip, CalDavManager};
+use crate::devices::{DeviceKind, DeviceManager};
+use crate::dmarc_reports::DmarcReportManager;
+use crate::login_anomaly::{Decision, LoginAnomalyDetector, LoginProtocol};
+use crate::antispam::dnsbl::{self, DnsblChecker, DnsblStage, DnsblVerdict};
+use crate::antispam::{impersonation, GreylistManager, GreylistStatus, ImpersonationGuard};
+use crate::antivirus::{scanner as antivirus, ScanResult, VirusAction, VirusScanner};
 use crate::authentication::{DkimValidator, SpfValidator};
-use crate::auto_reply::AutoReplySender;
+use crate::auto_reply::{AutoReplyManager, AutoReplySender};
 use crate::config::AuthenticationConfig;
 use crate::error::{MailError, Result};
+use crate::footers::{self, FooterManager, FooterResult};
+use crate::hooks::{HookManager, HookOutcome};
+use crate::imap::special_use::SpecialUse;
+use crate::mime::decode_header;
+use crate::notifications::{EventKind, NotificationRouter};
+use crate::quota::{QuotaManager, QuotaStatus};
+use crate::recovery::{self, ReadOnlyMode};
+use crate::billing::{BillingManager, BillingMetric};
+use crate::reporting::{ReportingManager, UsageEventKind};
+use crate::role_accounts::{RoleAccountManager, RoleTarget};
+use crate::security::oauth::{self, OAuthValidator};
+use crate::security::sasl::{self, ScramServer};
 use crate::security::{AuthMechanism, Authenticator, TlsConfig};
+use crate::sieve::{vacation, MessageContext, SieveDelivery, SieveManager, VacationConfig};
+use crate::spam::{delivery as spam, SpamClient, SpamFilter, SpamVerdict};
+use crate::smtp::budget::{self, DeliveryBudget, StageOutcome};
 use crate::smtp::commands::SmtpCommand;
+use crate::smtp::ingress::{self, Ingress, IngressListener};
+use crate::smtp::loop_detection::{self, DeliveryLog, MailLoop};
+use crate::smtp::postdelivery::{self, PostDeliveryJob, PostDeliveryQueue};
+use crate::smtp::queue::SmtpQueue;
+use crate::smtp::requiretls::TlsRequirement;
+use crate::smtp::trace::{self, Protocol, TraceInfo};
+use crate::smtp::routing::{RouteAction, RoutingTable};
+use crate::smtp::srs::Srs;
 use crate::storage::MaildirStorage;
+use crate::utils::dkim_signer::DkimSigner;
 use crate::utils::validate_email;
+use crate::workers::{Subsystem, WorkerPools};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -123,7 +159,17 @@ enum SessionResult {
 pub struct SmtpSession {
     state: SmtpState,
     from: Option<String>,
+    /// REQUIRETLS given on MAIL FROM for the current message (RFC 8689)
+    message_requires_tls: bool,
+    /// SIZE given on MAIL FROM for the current message (RFC 1870)
+    declared_size: Option<u64>,
     to: Vec<String>,
+    /// Owner and handling of each burner alias among the recipients
+    alias_targets: HashMap<String, (String, AliasDelivery)>,
+    /// Delivery of each role address among the recipients
+    role_targets: HashMap<String, RoleTarget>,
+    /// What delivery hooks did to the current message, per local recipient
+    hook_outcomes: HashMap<String, HookOutcome>,
     data: Vec<u8>,
     hostname: String,
     storage: Arc<MaildirStorage>,
@@ -141,8 +187,80 @@ pub struct SmtpSession {
     dkim_validator: Option<Arc<DkimValidator>>,
     client_ip: Option<IpAddr>,
     helo_domain: Option<String>,
+    // Trace headers: greeting protocol, client PTR name and TLS parameters
+    protocol: Protocol,
+    client_hostname: Option<String>,
+    tls_info: Option<String>,
+    // Listener named in the ingress header of stored mail
+    listener: IngressListener,
     // Auto-reply
     auto_reply_sender: Option<Arc<AutoReplySender>>,
+    // Inbound routing
+    routing: Option<Arc<RoutingTable>>,
+    relay_queue: Option<Arc<SmtpQueue>>,
+    srs: Option<Arc<Srs>>,
+    // Submission (RFC 6409): outbound queue instead of local storage
+    outbound_queue: Option<Arc<SmtpQueue>>,
+    dkim_signer: Option<Arc<DkimSigner>>,
+    quota_manager: Option<Arc<QuotaManager>>,
+    // Storage quotas of local recipients, checked against SIZE
+    recipient_quotas: Option<Arc<QuotaManager>>,
+    // New mail notifications for local recipients
+    notifications: Option<Arc<NotificationRouter>>,
+    // OAuth 2.0 bearer token authentication
+    oauth_validator: Option<Arc<OAuthValidator>>,
+    // Usage reporting
+    reporting: Option<Arc<ReportingManager>>,
+    // Per-user billing counters
+    billing: Option<Arc<BillingManager>>,
+    // Burner aliases delivering to their owners
+    aliases: Option<Arc<AliasManager>>,
+    // postmaster@ and abuse@ of hosted domains
+    roles: Option<Arc<RoleAccountManager>>,
+    // Display-name impersonation of protected internal names
+    impersonation: Option<Arc<ImpersonationGuard>>,
+    // Per-user time and IO budgets of the delivery pipeline
+    budget: Option<Arc<DeliveryBudget>>,
+    // Client devices and app passwords of users
+    devices: Option<Arc<DeviceManager>>,
+    // Assesses logins against the places users signed in from
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    // Footers of sender domains appended to submitted mail
+    footers: Option<Arc<FooterManager>>,
+    // Active Sieve scripts of local recipients
+    sieve: Option<Arc<SieveManager>>,
+    // Spam scoring of mail from unauthenticated clients
+    spam: Option<Arc<SpamFilter>>,
+    // Sandboxed hooks of local recipients' domains and mailboxes
+    hooks: Option<Arc<HookManager>>,
+    // Senders answered by Sieve vacation responses
+    vacations: Option<Arc<AutoReplyManager>>,
+    // Workers parsing, indexing and summarizing stored mail after the reply
+    post_delivery: Option<Arc<PostDeliveryQueue>>,
+    // Delay before the greeting; clients talking first are dropped
+    greet_pause: Option<Duration>,
+    // DNS blocklists checked against the client address, and the lists it
+    // is on
+    dnsbl: Option<Arc<DnsblChecker>>,
+    dnsbl_verdict: DnsblVerdict,
+    // Greylisting of unknown sender, recipient and client IP triplets
+    greylist: Option<Arc<GreylistManager>>,
+    // Ingestion of aggregate reports sent to our rua addresses
+    dmarc_reports: Option<Arc<DmarcReportManager>>,
+    invitations: Option<Arc<CalDavManager>>,
+    // clamd scanning of received messages
+    antivirus: Option<Arc<VirusScanner>>,
+    // Pools of background work; without them, tasks are spawned unbounded
+    workers: Option<Arc<WorkerPools>>,
+    // Read-only mode deferring mail, entered on storage failures
+    read_only: Option<Arc<ReadOnlyMode>>,
+    // Mail loop detection: Received hop limit and alert recipient
+    max_hops: usize,
+    loop_alert_to: Option<String>,
+    // Single-instance deliveries stored without Delivered-To, and the id of
+    // the Received header of the current message they are logged under
+    delivery_log: Option<Arc<DeliveryLog>>,
+    received_id: Option<String>,
 }
 
 impl SmtpSession {
@@ -152,24 +270,17 @@ impl SmtpSession {
         max_message_size: usize,
         auth_config: AuthenticationConfig,
     ) -> Self {
-        // Initialize SPF validator if enabled
-        let spf_validator = if auth_config.spf_enabled {
-            Some(Arc::new(SpfValidator::new()))
-        } else {
-            None
-        };
-
-        // Initialize DKIM validator if enabled
-        let dkim_validator = if auth_config.dkim_validate_incoming {
-            Some(Arc::new(DkimValidator::new()))
-        } else {
-            None
-        };
+        let (spf_validator, dkim_validator) = Self::build_validators(&auth_config);
 
         Self {
             state: SmtpState::Fresh,
             from: None,
+            message_requires_tls: false,
+            declared_size: None,
             to: Vec::new(),
+            alias_targets: HashMap::new(),
+            role_targets: HashMap::new(),
+            hook_outcomes: HashMap::new(),
             data: Vec::new(),
             hostname,
             storage,
@@ -186,11 +297,54 @@ impl SmtpSession {
             dkim_validator,
             client_ip: None,
             helo_domain: None,
+            protocol: Protocol::Esmtp,
+            client_hostname: None,
+            tls_info: None,
+            listener: IngressListener::Mx,
             auto_reply_sender: None,
+            routing: None,
+            relay_queue: None,
+            srs: None,
+            outbound_queue: None,
+            dkim_signer: None,
+            quota_manager: None,
+            recipient_quotas: None,
+            notifications: None,
+            oauth_validator: None,
+            reporting: None,
+            billing: None,
+            aliases: None,
+            roles: None,
+            impersonation: None,
+            budget: None,
+            devices: None,
+            login_anomalies: None,
+            footers: None,
+            sieve: None,
+            spam: None,
+            hooks: None,
+            vacations: None,
+            post_delivery: None,
+            greet_pause: None,
+            dnsbl: None,
+            dnsbl_verdict: DnsblVerdict::default(),
+            greylist: None,
+            dmarc_reports: None,
+            invitations: None,
+            antivirus: None,
+            workers: None,
+            read_only: None,
+            max_hops: loop_detection::DEFAULT_MAX_HOPS,
+            loop_alert_to: None,
+            delivery_log: None,
+            received_id: None,
         }
     }
 
     /// Create session with TLS and Auth support
+    ///
+    /// SPF/DKIM validators are not created here: the server builds them once
+    /// and attaches them with [`SmtpSession::with_validators`].
     pub fn with_security(
         hostname: String,
         storage: Arc<MaildirStorage>,
@@ -201,24 +355,15 @@ impl SmtpSession {
         require_tls: bool,
         auth_config: AuthenticationConfig,
     ) -> Self {
-        // Initialize SPF validator if enabled
-        let spf_validator = if auth_config.spf_enabled {
-            Some(Arc::new(SpfValidator::new()))
-        } else {
-            None
-        };
-
-        // Initialize DKIM validator if enabled
-        let dkim_validator = if auth_config.dkim_validate_incoming {
-            Some(Arc::new(DkimValidator::new()))
-        } else {
-            None
-        };
-
         Self {
             state: SmtpState::Fresh,
             from: None,
+            message_requires_tls: false,
+            declared_size: None,
             to: Vec::new(),
+            alias_targets: HashMap::new(),
+            role_targets: HashMap::new(),
+            hook_outcomes: HashMap::new(),
             data: Vec::new(),
             hostname,
             storage,
@@ -231,26 +376,384 @@ impl SmtpSession {
             require_auth,
             require_tls,
             auth_config,
-            spf_validator,
-            dkim_validator,
+            spf_validator: None,
+            dkim_validator: None,
             client_ip: None,
             helo_domain: None,
+            protocol: Protocol::Esmtp,
+            client_hostname: None,
+            tls_info: None,
+            listener: IngressListener::Mx,
             auto_reply_sender: None,
+            routing: None,
+            relay_queue: None,
+            srs: None,
+            outbound_queue: None,
+            dkim_signer: None,
+            quota_manager: None,
+            recipient_quotas: None,
+            notifications: None,
+            oauth_validator: None,
+            reporting: None,
+            billing: None,
+            aliases: None,
+            roles: None,
+            impersonation: None,
+            budget: None,
+            devices: None,
+            login_anomalies: None,
+            footers: None,
+            sieve: None,
+            spam: None,
+            hooks: None,
+            vacations: None,
+            post_delivery: None,
+            greet_pause: None,
+            dnsbl: None,
+            dnsbl_verdict: DnsblVerdict::default(),
+            greylist: None,
+            dmarc_reports: None,
+            invitations: None,
+            antivirus: None,
+            workers: None,
+            read_only: None,
+            max_hops: loop_detection::DEFAULT_MAX_HOPS,
+            loop_alert_to: None,
+            delivery_log: None,
+            received_id: None,
         }
     }
 
+    /// Create SPF/DKIM validators according to the authentication config
+    ///
+    /// Servers should build these once and share them across sessions with
+    /// [`SmtpSession::with_validators`] so their caches are reused.
+    pub fn build_validators(
+        auth_config: &AuthenticationConfig,
+    ) -> (Option<Arc<SpfValidator>>, Option<Arc<DkimValidator>>) {
+        let cache_ttl = Duration::from_secs(auth_config.auth_cache_ttl_secs);
+
+        // Initialize SPF validator if enabled
+        let spf_validator = if auth_config.spf_enabled {
+            Some(Arc::new(SpfValidator::with_cache_ttl(cache_ttl)))
+        } else {
+            None
+        };
+
+        // Initialize DKIM validator if enabled
+        let dkim_validator = if auth_config.dkim_validate_incoming {
+            Some(Arc::new(DkimValidator::with_cache_ttl(cache_ttl)))
+        } else {
+            None
+        };
+
+        (spf_validator, dkim_validator)
+    }
+
+    /// Use shared SPF/DKIM validators instead of per-session instances
+    pub fn with_validators(
+        mut self,
+        spf_validator: Option<Arc<SpfValidator>>,
+        dkim_validator: Option<Arc<DkimValidator>>,
+    ) -> Self {
+        self.spf_validator = spf_validator;
+        self.dkim_validator = dkim_validator;
+        self
+    }
+
     /// Set auto-reply sender for this session
     pub fn with_auto_reply(mut self, sender: Arc<AutoReplySender>) -> Self {
         self.auto_reply_sender = Some(sender);
         self
     }
 
+    /// Apply an inbound routing table
+    ///
+    /// `relay_queue` is required for forward rules; without it forwarded
+    /// recipients are deferred with a temporary failure.
+    pub fn with_routing(
+        mut self,
+        routing: Arc<RoutingTable>,
+        relay_queue: Option<Arc<SmtpQueue>>,
+    ) -> Self {
+        self.routing = Some(routing);
+        self.relay_queue = relay_queue;
+        self
+    }
+
+    /// Rewrite senders of forwarded mail and accept bounces to SRS addresses
+    ///
+    /// Bounces are relayed to the original sender, so this needs the relay
+    /// queue from [`Self::with_routing`].
+    pub fn with_srs(mut self, srs: Arc<Srs>) -> Self {
+        self.srs = Some(srs);
+        self
+    }
+
+    /// Record deliveries, submissions, spam and failed logins for usage reports
+    pub fn with_reporting(mut self, reporting: Arc<ReportingManager>) -> Self {
+        self.reporting = Some(reporting);
+        self
+    }
+
+    /// Count messages received by local mailboxes and messages submitted by
+    /// authenticated users for billing
+    pub fn with_billing(mut self, billing: Arc<BillingManager>) -> Self {
+        self.billing = Some(billing);
+        self
+    }
+
+    /// Deliver mail sent to burner aliases to their owners, refuse it for
+    /// blocked or expired aliases and discard it for muted ones
+    pub fn with_aliases(mut self, aliases: Arc<AliasManager>) -> Self {
+        self.aliases = Some(aliases);
+        self
+    }
+
+    /// Deliver mail to the provisioned role addresses of hosted domains to
+    /// their admin mailbox; spam filtering only tags it
+    pub fn with_role_accounts(mut self, roles: Arc<RoleAccountManager>) -> Self {
+        self.roles = Some(roles);
+        self
+    }
+
+    /// Run the active Sieve script of each local recipient on delivery;
+    /// redirects go through the relay queue from [`Self::with_routing`]
+    pub fn with_sieve(mut self, sieve: Arc<SieveManager>) -> Self {
+        self.sieve = Some(sieve);
+        self
+    }
+
+    /// Score mail from unauthenticated clients, filing spam into the Junk
+    /// folder of recipients with quarantine enabled and refusing mail above
+    /// the hard threshold
+    pub fn with_spam_filter(mut self, filter: Arc<SpamFilter>) -> Self {
+        self.spam = Some(filter);
+        self
+    }
+
+    /// Run the delivery hooks of each local recipient's domain and
+    /// mailbox before Sieve
+    pub fn with_hooks(mut self, hooks: Arc<HookManager>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Send the vacation responses of Sieve scripts through the relay
+    /// queue, at most once per sender and interval as tracked here
+    pub fn with_vacations(mut self, tracker: Arc<AutoReplyManager>) -> Self {
+        self.vacations = Some(tracker);
+        self
+    }
+
+    /// Hand stored mail to post-delivery workers instead of processing it
+    /// before the client gets its reply
+    pub fn with_post_delivery(mut self, queue: Arc<PostDeliveryQueue>) -> Self {
+        self.post_delivery = Some(queue);
+        self
+    }
+
+    /// Flag unauthenticated mail whose From display name impersonates a
+    /// protected name of a recipient's domain, and deliver it to Junk where
+    /// the domain asks for quarantine
+    pub fn with_impersonation_guard(mut self, guard: Arc<ImpersonationGuard>) -> Self {
+        self.impersonation = Some(guard);
+        self
+    }
+
+    /// Look the client up in DNS blocklists, refusing it or scoring its
+    /// messages as each list is configured
+    pub fn with_dnsbl(mut self, checker: Arc<DnsblChecker>) -> Self {
+        self.dnsbl = Some(checker);
+        self
+    }
+
+    /// Defer mail from unknown clients until they retry, unless it passes
+    /// SPF or DKIM
+    pub fn with_greylist(mut self, manager: Arc<GreylistManager>) -> Self {
+        self.greylist = Some(manager);
+        self
+    }
+
+    /// Store the DMARC and TLS reports attached to mail for the manager's
+    /// report addresses, which is still delivered
+    pub fn with_dmarc_reports(mut self, manager: Arc<DmarcReportManager>) -> Self {
+        self.dmarc_reports = Some(manager);
+        self
+    }
+
+    /// Add the calendar invitations of delivered mail to the calendars of
+    /// their recipients, and record the answers to those they organize
+    pub fn with_invitations(mut self, calendars: Arc<CalDavManager>) -> Self {
+        self.invitations = Some(calendars);
+        self
+    }
+
+    /// Scan messages for viruses, refusing or quarantining infected ones
+    pub fn with_antivirus(mut self, scanner: Arc<VirusScanner>) -> Self {
+        self.antivirus = Some(scanner);
+        self
+    }
+
+    /// Run notifications, auto-replies and AI summaries in these pools,
+    /// waiting while they are full
+    pub fn with_workers(mut self, workers: Arc<WorkerPools>) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Defer mail with 451 while the server is read-only, and switch to
+    /// read-only mode when storing fails on damaged storage
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(mode);
+        self
+    }
+
+    /// Turn this session into a submission session (RFC 6409)
+    ///
+    /// TLS and AUTH become mandatory, each user's daily sending quota is
+    /// enforced, and accepted messages are DKIM-signed (if a signer is given)
+    /// and queued for outbound delivery instead of being stored locally.
+    pub fn with_submission(
+        mut self,
+        outbound_queue: Arc<SmtpQueue>,
+        dkim_signer: Option<Arc<DkimSigner>>,
+        quota_manager: Arc<QuotaManager>,
+    ) -> Self {
+        self.require_tls = true;
+        self.require_auth = true;
+        self.listener = IngressListener::Submission;
+        self.outbound_queue = Some(outbound_queue);
+        self.dkim_signer = dkim_signer;
+        self.quota_manager = Some(quota_manager);
+        self
+    }
+
+    /// Refuse local recipients whose quota can't hold the size declared
+    /// with the MAIL FROM SIZE parameter
+    pub fn with_recipient_quotas(mut self, quotas: Arc<QuotaManager>) -> Self {
+        self.recipient_quotas = Some(quotas);
+        self
+    }
+
+    /// Notify local recipients of delivered mail
+    pub fn with_notifications(mut self, router: Arc<NotificationRouter>) -> Self {
+        self.notifications = Some(router);
+        self
+    }
+
+    /// Record the clients of users that log in as devices and accept their
+    /// app passwords
+    pub fn with_devices(mut self, devices: Arc<DeviceManager>) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Refuse logins from unusual locations while the anomaly detector
+    /// blocks them
+    pub fn with_login_anomalies(mut self, detector: Arc<LoginAnomalyDetector>) -> Self {
+        self.login_anomalies = Some(detector);
+        self
+    }
+
+    /// Append the footer of the sender's domain or group to submitted mail
+    /// before it is signed
+    pub fn with_footers(mut self, footers: Arc<FooterManager>) -> Self {
+        self.footers = Some(footers);
+        self
+    }
+
+    /// Account each recipient's pipeline usage, skipping optional stages
+    /// and limiting mailbox writes once they exceed their budget
+    pub fn with_delivery_budget(mut self, budget: Arc<DeliveryBudget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Enable OAUTHBEARER/XOAUTH2 authentication with the given validator
+    pub fn with_oauth(mut self, validator: Arc<OAuthValidator>) -> Self {
+        self.oauth_validator = Some(validator);
+        self
+    }
+
+    /// Set the real client IP (e.g. taken from a PROXY protocol header)
+    ///
+    /// Overrides the TCP peer address used for SPF validation.
+    pub fn with_client_ip(mut self, client_ip: IpAddr) -> Self {
+        self.client_ip = Some(client_ip);
+        self
+    }
+
+    /// Wait before sending the greeting and drop clients that send anything
+    /// first (early talkers, typically spam bots)
+    pub fn with_greet_pause(mut self, pause: Duration) -> Self {
+        self.greet_pause = (!pause.is_zero()).then_some(pause);
+        self
+    }
+
+    /// Refuse messages with more than `max_hops` Received headers and alert
+    /// `alert_to` (typically the postmaster) of detected loops
+    pub fn with_loop_detection(mut self, max_hops: usize, alert_to: Option<String>) -> Self {
+        self.max_hops = max_hops;
+        self.loop_alert_to = alert_to;
+        self
+    }
+
+    /// Remember single-instance deliveries, which carry no `Delivered-To`
+    /// header, in `log`, to refuse them when they come back
+    pub fn with_delivery_log(mut self, log: Arc<DeliveryLog>) -> Self {
+        self.delivery_log = Some(log);
+        self
+    }
+
     /// Handle SMTP session with comprehensive security checks and STARTTLS support
-    pub async fn handle(mut self, stream: TcpStream) -> Result<()> {
-        // Capture client IP for SPF validation
-        if let Ok(peer_addr) = stream.peer_addr() {
-            self.client_ip = Some(peer_addr.ip());
-            debug!("Client IP: {}", peer_addr.ip());
+    pub async fn handle(mut self, mut stream: TcpStream) -> Result<()> {
+        // Capture client IP for SPF validation (unless provided by a proxy)
+        if self.client_ip.is_none() {
+            if let Ok(peer_addr) = stream.peer_addr() {
+                self.client_ip = Some(peer_addr.ip());
+            }
+        }
+        if let Some(client_ip) = self.client_ip {
+            debug!("Client IP: {}", client_ip);
+            self.client_hostname = trace::lookup_client_hostname(client_ip).await;
+            if let Some(checker) = &self.dnsbl {
+                self.dnsbl_verdict = checker.check(client_ip).await;
+                if checker.reject_at() == DnsblStage::Connect {
+                    if let Some(hit) = self.dnsbl_verdict.rejection() {
+                        checker.record_rejection(hit);
+                        warn!("Refusing connection from {} listed on {}", client_ip, hit.zone);
+                        stream.write_all(dnsbl::reply(client_ip, hit).as_bytes()).await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if let Some(pause) = self.greet_pause {
+            let mut byte = [0u8; 1];
+            match timeout(pause, stream.peek(&mut byte)).await {
+                // Silent for the whole pause: a well-behaved client
+                Err(_) => {}
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(_)) => {
+                    warn!(
+                        "Dropping early talker {}",
+                        self.client_ip.map(|ip| ip.to_string()).unwrap_or_default()
+                    );
+                    stream
+                        .write_all(
+                            format!(
+                                "554 5.5.0 {} Protocol error: data sent before greeting\r\n",
+                                self.hostname
+                            )
+                            .as_bytes(),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+                Ok(Err(e)) => return Err(e.into()),
+            }
         }
 
         // Wrap in unified stream type (starts as plain)
@@ -359,6 +862,7 @@ impl SmtpSession {
                     if let SmtpCommand::Auth(mechanism, initial_response) = cmd.clone() {
                         if let Err(e) = self.handle_auth(&mechanism, initial_response, &mut buf_reader).await {
                             error!("AUTH error: {}", e);
+                            self.record_usage(UsageEventKind::AuthFailure, None, None).await;
                             buf_reader.write_all(b"535 Authentication failed\r\n").await?;
                             self.error_count += 1;
                         }
@@ -376,12 +880,18 @@ impl SmtpSession {
 
                             // Handle DATA mode
                             if self.state == SmtpState::Data {
-                                if let Err(e) = self.receive_data(&mut buf_reader).await {
-                                    error!("Error receiving data: {}", e);
-                                    buf_reader
-                                        .write_all(b"451 Error receiving message\r\n")
-                                        .await?;
-                                    self.error_count += 1;
+                                match self.receive_data(&mut buf_reader).await {
+                                    Ok(()) => {}
+                                    Err(MailError::MessageRejected(reply)) => {
+                                        buf_reader.write_all(reply.as_bytes()).await?;
+                                    }
+                                    Err(e) => {
+                                        error!("Error receiving data: {}", e);
+                                        buf_reader
+                                            .write_all(b"451 Error receiving message\r\n")
+                                            .await?;
+                                        self.error_count += 1;
+                                    }
                                 }
                             }
                         }
@@ -410,12 +920,14 @@ impl SmtpSession {
             (SmtpState::Fresh, SmtpCommand::Helo(domain)) => {
                 info!("HELO from {}", domain);
                 self.helo_domain = Some(domain.clone());
+                self.protocol = Protocol::Smtp;
                 self.state = SmtpState::Greeted;
                 Ok(format!("250 {} Hello {}\r\n", self.hostname, domain))
             }
             (SmtpState::Fresh, SmtpCommand::Ehlo(domain)) => {
                 info!("EHLO from {}", domain);
                 self.helo_domain = Some(domain.clone());
+                self.protocol = Protocol::Esmtp;
                 self.state = SmtpState::Greeted;
 
                 // Build EHLO response with capabilities
@@ -430,44 +942,102 @@ impl SmtpSession {
                 if !self.require_tls || self.is_encrypted {
                     response.push_str(&format!("250-SIZE {}\r\n", self.max_message_size));
 
-                    // Advertise AUTH if available and (encrypted or not requiring TLS)
-                    if let Some(ref _auth) = self.authenticator {
-                        if self.is_encrypted || self.tls_config.is_none() {
-                            response.push_str("250-AUTH PLAIN LOGIN\r\n");
+                    // Advertise AUTH if available; mechanisms sending a reusable
+                    // secret only when encrypted or TLS is not configured
+                    let tls_ok = self.is_encrypted || self.tls_config.is_none();
+                    let mut mechanisms = Vec::new();
+                    if let Some(authenticator) = &self.authenticator {
+                        if tls_ok {
+                            mechanisms.extend(["PLAIN", "LOGIN"]);
+                        }
+                        if authenticator.cram_md5_enabled() {
+                            mechanisms.push("CRAM-MD5");
                         }
+                        mechanisms.push("SCRAM-SHA-256");
                     }
+                    if self.oauth_validator.is_some() && tls_ok {
+                        mechanisms.extend(["OAUTHBEARER", "XOAUTH2"]);
+                    }
+                    if !mechanisms.is_empty() {
+                        response.push_str(&format!("250-AUTH {}\r\n", mechanisms.join(" ")));
+                    }
+                }
+
+                // REQUIRETLS is only offered over TLS (RFC 8689 section 4.1)
+                if self.is_encrypted {
+                    response.push_str("250-REQUIRETLS\r\n");
                 }
 
                 response.push_str("250 HELP\r\n");
                 Ok(response)
             }
-            (SmtpState::Greeted | SmtpState::MailFrom | SmtpState::RcptTo, SmtpCommand::MailFrom(from)) => {
+            (SmtpState::Greeted | SmtpState::MailFrom | SmtpState::RcptTo, SmtpCommand::MailFrom(from, params)) => {
                 // Check TLS if required
                 if self.require_tls && !self.is_encrypted {
                     warn!("MAIL FROM rejected: TLS required");
                     return Ok("530 Must issue STARTTLS first\r\n".to_string());
                 }
 
+                if params.require_tls && !self.is_encrypted {
+                    warn!("MAIL FROM rejected: REQUIRETLS over plaintext");
+                    return Ok("530 5.7.10 REQUIRETLS requires an encrypted session\r\n".to_string());
+                }
+
                 // Check authentication if required
                 if self.require_auth && self.authenticated_user.is_none() {
                     warn!("MAIL FROM rejected: authentication required");
                     return Ok("530 Authentication required\r\n".to_string());
                 }
 
-                // Validate email address (security: prevent injection)
-                validate_email(&from)?;
+                if self.read_only.as_ref().is_some_and(|mode| mode.is_read_only()) {
+                    warn!("MAIL FROM deferred: server is read-only");
+                    return Ok(recovery::smtp_reply().to_string());
+                }
+
+                // Clients on a rejecting blocklist may still authenticate
+                if let (Some(checker), Some(client_ip), None) =
+                    (&self.dnsbl, self.client_ip, &self.authenticated_user)
+                {
+                    if let Some(hit) = self.dnsbl_verdict.rejection() {
+                        checker.record_rejection(hit);
+                        warn!("MAIL FROM rejected: {} listed on {}", client_ip, hit.zone);
+                        return Ok(dnsbl::reply(client_ip, hit));
+                    }
+                }
+
+                // Enforce per-user sending quota on submission
+                if let (Some(quotas), Some(user)) = (&self.quota_manager, &self.authenticated_user) {
+                    if quotas.check_message_limit(user).await == QuotaStatus::MessageLimitExceeded {
+                        warn!("MAIL FROM rejected: daily sending quota exceeded for {}", user);
+                        return Ok("452 4.7.1 Daily sending quota exceeded\r\n".to_string());
+                    }
+                }
+
+                // Refuse oversized messages before their data is sent (RFC 1870)
+                if params.size.is_some_and(|size| size > self.max_message_size as u64) {
+                    warn!("MAIL FROM rejected: declared size {:?} exceeds limit", params.size);
+                    return Ok(format!(
+                        "552 5.3.4 Message size exceeds fixed maximum message size ({} bytes)\r\n",
+                        self.max_message_size
+                    ));
+                }
+
+                // Validate email address (security: prevent injection).
+                // The null reverse-path is used by bounces (RFC 5321 4.5.5).
+                if !from.is_empty() || self.outbound_queue.is_some() {
+                    validate_email(&from)?;
+                }
 
                 info!("MAIL FROM: {}", from);
                 self.from = Some(from);
+                self.message_requires_tls = params.require_tls;
+                self.declared_size = params.size;
                 self.to.clear();
                 self.data.clear();
                 self.state = SmtpState::MailFrom;
                 Ok("250 OK\r\n".to_string())
             }
             (SmtpState::MailFrom | SmtpState::RcptTo, SmtpCommand::RcptTo(to)) => {
-                // Validate email address (security: prevent injection)
-                validate_email(&to)?;
-
                 // Check recipient limit (security: prevent spam)
                 if self.to.len() >= MAX_RECIPIENTS {
                     warn!("Too many recipients: {}", self.to.len());
@@ -477,6 +1047,115 @@ impl SmtpSession {
                     ));
                 }
 
+                // Bounces to rewritten senders go back to the original sender.
+                // SRS local parts may exceed the usual length limit, so the
+                // decoded address is validated instead.
+                if let Some(srs) = self.srs.as_ref().filter(|srs| srs.is_srs_address(&to)) {
+                    if self.relay_queue.is_none() {
+                        warn!("RCPT TO {} is an SRS address but no relay queue is configured", to);
+                        return Ok("451 4.3.0 Relay temporarily unavailable\r\n".to_string());
+                    }
+
+                    let original = match srs.reverse(&to) {
+                        Ok(original) => original,
+                        Err(e) => {
+                            warn!("RCPT TO rejected: {}", e);
+                            return Ok("550 5.1.1 Invalid or expired SRS address\r\n".to_string());
+                        }
+                    };
+                    validate_email(&original)?;
+
+                    info!("RCPT TO: {} (SRS bounce for {})", to, original);
+                    self.to.push(to);
+                    self.state = SmtpState::RcptTo;
+                    return Ok("250 OK\r\n".to_string());
+                }
+
+                // Validate email address (security: prevent injection)
+                validate_email(&to)?;
+
+                // Role addresses go to their admin mailbox
+                let mut mailbox = to.clone();
+                if let Some(roles) = self.roles.as_ref().filter(|_| self.outbound_queue.is_none()) {
+                    if self.route_for(&to) == RouteAction::Local {
+                        match roles.resolve(&to).await {
+                            Ok(Some(target)) => {
+                                mailbox = target.mailbox.clone();
+                                self.role_targets.insert(to.clone(), target);
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                warn!("Role account lookup for {} failed: {}", to, e);
+                                return Ok("451 4.3.0 Temporary lookup failure\r\n".to_string());
+                            }
+                        }
+                    }
+                }
+
+                // Burner aliases stand in for their owner's mailbox
+                if let Some(aliases) = self.aliases.as_ref().filter(|_| !self.role_targets.contains_key(&to)) {
+                    match aliases.resolve(&to).await {
+                        Ok(Some(alias)) => match alias.delivery(Utc::now()) {
+                            AliasDelivery::Reject => {
+                                warn!("RCPT TO {} rejected: alias is blocked or expired", to);
+                                self.record_alias_sender(&to, false).await;
+                                return Ok("550 5.1.1 Mailbox unavailable\r\n".to_string());
+                            }
+                            delivery => {
+                                mailbox = alias.owner.clone();
+                                self.alias_targets.insert(to.clone(), (alias.owner, delivery));
+                            }
+                        },
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("Alias lookup for {} failed: {}", to, e);
+                            return Ok("451 4.3.0 Temporary lookup failure\r\n".to_string());
+                        }
+                    }
+                }
+
+                // Routing rules are evaluated before local delivery
+                match self.route_for(&to) {
+                    RouteAction::Reject { message } => {
+                        warn!("RCPT TO {} rejected by routing rule", to);
+                        return Ok(format!("550 5.7.1 {}\r\n", message));
+                    }
+                    RouteAction::Forward { host } if self.relay_queue.is_none() => {
+                        warn!("RCPT TO {} routed to {} but no relay queue is configured", to, host);
+                        return Ok("451 4.3.0 Relay temporarily unavailable\r\n".to_string());
+                    }
+                    _ => {}
+                }
+
+                // Deliveries would miss the final sync of a storage cutover
+                if self.outbound_queue.is_none() && self.storage.is_locked(&mailbox) {
+                    warn!("RCPT TO {} deferred: mailbox migration in progress", to);
+                    return Ok("450 4.2.1 Mailbox temporarily unavailable\r\n".to_string());
+                }
+
+                // A local recipient must have room for the declared size, or
+                // for at least a byte, and not have had its daily deliveries
+                if let Some(quotas) = &self.recipient_quotas {
+                    if self.outbound_queue.is_none() && self.route_for(&to) == RouteAction::Local {
+                        if quotas.check_message_limit(&mailbox).await == QuotaStatus::MessageLimitExceeded {
+                            warn!("RCPT TO {} deferred: daily delivery limit reached", to);
+                            return Ok("452 4.2.2 Mailbox received too many messages today\r\n".to_string());
+                        }
+                        let size = self.declared_size.unwrap_or(1);
+                        match quotas.check_storage(&mailbox, size).await {
+                            QuotaStatus::StorageExceeded => {
+                                warn!("RCPT TO {} rejected: {} bytes exceed storage quota", to, size);
+                                return Ok("452 4.2.2 Mailbox full\r\n".to_string());
+                            }
+                            QuotaStatus::MessageSizeExceeded => {
+                                warn!("RCPT TO {} rejected: {} bytes exceed message size limit", to, size);
+                                return Ok("552 5.3.4 Message too large for recipient\r\n".to_string());
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+
                 info!("RCPT TO: {}", to);
                 self.to.push(to);
                 self.state = SmtpState::RcptTo;
@@ -489,10 +1168,7 @@ impl SmtpSession {
             }
             (_, SmtpCommand::Rset) => {
                 info!("RSET command");
-                self.from = None;
-                self.to.clear();
-                self.data.clear();
-                self.state = SmtpState::Greeted;
+                self.reset_transaction();
                 Ok("250 OK\r\n".to_string())
             }
             (_, SmtpCommand::Noop) => {
@@ -592,6 +1268,17 @@ impl SmtpSession {
             return Err(MailError::SmtpProtocol("Empty message".to_string()));
         }
 
+        // Refuse looping mail before spending DNS lookups on it
+        let mail_loop = loop_detection::detect(&self.data, &self.to, self.max_hops).or_else(|| {
+            let log = self.delivery_log.as_ref()?;
+            log.detect(&self.data, &self.to, &self.hostname)
+        });
+        if let Some(mail_loop) = mail_loop {
+            self.raise_loop_alert(&mail_loop).await;
+            self.reset_transaction();
+            return Err(MailError::MessageRejected(mail_loop.reply()));
+        }
+
         // Perform SPF/DKIM validation
         let auth_result = self.validate_authentication().await;
 
@@ -599,46 +1286,528 @@ impl SmtpSession {
         if let Some(ref result) = auth_result {
             if self.should_reject_message(result) {
                 warn!("Rejecting message due to failed authentication");
+                for recipient in &self.to {
+                    self.record_usage(UsageEventKind::Spam, self.from.as_deref(), Some(recipient))
+                        .await;
+                }
                 return Err(MailError::SmtpProtocol(
                     "Message rejected due to authentication failure".to_string(),
                 ));
             }
         }
 
+        if let Some(reply) = self.check_greylist(auth_result.as_ref()).await {
+            self.reset_transaction();
+            return Err(MailError::MessageRejected(reply));
+        }
+
         // Prepend Authentication-Results header if we performed validation
         if let Some(result) = auth_result {
             self.prepend_auth_header(&result);
         }
 
-        // Store the email
-        self.store_email().await?;
+        let infected = self.check_virus().await?;
+
+        let quarantined = match self.budget.clone() {
+            Some(budget) => {
+                let users = self.mailbox_owners();
+                match budget
+                    .run_stage(&users, "impersonation check", self.check_impersonation())
+                    .await
+                {
+                    StageOutcome::Done(quarantined) => quarantined,
+                    StageOutcome::Skipped(reason) => {
+                        let mut data = budget::warning_header("impersonation check", reason)
+                            .into_bytes();
+                        data.extend_from_slice(&self.data);
+                        self.data = data;
+                        Vec::new()
+                    }
+                }
+            }
+            None => self.check_impersonation().await,
+        };
+
+        // Scored after the impersonation check, whose warning is a spam rule
+        let mut quarantined = quarantined;
+        // Required even over budget; a check that times out defers the message
+        let verdict = match self.budget.clone() {
+            Some(budget) => {
+                let users = self.mailbox_owners();
+                match budget.run_required_stage(&users, "spam check", self.check_spam()).await {
+                    Some(verdict) => verdict,
+                    None => {
+                        self.reset_transaction();
+                        return Err(MailError::MessageRejected(
+                            "451 4.7.1 Spam check timed out, please try again later\r\n"
+                                .to_string(),
+                        ));
+                    }
+                }
+            }
+            None => self.check_spam().await,
+        };
+        if let Some(verdict) = &verdict {
+            if verdict.reject {
+                warn!(
+                    "Rejecting message from {:?} as spam (score {:.1})",
+                    self.from, verdict.result.score
+                );
+                for recipient in &self.to {
+                    self.record_usage(UsageEventKind::Spam, self.from.as_deref(), Some(recipient))
+                        .await;
+                }
+                self.reset_transaction();
+                return Err(MailError::MessageRejected(verdict.reply()));
+            }
+            quarantined.extend(verdict.junk.iter().cloned());
+        }
+        if infected {
+            quarantined.extend(self.to.iter().cloned());
+        }
+        let spam = verdict.as_ref().is_some_and(SpamVerdict::is_spam);
+        self.record_role_volume(spam).await;
+        // Role mail may be held in Junk for review
+        quarantined.extend(
+            self.role_targets
+                .iter()
+                .filter(|(_, target)| target.quarantine)
+                .map(|(to, _)| to.clone()),
+        );
+
+        // Only the ingress header this server adds is trusted
+        self.data = ingress::strip_headers(&self.data);
+        self.prepend_received_header();
+
+        // The reply to DATA covers every recipient, so a hook rejecting
+        // the message refuses it for all of them
+        if let Some(reason) = self.run_hooks().await {
+            self.reset_transaction();
+            return Err(MailError::MessageRejected(format!("550 5.7.1 {}\r\n", reason)));
+        }
+
+        // Store the email; failing storage turns the server read-only
+        if let Err(e) = self.store_email(&quarantined, verdict.as_ref()).await {
+            match &self.read_only {
+                Some(mode) if mode.trip_on(&e) => {
+                    error!("Deferring message, storing failed: {}", e);
+                    self.reset_transaction();
+                    return Err(MailError::MessageRejected(recovery::smtp_reply().to_string()));
+                }
+                _ => return Err(e),
+            }
+        }
 
         // Send response
         buf_reader.write_all(b"250 OK: Message accepted\r\n").await?;
 
         // Reset state for next message
-        self.state = SmtpState::Greeted;
-        self.from = None;
-        self.to.clear();
-        self.data.clear();
+        self.reset_transaction();
 
         Ok(())
     }
 
-    async fn store_email(&self) -> Result<()> {
+    /// Forget the current transaction's envelope and message
+    fn reset_transaction(&mut self) {
+        self.state = SmtpState::Greeted;
+        self.from = None;
+        self.message_requires_tls = false;
+        self.declared_size = None;
+        self.to.clear();
+        self.alias_targets.clear();
+        self.role_targets.clear();
+        self.hook_outcomes.clear();
+        self.data.clear();
+    }
+
+    /// Mailboxes the current message is delivered to, with burner aliases
+    /// and role addresses standing in for their owners
+    fn mailbox_owners(&self) -> Vec<String> {
+        let mut owners: Vec<String> = self.to.iter().map(|to| self.mailbox_of(to)).collect();
+        owners.sort();
+        owners.dedup();
+        owners
+    }
+
+    /// Mailbox receiving mail to `recipient`
+    fn mailbox_of(&self, recipient: &str) -> String {
+        match (self.alias_targets.get(recipient), self.role_targets.get(recipient)) {
+            (Some((owner, _)), _) => owner.clone(),
+            (None, Some(target)) => target.mailbox.clone(),
+            (None, None) => recipient.to_string(),
+        }
+    }
+
+    /// Run the delivery hooks of each local recipient, keeping what they
+    /// did for [`Self::store_email`]; returns why a hook rejected the
+    /// message
+    async fn run_hooks(&mut self) -> Option<String> {
+        let hooks = self.hooks.clone()?;
+        if self.outbound_queue.is_some() {
+            return None;
+        }
+        let from = self.from.clone().unwrap_or_default();
+
+        for recipient in self.to.clone() {
+            let is_bounce = self
+                .srs
+                .as_ref()
+                .is_some_and(|srs| srs.is_srs_address(&recipient));
+            if is_bounce || self.route_for(&recipient) != RouteAction::Local {
+                continue;
+            }
+            let mailbox = self.mailbox_of(&recipient);
+
+            let run = hooks.run_for_delivery(&from, &recipient, &mailbox, &self.data);
+            let users = std::slice::from_ref(&mailbox);
+            let result = match &self.budget {
+                Some(budget) => match budget.run_stage(users, "hooks", run).await {
+                    StageOutcome::Done(result) => result,
+                    StageOutcome::Skipped(reason) => {
+                        warn!("Delivering to {} without hooks: {}", mailbox, reason);
+                        continue;
+                    }
+                },
+                None => run.await,
+            };
+            match result {
+                Ok(outcome) => {
+                    if let Some(reason) = outcome.reject {
+                        warn!("Hook of {} rejected email from {}: {}", mailbox, from, reason);
+                        return Some(reason);
+                    }
+                    if !outcome.is_empty() {
+                        self.hook_outcomes.insert(recipient, outcome);
+                    }
+                }
+                Err(e) => warn!("Delivering to {} without hooks: {}", mailbox, e),
+            }
+        }
+        None
+    }
+
+    /// Add a warning header for each protected name the sender's display
+    /// name impersonates; returns the recipients to deliver to Junk
+    async fn check_impersonation(&mut self) -> Vec<String> {
+        let Some(guard) = &self.impersonation else {
+            return Vec::new();
+        };
+        // Colleagues sending through this server are who they claim to be
+        if self.authenticated_user.is_some() {
+            return Vec::new();
+        }
+
+        let found = match guard.check(&self.data, &self.to).await {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Impersonation check failed: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut quarantined = Vec::new();
+        for impersonation in found {
+            warn!(
+                "Display name {:?} of {} impersonates {} of {}",
+                impersonation.display_name,
+                impersonation.address,
+                impersonation.protected_name,
+                impersonation.domain
+            );
+            let mut data = impersonation.header().into_bytes();
+            data.extend_from_slice(&self.data);
+            self.data = data;
+
+            if impersonation.quarantine {
+                quarantined.extend(
+                    self.to
+                        .iter()
+                        .filter(|to| {
+                            to.rsplit_once('@')
+                                .is_some_and(|(_, d)| d.eq_ignore_ascii_case(&impersonation.domain))
+                        })
+                        .cloned(),
+                );
+            }
+        }
+        quarantined
+    }
+
+    /// Count the message towards the volume of its role recipients
+    async fn record_role_volume(&self, spam: bool) {
+        let Some(roles) = &self.roles else {
+            return;
+        };
+        for address in self.role_targets.keys() {
+            if let Err(e) = roles.record(address, self.data.len(), spam).await {
+                warn!("Failed to record volume of {}: {}", address, e);
+            }
+        }
+    }
+
+    /// Scan the message with clamd and replace its virus status header;
+    /// true when it is infected and quarantined, refused otherwise
+    async fn check_virus(&mut self) -> Result<bool> {
+        let Some(scanner) = self.antivirus.clone() else {
+            return Ok(false);
+        };
+
+        self.data = antivirus::strip_headers(&self.data);
+        let result = match scanner.scan(&self.data).await {
+            Ok(result) => Some(result),
+            Err(e) if scanner.fail_open() => {
+                warn!("Virus scan failed, delivering unscanned: {}", e);
+                None
+            }
+            Err(e) => {
+                warn!("Virus scan failed, deferring message: {}", e);
+                self.reset_transaction();
+                return Err(MailError::MessageRejected(antivirus::unavailable_reply()));
+            }
+        };
+
+        let infected = match &result {
+            Some(ScanResult::Infected(signature)) => {
+                // Submitted mail would spread the virus further
+                if scanner.action() == VirusAction::Reject || self.outbound_queue.is_some() {
+                    warn!("Rejecting message from {:?} infected with {}", self.from, signature);
+                    scanner.record(VirusAction::Reject);
+                    let reply = antivirus::reply(signature);
+                    self.reset_transaction();
+                    return Err(MailError::MessageRejected(reply));
+                }
+                warn!("Quarantining message from {:?} infected with {}", self.from, signature);
+                scanner.record(VirusAction::Quarantine);
+                true
+            }
+            _ => false,
+        };
+
+        let mut data = antivirus::header(result.as_ref()).into_bytes();
+        data.extend_from_slice(&self.data);
+        self.data = data;
+        Ok(infected)
+    }
+
+    /// Score the message for spam and replace its spam headers with the
+    /// verdict; None when not scored
+    async fn check_spam(&mut self) -> Option<SpamVerdict> {
+        let filter = self.spam.clone()?;
+        // Colleagues sending through this server are not scored
+        if self.authenticated_user.is_some() {
+            return None;
+        }
+
+        self.data = spam::strip_headers(&self.data);
+        let from = self.from.clone().unwrap_or_default();
+        let tag_only: Vec<String> = self.role_targets.keys().cloned().collect();
+        let client = SpamClient {
+            ip: self.client_ip,
+            helo: self.helo_domain.clone(),
+            rules: self.dnsbl_verdict.rule_matches(),
+        };
+        let verdict = match filter.check(&self.data, &from, &self.to, &tag_only, &client).await {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                warn!("Spam check failed: {}", e);
+                return None;
+            }
+        };
+        info!(
+            "Spam score {:.1} for message from {} ({} quarantined)",
+            verdict.result.score,
+            from,
+            verdict.junk.len() + verdict.held.len()
+        );
+
+        if let Some(subject) = &verdict.subject {
+            self.data = spam::rewrite_subject(&self.data, subject);
+        }
+        let mut data = verdict.header().into_bytes();
+        data.extend_from_slice(&self.data);
+        self.data = data;
+        Some(verdict)
+    }
+
+    /// Deliver the message; local recipients in `junk` get it in their
+    /// Junk folder, and it is held in quarantine for those the spam
+    /// `verdict` holds it for
+    async fn store_email(&self, junk: &[String], verdict: Option<&SpamVerdict>) -> Result<()> {
+        if let Some(queue) = &self.outbound_queue {
+            return self.queue_submission(queue).await;
+        }
+
         if let Some(from) = &self.from {
             // Extract subject from email data for auto-reply
             let subject = self.extract_subject();
+            let ingress = Ingress::from_trace(self.listener, &self.trace_info()).header();
+
+            if let Some(reports) = self
+                .dmarc_reports
+                .as_ref()
+                .filter(|reports| self.to.iter().any(|to| reports.is_report_address(to)))
+            {
+                // Unreadable reports are still delivered
+                if let Err(e) = reports.ingest(&self.data).await {
+                    warn!("Failed to ingest aggregate reports from {}: {}", from, e);
+                }
+            }
 
             for recipient in &self.to {
-                info!("Storing email from {} to {}", from, recipient);
-                let email_id = self.storage.store(recipient, &self.data).await?;
+                if let Some((srs, queue)) = self
+                    .srs
+                    .as_ref()
+                    .filter(|srs| srs.is_srs_address(recipient))
+                    .zip(self.relay_queue.as_ref())
+                {
+                    match srs.reverse(recipient) {
+                        Ok(original) => {
+                            info!("Relaying bounce for {} to {}", recipient, original);
+                            queue
+                                .enqueue_with(from, &original, &self.data, None, self.tls_requirement())
+                                .await?;
+                        }
+                        Err(e) => warn!("Dropping bounce: {}", e),
+                    }
+                    continue;
+                }
+
+                if let (RouteAction::Forward { host }, Some(queue)) =
+                    (self.route_for(recipient), &self.relay_queue)
+                {
+                    // Keep SPF passing at the next hop
+                    let sender = match &self.srs {
+                        Some(srs) => srs.forward(from),
+                        None => from.clone(),
+                    };
+                    info!("Forwarding email from {} to {} via {}", sender, recipient, host);
+                    let mut data = loop_detection::delivered_to_header(recipient).into_bytes();
+                    data.extend_from_slice(&self.data);
+                    queue
+                        .enqueue_with(&sender, recipient, &data, Some(&host), self.tls_requirement())
+                        .await?;
+                    continue;
+                }
 
-                // Trigger summary generation asynchronously (fire-and-forget)
-                self.trigger_summary_generation(recipient, &email_id, from).await;
+                // Mail to a burner alias goes to its owner's mailbox
+                let alias = self.alias_targets.get(recipient);
+                if alias.is_some() {
+                    self.record_alias_sender(recipient, true).await;
+                }
+                let mailbox = match alias {
+                    Some((_, AliasDelivery::Discard)) => {
+                        info!("Discarding email from {} to muted alias {}", from, recipient);
+                        continue;
+                    }
+                    Some((owner, _)) => owner,
+                    None => match self.role_targets.get(recipient) {
+                        Some(target) => &target.mailbox,
+                        None => recipient,
+                    },
+                };
 
-                // Trigger auto-reply if configured
-                self.trigger_auto_reply(recipient, from, subject.as_deref()).await;
+                info!("Storing email from {} to {}", from, recipient);
+                let mut data = trace::return_path_header(from).into_bytes();
+                // A per-recipient header would give every shared copy its own content
+                if self.storage.is_single_instance() {
+                    if let (Some(log), Some(id)) = (&self.delivery_log, &self.received_id) {
+                        log.record(id, recipient);
+                    }
+                } else {
+                    data.extend_from_slice(loop_detection::delivered_to_header(recipient).as_bytes());
+                }
+                data.extend_from_slice(ingress.as_bytes());
+                let hooked = self.hook_outcomes.get(recipient);
+                if let Some(outcome) = hooked {
+                    data.extend_from_slice(outcome.header_block().as_bytes());
+                }
+                data.extend_from_slice(&self.data);
+                let _slot = match &self.budget {
+                    Some(budget) => {
+                        budget.record_delivery(mailbox, data.len());
+                        Some(budget.io_slot(mailbox).await)
+                    }
+                    None => None,
+                };
+                let quarantine = self.spam.as_ref().and_then(|filter| filter.quarantine());
+                if let Some((store, verdict)) = quarantine
+                    .zip(verdict)
+                    .filter(|(_, verdict)| verdict.held.contains(recipient))
+                    .filter(|_| !junk.contains(recipient))
+                {
+                    info!("Holding email from {} to {} in quarantine", from, recipient);
+                    store
+                        .hold(
+                            mailbox,
+                            from,
+                            &decode_header(subject.as_deref().unwrap_or_default()),
+                            verdict.result.score,
+                            &verdict.rules(),
+                            &data,
+                        )
+                        .await
+                        .map_err(|e| MailError::Storage(format!("Failed to quarantine: {}", e)))?;
+                    continue;
+                }
+                let (folder, email_id) = if junk.contains(recipient) {
+                    info!("Quarantining email from {} to {} in Junk", from, recipient);
+                    let folder = SpecialUse::Junk.folder();
+                    let email_id = self
+                        .storage
+                        .store_in_folder(mailbox, Some(folder), &data)
+                        .await?;
+                    (Some(folder.to_string()), email_id)
+                } else {
+                    let mut delivery = self.sieve_delivery(from, recipient, mailbox, &data).await;
+                    // Sieve's keep files into the folder a hook chose
+                    if let Some(folder) = hooked.and_then(|outcome| outcome.folder.as_ref()) {
+                        for kept in delivery.folders.iter_mut().filter(|kept| kept.is_none()) {
+                            *kept = Some(folder.clone());
+                        }
+                    }
+                    if let Some(vacation) = &delivery.vacation {
+                        self.send_vacation(from, recipient, mailbox, vacation).await;
+                    }
+                    if !delivery.header_edits.is_empty() {
+                        data = delivery.edit_header(&data);
+                    }
+                    match self.deliver_filtered(from, recipient, mailbox, &data, &delivery).await? {
+                        Some(stored) => stored,
+                        None => continue,
+                    }
+                };
+                self.record_usage(UsageEventKind::Received, Some(from), Some(mailbox))
+                    .await;
+                self.record_billing(mailbox, BillingMetric::MessageReceived)
+                    .await;
+                self.count_delivery(mailbox).await;
+
+                match &self.post_delivery {
+                    Some(queue) => {
+                        queue
+                            .submit(PostDeliveryJob {
+                                user: mailbox.to_string(),
+                                folder,
+                                email_id,
+                                from: from.clone(),
+                                message: data,
+                            })
+                            .await
+                    }
+                    // Trigger summary generation asynchronously (fire-and-forget)
+                    None => self.trigger_summary_generation(mailbox, &email_id, from).await,
+                }
+
+                // Trigger auto-reply and notifications if configured (never
+                // for bounces, notification emails or quarantined mail).
+                // Auto-replies to alias mail would reveal the owner's address.
+                if !from.is_empty() && !junk.contains(recipient) {
+                    if alias.is_none() && !self.role_targets.contains_key(recipient) {
+                        self.trigger_auto_reply(recipient, from, subject.as_deref()).await;
+                    }
+                    self.trigger_notification(mailbox, from, subject.as_deref()).await;
+                    self.process_invitation(mailbox).await;
+                }
             }
             Ok(())
         } else {
@@ -646,6 +1815,242 @@ impl SmtpSession {
         }
     }
 
+    /// Where the active Sieve script of `mailbox` delivers `message` from
+    /// `from` to `recipient`; INBOX without a script or when the script
+    /// fails
+    async fn sieve_delivery(
+        &self,
+        from: &str,
+        recipient: &str,
+        mailbox: &str,
+        message: &[u8],
+    ) -> SieveDelivery {
+        let Some(sieve) = &self.sieve else {
+            return SieveDelivery::from_result(&Default::default());
+        };
+        let context = MessageContext::from_message(message).with_envelope(from, recipient);
+        let message_id = context
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("message-id"))
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default();
+
+        let execution = sieve.execute_for_message(mailbox, &context, &message_id);
+        let result = match &self.budget {
+            Some(budget) => {
+                match budget.run_stage(&[mailbox.to_string()], "sieve", execution).await {
+                    StageOutcome::Done(result) => result,
+                    StageOutcome::Skipped(reason) => {
+                        warn!("Delivering to INBOX of {}: sieve skipped ({})", mailbox, reason);
+                        return SieveDelivery::from_result(&Default::default());
+                    }
+                }
+            }
+            None => execution.await,
+        };
+        match result {
+            Ok(result) => SieveDelivery::from_result(&result),
+            Err(e) => {
+                warn!("Sieve script of {} failed, delivering to INBOX: {}", mailbox, e);
+                SieveDelivery::from_result(&Default::default())
+            }
+        }
+    }
+
+    /// Answer the sender with the vacation response of `mailbox`'s script,
+    /// unless the message is automated or the sender was answered within
+    /// the script's interval; failures never hold up delivery
+    async fn send_vacation(
+        &self,
+        from: &str,
+        recipient: &str,
+        mailbox: &str,
+        response: &VacationConfig,
+    ) {
+        let (Some(tracker), Some(queue)) = (&self.vacations, &self.relay_queue) else {
+            debug!("Not sending vacation response of {}: no tracking or relay queue", mailbox);
+            return;
+        };
+        let context = MessageContext::from_message(&self.data);
+        if !vacation::should_respond(response, recipient, from, &context) {
+            debug!("No vacation response of {} to {}", mailbox, from);
+            return;
+        }
+
+        let handle = vacation::handle(response);
+        match tracker.vacation_due(mailbox, from, &handle, response.days).await {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("{} already got the vacation response of {}", from, mailbox);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to check vacation responses of {}: {}", mailbox, e);
+                return;
+            }
+        }
+
+        let message =
+            vacation::build_response(response, recipient, from, &context, &self.hostname);
+        if let Err(e) = queue
+            .enqueue_with("", from, &message, None, TlsRequirement::default())
+            .await
+        {
+            warn!("Failed to queue vacation response of {}: {}", mailbox, e);
+            return;
+        }
+        info!("Queued vacation response of {} to {}", mailbox, from);
+        if let Err(e) = tracker.record_vacation_sent(mailbox, from, &handle).await {
+            warn!("Failed to record vacation response of {}: {}", mailbox, e);
+        }
+    }
+
+    /// Store and redirect a message as its recipient's Sieve script asked;
+    /// returns the folder and id of the first stored copy, `None` if the
+    /// message was discarded or only redirected
+    async fn deliver_filtered(
+        &self,
+        from: &str,
+        recipient: &str,
+        mailbox: &str,
+        data: &[u8],
+        delivery: &SieveDelivery,
+    ) -> Result<Option<(Option<String>, String)>> {
+        let mut folders = delivery.folders.clone();
+        if !delivery.redirects.is_empty() {
+            match &self.relay_queue {
+                Some(queue) => {
+                    // Keep SPF passing at the next hop
+                    let sender = match &self.srs {
+                        Some(srs) => srs.forward(from),
+                        None => from.to_string(),
+                    };
+                    let mut message = loop_detection::delivered_to_header(recipient).into_bytes();
+                    message.extend_from_slice(&self.data);
+                    for address in &delivery.redirects {
+                        info!("Sieve redirects email from {} to {} on to {}", from, recipient, address);
+                        queue
+                            .enqueue_with(&sender, address, &message, None, self.tls_requirement())
+                            .await?;
+                    }
+                }
+                None => {
+                    warn!("Sieve of {} redirects but no relay queue is configured, keeping", mailbox);
+                    if !folders.contains(&None) {
+                        folders.insert(0, None);
+                    }
+                }
+            }
+        }
+        if delivery.is_discard() {
+            info!("Sieve discarded email from {} to {}", from, recipient);
+        }
+
+        let mut first = None;
+        for folder in &folders {
+            if let Some(folder) = folder {
+                info!("Sieve files email from {} to {} into {}", from, recipient, folder);
+            }
+            let stored = match self
+                .storage
+                .store_with_flags(mailbox, folder.as_deref(), &delivery.flags, data)
+                .await
+            {
+                Ok(email_id) => (folder.clone(), email_id),
+                // A folder name the script got wrong must not lose the message
+                Err(MailError::Storage(e)) if folder.is_some() => {
+                    warn!("Sieve of {} cannot file into {:?}: {}", mailbox, folder, e);
+                    if folders.contains(&None) {
+                        continue;
+                    }
+                    let email_id = self
+                        .storage
+                        .store_with_flags(mailbox, None, &delivery.flags, data)
+                        .await?;
+                    (None, email_id)
+                }
+                Err(e) => return Err(e),
+            };
+            first.get_or_insert(stored);
+        }
+        Ok(first)
+    }
+
+    /// TLS requirement the current message is relayed with
+    fn tls_requirement(&self) -> TlsRequirement {
+        TlsRequirement::for_message(self.message_requires_tls, &self.data)
+    }
+
+    /// Sign and queue a submitted message for every recipient
+    async fn queue_submission(&self, queue: &SmtpQueue) -> Result<()> {
+        let from = self
+            .from
+            .as_deref()
+            .ok_or_else(|| MailError::SmtpProtocol("No sender specified".to_string()))?;
+
+        let message = self.with_footer().await;
+        let data = match &self.dkim_signer {
+            Some(signer) => signer
+                .sign_email(&String::from_utf8_lossy(&message))?
+                .into_bytes(),
+            None => message,
+        };
+
+        for recipient in &self.to {
+            info!("Queuing submitted email from {} to {}", from, recipient);
+            queue
+                .enqueue_with(from, recipient, &data, None, self.tls_requirement())
+                .await?;
+            self.record_usage(UsageEventKind::Sent, Some(from), Some(recipient))
+                .await;
+        }
+
+        if let (Some(quotas), Some(user)) = (&self.quota_manager, &self.authenticated_user) {
+            if let Err(e) = quotas.increment_message_count(user).await {
+                warn!("Failed to record sent message for {}: {}", user, e);
+            }
+        }
+        let sender = self.authenticated_user.as_deref().unwrap_or(from);
+        self.record_billing(sender, BillingMetric::MessageSent).await;
+
+        Ok(())
+    }
+
+    /// Submitted message with the footer of the sender, unchanged when
+    /// there is none or it can't be added; lookup errors never hold up mail
+    async fn with_footer(&self) -> Vec<u8> {
+        let (Some(footers), Some(user)) = (&self.footers, &self.authenticated_user) else {
+            return self.data.clone();
+        };
+        let display_name = impersonation::from_header(&self.data)
+            .map(|(name, _)| name)
+            .unwrap_or_default();
+
+        match footers.footer_for(user, &display_name).await {
+            Ok(Some(footer)) => match footers::append_footer(&self.data, &footer) {
+                FooterResult::Added(message) => return message,
+                FooterResult::AlreadyPresent => {
+                    debug!("Message from {} already carries its footer", user)
+                }
+                FooterResult::Unsupported => {
+                    info!("No body of the message from {} takes a footer", user)
+                }
+            },
+            Ok(None) => {}
+            Err(e) => warn!("Failed to look up the footer of {}: {}", user, e),
+        }
+        self.data.clone()
+    }
+
+    /// Resolve the routing action for a recipient (local if no table)
+    fn route_for(&self, recipient: &str) -> RouteAction {
+        self.routing
+            .as_ref()
+            .map(|routing| routing.resolve(recipient))
+            .unwrap_or(RouteAction::Local)
+    }
+
     /// Extract subject from email data
     fn extract_subject(&self) -> Option<String> {
         let email_data = String::from_utf8_lossy(&self.data);
@@ -660,6 +2065,35 @@ impl SmtpSession {
         None
     }
 
+    /// Record a usage event for reports; failures never affect the session
+    async fn record_usage(&self, kind: UsageEventKind, sender: Option<&str>, recipient: Option<&str>) {
+        if let Some(reporting) = &self.reporting {
+            if let Err(e) = reporting.record(kind, sender, recipient, self.data.len()).await {
+                warn!("Failed to record {} usage event: {}", kind.as_str(), e);
+            }
+        }
+    }
+
+    /// Count a billing metric for a user; failures never affect the session
+    async fn record_billing(&self, email: &str, metric: BillingMetric) {
+        if let Some(billing) = &self.billing {
+            if let Err(e) = billing.record(email, metric).await {
+                warn!("Failed to record billing metric for {}: {}", email, e);
+            }
+        }
+    }
+
+    /// Count the current sender in an alias's statistics; failures never
+    /// affect the session
+    async fn record_alias_sender(&self, alias: &str, accepted: bool) {
+        if let Some(aliases) = &self.aliases {
+            let sender = self.from.as_deref().unwrap_or_default();
+            if let Err(e) = aliases.record_sender(alias, sender, accepted).await {
+                warn!("Failed to record sender of alias {}: {}", alias, e);
+            }
+        }
+    }
+
     /// Trigger auto-reply if enabled for recipient
     async fn trigger_auto_reply(&self, recipient: &str, sender: &str, subject: Option<&str>) {
         if let Some(auto_reply) = &self.auto_reply_sender {
@@ -668,7 +2102,7 @@ impl SmtpSession {
             let sender = sender.to_string();
             let subject = subject.map(|s| s.to_string());
 
-            tokio::spawn(async move {
+            self.spawn_background(Subsystem::Notifications, async move {
                 match auto_reply
                     .process_incoming_message(&recipient, &sender, subject.as_deref())
                     .await
@@ -683,7 +2117,124 @@ impl SmtpSession {
                         warn!("Failed to send auto-reply: {}", e);
                     }
                 }
-            });
+            })
+            .await;
+        }
+    }
+
+    /// Count a delivery against the daily limit of `mailbox` and warn its
+    /// user in the background when it reaches a storage warning threshold
+    async fn count_delivery(&self, mailbox: &str) {
+        let Some(quotas) = &self.recipient_quotas else {
+            return;
+        };
+        if let Err(e) = quotas.increment_message_count(mailbox).await {
+            warn!("Failed to count delivery to {}: {}", mailbox, e);
+        }
+        let Some(threshold) = quotas.storage_warning(mailbox).await else {
+            return;
+        };
+
+        let quota = quotas.get_quota(mailbox).await;
+        warn!("Mailbox {} reached {}% of its storage quota", mailbox, threshold);
+        if let Some(router) = &self.notifications {
+            let router = router.clone();
+            let mailbox = mailbox.to_string();
+            let title = format!("Your mailbox is {}% full", threshold);
+            let body = format!(
+                "Your mailbox uses {} MB of its {} MB quota. Delete or archive messages \
+                 to keep receiving mail; new mail is refused once it is full.",
+                quota.storage_used / (1024 * 1024),
+                quota.storage_limit / (1024 * 1024)
+            );
+
+            self.spawn_background(Subsystem::Notifications, async move {
+                if let Err(e) = router
+                    .notify(&mailbox, EventKind::QuotaWarning, &title, &body)
+                    .await
+                {
+                    warn!("Failed to warn {} about their quota: {}", mailbox, e);
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Raise a new mail notification in the background
+    async fn trigger_notification(&self, recipient: &str, sender: &str, subject: Option<&str>) {
+        if let Some(router) = &self.notifications {
+            let router = router.clone();
+            let recipient = recipient.to_string();
+            let body = format!(
+                "From: {}\nSubject: {}",
+                sender,
+                subject.unwrap_or("(no subject)")
+            );
+
+            self.spawn_background(Subsystem::Notifications, async move {
+                if let Err(e) = router
+                    .notify(&recipient, EventKind::NewMail, "New message", &body)
+                    .await
+                {
+                    warn!("Failed to notify {} of new mail: {}", recipient, e);
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Apply the calendar invitation of a delivered message in the background
+    async fn process_invitation(&self, mailbox: &str) {
+        let Some(calendars) = &self.invitations else {
+            return;
+        };
+        let (Some(invitation), Some(sender)) =
+            (itip::find_in_message(&self.data), itip::sender(&self.data))
+        else {
+            return;
+        };
+        let calendars = calendars.clone();
+        let mailbox = mailbox.to_string();
+
+        self.spawn_background(Subsystem::Notifications, async move {
+            match calendars.process_itip(&mailbox, &sender, &invitation).await {
+                Ok(itip::ItipOutcome::Ignored(reason)) => {
+                    debug!("Ignoring calendar invitation from {} to {}: {}", sender, mailbox, reason);
+                }
+                Ok(outcome) => debug!("Calendar invitation from {} to {}: {:?}", sender, mailbox, outcome),
+                Err(e) => warn!("Failed to process calendar invitation for {}: {}", mailbox, e),
+            }
+        })
+        .await;
+    }
+
+    /// Log a detected mail loop and alert the postmaster in the background
+    async fn raise_loop_alert(&self, mail_loop: &MailLoop) {
+        let sender = self.from.as_deref().unwrap_or("");
+        warn!(
+            "Mail loop from <{}> to {:?} rejected: {}",
+            sender, self.to, mail_loop
+        );
+
+        if let (Some(router), Some(alert_to)) = (&self.notifications, &self.loop_alert_to) {
+            let router = router.clone();
+            let alert_to = alert_to.clone();
+            let body = format!(
+                "A message from <{}> to {} was rejected: {}",
+                sender,
+                self.to.join(", "),
+                mail_loop
+            );
+
+            self.spawn_background(Subsystem::Notifications, async move {
+                if let Err(e) = router
+                    .notify(&alert_to, EventKind::SecurityAlert, "Mail loop detected", &body)
+                    .await
+                {
+                    warn!("Failed to alert {} of a mail loop: {}", alert_to, e);
+                }
+            })
+            .await;
         }
     }
 
@@ -697,7 +2248,7 @@ impl SmtpSession {
 
         for line in email_data.lines() {
             if line.starts_with("Subject:") {
-                subject = line.trim_start_matches("Subject:").trim().to_string();
+                subject = decode_header(line.trim_start_matches("Subject:"));
             } else if in_body {
                 body.push_str(line);
                 body.push('\n');
@@ -707,10 +2258,7 @@ impl SmtpSession {
         }
 
         // Limit body size for summary
-        if body.len() > 1000 {
-            body.truncate(1000);
-            body.push_str("...");
-        }
+        let body = postdelivery::summary_body(&body);
 
         // Call ai-runtime asynchronously (fire-and-forget)
         let ai_url = std::env::var("AI_RUNTIME_URL")
@@ -719,34 +2267,29 @@ impl SmtpSession {
         let email_id = email_id.to_string();
         let from = from.to_string();
 
-        tokio::spawn(async move {
-            let client = reqwest::Client::new();
-            let payload = serde_json::json!({
-                "user_email": user_email,
-                "email_id": email_id,
-                "from": from,
-                "subject": subject,
-                "body": body
-            });
-
-            match client
-                .post(format!("{}/api/generate-summary", ai_url))
-                .json(&payload)
-                .send()
-                .await
+        self.spawn_background(Subsystem::Ai, async move {
+            if let Err(e) =
+                postdelivery::request_summary(&ai_url, &user_email, &email_id, &from, &subject, &body)
+                    .await
             {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        info!("✅ Summary generation triggered for {}", user_email);
-                    } else {
-                        warn!("⚠️  Summary generation failed: {}", response.status());
-                    }
-                }
-                Err(e) => {
-                    warn!("⚠️  Failed to call ai-runtime: {}", e);
-                }
+                warn!("⚠️  Summary generation failed: {}", e);
             }
-        });
+        })
+        .await;
+    }
+
+    /// Run `task` in the background, in the pool of `subsystem` when the
+    /// session has pools
+    async fn spawn_background<F>(&self, subsystem: Subsystem, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        match &self.workers {
+            Some(workers) => workers.pool(subsystem).spawn(task).await,
+            None => {
+                tokio::spawn(task);
+            }
+        }
     }
 
     /// Handle STARTTLS command and perform TLS upgrade
@@ -834,6 +2377,11 @@ impl SmtpSession {
             })?;
 
         // Replace the stream with the TLS version
+        let (_, connection) = tls_stream.get_ref();
+        self.tls_info = connection
+            .protocol_version()
+            .zip(connection.negotiated_cipher_suite())
+            .map(|(version, suite)| format!("{:?} {:?}", version, suite.suite()));
         *stream = SmtpStream::Tls(tls_stream);
         self.is_encrypted = true;
 
@@ -845,6 +2393,81 @@ impl SmtpSession {
     }
 
     /// Handle AUTH command
+    /// Verify a PLAIN or LOGIN password: the account password, recording
+    /// the client as a device, or an app password of one of the user's
+    /// devices
+    async fn verify_password(
+        &self,
+        mechanism: AuthMechanism,
+        username: &str,
+        password: &str,
+    ) -> Result<bool> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(false);
+        };
+        let verified = authenticator
+            .authenticate_smtp(mechanism, username, password)
+            .await?;
+        let Some(devices) = &self.devices else {
+            return Ok(verified);
+        };
+
+        if verified {
+            let name = self.helo_domain.as_deref().unwrap_or_default();
+            if let Err(e) = devices
+                .record_login(username, DeviceKind::Smtp, name, self.client_ip)
+                .await
+            {
+                warn!("Failed to record device of {}: {}", username, e);
+            }
+            return Ok(true);
+        }
+        match devices
+            .app_password_login(username, password, self.client_ip)
+            .await
+        {
+            Ok(device) => Ok(device.is_some()),
+            Err(e) => {
+                warn!("Failed to check app passwords of {}: {}", username, e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Accept the verified login of `username`, unless the anomaly
+    /// detector blocks it
+    async fn auth_succeeded<S>(&mut self, username: &str, buf_reader: &mut BufReader<S>) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let blocked = match &self.login_anomalies {
+            Some(detector) => match detector
+                .assess(username, LoginProtocol::Smtp, self.client_ip)
+                .await
+            {
+                Ok(assessment) => assessment.decision == Decision::Block,
+                Err(e) => {
+                    warn!("Failed to assess login of {}: {}", username, e);
+                    false
+                }
+            },
+            None => false,
+        };
+        if blocked {
+            warn!("Authentication of {} refused: sign-in from an unusual location", username);
+            buf_reader
+                .write_all(b"535 5.7.8 Sign-in from an unusual location blocked, try again later\r\n")
+                .await?;
+            self.error_count += 1;
+            return Ok(());
+        }
+
+        self.authenticated_user = Some(username.to_string());
+        info!("Authentication successful for {}", username);
+        buf_reader.write_all(b"235 Authentication successful\r\n").await?;
+        Ok(())
+    }
+
     async fn handle_auth<S>(
         &mut self,
         mechanism: &str,
@@ -854,6 +2477,13 @@ impl SmtpSession {
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
     {
+        // Bearer tokens are checked against the OAuth provider, not the user database
+        if let Some(oauth_mechanism) = AuthMechanism::from_str(mechanism).filter(|m| m.is_oauth()) {
+            return self
+                .handle_oauth_auth(oauth_mechanism, initial_response, buf_reader)
+                .await;
+        }
+
         // Check if authenticator is available
         let authenticator = match &self.authenticator {
             Some(auth) => auth,
@@ -863,12 +2493,6 @@ impl SmtpSession {
             }
         };
 
-        // Require TLS if configured
-        if self.tls_config.is_some() && !self.is_encrypted {
-            buf_reader.write_all(b"530 Must issue STARTTLS first\r\n").await?;
-            return Ok(());
-        }
-
         // Check if already authenticated
         if self.authenticated_user.is_some() {
             buf_reader.write_all(b"503 Already authenticated\r\n").await?;
@@ -881,8 +2505,12 @@ impl SmtpSession {
             return Ok(());
         }
 
-        // Parse mechanism
+        // Parse mechanism; CRAM-MD5 only if enabled
         let auth_mechanism = match AuthMechanism::from_str(mechanism) {
+            Some(AuthMechanism::CramMd5) if !authenticator.cram_md5_enabled() => {
+                buf_reader.write_all(b"504 Authentication mechanism not supported\r\n").await?;
+                return Ok(());
+            }
             Some(m) => m,
             None => {
                 buf_reader.write_all(b"504 Authentication mechanism not supported\r\n").await?;
@@ -890,6 +2518,13 @@ impl SmtpSession {
             }
         };
 
+        // PLAIN/LOGIN require TLS if configured; challenge-response
+        // mechanisms never expose the password and are allowed before STARTTLS
+        if auth_mechanism.requires_tls() && self.tls_config.is_some() && !self.is_encrypted {
+            buf_reader.write_all(b"530 Must issue STARTTLS first\r\n").await?;
+            return Ok(());
+        }
+
         info!("AUTH {} initiated", mechanism);
 
         // Handle authentication based on mechanism
@@ -915,16 +2550,15 @@ impl SmtpSession {
                 let (username, password) = Authenticator::decode_plain_auth(&auth_data)?;
 
                 // Authenticate
-                let success = authenticator
-                    .authenticate_smtp(AuthMechanism::Plain, &username, &password)
+                let success = self
+                    .verify_password(AuthMechanism::Plain, &username, &password)
                     .await?;
 
                 if success {
-                    self.authenticated_user = Some(username.clone());
-                    info!("Authentication successful for {}", username);
-                    buf_reader.write_all(b"235 Authentication successful\r\n").await?;
+                    self.auth_succeeded(&username, buf_reader).await?;
                 } else {
                     warn!("Authentication failed for {}", username);
+                    self.record_usage(UsageEventKind::AuthFailure, None, Some(&username)).await;
                     buf_reader.write_all(b"535 Authentication failed\r\n").await?;
                     self.error_count += 1;
                 }
@@ -952,25 +2586,185 @@ impl SmtpSession {
                 let password = Authenticator::decode_login_credential(line.trim())?;
 
                 // Authenticate
+                let success = self
+                    .verify_password(AuthMechanism::Login, &username, &password)
+                    .await?;
+
+                if success {
+                    self.auth_succeeded(&username, buf_reader).await?;
+                } else {
+                    warn!("Authentication failed for {}", username);
+                    self.record_usage(UsageEventKind::AuthFailure, None, Some(&username)).await;
+                    buf_reader.write_all(b"535 Authentication failed\r\n").await?;
+                    self.error_count += 1;
+                }
+            }
+            AuthMechanism::CramMd5 => {
+                // CRAM-MD5: server challenge, client answers "user hexdigest"
+                let challenge = sasl::cram_md5_challenge(&self.hostname);
+                buf_reader
+                    .write_all(format!("334 {}\r\n", BASE64.encode(&challenge)).as_bytes())
+                    .await?;
+
+                let response = Self::read_auth_response(buf_reader).await?;
+                let (username, digest) = sasl::parse_cram_md5_response(&response)?;
+
                 let success = authenticator
-                    .authenticate_smtp(AuthMechanism::Login, &username, &password)
+                    .authenticate_cram_md5(&username, &challenge, &digest)
                     .await?;
 
                 if success {
-                    self.authenticated_user = Some(username.clone());
-                    info!("Authentication successful for {}", username);
-                    buf_reader.write_all(b"235 Authentication successful\r\n").await?;
+                    self.auth_succeeded(&username, buf_reader).await?;
                 } else {
                     warn!("Authentication failed for {}", username);
+                    self.record_usage(UsageEventKind::AuthFailure, None, Some(&username)).await;
                     buf_reader.write_all(b"535 Authentication failed\r\n").await?;
                     self.error_count += 1;
                 }
             }
+            AuthMechanism::OAuthBearer | AuthMechanism::XOAuth2 => {
+                // Dispatched to handle_oauth_auth before the user database checks
+                buf_reader.write_all(b"504 Authentication mechanism not supported\r\n").await?;
+            }
+            AuthMechanism::ScramSha256 => {
+                // SCRAM: client-first -> server-first -> client-final -> server-final
+                let client_first = match initial_response {
+                    Some(data) => Authenticator::decode_login_credential(&data)?,
+                    None => {
+                        buf_reader.write_all(b"334 \r\n").await?;
+                        Self::read_auth_response(buf_reader).await?
+                    }
+                };
+
+                let mut scram = ScramServer::parse_client_first(&client_first)?;
+                let username = scram.username().to_string();
+                let credentials = authenticator.get_scram_credentials(&username).await?;
+
+                let server_first = scram.server_first(credentials);
+                buf_reader
+                    .write_all(format!("334 {}\r\n", BASE64.encode(&server_first)).as_bytes())
+                    .await?;
+
+                let client_final = Self::read_auth_response(buf_reader).await?;
+                match scram.verify_client_final(&client_final)? {
+                    Some(server_final) => {
+                        // Client acknowledges the server signature with an empty line
+                        buf_reader
+                            .write_all(format!("334 {}\r\n", BASE64.encode(&server_final)).as_bytes())
+                            .await?;
+                        Self::read_auth_response(buf_reader).await?;
+
+                        authenticator.record_login(&username).await?;
+                        self.auth_succeeded(&username, buf_reader).await?;
+                    }
+                    None => {
+                        warn!("Authentication failed for {}", username);
+                        self.record_usage(UsageEventKind::AuthFailure, None, Some(&username)).await;
+                        buf_reader.write_all(b"535 Authentication failed\r\n").await?;
+                        self.error_count += 1;
+                    }
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Handle AUTH OAUTHBEARER / XOAUTH2
+    async fn handle_oauth_auth<S>(
+        &mut self,
+        mechanism: AuthMechanism,
+        initial_response: Option<String>,
+        buf_reader: &mut BufReader<S>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let validator = match &self.oauth_validator {
+            Some(validator) => validator.clone(),
+            None => {
+                buf_reader.write_all(b"504 Authentication mechanism not supported\r\n").await?;
+                return Ok(());
+            }
+        };
+
+        // Bearer tokens are reusable secrets: require TLS if configured
+        if self.tls_config.is_some() && !self.is_encrypted {
+            buf_reader.write_all(b"530 Must issue STARTTLS first\r\n").await?;
+            return Ok(());
+        }
+
+        if self.authenticated_user.is_some() {
+            buf_reader.write_all(b"503 Already authenticated\r\n").await?;
+            return Ok(());
+        }
+
+        if self.state != SmtpState::Greeted {
+            buf_reader.write_all(b"503 Bad sequence of commands\r\n").await?;
+            return Ok(());
+        }
+
+        info!("AUTH {} initiated", mechanism.as_str());
+
+        let response = match initial_response {
+            Some(data) => Authenticator::decode_login_credential(&data)?,
+            None => {
+                buf_reader.write_all(b"334 \r\n").await?;
+                Self::read_auth_response(buf_reader).await?
+            }
+        };
+
+        let (claimed_user, token) = match mechanism {
+            AuthMechanism::XOAuth2 => {
+                let (user, token) = oauth::parse_xoauth2(response.as_bytes())?;
+                (Some(user), token)
+            }
+            _ => oauth::parse_oauthbearer(response.as_bytes())?,
+        };
+
+        match validator.authenticate(claimed_user.as_deref(), &token).await {
+            Ok(username) => {
+                self.auth_succeeded(&username, buf_reader).await?;
+            }
+            Err(e) => {
+                warn!("OAuth authentication failed: {}", e);
+                self.record_usage(UsageEventKind::AuthFailure, None, claimed_user.as_deref())
+                    .await;
+                // RFC 7628: error challenge, then the client sends a dummy response
+                buf_reader
+                    .write_all(
+                        format!("334 {}\r\n", BASE64.encode(oauth::OAUTHBEARER_ERROR)).as_bytes(),
+                    )
+                    .await?;
+                let _ = Self::read_auth_response(buf_reader).await;
+                buf_reader.write_all(b"535 Authentication failed\r\n").await?;
+                self.error_count += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read and decode one base64 SASL response line
+    ///
+    /// A `*` line cancels the exchange (RFC 4954).
+    async fn read_auth_response<S>(buf_reader: &mut BufReader<S>) -> Result<String>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut line = String::new();
+        timeout(COMMAND_TIMEOUT, buf_reader.read_line(&mut line))
+            .await
+            .map_err(|_| MailError::SmtpProtocol("AUTH timeout".to_string()))??;
+
+        let line = line.trim();
+        if line == "*" {
+            return Err(MailError::SmtpProtocol("AUTH cancelled by client".to_string()));
+        }
+
+        Authenticator::decode_login_credential(line)
+    }
+
     /// Validate SPF and DKIM for incoming message
     async fn validate_authentication(&self) -> Option<crate::authentication::types::AuthenticationResults> {
         use crate::authentication::types::{AuthenticationResults, AuthenticationStatus, DkimAuthResult, SpfAuthResult};
@@ -997,6 +2791,7 @@ impl SmtpSession {
                             client_ip: client_ip.to_string(),
                             envelope_from: from.clone(),
                             reason: Some(format!("SPF validation error: {}", e)),
+                            mechanism: None,
                         }
                     }
                 }
@@ -1007,6 +2802,7 @@ impl SmtpSession {
                     client_ip: self.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
                     envelope_from: self.from.clone().unwrap_or_default(),
                     reason: Some("Missing client IP, envelope from, or HELO domain".to_string()),
+                    mechanism: None,
                 }
             }
         } else {
@@ -1015,6 +2811,7 @@ impl SmtpSession {
                 client_ip: String::new(),
                 envelope_from: String::new(),
                 reason: Some("SPF validation disabled".to_string()),
+                mechanism: None,
             }
         };
 
@@ -1059,6 +2856,48 @@ impl SmtpSession {
         })
     }
 
+    /// Greylist the message's triplets; the reply deferring or refusing
+    /// it, None to accept it. Lookup failures accept the message.
+    async fn check_greylist(
+        &self,
+        auth_result: Option<&crate::authentication::types::AuthenticationResults>,
+    ) -> Option<String> {
+        use crate::authentication::types::AuthenticationStatus;
+
+        let manager = self.greylist.as_ref()?;
+        // Colleagues sending through this server are not greylisted
+        if self.authenticated_user.is_some() {
+            return None;
+        }
+        let client_ip = self.client_ip?.to_string();
+        let from = self.from.clone().unwrap_or_default();
+        let passed_auth = auth_result.is_some_and(|result| {
+            result.spf.status == AuthenticationStatus::Pass
+                || result.dkim.status == AuthenticationStatus::Pass
+        });
+
+        let mut greylisted = false;
+        for recipient in &self.to {
+            match manager
+                .check_authenticated(&from, recipient, &client_ip, passed_auth)
+                .await
+            {
+                Ok(GreylistStatus::Blacklisted) => {
+                    warn!("Rejecting blacklisted sender {} from {}", from, client_ip);
+                    return Some("550 5.7.1 Sender is blacklisted\r\n".to_string());
+                }
+                Ok(GreylistStatus::Greylisted) => greylisted = true,
+                Ok(GreylistStatus::Whitelisted) => {}
+                Err(e) => warn!("Greylist check failed: {}", e),
+            }
+        }
+        if greylisted {
+            info!("Greylisting mail from {} via {}", from, client_ip);
+            return Some("451 4.7.1 Greylisted, please try again later\r\n".to_string());
+        }
+        None
+    }
+
     /// Determine if message should be rejected based on authentication results
     fn should_reject_message(&self, result: &crate::authentication::types::AuthenticationResults) -> bool {
         use crate::authentication::types::AuthenticationStatus;
@@ -1073,6 +2912,35 @@ impl SmtpSession {
         false
     }
 
+    /// Describe this hop for the `Received:` header
+    fn trace_info(&self) -> TraceInfo {
+        let mut trace = TraceInfo::new(self.hostname.clone(), self.protocol);
+        trace.helo = self.helo_domain.clone();
+        trace.client_ip = self.client_ip;
+        trace.client_hostname = self.client_hostname.clone();
+        trace.authenticated_user = self.authenticated_user.clone();
+        trace.id = Some(uuid::Uuid::new_v4().simple().to_string()[..12].to_uppercase());
+        if self.is_encrypted {
+            trace.tls = Some(self.tls_info.clone().unwrap_or_else(|| "TLS".to_string()));
+        }
+        trace
+    }
+
+    /// Prepend the `Received:` header of this hop to the message
+    fn prepend_received_header(&mut self) {
+        let recipient = match self.to.as_slice() {
+            [single] => Some(single.as_str()),
+            _ => None,
+        };
+        let trace = self.trace_info();
+        let header = trace.received_header(recipient, chrono::Utc::now());
+        self.received_id = trace.id;
+
+        let mut new_data = header.into_bytes();
+        new_data.extend_from_slice(&self.data);
+        self.data = new_data;
+    }
+
     /// Prepend Authentication-Results header to message
     fn prepend_auth_header(&mut self, result: &crate::authentication::types::AuthenticationResults) {
         let header = result.to_header(&self.hostname);
@@ -0,0 +1,326 @@
+//! Sender Rewriting Scheme (SRS)
+//!
+//! When mail is forwarded off-host, the original envelope sender would fail
+//! SPF at the next hop. SRS rewrites it into an address in our own domain
+//! that encodes the original sender, so bounces come back to us and can be
+//! routed to the real sender.
+//!
+//! # Address formats
+//! - `SRS0=HHHH=TT=domain=local@srs-domain` for a plain sender
+//! - `SRS1=HHHH=forwarder==HHHH=TT=domain=local@srs-domain` when the sender
+//!   was already rewritten by another forwarder
+//!
+//! `HHHH` is a truncated HMAC of the rest of the address, `TT` the day the
+//! address was created (base32, modulo 1024). Bounces to addresses with a bad
+//! hash or older than `max_age_days` are refused, so the SRS domain cannot be
+//! abused as an open relay.
+//!
+//! # Configuration
+//! ```toml
+//! [srs]
+//! enabled = true
+//! secret = "change-me"
+//! domain = "example.com"
+//! max_age_days = 21
+//! ```
+
+use crate::config::SrsConfig;
+use crate::error::{MailError, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Base32 alphabet used for timestamps
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Timestamps wrap around after this many days
+const TIMESTAMP_PERIOD: u64 = 1024;
+
+/// Number of hash characters kept in addresses
+const HASH_LENGTH: usize = 4;
+
+/// SRS address rewriter
+pub struct Srs {
+    secret: Vec<u8>,
+    domain: String,
+    max_age_days: u64,
+}
+
+impl Srs {
+    /// Create a rewriter from the `[srs]` config section
+    ///
+    /// `default_domain` is used when no SRS domain is configured.
+    pub fn new(config: &SrsConfig, default_domain: &str) -> Result<Self> {
+        if config.secret.is_empty() {
+            return Err(MailError::Config("srs.secret must be set".to_string()));
+        }
+
+        Ok(Self {
+            secret: config.secret.as_bytes().to_vec(),
+            domain: config
+                .domain
+                .clone()
+                .unwrap_or_else(|| default_domain.to_string())
+                .to_lowercase(),
+            max_age_days: config.max_age_days,
+        })
+    }
+
+    /// Rewrite an envelope sender for forwarding
+    ///
+    /// The null sender and senders already in the SRS domain are returned
+    /// unchanged.
+    pub fn forward(&self, sender: &str) -> String {
+        self.forward_at(sender, today())
+    }
+
+    /// Recover the original sender from an SRS address
+    pub fn reverse(&self, address: &str) -> Result<String> {
+        self.reverse_at(address, today())
+    }
+
+    /// True if `address` is an SRS address in our domain
+    pub fn is_srs_address(&self, address: &str) -> bool {
+        match address.rsplit_once('@') {
+            Some((local, domain)) => {
+                domain.eq_ignore_ascii_case(&self.domain) && srs_tag(local).is_some()
+            }
+            None => false,
+        }
+    }
+
+    fn forward_at(&self, sender: &str, day: u64) -> String {
+        let Some((local, domain)) = sender.rsplit_once('@') else {
+            return sender.to_string();
+        };
+        if domain.eq_ignore_ascii_case(&self.domain) {
+            return sender.to_string();
+        }
+
+        let rewritten = match srs_tag(local) {
+            // SRS0=... from another forwarder: keep its part, point back at it
+            Some(0) => {
+                let rest = &local[4..];
+                format!("SRS1={}={}={}", self.hash(&[domain, rest]), domain, rest)
+            }
+            // SRS1=HHHH=forwarder==...: keep the first forwarder
+            Some(_) => match local[5..].split_once('=') {
+                Some((_, rest)) => match rest.split_once('=') {
+                    Some((first_hop, opaque)) => format!(
+                        "SRS1={}={}={}",
+                        self.hash(&[first_hop, opaque]),
+                        first_hop,
+                        opaque
+                    ),
+                    None => return sender.to_string(),
+                },
+                None => return sender.to_string(),
+            },
+            None => {
+                let timestamp = encode_timestamp(day);
+                format!(
+                    "SRS0={}={}={}={}",
+                    self.hash(&[&timestamp, domain, local]),
+                    timestamp,
+                    domain,
+                    local
+                )
+            }
+        };
+
+        format!("{}@{}", rewritten, self.domain)
+    }
+
+    fn reverse_at(&self, address: &str, day: u64) -> Result<String> {
+        let invalid = || MailError::InvalidEmail(format!("Invalid SRS address: {}", address));
+
+        let (local, domain) = address.rsplit_once('@').ok_or_else(invalid)?;
+        if !domain.eq_ignore_ascii_case(&self.domain) {
+            return Err(invalid());
+        }
+
+        match srs_tag(local) {
+            Some(0) => {
+                let mut parts = local[5..].splitn(4, '=');
+                let (hash, timestamp, orig_domain, orig_local) =
+                    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                        (Some(h), Some(t), Some(d), Some(l)) if !d.is_empty() && !l.is_empty() => {
+                            (h, t, d, l)
+                        }
+                        _ => return Err(invalid()),
+                    };
+
+                self.check_hash(hash, &[timestamp, orig_domain, orig_local])
+                    .ok_or_else(invalid)?;
+
+                let age = decode_timestamp(timestamp)
+                    .map(|stamp| (day + TIMESTAMP_PERIOD - stamp) % TIMESTAMP_PERIOD)
+                    .ok_or_else(invalid)?;
+                if age > self.max_age_days {
+                    return Err(MailError::InvalidEmail(format!(
+                        "Expired SRS address: {}",
+                        address
+                    )));
+                }
+
+                Ok(format!("{}@{}", orig_local, orig_domain))
+            }
+            Some(_) => {
+                let (hash, rest) = local[5..].split_once('=').ok_or_else(invalid)?;
+                let (first_hop, opaque) = rest.split_once('=').ok_or_else(invalid)?;
+                if first_hop.is_empty() || opaque.is_empty() {
+                    return Err(invalid());
+                }
+
+                self.check_hash(hash, &[first_hop, opaque])
+                    .ok_or_else(invalid)?;
+
+                // The first forwarder validates its own SRS0 part
+                Ok(format!("SRS0{}@{}", opaque, first_hop))
+            }
+            None => Err(invalid()),
+        }
+    }
+
+    fn hash(&self, parts: &[&str]) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        for part in parts {
+            mac.update(part.to_lowercase().as_bytes());
+        }
+        let digest = BASE64.encode(mac.finalize().into_bytes());
+        digest[..HASH_LENGTH].to_string()
+    }
+
+    /// Compare hashes case-insensitively, as some MTAs lowercase addresses
+    fn check_hash(&self, hash: &str, parts: &[&str]) -> Option<()> {
+        self.hash(parts).eq_ignore_ascii_case(hash).then_some(())
+    }
+}
+
+/// SRS version of a local part (`SRS0=` or `SRS1=`), if any
+fn srs_tag(local: &str) -> Option<u8> {
+    let prefix = local.get(..5)?;
+    if prefix.eq_ignore_ascii_case("SRS0=") {
+        Some(0)
+    } else if prefix.eq_ignore_ascii_case("SRS1=") {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+fn today() -> u64 {
+    (chrono::Utc::now().timestamp() / 86_400) as u64
+}
+
+fn encode_timestamp(day: u64) -> String {
+    let day = day % TIMESTAMP_PERIOD;
+    let chars = [BASE32[(day >> 5) as usize], BASE32[(day & 31) as usize]];
+    String::from_utf8_lossy(&chars).into_owned()
+}
+
+fn decode_timestamp(timestamp: &str) -> Option<u64> {
+    let bytes = timestamp.as_bytes();
+    if bytes.len() != 2 {
+        return None;
+    }
+
+    let value = |c: u8| {
+        BASE32
+            .iter()
+            .position(|b| *b == c.to_ascii_uppercase())
+            .map(|p| p as u64)
+    };
+    Some((value(bytes[0])? << 5) | value(bytes[1])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srs(domain: &str) -> Srs {
+        Srs::new(
+            &SrsConfig {
+                enabled: true,
+                secret: "s3cret".to_string(),
+                domain: Some(domain.to_string()),
+                max_age_days: 21,
+            },
+            "unused.example",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_forward_and_reverse() {
+        let srs = srs("forwarder.example");
+        let rewritten = srs.forward_at("alice@origin.example", 20_000);
+
+        assert!(rewritten.starts_with("SRS0="));
+        assert!(rewritten.ends_with("=origin.example=alice@forwarder.example"));
+        assert!(srs.is_srs_address(&rewritten));
+        assert_eq!(
+            srs.reverse_at(&rewritten, 20_005).unwrap(),
+            "alice@origin.example"
+        );
+        // Some MTAs lowercase the whole address
+        assert_eq!(
+            srs.reverse_at(&rewritten.to_lowercase(), 20_005).unwrap(),
+            "alice@origin.example"
+        );
+    }
+
+    #[test]
+    fn test_reverse_rejects_tampered_and_expired() {
+        let srs = srs("forwarder.example");
+        let rewritten = srs.forward_at("alice@origin.example", 20_000);
+
+        let tampered = rewritten.replace("alice", "mallory");
+        assert!(srs.reverse_at(&tampered, 20_000).is_err());
+        assert!(srs.reverse_at(&rewritten, 20_022).is_err());
+        assert!(srs.reverse_at("SRS0=bad@forwarder.example", 20_000).is_err());
+    }
+
+    #[test]
+    fn test_timestamp_wraps() {
+        let srs = srs("forwarder.example");
+        let rewritten = srs.forward_at("alice@origin.example", 1023);
+        assert!(srs.reverse_at(&rewritten, 1025).is_ok());
+        assert_eq!(decode_timestamp(&encode_timestamp(777)), Some(777));
+    }
+
+    #[test]
+    fn test_double_forwarding_uses_srs1() {
+        let first = srs("first.example");
+        let second = srs("second.example");
+
+        let srs0 = first.forward_at("alice@origin.example", 20_000);
+        let srs1 = second.forward_at(&srs0, 20_000);
+        assert!(srs1.starts_with("SRS1="));
+        assert!(srs1.ends_with("@second.example"));
+
+        // A third hop keeps pointing at the first forwarder
+        let third = srs("third.example");
+        let srs1_again = third.forward_at(&srs1, 20_000);
+        assert!(srs1_again.contains("=first.example=="));
+
+        let back_to_first = second.reverse_at(&srs1, 20_000).unwrap();
+        assert_eq!(back_to_first, srs0);
+        assert_eq!(
+            first.reverse_at(&back_to_first, 20_000).unwrap(),
+            "alice@origin.example"
+        );
+    }
+
+    #[test]
+    fn test_local_and_null_senders_unchanged() {
+        let srs = srs("forwarder.example");
+        assert_eq!(srs.forward_at("", 1), "");
+        assert_eq!(
+            srs.forward_at("bob@forwarder.example", 1),
+            "bob@forwarder.example"
+        );
+    }
+}
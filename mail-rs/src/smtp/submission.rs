@@ -0,0 +1,281 @@
+//! Message submission listener (RFC 6409)
+//!
+//! A second SMTP listener, usually on port 587, dedicated to mail from
+//! authenticated users. Unlike the MX listener it:
+//! - always requires STARTTLS and AUTH before MAIL FROM
+//! - enforces per-user daily sending quotas
+//! - appends the footers of sender domains when `[footers]` is enabled
+//! - DKIM-signs messages when signing is configured
+//! - refuses infected messages when `[antivirus]` is enabled
+//! - hands messages to the outbound queue instead of local storage
+
+use crate::antivirus::VirusScanner;
+use crate::config::Config;
+use crate::devices::DeviceManager;
+use crate::login_anomaly::LoginAnomalyDetector;
+use crate::error::{MailError, Result};
+use crate::quota::{QuotaManager, UserQuota};
+use crate::security::{Authenticator, OAuthValidator, TlsConfig};
+use crate::smtp::queue::SmtpQueue;
+use crate::smtp::server::{
+    build_billing_manager, build_footer_manager, build_oauth_validator, build_reporting_manager,
+    build_tls_reporting, build_virus_scanner,
+};
+use crate::smtp::session::SmtpSession;
+use crate::storage::MaildirStorage;
+use crate::workers::{Subsystem, WorkerPools};
+use crate::recovery::ReadOnlyMode;
+use crate::utils::dkim_signer::DkimSigner;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+/// Interval between resets of the daily sending counters
+const QUOTA_RESET_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct SubmissionServer {
+    config: Config,
+    storage: Arc<MaildirStorage>,
+    tls_config: Arc<TlsConfig>,
+    authenticator: Arc<Authenticator>,
+    outbound_queue: Arc<SmtpQueue>,
+    dkim_signer: Option<Arc<DkimSigner>>,
+    quota_manager: Arc<QuotaManager>,
+    oauth_validator: Option<Arc<OAuthValidator>>,
+    devices: Option<Arc<DeviceManager>>,
+    login_anomalies: Option<Arc<LoginAnomalyDetector>>,
+    antivirus: Option<Arc<VirusScanner>>,
+    workers: Arc<WorkerPools>,
+    read_only: Arc<ReadOnlyMode>,
+}
+
+impl SubmissionServer {
+    /// Create the submission server
+    ///
+    /// TLS certificates and the auth database are mandatory here: a
+    /// submission port without them would accept unauthenticated relay.
+    pub async fn new(config: Config, storage: Arc<MaildirStorage>) -> Result<Self> {
+        Self::with_components(config, storage, None, None).await
+    }
+
+    /// Create the submission server with the given TLS configuration and
+    /// authenticator; `None` loads them from the config like [`Self::new`]
+    pub async fn with_components(
+        config: Config,
+        storage: Arc<MaildirStorage>,
+        tls_config: Option<Arc<TlsConfig>>,
+        authenticator: Option<Arc<Authenticator>>,
+    ) -> Result<Self> {
+        let tls_config = match (tls_config, &config.smtp.tls_cert_path, &config.smtp.tls_key_path) {
+            (Some(tls_config), _, _) => tls_config,
+            (None, Some(cert_path), Some(key_path)) => {
+                Arc::new(TlsConfig::from_pem_files(cert_path, key_path)?)
+            }
+            _ => {
+                return Err(MailError::Config(
+                    "Submission requires smtp.tls_cert_path and smtp.tls_key_path".to_string(),
+                ))
+            }
+        };
+
+        let authenticator = match (authenticator, &config.smtp.auth_database_url) {
+            (Some(authenticator), _) => authenticator,
+            (None, Some(db_url)) => Arc::new(
+                Authenticator::new(db_url)
+                    .await?
+                    .with_cram_md5(config.smtp.allow_cram_md5),
+            ),
+            (None, None) => {
+                return Err(MailError::Config(
+                    "Submission requires smtp.auth_database_url".to_string(),
+                ))
+            }
+        };
+
+        let dkim_signer = if config.authentication.dkim_enabled {
+            let key_pem = std::fs::read_to_string(&config.authentication.dkim_private_key_path)
+                .map_err(|e| {
+                    MailError::Config(format!("Failed to read DKIM private key: {}", e))
+                })?;
+            Some(Arc::new(DkimSigner::new(
+                &config.authentication.dkim_domain,
+                &config.authentication.dkim_selector,
+                &key_pem,
+            )?))
+        } else {
+            None
+        };
+
+        let mut outbound_queue = SmtpQueue::from_config(&config).await?;
+        if let Some(tls_reporting) = build_tls_reporting(&config).await? {
+            info!("TLS reporting enabled for outbound mail");
+            outbound_queue = outbound_queue.with_tls_reporting(tls_reporting);
+        }
+        let outbound_queue = Arc::new(outbound_queue);
+
+        let quota_manager = Arc::new(QuotaManager::with_defaults(UserQuota {
+            message_limit_daily: config.submission.daily_message_limit,
+            max_message_size: config.smtp.max_message_size as u64,
+            ..Default::default()
+        }));
+
+        let oauth_validator = build_oauth_validator(&config);
+        let antivirus = build_virus_scanner(&config);
+        let read_only = Arc::new(ReadOnlyMode::from_config(&config.recovery));
+        let workers = Arc::new(WorkerPools::pausing_writers(&config.workers, read_only.clone()));
+
+        Ok(Self {
+            config,
+            storage,
+            tls_config,
+            authenticator,
+            outbound_queue,
+            dkim_signer,
+            quota_manager,
+            oauth_validator,
+            devices: None,
+            login_anomalies: None,
+            antivirus,
+            workers,
+            read_only,
+        })
+    }
+
+    /// Record the clients of users that log in as devices and accept their
+    /// app passwords
+    pub fn with_devices(mut self, devices: Arc<DeviceManager>) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Refuse logins from unusual locations while the anomaly detector
+    /// blocks them
+    pub fn with_login_anomalies(mut self, detector: Arc<LoginAnomalyDetector>) -> Self {
+        self.login_anomalies = Some(detector);
+        self
+    }
+
+    /// Scan submitted messages with this scanner instead of a scanner of
+    /// its own
+    pub fn with_antivirus(mut self, scanner: Arc<VirusScanner>) -> Self {
+        self.antivirus = Some(scanner);
+        self
+    }
+
+    /// Deliver and run background work in these pools instead of pools of
+    /// its own
+    pub fn with_workers(mut self, workers: Arc<WorkerPools>) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Share this read-only flag, e.g. the one the API toggles, instead of
+    /// a flag of its own; pools given to [`Self::with_workers`] should
+    /// pause on the same flag
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = mode;
+        self
+    }
+
+    /// Per-user sending quotas, e.g. for the admin API
+    pub fn quota_manager(&self) -> Arc<QuotaManager> {
+        self.quota_manager.clone()
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let listener = TcpListener::bind(&self.config.submission.listen_addr).await?;
+        self.serve(listener).await
+    }
+
+    /// Accept connections on an already bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("Submission server listening on {}", listener.local_addr()?);
+        if self.dkim_signer.is_some() {
+            info!("Outgoing messages will be DKIM-signed");
+        }
+
+        let pool = self.workers.pool(Subsystem::Delivery).clone();
+        tokio::spawn(self.outbound_queue.clone().start_worker_in(Some(pool)));
+
+        let quota_manager = self.quota_manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(QUOTA_RESET_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = quota_manager.reset_daily_counts().await {
+                    warn!("Failed to reset daily sending quotas: {}", e);
+                }
+            }
+        });
+
+        let reporting = build_reporting_manager(&self.config).await?;
+        let billing = build_billing_manager(&self.config).await?;
+        let footers = build_footer_manager(&self.config).await?;
+
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    info!("New submission connection from {}", addr);
+
+                    let session = SmtpSession::with_security(
+                        self.config.server.hostname.clone(),
+                        self.storage.clone(),
+                        self.config.smtp.max_message_size,
+                        Some(self.tls_config.clone()),
+                        Some(self.authenticator.clone()),
+                        true,
+                        true,
+                        self.config.authentication.clone(),
+                    )
+                    .with_submission(
+                        self.outbound_queue.clone(),
+                        self.dkim_signer.clone(),
+                        self.quota_manager.clone(),
+                    );
+                    let session = match &self.oauth_validator {
+                        Some(validator) => session.with_oauth(validator.clone()),
+                        None => session,
+                    };
+                    let session = match &reporting {
+                        Some(reporting) => session.with_reporting(reporting.clone()),
+                        None => session,
+                    };
+                    let session = match &billing {
+                        Some(billing) => session.with_billing(billing.clone()),
+                        None => session,
+                    };
+                    let session = match &self.devices {
+                        Some(devices) => session.with_devices(devices.clone()),
+                        None => session,
+                    };
+                    let session = match &self.login_anomalies {
+                        Some(detector) => session.with_login_anomalies(detector.clone()),
+                        None => session,
+                    };
+                    let session = match &self.antivirus {
+                        Some(scanner) => session.with_antivirus(scanner.clone()),
+                        None => session,
+                    };
+                    let session = session
+                        .with_workers(self.workers.clone())
+                        .with_read_only(self.read_only.clone());
+                    let session = match &footers {
+                        Some(footers) => session.with_footers(footers.clone()),
+                        None => session,
+                    };
+
+                    tokio::spawn(async move {
+                        if let Err(e) = session.handle(socket).await {
+                            error!("Submission session error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                }
+            }
+        }
+    }
+}
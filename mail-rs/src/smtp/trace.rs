@@ -0,0 +1,227 @@
+//! Trace headers added on receipt and final delivery (RFC 5321 section 4.4)
+//!
+//! Every message accepted by the server gets a `Received:` header describing
+//! the hop: client HELO name, reverse DNS and IP, TLS parameters, the
+//! authenticated user and the protocol (`WITH` types from RFC 3848). Messages
+//! stored in a local mailbox also get a `Return-Path:` header carrying the
+//! envelope sender. The same [`TraceInfo`] serves SMTP and LMTP sessions.
+
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+use std::time::Duration;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Longest wait for the client's PTR record
+const REVERSE_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Protocol a message was received with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Client greeted with HELO
+    Smtp,
+    /// Client greeted with EHLO
+    Esmtp,
+    Lmtp,
+}
+
+/// Everything known about the client of one received message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceInfo {
+    /// Host name of this server
+    pub hostname: String,
+    pub protocol: Protocol,
+    /// Name given on HELO/EHLO/LHLO
+    pub helo: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    /// Reverse DNS name of the client
+    pub client_hostname: Option<String>,
+    /// Negotiated TLS version and cipher suite, if encrypted
+    pub tls: Option<String>,
+    /// User authenticated with AUTH
+    pub authenticated_user: Option<String>,
+    /// Queue or message identifier of this hop
+    pub id: Option<String>,
+}
+
+impl TraceInfo {
+    pub fn new(hostname: impl Into<String>, protocol: Protocol) -> Self {
+        Self {
+            hostname: hostname.into(),
+            protocol,
+            helo: None,
+            client_ip: None,
+            client_hostname: None,
+            tls: None,
+            authenticated_user: None,
+            id: None,
+        }
+    }
+
+    /// `WITH` protocol type (RFC 3848): `S` for TLS, `A` for authenticated
+    pub fn with_type(&self) -> String {
+        let base = match self.protocol {
+            Protocol::Smtp => return "SMTP".to_string(),
+            Protocol::Esmtp => "ESMTP",
+            Protocol::Lmtp => "LMTP",
+        };
+        let mut with = base.to_string();
+        if self.tls.is_some() {
+            with.push('S');
+        }
+        if self.authenticated_user.is_some() {
+            with.push('A');
+        }
+        with
+    }
+
+    /// `Received:` header line, folded, with the trailing CRLF
+    ///
+    /// `recipient` adds a `for` clause; leave it out for messages with
+    /// several recipients so they don't learn about each other.
+    pub fn received_header(&self, recipient: Option<&str>, at: DateTime<Utc>) -> String {
+        let helo = self
+            .helo
+            .as_deref()
+            .map(sanitize)
+            .filter(|helo| !helo.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let mut client = Vec::new();
+        if let Some(hostname) = &self.client_hostname {
+            client.push(sanitize(hostname));
+        }
+        if let Some(ip) = self.client_ip {
+            client.push(match ip {
+                IpAddr::V4(ip) => format!("[{}]", ip),
+                IpAddr::V6(ip) => format!("[IPv6:{}]", ip),
+            });
+        }
+
+        let mut header = format!("Received: from {}", helo);
+        if !client.is_empty() {
+            header.push_str(&format!(" ({})", client.join(" ")));
+        }
+        if let Some(tls) = &self.tls {
+            header.push_str(&format!("\r\n\t(using {})", sanitize(tls)));
+        }
+        if let Some(user) = &self.authenticated_user {
+            header.push_str(&format!("\r\n\t(authenticated sender: {})", sanitize(user)));
+        }
+        header.push_str(&format!(
+            "\r\n\tby {} (mail-rs) with {}",
+            sanitize(&self.hostname),
+            self.with_type()
+        ));
+        if let Some(id) = &self.id {
+            header.push_str(&format!(" id {}", sanitize(id)));
+        }
+        if let Some(recipient) = recipient {
+            header.push_str(&format!("\r\n\tfor <{}>", sanitize(recipient)));
+        }
+        header.push_str(&format!(";\r\n\t{}\r\n", at.to_rfc2822()));
+        header
+    }
+}
+
+/// `Return-Path:` header line for the envelope sender (`<>` for bounces)
+pub fn return_path_header(sender: &str) -> String {
+    format!("Return-Path: <{}>\r\n", sanitize(sender))
+}
+
+/// Reverse DNS name of a client, skipping loopback and private addresses
+pub async fn lookup_client_hostname(ip: IpAddr) -> Option<String> {
+    let local = match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+    };
+    if local {
+        return None;
+    }
+
+    let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
+    let lookup = tokio::time::timeout(REVERSE_LOOKUP_TIMEOUT, resolver.reverse_lookup(ip))
+        .await
+        .ok()?
+        .ok()?;
+    let name = lookup.iter().next()?.to_string();
+    Some(name.trim_end_matches('.').to_string())
+}
+
+/// Header-safe text: control characters, comment delimiters and
+/// backslashes removed
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '(' | ')' | '\\' | '<' | '>' | ';'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-03-10T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_with_type() {
+        let mut trace = TraceInfo::new("mx.example.com", Protocol::Esmtp);
+        assert_eq!(trace.with_type(), "ESMTP");
+        trace.tls = Some("TLSv1_3 TLS13_AES_256_GCM_SHA384".to_string());
+        assert_eq!(trace.with_type(), "ESMTPS");
+        trace.authenticated_user = Some("alice@example.com".to_string());
+        assert_eq!(trace.with_type(), "ESMTPSA");
+        trace.protocol = Protocol::Lmtp;
+        assert_eq!(trace.with_type(), "LMTPSA");
+        trace.protocol = Protocol::Smtp;
+        assert_eq!(trace.with_type(), "SMTP");
+    }
+
+    #[test]
+    fn test_received_header() {
+        let mut trace = TraceInfo::new("mx.example.com", Protocol::Esmtp);
+        trace.helo = Some("client.example.org".to_string());
+        trace.client_ip = Some("192.0.2.1".parse().unwrap());
+        trace.client_hostname = Some("mail.example.org".to_string());
+        trace.tls = Some("TLSv1_3 TLS13_AES_256_GCM_SHA384".to_string());
+        trace.id = Some("abc123".to_string());
+
+        assert_eq!(
+            trace.received_header(Some("bob@example.com"), at()),
+            "Received: from client.example.org (mail.example.org [192.0.2.1])\r\n\
+             \t(using TLSv1_3 TLS13_AES_256_GCM_SHA384)\r\n\
+             \tby mx.example.com (mail-rs) with ESMTPS id abc123\r\n\
+             \tfor <bob@example.com>;\r\n\
+             \tSun, 10 Mar 2024 12:00:00 +0000\r\n"
+        );
+    }
+
+    #[test]
+    fn test_received_header_minimal_and_hostile_helo() {
+        let mut trace = TraceInfo::new("mx.example.com", Protocol::Esmtp);
+        trace.client_ip = Some("2001:db8::1".parse().unwrap());
+        trace.authenticated_user = Some("alice@example.com".to_string());
+        let header = trace.received_header(None, at());
+        assert!(header.starts_with("Received: from unknown ([IPv6:2001:db8::1])\r\n"));
+        assert!(header.contains("(authenticated sender: alice@example.com)"));
+        assert!(header.contains("with ESMTPA;"));
+
+        trace.helo = Some("evil)\r\nX-Injected: yes".to_string());
+        let header = trace.received_header(None, at());
+        assert!(header.starts_with("Received: from evilX-Injected: yes ("));
+        assert_eq!(header.matches("\r\n").count(), 4);
+    }
+
+    #[test]
+    fn test_return_path() {
+        assert_eq!(
+            return_path_header("alice@example.org"),
+            "Return-Path: <alice@example.org>\r\n"
+        );
+        assert_eq!(return_path_header(""), "Return-Path: <>\r\n");
+    }
+}
@@ -0,0 +1,123 @@
+//! Transcripts of outbound SMTP sessions
+//!
+//! The client records every command and reply of a delivery attempt, along
+//! with the remote IP and negotiated TLS parameters, so operators can see
+//! exactly why a delivery failed. Message content is never recorded: the
+//! DATA payload shows up as a single redaction marker.
+
+use serde::{Deserialize, Serialize};
+
+/// Lines kept per transcript; later lines are dropped
+const MAX_LINES: usize = 200;
+
+/// Characters kept per line
+const MAX_LINE_LEN: usize = 512;
+
+/// Who produced a transcript line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Command sent by us
+    Sent,
+    /// Reply from the remote server
+    Received,
+    /// Connection event noted by the client (connect, TLS, redaction)
+    Info,
+}
+
+/// One line of a transcript
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TranscriptLine {
+    pub direction: Direction,
+    pub text: String,
+}
+
+/// Dialogue of one delivery to one server
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transcript {
+    /// Server address (`host:port`)
+    pub server: String,
+    /// IP address of the last connection
+    pub remote_ip: Option<String>,
+    /// Negotiated TLS version and cipher suite
+    pub tls: Option<String>,
+    pub lines: Vec<TranscriptLine>,
+}
+
+impl Transcript {
+    pub fn new(server: impl Into<String>) -> Self {
+        Self {
+            server: server.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn sent(&mut self, text: &str) {
+        self.push(Direction::Sent, text);
+    }
+
+    pub fn received(&mut self, text: &str) {
+        self.push(Direction::Received, text);
+    }
+
+    pub fn info(&mut self, text: &str) {
+        self.push(Direction::Info, text);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Plain text rendering: `>` for commands, `<` for replies and `*` for
+    /// connection events
+    pub fn render(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| {
+                let marker = match line.direction {
+                    Direction::Sent => '>',
+                    Direction::Received => '<',
+                    Direction::Info => '*',
+                };
+                format!("{} {}\r\n", marker, line.text)
+            })
+            .collect()
+    }
+
+    fn push(&mut self, direction: Direction, text: &str) {
+        if self.lines.len() >= MAX_LINES {
+            return;
+        }
+        let text = text.trim_end();
+        let text = match text.char_indices().nth(MAX_LINE_LEN) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text.to_string(),
+        };
+        self.lines.push(TranscriptLine { direction, text });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_and_limits() {
+        let mut transcript = Transcript::new("mx.example.net:25");
+        transcript.info("connected to 192.0.2.1");
+        transcript.received("220 mx ESMTP\r\n");
+        transcript.sent("EHLO client");
+        transcript.received(&"x".repeat(1000));
+
+        let rendered = transcript.render();
+        assert!(
+            rendered.starts_with("* connected to 192.0.2.1\r\n< 220 mx ESMTP\r\n> EHLO client\r\n")
+        );
+        assert_eq!(transcript.lines[3].text.len(), MAX_LINE_LEN + 3);
+
+        for _ in 0..MAX_LINES {
+            transcript.sent("NOOP");
+        }
+        assert_eq!(transcript.lines.len(), MAX_LINES);
+    }
+}
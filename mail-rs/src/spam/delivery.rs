@@ -0,0 +1,559 @@
+//! Spam scoring of delivered mail
+//!
+//! With `[spam] enabled`, SMTP scores messages from unauthenticated clients
+//! at the end of DATA. Spam headers the message came with are removed and
+//! replaced by the server's own:
+//!
+//! ```text
+//! X-Spam-Score: 6.5
+//! X-Spam-Status: Yes, score=6.5 required=5.0
+//!     tests=BODY_VIAGRA,SUBJECT_FREE
+//! ```
+//!
+//! Recipients with quarantine enabled get messages reaching their spam
+//! threshold in their Junk folder. A score at or above the hard
+//! `reject_threshold` refuses the message for every recipient, unless some
+//! are tag-only (role accounts): those get it with the headers only and
+//! the others in their Junk folder.
+//!
+//! With a URI blocklist checker ([`crate::antispam::uribl`]), the built-in
+//! score also counts links to listed domains, reported as
+//! `X-Spam-Report: uribl=spam.example@multi.surbl.org`.
+//!
+//! With a quarantine store ([`super::quarantine`]), those messages are held
+//! there instead of filed into Junk, and with `hold_rejected` refused ones
+//! are held too.
+//!
+//! With `backend = "rspamd"` the score and verdict come from rspamd instead
+//! (see [`super::rspamd`]).
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::warn;
+
+use super::manager::SpamManager;
+use super::quarantine::QuarantineStore;
+use crate::antispam::UriblChecker;
+use super::rspamd::{RspamdAction, RspamdClient};
+use super::types::{SpamAction, SpamResult, SpamRuleMatch};
+use crate::mime::entity::strip_header_fields;
+use crate::mime::{decode_header, encode_header, MimeParser, ParsedEmail};
+
+/// Header carrying the score
+pub const SCORE_HEADER: &str = "X-Spam-Score";
+/// Header carrying the verdict and the rules that matched
+pub const STATUS_HEADER: &str = "X-Spam-Status";
+/// Header carrying the listed domains the message links to
+pub const REPORT_HEADER: &str = "X-Spam-Report";
+
+/// Default score at or above which a message is refused
+pub const DEFAULT_REJECT_THRESHOLD: f64 = 15.0;
+
+/// What scores delivered messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamBackend {
+    /// Built-in rules and Bayesian classifier
+    #[default]
+    Builtin,
+    /// A local rspamd worker
+    Rspamd,
+}
+
+/// Scores messages delivered to local recipients
+pub struct SpamFilter {
+    manager: Arc<SpamManager>,
+    reject_threshold: f64,
+    /// Scores messages instead of the built-in rules and classifier
+    rspamd: Option<RspamdClient>,
+    /// Holds spam instead of the Junk folders
+    quarantine: Option<Arc<QuarantineStore>>,
+    /// Scores links to listed domains with the built-in rules
+    uribl: Option<Arc<UriblChecker>>,
+}
+
+/// SMTP client a message came from
+#[derive(Debug, Clone, Default)]
+pub struct SpamClient {
+    pub ip: Option<IpAddr>,
+    pub helo: Option<String>,
+    /// Rules the client matched, like DNS blocklist listings, added to the
+    /// built-in score
+    pub rules: Vec<SpamRuleMatch>,
+}
+
+/// What the filter decided for a message
+#[derive(Debug, Clone)]
+pub struct SpamVerdict {
+    pub result: SpamResult,
+    /// Global spam threshold, reported in the status header
+    pub required: f64,
+    /// Recipients whose Junk folder gets the message
+    pub junk: Vec<String>,
+    /// Recipients for whom the message is held in quarantine
+    pub held: Vec<String>,
+    /// Refuse the message: the score reached the hard threshold and no
+    /// recipient is tag-only
+    pub reject: bool,
+    /// Subject rspamd rewrote the message's to
+    pub subject: Option<String>,
+    /// Listed domains the message links to, for the report header
+    pub report: Option<String>,
+}
+
+impl SpamFilter {
+    pub fn new(manager: Arc<SpamManager>) -> Self {
+        Self {
+            manager,
+            reject_threshold: DEFAULT_REJECT_THRESHOLD,
+            rspamd: None,
+            quarantine: None,
+            uribl: None,
+        }
+    }
+
+    /// Score links to domains on URI blocklists
+    pub fn with_uribl(mut self, checker: Arc<UriblChecker>) -> Self {
+        self.uribl = Some(checker);
+        self
+    }
+
+    /// Hold spam in `store` instead of filing it into Junk
+    pub fn with_quarantine(mut self, store: Arc<QuarantineStore>) -> Self {
+        self.quarantine = Some(store);
+        self
+    }
+
+    /// The store spam is held in, if any
+    pub fn quarantine(&self) -> Option<&Arc<QuarantineStore>> {
+        self.quarantine.as_ref()
+    }
+
+    /// Score messages with rspamd
+    pub fn with_rspamd(mut self, client: RspamdClient) -> Self {
+        self.rspamd = Some(client);
+        self
+    }
+
+    /// Refuse messages scoring at least `threshold`
+    pub fn with_reject_threshold(mut self, threshold: f64) -> Self {
+        self.reject_threshold = threshold;
+        self
+    }
+
+    /// Score `message` from `from` to `recipients`, sent by `client`, and
+    /// log the action taken for each of them; `tag_only` recipients are
+    /// never refused the message nor get it in Junk
+    pub async fn check(
+        &self,
+        message: &[u8],
+        from: &str,
+        recipients: &[String],
+        tag_only: &[String],
+        client: &SpamClient,
+    ) -> Result<SpamVerdict> {
+        let parsed = MimeParser::parse(message)?;
+        let header = |name: &str| parsed.headers.get(name).cloned().unwrap_or_default();
+        let subject = decode_header(&header("subject"));
+
+        let mut rewritten = None;
+        let mut report = None;
+        let (result, required, refused) = match &self.rspamd {
+            Some(rspamd) => {
+                let reply = rspamd.check(message, from, recipients, client).await?;
+                rewritten = reply.rewritten_subject(&subject);
+                let refused = reply.action == RspamdAction::Reject;
+                (reply.result(), reply.required_score, refused)
+            }
+            None => {
+                let mut rules = client.rules.clone();
+                if let Some(uribl) = &self.uribl {
+                    let listed = uribl.check(&parsed).await;
+                    rules.extend(listed.rule_matches());
+                    report = listed.report();
+                }
+                let (result, required) = self.score(&parsed, recipients, &rules).await?;
+                let refused = result.score >= self.reject_threshold;
+                (result, required, refused)
+            }
+        };
+        let hold_rejected = self.quarantine.as_ref().is_some_and(|q| q.holds_rejected());
+        let reject =
+            refused && !hold_rejected && !recipients.iter().any(|to| tag_only.contains(to));
+
+        let mut junk = Vec::new();
+        let mut held = Vec::new();
+        let message_id = header("message-id");
+        for recipient in recipients {
+            let config = self.manager.config_for(recipient).await?;
+            let mut logged = result.clone();
+            // rspamd's verdict doesn't depend on the recipient's threshold
+            if self.rspamd.is_none() {
+                logged.is_spam = result.score >= config.spam_threshold;
+            }
+            logged.action = if tag_only.contains(recipient) {
+                match logged.is_spam {
+                    true => SpamAction::AddHeaders,
+                    false => SpamAction::Deliver,
+                }
+            } else if reject {
+                SpamAction::Reject
+            } else if refused || (logged.is_spam && config.quarantine_enabled) {
+                SpamAction::Quarantine
+            } else if logged.is_spam {
+                SpamAction::AddHeaders
+            } else {
+                SpamAction::Deliver
+            };
+            if logged.action == SpamAction::Quarantine {
+                match self.quarantine {
+                    Some(_) => held.push(recipient.clone()),
+                    None => junk.push(recipient.clone()),
+                }
+            }
+            if let Err(e) = self
+                .manager
+                .log_result(&message_id, recipient, from, &subject, &logged)
+                .await
+            {
+                warn!("Failed to log spam check for {}: {}", recipient, e);
+            }
+        }
+
+        Ok(SpamVerdict {
+            result,
+            required,
+            junk,
+            held,
+            reject,
+            subject: rewritten,
+            report,
+        })
+    }
+
+    /// Score with the built-in rules and classifier, adding `client_rules`;
+    /// returns the result and the global threshold
+    async fn score(
+        &self,
+        parsed: &ParsedEmail,
+        recipients: &[String],
+        client_rules: &[SpamRuleMatch],
+    ) -> Result<(SpamResult, f64)> {
+        if let Err(e) = self.manager.refresh().await {
+            warn!("Failed to reload spam training: {}", e);
+        }
+        let header = |name: &str| {
+            parsed
+                .headers
+                .get(name)
+                .map(|value| decode_header(value))
+                .unwrap_or_default()
+        };
+        let headers: Vec<(String, String)> = parsed
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        let mut result = self
+            .manager
+            .score_message(
+                &header("from"),
+                &recipients.join(", "),
+                &header("subject"),
+                body_text(parsed),
+                &headers,
+            )
+            .await;
+        for rule in client_rules {
+            result.score += rule.score;
+            result.rules_matched.push(rule.clone());
+        }
+        let required = self.manager.get_config(None).await?.spam_threshold;
+        result.is_spam = result.score >= required;
+        Ok((result, required))
+    }
+}
+
+impl SpamVerdict {
+    /// The score reached the global spam threshold, or rspamd considers
+    /// the message spam
+    pub fn is_spam(&self) -> bool {
+        self.result.is_spam
+    }
+
+    /// Names of the rules that matched, comma-separated
+    pub fn rules(&self) -> String {
+        let names: Vec<&str> = self
+            .result
+            .rules_matched
+            .iter()
+            .map(|rule| rule.rule_name.as_str())
+            .collect();
+        names.join(",")
+    }
+
+    /// `X-Spam-Score:` and `X-Spam-Status:` header lines, and
+    /// `X-Spam-Report:` when links are listed, with the trailing CRLF
+    pub fn header(&self) -> String {
+        let tests = match self.rules() {
+            rules if rules.is_empty() => "none".to_string(),
+            rules => rules,
+        };
+        let mut header = format!(
+            "{}: {:.1}\r\n{}: {}, score={:.1} required={:.1}\r\n\ttests={}\r\n",
+            SCORE_HEADER,
+            self.result.score,
+            STATUS_HEADER,
+            if self.is_spam() { "Yes" } else { "No" },
+            self.result.score,
+            self.required,
+            tests
+        );
+        if let Some(report) = &self.report {
+            header.push_str(&format!("{}: {}\r\n", REPORT_HEADER, report));
+        }
+        header
+    }
+
+    /// Reply refusing the message
+    pub fn reply(&self) -> String {
+        format!(
+            "550 5.7.1 Message refused as spam (score {:.1})\r\n",
+            self.result.score
+        )
+    }
+}
+
+/// Text the rules and the classifier look at: the plain-text body, else
+/// the HTML one
+pub(crate) fn body_text(parsed: &ParsedEmail) -> &str {
+    parsed
+        .text_body
+        .as_deref()
+        .or(parsed.html_body.as_deref())
+        .unwrap_or_default()
+}
+
+/// `message` with its `Subject:` replaced by `subject`
+pub fn rewrite_subject(message: &[u8], subject: &str) -> Vec<u8> {
+    let mut rewritten = format!("Subject: {}\r\n", encode_header(subject)).into_bytes();
+    rewritten.extend_from_slice(&strip_header_fields(message, &["Subject"]));
+    rewritten
+}
+
+/// `message` without the spam headers it came with
+pub fn strip_headers(message: &[u8]) -> Vec<u8> {
+    strip_header_fields(message, &[SCORE_HEADER, STATUS_HEADER, REPORT_HEADER])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RspamdConfig, SpamQuarantineConfig};
+    use crate::spam::SpamConfig;
+    use axum::http::{header, HeaderMap};
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::Mutex;
+
+    async fn filter() -> (SpamFilter, Arc<SpamManager>) {
+        let manager = Arc::new(SpamManager::connect("sqlite::memory:").await.unwrap());
+        (SpamFilter::new(manager.clone()), manager)
+    }
+
+    #[tokio::test]
+    async fn test_check_files_spam_per_recipient() {
+        let (filter, manager) = filter().await;
+        let lenient = SpamConfig {
+            spam_threshold: 20.0,
+            ..SpamConfig::default()
+        };
+        manager.update_config(Some("bob@example.com"), &lenient).await.unwrap();
+
+        let recipients = vec!["alice@example.com".to_string(), "bob@example.com".to_string()];
+        let spam = b"From: x@spam.test\r\nSubject: You are a winner\r\n\r\nBuy viagra now\r\n";
+        let verdict = filter
+            .check(spam, "x@spam.test", &recipients, &[], &SpamClient::default())
+            .await
+            .unwrap();
+        assert_eq!(verdict.result.score, 7.0);
+        assert!(!verdict.reject);
+        assert_eq!(verdict.junk, vec!["alice@example.com".to_string()]);
+        assert_eq!(
+            verdict.header(),
+            "X-Spam-Score: 7.0\r\n\
+             X-Spam-Status: Yes, score=7.0 required=5.0\r\n\
+             \ttests=SUBJECT_WINNER,BODY_VIAGRA\r\n"
+        );
+        assert_eq!(manager.get_logs(10).await.unwrap().len(), 2);
+
+        let ham = b"From: carol@example.org\r\nSubject: Lunch\r\n\r\nSee you at noon\r\n";
+        let verdict = filter
+            .check(ham, "carol@example.org", &recipients, &[], &SpamClient::default())
+            .await
+            .unwrap();
+        assert!(verdict.junk.is_empty());
+        assert!(verdict.header().contains("No, score=0.0 required=5.0\r\n\ttests=none"));
+    }
+
+    #[tokio::test]
+    async fn test_check_rejects_above_hard_threshold() {
+        let (filter, _) = filter().await;
+        let filter = filter.with_reject_threshold(6.0);
+        let recipients = vec!["alice@example.com".to_string()];
+        let spam = b"From: x@spam.test\r\nSubject: You are a winner\r\n\r\nBuy viagra now\r\n";
+        let verdict = filter
+            .check(spam, "x@spam.test", &recipients, &[], &SpamClient::default())
+            .await
+            .unwrap();
+        assert!(verdict.reject);
+        assert!(verdict.junk.is_empty());
+        assert!(verdict.reply().starts_with("550 5.7.1"));
+
+        // Tag-only recipients get it anyway, the others in Junk
+        let recipients = vec!["abuse@example.com".to_string(), "alice@example.com".to_string()];
+        let verdict = filter
+            .check(spam, "x@spam.test", &recipients, &recipients[..1], &SpamClient::default())
+            .await
+            .unwrap();
+        assert!(!verdict.reject);
+        assert!(verdict.is_spam());
+        assert_eq!(verdict.junk, vec!["alice@example.com".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_check_holds_spam_in_quarantine() {
+        let (filter, _) = filter().await;
+        let config = SpamQuarantineConfig {
+            enabled: true,
+            hold_rejected: true,
+            ..Default::default()
+        };
+        let store = QuarantineStore::connect("sqlite::memory:", &config).await.unwrap();
+        let filter = filter.with_reject_threshold(6.0).with_quarantine(Arc::new(store));
+        let recipients = vec!["alice@example.com".to_string()];
+        let spam = b"From: x@spam.test\r\nSubject: You are a winner\r\n\r\nBuy viagra now\r\n";
+        let verdict = filter
+            .check(spam, "x@spam.test", &recipients, &[], &SpamClient::default())
+            .await
+            .unwrap();
+        assert!(!verdict.reject);
+        assert!(verdict.junk.is_empty());
+        assert_eq!(verdict.held, recipients);
+        assert_eq!(verdict.rules(), "SUBJECT_WINNER,BODY_VIAGRA");
+    }
+
+    #[tokio::test]
+    async fn test_check_adds_client_rules() {
+        let (filter, _) = filter().await;
+        let recipients = vec!["alice@example.com".to_string()];
+        let listed = SpamClient {
+            rules: vec![SpamRuleMatch {
+                rule_name: "DNSBL_BL_EXAMPLE_NET".to_string(),
+                score: 5.5,
+                description: "Client listed on bl.example.net (127.0.0.2)".to_string(),
+            }],
+            ..SpamClient::default()
+        };
+        let ham = b"From: carol@example.org\r\nSubject: Lunch\r\n\r\nSee you at noon\r\n";
+        let verdict = filter
+            .check(ham, "carol@example.org", &recipients, &[], &listed)
+            .await
+            .unwrap();
+        assert!(verdict.is_spam());
+        assert_eq!(verdict.junk, recipients);
+        assert!(verdict.header().contains("tests=DNSBL_BL_EXAMPLE_NET"));
+    }
+
+    /// rspamd worker answering `/checkv2` with `reply`; requests' headers
+    /// are kept in the returned list
+    async fn rspamd(reply: &'static str) -> (RspamdClient, Arc<Mutex<Vec<HeaderMap>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let requests = seen.clone();
+        let app = Router::new().route(
+            "/checkv2",
+            post(move |headers: HeaderMap| {
+                requests.lock().unwrap().push(headers);
+                async move { ([(header::CONTENT_TYPE, "application/json")], reply) }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = RspamdConfig {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            ..RspamdConfig::default()
+        };
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (RspamdClient::from_config(&config), seen)
+    }
+
+    #[tokio::test]
+    async fn test_check_with_rspamd() {
+        let (client, seen) = rspamd(
+            r#"{"score": 8.2, "required_score": 15.0, "action": "rewrite subject",
+                "subject": "[SPAM] Lunch",
+                "symbols": {"BAYES_SPAM": {"name": "BAYES_SPAM", "score": 8.2}}}"#,
+        )
+        .await;
+        let (filter, manager) = filter().await;
+        let filter = filter.with_rspamd(client);
+        let recipients = vec!["alice@example.com".to_string(), "bob@example.com".to_string()];
+        let sender = SpamClient {
+            ip: Some("192.0.2.1".parse().unwrap()),
+            helo: Some("mx.example.org".to_string()),
+            ..SpamClient::default()
+        };
+
+        let message = b"From: carol@example.org\r\nSubject: Lunch\r\n\r\nSee you at noon\r\n";
+        let verdict = filter
+            .check(message, "carol@example.org", &recipients, &[], &sender)
+            .await
+            .unwrap();
+        assert!(verdict.is_spam());
+        assert!(!verdict.reject);
+        assert_eq!(verdict.junk, recipients);
+        assert_eq!(verdict.subject.as_deref(), Some("[SPAM] Lunch"));
+        assert!(verdict
+            .header()
+            .contains("Yes, score=8.2 required=15.0\r\n\ttests=BAYES_SPAM"));
+        assert_eq!(manager.get_logs(10).await.unwrap().len(), 2);
+
+        let headers = &seen.lock().unwrap()[0];
+        assert_eq!(headers["IP"], "192.0.2.1");
+        assert_eq!(headers["Helo"], "mx.example.org");
+        assert_eq!(headers["From"], "carol@example.org");
+        let rcpts: Vec<_> = headers.get_all("Rcpt").iter().collect();
+        assert_eq!(rcpts, ["alice@example.com", "bob@example.com"]);
+
+        let rewritten = rewrite_subject(message, "[SPAM] Lunch");
+        assert_eq!(
+            rewritten,
+            b"Subject: [SPAM] Lunch\r\nFrom: carol@example.org\r\n\r\nSee you at noon\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rspamd_reject_action_refuses() {
+        let (client, _) = rspamd(r#"{"score": 3.0, "required_score": 15.0, "action": "reject"}"#).await;
+        let (filter, _) = filter().await;
+        let filter = filter.with_rspamd(client);
+        let recipients = vec!["alice@example.com".to_string()];
+        let message = b"Subject: Hi\r\n\r\nHello\r\n";
+        let verdict = filter
+            .check(message, "x@spam.test", &recipients, &[], &SpamClient::default())
+            .await
+            .unwrap();
+        assert!(verdict.reject);
+        assert!(verdict.subject.is_none());
+    }
+
+    #[test]
+    fn test_strip_headers() {
+        let forged = b"X-Spam-Score: -10\r\nSubject: Hi\r\n\
+                       x-spam-status: No, score=-10\r\n\ttests=none\r\n\
+                       X-Spam-Report: uribl=none\r\n\r\nBody\r\n";
+        assert_eq!(strip_headers(forged), b"Subject: Hi\r\n\r\nBody\r\n");
+    }
+}
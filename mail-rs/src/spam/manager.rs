@@ -1,16 +1,25 @@
 //! Spam manager for database persistence and API
 //!
 //! Provides management of spam rules, configuration, and logging.
+//!
+//! Bayesian training is stored incrementally: token and message counts
+//! live in SQLite, and every change bumps a generation that other
+//! processes sharing the database use to reload the classifier.
 
 use anyhow::Result;
 use chrono::Utc;
-use sqlx::SqlitePool;
+use sha2::{Digest, Sha256};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use super::scorer::SpamScorer;
+use super::delivery::body_text;
+use super::scorer::{BayesianClassifier, SpamScorer};
 use super::types::*;
+use crate::mime::{MimeEntity, MimeParser};
 
 /// Spam management statistics
 #[derive(Debug, Clone, serde::Serialize)]
@@ -37,6 +46,8 @@ pub struct SpamStats {
 pub struct SpamManager {
     db: SqlitePool,
     scorer: Arc<RwLock<SpamScorer>>,
+    /// Training generation the classifier was loaded at
+    generation: AtomicI64,
 }
 
 impl SpamManager {
@@ -45,9 +56,17 @@ impl SpamManager {
         Self {
             db,
             scorer: Arc::new(RwLock::new(SpamScorer::default())),
+            generation: AtomicI64::new(-1),
         }
     }
 
+    /// Open the spam database at `database_url` and create its tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
     /// Initialize database tables
     pub async fn init_db(&self) -> Result<()> {
         sqlx::query(
@@ -114,6 +133,35 @@ impl SpamManager {
         .execute(&self.db)
         .await?;
 
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS spam_training (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                spam_messages INTEGER NOT NULL DEFAULT 0,
+                ham_messages INTEGER NOT NULL DEFAULT 0,
+                generation INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("INSERT OR IGNORE INTO spam_training (id) VALUES (1)")
+            .execute(&self.db)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS spam_trained_messages (
+                message_key TEXT PRIMARY KEY,
+                is_spam INTEGER NOT NULL,
+                trained_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
         // Load Bayesian tokens
         self.load_tokens().await?;
 
@@ -123,8 +171,14 @@ impl SpamManager {
         Ok(())
     }
 
-    /// Load Bayesian tokens from database
+    /// Load Bayesian tokens and message counts from database
     async fn load_tokens(&self) -> Result<()> {
+        let (spam_messages, ham_messages, generation): (i64, i64, i64) = sqlx::query_as(
+            "SELECT spam_messages, ham_messages, generation FROM spam_training WHERE id = 1"
+        )
+        .fetch_one(&self.db)
+        .await?;
+
         let rows = sqlx::query_as::<_, (String, i64, i64)>(
             "SELECT token, spam_count, ham_count FROM spam_tokens"
         )
@@ -136,12 +190,30 @@ impl SpamManager {
             .map(|(t, s, h)| (t, s as u32, h as u32))
             .collect();
 
+        let mut bayesian = BayesianClassifier::new();
+        bayesian.load_tokens(tokens);
+        bayesian.load_training_counts(spam_messages as u32, ham_messages as u32);
+
         let mut scorer = self.scorer.write().await;
-        scorer.bayesian_mut().load_tokens(tokens);
+        *scorer.bayesian_mut() = bayesian;
+        self.generation.store(generation, Ordering::SeqCst);
 
         Ok(())
     }
 
+    /// Reload the classifier if another process trained it since it was
+    /// loaded
+    pub async fn refresh(&self) -> Result<()> {
+        let (generation,): (i64,) =
+            sqlx::query_as("SELECT generation FROM spam_training WHERE id = 1")
+                .fetch_one(&self.db)
+                .await?;
+        if generation != self.generation.load(Ordering::SeqCst) {
+            self.load_tokens().await?;
+        }
+        Ok(())
+    }
+
     /// Load custom rules from database
     async fn load_rules(&self) -> Result<()> {
         let rows = sqlx::query_as::<_, (String, String, String, String, String, f64, i64)>(
@@ -207,6 +279,27 @@ impl SpamManager {
         }
     }
 
+    /// Spam config of a recipient, falling back to the global one
+    pub async fn config_for(&self, email: &str) -> Result<SpamConfig> {
+        let row = sqlx::query_as::<_, (f64, f64, i64, i64, String)>(
+            "SELECT spam_threshold, ham_threshold, quarantine_enabled, learning_enabled, quarantine_folder FROM spam_config WHERE owner_email = ? OR owner_email IS NULL ORDER BY owner_email IS NULL LIMIT 1"
+        )
+        .bind(email)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(match row {
+            Some((spam_threshold, ham_threshold, quarantine_enabled, learning_enabled, quarantine_folder)) => SpamConfig {
+                spam_threshold,
+                ham_threshold,
+                quarantine_enabled: quarantine_enabled != 0,
+                learning_enabled: learning_enabled != 0,
+                quarantine_folder,
+            },
+            None => SpamConfig::default(),
+        })
+    }
+
     /// Update spam config
     pub async fn update_config(&self, email: Option<&str>, config: &SpamConfig) -> Result<()> {
         let id = Uuid::new_v4().to_string();
@@ -429,55 +522,142 @@ impl SpamManager {
 
     /// Learn from spam message
     pub async fn learn_spam(&self, body: &str) -> Result<()> {
-        {
-            let mut scorer = self.scorer.write().await;
-            scorer.learn_spam(body);
-        }
-
-        // Save tokens
-        self.save_tokens().await?;
-
-        Ok(())
+        let mut tx = self.db.begin().await?;
+        self.learn(&mut tx, body, true, 1).await?;
+        self.commit(tx, &[(body, true, 1)]).await
     }
 
     /// Learn from ham message
     pub async fn learn_ham(&self, body: &str) -> Result<()> {
-        {
-            let mut scorer = self.scorer.write().await;
-            scorer.learn_ham(body);
-        }
+        let mut tx = self.db.begin().await?;
+        self.learn(&mut tx, body, false, 1).await?;
+        self.commit(tx, &[(body, false, 1)]).await
+    }
 
-        // Save tokens
-        self.save_tokens().await?;
+    /// Train the classifier on a whole `message` as `class`
+    ///
+    /// Messages are recognized by their Message-ID (or content when they
+    /// have none): training one again as the same class changes nothing,
+    /// and as the other class first forgets what it was learned as.
+    pub async fn train_message(&self, message: &[u8], class: SpamClass) -> Result<TrainOutcome> {
+        let parsed = MimeParser::parse(message)?;
+        let body = body_text(&parsed);
+        let key = message_key(message);
+        let is_spam = class.is_spam();
+
+        let mut tx = self.db.begin().await?;
+        let previous: Option<(bool,)> =
+            sqlx::query_as("SELECT is_spam FROM spam_trained_messages WHERE message_key = ?")
+                .bind(&key)
+                .fetch_optional(&mut *tx)
+                .await?;
+        let (outcome, changes) = match previous {
+            Some((was_spam,)) if was_spam == is_spam => return Ok(TrainOutcome::AlreadyLearned),
+            Some((was_spam,)) => (
+                TrainOutcome::Relearned,
+                vec![(body, was_spam, -1), (body, is_spam, 1)],
+            ),
+            None => (TrainOutcome::Learned, vec![(body, is_spam, 1)]),
+        };
+        for &(text, spam, delta) in &changes {
+            self.learn(&mut tx, text, spam, delta).await?;
+        }
+        sqlx::query(
+            "INSERT OR REPLACE INTO spam_trained_messages (message_key, is_spam, trained_at) VALUES (?, ?, ?)"
+        )
+        .bind(&key)
+        .bind(is_spam)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+        self.commit(tx, &changes).await?;
 
-        Ok(())
+        Ok(outcome)
     }
 
-    /// Save Bayesian tokens to database
-    async fn save_tokens(&self) -> Result<()> {
-        let tokens = {
+    /// Add (`delta` 1) or remove (-1) the tokens of `text` and a message to
+    /// the stored counts of its class
+    async fn learn(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        text: &str,
+        is_spam: bool,
+        delta: i64,
+    ) -> Result<()> {
+        let counts: HashMap<String, u32> = {
             let scorer = self.scorer.read().await;
-            scorer.bayesian().get_tokens()
+            scorer.bayesian().stemmed_counts(text)
         };
+        let (spam, ham) = if is_spam { (delta, 0) } else { (0, delta) };
+        let now = Utc::now().to_rfc3339();
 
-        // Batch update tokens
-        for (token, spam_count, ham_count) in tokens {
-            let id = Uuid::new_v4().to_string();
+        for (token, count) in counts {
+            let count = count as i64;
             sqlx::query(
                 r#"
-                INSERT OR REPLACE INTO spam_tokens (id, token, spam_count, ham_count, updated_at)
-                VALUES (?, ?, ?, ?, ?)
+                INSERT INTO spam_tokens (id, token, spam_count, ham_count, updated_at)
+                VALUES (?, ?, MAX(0, ?), MAX(0, ?), ?)
+                ON CONFLICT(token) DO UPDATE SET
+                    spam_count = MAX(0, spam_count + ?),
+                    ham_count = MAX(0, ham_count + ?),
+                    updated_at = excluded.updated_at
                 "#
             )
-            .bind(&id)
+            .bind(Uuid::new_v4().to_string())
             .bind(&token)
-            .bind(spam_count as i64)
-            .bind(ham_count as i64)
-            .bind(Utc::now().to_rfc3339())
-            .execute(&self.db)
+            .bind(spam * count)
+            .bind(ham * count)
+            .bind(&now)
+            .bind(spam * count)
+            .bind(ham * count)
+            .execute(&mut **tx)
             .await?;
         }
 
+        sqlx::query(
+            r#"
+            UPDATE spam_training SET
+                spam_messages = MAX(0, spam_messages + ?),
+                ham_messages = MAX(0, ham_messages + ?),
+                generation = generation + 1
+            WHERE id = 1
+            "#
+        )
+        .bind(spam)
+        .bind(ham)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Commit stored training and apply `changes` to the loaded classifier,
+    /// or reload it if another process trained it meanwhile
+    async fn commit(
+        &self,
+        mut tx: Transaction<'_, Sqlite>,
+        changes: &[(&str, bool, i64)],
+    ) -> Result<()> {
+        let (generation,): (i64,) =
+            sqlx::query_as("SELECT generation FROM spam_training WHERE id = 1")
+                .fetch_one(&mut *tx)
+                .await?;
+        tx.commit().await?;
+
+        let mut scorer = self.scorer.write().await;
+        let loaded = self.generation.load(Ordering::SeqCst);
+        if loaded + changes.len() as i64 != generation {
+            drop(scorer);
+            return self.load_tokens().await;
+        }
+        for &(text, is_spam, delta) in changes {
+            match delta > 0 {
+                true => scorer.bayesian_mut().learn(text, is_spam),
+                false => scorer.bayesian_mut().unlearn(text, is_spam),
+            }
+        }
+        self.generation.store(generation, Ordering::SeqCst);
+
         Ok(())
     }
 
@@ -540,3 +720,73 @@ impl SpamManager {
         self.score_message(from, to, subject, body, &[]).await
     }
 }
+
+/// Key a trained message is remembered by: its Message-ID, else a digest
+/// of its content
+fn message_key(message: &[u8]) -> String {
+    let id = MimeEntity::parse(message)
+        .header_value("message-id")
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty());
+    match id {
+        Some(id) => id,
+        None => {
+            let digest = Sha256::digest(message);
+            format!("sha256:{}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPAM: &[u8] = b"Message-ID: <1@spam.test>\r\nSubject: Offer\r\n\r\nCheap pills casino bonus\r\n";
+    const HAM: &[u8] = b"Subject: Notes\r\n\r\nMeeting notes for the project review\r\n";
+
+    #[tokio::test]
+    async fn test_train_message_persists_and_relearns() {
+        let manager = SpamManager::connect("sqlite::memory:").await.unwrap();
+        assert_eq!(manager.train_message(SPAM, SpamClass::Spam).await.unwrap(), TrainOutcome::Learned);
+        assert_eq!(
+            manager.train_message(SPAM, SpamClass::Spam).await.unwrap(),
+            TrainOutcome::AlreadyLearned
+        );
+        assert_eq!(manager.train_message(HAM, SpamClass::Ham).await.unwrap(), TrainOutcome::Learned);
+
+        // Another process sharing the database loads the training
+        let other = SpamManager::new(manager.db.clone());
+        other.init_db().await.unwrap();
+        let stats = other.get_stats().await.unwrap();
+        assert_eq!((stats.spam_learned, stats.ham_learned), (1, 1));
+        let result = other
+            .score_message("x@spam.test", "bob@example.com", "Offer", "cheap casino pills", &[])
+            .await;
+        assert!(result.rules_matched.iter().any(|rule| rule.rule_name == "BAYESIAN_SCORE" && rule.score > 0.0));
+
+        // Training it as the other class forgets what it was learned as,
+        // and the first manager picks that up on refresh
+        assert_eq!(other.train_message(SPAM, SpamClass::Ham).await.unwrap(), TrainOutcome::Relearned);
+        manager.refresh().await.unwrap();
+        let stats = manager.get_stats().await.unwrap();
+        assert_eq!((stats.spam_learned, stats.ham_learned), (0, 2));
+        let (spam_count,): (i64,) = sqlx::query_as("SELECT MAX(spam_count) FROM spam_tokens")
+            .fetch_one(&manager.db)
+            .await
+            .unwrap();
+        assert_eq!(spam_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_messages_without_id_keyed_by_content() {
+        let manager = SpamManager::connect("sqlite::memory:").await.unwrap();
+        assert!(message_key(HAM).starts_with("sha256:"));
+        assert_eq!(message_key(SPAM), "<1@spam.test>");
+
+        manager.learn_spam("casino bonus").await.unwrap();
+        assert_eq!(manager.train_message(HAM, SpamClass::Spam).await.unwrap(), TrainOutcome::Learned);
+        assert_eq!(manager.train_message(HAM, SpamClass::Ham).await.unwrap(), TrainOutcome::Relearned);
+        let stats = manager.get_stats().await.unwrap();
+        assert_eq!((stats.spam_learned, stats.ham_learned), (1, 1));
+    }
+}
@@ -1,11 +1,20 @@
 //! Spam scoring module
 //!
 //! Provides advanced spam detection with rule-based scoring and Bayesian learning.
+//! Mail delivered over SMTP is scored by [`delivery::SpamFilter`], with
+//! these or by a local rspamd worker ([`rspamd`]); suspect mail can be held
+//! in [`quarantine`] for its recipients to release or delete.
 
+pub mod delivery;
 pub mod manager;
+pub mod quarantine;
+pub mod rspamd;
 pub mod scorer;
 pub mod types;
 
+pub use delivery::{SpamBackend, SpamClient, SpamFilter, SpamVerdict};
 pub use manager::{SpamManager, SpamStats};
+pub use quarantine::{QuarantineStore, QuarantinedMessage};
+pub use rspamd::RspamdClient;
 pub use scorer::{BayesianClassifier, SpamScorer};
 pub use types::*;
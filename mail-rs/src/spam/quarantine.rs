@@ -0,0 +1,479 @@
+//! Quarantine of suspect mail
+//!
+//! With `[spam.quarantine] enabled`, spam a recipient would get in their
+//! Junk folder is held here instead, out of their mailbox. Users are mailed
+//! a digest of what was held since the last one and release messages back
+//! into their INBOX, or delete them, through the API or the MCP server.
+//! Held messages are removed after `retention_days`.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::config::SpamQuarantineConfig;
+use crate::storage::MaildirStorage;
+
+/// A message held in quarantine, without its content
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuarantinedMessage {
+    pub id: String,
+    /// Mailbox the message was meant for
+    pub recipient: String,
+    pub sender: String,
+    pub subject: String,
+    pub score: f64,
+    /// Rules that matched, comma-separated
+    pub reason: String,
+    pub size: i64,
+    pub held_at: DateTime<Utc>,
+}
+
+/// Holds suspect messages until their recipient releases or deletes them
+pub struct QuarantineStore {
+    db: SqlitePool,
+    hold_rejected: bool,
+    retention: Duration,
+}
+
+impl QuarantineStore {
+    /// Create a new quarantine store
+    pub fn new(db: SqlitePool, config: &SpamQuarantineConfig) -> Self {
+        Self {
+            db,
+            hold_rejected: config.hold_rejected,
+            retention: Duration::days(config.retention_days as i64),
+        }
+    }
+
+    /// Connect to `database_url` and create the quarantine table
+    pub async fn connect(database_url: &str, config: &SpamQuarantineConfig) -> Result<Self> {
+        let store = Self::new(SqlitePool::connect(database_url).await?, config);
+        store.init_db().await?;
+        Ok(store)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS spam_quarantine (
+                id TEXT PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                score REAL NOT NULL,
+                reason TEXT NOT NULL,
+                message BLOB NOT NULL,
+                held_at TEXT NOT NULL,
+                digested INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_spam_quarantine_recipient ON spam_quarantine(recipient)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Hold mail scoring above the reject threshold instead of refusing it
+    pub fn holds_rejected(&self) -> bool {
+        self.hold_rejected
+    }
+
+    /// Hold `message` from `sender` for `recipient`
+    pub async fn hold(
+        &self,
+        recipient: &str,
+        sender: &str,
+        subject: &str,
+        score: f64,
+        reason: &str,
+        message: &[u8],
+    ) -> Result<QuarantinedMessage> {
+        let held = QuarantinedMessage {
+            id: Uuid::new_v4().to_string(),
+            recipient: recipient.to_lowercase(),
+            sender: sender.to_string(),
+            subject: subject.to_string(),
+            score,
+            reason: reason.to_string(),
+            size: message.len() as i64,
+            held_at: Utc::now(),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO spam_quarantine
+                (id, recipient, sender, subject, score, reason, message, held_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&held.id)
+        .bind(&held.recipient)
+        .bind(&held.sender)
+        .bind(&held.subject)
+        .bind(held.score)
+        .bind(&held.reason)
+        .bind(message)
+        .bind(held.held_at.to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(held)
+    }
+
+    /// Messages held for `recipient`, newest first
+    pub async fn list(&self, recipient: &str, limit: i64) -> Result<Vec<QuarantinedMessage>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM spam_quarantine WHERE recipient = ? ORDER BY held_at DESC LIMIT ?",
+            COLUMNS
+        ))
+        .bind(recipient.to_lowercase())
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(message_from_row).collect()
+    }
+
+    /// Held message `id` of `recipient`
+    pub async fn get(&self, recipient: &str, id: &str) -> Result<Option<QuarantinedMessage>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM spam_quarantine WHERE recipient = ? AND id = ?",
+            COLUMNS
+        ))
+        .bind(recipient.to_lowercase())
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        row.as_ref().map(message_from_row).transpose()
+    }
+
+    /// Deliver held message `id` of `recipient` into their INBOX and remove
+    /// it from quarantine; returns the id of the delivered message, `None`
+    /// if nothing is held under `id`
+    pub async fn release(
+        &self,
+        recipient: &str,
+        id: &str,
+        storage: &MaildirStorage,
+    ) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT message FROM spam_quarantine WHERE recipient = ? AND id = ?")
+            .bind(recipient.to_lowercase())
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let message: Vec<u8> = row.try_get("message")?;
+
+        // Delivered first: a failed delete leaves a copy to release again
+        let email_id = storage
+            .store(recipient, &message)
+            .await
+            .map_err(|e| anyhow!("Failed to deliver released message: {}", e))?;
+        self.delete(recipient, id).await?;
+        info!("Released quarantined message {} to {}", id, recipient);
+        Ok(Some(email_id))
+    }
+
+    /// Delete held message `id` of `recipient`; returns whether it existed
+    pub async fn delete(&self, recipient: &str, id: &str) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM spam_quarantine WHERE recipient = ? AND id = ?")
+            .bind(recipient.to_lowercase())
+            .bind(id)
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    /// Messages not listed in a digest yet, by recipient
+    pub async fn pending_digests(&self) -> Result<BTreeMap<String, Vec<QuarantinedMessage>>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM spam_quarantine WHERE digested = 0 ORDER BY held_at",
+            COLUMNS
+        ))
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut pending: BTreeMap<String, Vec<QuarantinedMessage>> = BTreeMap::new();
+        for row in &rows {
+            let message = message_from_row(row)?;
+            pending
+                .entry(message.recipient.clone())
+                .or_default()
+                .push(message);
+        }
+        Ok(pending)
+    }
+
+    /// Record that `messages` were listed in a digest
+    pub async fn mark_digested(&self, messages: &[QuarantinedMessage]) -> Result<()> {
+        for message in messages {
+            sqlx::query("UPDATE spam_quarantine SET digested = 1 WHERE id = ?")
+                .bind(&message.id)
+                .execute(&self.db)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Remove messages held longer than the retention period
+    pub async fn expire(&self) -> Result<u64> {
+        let cutoff = Utc::now() - self.retention;
+        let expired = sqlx::query("DELETE FROM spam_quarantine WHERE held_at < ?")
+            .bind(cutoff.to_rfc3339())
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        Ok(expired)
+    }
+
+    /// Every `interval`, mail each user a digest of newly held messages
+    /// and remove expired ones
+    pub async fn run_digests(
+        self: Arc<Self>,
+        storage: Arc<MaildirStorage>,
+        from: String,
+        interval: std::time::Duration,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.send_digests(&storage, &from).await {
+                error!("Quarantine digest run failed: {}", e);
+            }
+            match self.expire().await {
+                Ok(0) => {}
+                Ok(expired) => info!("Removed {} expired quarantined messages", expired),
+                Err(e) => warn!("Failed to expire quarantined messages: {}", e),
+            }
+        }
+    }
+
+    /// Deliver a digest into the INBOX of every user with newly held
+    /// messages
+    pub async fn send_digests(&self, storage: &MaildirStorage, from: &str) -> Result<usize> {
+        let pending = self.pending_digests().await?;
+        for (recipient, messages) in &pending {
+            let digest = digest_email(from, recipient, messages);
+            storage
+                .store(recipient, &digest)
+                .await
+                .map_err(|e| anyhow!("Failed to deliver digest to {}: {}", recipient, e))?;
+            self.mark_digested(messages).await?;
+            info!(
+                "Sent quarantine digest of {} messages to {}",
+                messages.len(),
+                recipient
+            );
+        }
+        Ok(pending.len())
+    }
+}
+
+const COLUMNS: &str =
+    "id, recipient, sender, subject, score, reason, LENGTH(message) AS size, held_at";
+
+fn message_from_row(row: &SqliteRow) -> Result<QuarantinedMessage> {
+    let held_at: String = row.try_get("held_at")?;
+    Ok(QuarantinedMessage {
+        id: row.try_get("id")?,
+        recipient: row.try_get("recipient")?,
+        sender: row.try_get("sender")?,
+        subject: row.try_get("subject")?,
+        score: row.try_get("score")?,
+        reason: row.try_get("reason")?,
+        size: row.try_get("size")?,
+        held_at: DateTime::parse_from_rfc3339(&held_at)?.with_timezone(&Utc),
+    })
+}
+
+/// Plain text digest listing `messages` held for `recipient`
+pub fn digest_email(from: &str, recipient: &str, messages: &[QuarantinedMessage]) -> Vec<u8> {
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S %z");
+    let mut body = format!(
+        "{} suspected spam message(s) addressed to you were held in quarantine\r\n\
+         and are not in your mailbox:\r\n\r\n",
+        messages.len()
+    );
+    for message in messages {
+        // Subjects come from senders; keep each on one line
+        let subject: String = match message.subject.trim() {
+            "" => "(no subject)".to_string(),
+            subject => subject.chars().filter(|c| !c.is_control()).collect(),
+        };
+        body.push_str(&format!(
+            "- {}\r\n  From: {}\r\n  Held: {} (score {:.1})\r\n  Id: {}\r\n\r\n",
+            subject,
+            if message.sender.is_empty() {
+                "<>"
+            } else {
+                &message.sender
+            },
+            message.held_at.format("%Y-%m-%d %H:%M UTC"),
+            message.score,
+            message.id
+        ));
+    }
+    body.push_str(
+        "Release a message into your INBOX with POST /api/spam/quarantine/<id>/release,\r\n\
+         or delete it with DELETE /api/spam/quarantine/<id>. Held messages are\r\n\
+         removed automatically after a while.\r\n",
+    );
+
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: Quarantined messages ({})\r\nDate: {}\r\n\
+         Message-ID: <{}@quarantine>\r\nAuto-Submitted: auto-generated\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\r\n{}",
+        from,
+        recipient,
+        messages.len(),
+        date,
+        Uuid::new_v4().simple(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    async fn store() -> QuarantineStore {
+        QuarantineStore::connect("sqlite::memory:", &SpamQuarantineConfig::default())
+            .await
+            .unwrap()
+    }
+
+    const SPAM: &[u8] = b"From: x@spam.test\r\nSubject: You are a winner\r\n\r\nBuy now\r\n";
+
+    #[tokio::test]
+    async fn test_release_delivers_to_inbox() {
+        let store = store().await;
+        let dir = TempDir::new().unwrap();
+        let storage = MaildirStorage::new(dir.path().to_string_lossy().to_string());
+
+        let held = store
+            .hold(
+                "Alice@example.com",
+                "x@spam.test",
+                "You are a winner",
+                7.0,
+                "BODY_VIAGRA",
+                SPAM,
+            )
+            .await
+            .unwrap();
+        let listed = store.list("alice@example.com", 10).await.unwrap();
+        assert_eq!(
+            listed,
+            vec![QuarantinedMessage {
+                size: SPAM.len() as i64,
+                ..held.clone()
+            }]
+        );
+        assert!(store.list("bob@example.com", 10).await.unwrap().is_empty());
+
+        // Only the recipient can release it
+        assert!(store
+            .release("bob@example.com", &held.id, &storage)
+            .await
+            .unwrap()
+            .is_none());
+        let email_id = store
+            .release("alice@example.com", &held.id, &storage)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(store
+            .get("alice@example.com", &held.id)
+            .await
+            .unwrap()
+            .is_none());
+        let delivered =
+            std::fs::read(dir.path().join("alice@example.com/new").join(&email_id)).unwrap();
+        assert_eq!(delivered, SPAM);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let store = store().await;
+        let held = store
+            .hold("alice@example.com", "", "Hi", 6.0, "", SPAM)
+            .await
+            .unwrap();
+        assert!(!store.delete("bob@example.com", &held.id).await.unwrap());
+        assert!(store.delete("alice@example.com", &held.id).await.unwrap());
+        assert!(!store.delete("alice@example.com", &held.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_digests_list_new_messages_once() {
+        let store = store().await;
+        let dir = TempDir::new().unwrap();
+        let storage = MaildirStorage::new(dir.path().to_string_lossy().to_string());
+        let held = store
+            .hold(
+                "alice@example.com",
+                "x@spam.test",
+                "Win\r\nBcc: all",
+                7.0,
+                "",
+                SPAM,
+            )
+            .await
+            .unwrap();
+
+        let from = "postmaster@example.com";
+        assert_eq!(store.send_digests(&storage, from).await.unwrap(), 1);
+        assert_eq!(store.send_digests(&storage, from).await.unwrap(), 0);
+
+        let inbox = dir.path().join("alice@example.com/new");
+        let digests: Vec<_> = std::fs::read_dir(inbox).unwrap().collect();
+        assert_eq!(digests.len(), 1);
+        let digest = std::fs::read_to_string(digests[0].as_ref().unwrap().path()).unwrap();
+        assert!(digest.starts_with("From: postmaster@example.com\r\nTo: alice@example.com\r\n"));
+        assert!(digest.contains("- WinBcc: all\r\n  From: x@spam.test\r\n"));
+        assert!(digest.contains(&format!("Id: {}", held.id)));
+    }
+
+    #[tokio::test]
+    async fn test_expire_removes_old_messages() {
+        let store = QuarantineStore::connect(
+            "sqlite::memory:",
+            &SpamQuarantineConfig {
+                retention_days: 0,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        store
+            .hold("alice@example.com", "", "Hi", 6.0, "", SPAM)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(store.expire().await.unwrap(), 1);
+        assert!(store
+            .list("alice@example.com", 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
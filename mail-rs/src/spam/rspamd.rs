@@ -0,0 +1,244 @@
+//! rspamd backend
+//!
+//! With `[spam] backend = "rspamd"`, messages are scored by a local rspamd
+//! worker through its HTTP protocol (`POST /checkv2`) instead of the
+//! built-in rules and classifier. The client IP, HELO name and envelope are
+//! passed along so rspamd runs its own SPF, RBL and reputation checks.
+//!
+//! rspamd's action decides what happens to the message:
+//! - `add header`: tagged as spam and filed into Junk for recipients with
+//!   quarantine enabled
+//! - `rewrite subject`: the same, with the subject rspamd rewrote
+//! - `reject`: refused, like a score reaching `reject_threshold`
+//! - `no action`, `greylist` and `soft reject`: delivered, not spam
+//!
+//! # Configuration
+//! ```toml
+//! [spam]
+//! enabled = true
+//! backend = "rspamd"
+//!
+//! [spam.rspamd]
+//! url = "http://127.0.0.1:11333"
+//! timeout_ms = 5000
+//! ```
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::delivery::SpamClient;
+use super::types::{SpamAction, SpamResult, SpamRuleMatch};
+use crate::config::RspamdConfig;
+
+/// Subject of `rewrite subject` replies that don't carry one
+const SUBJECT_PREFIX: &str = "*** SPAM ***";
+
+/// Action rspamd recommends for a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum RspamdAction {
+    #[serde(rename = "no action")]
+    NoAction,
+    #[serde(rename = "greylist")]
+    Greylist,
+    #[serde(rename = "add header")]
+    AddHeader,
+    #[serde(rename = "rewrite subject")]
+    RewriteSubject,
+    #[serde(rename = "soft reject")]
+    SoftReject,
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+/// A symbol rspamd matched
+#[derive(Debug, Clone, Deserialize)]
+pub struct RspamdSymbol {
+    pub name: String,
+    #[serde(default)]
+    pub score: f64,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Reply of `/checkv2`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RspamdReply {
+    #[serde(default)]
+    pub score: f64,
+    /// Score at which rspamd rejects
+    #[serde(default)]
+    pub required_score: f64,
+    pub action: RspamdAction,
+    #[serde(default)]
+    pub symbols: HashMap<String, RspamdSymbol>,
+    /// New subject of `rewrite subject` replies
+    #[serde(default)]
+    pub subject: Option<String>,
+}
+
+impl RspamdReply {
+    /// rspamd wants the message tagged, rewritten or refused
+    pub fn is_spam(&self) -> bool {
+        matches!(
+            self.action,
+            RspamdAction::AddHeader | RspamdAction::RewriteSubject | RspamdAction::Reject
+        )
+    }
+
+    /// The reply as a scoring result, symbols by decreasing score
+    pub fn result(&self) -> SpamResult {
+        let mut rules: Vec<SpamRuleMatch> = self
+            .symbols
+            .values()
+            .map(|symbol| SpamRuleMatch {
+                rule_name: symbol.name.clone(),
+                score: symbol.score,
+                description: match (&symbol.description, symbol.options.is_empty()) {
+                    (Some(description), true) => description.clone(),
+                    (Some(description), false) => {
+                        format!("{} ({})", description, symbol.options.join(", "))
+                    }
+                    (None, _) => symbol.options.join(", "),
+                },
+            })
+            .collect();
+        rules.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.rule_name.cmp(&b.rule_name))
+        });
+
+        SpamResult {
+            score: self.score,
+            is_spam: self.is_spam(),
+            rules_matched: rules,
+            action: match self.action {
+                RspamdAction::Reject => SpamAction::Reject,
+                RspamdAction::AddHeader | RspamdAction::RewriteSubject => SpamAction::AddHeaders,
+                _ => SpamAction::Deliver,
+            },
+        }
+    }
+
+    /// Subject replacing `original`, for `rewrite subject` replies
+    pub fn rewritten_subject(&self, original: &str) -> Option<String> {
+        if self.action != RspamdAction::RewriteSubject {
+            return None;
+        }
+        Some(self.subject.clone().unwrap_or_else(|| {
+            format!("{} {}", SUBJECT_PREFIX, original)
+                .trim_end()
+                .to_string()
+        }))
+    }
+}
+
+/// Client of a local rspamd worker
+pub struct RspamdClient {
+    url: String,
+    password: Option<String>,
+    timeout: Duration,
+    http: reqwest::Client,
+}
+
+impl RspamdClient {
+    /// Client of the worker configured in `[spam.rspamd]`
+    pub fn from_config(config: &RspamdConfig) -> Self {
+        Self {
+            url: config.url.trim_end_matches('/').to_string(),
+            password: config.password.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// URL of the worker
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Score `message` from `from` to `recipients`, sent by `client`
+    pub async fn check(
+        &self,
+        message: &[u8],
+        from: &str,
+        recipients: &[String],
+        client: &SpamClient,
+    ) -> Result<RspamdReply> {
+        let mut request = self
+            .http
+            .post(format!("{}/checkv2", self.url))
+            .timeout(self.timeout)
+            .header("From", from)
+            .body(message.to_vec());
+        for recipient in recipients {
+            request = request.header("Rcpt", recipient.as_str());
+        }
+        if let Some(ip) = client.ip {
+            request = request.header("IP", ip.to_string());
+        }
+        if let Some(helo) = &client.helo {
+            request = request.header("Helo", helo.as_str());
+        }
+        if let Some(password) = &self.password {
+            request = request.header("Password", password.as_str());
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("rspamd answered {}", response.status()));
+        }
+        Ok(response.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_maps_to_result() {
+        let reply: RspamdReply = serde_json::from_str(
+            r#"{
+                "is_skipped": false,
+                "score": 7.5,
+                "required_score": 15.0,
+                "action": "rewrite subject",
+                "symbols": {
+                    "BAYES_SPAM": {"name": "BAYES_SPAM", "score": 5.1, "options": ["99.9%"]},
+                    "R_SPF_FAIL": {"name": "R_SPF_FAIL", "score": 2.4, "description": "SPF failed"},
+                    "ARC_NA": {"name": "ARC_NA", "score": 0.0}
+                },
+                "messages": {}
+            }"#,
+        )
+        .unwrap();
+
+        let result = reply.result();
+        assert!(result.is_spam);
+        assert_eq!(result.action, SpamAction::AddHeaders);
+        let rules: Vec<&str> = result
+            .rules_matched
+            .iter()
+            .map(|r| r.rule_name.as_str())
+            .collect();
+        assert_eq!(rules, ["BAYES_SPAM", "R_SPF_FAIL", "ARC_NA"]);
+        assert_eq!(result.rules_matched[0].description, "99.9%");
+        assert_eq!(
+            reply.rewritten_subject("Hello").as_deref(),
+            Some("*** SPAM *** Hello")
+        );
+
+        let greylisted = RspamdReply {
+            action: RspamdAction::Greylist,
+            ..reply
+        };
+        assert!(!greylisted.is_spam());
+        assert_eq!(greylisted.result().action, SpamAction::Deliver);
+        assert!(greylisted.rewritten_subject("Hello").is_none());
+    }
+}
@@ -238,6 +238,15 @@ impl SpamScorer {
                 score: 3.0,
                 is_enabled: true,
             },
+            SpamRule {
+                id: "DISPLAY_NAME_SPOOF".to_string(),
+                name: "DISPLAY_NAME_SPOOF".to_string(),
+                description: "From name impersonates a protected internal name".to_string(),
+                rule_type: SpamRuleType::Header,
+                pattern: "x-impersonation-warning".to_string(),
+                score: 6.0,
+                is_enabled: true,
+            },
 
             // Body content rules
             SpamRule {
@@ -398,6 +407,25 @@ impl BayesianClassifier {
         }
     }
 
+    /// Forget a message learned with `is_spam`, e.g. before relearning it
+    /// as the other class
+    pub fn unlearn(&mut self, text: &str, is_spam: bool) {
+        let counts = self.stemmed_counts(text);
+        let (tokens, count) = match is_spam {
+            true => (&mut self.spam_tokens, &mut self.spam_count),
+            false => (&mut self.ham_tokens, &mut self.ham_count),
+        };
+        *count = count.saturating_sub(1);
+        for (token, n) in counts {
+            if let Some(current) = tokens.get_mut(&token) {
+                *current = current.saturating_sub(n);
+                if *current == 0 {
+                    tokens.remove(&token);
+                }
+            }
+        }
+    }
+
     /// Classify a message, returns score between -1 (ham) and +1 (spam)
     pub fn classify(&self, text: &str) -> f64 {
         if self.spam_count == 0 || self.ham_count == 0 {
@@ -447,6 +475,15 @@ impl BayesianClassifier {
             .collect()
     }
 
+    /// How often each token of `text` occurs, as learning it counts them
+    pub fn stemmed_counts(&self, text: &str) -> HashMap<String, u32> {
+        let mut counts = HashMap::new();
+        for token in self.tokenize(text) {
+            *counts.entry(token).or_insert(0) += 1;
+        }
+        counts
+    }
+
     /// Get spam token count
     pub fn spam_token_count(&self) -> usize {
         self.spam_tokens.len()
@@ -462,6 +499,12 @@ impl BayesianClassifier {
         (self.spam_count, self.ham_count)
     }
 
+    /// Load message counts from database
+    pub fn load_training_counts(&mut self, spam_count: u32, ham_count: u32) {
+        self.spam_count = spam_count;
+        self.ham_count = ham_count;
+    }
+
     /// Load token data from database
     pub fn load_tokens(&mut self, tokens: Vec<(String, u32, u32)>) {
         for (token, spam_count, ham_count) in tokens {
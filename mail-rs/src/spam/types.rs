@@ -119,3 +119,29 @@ pub struct SpamLog {
     /// Timestamp
     pub created_at: DateTime<Utc>,
 }
+
+/// Class a message is trained as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamClass {
+    Spam,
+    Ham,
+}
+
+impl SpamClass {
+    pub fn is_spam(&self) -> bool {
+        *self == SpamClass::Spam
+    }
+}
+
+/// What training on a message changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrainOutcome {
+    /// First time the message was trained
+    Learned,
+    /// Trained before as the other class, which was forgotten
+    Relearned,
+    /// Already trained as this class; nothing changed
+    AlreadyLearned,
+}
@@ -0,0 +1,430 @@
+//! Message state changes shared by every frontend
+//!
+//! IMAP sessions, the web API and the MCP tools all change message state by
+//! renaming files in the user's Maildir. [`FlagEventBus`] broadcasts those
+//! changes as [`FlagEvent`]s, so a message marked read in one place shows
+//! up as read everywhere else: idling IMAP sessions wake up, API websocket
+//! clients get the new flags pushed, and the AI runtime's summary store
+//! follows along (see [`FlagEventBus::forward_to`]).
+//!
+//! Frontends running in the server process publish their changes directly.
+//! [`FlagEventBus::watch_maildir`] picks up changes made by other processes,
+//! such as the MCP server, from the filesystem. A state that is reported
+//! twice, once by a frontend and once by the watcher, is broadcast once.
+
+use crate::chaos::{self, Fault};
+use crate::error::MailError;
+use crate::imap::uid::base_name;
+use crate::imap::Mailbox;
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Events buffered per subscriber before slow subscribers start lagging
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Messages whose last state is remembered for de-duplication
+const MAX_TRACKED: usize = 100_000;
+
+/// Frontend that made a change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagSource {
+    Imap,
+    Api,
+    /// Seen on disk, made outside the server process, e.g. by the MCP
+    /// server
+    Maildir,
+}
+
+/// Change of a message's flags, or its removal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagEvent {
+    /// Owner of the mailbox
+    pub user: String,
+    /// IMAP mailbox name, e.g. `INBOX` or `Sent`
+    pub mailbox: String,
+    /// Maildir base name of the message, the id returned on delivery
+    pub message: String,
+    /// IMAP flags after the change, empty for new and expunged messages
+    pub flags: Vec<String>,
+    /// The message was removed from the mailbox
+    pub expunged: bool,
+    pub source: FlagSource,
+}
+
+impl FlagEvent {
+    /// Flags of `message` changed to `flags`
+    pub fn flags(
+        user: &str,
+        mailbox: &str,
+        message: &str,
+        flags: &[String],
+        source: FlagSource,
+    ) -> Self {
+        Self {
+            user: user.to_string(),
+            mailbox: mailbox_name(mailbox),
+            message: base_name(message).to_string(),
+            flags: flags.to_vec(),
+            expunged: false,
+            source,
+        }
+    }
+
+    /// `message` was removed from the mailbox
+    pub fn expunged(user: &str, mailbox: &str, message: &str, source: FlagSource) -> Self {
+        Self {
+            user: user.to_string(),
+            mailbox: mailbox_name(mailbox),
+            message: base_name(message).to_string(),
+            flags: Vec::new(),
+            expunged: true,
+            source,
+        }
+    }
+
+    /// Check whether the message is read after the change
+    pub fn is_seen(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "\\Seen")
+    }
+
+    /// Check whether the event concerns `mailbox` of `user`
+    pub fn concerns(&self, user: &str, mailbox: &str) -> bool {
+        self.user == user && self.mailbox == mailbox_name(mailbox)
+    }
+}
+
+/// INBOX is case-insensitive, other mailbox names are not
+fn mailbox_name(mailbox: &str) -> String {
+    if mailbox.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        mailbox.to_string()
+    }
+}
+
+/// Last state of a message: its sorted flags, or `None` once expunged
+type MessageState = Option<Vec<String>>;
+
+/// Broadcasts flag changes to every subscribed frontend
+pub struct FlagEventBus {
+    sender: broadcast::Sender<FlagEvent>,
+    /// Last broadcast state per (user, mailbox, message)
+    last: Mutex<HashMap<(String, String, String), MessageState>>,
+    /// Filesystem watchers started by [`watch_maildir`](Self::watch_maildir)
+    watchers: Mutex<Vec<Box<dyn Watcher + Send + Sync>>>,
+}
+
+impl Default for FlagEventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlagEventBus {
+    /// Create a bus without subscribers
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            last: Mutex::new(HashMap::new()),
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Broadcast a change to all subscribers
+    ///
+    /// Returns false when the message was already known to be in this
+    /// state, in which case nothing is sent.
+    pub fn publish(&self, event: FlagEvent) -> bool {
+        let key = (
+            event.user.clone(),
+            event.mailbox.clone(),
+            event.message.clone(),
+        );
+        let state = if event.expunged {
+            None
+        } else {
+            let mut flags = event.flags.clone();
+            flags.sort();
+            Some(flags)
+        };
+
+        {
+            let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+            if last.get(&key) == Some(&state) {
+                return false;
+            }
+            if last.len() >= MAX_TRACKED {
+                last.clear();
+            }
+            last.insert(key, state);
+        }
+
+        debug!(
+            "Flag event from {:?}: {}/{}/{} {:?}{}",
+            event.source,
+            event.user,
+            event.mailbox,
+            event.message,
+            event.flags,
+            if event.expunged { " (expunged)" } else { "" }
+        );
+        // Sending only fails without subscribers
+        let _ = self.sender.send(event);
+        true
+    }
+
+    /// Receive all changes published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<FlagEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish changes made to the mailboxes under `maildir_root` by other
+    /// processes
+    ///
+    /// Call once per maildir root. Watching stops when the bus is dropped.
+    pub fn watch_maildir(self: &Arc<Self>, maildir_root: &Path) -> Result<(), MailError> {
+        let root = maildir_root.to_path_buf();
+        let bus: Weak<Self> = Arc::downgrade(self);
+        let handler = {
+            let root = root.clone();
+            move |result: Result<Event, notify::Error>| match result {
+                Ok(event) => {
+                    if !matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    ) {
+                        return;
+                    }
+                    let Some(bus) = bus.upgrade() else {
+                        return;
+                    };
+                    for path in &event.paths {
+                        if let Some(event) = maildir_event(&root, path) {
+                            bus.publish(event);
+                        }
+                    }
+                }
+                Err(e) => warn!("Maildir watch error: {}", e),
+            }
+        };
+
+        let mut watcher =
+            RecommendedWatcher::new(handler, Config::default()).map_err(watch_error)?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+        info!("Publishing flag changes made under {:?}", root);
+
+        self.watchers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Box::new(watcher));
+        Ok(())
+    }
+
+    /// Forward every change to the AI runtime at `ai_url`, which keeps its
+    /// summaries' read state in sync
+    ///
+    /// Delivery is best effort: failures are logged and the event dropped.
+    pub fn forward_to(self: &Arc<Self>, ai_url: &str) -> JoinHandle<()> {
+        let url = format!("{}/api/flag-events", ai_url.trim_end_matches('/'));
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            loop {
+                match events.recv().await {
+                    Ok(_) if chaos::inject(Fault::AiRuntimeError).await => {
+                        debug!("Flag event rejected by {}: 500 (injected)", url)
+                    }
+                    Ok(event) => match client.post(&url).json(&event).send().await {
+                        Ok(response) if !response.status().is_success() => {
+                            debug!("Flag event rejected by {}: {}", url, response.status())
+                        }
+                        Ok(_) => {}
+                        Err(e) => debug!("Failed to forward flag event to {}: {}", url, e),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Flag event forwarder skipped {} events", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
+
+/// Event for a file under `root` that was created, renamed or removed
+///
+/// Only messages in `<user>/new`, `<user>/cur` and the same directories of
+/// `<user>/.<Folder>` count. A file that is gone is reported as expunged
+/// unless the message still exists under another name, as after a flag
+/// change.
+fn maildir_event(root: &Path, path: &Path) -> Option<FlagEvent> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<&str> = relative
+        .iter()
+        .map(|part| part.to_str())
+        .collect::<Option<_>>()?;
+    let (user, mailbox, dir, file) = match parts.as_slice() {
+        [user, dir, file] => (*user, "INBOX", *dir, *file),
+        [user, folder, dir, file] => (*user, folder.strip_prefix('.')?, *dir, *file),
+        _ => return None,
+    };
+    if !matches!(dir, "new" | "cur") || file.starts_with('.') {
+        return None;
+    }
+
+    if path.is_file() {
+        let flags = if dir == "cur" {
+            Mailbox::parse_maildir_flags(file)
+        } else {
+            Vec::new()
+        };
+        return Some(FlagEvent::flags(
+            user,
+            mailbox,
+            file,
+            &flags,
+            FlagSource::Maildir,
+        ));
+    }
+
+    let folder: PathBuf = path.parent()?.parent()?.to_path_buf();
+    let message = base_name(file);
+    let still_present = ["new", "cur"].iter().any(|dir| {
+        fs::read_dir(folder.join(dir))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .any(|entry| base_name(&entry.file_name().to_string_lossy()) == message)
+            })
+            .unwrap_or(false)
+    });
+    if still_present {
+        return None;
+    }
+    Some(FlagEvent::expunged(
+        user,
+        mailbox,
+        message,
+        FlagSource::Maildir,
+    ))
+}
+
+fn watch_error(e: notify::Error) -> MailError {
+    MailError::Io(std::io::Error::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_publish_deduplicates_states() {
+        let bus = FlagEventBus::new();
+        let mut events = bus.subscribe();
+        let seen = vec!["\\Seen".to_string(), "\\Flagged".to_string()];
+
+        assert!(bus.publish(FlagEvent::flags(
+            "alice@example.com",
+            "inbox",
+            "1700000000.1.host:2,FS",
+            &seen,
+            FlagSource::Imap
+        )));
+        // The watcher reporting the same rename adds nothing
+        let mut reordered = seen.clone();
+        reordered.reverse();
+        assert!(!bus.publish(FlagEvent::flags(
+            "alice@example.com",
+            "INBOX",
+            "1700000000.1.host:2,FS",
+            &reordered,
+            FlagSource::Maildir
+        )));
+        assert!(bus.publish(FlagEvent::expunged(
+            "alice@example.com",
+            "INBOX",
+            "1700000000.1.host",
+            FlagSource::Api
+        )));
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.mailbox, "INBOX");
+        assert_eq!(event.message, "1700000000.1.host");
+        assert!(event.is_seen());
+        assert!(event.concerns("alice@example.com", "Inbox"));
+        assert!(events.try_recv().unwrap().expunged);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_maildir_event_paths() {
+        let root = TempDir::new().unwrap();
+        let user = root.path().join("bob@example.com");
+        for dir in ["new", "cur", "tmp", ".Work/cur"] {
+            fs::create_dir_all(user.join(dir)).unwrap();
+        }
+        fs::write(user.join("cur/1.host:2,RS"), b"x").unwrap();
+        fs::write(user.join(".Work/cur/2.host:2,"), b"x").unwrap();
+        fs::write(user.join("tmp/3.host"), b"x").unwrap();
+
+        let event = maildir_event(root.path(), &user.join("cur/1.host:2,RS")).unwrap();
+        assert_eq!(event.mailbox, "INBOX");
+        assert_eq!(event.message, "1.host");
+        assert_eq!(event.flags, ["\\Answered", "\\Seen"]);
+
+        let event = maildir_event(root.path(), &user.join(".Work/cur/2.host:2,")).unwrap();
+        assert_eq!(event.mailbox, "Work");
+        assert!(!event.is_seen());
+
+        // Renamed away for a flag change: not an expunge
+        assert!(maildir_event(root.path(), &user.join("new/1.host")).is_none());
+        let event = maildir_event(root.path(), &user.join("new/4.host")).unwrap();
+        assert!(event.expunged);
+
+        assert!(maildir_event(root.path(), &user.join("tmp/3.host")).is_none());
+        assert!(maildir_event(root.path(), &user.join("mail-rs-uidlist")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch_maildir_publishes_external_changes() {
+        let root = TempDir::new().unwrap();
+        let user = root.path().join("carol@example.com");
+        fs::create_dir_all(user.join("new")).unwrap();
+        fs::create_dir_all(user.join("cur")).unwrap();
+        fs::write(user.join("new/5.host"), b"x").unwrap();
+
+        let bus = Arc::new(FlagEventBus::new());
+        bus.watch_maildir(root.path()).unwrap();
+        let mut events = bus.subscribe();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        fs::rename(user.join("new/5.host"), user.join("cur/5.host:2,S")).unwrap();
+
+        let seen = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = events.recv().await.unwrap();
+                if event.is_seen() {
+                    return event;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(seen.user, "carol@example.com");
+        assert_eq!(seen.message, "5.host");
+        assert_eq!(seen.source, FlagSource::Maildir);
+    }
+}
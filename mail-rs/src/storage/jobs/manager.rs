@@ -0,0 +1,393 @@
+//! Storage job manager: job records, status changes and checkpoints
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+
+use super::types::*;
+
+const COLUMNS: &str = "id, kind, root, max_messages_per_sec, status, checkpoint, total_users, \
+    users_done, processed, changed, failed, skipped_users, error, created_at, started_at, finished_at";
+
+/// Storage job manager
+pub struct StorageJobManager {
+    db: SqlitePool,
+}
+
+impl StorageJobManager {
+    /// Create a new storage job manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS storage_jobs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                root TEXT,
+                max_messages_per_sec INTEGER,
+                status TEXT NOT NULL,
+                checkpoint TEXT,
+                total_users INTEGER NOT NULL DEFAULT 0,
+                users_done INTEGER NOT NULL DEFAULT 0,
+                processed INTEGER NOT NULL DEFAULT 0,
+                changed INTEGER NOT NULL DEFAULT 0,
+                failed INTEGER NOT NULL DEFAULT 0,
+                skipped_users INTEGER NOT NULL DEFAULT 0,
+                error TEXT,
+                created_at TEXT NOT NULL,
+                started_at TEXT,
+                finished_at TEXT
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Queue a new job
+    pub async fn create(&self, job: &NewJob) -> Result<StorageJob> {
+        if let JobKind::Recompress { level } = job.kind {
+            if level > 9 {
+                return Err(anyhow!("Invalid compression level: {}", level));
+            }
+        }
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO storage_jobs (kind, root, max_messages_per_sec, status, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(serde_json::to_string(&job.kind)?)
+        .bind(&job.root)
+        .bind(job.max_messages_per_sec)
+        .bind(JobStatus::Pending.as_str())
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        self.get(result.last_insert_rowid())
+            .await?
+            .ok_or_else(|| anyhow!("Storage job vanished after insert"))
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<StorageJob>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM storage_jobs WHERE id = ?",
+            COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        row.as_ref().map(job_from_row).transpose()
+    }
+
+    /// All jobs, newest first
+    pub async fn list(&self) -> Result<Vec<StorageJob>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM storage_jobs ORDER BY id DESC",
+            COLUMNS
+        ))
+        .fetch_all(&self.db)
+        .await?;
+        rows.iter().map(job_from_row).collect()
+    }
+
+    /// Stop a pending or running job after its next checkpoint; returns
+    /// whether the job was in a state that can be paused
+    pub async fn pause(&self, id: i64) -> Result<bool> {
+        self.transition(
+            id,
+            &[JobStatus::Pending, JobStatus::Running],
+            JobStatus::Paused,
+        )
+        .await
+    }
+
+    /// Queue a paused or failed job again; it continues from its checkpoint
+    pub async fn resume(&self, id: i64) -> Result<bool> {
+        self.transition(
+            id,
+            &[JobStatus::Paused, JobStatus::Failed],
+            JobStatus::Pending,
+        )
+        .await
+    }
+
+    /// Give up on a job that has not finished
+    pub async fn cancel(&self, id: i64) -> Result<bool> {
+        let cancelled = self
+            .transition(
+                id,
+                &[
+                    JobStatus::Pending,
+                    JobStatus::Running,
+                    JobStatus::Paused,
+                    JobStatus::Failed,
+                ],
+                JobStatus::Cancelled,
+            )
+            .await?;
+        if cancelled {
+            sqlx::query("UPDATE storage_jobs SET finished_at = ? WHERE id = ?")
+                .bind(Utc::now().to_rfc3339())
+                .bind(id)
+                .execute(&self.db)
+                .await?;
+        }
+        Ok(cancelled)
+    }
+
+    async fn transition(&self, id: i64, from: &[JobStatus], to: JobStatus) -> Result<bool> {
+        let placeholders = vec!["?"; from.len()].join(", ");
+        let sql = format!(
+            "UPDATE storage_jobs SET status = ? WHERE id = ? AND status IN ({})",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql).bind(to.as_str()).bind(id);
+        for status in from {
+            query = query.bind(status.as_str());
+        }
+        Ok(query.execute(&self.db).await?.rows_affected() > 0)
+    }
+
+    /// Claim the next job to run
+    ///
+    /// Jobs left running by a previous process come first, so a restart
+    /// picks up where it stopped; then pending jobs in creation order.
+    pub async fn next_runnable(&self) -> Result<Option<StorageJob>> {
+        let row = sqlx::query(&format!(
+            r#"
+            SELECT {} FROM storage_jobs
+            WHERE status IN ('running', 'pending')
+            ORDER BY status = 'running' DESC, id
+            LIMIT 1
+            "#,
+            COLUMNS
+        ))
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(job) = row.as_ref().map(job_from_row).transpose()? else {
+            return Ok(None);
+        };
+
+        let claimed = sqlx::query(
+            r#"
+            UPDATE storage_jobs SET status = 'running', started_at = COALESCE(started_at, ?)
+            WHERE id = ? AND status IN ('running', 'pending')
+            "#,
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(job.id)
+        .execute(&self.db)
+        .await?;
+        if claimed.rows_affected() == 0 {
+            return Ok(None);
+        }
+        self.get(job.id).await
+    }
+
+    /// Current status of a job, to notice pauses and cancellations
+    pub async fn status(&self, id: i64) -> Result<Option<JobStatus>> {
+        let status: Option<String> =
+            sqlx::query_scalar("SELECT status FROM storage_jobs WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.db)
+                .await?;
+        status
+            .map(|status| {
+                JobStatus::parse(&status).ok_or_else(|| anyhow!("Invalid job status: {}", status))
+            })
+            .transpose()
+    }
+
+    /// Record how far a job got
+    pub async fn save_progress(
+        &self,
+        id: i64,
+        checkpoint: Option<&Checkpoint>,
+        progress: &Progress,
+    ) -> Result<()> {
+        let checkpoint = checkpoint.map(serde_json::to_string).transpose()?;
+        sqlx::query(
+            r#"
+            UPDATE storage_jobs SET checkpoint = ?, total_users = ?, users_done = ?,
+                processed = ?, changed = ?, failed = ?, skipped_users = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(checkpoint)
+        .bind(progress.total_users)
+        .bind(progress.users_done)
+        .bind(progress.processed)
+        .bind(progress.changed)
+        .bind(progress.failed)
+        .bind(progress.skipped_users)
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Mark a running job completed or failed
+    ///
+    /// Jobs paused or cancelled in the meantime keep that status.
+    pub async fn finish(&self, id: i64, status: JobStatus, error: Option<&str>) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE storage_jobs SET status = ?, error = ?, finished_at = ?
+            WHERE id = ? AND status = 'running'
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(error)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+fn job_from_row(row: &SqliteRow) -> Result<StorageJob> {
+    let status: String = row.get("status");
+    let checkpoint: Option<String> = row.get("checkpoint");
+    let max_rate: Option<i64> = row.get("max_messages_per_sec");
+
+    Ok(StorageJob {
+        id: row.get("id"),
+        kind: serde_json::from_str(row.get("kind"))?,
+        root: row.get("root"),
+        max_messages_per_sec: max_rate.map(|rate| rate as u32),
+        status: JobStatus::parse(&status)
+            .ok_or_else(|| anyhow!("Invalid job status: {}", status))?,
+        checkpoint: checkpoint
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+        total_users: row.get("total_users"),
+        users_done: row.get("users_done"),
+        processed: row.get("processed"),
+        changed: row.get("changed"),
+        failed: row.get("failed"),
+        skipped_users: row.get("skipped_users"),
+        error: row.get("error"),
+        created_at: parse_timestamp(row.get("created_at"))?,
+        started_at: row
+            .get::<Option<&str>, _>("started_at")
+            .map(parse_timestamp)
+            .transpose()?,
+        finished_at: row
+            .get::<Option<&str>, _>("finished_at")
+            .map(parse_timestamp)
+            .transpose()?,
+    })
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recompress() -> NewJob {
+        NewJob {
+            kind: JobKind::Recompress { level: 6 },
+            root: None,
+            max_messages_per_sec: Some(100),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_lifecycle() {
+        let manager = StorageJobManager::connect("sqlite::memory:").await.unwrap();
+
+        let first = manager.create(&recompress()).await.unwrap();
+        assert_eq!(first.status, JobStatus::Pending);
+        assert_eq!(first.kind, JobKind::Recompress { level: 6 });
+        let second = manager
+            .create(&NewJob {
+                kind: JobKind::RenameFiles,
+                root: Some("/srv/archive".to_string()),
+                max_messages_per_sec: None,
+            })
+            .await
+            .unwrap();
+        assert!(manager
+            .create(&NewJob {
+                kind: JobKind::Recompress { level: 10 },
+                root: None,
+                max_messages_per_sec: None,
+            })
+            .await
+            .is_err());
+
+        let claimed = manager.next_runnable().await.unwrap().unwrap();
+        assert_eq!(claimed.id, first.id);
+        assert_eq!(claimed.status, JobStatus::Running);
+        assert!(claimed.started_at.is_some());
+
+        let checkpoint = Checkpoint {
+            root: "/var/mail".to_string(),
+            user: "alice@example.com".to_string(),
+            message: Some("cur/1.2.host:2,S".to_string()),
+        };
+        let progress = Progress {
+            total_users: 3,
+            processed: 120,
+            changed: 80,
+            ..Default::default()
+        };
+        manager
+            .save_progress(first.id, Some(&checkpoint), &progress)
+            .await
+            .unwrap();
+
+        // A restart resumes the interrupted job before starting new ones
+        let resumed = manager.next_runnable().await.unwrap().unwrap();
+        assert_eq!(resumed.id, first.id);
+        assert_eq!(resumed.checkpoint, Some(checkpoint));
+        assert_eq!(resumed.progress(), progress);
+
+        assert!(manager.pause(first.id).await.unwrap());
+        assert!(!manager.pause(first.id).await.unwrap());
+        manager
+            .finish(first.id, JobStatus::Completed, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.status(first.id).await.unwrap(),
+            Some(JobStatus::Paused)
+        );
+
+        assert_eq!(
+            manager.next_runnable().await.unwrap().unwrap().id,
+            second.id
+        );
+        assert!(manager.cancel(second.id).await.unwrap());
+        assert!(!manager.resume(second.id).await.unwrap());
+        assert!(manager.resume(first.id).await.unwrap());
+
+        let jobs = manager.list().await.unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].status, JobStatus::Cancelled);
+        assert!(jobs[0].finished_at.is_some());
+        assert_eq!(jobs[1].status, JobStatus::Pending);
+        assert_eq!(manager.get(99).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,21 @@
+//! Background storage maintenance jobs
+//!
+//! Long-running tasks over every stored message, such as recompressing
+//! message files or moving them to the standard Maildir file name scheme.
+//! Jobs are queued through the admin API, run one at a time in the
+//! background, save a checkpoint as they go and can be paused, resumed and
+//! cancelled. A job interrupted by a restart continues from its last
+//! checkpoint. Each job can be throttled to a number of messages per
+//! second, and mailboxes locked for a backend migration are left alone.
+//!
+//! Re-encrypting mailboxes is not offered: there is no encrypted storage
+//! backend. A new task is one more [`JobKind`] variant and a branch in the
+//! runner.
+
+pub mod manager;
+pub mod runner;
+pub mod types;
+
+pub use manager::StorageJobManager;
+pub use runner::{RunOutcome, StorageJobRunner};
+pub use types::*;
@@ -0,0 +1,489 @@
+//! Storage job runner
+//!
+//! Runs one job at a time, so maintenance never competes with itself for
+//! disk bandwidth. Users are processed in sorted order per root and their
+//! messages in sorted id order; every [`CHECKPOINT_INTERVAL`] messages the
+//! position and counters are saved and the job's status is re-read, which
+//! is how pauses and cancellations take effect. Tasks are idempotent, so
+//! the few messages processed again after a crash are left as they are.
+//! In read-only mode a running job stops at its next checkpoint and is
+//! resumed from there once the mode is cleared.
+
+use super::manager::StorageJobManager;
+use super::types::*;
+use crate::imap::uid::{base_name, UidList};
+use crate::recovery::ReadOnlyMode;
+use crate::storage::compressed::CompressedMaildirStorage;
+use crate::storage::maildir::{
+    is_mailbox_locked, list_message_ids, list_user_dirs, message_path, user_path, write_atomic,
+};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::fs;
+use tracing::{error, info, warn};
+
+/// Save progress and check for pauses every this many messages
+pub const CHECKPOINT_INTERVAL: i64 = 100;
+
+/// How a job run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Every message was processed
+    Finished,
+    /// The job was paused or cancelled while running
+    Stopped,
+}
+
+/// Runs storage jobs against the server's Maildir roots
+pub struct StorageJobRunner {
+    manager: Arc<StorageJobManager>,
+    /// Roots processed by jobs without a root of their own
+    roots: Vec<PathBuf>,
+    /// Host part of renamed message files
+    hostname: String,
+    /// Holds jobs while the server is read-only
+    read_only: Option<Arc<ReadOnlyMode>>,
+}
+
+impl StorageJobRunner {
+    pub fn new(manager: Arc<StorageJobManager>, roots: Vec<PathBuf>, hostname: &str) -> Self {
+        Self {
+            manager,
+            roots,
+            // Maildir escapes these in the host part
+            hostname: hostname.replace('/', "\\057").replace(':', "\\072"),
+            read_only: None,
+        }
+    }
+
+    /// Pause jobs while the server is read-only
+    pub fn with_read_only(mut self, mode: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(mode);
+        self
+    }
+
+    /// Run queued jobs forever, checking for new ones every `poll_interval`
+    pub async fn run(self, poll_interval: Duration) {
+        loop {
+            if let Some(mode) = &self.read_only {
+                mode.writable().await;
+            }
+            match self.run_next().await {
+                Ok(Some(_)) => continue,
+                Ok(None) => {}
+                Err(e) => error!("Storage job runner error: {}", e),
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Run the next queued or interrupted job until it finishes or is
+    /// paused; returns None when there is nothing to do
+    pub async fn run_next(&self) -> Result<Option<RunOutcome>> {
+        let Some(job) = self.manager.next_runnable().await? else {
+            return Ok(None);
+        };
+        info!("Running storage job {} ({:?})", job.id, job.kind);
+
+        match self.process(&job).await {
+            Ok(RunOutcome::Finished) => {
+                info!("Storage job {} completed", job.id);
+                self.manager
+                    .finish(job.id, JobStatus::Completed, None)
+                    .await?;
+                Ok(Some(RunOutcome::Finished))
+            }
+            Ok(RunOutcome::Stopped) => {
+                info!("Storage job {} stopped", job.id);
+                Ok(Some(RunOutcome::Stopped))
+            }
+            Err(e) => {
+                error!("Storage job {} failed: {}", job.id, e);
+                self.manager
+                    .finish(job.id, JobStatus::Failed, Some(&e.to_string()))
+                    .await?;
+                Ok(Some(RunOutcome::Finished))
+            }
+        }
+    }
+
+    async fn process(&self, job: &StorageJob) -> Result<RunOutcome> {
+        let roots = match &job.root {
+            Some(root) => vec![PathBuf::from(root)],
+            None => self.roots.clone(),
+        };
+
+        let mut users = Vec::new();
+        for (index, root) in roots.iter().enumerate() {
+            for user in list_user_dirs(root).await? {
+                users.push((index, root, user));
+            }
+        }
+
+        // Skip everything up to the checkpoint
+        let resume = job.checkpoint.as_ref().and_then(|checkpoint| {
+            let index = roots
+                .iter()
+                .position(|root| root.to_string_lossy() == checkpoint.root)?;
+            Some((index, checkpoint))
+        });
+        let start = match resume {
+            Some((index, checkpoint)) => users
+                .iter()
+                .position(|(i, _, user)| {
+                    (*i, user) > (index, &checkpoint.user)
+                        || ((*i, user) == (index, &checkpoint.user) && checkpoint.message.is_some())
+                })
+                .unwrap_or(users.len()),
+            None => 0,
+        };
+
+        let mut progress = job.progress();
+        progress.total_users = users.len() as i64;
+        let mut checkpoint = job.checkpoint.clone();
+        let delay = job
+            .max_messages_per_sec
+            .filter(|rate| *rate > 0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate as f64));
+
+        for (_, root, user) in &users[start..] {
+            if !self.still_running(job.id).await? {
+                return Ok(RunOutcome::Stopped);
+            }
+            let position = |message: Option<String>| Checkpoint {
+                root: root.to_string_lossy().to_string(),
+                user: user.clone(),
+                message,
+            };
+
+            if is_mailbox_locked(root, user) {
+                warn!("Storage job {}: skipping locked mailbox {}", job.id, user);
+                progress.skipped_users += 1;
+            } else {
+                let after = checkpoint
+                    .take()
+                    .filter(|c| c.root == root.to_string_lossy() && &c.user == user)
+                    .and_then(|c| c.message);
+                let user_dir = user_path(root, user)?;
+
+                let mut since_checkpoint = 0;
+                for id in list_message_ids(&user_dir).await? {
+                    if after.as_ref().is_some_and(|after| &id <= after) {
+                        continue;
+                    }
+
+                    match self.process_message(&job.kind, &user_dir, &id).await {
+                        Ok(true) => progress.changed += 1,
+                        Ok(false) => {}
+                        Err(e) => {
+                            warn!("Storage job {}: {}/{}: {}", job.id, user, id, e);
+                            progress.failed += 1;
+                        }
+                    }
+                    progress.processed += 1;
+                    since_checkpoint += 1;
+
+                    if since_checkpoint >= CHECKPOINT_INTERVAL {
+                        since_checkpoint = 0;
+                        self.manager
+                            .save_progress(job.id, Some(&position(Some(id))), &progress)
+                            .await?;
+                        if !self.still_running(job.id).await? {
+                            return Ok(RunOutcome::Stopped);
+                        }
+                    }
+                    if let Some(delay) = delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+
+            progress.users_done += 1;
+            checkpoint = Some(position(None));
+            self.manager
+                .save_progress(job.id, checkpoint.as_ref(), &progress)
+                .await?;
+        }
+
+        self.manager
+            .save_progress(job.id, checkpoint.as_ref(), &progress)
+            .await?;
+        Ok(RunOutcome::Finished)
+    }
+
+    /// Whether to go on with a job; a job stopped by read-only mode keeps
+    /// its status and is resumed later
+    async fn still_running(&self, id: i64) -> Result<bool> {
+        if self.read_only.as_ref().is_some_and(|mode| mode.is_read_only()) {
+            return Ok(false);
+        }
+        Ok(self.manager.status(id).await? == Some(JobStatus::Running))
+    }
+
+    /// Apply a job's task to one message; returns whether it was changed
+    async fn process_message(&self, kind: &JobKind, user_dir: &Path, id: &str) -> Result<bool> {
+        let path = message_path(user_dir, id)?;
+        match kind {
+            JobKind::Recompress { level } => recompress(user_dir, &path, *level).await,
+            JobKind::RenameFiles => self.rename(&path).await,
+        }
+    }
+
+    /// Give a message file a `time.unique.host` name unless it has one
+    async fn rename(&self, path: &Path) -> Result<bool> {
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let base = base_name(&filename);
+        if follows_scheme(base) {
+            return Ok(false);
+        }
+
+        // Keep the delivery time, taken from the name or the file
+        let digits: String = base.chars().take_while(char::is_ascii_digit).collect();
+        let time = match digits.parse::<u64>() {
+            Ok(time) => time,
+            Err(_) => fs::metadata(path)
+                .await?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        let new_name = format!(
+            "{}.{}.{}{}",
+            time,
+            uuid::Uuid::new_v4().simple(),
+            self.hostname,
+            &filename[base.len()..]
+        );
+
+        let new_path = path.with_file_name(new_name);
+        let folder = path
+            .parent()
+            .and_then(Path::parent)
+            .ok_or_else(|| anyhow::anyhow!("Message outside a Maildir folder"))?;
+        UidList::rename_message(folder, path, &new_path)?;
+        Ok(true)
+    }
+}
+
+/// Rewrite a message gzip-compressed at `level`, or plain with level 0
+async fn recompress(user_dir: &Path, path: &Path, level: u32) -> Result<bool> {
+    let raw = fs::read(path).await?;
+    let plain = CompressedMaildirStorage::decompress(raw.clone())?;
+    let encoded = if level == 0 {
+        plain
+    } else {
+        CompressedMaildirStorage::new(String::new())
+            .with_level(level)
+            .compress(&plain)?
+    };
+    if encoded == raw {
+        return Ok(false);
+    }
+
+    // Keep the modification time, which clients see as the arrival date
+    let modified = fs::metadata(path).await?.modified().ok();
+    write_atomic(user_dir, path, &encoded).await?;
+    if let Some(modified) = modified {
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)?;
+    }
+    Ok(true)
+}
+
+/// Whether a base name looks like `time.unique.host`
+fn follows_scheme(base: &str) -> bool {
+    let mut parts = base.splitn(3, '.');
+    let (Some(time), Some(unique), Some(host)) = (parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+    !time.is_empty()
+        && time.bytes().all(|b| b.is_ascii_digit())
+        && !unique.is_empty()
+        && !host.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs as stdfs;
+    use tempfile::TempDir;
+
+    async fn setup() -> (TempDir, Arc<StorageJobManager>, StorageJobRunner) {
+        let dir = TempDir::new().unwrap();
+        for (user, id) in [
+            ("alice@example.com", "new/1700000001.1.host"),
+            ("alice@example.com", "cur/1700000002.2.host:2,S"),
+            ("alice@example.com", ".Sent/cur/1700000003.3.host:2,S"),
+            ("bob@example.com", "new/1700000004.4.host"),
+        ] {
+            let path = dir.path().join(user).join(id);
+            stdfs::create_dir_all(path.parent().unwrap()).unwrap();
+            stdfs::write(
+                &path,
+                format!("Subject: {}\r\n\r\n{}", id, "body ".repeat(100)),
+            )
+            .unwrap();
+        }
+
+        let manager = Arc::new(StorageJobManager::connect("sqlite::memory:").await.unwrap());
+        let runner = StorageJobRunner::new(
+            manager.clone(),
+            vec![dir.path().to_path_buf()],
+            "mail.example.com",
+        );
+        (dir, manager, runner)
+    }
+
+    fn new_job(kind: JobKind) -> NewJob {
+        NewJob {
+            kind,
+            root: None,
+            max_messages_per_sec: None,
+        }
+    }
+
+    fn is_gzip(path: PathBuf) -> bool {
+        stdfs::read(path).unwrap().starts_with(&[0x1f, 0x8b])
+    }
+
+    #[tokio::test]
+    async fn test_recompress_skips_locked_mailboxes() {
+        let (dir, manager, runner) = setup().await;
+        let alice = dir.path().join("alice@example.com");
+        let modified = stdfs::metadata(alice.join("new/1700000001.1.host"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        stdfs::write(dir.path().join("bob@example.com/.migration.lock"), "").unwrap();
+
+        let job = manager
+            .create(&new_job(JobKind::Recompress { level: 6 }))
+            .await
+            .unwrap();
+        assert_eq!(runner.run_next().await.unwrap(), Some(RunOutcome::Finished));
+        assert_eq!(runner.run_next().await.unwrap(), None);
+
+        let job = manager.get(job.id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!((job.total_users, job.users_done), (2, 2));
+        assert_eq!((job.processed, job.changed, job.failed), (3, 3, 0));
+        assert_eq!(job.skipped_users, 1);
+        assert!(is_gzip(alice.join(".Sent/cur/1700000003.3.host:2,S")));
+        assert!(!is_gzip(
+            dir.path().join("bob@example.com/new/1700000004.4.host")
+        ));
+        assert_eq!(
+            stdfs::metadata(alice.join("new/1700000001.1.host"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+
+        // Running it again changes nothing; level 0 decompresses
+        manager
+            .create(&new_job(JobKind::Recompress { level: 6 }))
+            .await
+            .unwrap();
+        runner.run_next().await.unwrap();
+        assert_eq!(manager.list().await.unwrap()[0].changed, 0);
+
+        manager
+            .create(&new_job(JobKind::Recompress { level: 0 }))
+            .await
+            .unwrap();
+        runner.run_next().await.unwrap();
+        assert_eq!(manager.list().await.unwrap()[0].changed, 3);
+        assert!(stdfs::read_to_string(alice.join("new/1700000001.1.host"))
+            .unwrap()
+            .starts_with("Subject: new/1700000001.1.host"));
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_checkpoint() {
+        let (dir, manager, runner) = setup().await;
+        let alice = dir.path().join("alice@example.com");
+
+        // A previous run got through alice's first message
+        let job = manager
+            .create(&new_job(JobKind::Recompress { level: 6 }))
+            .await
+            .unwrap();
+        let checkpoint = Checkpoint {
+            root: dir.path().to_string_lossy().to_string(),
+            user: "alice@example.com".to_string(),
+            message: Some(".Sent/cur/1700000003.3.host:2,S".to_string()),
+        };
+        let progress = Progress {
+            total_users: 2,
+            processed: 1,
+            changed: 1,
+            ..Default::default()
+        };
+        manager
+            .save_progress(job.id, Some(&checkpoint), &progress)
+            .await
+            .unwrap();
+
+        // Paused jobs are not picked up
+        assert!(manager.pause(job.id).await.unwrap());
+        assert_eq!(runner.run_next().await.unwrap(), None);
+        assert!(manager.resume(job.id).await.unwrap());
+
+        assert_eq!(runner.run_next().await.unwrap(), Some(RunOutcome::Finished));
+        let job = manager.get(job.id).await.unwrap().unwrap();
+        assert_eq!((job.processed, job.changed, job.users_done), (4, 4, 2));
+        assert!(!is_gzip(alice.join(".Sent/cur/1700000003.3.host:2,S")));
+        assert!(is_gzip(alice.join("cur/1700000002.2.host:2,S")));
+        assert!(is_gzip(
+            dir.path().join("bob@example.com/new/1700000004.4.host")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rename_keeps_flags_and_uids() {
+        let (dir, manager, runner) = setup().await;
+        let cur = dir.path().join("alice@example.com/cur");
+        stdfs::write(cur.join("1700000005.0123abcd:2,RS"), "compressed backend").unwrap();
+        stdfs::write(cur.join("imported:2,F"), "hand copied").unwrap();
+
+        let names = |dir: &Path| {
+            let mut names: Vec<String> = stdfs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = UidList::sync_folder(cur.parent().unwrap(), &names(&cur)).unwrap();
+
+        manager
+            .create(&new_job(JobKind::RenameFiles))
+            .await
+            .unwrap();
+        runner.run_next().await.unwrap();
+        assert_eq!(manager.list().await.unwrap()[0].changed, 2);
+
+        let renamed = names(&cur);
+        assert_eq!(renamed.len(), 3);
+        assert!(renamed.contains(&"1700000002.2.host:2,S".to_string()));
+        assert!(renamed.iter().all(|name| follows_scheme(base_name(name))));
+        let compressed = renamed.iter().find(|name| name.ends_with(":2,RS")).unwrap();
+        assert!(compressed.starts_with("1700000005.") && compressed.contains(".mail.example.com"));
+
+        let after = UidList::sync_folder(cur.parent().unwrap(), &renamed).unwrap();
+        assert_eq!(after.uid_validity, before.uid_validity);
+        assert_eq!(after.uid(compressed), before.uid("1700000005.0123abcd"));
+        let imported = renamed.iter().find(|name| name.ends_with(":2,F")).unwrap();
+        assert_eq!(after.uid(imported), before.uid("imported"));
+        assert_eq!(after.uid_next, before.uid_next);
+    }
+}
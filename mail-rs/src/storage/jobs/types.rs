@@ -0,0 +1,129 @@
+//! Storage job types
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Maintenance task applied to every message of a job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    /// Rewrite message files gzip-compressed at `level` (1-9), or
+    /// uncompressed with level 0
+    Recompress { level: u32 },
+    /// Rename message files that don't follow the `time.unique.host`
+    /// Maildir scheme, keeping their flags and IMAP UIDs
+    RenameFiles,
+}
+
+/// Lifecycle of a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for the runner
+    Pending,
+    /// Being processed, or interrupted by a restart
+    Running,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Paused => "paused",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(JobStatus::Pending),
+            "running" => Some(JobStatus::Running),
+            "paused" => Some(JobStatus::Paused),
+            "completed" => Some(JobStatus::Completed),
+            "failed" => Some(JobStatus::Failed),
+            "cancelled" => Some(JobStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// Last position reached by a job
+///
+/// Roots are processed in order, users and their messages in sorted order,
+/// so everything up to and including this position is done.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Maildir root
+    pub root: String,
+    pub user: String,
+    /// Last processed message id; None once the user is finished
+    pub message: Option<String>,
+}
+
+/// Request to start a job
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewJob {
+    #[serde(flatten)]
+    pub kind: JobKind,
+    /// Maildir root to process; defaults to the server's mailboxes
+    pub root: Option<String>,
+    /// Process at most this many messages per second (None = unthrottled)
+    pub max_messages_per_sec: Option<u32>,
+}
+
+/// A storage maintenance job and its progress
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageJob {
+    pub id: i64,
+    #[serde(flatten)]
+    pub kind: JobKind,
+    pub root: Option<String>,
+    pub max_messages_per_sec: Option<u32>,
+    pub status: JobStatus,
+    pub checkpoint: Option<Checkpoint>,
+    pub total_users: i64,
+    pub users_done: i64,
+    /// Messages looked at
+    pub processed: i64,
+    /// Messages rewritten or renamed
+    pub changed: i64,
+    /// Messages that could not be processed; the job goes on without them
+    pub failed: i64,
+    /// Mailboxes skipped because they were locked for migration
+    pub skipped_users: i64,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Progress counters saved with each checkpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub total_users: i64,
+    pub users_done: i64,
+    pub processed: i64,
+    pub changed: i64,
+    pub failed: i64,
+    pub skipped_users: i64,
+}
+
+impl StorageJob {
+    pub fn progress(&self) -> Progress {
+        Progress {
+            total_users: self.total_users,
+            users_done: self.users_done,
+            processed: self.processed,
+            changed: self.changed,
+            failed: self.failed,
+            skipped_users: self.skipped_users,
+        }
+    }
+}
@@ -1,7 +1,24 @@
+use super::metadata::MetadataIndex;
+use super::single_instance::{self, SisReport};
+use super::Storage;
+use crate::chaos::{self, Fault};
 use crate::error::{MailError, Result};
-use std::path::PathBuf;
+use crate::imap::Mailbox;
+use crate::quota::{maildirsize, MailboxUsage};
+use crate::imap::uid::base_name;
+use crate::residency::ResidencyMap;
+use crate::search::{IndexJob, IndexQueue};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::fs;
-use tracing::info;
+use tracing::{error, info, warn};
+
+/// Lock file marking a mailbox that is being cut over to another backend
+pub const MIGRATION_LOCK_FILE: &str = ".migration.lock";
+
+/// Per-process sequence number that keeps delivered file names unique
+static DELIVERY_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 /// Maildir storage backend
 ///
@@ -37,40 +54,299 @@ use tracing::info;
 /// ```
 pub struct MaildirStorage {
     base_path: PathBuf,
+    /// Region-specific roots for pinned domains and users
+    residency: Option<Arc<ResidencyMap>>,
+    /// Metadata of stored messages, kept up to date on every write
+    metadata: Option<Arc<MetadataIndex>>,
+    /// Link deliveries with identical content to one shared copy
+    single_instance: bool,
+    /// Queue stored, moved and deleted messages for the search index
+    search: Option<Arc<IndexQueue>>,
 }
 
 impl MaildirStorage {
     pub fn new(base_path: String) -> Self {
         Self {
             base_path: PathBuf::from(base_path),
+            residency: None,
+            metadata: None,
+            single_instance: false,
+            search: None,
+        }
+    }
+
+    /// Store mailboxes of domains and users pinned to a region below the
+    /// region's maildir path
+    ///
+    /// Changing an assignment does not move existing mail.
+    pub fn with_residency(mut self, residency: Arc<ResidencyMap>) -> Self {
+        self.residency = Some(residency);
+        self
+    }
+
+    /// Record stored, moved and deleted messages in `index`
+    pub fn with_metadata(mut self, index: Arc<MetadataIndex>) -> Self {
+        self.metadata = Some(index);
+        self
+    }
+
+    /// Queue stored, moved and deleted messages for the search index
+    pub fn with_search(mut self, queue: Arc<IndexQueue>) -> Self {
+        self.search = Some(queue);
+        self
+    }
+
+    /// Store identical deliveries once, see [`super::single_instance`]
+    pub fn with_single_instance(mut self) -> Self {
+        self.single_instance = true;
+        self
+    }
+
+    /// Whether identical deliveries share one copy
+    pub fn is_single_instance(&self) -> bool {
+        self.single_instance
+    }
+
+    /// Release shared copies no mailbox links anymore, in every root
+    pub async fn collect_single_instance_garbage(&self) -> Result<SisReport> {
+        let mut report = SisReport::default();
+        for root in self.roots() {
+            let root_report = single_instance::collect_garbage(&root).await?;
+            report.blobs += root_report.blobs;
+            report.links += root_report.links;
+            report.saved_bytes += root_report.saved_bytes;
+            report.removed += root_report.removed;
+        }
+        Ok(report)
+    }
+
+    /// Run [`Self::collect_single_instance_garbage`] every `interval`
+    pub async fn run_single_instance_gc(self: Arc<Self>, interval: std::time::Duration) {
+        loop {
+            match self.collect_single_instance_garbage().await {
+                Ok(report) => info!(
+                    "{} shared message copies save {} bytes, removed {} unused",
+                    report.blobs, report.saved_bytes, report.removed
+                ),
+                Err(e) => error!("Shared message copy collection failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Metadata index kept up to date by this storage, if any
+    pub fn metadata(&self) -> Option<&Arc<MetadataIndex>> {
+        self.metadata.as_ref()
+    }
+
+    /// Every directory holding mailboxes
+    fn roots(&self) -> Vec<PathBuf> {
+        match &self.residency {
+            Some(residency) => residency.maildir_roots(&self.base_path),
+            None => vec![self.base_path.clone()],
+        }
+    }
+
+    /// Directory holding the mailbox of `user`
+    pub fn root_for(&self, user: &str) -> PathBuf {
+        match &self.residency {
+            Some(residency) => residency.maildir_root(user, &self.base_path),
+            None => self.base_path.clone(),
         }
     }
 
     pub async fn store(&self, recipient: &str, data: &[u8]) -> Result<String> {
+        self.store_in_folder(recipient, None, data).await
+    }
+
+    /// Store a message in a folder of the recipient's mailbox (`None` for
+    /// INBOX), creating the folder if needed
+    pub async fn store_in_folder(
+        &self,
+        recipient: &str,
+        folder: Option<&str>,
+        data: &[u8],
+    ) -> Result<String> {
+        self.store_with_flags(recipient, folder, &[], data).await
+    }
+
+    /// Store a message like [`Self::store_in_folder`], with IMAP system
+    /// flags such as `\Seen`; flagged messages go straight to `cur/`
+    pub async fn store_with_flags(
+        &self,
+        recipient: &str,
+        folder: Option<&str>,
+        flags: &[String],
+        data: &[u8],
+    ) -> Result<String> {
+        // Deliveries during a cutover would miss the final sync
+        let base_path = self.root_for(recipient);
+        if is_mailbox_locked(&base_path, recipient) {
+            return Err(MailError::Storage(format!(
+                "Mailbox {} is locked for migration",
+                recipient
+            )));
+        }
+
         // Create mailbox directory structure if it doesn't exist
-        let mailbox_path = self.base_path.join(recipient);
+        let mut mailbox_path = base_path.join(recipient);
+        if let Some(folder) = folder {
+            if folder.is_empty() || folder.contains(['/', '\\']) || folder.starts_with('.') {
+                return Err(MailError::Storage(format!("Invalid folder name: {}", folder)));
+            }
+            self.ensure_maildir_structure(&mailbox_path).await?;
+            mailbox_path = mailbox_path.join(format!(".{}", folder));
+        }
         self.ensure_maildir_structure(&mailbox_path).await?;
 
         // Generate unique filename
         let filename = self.generate_filename();
         let tmp_path = mailbox_path.join("tmp").join(&filename);
-        let new_path = mailbox_path.join("new").join(&filename);
+        let flagged = Mailbox::build_maildir_filename_with_flags(&filename, flags);
+        let new_path = if flagged.ends_with(":2,") {
+            mailbox_path.join("new").join(&filename)
+        } else {
+            mailbox_path.join("cur").join(&flagged)
+        };
 
-        // Write to tmp directory first
-        fs::write(&tmp_path, data).await?;
+        if chaos::inject(Fault::StorageWrite).await {
+            return Err(MailError::Storage(format!(
+                "Injected write failure for {}",
+                recipient
+            )));
+        }
+
+        if self.single_instance {
+            // Hardlinks appear atomically, like the rename
+            single_instance::link(&base_path, data, &new_path).await?;
+        } else {
+            // Write to tmp directory first
+            fs::write(&tmp_path, data).await?;
 
-        // Move to new directory (atomic operation)
-        fs::rename(&tmp_path, &new_path).await?;
+            // Move to new directory (atomic operation)
+            fs::rename(&tmp_path, &new_path).await?;
+        }
+
+        account_async(&base_path.join(recipient), data.len() as i64, 1).await;
 
         info!(
             "Stored email for {} as {}",
             recipient,
             new_path.display()
         );
+        self.index_file(recipient, folder.unwrap_or("INBOX"), &new_path)
+            .await;
 
         Ok(filename)
     }
 
+    /// Move a delivered message, stored as `filename` in `folder` (`None`
+    /// for INBOX), to folder `to`; false if it is no longer there, e.g.
+    /// because a client moved or deleted it meanwhile
+    pub async fn move_message(
+        &self,
+        user: &str,
+        folder: Option<&str>,
+        filename: &str,
+        to: &str,
+    ) -> Result<bool> {
+        if to.is_empty() || to.contains(['/', '\\']) || to.starts_with('.') {
+            return Err(MailError::Storage(format!("Invalid folder name: {}", to)));
+        }
+        let mailbox_path = self.root_for(user).join(user);
+        let source = match folder {
+            Some(folder) => mailbox_path.join(format!(".{}", folder)),
+            None => mailbox_path.clone(),
+        };
+        let target = mailbox_path.join(format!(".{}", to));
+
+        // Clients move read messages to cur/ and add flags to the name
+        let mut found = None;
+        for subdir in ["new", "cur"] {
+            let Ok(mut entries) = fs::read_dir(source.join(subdir)).await else {
+                continue;
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.split(":2,").next() == Some(filename) {
+                    found = Some((subdir, name));
+                    break;
+                }
+            }
+            if found.is_some() {
+                break;
+            }
+        }
+        let Some((subdir, name)) = found else {
+            return Ok(false);
+        };
+
+        self.ensure_maildir_structure(&target).await?;
+        fs::rename(source.join(subdir).join(&name), target.join(subdir).join(&name)).await?;
+        self.index_file(user, to, &target.join(subdir).join(&name)).await;
+        self.unindex(user, folder.unwrap_or("INBOX"), &name).await;
+        Ok(true)
+    }
+
+    /// Record a message file in the metadata index and queue it for the
+    /// search index; failures only cost freshness until the next reconcile,
+    /// so they don't fail the write
+    async fn index_file(&self, user: &str, folder: &str, path: &Path) {
+        if let Some(index) = &self.metadata {
+            if let Err(e) = index.record_file(user, folder, path).await {
+                warn!("Failed to index {}: {}", path.display(), e);
+            }
+        }
+        if let Some(queue) = &self.search {
+            queue.push(IndexJob::Index {
+                user: user.to_string(),
+                folder: folder.to_string(),
+                path: path.to_path_buf(),
+                if_missing: false,
+            });
+        }
+    }
+
+    /// Remove a message from the metadata and search indexes
+    async fn unindex(&self, user: &str, folder: &str, message: &str) {
+        if let Some(index) = &self.metadata {
+            if let Err(e) = index.remove(user, folder, message).await {
+                warn!("Failed to unindex {} of {}: {}", message, user, e);
+            }
+        }
+        if let Some(queue) = &self.search {
+            queue.push(IndexJob::Remove {
+                user: user.to_string(),
+                folder: folder.to_string(),
+                message: base_name(message).to_string(),
+            });
+        }
+    }
+
+    /// Storage used by the mailbox of `user`, from its `maildirsize` file
+    /// rather than a walk over every folder
+    pub async fn usage(&self, user: &str) -> Result<MailboxUsage> {
+        let user_dir = user_path(&self.root_for(user), user)?;
+        Ok(tokio::task::spawn_blocking(move || maildirsize::usage(&user_dir))
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))??)
+    }
+
+    /// Measure the mailbox of `user` on disk and rewrite its
+    /// `maildirsize` file with the result
+    pub async fn recalculate_usage(&self, user: &str) -> Result<MailboxUsage> {
+        let user_dir = user_path(&self.root_for(user), user)?;
+        // Walking a large mailbox must not hold up the runtime
+        Ok(tokio::task::spawn_blocking(move || maildirsize::recalculate(&user_dir))
+            .await
+            .map_err(|e| MailError::Storage(e.to_string()))??)
+    }
+
+    /// Check whether a mailbox is locked for cutover
+    pub fn is_locked(&self, user: &str) -> bool {
+        is_mailbox_locked(&self.root_for(user), user)
+    }
+
     async fn ensure_maildir_structure(&self, mailbox_path: &PathBuf) -> Result<()> {
         for subdir in &["tmp", "new", "cur"] {
             let dir = mailbox_path.join(subdir);
@@ -84,17 +360,348 @@ impl MaildirStorage {
     }
 
     fn generate_filename(&self) -> String {
-        // Maildir filename format: timestamp.pid.hostname
-        let timestamp = std::time::SystemTime::now()
+        // Maildir filename format: time.M<usec>P<pid>Q<counter>.hostname, so
+        // deliveries within the same second don't overwrite each other
+        let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+            .unwrap_or_default();
 
         let pid = std::process::id();
         let hostname = gethostname::gethostname()
             .to_string_lossy()
             .to_string();
 
-        format!("{}.{}.{}", timestamp, pid, hostname)
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            pid,
+            DELIVERY_COUNTER.fetch_add(1, Ordering::Relaxed),
+            hostname
+        )
+    }
+}
+
+impl Storage for MaildirStorage {
+    async fn store(&self, user: &str, data: &[u8]) -> Result<String> {
+        user_path(&self.root_for(user), user)?;
+        let filename = MaildirStorage::store(self, user, data).await?;
+        Ok(format!("new/{}", filename))
+    }
+
+    async fn list_users(&self) -> Result<Vec<String>> {
+        let mut users = Vec::new();
+        for root in self.roots() {
+            users.extend(list_user_dirs(&root).await?);
+        }
+        users.sort();
+        users.dedup();
+        Ok(users)
+    }
+
+    async fn list_messages(&self, user: &str) -> Result<Vec<String>> {
+        list_message_ids(&user_path(&self.root_for(user), user)?).await
+    }
+
+    async fn read_message(&self, user: &str, id: &str) -> Result<Vec<u8>> {
+        let path = message_path(&user_path(&self.root_for(user), user)?, id)?;
+        Ok(fs::read(&path).await?)
+    }
+
+    async fn write_message(&self, user: &str, id: &str, data: &[u8]) -> Result<()> {
+        let user_dir = user_path(&self.root_for(user), user)?;
+        let path = message_path(&user_dir, id)?;
+        let previous = fs::metadata(&path).await.ok().map(|metadata| metadata.len());
+        write_atomic(&user_dir, &path, data).await?;
+        account_async(
+            &user_dir,
+            data.len() as i64 - previous.unwrap_or(0) as i64,
+            previous.is_none() as i64,
+        )
+        .await;
+        self.index_file(user, &id_folder(id), &path).await;
+        Ok(())
+    }
+
+    async fn delete_message(&self, user: &str, id: &str) -> Result<()> {
+        let user_dir = user_path(&self.root_for(user), user)?;
+        let path = message_path(&user_dir, id)?;
+        let size = fs::metadata(&path).await?.len();
+        fs::remove_file(&path).await?;
+        account_async(&user_dir, -(size as i64), -1).await;
+        let filename = id.rsplit('/').next().unwrap_or(id);
+        self.unindex(user, &id_folder(id), filename).await;
+        Ok(())
+    }
+
+    async fn lock_user(&self, user: &str) -> Result<()> {
+        lock_mailbox(&user_path(&self.root_for(user), user)?).await
+    }
+
+    async fn unlock_user(&self, user: &str) -> Result<()> {
+        unlock_mailbox(&user_path(&self.root_for(user), user)?).await
+    }
+
+    fn is_user_locked(&self, user: &str) -> bool {
+        self.is_locked(user)
+    }
+}
+
+/// Check whether `user`'s mailbox below `base_path` is locked for cutover
+pub fn is_mailbox_locked(base_path: &Path, user: &str) -> bool {
+    user_path(base_path, user)
+        .map(|dir| dir.join(MIGRATION_LOCK_FILE).exists())
+        .unwrap_or(false)
+}
+
+/// Mailbox directory of a user, rejecting names that could escape `base_path`
+pub(crate) fn user_path(base_path: &Path, user: &str) -> Result<PathBuf> {
+    if user.is_empty() || user.starts_with('.') || user.contains(['/', '\\', '\0']) {
+        return Err(MailError::Storage(format!("Invalid mailbox name: {:?}", user)));
+    }
+    Ok(base_path.join(user))
+}
+
+/// IMAP mailbox name of a message id
+fn id_folder(id: &str) -> String {
+    match id.split('/').collect::<Vec<_>>().as_slice() {
+        [folder, _, _] => folder.trim_start_matches('.').to_string(),
+        _ => "INBOX".to_string(),
+    }
+}
+
+/// Record a change in the mailbox's `maildirsize` file; a failure only
+/// skews usage until the file is next recalculated, so it doesn't fail
+/// the write
+pub(crate) fn account(user_dir: &Path, bytes: i64, messages: i64) {
+    if let Err(e) = maildirsize::record(user_dir, bytes, messages) {
+        warn!("Failed to update maildirsize of {}: {}", user_dir.display(), e);
+    }
+}
+
+/// [`account`] for async callers, off the runtime's worker threads
+pub(crate) async fn account_async(user_dir: &Path, bytes: i64, messages: i64) {
+    let dir = user_dir.to_path_buf();
+    if let Err(e) = tokio::task::spawn_blocking(move || account(&dir, bytes, messages)).await {
+        warn!("Failed to update maildirsize of {}: {}", user_dir.display(), e);
+    }
+}
+
+/// Path of a message id (`[.Folder/]new|cur/filename`) inside a mailbox
+pub(crate) fn message_path(user_dir: &Path, id: &str) -> Result<PathBuf> {
+    let invalid = || MailError::Storage(format!("Invalid message id: {:?}", id));
+
+    let parts: Vec<&str> = id.split('/').collect();
+    let (folder, subdir, filename) = match parts.as_slice() {
+        [subdir, filename] => (None, *subdir, *filename),
+        [folder, subdir, filename] => (Some(*folder), *subdir, *filename),
+        _ => return Err(invalid()),
+    };
+
+    if folder.is_some_and(|f| f.len() < 2 || !f.starts_with('.') || f.starts_with(".."))
+        || !matches!(subdir, "new" | "cur")
+        || filename.is_empty()
+        || filename.starts_with('.')
+        || filename.contains(['\\', '\0'])
+    {
+        return Err(invalid());
+    }
+
+    Ok(user_dir.join(id))
+}
+
+/// All message ids of a mailbox (INBOX and `.Folder` subfolders), sorted
+pub(crate) async fn list_message_ids(user_dir: &Path) -> Result<Vec<String>> {
+    let mut folders = vec![String::new()];
+    if let Ok(mut entries) = fs::read_dir(user_dir).await {
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') && name != MIGRATION_LOCK_FILE && entry.file_type().await?.is_dir() {
+                folders.push(format!("{}/", name));
+            }
+        }
+    }
+
+    let mut ids = Vec::new();
+    for folder in folders {
+        for subdir in ["new", "cur"] {
+            let Ok(mut entries) = fs::read_dir(user_dir.join(format!("{}{}", folder, subdir))).await
+            else {
+                continue;
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with('.') && entry.file_type().await?.is_file() {
+                    ids.push(format!("{}{}/{}", folder, subdir, name));
+                }
+            }
+        }
+    }
+
+    ids.sort();
+    Ok(ids)
+}
+
+/// Mailbox directories below `base_path`, sorted
+pub(crate) async fn list_user_dirs(base_path: &Path) -> Result<Vec<String>> {
+    let mut users = Vec::new();
+    let Ok(mut entries) = fs::read_dir(base_path).await else {
+        return Ok(users);
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') && entry.file_type().await?.is_dir() {
+            users.push(name);
+        }
+    }
+
+    users.sort();
+    Ok(users)
+}
+
+/// Write `data` to `path` through the mailbox's tmp/ directory
+pub(crate) async fn write_atomic(user_dir: &Path, path: &Path, data: &[u8]) -> Result<()> {
+    if chaos::inject(Fault::StorageWrite).await {
+        return Err(MailError::Storage(format!(
+            "Injected write failure for {}",
+            path.display()
+        )));
+    }
+
+    let tmp_dir = user_dir.join("tmp");
+    fs::create_dir_all(&tmp_dir).await?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let tmp_path = tmp_dir.join(format!("migrate.{}", uuid::Uuid::new_v4().simple()));
+    fs::write(&tmp_path, data).await?;
+    fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+pub(crate) async fn lock_mailbox(user_dir: &Path) -> Result<()> {
+    fs::create_dir_all(user_dir).await?;
+    fs::write(
+        user_dir.join(MIGRATION_LOCK_FILE),
+        chrono::Utc::now().to_rfc3339(),
+    )
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn unlock_mailbox(user_dir: &Path) -> Result<()> {
+    match fs::remove_file(user_dir.join(MIGRATION_LOCK_FILE)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_path_validation() {
+        let dir = Path::new("/var/mail/alice@example.com");
+
+        assert!(message_path(dir, "new/1.2.host").is_ok());
+        assert!(message_path(dir, "cur/1.2.host:2,S").is_ok());
+        assert!(message_path(dir, ".Sent/cur/1.2.host").is_ok());
+
+        for id in ["tmp/1", "../bob/new/1", ".Sent/../new/1", "..x/new/1", "new/..", "new/", "a/b/c/d", "./new/1"] {
+            assert!(message_path(dir, id).is_err(), "{} should be rejected", id);
+        }
+        assert!(user_path(Path::new("/var/mail"), "../etc").is_err());
+        assert!(user_path(Path::new("/var/mail"), ".migration.lock").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_single_instance_deliveries_share_a_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage =
+            MaildirStorage::new(dir.path().to_string_lossy().to_string()).with_single_instance();
+        let data = b"Subject: list\r\n\r\nbody";
+
+        let alice = storage.store("alice@example.com", data).await.unwrap();
+        let bob = storage.store_in_folder("bob@example.com", Some("Lists"), data).await.unwrap();
+        let alice = std::fs::metadata(dir.path().join("alice@example.com/new").join(alice)).unwrap();
+        let bob = std::fs::metadata(dir.path().join("bob@example.com/.Lists/new").join(bob)).unwrap();
+        assert_eq!(alice.ino(), bob.ino());
+
+        // The shared copy isn't a mailbox
+        assert_eq!(storage.list_users().await.unwrap().len(), 2);
+        let report = storage.collect_single_instance_garbage().await.unwrap();
+        assert_eq!((report.blobs, report.links, report.removed), (1, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_usage_follows_stores_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = MaildirStorage::new(dir.path().to_string_lossy().to_string());
+        let user = "alice@example.com";
+        let measured = || crate::quota::mailbox_usage(&dir.path().join(user)).unwrap();
+
+        let id = Storage::store(&storage, user, &[b'a'; 100]).await.unwrap();
+        storage.store_in_folder(user, Some("Sent"), &[b'b'; 40]).await.unwrap();
+        storage.write_message(user, &id, &[b'c'; 10]).await.unwrap();
+        storage.write_message(user, ".Drafts/cur/1.1.host:2,D", &[b'd'; 5]).await.unwrap();
+        assert_eq!(storage.usage(user).await.unwrap(), MailboxUsage { bytes: 55, messages: 3 });
+
+        storage.delete_message(user, &id).await.unwrap();
+        assert_eq!(storage.usage(user).await.unwrap(), measured());
+
+        // Only the maildirsize file is read, not the folders
+        std::fs::write(dir.path().join(user).join("new/1.2.host"), [0u8; 1000]).unwrap();
+        assert_eq!(storage.usage(user).await.unwrap().bytes, 45);
+        assert_eq!(storage.usage("bob@example.com").await.unwrap(), MailboxUsage::default());
+        assert!(storage.usage("../etc").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_storage_trait_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = MaildirStorage::new(dir.path().to_string_lossy().to_string());
+        let user = "alice@example.com";
+
+        let id = Storage::store(&storage, user, b"Subject: one\r\n\r\nbody").await.unwrap();
+        assert!(id.starts_with("new/"));
+        storage
+            .write_message(user, ".Sent/cur/1.1.host:2,S", b"Subject: two\r\n\r\nbody")
+            .await
+            .unwrap();
+
+        assert_eq!(storage.list_users().await.unwrap(), vec![user.to_string()]);
+        let ids = storage.list_messages(user).await.unwrap();
+        assert_eq!(ids, vec![".Sent/cur/1.1.host:2,S".to_string(), id.clone()]);
+        assert_eq!(
+            storage.read_message(user, &id).await.unwrap(),
+            b"Subject: one\r\n\r\nbody"
+        );
+
+        storage.lock_user(user).await.unwrap();
+        assert!(storage.is_user_locked(user));
+        assert_eq!(storage.list_messages(user).await.unwrap().len(), 2);
+        assert!(MaildirStorage::store(&storage, user, b"x").await.is_err());
+        storage.unlock_user(user).await.unwrap();
+        assert!(!storage.is_user_locked(user));
+
+        storage.delete_message(user, &id).await.unwrap();
+        assert_eq!(storage.list_messages(user).await.unwrap().len(), 1);
+
+        let flags = vec!["\\Seen".to_string(), "\\Flagged".to_string()];
+        let filename = storage
+            .store_with_flags(user, Some("Lists"), &flags, b"x")
+            .await
+            .unwrap();
+        assert!(dir
+            .path()
+            .join(user)
+            .join(".Lists/cur")
+            .join(format!("{}:2,FS", filename))
+            .exists());
     }
 }
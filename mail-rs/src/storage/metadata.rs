@@ -0,0 +1,756 @@
+//! Message metadata index
+//!
+//! Keeps the envelope of every stored message (Message-ID, From, To,
+//! Subject, Date, size, flags and folder) in SQLite next to the Maildir, so
+//! listings, counts and header searches neither walk the filesystem nor
+//! read message files. [`MaildirStorage`] records what it stores, moves and
+//! deletes; [`MetadataIndex::follow`] applies every change published on the
+//! [`FlagEventBus`], which includes what the Maildir watcher sees IMAP and
+//! other processes do. [`MetadataIndex::reconcile`] brings a mailbox back in
+//! line with its files, e.g. after changes made while the server was down.
+
+use super::events::{FlagEvent, FlagEventBus};
+use super::{MaildirStorage, Storage};
+use crate::imap::uid::base_name;
+use crate::imap::Mailbox;
+use crate::mime::{decode_header, MimeEntity};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+const COLUMNS: &str =
+    "folder, message, filename, recent, message_id, sender, recipients, subject, date, size, flags";
+
+/// Indexed envelope of one message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessageMetadata {
+    /// IMAP mailbox name, e.g. `INBOX` or `Sent`
+    pub folder: String,
+    /// Maildir base name, the id returned on delivery
+    pub message: String,
+    /// Current file name, including the flags suffix
+    pub filename: String,
+    /// Still in `new/`
+    pub recent: bool,
+    pub message_id: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub subject: Option<String>,
+    /// Date header, or the time the file was written when it has none
+    pub date: DateTime<Utc>,
+    pub size: u64,
+    pub flags: Vec<String>,
+}
+
+impl MessageMetadata {
+    pub fn is_seen(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "\\Seen")
+    }
+}
+
+/// Message counts of one folder
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FolderCounts {
+    pub folder: String,
+    pub messages: usize,
+    pub unseen: usize,
+    pub recent: usize,
+    /// Total size of the messages in bytes
+    pub size: u64,
+}
+
+/// Changes made by [`MetadataIndex::reconcile`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// SQLite index of message metadata
+pub struct MetadataIndex {
+    db: SqlitePool,
+}
+
+impl MetadataIndex {
+    /// Create a new metadata index
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let index = Self::new(SqlitePool::connect(database_url).await?);
+        index.init_db().await?;
+        Ok(index)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_metadata (
+                user TEXT NOT NULL,
+                folder TEXT NOT NULL,
+                message TEXT NOT NULL,
+                filename TEXT NOT NULL,
+                recent INTEGER NOT NULL,
+                message_id TEXT,
+                sender TEXT,
+                recipients TEXT,
+                subject TEXT,
+                date TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                flags TEXT NOT NULL,
+                indexed_at TEXT NOT NULL,
+                PRIMARY KEY (user, folder, message)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_message_metadata_date \
+             ON message_metadata(user, folder, date)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        // Mailboxes reconciled at least once, whose rows can be trusted
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_metadata_users (
+                user TEXT PRIMARY KEY,
+                reconciled_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record the message file at `path`, in `new/` or `cur/` of `folder`
+    ///
+    /// The header is only read for messages new to the index; for known
+    /// ones the file name, flags and `new/` state are updated.
+    pub async fn record_file(&self, user: &str, folder: &str, path: &Path) -> Result<()> {
+        let Some(filename) = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+        else {
+            return Ok(());
+        };
+        let recent = path
+            .parent()
+            .and_then(Path::file_name)
+            .is_some_and(|dir| dir == "new");
+        let flags = if recent {
+            Vec::new()
+        } else {
+            Mailbox::parse_maildir_flags(&filename)
+        };
+        let folder = folder_name(folder);
+
+        let updated = sqlx::query(
+            "UPDATE message_metadata SET filename = ?, recent = ?, flags = ?, indexed_at = ? \
+             WHERE user = ? AND folder = ? AND message = ?",
+        )
+        .bind(&filename)
+        .bind(recent)
+        .bind(flags.join(" "))
+        .bind(Utc::now().to_rfc3339())
+        .bind(user)
+        .bind(&folder)
+        .bind(base_name(&filename))
+        .execute(&self.db)
+        .await?;
+        if updated.rows_affected() > 0 {
+            return Ok(());
+        }
+
+        let file = fs::metadata(path).await?;
+        let header = read_header(path).await?;
+        let entity = MimeEntity::parse(&header);
+        let decoded = |name: &str| entity.header_value(name).map(|v| decode_header(&v));
+        let date = entity
+            .header_value("date")
+            .and_then(|v| DateTime::parse_from_rfc2822(v.trim()).ok())
+            .map(|date| date.with_timezone(&Utc))
+            .or_else(|| file.modified().ok().map(DateTime::<Utc>::from))
+            .unwrap_or_else(Utc::now);
+
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO message_metadata
+                (user, folder, message, filename, recent, message_id, sender, recipients,
+                 subject, date, size, flags, indexed_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(user)
+        .bind(&folder)
+        .bind(base_name(&filename))
+        .bind(&filename)
+        .bind(recent)
+        .bind(
+            entity
+                .header_value("message-id")
+                .map(|v| v.trim().to_string()),
+        )
+        .bind(decoded("from"))
+        .bind(decoded("to"))
+        .bind(decoded("subject"))
+        .bind(date.to_rfc3339())
+        .bind(file.len() as i64)
+        .bind(flags.join(" "))
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Forget a message; `message` may carry its flags suffix
+    pub async fn remove(&self, user: &str, folder: &str, message: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "DELETE FROM message_metadata WHERE user = ? AND folder = ? AND message = ?",
+        )
+        .bind(user)
+        .bind(folder_name(folder))
+        .bind(base_name(message))
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Apply a published change to the mailbox of `event.user` below
+    /// `maildir_root`
+    pub async fn apply(&self, event: &FlagEvent, maildir_root: &Path) -> Result<()> {
+        if event.expunged {
+            self.remove(&event.user, &event.mailbox, &event.message)
+                .await?;
+            return Ok(());
+        }
+        match find_file(
+            &folder_path(maildir_root, &event.user, &event.mailbox),
+            &event.message,
+        )
+        .await
+        {
+            Some(path) => self.record_file(&event.user, &event.mailbox, &path).await,
+            // Gone again, its removal follows
+            None => Ok(()),
+        }
+    }
+
+    /// Apply every change published on `bus` from now on
+    pub fn follow(
+        self: &Arc<Self>,
+        bus: &FlagEventBus,
+        storage: Arc<MaildirStorage>,
+    ) -> JoinHandle<()> {
+        let index = self.clone();
+        let mut events = bus.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let root = storage.root_for(&event.user);
+                        if let Err(e) = index.apply(&event, &root).await {
+                            warn!(
+                                "Failed to index {}/{}/{}: {}",
+                                event.user, event.mailbox, event.message, e
+                            );
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "Metadata index skipped {} changes, reconciling mailboxes",
+                            skipped
+                        );
+                        index.reconcile_storage(&storage).await;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Bring the rows of `user` in line with the files of the mailbox in
+    /// `user_dir`, reading only the headers of messages not yet indexed
+    pub async fn reconcile(&self, user: &str, user_dir: &Path) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        let root = user_dir.parent().unwrap_or(user_dir);
+        let folders = Mailbox::list_mailboxes(user, root)?;
+
+        for folder in &folders {
+            let mut indexed: HashMap<String, String> = sqlx::query(
+                "SELECT message, filename FROM message_metadata WHERE user = ? AND folder = ?",
+            )
+            .bind(user)
+            .bind(folder)
+            .fetch_all(&self.db)
+            .await?
+            .iter()
+            .map(|row| (row.get("message"), row.get("filename")))
+            .collect();
+
+            let dir = folder_path(root, user, folder);
+            for subdir in ["new", "cur"] {
+                let Ok(mut entries) = fs::read_dir(dir.join(subdir)).await else {
+                    continue;
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if name.starts_with('.') {
+                        continue;
+                    }
+                    match indexed.remove(base_name(&name)) {
+                        Some(filename) if filename == name => continue,
+                        Some(_) => report.updated += 1,
+                        None => report.added += 1,
+                    }
+                    if let Err(e) = self.record_file(user, folder, &entry.path()).await {
+                        warn!("Failed to index {}: {}", entry.path().display(), e);
+                    }
+                }
+            }
+            for message in indexed.keys() {
+                self.remove(user, folder, message).await?;
+                report.removed += 1;
+            }
+        }
+
+        // Folders deleted meanwhile
+        let stale: Vec<String> =
+            sqlx::query("SELECT DISTINCT folder FROM message_metadata WHERE user = ?")
+                .bind(user)
+                .fetch_all(&self.db)
+                .await?
+                .iter()
+                .map(|row| row.get("folder"))
+                .filter(|folder| !folders.contains(folder))
+                .collect();
+        for folder in stale {
+            let result = sqlx::query("DELETE FROM message_metadata WHERE user = ? AND folder = ?")
+                .bind(user)
+                .bind(&folder)
+                .execute(&self.db)
+                .await?;
+            report.removed += result.rows_affected() as usize;
+        }
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO message_metadata_users (user, reconciled_at) VALUES (?, ?)",
+        )
+        .bind(user)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+        Ok(report)
+    }
+
+    /// Reconcile every mailbox of `storage`
+    pub async fn reconcile_storage(&self, storage: &MaildirStorage) {
+        let users = match storage.list_users().await {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Failed to list mailboxes to index: {}", e);
+                return;
+            }
+        };
+        for user in users {
+            let user_dir = storage.root_for(&user).join(&user);
+            match self.reconcile(&user, &user_dir).await {
+                Ok(report) if report != ReconcileReport::default() => info!(
+                    "Indexed mailbox of {}: {} added, {} updated, {} removed",
+                    user, report.added, report.updated, report.removed
+                ),
+                Ok(_) => debug!("Index of {} is up to date", user),
+                Err(e) => warn!("Failed to index mailbox of {}: {}", user, e),
+            }
+        }
+    }
+
+    /// Whether the mailbox of `user` was reconciled, so its rows are
+    /// complete
+    pub async fn is_indexed(&self, user: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM message_metadata_users WHERE user = ?")
+            .bind(user)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Messages of a folder, newest first
+    pub async fn list(
+        &self,
+        user: &str,
+        folder: &str,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<MessageMetadata>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {COLUMNS} FROM message_metadata WHERE user = ? AND folder = ? \
+             ORDER BY date DESC, message DESC LIMIT ? OFFSET ?"
+        ))
+        .bind(user)
+        .bind(folder_name(folder))
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.iter().map(row_to_metadata).collect())
+    }
+
+    /// Messages of a folder still in `new/`, newest first
+    pub async fn list_recent(
+        &self,
+        user: &str,
+        folder: &str,
+        limit: usize,
+    ) -> Result<Vec<MessageMetadata>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {COLUMNS} FROM message_metadata WHERE user = ? AND folder = ? AND recent = 1 \
+             ORDER BY date DESC, message DESC LIMIT ?"
+        ))
+        .bind(user)
+        .bind(folder_name(folder))
+        .bind(limit as i64)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.iter().map(row_to_metadata).collect())
+    }
+
+    /// Every message of a folder, keyed by base name
+    pub async fn folder_messages(
+        &self,
+        user: &str,
+        folder: &str,
+    ) -> Result<HashMap<String, MessageMetadata>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {COLUMNS} FROM message_metadata WHERE user = ? AND folder = ?"
+        ))
+        .bind(user)
+        .bind(folder_name(folder))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(row_to_metadata)
+            .map(|metadata| (metadata.message.clone(), metadata))
+            .collect())
+    }
+
+    /// Messages whose From, To, Subject or Message-ID contains `query`,
+    /// case-insensitively, newest first
+    pub async fn search(
+        &self,
+        user: &str,
+        folder: Option<&str>,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<MessageMetadata>> {
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let rows = sqlx::query(&format!(
+            "SELECT {COLUMNS} FROM message_metadata WHERE user = ? AND (? IS NULL OR folder = ?) \
+             AND (sender LIKE ? ESCAPE '\\' OR recipients LIKE ? ESCAPE '\\' \
+                  OR subject LIKE ? ESCAPE '\\' OR message_id LIKE ? ESCAPE '\\') \
+             ORDER BY date DESC, message DESC LIMIT ?"
+        ))
+        .bind(user)
+        .bind(folder.map(folder_name))
+        .bind(folder.map(folder_name))
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(limit as i64)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.iter().map(row_to_metadata).collect())
+    }
+
+    /// Counts of every folder of `user` holding messages
+    pub async fn counts(&self, user: &str) -> Result<Vec<FolderCounts>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT folder, COUNT(*) AS messages,
+                   SUM(CASE WHEN (' ' || flags || ' ') LIKE '% \Seen %' THEN 0 ELSE 1 END) AS unseen,
+                   SUM(recent) AS recent, SUM(size) AS size
+            FROM message_metadata WHERE user = ?
+            GROUP BY folder ORDER BY folder
+            "#,
+        )
+        .bind(user)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| FolderCounts {
+                folder: row.get("folder"),
+                messages: row.get::<i64, _>("messages") as usize,
+                unseen: row.get::<i64, _>("unseen") as usize,
+                recent: row.get::<i64, _>("recent") as usize,
+                size: row.get::<i64, _>("size") as u64,
+            })
+            .collect())
+    }
+
+    /// Counts of one folder, zero when it holds no messages
+    pub async fn folder_counts(&self, user: &str, folder: &str) -> Result<FolderCounts> {
+        let folder = folder_name(folder);
+        Ok(self
+            .counts(user)
+            .await?
+            .into_iter()
+            .find(|counts| counts.folder == folder)
+            .unwrap_or(FolderCounts {
+                folder,
+                ..Default::default()
+            }))
+    }
+}
+
+/// INBOX is case-insensitive, other mailbox names are not
+fn folder_name(folder: &str) -> String {
+    if folder.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        folder.to_string()
+    }
+}
+
+/// Maildir folder of an IMAP mailbox name
+pub(crate) fn folder_path(maildir_root: &Path, user: &str, folder: &str) -> PathBuf {
+    let user_dir = maildir_root.join(user);
+    if folder.eq_ignore_ascii_case("INBOX") {
+        user_dir
+    } else {
+        user_dir.join(format!(".{}", folder))
+    }
+}
+
+/// Current file of message `base` in `new/` or `cur/` of `folder`
+pub(crate) async fn find_file(folder: &Path, base: &str) -> Option<PathBuf> {
+    for subdir in ["new", "cur"] {
+        let Ok(mut entries) = fs::read_dir(folder.join(subdir)).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if base_name(&entry.file_name().to_string_lossy()) == base {
+                return Some(entry.path());
+            }
+        }
+    }
+    None
+}
+
+/// Header block of a message file, up to and including the empty line
+async fn read_header(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(fs::File::open(path).await?);
+    let mut header = Vec::new();
+    loop {
+        let start = header.len();
+        if reader.read_until(b'\n', &mut header).await? == 0 {
+            break;
+        }
+        if matches!(&header[start..], b"\r\n" | b"\n") {
+            break;
+        }
+    }
+    Ok(header)
+}
+
+fn row_to_metadata(row: &SqliteRow) -> MessageMetadata {
+    let flags: String = row.get("flags");
+    let date: String = row.get("date");
+    MessageMetadata {
+        folder: row.get("folder"),
+        message: row.get("message"),
+        filename: row.get("filename"),
+        recent: row.get("recent"),
+        message_id: row.get("message_id"),
+        from: row.get("sender"),
+        to: row.get("recipients"),
+        subject: row.get("subject"),
+        date: DateTime::parse_from_rfc3339(&date)
+            .map(|date| date.with_timezone(&Utc))
+            .unwrap_or_default(),
+        size: row.get::<i64, _>("size") as u64,
+        flags: flags.split_whitespace().map(str::to_string).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FlagSource;
+    use tempfile::TempDir;
+
+    const USER: &str = "alice@example.com";
+
+    async fn setup() -> (TempDir, MetadataIndex) {
+        let dir = TempDir::new().unwrap();
+        let index = MetadataIndex::connect("sqlite::memory:").await.unwrap();
+        (dir, index)
+    }
+
+    fn deliver(root: &Path, folder: &str, subdir: &str, name: &str, subject: &str) -> PathBuf {
+        let dir = folder_path(root, USER, folder);
+        for sub in ["tmp", "new", "cur"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let path = dir.join(subdir).join(name);
+        std::fs::write(
+            &path,
+            format!(
+                "Message-ID: <{name}@example.com>\r\nFrom: Bob <bob@example.com>\r\n\
+                 To: {USER}\r\nSubject: {subject}\r\n\
+                 Date: Tue, 1 Oct 2024 10:00:00 +0200\r\n\r\nBody\r\n"
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_record_and_count() {
+        let (dir, index) = setup().await;
+        let path = deliver(
+            dir.path(),
+            "INBOX",
+            "new",
+            "1.a.host",
+            "=?UTF-8?Q?Caf=C3=A9?=",
+        );
+        index.record_file(USER, "inbox", &path).await.unwrap();
+        let read = deliver(dir.path(), "Sent", "cur", "2.a.host:2,S", "Re: Plans");
+        index.record_file(USER, "Sent", &read).await.unwrap();
+
+        let inbox = index.list(USER, "INBOX", 10, 0).await.unwrap();
+        assert_eq!(inbox.len(), 1);
+        let message = &inbox[0];
+        assert_eq!(message.message, "1.a.host");
+        assert_eq!(message.subject.as_deref(), Some("Café"));
+        assert_eq!(message.from.as_deref(), Some("Bob <bob@example.com>"));
+        assert_eq!(
+            message.message_id.as_deref(),
+            Some("<1.a.host@example.com>")
+        );
+        assert_eq!(message.date.to_rfc3339(), "2024-10-01T08:00:00+00:00");
+        assert!(message.recent && !message.is_seen());
+
+        let counts = index.counts(USER).await.unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(
+            (counts[0].messages, counts[0].unseen, counts[0].recent),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            (counts[1].messages, counts[1].unseen, counts[1].recent),
+            (1, 0, 0)
+        );
+        assert_eq!(
+            index.folder_counts(USER, "Drafts").await.unwrap().messages,
+            0
+        );
+
+        let found = index.search(USER, None, "plans", 10).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].folder, "Sent");
+        assert!(index
+            .search(USER, Some("INBOX"), "plans", 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(index
+            .search(USER, None, "100%", 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_apply_events() {
+        let (dir, index) = setup().await;
+        let path = deliver(dir.path(), "INBOX", "new", "1.a.host", "Hello");
+        let event = FlagEvent::flags(USER, "INBOX", "1.a.host", &[], FlagSource::Maildir);
+        index.apply(&event, dir.path()).await.unwrap();
+
+        // Marked read: moved to cur/ with the flag in its name
+        let read = path
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("cur/1.a.host:2,S");
+        std::fs::rename(&path, &read).unwrap();
+        let flags = vec!["\\Seen".to_string()];
+        let event = FlagEvent::flags(USER, "INBOX", "1.a.host", &flags, FlagSource::Imap);
+        index.apply(&event, dir.path()).await.unwrap();
+        let message = &index.list(USER, "INBOX", 10, 0).await.unwrap()[0];
+        assert_eq!(message.filename, "1.a.host:2,S");
+        assert!(message.is_seen() && !message.recent);
+        assert_eq!(message.subject.as_deref(), Some("Hello"));
+
+        let event = FlagEvent::expunged(USER, "INBOX", "1.a.host:2,S", FlagSource::Imap);
+        index.apply(&event, dir.path()).await.unwrap();
+        assert!(index.list(USER, "INBOX", 10, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let (dir, index) = setup().await;
+        let kept = deliver(dir.path(), "INBOX", "new", "1.a.host", "Kept");
+        let gone = deliver(dir.path(), "Archive", "cur", "2.a.host:2,S", "Gone");
+        let user_dir = dir.path().join(USER);
+        assert!(!index.is_indexed(USER).await.unwrap());
+
+        let report = index.reconcile(USER, &user_dir).await.unwrap();
+        assert_eq!(report.added, 2);
+        assert!(index.is_indexed(USER).await.unwrap());
+
+        std::fs::remove_file(&gone).unwrap();
+        std::fs::rename(&kept, user_dir.join("cur/1.a.host:2,F")).unwrap();
+        deliver(dir.path(), "INBOX", "new", "3.a.host", "New");
+        let report = index.reconcile(USER, &user_dir).await.unwrap();
+        assert_eq!(
+            report,
+            ReconcileReport {
+                added: 1,
+                updated: 1,
+                removed: 1
+            }
+        );
+        let inbox = index.folder_messages(USER, "INBOX").await.unwrap();
+        assert_eq!(inbox["1.a.host"].flags, vec!["\\Flagged".to_string()]);
+        assert!(inbox.contains_key("3.a.host"));
+
+        // A removed folder takes its rows with it
+        std::fs::remove_dir_all(user_dir.join(".Archive")).unwrap();
+        deliver(dir.path(), "Archive", "cur", "4.a.host:2,S", "Again");
+        index.reconcile(USER, &user_dir).await.unwrap();
+        std::fs::remove_dir_all(user_dir.join(".Archive")).unwrap();
+        let report = index.reconcile(USER, &user_dir).await.unwrap();
+        assert_eq!(report.removed, 1);
+        assert_eq!(index.counts(USER).await.unwrap().len(), 1);
+    }
+}
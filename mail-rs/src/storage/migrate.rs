@@ -0,0 +1,313 @@
+//! Moving users between storage backends
+//!
+//! A migration runs in phases:
+//! 1. **Copy**: messages missing from the destination are streamed over
+//!    while the user stays online. Message ids are kept, so an interrupted
+//!    run resumes where it stopped.
+//! 2. **Cutover** (optional): the source mailbox is locked, which closes
+//!    the user's IMAP sessions and defers deliveries, then a final copy
+//!    picks up messages that arrived during the first phase.
+//! 3. **Verify**: both sides must hold the same message ids with identical
+//!    SHA-256 digests. Only then is the source optionally removed.
+
+use super::Storage;
+use crate::error::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Log progress every this many copied messages
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// Migration settings
+#[derive(Debug, Clone)]
+pub struct MigrationOptions {
+    /// Copy at most this many messages per second (None = unthrottled)
+    pub max_messages_per_sec: Option<u32>,
+    /// Lock the source and run a final sync before verifying
+    pub cutover: bool,
+    /// Time given to IMAP sessions to notice the lock before the final sync
+    pub cutover_grace: Duration,
+    /// Keep the source locked after a successful cutover, until the server
+    /// is switched to the destination backend
+    pub keep_locked: bool,
+    /// Delete the source messages once verification succeeded
+    pub remove_source: bool,
+}
+
+impl Default for MigrationOptions {
+    fn default() -> Self {
+        Self {
+            max_messages_per_sec: None,
+            cutover: false,
+            cutover_grace: Duration::from_secs(5),
+            keep_locked: false,
+            remove_source: false,
+        }
+    }
+}
+
+/// Outcome of migrating one user
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrationReport {
+    pub user: String,
+    /// Messages copied by this run
+    pub copied: usize,
+    /// Messages already present in the destination (resumed run)
+    pub skipped: usize,
+    pub bytes_copied: u64,
+    pub source_count: usize,
+    pub destination_count: usize,
+    /// SHA-256 over the sorted (id, message digest) pairs of the source
+    pub digest: String,
+    /// Ids missing from the destination or with different content
+    pub mismatches: Vec<String>,
+    pub verified: bool,
+    pub cutover: bool,
+    pub source_removed: bool,
+}
+
+/// Streams users' mailboxes from one backend to another
+pub struct StorageMigrator<S, D> {
+    source: Arc<S>,
+    destination: Arc<D>,
+    options: MigrationOptions,
+}
+
+impl<S: Storage, D: Storage> StorageMigrator<S, D> {
+    pub fn new(source: Arc<S>, destination: Arc<D>, options: MigrationOptions) -> Self {
+        Self {
+            source,
+            destination,
+            options,
+        }
+    }
+
+    /// Migrate every user of the source backend
+    pub async fn migrate_all(&self) -> Result<Vec<MigrationReport>> {
+        let mut reports = Vec::new();
+        for user in self.source.list_users().await? {
+            reports.push(self.migrate_user(&user).await?);
+        }
+        Ok(reports)
+    }
+
+    /// Migrate one user: copy, optional cutover, verify
+    pub async fn migrate_user(&self, user: &str) -> Result<MigrationReport> {
+        let mut report = MigrationReport {
+            user: user.to_string(),
+            ..Default::default()
+        };
+
+        info!("Migrating mailbox {}", user);
+        report.skipped = self.sync(user, &mut report).await?;
+
+        if self.options.cutover {
+            info!("Cutover for {}: locking source mailbox", user);
+            self.source.lock_user(user).await?;
+            report.cutover = true;
+
+            let result = self.cutover(user, &mut report).await;
+            if result.is_err() || !report.verified || !self.options.keep_locked {
+                self.source.unlock_user(user).await?;
+            }
+            result?;
+        } else {
+            self.verify(user, &mut report).await?;
+        }
+
+        if report.verified && self.options.remove_source {
+            for id in self.source.list_messages(user).await? {
+                self.source.delete_message(user, &id).await?;
+            }
+            report.source_removed = true;
+        }
+
+        if report.verified {
+            info!(
+                "Migrated {}: {} copied, {} already present, {} messages verified",
+                user, report.copied, report.skipped, report.source_count
+            );
+        } else {
+            warn!(
+                "Verification failed for {}: {} mismatching messages",
+                user,
+                report.mismatches.len()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Final sync and verification while the source is locked
+    async fn cutover(&self, user: &str, report: &mut MigrationReport) -> Result<()> {
+        tokio::time::sleep(self.options.cutover_grace).await;
+        self.sync(user, report).await?;
+        self.verify(user, report).await
+    }
+
+    /// Copy messages missing from the destination, returning how many
+    /// were already there
+    async fn sync(&self, user: &str, report: &mut MigrationReport) -> Result<usize> {
+        let existing: HashSet<String> = self
+            .destination
+            .list_messages(user)
+            .await?
+            .into_iter()
+            .collect();
+
+        let delay = self
+            .options
+            .max_messages_per_sec
+            .filter(|rate| *rate > 0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate as f64));
+
+        let mut skipped = 0;
+        for id in self.source.list_messages(user).await? {
+            if existing.contains(&id) {
+                skipped += 1;
+                continue;
+            }
+
+            let data = self.source.read_message(user, &id).await?;
+            self.destination.write_message(user, &id, &data).await?;
+            report.copied += 1;
+            report.bytes_copied += data.len() as u64;
+
+            if report.copied.is_multiple_of(PROGRESS_INTERVAL) {
+                info!("{}: {} messages copied", user, report.copied);
+            }
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        Ok(skipped)
+    }
+
+    /// Compare message ids and content digests of both sides
+    async fn verify(&self, user: &str, report: &mut MigrationReport) -> Result<()> {
+        let source = self.digests(&*self.source, user).await?;
+        let destination = self.digests(&*self.destination, user).await?;
+
+        report.source_count = source.len();
+        report.destination_count = destination.len();
+        report.mismatches = source
+            .iter()
+            .filter(|(id, digest)| destination.get(*id) != Some(digest))
+            .map(|(id, _)| id.clone())
+            .collect();
+        report.verified = report.mismatches.is_empty() && source.len() == destination.len();
+
+        let mut overall = Sha256::new();
+        for (id, digest) in &source {
+            overall.update(id.as_bytes());
+            overall.update(b":");
+            overall.update(digest.as_bytes());
+            overall.update(b"\n");
+        }
+        report.digest = hex(&overall.finalize());
+
+        Ok(())
+    }
+
+    async fn digests<T: Storage>(&self, storage: &T, user: &str) -> Result<BTreeMap<String, String>> {
+        let mut digests = BTreeMap::new();
+        for id in storage.list_messages(user).await? {
+            let data = storage.read_message(user, &id).await?;
+            digests.insert(id, hex(&Sha256::digest(&data)));
+        }
+        Ok(digests)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{CompressedMaildirStorage, MaildirStorage};
+
+    fn backends() -> (tempfile::TempDir, Arc<MaildirStorage>, Arc<CompressedMaildirStorage>) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = MaildirStorage::new(dir.path().join("src").to_string_lossy().to_string());
+        let destination =
+            CompressedMaildirStorage::new(dir.path().join("dst").to_string_lossy().to_string());
+        (dir, Arc::new(source), Arc::new(destination))
+    }
+
+    #[tokio::test]
+    async fn test_migrate_and_resume() {
+        let (_dir, source, destination) = backends();
+        let user = "alice@example.com";
+        for (i, id) in ["new/1.a.host", "cur/2.b.host:2,S", ".Sent/cur/3.c.host:2,S"]
+            .iter()
+            .enumerate()
+        {
+            source
+                .write_message(user, id, format!("Subject: {}\r\n\r\nbody", i).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        // An interrupted run left one message behind
+        destination
+            .write_message(user, "new/1.a.host", b"Subject: 0\r\n\r\nbody")
+            .await
+            .unwrap();
+
+        let migrator = StorageMigrator::new(
+            source.clone(),
+            destination.clone(),
+            MigrationOptions {
+                cutover: true,
+                cutover_grace: Duration::ZERO,
+                remove_source: true,
+                ..Default::default()
+            },
+        );
+        let reports = migrator.migrate_all().await.unwrap();
+        let report = &reports[0];
+
+        assert_eq!(report.copied, 2);
+        assert_eq!(report.skipped, 1);
+        assert!(report.verified);
+        assert!(report.source_removed);
+        assert_eq!(report.source_count, 3);
+        assert_eq!(report.digest.len(), 64);
+        assert!(!source.is_user_locked(user));
+        assert!(source.list_messages(user).await.unwrap().is_empty());
+        assert_eq!(
+            destination.read_message(user, "cur/2.b.host:2,S").await.unwrap(),
+            b"Subject: 1\r\n\r\nbody"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verification_detects_mismatch() {
+        let (_dir, source, destination) = backends();
+        let user = "bob@example.com";
+        source.write_message(user, "new/1.a.host", b"original").await.unwrap();
+        destination.write_message(user, "new/1.a.host", b"tampered").await.unwrap();
+
+        let migrator = StorageMigrator::new(
+            source.clone(),
+            destination,
+            MigrationOptions {
+                remove_source: true,
+                ..Default::default()
+            },
+        );
+        let report = migrator.migrate_user(user).await.unwrap();
+
+        assert!(!report.verified);
+        assert_eq!(report.mismatches, vec!["new/1.a.host".to_string()]);
+        assert!(!report.source_removed);
+        assert_eq!(source.list_messages(user).await.unwrap().len(), 1);
+    }
+}
@@ -2,7 +2,72 @@
 //!
 //! Provides email storage backends:
 //! - [`maildir`]: Maildir format storage with atomic operations
+//! - [`compressed`]: Maildir layout with gzip-compressed message files
+//!
+//! Backends implement [`Storage`], which [`migrate`] uses to move users
+//! from one backend to another. [`jobs`] runs resumable maintenance tasks
+//! over stored messages in the background. [`events`] broadcasts flag
+//! changes to every frontend. [`metadata`] indexes message envelopes in
+//! SQLite for listings and counts. [`single_instance`] stores messages
+//! delivered to several mailboxes once.
 
+pub mod compressed;
+pub mod events;
+pub mod jobs;
 pub mod maildir;
+pub mod metadata;
+pub mod migrate;
+pub mod single_instance;
 
+pub use compressed::CompressedMaildirStorage;
+pub use events::{FlagEvent, FlagEventBus, FlagSource};
 pub use maildir::MaildirStorage;
+pub use metadata::{FolderCounts, MessageMetadata, MetadataIndex};
+pub use migrate::{MigrationOptions, MigrationReport, StorageMigrator};
+pub use single_instance::SisReport;
+
+use crate::error::Result;
+use std::future::Future;
+
+/// Message storage backend
+///
+/// Messages are addressed by an id relative to the user's mailbox that
+/// encodes folder and flags in Maildir form, e.g. `new/1700000000.1.host`
+/// or `.Sent/cur/1700000000.2.host:2,S`, so they survive a move between
+/// backends unchanged.
+pub trait Storage: Send + Sync {
+    /// Deliver a new message to the user's INBOX and return its id
+    fn store(&self, user: &str, data: &[u8]) -> impl Future<Output = Result<String>> + Send;
+
+    /// Users with a mailbox in this backend
+    fn list_users(&self) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// Ids of all messages of a user, in every folder, sorted
+    fn list_messages(&self, user: &str) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// Raw RFC 5322 content of a message
+    fn read_message(&self, user: &str, id: &str) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Write a message under the given id, replacing any existing one
+    fn write_message(
+        &self,
+        user: &str,
+        id: &str,
+        data: &[u8],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Delete a message
+    fn delete_message(&self, user: &str, id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Mark a mailbox as being cut over to another backend
+    ///
+    /// While locked, IMAP sessions of the user are closed and deliveries
+    /// are deferred with a temporary error.
+    fn lock_user(&self, user: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Release a cutover lock
+    fn unlock_user(&self, user: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Check whether a mailbox is locked for cutover
+    fn is_user_locked(&self, user: &str) -> bool;
+}
@@ -0,0 +1,191 @@
+//! Single-instance storage for messages delivered to several mailboxes
+//!
+//! Message files with identical content are hardlinks to one copy kept
+//! below `.sis/` in the maildir root, addressed by the SHA-256 of the
+//! content. Mailbox files stay complete messages, so IMAP and other Maildir
+//! tools read them as before; the link count of the shared copy is its
+//! reference count. Clients change flags by renaming and replace content
+//! through tmp/, so a shared copy is never modified in place.
+//!
+//! Deliveries then carry no per-recipient `Delivered-To` header, so a
+//! message to several local recipients is stored once; the SMTP session
+//! remembers those deliveries for loop detection instead (see
+//! [`crate::smtp::loop_detection::DeliveryLog`]).
+//!
+//! Expunges remove mailbox links wherever they happen; [`collect_garbage`]
+//! then removes shared copies only `.sis/` still links to. A new copy is
+//! linked from its mailbox before it appears in `.sis/`, so the collector
+//! never sees it unlinked.
+
+use crate::error::Result;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Directory below a maildir root holding the shared copies
+pub const SIS_DIR: &str = ".sis";
+
+/// Shared copies and what they save
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SisReport {
+    /// Shared copies still linked from a mailbox
+    pub blobs: usize,
+    /// Mailbox files linked to them
+    pub links: usize,
+    /// Bytes the extra links would take as separate files
+    pub saved_bytes: u64,
+    /// Shared copies removed because no mailbox links them anymore
+    pub removed: usize,
+}
+
+/// Path of the shared copy of content with `digest` below `root`
+fn blob_path(root: &Path, digest: &str) -> PathBuf {
+    root.join(SIS_DIR).join(&digest[..2]).join(digest)
+}
+
+fn content_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Create `target` as a link to the shared copy of `data` below `root`,
+/// storing the copy first if this content wasn't seen yet
+///
+/// Returns true if an existing copy was linked.
+pub(crate) async fn link(root: &Path, data: &[u8], target: &Path) -> Result<bool> {
+    let blob = blob_path(root, &content_digest(data));
+    match fs::hard_link(&blob, target).await {
+        Ok(()) => return Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let tmp_dir = root.join(SIS_DIR).join("tmp");
+    fs::create_dir_all(&tmp_dir).await?;
+    if let Some(parent) = blob.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = tmp_dir.join(uuid::Uuid::new_v4().simple().to_string());
+    fs::write(&tmp_path, data).await?;
+    // The mailbox link comes first, so the copy is never published with no
+    // other link for garbage collection to find
+    let linked = fs::hard_link(&tmp_path, target).await;
+    // Another delivery of the same content may have stored it meanwhile;
+    // this copy then stays a separate file
+    let published = match linked {
+        Ok(()) => fs::hard_link(&tmp_path, &blob).await,
+        Err(_) => Ok(()),
+    };
+    fs::remove_file(&tmp_path).await?;
+    linked?;
+    match published {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e.into()),
+        _ => Ok(false),
+    }
+}
+
+/// Remove shared copies below `root` that no mailbox links anymore and
+/// report on the rest
+pub async fn collect_garbage(root: &Path) -> Result<SisReport> {
+    let mut report = SisReport::default();
+    let Ok(mut prefixes) = fs::read_dir(root.join(SIS_DIR)).await else {
+        return Ok(report);
+    };
+
+    while let Some(prefix) = prefixes.next_entry().await? {
+        if prefix.file_name() == "tmp" || !prefix.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut blobs = fs::read_dir(prefix.path()).await?;
+        while let Some(blob) = blobs.next_entry().await? {
+            let metadata = blob.metadata().await?;
+            let links = metadata.nlink().saturating_sub(1);
+            if links == 0 {
+                match fs::remove_file(blob.path()).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => report.removed += 1,
+                }
+                continue;
+            }
+            report.blobs += 1;
+            report.links += links as usize;
+            report.saved_bytes += metadata.len() * (links - 1);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_identical_content_is_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("alice/new")).await.unwrap();
+        fs::create_dir_all(root.join("bob/new")).await.unwrap();
+        let data = b"Subject: list\r\n\r\nsame body";
+
+        assert!(!link(root, data, &root.join("alice/new/1")).await.unwrap());
+        assert!(link(root, data, &root.join("bob/new/1")).await.unwrap());
+        assert!(!link(root, b"other", &root.join("bob/new/2")).await.unwrap());
+
+        let alice = std::fs::metadata(root.join("alice/new/1")).unwrap();
+        let bob = std::fs::metadata(root.join("bob/new/1")).unwrap();
+        assert_eq!(alice.ino(), bob.ino());
+        assert_eq!(std::fs::read(root.join("bob/new/1")).unwrap(), data);
+
+        let report = collect_garbage(root).await.unwrap();
+        assert_eq!(
+            report,
+            SisReport {
+                blobs: 2,
+                links: 3,
+                saved_bytes: data.len() as u64,
+                removed: 0,
+            }
+        );
+
+        // Copies are released once every mailbox deleted its link
+        std::fs::remove_file(root.join("alice/new/1")).unwrap();
+        std::fs::remove_file(root.join("bob/new/1")).unwrap();
+        let report = collect_garbage(root).await.unwrap();
+        assert_eq!((report.blobs, report.removed), (1, 1));
+        assert!(!blob_path(root, &content_digest(data)).exists());
+
+        // Delivering the content again stores a new copy
+        assert!(!link(root, data, &root.join("alice/new/3")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_new_copies_survive_concurrent_collection() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir_all(root.join("alice/new")).await.unwrap();
+
+        let collector = {
+            let root = root.clone();
+            tokio::spawn(async move {
+                loop {
+                    collect_garbage(&root).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        for n in 0..200 {
+            let data = format!("Subject: {}\r\n\r\nbody", n);
+            let target = root.join("alice/new").join(n.to_string());
+            link(&root, data.as_bytes(), &target).await.unwrap();
+            assert!(link(&root, data.as_bytes(), &root.join("alice/new").join(format!("{}b", n)))
+                .await
+                .unwrap());
+            assert_eq!(std::fs::read(&target).unwrap(), data.as_bytes());
+        }
+        collector.abort();
+    }
+}
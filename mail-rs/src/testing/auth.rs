@@ -0,0 +1,163 @@
+//! In-memory user accounts
+
+use crate::error::{MailError, Result};
+use crate::security::sasl::{self, ScramCredentials};
+use crate::security::AuthBackend;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+struct Account {
+    password: String,
+    scram: ScramCredentials,
+}
+
+/// [`AuthBackend`] keeping accounts in memory
+///
+/// Passwords are kept in plain text, which is fine for tests but means this
+/// must never back a real server.
+#[derive(Default)]
+pub struct InMemoryAuthenticator {
+    accounts: Mutex<BTreeMap<String, Account>>,
+}
+
+impl InMemoryAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an authenticator with the given (email, password) accounts
+    pub fn with_users(users: &[(&str, &str)]) -> Self {
+        let authenticator = Self::new();
+        {
+            let mut accounts = authenticator.accounts.lock().unwrap();
+            for (email, password) in users {
+                accounts.insert(email.to_string(), Account::new(password));
+            }
+        }
+        authenticator
+    }
+}
+
+impl Account {
+    fn new(password: &str) -> Self {
+        Self {
+            password: password.to_string(),
+            scram: ScramCredentials::new(password),
+        }
+    }
+}
+
+impl AuthBackend for InMemoryAuthenticator {
+    async fn add_user(&self, email: &str, password: &str) -> Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(email) {
+            return Err(MailError::Storage(format!("User {} already exists", email)));
+        }
+        accounts.insert(email.to_string(), Account::new(password));
+        Ok(())
+    }
+
+    async fn delete_user(&self, email: &str) -> Result<()> {
+        self.accounts.lock().unwrap().remove(email);
+        Ok(())
+    }
+
+    async fn user_exists(&self, email: &str) -> Result<bool> {
+        Ok(self.accounts.lock().unwrap().contains_key(email))
+    }
+
+    async fn usernames(&self) -> Result<Vec<String>> {
+        Ok(self.accounts.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(username)
+            .is_some_and(|account| account.password == password))
+    }
+
+    async fn get_scram_credentials(&self, email: &str) -> Result<Option<ScramCredentials>> {
+        Ok(self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(email)
+            .map(|account| account.scram.clone()))
+    }
+
+    async fn authenticate_cram_md5(
+        &self,
+        username: &str,
+        challenge: &str,
+        digest_hex: &str,
+    ) -> Result<bool> {
+        Ok(self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(username)
+            .is_some_and(|account| {
+                sasl::verify_cram_md5(account.password.as_bytes(), challenge, digest_hex)
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::Authenticator;
+
+    /// Behaviour every [`AuthBackend`] implementation must share
+    async fn check_auth_contract<A: AuthBackend>(auth: &A) {
+        auth.add_user("bob@example.com", "secret").await.unwrap();
+        auth.add_user("alice@example.com", "hunter2").await.unwrap();
+        assert!(auth.add_user("bob@example.com", "again").await.is_err());
+
+        assert!(auth.user_exists("bob@example.com").await.unwrap());
+        assert!(!auth.user_exists("carol@example.com").await.unwrap());
+        assert_eq!(
+            auth.usernames().await.unwrap(),
+            vec!["alice@example.com".to_string(), "bob@example.com".to_string()]
+        );
+
+        assert!(auth.authenticate("bob@example.com", "secret").await.unwrap());
+        assert!(!auth.authenticate("bob@example.com", "wrong").await.unwrap());
+        assert!(!auth.authenticate("carol@example.com", "secret").await.unwrap());
+
+        let scram = auth.get_scram_credentials("bob@example.com").await.unwrap().unwrap();
+        assert_eq!(
+            scram.stored_key,
+            ScramCredentials::derive("secret", &scram.salt, scram.iterations).stored_key
+        );
+
+        let challenge = "<1896.697170952@postoffice.example.net>";
+        let digest = sasl::cram_md5_digest(b"secret", challenge);
+        assert!(auth.authenticate_cram_md5("bob@example.com", challenge, &digest).await.unwrap());
+        assert!(!auth.authenticate_cram_md5("bob@example.com", challenge, "00").await.unwrap());
+
+        auth.delete_user("bob@example.com").await.unwrap();
+        assert!(!auth.user_exists("bob@example.com").await.unwrap());
+        assert!(auth.get_scram_credentials("bob@example.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_matches_sqlite() {
+        check_auth_contract(&InMemoryAuthenticator::new()).await;
+        check_auth_contract(
+            &Authenticator::new("sqlite::memory:")
+                .await
+                .unwrap()
+                .with_cram_md5(true),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_with_users() {
+        let auth = InMemoryAuthenticator::with_users(&[("a@example.com", "pw")]);
+        assert!(auth.authenticate("a@example.com", "pw").await.unwrap());
+    }
+}
@@ -0,0 +1,16 @@
+//! Test doubles for integration tests and embedders
+//!
+//! Enabled with the `testing` feature. The fakes keep everything in memory,
+//! so tests can exercise code written against [`Storage`] and
+//! [`AuthBackend`] without a filesystem or SQLite:
+//! - [`InMemoryStorage`]: message storage
+//! - [`InMemoryAuthenticator`]: user accounts and credentials
+//!
+//! [`Storage`]: crate::storage::Storage
+//! [`AuthBackend`]: crate::security::AuthBackend
+
+pub mod auth;
+pub mod storage;
+
+pub use auth::InMemoryAuthenticator;
+pub use storage::InMemoryStorage;
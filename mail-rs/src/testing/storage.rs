@@ -0,0 +1,177 @@
+//! In-memory message storage
+
+use crate::error::{MailError, Result};
+use crate::storage::maildir::message_path;
+use crate::storage::Storage;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// [`Storage`] keeping every message in memory
+///
+/// Message ids follow the Maildir conventions of the real backends, so a
+/// mailbox can be migrated to or from disk-based storage unchanged.
+#[derive(Default)]
+pub struct InMemoryStorage {
+    /// user -> message id -> content
+    mailboxes: Mutex<HashMap<String, BTreeMap<String, Vec<u8>>>>,
+    locked: Mutex<HashSet<String>>,
+    next_id: AtomicU64,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of messages stored for a user
+    pub fn message_count(&self, user: &str) -> usize {
+        self.mailboxes
+            .lock()
+            .unwrap()
+            .get(user)
+            .map_or(0, BTreeMap::len)
+    }
+
+    fn check_id(id: &str) -> Result<()> {
+        // Same id rules as the Maildir backends
+        message_path(Path::new(""), id).map(|_| ())
+    }
+
+    fn not_found(user: &str, id: &str) -> MailError {
+        MailError::NotFound(format!("Message {} of {}", id, user))
+    }
+}
+
+impl Storage for InMemoryStorage {
+    async fn store(&self, user: &str, data: &[u8]) -> Result<String> {
+        if self.is_user_locked(user) {
+            return Err(MailError::Storage(format!(
+                "Mailbox {} is locked for migration",
+                user
+            )));
+        }
+
+        let id = format!("new/{}.memory", self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.write_message(user, &id, data).await?;
+        Ok(id)
+    }
+
+    async fn list_users(&self) -> Result<Vec<String>> {
+        let mut users: Vec<String> = self.mailboxes.lock().unwrap().keys().cloned().collect();
+        users.sort();
+        Ok(users)
+    }
+
+    async fn list_messages(&self, user: &str) -> Result<Vec<String>> {
+        Ok(self
+            .mailboxes
+            .lock()
+            .unwrap()
+            .get(user)
+            .map(|messages| messages.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn read_message(&self, user: &str, id: &str) -> Result<Vec<u8>> {
+        self.mailboxes
+            .lock()
+            .unwrap()
+            .get(user)
+            .and_then(|messages| messages.get(id).cloned())
+            .ok_or_else(|| Self::not_found(user, id))
+    }
+
+    async fn write_message(&self, user: &str, id: &str, data: &[u8]) -> Result<()> {
+        Self::check_id(id)?;
+        self.mailboxes
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_default()
+            .insert(id.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    async fn delete_message(&self, user: &str, id: &str) -> Result<()> {
+        self.mailboxes
+            .lock()
+            .unwrap()
+            .get_mut(user)
+            .and_then(|messages| messages.remove(id))
+            .map(|_| ())
+            .ok_or_else(|| Self::not_found(user, id))
+    }
+
+    async fn lock_user(&self, user: &str) -> Result<()> {
+        self.locked.lock().unwrap().insert(user.to_string());
+        Ok(())
+    }
+
+    async fn unlock_user(&self, user: &str) -> Result<()> {
+        self.locked.lock().unwrap().remove(user);
+        Ok(())
+    }
+
+    fn is_user_locked(&self, user: &str) -> bool {
+        self.locked.lock().unwrap().contains(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MaildirStorage, MigrationOptions, StorageMigrator};
+    use std::sync::Arc;
+
+    /// Behaviour every [`Storage`] implementation must share
+    async fn check_storage_contract<S: Storage>(storage: &S) {
+        let user = "alice@example.com";
+
+        let id = storage.store(user, b"first").await.unwrap();
+        assert!(id.starts_with("new/"));
+        storage.write_message(user, ".Sent/cur/9.9.host:2,S", b"sent").await.unwrap();
+        assert!(storage.write_message(user, "../escape", b"x").await.is_err());
+
+        assert_eq!(storage.list_users().await.unwrap(), vec![user.to_string()]);
+        assert_eq!(
+            storage.list_messages(user).await.unwrap(),
+            vec![".Sent/cur/9.9.host:2,S".to_string(), id.clone()]
+        );
+        assert_eq!(storage.read_message(user, &id).await.unwrap(), b"first");
+        assert!(storage.read_message(user, "new/missing").await.is_err());
+
+        storage.lock_user(user).await.unwrap();
+        assert!(storage.is_user_locked(user));
+        assert!(storage.store(user, b"deferred").await.is_err());
+        storage.unlock_user(user).await.unwrap();
+        assert!(!storage.is_user_locked(user));
+
+        storage.delete_message(user, &id).await.unwrap();
+        assert_eq!(storage.list_messages(user).await.unwrap().len(), 1);
+        assert!(storage.list_messages("nobody@example.com").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_matches_maildir() {
+        check_storage_contract(&InMemoryStorage::new()).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        check_storage_contract(&MaildirStorage::new(dir.path().to_string_lossy().to_string()))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_migrate_from_memory() {
+        let source = Arc::new(InMemoryStorage::new());
+        source.store("bob@example.com", b"hello").await.unwrap();
+        let destination = Arc::new(InMemoryStorage::new());
+
+        let migrator = StorageMigrator::new(source, destination.clone(), MigrationOptions::default());
+        let report = migrator.migrate_user("bob@example.com").await.unwrap();
+
+        assert!(report.verified);
+        assert_eq!(destination.message_count("bob@example.com"), 1);
+    }
+}
@@ -0,0 +1,399 @@
+//! TLS-RPT manager: outbound TLS session log and sent reports
+
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveTime, TimeZone, Utc};
+use sqlx::{Row, SqlitePool};
+
+use super::types::*;
+
+/// TLS-RPT manager
+pub struct TlsRptManager {
+    db: SqlitePool,
+}
+
+impl TlsRptManager {
+    /// Create a new TLS-RPT manager
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Connect to `database_url` and create the TLS-RPT tables
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let manager = Self::new(SqlitePool::connect(database_url).await?);
+        manager.init_db().await?;
+        Ok(manager)
+    }
+
+    /// Initialize database tables
+    pub async fn init_db(&self) -> Result<()> {
+        // result_type is NULL for successful sessions
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tls_sessions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                policy_domain TEXT NOT NULL,
+                day TEXT NOT NULL,
+                result_type TEXT,
+                mx_host TEXT NOT NULL,
+                receiving_ip TEXT,
+                info TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_tls_sessions_domain_day ON tls_sessions(policy_domain, day)",
+        )
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tls_reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                policy_domain TEXT NOT NULL,
+                day TEXT NOT NULL,
+                report_id TEXT NOT NULL,
+                rua TEXT,
+                sent_at TEXT NOT NULL,
+                UNIQUE(policy_domain, day)
+            )
+            "#,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a session that negotiated TLS
+    pub async fn record_success(&self, policy_domain: &str, mx_host: &str) -> Result<()> {
+        self.record(
+            policy_domain,
+            None,
+            mx_host,
+            None,
+            None,
+            Utc::now().date_naive(),
+        )
+        .await
+    }
+
+    /// Record a session that failed to negotiate TLS
+    pub async fn record_failure(&self, policy_domain: &str, failure: &TlsFailure) -> Result<()> {
+        self.record(
+            policy_domain,
+            Some(failure.result_type),
+            &failure.receiving_mx_hostname,
+            failure.receiving_ip.as_deref(),
+            failure.additional_information.as_deref(),
+            Utc::now().date_naive(),
+        )
+        .await
+    }
+
+    async fn record(
+        &self,
+        policy_domain: &str,
+        result_type: Option<TlsResultType>,
+        mx_host: &str,
+        receiving_ip: Option<&str>,
+        info: Option<&str>,
+        day: NaiveDate,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO tls_sessions (policy_domain, day, result_type, mx_host, receiving_ip, info, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(policy_domain.to_lowercase())
+        .bind(day.to_string())
+        .bind(result_type.map(|t| t.as_str()))
+        .bind(mx_host.to_lowercase())
+        .bind(receiving_ip)
+        .bind(info)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Domains and days with sessions before `before` that were not reported yet
+    pub async fn pending_reports(&self, before: NaiveDate) -> Result<Vec<PendingReport>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT s.policy_domain, s.day,
+                   SUM(CASE WHEN s.result_type IS NULL THEN 1 ELSE 0 END) AS successful,
+                   SUM(CASE WHEN s.result_type IS NULL THEN 0 ELSE 1 END) AS failed
+            FROM tls_sessions s
+            WHERE s.day < ?
+              AND NOT EXISTS (
+                  SELECT 1 FROM tls_reports r
+                  WHERE r.policy_domain = s.policy_domain AND r.day = s.day
+              )
+            GROUP BY s.policy_domain, s.day
+            ORDER BY s.day, s.policy_domain
+            "#,
+        )
+        .bind(before.to_string())
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let day: String = row.get("day");
+                Ok(PendingReport {
+                    policy_domain: row.get("policy_domain"),
+                    day: day.parse()?,
+                    successful_sessions: row.get("successful"),
+                    failed_sessions: row.get("failed"),
+                })
+            })
+            .collect()
+    }
+
+    /// Session counts and grouped failures for one domain and day
+    pub async fn policy_result(&self, policy_domain: &str, day: NaiveDate) -> Result<PolicyResult> {
+        let policy_domain = policy_domain.to_lowercase();
+        let rows = sqlx::query(
+            r#"
+            SELECT result_type, mx_host, receiving_ip, MAX(info) AS info, COUNT(*) AS n
+            FROM tls_sessions
+            WHERE policy_domain = ? AND day = ?
+            GROUP BY result_type, mx_host, receiving_ip
+            ORDER BY result_type, mx_host, receiving_ip
+            "#,
+        )
+        .bind(&policy_domain)
+        .bind(day.to_string())
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut summary = Summary {
+            total_successful_session_count: 0,
+            total_failure_session_count: 0,
+        };
+        let mut failure_details = Vec::new();
+        for row in &rows {
+            let count: i64 = row.get("n");
+            let result_type: Option<String> = row.get("result_type");
+            let Some(result_type) = result_type else {
+                summary.total_successful_session_count += count;
+                continue;
+            };
+
+            summary.total_failure_session_count += count;
+            failure_details.push(FailureDetails {
+                result_type: TlsResultType::parse(&result_type)
+                    .unwrap_or(TlsResultType::ValidationFailure),
+                receiving_mx_hostname: row.get("mx_host"),
+                receiving_ip: row.get("receiving_ip"),
+                failed_session_count: count,
+                additional_information: row.get("info"),
+            });
+        }
+
+        Ok(PolicyResult {
+            policy: PolicyDetails {
+                policy_type: "no-policy-found".to_string(),
+                policy_domain,
+            },
+            summary,
+            failure_details,
+        })
+    }
+
+    /// Build the RFC 8460 report covering one UTC day
+    pub async fn build_report(
+        &self,
+        policy_domain: &str,
+        day: NaiveDate,
+        organization_name: &str,
+        contact_info: &str,
+    ) -> Result<TlsReport> {
+        let start = Utc.from_utc_datetime(&day.and_time(NaiveTime::MIN));
+        let end = start + chrono::Duration::days(1) - chrono::Duration::seconds(1);
+
+        Ok(TlsReport {
+            organization_name: organization_name.to_string(),
+            date_range: DateRange {
+                start_datetime: start,
+                end_datetime: end,
+            },
+            contact_info: contact_info.to_string(),
+            report_id: format!(
+                "{}_{}_{}",
+                day,
+                policy_domain.to_lowercase(),
+                uuid::Uuid::new_v4()
+            ),
+            policies: vec![self.policy_result(policy_domain, day).await?],
+        })
+    }
+
+    /// Mark a day as reported (`rua` is None when the domain publishes no
+    /// TLS-RPT record, so the day is not retried)
+    pub async fn mark_sent(
+        &self,
+        policy_domain: &str,
+        day: NaiveDate,
+        report_id: &str,
+        rua: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO tls_reports (policy_domain, day, report_id, rua, sent_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(policy_domain.to_lowercase())
+        .bind(day.to_string())
+        .bind(report_id)
+        .bind(rua)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delete sessions and report records for days before `before`
+    pub async fn prune(&self, before: NaiveDate) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM tls_sessions WHERE day < ?")
+            .bind(before.to_string())
+            .execute(&self.db)
+            .await?;
+
+        sqlx::query("DELETE FROM tls_reports WHERE day < ?")
+            .bind(before.to_string())
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn manager() -> TlsRptManager {
+        TlsRptManager::connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn failure(result_type: TlsResultType, mx: &str) -> TlsFailure {
+        TlsFailure {
+            result_type,
+            receiving_mx_hostname: mx.to_string(),
+            receiving_ip: Some("192.0.2.1".to_string()),
+            additional_information: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_result_groups_failures() {
+        let manager = manager().await;
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        for _ in 0..3 {
+            manager
+                .record("Example.net", None, "mx1.example.net", None, None, day)
+                .await
+                .unwrap();
+        }
+        for _ in 0..2 {
+            let f = failure(TlsResultType::CertificateExpired, "mx2.example.net");
+            manager
+                .record(
+                    "example.net",
+                    Some(f.result_type),
+                    &f.receiving_mx_hostname,
+                    f.receiving_ip.as_deref(),
+                    None,
+                    day,
+                )
+                .await
+                .unwrap();
+        }
+        manager
+            .record(
+                "example.net",
+                Some(TlsResultType::StarttlsNotSupported),
+                "mx3.example.net",
+                None,
+                None,
+                day,
+            )
+            .await
+            .unwrap();
+
+        let result = manager.policy_result("example.net", day).await.unwrap();
+        assert_eq!(result.summary.total_successful_session_count, 3);
+        assert_eq!(result.summary.total_failure_session_count, 3);
+        assert_eq!(result.failure_details.len(), 2);
+        let expired = result
+            .failure_details
+            .iter()
+            .find(|d| d.result_type == TlsResultType::CertificateExpired)
+            .unwrap();
+        assert_eq!(expired.failed_session_count, 2);
+        assert_eq!(expired.receiving_ip.as_deref(), Some("192.0.2.1"));
+
+        let report = manager
+            .build_report("example.net", day, "Example", "postmaster@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            report.date_range.start_datetime.to_rfc3339(),
+            "2024-03-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            report.date_range.end_datetime.to_rfc3339(),
+            "2024-03-01T23:59:59+00:00"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pending_reports_excludes_sent_and_today() {
+        let manager = manager().await;
+        let today = Utc::now().date_naive();
+        let yesterday = today.pred_opt().unwrap();
+
+        manager
+            .record("a.example", None, "mx.a.example", None, None, yesterday)
+            .await
+            .unwrap();
+        manager
+            .record("b.example", None, "mx.b.example", None, None, yesterday)
+            .await
+            .unwrap();
+        manager
+            .record_failure(
+                "a.example",
+                &failure(TlsResultType::CertificateNotTrusted, "mx.a.example"),
+            )
+            .await
+            .unwrap();
+
+        let pending = manager.pending_reports(today).await.unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].policy_domain, "a.example");
+        assert_eq!(pending[0].successful_sessions, 1);
+        assert_eq!(pending[0].failed_sessions, 0);
+
+        manager
+            .mark_sent("a.example", yesterday, "r1", None)
+            .await
+            .unwrap();
+        let pending = manager.pending_reports(today).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].policy_domain, "b.example");
+
+        assert_eq!(manager.prune(today).await.unwrap(), 2);
+        assert!(manager.pending_reports(today).await.unwrap().is_empty());
+    }
+}
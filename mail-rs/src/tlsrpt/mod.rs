@@ -0,0 +1,14 @@
+//! SMTP TLS reporting (RFC 8460)
+//!
+//! The outbound SMTP client records, per recipient domain, whether each
+//! session negotiated TLS and why it did not. Once a UTC day is over, the
+//! sessions are aggregated into a JSON report that is gzipped and sent to
+//! the reporting addresses the domain publishes at `_smtp._tls.<domain>`.
+
+pub mod manager;
+pub mod sender;
+pub mod types;
+
+pub use manager::TlsRptManager;
+pub use sender::TlsReportSender;
+pub use types::*;
@@ -0,0 +1,351 @@
+//! Daily TLS report delivery
+//!
+//! Once an hour the sender looks for completed UTC days with recorded
+//! sessions, resolves each policy domain's `_smtp._tls` TXT record and
+//! delivers the gzipped JSON report to every `mailto:` or `https:` rua.
+
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDate, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use super::manager::TlsRptManager;
+use super::types::*;
+use crate::config::{Config, TlsReportingConfig};
+use crate::reporting::scheduler::wrap_base64;
+use crate::smtp::SmtpQueue;
+
+/// Interval between sender runs
+const SENDER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Media type of gzipped reports (RFC 8460 section 6.4)
+const REPORT_CONTENT_TYPE: &str = "application/tlsrpt+gzip";
+
+/// Builds and delivers pending TLS reports
+pub struct TlsReportSender {
+    manager: Arc<TlsRptManager>,
+    config: TlsReportingConfig,
+    queue: Arc<SmtpQueue>,
+    local_domain: String,
+    resolver: TokioAsyncResolver,
+    http: reqwest::Client,
+}
+
+impl TlsReportSender {
+    pub fn new(manager: Arc<TlsRptManager>, config: &Config, queue: Arc<SmtpQueue>) -> Self {
+        Self {
+            manager,
+            config: config.tls_reporting.clone(),
+            queue,
+            local_domain: config.server.domain.to_lowercase(),
+            resolver: TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default()),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Run forever, sending completed days every hour
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(SENDER_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.run_once().await {
+                error!("TLS report sender run failed: {}", e);
+            }
+        }
+    }
+
+    /// Send every pending report for days before today, then prune
+    pub async fn run_once(&self) -> Result<()> {
+        let today = Utc::now().date_naive();
+        for pending in self.manager.pending_reports(today).await? {
+            if let Err(e) = self.send(&pending.policy_domain, pending.day).await {
+                warn!(
+                    "Failed to send TLS report for {} on {}: {}",
+                    pending.policy_domain, pending.day, e
+                );
+            }
+        }
+
+        let pruned = self
+            .manager
+            .prune(today - Duration::days(self.config.retention_days as i64))
+            .await?;
+        if pruned > 0 {
+            info!("Pruned {} old TLS sessions", pruned);
+        }
+
+        Ok(())
+    }
+
+    /// Build the report for one domain and day and deliver it to every rua
+    async fn send(&self, policy_domain: &str, day: NaiveDate) -> Result<()> {
+        let from = self.from();
+        let report = self
+            .manager
+            .build_report(
+                policy_domain,
+                day,
+                self.config
+                    .organization_name
+                    .as_deref()
+                    .unwrap_or(&self.local_domain),
+                self.config.contact_info.as_deref().unwrap_or(&from),
+            )
+            .await?;
+
+        let rua = self.lookup_rua(policy_domain).await?;
+        if rua.is_empty() {
+            // Nobody to report to: don't try this day again
+            self.manager
+                .mark_sent(policy_domain, day, &report.report_id, None)
+                .await?;
+            return Ok(());
+        }
+
+        let compressed = gzip(&serde_json::to_vec(&report)?)?;
+        let mut delivered = Vec::new();
+        for uri in &rua {
+            let result = if let Some(address) = uri.strip_prefix("mailto:") {
+                let message =
+                    build_report_email(&from, address, &self.local_domain, &report, &compressed);
+                self.queue
+                    .enqueue(&from, address, &message)
+                    .await
+                    .map(|_| ())
+                    .map_err(Into::into)
+            } else if uri.starts_with("https://") {
+                self.post(uri, &compressed).await
+            } else {
+                Err(anyhow!("unsupported rua scheme"))
+            };
+
+            match result {
+                Ok(()) => {
+                    info!("Sent TLS report {} to {}", report.report_id, uri);
+                    delivered.push(uri.as_str());
+                }
+                Err(e) => warn!(
+                    "Failed to send TLS report {} to {}: {}",
+                    report.report_id, uri, e
+                ),
+            }
+        }
+
+        if delivered.is_empty() {
+            return Err(anyhow!("no rua of {} accepted the report", policy_domain));
+        }
+        self.manager
+            .mark_sent(
+                policy_domain,
+                day,
+                &report.report_id,
+                Some(&delivered.join(",")),
+            )
+            .await
+    }
+
+    async fn post(&self, uri: &str, compressed: &[u8]) -> Result<()> {
+        self.http
+            .post(uri)
+            .header(reqwest::header::CONTENT_TYPE, REPORT_CONTENT_TYPE)
+            .body(compressed.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Reporting URIs published at `_smtp._tls.<domain>`
+    async fn lookup_rua(&self, policy_domain: &str) -> Result<Vec<String>> {
+        let name = format!("_smtp._tls.{}.", policy_domain);
+        let lookup = match self.resolver.txt_lookup(name).await {
+            Ok(lookup) => lookup,
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                return Ok(Vec::new())
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(lookup
+            .iter()
+            .find_map(|record| parse_tlsrpt_record(&record.to_string()))
+            .unwrap_or_default())
+    }
+
+    fn from(&self) -> String {
+        self.config
+            .from
+            .clone()
+            .unwrap_or_else(|| format!("postmaster@{}", self.local_domain))
+    }
+}
+
+/// Parse a `v=TLSRPTv1; rua=...` record into its reporting URIs
+///
+/// Returns None if the record is not a TLS-RPT record.
+pub fn parse_tlsrpt_record(record: &str) -> Option<Vec<String>> {
+    let mut fields = record.split(';').map(str::trim);
+    if fields.next()? != "v=TLSRPTv1" {
+        return None;
+    }
+
+    let rua = fields
+        .filter_map(|field| field.strip_prefix("rua="))
+        .flat_map(|value| value.split(','))
+        .map(|uri| {
+            let uri = uri.trim();
+            // mailto URIs may carry a query string (RFC 6068)
+            match uri.strip_prefix("mailto:") {
+                Some(address) => format!("mailto:{}", address.split('?').next().unwrap_or(address)),
+                None => uri.to_string(),
+            }
+        })
+        .filter(|uri| !uri.is_empty())
+        .collect();
+
+    Some(rua)
+}
+
+/// Build the report mail (RFC 8460 section 5.3)
+pub fn build_report_email(
+    from: &str,
+    to: &str,
+    submitter: &str,
+    report: &TlsReport,
+    compressed: &[u8],
+) -> Vec<u8> {
+    let policy_domain = report
+        .policies
+        .first()
+        .map(|p| p.policy.policy_domain.as_str())
+        .unwrap_or_default();
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S %z");
+    let boundary = format!("----=_TLSRPT_{}", uuid::Uuid::new_v4().simple());
+    let filename = format!(
+        "{}!{}!{}!{}.json.gz",
+        submitter,
+        policy_domain,
+        report.date_range.start_datetime.timestamp(),
+        report.date_range.end_datetime.timestamp()
+    );
+
+    format!(
+        "From: <{from}>\r\n\
+         To: <{to}>\r\n\
+         Subject: Report Domain: {policy_domain} Submitter: {submitter} Report-ID: <{report_id}>\r\n\
+         Date: {date}\r\n\
+         TLS-Report-Domain: {policy_domain}\r\n\
+         TLS-Report-Submitter: {submitter}\r\n\
+         Auto-Submitted: auto-generated\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=\"tlsrpt\"; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=\"UTF-8\"\r\n\
+         \r\n\
+         This is an aggregate TLS report from {submitter} for {policy_domain}.\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: {REPORT_CONTENT_TYPE}; name=\"{filename}\"\r\n\
+         Content-Disposition: attachment; filename=\"{filename}\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         {data}\
+         --{boundary}--\r\n",
+        report_id = report.report_id,
+        data = wrap_base64(compressed),
+    )
+    .into_bytes()
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use chrono::TimeZone;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_parse_tlsrpt_record() {
+        assert_eq!(
+            parse_tlsrpt_record(
+                "v=TLSRPTv1; rua=mailto:tlsrpt@example.net?subject=x,https://reports.example.net/v1"
+            ),
+            Some(vec![
+                "mailto:tlsrpt@example.net".to_string(),
+                "https://reports.example.net/v1".to_string()
+            ])
+        );
+        assert_eq!(parse_tlsrpt_record("v=spf1 -all"), None);
+    }
+
+    #[test]
+    fn test_build_report_email() {
+        let report = TlsReport {
+            organization_name: "Example".to_string(),
+            date_range: DateRange {
+                start_datetime: Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap(),
+                end_datetime: Utc.with_ymd_and_hms(2024, 3, 1, 23, 59, 59).unwrap(),
+            },
+            contact_info: "postmaster@example.com".to_string(),
+            report_id: "r1".to_string(),
+            policies: vec![PolicyResult {
+                policy: PolicyDetails {
+                    policy_type: "no-policy-found".to_string(),
+                    policy_domain: "example.net".to_string(),
+                },
+                summary: Summary {
+                    total_successful_session_count: 1,
+                    total_failure_session_count: 0,
+                },
+                failure_details: Vec::new(),
+            }],
+        };
+        let json = serde_json::to_vec(&report).unwrap();
+        let message = build_report_email(
+            "postmaster@example.com",
+            "tlsrpt@example.net",
+            "example.com",
+            &report,
+            &gzip(&json).unwrap(),
+        );
+        let message = String::from_utf8(message).unwrap();
+
+        assert!(message.contains(
+            "Subject: Report Domain: example.net Submitter: example.com Report-ID: <r1>\r\n"
+        ));
+        assert!(message.contains("TLS-Report-Domain: example.net\r\n"));
+        assert!(message.contains("report-type=\"tlsrpt\""));
+        assert!(
+            message.contains("filename=\"example.com!example.net!1709251200!1709337599.json.gz\"")
+        );
+
+        let encoded: String = message
+            .split("Content-Transfer-Encoding: base64\r\n\r\n")
+            .nth(1)
+            .unwrap()
+            .split("--")
+            .next()
+            .unwrap()
+            .split("\r\n")
+            .collect();
+        let mut decoded = Vec::new();
+        GzDecoder::new(&BASE64.decode(encoded).unwrap()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, json);
+    }
+}
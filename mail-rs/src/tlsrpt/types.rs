@@ -0,0 +1,169 @@
+//! TLS reporting data types (RFC 8460)
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Reason a TLS session could not be established (RFC 8460 section 4.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsResultType {
+    StarttlsNotSupported,
+    CertificateHostMismatch,
+    CertificateExpired,
+    CertificateNotTrusted,
+    ValidationFailure,
+}
+
+impl TlsResultType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StarttlsNotSupported => "starttls-not-supported",
+            Self::CertificateHostMismatch => "certificate-host-mismatch",
+            Self::CertificateExpired => "certificate-expired",
+            Self::CertificateNotTrusted => "certificate-not-trusted",
+            Self::ValidationFailure => "validation-failure",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "starttls-not-supported" => Some(Self::StarttlsNotSupported),
+            "certificate-host-mismatch" => Some(Self::CertificateHostMismatch),
+            "certificate-expired" => Some(Self::CertificateExpired),
+            "certificate-not-trusted" => Some(Self::CertificateNotTrusted),
+            "validation-failure" => Some(Self::ValidationFailure),
+            _ => None,
+        }
+    }
+}
+
+/// A failed TLS negotiation with a receiving MX
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFailure {
+    pub result_type: TlsResultType,
+    pub receiving_mx_hostname: String,
+    pub receiving_ip: Option<String>,
+    pub additional_information: Option<String>,
+}
+
+/// Aggregated report for one policy domain and day (RFC 8460 section 4.4)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsReport {
+    pub organization_name: String,
+    pub date_range: DateRange,
+    pub contact_info: String,
+    pub report_id: String,
+    pub policies: Vec<PolicyResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DateRange {
+    pub start_datetime: DateTime<Utc>,
+    pub end_datetime: DateTime<Utc>,
+}
+
+/// Sessions towards one policy domain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyResult {
+    pub policy: PolicyDetails,
+    pub summary: Summary,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure_details: Vec<FailureDetails>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyDetails {
+    /// Always "no-policy-found" until MTA-STS/DANE are enforced
+    pub policy_type: String,
+    pub policy_domain: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    pub total_successful_session_count: i64,
+    pub total_failure_session_count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailureDetails {
+    pub result_type: TlsResultType,
+    pub receiving_mx_hostname: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiving_ip: Option<String>,
+    pub failed_session_count: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_information: Option<String>,
+}
+
+/// Day of sessions for a domain that has not been reported yet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingReport {
+    pub policy_domain: String,
+    pub day: NaiveDate,
+    pub successful_sessions: i64,
+    pub failed_sessions: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_report_json_field_names() {
+        let report = TlsReport {
+            organization_name: "Example".to_string(),
+            date_range: DateRange {
+                start_datetime: Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap(),
+                end_datetime: Utc.with_ymd_and_hms(2024, 3, 1, 23, 59, 59).unwrap(),
+            },
+            contact_info: "postmaster@example.com".to_string(),
+            report_id: "r1".to_string(),
+            policies: vec![PolicyResult {
+                policy: PolicyDetails {
+                    policy_type: "no-policy-found".to_string(),
+                    policy_domain: "example.net".to_string(),
+                },
+                summary: Summary {
+                    total_successful_session_count: 5,
+                    total_failure_session_count: 1,
+                },
+                failure_details: vec![FailureDetails {
+                    result_type: TlsResultType::CertificateExpired,
+                    receiving_mx_hostname: "mx.example.net".to_string(),
+                    receiving_ip: None,
+                    failed_session_count: 1,
+                    additional_information: None,
+                }],
+            }],
+        };
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["date-range"]["start-datetime"], "2024-03-01T00:00:00Z");
+        assert_eq!(
+            json["policies"][0]["policy"]["policy-type"],
+            "no-policy-found"
+        );
+        assert_eq!(
+            json["policies"][0]["summary"]["total-failure-session-count"],
+            1
+        );
+        assert_eq!(
+            json["policies"][0]["failure-details"][0]["result-type"],
+            "certificate-expired"
+        );
+        assert!(json["policies"][0]["failure-details"][0]
+            .get("receiving-ip")
+            .is_none());
+        assert_eq!(
+            TlsResultType::parse("certificate-expired"),
+            Some(TlsResultType::CertificateExpired)
+        );
+    }
+}
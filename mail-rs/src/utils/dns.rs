@@ -8,6 +8,7 @@
 //! - Fallback to A/AAAA records
 //! - Caching (future)
 
+use crate::chaos::{self, Fault};
 use crate::error::{MailError, Result};
 use std::net::SocketAddr;
 use tracing::{debug, info, warn};
@@ -35,6 +36,13 @@ use trust_dns_resolver::TokioAsyncResolver;
 pub async fn lookup_mx(domain: &str) -> Result<Vec<String>> {
     info!("Looking up MX records for {}", domain);
 
+    if chaos::inject(Fault::DnsTimeout).await {
+        return Err(MailError::DnsLookup(format!(
+            "Injected timeout looking up MX records for {}",
+            domain
+        )));
+    }
+
     // Create resolver
     let resolver = TokioAsyncResolver::tokio(
         ResolverConfig::default(),
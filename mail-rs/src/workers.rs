@@ -0,0 +1,389 @@
+//! Bounded worker pools for background work
+//!
+//! Outbound delivery, search indexing, AI summaries, notifications and
+//! webhook calls each run in a [`WorkerPool`] of `[workers]` with a fixed
+//! concurrency. A caller handing work to a full pool waits for a slot, so a
+//! slow subsystem holds up its producers (an SMTP transaction, the queue
+//! worker) instead of piling up tasks. Every task also takes a slot of the
+//! global `max_tasks` budget shared by all pools.
+//!
+//! Delivery and indexing pools write to storage; while the server is in
+//! read-only mode (see [`crate::recovery`]) they hold new tasks until the
+//! mode is cleared.
+//!
+//! Queue depths and wait and run times of each pool are reported on
+//! `/api/admin/workers`.
+
+use crate::config::WorkersConfig;
+use crate::recovery::ReadOnlyMode;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Work running in a pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subsystem {
+    /// Outbound deliveries of the queue
+    Delivery,
+    /// Search indexing of delivered mail
+    Indexing,
+    /// AI summary requests
+    Ai,
+    /// New mail notifications, alerts and auto-replies
+    Notifications,
+    /// Webhook calls of the notification channels
+    Webhooks,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [
+        Subsystem::Delivery,
+        Subsystem::Indexing,
+        Subsystem::Ai,
+        Subsystem::Notifications,
+        Subsystem::Webhooks,
+    ];
+
+    /// Whether tasks of the subsystem write to storage
+    pub fn writes(self) -> bool {
+        matches!(self, Subsystem::Delivery | Subsystem::Indexing)
+    }
+}
+
+/// Load and latency of a pool since the server started
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PoolStats {
+    pub subsystem: Subsystem,
+    pub concurrency: usize,
+    pub running: usize,
+    /// Tasks waiting for a slot
+    pub waiting: usize,
+    pub max_waiting: usize,
+    pub completed: u64,
+    pub avg_wait_ms: u64,
+    pub max_wait_ms: u64,
+    pub avg_run_ms: u64,
+    pub max_run_ms: u64,
+}
+
+/// Every pool and the global budget
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorkerStats {
+    pub max_tasks: usize,
+    /// Tasks running in any pool
+    pub running: usize,
+    pub pools: Vec<PoolStats>,
+}
+
+#[derive(Default)]
+struct Timings {
+    completed: u64,
+    total_wait: Duration,
+    max_wait: Duration,
+    total_run: Duration,
+    max_run: Duration,
+}
+
+/// Slots held by a running task
+struct Slot {
+    _pool: OwnedSemaphorePermit,
+    _budget: OwnedSemaphorePermit,
+}
+
+/// Counts a caller as waiting until it gets its slot or gives up
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs up to `concurrency` tasks of a subsystem at the same time
+pub struct WorkerPool {
+    subsystem: Subsystem,
+    concurrency: usize,
+    permits: Arc<Semaphore>,
+    budget: Arc<Semaphore>,
+    /// Holds new tasks while read-only
+    read_only: Option<Arc<ReadOnlyMode>>,
+    waiting: AtomicUsize,
+    max_waiting: AtomicUsize,
+    timings: Mutex<Timings>,
+}
+
+impl WorkerPool {
+    fn new(
+        subsystem: Subsystem,
+        concurrency: usize,
+        budget: Arc<Semaphore>,
+        read_only: Option<Arc<ReadOnlyMode>>,
+    ) -> Self {
+        let concurrency = concurrency.max(1);
+        Self {
+            subsystem,
+            concurrency,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            budget,
+            read_only,
+            waiting: AtomicUsize::new(0),
+            max_waiting: AtomicUsize::new(0),
+            timings: Mutex::new(Timings::default()),
+        }
+    }
+
+    pub fn subsystem(&self) -> Subsystem {
+        self.subsystem
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Run `task` in this pool, waiting for a slot first
+    pub async fn run<F: Future>(&self, task: F) -> F::Output {
+        let slot = self.acquire().await;
+        let started = Instant::now();
+        let output = task.await;
+        self.finish(started);
+        drop(slot);
+        output
+    }
+
+    /// Spawn `task` once the pool has a slot for it
+    ///
+    /// Returns when the task is started, so producers slow down to the pace
+    /// of the pool.
+    pub async fn spawn<F>(self: &Arc<Self>, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let slot = self.acquire().await;
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            task.await;
+            pool.finish(started);
+            drop(slot);
+        });
+    }
+
+    /// Wait until writable, for a slot of the pool, then one of the budget
+    async fn acquire(&self) -> Slot {
+        let waiting = self.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_waiting.fetch_max(waiting, Ordering::Relaxed);
+        let guard = Waiting(&self.waiting);
+
+        let started = Instant::now();
+        if let Some(mode) = &self.read_only {
+            mode.writable().await;
+        }
+        // Neither semaphore is ever closed
+        let pool = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool closed");
+        let budget = self
+            .budget
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("budget closed");
+        drop(guard);
+
+        let waited = started.elapsed();
+        let mut timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        timings.total_wait += waited;
+        timings.max_wait = timings.max_wait.max(waited);
+        Slot {
+            _pool: pool,
+            _budget: budget,
+        }
+    }
+
+    fn finish(&self, started: Instant) {
+        let ran = started.elapsed();
+        let mut timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        timings.completed += 1;
+        timings.total_run += ran;
+        timings.max_run = timings.max_run.max(ran);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        // Waits are recorded when the slot is taken, runs when they end
+        let started = timings.completed + self.running() as u64;
+        PoolStats {
+            subsystem: self.subsystem,
+            concurrency: self.concurrency,
+            running: self.running(),
+            waiting: self.waiting.load(Ordering::Relaxed),
+            max_waiting: self.max_waiting.load(Ordering::Relaxed),
+            completed: timings.completed,
+            avg_wait_ms: average_ms(timings.total_wait, started),
+            max_wait_ms: timings.max_wait.as_millis() as u64,
+            avg_run_ms: average_ms(timings.total_run, timings.completed),
+            max_run_ms: timings.max_run.as_millis() as u64,
+        }
+    }
+
+    fn running(&self) -> usize {
+        self.concurrency - self.permits.available_permits()
+    }
+}
+
+fn average_ms(total: Duration, count: u64) -> u64 {
+    (total.as_millis() as u64).checked_div(count).unwrap_or(0)
+}
+
+/// The pools of every subsystem, sharing the global task budget
+pub struct WorkerPools {
+    max_tasks: usize,
+    budget: Arc<Semaphore>,
+    pools: Vec<Arc<WorkerPool>>,
+}
+
+impl WorkerPools {
+    pub fn from_config(config: &WorkersConfig) -> Self {
+        Self::new(config, None)
+    }
+
+    /// Pools whose writers pause while `read_only` is set
+    pub fn pausing_writers(config: &WorkersConfig, read_only: Arc<ReadOnlyMode>) -> Self {
+        Self::new(config, Some(read_only))
+    }
+
+    fn new(config: &WorkersConfig, read_only: Option<Arc<ReadOnlyMode>>) -> Self {
+        let max_tasks = config.max_tasks.max(1);
+        let budget = Arc::new(Semaphore::new(max_tasks));
+        let pools = Subsystem::ALL
+            .into_iter()
+            .map(|subsystem| {
+                let concurrency = match subsystem {
+                    Subsystem::Delivery => config.delivery,
+                    Subsystem::Indexing => config.indexing,
+                    Subsystem::Ai => config.ai,
+                    Subsystem::Notifications => config.notifications,
+                    Subsystem::Webhooks => config.webhooks,
+                };
+                let read_only = read_only.clone().filter(|_| subsystem.writes());
+                Arc::new(WorkerPool::new(
+                    subsystem,
+                    concurrency,
+                    budget.clone(),
+                    read_only,
+                ))
+            })
+            .collect();
+        Self {
+            max_tasks,
+            budget,
+            pools,
+        }
+    }
+
+    /// Pool of `subsystem`
+    pub fn pool(&self, subsystem: Subsystem) -> &Arc<WorkerPool> {
+        &self.pools[subsystem as usize]
+    }
+
+    pub fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            max_tasks: self.max_tasks,
+            running: self.max_tasks - self.budget.available_permits(),
+            pools: self.pools.iter().map(|pool| pool.stats()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn pools(max_tasks: usize, concurrency: usize) -> WorkerPools {
+        WorkerPools::from_config(&WorkersConfig {
+            max_tasks,
+            delivery: concurrency,
+            indexing: concurrency,
+            ai: concurrency,
+            notifications: concurrency,
+            webhooks: concurrency,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_full_pool_holds_up_spawn() {
+        let pools = pools(10, 1);
+        let pool = pools.pool(Subsystem::Webhooks).clone();
+        assert_eq!(pool.subsystem(), Subsystem::Webhooks);
+
+        let (release, released) = oneshot::channel::<()>();
+        pool.spawn(async move {
+            released.await.ok();
+        })
+        .await;
+        assert_eq!(pool.stats().running, 1);
+
+        // The second task waits until the first ends
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.spawn(async {}).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+        assert_eq!(pool.stats().waiting, 1);
+
+        release.send(()).unwrap();
+        second.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let stats = pool.stats();
+        assert_eq!((stats.running, stats.waiting, stats.max_waiting), (0, 0, 1));
+        assert_eq!(stats.completed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_budget_is_shared_by_pools() {
+        let pools = pools(1, 4);
+        let (release, released) = oneshot::channel::<()>();
+        pools
+            .pool(Subsystem::Delivery)
+            .spawn(async move {
+                released.await.ok();
+            })
+            .await;
+        assert_eq!(pools.stats().running, 1);
+
+        // Another pool with free slots still waits for the budget
+        let ai = pools.pool(Subsystem::Ai).clone();
+        let blocked = tokio::time::timeout(Duration::from_millis(50), ai.run(async { 1 })).await;
+        assert!(blocked.is_err());
+
+        release.send(()).unwrap();
+        assert_eq!(ai.run(async { 2 }).await, 2);
+        assert_eq!(ai.stats().completed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_writers_pause_while_read_only() {
+        let mode = Arc::new(ReadOnlyMode::from_config(&Default::default()));
+        mode.enter("maintenance", crate::recovery::Trigger::Admin);
+        let pools = WorkerPools::pausing_writers(&WorkersConfig::default(), mode.clone());
+
+        // Notifications don't write to storage and keep running
+        assert_eq!(pools.pool(Subsystem::Notifications).run(async { 1 }).await, 1);
+        let delivery = pools.pool(Subsystem::Delivery).clone();
+        let paused = tokio::time::timeout(Duration::from_millis(50), delivery.run(async { 2 })).await;
+        assert!(paused.is_err());
+
+        mode.clear();
+        assert_eq!(delivery.run(async { 3 }).await, 3);
+    }
+}
@@ -0,0 +1,495 @@
+//! Integration tests for IMAP AUTHENTICATE with challenge-response mechanisms
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use mail_rs::imap::{ImapCommand, ImapSession};
+use mail_rs::security::{sasl, Authenticator};
+use sha2::{Digest, Sha256};
+
+async fn new_session(allow_cram_md5: bool) -> (ImapSession, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!(
+        "sqlite://{}?mode=rwc",
+        dir.path().join("users.db").display()
+    );
+    let authenticator = Authenticator::new(&db_url)
+        .await
+        .unwrap()
+        .with_cram_md5(allow_cram_md5);
+    authenticator
+        .add_user("testuser@example.com", "testpass123")
+        .await
+        .unwrap();
+
+    let root = dir.path().to_string_lossy().to_string();
+    (ImapSession::new(authenticator, root), dir)
+}
+
+fn authenticate(mechanism: &str, initial_response: Option<&str>) -> ImapCommand {
+    ImapCommand::Authenticate {
+        mechanism: mechanism.to_string(),
+        initial_response: initial_response.map(str::to_string),
+    }
+}
+
+/// Base64 payload of a `+ ` continuation
+fn continuation(response: &str) -> String {
+    let payload = response
+        .strip_prefix("+ ")
+        .unwrap_or_else(|| panic!("Expected continuation, got: {}", response));
+    String::from_utf8(BASE64.decode(payload.trim()).unwrap()).unwrap()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Client-final message of a SCRAM-SHA-256 exchange
+fn scram_client_final(password: &str, client_first_bare: &str, server_first: &str) -> String {
+    let attr = |name: &str| {
+        server_first
+            .split(',')
+            .find_map(|a| a.strip_prefix(&format!("{}=", name)))
+            .unwrap()
+            .to_string()
+    };
+    let salt = BASE64.decode(attr("s")).unwrap();
+    let iterations: u32 = attr("i").parse().unwrap();
+
+    let mut salted = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted);
+    let client_key = hmac_sha256(&salted, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+
+    let without_proof = format!("c={},r={}", BASE64.encode("n,,"), attr("r"));
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+    let proof: Vec<u8> = client_key
+        .iter()
+        .zip(signature)
+        .map(|(k, s)| k ^ s)
+        .collect();
+    format!("{},p={}", without_proof, BASE64.encode(proof))
+}
+
+#[tokio::test]
+async fn test_capability_lists_challenge_response_mechanisms() {
+    let (mut session, _dir) = new_session(false).await;
+    let response = session
+        .handle_command("A1".to_string(), ImapCommand::Capability)
+        .await
+        .unwrap();
+    assert!(response.contains("AUTH=SCRAM-SHA-256"));
+    assert!(!response.contains("AUTH=CRAM-MD5"));
+
+    let response = session
+        .handle_command("A2".to_string(), authenticate("CRAM-MD5", None))
+        .await
+        .unwrap();
+    assert!(response.starts_with("A2 NO"), "Got: {}", response);
+
+    let (mut session, _dir) = new_session(true).await;
+    let response = session
+        .handle_command("A1".to_string(), ImapCommand::Capability)
+        .await
+        .unwrap();
+    assert!(response.contains("AUTH=CRAM-MD5"));
+}
+
+#[tokio::test]
+async fn test_authenticate_scram_sha256() {
+    let (mut session, _dir) = new_session(false).await;
+    let client_first_bare = "n=testuser@example.com,r=fyko+d2lbbFgONRv9qkxdawL";
+    let initial = BASE64.encode(format!("n,,{}", client_first_bare));
+
+    let response = session
+        .handle_command(
+            "A1".to_string(),
+            authenticate("SCRAM-SHA-256", Some(&initial)),
+        )
+        .await
+        .unwrap();
+    let server_first = continuation(&response);
+    assert!(server_first.starts_with("r=fyko+d2lbbFgONRv9qkxdawL"));
+
+    let client_final = scram_client_final("testpass123", client_first_bare, &server_first);
+    let response = session
+        .handle_auth_response(&BASE64.encode(client_final))
+        .await
+        .unwrap();
+    assert!(continuation(&response).starts_with("v="));
+
+    let response = session.handle_auth_response("").await.unwrap();
+    assert_eq!(response, "A1 OK AUTHENTICATE completed\r\n");
+    assert!(session.is_authenticated());
+}
+
+#[tokio::test]
+async fn test_authenticate_scram_sha256_wrong_password() {
+    let (mut session, _dir) = new_session(false).await;
+    let client_first_bare = "n=testuser@example.com,r=abcdef";
+
+    // Without SASL-IR the client-first arrives as a continuation line
+    let response = session
+        .handle_command("A1".to_string(), authenticate("SCRAM-SHA-256", None))
+        .await
+        .unwrap();
+    assert_eq!(response, "+ \r\n");
+    let response = session
+        .handle_auth_response(&BASE64.encode(format!("n,,{}", client_first_bare)))
+        .await
+        .unwrap();
+    let server_first = continuation(&response);
+
+    let client_final = scram_client_final("wrong", client_first_bare, &server_first);
+    let response = session
+        .handle_auth_response(&BASE64.encode(client_final))
+        .await
+        .unwrap();
+    assert!(response.starts_with("A1 NO"), "Got: {}", response);
+    assert!(!session.is_authenticated());
+}
+
+#[tokio::test]
+async fn test_authenticate_cram_md5() {
+    let (mut session, _dir) = new_session(true).await;
+
+    let response = session
+        .handle_command("A1".to_string(), authenticate("CRAM-MD5", None))
+        .await
+        .unwrap();
+    let challenge = continuation(&response);
+
+    let digest = sasl::cram_md5_digest(b"testpass123", &challenge);
+    let answer = BASE64.encode(format!("testuser@example.com {}", digest));
+    let response = session.handle_auth_response(&answer).await.unwrap();
+    assert_eq!(response, "A1 OK AUTHENTICATE completed\r\n");
+    assert!(session.is_authenticated());
+}
+
+#[tokio::test]
+async fn test_authenticate_plain() {
+    let (mut session, _dir) = new_session(false).await;
+    let response = session
+        .handle_command("A1".to_string(), ImapCommand::Capability)
+        .await
+        .unwrap();
+    assert!(response.contains("AUTH=PLAIN AUTH=LOGIN"));
+
+    // SASL-IR, with a wrong password
+    let initial = BASE64.encode("\0testuser@example.com\0wrong");
+    let response = session
+        .handle_command("A2".to_string(), authenticate("PLAIN", Some(&initial)))
+        .await
+        .unwrap();
+    assert_eq!(
+        response,
+        "A2 NO AUTHENTICATE failed - invalid credentials\r\n"
+    );
+
+    // Acting as another user is refused
+    let initial = BASE64.encode("other@example.com\0testuser@example.com\0testpass123");
+    let response = session
+        .handle_command("A3".to_string(), authenticate("PLAIN", Some(&initial)))
+        .await
+        .unwrap();
+    assert!(
+        response.starts_with("A3 NO [AUTHORIZATIONFAILED]"),
+        "Got: {}",
+        response
+    );
+
+    // Response as a continuation line
+    let response = session
+        .handle_command("A4".to_string(), authenticate("PLAIN", None))
+        .await
+        .unwrap();
+    assert_eq!(response, "+ \r\n");
+    let response = session
+        .handle_auth_response(
+            &BASE64.encode("testuser@example.com\0testuser@example.com\0testpass123"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response, "A4 OK AUTHENTICATE completed\r\n");
+    assert_eq!(session.username(), Some("testuser@example.com"));
+}
+
+#[tokio::test]
+async fn test_authenticate_login() {
+    let (mut session, _dir) = new_session(false).await;
+
+    let response = session
+        .handle_command("A1".to_string(), authenticate("LOGIN", None))
+        .await
+        .unwrap();
+    assert_eq!(continuation(&response), "Username:");
+    let response = session
+        .handle_auth_response(&BASE64.encode("testuser@example.com"))
+        .await
+        .unwrap();
+    assert_eq!(continuation(&response), "Password:");
+    let response = session
+        .handle_auth_response(&BASE64.encode("testpass123"))
+        .await
+        .unwrap();
+    assert_eq!(response, "A1 OK AUTHENTICATE completed\r\n");
+    assert!(session.is_authenticated());
+
+    // The username may come as an initial response; "*" cancels
+    let (mut session, _dir) = new_session(false).await;
+    let initial = BASE64.encode("testuser@example.com");
+    let response = session
+        .handle_command("A1".to_string(), authenticate("LOGIN", Some(&initial)))
+        .await
+        .unwrap();
+    assert_eq!(continuation(&response), "Password:");
+    let response = session.handle_auth_response("*").await.unwrap();
+    assert_eq!(response, "A1 BAD AUTHENTICATE cancelled\r\n");
+    assert!(!session.is_authenticated());
+}
+
+#[tokio::test]
+async fn test_mfa_code_required_for_password_logins() {
+    use mail_rs::mfa::MfaManager;
+    use std::sync::Arc;
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    let (session, dir) = new_session(false).await;
+    let mfa = MfaManager::connect(&format!(
+        "sqlite://{}?mode=rwc",
+        dir.path().join("mfa.db").display()
+    ))
+    .await
+    .unwrap();
+    let setup = mfa.start_setup("testuser@example.com").await.unwrap();
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(setup.secret).to_bytes().unwrap(),
+        None,
+        String::new(),
+    )
+    .unwrap();
+    let backup_codes = mfa
+        .complete_setup("testuser@example.com", &totp.generate_current().unwrap())
+        .await
+        .unwrap();
+    let mfa = Arc::new(mfa);
+    let mut session = session.with_mfa(mfa.clone());
+
+    let login = |tag: &str, password: &str| {
+        (
+            tag.to_string(),
+            ImapCommand::Login {
+                username: "testuser@example.com".to_string(),
+                password: password.to_string(),
+            },
+        )
+    };
+
+    // The password alone is no longer enough
+    let (tag, command) = login("A1", "testpass123");
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert!(response.starts_with("A1 NO"), "Got: {}", response);
+
+    // Challenge-response mechanisms cannot carry the code
+    let client_first_bare = "n=testuser@example.com,r=abcdef";
+    let response = session
+        .handle_command(
+            "A2".to_string(),
+            authenticate(
+                "SCRAM-SHA-256",
+                Some(&BASE64.encode(format!("n,,{}", client_first_bare))),
+            ),
+        )
+        .await
+        .unwrap();
+    let server_first = continuation(&response);
+    let client_final = scram_client_final("testpass123", client_first_bare, &server_first);
+    let response = session
+        .handle_auth_response(&BASE64.encode(client_final))
+        .await
+        .unwrap();
+    assert!(
+        response.starts_with("A2 NO [AUTHENTICATIONFAILED]"),
+        "Got: {}",
+        response
+    );
+
+    // password:code, with a backup code standing in for the TOTP code
+    let (tag, command) = login("A3", &format!("testpass123:{}", backup_codes[0]));
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "A3 OK LOGIN completed\r\n");
+
+    let (session, _dir) = new_session(false).await;
+    let mut session = session.with_mfa(mfa);
+    let password = format!("testpass123:{}", totp.generate_current().unwrap());
+    let response = session
+        .handle_command(
+            "A1".to_string(),
+            authenticate(
+                "PLAIN",
+                Some(&BASE64.encode(format!("\0testuser@example.com\0{}", password))),
+            ),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response, "A1 OK AUTHENTICATE completed\r\n");
+}
+
+#[tokio::test]
+async fn test_devices_and_app_passwords() {
+    use mail_rs::devices::{DeviceKind, DeviceManager};
+    use std::sync::Arc;
+
+    let (session, dir) = new_session(false).await;
+    let devices = Arc::new(
+        DeviceManager::connect(&format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("devices.db").display()
+        ))
+        .await
+        .unwrap(),
+    );
+    let mut session = session
+        .with_devices(devices.clone())
+        .with_client_ip("203.0.113.7".parse().unwrap());
+
+    let login = |tag: &str, password: &str| {
+        (
+            tag.to_string(),
+            ImapCommand::Login {
+                username: "testuser@example.com".to_string(),
+                password: password.to_string(),
+            },
+        )
+    };
+
+    // The device is named by the client's ID and recorded once it goes on
+    let (_, command) =
+        ImapCommand::parse("A1 ID (\"name\" \"Thunderbird\" \"version\" \"128.0\")").unwrap();
+    let response = session
+        .handle_command("A1".to_string(), command)
+        .await
+        .unwrap();
+    assert!(
+        response.starts_with("* ID (\"name\" \"mail-rs\""),
+        "Got: {}",
+        response
+    );
+    assert!(
+        response.ends_with("A1 OK ID completed\r\n"),
+        "Got: {}",
+        response
+    );
+    let (tag, command) = login("A2", "testpass123");
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "A2 OK LOGIN completed\r\n");
+    session
+        .handle_command("A3".to_string(), ImapCommand::Noop)
+        .await
+        .unwrap();
+
+    let listed = devices.list("testuser@example.com").await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].kind, DeviceKind::Imap);
+    assert_eq!(listed[0].name, "Thunderbird 128.0");
+    assert_eq!(listed[0].last_network.as_deref(), Some("203.0.0.0/16"));
+
+    // App passwords log in as their own device until revoked
+    let app = devices
+        .create_app_password("testuser@example.com", "Phone")
+        .await
+        .unwrap();
+    let (session, _dir) = new_session(false).await;
+    let mut session = session.with_devices(devices.clone());
+    let (tag, command) = login("A1", &app.password);
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "A1 OK LOGIN completed\r\n");
+
+    devices
+        .revoke("testuser@example.com", &app.device.id)
+        .await
+        .unwrap()
+        .unwrap();
+    let (session, _dir) = new_session(false).await;
+    let mut session = session.with_devices(devices.clone());
+    let (tag, command) = login("A1", &app.password);
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert!(response.starts_with("A1 NO"), "Got: {}", response);
+}
+
+#[tokio::test]
+async fn test_unusual_login_blocked() {
+    use mail_rs::config::LoginAnomalyConfig;
+    use mail_rs::login_anomaly::LoginAnomalyDetector;
+    use std::sync::Arc;
+
+    let (_session, dir) = new_session(false).await;
+    let detector = Arc::new(
+        LoginAnomalyDetector::connect(
+            &format!(
+                "sqlite://{}?mode=rwc",
+                dir.path().join("anomalies.db").display()
+            ),
+            LoginAnomalyConfig {
+                enabled: true,
+                block_protocol_logins: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap(),
+    );
+    // The first login teaches the network, the same network passes later
+    for (ip, allowed) in [
+        ("203.0.113.7", true),
+        ("203.0.200.1", true),
+        ("198.51.100.1", false),
+        ("198.51.100.2", false),
+    ] {
+        let (session, _dir) = new_session(false).await;
+        let mut session = session
+            .with_login_anomalies(detector.clone())
+            .with_client_ip(ip.parse().unwrap());
+        let response = session
+            .handle_command(
+                "A1".to_string(),
+                ImapCommand::Login {
+                    username: "testuser@example.com".to_string(),
+                    password: "testpass123".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.starts_with("A1 OK"), allowed, "{}: {}", ip, response);
+        assert_eq!(session.is_authenticated(), allowed);
+    }
+
+    // Lifting the block trusts the network
+    let lifted = detector
+        .unblock("testuser@example.com", "admin@example.com", None)
+        .await
+        .unwrap();
+    assert_eq!(lifted.len(), 1);
+    let (session, _dir) = new_session(false).await;
+    let mut session = session
+        .with_login_anomalies(detector.clone())
+        .with_client_ip("198.51.100.3".parse().unwrap());
+    let response = session
+        .handle_command(
+            "A1".to_string(),
+            ImapCommand::Login {
+                username: "testuser@example.com".to_string(),
+                password: "testpass123".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(response, "A1 OK LOGIN completed\r\n");
+}
@@ -1,7 +1,13 @@
-//! Integration tests for IMAP write operations (STORE, COPY, EXPUNGE)
-
-use mail_rs::imap::{Mailbox, StoreOperation};
+//! Integration tests for IMAP write operations (STORE, COPY, APPEND, EXPUNGE)
+//! and UID commands
+
+use mail_rs::imap::{ImapCommand, ImapSession, Mailbox, StoreOperation};
+use mail_rs::recovery::{ReadOnlyMode, Trigger};
+use mail_rs::security::Authenticator;
+use mail_rs::spam::SpamManager;
+use mail_rs::storage::{FlagEventBus, FlagSource};
 use std::fs;
+use std::sync::Arc;
 use tempfile::TempDir;
 
 /// Helper to create a test maildir structure
@@ -67,7 +73,7 @@ fn test_store_add_seen_flag() {
 
     // Find message with "Test 1" subject
     let msg_with_flag = mailbox.messages().iter().find(|m| {
-        String::from_utf8_lossy(&m.content).contains("Test 1")
+        String::from_utf8_lossy(m.content().unwrap()).contains("Test 1")
     }).expect("Should find Test 1 message");
 
     assert!(msg_with_flag.flags.contains(&"\\Seen".to_string()));
@@ -103,7 +109,7 @@ fn test_store_add_multiple_flags() {
     // Reload and check persistence
     let mailbox = Mailbox::open(&email, "INBOX", temp_dir.path()).unwrap();
     let msg_with_flags = mailbox.messages().iter().find(|m| {
-        String::from_utf8_lossy(&m.content).contains("Test 1")
+        String::from_utf8_lossy(m.content().unwrap()).contains("Test 1")
     }).expect("Should find Test 1 message");
 
     assert!(msg_with_flags.flags.contains(&"\\Seen".to_string()));
@@ -249,7 +255,7 @@ fn test_copy_message_to_sent() {
     // Copy message 1 to Sent
     let result = mailbox.copy_messages("1", "Sent", &email, temp_dir.path());
     assert!(result.is_ok());
-    assert_eq!(result.unwrap(), 1);
+    assert_eq!(result.unwrap().uids.len(), 1);
 
     // Verify original still exists in INBOX
     assert_eq!(mailbox.message_count(), 3);
@@ -259,6 +265,32 @@ fn test_copy_message_to_sent() {
     assert_eq!(sent_mailbox.message_count(), 1);
 }
 
+#[test]
+fn test_append_to_sent() {
+    let (temp_dir, email) = setup_test_maildir();
+    fs::create_dir_all(temp_dir.path().join(&email).join(".Sent")).unwrap();
+
+    let content = b"From: test@example.com\r\nSubject: Sent copy\r\n\r\nBody";
+    Mailbox::append(
+        &email,
+        "Sent",
+        temp_dir.path(),
+        content,
+        &["\\Seen".to_string()],
+        None,
+    )
+    .unwrap();
+
+    let sent_mailbox = Mailbox::open(&email, "Sent", temp_dir.path()).unwrap();
+    assert_eq!(sent_mailbox.message_count(), 1);
+    let msg = sent_mailbox.get_message(1).unwrap();
+    assert_eq!(msg.content().unwrap(), content);
+    assert_eq!(msg.flags, vec!["\\Seen".to_string()]);
+
+    // Missing folders are not created implicitly
+    assert!(Mailbox::append(&email, "Drafts", temp_dir.path(), content, &[], None).is_err());
+}
+
 #[test]
 fn test_copy_preserves_flags() {
     let (temp_dir, email) = setup_test_maildir();
@@ -299,7 +331,7 @@ fn test_copy_multiple_messages() {
     // Copy multiple messages (1:2)
     let result = mailbox.copy_messages("1:2", "Trash", &email, temp_dir.path());
     assert!(result.is_ok());
-    assert_eq!(result.unwrap(), 2);
+    assert_eq!(result.unwrap().uids.len(), 2);
 
     // Verify copies exist
     let trash_mailbox = Mailbox::open(&email, "Trash", temp_dir.path()).unwrap();
@@ -335,3 +367,611 @@ fn test_full_workflow_mark_delete_expunge() {
     let mailbox = Mailbox::open(&email, "INBOX", temp_dir.path()).unwrap();
     assert_eq!(mailbox.message_count(), 2);
 }
+
+#[tokio::test]
+async fn test_uid_commands_use_persistent_uids() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+
+    let mut session = ImapSession::new(authenticator, root);
+    let login = ImapCommand::Login {
+        username: email.clone(),
+        password: "secret".to_string(),
+    };
+    session.handle_command("A1".to_string(), login).await.unwrap();
+    let select = session
+        .handle_command("A2".to_string(), ImapCommand::Select { mailbox: "INBOX".to_string() })
+        .await
+        .unwrap();
+    assert!(select.contains("[UIDNEXT 4]"));
+
+    // Expunge the first message; the others keep their UIDs
+    let mut mailbox = Mailbox::open(&email, "INBOX", temp_dir.path()).unwrap();
+    mailbox.store_flags("1", &StoreOperation::Add, &["\\Deleted".to_string()]).unwrap();
+    mailbox.expunge().unwrap();
+    let select = session
+        .handle_command("A3".to_string(), ImapCommand::Select { mailbox: "INBOX".to_string() })
+        .await
+        .unwrap();
+    let validity = mailbox.uid_validity();
+    assert!(select.contains(&format!("[UIDVALIDITY {}]", validity)));
+
+    let (tag, command) = ImapCommand::parse("A4 UID FETCH 3:* FLAGS").unwrap();
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "* 2 FETCH (UID 3 FLAGS (\\Seen))\r\nA4 OK UID FETCH completed\r\n");
+
+    let (tag, command) = ImapCommand::parse("A5 UID SEARCH ALL").unwrap();
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert!(response.starts_with("* SEARCH 2 3\r\n"));
+
+    let (tag, command) = ImapCommand::parse("A6 UID STORE 2 +FLAGS (\\Flagged)").unwrap();
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "* 1 FETCH (UID 2 FLAGS (\\Flagged))\r\nA6 OK UID STORE completed\r\n");
+
+    let (tag, command) = ImapCommand::parse("A7 UID STORE 1 +FLAGS (\\Seen)").unwrap();
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "A7 OK UID STORE completed\r\n");
+}
+
+#[tokio::test]
+async fn test_idle_pushes_mailbox_changes() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+
+    let mut session = ImapSession::new(authenticator, root);
+    for line in ["A1 LOGIN test@example.com secret", "A2 SELECT INBOX"] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        session.handle_command(tag, command).await.unwrap();
+    }
+    let (tag, command) = ImapCommand::parse("A3 IDLE").unwrap();
+    assert_eq!(session.handle_command(tag, command).await.unwrap(), "+ idling\r\n");
+    assert!(session.mailbox_updates().unwrap().is_empty());
+
+    // A delivery wakes the idling session
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let maildir = temp_dir.path().join(&email);
+    fs::write(maildir.join("new/4.eml"), "Subject: Test 4\r\n\r\nBody 4").unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(2), session.idle_changed())
+        .await
+        .expect("delivery should be notified");
+    assert_eq!(session.mailbox_updates().unwrap(), "* 4 EXISTS\r\n* 4 RECENT\r\n");
+
+    // Another client expunges the first message and flags the last one
+    fs::remove_file(maildir.join("new/1.eml")).unwrap();
+    fs::rename(maildir.join("new/4.eml"), maildir.join("cur/4.eml:2,F")).unwrap();
+    assert_eq!(
+        session.mailbox_updates().unwrap(),
+        "* 1 EXPUNGE\r\n* 3 FETCH (FLAGS (\\Flagged))\r\n"
+    );
+
+    let (tag, command) = ImapCommand::parse("DONE").unwrap();
+    assert_eq!(session.handle_command(tag, command).await.unwrap(), "A3 OK IDLE terminated\r\n");
+    assert!(!session.is_idle());
+}
+
+#[tokio::test]
+async fn test_read_only_mode_refuses_changes() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+    let mode = Arc::new(ReadOnlyMode::from_config(&Default::default()));
+    mode.enter("database disk image is malformed", Trigger::Automatic);
+
+    let mut session = ImapSession::new(authenticator, root).with_read_only(mode.clone());
+    let (tag, command) = ImapCommand::parse("A1 LOGIN test@example.com secret").unwrap();
+    session.handle_command(tag, command).await.unwrap();
+    let (tag, command) = ImapCommand::parse("A2 SELECT INBOX").unwrap();
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert!(response.ends_with("A2 OK [READ-ONLY] SELECT completed\r\n"));
+
+    // Reading still works, changes are refused
+    let (tag, command) = ImapCommand::parse("A3 FETCH 1 (FLAGS)").unwrap();
+    assert!(session.handle_command(tag, command).await.unwrap().contains("A3 OK"));
+    for line in ["A4 STORE 1 +FLAGS (\\Seen)", "A5 UID COPY 1 Trash", "A6 EXPUNGE"] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        let response = session.handle_command(tag.clone(), command).await.unwrap();
+        assert!(response.starts_with(&format!("{} NO [CANNOT]", tag)), "{}", response);
+    }
+    assert!(temp_dir.path().join(&email).join("new/1.eml").exists());
+
+    mode.clear();
+    let (tag, command) = ImapCommand::parse("A7 STORE 1 +FLAGS (\\Seen)").unwrap();
+    assert!(session.handle_command(tag, command).await.unwrap().contains("A7 OK"));
+}
+
+#[tokio::test]
+async fn test_flag_changes_shared_between_sessions() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+    let bus = Arc::new(FlagEventBus::new());
+    let mut events = bus.subscribe();
+
+    let mut writer = ImapSession::new(authenticator.clone(), root.clone())
+        .with_flag_events(bus.clone());
+    let mut reader = ImapSession::new(authenticator, root).with_flag_events(bus);
+    for session in [&mut writer, &mut reader] {
+        for line in ["A1 LOGIN test@example.com secret", "A2 SELECT INBOX"] {
+            let (tag, command) = ImapCommand::parse(line).unwrap();
+            session.handle_command(tag, command).await.unwrap();
+        }
+    }
+    let (tag, command) = ImapCommand::parse("B1 IDLE").unwrap();
+    reader.handle_command(tag, command).await.unwrap();
+
+    // Marking a message read is published and wakes the idling session
+    let (tag, command) = ImapCommand::parse("A3 STORE 1 +FLAGS (\\Seen)").unwrap();
+    writer.handle_command(tag, command).await.unwrap();
+    let event = events.try_recv().unwrap();
+    assert_eq!(event.message, "1.eml");
+    assert_eq!(event.source, FlagSource::Imap);
+    assert!(event.is_seen());
+    tokio::time::timeout(std::time::Duration::from_secs(2), reader.idle_changed())
+        .await
+        .expect("flag change should be notified");
+    assert_eq!(reader.mailbox_updates().unwrap(), "* 1 FETCH (FLAGS (\\Seen))\r\n");
+    let (tag, command) = ImapCommand::parse("DONE").unwrap();
+    reader.handle_command(tag, command).await.unwrap();
+
+    // Expunges are published too, and NOOP reports them
+    for line in ["A4 STORE 2 +FLAGS (\\Deleted)", "A5 EXPUNGE"] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        writer.handle_command(tag, command).await.unwrap();
+    }
+    assert!(!events.try_recv().unwrap().expunged);
+    let event = events.try_recv().unwrap();
+    assert!(event.expunged);
+    assert_eq!(event.message, "2.eml");
+    let (tag, command) = ImapCommand::parse("B2 NOOP").unwrap();
+    assert_eq!(
+        reader.handle_command(tag, command).await.unwrap(),
+        "* 2 EXPUNGE\r\nB2 OK NOOP completed\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_first_login_creates_special_use_folders() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+
+    let mut session = ImapSession::new(authenticator, root);
+    let mut responses = Vec::new();
+    for line in ["A1 CAPABILITY", "A2 LOGIN test@example.com secret", "A3 LIST \"\" *"] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        responses.push(session.handle_command(tag, command).await.unwrap());
+    }
+    assert!(responses[0].split_whitespace().any(|cap| cap == "SPECIAL-USE"));
+    assert_eq!(
+        responses[2],
+        "* LIST (\\Archive) \"/\" \"Archive\"\r\n\
+         * LIST (\\Drafts) \"/\" \"Drafts\"\r\n\
+         * LIST () \"/\" \"INBOX\"\r\n\
+         * LIST (\\Junk) \"/\" \"Junk\"\r\n\
+         * LIST (\\Sent) \"/\" \"Sent\"\r\n\
+         * LIST (\\Trash) \"/\" \"Trash\"\r\n\
+         A3 OK LIST completed\r\n"
+    );
+
+    // The new folders are usable right away
+    let append = Mailbox::append(&email, "Sent", temp_dir.path(), b"Subject: x\r\n\r\n", &[], None);
+    assert!(append.is_ok());
+}
+
+#[tokio::test]
+async fn test_uidplus_copy_and_uid_expunge() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+
+    let mut session = ImapSession::new(authenticator, temp_dir.path().to_str().unwrap().to_string());
+    let mut responses = Vec::new();
+    for line in [
+        "A1 CAPABILITY",
+        "A2 LOGIN test@example.com secret",
+        "A3 SELECT INBOX",
+        "A4 UID COPY 2:3 Archive",
+        "A5 UID STORE 1:2 +FLAGS (\\Deleted)",
+        "A6 UID EXPUNGE 2",
+        "A7 EXPUNGE",
+    ] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        responses.push(session.handle_command(tag, command).await.unwrap());
+    }
+    assert!(responses[0].split_whitespace().any(|cap| cap == "UIDPLUS"));
+
+    let archive = Mailbox::open(&email, "Archive", temp_dir.path()).unwrap();
+    assert_eq!(
+        responses[3],
+        format!(
+            "A4 OK [COPYUID {} 2:3 1:2] UID COPY completed (2 messages)\r\n",
+            archive.uid_validity()
+        )
+    );
+    let copy = archive.get_messages_by_uid("2")[0];
+    assert!(copy.content().unwrap().ends_with(b"Body 3"));
+
+    // Only the deleted message in the UID set goes
+    assert_eq!(responses[5], "* 2 EXPUNGE\r\nA6 OK UID EXPUNGE completed\r\n");
+    assert_eq!(responses[6], "* 1 EXPUNGE\r\nA7 OK EXPUNGE completed\r\n");
+}
+
+#[tokio::test]
+async fn test_copy_to_and_from_junk_trains_spam() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let spam = Arc::new(SpamManager::connect("sqlite::memory:").await.unwrap());
+
+    async fn run(session: &mut ImapSession, line: &str) -> String {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        session.handle_command(tag, command).await.unwrap()
+    }
+
+    // Training happens in the background after COPY completes
+    async fn trained(spam: &SpamManager, spam_learned: u32, ham_learned: u32) -> bool {
+        for _ in 0..100 {
+            let stats = spam.get_stats().await.unwrap();
+            if (stats.spam_learned, stats.ham_learned) == (spam_learned, ham_learned) {
+                return true;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    let mut session = ImapSession::new(authenticator, temp_dir.path().to_str().unwrap().to_string())
+        .with_spam_training(spam.clone());
+    run(&mut session, "A1 LOGIN test@example.com secret").await;
+    run(&mut session, "A2 SELECT INBOX").await;
+    run(&mut session, "A3 COPY 2 Archive").await;
+    run(&mut session, "A4 COPY 1 Junk").await;
+    assert!(trained(&spam, 1, 0).await);
+
+    // Copying it back out of Junk relearns it as ham; into Trash doesn't
+    run(&mut session, "A5 SELECT Junk").await;
+    run(&mut session, "A6 COPY 1 Trash").await;
+    run(&mut session, "A7 COPY 1 INBOX").await;
+    assert!(trained(&spam, 0, 1).await);
+}
+
+#[tokio::test]
+async fn test_subscriptions_persist_across_sessions() {
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+
+    let mut session = ImapSession::new(authenticator.clone(), root.clone());
+    let mut responses = Vec::new();
+    for line in [
+        "A1 LOGIN test@example.com secret",
+        "A2 NAMESPACE",
+        "A3 LSUB \"\" \"*\"",
+        "A4 UNSUBSCRIBE Junk",
+        "A5 UNSUBSCRIBE Junk",
+        "A6 SUBSCRIBE \"Lists/rust\"",
+    ] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        responses.push(session.handle_command(tag, command).await.unwrap());
+    }
+    assert_eq!(
+        responses[1],
+        "* NAMESPACE ((\"\" \"/\")) NIL NIL\r\nA2 OK NAMESPACE completed\r\n"
+    );
+    // Every folder starts out subscribed
+    assert_eq!(responses[2].matches("* LSUB () ").count(), 6);
+    assert_eq!(responses[3], "A4 OK UNSUBSCRIBE completed\r\n");
+    assert!(responses[4].starts_with("A5 NO"));
+    assert_eq!(responses[5], "A6 OK SUBSCRIBE completed\r\n");
+
+    let mut session = ImapSession::new(authenticator, root);
+    for line in ["B1 LOGIN test@example.com secret", "B2 LSUB \"\" \"*\""] {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        responses.push(session.handle_command(tag, command).await.unwrap());
+    }
+    assert_eq!(
+        responses[7],
+        "* LSUB () \"/\" \"Archive\"\r\n\
+         * LSUB () \"/\" \"Drafts\"\r\n\
+         * LSUB () \"/\" \"INBOX\"\r\n\
+         * LSUB (\\Noselect) \"/\" \"Lists/rust\"\r\n\
+         * LSUB () \"/\" \"Sent\"\r\n\
+         * LSUB () \"/\" \"Trash\"\r\n\
+         B2 OK LSUB completed\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_quota_commands_and_append_over_quota() {
+    use mail_rs::quota::QuotaManager;
+    use std::sync::Arc;
+
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+    let quotas = Arc::new(QuotaManager::new());
+
+    async fn run(session: &mut ImapSession, line: &str) -> String {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        session.handle_command(tag, command).await.unwrap()
+    }
+
+    // Users may read but not change their quota
+    let mut session = ImapSession::new(authenticator.clone(), root.clone())
+        .with_quotas(quotas.clone(), Vec::new());
+    assert!(run(&mut session, "A1 CAPABILITY").await.contains(" QUOTA "));
+    run(&mut session, "A2 LOGIN test@example.com secret").await;
+    assert_eq!(
+        run(&mut session, "A3 GETQUOTA \"\"").await,
+        "* QUOTA \"\" (STORAGE 1 1048576)\r\nA3 OK GETQUOTA completed\r\n"
+    );
+    assert!(run(&mut session, "A4 SETQUOTA \"\" (STORAGE 1)")
+        .await
+        .starts_with("A4 NO [NOPERM]"));
+    assert!(run(&mut session, "A5 GETQUOTA other@example.com")
+        .await
+        .starts_with("A5 NO"));
+
+    let mut admin = ImapSession::new(authenticator, root)
+        .with_quotas(quotas.clone(), vec![email.clone()]);
+    run(&mut admin, "B1 LOGIN test@example.com secret").await;
+    assert_eq!(
+        run(&mut admin, "B2 SETQUOTA \"\" (STORAGE 1)").await,
+        "* QUOTA \"\" (STORAGE 1 1)\r\nB2 OK SETQUOTA completed\r\n"
+    );
+    assert_eq!(
+        run(&mut session, "A6 GETQUOTAROOT inbox").await,
+        "* QUOTAROOT \"inbox\" \"\"\r\n\
+         * QUOTA \"\" (STORAGE 1 1)\r\n\
+         A6 OK GETQUOTAROOT completed\r\n"
+    );
+    assert!(run(&mut session, "A7 GETQUOTAROOT Missing")
+        .await
+        .starts_with("A7 NO"));
+
+    let append = |tag: &str, size: usize| {
+        (
+            tag.to_string(),
+            ImapCommand::Append {
+                mailbox: "INBOX".to_string(),
+                flags: Vec::new(),
+                internal_date: None,
+                size,
+                message: vec![b'x'; size],
+            },
+        )
+    };
+    let (tag, command) = append("A8", 2000);
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert_eq!(response, "A8 NO [OVERQUOTA] Storage quota exceeded\r\n");
+
+    // A smaller message still fits
+    let (tag, command) = append("A9", 500);
+    let response = session.handle_command(tag, command).await.unwrap();
+    assert!(response.starts_with("A9 OK [APPENDUID "));
+    assert!(response.ends_with("] APPEND completed\r\n"));
+    let inbox = Mailbox::open(&email, "INBOX", temp_dir.path()).unwrap();
+    assert_eq!(inbox.message_count(), 4);
+}
+
+#[tokio::test]
+async fn test_status_and_list_status_from_metadata_index() {
+    use mail_rs::storage::MetadataIndex;
+
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+    let root = temp_dir.path().to_str().unwrap().to_string();
+
+    async fn run(session: &mut ImapSession, line: &str) -> String {
+        let (tag, command) = ImapCommand::parse(line).unwrap();
+        session.handle_command(tag, command).await.unwrap()
+    }
+
+    // Without an index the folder is read
+    let mut session = ImapSession::new(authenticator.clone(), root.clone());
+    run(&mut session, "A1 LOGIN test@example.com secret").await;
+    assert_eq!(
+        run(&mut session, "A2 STATUS inbox (MESSAGES UNSEEN RECENT UIDNEXT)").await,
+        "* STATUS \"INBOX\" (MESSAGES 3 UNSEEN 2 RECENT 2 UIDNEXT 4)\r\nA2 OK STATUS completed\r\n"
+    );
+    assert!(run(&mut session, "A3 STATUS Missing (MESSAGES)")
+        .await
+        .starts_with("A3 NO"));
+
+    let index = Arc::new(MetadataIndex::connect("sqlite::memory:").await.unwrap());
+    index
+        .reconcile(&email, &temp_dir.path().join(&email))
+        .await
+        .unwrap();
+    let mut session = ImapSession::new(authenticator, root).with_metadata(index.clone());
+    assert!(run(&mut session, "B1 CAPABILITY").await.contains(" LIST-STATUS "));
+    run(&mut session, "B2 LOGIN test@example.com secret").await;
+
+    // Counts come from the index, not from files it hasn't seen yet
+    fs::write(
+        temp_dir.path().join(&email).join("new/4.eml"),
+        "Subject: Test 4\r\n\r\nBody 4",
+    )
+    .unwrap();
+    assert_eq!(
+        run(&mut session, "B3 STATUS INBOX (MESSAGES UNSEEN SIZE)").await,
+        "* STATUS \"INBOX\" (MESSAGES 3 UNSEEN 2 SIZE 150)\r\nB3 OK STATUS completed\r\n"
+    );
+    index
+        .record_file(&email, "INBOX", &temp_dir.path().join(&email).join("new/4.eml"))
+        .await
+        .unwrap();
+    let listed = run(&mut session, "B4 LIST \"\" * RETURN (STATUS (MESSAGES UNSEEN))").await;
+    assert!(listed.contains(
+        "* LIST () \"/\" \"INBOX\"\r\n* STATUS \"INBOX\" (MESSAGES 4 UNSEEN 3)\r\n"
+    ));
+    assert!(listed.contains("* STATUS \"Sent\" (MESSAGES 0 UNSEEN 0)\r\n"));
+    assert!(listed.ends_with("B4 OK LIST completed\r\n"));
+}
+
+/// Send `commands` and read responses up to the tagged reply to `last_tag`
+async fn exchange<S>(stream: &mut tokio::io::BufReader<S>, commands: &str, last_tag: &str) -> String
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    stream.get_mut().write_all(commands.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    while !response.contains(&format!("{} ", last_tag)) {
+        if stream.read_line(&mut response).await.unwrap() == 0 {
+            break;
+        }
+    }
+    response
+}
+
+#[tokio::test]
+async fn test_compress_deflate_over_connection() {
+    use mail_rs::config::Config;
+    use mail_rs::imap::compress::DeflateStream;
+    use mail_rs::imap::ImapServer;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let (temp_dir, email) = setup_test_maildir();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+
+    let mut config = Config::default();
+    config.storage.maildir_path = temp_dir.path().to_str().unwrap().to_string();
+    let server = ImapServer::new(Arc::new(config)).with_authenticator(authenticator);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { server.serve(listener).await });
+
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut greeting = String::new();
+    stream.read_line(&mut greeting).await.unwrap();
+
+    let capability = exchange(&mut stream, "A1 CAPABILITY\r\n", "A1").await;
+    assert!(capability.contains(" COMPRESS=DEFLATE"));
+    exchange(&mut stream, "A2 LOGIN test@example.com secret\r\n", "A2").await;
+    assert_eq!(
+        exchange(&mut stream, "A3 COMPRESS DEFLATE\r\n", "A3").await,
+        "A3 OK DEFLATE active\r\n"
+    );
+
+    // Both directions are deflate-compressed from here on
+    let mut stream = BufReader::new(DeflateStream::new(stream.into_inner(), &[]));
+    let response = exchange(
+        &mut stream,
+        "A4 SELECT INBOX\r\nA5 COMPRESS DEFLATE\r\n",
+        "A5",
+    )
+    .await;
+    assert!(response.contains("* 3 EXISTS\r\n"));
+    assert!(response.contains("A4 OK"));
+    assert!(response.ends_with("A5 NO [COMPRESSIONACTIVE] DEFLATE active via COMPRESS\r\n"));
+}
+
+#[tokio::test]
+async fn test_search_charset_and_literals_over_connection() {
+    use mail_rs::config::Config;
+    use mail_rs::imap::ImapServer;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (temp_dir, email) = setup_test_maildir();
+    fs::write(
+        temp_dir.path().join(&email).join("new/4.eml"),
+        "From: dana@example.com\r\nSubject: Crème brûlée\r\n\r\nDessert",
+    )
+    .unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let authenticator = Authenticator::new(&format!("sqlite:{}", db_file.path().display()))
+        .await
+        .unwrap();
+    authenticator.add_user(&email, "secret").await.unwrap();
+
+    let mut config = Config::default();
+    config.storage.maildir_path = temp_dir.path().to_str().unwrap().to_string();
+    let server = ImapServer::new(Arc::new(config)).with_authenticator(authenticator);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { server.serve(listener).await });
+
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut greeting = String::new();
+    stream.read_line(&mut greeting).await.unwrap();
+    exchange(&mut stream, "A1 LOGIN test@example.com secret\r\n", "A1").await;
+    exchange(&mut stream, "A2 SELECT INBOX\r\n", "A2").await;
+
+    // The UTF-8 search string is sent as a literal
+    let query = "brûlée";
+    stream
+        .get_mut()
+        .write_all(format!("A3 SEARCH CHARSET UTF-8 SUBJECT {{{}}}\r\n", query.len()).as_bytes())
+        .await
+        .unwrap();
+    let mut continuation = String::new();
+    stream.read_line(&mut continuation).await.unwrap();
+    assert!(continuation.starts_with("+ "));
+    let response = exchange(&mut stream, &format!("{} UNSEEN\r\n", query), "A3").await;
+    let (untagged, tagged) = response.split_once("\r\n").unwrap();
+    assert_eq!(untagged.split_whitespace().count(), 3, "{}", response);
+    assert!(tagged.starts_with("A3 OK"));
+
+    let response = exchange(&mut stream, "A4 SEARCH OR FROM alice (FROM bob SEEN)\r\n", "A4").await;
+    assert!(response.starts_with("* SEARCH "));
+    assert_eq!(response.lines().next().unwrap().split_whitespace().count(), 3);
+
+    let response = exchange(&mut stream, "A5 SEARCH NOT SEEN LARGER 40\r\n", "A5").await;
+    assert_eq!(response.lines().next().unwrap().split_whitespace().count(), 5);
+
+    assert_eq!(
+        exchange(&mut stream, "A6 SEARCH CHARSET KOI8-R ALL\r\n", "A6").await,
+        "A6 NO [BADCHARSET (US-ASCII UTF-8)] Unsupported charset\r\n"
+    );
+    assert!(exchange(&mut stream, "A7 SEARCH SINCE yesterday\r\n", "*")
+        .await
+        .starts_with("* BAD"));
+}
@@ -0,0 +1,102 @@
+//! Integration tests for demo data seeding
+
+use mail_rs::caldav::CalDavManager;
+use mail_rs::security::Authenticator;
+use mail_rs::seed::{self, DemoData, SeedOptions, DEMO_COLLECTION};
+use mail_rs::storage::{MaildirStorage, Storage};
+use sqlx::SqlitePool;
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_seed_mailboxes_users_and_caldav() {
+    let dir = TempDir::new().unwrap();
+    let database = format!("sqlite://{}/seed.db?mode=rwc", dir.path().display());
+    let storage = MaildirStorage::new(dir.path().join("mail").display().to_string());
+    let auth = Authenticator::new(&database).await.unwrap();
+    let caldav = CalDavManager::new(SqlitePool::connect(&database).await.unwrap());
+    caldav.init_db().await.unwrap();
+
+    let data = DemoData::generate(&SeedOptions::default());
+    let report = seed::seed(&data, &storage, Some(&auth), Some(&caldav))
+        .await
+        .unwrap();
+    assert_eq!(report.users_created, 3);
+    assert_eq!(report.messages, data.messages.len());
+
+    for user in &data.users {
+        assert!(auth
+            .authenticate(&user.email, "demo-password")
+            .await
+            .unwrap());
+
+        let ids = storage.list_messages(&user.email).await.unwrap();
+        let expected = data
+            .messages
+            .iter()
+            .filter(|message| message.user == user.email)
+            .count();
+        assert_eq!(ids.len(), expected);
+        assert!(ids.iter().any(|id| id.starts_with(".Junk/")));
+        assert!(ids
+            .iter()
+            .any(|id| id.starts_with(".Sent/cur/") && id.contains(":2,S")));
+
+        let calendars = caldav.list_calendars(&user.email).await.unwrap();
+        assert_eq!(calendars.len(), 1);
+        assert_eq!(calendars[0].name, DEMO_COLLECTION);
+        assert_eq!(caldav.list_events(&calendars[0].id).await.unwrap().len(), 3);
+
+        let addressbooks = caldav.list_addressbooks(&user.email).await.unwrap();
+        let contacts = caldav.list_contacts(&addressbooks[0].id).await.unwrap();
+        assert_eq!(addressbooks.len(), 1);
+        for other in data.users.iter().filter(|other| other.email != user.email) {
+            assert!(contacts
+                .iter()
+                .any(|contact| contact.email.as_deref() == Some(other.email.as_str())));
+        }
+    }
+
+    // Seeding again leaves the mailboxes alone
+    let again = seed::seed(&data, &storage, Some(&auth), Some(&caldav))
+        .await
+        .unwrap();
+    assert_eq!(again.users_skipped, 3);
+    assert_eq!(again.messages, 0);
+    assert_eq!(
+        caldav
+            .list_calendars(&data.users[0].email)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_seed_is_reproducible_on_disk() {
+    let options = SeedOptions {
+        seed: 1234,
+        users: 2,
+        conversations: 3,
+        ..Default::default()
+    };
+
+    let mut contents = Vec::new();
+    for _ in 0..2 {
+        let dir = TempDir::new().unwrap();
+        let storage = MaildirStorage::new(dir.path().display().to_string());
+        let data = DemoData::generate(&options);
+        seed::seed(&data, &storage, None, None).await.unwrap();
+
+        let mut messages = Vec::new();
+        for user in &data.users {
+            for id in storage.list_messages(&user.email).await.unwrap() {
+                messages.push(storage.read_message(&user.email, &id).await.unwrap());
+            }
+        }
+        messages.sort();
+        contents.push(messages);
+    }
+    assert!(!contents[0].is_empty());
+    assert_eq!(contents[0], contents[1]);
+}
@@ -12,6 +12,13 @@ use tokio::net::TcpStream;
 /// Helper function to start SMTP server with AUTH enabled
 async fn start_test_server_with_auth(
     port: u16,
+) -> Result<(tokio::task::JoinHandle<()>, Arc<Authenticator>), Box<dyn std::error::Error>> {
+    start_test_server(port, true).await
+}
+
+async fn start_test_server(
+    port: u16,
+    allow_cram_md5: bool,
 ) -> Result<(tokio::task::JoinHandle<()>, Arc<Authenticator>), Box<dyn std::error::Error>> {
     let tempdir = tempfile::tempdir()?;
     let maildir_path = tempdir.path().join("maildir");
@@ -30,10 +37,11 @@ async fn start_test_server_with_auth(
     config.smtp.enable_auth = true;
     config.smtp.auth_database_url = Some(db_url.clone());
     config.smtp.require_auth = true;
+    config.smtp.allow_cram_md5 = allow_cram_md5;
     config.storage.maildir_path = maildir_path.to_str().unwrap().to_string();
 
     // Initialize authenticator and add test user
-    let authenticator = Arc::new(Authenticator::new(&db_url).await?);
+    let authenticator = Arc::new(Authenticator::new(&db_url).await?.with_cram_md5(allow_cram_md5));
     authenticator
         .add_user("testuser@example.com", "testpass123")
         .await?;
@@ -293,7 +301,7 @@ async fn test_auth_unknown_mechanism() {
     }
 
     // Try unsupported mechanism
-    write_line(&mut write_half, "AUTH CRAM-MD5").await.unwrap();
+    write_line(&mut write_half, "AUTH NTLM").await.unwrap();
 
     let response = read_line(&mut reader).await;
     assert!(
@@ -305,3 +313,90 @@ async fn test_auth_unknown_mechanism() {
     // Clean up
     write_line(&mut write_half, "QUIT").await.unwrap();
 }
+
+#[tokio::test]
+async fn test_auth_cram_md5_success() {
+    let port = 5030;
+    let (_handle, _auth) = start_test_server_with_auth(port).await.unwrap();
+
+    let stream = connect_to_server(port).await.unwrap();
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    // Read greeting
+    read_line(&mut reader).await;
+
+    // Send EHLO
+    write_line(&mut write_half, "EHLO test.client").await.unwrap();
+
+    // Read EHLO response
+    let mut cram_advertised = false;
+    loop {
+        let line = read_line(&mut reader).await;
+        if line.contains("CRAM-MD5") {
+            cram_advertised = true;
+        }
+        if line.starts_with("250 ") {
+            break;
+        }
+    }
+    assert!(cram_advertised, "CRAM-MD5 should be advertised in EHLO");
+
+    write_line(&mut write_half, "AUTH CRAM-MD5").await.unwrap();
+
+    // Server sends base64 challenge
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("334 "), "Expected 334 challenge, got: {}", response);
+    let challenge = BASE64.decode(response[4..].trim()).unwrap();
+    let challenge = String::from_utf8(challenge).unwrap();
+
+    let digest = mail_rs::security::sasl::cram_md5_digest(b"testpass123", &challenge);
+    let answer = BASE64.encode(format!("testuser@example.com {}", digest));
+    write_line(&mut write_half, &answer).await.unwrap();
+
+    let response = read_line(&mut reader).await;
+    assert!(
+        response.starts_with("235"),
+        "Expected 235 Authentication successful, got: {}",
+        response
+    );
+
+    // Clean up
+    write_line(&mut write_half, "QUIT").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_auth_cram_md5_disabled() {
+    let port = 5033;
+    let (_handle, _auth) = start_test_server(port, false).await.unwrap();
+
+    let stream = connect_to_server(port).await.unwrap();
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    read_line(&mut reader).await;
+    write_line(&mut write_half, "EHLO test.client").await.unwrap();
+
+    let mut auth_line = String::new();
+    loop {
+        let line = read_line(&mut reader).await;
+        if line.contains("AUTH ") {
+            auth_line = line.clone();
+        }
+        if line.starts_with("250 ") {
+            break;
+        }
+    }
+    assert!(auth_line.contains("SCRAM-SHA-256"), "Got: {}", auth_line);
+    assert!(
+        !auth_line.split_whitespace().any(|m| m == "CRAM-MD5"),
+        "Got: {}",
+        auth_line
+    );
+
+    write_line(&mut write_half, "AUTH CRAM-MD5").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("504"), "Expected 504, got: {}", response);
+
+    write_line(&mut write_half, "QUIT").await.unwrap();
+}
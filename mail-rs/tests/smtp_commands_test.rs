@@ -1,4 +1,4 @@
-use mail_rs::smtp::SmtpCommand;
+use mail_rs::smtp::{MailParameters, SmtpCommand};
 
 #[test]
 fn test_parse_helo() {
@@ -21,13 +21,13 @@ fn test_parse_helo_case_insensitive() {
 #[test]
 fn test_parse_mail_from() {
     let cmd = SmtpCommand::parse("MAIL FROM:<sender@example.com>").unwrap();
-    assert_eq!(cmd, SmtpCommand::MailFrom("sender@example.com".to_string()));
+    assert_eq!(cmd, SmtpCommand::MailFrom("sender@example.com".to_string(), MailParameters::default()));
 }
 
 #[test]
 fn test_parse_mail_from_no_brackets() {
     let cmd = SmtpCommand::parse("MAIL FROM:sender@example.com").unwrap();
-    assert_eq!(cmd, SmtpCommand::MailFrom("sender@example.com".to_string()));
+    assert_eq!(cmd, SmtpCommand::MailFrom("sender@example.com".to_string(), MailParameters::default()));
 }
 
 #[test]
@@ -31,6 +31,7 @@ async fn start_test_server() -> SocketAddr {
                     dkim_selector: "".to_string(),
                     dkim_private_key_path: "".to_string(),
                     dkim_validate_incoming: false,
+                    auth_cache_ttl_secs: 0,
                 };
                 let session = mail_rs::smtp::SmtpSession::new(
                     "test.localhost".to_string(),
@@ -303,3 +304,1445 @@ async fn test_smtp_too_many_recipients() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_submission_requires_tls_before_mail_from() {
+    use mail_rs::quota::QuotaManager;
+    use mail_rs::smtp::SmtpQueue;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let queue = Arc::new(SmtpQueue::new("sqlite::memory:").await.unwrap());
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "test.localhost".to_string(),
+            Arc::new(mail_rs::storage::MaildirStorage::new("/tmp/test-maildir".to_string())),
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_submission(queue, None, Arc::new(QuotaManager::new()));
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let _greeting = read_line(&mut reader).await;
+
+    write_line(&mut writer, "EHLO test.client").await.unwrap();
+    loop {
+        let line = read_line(&mut reader).await;
+        if line.starts_with("250 ") {
+            break;
+        }
+    }
+
+    write_line(&mut writer, "MAIL FROM:<sender@example.com>").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("530"), "Expected 530, got: {}", response);
+}
+
+/// Start a single-connection SMTP session with a greet pause
+async fn start_greet_pause_server(pause: Duration) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "test.localhost".to_string(),
+            Arc::new(mail_rs::storage::MaildirStorage::new("/tmp/test-maildir".to_string())),
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_greet_pause(pause);
+        let _ = session.handle(socket).await;
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_greet_pause_drops_early_talker() {
+    let addr = start_greet_pause_server(Duration::from_millis(500)).await;
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    // Talk before the greeting
+    write_line(&mut writer, "EHLO spam.bot").await.unwrap();
+
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("554"), "Expected 554, got: {}", response);
+
+    // Closed, possibly with a reset since the early input was never read
+    let mut line = String::new();
+    let closed = matches!(reader.read_line(&mut line).await, Ok(0) | Err(_));
+    assert!(closed, "Expected connection to be closed, got: {}", line);
+}
+
+#[tokio::test]
+async fn test_greet_pause_greets_patient_client() {
+    let addr = start_greet_pause_server(Duration::from_millis(200)).await;
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let greeting = read_line(&mut reader).await;
+    assert!(greeting.starts_with("220"), "Expected 220 greeting, got: {}", greeting);
+
+    write_line(&mut writer, "HELO test.client").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+}
+
+#[tokio::test]
+async fn test_smtp_size_parameter() {
+    use mail_rs::quota::{QuotaManager, UserQuota};
+
+    let quotas = Arc::new(QuotaManager::new());
+    quotas
+        .set_quota(UserQuota {
+            storage_limit: 2000,
+            storage_used: 1500,
+            ..UserQuota::new("full@example.com".to_string())
+        })
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "test.localhost".to_string(),
+            Arc::new(mail_rs::storage::MaildirStorage::new("/tmp/test-maildir".to_string())),
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_recipient_quotas(quotas);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO test.client").await.unwrap();
+    read_line(&mut reader).await;
+
+    // Larger than the server accepts: refused before any data is sent
+    write_line(&mut writer, "MAIL FROM:<sender@example.com> SIZE=20000000").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("552 5.3.4"), "Expected 552, got: {}", response);
+
+    write_line(&mut writer, "MAIL FROM:<sender@example.com> SIZE=1000").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    // Doesn't fit in the remaining quota
+    write_line(&mut writer, "RCPT TO:<full@example.com>").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("452 4.2.2"), "Expected 452, got: {}", response);
+
+    write_line(&mut writer, "RCPT TO:<other@example.com>").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+}
+
+#[tokio::test]
+async fn test_smtp_delivery_quota() {
+    use mail_rs::quota::{QuotaManager, UserQuota};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let quotas = Arc::new(QuotaManager::new());
+    quotas
+        .set_quota(UserQuota {
+            storage_limit: 2000,
+            storage_used: 2000,
+            ..UserQuota::new("full@example.com".to_string())
+        })
+        .await
+        .unwrap();
+    quotas
+        .set_quota(UserQuota {
+            message_limit_daily: 1,
+            ..UserQuota::new("busy@example.com".to_string())
+        })
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_recipient_quotas(quotas);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO test.client").await.unwrap();
+    read_line(&mut reader).await;
+
+    // A full mailbox is refused even without a declared size
+    write_line(&mut writer, "MAIL FROM:<sender@example.com>").await.unwrap();
+    read_line(&mut reader).await;
+    write_line(&mut writer, "RCPT TO:<full@example.com>").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("452 4.2.2"), "Expected 452, got: {}", response);
+
+    write_line(&mut writer, "RCPT TO:<busy@example.com>").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+    write_line(&mut writer, "DATA").await.unwrap();
+    read_line(&mut reader).await;
+    write_line(&mut writer, "Subject: one\r\n\r\nbody\r\n.").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    // The daily delivery limit is used up
+    write_line(&mut writer, "MAIL FROM:<sender@example.com>").await.unwrap();
+    read_line(&mut reader).await;
+    write_line(&mut writer, "RCPT TO:<busy@example.com>").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("452"), "Expected 452, got: {}", response);
+}
+
+#[tokio::test]
+async fn test_trace_headers_on_delivery() {
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        );
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+    for command in [
+        "MAIL FROM:<sender@example.org>",
+        "RCPT TO:<bob@example.com>",
+        "DATA",
+    ] {
+        write_line(&mut writer, command).await.unwrap();
+        read_line(&mut reader).await;
+    }
+    write_line(&mut writer, "Subject: Trace\r\n\r\nHello\r\n.").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    let new = maildir.path().join("bob@example.com").join("new");
+    let entry = std::fs::read_dir(new).unwrap().next().unwrap().unwrap();
+    let message = std::fs::read_to_string(entry.path()).unwrap();
+    assert!(message.starts_with(
+        "Return-Path: <sender@example.org>\r\n\
+         Delivered-To: bob@example.com\r\n\
+         X-Mail-Ingress: listener=mx; protocol=SMTP; ip=127.0.0.1\r\n\
+         Received: from client.example.org ([127.0.0.1])\r\n\
+         \tby mx.test.localhost (mail-rs) with SMTP id "
+    ));
+    assert!(message.contains("\r\n\tfor <bob@example.com>;\r\n\t"));
+    assert!(message.ends_with("Subject: Trace\r\n\r\nHello\r\n"));
+}
+
+#[tokio::test]
+async fn test_ingress_header_replaces_forged_ones() {
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        );
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+    for command in [
+        "MAIL FROM:<sender@example.org>",
+        "RCPT TO:<bob@example.com>",
+        "DATA",
+    ] {
+        write_line(&mut writer, command).await.unwrap();
+        read_line(&mut reader).await;
+    }
+    write_line(
+        &mut writer,
+        "X-Mail-Ingress: listener=submission; protocol=ESMTPSA;\r\n\tauth=ceo@example.com\r\n\
+         Subject: Ingress\r\n\r\nHello\r\n.",
+    )
+    .await
+    .unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    let new = maildir.path().join("bob@example.com").join("new");
+    let entry = std::fs::read_dir(new).unwrap().next().unwrap().unwrap();
+    let message = std::fs::read(entry.path()).unwrap();
+    assert_eq!(String::from_utf8_lossy(&message).matches("X-Mail-Ingress:").count(), 1);
+    assert!(!String::from_utf8_lossy(&message).contains("ceo@example.com"));
+
+    let ingress = mail_rs::smtp::Ingress::from_message(&message).unwrap();
+    assert_eq!(ingress.listener, mail_rs::smtp::IngressListener::Mx);
+    assert_eq!(ingress.protocol, "SMTP");
+    assert_eq!(ingress.ip, Some("127.0.0.1".parse().unwrap()));
+    assert_eq!(ingress.auth, None);
+    assert_eq!(ingress.tls, None);
+}
+
+#[tokio::test]
+async fn test_mail_loop_rejected() {
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_loop_detection(2, None);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    let hop = "Received: from a by relay.example.org; Mon, 1 Jan 2024 00:00:00 +0000\r\n";
+    let messages = [
+        // Already delivered here for bob, e.g. forwarded back by a remote alias
+        ("Delivered-To: bob@example.com\r\nSubject: Loop\r\n\r\nHi\r\n.".to_string(), "554 5.4.6"),
+        (format!("{}{}{}Subject: Hops\r\n\r\nHi\r\n.", hop, hop, hop), "554 5.4.6"),
+        (format!("{}{}Subject: Fine\r\n\r\nHi\r\n.", hop, hop), "250"),
+    ];
+    for (message, expected) in messages {
+        for command in [
+            "MAIL FROM:<sender@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, &message).await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with(expected), "Expected {}, got: {}", expected, response);
+    }
+
+    let new = maildir.path().join("bob@example.com").join("new");
+    assert_eq!(std::fs::read_dir(new).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn test_single_instance_delivery_stored_once() {
+    use std::os::unix::fs::MetadataExt;
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(
+        mail_rs::storage::MaildirStorage::new(maildir.path().display().to_string())
+            .with_single_instance(),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_loop_detection(2, None)
+        .with_delivery_log(Arc::new(mail_rs::smtp::loop_detection::DeliveryLog::default()));
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    let users = ["bob@example.com", "carol@example.com", "dave@example.com"];
+    write_line(&mut writer, "MAIL FROM:<sender@example.org>").await.unwrap();
+    read_line(&mut reader).await;
+    for user in users {
+        write_line(&mut writer, &format!("RCPT TO:<{}>", user)).await.unwrap();
+        read_line(&mut reader).await;
+    }
+    write_line(&mut writer, "DATA").await.unwrap();
+    read_line(&mut reader).await;
+    write_line(&mut writer, "Subject: List\r\n\r\nHi\r\n.").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    let copies: Vec<_> = users
+        .iter()
+        .map(|user| {
+            let new = maildir.path().join(user).join("new");
+            let entries: Vec<_> = std::fs::read_dir(new).unwrap().collect();
+            assert_eq!(entries.len(), 1);
+            entries[0].as_ref().unwrap().path()
+        })
+        .collect();
+    let inode = std::fs::metadata(&copies[0]).unwrap().ino();
+    for copy in &copies {
+        assert_eq!(std::fs::metadata(copy).unwrap().ino(), inode);
+    }
+    let blobs: Vec<_> = std::fs::read_dir(maildir.path().join(".sis"))
+        .unwrap()
+        .map(|dir| dir.unwrap().path())
+        .filter(|dir| !dir.ends_with("tmp"))
+        .flat_map(|dir| std::fs::read_dir(dir).unwrap())
+        .map(|blob| blob.unwrap().path())
+        .collect();
+    assert_eq!(blobs.len(), 1);
+    let blob = std::fs::metadata(&blobs[0]).unwrap();
+    assert_eq!(blob.ino(), inode);
+    assert_eq!(blob.nlink(), users.len() as u64 + 1);
+
+    // Forwarded back by a remote alias: the delivery log catches it
+    let message = std::fs::read_to_string(&copies[0]).unwrap();
+    assert!(!message.contains("Delivered-To:"), "{}", message);
+    for command in [
+        "MAIL FROM:<sender@example.org>",
+        "RCPT TO:<bob@example.com>",
+        "DATA",
+    ] {
+        write_line(&mut writer, command).await.unwrap();
+        read_line(&mut reader).await;
+    }
+    write_line(&mut writer, &format!("{}.", message)).await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("554 5.4.6"), "Expected 554, got: {}", response);
+}
+
+#[tokio::test]
+async fn test_impersonating_display_name_quarantined() {
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let guard = mail_rs::antispam::ImpersonationGuard::connect("sqlite::memory:")
+        .await
+        .unwrap();
+    guard.add_name("example.com", "Jane Doe").await.unwrap();
+    guard.set_quarantine("example.com", true).await.unwrap();
+    let guard = Arc::new(guard);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_impersonation_guard(guard);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    let messages = [
+        "From: \"Jane Doe\" <ceo@freemail.example.org>\r\nSubject: Wire\r\n\r\nPay now\r\n.",
+        "From: Jane Doe <jane@example.com>\r\nSubject: Lunch\r\n\r\nNoon?\r\n.",
+    ];
+    for message in messages {
+        for command in [
+            "MAIL FROM:<sender@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, message).await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+    }
+
+    let mailbox = maildir.path().join("bob@example.com");
+    let junk = std::fs::read_dir(mailbox.join(".Junk/new")).unwrap();
+    let [entry] = junk.collect::<Vec<_>>().try_into().unwrap();
+    let message = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+    assert!(message.contains(
+        "X-Impersonation-Warning: display name \"Jane Doe\" matches Jane Doe of example.com"
+    ));
+    assert!(message.contains("Subject: Wire"));
+
+    let inbox: Vec<_> = std::fs::read_dir(mailbox.join("new")).unwrap().collect();
+    assert_eq!(inbox.len(), 1);
+    let message = std::fs::read_to_string(inbox[0].as_ref().unwrap().path()).unwrap();
+    assert!(!message.contains("X-Impersonation-Warning"));
+}
+
+#[tokio::test]
+async fn test_spam_scored_on_delivery() {
+    use mail_rs::spam::{SpamFilter, SpamManager};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let manager = SpamManager::connect("sqlite::memory:").await.unwrap();
+    let filter = Arc::new(SpamFilter::new(Arc::new(manager)).with_reject_threshold(12.0));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_spam_filter(filter);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    let messages = [
+        ("Subject: You are a winner\r\n\r\nBuy viagra\r\n.", "250"),
+        (
+            "X-Spam-Status: No, score=-99\r\nSubject: Lunch\r\n\r\nNoon?\r\n.",
+            "250",
+        ),
+        ("Subject: Hi\r\n\r\nviagra cialis from a nigerian prince\r\n.", "550"),
+    ];
+    for (message, expected) in messages {
+        for command in [
+            "MAIL FROM:<sender@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, message).await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(
+            response.starts_with(expected),
+            "Expected {}, got: {}",
+            expected,
+            response
+        );
+    }
+
+    let mailbox = maildir.path().join("bob@example.com");
+    let junk = std::fs::read_dir(mailbox.join(".Junk/new")).unwrap();
+    let [entry] = junk.collect::<Vec<_>>().try_into().unwrap();
+    let message = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+    assert!(message.contains("X-Spam-Score: 7.0\r\n"));
+    assert!(message.contains(
+        "X-Spam-Status: Yes, score=7.0 required=5.0\r\n\ttests=SUBJECT_WINNER,BODY_VIAGRA\r\n"
+    ));
+
+    let inbox: Vec<_> = std::fs::read_dir(mailbox.join("new")).unwrap().collect();
+    assert_eq!(inbox.len(), 1);
+    let message = std::fs::read_to_string(inbox[0].as_ref().unwrap().path()).unwrap();
+    assert_eq!(message.matches("X-Spam-Status:").count(), 1);
+    assert!(message.contains("X-Spam-Status: No, score=0.0 required=5.0"));
+    assert!(!message.contains("-99"));
+}
+
+#[tokio::test]
+async fn test_slow_spam_check_defers_under_delivery_budget() {
+    use mail_rs::config::RspamdConfig;
+    use mail_rs::smtp::budget::{BudgetLimits, DeliveryBudget};
+    use mail_rs::spam::{RspamdClient, SpamFilter, SpamManager};
+
+    // An rspamd worker that accepts checks but never answers
+    let rspamd = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rspamd_url = format!("http://{}", rspamd.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = rspamd.accept().await {
+            held.push(socket);
+        }
+    });
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let manager = SpamManager::connect("sqlite::memory:").await.unwrap();
+    let filter = Arc::new(
+        SpamFilter::new(Arc::new(manager)).with_rspamd(RspamdClient::from_config(&RspamdConfig {
+            url: rspamd_url,
+            ..Default::default()
+        })),
+    );
+    let budget = Arc::new(DeliveryBudget::new(BudgetLimits {
+        stage_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    }));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session_budget = budget.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_spam_filter(filter)
+        .with_delivery_budget(session_budget);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+    for command in [
+        "MAIL FROM:<sender@example.org>",
+        "RCPT TO:<bob@example.com>",
+        "DATA",
+    ] {
+        write_line(&mut writer, command).await.unwrap();
+        read_line(&mut reader).await;
+    }
+    write_line(
+        &mut writer,
+        "X-Spam-Status: No, score=-99\r\nSubject: Lunch\r\n\r\nNoon?\r\n.",
+    )
+    .await
+    .unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(3), read_line(&mut reader))
+        .await
+        .expect("the spam check held up the reply to DATA");
+    assert!(response.starts_with("451 4.7.1"), "Expected 451, got: {}", response);
+
+    // Never delivered unchecked
+    assert!(!maildir.path().join("bob@example.com").exists());
+    assert_eq!(budget.heaviest(1)[0].timeouts, 1);
+}
+
+#[tokio::test]
+async fn test_spam_checked_when_delivery_budget_exhausted() {
+    use mail_rs::smtp::budget::{BudgetLimits, DeliveryBudget, StageOutcome};
+    use mail_rs::spam::{SpamFilter, SpamManager};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let manager = SpamManager::connect("sqlite::memory:").await.unwrap();
+    let filter = Arc::new(SpamFilter::new(Arc::new(manager)).with_reject_threshold(12.0));
+    let budget = Arc::new(DeliveryBudget::new(BudgetLimits {
+        user_time_per_minute: Some(Duration::from_millis(10)),
+        ..Default::default()
+    }));
+    // Bob's mail already used up his stage time
+    let bob = ["bob@example.com".to_string()];
+    let outcome = budget.run_stage(&bob, "sieve", sleep(Duration::from_millis(20))).await;
+    assert_eq!(outcome, StageOutcome::Done(()));
+    assert!(budget.over_budget("bob@example.com"));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session_budget = budget.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_spam_filter(filter)
+        .with_delivery_budget(session_budget);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    let messages = [
+        ("Subject: Hi\r\n\r\nviagra cialis from a nigerian prince\r\n.", "550"),
+        ("Subject: Lunch\r\n\r\nNoon?\r\n.", "250"),
+    ];
+    for (message, expected) in messages {
+        for command in [
+            "MAIL FROM:<sender@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, message).await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with(expected), "Expected {}, got: {}", expected, response);
+    }
+
+    let inbox: Vec<_> = std::fs::read_dir(maildir.path().join("bob@example.com/new"))
+        .unwrap()
+        .collect();
+    assert_eq!(inbox.len(), 1);
+    let message = std::fs::read_to_string(inbox[0].as_ref().unwrap().path()).unwrap();
+    assert!(message.contains("X-Spam-Status: No, score=0.0 required=5.0"));
+    assert!(!message.contains("spam check skipped"));
+}
+
+#[tokio::test]
+async fn test_greylisting_defers_until_retry() {
+    use mail_rs::antispam::greylist::{GreylistConfig, GreylistManager};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let config = GreylistConfig {
+        delay_seconds: 0,
+        ..Default::default()
+    };
+    let manager = Arc::new(
+        GreylistManager::connect_with("sqlite::memory:", config)
+            .await
+            .unwrap(),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_greylist(manager);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    // First attempt deferred, the retry accepted
+    for expected in ["451 4.7.1", "250"] {
+        for command in [
+            "MAIL FROM:<sender@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, "Subject: Hi\r\n\r\nHello\r\n.").await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(
+            response.starts_with(expected),
+            "Expected {}, got: {}",
+            expected,
+            response
+        );
+    }
+
+    let inbox: Vec<_> = std::fs::read_dir(maildir.path().join("bob@example.com/new"))
+        .unwrap()
+        .collect();
+    assert_eq!(inbox.len(), 1);
+}
+
+#[tokio::test]
+async fn test_role_accounts_delivery() {
+    use mail_rs::role_accounts::RoleAccountManager;
+    use mail_rs::spam::{SpamFilter, SpamManager};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let roles = RoleAccountManager::connect("sqlite::memory:")
+        .await
+        .unwrap()
+        .with_mailbox("admin@example.com");
+    roles.provision("example.com").await.unwrap();
+    let roles = Arc::new(roles);
+    let spam = SpamManager::connect("sqlite::memory:").await.unwrap();
+    let filter = Arc::new(SpamFilter::new(Arc::new(spam)).with_reject_threshold(6.0));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session_roles = roles.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_role_accounts(session_roles)
+        .with_spam_filter(filter);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    // Above the hard threshold, but abuse@ must still get it
+    let messages = [
+        (
+            ["RCPT TO:<abuse@example.com>", "RCPT TO:<bob@example.com>"].as_slice(),
+            "Subject: You are a winner\r\n\r\nBuy viagra\r\n.",
+        ),
+        (
+            ["RCPT TO:<PostMaster@example.com>"].as_slice(),
+            "Subject: Bounce\r\n\r\nYour server rejected our mail\r\n.",
+        ),
+    ];
+    for (recipients, message) in messages {
+        write_line(&mut writer, "MAIL FROM:<sender@example.org>").await.unwrap();
+        read_line(&mut reader).await;
+        for rcpt in recipients {
+            write_line(&mut writer, rcpt).await.unwrap();
+            let response = read_line(&mut reader).await;
+            assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+        }
+        write_line(&mut writer, "DATA").await.unwrap();
+        read_line(&mut reader).await;
+        write_line(&mut writer, message).await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+    }
+
+    let read = |path: &str| -> Vec<String> {
+        std::fs::read_dir(maildir.path().join(path))
+            .map(|entries| {
+                entries
+                    .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let admin = read("admin@example.com/new");
+    assert_eq!(admin.len(), 2);
+    let tagged = admin.iter().find(|m| m.contains("Subject: You are a winner")).unwrap();
+    assert!(tagged.contains("Delivered-To: abuse@example.com\r\n"));
+    assert!(tagged.contains("X-Spam-Status: Yes, score=7.0"));
+    assert!(admin.iter().any(|m| m.contains("Delivered-To: PostMaster@example.com\r\n")));
+    assert_eq!(read("bob@example.com/.Junk/new").len(), 1);
+    assert!(read("bob@example.com/new").is_empty());
+    assert!(read("abuse@example.com/new").is_empty());
+
+    let today = chrono::Utc::now().date_naive();
+    let abuse = roles.volume("abuse@example.com", today).await.unwrap();
+    assert_eq!((abuse[0].messages, abuse[0].spam), (1, 1));
+    let postmaster = roles.volume("postmaster@example.com", today).await.unwrap();
+    assert_eq!((postmaster[0].messages, postmaster[0].spam), (1, 0));
+}
+
+#[tokio::test]
+async fn test_burner_alias_delivery() {
+    use mail_rs::aliases::{AliasManager, AliasUpdate};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let aliases = Arc::new(AliasManager::connect("sqlite::memory:").await.unwrap());
+    let shop = aliases
+        .create("bob@example.com", Some("shop"), "example.com", None, None)
+        .await
+        .unwrap();
+    let news = aliases
+        .create("bob@example.com", Some("news"), "example.com", None, None)
+        .await
+        .unwrap();
+    let leaked = aliases
+        .create("bob@example.com", Some("leaked"), "example.com", None, None)
+        .await
+        .unwrap();
+    let mute = AliasUpdate {
+        muted: Some(true),
+        ..Default::default()
+    };
+    aliases.update("bob@example.com", &news.address, &mute).await.unwrap();
+    let block = AliasUpdate {
+        blocked: Some(true),
+        ..Default::default()
+    };
+    aliases.update("bob@example.com", &leaked.address, &block).await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session_aliases = aliases.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_aliases(session_aliases);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    write_line(&mut writer, "MAIL FROM:<promo@shop.example.org>").await.unwrap();
+    read_line(&mut reader).await;
+    for (alias, expected) in [(&shop, "250"), (&news, "250"), (&leaked, "550")] {
+        write_line(&mut writer, &format!("RCPT TO:<{}>", alias.address))
+            .await
+            .unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with(expected), "Expected {}, got: {}", expected, response);
+    }
+    write_line(&mut writer, "DATA").await.unwrap();
+    read_line(&mut reader).await;
+    write_line(&mut writer, "Subject: Deals\r\n\r\n50% off\r\n.").await.unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    // Only the active alias reaches the owner's inbox
+    let inbox: Vec<_> = std::fs::read_dir(maildir.path().join("bob@example.com/new"))
+        .unwrap()
+        .collect();
+    assert_eq!(inbox.len(), 1);
+    let message = std::fs::read_to_string(inbox[0].as_ref().unwrap().path()).unwrap();
+    assert!(message.contains(&format!("Delivered-To: {}", shop.address)));
+    assert!(!maildir.path().join(&shop.address).exists());
+    assert!(!maildir.path().join(&news.address).exists());
+
+    for (alias, messages, rejected) in [(&shop, 1, 0), (&news, 1, 0), (&leaked, 0, 1)] {
+        let stats = aliases
+            .stats("bob@example.com", &alias.address)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((stats.messages, stats.rejected), (messages, rejected));
+        assert_eq!(stats.senders[0].sender, "promo@shop.example.org");
+    }
+}
+
+#[tokio::test]
+async fn test_sieve_filters_delivery() {
+    use mail_rs::sieve::{CreateSieveScriptRequest, SieveManager};
+    use mail_rs::smtp::routing::RoutingTable;
+    use mail_rs::smtp::SmtpQueue;
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let sieve = Arc::new(SieveManager::connect("sqlite::memory:").await.unwrap());
+    let script = r#"
+        require ["fileinto", "imap4flags"];
+        if header :contains "subject" "digest" {
+            fileinto "Lists";
+            addflag "\\Seen";
+        } elsif header :contains "subject" "invoice" {
+            redirect "accounts@example.org";
+        } elsif header :contains "subject" "casino" {
+            discard;
+        }
+    "#;
+    sieve
+        .create_script(
+            "bob@example.com",
+            &CreateSieveScriptRequest {
+                name: "filters".to_string(),
+                script_content: script.to_string(),
+                activate: true,
+            },
+        )
+        .await
+        .unwrap();
+    let queue = Arc::new(SmtpQueue::new("sqlite::memory:").await.unwrap());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session_queue = queue.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_routing(Arc::new(RoutingTable::new(&[])), Some(session_queue))
+        .with_sieve(sieve);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    for subject in ["Weekly digest", "Invoice 42", "Casino bonus", "Lunch"] {
+        for command in [
+            "MAIL FROM:<sender@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, &format!("Subject: {}\r\n\r\nHello\r\n.", subject))
+            .await
+            .unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+    }
+
+    let mailbox = maildir.path().join("bob@example.com");
+    let read = |dir: &str| -> Vec<(String, String)> {
+        std::fs::read_dir(mailbox.join(dir))
+            .map(|entries| {
+                entries
+                    .map(|entry| {
+                        let path = entry.unwrap().path();
+                        let name = path.file_name().unwrap().to_string_lossy().to_string();
+                        (name, std::fs::read_to_string(&path).unwrap())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    // Filed into Lists as read, the casino mail dropped, the rest in INBOX
+    let [(name, message)] = read(".Lists/cur").try_into().unwrap();
+    assert!(name.ends_with(":2,S"), "Unexpected file name {}", name);
+    assert!(message.contains("Subject: Weekly digest"));
+    assert!(read(".Lists/new").is_empty());
+    let [(_, message)] = read("new").try_into().unwrap();
+    assert!(message.contains("Subject: Lunch"));
+    assert!(read("cur").is_empty());
+
+    let [redirected] = queue.list(None, 10).await.unwrap().try_into().unwrap();
+    assert_eq!(redirected.to_addr, "accounts@example.org");
+    assert_eq!(redirected.from_addr, "sender@example.org");
+    assert!(String::from_utf8_lossy(&redirected.data).contains("Subject: Invoice 42"));
+}
+
+/// Sieve variables, includes and header edits apply to the stored message
+#[tokio::test]
+async fn test_sieve_variables_include_editheader() {
+    use mail_rs::sieve::{CreateSieveScriptRequest, SieveManager, GLOBAL_SCRIPTS_OWNER};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let sieve = Arc::new(SieveManager::connect("sqlite::memory:").await.unwrap());
+    let script = |name: &str, content: &str, activate| CreateSieveScriptRequest {
+        name: name.to_string(),
+        script_content: content.to_string(),
+        activate,
+    };
+    sieve
+        .create_script(
+            GLOBAL_SCRIPTS_OWNER,
+            &script(
+                "spam",
+                r#"
+                require ["editheader"];
+                deleteheader "X-Spam-Flag";
+                addheader "X-Filtered" "global";
+                "#,
+                false,
+            ),
+        )
+        .await
+        .unwrap();
+    sieve
+        .create_script(
+            "bob@example.com",
+            &script(
+                "lists",
+                r#"
+                require ["include", "variables", "fileinto"];
+                include :global "spam";
+                if header :matches "List-Id" "*<*.example.org>" {
+                    set :lower "list" "${2}";
+                    fileinto "Lists.${list}";
+                }
+                "#,
+                true,
+            ),
+        )
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_sieve(sieve);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+    for command in [
+        "MAIL FROM:<sender@example.org>",
+        "RCPT TO:<bob@example.com>",
+        "DATA",
+    ] {
+        write_line(&mut writer, command).await.unwrap();
+        read_line(&mut reader).await;
+    }
+    write_line(
+        &mut writer,
+        "List-Id: Rust <Rust-Users.example.org>\r\nX-Spam-Flag: YES\r\nSubject: Release\r\n\r\nHello\r\n.",
+    )
+    .await
+    .unwrap();
+    let response = read_line(&mut reader).await;
+    assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+
+    let folder = maildir.path().join("bob@example.com/.Lists.rust-users/new");
+    let [entry] = std::fs::read_dir(&folder)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>()
+        .try_into()
+        .unwrap();
+    let message = std::fs::read_to_string(entry).unwrap();
+    assert!(message.starts_with("X-Filtered: global\r\n"), "{}", message);
+    assert!(!message.contains("X-Spam-Flag"));
+    assert!(message.contains("Return-Path: <sender@example.org>"));
+    assert!(message.ends_with("\r\n\r\nHello\r\n"));
+}
+
+/// A Sieve vacation answers a sender once per interval, never automated mail
+#[tokio::test]
+async fn test_sieve_vacation_responds_once() {
+    use mail_rs::auto_reply::AutoReplyManager;
+    use mail_rs::sieve::{CreateSieveScriptRequest, SieveManager};
+    use mail_rs::smtp::routing::RoutingTable;
+    use mail_rs::smtp::SmtpQueue;
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let sieve = Arc::new(SieveManager::connect("sqlite::memory:").await.unwrap());
+    sieve
+        .create_script(
+            "bob@example.com",
+            &CreateSieveScriptRequest {
+                name: "away".to_string(),
+                script_content: r#"
+                    require "vacation";
+                    vacation :days 3 "Back on Monday.";
+                "#
+                .to_string(),
+                activate: true,
+            },
+        )
+        .await
+        .unwrap();
+    let vacations = Arc::new(AutoReplyManager::connect("sqlite::memory:").await.unwrap());
+    let queue = Arc::new(SmtpQueue::new("sqlite::memory:").await.unwrap());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session_queue = queue.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_routing(Arc::new(RoutingTable::new(&[])), Some(session_queue))
+        .with_sieve(sieve)
+        .with_vacations(vacations);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    for headers in [
+        "Subject: Lunch",
+        "Subject: Lunch again",
+        "Subject: Build failed\r\nAuto-Submitted: auto-generated",
+    ] {
+        for command in [
+            "MAIL FROM:<alice@example.org>",
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        let message = format!(
+            "From: alice@example.org\r\nTo: bob@example.com\r\n{}\r\n\r\nHello\r\n.",
+            headers
+        );
+        write_line(&mut writer, &message).await.unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with("250"), "Expected 250, got: {}", response);
+    }
+
+    // Every message is delivered, only the first one answered
+    let delivered = std::fs::read_dir(maildir.path().join("bob@example.com/new"))
+        .unwrap()
+        .count();
+    assert_eq!(delivered, 3);
+
+    let [response] = queue.list(None, 10).await.unwrap().try_into().unwrap();
+    assert_eq!(response.to_addr, "alice@example.org");
+    assert_eq!(response.from_addr, "");
+    let data = String::from_utf8_lossy(&response.data);
+    assert!(data.contains("Subject: Auto: Lunch\r\n"));
+    assert!(data.contains("Auto-Submitted: auto-replied (vacation)\r\n"));
+    assert!(data.contains("Back on Monday."));
+}
+
+/// Delivery hooks tag and file mail before Sieve, or reject it
+#[tokio::test]
+async fn test_delivery_hooks() {
+    use mail_rs::hooks::{HookManager, HookScope};
+    use mail_rs::sieve::{CreateSieveScriptRequest, SieveManager};
+
+    let maildir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(mail_rs::storage::MaildirStorage::new(
+        maildir.path().display().to_string(),
+    ));
+    let hooks = Arc::new(HookManager::connect("sqlite::memory:").await.unwrap());
+    // The domain's hook tags mail and files it into Hooked
+    let tagging = wat::parse_str(
+        r#"(module
+            (import "mail" "add_header" (func $add_header (param i32 i32 i32 i32) (result i32)))
+            (import "mail" "set_folder" (func $set_folder (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "X-Hooked")
+            (data (i32.const 16) "yes")
+            (data (i32.const 32) "Hooked")
+            (func (export "on_delivery")
+                (drop (call $add_header (i32.const 0) (i32.const 8) (i32.const 16) (i32.const 3)))
+                (drop (call $set_folder (i32.const 32) (i32.const 6)))))"#,
+    )
+    .unwrap();
+    hooks
+        .upload(
+            &HookScope::Domain("example.com".to_string()),
+            &tagging,
+            "admin@example.com",
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+    // Bob's hook rejects mail from senders whose address starts with "eve"
+    let rejecting = wat::parse_str(
+        r#"(module
+            (import "mail" "sender" (func $sender (param i32 i32) (result i32)))
+            (import "mail" "reject" (func $reject (param i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "No mail from Eve")
+            (func (export "on_delivery")
+                (drop (call $sender (i32.const 100) (i32.const 64)))
+                (if (i32.eq (i32.load (i32.const 100)) (i32.const 0x40657665))
+                    (then (call $reject (i32.const 0) (i32.const 16))))))"#,
+    )
+    .unwrap();
+    hooks
+        .upload(
+            &HookScope::User("bob@example.com".to_string()),
+            &rejecting,
+            "bob@example.com",
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+    let sieve = Arc::new(SieveManager::connect("sqlite::memory:").await.unwrap());
+    sieve
+        .create_script(
+            "bob@example.com",
+            &CreateSieveScriptRequest {
+                name: "lists".to_string(),
+                script_content: r#"
+                    require "fileinto";
+                    if allof (header :is "x-hooked" "yes", header :contains "subject" "digest") {
+                        fileinto "Lists";
+                    }
+                "#
+                .to_string(),
+                activate: true,
+            },
+        )
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let session = mail_rs::smtp::SmtpSession::new(
+            "mx.test.localhost".to_string(),
+            storage,
+            10 * 1024 * 1024,
+            mail_rs::config::Config::default().authentication,
+        )
+        .with_hooks(hooks)
+        .with_sieve(sieve);
+        let _ = session.handle(socket).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    read_line(&mut reader).await;
+    write_line(&mut writer, "HELO client.example.org").await.unwrap();
+    read_line(&mut reader).await;
+
+    for (sender, subject, reply) in [
+        ("alice@example.org", "Weekly digest", "250"),
+        ("alice@example.org", "Lunch", "250"),
+        ("eve@example.org", "Hello", "550 5.7.1 No mail from Eve"),
+    ] {
+        for command in [
+            &format!("MAIL FROM:<{}>", sender),
+            "RCPT TO:<bob@example.com>",
+            "DATA",
+        ] {
+            write_line(&mut writer, command).await.unwrap();
+            read_line(&mut reader).await;
+        }
+        write_line(&mut writer, &format!("Subject: {}\r\n\r\nHello\r\n.", subject))
+            .await
+            .unwrap();
+        let response = read_line(&mut reader).await;
+        assert!(response.starts_with(reply), "Expected {}, got: {}", reply, response);
+    }
+
+    // Sieve saw the added header; its keep went to the hook's folder
+    let mailbox = maildir.path().join("bob@example.com");
+    let read = |dir: &str| -> Vec<String> {
+        std::fs::read_dir(mailbox.join(dir))
+            .map(|entries| {
+                entries
+                    .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let [message] = read(".Lists/new").try_into().unwrap();
+    assert!(message.contains("X-Hooked: yes\r\n"));
+    assert!(message.contains("Subject: Weekly digest"));
+    let [message] = read(".Hooked/new").try_into().unwrap();
+    assert!(message.contains("X-Hooked: yes\r\n"));
+    assert!(message.contains("Subject: Lunch"));
+    assert!(read("new").is_empty());
+}
@@ -9,6 +9,8 @@ use axum::{
     routing::{get, post},
     Json, Router,
 };
+use mail_rs::mime::{decode_header, MessageBuilder};
+use mail_rs::storage::{MessageMetadata, MetadataIndex};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -63,6 +65,10 @@ struct ToolParameter {
 /// Application state
 struct AppState {
     smtp_server: String,
+    /// Database holding the spam quarantine
+    database_url: String,
+    /// Message metadata kept by the mail server in the same database
+    metadata: Option<MetadataIndex>,
 }
 
 #[tokio::main]
@@ -82,8 +88,22 @@ async fn main() -> anyhow::Result<()> {
     let smtp_port = std::env::var("SMTP_PORT").unwrap_or_else(|_| "2525".to_string());
     let smtp_server = format!("{}:{}", smtp_host, smtp_port);
 
+    let database_url = std::env::var("MAIL_DATABASE_URL")
+        .unwrap_or_else(|_| "sqlite://mail-rs/data/users.db".to_string());
+
+    // Without the index every tool reads the maildir
+    let metadata = match MetadataIndex::connect(&database_url).await {
+        Ok(index) => Some(index),
+        Err(e) => {
+            warn!("Metadata index unavailable, reading the maildir: {}", e);
+            None
+        }
+    };
+
     let state = Arc::new(AppState {
         smtp_server: smtp_server.clone(),
+        database_url,
+        metadata,
     });
 
     info!("📧 Using SMTP server: {}", smtp_server);
@@ -226,7 +246,7 @@ fn handle_tools_list(id: u64) -> Result<Json<McpResponse>, (StatusCode, String)>
         },
         Tool {
             name: "mark_as_read".to_string(),
-            description: "Mark an email as read by moving it from new/ to cur/".to_string(),
+            description: "Mark an email as read, for IMAP clients and the web interface too".to_string(),
             parameters: vec![
                 ToolParameter {
                     name: "email".to_string(),
@@ -275,6 +295,63 @@ fn handle_tools_list(id: u64) -> Result<Json<McpResponse>, (StatusCode, String)>
             ],
             server: "mail".to_string(),
         },
+        Tool {
+            name: "list_quarantined".to_string(),
+            description: "List suspected spam held in quarantine for a user".to_string(),
+            parameters: vec![
+                ToolParameter {
+                    name: "email".to_string(),
+                    description: "Email address (e.g. test@example.com)".to_string(),
+                    param_type: "string".to_string(),
+                    required: true,
+                },
+                ToolParameter {
+                    name: "limit".to_string(),
+                    description: "Maximum number of messages to return (default: 20)".to_string(),
+                    param_type: "number".to_string(),
+                    required: false,
+                },
+            ],
+            server: "mail".to_string(),
+        },
+        Tool {
+            name: "release_quarantined".to_string(),
+            description: "Release a quarantined message into the user's INBOX".to_string(),
+            parameters: vec![
+                ToolParameter {
+                    name: "email".to_string(),
+                    description: "Email address (e.g. test@example.com)".to_string(),
+                    param_type: "string".to_string(),
+                    required: true,
+                },
+                ToolParameter {
+                    name: "quarantine_id".to_string(),
+                    description: "Id from list_quarantined result".to_string(),
+                    param_type: "string".to_string(),
+                    required: true,
+                },
+            ],
+            server: "mail".to_string(),
+        },
+        Tool {
+            name: "delete_quarantined".to_string(),
+            description: "Delete a quarantined message permanently".to_string(),
+            parameters: vec![
+                ToolParameter {
+                    name: "email".to_string(),
+                    description: "Email address (e.g. test@example.com)".to_string(),
+                    param_type: "string".to_string(),
+                    required: true,
+                },
+                ToolParameter {
+                    name: "quarantine_id".to_string(),
+                    description: "Id from list_quarantined result".to_string(),
+                    param_type: "string".to_string(),
+                    required: true,
+                },
+            ],
+            server: "mail".to_string(),
+        },
     ];
 
     Ok(Json(McpResponse {
@@ -308,12 +385,15 @@ async fn handle_tools_call(
 
     match tool_name {
         "send_email" => send_email_tool(state, arguments, request.id).await,
-        "list_emails" => list_emails_tool(arguments, request.id).await,
+        "list_emails" => list_emails_tool(state, arguments, request.id).await,
         "read_email" => read_email_tool(arguments, request.id).await,
-        "search_emails" => search_emails_tool(arguments, request.id).await,
+        "search_emails" => search_emails_tool(state, arguments, request.id).await,
         "mark_as_read" => mark_as_read_tool(arguments, request.id).await,
         "delete_email" => delete_email_tool(arguments, request.id).await,
-        "get_email_count" => get_email_count_tool(arguments, request.id).await,
+        "get_email_count" => get_email_count_tool(state, arguments, request.id).await,
+        "list_quarantined" => list_quarantined_tool(state, arguments, request.id).await,
+        "release_quarantined" => release_quarantined_tool(state, arguments, request.id).await,
+        "delete_quarantined" => delete_quarantined_tool(state, arguments, request.id).await,
         _ => Ok(Json(McpResponse {
             jsonrpc: "2.0".to_string(),
             result: None,
@@ -435,17 +515,15 @@ async fn send_email_smtp(
     debug!("← {}", line.trim());
 
     // Email content
-    let email_data = format!(
-        "From: AI Assistant <ai@example.com>\r\n\
-         To: <{}>\r\n\
-         Subject: {}\r\n\
-         \r\n\
-         {}\r\n\
-         .\r\n",
-        to, subject, body
-    );
-
-    writer.write_all(email_data.as_bytes()).await?;
+    let email_data = MessageBuilder::new()
+        .from("AI Assistant <ai@example.com>")
+        .to(&format!("<{}>", to))
+        .subject(subject)
+        .text(body)
+        .build();
+
+    writer.write_all(&email_data).await?;
+    writer.write_all(b"\r\n.\r\n").await?;
     line.clear();
     reader.read_line(&mut line).await?;
     debug!("← {}", line.trim());
@@ -459,8 +537,33 @@ async fn send_email_smtp(
     Ok(())
 }
 
+/// The metadata index, if it holds every message of `email`
+async fn indexed<'a>(state: &'a AppState, email: &str) -> Option<&'a MetadataIndex> {
+    let index = state.metadata.as_ref()?;
+    match index.is_indexed(email).await {
+        Ok(true) => Some(index),
+        Ok(false) => None,
+        Err(e) => {
+            warn!("Failed to check metadata index of {}: {}", email, e);
+            None
+        }
+    }
+}
+
+/// Summary of an indexed message, shaped like the maildir listings
+fn metadata_json(metadata: &MessageMetadata, email_addr: &str) -> serde_json::Value {
+    serde_json::json!({
+        "id": metadata.filename,
+        "to": email_addr,
+        "from": metadata.from,
+        "subject": metadata.subject,
+        "date": metadata.date.to_rfc2822(),
+    })
+}
+
 /// List emails tool implementation
 async fn list_emails_tool(
+    state: Arc<AppState>,
     arguments: HashMap<String, serde_json::Value>,
     id: u64,
 ) -> Result<Json<McpResponse>, (StatusCode, String)> {
@@ -485,7 +588,20 @@ async fn list_emails_tool(
 
     let mut all_emails = Vec::new();
 
-    if let Some(email) = email_filter {
+    let index = match email_filter {
+        Some(email) => indexed(&state, email).await,
+        None => None,
+    };
+
+    if let (Some(index), Some(email)) = (index, email_filter) {
+        all_emails = index
+            .list_recent(email, "INBOX", limit)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .iter()
+            .map(|metadata| metadata_json(metadata, email))
+            .collect();
+    } else if let Some(email) = email_filter {
         // List emails for specific address
         let maildir_path = format!("mail-rs/data/maildir/{}/new", email);
         let path = Path::new(&maildir_path);
@@ -590,9 +706,9 @@ fn read_maildir_emails(
                             break; // End of headers
                         }
                         if line.starts_with("From:") {
-                            from = line[5..].trim().to_string();
+                            from = decode_header(&line[5..]);
                         } else if line.starts_with("Subject:") {
-                            subject = line[8..].trim().to_string();
+                            subject = decode_header(&line[8..]);
                         } else if line.starts_with("Date:") {
                             date = line[5..].trim().to_string();
                         }
@@ -654,7 +770,7 @@ async fn read_email_tool(
                     in_body = true;
                 } else if let Some(colon_pos) = line.find(':') {
                     let key = line[..colon_pos].to_string();
-                    let value = line[colon_pos + 1..].trim().to_string();
+                    let value = decode_header(&line[colon_pos + 1..]);
                     headers.insert(key, value);
                 }
             }
@@ -688,7 +804,10 @@ async fn read_email_tool(
 }
 
 /// Search emails tool implementation
+///
+/// Indexed mailboxes are searched by header; others by full content.
 async fn search_emails_tool(
+    state: Arc<AppState>,
     arguments: HashMap<String, serde_json::Value>,
     id: u64,
 ) -> Result<Json<McpResponse>, (StatusCode, String)> {
@@ -708,6 +827,30 @@ async fn search_emails_tool(
 
     info!("🔍 Searching emails for: {} with query: {}", email, query);
 
+    if let Some(index) = indexed(&state, email).await {
+        let matching_emails: Vec<_> = index
+            .search(email, Some("INBOX"), query, usize::MAX)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .iter()
+            .filter(|metadata| metadata.recent)
+            .map(|metadata| metadata_json(metadata, email))
+            .collect();
+
+        info!("✅ Found {} matching emails", matching_emails.len());
+
+        return Ok(Json(McpResponse {
+            jsonrpc: "2.0".to_string(),
+            result: Some(serde_json::json!({
+                "emails": matching_emails,
+                "count": matching_emails.len(),
+                "query": query,
+            })),
+            error: None,
+            id,
+        }));
+    }
+
     let maildir_path = format!("mail-rs/data/maildir/{}/new", email);
     let path = Path::new(&maildir_path);
 
@@ -749,9 +892,9 @@ async fn search_emails_tool(
                                 break;
                             }
                             if line.starts_with("From:") {
-                                from = line[5..].trim().to_string();
+                                from = decode_header(&line[5..]);
                             } else if line.starts_with("Subject:") {
-                                subject = line[8..].trim().to_string();
+                                subject = decode_header(&line[8..]);
                             }
                         }
 
@@ -788,7 +931,9 @@ async fn mark_as_read_tool(
     arguments: HashMap<String, serde_json::Value>,
     id: u64,
 ) -> Result<Json<McpResponse>, (StatusCode, String)> {
-    use std::fs;
+    use mail_rs::error::MailError;
+    use mail_rs::imap::uid::base_name;
+    use mail_rs::imap::{Mailbox, StoreOperation};
     use std::path::Path;
 
     let email = arguments
@@ -803,11 +948,21 @@ async fn mark_as_read_tool(
 
     info!("📖 Marking email as read: {} for {}", email_id, email);
 
-    // Move email from new/ to cur/
-    let new_path = format!("mail-rs/data/maildir/{}/new/{}", email, email_id);
-    let cur_path = format!("mail-rs/data/maildir/{}/cur/{}", email, email_id);
+    // Set \Seen the way IMAP does; the mail server picks up the rename and
+    // tells IMAP sessions, the web API and the summary store
+    let result = Mailbox::open(email, "INBOX", Path::new("mail-rs/data/maildir")).and_then(
+        |mut mailbox| {
+            let sequence = mailbox
+                .messages()
+                .iter()
+                .find(|msg| base_name(&msg.filename) == base_name(email_id))
+                .map(|msg| msg.sequence.to_string())
+                .ok_or_else(|| MailError::Storage(format!("Email {} not found", email_id)))?;
+            mailbox.store_flags(&sequence, &StoreOperation::Add, &["\\Seen".to_string()])
+        },
+    );
 
-    match fs::rename(&new_path, &cur_path) {
+    match result {
         Ok(_) => {
             info!("✅ Email marked as read: {}", email_id);
             Ok(Json(McpResponse {
@@ -894,6 +1049,7 @@ async fn delete_email_tool(
 
 /// Get email count tool implementation
 async fn get_email_count_tool(
+    state: Arc<AppState>,
     arguments: HashMap<String, serde_json::Value>,
     id: u64,
 ) -> Result<Json<McpResponse>, (StatusCode, String)> {
@@ -907,6 +1063,25 @@ async fn get_email_count_tool(
 
     info!("📊 Getting email count for: {}", email);
 
+    if let Some(index) = indexed(&state, email).await {
+        let counts = index
+            .folder_counts(email, "INBOX")
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+        info!("📧 Found {} unread emails for {}", counts.recent, email);
+
+        return Ok(Json(McpResponse {
+            jsonrpc: "2.0".to_string(),
+            result: Some(serde_json::json!({
+                "count": counts.recent,
+                "email": email
+            })),
+            error: None,
+            id,
+        }));
+    }
+
     let maildir_path = format!("mail-rs/data/maildir/{}/new", email);
     let path = Path::new(&maildir_path);
 
@@ -939,3 +1114,161 @@ async fn get_email_count_tool(
         id,
     }))
 }
+
+/// Open the spam quarantine shared with the mail server
+async fn open_quarantine(
+    state: &AppState,
+) -> Result<mail_rs::spam::QuarantineStore, (StatusCode, String)> {
+    use mail_rs::config::SpamQuarantineConfig;
+    use mail_rs::spam::QuarantineStore;
+
+    QuarantineStore::connect(&state.database_url, &SpamQuarantineConfig::default())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to open spam quarantine: {}", e),
+            )
+        })
+}
+
+/// Arguments naming a user and one of their quarantined messages
+fn quarantine_arguments(
+    arguments: &HashMap<String, serde_json::Value>,
+) -> Result<(&str, &str), (StatusCode, String)> {
+    let email = arguments
+        .get("email")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Missing 'email' argument".to_string()))?;
+
+    let quarantine_id = arguments
+        .get("quarantine_id")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Missing 'quarantine_id' argument".to_string(),
+            )
+        })?;
+
+    Ok((email, quarantine_id))
+}
+
+/// List quarantined messages tool implementation
+async fn list_quarantined_tool(
+    state: Arc<AppState>,
+    arguments: HashMap<String, serde_json::Value>,
+    id: u64,
+) -> Result<Json<McpResponse>, (StatusCode, String)> {
+    let email = arguments
+        .get("email")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Missing 'email' argument".to_string()))?;
+
+    let limit = arguments
+        .get("limit")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(20);
+
+    info!("🛡️  Listing quarantined emails for: {}", email);
+
+    let store = open_quarantine(&state).await?;
+    let messages = store
+        .list(email, limit)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(McpResponse {
+        jsonrpc: "2.0".to_string(),
+        result: Some(serde_json::json!({
+            "messages": messages,
+            "count": messages.len()
+        })),
+        error: None,
+        id,
+    }))
+}
+
+/// Release quarantined message tool implementation
+async fn release_quarantined_tool(
+    state: Arc<AppState>,
+    arguments: HashMap<String, serde_json::Value>,
+    id: u64,
+) -> Result<Json<McpResponse>, (StatusCode, String)> {
+    use mail_rs::storage::MaildirStorage;
+
+    let (email, quarantine_id) = quarantine_arguments(&arguments)?;
+
+    info!("📬 Releasing quarantined email: {} for {}", quarantine_id, email);
+
+    let store = open_quarantine(&state).await?;
+    let storage = MaildirStorage::new("mail-rs/data/maildir".to_string());
+    let result = match store.release(email, quarantine_id, &storage).await {
+        Ok(Some(email_id)) => {
+            info!("✅ Quarantined email released: {}", quarantine_id);
+            serde_json::json!({
+                "success": true,
+                "email_id": email_id,
+                "message": format!("Email {} released to INBOX", quarantine_id)
+            })
+        }
+        Ok(None) => serde_json::json!({
+            "success": false,
+            "error": "Quarantined email not found"
+        }),
+        Err(e) => {
+            warn!("⚠️  Failed to release quarantined email: {}", e);
+            serde_json::json!({
+                "success": false,
+                "error": format!("Failed to release email: {}", e)
+            })
+        }
+    };
+
+    Ok(Json(McpResponse {
+        jsonrpc: "2.0".to_string(),
+        result: Some(result),
+        error: None,
+        id,
+    }))
+}
+
+/// Delete quarantined message tool implementation
+async fn delete_quarantined_tool(
+    state: Arc<AppState>,
+    arguments: HashMap<String, serde_json::Value>,
+    id: u64,
+) -> Result<Json<McpResponse>, (StatusCode, String)> {
+    let (email, quarantine_id) = quarantine_arguments(&arguments)?;
+
+    info!("🗑️  Deleting quarantined email: {} for {}", quarantine_id, email);
+
+    let store = open_quarantine(&state).await?;
+    let result = match store.delete(email, quarantine_id).await {
+        Ok(true) => {
+            info!("✅ Quarantined email deleted: {}", quarantine_id);
+            serde_json::json!({
+                "success": true,
+                "message": format!("Email {} deleted", quarantine_id)
+            })
+        }
+        Ok(false) => serde_json::json!({
+            "success": false,
+            "error": "Quarantined email not found or already deleted"
+        }),
+        Err(e) => {
+            warn!("⚠️  Failed to delete quarantined email: {}", e);
+            serde_json::json!({
+                "success": false,
+                "error": format!("Failed to delete email: {}", e)
+            })
+        }
+    };
+
+    Ok(Json(McpResponse {
+        jsonrpc: "2.0".to_string(),
+        result: Some(result),
+        error: None,
+        id,
+    }))
+}